@@ -0,0 +1,116 @@
+//! Source IP access control of listeners.
+//!
+//! Connections are checked against the allow and deny lists of their listener when they
+//! are accepted, before any handshake work is done for them.
+
+use std::net::IpAddr;
+
+use log::debug;
+
+use crate::address::{Address, AddressMask};
+use crate::auto_ban;
+use crate::client_proxy_selector::{ip_to_u128, matches_ip_mask};
+use crate::config::AccessControlConfig;
+use crate::option_util::NoneOrSome;
+
+/// Allow and deny lists of client IPs.
+#[derive(Debug)]
+pub struct AccessControl {
+    allow: Vec<AddressMask>,
+    deny: Vec<AddressMask>,
+}
+
+impl AccessControl {
+    pub fn new(config: &AccessControlConfig) -> std::io::Result<Self> {
+        let parse = |masks: &NoneOrSome<String>| {
+            masks
+                .iter()
+                .map(|mask| match AddressMask::from(mask) {
+                    Ok(AddressMask {
+                        address: Address::Hostname(_),
+                        ..
+                    })
+                    | Err(_) => Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("invalid access_control entry '{mask}': expected an IP or CIDR"),
+                    )),
+                    Ok(mask) => Ok(mask),
+                })
+                .collect::<std::io::Result<Vec<_>>>()
+        };
+        Ok(Self {
+            allow: parse(&config.allow)?,
+            deny: parse(&config.deny)?,
+        })
+    }
+
+    /// Returns whether a client at `ip` may connect. The IP must not be denied, and must
+    /// be allowed unless the allow list is empty.
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        let ip = ip_to_u128(ip);
+        if self.deny.iter().any(|mask| matches_ip_mask(mask, ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|mask| matches_ip_mask(mask, ip))
+    }
+}
+
+/// Refuses a QUIC connection attempt when the client may not connect or is banned, and
+/// returns it otherwise.
+pub fn admit_quic(
+    access_control: Option<&AccessControl>,
+    incoming: quinn::Incoming,
+) -> Option<quinn::Incoming> {
+    let address = incoming.remote_address();
+    match access_control {
+        Some(access_control) if !access_control.permits(address.ip()) => {
+            debug!("Refused {address}, the address is not allowed to connect");
+            incoming.refuse();
+            None
+        }
+        _ if auto_ban::is_banned(address.ip()) => {
+            debug!("Refused {address}, the address is banned");
+            incoming.refuse();
+            None
+        }
+        _ => Some(incoming),
+    }
+}
+
+pub fn validate_config(config: &AccessControlConfig) -> std::io::Result<()> {
+    AccessControl::new(config).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_control(yaml: &str) -> std::io::Result<AccessControl> {
+        AccessControl::new(&serde_yaml::from_str(yaml).unwrap())
+    }
+
+    #[test]
+    fn test_permits() {
+        let access_control =
+            access_control("allow: [10.0.0.0/8, \"2001:db8::/32\"]\ndeny: 10.0.0.13").unwrap();
+        assert!(access_control.permits("10.1.2.3".parse().unwrap()));
+        assert!(access_control.permits("2001:db8::1".parse().unwrap()));
+        assert!(access_control.permits("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!access_control.permits("10.0.0.13".parse().unwrap()));
+        assert!(!access_control.permits("192.168.1.1".parse().unwrap()));
+        assert!(!access_control.permits("2001:db9::1".parse().unwrap()));
+
+        let deny_only = access_control("deny: 192.168.0.0/16").unwrap();
+        assert!(deny_only.permits("10.1.2.3".parse().unwrap()));
+        assert!(!deny_only.permits("192.168.1.1".parse().unwrap()));
+
+        let allow_all = access_control("{}").unwrap();
+        assert!(allow_all.permits("192.168.1.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_invalid_entries() {
+        assert!(access_control("allow: example.com").is_err());
+        assert!(access_control("deny: 10.0.0.0/33").is_err());
+    }
+}
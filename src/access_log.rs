@@ -0,0 +1,422 @@
+//! Structured access logs of forwarded sessions.
+//!
+//! A line is written for each TCP stream or UDP session that a server forwards, when the
+//! session ends. The log is configured with a top-level `access_log` entry, and lines
+//! are JSON objects or logfmt records written to stdout, a rotated file or syslog.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock};
+use std::time::{Instant, SystemTime};
+
+use log::error;
+use parking_lot::{Mutex, RwLock};
+use serde_json::Value;
+
+use crate::address::NetLocation;
+use crate::clash_api::format_rfc3339;
+use crate::config::{AccessLogConfig, AccessLogFormat};
+use crate::metrics::{ByteCounter, Counter};
+
+static LOGGER: LazyLock<RwLock<Option<Arc<AccessLogger>>>> = LazyLock::new(|| RwLock::new(None));
+
+/// Sets up the access log, or disables it. The log is kept open if its config is
+/// unchanged.
+pub fn configure(config: Option<AccessLogConfig>) -> std::io::Result<()> {
+    let mut logger = LOGGER.write();
+    match config {
+        Some(config) => {
+            if logger.as_ref().is_none_or(|logger| logger.config != config) {
+                *logger = Some(Arc::new(AccessLogger::new(config)?));
+            }
+        }
+        None => *logger = None,
+    }
+    Ok(())
+}
+
+pub fn validate_config(config: &AccessLogConfig) -> std::io::Result<()> {
+    let invalid = |message: &str| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid access log {}: {message}", config.access_log),
+        ))
+    };
+    match Destination::parse(&config.access_log) {
+        Destination::File(path) if path.as_os_str().is_empty() => {
+            return invalid("expected stdout, syslog or a file path");
+        }
+        Destination::File(_) if config.max_size_mb > 0 && config.max_files == 0 => {
+            return invalid("max_files must be at least 1 when max_size_mb is set");
+        }
+        Destination::Syslog if !cfg!(unix) => {
+            return invalid("syslog is only supported on Unix");
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+enum Destination {
+    Stdout,
+    Syslog,
+    File(PathBuf),
+}
+
+impl Destination {
+    fn parse(value: &str) -> Self {
+        match value {
+            "stdout" => Self::Stdout,
+            "syslog" => Self::Syslog,
+            path => Self::File(PathBuf::from(path)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AccessLogger {
+    config: AccessLogConfig,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLogger {
+    fn new(config: AccessLogConfig) -> std::io::Result<Self> {
+        let sink = match Destination::parse(&config.access_log) {
+            Destination::Stdout => Sink::Stdout,
+            #[cfg(unix)]
+            Destination::Syslog => Sink::Syslog(None),
+            #[cfg(not(unix))]
+            Destination::Syslog => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "syslog is only supported on Unix",
+                ));
+            }
+            Destination::File(path) => Sink::File(RotatingFile::open(
+                path,
+                config.max_size_mb.saturating_mul(1024 * 1024),
+                config.max_files,
+            )?),
+        };
+        Ok(Self {
+            config,
+            sink: Mutex::new(sink),
+        })
+    }
+
+    fn write(&self, entry: &AccessLogEntry) {
+        let line = match self.config.format {
+            AccessLogFormat::Json => entry.to_json(),
+            AccessLogFormat::Logfmt => entry.to_logfmt(),
+        };
+        if let Err(e) = self.sink.lock().write_line(&line) {
+            error!("Failed to write access log: {e}");
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Sink {
+    Stdout,
+    File(RotatingFile),
+    /// Connected on first use, and again after a failed send.
+    #[cfg(unix)]
+    Syslog(Option<std::os::unix::net::UnixDatagram>),
+}
+
+impl Sink {
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        match self {
+            Sink::Stdout => writeln!(std::io::stdout().lock(), "{line}"),
+            Sink::File(file) => file.write_line(line),
+            #[cfg(unix)]
+            Sink::Syslog(socket) => {
+                // Facility user, severity info.
+                let message = format!("<14>shoes: {line}");
+                if let Some(connected) = socket
+                    && connected.send(message.as_bytes()).is_ok()
+                {
+                    return Ok(());
+                }
+                let connected = connect_syslog()?;
+                connected.send(message.as_bytes())?;
+                *socket = Some(connected);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+fn connect_syslog() -> std::io::Result<std::os::unix::net::UnixDatagram> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    let path = if std::path::Path::new("/dev/log").exists() {
+        "/dev/log"
+    } else {
+        "/var/run/syslog"
+    };
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+/// A log file that is renamed to `<path>.1` when it reaches its maximum size, shifting
+/// older files to `<path>.2` and so on.
+#[derive(Debug)]
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// 0 to never rotate.
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.max_size > 0 && self.size > 0 && self.size + len > self.max_size {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{line}")?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |index: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{index}"));
+            PathBuf::from(path)
+        };
+        let _ = std::fs::remove_file(rotated(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let _ = std::fs::rename(rotated(index), rotated(index + 1));
+        }
+        std::fs::rename(&self.path, rotated(1))?;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+/// A line of the access log.
+#[derive(Debug)]
+pub struct AccessLogEntry {
+    /// When the session ended.
+    pub time: SystemTime,
+    /// Identifies the listener, e.g. `tcp://0.0.0.0:443`.
+    pub listener: String,
+    pub protocol: String,
+    /// Either `tcp` or `udp`.
+    pub network: &'static str,
+    pub user: Option<String>,
+    pub source: Option<SocketAddr>,
+    pub destination: String,
+    /// Name of the chain group the session was forwarded through.
+    pub outbound: String,
+    pub duration_ms: u64,
+    /// Bytes sent from the client towards the destination.
+    pub bytes_up: u64,
+    /// Bytes sent from the destination back to the client.
+    pub bytes_down: u64,
+}
+
+impl AccessLogEntry {
+    /// Fields in the order they are written. Unknown users and sources are left out.
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        let mut fields = vec![
+            ("time", Value::from(format_rfc3339(self.time))),
+            ("listener", Value::from(self.listener.as_str())),
+            ("protocol", Value::from(self.protocol.as_str())),
+            ("network", Value::from(self.network)),
+        ];
+        if let Some(user) = &self.user {
+            fields.push(("user", Value::from(user.as_str())));
+        }
+        if let Some(source) = self.source {
+            fields.push(("source", Value::from(source.to_string())));
+        }
+        fields.extend([
+            ("destination", Value::from(self.destination.as_str())),
+            ("outbound", Value::from(self.outbound.as_str())),
+            ("duration_ms", Value::from(self.duration_ms)),
+            ("bytes_up", Value::from(self.bytes_up)),
+            ("bytes_down", Value::from(self.bytes_down)),
+        ]);
+        fields
+    }
+
+    fn to_json(&self) -> String {
+        let fields: Vec<String> = self
+            .fields()
+            .into_iter()
+            .map(|(key, value)| format!("\"{key}\":{value}"))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    fn to_logfmt(&self) -> String {
+        let fields: Vec<String> = self
+            .fields()
+            .into_iter()
+            .map(|(key, value)| match value {
+                Value::String(s) => format!("{key}={}", logfmt_value(&s)),
+                value => format!("{key}={value}"),
+            })
+            .collect();
+        fields.join(" ")
+    }
+}
+
+/// Quotes a logfmt value if it is empty or contains spaces, quotes or `=`.
+fn logfmt_value(value: &str) -> String {
+    if !value.is_empty()
+        && !value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '"' || c == '=')
+    {
+        return value.to_string();
+    }
+    // JSON string escaping is also valid logfmt quoting.
+    Value::from(value).to_string()
+}
+
+/// Writes the access log entry of a forwarded session when dropped.
+#[derive(Debug)]
+pub struct SessionLog {
+    logger: Arc<AccessLogger>,
+    start: Instant,
+    entry: AccessLogEntry,
+    bytes_up: Arc<Counter>,
+    bytes_down: Arc<Counter>,
+}
+
+/// Starts the access log entry of a session, or returns None if the access log is
+/// disabled.
+pub fn start_session(
+    network: &'static str,
+    listener: &str,
+    protocol: &str,
+    user: Option<&str>,
+    source: Option<SocketAddr>,
+    destination: &NetLocation,
+    outbound: &str,
+) -> Option<SessionLog> {
+    let logger = LOGGER.read().clone()?;
+    Some(SessionLog {
+        logger,
+        start: Instant::now(),
+        entry: AccessLogEntry {
+            time: SystemTime::now(),
+            listener: listener.to_string(),
+            protocol: protocol.to_string(),
+            network,
+            user: user.map(str::to_string),
+            source,
+            destination: destination.to_string(),
+            outbound: outbound.to_string(),
+            duration_ms: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+        },
+        bytes_up: Arc::new(Counter::default()),
+        bytes_down: Arc::new(Counter::default()),
+    })
+}
+
+impl SessionLog {
+    /// Adds the session's byte counts to the (up, down) counters of the session.
+    pub fn add_byte_counters(&self, up: &mut ByteCounter, down: &mut ByteCounter) {
+        up.push(self.bytes_up.clone());
+        down.push(self.bytes_down.clone());
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        self.entry.time = SystemTime::now();
+        self.entry.duration_ms = self.start.elapsed().as_millis() as u64;
+        self.entry.bytes_up = self.bytes_up.get();
+        self.entry.bytes_down = self.bytes_down.get();
+        self.logger.write(&self.entry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry() -> AccessLogEntry {
+        AccessLogEntry {
+            time: UNIX_EPOCH + Duration::from_millis(1704164645123),
+            listener: "tcp://0.0.0.0:443".to_string(),
+            protocol: "Vless".to_string(),
+            network: "tcp",
+            user: Some("alice smith".to_string()),
+            source: Some("1.2.3.4:5678".parse().unwrap()),
+            destination: "example.com:443".to_string(),
+            outbound: "direct".to_string(),
+            duration_ms: 1500,
+            bytes_up: 100,
+            bytes_down: 2000,
+        }
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(
+            entry().to_json(),
+            r#"{"time":"2024-01-02T03:04:05.123Z","listener":"tcp://0.0.0.0:443","protocol":"Vless","network":"tcp","user":"alice smith","source":"1.2.3.4:5678","destination":"example.com:443","outbound":"direct","duration_ms":1500,"bytes_up":100,"bytes_down":2000}"#
+        );
+    }
+
+    #[test]
+    fn test_logfmt() {
+        assert_eq!(
+            entry().to_logfmt(),
+            "time=2024-01-02T03:04:05.123Z listener=tcp://0.0.0.0:443 protocol=Vless \
+             network=tcp user=\"alice smith\" source=1.2.3.4:5678 destination=example.com:443 \
+             outbound=direct duration_ms=1500 bytes_up=100 bytes_down=2000"
+        );
+
+        let mut entry = entry();
+        entry.user = None;
+        entry.source = None;
+        assert!(!entry.to_logfmt().contains("user="));
+        assert_eq!(logfmt_value(""), "\"\"");
+        assert_eq!(logfmt_value("a=\"b\""), r#""a=\"b\"""#);
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access.log");
+        let mut file = RotatingFile::open(path.clone(), 10, 2).unwrap();
+        for line in ["first", "second", "third", "fourth"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).unwrap();
+        assert_eq!(read("access.log"), "fourth\n");
+        assert_eq!(read("access.log.1"), "third\n");
+        assert_eq!(read("access.log.2"), "second\n");
+        assert!(!dir.path().join("access.log.3").exists());
+    }
+}
@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Address {
@@ -81,52 +81,128 @@ impl std::fmt::Display for Address {
     }
 }
 
+/// Parses a host with an optional IPv6 zone ID, e.g. `fe80::1%eth0` or `fe80::1%2`, and
+/// returns the address and the scope ID of the zone, or 0 without a zone.
+fn parse_host(host: &str) -> std::io::Result<(Address, u32)> {
+    let Some((address, zone)) = host.split_once('%') else {
+        return Ok((Address::from(host)?, 0));
+    };
+    let address = Address::from(address)?;
+    if !address.is_ipv6() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Zone IDs are only supported on IPv6 addresses: {host}"),
+        ));
+    }
+    Ok((address, parse_zone_id(zone)?))
+}
+
+/// Returns the scope ID of an IPv6 zone, given as a number or as an interface name.
+fn parse_zone_id(zone: &str) -> std::io::Result<u32> {
+    if let Ok(scope_id) = zone.parse::<u32>() {
+        return Ok(scope_id);
+    }
+    if let Ok(name) = std::ffi::CString::new(zone) {
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if index != 0 {
+            return Ok(index);
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        format!("Unknown IPv6 zone: {zone}"),
+    ))
+}
+
+/// Writes an address the way it appears in front of a port. IPv6 addresses are enclosed
+/// in brackets, with their zone ID if there is one, e.g. `[2001:db8::1]` or `[fe80::1%2]`.
+fn fmt_host(f: &mut std::fmt::Formatter, address: &Address, scope_id: u32) -> std::fmt::Result {
+    match address {
+        Address::Ipv6(addr) if scope_id != 0 => write!(f, "[{addr}%{scope_id}]"),
+        Address::Ipv6(addr) => write!(f, "[{addr}]"),
+        address => write!(f, "{address}"),
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub struct NetLocation {
     address: Address,
     port: u16,
+    /// Scope ID of the zone of an IPv6 address, or 0 without a zone.
+    scope_id: u32,
 }
 
 impl NetLocation {
     pub const UNSPECIFIED: Self = NetLocation::new(Address::UNSPECIFIED, 0);
 
     pub const fn new(address: Address, port: u16) -> Self {
-        Self { address, port }
+        Self {
+            address,
+            port,
+            scope_id: 0,
+        }
     }
 
     pub fn is_unspecified(&self) -> bool {
         self == &Self::UNSPECIFIED
     }
 
+    /// Parses `host:port`, or `host` when there is a default port. IPv6 addresses are
+    /// enclosed in brackets when followed by a port, e.g. `[2001:db8::1]:443`, and can
+    /// have a zone ID, e.g. `[fe80::1%eth0]:443`.
     pub fn from_str(s: &str, default_port: Option<u16>) -> std::io::Result<Self> {
-        let (address_str, port, expect_ipv6) = match s.rfind(':') {
-            Some(i) => {
-                // The ':' could be from an ipv6 address.
-                match s[i + 1..].parse::<u16>() {
-                    Ok(port) => (&s[0..i], Some(port), false),
-                    Err(_) => (s, default_port, true),
+        let (host, port, expect_ipv6) = if let Some(rest) = s.strip_prefix('[') {
+            let (host, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| std::io::Error::other("Invalid location"))?;
+            let port = if rest.is_empty() {
+                default_port
+            } else {
+                let port = rest
+                    .strip_prefix(':')
+                    .and_then(|port| port.parse::<u16>().ok())
+                    .ok_or_else(|| std::io::Error::other("Invalid port"))?;
+                Some(port)
+            };
+            (host, port, true)
+        } else {
+            match s.rfind(':') {
+                // Without brackets, a location with several colons is an IPv6 address
+                // when there is a default port. Otherwise, the last group is the port,
+                // as in `::1:443`.
+                Some(i) if default_port.is_some() && s[0..i].contains(':') => {
+                    (s, default_port, true)
                 }
+                Some(i) => {
+                    let port = s[i + 1..]
+                        .parse::<u16>()
+                        .map_err(|_| std::io::Error::other("Invalid port"))?;
+                    (&s[0..i], Some(port), s[0..i].contains(':'))
+                }
+                None => (s, default_port, false),
             }
-            None => (s, default_port, false),
         };
 
-        let address = Address::from(address_str)?;
+        let (address, scope_id) = parse_host(host)?;
         if expect_ipv6 && !address.is_ipv6() {
             return Err(std::io::Error::other("Invalid location"));
         }
 
         let port = port.ok_or_else(|| std::io::Error::other("No port"))?;
 
-        Ok(Self { address, port })
+        Ok(Self {
+            address,
+            port,
+            scope_id,
+        })
     }
 
-    #[cfg(test)]
     pub fn from_ip_addr(ip: IpAddr, port: u16) -> Self {
         let address = match ip {
             IpAddr::V4(addr) => Address::Ipv4(addr),
             IpAddr::V6(addr) => Address::Ipv6(addr),
         };
-        Self { address, port }
+        Self::new(address, port)
     }
 
     pub fn components(&self) -> (&Address, u16) {
@@ -145,9 +221,19 @@ impl NetLocation {
         self.port
     }
 
+    /// Returns the scope ID of the zone of an IPv6 address, or 0 without a zone.
+    pub fn scope_id(&self) -> u32 {
+        self.scope_id
+    }
+
     pub fn to_socket_addr_nonblocking(&self) -> Option<SocketAddr> {
         match self.address {
-            Address::Ipv6(ref addr) => Some(SocketAddr::new(IpAddr::V6(*addr), self.port)),
+            Address::Ipv6(ref addr) => Some(SocketAddr::V6(SocketAddrV6::new(
+                *addr,
+                self.port,
+                0,
+                self.scope_id,
+            ))),
             Address::Ipv4(ref addr) => Some(SocketAddr::new(IpAddr::V4(*addr), self.port)),
             Address::Hostname(ref _d) => None,
         }
@@ -156,7 +242,8 @@ impl NetLocation {
 
 impl std::fmt::Display for NetLocation {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}:{}", self.address, self.port)
+        fmt_host(f, &self.address, self.scope_id)?;
+        write!(f, ":{}", self.port)
     }
 }
 
@@ -256,6 +343,8 @@ impl From<&NetLocation> for ResolvedLocation {
 pub struct NetLocationPortRange {
     address: Address,
     ports: Vec<u16>,
+    /// Scope ID of the zone of an IPv6 address, or 0 without a zone.
+    scope_id: u32,
 }
 
 impl From<NetLocation> for NetLocationPortRange {
@@ -263,6 +352,7 @@ impl From<NetLocation> for NetLocationPortRange {
         Self {
             address: location.address,
             ports: vec![location.port],
+            scope_id: location.scope_id,
         }
     }
 }
@@ -277,23 +367,45 @@ impl NetLocationPortRange {
                 "No valid ports specified",
             ));
         }
-        Ok(Self { address, ports })
+        Ok(Self {
+            address,
+            ports,
+            scope_id: 0,
+        })
     }
 
     pub fn from_str(s: &str) -> std::io::Result<Self> {
-        // Split address and port specification
-        let (address_str, port_str) = match s.rfind(':') {
-            Some(i) => (&s[0..i], &s[i + 1..]),
-            None => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    "Missing port specification",
-                ));
-            }
+        // Split address and port specification. IPv6 addresses can be enclosed in
+        // brackets, e.g. `[::]:443` or `[fe80::1%eth0]:443`.
+        let (address_str, port_str, expect_ipv6) = match s.strip_prefix('[') {
+            Some(rest) => match rest.split_once("]:") {
+                Some((address_str, port_str)) => (address_str, port_str, true),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Missing port specification",
+                    ));
+                }
+            },
+            None => match s.rfind(':') {
+                Some(i) => (&s[0..i], &s[i + 1..], false),
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "Missing port specification",
+                    ));
+                }
+            },
         };
 
         // Parse the address
-        let address = Address::from(address_str)?;
+        let (address, scope_id) = parse_host(address_str)?;
+        if expect_ipv6 && !address.is_ipv6() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid IPv6 address: {address_str}"),
+            ));
+        }
 
         // Parse the port ranges
         let mut ports = Vec::new();
@@ -345,7 +457,30 @@ impl NetLocationPortRange {
             }
         }
 
-        Self::new(address, ports)
+        let mut location = Self::new(address, ports)?;
+        location.scope_id = scope_id;
+        Ok(location)
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    /// Returns the ports in ascending order.
+    pub fn ports(&self) -> &[u16] {
+        &self.ports
+    }
+
+    /// Returns the location if there is only a single port.
+    pub fn single_location(&self) -> Option<NetLocation> {
+        match self.ports.as_slice() {
+            [port] => Some(NetLocation {
+                address: self.address.clone(),
+                port: *port,
+                scope_id: self.scope_id,
+            }),
+            _ => None,
+        }
     }
 
     pub fn to_socket_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
@@ -360,9 +495,13 @@ impl NetLocationPortRange {
                 Ok(socket_addrs)
             }
             Address::Ipv6(addr) => {
-                let ip = IpAddr::V6(*addr);
                 for &port in &self.ports {
-                    socket_addrs.push(SocketAddr::new(ip, port));
+                    socket_addrs.push(SocketAddr::V6(SocketAddrV6::new(
+                        *addr,
+                        port,
+                        0,
+                        self.scope_id,
+                    )));
                 }
                 Ok(socket_addrs)
             }
@@ -387,7 +526,8 @@ impl NetLocationPortRange {
 
 impl std::fmt::Display for NetLocationPortRange {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "{}:", self.address)?;
+        fmt_host(f, &self.address, self.scope_id)?;
+        write!(f, ":")?;
 
         // It shouldn't be possible to create an instance with empty ports.
         assert!(!self.ports.is_empty());
@@ -439,6 +579,71 @@ impl serde::ser::Serialize for NetLocationPortRange {
     }
 }
 
+/// An inclusive range of ports, e.g. `443` or `8000-8100`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn from_str(s: &str) -> std::io::Result<Self> {
+        let parse_port = |port: &str| {
+            port.trim().parse::<u16>().map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid port number: {e}"),
+                )
+            })
+        };
+
+        let (start, end) = match s.split_once('-') {
+            Some((start, end)) => (parse_port(start)?, parse_port(end)?),
+            None => {
+                let port = parse_port(s)?;
+                (port, port)
+            }
+        };
+
+        if start > end {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid port range (start > end): {start}-{end}"),
+            ));
+        }
+
+        Ok(Self { start, end })
+    }
+
+    #[inline]
+    pub fn contains(&self, port: u16) -> bool {
+        self.start <= port && port <= self.end
+    }
+}
+
+impl std::fmt::Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl serde::ser::Serialize for PortRange {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        if self.start == self.end {
+            serializer.serialize_u16(self.start)
+        } else {
+            serializer.serialize_str(&self.to_string())
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AddressMask {
     pub address: Address,
@@ -658,6 +863,85 @@ mod tests {
         assert_eq!(deserialized.port(), 8080);
     }
 
+    #[test]
+    fn test_netlocation_ipv6() {
+        let location = NetLocation::from_str("[2001:db8::1]:443", None).unwrap();
+        assert_eq!(
+            location.address(),
+            &Address::Ipv6("2001:db8::1".parse().unwrap())
+        );
+        assert_eq!(location.port(), 443);
+        assert_eq!(location.to_string(), "[2001:db8::1]:443");
+        assert_eq!(
+            location.to_socket_addr_nonblocking(),
+            Some("[2001:db8::1]:443".parse().unwrap())
+        );
+
+        // Without brackets, the last group is the port unless there is a default port.
+        let location = NetLocation::from_str("::1:8080", None).unwrap();
+        assert_eq!(location.to_string(), "[::1]:8080");
+        let location = NetLocation::from_str("2001:db8::1", Some(0)).unwrap();
+        assert_eq!(location.to_string(), "[2001:db8::1]:0");
+        let location = NetLocation::from_str("[::1]", Some(80)).unwrap();
+        assert_eq!(location.to_string(), "[::1]:80");
+
+        assert!(NetLocation::from_str("[::1]", None).is_err());
+        assert!(NetLocation::from_str("[::1]443", None).is_err());
+        assert!(NetLocation::from_str("[::1:443", None).is_err());
+        assert!(NetLocation::from_str("[example.com]:443", None).is_err());
+        assert!(NetLocation::from_str("[127.0.0.1]:443", None).is_err());
+    }
+
+    #[test]
+    fn test_netlocation_zone_id() {
+        let location = NetLocation::from_str("[fe80::1%3]:443", None).unwrap();
+        assert_eq!(location.scope_id(), 3);
+        assert_eq!(location.to_string(), "[fe80::1%3]:443");
+        assert_eq!(
+            location.to_socket_addr_nonblocking(),
+            Some("[fe80::1%3]:443".parse().unwrap())
+        );
+
+        let yaml_str = serde_yaml::to_string(&location).unwrap();
+        let deserialized: NetLocation = serde_yaml::from_str(&yaml_str).unwrap();
+        assert_eq!(deserialized, location);
+
+        #[cfg(target_os = "linux")]
+        assert_ne!(
+            NetLocation::from_str("[::1%lo]:443", None)
+                .unwrap()
+                .scope_id(),
+            0
+        );
+
+        assert!(NetLocation::from_str("[fe80::1%]:443", None).is_err());
+        assert!(NetLocation::from_str("[fe80::1%no-such-interface]:443", None).is_err());
+        assert!(NetLocation::from_str("127.0.0.1%3:443", None).is_err());
+    }
+
+    #[test]
+    fn test_netlocation_port_range_ipv6() {
+        let range = NetLocationPortRange::from_str("[::]:443,8000-8001").unwrap();
+        assert_eq!(range.address(), &Address::Ipv6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(range.ports(), &[443, 8000, 8001]);
+        assert_eq!(range.to_string(), "[::]:443,8000,8001");
+
+        // Unbracketed addresses are still supported.
+        let range = NetLocationPortRange::from_str(":::443").unwrap();
+        assert_eq!(range.to_string(), "[::]:443");
+
+        let range = NetLocationPortRange::from_str("[fe80::1%2]:53").unwrap();
+        assert_eq!(
+            range.to_socket_addrs().unwrap(),
+            vec!["[fe80::1%2]:53".parse().unwrap()]
+        );
+        assert_eq!(range.single_location().unwrap().scope_id(), 2);
+        assert_eq!(range.to_string(), "[fe80::1%2]:53");
+
+        assert!(NetLocationPortRange::from_str("[::1]").is_err());
+        assert!(NetLocationPortRange::from_str("[127.0.0.1]:443").is_err());
+    }
+
     #[test]
     fn test_address_mask_serialization() {
         let address_mask =
@@ -685,4 +969,24 @@ mod tests {
 
         assert_eq!(net_location_mask.to_string(), deserialized.to_string());
     }
+
+    #[test]
+    fn test_port_range() {
+        let range = PortRange::from_str("8000-8100").unwrap();
+        assert!(range.contains(8000));
+        assert!(range.contains(8100));
+        assert!(!range.contains(8101));
+        assert_eq!(range.to_string(), "8000-8100");
+
+        let single = PortRange::from_str("443").unwrap();
+        assert!(single.contains(443));
+        assert!(!single.contains(444));
+
+        assert!(PortRange::from_str("100-1").is_err());
+        assert!(PortRange::from_str("http").is_err());
+
+        let ranges: Vec<PortRange> = serde_yaml::from_str("[443, \"8000-8100\"]").unwrap();
+        assert_eq!(ranges, vec![single, range]);
+        assert_eq!(serde_yaml::to_string(&ranges).unwrap(), "- 443\n- 8000-8100\n");
+    }
 }
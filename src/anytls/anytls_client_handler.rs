@@ -2,12 +2,10 @@
 //!
 //! Implements TcpClientHandler for AnyTLS protocol outbound connections.
 //!
-//! TODO: Implement session pooling to enable real multiplexing.
-//! Currently each request creates a new TLS connection + AnyTLS session with a single stream.
-//! To benefit from AnyTLS multiplexing:
-//! - Pool/reuse AnyTlsClientSession instances across multiple client requests
-//! - Open multiple streams on the same session for different destinations
-//! - Add config options like idle_session_timeout, min_idle_session (similar to sing-box)
+//! When AnyTLS is the last hop of a chain, TCP connections go through the session pool
+//! of its proxy connector instead (see `AnyTlsClientPool`), and this handler only passes
+//! through the connections of new sessions. Otherwise, each request creates a new
+//! session with a single stream.
 
 use async_trait::async_trait;
 use std::sync::Arc;
@@ -16,10 +14,13 @@ use tokio::io::AsyncWriteExt;
 use crate::address::{Address, NetLocation, ResolvedLocation};
 use crate::anytls::anytls_client_session::AnyTlsClientSession;
 use crate::anytls::anytls_padding::PaddingFactory;
+use crate::anytls::is_anytls_session_location;
 use crate::async_stream::AsyncMessageStream;
 use crate::async_stream::AsyncStream;
+use crate::config::TrafficPaddingConfig;
 use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
 use crate::uot::UOT_V2_MAGIC_ADDRESS;
+use crate::uot::uot_common::write_v2_connect_request;
 use crate::vless::VlessMessageStream;
 
 /// AnyTLS client handler implementing TcpClientHandler.
@@ -32,6 +33,8 @@ pub struct AnyTlsClientHandler {
     password: String,
     /// Padding factory for traffic obfuscation
     padding: Arc<PaddingFactory>,
+    /// Padding after the padding scheme ends
+    traffic_padding: Option<TrafficPaddingConfig>,
     /// UDP enabled
     udp_enabled: bool,
 }
@@ -46,10 +49,16 @@ impl std::fmt::Debug for AnyTlsClientHandler {
 
 impl AnyTlsClientHandler {
     /// Create a new AnyTLS client handler
-    pub fn new(password: String, padding: Arc<PaddingFactory>, udp_enabled: bool) -> Self {
+    pub fn new(
+        password: String,
+        padding: Arc<PaddingFactory>,
+        traffic_padding: Option<TrafficPaddingConfig>,
+        udp_enabled: bool,
+    ) -> Self {
         Self {
             password,
             padding,
+            traffic_padding,
             udp_enabled,
         }
     }
@@ -62,9 +71,21 @@ impl TcpClientHandler for AnyTlsClientHandler {
         client_stream: Box<dyn AsyncStream>,
         remote_location: ResolvedLocation,
     ) -> std::io::Result<TcpClientSetupResult> {
-        let session =
-            AnyTlsClientSession::new(client_stream, &self.password, Arc::clone(&self.padding))
-                .await?;
+        // The session pool opens the session on this connection.
+        if is_anytls_session_location(remote_location.location()) {
+            return Ok(TcpClientSetupResult {
+                client_stream,
+                early_data: None,
+            });
+        }
+
+        let session = AnyTlsClientSession::new(
+            client_stream,
+            &self.password,
+            Arc::clone(&self.padding),
+            self.traffic_padding,
+        )
+        .await?;
 
         let stream = session.open_stream(remote_location.into_location()).await?;
 
@@ -90,17 +111,22 @@ impl TcpClientHandler for AnyTlsClientHandler {
             ));
         }
 
-        let session =
-            AnyTlsClientSession::new(client_stream, &self.password, Arc::clone(&self.padding))
-                .await?;
+        let session = AnyTlsClientSession::new(
+            client_stream,
+            &self.password,
+            Arc::clone(&self.padding),
+            self.traffic_padding,
+        )
+        .await?;
 
         // UoT V2 Connect Mode: single destination via magic address
         let uot_dest = NetLocation::new(Address::Hostname(UOT_V2_MAGIC_ADDRESS.to_string()), 0);
         let mut stream = session.open_stream(uot_dest).await?;
 
         // UoT V2 header: isConnect(1) + destination
-        stream.write_u8(1).await?;
-        stream.write_all(&encode_socks_address(target.location())).await?;
+        stream
+            .write_all(&write_v2_connect_request(target.location()))
+            .await?;
         stream.flush().await?;
 
         let message_stream = VlessMessageStream::new(stream);
@@ -108,27 +134,3 @@ impl TcpClientHandler for AnyTlsClientHandler {
         Ok(Box::new(message_stream))
     }
 }
-
-/// Encode a NetLocation to SOCKS address format
-fn encode_socks_address(location: &NetLocation) -> Vec<u8> {
-    let mut buf = Vec::with_capacity(32);
-
-    match location.address() {
-        Address::Ipv4(ip) => {
-            buf.push(0x01); // IPv4
-            buf.extend_from_slice(&ip.octets());
-        }
-        Address::Ipv6(ip) => {
-            buf.push(0x04); // IPv6
-            buf.extend_from_slice(&ip.octets());
-        }
-        Address::Hostname(host) => {
-            buf.push(0x03); // Domain
-            buf.push(host.len() as u8);
-            buf.extend_from_slice(host.as_bytes());
-        }
-    }
-
-    buf.extend_from_slice(&location.port().to_be_bytes());
-    buf
-}
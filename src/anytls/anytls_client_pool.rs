@@ -0,0 +1,218 @@
+//! AnyTLS Client Session Pool
+//!
+//! Reuses idle sessions across client requests, like the reference implementation:
+//! a request takes the most recently used idle session, or opens a new connection when
+//! there is none, and the session returns to the pool when its stream closes. Sessions
+//! that stay idle for longer than the idle timeout are closed.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::address::NetLocation;
+use crate::anytls::anytls_client_session::AnyTlsClientSession;
+use crate::anytls::anytls_padding::PaddingFactory;
+use crate::anytls::anytls_stream::AnyTlsStream;
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::config::TrafficPaddingConfig;
+
+/// Longest time between checks for idle sessions.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+struct IdleSession {
+    session: Arc<AnyTlsClientSession>,
+    idle_since: Instant,
+}
+
+type IdleSessions = Mutex<Vec<IdleSession>>;
+
+/// Pool of AnyTLS sessions of an outbound.
+pub struct AnyTlsClientPool {
+    password: String,
+    padding: Arc<PaddingFactory>,
+    traffic_padding: Option<TrafficPaddingConfig>,
+    idle_timeout: Duration,
+    idle_sessions: Arc<IdleSessions>,
+}
+
+impl std::fmt::Debug for AnyTlsClientPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AnyTlsClientPool")
+            .field("idle_timeout", &self.idle_timeout)
+            .field("idle_sessions", &self.idle_sessions.lock().len())
+            .finish()
+    }
+}
+
+impl AnyTlsClientPool {
+    pub fn new(
+        password: String,
+        padding: Arc<PaddingFactory>,
+        traffic_padding: Option<TrafficPaddingConfig>,
+        idle_timeout: Duration,
+    ) -> Self {
+        Self {
+            password,
+            padding,
+            traffic_padding,
+            idle_timeout,
+            idle_sessions: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Opens a stream to `target`. `connect` opens a new connection to the server, and
+    /// is only awaited when there is no idle session.
+    pub async fn open_stream<F>(
+        &self,
+        target: &NetLocation,
+        connect: F,
+    ) -> io::Result<Box<dyn AsyncStream>>
+    where
+        F: Future<Output = io::Result<Box<dyn AsyncStream>>>,
+    {
+        let session = match self.take_idle_session() {
+            Some(session) => {
+                log::debug!("AnyTLS client: reusing idle session");
+                session
+            }
+            None => {
+                log::debug!("AnyTLS client: opening new session");
+                let session = AnyTlsClientSession::new(
+                    connect.await?,
+                    &self.password,
+                    self.padding.clone(),
+                    self.traffic_padding,
+                )
+                .await?;
+                tokio::spawn(close_when_idle(
+                    Arc::downgrade(&self.idle_sessions),
+                    Arc::clone(&session),
+                    self.idle_timeout,
+                ));
+                session
+            }
+        };
+
+        // Returns the session to the pool if the stream fails to open.
+        let lease = SessionLease {
+            session: Some(Arc::clone(&session)),
+            idle_sessions: Arc::downgrade(&self.idle_sessions),
+        };
+        let stream = session.open_stream(target.clone()).await?;
+        Ok(Box::new(PooledStream {
+            stream,
+            _lease: lease,
+        }))
+    }
+
+    fn take_idle_session(&self) -> Option<Arc<AnyTlsClientSession>> {
+        let mut idle_sessions = self.idle_sessions.lock();
+        idle_sessions.retain(|idle| !idle.session.is_closed());
+        idle_sessions.pop().map(|idle| idle.session)
+    }
+}
+
+async fn close_when_idle(
+    idle_sessions: Weak<IdleSessions>,
+    session: Arc<AnyTlsClientSession>,
+    idle_timeout: Duration,
+) {
+    let mut interval = tokio::time::interval(idle_timeout.min(IDLE_CHECK_INTERVAL));
+    // The first tick completes immediately.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if session.is_closed() {
+            break;
+        }
+        // The pool was dropped, so the session is closed when its stream closes.
+        let Some(pool) = idle_sessions.upgrade() else {
+            break;
+        };
+        let mut pool_sessions = pool.lock();
+        if let Some(index) = pool_sessions.iter().position(|idle| {
+            Arc::ptr_eq(&idle.session, &session) && idle.idle_since.elapsed() >= idle_timeout
+        }) {
+            pool_sessions.remove(index);
+            session.close();
+            log::debug!("AnyTLS client: closed idle session");
+            break;
+        }
+    }
+}
+
+/// Returns the session to the pool when the stream is dropped.
+struct SessionLease {
+    session: Option<Arc<AnyTlsClientSession>>,
+    idle_sessions: Weak<IdleSessions>,
+}
+
+impl Drop for SessionLease {
+    fn drop(&mut self) {
+        let Some(session) = self.session.take() else {
+            return;
+        };
+        match self.idle_sessions.upgrade() {
+            Some(idle_sessions) if !session.is_closed() => {
+                idle_sessions.lock().push(IdleSession {
+                    session,
+                    idle_since: Instant::now(),
+                });
+            }
+            _ => session.close(),
+        }
+    }
+}
+
+/// Stream of a pooled session.
+struct PooledStream {
+    // Dropped before the lease, so that the FIN is queued before the session is reused.
+    stream: AnyTlsStream,
+    _lease: SessionLease,
+}
+
+impl AsyncRead for PooledStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PooledStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for PooledStream {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn poll_write_ping(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Pin::new(&mut self.stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncStream for PooledStream {}
@@ -17,7 +17,12 @@ use crate::anytls::anytls_padding::PaddingFactory;
 use crate::anytls::anytls_stream::{AnyTlsStream, STREAM_CHANNEL_BUFFER};
 use crate::anytls::anytls_types::{Command, FRAME_HEADER_SIZE, Frame, FrameCodec, StringMap};
 use crate::async_stream::AsyncStream;
+use crate::config::TrafficPaddingConfig;
 use crate::socks_handler::write_location_to_vec;
+use crate::traffic_padding::TrafficPadding;
+
+/// Maximum padding bytes in a WASTE frame sent for traffic padding
+const MAX_TRAFFIC_PADDING_LEN: usize = 1024;
 
 /// Outgoing message types for the unified writer channel
 enum OutgoingMessage {
@@ -34,6 +39,8 @@ enum OutgoingMessage {
     Data { stream_id: u32, data: Bytes },
     /// FIN frame for a stream - encoded in writer loop
     Fin { stream_id: u32 },
+    /// Stops the writer loop and shuts down the transport
+    Close,
 }
 
 /// AnyTLS client session - manages multiplexed streams over a connection
@@ -97,11 +104,15 @@ impl AnyTlsClientSession {
     /// sent together with the first stream's SYN and destination address as a
     /// single TLS record to avoid fingerprinting.
     ///
+    /// When `traffic_padding` is set, writes after the padding scheme ends are padded and
+    /// delayed too.
+    ///
     /// Returns the session wrapped in Arc for shared ownership.
     pub async fn new(
         mut transport: Box<dyn AsyncStream>,
         password: &str,
         padding: Arc<PaddingFactory>,
+        traffic_padding: Option<TrafficPaddingConfig>,
     ) -> io::Result<Arc<Self>> {
         let hash_result = digest(&SHA256, password.as_bytes());
         let mut password_hash = [0u8; 32];
@@ -135,7 +146,14 @@ impl AnyTlsClientSession {
 
         // Spawn background tasks
         let (read_half, write_half) = tokio::io::split(transport);
-        Self::spawn_tasks(Arc::clone(&session), read_half, write_half, outgoing_rx);
+        let traffic_padding = traffic_padding.as_ref().map(TrafficPadding::new);
+        Self::spawn_tasks(
+            Arc::clone(&session),
+            read_half,
+            write_half,
+            outgoing_rx,
+            traffic_padding,
+        );
 
         Ok(session)
     }
@@ -204,6 +222,7 @@ impl AnyTlsClientSession {
         reader: R,
         writer: W,
         outgoing_rx: mpsc::UnboundedReceiver<OutgoingMessage>,
+        traffic_padding: Option<TrafficPadding>,
     ) where
         R: tokio::io::AsyncRead + Send + Unpin + 'static,
         W: tokio::io::AsyncWrite + Send + Unpin + 'static,
@@ -211,17 +230,22 @@ impl AnyTlsClientSession {
         // Writer task - handles all outgoing messages (control and data)
         let session_writer = Arc::clone(&session);
         tokio::spawn(async move {
-            if let Err(e) = Self::writer_loop(session_writer, writer, outgoing_rx).await {
+            if let Err(e) =
+                Self::writer_loop(session_writer, writer, outgoing_rx, traffic_padding).await
+            {
                 log::debug!("AnyTLS client writer ended: {}", e);
             }
         });
 
-        // Reader task
+        // Reader task - the session is closed when the server closes the connection
         let session_reader = Arc::clone(&session);
         tokio::spawn(async move {
-            if let Err(e) = Self::reader_loop(session_reader, reader).await {
+            if let Err(e) = Self::reader_loop(Arc::clone(&session_reader), reader).await {
                 log::debug!("AnyTLS client reader ended: {}", e);
             }
+            session_reader.close();
+            // Dropping the senders ends the reads of open streams
+            session_reader.streams.write().await.clear();
         });
     }
 
@@ -239,6 +263,7 @@ impl AnyTlsClientSession {
         session: Arc<Self>,
         mut writer: W,
         mut outgoing_rx: mpsc::UnboundedReceiver<OutgoingMessage>,
+        mut traffic_padding: Option<TrafficPadding>,
     ) -> io::Result<()>
     where
         W: tokio::io::AsyncWrite + Send + Unpin,
@@ -265,8 +290,14 @@ impl AnyTlsClientSession {
                 OutgoingMessage::Buffered { data } => {
                     // Send buffered frames as single TLS record to avoid fingerprinting
                     log::debug!("AnyTLS client writer: buffered frames {} bytes", data.len());
-                    Self::write_with_padding(&session, &mut writer, &data, &mut padding_buf)
-                        .await?;
+                    Self::write_with_padding(
+                        &session,
+                        &mut writer,
+                        &data,
+                        &mut padding_buf,
+                        &mut traffic_padding,
+                    )
+                    .await?;
                     writer.flush().await?;
                 }
                 OutgoingMessage::Control {
@@ -279,8 +310,14 @@ impl AnyTlsClientSession {
                         "AnyTLS client writer: control frame {} bytes",
                         write_buf.len()
                     );
-                    Self::write_with_padding(&session, &mut writer, &write_buf, &mut padding_buf)
-                        .await?;
+                    Self::write_with_padding(
+                        &session,
+                        &mut writer,
+                        &write_buf,
+                        &mut padding_buf,
+                        &mut traffic_padding,
+                    )
+                    .await?;
                     writer.flush().await?;
                 }
                 OutgoingMessage::Data { stream_id, data } => {
@@ -290,24 +327,37 @@ impl AnyTlsClientSession {
                         stream_id,
                         write_buf.len()
                     );
-                    Self::write_with_padding(&session, &mut writer, &write_buf, &mut padding_buf)
-                        .await?;
+                    Self::write_with_padding(
+                        &session,
+                        &mut writer,
+                        &write_buf,
+                        &mut padding_buf,
+                        &mut traffic_padding,
+                    )
+                    .await?;
                     writer.flush().await?;
                 }
                 OutgoingMessage::Fin { stream_id } => {
                     Frame::control(Command::Fin, stream_id).encode_into(&mut write_buf);
                     log::debug!("AnyTLS client writer: stream {} FIN", stream_id);
-                    Self::write_with_padding(&session, &mut writer, &write_buf, &mut padding_buf)
-                        .await?;
+                    Self::write_with_padding(
+                        &session,
+                        &mut writer,
+                        &write_buf,
+                        &mut padding_buf,
+                        &mut traffic_padding,
+                    )
+                    .await?;
                     writer.flush().await?;
 
                     let mut streams = session.streams.write().await;
                     streams.remove(&stream_id);
                 }
+                OutgoingMessage::Close => break,
             }
         }
         log::debug!("AnyTLS client writer loop: channel closed, exiting");
-        Ok(())
+        writer.shutdown().await
     }
 
     /// Write data with padding applied (client-side padding)
@@ -319,11 +369,14 @@ impl AnyTlsClientSession {
     ///
     /// This matches the reference Go implementation which uses slices.Concat()
     /// to combine payload and padding before writing.
+    ///
+    /// After the padding scheme ends, data is written with traffic padding, if enabled.
     async fn write_with_padding<W>(
         session: &Arc<Self>,
         writer: &mut W,
         data: &[u8],
         padding_buf: &mut BytesMut,
+        traffic_padding: &mut Option<TrafficPadding>,
     ) -> io::Result<()>
     where
         W: tokio::io::AsyncWrite + Send + Unpin,
@@ -331,8 +384,9 @@ impl AnyTlsClientSession {
         use crate::anytls::anytls_padding::CHECK_MARK;
 
         if !session.send_padding.load(Ordering::Relaxed) {
-            // Padding disabled, write directly
-            return writer.write_all(data).await;
+            // Padding scheme ended, write directly or with traffic padding
+            return Self::write_with_traffic_padding(writer, data, padding_buf, traffic_padding)
+                .await;
         }
 
         // Increment packet counter and check if we should still pad
@@ -341,7 +395,8 @@ impl AnyTlsClientSession {
 
         if pkt >= stop {
             session.send_padding.store(false, Ordering::Relaxed);
-            return writer.write_all(data).await;
+            return Self::write_with_traffic_padding(writer, data, padding_buf, traffic_padding)
+                .await;
         }
 
         // Get padding sizes for this packet
@@ -421,6 +476,56 @@ impl AnyTlsClientSession {
         Ok(())
     }
 
+    /// Write data after an optional delay, followed by a WASTE frame in the same write
+    /// and sometimes by a standalone WASTE frame, as long as the overhead budget allows.
+    async fn write_with_traffic_padding<W>(
+        writer: &mut W,
+        data: &[u8],
+        padding_buf: &mut BytesMut,
+        traffic_padding: &mut Option<TrafficPadding>,
+    ) -> io::Result<()>
+    where
+        W: tokio::io::AsyncWrite + Send + Unpin,
+    {
+        let Some(traffic_padding) = traffic_padding else {
+            return writer.write_all(data).await;
+        };
+
+        if let Some(delay) = traffic_padding.delay() {
+            tokio::time::sleep(delay).await;
+        }
+
+        let max_frame_len = FRAME_HEADER_SIZE + MAX_TRAFFIC_PADDING_LEN;
+        match traffic_padding.padding_len(data.len(), FRAME_HEADER_SIZE, max_frame_len) {
+            Some(frame_len) => {
+                padding_buf.clear();
+                padding_buf.reserve(data.len() + frame_len);
+                padding_buf.extend_from_slice(data);
+                Self::put_waste_frame(padding_buf, frame_len - FRAME_HEADER_SIZE);
+                writer.write_all(padding_buf).await?;
+            }
+            None => writer.write_all(data).await?,
+        }
+
+        if let Some(frame_len) = traffic_padding.dummy_frame_len(FRAME_HEADER_SIZE, max_frame_len) {
+            padding_buf.clear();
+            padding_buf.reserve(frame_len);
+            Self::put_waste_frame(padding_buf, frame_len - FRAME_HEADER_SIZE);
+            writer.flush().await?;
+            writer.write_all(padding_buf).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Append a WASTE frame with `len` zero bytes
+    fn put_waste_frame(buf: &mut BytesMut, len: usize) {
+        buf.put_u8(Command::Waste as u8);
+        buf.put_u32(0); // stream_id = 0 for padding
+        buf.put_u16(len as u16);
+        buf.put_bytes(0, len);
+    }
+
     /// Reader loop - receives frames from the transport
     async fn reader_loop<R>(session: Arc<Self>, mut reader: R) -> io::Result<()>
     where
@@ -574,6 +679,17 @@ impl AnyTlsClientSession {
         Ok(())
     }
 
+    /// Returns true once the session was closed, or its connection ended.
+    pub fn is_closed(&self) -> bool {
+        self.is_closed.load(Ordering::Relaxed)
+    }
+
+    /// Close the session, shutting down its transport.
+    pub fn close(&self) {
+        self.is_closed.store(true, Ordering::Relaxed);
+        let _ = self.outgoing_tx.send(OutgoingMessage::Close);
+    }
+
     /// Open a new stream to the given destination
     ///
     /// Takes `self: &Arc<Self>` to allow the stream to hold a reference
@@ -16,6 +16,7 @@ use crate::address::NetLocation;
 use crate::anytls::anytls_padding::PaddingFactory;
 use crate::anytls::anytls_server_session::AnyTlsSession;
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
 use crate::copy_bidirectional::copy_bidirectional;
 use crate::resolver::Resolver;
@@ -119,7 +120,7 @@ impl TcpServerHandler for AnyTlsServerHandler {
             if let Some(ref fallback) = self.fallback {
                 return self.fallback_to_dest(server_stream, reader, fallback).await;
             }
-            return Err(std::io::Error::new(
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::PermissionDenied,
                 "authentication failed (prefix mismatch)",
             ));
@@ -141,7 +142,7 @@ impl TcpServerHandler for AnyTlsServerHandler {
                 if let Some(ref fallback) = self.fallback {
                     return self.fallback_to_dest(server_stream, reader, fallback).await;
                 }
-                return Err(std::io::Error::new(
+                return Err(auto_ban::rejected(
                     std::io::ErrorKind::PermissionDenied,
                     "authentication failed",
                 ));
@@ -10,6 +10,8 @@ use crate::anytls::anytls_types::{Command, FRAME_HEADER_SIZE, Frame, FrameCodec,
 use crate::async_stream::{AsyncMessageStream, AsyncTargetedMessageStream};
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
 use crate::copy_bidirectional::copy_bidirectional;
+use crate::metrics::ByteCounter;
+use crate::rate_limit::RateLimiter;
 use crate::resolver::Resolver;
 use crate::routing::{ServerStream, run_udp_routing};
 use crate::socks_handler::read_location_direct;
@@ -876,7 +878,17 @@ impl AnyTlsSession {
                 log::debug!("AnyTLS stream {} UoT V2 connect: connected", stream_id);
 
                 // Run UDP copy
-                let result = run_udp_copy(server_stream, client_stream, false, false).await;
+                let result = run_udp_copy(
+                    server_stream,
+                    client_stream,
+                    false,
+                    false,
+                    ByteCounter::default(),
+                    ByteCounter::default(),
+                    RateLimiter::default(),
+                    RateLimiter::default(),
+                )
+                .await;
 
                 if let Err(e) = &result {
                     log::debug!("AnyTLS stream {} UoT V2 connect ended: {}", stream_id, e);
@@ -928,7 +940,10 @@ impl AnyTlsSession {
             ServerStream::Targeted(server_stream),
             self.proxy_provider.clone(),
             self.resolver.clone(),
+            None,
             false, // no initial flush needed
+            RateLimiter::default(),
+            RateLimiter::default(),
         )
         .await;
 
@@ -4,8 +4,13 @@
 //! - Session multiplexing: Multiple proxy streams over a single TLS connection
 //! - Configurable padding: First N packets are padded to obscure fingerprints
 //! - Password authentication: SHA256-based user authentication
+//!
+//! Clients keep idle sessions in a pool and reuse them for later connections. The
+//! connection of a new session goes through the client chain to a pseudo-destination,
+//! for which the client handler returns the stream without opening a session.
 
 mod anytls_client_handler;
+mod anytls_client_pool;
 mod anytls_client_session;
 mod anytls_padding;
 mod anytls_server_handler;
@@ -14,5 +19,20 @@ mod anytls_stream;
 mod anytls_types;
 
 pub use anytls_client_handler::AnyTlsClientHandler;
+pub use anytls_client_pool::AnyTlsClientPool;
 pub use anytls_padding::PaddingFactory;
 pub use anytls_server_handler::AnyTlsServerHandler;
+
+use crate::address::{Address, NetLocation};
+
+const SESSION_ADDRESS: &str = "session.anytls.internal";
+
+/// Destination of connections that carry pooled AnyTLS sessions.
+pub fn anytls_session_location() -> NetLocation {
+    NetLocation::new(Address::Hostname(SESSION_ADDRESS.to_string()), 0)
+}
+
+/// Returns whether a connection to `location` carries a pooled AnyTLS session.
+pub fn is_anytls_session_location(location: &NetLocation) -> bool {
+    location.address().hostname() == Some(SESSION_ADDRESS)
+}
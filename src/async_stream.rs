@@ -170,7 +170,13 @@ impl AsyncShutdownMessage for UdpSocket {
     }
 }
 
-pub trait AsyncStream: AsyncRead + AsyncWrite + AsyncPing + Unpin + Send + Sync {}
+pub trait AsyncStream: AsyncRead + AsyncWrite + AsyncPing + Unpin + Send + Sync {
+    /// Returns the TCP stream that this stream reads from and writes to without changing
+    /// the data, so that copies can move it in the kernel instead.
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        None
+    }
+}
 
 pub trait AsyncMessageStream:
     AsyncReadMessage
@@ -232,7 +238,11 @@ impl AsyncPing for TcpStream {
     }
 }
 
-impl AsyncStream for TcpStream {}
+impl AsyncStream for TcpStream {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        Some(self)
+    }
+}
 
 #[cfg(target_family = "unix")]
 impl AsyncPing for UnixStream {
@@ -447,8 +457,16 @@ impl<T: ?Sized + AsyncWriteSourcedMessage + Unpin> AsyncWriteSourcedMessage for
     }
 }
 
-impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for Box<T> {}
-impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for &mut T {}
+impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for Box<T> {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        (**self).as_tcp_stream()
+    }
+}
+impl<T: ?Sized + AsyncStream + Unpin> AsyncStream for &mut T {
+    fn as_tcp_stream(&mut self) -> Option<&mut TcpStream> {
+        (**self).as_tcp_stream()
+    }
+}
 
 impl<T: ?Sized + AsyncMessageStream + Unpin> AsyncMessageStream for Box<T> {}
 impl<T: ?Sized + AsyncMessageStream + Unpin> AsyncMessageStream for &mut T {}
@@ -0,0 +1,382 @@
+//! Automatic bans of client IPs that fail handshakes too often.
+//!
+//! Servers record a failure for the client IP whenever a handshake fails with an error
+//! created by [`rejected`], which protocols return when the client fails authentication,
+//! replays a handshake or violates the protocol. Clients that disconnect or time out during
+//! the handshake aren't counted. When an IP fails `auto_ban_failures` times within
+//! `auto_ban_window_secs`, it is banned for `auto_ban_duration_secs`, and its connections are
+//! dropped when they are accepted, like those of IPs denied by access control. Bans are
+//! listed and lifted through the Clash API and the stats API.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use lru::LruCache;
+use parking_lot::Mutex;
+
+use crate::config::AutoBanConfig;
+
+/// Number of IPs whose failures are tracked. Above it, the IPs that failed least recently
+/// are forgotten.
+const MAX_TRACKED_IPS: usize = 65536;
+
+/// Number of banned IPs. Above it, the oldest bans are lifted early.
+const MAX_BANNED_IPS: usize = 65536;
+
+/// Interval at which stale failures and expired bans are removed, as connections are checked.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+static STATE: Mutex<Option<BanList>> = Mutex::new(None);
+
+/// Error of a client that failed authentication or violated the protocol.
+#[derive(Debug)]
+struct Rejected(String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// Returns an error for a handshake of a client that failed authentication, replayed an
+/// earlier handshake or violated the protocol. Handshakes that fail with it count towards
+/// the automatic ban of the client IP.
+pub fn rejected(kind: std::io::ErrorKind, message: impl Into<String>) -> std::io::Error {
+    std::io::Error::new(kind, Rejected(message.into()))
+}
+
+/// Returns whether `error` was returned by [`rejected`].
+pub fn is_rejected(error: &std::io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Rejected>())
+}
+
+#[derive(Debug, Clone, Copy)]
+struct BanSettings {
+    failures: usize,
+    window: Duration,
+    duration: Duration,
+}
+
+impl From<&AutoBanConfig> for BanSettings {
+    fn from(config: &AutoBanConfig) -> Self {
+        Self {
+            failures: config.auto_ban_failures as usize,
+            window: Duration::from_secs(config.auto_ban_window_secs),
+            duration: Duration::from_secs(config.auto_ban_duration_secs),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BanList {
+    settings: BanSettings,
+    /// Times of the last failures of each IP, at most `settings.failures` of them, ordered
+    /// by the last failure.
+    failures: LruCache<IpAddr, VecDeque<Instant>>,
+    /// Banned IPs and when their bans end, ordered by when they were banned.
+    bans: LruCache<IpAddr, Instant>,
+    last_sweep: Instant,
+}
+
+impl BanList {
+    fn new(settings: BanSettings, now: Instant) -> Self {
+        Self {
+            settings,
+            failures: LruCache::new(NonZeroUsize::new(MAX_TRACKED_IPS).unwrap()),
+            bans: LruCache::new(NonZeroUsize::new(MAX_BANNED_IPS).unwrap()),
+            last_sweep: now,
+        }
+    }
+
+    fn record_failure(&mut self, ip: IpAddr, now: Instant) {
+        if self.is_banned(ip, now) {
+            return;
+        }
+
+        // A reload can lower the number of failures below the ones already recorded.
+        let times = self.failures.get_or_insert_mut(ip, VecDeque::new);
+        while times.len() >= self.settings.failures {
+            times.pop_front();
+        }
+        times.push_back(now);
+        if times.len() >= self.settings.failures
+            && times
+                .front()
+                .is_some_and(|first| now.duration_since(*first) < self.settings.window)
+        {
+            self.failures.pop(&ip);
+            self.bans.put(ip, now + self.settings.duration);
+            warn!(
+                "Banned {ip} for {}s after {} failed handshakes",
+                self.settings.duration.as_secs(),
+                self.settings.failures
+            );
+        }
+    }
+
+    fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if now.duration_since(self.last_sweep) >= SWEEP_INTERVAL {
+            self.sweep(now);
+        }
+        match self.bans.peek(&ip) {
+            Some(until) if *until > now => true,
+            Some(_) => {
+                self.bans.pop(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Forgets the failures that are too old to lead to a ban, and removes expired bans.
+    fn sweep(&mut self, now: Instant) {
+        self.last_sweep = now;
+        while let Some((_, times)) = self.failures.peek_lru() {
+            if times
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < self.settings.window)
+            {
+                break;
+            }
+            self.failures.pop_lru();
+        }
+        let expired: Vec<IpAddr> = self
+            .bans
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(ip, _)| *ip)
+            .collect();
+        for ip in expired {
+            self.bans.pop(&ip);
+        }
+    }
+}
+
+/// Enables bans with the given settings, or disables them and lifts all bans. Bans and
+/// failures are kept when the settings change.
+pub fn configure(config: Option<AutoBanConfig>) {
+    let mut state = STATE.lock();
+    match (config, state.as_mut()) {
+        (Some(config), Some(ban_list)) => ban_list.settings = BanSettings::from(&config),
+        (Some(config), None) => {
+            *state = Some(BanList::new(BanSettings::from(&config), Instant::now()))
+        }
+        (None, _) => *state = None,
+    }
+}
+
+pub fn validate_config(config: &AutoBanConfig) -> std::io::Result<()> {
+    let invalid = |message: &str| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid auto ban config: {message}"),
+        ))
+    };
+    if config.auto_ban_failures == 0 {
+        return invalid("auto_ban_failures must be at least 1");
+    }
+    if config.auto_ban_window_secs == 0 {
+        return invalid("auto_ban_window_secs must be at least 1");
+    }
+    if config.auto_ban_duration_secs == 0 {
+        return invalid("auto_ban_duration_secs must be at least 1");
+    }
+    Ok(())
+}
+
+/// Records a handshake of a client at `ip` that failed with an error returned by
+/// [`rejected`], and bans the IP when it failed too often.
+pub fn record_failure(ip: IpAddr) {
+    if let Some(ban_list) = STATE.lock().as_mut() {
+        ban_list.record_failure(ip.to_canonical(), Instant::now());
+    }
+}
+
+/// Returns whether connections from `ip` are dropped because it is banned.
+pub fn is_banned(ip: IpAddr) -> bool {
+    STATE
+        .lock()
+        .as_mut()
+        .is_some_and(|ban_list| ban_list.is_banned(ip.to_canonical(), Instant::now()))
+}
+
+/// Returns the banned IPs with the time left on their bans, sorted by IP.
+pub fn bans() -> Vec<(IpAddr, Duration)> {
+    let now = Instant::now();
+    let mut bans: Vec<(IpAddr, Duration)> = STATE
+        .lock()
+        .as_ref()
+        .map(|ban_list| {
+            ban_list
+                .bans
+                .iter()
+                .filter(|(_, until)| **until > now)
+                .map(|(ip, until)| (*ip, until.duration_since(now)))
+                .collect()
+        })
+        .unwrap_or_default();
+    bans.sort_unstable_by_key(|(ip, _)| *ip);
+    bans
+}
+
+/// Lifts the ban of `ip`, and forgets its failures. Returns false if it wasn't banned.
+pub fn unban(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let mut state = STATE.lock();
+    let Some(ban_list) = state.as_mut() else {
+        return false;
+    };
+    ban_list.failures.pop(&ip);
+    ban_list.is_banned(ip, Instant::now()) && ban_list.bans.pop(&ip).is_some()
+}
+
+/// Lifts all bans, and forgets all failures.
+pub fn clear() {
+    if let Some(ban_list) = STATE.lock().as_mut() {
+        ban_list.failures.clear();
+        ban_list.bans.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn ban_list(failures: u32, now: Instant) -> BanList {
+        BanList::new(
+            BanSettings::from(&AutoBanConfig {
+                auto_ban_failures: failures,
+                auto_ban_window_secs: 60,
+                auto_ban_duration_secs: 600,
+            }),
+            now,
+        )
+    }
+
+    #[test]
+    fn test_ban_after_failures() {
+        let start = Instant::now();
+        let mut ban_list = ban_list(3, start);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+
+        ban_list.record_failure(ip, start);
+        ban_list.record_failure(ip, start + Duration::from_secs(10));
+        ban_list.record_failure(other, start + Duration::from_secs(10));
+        assert!(!ban_list.is_banned(ip, start + Duration::from_secs(10)));
+
+        ban_list.record_failure(ip, start + Duration::from_secs(20));
+        assert!(ban_list.is_banned(ip, start + Duration::from_secs(20)));
+        assert!(!ban_list.is_banned(other, start + Duration::from_secs(20)));
+        assert!(ban_list.is_banned(ip, start + Duration::from_secs(619)));
+        assert!(!ban_list.is_banned(ip, start + Duration::from_secs(620)));
+        assert!(ban_list.bans.is_empty());
+    }
+
+    #[test]
+    fn test_failures_outside_window() {
+        let start = Instant::now();
+        let mut ban_list = ban_list(3, start);
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+
+        for secs in [0, 40, 80, 120] {
+            ban_list.record_failure(ip, start + Duration::from_secs(secs));
+        }
+        assert!(!ban_list.is_banned(ip, start + Duration::from_secs(120)));
+
+        ban_list.record_failure(ip, start + Duration::from_secs(121));
+        assert!(ban_list.is_banned(ip, start + Duration::from_secs(121)));
+    }
+
+    #[test]
+    fn test_lowered_failures() {
+        let start = Instant::now();
+        let mut ban_list = ban_list(5, start);
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for secs in 0..3 {
+            ban_list.record_failure(ip, start + Duration::from_secs(secs));
+        }
+        assert!(!ban_list.is_banned(ip, start + Duration::from_secs(2)));
+
+        // Like configure on a reload.
+        ban_list.settings = BanSettings {
+            failures: 2,
+            ..ban_list.settings
+        };
+        ban_list.record_failure(ip, start + Duration::from_secs(3));
+        assert!(ban_list.is_banned(ip, start + Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_tracked_ips_are_bounded() {
+        let start = Instant::now();
+        let mut ban_list = ban_list(2, start);
+        let first: IpAddr = "10.0.0.0".parse().unwrap();
+
+        ban_list.record_failure(first, start);
+        for i in 1..=MAX_TRACKED_IPS as u32 {
+            ban_list.record_failure(Ipv4Addr::from(0x0a00_0000 + i).into(), start);
+        }
+        assert_eq!(ban_list.failures.len(), MAX_TRACKED_IPS);
+
+        // The failure of the least recently failing IP was forgotten.
+        ban_list.record_failure(first, start);
+        assert!(!ban_list.is_banned(first, start));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let start = Instant::now();
+        let mut ban_list = ban_list(2, start);
+        let banned: IpAddr = "192.0.2.1".parse().unwrap();
+        let failed: IpAddr = "192.0.2.2".parse().unwrap();
+        let recent: IpAddr = "192.0.2.3".parse().unwrap();
+
+        ban_list.record_failure(banned, start);
+        ban_list.record_failure(banned, start);
+        ban_list.record_failure(failed, start);
+        ban_list.record_failure(recent, start + Duration::from_secs(590));
+        assert_eq!(ban_list.bans.len(), 1);
+        assert_eq!(ban_list.failures.len(), 2);
+
+        // Checking any IP sweeps the stale failure and, once it ended, the ban.
+        assert!(ban_list.is_banned(banned, start + Duration::from_secs(600 - 1)));
+        assert_eq!(ban_list.failures.len(), 1);
+        assert!(ban_list.failures.contains(&recent));
+
+        ban_list.is_banned(recent, start + Duration::from_secs(660));
+        assert!(ban_list.bans.is_empty());
+        assert!(ban_list.failures.is_empty());
+    }
+
+    #[test]
+    fn test_rejected() {
+        let error = rejected(std::io::ErrorKind::InvalidData, "bad password");
+        assert!(is_rejected(&error));
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "bad password");
+        assert!(!is_rejected(&std::io::Error::other("bad password")));
+        assert!(!is_rejected(&std::io::ErrorKind::UnexpectedEof.into()));
+    }
+
+    #[test]
+    fn test_validate_config() {
+        let config = |failures, window, duration| AutoBanConfig {
+            auto_ban_failures: failures,
+            auto_ban_window_secs: window,
+            auto_ban_duration_secs: duration,
+        };
+        assert!(validate_config(&config(10, 60, 600)).is_ok());
+        assert!(validate_config(&config(0, 60, 600)).is_err());
+        assert!(validate_config(&config(10, 0, 600)).is_err());
+        assert!(validate_config(&config(10, 60, 0)).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+//! Pool of the buffers used by relay copy loops.
+//!
+//! Every relayed stream or datagram session needs a buffer for each direction, and
+//! allocating and freeing them per connection churns the allocator when there are tens of
+//! thousands of connections. Instead, each worker thread keeps free buffers of each size in
+//! a thread local list, so taking and returning one needs no locks or atomics. A buffer is
+//! returned to the list of the thread that drops it, which is not necessarily the one that
+//! took it, and is freed when that list is full.
+//!
+//! The size of TCP relay buffers and the length of the lists are configured with a
+//! top-level `relay_buffer_size` / `relay_buffer_pool_size` entry.
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::config::BufferPoolConfig;
+use crate::util::allocate_vec;
+
+const DEFAULT_RELAY_BUFFER_SIZE: usize = 16384;
+const DEFAULT_POOL_SIZE: usize = 64;
+const MIN_RELAY_BUFFER_SIZE: usize = 1024;
+
+static RELAY_BUFFER_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_RELAY_BUFFER_SIZE);
+static POOL_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_POOL_SIZE);
+
+thread_local! {
+    static FREE_LISTS: RefCell<Vec<FreeList>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Free buffers of a single size. Only a handful of sizes are used, so the lists of a
+/// thread are searched linearly.
+struct FreeList {
+    size: usize,
+    buffers: Vec<Box<[u8]>>,
+}
+
+/// A buffer taken from the pool, returned to it when dropped.
+///
+/// The contents are not initialized, and may contain data of previous users.
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buf: Box<[u8]>,
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        let buf = std::mem::take(&mut self.buf);
+        let pool_size = POOL_SIZE.load(Ordering::Relaxed);
+        // The list is gone when buffers are dropped while the thread exits, in which case
+        // the buffer is freed.
+        let _ = FREE_LISTS.try_with(|lists| {
+            let mut lists = lists.borrow_mut();
+            let list = match lists.iter().position(|list| list.size == buf.len()) {
+                Some(index) => &mut lists[index],
+                None => {
+                    lists.push(FreeList {
+                        size: buf.len(),
+                        buffers: Vec::new(),
+                    });
+                    lists.last_mut().unwrap()
+                }
+            };
+            // Lists only shrink as buffers are taken, so a smaller pool size takes
+            // effect gradually.
+            if list.buffers.len() < pool_size {
+                list.buffers.push(buf);
+            }
+        });
+    }
+}
+
+/// Takes a buffer of `size` bytes from the current thread's pool, allocating one if the
+/// pool has none.
+pub fn take(size: usize) -> PooledBuffer {
+    let buf = FREE_LISTS
+        .try_with(|lists| {
+            lists
+                .borrow_mut()
+                .iter_mut()
+                .find(|list| list.size == size)
+                .and_then(|list| list.buffers.pop())
+        })
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| allocate_vec(size).into_boxed_slice());
+    PooledBuffer { buf }
+}
+
+/// Returns the configured size of TCP relay buffers.
+pub fn relay_buffer_size() -> usize {
+    RELAY_BUFFER_SIZE.load(Ordering::Relaxed)
+}
+
+/// Sets the size of relay buffers taken from now on, and the length of the free lists.
+pub fn configure(config: Option<BufferPoolConfig>) {
+    let config = config.unwrap_or_default();
+    RELAY_BUFFER_SIZE.store(
+        config
+            .relay_buffer_size
+            .unwrap_or(DEFAULT_RELAY_BUFFER_SIZE),
+        Ordering::Relaxed,
+    );
+    POOL_SIZE.store(
+        config.relay_buffer_pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+        Ordering::Relaxed,
+    );
+}
+
+pub fn validate_config(config: &BufferPoolConfig) -> std::io::Result<()> {
+    if let Some(size) = config.relay_buffer_size
+        && size < MIN_RELAY_BUFFER_SIZE
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "invalid buffer pool config: relay_buffer_size must be at least {MIN_RELAY_BUFFER_SIZE}"
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take_reuses_buffers() {
+        // Each test runs on its own thread, so the pool starts empty.
+        let first = take(1000);
+        assert_eq!(first.len(), 1000);
+        let first_ptr = first.as_ptr();
+        drop(first);
+
+        let second = take(1000);
+        assert_eq!(second.as_ptr(), first_ptr);
+        let other = take(2000);
+        assert_eq!(other.len(), 2000);
+        assert_ne!(other.as_ptr(), first_ptr);
+
+        let buffers: Vec<_> = (0..DEFAULT_POOL_SIZE + 1).map(|_| take(1000)).collect();
+        drop(buffers);
+        let pooled = FREE_LISTS.with(|lists| {
+            lists
+                .borrow()
+                .iter()
+                .find(|list| list.size == 1000)
+                .map_or(0, |list| list.buffers.len())
+        });
+        assert_eq!(pooled, DEFAULT_POOL_SIZE);
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config(&BufferPoolConfig::default()).is_ok());
+        let config = BufferPoolConfig {
+            relay_buffer_size: Some(512),
+            relay_buffer_pool_size: None,
+        };
+        assert!(validate_config(&config).is_err());
+        let config = BufferPoolConfig {
+            relay_buffer_size: Some(65536),
+            relay_buffer_pool_size: Some(0),
+        };
+        assert!(validate_config(&config).is_ok());
+    }
+}
@@ -0,0 +1,561 @@
+//! Picks the chain of a `ClientChainGroup` for new connections.
+//!
+//! Least-connections counts the open connections of each chain with a guard that is
+//! held by the connection's stream, so it is only added when that strategy is used.
+//! Url-test and failover use the health check results that are kept by the group's
+//! selector.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use log::info;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncShutdownMessage,
+    AsyncStream, AsyncWriteMessage,
+};
+use crate::client_proxy_selector::OutboundSelector;
+use crate::config::BalanceStrategy;
+
+#[derive(Debug)]
+pub struct ChainBalancer {
+    strategy: BalanceStrategy,
+    /// Open connections of each chain, only counted for least-connections.
+    active_connections: Vec<Arc<AtomicUsize>>,
+    /// How much faster another chain must be to replace the current url-test chain.
+    tolerance: Duration,
+}
+
+impl ChainBalancer {
+    pub fn new(strategy: BalanceStrategy, num_chains: usize) -> Self {
+        Self {
+            strategy,
+            active_connections: (0..num_chains)
+                .map(|_| Arc::new(AtomicUsize::new(0)))
+                .collect(),
+            tolerance: Duration::ZERO,
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: Duration) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn strategy(&self) -> BalanceStrategy {
+        self.strategy
+    }
+
+    pub fn tolerance(&self) -> Duration {
+        self.tolerance
+    }
+
+    /// Picks one of the chain indices in `candidates` for a connection from `source` to
+    /// `target`. `next_index` is the round-robin position of the candidates, or the current
+    /// chain for url-test and failover.
+    pub fn pick(
+        &self,
+        candidates: &[usize],
+        next_index: &AtomicU32,
+        target: &NetLocation,
+        source: Option<IpAddr>,
+        selector: &OutboundSelector,
+    ) -> usize {
+        match self.strategy {
+            BalanceStrategy::RoundRobin => {
+                let idx = next_index.fetch_add(1, Ordering::Relaxed) as usize;
+                candidates[idx % candidates.len()]
+            }
+            BalanceStrategy::Random => candidates[rand::random_range(0..candidates.len())],
+            BalanceStrategy::LeastConnections => {
+                // Ties are broken in round-robin order.
+                let start = next_index.fetch_add(1, Ordering::Relaxed) as usize;
+                (0..candidates.len())
+                    .map(|offset| candidates[(start + offset) % candidates.len()])
+                    .min_by_key(|&index| self.active_connections[index].load(Ordering::Relaxed))
+                    .unwrap()
+            }
+            BalanceStrategy::ConsistentHash => {
+                rendezvous_hash(candidates, destination_host(target))
+            }
+            BalanceStrategy::SourceHash => match source {
+                Some(source) => rendezvous_hash(candidates, source),
+                // Connections without a known source, e.g. from the DNS resolver, are
+                // spread by destination instead.
+                None => rendezvous_hash(candidates, destination_host(target)),
+            },
+            BalanceStrategy::UrlTest => self.pick_fastest(candidates, next_index, selector),
+            BalanceStrategy::Failover => self.pick_first_healthy(candidates, next_index, selector),
+        }
+    }
+
+    /// Keeps the current chain unless another chain is faster by more than the tolerance,
+    /// or the current chain failed its last check. Until chains were checked, the first
+    /// candidate is used.
+    fn pick_fastest(
+        &self,
+        candidates: &[usize],
+        current_index: &AtomicU32,
+        selector: &OutboundSelector,
+    ) -> usize {
+        let health = selector.health();
+        let (previous, current) = current_candidate(candidates, current_index);
+
+        let Some((fastest, fastest_latency)) = candidates
+            .iter()
+            .filter_map(|&index| health.latency(index).map(|latency| (index, latency)))
+            .min_by_key(|&(_, latency)| latency)
+        else {
+            return current;
+        };
+        if health
+            .latency(current)
+            .is_some_and(|latency| latency <= fastest_latency + self.tolerance)
+        {
+            return current;
+        }
+
+        switch_chain(current_index, previous, current, fastest, selector);
+        fastest
+    }
+
+    /// Uses the first candidate that passed its last check, or was not checked yet.
+    /// When all candidates failed, the first one is used.
+    fn pick_first_healthy(
+        &self,
+        candidates: &[usize],
+        current_index: &AtomicU32,
+        selector: &OutboundSelector,
+    ) -> usize {
+        let (previous, current) = current_candidate(candidates, current_index);
+        let next = candidates
+            .iter()
+            .copied()
+            .find(|&index| selector.health().is_healthy(index))
+            .unwrap_or(candidates[0]);
+        if next != current {
+            switch_chain(current_index, previous, current, next, selector);
+        }
+        next
+    }
+
+    /// Counts a connection on chain `index` until the returned guard is dropped.
+    /// Returns None when connections are not counted.
+    pub fn track(&self, index: usize) -> Option<ActiveConnectionGuard> {
+        if self.strategy != BalanceStrategy::LeastConnections {
+            return None;
+        }
+        let counter = self.active_connections[index].clone();
+        counter.fetch_add(1, Ordering::Relaxed);
+        Some(ActiveConnectionGuard { counter })
+    }
+}
+
+/// Returns the stored current chain, and the current candidate, which is the first one
+/// when the stored chain is not a candidate.
+fn current_candidate(candidates: &[usize], current_index: &AtomicU32) -> (u32, usize) {
+    let previous = current_index.load(Ordering::Relaxed);
+    if candidates.contains(&(previous as usize)) {
+        (previous, previous as usize)
+    } else {
+        (previous, candidates[0])
+    }
+}
+
+/// Stores the new current chain. The change is logged and counted in metrics once, by
+/// the connection that stored it.
+fn switch_chain(
+    current_index: &AtomicU32,
+    previous: u32,
+    current: usize,
+    next: usize,
+    selector: &OutboundSelector,
+) {
+    if current_index
+        .compare_exchange(previous, next as u32, Ordering::Relaxed, Ordering::Relaxed)
+        .is_err()
+    {
+        return;
+    }
+    let chains = selector.chains();
+    info!(
+        "{}: switched from {} to {}",
+        selector.name(),
+        chains[current].name,
+        chains[next].name
+    );
+    if let Some(metrics) = crate::metrics::outbound(selector.name()) {
+        metrics.chain_switches.inc();
+    }
+}
+
+/// Picks the candidate with the highest hash of `key` and its index. When a chain is
+/// removed, only the keys that used it move to other chains.
+fn rendezvous_hash(candidates: &[usize], key: impl Hash) -> usize {
+    *candidates
+        .iter()
+        .max_by_key(|&&index| {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            index.hash(&mut hasher);
+            hasher.finish()
+        })
+        .unwrap()
+}
+
+/// Hashed for consistent hashing. Ports are left out so that all connections to a
+/// site use the same chain.
+fn destination_host(target: &NetLocation) -> String {
+    match target.address() {
+        Address::Hostname(hostname) => hostname.to_lowercase(),
+        address => address.to_string(),
+    }
+}
+
+#[derive(Debug)]
+pub struct ActiveConnectionGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl Drop for ActiveConnectionGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Stream of a counted connection.
+pub struct TrackedStream {
+    inner: Box<dyn AsyncStream>,
+    _guard: ActiveConnectionGuard,
+}
+
+impl TrackedStream {
+    pub fn new(inner: Box<dyn AsyncStream>, guard: ActiveConnectionGuard) -> Self {
+        Self {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl AsyncRead for TrackedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TrackedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for TrackedStream {
+    fn supports_ping(&self) -> bool {
+        self.inner.supports_ping()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_ping(cx)
+    }
+}
+
+impl AsyncStream for TrackedStream {
+    fn as_tcp_stream(&mut self) -> Option<&mut tokio::net::TcpStream> {
+        self.inner.as_tcp_stream()
+    }
+}
+
+/// Message stream of a counted UDP connection.
+pub struct TrackedMessageStream {
+    inner: Box<dyn AsyncMessageStream>,
+    _guard: ActiveConnectionGuard,
+}
+
+impl TrackedMessageStream {
+    pub fn new(inner: Box<dyn AsyncMessageStream>, guard: ActiveConnectionGuard) -> Self {
+        Self {
+            inner,
+            _guard: guard,
+        }
+    }
+}
+
+impl AsyncReadMessage for TrackedMessageStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_read_message(cx, buf)
+    }
+}
+
+impl AsyncWriteMessage for TrackedMessageStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_message(cx, buf)
+    }
+}
+
+impl AsyncFlushMessage for TrackedMessageStream {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush_message(cx)
+    }
+}
+
+impl AsyncShutdownMessage for TrackedMessageStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown_message(cx)
+    }
+}
+
+impl AsyncPing for TrackedMessageStream {
+    fn supports_ping(&self) -> bool {
+        self.inner.supports_ping()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().inner).poll_write_ping(cx)
+    }
+}
+
+impl AsyncMessageStream for TrackedMessageStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_proxy_selector::ChainInfo;
+
+    fn target(hostname: &str) -> NetLocation {
+        NetLocation::new(Address::Hostname(hostname.to_string()), 443)
+    }
+
+    fn selector(num_chains: usize) -> OutboundSelector {
+        OutboundSelector::new(
+            (0..num_chains)
+                .map(|i| ChainInfo {
+                    name: format!("chain-{i}"),
+                    kind: String::from("Direct"),
+                    supports_udp: true,
+                })
+                .collect(),
+        )
+    }
+
+    fn pick(balancer: &ChainBalancer, candidates: &[usize], next_index: &AtomicU32) -> usize {
+        pick_for(balancer, candidates, next_index, &target("example.com"))
+    }
+
+    fn pick_for(
+        balancer: &ChainBalancer,
+        candidates: &[usize],
+        next_index: &AtomicU32,
+        target: &NetLocation,
+    ) -> usize {
+        balancer.pick(candidates, next_index, target, None, &selector(4))
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let balancer = ChainBalancer::new(BalanceStrategy::RoundRobin, 3);
+        let next_index = AtomicU32::new(0);
+        let picks: Vec<usize> = (0..4)
+            .map(|_| pick(&balancer, &[0, 2], &next_index))
+            .collect();
+        assert_eq!(picks, vec![0, 2, 0, 2]);
+        assert!(balancer.track(0).is_none());
+    }
+
+    #[test]
+    fn test_least_connections() {
+        let balancer = ChainBalancer::new(BalanceStrategy::LeastConnections, 3);
+        let next_index = AtomicU32::new(0);
+        let candidates = [0, 1, 2];
+
+        let first = balancer.track(0).unwrap();
+        let _second = balancer.track(1).unwrap();
+        assert_eq!(pick(&balancer, &candidates, &next_index), 2);
+
+        let _third = balancer.track(2).unwrap();
+        drop(first);
+        assert_eq!(pick(&balancer, &candidates, &next_index), 0);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let balancer = ChainBalancer::new(BalanceStrategy::ConsistentHash, 4);
+        let next_index = AtomicU32::new(0);
+        let all = [0, 1, 2, 3];
+
+        // The same host always uses the same chain, regardless of case and port.
+        let index = pick(&balancer, &all, &next_index);
+        for _ in 0..10 {
+            assert_eq!(pick(&balancer, &all, &next_index), index);
+        }
+        let other_port = NetLocation::new(Address::Hostname("EXAMPLE.com".to_string()), 80);
+        assert_eq!(pick_for(&balancer, &all, &next_index, &other_port), index);
+
+        // Removing another chain doesn't move the host.
+        let remaining: Vec<usize> = all
+            .iter()
+            .copied()
+            .filter(|&i| i != (index + 1) % 4)
+            .collect();
+        assert_eq!(pick(&balancer, &remaining, &next_index), index);
+    }
+
+    #[test]
+    fn test_source_hash() {
+        let balancer = ChainBalancer::new(BalanceStrategy::SourceHash, 4);
+        let next_index = AtomicU32::new(0);
+        let selector = selector(4);
+        let all = [0, 1, 2, 3];
+        let source = Some(IpAddr::from([192, 168, 1, 20]));
+
+        // The same client uses the same chain for all destinations.
+        let index = balancer.pick(&all, &next_index, &target("a.com"), source, &selector);
+        for host in ["b.com", "c.com", "d.com"] {
+            assert_eq!(
+                balancer.pick(&all, &next_index, &target(host), source, &selector),
+                index
+            );
+        }
+
+        // Without a source, destinations are hashed like consistent hashing.
+        let consistent_hash = ChainBalancer::new(BalanceStrategy::ConsistentHash, 4);
+        assert_eq!(
+            balancer.pick(&all, &next_index, &target("a.com"), None, &selector),
+            consistent_hash.pick(&all, &next_index, &target("a.com"), None, &selector)
+        );
+    }
+
+    #[test]
+    fn test_random_picks_candidates() {
+        let balancer = ChainBalancer::new(BalanceStrategy::Random, 3);
+        let next_index = AtomicU32::new(0);
+        for _ in 0..20 {
+            let index = pick(&balancer, &[1, 2], &next_index);
+            assert!(index == 1 || index == 2);
+        }
+    }
+
+    #[test]
+    fn test_url_test() {
+        let balancer = ChainBalancer::new(BalanceStrategy::UrlTest, 3)
+            .with_tolerance(Duration::from_millis(50));
+        let current_index = AtomicU32::new(0);
+        let selector = selector(3);
+        let health = selector.health();
+        let example = target("example.com");
+
+        // Unchecked chains use the first candidate.
+        assert_eq!(
+            balancer.pick(&[1, 2], &current_index, &example, None, &selector),
+            1
+        );
+
+        health.record(0, Some(Duration::from_millis(200)));
+        health.record(1, Some(Duration::from_millis(100)));
+        health.record(2, Some(Duration::from_millis(120)));
+        assert_eq!(
+            balancer.pick(&[0, 1, 2], &current_index, &example, None, &selector),
+            1
+        );
+
+        // Within the tolerance, the current chain is kept.
+        health.record(2, Some(Duration::from_millis(60)));
+        assert_eq!(
+            balancer.pick(&[0, 1, 2], &current_index, &example, None, &selector),
+            1
+        );
+
+        // A failed check replaces the chain.
+        health.record(1, None);
+        assert_eq!(
+            balancer.pick(&[0, 1, 2], &current_index, &example, None, &selector),
+            2
+        );
+    }
+
+    #[test]
+    fn test_failover() {
+        let balancer = ChainBalancer::new(BalanceStrategy::Failover, 3);
+        let current_index = AtomicU32::new(0);
+        let selector = selector(3);
+        let health = selector.health();
+        let example = target("example.com");
+        let all = [0, 1, 2];
+
+        // Unchecked chains count as healthy.
+        assert_eq!(
+            balancer.pick(&all, &current_index, &example, None, &selector),
+            0
+        );
+
+        health.record(0, None);
+        health.record(1, Some(Duration::from_millis(300)));
+        assert_eq!(
+            balancer.pick(&all, &current_index, &example, None, &selector),
+            1
+        );
+        assert_eq!(current_index.load(Ordering::Relaxed), 1);
+
+        // Fails back once the first chain passes again.
+        health.record(0, Some(Duration::from_millis(500)));
+        assert_eq!(
+            balancer.pick(&all, &current_index, &example, None, &selector),
+            0
+        );
+
+        // Uses the first chain when all checks fail.
+        health.record(0, None);
+        health.record(1, None);
+        health.record(2, None);
+        assert_eq!(
+            balancer.pick(&all, &current_index, &example, None, &selector),
+            0
+        );
+    }
+}
@@ -0,0 +1,325 @@
+//! Health checks of the chains of a `ClientChainGroup`.
+//!
+//! A check times a HTTP request through a chain until the response status line. The
+//! results are kept with the group's `OutboundSelector`, so they are shared by groups
+//! with the same chains and are listed through the Clash API.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::address::{Address, NetLocation, ResolvedLocation};
+use crate::async_stream::AsyncStream;
+use crate::client_proxy_chain::{ClientProxyChain, DialTimeouts};
+use crate::client_proxy_selector::OutboundSelector;
+use crate::config::HealthCheckConfig;
+use crate::crypto::{CryptoConnection, CryptoTlsStream, perform_crypto_handshake};
+use crate::resolver::Resolver;
+use crate::rustls_config_util::create_client_config;
+
+/// Number of results kept for each chain.
+const HISTORY_LEN: usize = 10;
+
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyRecord {
+    pub time: SystemTime,
+    /// None when the check failed.
+    pub latency: Option<Duration>,
+}
+
+#[derive(Debug)]
+pub struct ChainHealth {
+    history: Vec<Mutex<VecDeque<LatencyRecord>>>,
+}
+
+impl ChainHealth {
+    pub fn new(num_chains: usize) -> Self {
+        Self {
+            history: (0..num_chains)
+                .map(|_| Mutex::new(VecDeque::new()))
+                .collect(),
+        }
+    }
+
+    pub fn record(&self, index: usize, latency: Option<Duration>) {
+        let mut history = self.history[index].lock();
+        if history.len() == HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(LatencyRecord {
+            time: SystemTime::now(),
+            latency,
+        });
+    }
+
+    /// Returns the results of chain `index`, oldest first.
+    pub fn history(&self, index: usize) -> Vec<LatencyRecord> {
+        self.history[index].lock().iter().copied().collect()
+    }
+
+    /// Returns the latency of the last check, or None if the chain was not checked yet
+    /// or the check failed.
+    pub fn latency(&self, index: usize) -> Option<Duration> {
+        self.history[index]
+            .lock()
+            .back()
+            .and_then(|record| record.latency)
+    }
+
+    /// Returns true if the chain passed its last check, or was not checked yet.
+    pub fn is_healthy(&self, index: usize) -> bool {
+        self.history[index]
+            .lock()
+            .back()
+            .is_none_or(|record| record.latency.is_some())
+    }
+}
+
+/// Where health checks send their request, parsed from a http:// or https:// URL.
+#[derive(Debug, Clone)]
+pub struct HealthCheckTarget {
+    location: NetLocation,
+    /// Set for https URLs.
+    server_name: Option<rustls::pki_types::ServerName<'static>>,
+    host: String,
+    path: String,
+}
+
+impl HealthCheckTarget {
+    pub fn from_url(url: &str) -> std::io::Result<Self> {
+        let invalid = |message: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid health check url {url}: {message}"),
+            )
+        };
+        let parsed = url::Url::parse(url).map_err(|e| invalid(&e.to_string()))?;
+        let tls = match parsed.scheme() {
+            "http" => false,
+            "https" => true,
+            _ => return Err(invalid("expected a http or https URL")),
+        };
+        let (address, server_name) = match parsed.host() {
+            Some(url::Host::Domain(domain)) => (Address::Hostname(domain.to_string()), domain),
+            Some(url::Host::Ipv4(ip)) => (Address::Ipv4(ip), parsed.host_str().unwrap()),
+            Some(url::Host::Ipv6(ip)) => (
+                Address::Ipv6(ip),
+                parsed.host_str().unwrap().trim_matches(['[', ']']),
+            ),
+            None => return Err(invalid("missing host")),
+        };
+        let server_name = if tls {
+            Some(
+                rustls::pki_types::ServerName::try_from(server_name.to_string())
+                    .map_err(|e| invalid(&e.to_string()))?,
+            )
+        } else {
+            None
+        };
+        let port = parsed.port_or_known_default().unwrap();
+        let host = match parsed.port() {
+            Some(port) => format!("{}:{port}", parsed.host_str().unwrap()),
+            None => parsed.host_str().unwrap().to_string(),
+        };
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{query}", parsed.path()),
+            None => parsed.path().to_string(),
+        };
+        Ok(Self {
+            location: NetLocation::new(address, port),
+            server_name,
+            host,
+            path,
+        })
+    }
+
+    fn request(&self) -> String {
+        format!(
+            "HEAD {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: shoes\r\nConnection: close\r\n\r\n",
+            self.path, self.host
+        )
+    }
+}
+
+struct HealthChecker {
+    target: HealthCheckTarget,
+    client_config: Option<Arc<rustls::ClientConfig>>,
+    timeout: Duration,
+}
+
+impl HealthChecker {
+    fn new(target: HealthCheckTarget, timeout: Duration) -> Self {
+        let client_config = target.server_name.as_ref().map(|_| {
+            Arc::new(create_client_config(
+                true,
+                vec![],
+                vec![String::from("http/1.1")],
+                true,
+                None,
+                false,
+                None,
+                None,
+                None,
+            ))
+        });
+        Self {
+            target,
+            client_config,
+            timeout,
+        }
+    }
+
+    async fn check(
+        &self,
+        chain: &ClientProxyChain,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        tokio::time::timeout(self.timeout, self.request(chain, resolver))
+            .await
+            .map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "health check timed out")
+            })??;
+        Ok(start.elapsed())
+    }
+
+    async fn request(
+        &self,
+        chain: &ClientProxyChain,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<()> {
+        let result = chain
+            .connect_tcp(
+                ResolvedLocation::new(self.target.location.clone()),
+                resolver,
+                DialTimeouts::default(),
+            )
+            .await?;
+        let mut stream = result.client_stream;
+
+        if let (Some(client_config), Some(server_name)) =
+            (&self.client_config, &self.target.server_name)
+        {
+            let client_conn =
+                rustls::ClientConnection::new(client_config.clone(), server_name.clone())
+                    .map_err(std::io::Error::other)?;
+            let mut connection = CryptoConnection::new_rustls_client(client_conn);
+            perform_crypto_handshake(&mut connection, &mut stream, 16384).await?;
+            stream = Box::new(CryptoTlsStream::new(stream, connection)) as Box<dyn AsyncStream>;
+        }
+
+        stream.write_all(self.target.request().as_bytes()).await?;
+        stream.flush().await?;
+
+        let mut response = result.early_data.unwrap_or_default();
+        let mut buf = [0u8; 64];
+        while response.len() < 5 {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed before the response",
+                ));
+            }
+            response.extend_from_slice(&buf[..n]);
+        }
+        if !response.starts_with(b"HTTP/") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "invalid HTTP response",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Checks the chains every interval, until the group that owns them is dropped.
+pub fn spawn_health_checks(
+    chains: Weak<Vec<ClientProxyChain>>,
+    selector: Arc<OutboundSelector>,
+    config: &HealthCheckConfig,
+    resolver: Arc<dyn Resolver>,
+) {
+    // Validated when loading config
+    let target = HealthCheckTarget::from_url(&config.url).expect("Invalid health check url");
+    let checker = HealthChecker::new(target, Duration::from_secs(config.timeout_secs));
+    let interval = Duration::from_secs(config.interval_secs);
+
+    tokio::spawn(async move {
+        loop {
+            let Some(chains) = chains.upgrade() else {
+                break;
+            };
+            let results = futures::future::join_all(
+                chains.iter().map(|chain| checker.check(chain, &resolver)),
+            )
+            .await;
+            drop(chains);
+
+            for (index, result) in results.into_iter().enumerate() {
+                let name = &selector.chains()[index].name;
+                match result {
+                    Ok(latency) => {
+                        debug!("Health check of {name}: {}ms", latency.as_millis());
+                        selector.health().record(index, Some(latency));
+                    }
+                    Err(e) => {
+                        debug!("Health check of {name} failed: {e}");
+                        selector.health().record(index, None);
+                    }
+                }
+            }
+
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_check_target() {
+        let target = HealthCheckTarget::from_url("https://www.gstatic.com/generate_204").unwrap();
+        assert_eq!(target.location.to_string(), "www.gstatic.com:443");
+        assert!(target.server_name.is_some());
+        assert_eq!(
+            target.request(),
+            "HEAD /generate_204 HTTP/1.1\r\nHost: www.gstatic.com\r\nUser-Agent: shoes\r\nConnection: close\r\n\r\n"
+        );
+
+        let target = HealthCheckTarget::from_url("http://[::1]:8080/test?a=b").unwrap();
+        assert_eq!(target.location.port(), 8080);
+        assert!(target.server_name.is_none());
+        assert_eq!(target.host, "[::1]:8080");
+        assert_eq!(target.path, "/test?a=b");
+
+        assert!(HealthCheckTarget::from_url("ftp://example.com/").is_err());
+        assert!(HealthCheckTarget::from_url("not a url").is_err());
+    }
+
+    #[test]
+    fn test_chain_health_history() {
+        let health = ChainHealth::new(2);
+        assert_eq!(health.latency(0), None);
+
+        health.record(0, Some(Duration::from_millis(100)));
+        assert_eq!(health.latency(0), Some(Duration::from_millis(100)));
+        health.record(0, None);
+        assert_eq!(health.latency(0), None);
+        assert!(!health.is_healthy(0));
+        assert!(health.is_healthy(1));
+
+        for i in 0..HISTORY_LEN as u64 + 5 {
+            health.record(1, Some(Duration::from_millis(i)));
+        }
+        let history = health.history(1);
+        assert_eq!(history.len(), HISTORY_LEN);
+        assert_eq!(history[0].latency, Some(Duration::from_millis(5)));
+    }
+}
@@ -0,0 +1,236 @@
+//! Tracks forwarded connections so they can be listed and closed through the API.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::SystemTime;
+
+use parking_lot::RwLock;
+use tokio::sync::Notify;
+
+use crate::address::NetLocation;
+use crate::metrics::{ByteCounter, Counter};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static TRACKER: LazyLock<ConnectionTracker> = LazyLock::new(ConnectionTracker::default);
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn tracker() -> &'static ConnectionTracker {
+    &TRACKER
+}
+
+/// Starts tracking a connection, or returns None if the Clash API is disabled.
+///
+/// The connection is tracked until the returned guard is dropped.
+pub fn track_connection(
+    network: &'static str,
+    inbound: &str,
+    source: Option<SocketAddr>,
+    destination: &NetLocation,
+    chain: &str,
+) -> Option<ConnectionGuard> {
+    if !is_enabled() {
+        return None;
+    }
+    Some(TRACKER.track(network, inbound, source, destination, chain))
+}
+
+#[derive(Debug)]
+pub struct TrackedConnection {
+    pub id: u64,
+    /// Either `tcp` or `udp`.
+    pub network: &'static str,
+    /// Identifies the listener the connection was accepted on, e.g. `tcp://0.0.0.0:443`.
+    pub inbound: String,
+    pub source: Option<SocketAddr>,
+    pub destination: NetLocation,
+    /// Name of the chain group the connection was forwarded through.
+    pub chain: String,
+    pub start: SystemTime,
+    /// Bytes sent from the client towards the destination.
+    pub upload: Arc<Counter>,
+    /// Bytes sent from the destination back to the client.
+    pub download: Arc<Counter>,
+    closed: Notify,
+}
+
+impl TrackedConnection {
+    /// Asks the task forwarding the connection to stop.
+    pub fn close(&self) {
+        self.closed.notify_one();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ConnectionTracker {
+    next_id: AtomicU64,
+    connections: RwLock<BTreeMap<u64, Arc<TrackedConnection>>>,
+    upload_total: Arc<Counter>,
+    download_total: Arc<Counter>,
+}
+
+impl ConnectionTracker {
+    fn track(
+        &'static self,
+        network: &'static str,
+        inbound: &str,
+        source: Option<SocketAddr>,
+        destination: &NetLocation,
+        chain: &str,
+    ) -> ConnectionGuard {
+        let connection = Arc::new(TrackedConnection {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed) + 1,
+            network,
+            inbound: inbound.to_string(),
+            source,
+            destination: destination.clone(),
+            chain: chain.to_string(),
+            start: SystemTime::now(),
+            upload: Arc::new(Counter::default()),
+            download: Arc::new(Counter::default()),
+            closed: Notify::new(),
+        });
+        self.connections
+            .write()
+            .insert(connection.id, connection.clone());
+        ConnectionGuard {
+            tracker: self,
+            connection,
+        }
+    }
+
+    /// Returns the active connections, oldest first.
+    pub fn connections(&self) -> Vec<Arc<TrackedConnection>> {
+        self.connections.read().values().cloned().collect()
+    }
+
+    /// Closes the connection with the given id, returning false if there is none.
+    pub fn close(&self, id: u64) -> bool {
+        match self.connections.read().get(&id) {
+            Some(connection) => {
+                connection.close();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn close_all(&self) {
+        for connection in self.connections.read().values() {
+            connection.close();
+        }
+    }
+
+    /// Total bytes uploaded by all connections, including closed ones.
+    pub fn upload_total(&self) -> u64 {
+        self.upload_total.get()
+    }
+
+    /// Total bytes downloaded by all connections, including closed ones.
+    pub fn download_total(&self) -> u64 {
+        self.download_total.get()
+    }
+}
+
+/// Removes a connection from its tracker when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    tracker: &'static ConnectionTracker,
+    connection: Arc<TrackedConnection>,
+}
+
+impl ConnectionGuard {
+    /// Adds the connection's counters to the (up, down) byte counters of a session.
+    pub fn add_byte_counters(&self, up: &mut ByteCounter, down: &mut ByteCounter) {
+        up.push(self.connection.upload.clone());
+        up.push(self.tracker.upload_total.clone());
+        down.push(self.connection.download.clone());
+        down.push(self.tracker.download_total.clone());
+    }
+
+    /// Runs `future` until it completes, or until the connection is closed through the API.
+    pub async fn run<F>(&self, future: F) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        tokio::select! {
+            result = future => result,
+            _ = self.connection.closed.notified() => Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionAborted,
+                "connection closed through the Clash API",
+            )),
+        }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.tracker.connections.write().remove(&self.connection.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_tracker() -> &'static ConnectionTracker {
+        Box::leak(Box::new(ConnectionTracker::default()))
+    }
+
+    fn destination() -> NetLocation {
+        NetLocation::from_str("example.com:443", None).unwrap()
+    }
+
+    #[test]
+    fn test_track_connection() {
+        let tracker = new_tracker();
+        let guard = tracker.track(
+            "tcp",
+            "tcp://127.0.0.1:1080",
+            None,
+            &destination(),
+            "direct",
+        );
+
+        let (mut up, mut down) = (ByteCounter::default(), ByteCounter::default());
+        guard.add_byte_counters(&mut up, &mut down);
+        up.add(10);
+        down.add(20);
+
+        let connections = tracker.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].id, 1);
+        assert_eq!(connections[0].upload.get(), 10);
+        assert_eq!(connections[0].download.get(), 20);
+
+        drop(guard);
+        assert!(tracker.connections().is_empty());
+        assert_eq!(tracker.upload_total(), 10);
+        assert_eq!(tracker.download_total(), 20);
+    }
+
+    #[tokio::test]
+    async fn test_close_connection() {
+        let tracker = new_tracker();
+        let guard = tracker.track("udp", "quic://[::]:443", None, &destination(), "direct");
+        let id = tracker.connections()[0].id;
+
+        assert!(!tracker.close(id + 1));
+        assert!(tracker.close(id));
+
+        let result = guard.run(std::future::pending()).await;
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::ConnectionAborted
+        );
+    }
+}
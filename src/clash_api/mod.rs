@@ -0,0 +1,13 @@
+//! Clash-compatible REST API.
+//!
+//! Serves the subset of the Clash external-controller API that dashboards such as yacd
+//! use to manage an instance: listing proxies and switching the chain used by a chain
+//! group, listing rules, listing and closing connections, and streaming traffic.
+//!
+//! Connections are only tracked while a Clash API listener is configured.
+
+mod connection_tracker;
+mod server;
+
+pub use connection_tracker::{ConnectionGuard, set_enabled, track_connection};
+pub use server::{format_rfc3339, start_clash_api_server};
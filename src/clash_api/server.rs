@@ -0,0 +1,667 @@
+//! HTTP handlers for the Clash external-controller endpoints.
+
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode, header};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyExt, Empty, Full, StreamBody};
+use hyper::body::{Frame, Incoming};
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use subtle::ConstantTimeEq;
+use tokio::task::JoinHandle;
+
+use super::connection_tracker::{TrackedConnection, set_enabled, tracker};
+use crate::address::{Address, NetLocationMask};
+use crate::auto_ban;
+use crate::chain_health::LatencyRecord;
+use crate::client_proxy_selector::{
+    ChainInfo, ConnectAction, OutboundSelector, outbound_selectors, proxy_selectors,
+};
+use crate::config::{ClashApiConfig, RateLimitConfig, UserConfig};
+use crate::user_store::{get_user_store, user_stores};
+
+type Body = UnsyncBoxBody<Bytes, Infallible>;
+
+/// Request body of `PUT /proxies/{name}`.
+#[derive(Debug, Deserialize)]
+struct SelectProxyRequest {
+    /// Name of the chain to use, or an empty string to use all chains round-robin.
+    name: String,
+}
+
+/// Request body of `PUT /users/{group}/{name}`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PutUserRequest {
+    password: Option<String>,
+    #[serde(alias = "uuid")]
+    user_id: Option<String>,
+    rate_limit: Option<RateLimitConfig>,
+}
+
+fn empty_body() -> Body {
+    Empty::<Bytes>::new().boxed_unsync()
+}
+
+fn json_response(status: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(value.to_string())).boxed_unsync())
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> Response<Body> {
+    json_response(status, &json!({ "message": message }))
+}
+
+fn no_content() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(empty_body())
+        .unwrap()
+}
+
+fn not_found() -> Response<Body> {
+    error_response(StatusCode::NOT_FOUND, "Resource not found")
+}
+
+/// Returns the token passed as `Authorization: Bearer <token>` or as a `token` query
+/// parameter, which browsers use for streaming requests.
+fn request_token<B>(req: &Request<B>) -> Option<Cow<'_, str>> {
+    if let Some(token) = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+    {
+        return Some(Cow::Borrowed(token));
+    }
+
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .and_then(|token| percent_decode_str(token).decode_utf8().ok())
+}
+
+fn is_authorized<B>(req: &Request<B>, secret: Option<&str>) -> bool {
+    let Some(secret) = secret else {
+        return true;
+    };
+    request_token(req).is_some_and(|token| token.as_bytes().ct_eq(secret.as_bytes()).into())
+}
+
+/// Formats a time as RFC 3339 in UTC, e.g. `2024-01-02T03:04:05.000Z`.
+pub fn format_rfc3339(time: SystemTime) -> String {
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = duration.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs_of_day = secs % 86400;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+        duration.subsec_millis()
+    )
+}
+
+/// Converts days since the Unix epoch to a (year, month, day) date, using Howard
+/// Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Health check results, where failed checks have a delay of 0.
+fn history_json(history: &[LatencyRecord]) -> Value {
+    history
+        .iter()
+        .map(|record| {
+            json!({
+                "time": format_rfc3339(record.time),
+                "delay": record.latency.map_or(0, |latency| latency.as_millis() as u64),
+            })
+        })
+        .collect()
+}
+
+fn chain_json(chain: &ChainInfo, history: &[LatencyRecord]) -> Value {
+    json!({
+        "name": chain.name,
+        "type": chain.kind,
+        "udp": chain.supports_udp,
+        "history": history_json(history),
+    })
+}
+
+/// Chain groups with more than one chain are shown as selector groups, whose `now` is
+/// empty while chains are used round-robin.
+fn group_json(selector: &OutboundSelector) -> Value {
+    let now = selector
+        .selected()
+        .map(|index| selector.chains()[index].name.as_str())
+        .unwrap_or_default();
+    let all: Vec<&str> = selector
+        .chains()
+        .iter()
+        .map(|chain| chain.name.as_str())
+        .collect();
+    json!({
+        "name": selector.name(),
+        "type": "Selector",
+        "udp": selector.chains().iter().any(|chain| chain.supports_udp),
+        "now": now,
+        "all": all,
+        "history": [],
+    })
+}
+
+fn proxies() -> Map<String, Value> {
+    let mut proxies = Map::new();
+    proxies.insert(
+        String::from("REJECT"),
+        json!({ "name": "REJECT", "type": "Reject", "udp": true, "history": [] }),
+    );
+    for selector in outbound_selectors() {
+        for (index, chain) in selector.chains().iter().enumerate() {
+            let history = selector.health().history(index);
+            proxies.insert(chain.name.clone(), chain_json(chain, &history));
+        }
+        if selector.chains().len() > 1 {
+            proxies.insert(selector.name().to_string(), group_json(&selector));
+        }
+    }
+    proxies
+}
+
+fn select_proxy(name: &str, body: &[u8]) -> Response<Body> {
+    let request: SelectProxyRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("Body invalid: {e}")),
+    };
+
+    let Some(selector) = outbound_selectors()
+        .into_iter()
+        .find(|selector| selector.name() == name && selector.chains().len() > 1)
+    else {
+        return not_found();
+    };
+
+    let chain_name = Some(request.name.as_str()).filter(|name| !name.is_empty());
+    match selector.select(chain_name) {
+        Ok(()) => no_content(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+fn users() -> Value {
+    let groups: Map<String, Value> = user_stores()
+        .into_iter()
+        .map(|(group, store)| {
+            let names: Vec<&str> = store
+                .users()
+                .iter()
+                .map(|user| user.name.as_str())
+                .collect();
+            (group, json!(names))
+        })
+        .collect();
+    json!({ "users": groups })
+}
+
+fn put_user(group: &str, name: &str, body: &[u8]) -> Response<Body> {
+    let request: PutUserRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return error_response(StatusCode::BAD_REQUEST, &format!("Body invalid: {e}")),
+    };
+    let Some(store) = get_user_store(group) else {
+        return not_found();
+    };
+    let user = UserConfig {
+        name: name.to_string(),
+        password: request.password,
+        user_id: request.user_id,
+        rate_limit: request.rate_limit,
+    };
+    match store.add(user) {
+        Ok(()) => no_content(),
+        Err(e) => error_response(StatusCode::BAD_REQUEST, &e.to_string()),
+    }
+}
+
+fn bans() -> Value {
+    let now = SystemTime::now();
+    let bans: Vec<Value> = auto_ban::bans()
+        .into_iter()
+        .map(|(ip, remaining)| json!({ "ip": ip, "expires": format_rfc3339(now + remaining) }))
+        .collect();
+    json!({ "bans": bans })
+}
+
+fn rule_type(mask: &NetLocationMask) -> &'static str {
+    match mask.address_mask.address {
+        Address::Hostname(_) => "DomainSuffix",
+        _ if mask.address_mask.netmask == 0 => "Match",
+        _ => "IPCIDR",
+    }
+}
+
+fn rules() -> Vec<Value> {
+    let mut rules = vec![];
+    for selector in proxy_selectors() {
+        for rule in selector.rules() {
+            let proxy = match &rule.action {
+                ConnectAction::Allow { chain_group, .. } => chain_group.label(),
+                ConnectAction::Block => "REJECT",
+            };
+            for mask in rule.masks.iter() {
+                rules.push(json!({
+                    "type": rule_type(mask),
+                    "payload": mask.to_string(),
+                    "proxy": proxy,
+                }));
+            }
+        }
+    }
+    rules
+}
+
+fn connection_json(connection: &TrackedConnection) -> Value {
+    let (source_ip, source_port) = match connection.source {
+        Some(source) => (source.ip().to_string(), source.port().to_string()),
+        None => (String::new(), String::new()),
+    };
+    let (destination_ip, host) = match connection.destination.address() {
+        Address::Hostname(hostname) => (String::new(), hostname.clone()),
+        address => (address.to_string(), String::new()),
+    };
+    json!({
+        "id": connection.id.to_string(),
+        "metadata": {
+            "network": connection.network,
+            "type": connection.inbound,
+            "sourceIP": source_ip,
+            "sourcePort": source_port,
+            "destinationIP": destination_ip,
+            "destinationPort": connection.destination.port().to_string(),
+            "host": host,
+        },
+        "upload": connection.upload.get(),
+        "download": connection.download.get(),
+        "start": format_rfc3339(connection.start),
+        "chains": [connection.chain],
+        "rule": "",
+        "rulePayload": "",
+    })
+}
+
+fn connections() -> Value {
+    let tracker = tracker();
+    let connections: Vec<Value> = tracker
+        .connections()
+        .iter()
+        .map(|connection| connection_json(connection))
+        .collect();
+    json!({
+        "downloadTotal": tracker.download_total(),
+        "uploadTotal": tracker.upload_total(),
+        "connections": connections,
+    })
+}
+
+/// Streams the bytes transferred during each second as `{"up":..,"down":..}` lines.
+fn traffic() -> Response<Body> {
+    let tracker = tracker();
+    let initial = (
+        tracker.upload_total(),
+        tracker.download_total(),
+        tokio::time::interval(Duration::from_secs(1)),
+    );
+    let stream = futures::stream::unfold(initial, move |(up, down, mut interval)| async move {
+        interval.tick().await;
+        let (next_up, next_down) = (tracker.upload_total(), tracker.download_total());
+        let line = format!(
+            "{{\"up\":{},\"down\":{}}}\n",
+            next_up.saturating_sub(up),
+            next_down.saturating_sub(down)
+        );
+        Some((
+            Ok::<_, Infallible>(Frame::data(Bytes::from(line))),
+            (next_up, next_down, interval),
+        ))
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(StreamBody::new(stream).boxed_unsync())
+        .unwrap()
+}
+
+fn route(method: &Method, path: &str, body: &[u8]) -> Response<Body> {
+    let segments: Vec<Cow<'_, str>> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| percent_decode_str(segment).decode_utf8_lossy())
+        .collect();
+    let segments: Vec<&str> = segments.iter().map(|segment| segment.as_ref()).collect();
+
+    match (method, segments.as_slice()) {
+        (&Method::GET, []) => json_response(StatusCode::OK, &json!({ "hello": "shoes" })),
+        (&Method::GET, ["version"]) => json_response(
+            StatusCode::OK,
+            &json!({ "version": env!("CARGO_PKG_VERSION"), "premium": false }),
+        ),
+        (&Method::GET, ["configs"]) => json_response(
+            StatusCode::OK,
+            &json!({ "port": 0, "socks-port": 0, "mode": "rule", "log-level": "info" }),
+        ),
+        (&Method::GET, ["proxies"]) => {
+            json_response(StatusCode::OK, &json!({ "proxies": proxies() }))
+        }
+        (&Method::GET, ["proxies", name]) => match proxies().get(*name) {
+            Some(proxy) => json_response(StatusCode::OK, proxy),
+            None => not_found(),
+        },
+        (&Method::PUT, ["proxies", name]) => select_proxy(name, body),
+        (&Method::GET, ["rules"]) => json_response(StatusCode::OK, &json!({ "rules": rules() })),
+        (&Method::GET, ["connections"]) => json_response(StatusCode::OK, &connections()),
+        (&Method::DELETE, ["connections"]) => {
+            tracker().close_all();
+            no_content()
+        }
+        (&Method::DELETE, ["connections", id]) => match id.parse() {
+            Ok(id) if tracker().close(id) => no_content(),
+            _ => not_found(),
+        },
+        (&Method::GET, ["traffic"]) => traffic(),
+        (&Method::GET, ["users"]) => json_response(StatusCode::OK, &users()),
+        (&Method::PUT, ["users", group, name]) => put_user(group, name, body),
+        (&Method::DELETE, ["users", group, name]) => match get_user_store(group) {
+            Some(store) if store.remove(name) => no_content(),
+            _ => not_found(),
+        },
+        (&Method::GET, ["bans"]) => json_response(StatusCode::OK, &bans()),
+        (&Method::DELETE, ["bans"]) => {
+            auto_ban::clear();
+            no_content()
+        }
+        (&Method::DELETE, ["bans", ip]) => match ip.parse() {
+            Ok(ip) if auto_ban::unban(ip) => no_content(),
+            _ => not_found(),
+        },
+        _ => not_found(),
+    }
+}
+
+async fn handle_request(
+    req: Request<Incoming>,
+    secret: Option<Arc<str>>,
+) -> Result<Response<Body>, Infallible> {
+    let mut response = if req.method() == Method::OPTIONS {
+        let mut response = no_content();
+        let headers = response.headers_mut();
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            "GET, PUT, DELETE, OPTIONS".parse().unwrap(),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            "Authorization, Content-Type".parse().unwrap(),
+        );
+        response
+    } else if !is_authorized(&req, secret.as_deref()) {
+        error_response(StatusCode::UNAUTHORIZED, "Unauthorized")
+    } else {
+        let (parts, body) = req.into_parts();
+        match body.collect().await {
+            Ok(body) => route(&parts.method, parts.uri.path(), &body.to_bytes()),
+            Err(e) => error_response(StatusCode::BAD_REQUEST, &format!("Body invalid: {e}")),
+        }
+    };
+
+    response
+        .headers_mut()
+        .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, "*".parse().unwrap());
+    Ok(response)
+}
+
+/// Starts the HTTP listener serving the Clash API, and enables connection tracking.
+pub async fn start_clash_api_server(config: ClashApiConfig) -> std::io::Result<JoinHandle<()>> {
+    println!("Starting Clash API server at http://{}", config.clash_api);
+
+    let listener = tokio::net::TcpListener::bind(config.clash_api).await?;
+    set_enabled(true);
+
+    let secret: Option<Arc<str>> = config.secret.map(Arc::from);
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Clash API accept failed: {e}");
+                    continue;
+                }
+            };
+
+            let secret = secret.clone();
+            tokio::spawn(async move {
+                let service =
+                    hyper::service::service_fn(move |req| handle_request(req, secret.clone()));
+                if let Err(e) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("Clash API connection from {addr} ended with error: {e}");
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client_proxy_selector::get_outbound_selector;
+
+    async fn body_json(response: Response<Body>) -> Value {
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    fn chain_info(name: &str) -> ChainInfo {
+        ChainInfo {
+            name: name.to_string(),
+            kind: String::from("SOCKS5"),
+            supports_udp: true,
+        }
+    }
+
+    #[test]
+    fn test_format_rfc3339() {
+        assert_eq!(format_rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_millis(1704164645123)),
+            "2024-01-02T03:04:05.123Z"
+        );
+        assert_eq!(
+            format_rfc3339(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+    }
+
+    #[test]
+    fn test_is_authorized() {
+        let request = |uri: &str, authorization: Option<&str>| {
+            let mut builder = Request::builder().uri(uri);
+            if let Some(authorization) = authorization {
+                builder = builder.header(header::AUTHORIZATION, authorization);
+            }
+            builder.body(()).unwrap()
+        };
+
+        assert!(is_authorized(&request("/proxies", None), None));
+        assert!(!is_authorized(&request("/proxies", None), Some("secret")));
+        assert!(is_authorized(
+            &request("/proxies", Some("Bearer secret")),
+            Some("secret")
+        ));
+        assert!(!is_authorized(
+            &request("/proxies", Some("Bearer wrong")),
+            Some("secret")
+        ));
+        assert!(is_authorized(
+            &request("/traffic?token=my%20secret", None),
+            Some("my secret")
+        ));
+    }
+
+    #[test]
+    fn test_rule_type() {
+        let rule_type = |mask: &str| rule_type(&NetLocationMask::from(mask).unwrap());
+        assert_eq!(rule_type("0.0.0.0/0"), "Match");
+        assert_eq!(rule_type("192.168.0.0/16"), "IPCIDR");
+        assert_eq!(rule_type("example.com"), "DomainSuffix");
+    }
+
+    #[tokio::test]
+    async fn test_select_proxy() {
+        let selector = get_outbound_selector(vec![
+            chain_info("clash-api-test-a"),
+            chain_info("clash-api-test-b"),
+        ]);
+        let path = "/proxies/clash-api-test-a%20%7C%20clash-api-test-b";
+
+        let response = route(&Method::GET, path, b"");
+        assert_eq!(response.status(), StatusCode::OK);
+        let group = body_json(response).await;
+        assert_eq!(group["type"], "Selector");
+        assert_eq!(group["now"], "");
+
+        let response = route(&Method::PUT, path, br#"{"name":"clash-api-test-b"}"#);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(selector.selected(), Some(1));
+
+        let response = route(&Method::PUT, path, br#"{"name":"unknown"}"#);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = route(&Method::PUT, path, br#"{"name":""}"#);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(selector.selected(), None);
+
+        let response = route(&Method::PUT, "/proxies/unknown", br#"{"name":""}"#);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_proxy_history() {
+        let selector = get_outbound_selector(vec![
+            chain_info("clash-api-history-a"),
+            chain_info("clash-api-history-b"),
+        ]);
+        selector.health().record(0, Some(Duration::from_millis(120)));
+        selector.health().record(0, None);
+
+        let response = route(&Method::GET, "/proxies/clash-api-history-a", b"");
+        let proxy = body_json(response).await;
+        assert_eq!(proxy["history"][0]["delay"], 120);
+        assert_eq!(proxy["history"][1]["delay"], 0);
+
+        let response = route(&Method::GET, "/proxies/clash-api-history-b", b"");
+        let proxy = body_json(response).await;
+        assert_eq!(proxy["history"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_users() {
+        crate::user_store::configure(vec![crate::config::UserGroupConfig {
+            user_group: String::from("clash-api-users"),
+            users: vec![],
+        }]);
+        let path = "/users/clash-api-users/alice";
+
+        let response = route(&Method::PUT, path, br#"{"password":"secret"}"#);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = route(&Method::GET, "/users", b"");
+        assert_eq!(
+            body_json(response).await["users"]["clash-api-users"],
+            json!(["alice"])
+        );
+
+        let response = route(&Method::PUT, path, br#"{"user_id":"not a uuid"}"#);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = route(&Method::PUT, "/users/unknown/alice", br#"{"password":"a"}"#);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let response = route(&Method::DELETE, path, b"");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let response = route(&Method::DELETE, path, b"");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_bans() {
+        crate::auto_ban::configure(Some(crate::config::AutoBanConfig {
+            auto_ban_failures: 2,
+            auto_ban_window_secs: 60,
+            auto_ban_duration_secs: 600,
+        }));
+        let ip: std::net::IpAddr = "203.0.113.9".parse().unwrap();
+        auto_ban::record_failure(ip);
+        auto_ban::record_failure(ip);
+        assert!(auto_ban::is_banned(ip));
+
+        let response = route(&Method::GET, "/bans", b"");
+        assert_eq!(response.status(), StatusCode::OK);
+        let bans = body_json(response).await;
+        assert!(
+            bans["bans"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|ban| ban["ip"] == "203.0.113.9")
+        );
+
+        let response = route(&Method::DELETE, "/bans/203.0.113.9", b"");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!auto_ban::is_banned(ip));
+        let response = route(&Method::DELETE, "/bans/203.0.113.9", b"");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = route(&Method::DELETE, "/bans/not-an-ip", b"");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        auto_ban::record_failure(ip);
+        auto_ban::record_failure(ip);
+        let response = route(&Method::DELETE, "/bans", b"");
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(!auto_ban::is_banned(ip));
+        crate::auto_ban::configure(None);
+    }
+
+    #[tokio::test]
+    async fn test_route_not_found() {
+        let response = route(&Method::GET, "/unknown", b"");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(body_json(response).await["message"], "Resource not found");
+
+        let response = route(&Method::DELETE, "/connections/not-a-number", b"");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
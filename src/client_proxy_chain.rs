@@ -19,18 +19,99 @@
 //! - `initial_hop`: Pool of `InitialHopEntry` (Direct or Proxy) for hop 0
 //! - `subsequent_hops`: Protocol connectors for hops 1+ (no socket creation)
 
+use std::net::IpAddr;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
 use log::debug;
 
-use crate::address::ResolvedLocation;
+use crate::address::{NetLocation, ResolvedLocation};
+use crate::anytls::anytls_session_location;
 use crate::async_stream::AsyncMessageStream;
+use crate::chain_balancer::{
+    ActiveConnectionGuard, ChainBalancer, TrackedMessageStream, TrackedStream,
+};
+use crate::chain_health::spawn_health_checks;
+use crate::client_proxy_selector::{ChainInfo, OutboundSelector};
+use crate::config::{BalanceStrategy, DialConfig, HealthCheckConfig};
+use crate::metrics::OutboundMetrics;
+use crate::mux::mux_cool_location;
 use crate::resolver::Resolver;
+use crate::socket_util::DialOptions;
 use crate::tcp::proxy_connector::ProxyConnector;
 use crate::tcp::socket_connector::SocketConnector;
 use crate::tcp::tcp_handler::TcpClientSetupResult;
 
+/// Limits on the steps of connecting through a chain. No limit waits as long as the
+/// operating system or the protocol does.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DialTimeouts {
+    /// Connecting the socket of the first hop.
+    pub connect: Option<Duration>,
+    /// The protocol handshake of each proxy hop.
+    pub handshake: Option<Duration>,
+}
+
+impl DialTimeouts {
+    async fn connect<T>(
+        &self,
+        future: impl Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        with_timeout(self.connect, "connect", future).await
+    }
+
+    async fn handshake<T>(
+        &self,
+        future: impl Future<Output = std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        with_timeout(self.handshake, "proxy handshake", future).await
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    step: &str,
+    future: impl Future<Output = std::io::Result<T>>,
+) -> std::io::Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("{step} timed out after {timeout:?}"),
+            )
+        })?,
+        None => future.await,
+    }
+}
+
+/// How a group connects through its chains.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DialPolicy {
+    pub timeouts: DialTimeouts,
+    /// Times a failed connection is retried on the same chain.
+    pub retries: u32,
+    /// Wait before the first retry, doubled for each further retry.
+    pub retry_backoff: Duration,
+    /// Whether the other chains are tried, in order, once the retries of a chain are
+    /// exhausted.
+    pub fallback: bool,
+}
+
+impl From<&DialConfig> for DialPolicy {
+    fn from(config: &DialConfig) -> Self {
+        Self {
+            timeouts: DialTimeouts {
+                connect: config.connect_timeout_secs.map(Duration::from_secs),
+                handshake: config.handshake_timeout_secs.map(Duration::from_secs),
+            },
+            retries: config.retries,
+            retry_backoff: Duration::from_millis(config.retry_backoff_ms),
+            fallback: config.fallback,
+        }
+    }
+}
+
 /// Entry in the initial hop (hop 0) pool.
 ///
 /// Each entry pairs socket creation with optional protocol setup,
@@ -188,7 +269,7 @@ impl ClientProxyChain {
 
     /// Returns true if this chain is "direct-only": all initial hops are Direct
     /// and there are no subsequent hops. Such chains can be used for UDP/QUIC
-    /// DNS while still supporting bind_interface.
+    /// DNS while still supporting bind_interface, fwmark and DSCP.
     pub fn is_direct_only(&self) -> bool {
         if !self.subsequent_hops.is_empty() {
             return false;
@@ -198,19 +279,39 @@ impl ClientProxyChain {
             .all(|entry| matches!(entry, InitialHopEntry::Direct(_)))
     }
 
-    /// Returns the bind_interface from a direct-only chain.
-    /// Returns None if not direct-only or if no bind_interface is configured.
-    pub fn get_bind_interface(&self) -> Option<&str> {
+    /// Returns the socket options from a direct-only chain.
+    /// Returns None if not direct-only.
+    pub fn get_dial_options(&self) -> Option<DialOptions> {
         if !self.is_direct_only() {
             return None;
         }
-        // All entries should have the same bind_interface, return from the first.
+        // All entries should have the same socket options, return from the first.
         self.initial_hop.first().and_then(|entry| match entry {
-            InitialHopEntry::Direct(socket) => socket.bind_interface(),
+            InitialHopEntry::Direct(socket) => Some(socket.dial_options()),
             InitialHopEntry::Proxy { .. } => None,
         })
     }
 
+    /// Sends an ICMP echo request to `ip` and waits for the reply. Only direct-only
+    /// chains can carry ICMP, others return an `Unsupported` error.
+    pub async fn ping(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        ip: IpAddr,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        if !self.is_direct_only() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Chain does not support ICMP",
+            ));
+        }
+        match self.select_initial_hop_entry() {
+            InitialHopEntry::Direct(socket) => socket.ping(resolver, ip, payload).await,
+            InitialHopEntry::Proxy { .. } => unreachable!("direct-only chain has a proxy"),
+        }
+    }
+
     /// Select an initial hop entry (round-robin).
     fn select_initial_hop_entry(&self) -> &InitialHopEntry {
         if self.initial_hop.len() == 1 {
@@ -243,6 +344,7 @@ impl ClientProxyChain {
         &self,
         remote_location: ResolvedLocation,
         resolver: &Arc<dyn Resolver>,
+        timeouts: DialTimeouts,
     ) -> std::io::Result<TcpClientSetupResult> {
         // Select initial hop entry (socket + optional proxy paired)
         let entry = self.select_initial_hop_entry();
@@ -250,6 +352,81 @@ impl ClientProxyChain {
         // Select proxy connectors for subsequent hops
         let subsequent_proxies = self.select_subsequent_proxies();
 
+        // With mux, the last proxy carries the connection in a session shared with other
+        // connections, and the chain is only connected when a new session is needed.
+        let final_proxy = match (subsequent_proxies.last(), entry) {
+            (Some(proxy), _) => Some(*proxy),
+            (None, InitialHopEntry::Proxy { proxy, .. }) => Some(proxy.as_ref()),
+            (None, InitialHopEntry::Direct(_)) => None,
+        };
+        // Sessions open streams without a protocol setup, so the target is prepared here.
+        let remote_location = match final_proxy {
+            Some(proxy) if proxy.mux_client().is_some() || proxy.anytls_pool().is_some() => {
+                proxy.prepare_target(&remote_location).await?
+            }
+            _ => remote_location,
+        };
+        if let Some(mux_client) = final_proxy.and_then(|proxy| proxy.mux_client()) {
+            let connect_session = async {
+                Self::connect_tcp_through(
+                    entry,
+                    &subsequent_proxies,
+                    mux_cool_location().into(),
+                    resolver,
+                    timeouts,
+                )
+                .await
+                .map(|result| result.client_stream)
+            };
+            let client_stream = mux_client
+                .open_stream(remote_location.location(), connect_session)
+                .await?;
+            return Ok(TcpClientSetupResult {
+                client_stream,
+                early_data: None,
+            });
+        }
+
+        // Likewise, AnyTLS connections reuse idle sessions of the last proxy.
+        if let Some(anytls_pool) = final_proxy.and_then(|proxy| proxy.anytls_pool()) {
+            let connect_session = async {
+                Self::connect_tcp_through(
+                    entry,
+                    &subsequent_proxies,
+                    anytls_session_location().into(),
+                    resolver,
+                    timeouts,
+                )
+                .await
+                .map(|result| result.client_stream)
+            };
+            let client_stream = anytls_pool
+                .open_stream(remote_location.location(), connect_session)
+                .await?;
+            return Ok(TcpClientSetupResult {
+                client_stream,
+                early_data: None,
+            });
+        }
+
+        Self::connect_tcp_through(
+            entry,
+            &subsequent_proxies,
+            remote_location,
+            resolver,
+            timeouts,
+        )
+        .await
+    }
+
+    /// Connect through the selected hops to the remote location.
+    async fn connect_tcp_through(
+        entry: &InitialHopEntry,
+        subsequent_proxies: &[&dyn ProxyConnector],
+        remote_location: ResolvedLocation,
+        resolver: &Arc<dyn Resolver>,
+        timeouts: DialTimeouts,
+    ) -> std::io::Result<TcpClientSetupResult> {
         debug!(
             "Chain TCP connect: 1 initial + {} subsequent hop(s) -> {}",
             subsequent_proxies.len(),
@@ -267,7 +444,9 @@ impl ClientProxyChain {
             InitialHopEntry::Direct(socket) => {
                 // Socket connects to first subsequent proxy (or final target)
                 debug!("Initial hop: Direct -> {}", first_subsequent_target.location());
-                let stream = socket.connect(resolver, &first_subsequent_target).await?;
+                let stream = timeouts
+                    .connect(socket.connect(resolver, &first_subsequent_target))
+                    .await?;
                 TcpClientSetupResult {
                     client_stream: stream,
                     early_data: None,
@@ -281,10 +460,12 @@ impl ClientProxyChain {
                     first_subsequent_target.location()
                 );
                 let proxy_loc = proxy.proxy_location().into();
-                let stream = socket.connect(resolver, &proxy_loc).await?;
+                let stream = timeouts
+                    .connect(socket.connect(resolver, &proxy_loc))
+                    .await?;
                 // Protocol setup targeting first subsequent proxy (or final target)
-                proxy
-                    .setup_tcp_stream(stream, &first_subsequent_target)
+                timeouts
+                    .handshake(proxy.setup_tcp_stream(stream, &first_subsequent_target))
                     .await?
             }
         };
@@ -304,7 +485,9 @@ impl ClientProxyChain {
                 target.location()
             );
 
-            result = proxy.setup_tcp_stream(result.client_stream, &target).await?;
+            result = timeouts
+                .handshake(proxy.setup_tcp_stream(result.client_stream, &target))
+                .await?;
 
             // Early data from intermediate hops is unexpected
             if let Some(data) = &result.early_data
@@ -337,6 +520,7 @@ impl ClientProxyChain {
         &self,
         resolver: &Arc<dyn Resolver>,
         target: ResolvedLocation,
+        timeouts: DialTimeouts,
     ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
         // Check if UDP is supported
         if self.udp_final_hop_indices.is_empty() {
@@ -363,7 +547,9 @@ impl ClientProxyChain {
             match entry {
                 InitialHopEntry::Direct(socket) => {
                     debug!("Chain UDP: Direct connection (native UDP)");
-                    socket.connect_udp_bidirectional(resolver, target).await
+                    timeouts
+                        .connect(socket.connect_udp_bidirectional(resolver, target))
+                        .await
                 }
                 InitialHopEntry::Proxy { socket, proxy } => {
                     debug!(
@@ -371,8 +557,12 @@ impl ClientProxyChain {
                         proxy.proxy_location()
                     );
                     let proxy_loc = proxy.proxy_location().into();
-                    let stream = socket.connect(resolver, &proxy_loc).await?;
-                    proxy.setup_udp_bidirectional(stream, target).await
+                    let stream = timeouts
+                        .connect(socket.connect(resolver, &proxy_loc))
+                        .await?;
+                    timeouts
+                        .handshake(proxy.setup_udp_bidirectional(stream, target))
+                        .await
                 }
             }
         } else {
@@ -424,7 +614,9 @@ impl ClientProxyChain {
                     };
 
                     debug!("Chain UDP: Direct -> {} (TCP)", first_target.location());
-                    let mut stream = socket.connect(resolver, &first_target).await?;
+                    let mut stream = timeouts
+                        .connect(socket.connect(resolver, &first_target))
+                        .await?;
 
                     // Process intermediate hops (all TCP)
                     for (i, proxy) in intermediate_proxies.iter().enumerate() {
@@ -439,7 +631,9 @@ impl ClientProxyChain {
                             proxy.proxy_location(),
                             next_target.location()
                         );
-                        let result = proxy.setup_tcp_stream(stream, &next_target).await?;
+                        let result = timeouts
+                            .handshake(proxy.setup_tcp_stream(stream, &next_target))
+                            .await?;
                         stream = result.client_stream;
                     }
 
@@ -448,7 +642,9 @@ impl ClientProxyChain {
                         "Chain UDP final hop: {} (UDP)",
                         final_proxy.proxy_location()
                     );
-                    final_proxy.setup_udp_bidirectional(stream, target).await
+                    timeouts
+                        .handshake(final_proxy.setup_udp_bidirectional(stream, target))
+                        .await
                 }
                 InitialHopEntry::Proxy { socket, proxy } => {
                     // Determine first target after initial hop
@@ -464,8 +660,12 @@ impl ClientProxyChain {
                         first_target.location()
                     );
                     let proxy_loc = proxy.proxy_location().into();
-                    let stream = socket.connect(resolver, &proxy_loc).await?;
-                    let result = proxy.setup_tcp_stream(stream, &first_target).await?;
+                    let stream = timeouts
+                        .connect(socket.connect(resolver, &proxy_loc))
+                        .await?;
+                    let result = timeouts
+                        .handshake(proxy.setup_tcp_stream(stream, &first_target))
+                        .await?;
                     let mut stream = result.client_stream;
 
                     // Process intermediate hops (all TCP)
@@ -481,7 +681,9 @@ impl ClientProxyChain {
                             proxy.proxy_location(),
                             next_target.location()
                         );
-                        let result = proxy.setup_tcp_stream(stream, &next_target).await?;
+                        let result = timeouts
+                            .handshake(proxy.setup_tcp_stream(stream, &next_target))
+                            .await?;
                         stream = result.client_stream;
                     }
 
@@ -490,19 +692,27 @@ impl ClientProxyChain {
                         "Chain UDP final hop: {} (UDP)",
                         final_proxy.proxy_location()
                     );
-                    final_proxy.setup_udp_bidirectional(stream, target).await
+                    timeouts
+                        .handshake(final_proxy.setup_udp_bidirectional(stream, target))
+                        .await
                 }
             }
         }
     }
 }
 
-/// A group of proxy chains for round-robin selection.
+/// A group of proxy chains, of which one is picked for each connection.
 pub struct ClientChainGroup {
-    chains: Vec<ClientProxyChain>,
+    /// Shared with the health checks, which stop when the group is dropped.
+    chains: Arc<Vec<ClientProxyChain>>,
+    tcp_chain_indices: Vec<usize>,
     next_tcp_index: AtomicU32,
     pub(crate) udp_chain_indices: Vec<usize>,
     next_udp_index: AtomicU32,
+    /// Names the group and its chains, and pins a chain when one is selected at runtime.
+    selector: Arc<OutboundSelector>,
+    balancer: ChainBalancer,
+    dial_policy: DialPolicy,
 }
 
 impl std::fmt::Debug for ClientChainGroup {
@@ -510,6 +720,9 @@ impl std::fmt::Debug for ClientChainGroup {
         f.debug_struct("ClientChainGroup")
             .field("chains_count", &self.chains.len())
             .field("udp_chain_indices", &self.udp_chain_indices)
+            .field("selector", &self.selector.name())
+            .field("balancer", &self.balancer.strategy())
+            .field("dial_policy", &self.dial_policy)
             .finish()
     }
 }
@@ -528,28 +741,143 @@ impl ClientChainGroup {
             .map(|(i, _)| i)
             .collect();
 
+        let selector = Arc::new(OutboundSelector::new(
+            chains
+                .iter()
+                .enumerate()
+                .map(|(i, chain)| ChainInfo {
+                    name: format!("chain-{i}"),
+                    kind: String::from("Unknown"),
+                    supports_udp: chain.supports_udp(),
+                })
+                .collect(),
+        ));
+
+        let balancer = ChainBalancer::new(BalanceStrategy::default(), chains.len());
+
         Self {
-            chains,
+            tcp_chain_indices: (0..chains.len()).collect(),
+            chains: Arc::new(chains),
             next_tcp_index: AtomicU32::new(0),
             udp_chain_indices,
             next_udp_index: AtomicU32::new(0),
+            selector,
+            balancer,
+            dial_policy: DialPolicy::default(),
         }
     }
 
+    /// Sets how a chain is picked when none is selected at runtime.
+    pub fn with_balancer(mut self, strategy: BalanceStrategy) -> Self {
+        self.balancer = ChainBalancer::new(strategy, self.chains.len())
+            .with_tolerance(self.balancer.tolerance());
+        self
+    }
+
+    /// Starts checking the chains periodically, which must be done in a tokio runtime.
+    /// The results are recorded with the selector, so this is called after
+    /// `with_selector`.
+    pub fn with_health_check(
+        mut self,
+        config: &HealthCheckConfig,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
+        self.balancer = ChainBalancer::new(self.balancer.strategy(), self.chains.len())
+            .with_tolerance(Duration::from_millis(config.tolerance_ms));
+        spawn_health_checks(
+            Arc::downgrade(&self.chains),
+            self.selector.clone(),
+            config,
+            resolver,
+        );
+        self
+    }
+
+    /// Sets the timeouts, retries and fallback used when connecting through the chains.
+    pub fn with_dial_policy(mut self, dial_policy: DialPolicy) -> Self {
+        self.dial_policy = dial_policy;
+        self
+    }
+
+    /// Replaces the selector, which must describe the same chains as the group.
+    pub fn with_selector(mut self, selector: Arc<OutboundSelector>) -> Self {
+        assert_eq!(
+            selector.chains().len(),
+            self.chains.len(),
+            "selector must have one entry per chain"
+        );
+        self.selector = selector;
+        self
+    }
+
+    pub fn selector(&self) -> &Arc<OutboundSelector> {
+        &self.selector
+    }
+
+    /// Identifies the group in metrics, e.g. `socks5@1.2.3.4:1080 | direct`.
+    pub fn label(&self) -> &str {
+        self.selector.name()
+    }
+
+    /// Returns the metrics for this group, or None if metrics are disabled.
+    pub fn outbound_metrics(&self) -> Option<Arc<OutboundMetrics>> {
+        crate::metrics::outbound(self.label())
+    }
+
     pub async fn connect_tcp(
         &self,
         remote_location: ResolvedLocation,
         resolver: &Arc<dyn Resolver>,
     ) -> std::io::Result<TcpClientSetupResult> {
-        let idx = self.next_tcp_index.fetch_add(1, Ordering::Relaxed) as usize;
-        let chain = &self.chains[idx % self.chains.len()];
-        chain.connect_tcp(remote_location, resolver).await
+        self.connect_tcp_with_source(remote_location, None, resolver)
+            .await
+    }
+
+    /// Connects for a client at `source`, which the `source_hash` balancer picks the chain
+    /// by.
+    pub async fn connect_tcp_with_source(
+        &self,
+        remote_location: ResolvedLocation,
+        source: Option<IpAddr>,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        let chain_idx = match self.selector.selected() {
+            Some(index) => index,
+            None => self.balancer.pick(
+                &self.tcp_chain_indices,
+                &self.next_tcp_index,
+                remote_location.location(),
+                source,
+                &self.selector,
+            ),
+        };
+        let (mut result, guard) = self
+            .dial(chain_idx, &self.tcp_chain_indices, |chain, timeouts| {
+                chain.connect_tcp(remote_location.clone(), resolver, timeouts)
+            })
+            .await?;
+        if let Some(guard) = guard {
+            result.client_stream = Box::new(TrackedStream::new(result.client_stream, guard));
+        }
+        Ok(result)
     }
 
     pub async fn connect_udp_bidirectional(
         &self,
         resolver: &Arc<dyn Resolver>,
         target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        self.connect_udp_bidirectional_with_source(resolver, target, None)
+            .await
+    }
+
+    /// Connects for a client at `source`, which the `source_hash` balancer picks the chain
+    /// by.
+    pub async fn connect_udp_bidirectional_with_source(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        target: ResolvedLocation,
+        source: Option<IpAddr>,
     ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
         if self.udp_chain_indices.is_empty() {
             return Err(std::io::Error::new(
@@ -558,10 +886,102 @@ impl ClientChainGroup {
             ));
         }
 
-        let idx = self.next_udp_index.fetch_add(1, Ordering::Relaxed) as usize;
-        let chain_idx = self.udp_chain_indices[idx % self.udp_chain_indices.len()];
-        let chain = &self.chains[chain_idx];
-        chain.connect_udp_bidirectional(resolver, target).await
+        // A selected chain without UDP support falls back to the other chains.
+        let chain_idx = match self.selector.selected() {
+            Some(index) if self.chains[index].supports_udp() => index,
+            _ => self.balancer.pick(
+                &self.udp_chain_indices,
+                &self.next_udp_index,
+                target.location(),
+                source,
+                &self.selector,
+            ),
+        };
+        let (stream, guard) = self
+            .dial(chain_idx, &self.udp_chain_indices, |chain, timeouts| {
+                chain.connect_udp_bidirectional(resolver, target.clone(), timeouts)
+            })
+            .await?;
+        Ok(match guard {
+            Some(guard) => Box::new(TrackedMessageStream::new(stream, guard)),
+            None => stream,
+        })
+    }
+
+    /// Pings `ip` for a client at `source` through the chain that a TCP connection to it
+    /// would use. Chains that can't carry ICMP return an `Unsupported` error.
+    pub async fn ping(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        ip: IpAddr,
+        payload: &[u8],
+        source: Option<IpAddr>,
+    ) -> std::io::Result<()> {
+        let chain_idx = match self.selector.selected() {
+            Some(index) => index,
+            None => self.balancer.pick(
+                &self.tcp_chain_indices,
+                &self.next_tcp_index,
+                &NetLocation::from_ip_addr(ip, 0),
+                source,
+                &self.selector,
+            ),
+        };
+        self.chains[chain_idx].ping(resolver, ip, payload).await
+    }
+
+    /// Connects through chain `chain_idx` with `connect`, retrying and then falling back
+    /// to the chains after it in `candidates` as the dial policy allows. The connection
+    /// is returned with the guard counting it on the chain that was used.
+    async fn dial<'a, T, F, Fut>(
+        &'a self,
+        chain_idx: usize,
+        candidates: &[usize],
+        mut connect: F,
+    ) -> std::io::Result<(T, Option<ActiveConnectionGuard>)>
+    where
+        F: FnMut(&'a ClientProxyChain, DialTimeouts) -> Fut,
+        Fut: Future<Output = std::io::Result<T>>,
+    {
+        let policy = self.dial_policy;
+        let mut chain_order = vec![chain_idx];
+        if policy.fallback {
+            let start = candidates
+                .iter()
+                .position(|&index| index == chain_idx)
+                .map_or(0, |position| position + 1);
+            chain_order.extend(
+                candidates[start..]
+                    .iter()
+                    .chain(&candidates[..start])
+                    .filter(|&&index| index != chain_idx),
+            );
+        }
+
+        let mut last_error = None;
+        for index in chain_order {
+            let mut backoff = policy.retry_backoff;
+            for attempt in 0..=policy.retries {
+                if attempt > 0 {
+                    tokio::time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                let guard = self.balancer.track(index);
+                match connect(&self.chains[index], policy.timeouts).await {
+                    Ok(connection) => return Ok((connection, guard)),
+                    Err(e) => {
+                        debug!(
+                            "Chain {} failed to connect (attempt {}/{}): {e}",
+                            self.selector.chains()[index].name,
+                            attempt + 1,
+                            policy.retries + 1
+                        );
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(last_error.unwrap())
     }
 
     #[cfg(test)]
@@ -574,14 +994,14 @@ impl ClientChainGroup {
         self.chains.iter().all(|chain| chain.is_direct_only())
     }
 
-    /// Returns the bind_interface if all chains are direct-only and share
-    /// the same bind_interface (or all have None).
-    pub fn get_bind_interface(&self) -> Option<&str> {
+    /// Returns the socket options if all chains are direct-only and share
+    /// the same socket options.
+    pub fn get_dial_options(&self) -> Option<DialOptions> {
         if !self.is_direct_only() {
             return None;
         }
-        // Return bind_interface from first chain (all should be the same in a group).
-        self.chains.first().and_then(|chain| chain.get_bind_interface())
+        // Return socket options from first chain (all should be the same in a group).
+        self.chains.first().and_then(|chain| chain.get_dial_options())
     }
 }
 
@@ -867,6 +1287,114 @@ mod tests {
         assert!(group.udp_chain_indices.is_empty());
     }
 
+    /// Mock SocketConnector that counts connects, which fail or never complete.
+    #[derive(Debug)]
+    struct CountingSocketConnector {
+        attempts: Arc<std::sync::atomic::AtomicUsize>,
+        hang: bool,
+    }
+
+    #[async_trait]
+    impl SocketConnector for CountingSocketConnector {
+        async fn connect(
+            &self,
+            _resolver: &Arc<dyn Resolver>,
+            _address: &ResolvedLocation,
+        ) -> std::io::Result<Box<dyn AsyncStream>> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            if self.hang {
+                std::future::pending::<()>().await;
+            }
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        }
+
+        async fn connect_udp_bidirectional(
+            &self,
+            _resolver: &Arc<dyn Resolver>,
+            _target: ResolvedLocation,
+        ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+            self.attempts.fetch_add(1, Ordering::Relaxed);
+            Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused))
+        }
+
+        fn bind_interface(&self) -> Option<&str> {
+            None
+        }
+    }
+
+    fn counting_chain(hang: bool) -> (ClientProxyChain, Arc<std::sync::atomic::AtomicUsize>) {
+        let attempts = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let socket = CountingSocketConnector {
+            attempts: attempts.clone(),
+            hang,
+        };
+        let chain = ClientProxyChain::new(vec![InitialHopEntry::Direct(Box::new(socket))], vec![]);
+        (chain, attempts)
+    }
+
+    fn test_resolver() -> Arc<dyn Resolver> {
+        Arc::new(crate::resolver::NativeResolver::new())
+    }
+
+    fn test_location() -> ResolvedLocation {
+        NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 80).into()
+    }
+
+    #[tokio::test]
+    async fn test_chain_connect_timeout() {
+        let (chain, attempts) = counting_chain(true);
+        let timeouts = DialTimeouts {
+            connect: Some(Duration::from_millis(10)),
+            handshake: None,
+        };
+        let error = chain
+            .connect_tcp(test_location(), &test_resolver(), timeouts)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::TimedOut);
+        assert_eq!(attempts.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_group_dial_retries() {
+        let (chain1, attempts1) = counting_chain(false);
+        let (chain2, attempts2) = counting_chain(false);
+        let group = ClientChainGroup::new(vec![chain1, chain2]).with_dial_policy(DialPolicy {
+            retries: 2,
+            retry_backoff: Duration::from_millis(1),
+            ..Default::default()
+        });
+        let error = group
+            .connect_tcp(test_location(), &test_resolver())
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::ConnectionRefused);
+        assert_eq!(attempts1.load(Ordering::Relaxed), 3);
+        assert_eq!(attempts2.load(Ordering::Relaxed), 0);
+    }
+
+    #[tokio::test]
+    async fn test_group_dial_fallback() {
+        let (chain1, attempts1) = counting_chain(false);
+        let (chain2, attempts2) = counting_chain(false);
+        let group = ClientChainGroup::new(vec![chain1, chain2]).with_dial_policy(DialPolicy {
+            retries: 1,
+            retry_backoff: Duration::from_millis(1),
+            fallback: true,
+            ..Default::default()
+        });
+        assert!(
+            group
+                .connect_udp_bidirectional(&test_resolver(), test_location())
+                .await
+                .is_err()
+        );
+        assert_eq!(attempts1.load(Ordering::Relaxed), 2);
+        assert_eq!(attempts2.load(Ordering::Relaxed), 2);
+    }
+
     #[test]
     fn test_pool_pairing_fix_socket_proxy_always_paired() {
         // Create a mixed pool simulating: vmess@1080, vless@1081, direct
@@ -1,14 +1,20 @@
 use log::{debug, error};
 use lru::LruCache;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashMap;
 use std::hash::Hash;
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::NonZeroUsize;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock, Weak};
 
 use crate::address::{Address, NetLocation, ResolvedLocation};
-use crate::address::{AddressMask, NetLocationMask};
+use crate::address::{AddressMask, NetLocationMask, PortRange};
+use crate::chain_health::ChainHealth;
 use crate::client_proxy_chain::ClientChainGroup;
+use crate::fake_ip;
+use crate::geoip::{self, GeoIpCode};
+use crate::geosite;
 use crate::resolver::{resolve_location, Resolver};
 
 /// Cache key for routing decisions.
@@ -110,15 +116,123 @@ impl RoutingCache {
     }
 }
 
+/// A routing rule. A connection matches when its destination matches one of `masks`,
+/// and it also satisfies each of the other matchers that are not empty.
 #[derive(Debug)]
 pub struct ConnectRule {
     pub masks: Vec<NetLocationMask>,
+    /// Hostname destinations containing one of these keywords, in lowercase.
+    pub domain_keywords: Vec<String>,
+    /// Destination ports.
+    pub ports: Vec<PortRange>,
+    /// Source IP/CIDR masks. These never match when the source is unknown.
+    pub source_ips: Vec<AddressMask>,
+    /// GeoIP codes of the destination IP.
+    pub geoip: Vec<GeoIpCode>,
+    /// Geosite categories of hostname destinations, in lowercase.
+    pub geosite: Vec<String>,
+    /// Whether QUIC flows that match are blocked.
+    pub block_quic: bool,
     pub action: ConnectAction,
 }
 
 impl ConnectRule {
     pub fn new(masks: Vec<NetLocationMask>, action: ConnectAction) -> Self {
-        Self { masks, action }
+        Self {
+            masks,
+            domain_keywords: vec![],
+            ports: vec![],
+            source_ips: vec![],
+            geoip: vec![],
+            geosite: vec![],
+            block_quic: false,
+            action,
+        }
+    }
+
+    pub fn with_domain_keywords(mut self, domain_keywords: Vec<String>) -> Self {
+        self.domain_keywords = domain_keywords;
+        self
+    }
+
+    pub fn with_ports(mut self, ports: Vec<PortRange>) -> Self {
+        self.ports = ports;
+        self
+    }
+
+    pub fn with_source_ips(mut self, source_ips: Vec<AddressMask>) -> Self {
+        self.source_ips = source_ips;
+        self
+    }
+
+    pub fn with_geoip(mut self, geoip: Vec<GeoIpCode>) -> Self {
+        self.geoip = geoip;
+        self
+    }
+
+    pub fn with_geosite(mut self, geosite: Vec<String>) -> Self {
+        self.geosite = geosite;
+        self
+    }
+
+    pub fn with_block_quic(mut self, block_quic: bool) -> Self {
+        self.block_quic = block_quic;
+        self
+    }
+
+    /// Returns whether the connection satisfies the matchers besides `masks`.
+    #[inline]
+    fn matches_conditions(&self, location: &NetLocation, source_ip: Option<u128>) -> bool {
+        if !self.ports.is_empty()
+            && !self
+                .ports
+                .iter()
+                .any(|range| range.contains(location.port()))
+        {
+            return false;
+        }
+
+        if !self.domain_keywords.is_empty() {
+            let Some(hostname) = location.address().hostname() else {
+                return false;
+            };
+            if !self
+                .domain_keywords
+                .iter()
+                .any(|keyword| contains_ignore_ascii_case(hostname, keyword))
+            {
+                return false;
+            }
+        }
+
+        if !self.source_ips.is_empty() {
+            let Some(source_ip) = source_ip else {
+                return false;
+            };
+            if !self
+                .source_ips
+                .iter()
+                .any(|mask| matches_ip_mask(mask, source_ip))
+            {
+                return false;
+            }
+        }
+
+        if !self.geosite.is_empty() {
+            let Some(hostname) = location.address().hostname() else {
+                return false;
+            };
+            match geosite::matches(hostname, &self.geosite) {
+                Ok(true) => {}
+                Ok(false) => return false,
+                Err(e) => {
+                    error!("Non-fatal error while trying to match geosite for {hostname}: {e}");
+                    return false;
+                }
+            }
+        }
+
+        true
     }
 }
 
@@ -126,6 +240,8 @@ impl ConnectRule {
 pub enum ConnectAction {
     Allow {
         override_address: Option<NetLocation>,
+        /// Replaces the destination port, or the port of `override_address`.
+        override_port: Option<u16>,
         /// The chain group for this rule - multiple chains for round-robin selection.
         chain_group: ClientChainGroup,
     },
@@ -136,10 +252,19 @@ impl ConnectAction {
     pub fn new_allow(override_address: Option<NetLocation>, chain_group: ClientChainGroup) -> Self {
         ConnectAction::Allow {
             override_address,
+            override_port: None,
             chain_group,
         }
     }
 
+    /// Rewrites the destination port of allowed connections. Has no effect on blocks.
+    pub fn with_override_port(mut self, port: Option<u16>) -> Self {
+        if let ConnectAction::Allow { override_port, .. } = &mut self {
+            *override_port = port;
+        }
+        self
+    }
+
     pub fn new_block() -> Self {
         ConnectAction::Block
     }
@@ -148,26 +273,39 @@ impl ConnectAction {
     ///
     /// Takes ownership of the location. If there's an override_address, a new
     /// ResolvedLocation is created (the resolved_addr doesn't apply to a different
-    /// destination). Otherwise, the original location is passed through, preserving
-    /// any resolution that was done during rule matching.
+    /// destination). If only the port is overridden, the resolved_addr is kept with the
+    /// new port. Otherwise, the original location is passed through, preserving any
+    /// resolution that was done during rule matching.
     pub fn to_decision(&self, location: ResolvedLocation) -> ConnectDecision<'_> {
         match self {
             ConnectAction::Allow {
                 override_address,
+                override_port,
                 chain_group,
             } => {
-                let remote_location = match override_address {
-                    Some(l) => {
+                let remote_location = match (override_address, override_port) {
+                    (Some(l), _) => {
                         // Override address - create fresh location (resolved_addr doesn't apply)
-                        let new_loc = if l.port() > 0 {
-                            l.clone()
-                        } else {
+                        let port = match (override_port, l.port()) {
+                            (Some(port), _) => *port,
                             // If port of 0 is specified, take the requested port.
-                            NetLocation::new(l.address().clone(), location.location().port())
+                            (None, 0) => location.location().port(),
+                            (None, port) => port,
                         };
-                        new_loc.into()
+                        NetLocation::new(l.address().clone(), port).into()
+                    }
+                    (None, Some(port)) => {
+                        // Override port - the resolved address still applies
+                        let new_loc = NetLocation::new(location.address().clone(), *port);
+                        match location.resolved_addr() {
+                            Some(addr) => ResolvedLocation::with_resolved(
+                                new_loc,
+                                SocketAddr::new(addr.ip(), *port),
+                            ),
+                            None => new_loc.into(),
+                        }
                     }
-                    None => {
+                    (None, None) => {
                         // No override - pass through with any resolution intact
                         location
                     }
@@ -256,7 +394,12 @@ impl ClientProxySelector {
         // Enable caching if:
         // 1. DNS resolution is enabled (expensive operation), OR
         // 2. Many rules (linear scan becomes expensive)
-        let cache = if resolve_rule_hostnames || rules.len() > CACHE_RULE_THRESHOLD {
+        // Decisions are cached by destination, so caching is disabled when any rule depends
+        // on the source IP.
+        let has_source_rules = rules.iter().any(|rule| !rule.source_ips.is_empty());
+        let cache = if has_source_rules {
+            None
+        } else if resolve_rule_hostnames || rules.len() > CACHE_RULE_THRESHOLD {
             Some(RoutingCache::new(cache_capacity.max(1)))
         } else {
             None
@@ -285,45 +428,85 @@ impl ClientProxySelector {
         location: ResolvedLocation,
         resolver: &Arc<dyn Resolver>,
     ) -> std::io::Result<ConnectDecision<'a>> {
-        // Derive resolved_ip from any pre-resolved address
-        let resolved_ip = location.resolved_addr().map(|addr| ip_to_u128(addr.ip()));
+        self.judge_with_source(location, None, resolver).await
+    }
 
-        // If caching is disabled, go directly to rule matching
-        let cache = match &self.cache {
-            Some(c) => c,
-            None => {
-                return self.judge_uncached(location, resolved_ip, resolver).await;
+    /// Judge a connection request from `source`, which is matched against the rules'
+    /// source IP masks.
+    #[inline]
+    pub async fn judge_with_source<'a>(
+        &'a self,
+        location: ResolvedLocation,
+        source: Option<IpAddr>,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<ConnectDecision<'a>> {
+        let (rule_index, location) = self.find_rule(location, source, resolver).await?;
+        match rule_index {
+            Some(rule_index) => Ok(self.rules[rule_index].action.to_decision(location)),
+            None => Ok(ConnectDecision::Block),
+        }
+    }
+
+    /// Judge a QUIC flow from `source`. Flows that match a rule with `block_quic` are
+    /// blocked, so that the client falls back to TCP.
+    pub async fn judge_quic<'a>(
+        &'a self,
+        location: ResolvedLocation,
+        source: Option<IpAddr>,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<ConnectDecision<'a>> {
+        let (rule_index, location) = self.find_rule(location, source, resolver).await?;
+        match rule_index {
+            Some(rule_index) if !self.rules[rule_index].block_quic => {
+                Ok(self.rules[rule_index].action.to_decision(location))
             }
-        };
+            _ => Ok(ConnectDecision::Block),
+        }
+    }
+
+    /// Finds the index of the rule that matches the connection, or None if no rule
+    /// matches. Also returns the location, which may have been restored from a fake IP
+    /// or resolved.
+    async fn find_rule(
+        &self,
+        location: ResolvedLocation,
+        source: Option<IpAddr>,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<(Option<usize>, ResolvedLocation)> {
+        // Route fake-IP destinations by the domain they were handed out for.
+        let mut location = fake_ip::restore_location(location)?;
+
+        // Derive resolved_ip from any pre-resolved address
+        let resolved_ip = location.resolved_addr().map(|addr| ip_to_u128(addr.ip()));
+        let source_ip = source.map(ip_to_u128);
 
         // Fast path: check cache first
         // Note: cached decisions don't include resolved_addr, so we pass through the location
-        if let Some(cached) = cache.get(location.location()) {
-            return Ok(self.cached_to_decision(cached, location));
+        if let Some(cache) = &self.cache
+            && let Some(cached) = cache.get(location.location())
+        {
+            let rule_index = match cached {
+                CachedDecision::Allow(rule_index) => Some(rule_index),
+                CachedDecision::Block => None,
+            };
+            return Ok((rule_index, location));
         }
 
         // Slow path: full rule matching (may resolve and update the location)
-        let mut location = location;
-        match match_rule(
+        let rule_index = match_rule(
             &self.rules,
             &mut location,
             resolved_ip,
+            source_ip,
             resolver,
             self.resolve_rule_hostnames,
         )
-        .await?
-        {
-            Some(rule_index) => {
-                // Cache the result
-                cache.insert(location.location(), CachedDecision::Allow(rule_index));
-                Ok(self.rules[rule_index].action.to_decision(location))
-            }
-            None => {
-                // Cache the block decision
-                cache.insert(location.location(), CachedDecision::Block);
-                Ok(ConnectDecision::Block)
-            }
+        .await?;
+        if let Some(cache) = &self.cache {
+            let cached = rule_index.map_or(CachedDecision::Block, CachedDecision::Allow);
+            cache.insert(location.location(), cached);
         }
+        Ok((rule_index, location))
     }
 
     /// Judge without using the cache. Useful for testing or when cache bypass is needed.
@@ -333,12 +516,25 @@ impl ClientProxySelector {
         location: ResolvedLocation,
         resolved_ip: Option<u128>,
         resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<ConnectDecision<'a>> {
+        self.judge_without_cache(location, resolved_ip, None, resolver)
+            .await
+    }
+
+    #[inline]
+    async fn judge_without_cache<'a>(
+        &'a self,
+        location: ResolvedLocation,
+        resolved_ip: Option<u128>,
+        source_ip: Option<u128>,
+        resolver: &Arc<dyn Resolver>,
     ) -> std::io::Result<ConnectDecision<'a>> {
         let mut location = location;
         match match_rule(
             &self.rules,
             &mut location,
             resolved_ip,
+            source_ip,
             resolver,
             self.resolve_rule_hostnames,
         )
@@ -349,15 +545,6 @@ impl ClientProxySelector {
         }
     }
 
-    /// Convert a cached decision back to a ConnectDecision.
-    #[inline]
-    fn cached_to_decision(&self, cached: CachedDecision, location: ResolvedLocation) -> ConnectDecision<'_> {
-        match cached {
-            CachedDecision::Allow(rule_index) => self.rules[rule_index].action.to_decision(location),
-            CachedDecision::Block => ConnectDecision::Block,
-        }
-    }
-
     #[cfg(test)]
     fn cache_size(&self) -> usize {
         self.cache.as_ref().map_or(0, |c| c.len())
@@ -374,10 +561,145 @@ impl ClientProxySelector {
     fn is_cache_enabled(&self) -> bool {
         self.cache.is_some()
     }
+
+    pub fn rules(&self) -> &[ConnectRule] {
+        &self.rules
+    }
+}
+
+static PROXY_SELECTORS: LazyLock<Mutex<Vec<Weak<ClientProxySelector>>>> =
+    LazyLock::new(Default::default);
+
+/// Registers a selector so that its rules can be listed through the Clash API.
+///
+/// Only a weak reference is kept, so selectors of stopped servers are dropped.
+pub fn register_proxy_selector(selector: &Arc<ClientProxySelector>) {
+    let mut selectors = PROXY_SELECTORS.lock();
+    selectors.retain(|s| s.strong_count() > 0);
+    selectors.push(Arc::downgrade(selector));
+}
+
+/// Returns all registered selectors that are still in use.
+pub fn proxy_selectors() -> Vec<Arc<ClientProxySelector>> {
+    PROXY_SELECTORS
+        .lock()
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+/// Describes a chain of a chain group.
+#[derive(Debug, Clone)]
+pub struct ChainInfo {
+    /// Identifies the chain, e.g. `socks5@1.2.3.4:1080 -> vless@5.6.7.8:443`.
+    pub name: String,
+    /// The proxy type, e.g. `SOCKS5`, or `Relay` for multi-hop chains.
+    pub kind: String,
+    pub supports_udp: bool,
+}
+
+const ROUND_ROBIN: usize = usize::MAX;
+
+/// Runtime-mutable selection of the chain used by a chain group.
+///
+/// By default, chain groups use their chains round-robin. A chain can be pinned at
+/// runtime, e.g. through the Clash API. Chain groups with the same chains share a
+/// selector, so a selection applies to all of them and is kept across config reloads.
+/// The selector also keeps the health check results of the chains.
+#[derive(Debug)]
+pub struct OutboundSelector {
+    name: String,
+    chains: Vec<ChainInfo>,
+    selected: AtomicUsize,
+    health: ChainHealth,
+}
+
+impl OutboundSelector {
+    pub fn new(chains: Vec<ChainInfo>) -> Self {
+        let name = chains
+            .iter()
+            .map(|chain| chain.name.as_str())
+            .collect::<Vec<_>>()
+            .join(" | ");
+        Self {
+            name,
+            health: ChainHealth::new(chains.len()),
+            chains,
+            selected: AtomicUsize::new(ROUND_ROBIN),
+        }
+    }
+
+    /// Identifies the chain group, e.g. `socks5@1.2.3.4:1080 | direct`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn chains(&self) -> &[ChainInfo] {
+        &self.chains
+    }
+
+    pub fn health(&self) -> &ChainHealth {
+        &self.health
+    }
+
+    /// Returns the index of the selected chain, or None if chains are used round-robin.
+    #[inline]
+    pub fn selected(&self) -> Option<usize> {
+        let index = self.selected.load(Ordering::Relaxed);
+        (index != ROUND_ROBIN).then_some(index)
+    }
+
+    /// Selects the chain with the given name, or restores round-robin selection.
+    pub fn select(&self, chain_name: Option<&str>) -> std::io::Result<()> {
+        let index = match chain_name {
+            Some(chain_name) => self
+                .chains
+                .iter()
+                .position(|chain| chain.name == chain_name)
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!("{} has no chain named {chain_name}", self.name),
+                    )
+                })?,
+            None => ROUND_ROBIN,
+        };
+        self.selected.store(index, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+static OUTBOUND_SELECTORS: LazyLock<Mutex<HashMap<String, Weak<OutboundSelector>>>> =
+    LazyLock::new(Default::default);
+
+/// Returns the shared selector for chain groups with the given chains.
+pub fn get_outbound_selector(chains: Vec<ChainInfo>) -> Arc<OutboundSelector> {
+    let selector = OutboundSelector::new(chains);
+
+    let mut selectors = OUTBOUND_SELECTORS.lock();
+    if let Some(existing) = selectors.get(selector.name()).and_then(Weak::upgrade) {
+        return existing;
+    }
+    selectors.retain(|_, s| s.strong_count() > 0);
+
+    let selector = Arc::new(selector);
+    selectors.insert(selector.name.clone(), Arc::downgrade(&selector));
+    selector
+}
+
+/// Returns the selectors of all chain groups that are still in use, sorted by name.
+pub fn outbound_selectors() -> Vec<Arc<OutboundSelector>> {
+    let mut selectors: Vec<_> = OUTBOUND_SELECTORS
+        .lock()
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect();
+    selectors.sort_by(|a, b| a.name.cmp(&b.name));
+    selectors
 }
 
 #[inline]
-fn ip_to_u128(ip: IpAddr) -> u128 {
+pub fn ip_to_u128(ip: IpAddr) -> u128 {
     match ip {
         IpAddr::V4(addr) => ipv4_to_u128(addr),
         IpAddr::V6(addr) => ipv6_to_u128(addr),
@@ -394,6 +716,27 @@ fn ipv6_to_u128(ip: Ipv6Addr) -> u128 {
     u128::from(ip)
 }
 
+#[inline]
+fn contains_ignore_ascii_case(hostname: &str, lowercase_keyword: &str) -> bool {
+    if lowercase_keyword.is_empty() {
+        return true;
+    }
+    hostname
+        .as_bytes()
+        .windows(lowercase_keyword.len())
+        .any(|window| window.eq_ignore_ascii_case(lowercase_keyword.as_bytes()))
+}
+
+#[inline]
+pub fn matches_ip_mask(mask: &AddressMask, ip: u128) -> bool {
+    let masked_ip = ip & mask.netmask;
+    match mask.address {
+        Address::Ipv4(addr) => ipv4_to_u128(addr) & mask.netmask == masked_ip,
+        Address::Ipv6(addr) => ipv6_to_u128(addr) & mask.netmask == masked_ip,
+        Address::Hostname(_) => false,
+    }
+}
+
 #[inline]
 fn matches_domain(base_domain: &str, hostname: &str) -> bool {
     if hostname.ends_with(base_domain) {
@@ -417,10 +760,19 @@ async fn match_rule(
     rules: &[ConnectRule],
     location: &mut ResolvedLocation,
     mut resolved_ip: Option<u128>,
+    source_ip: Option<u128>,
     resolver: &Arc<dyn Resolver>,
     resolve_rule_hostnames: bool,
 ) -> std::io::Result<Option<usize>> {
     for (rule_index, rule) in rules.iter().enumerate() {
+        if !rule.matches_conditions(location.location(), source_ip) {
+            continue;
+        }
+        if !rule.geoip.is_empty()
+            && !matches_geoip(&rule.geoip, location, &mut resolved_ip, resolver).await?
+        {
+            continue;
+        }
         for mask in rule.masks.iter() {
             match match_mask(
                 mask,
@@ -460,6 +812,41 @@ enum MatchMaskError {
     Fatal(std::io::Error),
 }
 
+/// Returns whether the destination IP belongs to one of `codes`.
+/// Hostname destinations are resolved, since they can't be looked up otherwise.
+async fn matches_geoip(
+    codes: &[GeoIpCode],
+    location: &mut ResolvedLocation,
+    resolved_ip: &mut Option<u128>,
+    resolver: &Arc<dyn Resolver>,
+) -> std::io::Result<bool> {
+    let ip = match location.location().address() {
+        Address::Ipv4(ip) => IpAddr::V4(*ip),
+        Address::Ipv6(ip) => IpAddr::V6(*ip),
+        Address::Hostname(_) => match location.resolved_addr() {
+            Some(socket_addr) => socket_addr.ip(),
+            None => {
+                let socket_addr = resolve_location(location, resolver).await.map_err(|e| {
+                    std::io::Error::other(format!(
+                        "fatal error while matching geoip for {}: {e}",
+                        location.location()
+                    ))
+                })?;
+                resolved_ip.replace(ip_to_u128(socket_addr.ip()));
+                socket_addr.ip()
+            }
+        },
+    };
+
+    match geoip::matches(ip, codes) {
+        Ok(is_match) => Ok(is_match),
+        Err(e) => {
+            error!("Non-fatal error while trying to match geoip for {ip}: {e}");
+            Ok(false)
+        }
+    }
+}
+
 // Helper function for testing - exposed for unit tests
 #[cfg(test)]
 pub fn matches_domain_for_test(base_domain: &str, hostname: &str) -> bool {
@@ -1056,6 +1443,148 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_domain_keyword_rule() {
+        let rules = vec![
+            ConnectRule::new(vec![NetLocationMask::ANY], ConnectAction::new_block())
+                .with_domain_keywords(vec!["tracker".to_string()]),
+            allow_rule(vec!["0.0.0.0/0"], "default"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+
+        let location = NetLocation::new(Address::Hostname("ads.Tracker-cdn.net".into()), 443);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Block));
+
+        let location = NetLocation::new(Address::Hostname("example.com".into()), 443);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+
+        // Keywords only match hostname destinations.
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), 443);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_port_range_rule() {
+        let rules = vec![
+            ConnectRule::new(
+                vec![NetLocationMask::from("10.0.0.0/8").unwrap()],
+                ConnectAction::new_block(),
+            )
+            .with_ports(vec![
+                PortRange::from_str("22").unwrap(),
+                PortRange::from_str("6000-7000").unwrap(),
+            ]),
+            allow_rule(vec!["0.0.0.0/0"], "default"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+
+        for port in [22, 6000, 6500, 7000] {
+            let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(10, 1, 2, 3)), port);
+            let decision = selector.judge(location.into(), &resolver).await.unwrap();
+            assert!(matches!(decision, ConnectDecision::Block), "port {port}");
+        }
+
+        for port in [23, 5999, 7001] {
+            let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(10, 1, 2, 3)), port);
+            let decision = selector.judge(location.into(), &resolver).await.unwrap();
+            assert!(matches!(decision, ConnectDecision::Allow { .. }), "port {port}");
+        }
+
+        // The destination must still match the masks.
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(8, 8, 8, 8)), 22);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_source_ip_rule() {
+        let rules = vec![
+            ConnectRule::new(vec![NetLocationMask::ANY], ConnectAction::new_block())
+                .with_source_ips(vec![
+                    AddressMask::from("192.168.2.0/24").unwrap(),
+                    AddressMask::from("fd00::/8").unwrap(),
+                ]),
+            allow_rule(vec!["0.0.0.0/0"], "default"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), 443);
+
+        let source = IpAddr::V4(Ipv4Addr::new(192, 168, 2, 50));
+        let decision = selector
+            .judge_with_source(location.clone().into(), Some(source), &resolver)
+            .await
+            .unwrap();
+        assert!(matches!(decision, ConnectDecision::Block));
+
+        let source = IpAddr::V6("fd12::1".parse().unwrap());
+        let decision = selector
+            .judge_with_source(location.clone().into(), Some(source), &resolver)
+            .await
+            .unwrap();
+        assert!(matches!(decision, ConnectDecision::Block));
+
+        let source = IpAddr::V4(Ipv4Addr::new(192, 168, 3, 50));
+        let decision = selector
+            .judge_with_source(location.clone().into(), Some(source), &resolver)
+            .await
+            .unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+
+        // Source IP rules never match when the source is unknown.
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_geoip_private_rule() {
+        let rules = vec![
+            ConnectRule::new(vec![NetLocationMask::ANY], ConnectAction::new_block())
+                .with_geoip(vec![GeoIpCode::Private]),
+            allow_rule(vec!["0.0.0.0/0", "::/0"], "default"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(192, 168, 1, 1)), 80);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Block));
+
+        let location = NetLocation::new(Address::Ipv6(Ipv6Addr::LOCALHOST), 80);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Block));
+
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(8, 8, 8, 8)), 80);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_geosite_rule_without_database() {
+        let rules = vec![
+            ConnectRule::new(vec![NetLocationMask::ANY], ConnectAction::new_block())
+                .with_geosite(vec!["google".to_string()]),
+            allow_rule(vec!["0.0.0.0/0"], "default"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+
+        // Geosite rules only match hostname destinations.
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(8, 8, 8, 8)), 443);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+
+        // Without a database, the rule is skipped rather than failing the connection.
+        let location = NetLocation::new(Address::Hostname("google.com".into()), 443);
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+    }
+
     #[tokio::test]
     async fn test_first_rule_wins() {
         let rules = vec![
@@ -1164,6 +1693,34 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_block_quic() {
+        let rules = vec![
+            allow_rule(vec!["youtube.com"], "direct").with_block_quic(true),
+            allow_rule(vec!["0.0.0.0/0"], "default"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+
+        let location = NetLocation::new(Address::Hostname("www.youtube.com".to_string()), 443);
+        let decision = selector
+            .judge_quic(location.clone().into(), None, &resolver)
+            .await
+            .unwrap();
+        assert!(matches!(decision, ConnectDecision::Block));
+
+        // Other flows that match the rule are allowed.
+        let decision = selector.judge(location.into(), &resolver).await.unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+
+        let location = NetLocation::new(Address::Hostname("example.com".to_string()), 443);
+        let decision = selector
+            .judge_quic(location.into(), None, &resolver)
+            .await
+            .unwrap();
+        assert!(matches!(decision, ConnectDecision::Allow { .. }));
+    }
+
     #[tokio::test]
     async fn test_no_default_rule_blocks() {
         let rules = vec![allow_rule(vec!["192.168.1.0/24"], "lan")];
@@ -1266,6 +1823,62 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn test_port_override() {
+        let masks = vec![NetLocationMask::from("0.0.0.0/0:53").unwrap()];
+        let rules = vec![
+            ConnectRule::new(
+                masks,
+                ConnectAction::new_allow(None, mock_chain_group()).with_override_port(Some(5353)),
+            ),
+            allow_rule_with_override(vec!["0.0.0.0/0"], "proxy", "10.0.0.1:0"),
+        ];
+        let selector = ClientProxySelector::new(rules);
+        let resolver = mock_resolver();
+
+        // Only the port is rewritten, and the resolved address is kept.
+        let location = NetLocation::new(Address::Hostname("dns.example.com".to_string()), 53);
+        let resolved_addr: SocketAddr = "1.2.3.4:53".parse().unwrap();
+        let decision = selector
+            .judge(
+                ResolvedLocation::with_resolved(location, resolved_addr),
+                &resolver,
+            )
+            .await
+            .unwrap();
+        match decision {
+            ConnectDecision::Allow {
+                remote_location, ..
+            } => {
+                assert_eq!(remote_location.address().to_string(), "dns.example.com");
+                assert_eq!(remote_location.port(), 5353);
+                assert_eq!(
+                    remote_location.resolved_addr(),
+                    Some("1.2.3.4:5353".parse().unwrap())
+                );
+            }
+            ConnectDecision::Block => panic!("Expected Allow"),
+        }
+
+        // The port also replaces the port of an override address.
+        let action = ConnectAction::new_allow(
+            Some(NetLocation::from_str("10.0.0.1:0", Some(0)).unwrap()),
+            mock_chain_group(),
+        )
+        .with_override_port(Some(8053));
+        let location = NetLocation::new(Address::Ipv4(Ipv4Addr::new(1, 2, 3, 4)), 53);
+        match action.to_decision(location.into()) {
+            ConnectDecision::Allow {
+                remote_location, ..
+            } => {
+                assert_eq!(remote_location.address().to_string(), "10.0.0.1");
+                assert_eq!(remote_location.port(), 8053);
+                assert_eq!(remote_location.resolved_addr(), None);
+            }
+            ConnectDecision::Block => panic!("Expected Allow"),
+        }
+    }
+
     #[tokio::test]
     async fn test_multiple_masks_in_rule() {
         let rules = vec![
@@ -1740,6 +2353,21 @@ mod tests {
         assert!(selector.is_cache_enabled());
     }
 
+    #[tokio::test]
+    async fn test_selector_cache_disabled_with_source_rules() {
+        // Decisions depending on the source IP can't be cached by destination
+        let mut rules: Vec<ConnectRule> = (0..20)
+            .map(|i| allow_rule(vec![&format!("10.0.{}.0/24", i)], &format!("rule{}", i)))
+            .collect();
+        rules.push(
+            ConnectRule::new(vec![NetLocationMask::ANY], ConnectAction::new_block())
+                .with_source_ips(vec![AddressMask::from("192.168.0.0/16").unwrap()]),
+        );
+
+        let selector = ClientProxySelector::with_options(rules, true);
+        assert!(!selector.is_cache_enabled());
+    }
+
     #[tokio::test]
     async fn test_selector_cache_hit() {
         // Use selector_with_cache to ensure caching is enabled for this test
@@ -2036,4 +2664,44 @@ mod tests {
             "172.17.0.0/24 should have netmask != 0"
         );
     }
+
+    fn chain_info(name: &str) -> ChainInfo {
+        ChainInfo {
+            name: name.to_string(),
+            kind: String::from("SOCKS5"),
+            supports_udp: true,
+        }
+    }
+
+    #[test]
+    fn test_outbound_selector_select() {
+        let selector = OutboundSelector::new(vec![chain_info("a"), chain_info("b")]);
+        assert_eq!(selector.name(), "a | b");
+        assert_eq!(selector.selected(), None);
+
+        selector.select(Some("b")).unwrap();
+        assert_eq!(selector.selected(), Some(1));
+
+        assert!(selector.select(Some("c")).is_err());
+        assert_eq!(selector.selected(), Some(1));
+
+        selector.select(None).unwrap();
+        assert_eq!(selector.selected(), None);
+    }
+
+    #[test]
+    fn test_get_outbound_selector_is_shared() {
+        let chains = vec![chain_info("shared-test-a"), chain_info("shared-test-b")];
+        let first = get_outbound_selector(chains.clone());
+        first.select(Some("shared-test-b")).unwrap();
+
+        let second = get_outbound_selector(chains.clone());
+        assert!(Arc::ptr_eq(&first, &second));
+        assert_eq!(second.selected(), Some(1));
+
+        drop(first);
+        drop(second);
+        let third = get_outbound_selector(chains);
+        assert_eq!(third.selected(), None);
+    }
 }
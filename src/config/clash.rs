@@ -0,0 +1,891 @@
+//! Conversion of Clash and Clash.Meta (mihomo) configs.
+//!
+//! Proxies become client groups named after the proxies, rules become a rule group, and
+//! the listening ports become servers that use the rule group. Proxy groups are resolved
+//! into the client chains of the rules that use them, with a balancer that matches the
+//! group type.
+
+use std::collections::HashMap;
+
+use serde_yaml::{Mapping, Value};
+
+use super::convert_util::{
+    ConvertedConfigs, MATCH_ALL, TlsLayer, TransportLayer, client_group, direct_client, get_bool,
+    get_str, get_str_list, get_u64, host_key_fingerprint, mapping, opt_str, parse_entry, sequence,
+    server_address, verify_host_key, wrap_protocol,
+};
+use super::types::{Config, RuleConfig};
+
+/// Name of the rule group with the converted rules.
+const RULE_GROUP: &str = "clash-rules";
+
+/// Converts a Clash config into shoes configs.
+///
+/// Proxies, groups and rules that can't be converted are skipped and listed in the
+/// warnings. Returns an error only if the config is not a YAML mapping.
+pub fn convert_clash_config(config_str: &str) -> std::io::Result<ConvertedConfigs> {
+    let clash: Value = serde_yaml::from_str(config_str).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid Clash config: {e}"),
+        )
+    })?;
+    if !clash.is_mapping() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid Clash config: expected a mapping",
+        ));
+    }
+
+    let mut converter = ClashConverter::new(&clash);
+    converter.convert_proxies();
+    converter.convert_rules();
+    converter.convert_listeners();
+    Ok(converter.converted)
+}
+
+/// Converts only the proxies of a Clash config, e.g. of a subscription, into client groups
+/// named after the proxies.
+pub(crate) fn convert_clash_proxies(clash: &Value) -> ConvertedConfigs {
+    let mut converter = ClashConverter::new(clash);
+    converter.convert_proxies();
+    converter.converted
+}
+
+struct ClashConverter<'a> {
+    clash: &'a Value,
+    /// Names of the proxies that were converted.
+    proxies: Vec<String>,
+    groups: HashMap<String, &'a Value>,
+    /// Converted actions of rule targets, or why the target can't be converted.
+    targets: HashMap<String, Result<Mapping, String>>,
+    converted: ConvertedConfigs,
+}
+
+/// A converted rule, which adjacent rules with the same matcher and target are merged
+/// into.
+struct PendingRule {
+    source: String,
+    field: &'static str,
+    values: Vec<Value>,
+    target: String,
+}
+
+impl<'a> ClashConverter<'a> {
+    fn new(clash: &'a Value) -> Self {
+        let groups = sequence(clash, "proxy-groups")
+            .iter()
+            .filter_map(|group| Some((get_str(group, "name")?, group)))
+            .collect();
+        Self {
+            clash,
+            proxies: vec![],
+            groups,
+            targets: HashMap::new(),
+            converted: ConvertedConfigs::default(),
+        }
+    }
+
+    fn warn(&mut self, warning: String) {
+        self.converted.warnings.push(warning);
+    }
+
+    fn convert_proxies(&mut self) {
+        for proxy in sequence(self.clash, "proxies") {
+            let Some(name) = get_str(proxy, "name") else {
+                self.warn("Skipped proxy without a name".to_string());
+                continue;
+            };
+            match convert_proxy(proxy).and_then(|client| client_group(&name, client)) {
+                Ok(config) => {
+                    self.converted.configs.push(config);
+                    self.proxies.push(name);
+                }
+                Err(e) => self.warn(format!("Skipped proxy {name}: {e}")),
+            }
+        }
+    }
+
+    fn convert_rules(&mut self) {
+        let lines: Vec<String> = match get_str(self.clash, "mode").as_deref() {
+            Some("global") => vec!["MATCH,GLOBAL".to_string()],
+            Some("direct") => vec!["MATCH,DIRECT".to_string()],
+            _ => sequence(self.clash, "rules")
+                .iter()
+                .filter_map(|rule| rule.as_str().map(str::to_string))
+                .collect(),
+        };
+
+        let mut pending: Vec<PendingRule> = vec![];
+        for line in lines {
+            let (field, values, target) = match convert_rule(&line) {
+                Ok(rule) => rule,
+                Err(e) => {
+                    self.warn(format!("Skipped rule {line}: {e}"));
+                    continue;
+                }
+            };
+            match pending.last_mut() {
+                Some(last) if last.field == field && last.target == target => {
+                    last.values.extend(values);
+                }
+                _ => pending.push(PendingRule {
+                    source: line,
+                    field,
+                    values,
+                    target,
+                }),
+            }
+        }
+
+        // Clash connects directly when no rule matches, while shoes blocks.
+        if !pending
+            .last()
+            .is_some_and(|last| last.values.contains(&Value::from(MATCH_ALL)))
+        {
+            pending.push(PendingRule {
+                source: "MATCH,DIRECT".to_string(),
+                field: "masks",
+                values: vec![Value::from(MATCH_ALL)],
+                target: "DIRECT".to_string(),
+            });
+        }
+
+        let mut rules = vec![];
+        for rule in pending {
+            let action = match self.target_action(&rule.target) {
+                Ok(action) => action,
+                Err(e) => {
+                    self.warn(format!("Skipped rule {}: {e}", rule.source));
+                    continue;
+                }
+            };
+            let mut entry = Mapping::new();
+            entry.insert(Value::from(rule.field), Value::Sequence(rule.values));
+            entry.extend(action);
+            let entry = Value::Mapping(entry);
+            match parse_entry::<RuleConfig>(entry.clone()) {
+                Ok(_) => rules.push(entry),
+                Err(e) => self.warn(format!("Skipped rule {}: {e}", rule.source)),
+            }
+        }
+
+        let group = mapping([
+            ("rule_group", Value::from(RULE_GROUP)),
+            ("rules", Value::Sequence(rules)),
+        ]);
+        match parse_entry::<Config>(group) {
+            Ok(config) => self.converted.configs.push(config),
+            Err(e) => self.warn(format!("Skipped rules: {e}")),
+        }
+    }
+
+    fn convert_listeners(&mut self) {
+        let allow_lan = get_bool(self.clash, "allow-lan").unwrap_or(false);
+        let bind_address = match get_str(self.clash, "bind-address") {
+            Some(address) if allow_lan && address != "*" => address,
+            _ if allow_lan => "0.0.0.0".to_string(),
+            _ => "127.0.0.1".to_string(),
+        };
+
+        let credentials = get_str_list(self.clash, "authentication");
+        if credentials.len() > 1 {
+            self.warn("Only the first user of authentication is used".to_string());
+        }
+        let (username, password) = match credentials.first().and_then(|c| c.split_once(':')) {
+            Some((username, password)) => (
+                Value::from(username.to_string()),
+                Value::from(password.to_string()),
+            ),
+            None => (Value::Null, Value::Null),
+        };
+
+        let listeners = [
+            ("port", "http", None),
+            ("socks-port", "socks", None),
+            ("mixed-port", "mixed", None),
+            ("redir-port", "redirect", None),
+            ("tproxy-port", "tproxy", None),
+            ("tproxy-port", "tproxy", Some("udp")),
+        ];
+        for (key, protocol, transport) in listeners {
+            let Some(port) = get_u64(self.clash, key).filter(|port| *port != 0) else {
+                continue;
+            };
+            let authenticated = matches!(protocol, "http" | "socks" | "mixed");
+            let server = mapping([
+                ("address", Value::from(server_address(&bind_address, port))),
+                ("transport", transport.map_or(Value::Null, Value::from)),
+                (
+                    "protocol",
+                    mapping([
+                        ("type", Value::from(protocol)),
+                        (
+                            "username",
+                            if authenticated {
+                                username.clone()
+                            } else {
+                                Value::Null
+                            },
+                        ),
+                        (
+                            "password",
+                            if authenticated {
+                                password.clone()
+                            } else {
+                                Value::Null
+                            },
+                        ),
+                    ]),
+                ),
+                ("rules", Value::from(RULE_GROUP)),
+            ]);
+            match parse_entry::<Config>(server) {
+                Ok(config) => self.converted.configs.push(config),
+                Err(e) => self.warn(format!("Skipped {key} {port}: {e}")),
+            }
+        }
+        if self.clash.get("listeners").is_some() {
+            self.warn("Skipped listeners, only the port settings are converted".to_string());
+        }
+    }
+
+    /// Returns the action fields of a rule that sends connections to `target`.
+    fn target_action(&mut self, target: &str) -> Result<Mapping, String> {
+        if let Some(action) = self.targets.get(target) {
+            return action.clone();
+        }
+        let action = self.convert_target(target);
+        self.targets.insert(target.to_string(), action.clone());
+        action
+    }
+
+    fn convert_target(&self, target: &str) -> Result<Mapping, String> {
+        let mut action = Mapping::new();
+        if is_reject(target) {
+            action.insert(Value::from("action"), Value::from("block"));
+            return Ok(action);
+        }
+        action.insert(Value::from("action"), Value::from("allow"));
+        if target == "DIRECT" {
+            return Ok(action);
+        }
+
+        let chains = self.chains(target, &mut vec![])?;
+        if chains.is_empty() {
+            return Err(format!("{target} has no supported proxies"));
+        }
+        let group = self.groups.get(target).copied();
+        if chains.len() > 1
+            && let Some(group) = group
+        {
+            let (balancer, health_check) = match get_str(group, "type").as_deref() {
+                Some("url-test") => ("url_test", health_check(group)),
+                Some("fallback") => ("failover", health_check(group)),
+                Some("load-balance") => match get_str(group, "strategy").as_deref() {
+                    Some("round-robin") => ("round_robin", Value::Null),
+                    Some("sticky-sessions") => ("source_hash", Value::Null),
+                    _ => ("consistent_hash", Value::Null),
+                },
+                // Connections use the first proxy of the group that is up, unless
+                // another one is selected through the Clash API.
+                _ => ("failover", health_check(group)),
+            };
+            action.insert(Value::from("balancer"), Value::from(balancer));
+            if !health_check.is_null() {
+                action.insert(Value::from("health_check"), health_check);
+            }
+        }
+        action.insert(Value::from("client_chains"), Value::Sequence(chains));
+        Ok(action)
+    }
+
+    /// Returns the client chains of a proxy or group. Groups other than relays are
+    /// flattened into the chains of their members.
+    fn chains(&self, name: &str, visiting: &mut Vec<String>) -> Result<Vec<Value>, String> {
+        if name == "DIRECT" {
+            return Ok(vec![direct_client()]);
+        }
+        if is_reject(name) {
+            return Ok(vec![]);
+        }
+        if self.proxies.iter().any(|proxy| proxy == name) {
+            return Ok(vec![Value::from(name)]);
+        }
+        let Some(group) = self.groups.get(name).copied() else {
+            return Err(format!("unknown or skipped proxy {name}"));
+        };
+        if visiting.iter().any(|visited| visited == name) {
+            return Err(format!("group {name} contains itself"));
+        }
+        visiting.push(name.to_string());
+
+        let mut members = get_str_list(group, "proxies");
+        if get_bool(group, "include-all").unwrap_or(false)
+            || get_bool(group, "include-all-proxies").unwrap_or(false)
+        {
+            members.extend(self.proxies.iter().cloned());
+        }
+        if group.get("use").is_some() && members.is_empty() {
+            return Err(format!("group {name} only uses proxy providers"));
+        }
+
+        let chains = if get_str(group, "type").as_deref() == Some("relay") {
+            let mut hops = vec![];
+            for member in members {
+                let chains = self.chains(&member, visiting)?;
+                hops.push(match chains.len() {
+                    0 => return Err(format!("relay {name} contains {member}")),
+                    1 => chains.into_iter().next().unwrap(),
+                    _ if chains.iter().all(Value::is_string) => {
+                        mapping([("pool", Value::Sequence(chains))])
+                    }
+                    _ => return Err(format!("relay {name} contains a group of relays")),
+                });
+            }
+            vec![mapping([("chain", Value::Sequence(hops))])]
+        } else {
+            let mut chains: Vec<Value> = vec![];
+            for member in members {
+                for chain in self.chains(&member, visiting)? {
+                    if !chains.contains(&chain) {
+                        chains.push(chain);
+                    }
+                }
+            }
+            chains
+        };
+        visiting.pop();
+        Ok(chains)
+    }
+}
+
+fn is_reject(target: &str) -> bool {
+    matches!(target, "REJECT" | "REJECT-DROP" | "REJECT-TINY" | "PASS")
+}
+
+fn health_check(group: &Value) -> Value {
+    let entries = [
+        ("url", opt_str(get_str(group, "url"))),
+        (
+            "interval_secs",
+            get_u64(group, "interval").map_or(Value::Null, Value::from),
+        ),
+        (
+            "timeout_secs",
+            get_u64(group, "timeout").map_or(Value::Null, |ms| Value::from(ms.div_ceil(1000))),
+        ),
+        (
+            "tolerance_ms",
+            get_u64(group, "tolerance").map_or(Value::Null, Value::from),
+        ),
+    ];
+    if entries.iter().all(|(_, value)| value.is_null()) {
+        return Value::Null;
+    }
+    mapping(entries)
+}
+
+/// Converts a rule like `DOMAIN-SUFFIX,example.com,proxy` into the matcher field, its
+/// values and the target.
+fn convert_rule(line: &str) -> Result<(&'static str, Vec<Value>, String), String> {
+    let parts: Vec<&str> = line.split(',').map(str::trim).collect();
+    let kind = parts[0].to_ascii_uppercase();
+    if kind == "MATCH" || kind == "FINAL" {
+        let target = parts.get(1).ok_or("missing target")?;
+        return Ok(("masks", vec![Value::from(MATCH_ALL)], target.to_string()));
+    }
+    let (Some(payload), Some(target)) = (parts.get(1), parts.get(2)) else {
+        return Err("missing target".to_string());
+    };
+    let (field, values) = match kind.as_str() {
+        // Hostname masks also match subdomains, so DOMAIN rules do too.
+        "DOMAIN" | "DOMAIN-SUFFIX" => (
+            "masks",
+            vec![payload.trim_start_matches("+.").trim_start_matches('.')],
+        ),
+        "DOMAIN-KEYWORD" => ("domain_keywords", vec![*payload]),
+        "IP-CIDR" | "IP-CIDR6" => ("masks", vec![*payload]),
+        "SRC-IP-CIDR" => ("source_ips", vec![*payload]),
+        "DST-PORT" => ("ports", payload.split('/').collect()),
+        "GEOIP" => ("geoip", vec![*payload]),
+        "GEOSITE" => ("geosite", vec![*payload]),
+        _ => return Err(format!("unsupported rule type {kind}")),
+    };
+    let values = values
+        .into_iter()
+        .map(|value| match field {
+            "geoip" | "geosite" => Value::from(value.to_ascii_lowercase()),
+            _ => Value::from(value),
+        })
+        .collect();
+    Ok((field, values, target.to_string()))
+}
+
+/// Converts a Clash proxy into a client config.
+pub(super) fn convert_proxy(proxy: &Value) -> Result<Value, String> {
+    let proxy_type = get_str(proxy, "type").ok_or("missing type")?;
+    let server = get_str(proxy, "server").ok_or("missing server")?;
+    let port = get_u64(proxy, "port").ok_or("missing port")?;
+    let udp = get_bool(proxy, "udp").map_or(Value::Null, Value::Bool);
+    let field = |key: &str| opt_str(get_str(proxy, key));
+
+    let tls_enabled = get_bool(proxy, "tls").unwrap_or(false);
+    let mut transport = None;
+    let mut transport_value = Value::Null;
+    let mut quic_settings = Value::Null;
+
+    let protocol = match proxy_type.as_str() {
+        "ss" => {
+            let (plugin, plugin_opts, shadow_tls) = convert_ss_plugin(proxy)?;
+            let protocol = mapping([
+                ("type", Value::from("shadowsocks")),
+                ("cipher", field("cipher")),
+                ("password", field("password")),
+                ("udp_enabled", udp),
+                ("plugin", plugin),
+                ("plugin_opts", plugin_opts),
+            ]);
+            match shadow_tls {
+                Some(mut shadow_tls) => {
+                    if let Some(m) = shadow_tls.as_mapping_mut() {
+                        m.insert(Value::from("protocol"), protocol);
+                    }
+                    shadow_tls
+                }
+                None => protocol,
+            }
+        }
+        "vmess" => {
+            if get_u64(proxy, "alterId").is_some_and(|alter_id| alter_id != 0) {
+                return Err("legacy VMess with alterId is not supported".to_string());
+            }
+            transport = convert_transport(proxy)?;
+            mapping([
+                ("type", Value::from("vmess")),
+                (
+                    "cipher",
+                    Value::from(get_str(proxy, "cipher").unwrap_or_else(|| "auto".to_string())),
+                ),
+                ("user_id", field("uuid")),
+                ("udp_enabled", udp),
+            ])
+        }
+        "vless" => {
+            transport = convert_transport(proxy)?;
+            mapping([
+                ("type", Value::from("vless")),
+                ("user_id", field("uuid")),
+                ("udp_enabled", udp),
+                (
+                    "flow",
+                    opt_str(get_str(proxy, "flow").filter(|flow| !flow.is_empty())),
+                ),
+            ])
+        }
+        "trojan" => {
+            transport = convert_transport(proxy)?;
+            mapping([
+                ("type", Value::from("trojan")),
+                ("password", field("password")),
+            ])
+        }
+        "socks5" => mapping([
+            ("type", Value::from("socks")),
+            ("username", field("username")),
+            ("password", field("password")),
+            ("udp_enabled", udp),
+        ]),
+        "http" => mapping([
+            ("type", Value::from("http")),
+            ("username", field("username")),
+            ("password", field("password")),
+            ("headers", proxy.get("headers").cloned().unwrap_or_default()),
+        ]),
+        "snell" => {
+            let version = get_u64(proxy, "version").unwrap_or(1);
+            if proxy.get("obfs-opts").is_some() {
+                return Err("snell obfs is not supported".to_string());
+            }
+            mapping([
+                ("type", Value::from("snell")),
+                ("cipher", Value::from("aes-128-gcm")),
+                ("password", field("psk")),
+                ("version", Value::from(version)),
+                ("udp_enabled", udp),
+            ])
+        }
+        "anytls" => mapping([
+            ("type", Value::from("anytls")),
+            ("password", field("password")),
+            ("udp_enabled", udp),
+        ]),
+        "hysteria2" => {
+            transport_value = Value::from("quic");
+            quic_settings = mapping([
+                (
+                    "verify",
+                    if get_bool(proxy, "skip-cert-verify").unwrap_or(false) {
+                        Value::Bool(false)
+                    } else {
+                        Value::Null
+                    },
+                ),
+                ("sni_hostname", field("sni")),
+                ("alpn_protocols", alpn(proxy)),
+            ]);
+            let obfs = match get_str(proxy, "obfs").as_deref() {
+                None | Some("") => Value::Null,
+                Some("salamander") => mapping([
+                    ("type", Value::from("salamander")),
+                    ("password", field("obfs-password")),
+                ]),
+                Some(obfs) => return Err(format!("unsupported obfs {obfs}")),
+            };
+            let bandwidth = mapping([("up", field("up")), ("down", field("down"))]);
+            mapping([
+                ("type", Value::from("hysteria2")),
+                ("password", field("password")),
+                ("udp_enabled", udp),
+                ("obfs", obfs),
+                (
+                    "bandwidth",
+                    if bandwidth.as_mapping().is_some_and(Mapping::is_empty) {
+                        Value::Null
+                    } else {
+                        bandwidth
+                    },
+                ),
+            ])
+        }
+        "ssh" => {
+            let host_key_fingerprints = get_str_list(proxy, "host-key")
+                .iter()
+                .map(|host_key| host_key_fingerprint(host_key))
+                .collect::<Result<Vec<_>, _>>()?;
+            mapping([
+                ("type", Value::from("ssh")),
+                ("username", field("username")),
+                ("password", field("password")),
+                ("private_key", field("private-key")),
+                ("private_key_passphrase", field("private-key-passphrase")),
+                ("verify_host_key", verify_host_key(&host_key_fingerprints)),
+                ("host_key_fingerprints", Value::from(host_key_fingerprints)),
+            ])
+        }
+        "wireguard" => {
+            let mut local_addresses = vec![];
+            if let Some(ip) = get_str(proxy, "ip") {
+                local_addresses.push(with_prefix(ip, "32"));
+            }
+            if let Some(ipv6) = get_str(proxy, "ipv6") {
+                local_addresses.push(with_prefix(ipv6, "128"));
+            }
+            mapping([
+                ("type", Value::from("wireguard")),
+                ("private_key", field("private-key")),
+                ("peer_public_key", field("public-key")),
+                ("preshared_key", field("pre-shared-key")),
+                ("local_addresses", Value::from(local_addresses)),
+                (
+                    "allowed_ips",
+                    proxy.get("allowed-ips").cloned().unwrap_or_default(),
+                ),
+                (
+                    "reserved",
+                    proxy.get("reserved").cloned().unwrap_or_default(),
+                ),
+                (
+                    "mtu",
+                    get_u64(proxy, "mtu").map_or(Value::Null, Value::from),
+                ),
+                ("udp_enabled", udp),
+            ])
+        }
+        other => return Err(format!("unsupported proxy type {other}")),
+    };
+
+    let reality = proxy.get("reality-opts").map(|opts| {
+        (
+            get_str(opts, "public-key").unwrap_or_default(),
+            get_str(opts, "short-id"),
+        )
+    });
+    let tls = if matches!(proxy_type.as_str(), "trojan" | "anytls")
+        || (tls_enabled && proxy_type != "hysteria2")
+        || reality.is_some()
+    {
+        Some(TlsLayer {
+            sni: get_str(proxy, "servername").or_else(|| get_str(proxy, "sni")),
+            insecure: get_bool(proxy, "skip-cert-verify").unwrap_or(false),
+            alpn: get_str_list(proxy, "alpn"),
+            fingerprint: get_str(proxy, "client-fingerprint"),
+            reality,
+        })
+    } else {
+        None
+    };
+
+    Ok(mapping([
+        ("address", Value::from(server_address(&server, port))),
+        ("transport", transport_value),
+        ("quic_settings", quic_settings),
+        ("protocol", wrap_protocol(protocol, transport, tls)),
+    ]))
+}
+
+/// Converts the `network` of a VMess, VLESS or Trojan proxy.
+fn convert_transport(proxy: &Value) -> Result<Option<TransportLayer>, String> {
+    match get_str(proxy, "network").as_deref() {
+        None | Some("tcp") | Some("") => Ok(None),
+        Some("ws") => {
+            let opts = proxy.get("ws-opts").cloned().unwrap_or_default();
+            let mut headers = opts
+                .get("headers")
+                .and_then(Value::as_mapping)
+                .cloned()
+                .unwrap_or_default();
+            let host = headers
+                .remove("Host")
+                .or_else(|| headers.remove("host"))
+                .and_then(|host| host.as_str().map(str::to_string));
+            if get_bool(&opts, "v2ray-http-upgrade").unwrap_or(false) {
+                return Ok(Some(TransportLayer::HttpUpgrade {
+                    path: get_str(&opts, "path"),
+                    host,
+                }));
+            }
+            Ok(Some(TransportLayer::Websocket {
+                path: get_str(&opts, "path"),
+                host,
+                headers,
+                max_early_data: get_u64(&opts, "max-early-data"),
+            }))
+        }
+        Some("grpc") => {
+            let opts = proxy.get("grpc-opts").cloned().unwrap_or_default();
+            Ok(Some(TransportLayer::Grpc {
+                service_name: get_str(&opts, "grpc-service-name"),
+                host: None,
+            }))
+        }
+        Some(network) => Err(format!("unsupported network {network}")),
+    }
+}
+
+/// Converts the plugin of a Shadowsocks proxy into a SIP003 plugin, or into a ShadowTLS
+/// layer for the `shadow-tls` plugin.
+fn convert_ss_plugin(proxy: &Value) -> Result<(Value, Value, Option<Value>), String> {
+    let opts = proxy.get("plugin-opts").cloned().unwrap_or_default();
+    let option = |key: &str| get_str(&opts, key);
+    match get_str(proxy, "plugin").as_deref() {
+        None | Some("") => Ok((Value::Null, Value::Null, None)),
+        Some("obfs") => {
+            let mut plugin_opts = format!(
+                "obfs={}",
+                option("mode").unwrap_or_else(|| "http".to_string())
+            );
+            if let Some(host) = option("host") {
+                plugin_opts.push_str(&format!(";obfs-host={host}"));
+            }
+            Ok((Value::from("obfs-local"), Value::from(plugin_opts), None))
+        }
+        Some("v2ray-plugin") => {
+            let mut plugin_opts = vec![];
+            if get_bool(&opts, "tls").unwrap_or(false) {
+                plugin_opts.push("tls".to_string());
+            }
+            if let Some(host) = option("host") {
+                plugin_opts.push(format!("host={host}"));
+            }
+            if let Some(path) = option("path") {
+                plugin_opts.push(format!("path={path}"));
+            }
+            Ok((
+                Value::from("v2ray-plugin"),
+                Value::from(plugin_opts.join(";")),
+                None,
+            ))
+        }
+        Some("shadow-tls") => {
+            if get_u64(&opts, "version").is_some_and(|version| version != 3) {
+                return Err("only ShadowTLS v3 is supported".to_string());
+            }
+            let shadow_tls = mapping([
+                ("type", Value::from("shadowtls")),
+                ("password", opt_str(option("password"))),
+                ("sni_hostname", opt_str(option("host"))),
+            ]);
+            Ok((Value::Null, Value::Null, Some(shadow_tls)))
+        }
+        Some(plugin) => Err(format!("unsupported plugin {plugin}")),
+    }
+}
+
+fn alpn(proxy: &Value) -> Value {
+    let alpn = get_str_list(proxy, "alpn");
+    if alpn.is_empty() {
+        Value::Null
+    } else {
+        Value::from(alpn)
+    }
+}
+
+fn with_prefix(ip: String, bits: &str) -> String {
+    if ip.contains('/') {
+        ip
+    } else {
+        format!("{ip}/{bits}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientProxyConfig, RuleActionConfig, ServerProxyConfig};
+
+    const CLASH_CONFIG: &str = r#"
+mixed-port: 7890
+allow-lan: true
+authentication: ["user:pass"]
+proxies:
+  - name: ss1
+    type: ss
+    server: 192.0.2.1
+    port: 8388
+    cipher: aes-128-gcm
+    password: secret
+  - name: vmess-ws
+    type: vmess
+    server: vmess.example.com
+    port: 443
+    uuid: b831381d-6324-4d53-ad4f-8cda48b30811
+    alterId: 0
+    tls: true
+    servername: cdn.example.com
+    network: ws
+    ws-opts:
+      path: /ray
+      headers:
+        Host: cdn.example.com
+  - name: legacy
+    type: ssr
+    server: 192.0.2.2
+    port: 1
+proxy-groups:
+  - name: auto
+    type: url-test
+    proxies: [ss1, vmess-ws]
+    url: http://www.gstatic.com/generate_204
+    interval: 300
+  - name: relay
+    type: relay
+    proxies: [ss1, vmess-ws]
+rules:
+  - DOMAIN-SUFFIX,google.com,auto
+  - DOMAIN,youtube.com,auto
+  - DOMAIN-KEYWORD,ads,REJECT
+  - PROCESS-NAME,curl,DIRECT
+  - IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+  - DST-PORT,22,relay
+  - GEOIP,CN,legacy
+  - MATCH,ss1
+"#;
+
+    #[test]
+    fn test_convert_clash_config() {
+        let converted = convert_clash_config(CLASH_CONFIG).unwrap();
+        assert_eq!(converted.warnings.len(), 3, "{:?}", converted.warnings);
+        assert!(converted.warnings[0].starts_with("Skipped proxy legacy"));
+        assert!(converted.warnings[1].starts_with("Skipped rule PROCESS-NAME"));
+        assert!(converted.warnings[2].starts_with("Skipped rule GEOIP"));
+
+        let configs = converted.configs;
+        assert_eq!(configs.len(), 4);
+        match &configs[1] {
+            Config::ClientConfigGroup(group) => {
+                assert_eq!(group.client_group, "vmess-ws");
+                let client = match group.client_proxies.iter().next() {
+                    Some(crate::config::ConfigSelection::Config(client)) => client,
+                    _ => panic!("Expected inline client"),
+                };
+                assert!(matches!(client.protocol, ClientProxyConfig::Tls(_)));
+            }
+            _ => panic!("Expected client group"),
+        }
+
+        let Config::RuleConfigGroup(group) = &configs[2] else {
+            panic!("Expected rule group");
+        };
+        let rules: Vec<&RuleConfig> = group.rules.iter().collect();
+        // The google.com and youtube.com rules are merged.
+        assert_eq!(rules.len(), 5);
+        assert_eq!(rules[0].masks.len(), 2);
+        match &rules[0].action {
+            RuleActionConfig::Allow {
+                client_chains,
+                balancer,
+                health_check,
+                ..
+            } => {
+                assert_eq!(client_chains.len(), 2);
+                assert_eq!(*balancer, crate::config::BalanceStrategy::UrlTest);
+                assert_eq!(health_check.as_ref().unwrap().interval_secs, 300);
+            }
+            RuleActionConfig::Block => panic!("Expected allow rule"),
+        }
+        assert!(matches!(rules[1].action, RuleActionConfig::Block));
+        match &rules[3].action {
+            RuleActionConfig::Allow { client_chains, .. } => {
+                let chain = client_chains.iter().next().unwrap();
+                assert_eq!(chain.hops.len(), 2);
+            }
+            RuleActionConfig::Block => panic!("Expected allow rule"),
+        }
+
+        match &configs[3] {
+            Config::Server(server) => {
+                assert_eq!(server.bind_location.to_string(), "0.0.0.0:7890");
+                assert!(matches!(
+                    &server.protocol,
+                    ServerProxyConfig::Mixed { username: Some(username), .. } if username == "user"
+                ));
+            }
+            _ => panic!("Expected server"),
+        }
+    }
+
+    #[test]
+    fn test_convert_clash_config_unmatched_direct() {
+        let converted = convert_clash_config("rules:\n  - DOMAIN,example.com,REJECT\n").unwrap();
+        let Config::RuleConfigGroup(group) = &converted.configs[0] else {
+            panic!("Expected rule group");
+        };
+        let rules: Vec<&RuleConfig> = group.rules.iter().collect();
+        assert_eq!(rules.len(), 2);
+        assert!(matches!(rules[1].action, RuleActionConfig::Allow { .. }));
+
+        assert!(convert_clash_config("- not a mapping").is_err());
+    }
+
+    #[test]
+    fn test_convert_ssh_host_key() {
+        let proxy: Value = serde_yaml::from_str(
+            "{type: ssh, server: 192.0.2.2, port: 22, username: root, password: secret, host-key: [\"ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4UzRMijUQge+SWupQlx6CrjkRRVTfX9yhyV+uvNhNo\"]}",
+        )
+        .unwrap();
+        let protocol = &convert_proxy(&proxy).unwrap()["protocol"];
+        assert_eq!(
+            protocol["host_key_fingerprints"][0],
+            "SHA256:noGoI9DR1xuyJQ/nt/PhfV99AYOihJ/bbwA4kbONHvI"
+        );
+        assert!(protocol.get("verify_host_key").is_none());
+
+        let proxy: Value = serde_yaml::from_str(
+            "{type: ssh, server: 192.0.2.2, port: 22, username: root, password: secret}",
+        )
+        .unwrap();
+        let protocol = &convert_proxy(&proxy).unwrap()["protocol"];
+        assert_eq!(protocol["verify_host_key"], false);
+    }
+}
@@ -0,0 +1,665 @@
+//! Utilities for converting the configs of other proxies.
+//!
+//! Converters read the foreign config as a [`serde_yaml::Value`] tree, which also covers
+//! JSON, and write shoes config entries as values in the format of shoes config files.
+//! The entries are then parsed like entries of a config file, so that conversions are
+//! checked by the same code.
+
+use std::collections::HashMap;
+
+use russh::keys::{HashAlg, PublicKey};
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+
+use super::types::{ClientConfig, Config};
+
+/// Configs converted from another proxy's config.
+#[derive(Debug, Clone, Default)]
+pub struct ConvertedConfigs {
+    pub configs: Vec<Config>,
+    /// Entries of the source config that were skipped or only partially converted.
+    pub warnings: Vec<String>,
+}
+
+/// Builds a mapping of the entries whose values are not null.
+pub(crate) fn mapping<const N: usize>(entries: [(&str, Value); N]) -> Value {
+    let mut mapping = Mapping::new();
+    for (key, value) in entries {
+        if !value.is_null() {
+            mapping.insert(Value::from(key), value);
+        }
+    }
+    Value::Mapping(mapping)
+}
+
+/// Returns a value for an optional string.
+pub(crate) fn opt_str(value: Option<String>) -> Value {
+    value.map_or(Value::Null, Value::String)
+}
+
+/// Returns a string field. Numbers and booleans are accepted too, since passwords and
+/// ports are often written without quotes.
+pub(crate) fn get_str(value: &Value, key: &str) -> Option<String> {
+    match value.get(key)? {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+/// Returns a boolean field, also accepting the strings `true` and `false`.
+pub(crate) fn get_bool(value: &Value, key: &str) -> Option<bool> {
+    match value.get(key)? {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns an unsigned integer field, also accepting numeric strings.
+pub(crate) fn get_u64(value: &Value, key: &str) -> Option<u64> {
+    match value.get(key)? {
+        Value::Number(n) => n.as_u64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// Returns a field that is either a string or a list of strings.
+pub(crate) fn get_str_list(value: &Value, key: &str) -> Vec<String> {
+    match value.get(key) {
+        Some(Value::String(s)) => vec![s.clone()],
+        Some(Value::Sequence(values)) => values
+            .iter()
+            .filter_map(|value| match value {
+                Value::String(s) => Some(s.clone()),
+                Value::Number(n) => Some(n.to_string()),
+                _ => None,
+            })
+            .collect(),
+        _ => vec![],
+    }
+}
+
+/// Returns the `client_fingerprint` for a uTLS fingerprint name, if shoes imitates that
+/// browser.
+pub(crate) fn client_fingerprint(name: &str) -> Option<&'static str> {
+    match name.to_ascii_lowercase().as_str() {
+        "chrome" | "edge" | "360" | "qq" => Some("chrome"),
+        "firefox" => Some("firefox"),
+        "safari" | "ios" => Some("safari"),
+        "random" | "randomized" => Some("random"),
+        _ => None,
+    }
+}
+
+/// TLS settings of an outbound.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TlsLayer {
+    pub sni: Option<String>,
+    pub insecure: bool,
+    pub alpn: Vec<String>,
+    pub fingerprint: Option<String>,
+    /// Reality public key and short ID, instead of a TLS server certificate.
+    pub reality: Option<(String, Option<String>)>,
+}
+
+/// Transport of an outbound between TLS and the proxy protocol.
+#[derive(Debug, Clone)]
+pub(crate) enum TransportLayer {
+    Websocket {
+        path: Option<String>,
+        host: Option<String>,
+        headers: Mapping,
+        max_early_data: Option<u64>,
+    },
+    Grpc {
+        service_name: Option<String>,
+        host: Option<String>,
+    },
+    HttpUpgrade {
+        path: Option<String>,
+        host: Option<String>,
+    },
+}
+
+/// Wraps a proxy protocol in a transport and TLS layer.
+pub(crate) fn wrap_protocol(
+    protocol: Value,
+    transport: Option<TransportLayer>,
+    tls: Option<TlsLayer>,
+) -> Value {
+    let is_grpc = matches!(transport, Some(TransportLayer::Grpc { .. }));
+    let protocol = match transport {
+        None => protocol,
+        Some(TransportLayer::Websocket {
+            path,
+            host,
+            headers,
+            max_early_data,
+        }) => mapping([
+            ("type", Value::from("websocket")),
+            ("matching_path", opt_str(path)),
+            ("host", opt_str(host)),
+            (
+                "matching_headers",
+                if headers.is_empty() {
+                    Value::Null
+                } else {
+                    Value::Mapping(headers)
+                },
+            ),
+            (
+                "max_early_data",
+                max_early_data.map_or(Value::Null, Value::from),
+            ),
+            ("protocol", protocol),
+        ]),
+        Some(TransportLayer::Grpc { service_name, host }) => mapping([
+            ("type", Value::from("grpc")),
+            ("service_name", opt_str(service_name)),
+            ("host", opt_str(host)),
+            ("protocol", protocol),
+        ]),
+        Some(TransportLayer::HttpUpgrade { path, host }) => mapping([
+            ("type", Value::from("httpupgrade")),
+            ("path", opt_str(path)),
+            ("host", opt_str(host)),
+            ("protocol", protocol),
+        ]),
+    };
+
+    let Some(tls) = tls else {
+        return protocol;
+    };
+    let fingerprint = tls
+        .fingerprint
+        .as_deref()
+        .and_then(client_fingerprint)
+        .map_or(Value::Null, Value::from);
+    if let Some((public_key, short_id)) = tls.reality {
+        return mapping([
+            ("type", Value::from("reality")),
+            ("public_key", Value::String(public_key)),
+            ("short_id", opt_str(short_id)),
+            ("sni_hostname", opt_str(tls.sni)),
+            ("client_fingerprint", fingerprint),
+            ("protocol", protocol),
+        ]);
+    }
+    let mut alpn = tls.alpn;
+    if is_grpc && alpn.is_empty() {
+        alpn.push("h2".to_string());
+    }
+    mapping([
+        ("type", Value::from("tls")),
+        (
+            "verify",
+            if tls.insecure {
+                Value::Bool(false)
+            } else {
+                Value::Null
+            },
+        ),
+        ("sni_hostname", opt_str(tls.sni)),
+        (
+            "alpn_protocols",
+            if alpn.is_empty() {
+                Value::Null
+            } else {
+                Value::from(alpn)
+            },
+        ),
+        ("client_fingerprint", fingerprint),
+        ("protocol", protocol),
+    ])
+}
+
+/// Returns the address of a server, e.g. `example.com:443` or `[2001:db8::1]:443`.
+pub(crate) fn server_address(server: &str, port: u64) -> String {
+    let server = server
+        .strip_prefix('[')
+        .and_then(|server| server.strip_suffix(']'))
+        .unwrap_or(server);
+    if server.contains(':') {
+        format!("[{server}]:{port}")
+    } else {
+        format!("{server}:{port}")
+    }
+}
+
+/// Returns the fingerprint of an OpenSSH public key, e.g. `ssh-ed25519 AAAA...`.
+pub(crate) fn host_key_fingerprint(host_key: &str) -> Result<String, String> {
+    let key = PublicKey::from_openssh(host_key).map_err(|e| format!("invalid host_key: {e}"))?;
+    Ok(key.fingerprint(HashAlg::Sha256).to_string())
+}
+
+/// Returns the `verify_host_key` field of an SSH client, which is disabled when the
+/// source config accepts any host key.
+pub(crate) fn verify_host_key(host_key_fingerprints: &[String]) -> Value {
+    if host_key_fingerprints.is_empty() {
+        Value::Bool(false)
+    } else {
+        Value::Null
+    }
+}
+
+/// Parses a converted entry like an entry of a config file.
+pub(crate) fn parse_entry<T: DeserializeOwned>(entry: Value) -> Result<T, String> {
+    serde_yaml::from_value(entry).map_err(|e| e.to_string())
+}
+
+/// Parses a JSON config, which may have `//` and `/* */` comments like Xray configs.
+pub(crate) fn parse_json_config(config_str: &str, format: &str) -> std::io::Result<Value> {
+    let config: Value = serde_json::from_str(&strip_json_comments(config_str)).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid {format} config: {e}"),
+        )
+    })?;
+    if !config.is_mapping() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid {format} config: expected an object"),
+        ));
+    }
+    Ok(config)
+}
+
+/// Removes the comments of a JSON document, keeping comment markers inside strings.
+fn strip_json_comments(json: &str) -> String {
+    let mut stripped = String::with_capacity(json.len());
+    let mut chars = json.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            stripped.push(c);
+            match c {
+                '\\' => stripped.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek().copied()) {
+            ('"', _) => {
+                in_string = true;
+                stripped.push(c);
+            }
+            ('/', Some('/')) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        stripped.push(c);
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+                stripped.push(' ');
+            }
+            _ => stripped.push(c),
+        }
+    }
+    stripped
+}
+
+/// Returns a list field, or an empty list if it is missing.
+pub(crate) fn sequence<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value
+        .get(key)
+        .and_then(Value::as_sequence)
+        .map_or(&[], Vec::as_slice)
+}
+
+/// Returns a Go duration like `3m` or `1m30s` in seconds.
+pub(crate) fn parse_duration_secs(duration: &str) -> Option<u64> {
+    let mut millis = 0u64;
+    let mut rest = duration.trim();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let value: u64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        millis += value
+            * match &rest[..unit] {
+                "ms" => 1,
+                "" | "s" => 1000,
+                "m" => 60_000,
+                "h" => 3_600_000,
+                _ => return None,
+            };
+        rest = &rest[unit..];
+    }
+    Some(millis.div_ceil(1000))
+}
+
+/// Parses a converted client config and returns the client group named after the
+/// outbound.
+pub(crate) fn client_group(name: &str, client: Value) -> Result<Config, String> {
+    parse_entry::<ClientConfig>(client.clone())?;
+    parse_entry::<Config>(mapping([
+        ("client_group", Value::from(name)),
+        ("client_proxies", client),
+    ]))
+}
+
+/// Returns a client config that connects directly.
+pub(crate) fn direct_client() -> Value {
+    mapping([("protocol", mapping([("type", Value::from("direct"))]))])
+}
+
+/// TLS settings of an inbound.
+#[derive(Debug, Clone)]
+pub(crate) enum ServerTlsLayer {
+    Tls {
+        cert: String,
+        key: String,
+        alpn: Vec<String>,
+    },
+    /// Reality, with a target for each server name that clients may request.
+    Reality {
+        server_names: Vec<String>,
+        private_key: String,
+        short_ids: Vec<String>,
+        dest: String,
+    },
+}
+
+/// Wraps a server protocol in a transport and TLS layer.
+pub(crate) fn wrap_server_protocol(
+    protocol: Value,
+    transport: Option<TransportLayer>,
+    tls: Option<ServerTlsLayer>,
+) -> Value {
+    let is_grpc = matches!(transport, Some(TransportLayer::Grpc { .. }));
+    let protocol = match transport {
+        None => protocol,
+        Some(TransportLayer::Websocket { path, host, .. }) => mapping([
+            ("type", Value::from("websocket")),
+            (
+                "targets",
+                Value::Sequence(vec![mapping([
+                    ("matching_path", opt_str(path)),
+                    ("matching_host", opt_str(host)),
+                    ("protocol", protocol),
+                ])]),
+            ),
+        ]),
+        Some(TransportLayer::Grpc { service_name, .. }) => mapping([
+            ("type", Value::from("grpc")),
+            ("service_name", opt_str(service_name)),
+            ("protocol", protocol),
+        ]),
+        Some(TransportLayer::HttpUpgrade { path, host }) => mapping([
+            ("type", Value::from("httpupgrade")),
+            ("path", opt_str(path)),
+            ("host", opt_str(host)),
+            ("protocol", protocol),
+        ]),
+    };
+
+    match tls {
+        None => protocol,
+        Some(ServerTlsLayer::Tls {
+            cert,
+            key,
+            mut alpn,
+        }) => {
+            if is_grpc && alpn.is_empty() {
+                alpn.push("h2".to_string());
+            }
+            mapping([
+                ("type", Value::from("tls")),
+                (
+                    "default_tls_target",
+                    mapping([
+                        ("cert", Value::String(cert)),
+                        ("key", Value::String(key)),
+                        (
+                            "alpn_protocols",
+                            if alpn.is_empty() {
+                                Value::Null
+                            } else {
+                                Value::from(alpn)
+                            },
+                        ),
+                        ("protocol", protocol),
+                    ]),
+                ),
+            ])
+        }
+        Some(ServerTlsLayer::Reality {
+            server_names,
+            private_key,
+            short_ids,
+            dest,
+        }) => {
+            let mut targets = Mapping::new();
+            for server_name in server_names {
+                targets.insert(
+                    Value::String(server_name),
+                    mapping([
+                        ("private_key", Value::from(private_key.as_str())),
+                        (
+                            "short_ids",
+                            if short_ids.is_empty() {
+                                Value::Null
+                            } else {
+                                Value::from(short_ids.clone())
+                            },
+                        ),
+                        ("dest", Value::from(dest.as_str())),
+                        ("protocol", protocol.clone()),
+                    ]),
+                );
+            }
+            mapping([
+                ("type", Value::from("tls")),
+                ("reality_targets", Value::Mapping(targets)),
+            ])
+        }
+    }
+}
+
+/// Destination matchers of a rule of another proxy. Unlike the matchers of a shoes rule,
+/// which all have to match, a destination only has to match one kind of them.
+#[derive(Debug, Default)]
+pub(crate) struct RuleMatchers {
+    pub masks: Vec<String>,
+    pub domain_keywords: Vec<String>,
+    pub geosite: Vec<String>,
+    pub geoip: Vec<String>,
+    /// Ports and source IPs, which have to match as well.
+    pub ports: Vec<String>,
+    pub source_ips: Vec<String>,
+}
+
+impl RuleMatchers {
+    /// Returns a shoes rule for each kind of destination matcher, or a single rule when
+    /// any destination matches.
+    pub(crate) fn into_rules(self, action: &Mapping) -> Vec<Value> {
+        let other_matchers = [("ports", self.ports), ("source_ips", self.source_ips)];
+        let mut destinations = vec![
+            ("masks", self.masks),
+            ("domain_keywords", self.domain_keywords),
+            ("geosite", self.geosite),
+            ("geoip", self.geoip),
+        ];
+        destinations.retain(|(_, values)| !values.is_empty());
+        if destinations.is_empty() {
+            // Rules with only other matchers match any destination already.
+            let masks = if other_matchers.iter().all(|(_, values)| values.is_empty()) {
+                vec![MATCH_ALL.to_string()]
+            } else {
+                vec![]
+            };
+            destinations.push(("masks", masks));
+        }
+
+        destinations
+            .into_iter()
+            .map(|(field, values)| {
+                let mut rule = Mapping::new();
+                if !values.is_empty() {
+                    rule.insert(Value::from(field), Value::from(values));
+                }
+                for (field, values) in other_matchers.iter() {
+                    if !values.is_empty() {
+                        rule.insert(Value::from(*field), Value::from(values.clone()));
+                    }
+                }
+                rule.extend(action.clone());
+                Value::Mapping(rule)
+            })
+            .collect()
+    }
+}
+
+/// Mask of the rules that match all destinations.
+pub(crate) const MATCH_ALL: &str = "0.0.0.0/0";
+
+/// An outbound of another proxy, which rules send connections to by its tag.
+#[derive(Debug, Clone)]
+pub(crate) enum Outbound {
+    Direct,
+    Block,
+    /// A proxy, converted into a client group named after its tag. With a detour, it is
+    /// connected to through the detour.
+    Proxy {
+        detour: Option<String>,
+    },
+    /// A group of other outbounds, and the balancer that picks one of them.
+    Group {
+        members: Vec<String>,
+        balancer: &'static str,
+        health_check: Value,
+    },
+}
+
+/// Returns the action fields of a rule that connects directly.
+pub(crate) fn direct_action() -> Mapping {
+    let mut action = Mapping::new();
+    action.insert(Value::from("action"), Value::from("allow"));
+    action
+}
+
+/// Returns the action fields of a rule that blocks connections.
+pub(crate) fn block_action() -> Mapping {
+    let mut action = Mapping::new();
+    action.insert(Value::from("action"), Value::from("block"));
+    action
+}
+
+/// The converted outbounds of another proxy's config, by tag.
+#[derive(Debug, Default)]
+pub(crate) struct Outbounds(HashMap<String, Outbound>);
+
+impl Outbounds {
+    pub(crate) fn insert(&mut self, tag: String, outbound: Outbound) {
+        self.0.insert(tag, outbound);
+    }
+
+    /// Returns the tag of the outbound that a proxy connects through.
+    pub(crate) fn detour(&self, tag: &str) -> Option<&str> {
+        match self.0.get(tag) {
+            Some(Outbound::Proxy { detour }) => detour.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Returns the action fields of a rule that sends connections to the outbound.
+    pub(crate) fn action(&self, tag: &str) -> Result<Mapping, String> {
+        if matches!(self.0.get(tag), Some(Outbound::Block)) {
+            return Ok(block_action());
+        }
+        let mut action = direct_action();
+        if matches!(self.0.get(tag), Some(Outbound::Direct)) {
+            return Ok(action);
+        }
+
+        let chains = self.chains(tag, &mut vec![])?;
+        if chains.is_empty() {
+            return Err(format!("{tag} has no supported outbounds"));
+        }
+        if chains.len() > 1
+            && let Some(Outbound::Group {
+                balancer,
+                health_check,
+                ..
+            }) = self.0.get(tag)
+        {
+            action.insert(Value::from("balancer"), Value::from(*balancer));
+            if !health_check.is_null() {
+                action.insert(Value::from("health_check"), health_check.clone());
+            }
+        }
+        action.insert(Value::from("client_chains"), Value::Sequence(chains));
+        Ok(action)
+    }
+
+    /// Returns the client chains of an outbound. Groups are flattened into the chains of
+    /// their members.
+    fn chains(&self, tag: &str, visiting: &mut Vec<String>) -> Result<Vec<Value>, String> {
+        match self.0.get(tag) {
+            None => Err(format!("unknown or skipped outbound {tag}")),
+            Some(Outbound::Direct) => Ok(vec![direct_client()]),
+            Some(Outbound::Block) => Ok(vec![]),
+            Some(Outbound::Proxy { .. }) => Ok(vec![self.chain(tag)?]),
+            Some(Outbound::Group { members, .. }) => {
+                if visiting.iter().any(|visited| visited == tag) {
+                    return Err(format!("group {tag} contains itself"));
+                }
+                visiting.push(tag.to_string());
+                let mut chains: Vec<Value> = vec![];
+                for member in members {
+                    for chain in self.chains(member, visiting)? {
+                        if !chains.contains(&chain) {
+                            chains.push(chain);
+                        }
+                    }
+                }
+                visiting.pop();
+                Ok(chains)
+            }
+        }
+    }
+
+    /// Returns the chain through a proxy and the proxies it is connected to through.
+    fn chain(&self, tag: &str) -> Result<Value, String> {
+        let mut hops = vec![Value::from(tag)];
+        let mut current = tag;
+        while let Some(Outbound::Proxy {
+            detour: Some(detour),
+        }) = self.0.get(current)
+        {
+            match self.0.get(detour.as_str()) {
+                Some(Outbound::Direct) => break,
+                Some(Outbound::Proxy { .. }) if hops.len() <= self.0.len() => {
+                    hops.insert(0, Value::from(detour.as_str()));
+                }
+                Some(Outbound::Proxy { .. }) => {
+                    return Err(format!("detour of {tag} leads back to itself"));
+                }
+                _ => return Err(format!("detour {detour} of {current} is not a proxy")),
+            }
+            current = detour.as_str();
+        }
+        if hops.len() == 1 {
+            return Ok(hops.remove(0));
+        }
+        Ok(mapping([("chain", Value::Sequence(hops))]))
+    }
+}
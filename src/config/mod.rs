@@ -4,30 +4,99 @@
 //! - [`types`]: All configuration types (server, client, rules, etc.)
 //! - [`pem`]: PEM file handling and certificate loading
 //! - [`validate`]: Configuration validation and server config creation
+//! - [`report`]: Validation of config files without starting servers
+//! - [`clash`]: Clash and Clash.Meta configuration conversion
 //! - [`singbox`]: Sing-box JSON configuration conversion
+//! - [`xray`]: Xray and V2Ray JSON configuration conversion
+//! - [`share_link`]: Proxy share links like `vless://` and `ss://` URIs
+//! - [`subscription`]: Conversion of fetched subscriptions into client groups
 //! - [`convert_util`]: Utilities for preprocessing JSON-like configs
 //!
 //! The main entry points are:
-//! - [`load_configs`]: Load config files from disk
+//! - [`load_configs`]: Load config files from disk, with the files they include
 //! - [`convert_cert_paths`]: Convert PEM file paths to inline data
 //! - [`create_server_configs`]: Validate and create final server configs
-//! - [`singbox::convert_singbox_config`]: Convert sing-box configs to shoes format
+//! - [`validate()`]: Validate config files and report what would be started
+//! - [`convert_clash_config`]: Convert Clash configs to shoes format
+//! - [`convert_singbox_config`]: Convert sing-box configs to shoes format
+//! - [`convert_xray_config`]: Convert Xray configs to shoes format
+//! - [`parse_share_link`]: Parse a share link like `vless://...` into a client config
+//! - [`server_share_links`]: Create the share links that clients use to connect to a server
 
+mod clash;
+mod convert_util;
 mod pem;
+pub(crate) mod report;
+mod share_link;
+mod singbox;
+mod subscription;
 mod types;
 mod validate;
+mod xray;
 
+pub use clash::convert_clash_config;
+pub use convert_util::ConvertedConfigs;
 pub use pem::convert_cert_paths;
+pub use report::{ValidationReport, validate};
+#[allow(unused_imports)] // Used by library embedders
+pub use share_link::{parse_share_link, server_share_links};
+pub use singbox::convert_singbox_config;
+pub(crate) use subscription::{convert_subscription, subscription_chain, validate_subscription};
 pub use types::*;
 pub use validate::{create_server_configs, ValidatedConfigs};
+pub use xray::convert_xray_config;
+
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+
+use log::warn;
+use serde::Deserialize;
 
 /// Loads configuration files from the provided paths.
 ///
-/// Reads each file, parses it as YAML, and returns the combined list of configs.
+/// Reads each file, parses it as YAML, JSON or TOML depending on its extension, and returns
+/// the combined list of configs.
+/// `include` entries are replaced by the configs of the files they include.
 pub async fn load_configs(args: &Vec<String>) -> std::io::Result<Vec<Config>> {
+    load_configs_with_includes(args)
+        .await
+        .map(|(configs, _)| configs)
+}
+
+/// Loads configuration files like [`load_configs`], and also returns the paths of the
+/// included files, in the order they were read.
+pub async fn load_configs_with_includes(
+    args: &Vec<String>,
+) -> std::io::Result<(Vec<Config>, Vec<String>)> {
     let mut all_configs = vec![];
+    let mut included_paths = vec![];
     for config_filename in args {
-        let config_bytes = match tokio::fs::read(config_filename).await {
+        let mut including = vec![];
+        load_config_file(
+            PathBuf::from(config_filename),
+            &mut including,
+            &mut all_configs,
+            &mut included_paths,
+        )
+        .await?;
+    }
+
+    Ok((all_configs, included_paths))
+}
+
+/// Loads a config file and, in place of its `include` entries, the files they include.
+///
+/// `including` holds the files that are being loaded, to reject include cycles.
+fn load_config_file<'a>(
+    path: PathBuf,
+    including: &'a mut Vec<PathBuf>,
+    all_configs: &'a mut Vec<Config>,
+    included_paths: &'a mut Vec<String>,
+) -> Pin<Box<dyn Future<Output = std::io::Result<()>> + Send + 'a>> {
+    Box::pin(async move {
+        let config_filename = path.display();
+        let config_bytes = match tokio::fs::read(&path).await {
             Ok(b) => b,
             Err(e) => {
                 return Err(std::io::Error::new(
@@ -47,19 +116,139 @@ pub async fn load_configs(args: &Vec<String>) -> std::io::Result<Vec<Config>> {
             }
         };
 
-        let mut configs = match serde_yaml::from_str::<Vec<Config>>(&config_str) {
+        let format = ConfigFormat::from_path(&path);
+        let configs = match format.parse(&config_str) {
             Ok(c) => c,
             Err(e) => {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidInput,
-                    format!("Could not parse config file {config_filename} as config YAML: {e}"),
+                    format!(
+                        "Could not parse config file {config_filename} as config {format}: {e}"
+                    ),
                 ));
             }
         };
-        all_configs.append(&mut configs)
+
+        let canonical_path = tokio::fs::canonicalize(&path)
+            .await
+            .unwrap_or_else(|_| path.clone());
+        if including.contains(&canonical_path) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Config file {config_filename} includes itself"),
+            ));
+        }
+        including.push(canonical_path);
+
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        for config in configs {
+            let Config::Include(include) = config else {
+                all_configs.push(config);
+                continue;
+            };
+            for pattern in include.include.iter() {
+                for included_path in expand_include_pattern(base_dir, pattern)? {
+                    included_paths.push(included_path.display().to_string());
+                    load_config_file(included_path, including, all_configs, included_paths).await?;
+                }
+            }
+        }
+
+        including.pop();
+        Ok(())
+    })
+}
+
+/// Format of a config file, detected by its extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Yaml,
+    Json,
+    /// A table with a `config` array of tables, e.g. `[[config]]` for each entry.
+    Toml,
+}
+
+/// Entries of a TOML config file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfigFile {
+    #[serde(default)]
+    config: Vec<Config>,
+}
+
+impl ConfigFormat {
+    /// Files without a `.json` or `.toml` extension are YAML.
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some(extension) if extension.eq_ignore_ascii_case("json") => ConfigFormat::Json,
+            Some(extension) if extension.eq_ignore_ascii_case("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Yaml,
+        }
     }
 
-    Ok(all_configs)
+    fn parse(self, config_str: &str) -> Result<Vec<Config>, String> {
+        match self {
+            ConfigFormat::Yaml => {
+                serde_yaml::from_str::<Vec<Config>>(config_str).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Json => {
+                serde_json::from_str::<Vec<Config>>(config_str).map_err(|e| e.to_string())
+            }
+            ConfigFormat::Toml => toml::from_str::<TomlConfigFile>(config_str)
+                .map(|file| file.config)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ConfigFormat::Yaml => "YAML",
+            ConfigFormat::Json => "JSON",
+            ConfigFormat::Toml => "TOML",
+        })
+    }
+}
+
+/// Returns the files matching an include pattern, relative to `base_dir` unless it is
+/// absolute, in lexicographic order. Patterns without glob characters are returned as is,
+/// so that missing files are reported when they are read.
+fn expand_include_pattern(base_dir: &Path, pattern: &str) -> std::io::Result<Vec<PathBuf>> {
+    let is_glob = pattern.contains(['*', '?', '[']);
+    if !is_glob {
+        return Ok(vec![base_dir.join(pattern)]);
+    }
+
+    let full_pattern = if Path::new(pattern).is_absolute() {
+        pattern.to_string()
+    } else {
+        let base_dir = base_dir.to_str().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Config directory {} is not valid UTF8", base_dir.display()),
+            )
+        })?;
+        Path::new(&glob::Pattern::escape(base_dir))
+            .join(pattern)
+            .to_string_lossy()
+            .into_owned()
+    };
+
+    let paths = glob::glob(&full_pattern).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid include pattern {pattern}: {e}"),
+        )
+    })?;
+    let mut paths = paths
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(glob::GlobError::into_error)?;
+    paths.sort();
+    if paths.is_empty() {
+        warn!("Include pattern {full_pattern} did not match any files");
+    }
+    Ok(paths)
 }
 
 /// Load config from a string (used by FFI targets)
@@ -72,3 +261,74 @@ pub fn load_config_str(config_str: &str) -> std::io::Result<Vec<Config>> {
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_load_configs_with_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let main_path = write(
+            "main.yaml",
+            "- include: conf.d/*.yaml\n- worker_threads: 4\n- include: groups.yaml\n",
+        );
+        write("conf.d/b.yaml", "- fake_ip_range: 198.18.0.0/15\n");
+        write("conf.d/a.yaml", "- crypto_provider: ring\n");
+        write("groups.yaml", "- user_group: admins\n");
+
+        let (configs, included_paths) = load_configs_with_includes(&vec![main_path.clone()])
+            .await
+            .unwrap();
+        assert!(matches!(
+            configs.as_slice(),
+            [
+                Config::Crypto(_),
+                Config::FakeIp(_),
+                Config::Runtime(_),
+                Config::UserGroup(_),
+            ]
+        ));
+        assert_eq!(included_paths.len(), 3);
+        assert!(included_paths[0].ends_with("a.yaml"));
+
+        write("groups.yaml", "- include: main.yaml\n");
+        let result = load_configs(&vec![main_path]).await;
+        assert!(result.unwrap_err().to_string().contains("includes itself"));
+    }
+
+    #[tokio::test]
+    async fn test_load_configs_json_and_toml() {
+        let dir = tempfile::tempdir().unwrap();
+        let json_path = dir.path().join("main.json");
+        std::fs::write(
+            &json_path,
+            r#"[{"worker_threads": 2}, {"include": "crypto.toml"}]"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.path().join("crypto.toml"),
+            "[[config]]\ncrypto_provider = \"ring\"\n\n[[config]]\nuser_group = \"admins\"\n",
+        )
+        .unwrap();
+
+        let configs = load_configs(&vec![json_path.to_str().unwrap().to_string()])
+            .await
+            .unwrap();
+        assert!(matches!(
+            configs.as_slice(),
+            [Config::Runtime(_), Config::Crypto(_), Config::UserGroup(_)]
+        ));
+
+        let toml_path = dir.path().join("invalid.toml");
+        std::fs::write(&toml_path, "crypto_provider = \"ring\"\n").unwrap();
+        let result = load_configs(&vec![toml_path.to_str().unwrap().to_string()]).await;
+        assert!(result.unwrap_err().to_string().contains("as config TOML"));
+    }
+}
@@ -16,11 +16,13 @@ use crate::config::ConfigSelection;
 /// - Loads all named PEMs that point to files into data-backed PEMs
 /// - Converts inline file paths in cert/key fields to new named PEM items
 ///
-/// Returns the converted configs and the count of PEM files loaded.
-pub async fn convert_cert_paths(all_configs: Vec<Config>) -> std::io::Result<(Vec<Config>, usize)> {
+/// Returns the converted configs and the paths of the PEM files loaded.
+pub async fn convert_cert_paths(
+    all_configs: Vec<Config>,
+) -> std::io::Result<(Vec<Config>, Vec<String>)> {
     let mut path_pem_configs: HashMap<String, NamedPem> = HashMap::new();
     let mut data_configs = vec![];
-    let mut load_count = 0usize;
+    let mut loaded_paths = vec![];
     let mut server_configs = vec![];
     let mut client_group_configs = vec![];
     let mut other_configs = vec![];
@@ -44,7 +46,7 @@ pub async fn convert_cert_paths(all_configs: Vec<Config>) -> std::io::Result<(Ve
                     source: PemSource::Data(loaded_data),
                 };
 
-                load_count += 1;
+                loaded_paths.push(path.clone());
                 path_pem_configs.insert(path, loaded_config);
             }
             Config::Server(server_config) => {
@@ -88,10 +90,9 @@ pub async fn convert_cert_paths(all_configs: Vec<Config>) -> std::io::Result<(Ve
     }
 
     // Generate new named configs from inlined paths
-    load_count += unknown_pem_paths.len();
-
     for (path, new_name) in unknown_pem_paths.into_iter() {
         let data = read_pem_to_string(&path).await?;
+        loaded_paths.push(path);
         let loaded_config = NamedPem {
             pem: new_name,
             source: PemSource::Data(data),
@@ -109,7 +110,7 @@ pub async fn convert_cert_paths(all_configs: Vec<Config>) -> std::io::Result<(Ve
         updated_configs.push(Config::Server(server_config));
     }
 
-    Ok((updated_configs, load_count))
+    Ok((updated_configs, loaded_paths))
 }
 
 fn gather_pem_file_paths_from_server_config(
@@ -218,6 +219,13 @@ fn gather_pem_file_paths_from_server_proxy(
                     known_pem_paths,
                     unknown_pem_paths,
                 )?;
+                for alpn_protocol in tls_config.alpn_targets.values_mut() {
+                    gather_pem_file_paths_from_server_proxy(
+                        alpn_protocol,
+                        known_pem_paths,
+                        unknown_pem_paths,
+                    )?;
+                }
                 // Check override rules
                 for rule in tls_config.override_rules.iter_mut() {
                     gather_pem_file_paths_from_rule(rule, known_pem_paths, unknown_pem_paths);
@@ -245,6 +253,13 @@ fn gather_pem_file_paths_from_server_proxy(
                     known_pem_paths,
                     unknown_pem_paths,
                 )?;
+                for alpn_protocol in tls_config.alpn_targets.values_mut() {
+                    gather_pem_file_paths_from_server_proxy(
+                        alpn_protocol,
+                        known_pem_paths,
+                        unknown_pem_paths,
+                    )?;
+                }
                 // Check override rules
                 for rule in tls_config.override_rules.iter_mut() {
                     gather_pem_file_paths_from_rule(rule, known_pem_paths, unknown_pem_paths);
@@ -306,6 +321,53 @@ fn gather_pem_file_paths_from_server_proxy(
                 }
             }
         }
+        ServerProxyConfig::Dispatch { targets, fallback } => {
+            for target in targets.iter_mut() {
+                gather_pem_file_paths_from_server_proxy(
+                    &mut target.protocol,
+                    known_pem_paths,
+                    unknown_pem_paths,
+                )?;
+                for rule in target.override_rules.iter_mut() {
+                    gather_pem_file_paths_from_rule(rule, known_pem_paths, unknown_pem_paths);
+                }
+            }
+            if let Some(fallback) = fallback {
+                gather_pem_file_paths_from_server_proxy(
+                    fallback,
+                    known_pem_paths,
+                    unknown_pem_paths,
+                )?;
+            }
+        }
+        ServerProxyConfig::Grpc {
+            protocol,
+            override_rules,
+            ..
+        }
+        | ServerProxyConfig::HttpUpgrade {
+            protocol,
+            override_rules,
+            ..
+        }
+        | ServerProxyConfig::Xhttp {
+            protocol,
+            override_rules,
+            ..
+        }
+        | ServerProxyConfig::Obfs4 {
+            protocol,
+            override_rules,
+            ..
+        } => {
+            gather_pem_file_paths_from_server_proxy(protocol, known_pem_paths, unknown_pem_paths)?;
+            for rule in override_rules.iter_mut() {
+                gather_pem_file_paths_from_rule(rule, known_pem_paths, unknown_pem_paths);
+            }
+        }
+        ServerProxyConfig::Ssh { host_key, .. } => {
+            process_pem_path(host_key, known_pem_paths, unknown_pem_paths);
+        }
         _ => {}
     }
     Ok(())
@@ -410,6 +472,33 @@ fn gather_pem_file_paths_from_client_proxy(
                 unknown_pem_paths,
             );
         }
+        ClientProxyConfig::Grpc(grpc_config) => {
+            gather_pem_file_paths_from_client_proxy(
+                &mut grpc_config.protocol,
+                known_pem_paths,
+                unknown_pem_paths,
+            );
+        }
+        ClientProxyConfig::HttpUpgrade(httpupgrade_config) => {
+            gather_pem_file_paths_from_client_proxy(
+                &mut httpupgrade_config.protocol,
+                known_pem_paths,
+                unknown_pem_paths,
+            );
+        }
+        ClientProxyConfig::Xhttp(xhttp_config) => {
+            gather_pem_file_paths_from_client_proxy(
+                &mut xhttp_config.protocol,
+                known_pem_paths,
+                unknown_pem_paths,
+            );
+        }
+        ClientProxyConfig::Obfs4 { protocol, .. } => {
+            gather_pem_file_paths_from_client_proxy(protocol, known_pem_paths, unknown_pem_paths);
+        }
+        ClientProxyConfig::Ssh { private_key, .. } => {
+            process_optional_pem_path(private_key, known_pem_paths, unknown_pem_paths);
+        }
         _ => {}
     }
 }
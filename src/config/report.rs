@@ -0,0 +1,123 @@
+//! Validation of config files without starting servers.
+
+use super::subscription::replace_subscriptions;
+use super::types::Config;
+use super::{convert_cert_paths, create_server_configs, load_configs_with_includes};
+
+/// Summary of config files that passed validation.
+#[derive(Debug, Clone, Default)]
+pub struct ValidationReport {
+    /// Files that were included by the given config files.
+    pub included_files: Vec<String>,
+    /// Certificate and key files that were read.
+    pub pem_files: Vec<String>,
+    /// Listeners that would be started, e.g. `tcp://0.0.0.0:443` or `tun://tun0`.
+    pub listeners: Vec<String>,
+    /// Names of the DNS groups, including those created for inline DNS configs.
+    pub dns_groups: Vec<String>,
+}
+
+/// Validates config files like a startup would, without binding sockets or resolving
+/// hostnames.
+///
+/// Parses the files and the files they include, reads the certificate and key files, and
+/// checks the configs: references to client, rule, DNS and user groups, keys and UUIDs,
+/// and listeners that would bind the same port. Subscriptions are not fetched; their
+/// groups are checked as if they connected directly. Deployments can run it before rolling out
+/// a config, as `shoes --dry-run` does.
+pub async fn validate(paths: &Vec<String>) -> std::io::Result<ValidationReport> {
+    let (configs, included_files) = load_configs_with_includes(paths).await?;
+    let report = validate_configs(configs).await?;
+    Ok(ValidationReport {
+        included_files,
+        ..report
+    })
+}
+
+/// Validates configs that were built in code or loaded by the caller, like [`validate`]
+/// validates config files.
+pub async fn validate_configs(configs: Vec<Config>) -> std::io::Result<ValidationReport> {
+    let (configs, pem_files) = convert_cert_paths(configs).await?;
+    let configs = replace_subscriptions(configs)?;
+    let validated = create_server_configs(configs)?;
+
+    let listeners = validated
+        .configs
+        .iter()
+        .map(|config| match config {
+            Config::Server(s) => format!("{}://{}", s.transport, s.bind_location),
+            Config::TunServer(t) => {
+                format!("tun://{}", t.device_name.as_deref().unwrap_or("default"))
+            }
+            Config::Metrics(m) => format!("metrics://{}", m.metrics),
+            Config::ClashApi(c) => format!("clash_api://{}", c.clash_api),
+            Config::ReverseBridge(b) => format!("reverse_bridge://{}", b.reverse_bridge),
+            _ => unreachable!(
+                "create_server_configs only returns Server, TunServer, ReverseBridge, Metrics \
+                 and ClashApi"
+            ),
+        })
+        .collect();
+
+    Ok(ValidationReport {
+        included_files: vec![],
+        pem_files,
+        listeners,
+        dns_groups: validated
+            .dns_groups
+            .into_iter()
+            .map(|group| group.name)
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_validate() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        let paths = vec![path.to_str().unwrap().to_string()];
+
+        std::fs::write(
+            &path,
+            r#"
+- address: 127.0.0.1:1080
+  protocol:
+    type: socks
+- address: 0.0.0.0:8080
+  protocol:
+    type: http
+- metrics: 127.0.0.1:9100
+"#,
+        )
+        .unwrap();
+        let report = validate(&paths).await.unwrap();
+        assert_eq!(
+            report.listeners,
+            vec![
+                "tcp://127.0.0.1:1080",
+                "tcp://0.0.0.0:8080",
+                "metrics://127.0.0.1:9100",
+            ]
+        );
+        assert!(report.pem_files.is_empty());
+
+        std::fs::write(
+            &path,
+            r#"
+- address: 127.0.0.1:1080
+  protocol:
+    type: socks
+- address: 0.0.0.0:1080
+  protocol:
+    type: http
+"#,
+        )
+        .unwrap();
+        let error = validate(&paths).await.unwrap_err();
+        assert!(error.to_string().contains("both bind port 1080"), "{error}");
+    }
+}
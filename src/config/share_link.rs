@@ -0,0 +1,1172 @@
+//! Proxy share links, e.g. `vless://` and `ss://` URIs.
+//!
+//! Links are parsed by turning them into the proxies of a Clash config and converting them
+//! like those, so that they support the same transports and TLS settings. Links of the
+//! configured servers are generated for clients by [`server_share_links`].
+
+use std::collections::HashMap;
+
+use base64::engine::{Engine as _, general_purpose};
+use percent_encoding::percent_decode_str;
+use serde_yaml::{Mapping, Value};
+
+use super::clash::convert_proxy;
+use super::convert_util::{ConvertedConfigs, client_group, get_str, mapping, opt_str};
+use super::types::{
+    BindLocation, ClientConfig, Hysteria2ObfsConfig, ServerConfig, ServerProxyConfig,
+    ShadowsocksConfig, ShadowsocksPluginConfig, TlsServerConfig, Transport, UserConfig,
+    UserGroupConfig,
+};
+use crate::reality::derive_public_key;
+
+/// Schemes of the supported share links.
+const SCHEMES: [&str; 6] = ["vless", "vmess", "trojan", "ss", "hysteria2", "hy2"];
+
+/// Parses a share link like `vless://...` or `ss://...` into a client config.
+///
+/// `vless://`, `vmess://`, `trojan://`, `ss://` and `hysteria2://` (or `hy2://`) links are
+/// supported, with the transports and TLS settings that Clash proxies support.
+#[allow(dead_code)] // Used by library embedders
+pub fn parse_share_link(link: &str) -> std::io::Result<ClientConfig> {
+    let invalid = |e: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid share link: {e}"),
+        )
+    };
+    let (_, client) = convert_share_link(link).map_err(invalid)?;
+    serde_yaml::from_value(client).map_err(|e| invalid(e.to_string()))
+}
+
+/// Returns true if a string is a share link, rather than e.g. the name of a client group.
+pub(crate) fn is_share_link(value: &str) -> bool {
+    value.split_once("://").is_some_and(|(scheme, _)| {
+        SCHEMES
+            .iter()
+            .any(|known| scheme.eq_ignore_ascii_case(known))
+    })
+}
+
+/// Converts a list of share links, one per line and optionally base64 encoded as a whole,
+/// into client groups named after the proxies.
+///
+/// Links that can't be converted are skipped and listed in the warnings.
+pub(crate) fn convert_share_links(content: &str) -> ConvertedConfigs {
+    let decoded = decode_base64(content).and_then(|decoded| String::from_utf8(decoded).ok());
+    let content = decoded.as_deref().unwrap_or(content);
+
+    let mut converted = ConvertedConfigs::default();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match convert_share_link(line).and_then(|(name, client)| client_group(&name, client)) {
+            Ok(config) => converted.configs.push(config),
+            Err(e) => converted
+                .warnings
+                .push(format!("Skipped share link on line {}: {e}", index + 1)),
+        }
+    }
+    converted
+}
+
+/// Converts a share link into the name and client config of the proxy it describes.
+pub(crate) fn convert_share_link(link: &str) -> Result<(String, Value), String> {
+    let proxy = share_link_proxy(link.trim())?;
+    let name = proxy
+        .get("name")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    Ok((name, convert_proxy(&proxy)?))
+}
+
+/// Returns the Clash proxy that a share link describes.
+fn share_link_proxy(link: &str) -> Result<Value, String> {
+    let (scheme, _) = link.split_once("://").ok_or("missing scheme")?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "vmess" => vmess_proxy(link),
+        "ss" => shadowsocks_proxy(link),
+        "vless" | "trojan" | "hysteria2" | "hy2" => {
+            let url = url::Url::parse(link).map_err(|e| e.to_string())?;
+            let link = ShareLink::new(&url)?;
+            match url.scheme() {
+                "vless" => Ok(link.vless_proxy()),
+                "trojan" => Ok(link.trojan_proxy()),
+                _ => Ok(link.hysteria2_proxy()),
+            }
+        }
+        other => Err(format!("unsupported scheme {other}")),
+    }
+}
+
+/// The parts of a `scheme://user@server:port?query#name` link.
+struct ShareLink {
+    user: String,
+    server: String,
+    port: u64,
+    query: Vec<(String, String)>,
+    name: String,
+}
+
+impl ShareLink {
+    fn new(url: &url::Url) -> Result<Self, String> {
+        let server = url.host_str().ok_or("missing server")?.to_string();
+        let port = url.port().ok_or("missing port")?;
+        Ok(Self {
+            user: decode(url.username()),
+            name: url
+                .fragment()
+                .map(decode)
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| format!("{server}:{port}")),
+            server,
+            port: port.into(),
+            query: url.query_pairs().into_owned().collect(),
+        })
+    }
+
+    fn param(&self, key: &str) -> Option<String> {
+        self.query
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+            .filter(|value| !value.is_empty())
+    }
+
+    fn flag(&self, key: &str) -> Value {
+        match self.param(key).as_deref() {
+            Some("1") | Some("true") => Value::Bool(true),
+            _ => Value::Null,
+        }
+    }
+
+    fn list(&self, key: &str) -> Value {
+        match self.param(key) {
+            Some(values) => Value::from(values.split(',').map(str::to_string).collect::<Vec<_>>()),
+            None => Value::Null,
+        }
+    }
+
+    fn common(&self, proxy_type: &str) -> Mapping {
+        let Value::Mapping(common) = mapping([
+            ("name", Value::from(self.name.as_str())),
+            ("type", Value::from(proxy_type)),
+            ("server", Value::from(self.server.as_str())),
+            ("port", Value::from(self.port)),
+        ]) else {
+            unreachable!("mapping returns a mapping");
+        };
+        common
+    }
+
+    /// Adds the TLS and transport fields of VLESS and Trojan links.
+    fn add_stream_fields(&self, proxy: &mut Mapping, default_security: &str) {
+        let security = self
+            .param("security")
+            .unwrap_or_else(|| default_security.to_string());
+        let stream = mapping([
+            (
+                "tls",
+                Value::Bool(security == "tls" || security == "reality"),
+            ),
+            ("servername", opt_str(self.param("sni"))),
+            ("skip-cert-verify", self.flag("allowInsecure")),
+            ("alpn", self.list("alpn")),
+            ("client-fingerprint", opt_str(self.param("fp"))),
+            (
+                "reality-opts",
+                if security == "reality" {
+                    mapping([
+                        ("public-key", opt_str(self.param("pbk"))),
+                        ("short-id", opt_str(self.param("sid"))),
+                    ])
+                } else {
+                    Value::Null
+                },
+            ),
+        ]);
+        extend(proxy, stream);
+        extend(
+            proxy,
+            transport_fields(
+                self.param("type").as_deref(),
+                self.param("headerType").as_deref(),
+                self.param("path"),
+                self.param("host"),
+                self.param("serviceName"),
+            ),
+        );
+    }
+
+    fn vless_proxy(&self) -> Value {
+        let mut proxy = self.common("vless");
+        proxy.insert(Value::from("uuid"), Value::from(self.user.as_str()));
+        if let Some(flow) = self.param("flow") {
+            proxy.insert(Value::from("flow"), Value::from(flow));
+        }
+        self.add_stream_fields(&mut proxy, "none");
+        Value::Mapping(proxy)
+    }
+
+    fn trojan_proxy(&self) -> Value {
+        let mut proxy = self.common("trojan");
+        proxy.insert(Value::from("password"), Value::from(self.user.as_str()));
+        self.add_stream_fields(&mut proxy, "tls");
+        Value::Mapping(proxy)
+    }
+
+    fn hysteria2_proxy(&self) -> Value {
+        let mut proxy = self.common("hysteria2");
+        extend(
+            &mut proxy,
+            mapping([
+                ("password", Value::from(self.user.as_str())),
+                ("sni", opt_str(self.param("sni"))),
+                ("skip-cert-verify", self.flag("insecure")),
+                ("alpn", self.list("alpn")),
+                ("obfs", opt_str(self.param("obfs"))),
+                ("obfs-password", opt_str(self.param("obfs-password"))),
+            ]),
+        );
+        Value::Mapping(proxy)
+    }
+}
+
+/// Converts a `vmess://` link, whose payload is base64 encoded JSON in the v2rayN format.
+fn vmess_proxy(link: &str) -> Result<Value, String> {
+    let payload = &link["vmess://".len()..];
+    let json = decode_base64(payload).ok_or("invalid base64 payload")?;
+    let vmess: Value = serde_json::from_slice(&json).map_err(|e| e.to_string())?;
+    let field = |key: &str| get_str(&vmess, key).filter(|s| !s.is_empty());
+
+    let server = field("add").ok_or("missing add")?;
+    let port = field("port")
+        .and_then(|port| port.parse::<u64>().ok())
+        .ok_or("missing port")?;
+    let mut proxy = mapping([
+        (
+            "name",
+            Value::from(field("ps").unwrap_or_else(|| format!("{server}:{port}"))),
+        ),
+        ("type", Value::from("vmess")),
+        ("server", Value::from(server)),
+        ("port", Value::from(port)),
+        ("uuid", opt_str(field("id"))),
+        (
+            "alterId",
+            Value::from(
+                field("aid")
+                    .and_then(|aid| aid.parse::<u64>().ok())
+                    .unwrap_or(0),
+            ),
+        ),
+        ("cipher", opt_str(field("scy"))),
+        ("tls", Value::Bool(field("tls").as_deref() == Some("tls"))),
+        ("servername", opt_str(field("sni"))),
+        (
+            "alpn",
+            field("alpn").map_or(Value::Null, |alpn| {
+                Value::from(alpn.split(',').map(str::to_string).collect::<Vec<_>>())
+            }),
+        ),
+        ("client-fingerprint", opt_str(field("fp"))),
+    ]);
+    let Value::Mapping(fields) = &mut proxy else {
+        unreachable!("mapping returns a mapping");
+    };
+    // gRPC links put the service name in the path.
+    extend(
+        fields,
+        transport_fields(
+            field("net").as_deref(),
+            field("type").as_deref(),
+            field("path"),
+            field("host"),
+            field("path"),
+        ),
+    );
+    Ok(proxy)
+}
+
+/// Converts a `ss://` link, either in the SIP002 format
+/// `ss://base64(method:password)@server:port/?plugin=...#name` or in the legacy format
+/// `ss://base64(method:password@server:port)#name`.
+fn shadowsocks_proxy(link: &str) -> Result<Value, String> {
+    let rest = &link["ss://".len()..];
+    let (rest, name) = match rest.split_once('#') {
+        Some((rest, name)) => (rest, Some(decode(name))),
+        None => (rest, None),
+    };
+
+    let (user_info, server_part) = match rest.rsplit_once('@') {
+        Some((user_info, server_part)) => {
+            // The user info is base64 encoded, or percent encoded for 2022 ciphers.
+            let user_info = decode_base64(user_info)
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .unwrap_or_else(|| decode(user_info));
+            (user_info, server_part.to_string())
+        }
+        None => {
+            let (encoded, query) = match rest.split_once('?') {
+                Some((encoded, query)) => (encoded.trim_end_matches('/'), format!("?{query}")),
+                None => (rest.trim_end_matches('/'), String::new()),
+            };
+            let decoded = decode_base64(encoded)
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .ok_or("invalid base64 user info")?;
+            let (user_info, server) = decoded.rsplit_once('@').ok_or("missing server")?;
+            (user_info.to_string(), format!("{server}{query}"))
+        }
+    };
+    let (cipher, password) = user_info.split_once(':').ok_or("missing password")?;
+
+    let url = url::Url::parse(&format!("ss://{server_part}")).map_err(|e| e.to_string())?;
+    let server = url.host_str().ok_or("missing server")?.to_string();
+    let port = url.port().ok_or("missing port")?;
+    let name = name
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| format!("{server}:{port}"));
+    let (plugin, plugin_opts) = match url
+        .query_pairs()
+        .find(|(key, _)| key == "plugin")
+        .map(|(_, plugin)| plugin.into_owned())
+    {
+        Some(plugin) => shadowsocks_plugin(&plugin)?,
+        None => (Value::Null, Value::Null),
+    };
+
+    Ok(mapping([
+        ("name", Value::from(name)),
+        ("type", Value::from("ss")),
+        ("server", Value::from(server)),
+        ("port", Value::from(u64::from(port))),
+        ("cipher", Value::from(cipher)),
+        ("password", Value::from(password)),
+        ("plugin", plugin),
+        ("plugin-opts", plugin_opts),
+    ]))
+}
+
+/// Converts the SIP003 `plugin` parameter of a `ss://` link, e.g.
+/// `obfs-local;obfs=http;obfs-host=example.com`, into a Clash plugin and its options.
+fn shadowsocks_plugin(plugin: &str) -> Result<(Value, Value), String> {
+    let mut parts = plugin.split(';');
+    let name = parts.next().unwrap_or_default();
+    let options: Vec<(&str, &str)> = parts
+        .map(|option| option.split_once('=').unwrap_or((option, "")))
+        .collect();
+    let option = |key: &str| {
+        options
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, value)| Value::from(*value))
+            .unwrap_or_default()
+    };
+    match name {
+        "obfs-local" | "simple-obfs" => Ok((
+            Value::from("obfs"),
+            mapping([("mode", option("obfs")), ("host", option("obfs-host"))]),
+        )),
+        "v2ray-plugin" => Ok((
+            Value::from("v2ray-plugin"),
+            mapping([
+                ("mode", Value::from("websocket")),
+                (
+                    "tls",
+                    Value::Bool(options.iter().any(|(key, _)| *key == "tls")),
+                ),
+                ("host", option("host")),
+                ("path", option("path")),
+            ]),
+        )),
+        other => Err(format!("unsupported plugin {other}")),
+    }
+}
+
+/// Returns the Clash transport fields of a link's `type` (or `net`), header type, path,
+/// host and gRPC service name.
+fn transport_fields(
+    network: Option<&str>,
+    header_type: Option<&str>,
+    path: Option<String>,
+    host: Option<String>,
+    service_name: Option<String>,
+) -> Value {
+    match network {
+        None | Some("tcp") | Some("raw") => match header_type {
+            None | Some("none") => Value::Null,
+            // Rejected by the Clash conversion as an unsupported network.
+            Some(header_type) => mapping([("network", Value::from(format!("tcp-{header_type}")))]),
+        },
+        Some("ws") | Some("httpupgrade") => mapping([
+            ("network", Value::from("ws")),
+            (
+                "ws-opts",
+                mapping([
+                    ("path", opt_str(path)),
+                    (
+                        "headers",
+                        host.map_or(Value::Null, |host| mapping([("Host", Value::from(host))])),
+                    ),
+                    (
+                        "v2ray-http-upgrade",
+                        if network == Some("httpupgrade") {
+                            Value::Bool(true)
+                        } else {
+                            Value::Null
+                        },
+                    ),
+                ]),
+            ),
+        ]),
+        Some("grpc") => mapping([
+            ("network", Value::from("grpc")),
+            (
+                "grpc-opts",
+                mapping([("grpc-service-name", opt_str(service_name))]),
+            ),
+        ]),
+        Some(network) => mapping([("network", Value::from(network))]),
+    }
+}
+
+/// Returns the share links that clients use to connect to a server at `host`, e.g. the
+/// public hostname or IP address of a server that binds `0.0.0.0`.
+///
+/// A link is returned for each TLS, Reality and transport target that leads to a VLESS,
+/// Trojan, Shadowsocks or Hysteria2 server, and for each of its users, including the users
+/// of its user group in `user_groups`. Targets that links can't describe, like ShadowTLS
+/// or obfs4, are skipped.
+pub fn server_share_links(
+    server: &ServerConfig,
+    user_groups: &[UserGroupConfig],
+    host: &str,
+) -> std::io::Result<Vec<String>> {
+    // Links describe a single port, so listeners on several addresses use the first one.
+    let location = match &server.bind_location {
+        BindLocation::Address(location) => location,
+        BindLocation::Addresses {
+            addresses,
+            interfaces,
+        } => match addresses.first().or(interfaces.first()) {
+            Some(location) => location,
+            None => return Ok(vec![]),
+        },
+        BindLocation::Path(_) | BindLocation::Systemd(_) => return Ok(vec![]),
+    };
+    let sni = host
+        .parse::<std::net::IpAddr>()
+        .is_err()
+        .then(|| host.to_string());
+    let host = match host.parse::<std::net::Ipv6Addr>() {
+        Ok(ip) => format!("[{ip}]"),
+        Err(_) => host.to_string(),
+    };
+    let links = LinkBuilder {
+        host,
+        sni,
+        port: location.ports()[0],
+        user_groups,
+    };
+    links.url("trojan", "").map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid host {}: {e}", links.host),
+        )
+    })?;
+
+    let mut result = vec![];
+    match (&server.transport, &server.protocol) {
+        (Transport::Tcp, protocol) => links.add_links(protocol, &Stream::default(), &mut result),
+        (Transport::Quic, ServerProxyConfig::Hysteria2 { password, obfs, .. }) => {
+            let alpn: Vec<String> = server
+                .quic_settings
+                .as_ref()
+                .map(|quic| quic.alpn_protocols.iter().cloned().collect())
+                .unwrap_or_default();
+            let mut params = links.sni_params(links.sni.clone(), alpn);
+            if let Some(Hysteria2ObfsConfig::Salamander { password }) = obfs {
+                params.push(("obfs", "salamander".to_string()));
+                params.push(("obfs-password", password.clone()));
+            }
+            result.push(links.link("hysteria2", password, None, params));
+        }
+        _ => {}
+    }
+    Ok(result)
+}
+
+/// Builds the share links of a server at a host and port.
+struct LinkBuilder<'a> {
+    host: String,
+    port: u16,
+    /// SNI of clients that connect to the host, unless it's an IP address.
+    sni: Option<String>,
+    user_groups: &'a [UserGroupConfig],
+}
+
+/// The TLS or Reality layer and the transport that a server protocol is reached through.
+#[derive(Clone, Default)]
+struct Stream {
+    /// `security` and the other parameters of the TLS or Reality layer.
+    security: Option<Vec<(&'static str, String)>>,
+    /// `type` and the other parameters of the transport.
+    network: Option<Vec<(&'static str, String)>>,
+    vision: bool,
+}
+
+impl LinkBuilder<'_> {
+    fn add_links(&self, protocol: &ServerProxyConfig, stream: &Stream, links: &mut Vec<String>) {
+        match protocol {
+            ServerProxyConfig::Tls {
+                tls_targets,
+                default_tls_target,
+                reality_targets,
+                ..
+            } if stream.security.is_none() && stream.network.is_none() => {
+                for (sni, target) in sorted(tls_targets) {
+                    if let Some(sni) = self.target_sni(sni) {
+                        self.add_tls_links(target, Some(sni), links);
+                    }
+                }
+                if let Some(target) = default_tls_target {
+                    self.add_tls_links(target, self.sni.clone(), links);
+                }
+                for (sni, target) in sorted(reality_targets) {
+                    let (Some(sni), Ok(public_key)) =
+                        (self.target_sni(sni), derive_public_key(&target.private_key))
+                    else {
+                        continue;
+                    };
+                    let mut security = vec![
+                        ("security", "reality".to_string()),
+                        ("sni", sni),
+                        ("fp", "chrome".to_string()),
+                        ("pbk", public_key),
+                    ];
+                    if let Some(short_id) = target.short_ids.iter().find(|id| !id.is_empty()) {
+                        security.push(("sid", short_id.clone()));
+                    }
+                    let stream = Stream {
+                        security: Some(security),
+                        network: None,
+                        vision: target.vision,
+                    };
+                    self.add_links(&target.protocol, &stream, links);
+                }
+            }
+            ServerProxyConfig::Websocket { targets } if stream.network.is_none() => {
+                for target in targets.iter() {
+                    let network = transport_params(
+                        "ws",
+                        target.matching_path.clone(),
+                        target.matching_host.clone(),
+                    );
+                    self.add_links(&target.protocol, &stream.with_network(network), links);
+                }
+            }
+            ServerProxyConfig::HttpUpgrade {
+                path,
+                host,
+                protocol,
+                ..
+            } if stream.network.is_none() => {
+                let network = transport_params("httpupgrade", path.clone(), host.clone());
+                self.add_links(protocol, &stream.with_network(network), links);
+            }
+            ServerProxyConfig::Xhttp {
+                path,
+                host,
+                protocol,
+                ..
+            } if stream.network.is_none() => {
+                let network = transport_params("xhttp", Some(path.clone()), host.clone());
+                self.add_links(protocol, &stream.with_network(network), links);
+            }
+            ServerProxyConfig::Grpc {
+                service_name,
+                protocol,
+                ..
+            } if stream.network.is_none() => {
+                let network = vec![
+                    ("type", "grpc".to_string()),
+                    ("serviceName", service_name.clone()),
+                ];
+                self.add_links(protocol, &stream.with_network(network), links);
+            }
+            ServerProxyConfig::Dispatch { targets, fallback } => {
+                for target in targets.iter() {
+                    self.add_links(&target.protocol, stream, links);
+                }
+                if let Some(fallback) = fallback {
+                    self.add_links(fallback, stream, links);
+                }
+            }
+            ServerProxyConfig::Vless {
+                user_id,
+                user_group,
+                flow,
+                ..
+            } => {
+                let mut params = vec![("encryption", "none".to_string())];
+                if stream.vision || flow.is_some() {
+                    params.push(("flow", "xtls-rprx-vision".to_string()));
+                }
+                params.extend(stream.params());
+                for (name, user_id) in self.users(user_id, user_group, |user| &user.user_id) {
+                    links.push(self.link("vless", &user_id, name, params.clone()));
+                }
+            }
+            ServerProxyConfig::Trojan {
+                password,
+                user_group,
+                shadowsocks: None,
+                ..
+            } => {
+                let params = stream.params();
+                for (name, password) in self.users(password, user_group, |user| &user.password) {
+                    links.push(self.link("trojan", &password, name, params.clone()));
+                }
+            }
+            ServerProxyConfig::Shadowsocks {
+                config,
+                users,
+                plugin,
+                user_group,
+                ..
+            } if stream.security.is_none() && stream.network.is_none() => {
+                let params = plugin
+                    .as_ref()
+                    .map(|plugin| vec![("plugin", client_plugin(plugin))])
+                    .unwrap_or_default();
+                let (method, password) = match config {
+                    ShadowsocksConfig::Legacy { cipher, password } => {
+                        (cipher.name().to_string(), password.clone())
+                    }
+                    ShadowsocksConfig::Aead2022 {
+                        cipher, key_bytes, ..
+                    } => (
+                        format!("2022-blake3-{}", cipher.name()),
+                        general_purpose::STANDARD.encode(key_bytes),
+                    ),
+                };
+                // Users of a multi-user server send the server key and their own key.
+                let mut credentials: Vec<(Option<String>, String)> = users
+                    .iter()
+                    .map(|user| {
+                        let name = Some(user.name.clone()).filter(|name| !name.is_empty());
+                        (name, format!("{password}:{}", user.password))
+                    })
+                    .chain(
+                        self.users("", user_group, |user| &user.password)
+                            .into_iter()
+                            .map(|(name, user_key)| (name, format!("{password}:{user_key}"))),
+                    )
+                    .collect();
+                if users.is_empty() && user_group.is_none() {
+                    credentials.push((None, password));
+                }
+                for (name, password) in credentials {
+                    links.push(self.shadowsocks_link(&method, &password, name, params.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn add_tls_links(
+        &self,
+        target: &TlsServerConfig,
+        sni: Option<String>,
+        links: &mut Vec<String>,
+    ) {
+        let alpn = target.alpn_protocols.iter().cloned().collect();
+        let stream = Stream {
+            security: Some(self.tls_params(sni.clone(), alpn)),
+            network: None,
+            vision: target.vision,
+        };
+        self.add_links(&target.protocol, &stream, links);
+        for (alpn, protocol) in sorted(&target.alpn_targets) {
+            let stream = Stream {
+                security: Some(self.tls_params(sni.clone(), vec![alpn.clone()])),
+                network: None,
+                vision: false,
+            };
+            self.add_links(protocol, &stream, links);
+        }
+    }
+
+    fn tls_params(&self, sni: Option<String>, alpn: Vec<String>) -> Vec<(&'static str, String)> {
+        let mut params = vec![("security", "tls".to_string())];
+        params.extend(self.sni_params(sni, alpn));
+        params
+    }
+
+    fn sni_params(&self, sni: Option<String>, alpn: Vec<String>) -> Vec<(&'static str, String)> {
+        let mut params = vec![];
+        if let Some(sni) = sni {
+            params.push(("sni", sni));
+        }
+        if !alpn.is_empty() {
+            params.push(("alpn", alpn.join(",")));
+        }
+        params
+    }
+
+    /// Returns the SNI of a TLS or Reality target, or the host for a wildcard target that
+    /// matches it.
+    fn target_sni(&self, sni: &str) -> Option<String> {
+        match sni.strip_prefix('*') {
+            Some(suffix) => self
+                .sni
+                .clone()
+                .filter(|host| suffix.starts_with('.') && host.ends_with(suffix)),
+            None => Some(sni.to_string()),
+        }
+    }
+
+    /// Returns the names and credentials of the inline user and the users of a user group.
+    fn users(
+        &self,
+        credential: &str,
+        user_group: &Option<String>,
+        user_credential: fn(&UserConfig) -> &Option<String>,
+    ) -> Vec<(Option<String>, String)> {
+        let mut users = vec![];
+        if !credential.is_empty() {
+            users.push((None, credential.to_string()));
+        }
+        let group = self
+            .user_groups
+            .iter()
+            .find(|group| Some(&group.user_group) == user_group.as_ref());
+        for user in group
+            .map(|group| group.users.as_slice())
+            .unwrap_or_default()
+        {
+            if let Some(credential) = user_credential(user) {
+                users.push((Some(user.name.clone()), credential.clone()));
+            }
+        }
+        users
+    }
+
+    fn url(&self, scheme: &str, user: &str) -> Result<url::Url, String> {
+        let mut url = url::Url::parse(&format!("{scheme}://{}:{}", self.host, self.port))
+            .map_err(|e| e.to_string())?;
+        if url.host().is_none() {
+            return Err("missing host".to_string());
+        }
+        url.set_username(user)
+            .map_err(|_| "invalid user".to_string())?;
+        Ok(url)
+    }
+
+    fn link(
+        &self,
+        scheme: &str,
+        user: &str,
+        name: Option<String>,
+        params: Vec<(&str, String)>,
+    ) -> String {
+        let url = self.url(scheme, user).expect("host was checked");
+        finish_link(url, name, params)
+    }
+
+    /// Returns a SIP002 link. The method and password are base64 encoded, except for 2022
+    /// ciphers, whose keys are percent encoded.
+    fn shadowsocks_link(
+        &self,
+        method: &str,
+        password: &str,
+        name: Option<String>,
+        params: Vec<(&str, String)>,
+    ) -> String {
+        let mut url = if method.starts_with("2022-") {
+            let mut url = self.url("ss", method).expect("host was checked");
+            let _ = url.set_password(Some(password));
+            url
+        } else {
+            let user_info = general_purpose::URL_SAFE_NO_PAD.encode(format!("{method}:{password}"));
+            self.url("ss", &user_info).expect("host was checked")
+        };
+        url.set_path("/");
+        finish_link(url, name, params)
+    }
+}
+
+impl Stream {
+    fn with_network(&self, network: Vec<(&'static str, String)>) -> Self {
+        Self {
+            network: Some(network),
+            ..self.clone()
+        }
+    }
+
+    /// Returns the parameters of VLESS and Trojan links.
+    fn params(&self) -> Vec<(&'static str, String)> {
+        let security = self
+            .security
+            .clone()
+            .unwrap_or_else(|| vec![("security", "none".to_string())]);
+        let network = self
+            .network
+            .clone()
+            .unwrap_or_else(|| vec![("type", "tcp".to_string())]);
+        security.into_iter().chain(network).collect()
+    }
+}
+
+fn finish_link(mut url: url::Url, name: Option<String>, params: Vec<(&str, String)>) -> String {
+    if !params.is_empty() {
+        url.query_pairs_mut().extend_pairs(params);
+    }
+    url.set_fragment(name.as_deref());
+    url.to_string()
+}
+
+fn transport_params(
+    network: &str,
+    path: Option<String>,
+    host: Option<String>,
+) -> Vec<(&'static str, String)> {
+    let mut params = vec![("type", network.to_string())];
+    if let Some(path) = path {
+        params.push(("path", path));
+    }
+    // Wildcard hosts can't be sent by clients.
+    if let Some(host) = host.filter(|host| !host.starts_with('*')) {
+        params.push(("host", host));
+    }
+    params
+}
+
+/// Returns the `plugin` parameter of a `ss://` link for the plugin of a server, e.g.
+/// `v2ray-plugin;tls;host=example.com` for `v2ray-plugin` with `server;tls;host=example.com`.
+fn client_plugin(plugin: &ShadowsocksPluginConfig) -> String {
+    let name = std::path::Path::new(&plugin.plugin)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(&plugin.plugin);
+    let name = match name {
+        "obfs-server" => "obfs-local",
+        name => name,
+    };
+    std::iter::once(name)
+        .chain(
+            plugin
+                .plugin_opts
+                .iter()
+                .flat_map(|opts| opts.split(';'))
+                .filter(|option| !option.is_empty() && *option != "server"),
+        )
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+fn sorted<T>(targets: &HashMap<String, T>) -> Vec<(&String, &T)> {
+    let mut targets: Vec<_> = targets.iter().collect();
+    targets.sort_by(|a, b| a.0.cmp(b.0));
+    targets
+}
+
+fn extend(proxy: &mut Mapping, fields: Value) {
+    if let Value::Mapping(fields) = fields {
+        proxy.extend(fields);
+    }
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+/// Decodes standard or URL-safe base64, with or without padding.
+fn decode_base64(value: &str) -> Option<Vec<u8>> {
+    let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+    let value = value.trim_end_matches('=');
+    general_purpose::STANDARD_NO_PAD
+        .decode(value)
+        .or_else(|_| general_purpose::URL_SAFE_NO_PAD.decode(value))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::{
+        ClientChainHop, ClientProxyConfig, Config, ConfigSelection, RuleActionConfig,
+    };
+
+    fn client(link: &str) -> (String, ClientConfig) {
+        let (name, client) = convert_share_link(link).unwrap();
+        (name, serde_yaml::from_value(client).unwrap())
+    }
+
+    #[test]
+    fn test_convert_share_link() {
+        let (name, vless) = client(
+            "vless://b831381d-6324-4d53-ad4f-8cda48b30811@example.com:443?encryption=none\
+             &security=reality&sni=www.microsoft.com&fp=chrome\
+             &pbk=SbVKOEMjK0sIlbwg4akyBg5mL5KZwwB-ed4eEE7YnRc&sid=6ba85179e30d4fc2\
+             &type=tcp&flow=xtls-rprx-vision#My%20Node",
+        );
+        assert_eq!(name, "My Node");
+        assert_eq!(vless.address.to_string(), "example.com:443");
+        assert!(matches!(vless.protocol, ClientProxyConfig::Reality { .. }));
+
+        let (name, trojan) =
+            client("trojan://secret@192.0.2.1:443?type=ws&path=%2Fws&host=cdn.example.com");
+        assert_eq!(name, "192.0.2.1:443");
+        assert!(matches!(trojan.protocol, ClientProxyConfig::Tls(_)));
+
+        let (_, hysteria2) = client(
+            "hy2://secret@example.com:8443/?sni=example.com&obfs=salamander&obfs-password=x",
+        );
+        assert_eq!(hysteria2.transport, Transport::Quic);
+
+        let (name, ss) = client("ss://YWVzLTEyOC1nY206c2VjcmV0@192.0.2.2:8388#ss");
+        assert_eq!(name, "ss");
+        assert!(matches!(ss.protocol, ClientProxyConfig::Shadowsocks { .. }));
+        let (_, legacy) = client("ss://YWVzLTEyOC1nY206c2VjcmV0QDE5Mi4wLjIuMjo4Mzg4");
+        assert_eq!(legacy.address.to_string(), "192.0.2.2:8388");
+
+        let vmess = general_purpose::STANDARD.encode(
+            r#"{"v":"2","ps":"vm","add":"example.com","port":"443","id":"b831381d-6324-4d53-ad4f-8cda48b30811","aid":"0","net":"ws","path":"/ray","tls":"tls"}"#,
+        );
+        let (name, vmess) = client(&format!("vmess://{vmess}"));
+        assert_eq!(name, "vm");
+        assert!(matches!(vmess.protocol, ClientProxyConfig::Tls(_)));
+
+        assert!(convert_share_link("ssr://abc").is_err());
+        assert!(convert_share_link("vless://uuid@example.com").is_err());
+    }
+
+    #[test]
+    fn test_parse_share_link() {
+        let client = parse_share_link("trojan://secret@192.0.2.1:443?sni=example.com").unwrap();
+        assert!(matches!(client.protocol, ClientProxyConfig::Tls(_)));
+        assert!(parse_share_link("trojan://secret@192.0.2.1").is_err());
+
+        assert!(is_share_link("VLESS://uuid@example.com:443"));
+        assert!(!is_share_link("my-proxies"));
+        assert!(!is_share_link("https://example.com"));
+    }
+
+    #[test]
+    fn test_share_links_in_config() {
+        let configs: Vec<Config> = serde_yaml::from_str(
+            r#"
+- client_group: links
+  client_proxies:
+    - trojan://secret@192.0.2.1:443#a
+    - other-group
+- address: 127.0.0.1:1080
+  protocol:
+    type: socks
+  rules:
+    - masks: 0.0.0.0/0
+      action: allow
+      client_chain:
+        chain:
+          - ss://YWVzLTEyOC1nY206c2VjcmV0@192.0.2.2:8388
+          - pool: [hy2://secret@192.0.2.3:443, links]
+"#,
+        )
+        .unwrap();
+
+        let Config::ClientConfigGroup(group) = &configs[0] else {
+            panic!("Expected client group");
+        };
+        let proxies: Vec<_> = group.client_proxies.iter().collect();
+        assert!(matches!(proxies[0], ConfigSelection::Config(_)));
+        assert!(matches!(proxies[1], ConfigSelection::GroupName(_)));
+
+        let Config::Server(server) = &configs[1] else {
+            panic!("Expected server");
+        };
+        let Some(ConfigSelection::Config(rule)) = server.rules.iter().next() else {
+            panic!("Expected inline rule");
+        };
+        let RuleActionConfig::Allow { client_chains, .. } = &rule.action else {
+            panic!("Expected allow rule");
+        };
+        let hops: Vec<_> = client_chains.iter().next().unwrap().hops.iter().collect();
+        assert!(matches!(
+            hops[0],
+            ClientChainHop::Single(ConfigSelection::Config(_))
+        ));
+        let ClientChainHop::Pool(pool) = hops[1] else {
+            panic!("Expected pool");
+        };
+        let pool: Vec<_> = pool.iter().collect();
+        assert!(matches!(pool[0], ConfigSelection::Config(_)));
+        assert!(matches!(pool[1], ConfigSelection::GroupName(_)));
+
+        assert!(
+            serde_yaml::from_str::<Vec<Config>>(
+                "- client_group: bad\n  client_proxies: vless://uuid@example.com\n"
+            )
+            .is_err()
+        );
+    }
+
+    fn share_links(config: &str, host: &str) -> Vec<String> {
+        let configs: Vec<Config> = serde_yaml::from_str(config).unwrap();
+        let user_groups: Vec<UserGroupConfig> = configs
+            .iter()
+            .filter_map(|config| match config {
+                Config::UserGroup(group) => Some(group.clone()),
+                _ => None,
+            })
+            .collect();
+        let Some(Config::Server(server)) = configs.last() else {
+            panic!("Expected server");
+        };
+        server_share_links(server, &user_groups, host).unwrap()
+    }
+
+    #[test]
+    fn test_server_share_links() {
+        let (private_key, public_key) = crate::reality::generate_keypair().unwrap();
+        let links = share_links(
+            &format!(
+                r#"
+- address: 0.0.0.0:443
+  protocol:
+    type: tls
+    reality_targets:
+      "www.example.com":
+        private_key: {private_key}
+        short_ids: ["", "6ba85179e30d4fc2"]
+        dest: www.example.com:443
+        vision: true
+        protocol:
+          type: vless
+          user_id: b831381d-6324-4d53-ad4f-8cda48b30811
+"#
+            ),
+            "proxy.example.com",
+        );
+        assert_eq!(
+            links,
+            vec![format!(
+                "vless://b831381d-6324-4d53-ad4f-8cda48b30811@proxy.example.com:443\
+                 ?encryption=none&flow=xtls-rprx-vision&security=reality&sni=www.example.com\
+                 &fp=chrome&pbk={public_key}&sid=6ba85179e30d4fc2&type=tcp"
+            )]
+        );
+        let vless = parse_share_link(&links[0]).unwrap();
+        assert!(matches!(vless.protocol, ClientProxyConfig::Reality { .. }));
+
+        let links = share_links(
+            r#"
+- user_group: users
+  users:
+    - name: alice
+      password: alice-secret
+- address: 0.0.0.0:8443
+  protocol:
+    type: tls
+    tls_targets:
+      "*.example.com":
+        cert: cert.pem
+        key: key.pem
+        alpn_protocols: [http/1.1]
+        protocol:
+          type: ws
+          targets:
+            - matching_path: /ws
+              protocol:
+                type: trojan
+                password: secret
+                user_group: users
+"#,
+            "cdn.example.com",
+        );
+        assert_eq!(
+            links,
+            vec![
+                "trojan://secret@cdn.example.com:8443?security=tls&sni=cdn.example.com\
+                 &alpn=http%2F1.1&type=ws&path=%2Fws",
+                "trojan://alice-secret@cdn.example.com:8443?security=tls&sni=cdn.example.com\
+                 &alpn=http%2F1.1&type=ws&path=%2Fws#alice",
+            ]
+        );
+        for link in links.iter() {
+            let trojan = parse_share_link(link).unwrap();
+            assert!(matches!(trojan.protocol, ClientProxyConfig::Tls(_)));
+        }
+
+        let links = share_links(
+            r#"
+- address: 0.0.0.0:8388
+  protocol:
+    type: ss
+    cipher: 2022-blake3-aes-128-gcm
+    password: AAAAAAAAAAAAAAAAAAAAAA==
+    users:
+      - name: bob
+        password: AQEBAQEBAQEBAQEBAQEBAQ==
+"#,
+            "2001:db8::1",
+        );
+        assert_eq!(
+            links,
+            vec![
+                "ss://2022-blake3-aes-128-gcm:AAAAAAAAAAAAAAAAAAAAAA%3D%3D%3AAQEBAQEBAQEBAQEBAQEBAQ%3D%3D\
+                 @[2001:db8::1]:8388/#bob"
+            ]
+        );
+        let ss = parse_share_link(&links[0]).unwrap();
+        assert_eq!(ss.address.to_string(), "[2001:db8::1]:8388");
+
+        let links = share_links(
+            r#"
+- address: 0.0.0.0:443
+  transport: quic
+  quic_settings:
+    cert: cert.pem
+    key: key.pem
+    alpn_protocols: [h3]
+  protocol:
+    type: hysteria2
+    password: secret
+    obfs:
+      type: salamander
+      password: obfs-secret
+"#,
+            "192.0.2.1",
+        );
+        assert_eq!(
+            links,
+            vec![
+                "hysteria2://secret@192.0.2.1:443?alpn=h3&obfs=salamander&obfs-password=obfs-secret"
+            ]
+        );
+        assert_eq!(
+            parse_share_link(&links[0]).unwrap().transport,
+            Transport::Quic
+        );
+
+        let socks = "- address: 0.0.0.0:1080\n  protocol:\n    type: socks\n";
+        assert!(share_links(socks, "192.0.2.1").is_empty());
+        let configs: Vec<Config> = serde_yaml::from_str(socks).unwrap();
+        let Config::Server(server) = &configs[0] else {
+            panic!("Expected server");
+        };
+        assert!(server_share_links(server, &[], "invalid host").is_err());
+    }
+
+    #[test]
+    fn test_convert_share_links() {
+        let links =
+            "trojan://secret@192.0.2.1:443#a\n\nss://invalid\nhysteria2://x@192.0.2.3:443#b\n";
+        let converted = convert_share_links(&general_purpose::STANDARD.encode(links));
+        assert_eq!(converted.configs.len(), 2);
+        assert_eq!(converted.warnings.len(), 1);
+        assert!(converted.warnings[0].starts_with("Skipped share link on line 3"));
+    }
+}
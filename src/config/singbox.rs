@@ -0,0 +1,911 @@
+//! Conversion of sing-box configs.
+//!
+//! Proxy outbounds become client groups named after their tags, route rules become a rule
+//! group, and inbounds become servers and TUN configs that use the rule group. Selector
+//! and URLTest outbounds are resolved into the client chains of the rules that use them,
+//! and ShadowTLS outbounds become a layer of the outbounds that use them as detour.
+
+use std::collections::HashMap;
+
+use serde_yaml::{Mapping, Value};
+
+use super::convert_util::{
+    ConvertedConfigs, Outbound, Outbounds, RuleMatchers, ServerTlsLayer, TlsLayer, TransportLayer,
+    block_action, client_group, direct_action, get_bool, get_str, get_str_list, get_u64,
+    host_key_fingerprint, mapping, opt_str, parse_duration_secs, parse_entry, parse_json_config,
+    sequence, server_address, verify_host_key, wrap_protocol, wrap_server_protocol,
+};
+use super::types::{Config, RuleConfig};
+
+/// Name of the rule group with the converted rules.
+const RULE_GROUP: &str = "singbox-rules";
+
+/// Converts a sing-box config into shoes configs.
+///
+/// Inbounds, outbounds and rules that can't be converted are skipped and listed in the
+/// warnings. Returns an error only if the config is not a JSON object.
+pub fn convert_singbox_config(config_str: &str) -> std::io::Result<ConvertedConfigs> {
+    let singbox = parse_json_config(config_str, "sing-box")?;
+    let mut converter = SingboxConverter {
+        singbox: &singbox,
+        outbounds: Outbounds::default(),
+        converted: ConvertedConfigs::default(),
+    };
+    converter.convert_outbounds();
+    converter.convert_route();
+    converter.convert_inbounds();
+    Ok(converter.converted)
+}
+
+/// Converts only the proxy outbounds of a sing-box config, e.g. of a subscription, into
+/// client groups named after their tags.
+///
+/// Outbounds that connect through another outbound are skipped, since the proxies are used
+/// on their own.
+pub(crate) fn convert_singbox_outbounds(singbox: &Value) -> ConvertedConfigs {
+    let mut converter = SingboxConverter {
+        singbox,
+        outbounds: Outbounds::default(),
+        converted: ConvertedConfigs::default(),
+    };
+    converter.convert_outbounds();
+
+    let SingboxConverter {
+        outbounds,
+        mut converted,
+        ..
+    } = converter;
+    let mut warnings = vec![];
+    converted.configs.retain(|config| {
+        let Config::ClientConfigGroup(group) = config else {
+            return true;
+        };
+        match outbounds.detour(&group.client_group) {
+            Some(detour) => {
+                warnings.push(format!(
+                    "Skipped outbound {}: detour {detour} is not supported",
+                    group.client_group
+                ));
+                false
+            }
+            None => true,
+        }
+    });
+    converted.warnings.extend(warnings);
+    converted
+}
+
+struct SingboxConverter<'a> {
+    singbox: &'a Value,
+    outbounds: Outbounds,
+    converted: ConvertedConfigs,
+}
+
+impl SingboxConverter<'_> {
+    fn warn(&mut self, warning: String) {
+        self.converted.warnings.push(warning);
+    }
+
+    fn convert_outbounds(&mut self) {
+        let outbounds = sequence(self.singbox, "outbounds");
+        let shadow_tls: HashMap<String, &Value> = outbounds
+            .iter()
+            .filter(|outbound| get_str(outbound, "type").as_deref() == Some("shadowtls"))
+            .filter_map(|outbound| Some((get_str(outbound, "tag")?, outbound)))
+            .collect();
+
+        for outbound in outbounds {
+            let Some(tag) = get_str(outbound, "tag") else {
+                self.warn("Skipped outbound without a tag".to_string());
+                continue;
+            };
+            let converted = match get_str(outbound, "type").as_deref() {
+                Some("direct") => Ok(Outbound::Direct),
+                Some("block") => Ok(Outbound::Block),
+                Some("shadowtls") => continue,
+                Some("selector") => {
+                    // Connections use the default outbound unless another one is selected
+                    // through the Clash API, and the next one when it is down.
+                    let mut members = get_str_list(outbound, "outbounds");
+                    if let Some(default) = get_str(outbound, "default") {
+                        members.retain(|member| *member != default);
+                        members.insert(0, default);
+                    }
+                    Ok(Outbound::Group {
+                        members,
+                        balancer: "failover",
+                        health_check: Value::Null,
+                    })
+                }
+                Some("urltest") => Ok(Outbound::Group {
+                    members: get_str_list(outbound, "outbounds"),
+                    balancer: "url_test",
+                    health_check: health_check(outbound),
+                }),
+                _ => convert_outbound(outbound, &shadow_tls).and_then(|(client, detour)| {
+                    self.converted.configs.push(client_group(&tag, client)?);
+                    Ok(Outbound::Proxy { detour })
+                }),
+            };
+            match converted {
+                Ok(converted) => self.outbounds.insert(tag, converted),
+                Err(e) => self.warn(format!("Skipped outbound {tag}: {e}")),
+            }
+        }
+    }
+
+    fn convert_route(&mut self) {
+        let route = self.singbox.get("route").cloned().unwrap_or_default();
+        let mut rules = vec![];
+        for (index, rule) in sequence(&route, "rules").iter().enumerate() {
+            let converted = convert_rule_matchers(rule).and_then(|matchers| {
+                let action = self.rule_action(rule)?;
+                Ok(matchers.into_rules(&action))
+            });
+            match converted {
+                Ok(converted) => rules.extend(converted),
+                Err(e) => self.warn(format!("Skipped route rule {}: {e}", index + 1)),
+            }
+        }
+
+        // Connections that match no rule use the final outbound, or the first one, or
+        // connect directly without outbounds.
+        let final_tag = get_str(&route, "final").or_else(|| {
+            sequence(self.singbox, "outbounds")
+                .first()
+                .and_then(|outbound| get_str(outbound, "tag"))
+        });
+        match final_tag.map(|tag| (self.outbounds.action(&tag), tag)) {
+            Some((Ok(action), _)) => rules.extend(RuleMatchers::default().into_rules(&action)),
+            Some((Err(e), tag)) => self.warn(format!("Skipped final outbound {tag}: {e}")),
+            None => rules.extend(RuleMatchers::default().into_rules(&direct_action())),
+        }
+
+        let mut converted = vec![];
+        for rule in rules {
+            match parse_entry::<RuleConfig>(rule.clone()) {
+                Ok(_) => converted.push(rule),
+                Err(e) => self.warn(format!("Skipped converted rule: {e}")),
+            }
+        }
+        let group = mapping([
+            ("rule_group", Value::from(RULE_GROUP)),
+            ("rules", Value::Sequence(converted)),
+        ]);
+        match parse_entry::<Config>(group) {
+            Ok(config) => self.converted.configs.push(config),
+            Err(e) => self.warn(format!("Skipped rules: {e}")),
+        }
+    }
+
+    /// Returns the action fields of the shoes rules of a route rule.
+    fn rule_action(&self, rule: &Value) -> Result<Mapping, String> {
+        match get_str(rule, "action").as_deref() {
+            None | Some("route") => {
+                let outbound = get_str(rule, "outbound").ok_or("missing outbound")?;
+                self.outbounds.action(&outbound)
+            }
+            Some("reject") => Ok(block_action()),
+            Some(action) => Err(format!("unsupported action {action}")),
+        }
+    }
+
+    fn convert_inbounds(&mut self) {
+        for inbound in sequence(self.singbox, "inbounds") {
+            let name = get_str(inbound, "tag")
+                .or_else(|| get_str(inbound, "type"))
+                .unwrap_or_default();
+            let entries = match self.convert_inbound(inbound, &name) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.warn(format!("Skipped inbound {name}: {e}"));
+                    continue;
+                }
+            };
+            for entry in entries {
+                match parse_entry::<Config>(entry) {
+                    Ok(config) => self.converted.configs.push(config),
+                    Err(e) => self.warn(format!("Skipped inbound {name}: {e}")),
+                }
+            }
+        }
+    }
+
+    /// Converts an inbound into server or TUN configs.
+    fn convert_inbound(&mut self, inbound: &Value, name: &str) -> Result<Vec<Value>, String> {
+        let inbound_type = get_str(inbound, "type").ok_or("missing type")?;
+        if inbound_type == "tun" {
+            return Ok(vec![convert_tun(inbound)?]);
+        }
+
+        let listen = get_str(inbound, "listen").unwrap_or_else(|| "0.0.0.0".to_string());
+        let port = get_u64(inbound, "listen_port").ok_or("missing listen_port")?;
+        let address = Value::from(server_address(&listen, port));
+        let users = sequence(inbound, "users");
+        if users.len() > 1 && inbound_type != "shadowsocks" {
+            self.warn(format!("Only the first user of inbound {name} is used"));
+        }
+        let user = users.first().cloned().unwrap_or_default();
+        let user_field = |key: &str| opt_str(get_str(&user, key));
+
+        let mut transport = None;
+        let mut tls = server_tls(inbound)?;
+        let mut quic_settings = Value::Null;
+        let protocol = match inbound_type.as_str() {
+            "mixed" | "socks" | "http" => mapping([
+                ("type", Value::from(inbound_type.as_str())),
+                ("username", user_field("username")),
+                ("password", user_field("password")),
+            ]),
+            "shadowsocks" => {
+                let users: Vec<Value> = users
+                    .iter()
+                    .map(|user| {
+                        mapping([
+                            ("name", opt_str(get_str(user, "name"))),
+                            ("password", opt_str(get_str(user, "password"))),
+                        ])
+                    })
+                    .collect();
+                mapping([
+                    ("type", Value::from("shadowsocks")),
+                    ("cipher", opt_str(get_str(inbound, "method"))),
+                    ("password", opt_str(get_str(inbound, "password"))),
+                    (
+                        "users",
+                        if users.is_empty() {
+                            Value::Null
+                        } else {
+                            Value::Sequence(users)
+                        },
+                    ),
+                ])
+            }
+            "vmess" => {
+                if get_u64(&user, "alterId").is_some_and(|alter_id| alter_id != 0) {
+                    return Err("legacy VMess with alterId is not supported".to_string());
+                }
+                transport = convert_transport(inbound)?;
+                mapping([
+                    ("type", Value::from("vmess")),
+                    ("cipher", Value::from("auto")),
+                    ("user_id", user_field("uuid")),
+                ])
+            }
+            "vless" => {
+                transport = convert_transport(inbound)?;
+                mapping([
+                    ("type", Value::from("vless")),
+                    ("user_id", user_field("uuid")),
+                    (
+                        "flow",
+                        opt_str(get_str(&user, "flow").filter(|flow| !flow.is_empty())),
+                    ),
+                ])
+            }
+            "trojan" => {
+                transport = convert_transport(inbound)?;
+                mapping([
+                    ("type", Value::from("trojan")),
+                    ("password", user_field("password")),
+                ])
+            }
+            "hysteria2" => {
+                let Some(ServerTlsLayer::Tls { cert, key, alpn }) = tls.take() else {
+                    return Err("hysteria2 requires a TLS certificate".to_string());
+                };
+                quic_settings = mapping([
+                    ("cert", Value::String(cert)),
+                    ("key", Value::String(key)),
+                    (
+                        "alpn_protocols",
+                        if alpn.is_empty() {
+                            Value::Null
+                        } else {
+                            Value::from(alpn)
+                        },
+                    ),
+                ]);
+                mapping([
+                    ("type", Value::from("hysteria2")),
+                    ("password", user_field("password")),
+                    ("obfs", hysteria2_obfs(inbound)?),
+                    ("bandwidth", hysteria2_bandwidth(inbound)),
+                ])
+            }
+            "redirect" => mapping([("type", Value::from("redirect"))]),
+            "tproxy" => {
+                let server = |transport: Option<&str>| {
+                    mapping([
+                        ("address", address.clone()),
+                        ("transport", transport.map_or(Value::Null, Value::from)),
+                        ("protocol", mapping([("type", Value::from("tproxy"))])),
+                        ("rules", Value::from(RULE_GROUP)),
+                    ])
+                };
+                return Ok(match get_str(inbound, "network").as_deref() {
+                    Some("tcp") => vec![server(None)],
+                    Some("udp") => vec![server(Some("udp"))],
+                    _ => vec![server(None), server(Some("udp"))],
+                });
+            }
+            other => return Err(format!("unsupported inbound type {other}")),
+        };
+
+        Ok(vec![mapping([
+            ("address", address),
+            (
+                "transport",
+                if quic_settings.is_null() {
+                    Value::Null
+                } else {
+                    Value::from("quic")
+                },
+            ),
+            ("quic_settings", quic_settings),
+            ("protocol", wrap_server_protocol(protocol, transport, tls)),
+            ("rules", Value::from(RULE_GROUP)),
+        ])])
+    }
+}
+
+fn health_check(group: &Value) -> Value {
+    let entries = [
+        ("url", opt_str(get_str(group, "url"))),
+        (
+            "interval_secs",
+            get_str(group, "interval")
+                .and_then(|interval| parse_duration_secs(&interval))
+                .map_or(Value::Null, Value::from),
+        ),
+        (
+            "tolerance_ms",
+            get_u64(group, "tolerance").map_or(Value::Null, Value::from),
+        ),
+    ];
+    if entries.iter().all(|(_, value)| value.is_null()) {
+        return Value::Null;
+    }
+    mapping(entries)
+}
+
+/// Converts the matchers of a route rule.
+fn convert_rule_matchers(rule: &Value) -> Result<RuleMatchers, String> {
+    if get_str(rule, "type").as_deref() == Some("logical") {
+        return Err("logical rules are not supported".to_string());
+    }
+    if get_bool(rule, "invert").unwrap_or(false) {
+        return Err("inverted rules are not supported".to_string());
+    }
+    let fields = rule.as_mapping().ok_or("expected an object")?;
+
+    let mut matchers = RuleMatchers::default();
+    for key in fields.keys() {
+        let key = key.as_str().unwrap_or_default();
+        let values = get_str_list(rule, key);
+        match key {
+            "type" | "action" | "outbound" => {}
+            // Hostname masks also match subdomains, so domain rules do too.
+            "domain" | "domain_suffix" => matchers.masks.extend(
+                values
+                    .iter()
+                    .map(|domain| domain.trim_start_matches('.').to_string()),
+            ),
+            "ip_cidr" => matchers.masks.extend(values),
+            "domain_keyword" => matchers.domain_keywords.extend(values),
+            "geosite" => matchers.geosite.extend(values),
+            "geoip" => matchers
+                .geoip
+                .extend(values.iter().map(|code| code.to_ascii_lowercase())),
+            "ip_is_private" if get_bool(rule, key).unwrap_or(false) => {
+                matchers.geoip.push("private".to_string());
+            }
+            "source_ip_cidr" => matchers.source_ips.extend(values),
+            "port" => matchers.ports.extend(values),
+            "port_range" => matchers
+                .ports
+                .extend(values.iter().map(|range| range.replace(':', "-"))),
+            other => return Err(format!("unsupported field {other}")),
+        }
+    }
+    Ok(matchers)
+}
+
+/// Converts a proxy outbound into a client config and the tag of its detour.
+fn convert_outbound(
+    outbound: &Value,
+    shadow_tls: &HashMap<String, &Value>,
+) -> Result<(Value, Option<String>), String> {
+    let outbound_type = get_str(outbound, "type").ok_or("missing type")?;
+    let server = get_str(outbound, "server").ok_or("missing server")?;
+    let port = get_u64(outbound, "server_port").ok_or("missing server_port")?;
+    let field = |key: &str| opt_str(get_str(outbound, key));
+    let udp = match get_str(outbound, "network").as_deref() {
+        Some("tcp") => Value::Bool(false),
+        _ => Value::Null,
+    };
+
+    let mut tls = client_tls(outbound);
+    let mut transport = None;
+    let mut transport_value = Value::Null;
+    let mut quic_settings = Value::Null;
+
+    let protocol = match outbound_type.as_str() {
+        "socks" => {
+            if get_str(outbound, "version").is_some_and(|version| version != "5") {
+                return Err("only SOCKS5 is supported".to_string());
+            }
+            mapping([
+                ("type", Value::from("socks")),
+                ("username", field("username")),
+                ("password", field("password")),
+            ])
+        }
+        "http" => mapping([
+            ("type", Value::from("http")),
+            ("username", field("username")),
+            ("password", field("password")),
+            (
+                "headers",
+                outbound.get("headers").cloned().unwrap_or_default(),
+            ),
+        ]),
+        "shadowsocks" => mapping([
+            ("type", Value::from("shadowsocks")),
+            ("cipher", field("method")),
+            ("password", field("password")),
+            ("udp_enabled", udp),
+            ("plugin", field("plugin")),
+            ("plugin_opts", field("plugin_opts")),
+        ]),
+        "vmess" => {
+            if get_u64(outbound, "alter_id").is_some_and(|alter_id| alter_id != 0) {
+                return Err("legacy VMess with alter_id is not supported".to_string());
+            }
+            transport = convert_transport(outbound)?;
+            mapping([
+                ("type", Value::from("vmess")),
+                (
+                    "cipher",
+                    Value::from(
+                        get_str(outbound, "security").unwrap_or_else(|| "auto".to_string()),
+                    ),
+                ),
+                ("user_id", field("uuid")),
+                ("udp_enabled", udp),
+            ])
+        }
+        "vless" => {
+            transport = convert_transport(outbound)?;
+            mapping([
+                ("type", Value::from("vless")),
+                ("user_id", field("uuid")),
+                ("udp_enabled", udp),
+                (
+                    "flow",
+                    opt_str(get_str(outbound, "flow").filter(|flow| !flow.is_empty())),
+                ),
+            ])
+        }
+        "trojan" => {
+            transport = convert_transport(outbound)?;
+            mapping([
+                ("type", Value::from("trojan")),
+                ("password", field("password")),
+            ])
+        }
+        "anytls" => mapping([
+            ("type", Value::from("anytls")),
+            ("password", field("password")),
+            ("udp_enabled", udp),
+        ]),
+        "hysteria2" => {
+            let tls = tls.take().unwrap_or_default();
+            transport_value = Value::from("quic");
+            quic_settings = mapping([
+                (
+                    "verify",
+                    if tls.insecure {
+                        Value::Bool(false)
+                    } else {
+                        Value::Null
+                    },
+                ),
+                ("sni_hostname", opt_str(tls.sni)),
+                (
+                    "alpn_protocols",
+                    if tls.alpn.is_empty() {
+                        Value::Null
+                    } else {
+                        Value::from(tls.alpn)
+                    },
+                ),
+            ]);
+            mapping([
+                ("type", Value::from("hysteria2")),
+                ("password", field("password")),
+                ("udp_enabled", udp),
+                ("obfs", hysteria2_obfs(outbound)?),
+                ("bandwidth", hysteria2_bandwidth(outbound)),
+            ])
+        }
+        "ssh" => {
+            let host_key_fingerprints = get_str_list(outbound, "host_key")
+                .iter()
+                .map(|host_key| host_key_fingerprint(host_key))
+                .collect::<Result<Vec<_>, _>>()?;
+            mapping([
+                ("type", Value::from("ssh")),
+                (
+                    "username",
+                    Value::from(get_str(outbound, "user").unwrap_or_else(|| "root".to_string())),
+                ),
+                ("password", field("password")),
+                (
+                    "private_key",
+                    opt_str(
+                        get_str(outbound, "private_key")
+                            .or_else(|| get_str(outbound, "private_key_path")),
+                    ),
+                ),
+                ("private_key_passphrase", field("private_key_passphrase")),
+                ("verify_host_key", verify_host_key(&host_key_fingerprints)),
+                ("host_key_fingerprints", Value::from(host_key_fingerprints)),
+            ])
+        }
+        "wireguard" => mapping([
+            ("type", Value::from("wireguard")),
+            ("private_key", field("private_key")),
+            ("peer_public_key", field("peer_public_key")),
+            ("preshared_key", field("pre_shared_key")),
+            (
+                "local_addresses",
+                Value::from(get_str_list(outbound, "local_address")),
+            ),
+            (
+                "reserved",
+                outbound.get("reserved").cloned().unwrap_or_default(),
+            ),
+            (
+                "mtu",
+                get_u64(outbound, "mtu").map_or(Value::Null, Value::from),
+            ),
+            ("udp_enabled", udp),
+        ]),
+        other => return Err(format!("unsupported outbound type {other}")),
+    };
+
+    let mut address = server_address(&server, port);
+    let mut protocol = wrap_protocol(protocol, transport, tls);
+    let mut detour = get_str(outbound, "detour");
+    if let Some(shadow_tls) = detour.as_ref().and_then(|detour| shadow_tls.get(detour)) {
+        // The outbound is carried by the ShadowTLS connection, and its own server is
+        // not used.
+        if get_u64(shadow_tls, "version") != Some(3) {
+            return Err("only ShadowTLS v3 is supported".to_string());
+        }
+        let server = get_str(shadow_tls, "server").ok_or("missing ShadowTLS server")?;
+        let port = get_u64(shadow_tls, "server_port").ok_or("missing ShadowTLS server_port")?;
+        address = server_address(&server, port);
+        protocol = mapping([
+            ("type", Value::from("shadowtls")),
+            ("password", opt_str(get_str(shadow_tls, "password"))),
+            (
+                "sni_hostname",
+                opt_str(client_tls(shadow_tls).and_then(|tls| tls.sni)),
+            ),
+            ("protocol", protocol),
+        ]);
+        detour = get_str(shadow_tls, "detour");
+    }
+
+    let client = mapping([
+        ("address", Value::from(address)),
+        ("transport", transport_value),
+        ("quic_settings", quic_settings),
+        ("protocol", protocol),
+    ]);
+    Ok((client, detour))
+}
+
+fn is_enabled(value: &&Value) -> bool {
+    get_bool(value, "enabled").unwrap_or(false)
+}
+
+fn client_tls(outbound: &Value) -> Option<TlsLayer> {
+    let tls = outbound.get("tls").filter(is_enabled)?;
+    Some(TlsLayer {
+        sni: get_str(tls, "server_name"),
+        insecure: get_bool(tls, "insecure").unwrap_or(false),
+        alpn: get_str_list(tls, "alpn"),
+        fingerprint: tls
+            .get("utls")
+            .filter(is_enabled)
+            .and_then(|utls| get_str(utls, "fingerprint")),
+        reality: tls.get("reality").filter(is_enabled).map(|reality| {
+            (
+                get_str(reality, "public_key").unwrap_or_default(),
+                get_str(reality, "short_id"),
+            )
+        }),
+    })
+}
+
+fn server_tls(inbound: &Value) -> Result<Option<ServerTlsLayer>, String> {
+    let Some(tls) = inbound.get("tls").filter(is_enabled) else {
+        return Ok(None);
+    };
+    if let Some(reality) = tls.get("reality").filter(is_enabled) {
+        let handshake = reality.get("handshake").cloned().unwrap_or_default();
+        let server = get_str(&handshake, "server").ok_or("missing reality handshake server")?;
+        let port = get_u64(&handshake, "server_port").unwrap_or(443);
+        let mut server_names = get_str_list(tls, "server_name");
+        if server_names.is_empty() {
+            server_names.push(server.clone());
+        }
+        return Ok(Some(ServerTlsLayer::Reality {
+            server_names,
+            private_key: get_str(reality, "private_key").ok_or("missing reality private_key")?,
+            short_ids: get_str_list(reality, "short_id"),
+            dest: server_address(&server, port),
+        }));
+    }
+    let (Some(cert), Some(key)) = (get_str(tls, "certificate_path"), get_str(tls, "key_path"))
+    else {
+        return Err("only TLS certificate_path and key_path are supported".to_string());
+    };
+    Ok(Some(ServerTlsLayer::Tls {
+        cert,
+        key,
+        alpn: get_str_list(tls, "alpn"),
+    }))
+}
+
+fn convert_transport(value: &Value) -> Result<Option<TransportLayer>, String> {
+    let Some(transport) = value.get("transport") else {
+        return Ok(None);
+    };
+    match get_str(transport, "type").as_deref() {
+        Some("ws") => {
+            let mut headers = transport
+                .get("headers")
+                .and_then(Value::as_mapping)
+                .cloned()
+                .unwrap_or_default();
+            let host = headers
+                .remove("Host")
+                .or_else(|| headers.remove("host"))
+                .and_then(|host| host.as_str().map(str::to_string));
+            Ok(Some(TransportLayer::Websocket {
+                path: get_str(transport, "path"),
+                host,
+                headers,
+                max_early_data: get_u64(transport, "max_early_data").filter(|size| *size != 0),
+            }))
+        }
+        Some("grpc") => Ok(Some(TransportLayer::Grpc {
+            service_name: get_str(transport, "service_name"),
+            host: None,
+        })),
+        Some("httpupgrade") => Ok(Some(TransportLayer::HttpUpgrade {
+            path: get_str(transport, "path"),
+            host: get_str(transport, "host"),
+        })),
+        Some(other) => Err(format!("unsupported transport {other}")),
+        None => Err("missing transport type".to_string()),
+    }
+}
+
+fn hysteria2_obfs(value: &Value) -> Result<Value, String> {
+    let Some(obfs) = value.get("obfs") else {
+        return Ok(Value::Null);
+    };
+    match get_str(obfs, "type").as_deref() {
+        Some("salamander") => Ok(mapping([
+            ("type", Value::from("salamander")),
+            ("password", opt_str(get_str(obfs, "password"))),
+        ])),
+        Some(other) => Err(format!("unsupported obfs {other}")),
+        None => Err("missing obfs type".to_string()),
+    }
+}
+
+fn hysteria2_bandwidth(value: &Value) -> Value {
+    let (up, down) = (get_u64(value, "up_mbps"), get_u64(value, "down_mbps"));
+    if up.is_none() && down.is_none() {
+        return Value::Null;
+    }
+    mapping([
+        ("up", up.map_or(Value::Null, Value::from)),
+        ("down", down.map_or(Value::Null, Value::from)),
+    ])
+}
+
+/// Converts a TUN inbound into a TUN config.
+fn convert_tun(inbound: &Value) -> Result<Value, String> {
+    let mut addresses = get_str_list(inbound, "address");
+    addresses.extend(get_str_list(inbound, "inet4_address"));
+    let (address, netmask) = match addresses.iter().find(|cidr| cidr.contains('.')) {
+        Some(cidr) => {
+            let (address, prefix) = cidr.split_once('/').unwrap_or((cidr.as_str(), "32"));
+            let prefix: u32 = prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= 32)
+                .ok_or_else(|| format!("invalid TUN address {cidr}"))?;
+            let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            (
+                Value::from(address),
+                Value::from(std::net::Ipv4Addr::from(netmask).to_string()),
+            )
+        }
+        None => (Value::Null, Value::Null),
+    };
+    Ok(mapping([
+        (
+            "device_name",
+            Value::from(get_str(inbound, "interface_name").unwrap_or_else(|| "tun0".to_string())),
+        ),
+        ("address", address),
+        ("netmask", netmask),
+        (
+            "mtu",
+            get_u64(inbound, "mtu").map_or(Value::Null, Value::from),
+        ),
+        ("rules", Value::from(RULE_GROUP)),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientProxyConfig, RuleActionConfig, ServerProxyConfig};
+
+    const SINGBOX_CONFIG: &str = r#"{
+  // sing-box configs are JSON, comments are accepted anyway.
+  "inbounds": [
+    {"type": "mixed", "tag": "mixed-in", "listen": "127.0.0.1", "listen_port": 2080},
+    {
+      "type": "vless",
+      "listen": "0.0.0.0",
+      "listen_port": 443,
+      "users": [{"uuid": "b831381d-6324-4d53-ad4f-8cda48b30811", "flow": "xtls-rprx-vision"}],
+      "tls": {
+        "enabled": true,
+        "server_name": "www.example.com",
+        "reality": {
+          "enabled": true,
+          "handshake": {"server": "www.example.com", "server_port": 443},
+          "private_key": "SMhPSWCA9Fk2yJ3bJPGS3Fxdxvqj9-8hs_-XsQLW2Gg",
+          "short_id": ["0123456789abcdef"]
+        }
+      }
+    },
+    {"type": "naive", "listen_port": 8443}
+  ],
+  "outbounds": [
+    {
+      "type": "trojan",
+      "tag": "trojan-ws",
+      "server": "trojan.example.com",
+      "server_port": 443,
+      "password": "secret",
+      "tls": {"enabled": true, "server_name": "cdn.example.com"},
+      "transport": {"type": "ws", "path": "/ws", "headers": {"Host": "cdn.example.com"}}
+    },
+    {
+      "type": "shadowsocks",
+      "tag": "ss",
+      "server": "192.0.2.1",
+      "server_port": 8388,
+      "method": "aes-128-gcm",
+      "password": "secret",
+      "detour": "trojan-ws"
+    },
+    {"type": "tuic", "tag": "tuic", "server": "192.0.2.2", "server_port": 443},
+    {
+      "type": "urltest",
+      "tag": "auto",
+      "outbounds": ["trojan-ws", "ss"],
+      "interval": "3m"
+    },
+    {"type": "direct", "tag": "direct"},
+    {"type": "block", "tag": "block"}
+  ],
+  "route": {
+    "rules": [
+      {"domain_suffix": [".google.com"], "domain_keyword": ["youtube"], "outbound": "auto"},
+      {"geoip": ["CN"], "port": [80, 443], "outbound": "direct"},
+      {"protocol": "dns", "outbound": "direct"},
+      {"ip_cidr": ["10.0.0.0/8"], "action": "reject"},
+      {"domain": ["tuic.example.com"], "outbound": "tuic"}
+    ],
+    "final": "ss"
+  }
+}"#;
+
+    #[test]
+    fn test_convert_singbox_config() {
+        let converted = convert_singbox_config(SINGBOX_CONFIG).unwrap();
+        assert_eq!(converted.warnings.len(), 4, "{:?}", converted.warnings);
+        assert!(converted.warnings[0].starts_with("Skipped outbound tuic"));
+        assert!(converted.warnings[1].starts_with("Skipped route rule 3"));
+        assert!(converted.warnings[2].starts_with("Skipped route rule 5"));
+        assert!(converted.warnings[3].starts_with("Skipped inbound naive"));
+
+        let configs = converted.configs;
+        assert_eq!(configs.len(), 5);
+        match &configs[0] {
+            Config::ClientConfigGroup(group) => {
+                assert_eq!(group.client_group, "trojan-ws");
+                let client = match group.client_proxies.iter().next() {
+                    Some(crate::config::ConfigSelection::Config(client)) => client,
+                    _ => panic!("Expected inline client"),
+                };
+                assert!(matches!(client.protocol, ClientProxyConfig::Tls(_)));
+            }
+            _ => panic!("Expected client group"),
+        }
+
+        let Config::RuleConfigGroup(group) = &configs[2] else {
+            panic!("Expected rule group");
+        };
+        let rules: Vec<&RuleConfig> = group.rules.iter().collect();
+        // The first route rule is split into a rule for each kind of destination.
+        assert_eq!(rules.len(), 5);
+        assert_eq!(rules[0].masks.len(), 1);
+        assert_eq!(rules[1].domain_keywords.len(), 1);
+        match &rules[0].action {
+            RuleActionConfig::Allow {
+                client_chains,
+                balancer,
+                health_check,
+                ..
+            } => {
+                assert_eq!(client_chains.len(), 2);
+                assert_eq!(*balancer, crate::config::BalanceStrategy::UrlTest);
+                assert_eq!(health_check.as_ref().unwrap().interval_secs, 180);
+                // The ss outbound is connected to through its detour.
+                let chain = client_chains.iter().nth(1).unwrap();
+                assert_eq!(chain.hops.len(), 2);
+            }
+            RuleActionConfig::Block => panic!("Expected allow rule"),
+        }
+        assert_eq!(rules[2].ports.len(), 2);
+        assert!(matches!(rules[3].action, RuleActionConfig::Block));
+        assert!(matches!(rules[4].action, RuleActionConfig::Allow { .. }));
+
+        match &configs[3] {
+            Config::Server(server) => {
+                assert_eq!(server.bind_location.to_string(), "127.0.0.1:2080");
+                assert!(matches!(server.protocol, ServerProxyConfig::Mixed { .. }));
+            }
+            _ => panic!("Expected server"),
+        }
+        match &configs[4] {
+            Config::Server(server) => match &server.protocol {
+                ServerProxyConfig::Tls {
+                    reality_targets, ..
+                } => assert!(reality_targets.contains_key("www.example.com")),
+                _ => panic!("Expected reality server"),
+            },
+            _ => panic!("Expected server"),
+        }
+    }
+
+    #[test]
+    fn test_convert_singbox_tun() {
+        let converted = convert_singbox_config(
+            r#"{"inbounds": [{"type": "tun", "address": ["172.19.0.1/30"], "mtu": 9000}]}"#,
+        )
+        .unwrap();
+        assert!(converted.warnings.is_empty(), "{:?}", converted.warnings);
+        let Config::TunServer(tun) = &converted.configs[1] else {
+            panic!("Expected TUN config");
+        };
+        assert_eq!(tun.netmask.unwrap().to_string(), "255.255.255.252");
+        assert_eq!(tun.mtu, 9000);
+
+        assert!(convert_singbox_config("[]").is_err());
+    }
+}
@@ -0,0 +1,222 @@
+//! Conversion of fetched subscriptions into client groups.
+//!
+//! Subscriptions are fetched by the reload loop, which replaces each subscription entry
+//! with the client group of its proxies before the configs are validated.
+
+use serde_yaml::Value;
+
+use crate::option_util::OneOrSome;
+
+use super::clash::convert_clash_proxies;
+use super::convert_util::{ConvertedConfigs, parse_json_config};
+use super::share_link::convert_share_links;
+use super::singbox::convert_singbox_outbounds;
+use super::types::{
+    ClientChain, ClientConfig, ClientConfigGroup, Config, ConfigSelection, SubscriptionConfig,
+    SubscriptionFormat,
+};
+use super::validate::resolve_client_chain;
+
+/// Checks the URL and interval of a subscription.
+pub(crate) fn validate_subscription(subscription: &SubscriptionConfig) -> std::io::Result<()> {
+    let invalid = |message: String| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "invalid subscription {}: {message}",
+                subscription.subscription
+            ),
+        )
+    };
+    let url = url::Url::parse(&subscription.url).map_err(|e| invalid(e.to_string()))?;
+    if !matches!(url.scheme(), "http" | "https") || url.host().is_none() {
+        return Err(invalid("url must be a http or https URL".to_string()));
+    }
+    if subscription.interval_secs == 0 {
+        return Err(invalid("interval_secs must be at least 1".to_string()));
+    }
+    Ok(())
+}
+
+/// Returns the client chain that a subscription is fetched through, with its group
+/// references resolved using the client groups of `configs`.
+pub(crate) fn subscription_chain(
+    subscription: &SubscriptionConfig,
+    configs: &[Config],
+) -> std::io::Result<ClientChain> {
+    let Some(chain) = &subscription.client_chain else {
+        return Ok(ClientChain::default());
+    };
+    resolve_client_chain(configs, chain).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "invalid client_chain of subscription {}: {e}",
+                subscription.subscription
+            ),
+        )
+    })
+}
+
+/// Converts the content of a subscription into the client group of the subscription.
+///
+/// Returns the group, and warnings about proxies that were skipped. Returns an error if
+/// none of the proxies could be converted.
+pub(crate) fn convert_subscription(
+    subscription: &SubscriptionConfig,
+    content: &str,
+) -> std::io::Result<(Config, Vec<String>)> {
+    let converted = match subscription.format {
+        SubscriptionFormat::Auto => {
+            // Clash and sing-box configs are mappings, share link lists are not.
+            match serde_yaml::from_str::<Value>(content) {
+                Ok(config) if config.get("proxies").is_some() => convert_clash_proxies(&config),
+                Ok(config) if config.get("outbounds").is_some() => {
+                    convert_singbox_outbounds(&config)
+                }
+                _ => convert_share_links(content),
+            }
+        }
+        SubscriptionFormat::UriList => convert_share_links(content),
+        SubscriptionFormat::Clash => {
+            let config: Value = serde_yaml::from_str(content).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid Clash config: {e}"),
+                )
+            })?;
+            convert_clash_proxies(&config)
+        }
+        SubscriptionFormat::Singbox => {
+            convert_singbox_outbounds(&parse_json_config(content, "sing-box")?)
+        }
+    };
+
+    let ConvertedConfigs { configs, warnings } = converted;
+    let clients: Vec<ConfigSelection<ClientConfig>> = configs
+        .into_iter()
+        .flat_map(|config| match config {
+            Config::ClientConfigGroup(group) => group.client_proxies.into_vec(),
+            _ => vec![],
+        })
+        .collect();
+    if clients.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "subscription {} has no supported proxies",
+                subscription.subscription
+            ),
+        ));
+    }
+
+    let group = ClientConfigGroup {
+        client_group: subscription.subscription.clone(),
+        client_proxies: OneOrSome::Some(clients),
+    };
+    Ok((Config::ClientConfigGroup(group), warnings))
+}
+
+/// Replaces subscriptions with client groups that connect directly, so that configs can be
+/// validated without fetching the subscriptions.
+pub(crate) fn replace_subscriptions(configs: Vec<Config>) -> std::io::Result<Vec<Config>> {
+    let mut subscriptions = vec![];
+    let configs: Vec<Config> = configs
+        .into_iter()
+        .map(|config| match config {
+            Config::Subscription(subscription) => {
+                let group = ClientConfigGroup {
+                    client_group: subscription.subscription.clone(),
+                    client_proxies: OneOrSome::One(
+                        ConfigSelection::Config(ClientConfig::default()),
+                    ),
+                };
+                subscriptions.push(subscription);
+                Config::ClientConfigGroup(group)
+            }
+            config => config,
+        })
+        .collect();
+
+    for subscription in subscriptions.iter() {
+        validate_subscription(subscription)?;
+        subscription_chain(subscription, &configs)?;
+    }
+    Ok(configs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscription(format: SubscriptionFormat) -> SubscriptionConfig {
+        SubscriptionConfig {
+            subscription: "provider".to_string(),
+            url: "https://example.com/sub".to_string(),
+            format,
+            interval_secs: 3600,
+            user_agent: None,
+            client_chain: None,
+        }
+    }
+
+    fn group_size(config: &Config) -> usize {
+        match config {
+            Config::ClientConfigGroup(group) => {
+                assert_eq!(group.client_group, "provider");
+                group.client_proxies.len()
+            }
+            _ => panic!("Expected client group"),
+        }
+    }
+
+    #[test]
+    fn test_convert_subscription() {
+        let auto = subscription(SubscriptionFormat::Auto);
+
+        let clash = r#"
+proxies:
+  - {name: a, type: trojan, server: 192.0.2.1, port: 443, password: secret}
+  - {name: b, type: ssr, server: 192.0.2.2, port: 443}
+"#;
+        let (group, warnings) = convert_subscription(&auto, clash).unwrap();
+        assert_eq!(group_size(&group), 1);
+        assert_eq!(warnings.len(), 1);
+
+        let singbox = r#"{"outbounds": [
+            {"type": "direct", "tag": "direct"},
+            {"type": "trojan", "tag": "a", "server": "192.0.2.1", "server_port": 443,
+             "password": "secret", "tls": {"enabled": true}},
+            {"type": "trojan", "tag": "b", "server": "192.0.2.2", "server_port": 443,
+             "password": "secret", "detour": "a"}
+        ]}"#;
+        let (group, warnings) = convert_subscription(&auto, singbox).unwrap();
+        assert_eq!(group_size(&group), 1);
+        assert_eq!(warnings.len(), 1);
+
+        let links = "trojan://secret@192.0.2.1:443#a\nhysteria2://secret@192.0.2.2:443#b\n";
+        let (group, warnings) = convert_subscription(&auto, links).unwrap();
+        assert_eq!(group_size(&group), 2);
+        assert!(warnings.is_empty());
+
+        assert!(convert_subscription(&subscription(SubscriptionFormat::Clash), links).is_err());
+        assert!(convert_subscription(&auto, "not a subscription").is_err());
+    }
+
+    #[test]
+    fn test_replace_subscriptions() {
+        let mut with_chain = subscription(SubscriptionFormat::Auto);
+        with_chain.client_chain = Some(ConfigSelection::GroupName("missing".to_string()));
+        assert!(replace_subscriptions(vec![Config::Subscription(with_chain)]).is_err());
+
+        let configs = replace_subscriptions(vec![Config::Subscription(subscription(
+            SubscriptionFormat::Auto,
+        ))])
+        .unwrap();
+        assert_eq!(group_size(&configs[0]), 1);
+
+        let mut invalid = subscription(SubscriptionFormat::Auto);
+        invalid.url = "ftp://example.com/sub".to_string();
+        assert!(replace_subscriptions(vec![Config::Subscription(invalid)]).is_err());
+    }
+}
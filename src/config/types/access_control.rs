@@ -0,0 +1,35 @@
+//! Source IP access control configuration.
+
+use serde::{Deserialize, Serialize};
+
+use crate::option_util::NoneOrSome;
+
+/// Client IPs that may connect to a listener, checked when connections are accepted.
+///
+/// ```yaml
+/// access_control:
+///   allow: [10.0.0.0/8, 192.168.1.0/24]
+///   deny: 10.0.0.13
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessControlConfig {
+    /// IPs or CIDRs of the clients that may connect. All clients may connect when empty.
+    #[serde(default, skip_serializing_if = "NoneOrSome::is_unspecified")]
+    pub allow: NoneOrSome<String>,
+    /// IPs or CIDRs of the clients that may not connect, even when they are allowed.
+    #[serde(default, skip_serializing_if = "NoneOrSome::is_unspecified")]
+    pub deny: NoneOrSome<String>,
+}
+
+/// Access control of the listeners that don't have their own.
+///
+/// ```yaml
+/// - default_access_control:
+///     allow: 10.0.0.0/8
+/// ```
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DefaultAccessControlConfig {
+    pub default_access_control: AccessControlConfig,
+}
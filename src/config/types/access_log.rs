@@ -0,0 +1,42 @@
+//! Access log configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Structured log of forwarded sessions, with a line for each session when it ends.
+///
+/// ```yaml
+/// - access_log: /var/log/shoes/access.log
+///   format: logfmt
+///   max_size_mb: 100
+///   max_files: 5
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// Where to write: `stdout`, `syslog`, or the path of a file.
+    pub access_log: String,
+    #[serde(default)]
+    pub format: AccessLogFormat,
+    /// Size in megabytes at which the log file is rotated, 0 to never rotate.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+    /// Number of rotated log files to keep.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Logfmt,
+}
+
+fn default_max_size_mb() -> u64 {
+    100
+}
+
+fn default_max_files() -> usize {
+    5
+}
@@ -0,0 +1,31 @@
+//! Automatic ban configuration.
+
+use serde::{Deserialize, Serialize};
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_duration_secs() -> u64 {
+    600
+}
+
+/// Bans of client IPs that fail handshakes or authentication too often.
+///
+/// ```yaml
+/// - auto_ban_failures: 10
+///   auto_ban_window_secs: 60
+///   auto_ban_duration_secs: 600
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AutoBanConfig {
+    /// Failures of a client IP within the window after which it is banned.
+    pub auto_ban_failures: u32,
+    /// Seconds in which failures are counted.
+    #[serde(default = "default_window_secs")]
+    pub auto_ban_window_secs: u64,
+    /// Seconds for which a banned IP can't connect.
+    #[serde(default = "default_duration_secs")]
+    pub auto_ban_duration_secs: u64,
+}
@@ -0,0 +1,22 @@
+//! Relay buffer pool configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Sizes of the buffers that relay streams, and how many are kept for reuse.
+///
+/// ```yaml
+/// - relay_buffer_size: 32768
+///   relay_buffer_pool_size: 128
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct BufferPoolConfig {
+    /// Size in bytes of the buffer for each direction of a relayed TCP stream. 16384 when
+    /// unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_buffer_size: Option<usize>,
+    /// Free buffers of each size that a worker thread keeps for reuse. 64 when unset, and
+    /// 0 frees buffers as soon as their relay ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay_buffer_pool_size: Option<usize>,
+}
@@ -0,0 +1,22 @@
+//! Clash API listener configuration.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/// HTTP listener that serves the Clash external-controller API, so that dashboards
+/// such as yacd can manage the instance.
+///
+/// ```yaml
+/// - clash_api: 127.0.0.1:9090
+///   secret: my-secret
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClashApiConfig {
+    /// Address to listen on.
+    pub clash_api: SocketAddr,
+    /// Bearer token required by all requests, if set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
@@ -5,19 +5,26 @@ use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 
 use crate::address::NetLocation;
-use crate::option_util::{NoneOrOne, NoneOrSome};
+use crate::option_util::{NoneOrOne, NoneOrSome, OneOrSome};
+use crate::tls_fingerprint::TlsFingerprint;
 
 use super::common::{
-    default_reality_client_short_id, default_true, is_false, is_true, unspecified_address,
+    default_anytls_idle_session_timeout_secs, default_grpc_service_name,
+    default_reality_client_short_id, default_snell_version, default_true, default_xhttp_path,
+    is_default_anytls_idle_session_timeout_secs, is_default_grpc_service_name,
+    is_default_snell_version, is_default_xhttp_path, is_false, is_true, unspecified_address,
+};
+use super::rate_limit::parse_bandwidth;
+use super::server::{Obfs4IatMode, WebsocketPingType};
+use super::shadowsocks::{ShadowsocksConfig, ShadowsocksPluginConfig};
+use super::transport::{
+    ClientQuicConfig, DomainStrategy, HappyEyeballsConfig, KcpConfig, TcpConfig, Transport,
 };
-use super::server::WebsocketPingType;
-use super::shadowsocks::ShadowsocksConfig;
-use super::transport::{ClientQuicConfig, TcpConfig, Transport};
 
 /// Custom deserializer for ClientProxyConfig::Shadowsocks
 fn deserialize_shadowsocks_client<'de, D>(
     deserializer: D,
-) -> Result<(ShadowsocksConfig, bool), D::Error>
+) -> Result<(ShadowsocksConfig, bool, Option<ShadowsocksPluginConfig>), D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -30,19 +37,26 @@ where
         password: String,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        #[serde(default)]
+        plugin: Option<String>,
+        #[serde(default)]
+        plugin_opts: Option<String>,
     }
 
     let temp = ShadowsocksClientTemp::deserialize(deserializer)?;
     let config =
         ShadowsocksConfig::from_fields(&temp.cipher, &temp.password).map_err(Error::custom)?;
+    let plugin = ShadowsocksPluginConfig::from_fields(temp.plugin, temp.plugin_opts)
+        .map_err(Error::custom)?;
 
-    Ok((config, temp.udp_enabled))
+    Ok((config, temp.udp_enabled, plugin))
 }
 
 /// Custom serializer for ClientProxyConfig::Shadowsocks - flattens config fields
 fn serialize_shadowsocks_client<S>(
     config: &ShadowsocksConfig,
     udp_enabled: &bool,
+    plugin: &Option<ShadowsocksPluginConfig>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
@@ -51,17 +65,23 @@ where
     use serde::ser::SerializeStruct;
 
     // Only serialize udp_enabled if it's not the default (true)
-    let field_count = if *udp_enabled { 2 } else { 3 };
+    let field_count = if *udp_enabled { 2 } else { 3 }
+        + plugin.as_ref().map_or(0, ShadowsocksPluginConfig::field_count);
     let mut state = serializer.serialize_struct("Shadowsocks", field_count)?;
     config.serialize_fields(&mut state)?;
     if !*udp_enabled {
         state.serialize_field("udp_enabled", udp_enabled)?;
     }
+    if let Some(plugin) = plugin {
+        plugin.serialize_fields(&mut state)?;
+    }
     state.end()
 }
 
 /// Custom deserializer for ClientProxyConfig::Snell - flattens config fields
-fn deserialize_snell_client<'de, D>(deserializer: D) -> Result<(ShadowsocksConfig, bool), D::Error>
+fn deserialize_snell_client<'de, D>(
+    deserializer: D,
+) -> Result<(ShadowsocksConfig, bool, u8), D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -74,19 +94,22 @@ where
         password: String,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        #[serde(default = "default_snell_version")]
+        version: u8,
     }
 
     let temp = SnellClientTemp::deserialize(deserializer)?;
     let config =
         ShadowsocksConfig::from_fields(&temp.cipher, &temp.password).map_err(Error::custom)?;
 
-    Ok((config, temp.udp_enabled))
+    Ok((config, temp.udp_enabled, temp.version))
 }
 
 /// Custom serializer for ClientProxyConfig::Snell - flattens config fields
 fn serialize_snell_client<S>(
     config: &ShadowsocksConfig,
     udp_enabled: &bool,
+    version: &u8,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
@@ -94,18 +117,24 @@ where
 {
     use serde::ser::SerializeStruct;
 
-    // Only serialize udp_enabled if it's not the default (true)
-    let field_count = if *udp_enabled { 2 } else { 3 };
+    // Only serialize udp_enabled and version if they're not the defaults
+    let is_default_version = is_default_snell_version(version);
+    let field_count = 2 + usize::from(!*udp_enabled) + usize::from(!is_default_version);
     let mut state = serializer.serialize_struct("Snell", field_count)?;
     config.serialize_fields(&mut state)?;
     if !*udp_enabled {
         state.serialize_field("udp_enabled", udp_enabled)?;
     }
+    if !is_default_version {
+        state.serialize_field("version", version)?;
+    }
     state.end()
 }
 
 /// Custom deserializer for ClientProxyConfig::Vmess that validates legacy aead field
-fn deserialize_vmess_client<'de, D>(deserializer: D) -> Result<(String, String, bool), D::Error>
+fn deserialize_vmess_client<'de, D>(
+    deserializer: D,
+) -> Result<(String, String, bool, Option<MuxConfig>, bool, bool), D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -120,6 +149,12 @@ where
         aead: Option<bool>,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        #[serde(default)]
+        mux: Option<MuxConfig>,
+        #[serde(default = "default_true")]
+        chunk_masking: bool,
+        #[serde(default)]
+        global_padding: bool,
     }
 
     let temp = VmessClientTemp::deserialize(deserializer)?;
@@ -139,7 +174,14 @@ where
         );
     }
 
-    Ok((temp.cipher, temp.user_id, temp.udp_enabled))
+    Ok((
+        temp.cipher,
+        temp.user_id,
+        temp.udp_enabled,
+        temp.mux,
+        temp.chunk_masking,
+        temp.global_padding,
+    ))
 }
 
 /// Custom deserializer for TlsClientConfig that handles deprecated shadowtls_password field
@@ -170,6 +212,12 @@ where
         shadowtls_password: Option<String>,
         #[serde(default)]
         vision: bool,
+        #[serde(default)]
+        client_fingerprint: Option<TlsFingerprint>,
+        #[serde(default)]
+        ech_config_list: Option<String>,
+        #[serde(default)]
+        post_quantum: Option<bool>,
         protocol: Box<ClientProxyConfig>,
     }
 
@@ -183,6 +231,11 @@ where
              Use either 'vision: true' with regular TLS, or 'type: shadowtls' for ShadowTLS.",
         ));
     }
+    if temp.ech_config_list.is_some() && temp.shadowtls_password.is_some() {
+        return Err(Error::custom(
+            "TLS client config cannot have both ech_config_list and shadowtls_password set.",
+        ));
+    }
 
     // Check if deprecated shadowtls_password was used
     if let Some(password) = temp.shadowtls_password {
@@ -202,9 +255,13 @@ where
             key: temp.key,
             cert: temp.cert,
             vision: false,
+            client_fingerprint: None,
+            ech_config_list: None,
+            post_quantum: None,
             protocol: Box::new(ClientProxyConfig::ShadowTls {
                 password,
                 sni_hostname: temp.sni_hostname.into_option(),
+                client_fingerprint: temp.client_fingerprint,
                 protocol: temp.protocol,
             }),
         });
@@ -220,6 +277,9 @@ where
         key: temp.key,
         cert: temp.cert,
         vision: temp.vision,
+        client_fingerprint: temp.client_fingerprint,
+        ech_config_list: temp.ech_config_list,
+        post_quantum: temp.post_quantum,
         protocol: temp.protocol,
     })
 }
@@ -237,6 +297,24 @@ where
 pub struct ClientConfig {
     #[serde(default, skip_serializing_if = "NoneOrOne::is_unspecified")]
     pub bind_interface: NoneOrOne<String>,
+    /// Firewall mark (`SO_MARK`) of outgoing sockets, for policy routing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fwmark: Option<u32>,
+    /// DSCP value (0-63) of outgoing packets.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dscp: Option<u8>,
+    /// How the hostname that the client connects to is resolved: the destination for
+    /// `direct`, or the proxy server address.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub domain_strategy: Option<DomainStrategy>,
+    /// Resolves the hostnames that are sent to the proxy locally, with `domain_strategy`,
+    /// and sends an IP address instead. By default, the proxy resolves them.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub resolve_locally: bool,
+    /// Tunnels UDP over TCP streams of the protocol with UoT V2 connect mode, instead of
+    /// the UDP support of the protocol. The server has to support UoT.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub udp_over_tcp: bool,
     #[serde(
         default = "unspecified_address",
         skip_serializing_if = "NetLocation::is_unspecified"
@@ -247,19 +325,31 @@ pub struct ClientConfig {
     pub transport: Transport,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub tcp_settings: Option<TcpConfig>,
+    /// Connection attempts to destinations with several addresses, TCP transport only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub happy_eyeballs: Option<HappyEyeballsConfig>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub quic_settings: Option<ClientQuicConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kcp_settings: Option<KcpConfig>,
 }
 
 impl Default for ClientConfig {
     fn default() -> Self {
         Self {
             bind_interface: NoneOrOne::None,
+            fwmark: None,
+            dscp: None,
+            domain_strategy: None,
+            resolve_locally: false,
+            udp_over_tcp: false,
             address: unspecified_address(),
             protocol: ClientProxyConfig::Direct,
             transport: Transport::default(),
             tcp_settings: None,
+            happy_eyeballs: None,
             quic_settings: None,
+            kcp_settings: None,
         }
     }
 }
@@ -268,6 +358,7 @@ impl Default for ClientConfig {
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ClientProxyConfig {
     Direct,
+    #[serde(alias = "http_proxy")]
     Http {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         username: Option<String>,
@@ -277,6 +368,10 @@ pub enum ClientProxyConfig {
         /// Used when the upstream proxy blocks by hostname.
         #[serde(default, skip_serializing_if = "is_false")]
         resolve_hostname: bool,
+        /// Extra headers of the CONNECT request, e.g. a `User-Agent` that a corporate
+        /// proxy requires.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
     },
     #[serde(alias = "socks5")]
     Socks {
@@ -284,6 +379,11 @@ pub enum ClientProxyConfig {
         username: Option<String>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         password: Option<String>,
+        /// Sends UDP through the server with UDP ASSOCIATE. Datagrams go from this host
+        /// to the relay of the server, so this is only allowed at hop 0 and without
+        /// wrapping protocols.
+        #[serde(default, skip_serializing_if = "is_false")]
+        udp_enabled: bool,
     },
     #[serde(
         alias = "ss",
@@ -293,6 +393,8 @@ pub enum ClientProxyConfig {
     Shadowsocks {
         config: ShadowsocksConfig,
         udp_enabled: bool,
+        /// SIP003 plugin that carries connections to the server (hop 0 only)
+        plugin: Option<ShadowsocksPluginConfig>,
     },
     #[serde(
         deserialize_with = "deserialize_snell_client",
@@ -301,16 +403,30 @@ pub enum ClientProxyConfig {
     Snell {
         config: ShadowsocksConfig,
         udp_enabled: bool,
+        /// Protocol version, 2 or 3. UDP requires version 3.
+        version: u8,
     },
     Vless {
         user_id: String,
         #[serde(default = "default_true", skip_serializing_if = "is_true")]
         udp_enabled: bool,
+        /// Send UDP in XUDP frames over a MUX connection, which gets full-cone NAT from
+        /// the server, instead of a UDP connection per target.
+        #[serde(default, skip_serializing_if = "is_false")]
+        xudp: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mux: Option<MuxConfig>,
+        /// Flow of Xray configs. `xtls-rprx-vision` turns on Vision in the enclosing
+        /// TLS or Reality config, like `vision: true`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flow: Option<VlessFlow>,
     },
     Trojan {
         password: String,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         shadowsocks: Option<ShadowsocksConfig>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mux: Option<MuxConfig>,
     },
     Reality {
         public_key: String,
@@ -334,6 +450,11 @@ pub enum ClientProxyConfig {
         #[serde(default, skip_serializing_if = "is_false")]
         vision: bool,
 
+        /// Browser whose ClientHello is imitated (optional)
+        /// Valid values: "chrome", "firefox", "safari", "random"
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_fingerprint: Option<TlsFingerprint>,
+
         protocol: Box<ClientProxyConfig>,
     },
     #[serde(alias = "shadowtls")]
@@ -345,6 +466,10 @@ pub enum ClientProxyConfig {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         sni_hostname: Option<String>,
 
+        /// Browser whose cipher suite and group preferences are used (optional)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        client_fingerprint: Option<TlsFingerprint>,
+
         /// Inner protocol (typically VLESS, Trojan, etc.)
         protocol: Box<ClientProxyConfig>,
     },
@@ -356,9 +481,37 @@ pub enum ClientProxyConfig {
         user_id: String,
         #[serde(default = "default_true", skip_serializing_if = "is_true")]
         udp_enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        mux: Option<MuxConfig>,
+        /// Masks the length of each chunk. Turned off by the `zero` cipher.
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        chunk_masking: bool,
+        /// Adds random padding to each chunk. Requires chunk masking.
+        #[serde(default, skip_serializing_if = "is_false")]
+        global_padding: bool,
     },
     #[serde(alias = "ws")]
     Websocket(WebsocketClientConfig),
+    /// gRPC transport (v2ray "gun"), wrapping the inner protocol in a gRPC stream.
+    Grpc(GrpcClientConfig),
+    /// HTTPUpgrade transport (Xray/sing-box "httpupgrade"), wrapping the inner
+    /// protocol after an HTTP/1.1 upgrade.
+    #[serde(alias = "http_upgrade")]
+    HttpUpgrade(HttpUpgradeClientConfig),
+    /// XHTTP transport (Xray "xhttp", formerly SplitHTTP), wrapping the inner
+    /// protocol in HTTP/2 requests.
+    #[serde(alias = "splithttp")]
+    Xhttp(XhttpClientConfig),
+    /// obfs4 style obfuscation layer, wrapping the inner protocol.
+    Obfs4 {
+        /// The server's X25519 public key, base64url encoded.
+        public_key: String,
+        /// The server's 20 byte node ID, base64url encoded.
+        node_id: String,
+        #[serde(default, skip_serializing_if = "Obfs4IatMode::is_default")]
+        iat_mode: Obfs4IatMode,
+        protocol: Box<ClientProxyConfig>,
+    },
     #[serde(alias = "noop")]
     PortForward,
     /// AnyTLS outbound protocol
@@ -372,6 +525,15 @@ pub enum ClientProxyConfig {
         /// Each line is a key=value pair like "stop=8" or "0=30-30"
         #[serde(default, skip_serializing_if = "Option::is_none")]
         padding_scheme: Option<Vec<String>>,
+        /// Seconds before an idle session is closed (default: 30)
+        #[serde(
+            default = "default_anytls_idle_session_timeout_secs",
+            skip_serializing_if = "is_default_anytls_idle_session_timeout_secs"
+        )]
+        idle_session_timeout_secs: u64,
+        /// Padding and delays of the writes after the padding scheme ends (optional)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        traffic_padding: Option<TrafficPaddingConfig>,
     },
     /// NaiveProxy client protocol (HTTP/2 CONNECT with padding)
     #[serde(alias = "naive")]
@@ -394,18 +556,184 @@ pub enum ClientProxyConfig {
         /// Bandwidth configuration
         #[serde(default)]
         bandwidth: Option<Hysteria2Bandwidth>,
+        /// Obfuscation of QUIC packets, must match the server
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        obfs: Option<Hysteria2ObfsConfig>,
     },
+    /// MASQUE proxy, reached over HTTP/3. Like Hysteria2, it requires
+    /// `transport: quic` and terminates the chain at hop 0.
+    Masque {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// Juicity proxy. Like Hysteria2, it requires `transport: quic` and terminates the
+    /// chain at hop 0.
+    Juicity {
+        uuid: String,
+        password: String,
+    },
+    /// SSH outbound: opens a `direct-tcpip` channel to the destination.
+    Ssh {
+        username: String,
+        /// Password authentication (mutually exclusive with private_key)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+        /// OpenSSH private key, either inline PEM data or a file path
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private_key: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        private_key_passphrase: Option<String>,
+        /// Accepted server host key fingerprints ("SHA256:..."). Required unless
+        /// `verify_host_key` is false.
+        #[serde(
+            alias = "host_key_fingerprint",
+            default,
+            skip_serializing_if = "NoneOrSome::is_unspecified"
+        )]
+        host_key_fingerprints: NoneOrSome<String>,
+        /// Accept any server host key when false, which lets an on-path attacker
+        /// intercept the session.
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        verify_host_key: bool,
+    },
+    /// WireGuard outbound. Like Hysteria2, it terminates the chain at hop 0:
+    /// `address` is the peer endpoint and connections are made inside the tunnel.
+    #[serde(alias = "wg")]
+    Wireguard {
+        /// Local private key (base64)
+        private_key: String,
+        /// Peer public key (base64)
+        peer_public_key: String,
+        /// Optional preshared key (base64)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        preshared_key: Option<String>,
+        /// Local tunnel addresses, e.g. "172.16.0.2/32"
+        #[serde(alias = "local_address")]
+        local_addresses: OneOrSome<String>,
+        /// Destinations routed through the peer (default: everything)
+        #[serde(alias = "allowed_ip", default = "default_wireguard_allowed_ips")]
+        allowed_ips: OneOrSome<String>,
+        /// Three bytes written into every message header (used by Cloudflare WARP)
+        #[serde(default, skip_serializing_if = "NoneOrSome::is_unspecified")]
+        reserved: NoneOrSome<u8>,
+        #[serde(default = "default_wireguard_mtu")]
+        mtu: u16,
+        /// Persistent keepalive interval in seconds
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        persistent_keepalive: Option<u16>,
+        #[serde(default = "default_true", skip_serializing_if = "is_true")]
+        udp_enabled: bool,
+    },
+    /// Portal of a reverse proxy. Like WireGuard, it terminates the chain at hop 0:
+    /// connections are made through the tunnels of bridges that connected to a server
+    /// with `domain` as their destination, and `address` is not used.
+    Portal {
+        domain: String,
+    },
+}
+
+/// Stream multiplexing (mux.cool) of a VMess, VLESS or Trojan outbound: TCP connections
+/// share connections to the server instead of each opening their own.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MuxConfig {
+    /// Maximum number of connections carried by one connection to the server
+    #[serde(default = "default_mux_concurrency")]
+    pub concurrency: usize,
+}
+
+/// Padding of an outbound's traffic beyond what its protocol does by default, to make
+/// the sizes and timing of writes harder to classify.
+///
+/// ```yaml
+/// traffic_padding:
+///   overhead_percent: 10
+///   max_delay_ms: 20
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct TrafficPaddingConfig {
+    /// Padding bytes sent per 100 bytes of payload, at most.
+    #[serde(default = "default_traffic_padding_overhead_percent")]
+    pub overhead_percent: u32,
+    /// Writes are delayed by a random time up to this many milliseconds. Not delayed
+    /// when 0.
+    #[serde(default)]
+    pub max_delay_ms: u64,
+}
+
+fn default_traffic_padding_overhead_percent() -> u32 {
+    10
+}
+
+/// Flow of a VLESS user
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum VlessFlow {
+    #[serde(rename = "xtls-rprx-vision")]
+    XtlsRprxVision,
+}
+
+fn default_mux_concurrency() -> usize {
+    8
+}
+
+fn default_wireguard_allowed_ips() -> OneOrSome<String> {
+    OneOrSome::Some(vec!["0.0.0.0/0".to_string(), "::/0".to_string()])
+}
+
+fn default_wireguard_mtu() -> u16 {
+    1420
 }
 
 /// Bandwidth configuration for Hysteria2
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Hysteria2Bandwidth {
     /// Upload bandwidth (e.g., "100 mbps", "1 gbps")
+    #[serde(
+        default,
+        alias = "up_mbps",
+        deserialize_with = "deserialize_bandwidth_value"
+    )]
     pub up: Option<String>,
     /// Download bandwidth (e.g., "200 mbps", "1 gbps")
+    #[serde(
+        default,
+        alias = "down_mbps",
+        deserialize_with = "deserialize_bandwidth_value"
+    )]
     pub down: Option<String>,
 }
 
+/// Obfuscation of Hysteria2 QUIC packets
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum Hysteria2ObfsConfig {
+    /// XORs packets with a key derived from the password and a random salt
+    Salamander { password: String },
+}
+
+/// Accepts bandwidth strings, or numbers in Mbps like `up_mbps: 100`.
+fn deserialize_bandwidth_value<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BandwidthValue {
+        Number(f64),
+        String(String),
+    }
+
+    Ok(
+        Option::<BandwidthValue>::deserialize(deserializer)?.map(|value| match value {
+            BandwidthValue::Number(mbps) => format!("{mbps} mbps"),
+            BandwidthValue::String(s) => s,
+        }),
+    )
+}
+
 impl Hysteria2Bandwidth {
     /// Parse upload bandwidth to bytes per second
     pub fn parse_up(&self) -> std::io::Result<u64> {
@@ -424,44 +752,7 @@ impl Hysteria2Bandwidth {
                 format!("bandwidth {} not specified", field),
             )
         })?;
-
-        let s = s.trim().to_lowercase();
-        // Find first non-digit, non-dot, non-space character to separate number from unit
-        let mut num_end = 0;
-        for (i, c) in s.chars().enumerate() {
-            if c.is_ascii_digit() || c == '.' {
-                num_end = i + 1;
-            } else if !c.is_whitespace() {
-                break;
-            }
-        }
-
-        let num_str = s[..num_end].trim();
-        let unit = s[num_end..].trim();
-
-        let num: f64 = num_str.parse().map_err(|_| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidInput,
-                format!("invalid bandwidth value: {}", s),
-            )
-        })?;
-
-        let multiplier = match unit {
-            "bps" => 1.0,
-            "kbps" | "k" => 1024.0,
-            "mbps" | "m" => 1024.0 * 1024.0,
-            "gbps" | "g" => 1024.0 * 1024.0 * 1024.0,
-            "tbps" | "t" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
-            "" => 1024.0 * 1024.0, // Default to mbps if no unit
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidInput,
-                    format!("unknown bandwidth unit: {}", unit),
-                ));
-            }
-        };
-
-        Ok((num * multiplier / 8.0) as u64) // Convert bits to bytes
+        parse_bandwidth(s)
     }
 }
 
@@ -483,6 +774,40 @@ impl ClientProxyConfig {
         matches!(self, ClientProxyConfig::Direct)
     }
 
+    /// Returns the mux config of the innermost protocol, if it has one.
+    pub fn mux_config(&self) -> Option<&MuxConfig> {
+        match self {
+            ClientProxyConfig::Vless { mux, .. }
+            | ClientProxyConfig::Vmess { mux, .. }
+            | ClientProxyConfig::Trojan { mux, .. } => mux.as_ref(),
+            ClientProxyConfig::Tls(TlsClientConfig { protocol, .. })
+            | ClientProxyConfig::Reality { protocol, .. }
+            | ClientProxyConfig::ShadowTls { protocol, .. }
+            | ClientProxyConfig::Websocket(WebsocketClientConfig { protocol, .. })
+            | ClientProxyConfig::Grpc(GrpcClientConfig { protocol, .. })
+            | ClientProxyConfig::HttpUpgrade(HttpUpgradeClientConfig { protocol, .. })
+            | ClientProxyConfig::Xhttp(XhttpClientConfig { protocol, .. })
+            | ClientProxyConfig::Obfs4 { protocol, .. } => protocol.mux_config(),
+            _ => None,
+        }
+    }
+
+    /// Returns the innermost protocol if it is AnyTLS, whose sessions are pooled.
+    pub fn anytls_config(&self) -> Option<&ClientProxyConfig> {
+        match self {
+            ClientProxyConfig::Anytls { .. } => Some(self),
+            ClientProxyConfig::Tls(TlsClientConfig { protocol, .. })
+            | ClientProxyConfig::Reality { protocol, .. }
+            | ClientProxyConfig::ShadowTls { protocol, .. }
+            | ClientProxyConfig::Websocket(WebsocketClientConfig { protocol, .. })
+            | ClientProxyConfig::Grpc(GrpcClientConfig { protocol, .. })
+            | ClientProxyConfig::HttpUpgrade(HttpUpgradeClientConfig { protocol, .. })
+            | ClientProxyConfig::Xhttp(XhttpClientConfig { protocol, .. })
+            | ClientProxyConfig::Obfs4 { protocol, .. } => protocol.anytls_config(),
+            _ => None,
+        }
+    }
+
     /// Returns the protocol name for display/error messages
     pub fn protocol_name(&self) -> &str {
         match self {
@@ -498,10 +823,19 @@ impl ClientProxyConfig {
             ClientProxyConfig::ShadowTls { .. } => "ShadowTLS",
             ClientProxyConfig::Vmess { .. } => "VMess",
             ClientProxyConfig::Websocket(..) => "WebSocket",
+            ClientProxyConfig::Grpc(..) => "gRPC",
+            ClientProxyConfig::HttpUpgrade(..) => "HTTPUpgrade",
+            ClientProxyConfig::Xhttp(..) => "XHTTP",
+            ClientProxyConfig::Obfs4 { .. } => "obfs4",
             ClientProxyConfig::PortForward => "PortForward",
             ClientProxyConfig::Anytls { .. } => "AnyTLS",
             ClientProxyConfig::Naiveproxy { .. } => "NaiveProxy",
             ClientProxyConfig::Hysteria2 { .. } => "Hysteria2",
+            ClientProxyConfig::Masque { .. } => "MASQUE",
+            ClientProxyConfig::Juicity { .. } => "Juicity",
+            ClientProxyConfig::Ssh { .. } => "SSH",
+            ClientProxyConfig::Wireguard { .. } => "WireGuard",
+            ClientProxyConfig::Portal { .. } => "Portal",
         }
     }
 }
@@ -537,17 +871,105 @@ pub struct TlsClientConfig {
     #[serde(default, skip_serializing_if = "is_false")]
     pub vision: bool,
 
+    /// Browser whose cipher suite and group preferences are used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_fingerprint: Option<TlsFingerprint>,
+
+    /// Base64 ECHConfigList of the server. The SNI is then only sent encrypted, and the
+    /// public name of the ECH config is sent in the clear.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ech_config_list: Option<String>,
+
+    /// Key exchange with the hybrid X25519MLKEM768 group. When unset, rustls defaults are
+    /// used. When true, the hybrid group is preferred. When false, only classical groups
+    /// are offered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_quantum: Option<bool>,
+
     pub protocol: Box<ClientProxyConfig>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct WebsocketClientConfig {
-    #[serde(default, skip_serializing_if = "Option::is_none")]
+    /// Path of the upgrade request.
+    #[serde(alias = "path", default, skip_serializing_if = "Option::is_none")]
     pub matching_path: Option<String>,
+    /// Host header of the upgrade request, e.g. the CDN hostname. Defaults to a Host
+    /// in `matching_headers`, then to the TLS SNI hostname.
     #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// Extra headers of the upgrade request.
+    #[serde(alias = "headers", default, skip_serializing_if = "Option::is_none")]
     pub matching_headers: Option<HashMap<String, String>>,
     #[serde(default, skip_serializing_if = "WebsocketPingType::is_default")]
     pub ping_type: WebsocketPingType,
+    /// Bytes of the first write sent in the upgrade request, as v2ray and Xray early
+    /// data. Defaults to the `ed` parameter of the path, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_early_data: Option<usize>,
+    pub protocol: Box<ClientProxyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct GrpcClientConfig {
+    #[serde(
+        default = "default_grpc_service_name",
+        skip_serializing_if = "is_default_grpc_service_name"
+    )]
+    pub service_name: String,
+    /// HTTP/2 :authority, e.g. the CDN hostname. Defaults to the server hostname
+    /// when gRPC is the outermost protocol of the hop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    pub protocol: Box<ClientProxyConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HttpUpgradeClientConfig {
+    /// Request path (default: /).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Host header, e.g. the CDN hostname. Defaults to the server hostname when
+    /// HTTPUpgrade is the outermost protocol of the hop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<HashMap<String, String>>,
+    pub protocol: Box<ClientProxyConfig>,
+}
+
+/// How the XHTTP client sends its uploads.
+#[derive(Default, Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum XhttpMode {
+    /// Every upload is a numbered POST request, which passes through any CDN.
+    #[default]
+    PacketUp,
+    /// Uploads are streamed in one POST request, next to the download request.
+    StreamUp,
+    /// Both directions are streamed in a single POST request.
+    StreamOne,
+}
+
+impl XhttpMode {
+    pub fn is_default(&self) -> bool {
+        matches!(self, XhttpMode::PacketUp)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct XhttpClientConfig {
+    #[serde(
+        default = "default_xhttp_path",
+        skip_serializing_if = "is_default_xhttp_path"
+    )]
+    pub path: String,
+    /// HTTP/2 :authority, e.g. the CDN hostname. Defaults to the server hostname
+    /// when XHTTP is the outermost protocol of the hop.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(default, skip_serializing_if = "XhttpMode::is_default")]
+    pub mode: XhttpMode,
     pub protocol: Box<ClientProxyConfig>,
 }
 
@@ -559,14 +981,22 @@ mod tests {
     fn create_test_client_config() -> ClientConfig {
         ClientConfig {
             bind_interface: NoneOrOne::One("eth0".to_string()),
+            fwmark: None,
+            dscp: None,
+            domain_strategy: None,
+            resolve_locally: false,
+            udp_over_tcp: false,
             address: NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 1080),
             protocol: ClientProxyConfig::Socks {
                 username: Some("client_user".to_string()),
                 password: Some("client_pass".to_string()),
+                udp_enabled: false,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
+            happy_eyeballs: None,
             quic_settings: None,
+            kcp_settings: None,
         }
     }
 
@@ -683,6 +1113,203 @@ protocol:
         assert!(matches!(result.unwrap(), ClientProxyConfig::Websocket(_)));
     }
 
+    #[test]
+    fn test_websocket_client_config_early_data() {
+        let yaml = r#"
+type: websocket
+matching_path: "/ws"
+max_early_data: 2048
+protocol:
+  type: direct
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::Websocket(config) => {
+                assert_eq!(config.max_early_data, Some(2048));
+            }
+            _ => panic!("Expected websocket config"),
+        }
+    }
+
+    #[test]
+    fn test_websocket_client_config_headers() {
+        let yaml = r#"
+type: websocket
+path: "/ws"
+host: cdn.example.com
+headers:
+  User-Agent: Mozilla/5.0
+protocol:
+  type: direct
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::Websocket(config) => {
+                assert_eq!(config.matching_path.as_deref(), Some("/ws"));
+                assert_eq!(config.host.as_deref(), Some("cdn.example.com"));
+                assert_eq!(
+                    config.matching_headers.unwrap().get("User-Agent").unwrap(),
+                    "Mozilla/5.0"
+                );
+            }
+            _ => panic!("Expected websocket config"),
+        }
+    }
+
+    #[test]
+    fn test_grpc_client_config() {
+        let yaml = r#"
+type: grpc
+protocol:
+  type: vless
+  user_id: "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4"
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::Grpc(config) => {
+                assert_eq!(config.service_name, "GunService");
+                assert!(config.host.is_none());
+            }
+            _ => panic!("Expected Grpc config"),
+        }
+    }
+
+    #[test]
+    fn test_xhttp_client_config() {
+        let yaml = r#"
+type: xhttp
+path: /xhttp
+mode: stream-up
+protocol:
+  type: vless
+  user_id: "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4"
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::Xhttp(config) => {
+                assert_eq!(config.path, "/xhttp");
+                assert_eq!(config.mode, XhttpMode::StreamUp);
+                assert!(config.host.is_none());
+            }
+            _ => panic!("Expected Xhttp config"),
+        }
+    }
+
+    #[test]
+    fn test_httpupgrade_client_config() {
+        let yaml = r#"
+type: httpupgrade
+path: /up
+host: cdn.example.com
+protocol:
+  type: vless
+  user_id: "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4"
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::HttpUpgrade(config) => {
+                assert_eq!(config.path.as_deref(), Some("/up"));
+                assert_eq!(config.host.as_deref(), Some("cdn.example.com"));
+                assert!(config.headers.is_none());
+            }
+            _ => panic!("Expected HttpUpgrade config"),
+        }
+    }
+
+    #[test]
+    fn test_obfs4_client_config() {
+        let yaml = r#"
+type: obfs4
+public_key: "SERVER_PUBLIC_KEY"
+node_id: "SERVER_NODE_ID"
+iat_mode: enabled
+protocol:
+  type: vless
+  user_id: "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4"
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::Obfs4 {
+                iat_mode, protocol, ..
+            } => {
+                assert_eq!(iat_mode, Obfs4IatMode::Enabled);
+                assert!(matches!(*protocol, ClientProxyConfig::Vless { .. }));
+            }
+            _ => panic!("Expected Obfs4 config"),
+        }
+    }
+
+    #[test]
+    fn test_client_fingerprint() {
+        let yaml = r#"
+type: reality
+public_key: "SERVER_PUBLIC_KEY"
+sni_hostname: "www.example.com"
+client_fingerprint: firefox
+protocol:
+  type: vless
+  user_id: "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4"
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            result,
+            ClientProxyConfig::Reality {
+                client_fingerprint: Some(TlsFingerprint::Firefox),
+                ..
+            }
+        ));
+        let serialized = serde_yaml::to_string(&result).unwrap();
+        assert!(serialized.contains("client_fingerprint: firefox"));
+
+        let yaml = r#"
+type: tls
+client_fingerprint: chrome
+protocol:
+  type: socks
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match result {
+            ClientProxyConfig::Tls(config) => {
+                assert_eq!(config.client_fingerprint, Some(TlsFingerprint::Chrome));
+            }
+            _ => panic!("Expected Tls config"),
+        }
+
+        let yaml = r#"
+type: tls
+client_fingerprint: netscape
+protocol:
+  type: socks
+"#;
+        assert!(serde_yaml::from_str::<ClientProxyConfig>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_tls_post_quantum() {
+        let yaml = r#"
+type: tls
+post_quantum: true
+protocol:
+  type: socks
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        match &result {
+            ClientProxyConfig::Tls(config) => assert_eq!(config.post_quantum, Some(true)),
+            _ => panic!("Expected Tls config"),
+        }
+        let serialized = serde_yaml::to_string(&result).unwrap();
+        assert!(serialized.contains("post_quantum: true"));
+
+        let yaml = r#"
+type: tls
+protocol:
+  type: socks
+"#;
+        let result: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let serialized = serde_yaml::to_string(&result).unwrap();
+        assert!(!serialized.contains("post_quantum"));
+    }
+
     #[test]
     fn test_client_proxy_config_hysteria2() {
         let yaml = r#"
@@ -711,4 +1338,271 @@ password: "test_password"
             ClientProxyConfig::Hysteria2 { .. }
         ));
     }
+
+    #[test]
+    fn test_client_proxy_config_hysteria2_bandwidth() {
+        let yaml = r#"
+type: hysteria2
+password: "test_password"
+bandwidth:
+  up_mbps: 100
+  down: "1 gbps"
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let ClientProxyConfig::Hysteria2 { bandwidth, .. } = config else {
+            panic!("Expected Hysteria2 protocol");
+        };
+        let (up, down) = resolve_hysteria2_bandwidth(&bandwidth).unwrap();
+        assert_eq!(up, 100 * 1024 * 1024 / 8);
+        assert_eq!(down, 1024 * 1024 * 1024 / 8);
+    }
+
+    #[test]
+    fn test_client_proxy_config_hysteria2_obfs() {
+        let yaml = r#"
+type: hysteria2
+password: "test_password"
+obfs:
+  type: salamander
+  password: "obfs_password"
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let ClientProxyConfig::Hysteria2 { obfs, .. } = config else {
+            panic!("Expected Hysteria2 protocol");
+        };
+        let Some(Hysteria2ObfsConfig::Salamander { password }) = obfs else {
+            panic!("Expected salamander obfs");
+        };
+        assert_eq!(password, "obfs_password");
+    }
+
+    #[test]
+    fn test_client_proxy_config_shadowsocks_plugin() {
+        let yaml = r#"
+type: shadowsocks
+cipher: aes-256-gcm
+password: secret
+plugin: obfs-local
+plugin_opts: "obfs=http;obfs-host=www.example.com"
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let ClientProxyConfig::Shadowsocks { ref plugin, .. } = config else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        assert_eq!(
+            plugin.as_ref(),
+            Some(&ShadowsocksPluginConfig {
+                plugin: "obfs-local".to_string(),
+                plugin_opts: Some("obfs=http;obfs-host=www.example.com".to_string()),
+            })
+        );
+
+        let yaml_str = serde_yaml::to_string(&config).unwrap();
+        assert!(yaml_str.contains("plugin: obfs-local"));
+    }
+
+    #[test]
+    fn test_client_proxy_config_shadowsocks_identity_keys() {
+        let yaml = r#"
+type: shadowsocks
+cipher: 2022-blake3-aes-128-gcm
+password: "AAECAwQFBgcICQoLDA0ODw==:EBESExQVFhcYGRobHB0eHw=="
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        let ClientProxyConfig::Shadowsocks {
+            config:
+                ShadowsocksConfig::Aead2022 {
+                    ref key_bytes,
+                    ref identity_keys,
+                    ..
+                },
+            ..
+        } = config
+        else {
+            panic!("Expected Shadowsocks 2022 protocol");
+        };
+        assert_eq!(&key_bytes[..], &(16u8..32).collect::<Vec<_>>()[..]);
+        assert_eq!(identity_keys.len(), 1);
+        assert_eq!(&identity_keys[0][..], &(0u8..16).collect::<Vec<_>>()[..]);
+
+        let yaml_str = serde_yaml::to_string(&config).unwrap();
+        assert!(yaml_str.contains("AAECAwQFBgcICQoLDA0ODw==:EBESExQVFhcYGRobHB0eHw=="));
+
+        // Identity headers are encrypted with AES, and keys must match the cipher.
+        let yaml = r#"
+type: shadowsocks
+cipher: 2022-blake3-chacha20-poly1305
+password: "AAECAwQFBgcICQoLDA0ODwABAgMEBQYHCAkKCwwNDg8=:EBESExQVFhcYGRobHB0eHxAREhMUFRYXGBkaGxwdHh8="
+"#;
+        assert!(serde_yaml::from_str::<ClientProxyConfig>(yaml).is_err());
+        let yaml = r#"
+type: shadowsocks
+cipher: 2022-blake3-aes-256-gcm
+password: "AAECAwQFBgcICQoLDA0ODw==:EBESExQVFhcYGRobHB0eHw=="
+"#;
+        assert!(serde_yaml::from_str::<ClientProxyConfig>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_client_proxy_config_mux() {
+        let yaml = r#"
+type: tls
+protocol:
+  type: vmess
+  cipher: aes-128-gcm
+  user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+  mux:
+    concurrency: 4
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.mux_config().unwrap().concurrency, 4);
+
+        let yaml_str = serde_yaml::to_string(&config).unwrap();
+        let config: ClientProxyConfig = serde_yaml::from_str(&yaml_str).unwrap();
+        assert_eq!(config.mux_config().unwrap().concurrency, 4);
+
+        let yaml = r#"
+type: trojan
+password: secret
+mux: {}
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.mux_config().unwrap().concurrency, 8);
+
+        let yaml = r#"
+type: vless
+user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(config.mux_config().is_none());
+        assert!(!serde_yaml::to_string(&config).unwrap().contains("mux"));
+    }
+
+    #[test]
+    fn test_client_proxy_config_vless_xudp() {
+        let yaml = r#"
+type: vless
+user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+xudp: true
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            config,
+            ClientProxyConfig::Vless { xudp: true, .. }
+        ));
+        assert!(
+            serde_yaml::to_string(&config)
+                .unwrap()
+                .contains("xudp: true")
+        );
+
+        let yaml = r#"
+type: vless
+user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+"#;
+        let config: ClientProxyConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            config,
+            ClientProxyConfig::Vless { xudp: false, .. }
+        ));
+        assert!(!serde_yaml::to_string(&config).unwrap().contains("xudp"));
+    }
+
+    #[test]
+    fn test_client_proxy_config_masque() {
+        let yaml = r#"
+type: masque
+username: user
+password: pass
+"#;
+        let result: Result<ClientProxyConfig, _> = serde_yaml::from_str(yaml);
+        assert!(matches!(
+            result.unwrap(),
+            ClientProxyConfig::Masque {
+                username: Some(_),
+                password: Some(_),
+            }
+        ));
+    }
+
+    #[test]
+    fn test_client_proxy_config_juicity() {
+        let yaml = r#"
+type: juicity
+uuid: 550e8400-e29b-41d4-a716-446655440000
+password: secret
+"#;
+        let result: Result<ClientProxyConfig, _> = serde_yaml::from_str(yaml);
+        let config = result.unwrap();
+        assert!(matches!(config, ClientProxyConfig::Juicity { .. }));
+        assert_eq!(config.protocol_name(), "Juicity");
+    }
+
+    #[test]
+    fn test_client_proxy_config_wireguard() {
+        let yaml = r#"
+type: wireguard
+private_key: "YNXtAzepDqRv9H52osJVDQnznT5AL11eVlIHq3nApGA="
+peer_public_key: "bmXOC+F1FxEMF9dyiK2H5/1SUtzH0JuVo51h2wPfgyo="
+local_address: "172.16.0.2/32"
+reserved: [1, 2, 3]
+"#;
+        let result: Result<ClientProxyConfig, _> = serde_yaml::from_str(yaml);
+        match result.unwrap() {
+            ClientProxyConfig::Wireguard {
+                local_addresses,
+                allowed_ips,
+                reserved,
+                mtu,
+                udp_enabled,
+                ..
+            } => {
+                assert_eq!(local_addresses.into_vec(), vec!["172.16.0.2/32"]);
+                assert_eq!(allowed_ips.into_vec(), vec!["0.0.0.0/0", "::/0"]);
+                assert_eq!(reserved.into_vec(), vec![1, 2, 3]);
+                assert_eq!(mtu, 1420);
+                assert!(udp_enabled);
+            }
+            other => panic!("Expected Wireguard, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_client_proxy_config_portal() {
+        let yaml = r#"
+type: portal
+domain: tunnel.internal
+"#;
+        let result: Result<ClientProxyConfig, _> = serde_yaml::from_str(yaml);
+        match result.unwrap() {
+            ClientProxyConfig::Portal { domain } => assert_eq!(domain, "tunnel.internal"),
+            other => panic!("Expected Portal, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_client_proxy_config_ssh() {
+        let yaml = r#"
+type: ssh
+username: "user"
+password: "pass"
+host_key_fingerprint: "SHA256:noGoI9DR1xuyJQ/nt/PhfV99AYOihJ/bbwA4kbONHvI"
+"#;
+        let result: Result<ClientProxyConfig, _> = serde_yaml::from_str(yaml);
+        match result.unwrap() {
+            ClientProxyConfig::Ssh {
+                username,
+                password,
+                private_key,
+                host_key_fingerprints,
+                ..
+            } => {
+                assert_eq!(username, "user");
+                assert_eq!(password.as_deref(), Some("pass"));
+                assert!(private_key.is_none());
+                assert_eq!(host_key_fingerprints.len(), 1);
+            }
+            other => panic!("Expected Ssh, got {other:?}"),
+        }
+    }
 }
@@ -2,7 +2,7 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::address::{NetLocation, NetLocationMask, NetLocationPortRange};
+use crate::address::{NetLocation, NetLocationMask, NetLocationPortRange, PortRange};
 use crate::option_util::OneOrSome;
 
 /// Default Reality short_id: all zeros (16 hex chars = 8 bytes of zeros)
@@ -28,11 +28,56 @@ pub fn default_reality_server_short_ids() -> OneOrSome<String> {
     OneOrSome::One(DEFAULT_REALITY_SHORT_ID.to_string())
 }
 
+/// AEAD auth IDs allow a 120 second difference from the server time, see authid.go in
+/// v2ray-core.
+pub fn default_vmess_max_time_diff_secs() -> u64 {
+    120
+}
+
+pub fn is_default_vmess_max_time_diff_secs(secs: &u64) -> bool {
+    *secs == default_vmess_max_time_diff_secs()
+}
+
+/// Snell v3, which added UDP relay.
+pub fn default_snell_version() -> u8 {
+    3
+}
+
+pub fn is_default_snell_version(version: &u8) -> bool {
+    *version == default_snell_version()
+}
+
+/// Idle AnyTLS sessions are closed after 30 seconds, like in sing-box.
+pub fn default_anytls_idle_session_timeout_secs() -> u64 {
+    30
+}
+
+pub fn is_default_anytls_idle_session_timeout_secs(secs: &u64) -> bool {
+    *secs == default_anytls_idle_session_timeout_secs()
+}
+
 pub fn default_reality_time_diff() -> Option<u64> {
     // 1 minute
     Some(1000 * 60)
 }
 
+/// Default gun service name, giving the request path `/GunService/Tun`.
+pub fn default_grpc_service_name() -> String {
+    "GunService".to_string()
+}
+
+pub fn is_default_grpc_service_name(name: &String) -> bool {
+    name == "GunService"
+}
+
+pub fn default_xhttp_path() -> String {
+    "/".to_string()
+}
+
+pub fn is_default_xhttp_path(path: &String) -> bool {
+    path == "/"
+}
+
 pub fn unspecified_address() -> NetLocation {
     NetLocation::UNSPECIFIED
 }
@@ -129,3 +174,30 @@ impl<'de> serde::de::Deserialize<'de> for NetLocationPortRange {
         Ok(net_location_port_range)
     }
 }
+
+impl<'de> serde::de::Deserialize<'de> for PortRange {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::de::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum PortRangeValue {
+            Port(u16),
+            Range(String),
+        }
+
+        match PortRangeValue::deserialize(deserializer)? {
+            PortRangeValue::Port(port) => Ok(PortRange {
+                start: port,
+                end: port,
+            }),
+            PortRangeValue::Range(value) => PortRange::from_str(&value).map_err(|e| {
+                serde::de::Error::invalid_value(
+                    serde::de::Unexpected::Other(&format!("invalid port range: {e}")),
+                    &"valid port range (port[-port])",
+                )
+            }),
+        }
+    }
+}
@@ -0,0 +1,33 @@
+//! Crypto provider configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Crypto provider used by TLS, QUIC and REALITY's TLS connections.
+///
+/// ```yaml
+/// - crypto_provider: ring
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct CryptoConfig {
+    pub crypto_provider: CryptoProviderKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CryptoProviderKind {
+    /// aws-lc-rs, always available.
+    #[default]
+    AwsLcRs,
+    /// ring, available when built with the `ring` feature.
+    Ring,
+}
+
+impl std::fmt::Display for CryptoProviderKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CryptoProviderKind::AwsLcRs => write!(f, "aws-lc-rs"),
+            CryptoProviderKind::Ring => write!(f, "ring"),
+        }
+    }
+}
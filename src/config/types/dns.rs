@@ -2,10 +2,11 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::config::types::common::default_true;
 use crate::config::types::rules::ClientChain;
 use crate::config::types::selection::ConfigSelection;
-use crate::dns::IpStrategy;
-use crate::option_util::NoneOrSome;
+use crate::dns::{DnsTlsVerification, IpStrategy};
+use crate::option_util::{NoneOrSome, OneOrSome};
 
 /// A DNS server specification in config.
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -14,7 +15,8 @@ pub enum DnsServerSpec {
     /// Simple URL string: "system", "udp://8.8.8.8", etc.
     /// Must be IP-based (no hostnames). Cannot have bootstrap_url.
     Simple(String),
-    /// Object with URL and optional client_chain, bootstrap_url, server_name, ip_strategy.
+    /// Object with URL and optional client_chain, bootstrap_url, server_name, ip_strategy,
+    /// verify, server_fingerprints.
     WithOptions {
         url: String,
         #[serde(default)]
@@ -23,12 +25,18 @@ pub enum DnsServerSpec {
         /// Can be a URL string (e.g., "udp://8.8.8.8") or a dns_group name.
         #[serde(default)]
         bootstrap_url: Option<String>,
-        /// SNI server name override for TLS/HTTPS. Defaults to hostname from URL.
+        /// SNI server name override for TLS/HTTPS/QUIC. Defaults to hostname from URL.
         #[serde(default)]
         server_name: Option<String>,
         /// IP lookup strategy for DNS resolution. Defaults to ipv4_then_ipv6.
         #[serde(default)]
         ip_strategy: IpStrategy,
+        /// Verify the server certificate for TLS/HTTPS/QUIC. Defaults to true.
+        #[serde(default = "default_true")]
+        verify: bool,
+        /// SHA256 fingerprints of accepted server certificates for TLS/HTTPS/QUIC.
+        #[serde(alias = "server_fingerprint", default)]
+        server_fingerprints: NoneOrSome<String>,
     },
 }
 
@@ -88,6 +96,24 @@ impl DnsServerSpec {
         }
     }
 
+    /// Get the certificate verification options (defaults to root CA verification for
+    /// Simple variant).
+    pub fn tls_verification(&self) -> DnsTlsVerification {
+        if let Self::WithOptions {
+            verify,
+            server_fingerprints,
+            ..
+        } = self
+        {
+            DnsTlsVerification {
+                verify: *verify,
+                server_fingerprints: server_fingerprints.clone().into_vec(),
+            }
+        } else {
+            DnsTlsVerification::default()
+        }
+    }
+
     /// Get the ip_strategy (defaults to Ipv4ThenIpv6 for Simple variant).
     pub fn ip_strategy(&self) -> IpStrategy {
         if let Self::WithOptions { ip_strategy, .. } = self {
@@ -135,6 +161,32 @@ impl DnsConfig {
     }
 }
 
+/// Upstream DNS servers for queries under specific domains, used by `dns` servers.
+/// Like `DnsConfig`, `servers` is mutated to a single group name reference during
+/// validation.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DnsUpstreamConfig {
+    /// Domains that are resolved by `servers`, including their subdomains.
+    #[serde(alias = "domain")]
+    pub domains: OneOrSome<String>,
+    #[serde(alias = "server")]
+    pub servers: NoneOrSome<DnsServerSpec>,
+}
+
+impl DnsUpstreamConfig {
+    /// Get the resolved group name after validation.
+    /// Panics if called before validation.
+    pub fn resolved_group(&self) -> &str {
+        match &self.servers {
+            NoneOrSome::One(spec) => spec
+                .as_group_ref()
+                .expect("DnsUpstreamConfig.servers should be a group name after validation"),
+            _ => panic!("DnsUpstreamConfig.servers should be a group name after validation"),
+        }
+    }
+}
+
 /// A DNS server spec with all group references expanded.
 /// Client chains contain actual ClientConfig objects, not group names.
 #[derive(Debug, Clone)]
@@ -146,6 +198,7 @@ pub struct ExpandedDnsSpec {
     /// Bootstrap resolver URL or group name. Groups are resolved at runtime.
     pub bootstrap_url: Option<String>,
     pub ip_strategy: IpStrategy,
+    pub tls_verification: DnsTlsVerification,
 }
 
 /// A DNS group with all specs expanded.
@@ -202,6 +255,24 @@ server_name: dns.google
         assert_eq!(spec.server_name(), Some("dns.google"));
     }
 
+    #[test]
+    fn test_dns_server_spec_tls_verification() {
+        let yaml = r#"
+url: quic://10.0.0.53
+verify: false
+server_fingerprint: "ab:cd"
+"#;
+        let spec: DnsServerSpec = serde_yaml::from_str(yaml).unwrap();
+        let verification = spec.tls_verification();
+        assert!(!verification.verify);
+        assert_eq!(verification.server_fingerprints, vec!["ab:cd".to_string()]);
+
+        let spec: DnsServerSpec = serde_yaml::from_str("url: tls://1.1.1.1").unwrap();
+        assert_eq!(spec.tls_verification(), DnsTlsVerification::default());
+        let spec: DnsServerSpec = serde_yaml::from_str("tls://1.1.1.1").unwrap();
+        assert_eq!(spec.tls_verification(), DnsTlsVerification::default());
+    }
+
     #[test]
     fn test_dns_server_spec_with_bootstrap_group_ref() {
         let yaml = r#"
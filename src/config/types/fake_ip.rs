@@ -0,0 +1,19 @@
+//! Fake-IP DNS configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Address ranges that fake-IP DNS answers are allocated from.
+///
+/// ```yaml
+/// - fake_ip_range: 198.18.0.0/15
+///   fake_ip_range6: fc00::/18
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeIpConfig {
+    /// IPv4 CIDR used to answer A queries.
+    pub fake_ip_range: String,
+    /// IPv6 CIDR used to answer AAAA queries. AAAA queries get empty answers when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fake_ip_range6: Option<String>,
+}
@@ -0,0 +1,20 @@
+//! GeoIP and geosite database configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Databases used by `geoip` and `geosite` rules.
+///
+/// ```yaml
+/// - geoip_database: /usr/share/GeoIP/GeoLite2-Country.mmdb
+///   geosite_database: /usr/share/v2ray/geosite.dat
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoConfig {
+    /// Path to a MaxMind `.mmdb` country database. The database is loaded on first use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geoip_database: Option<String>,
+    /// Path to a v2ray `geosite.dat` domain list. The file is loaded on first use.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub geosite_database: Option<String>,
+}
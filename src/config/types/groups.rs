@@ -4,12 +4,28 @@ use serde::{Deserialize, Serialize};
 
 use crate::option_util::OneOrSome;
 
+use super::access_control::DefaultAccessControlConfig;
+use super::access_log::AccessLogConfig;
+use super::auto_ban::AutoBanConfig;
+use super::buffer_pool::BufferPoolConfig;
 use super::client::ClientConfig;
+use super::crypto::CryptoConfig;
 use super::dns::DnsConfigGroup;
+use super::clash_api::ClashApiConfig;
+use super::fake_ip::FakeIpConfig;
+use super::geo::GeoConfig;
+use super::include::IncludeConfig;
+use super::metrics::MetricsConfig;
+use super::privileges::PrivilegesConfig;
+use super::rate_limit::RateLimitConfig;
+use super::reverse::ReverseBridgeConfig;
 use super::rules::RuleConfig;
+use super::runtime::RuntimeConfig;
 use super::selection::ConfigSelection;
 use super::server::ServerConfig;
+use super::subscription::SubscriptionConfig;
 use super::tun::TunConfig;
+use super::udp_sessions::UdpSessionConfig;
 
 /// A named group of client proxies.
 ///
@@ -31,6 +47,33 @@ pub struct RuleConfigGroup {
     pub rules: OneOrSome<RuleConfig>,
 }
 
+/// A named group of users, that servers with per-user credentials refer to with
+/// `user_group`. Users can also be added and removed through the Clash API, until the
+/// config is reloaded.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserGroupConfig {
+    pub user_group: String,
+    #[serde(default, alias = "user")]
+    pub users: Vec<UserConfig>,
+}
+
+/// A user of a user group. Each protocol uses the credential it needs: the password
+/// for trojan, socks and shadowsocks 2022 (a base64 PSK), the user_id for vless and
+/// vmess, and both for tuic.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserConfig {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    #[serde(default, alias = "uuid", skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    /// Bandwidth limits shared by the connections of the user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConfig>,
+}
+
 #[derive(Debug, Clone)]
 pub struct NamedPem {
     pub pem: String, // The name identifier
@@ -156,7 +199,39 @@ pub enum Config {
     ClientConfigGroup(ClientConfigGroup),
     RuleConfigGroup(RuleConfigGroup),
     DnsConfigGroup(DnsConfigGroup),
+    UserGroup(UserGroupConfig),
     NamedPem(NamedPem),
+    /// Prometheus metrics HTTP listener.
+    Metrics(MetricsConfig),
+    /// Clash external-controller API HTTP listener.
+    ClashApi(ClashApiConfig),
+    /// Databases used by geo routing rules.
+    Geo(GeoConfig),
+    /// Address ranges used for fake-IP DNS answers.
+    FakeIp(FakeIpConfig),
+    /// Structured log of forwarded sessions.
+    AccessLog(AccessLogConfig),
+    /// Bridge of a reverse proxy, with connections to a portal.
+    ReverseBridge(ReverseBridgeConfig),
+    /// Limits of UDP session tables.
+    UdpSessions(UdpSessionConfig),
+    /// Sizes of relay buffers and their pool.
+    BufferPool(BufferPoolConfig),
+    /// Threads of the async runtime.
+    Runtime(RuntimeConfig),
+    /// Crypto provider of TLS connections.
+    Crypto(CryptoConfig),
+    /// User and group to switch to after startup.
+    Privileges(PrivilegesConfig),
+    /// Access control of listeners without their own.
+    DefaultAccessControl(DefaultAccessControlConfig),
+    /// Bans of clients that fail handshakes too often.
+    AutoBan(AutoBanConfig),
+    /// Other config files, replaced by their entries when config files are loaded.
+    Include(IncludeConfig),
+    /// Proxies fetched from a subscription URL, replaced by a client group when configs
+    /// are loaded.
+    Subscription(SubscriptionConfig),
 }
 
 impl<'de> serde::de::Deserialize<'de> for Config {
@@ -179,9 +254,41 @@ impl<'de> serde::de::Deserialize<'de> for Config {
         let has_client_group = map.contains_key(Value::String("client_group".to_string()));
         let has_rule_group = map.contains_key(Value::String("rule_group".to_string()));
         let has_dns_group = map.contains_key(Value::String("dns_group".to_string()));
+        let has_user_group = map.contains_key(Value::String("user_group".to_string()));
         let has_address = map.contains_key(Value::String("address".to_string()));
+        let has_interface = map.contains_key(Value::String("interface".to_string()));
         let has_path_field = map.contains_key(Value::String("path".to_string()));
+        let has_systemd = map.contains_key(Value::String("systemd".to_string()));
         let has_pem = map.contains_key(Value::String("pem".to_string()));
+        let has_metrics = map.contains_key(Value::String("metrics".to_string()));
+        let has_clash_api = map.contains_key(Value::String("clash_api".to_string()));
+        let has_geoip_database = map.contains_key(Value::String("geoip_database".to_string()));
+        let has_geosite_database =
+            map.contains_key(Value::String("geosite_database".to_string()));
+        let has_fake_ip_range = map.contains_key(Value::String("fake_ip_range".to_string()));
+        let has_access_log = map.contains_key(Value::String("access_log".to_string()));
+        let has_reverse_bridge = map.contains_key(Value::String("reverse_bridge".to_string()));
+        let has_udp_max_sessions = map.contains_key(Value::String("udp_max_sessions".to_string()));
+        let has_udp_idle_timeout =
+            map.contains_key(Value::String("udp_idle_timeout_secs".to_string()));
+        let has_relay_buffer_size =
+            map.contains_key(Value::String("relay_buffer_size".to_string()));
+        let has_relay_buffer_pool_size =
+            map.contains_key(Value::String("relay_buffer_pool_size".to_string()));
+        let has_worker_threads = map.contains_key(Value::String("worker_threads".to_string()));
+        let has_crypto_provider = map.contains_key(Value::String("crypto_provider".to_string()));
+        let has_user = map.contains_key(Value::String("user".to_string()));
+        let has_group = map.contains_key(Value::String("group".to_string()));
+        let has_default_access_control =
+            map.contains_key(Value::String("default_access_control".to_string()));
+        let has_auto_ban_failures =
+            map.contains_key(Value::String("auto_ban_failures".to_string()));
+        let has_auto_ban_window =
+            map.contains_key(Value::String("auto_ban_window_secs".to_string()));
+        let has_auto_ban_duration =
+            map.contains_key(Value::String("auto_ban_duration_secs".to_string()));
+        let has_include = map.contains_key(Value::String("include".to_string()));
+        let has_subscription = map.contains_key(Value::String("subscription".to_string()));
 
         // Check if this is a TUN config
         // TUN configs have 'device_name' (Linux) or 'device_fd' (iOS/Android)
@@ -196,6 +303,81 @@ impl<'de> serde::de::Deserialize<'de> for Config {
             serde_yaml::from_value(value)
                 .map(Config::NamedPem)
                 .map_err(|e| Error::custom(format!("invalid named PEM config: {e}")))
+        } else if has_metrics {
+            // MetricsConfig
+            serde_yaml::from_value(value)
+                .map(Config::Metrics)
+                .map_err(|e| Error::custom(format!("invalid metrics config: {e}")))
+        } else if has_clash_api {
+            // ClashApiConfig
+            serde_yaml::from_value(value)
+                .map(Config::ClashApi)
+                .map_err(|e| Error::custom(format!("invalid clash_api config: {e}")))
+        } else if has_geoip_database || has_geosite_database {
+            // GeoConfig
+            serde_yaml::from_value(value)
+                .map(Config::Geo)
+                .map_err(|e| Error::custom(format!("invalid geo config: {e}")))
+        } else if has_fake_ip_range {
+            // FakeIpConfig
+            serde_yaml::from_value(value)
+                .map(Config::FakeIp)
+                .map_err(|e| Error::custom(format!("invalid fake IP config: {e}")))
+        } else if has_access_log {
+            // AccessLogConfig
+            serde_yaml::from_value(value)
+                .map(Config::AccessLog)
+                .map_err(|e| Error::custom(format!("invalid access log config: {e}")))
+        } else if has_reverse_bridge {
+            // ReverseBridgeConfig
+            serde_yaml::from_value(value)
+                .map(Config::ReverseBridge)
+                .map_err(|e| Error::custom(format!("invalid reverse bridge config: {e}")))
+        } else if has_udp_max_sessions || has_udp_idle_timeout {
+            // UdpSessionConfig
+            serde_yaml::from_value(value)
+                .map(Config::UdpSessions)
+                .map_err(|e| Error::custom(format!("invalid UDP session config: {e}")))
+        } else if has_relay_buffer_size || has_relay_buffer_pool_size {
+            // BufferPoolConfig
+            serde_yaml::from_value(value)
+                .map(Config::BufferPool)
+                .map_err(|e| Error::custom(format!("invalid buffer pool config: {e}")))
+        } else if has_worker_threads {
+            // RuntimeConfig
+            serde_yaml::from_value(value)
+                .map(Config::Runtime)
+                .map_err(|e| Error::custom(format!("invalid runtime config: {e}")))
+        } else if has_crypto_provider {
+            // CryptoConfig
+            serde_yaml::from_value(value)
+                .map(Config::Crypto)
+                .map_err(|e| Error::custom(format!("invalid crypto config: {e}")))
+        } else if has_user || has_group {
+            // PrivilegesConfig
+            serde_yaml::from_value(value)
+                .map(Config::Privileges)
+                .map_err(|e| Error::custom(format!("invalid privileges config: {e}")))
+        } else if has_default_access_control {
+            // DefaultAccessControlConfig
+            serde_yaml::from_value(value)
+                .map(Config::DefaultAccessControl)
+                .map_err(|e| Error::custom(format!("invalid default access control config: {e}")))
+        } else if has_auto_ban_failures || has_auto_ban_window || has_auto_ban_duration {
+            // AutoBanConfig
+            serde_yaml::from_value(value)
+                .map(Config::AutoBan)
+                .map_err(|e| Error::custom(format!("invalid auto ban config: {e}")))
+        } else if has_include {
+            // IncludeConfig
+            serde_yaml::from_value(value)
+                .map(Config::Include)
+                .map_err(|e| Error::custom(format!("invalid include config: {e}")))
+        } else if has_subscription {
+            // SubscriptionConfig
+            serde_yaml::from_value(value)
+                .map(Config::Subscription)
+                .map_err(|e| Error::custom(format!("invalid subscription config: {e}")))
         } else if has_client_group {
             // ClientConfigGroup
             serde_yaml::from_value(value)
@@ -211,12 +393,17 @@ impl<'de> serde::de::Deserialize<'de> for Config {
             serde_yaml::from_value(value)
                 .map(Config::DnsConfigGroup)
                 .map_err(|e| Error::custom(format!("invalid DNS config group: {e}")))
+        } else if has_user_group {
+            // UserGroupConfig
+            serde_yaml::from_value(value)
+                .map(Config::UserGroup)
+                .map_err(|e| Error::custom(format!("invalid user group: {e}")))
         } else if is_tun_config {
             // TunConfig - identified by having 'name' or 'raw_fd' without 'protocol' wrapper
             serde_yaml::from_value(value)
                 .map(Config::TunServer)
                 .map_err(|e| Error::custom(format!("invalid TUN config: {e}")))
-        } else if has_address || has_path_field {
+        } else if has_address || has_interface || has_path_field || has_systemd {
             // ServerConfig - its custom deserializer validates unknown fields
             serde_yaml::from_value(value)
                 .map(Config::Server)
@@ -230,10 +417,26 @@ impl<'de> serde::de::Deserialize<'de> for Config {
 
             Err(Error::custom(format!(
                 "Unable to determine config type. Found fields: {found_fields:?}. Expected one of:\n\
-                - Server config: must have 'address' or 'path' field\n\
+                - Server config: must have 'address', 'interface', 'path' or 'systemd' field\n\
                 - Client config group: must have 'client_group' field\n\
                 - Rule config group: must have 'rule_group' field\n\
-                - DNS config group: must have 'dns_group' field"
+                - DNS config group: must have 'dns_group' field\n\
+                - User group: must have 'user_group' field\n\
+                - Metrics config: must have 'metrics' field\n\
+                - Clash API config: must have 'clash_api' field\n\
+                - Geo config: must have 'geoip_database' or 'geosite_database' field\n\
+                - Fake IP config: must have 'fake_ip_range' field\n\
+                - Access log config: must have 'access_log' field\n\
+                - Reverse bridge config: must have 'reverse_bridge' field\n\
+                - UDP session config: must have 'udp_max_sessions' or 'udp_idle_timeout_secs' field\n\
+                - Buffer pool config: must have 'relay_buffer_size' or 'relay_buffer_pool_size' field\n\
+                - Runtime config: must have 'worker_threads' field\n\
+                - Crypto config: must have 'crypto_provider' field\n\
+                - Privileges config: must have 'user' or 'group' field\n\
+                - Default access control config: must have 'default_access_control' field\n\
+                - Auto ban config: must have 'auto_ban_failures' field\n\
+                - Include config: must have 'include' field\n\
+                - Subscription config: must have 'subscription' field"
             )))
         }
     }
@@ -250,7 +453,23 @@ impl serde::ser::Serialize for Config {
             Config::ClientConfigGroup(group) => group.serialize(serializer),
             Config::RuleConfigGroup(group) => group.serialize(serializer),
             Config::DnsConfigGroup(group) => group.serialize(serializer),
+            Config::UserGroup(group) => group.serialize(serializer),
             Config::NamedPem(pem) => pem.serialize(serializer),
+            Config::Metrics(metrics) => metrics.serialize(serializer),
+            Config::ClashApi(clash_api) => clash_api.serialize(serializer),
+            Config::Geo(geo) => geo.serialize(serializer),
+            Config::FakeIp(fake_ip) => fake_ip.serialize(serializer),
+            Config::AccessLog(access_log) => access_log.serialize(serializer),
+            Config::ReverseBridge(bridge) => bridge.serialize(serializer),
+            Config::UdpSessions(udp_sessions) => udp_sessions.serialize(serializer),
+            Config::BufferPool(buffer_pool) => buffer_pool.serialize(serializer),
+            Config::Runtime(runtime) => runtime.serialize(serializer),
+            Config::Crypto(crypto) => crypto.serialize(serializer),
+            Config::Privileges(privileges) => privileges.serialize(serializer),
+            Config::DefaultAccessControl(access_control) => access_control.serialize(serializer),
+            Config::AutoBan(auto_ban) => auto_ban.serialize(serializer),
+            Config::Include(include) => include.serialize(serializer),
+            Config::Subscription(subscription) => subscription.serialize(serializer),
         }
     }
 }
@@ -259,24 +478,35 @@ impl serde::ser::Serialize for Config {
 mod tests {
     use super::*;
     use crate::address::{NetLocation, NetLocationMask};
+    use crate::config::types::access_log::AccessLogFormat;
     use crate::config::types::client::ClientProxyConfig;
+    use crate::config::types::crypto::CryptoProviderKind;
     use crate::config::types::rules::ClientChain;
-    use crate::config::types::rules::{ClientChainHop, RuleActionConfig};
+    use crate::config::types::rules::{BalanceStrategy, ClientChainHop, RuleActionConfig};
     use crate::config::types::transport::Transport;
     use crate::option_util::NoneOrSome;
+    use std::collections::HashMap;
     use std::net::{IpAddr, Ipv4Addr};
 
     fn create_test_client_config() -> ClientConfig {
         ClientConfig {
             bind_interface: crate::option_util::NoneOrOne::One("eth0".to_string()),
+            fwmark: None,
+            dscp: None,
+            domain_strategy: None,
+            resolve_locally: false,
+            udp_over_tcp: false,
             address: NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)), 1080),
             protocol: ClientProxyConfig::Socks {
                 username: Some("client_user".to_string()),
                 password: Some("client_pass".to_string()),
+                udp_enabled: false,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
+            happy_eyeballs: None,
             quic_settings: None,
+            kcp_settings: None,
         }
     }
 
@@ -291,12 +521,20 @@ mod tests {
                     IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
                     8080,
                 )),
+                override_port: None,
                 client_chains: NoneOrSome::One(ClientChain {
                     hops: OneOrSome::One(ClientChainHop::Single(ConfigSelection::GroupName(
                         "test-proxy-group".to_string(),
                     ))),
                 }),
+
+                balancer: BalanceStrategy::default(),
+                health_check: None,
+                dial: None,
+                domain_strategy: None,
+                resolve_locally: None,
             },
+            ..Default::default()
         }
     }
 
@@ -308,15 +546,23 @@ mod tests {
                 ConfigSelection::Config(create_test_client_config()),
                 ConfigSelection::Config(ClientConfig {
                     bind_interface: crate::option_util::NoneOrOne::None,
+                    fwmark: None,
+                    dscp: None,
+                    domain_strategy: None,
+                    resolve_locally: false,
+                    udp_over_tcp: false,
                     address: NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53),
                     protocol: ClientProxyConfig::Http {
                         username: None,
                         password: None,
                         resolve_hostname: false,
+                        headers: HashMap::new(),
                     },
                     transport: Transport::Tcp,
                     tcp_settings: None,
+                    happy_eyeballs: None,
                     quic_settings: None,
+                    kcp_settings: None,
                 }),
             ]),
         })];
@@ -380,6 +626,7 @@ client_proxies:
                 RuleConfig {
                     masks: OneOrSome::One(NetLocationMask::ANY),
                     action: RuleActionConfig::Block,
+                    ..Default::default()
                 },
             ]),
         })];
@@ -557,6 +804,303 @@ client_proxies:
         }
     }
 
+    #[test]
+    fn test_config_metrics() {
+        let yaml = "metrics: 127.0.0.1:9100";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::Metrics(metrics) => {
+                assert_eq!(metrics.metrics, "127.0.0.1:9100".parse().unwrap());
+            }
+            _ => panic!("Expected Metrics config"),
+        }
+
+        let serialized = serde_yaml::to_string(&config).unwrap();
+        assert!(matches!(
+            serde_yaml::from_str::<Config>(&serialized).unwrap(),
+            Config::Metrics(_)
+        ));
+
+        let result: Result<Config, _> = serde_yaml::from_str("metrics: not-an-address");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_clash_api() {
+        let yaml = r#"
+clash_api: 127.0.0.1:9090
+secret: my-secret
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::ClashApi(clash_api) => {
+                assert_eq!(clash_api.clash_api, "127.0.0.1:9090".parse().unwrap());
+                assert_eq!(clash_api.secret.as_deref(), Some("my-secret"));
+            }
+            _ => panic!("Expected ClashApi config"),
+        }
+
+        let config: Config = serde_yaml::from_str("clash_api: 127.0.0.1:9090").unwrap();
+        assert!(matches!(config, Config::ClashApi(ClashApiConfig { secret: None, .. })));
+
+        let result: Result<Config, _> =
+            serde_yaml::from_str("clash_api: 127.0.0.1:9090\nsecrets: typo");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_geo() {
+        let config: Config = serde_yaml::from_str("geoip_database: /tmp/country.mmdb").unwrap();
+        match &config {
+            Config::Geo(geo) => {
+                assert_eq!(geo.geoip_database.as_deref(), Some("/tmp/country.mmdb"));
+                assert_eq!(geo.geosite_database, None);
+            }
+            _ => panic!("Expected Geo config"),
+        }
+
+        let config: Config = serde_yaml::from_str("geosite_database: /tmp/geosite.dat").unwrap();
+        match &config {
+            Config::Geo(geo) => {
+                assert_eq!(geo.geoip_database, None);
+                assert_eq!(geo.geosite_database.as_deref(), Some("/tmp/geosite.dat"));
+            }
+            _ => panic!("Expected Geo config"),
+        }
+    }
+
+    #[test]
+    fn test_config_fake_ip() {
+        let yaml = "fake_ip_range: 198.18.0.0/15\nfake_ip_range6: fc00::/18";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::FakeIp(fake_ip) => {
+                assert_eq!(fake_ip.fake_ip_range, "198.18.0.0/15");
+                assert_eq!(fake_ip.fake_ip_range6.as_deref(), Some("fc00::/18"));
+            }
+            _ => panic!("Expected FakeIp config"),
+        }
+
+        let config: Config = serde_yaml::from_str("fake_ip_range: 198.18.0.0/15").unwrap();
+        assert!(matches!(
+            config,
+            Config::FakeIp(FakeIpConfig {
+                fake_ip_range6: None,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_config_access_log() {
+        let yaml = "access_log: /var/log/shoes/access.log\nformat: logfmt\nmax_files: 2";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::AccessLog(access_log) => {
+                assert_eq!(access_log.access_log, "/var/log/shoes/access.log");
+                assert_eq!(access_log.format, AccessLogFormat::Logfmt);
+                assert_eq!(access_log.max_size_mb, 100);
+                assert_eq!(access_log.max_files, 2);
+            }
+            _ => panic!("Expected AccessLog config"),
+        }
+
+        let result: Result<Config, _> = serde_yaml::from_str("access_log: stdout\nformat: xml");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_udp_sessions() {
+        let yaml = "udp_max_sessions: 256\nudp_idle_timeout_secs: 30";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::UdpSessions(udp_sessions) => {
+                assert_eq!(udp_sessions.udp_max_sessions, Some(256));
+                assert_eq!(udp_sessions.udp_idle_timeout_secs, Some(30));
+            }
+            _ => panic!("Expected UdpSessions config"),
+        }
+
+        let config: Config = serde_yaml::from_str("udp_idle_timeout_secs: 30").unwrap();
+        assert!(matches!(
+            config,
+            Config::UdpSessions(UdpSessionConfig {
+                udp_max_sessions: None,
+                ..
+            })
+        ));
+
+        let result: Result<Config, _> =
+            serde_yaml::from_str("udp_max_sessions: 256\nudp_max_age: 30");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_default_access_control() {
+        let yaml =
+            "default_access_control:\n  allow: [10.0.0.0/8, 192.168.1.0/24]\n  deny: 10.0.0.13";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::DefaultAccessControl(config) => {
+                let access_control = &config.default_access_control;
+                assert_eq!(access_control.allow.len(), 2);
+                assert_eq!(access_control.deny.len(), 1);
+            }
+            _ => panic!("Expected DefaultAccessControl config"),
+        }
+
+        let result: Result<Config, _> =
+            serde_yaml::from_str("default_access_control:\n  permit: 10.0.0.0/8");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_auto_ban() {
+        let yaml = "auto_ban_failures: 5\nauto_ban_duration_secs: 3600";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::AutoBan(auto_ban) => {
+                assert_eq!(auto_ban.auto_ban_failures, 5);
+                assert_eq!(auto_ban.auto_ban_window_secs, 60);
+                assert_eq!(auto_ban.auto_ban_duration_secs, 3600);
+            }
+            _ => panic!("Expected AutoBan config"),
+        }
+
+        let result: Result<Config, _> = serde_yaml::from_str("auto_ban_window_secs: 60");
+        assert!(result.is_err());
+        let result: Result<Config, _> =
+            serde_yaml::from_str("auto_ban_failures: 5\nauto_ban_exempt: 10.0.0.1");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_buffer_pool() {
+        let yaml = "relay_buffer_size: 65536\nrelay_buffer_pool_size: 128";
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::BufferPool(buffer_pool) => {
+                assert_eq!(buffer_pool.relay_buffer_size, Some(65536));
+                assert_eq!(buffer_pool.relay_buffer_pool_size, Some(128));
+            }
+            _ => panic!("Expected BufferPool config"),
+        }
+
+        let config: Config = serde_yaml::from_str("relay_buffer_pool_size: 0").unwrap();
+        assert!(matches!(
+            config,
+            Config::BufferPool(BufferPoolConfig {
+                relay_buffer_size: None,
+                ..
+            })
+        ));
+
+        let result: Result<Config, _> =
+            serde_yaml::from_str("relay_buffer_size: 65536\nrelay_buffer_count: 128");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_runtime() {
+        let config: Config = serde_yaml::from_str("worker_threads: 8").unwrap();
+        match config {
+            Config::Runtime(runtime) => assert_eq!(runtime.worker_threads, 8),
+            _ => panic!("Expected Runtime config"),
+        }
+
+        let result: Result<Config, _> =
+            serde_yaml::from_str("worker_threads: 8\nblocking_threads: 4");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_privileges() {
+        let config: Config = serde_yaml::from_str("user: shoes\ngroup: nogroup").unwrap();
+        match config {
+            Config::Privileges(privileges) => {
+                assert_eq!(privileges.user.as_deref(), Some("shoes"));
+                assert_eq!(privileges.group.as_deref(), Some("nogroup"));
+            }
+            _ => panic!("Expected Privileges config"),
+        }
+
+        let result: Result<Config, _> = serde_yaml::from_str("user: shoes\nhome: /var/lib/shoes");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_server_interface() {
+        let config: Config =
+            serde_yaml::from_str("interface: eth0:443\nprotocol:\n  type: http").unwrap();
+        assert!(matches!(config, Config::Server(_)));
+
+        let config: Config =
+            serde_yaml::from_str("systemd: https\nprotocol:\n  type: http").unwrap();
+        assert!(matches!(config, Config::Server(_)));
+    }
+
+    #[test]
+    fn test_config_crypto() {
+        let config: Config = serde_yaml::from_str("crypto_provider: ring").unwrap();
+        match config {
+            Config::Crypto(crypto) => {
+                assert_eq!(crypto.crypto_provider, CryptoProviderKind::Ring)
+            }
+            _ => panic!("Expected Crypto config"),
+        }
+        let config: Config = serde_yaml::from_str("crypto_provider: aws-lc-rs").unwrap();
+        assert!(matches!(
+            config,
+            Config::Crypto(CryptoConfig {
+                crypto_provider: CryptoProviderKind::AwsLcRs
+            })
+        ));
+
+        let result: Result<Config, _> = serde_yaml::from_str("crypto_provider: openssl");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_include() {
+        let config: Config = serde_yaml::from_str("include: [servers.yaml, rules/*.yaml]").unwrap();
+        match config {
+            Config::Include(include) => assert_eq!(
+                include.include.into_vec(),
+                vec!["servers.yaml".to_string(), "rules/*.yaml".to_string()]
+            ),
+            _ => panic!("Expected Include config"),
+        }
+
+        let result: Result<Config, _> = serde_yaml::from_str("include: a.yaml\noptional: true");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_config_reverse_bridge() {
+        let yaml = r#"
+reverse_bridge: tunnel.internal
+client_chain:
+  address: "portal.example.com:443"
+  protocol:
+    type: trojan
+    password: secret
+"#;
+        let config: Config = serde_yaml::from_str(yaml).unwrap();
+        match &config {
+            Config::ReverseBridge(bridge) => {
+                assert_eq!(bridge.reverse_bridge, "tunnel.internal");
+                assert_eq!(bridge.client_chains.iter().count(), 1);
+                assert_eq!(bridge.connections, 2);
+                assert!(bridge.rules.is_unspecified());
+            }
+            _ => panic!("Expected ReverseBridge config"),
+        }
+
+        let result: Result<Config, _> =
+            serde_yaml::from_str("reverse_bridge: tunnel.internal\nconnection: 2");
+        assert!(result.is_err());
+    }
+
     #[test]
     fn test_rejects_unknown_field_in_client_config_group() {
         let yaml = r#"
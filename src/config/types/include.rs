@@ -0,0 +1,21 @@
+//! Config file include configuration.
+
+use serde::{Deserialize, Serialize};
+
+use crate::option_util::OneOrSome;
+
+/// Other config files whose entries replace this entry.
+///
+/// ```yaml
+/// - include: servers.yaml
+/// - include: [clients/*.yaml, rules/*.yaml]
+/// ```
+///
+/// Paths are relative to the directory of the including file, and can be glob patterns.
+/// The files matching a pattern are included in lexicographic order. Included files can
+/// include other files, but not themselves.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct IncludeConfig {
+    pub include: OneOrSome<String>,
+}
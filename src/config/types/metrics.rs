@@ -0,0 +1,17 @@
+//! Metrics listener configuration.
+
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/// HTTP listener that serves Prometheus metrics at `/metrics`.
+///
+/// ```yaml
+/// - metrics: 127.0.0.1:9100
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    /// Address to listen on.
+    pub metrics: SocketAddr,
+}
@@ -4,6 +4,8 @@
 //! organized into submodules by functionality:
 //!
 //! - [`common`]: Shared helpers and constants
+//! - [`access_control`]: Source IP access control of listeners
+//! - [`auto_ban`]: Automatic bans of clients that fail handshakes
 //! - [`transport`]: Transport layer types (TCP, QUIC, UDP)
 //! - [`shadowsocks`]: Shadowsocks protocol configuration
 //! - [`selection`]: ConfigSelection for referencing groups or inline configs
@@ -12,33 +14,91 @@
 //! - [`rules`]: Rule configurations for traffic routing
 //! - [`groups`]: Top-level configuration groups and the Config enum
 //! - [`dns`]: DNS server configuration
+//! - [`metrics`]: Metrics listener configuration
+//! - [`clash_api`]: Clash API listener configuration
+//! - [`geo`]: GeoIP and geosite database configuration
+//! - [`fake_ip`]: Fake-IP DNS configuration
+//! - [`rate_limit`]: Bandwidth limits of servers and users
+//! - [`access_log`]: Access log of forwarded sessions
+//! - [`reverse`]: Reverse proxy bridge configuration
+//! - [`udp_sessions`]: Limits of UDP session tables
+//! - [`buffer_pool`]: Sizes of relay buffers and their pool
+//! - [`runtime`]: Threads of the async runtime
+//! - [`crypto`]: Crypto provider of TLS connections
+//! - [`privileges`]: User and group to switch to after startup
+//! - [`include`]: Other config files included by a config file
+//! - [`subscription`]: Proxies fetched from subscription URLs
 
+pub mod access_control;
+pub mod access_log;
+pub mod auto_ban;
+pub mod buffer_pool;
+pub mod clash_api;
 pub mod client;
 pub mod common;
+pub mod crypto;
 pub mod dns;
+pub mod fake_ip;
+pub mod geo;
 pub mod groups;
+pub mod include;
+pub mod metrics;
+pub mod privileges;
+pub mod rate_limit;
+pub mod reverse;
 pub mod rules;
+pub mod runtime;
 pub mod selection;
 pub mod server;
 pub mod shadowsocks;
+pub mod subscription;
 pub mod transport;
 pub mod tun;
+pub mod udp_sessions;
 
 // Re-export all public types for convenience
+pub use access_control::{AccessControlConfig, DefaultAccessControlConfig};
+pub use access_log::{AccessLogConfig, AccessLogFormat};
+pub use auto_ban::AutoBanConfig;
+pub use buffer_pool::BufferPoolConfig;
+pub use clash_api::ClashApiConfig;
 pub use client::{
-    ClientConfig, ClientProxyConfig, TlsClientConfig, WebsocketClientConfig,
-    resolve_hysteria2_bandwidth,
+    ClientConfig, ClientProxyConfig, GrpcClientConfig, HttpUpgradeClientConfig,
+    Hysteria2ObfsConfig, TlsClientConfig, TrafficPaddingConfig, WebsocketClientConfig,
+    XhttpClientConfig, XhttpMode, resolve_hysteria2_bandwidth,
 };
 pub use common::DEFAULT_REALITY_SHORT_ID;
-pub use groups::{ClientConfigGroup, Config, NamedPem, PemSource};
-pub use rules::{ClientChain, ClientChainHop, RuleActionConfig, RuleConfig};
+pub use crypto::{CryptoConfig, CryptoProviderKind};
+pub use fake_ip::FakeIpConfig;
+pub use geo::GeoConfig;
+pub use groups::{ClientConfigGroup, Config, NamedPem, PemSource, UserConfig, UserGroupConfig};
+pub use include::IncludeConfig;
+pub use metrics::MetricsConfig;
+pub use privileges::PrivilegesConfig;
+pub use rate_limit::RateLimitConfig;
+pub use reverse::ReverseBridgeConfig;
+pub use rules::{
+    BalanceStrategy, ClientChain, ClientChainHop, DialConfig, HealthCheckConfig, RuleActionConfig,
+    RuleConfig,
+};
+pub use runtime::RuntimeConfig;
 pub use selection::ConfigSelection;
 pub use server::{
-    RealityServerConfig, ServerConfig, ServerProxyConfig, ShadowTlsServerConfig,
-    ShadowTlsServerHandshakeConfig, TlsServerConfig, WebsocketPingType, WebsocketServerConfig,
-    direct_allow_rule,
+    DecoyConfig, DispatchTargetConfig, Obfs4IatMode, RealityServerConfig, ServerConfig,
+    ServerProxyConfig, ShadowTlsServerConfig, ShadowTlsServerHandshakeConfig, ShadowTlsWildcardSni,
+    TlsServerConfig, WebsocketPingType, WebsocketServerConfig, direct_allow_rule,
+};
+pub use shadowsocks::{
+    ShadowsocksConfig, ShadowsocksPluginConfig, ShadowsocksUserConfig, decode_aead2022_user_key,
+};
+pub use transport::{
+    BindLocation, ClientQuicConfig, ConnectionLimitConfig, ConnectionLimitMode, DomainStrategy,
+    HappyEyeballsConfig, IpFamily, KcpConfig, KcpCryptMethod, KcpMode, ProxyProtocolVersion,
+    ServerQuicConfig, SessionTimeoutConfig, TcpConfig, Transport,
+};
+pub use subscription::{SubscriptionConfig, SubscriptionFormat};
+pub use tun::{TunConfig, TunIcmpMode};
+pub use udp_sessions::UdpSessionConfig;
+pub use dns::{
+    DnsConfig, DnsConfigGroup, DnsServerSpec, DnsUpstreamConfig, ExpandedDnsGroup, ExpandedDnsSpec,
 };
-pub use shadowsocks::ShadowsocksConfig;
-pub use transport::{BindLocation, ClientQuicConfig, ServerQuicConfig, TcpConfig, Transport};
-pub use tun::TunConfig;
-pub use dns::{DnsConfig, DnsConfigGroup, DnsServerSpec, ExpandedDnsGroup, ExpandedDnsSpec};
@@ -0,0 +1,21 @@
+//! Privilege dropping configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// User and group to switch to once the servers are started.
+///
+/// ```yaml
+/// - user: shoes
+///   group: shoes
+/// ```
+///
+/// Either can be a name or a numeric ID. Without `group`, the primary group of `user` is
+/// used. Applied once at startup, so changes take effect after a restart.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PrivilegesConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
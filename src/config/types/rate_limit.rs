@@ -0,0 +1,107 @@
+//! Bandwidth limit configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Bandwidth limits of a server or user, shared by all of its connections.
+///
+/// ```yaml
+/// rate_limit:
+///   up: 10mbps
+///   down: 50mbps
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Limit of traffic from clients towards remote destinations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub up: Option<String>,
+    /// Limit of traffic from remote destinations back to clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub down: Option<String>,
+}
+
+impl RateLimitConfig {
+    /// Parses the (up, down) limits to bytes per second. None means unlimited.
+    pub fn parse(&self) -> std::io::Result<(Option<u64>, Option<u64>)> {
+        let parse = |value: &Option<String>| -> std::io::Result<Option<u64>> {
+            let Some(value) = value else {
+                return Ok(None);
+            };
+            let rate = parse_bandwidth(value)?;
+            if rate == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("bandwidth limit must be more than 0: {value}"),
+                ));
+            }
+            Ok(Some(rate))
+        };
+        Ok((parse(&self.up)?, parse(&self.down)?))
+    }
+}
+
+/// Parses a bandwidth like "100 mbps" or "1gbps" to bytes per second. Numbers without
+/// a unit are in Mbps.
+pub fn parse_bandwidth(value: &str) -> std::io::Result<u64> {
+    let s = value.trim().to_lowercase();
+    // Find first non-digit, non-dot, non-space character to separate number from unit
+    let mut num_end = 0;
+    for (i, c) in s.chars().enumerate() {
+        if c.is_ascii_digit() || c == '.' {
+            num_end = i + 1;
+        } else if !c.is_whitespace() {
+            break;
+        }
+    }
+
+    let num_str = s[..num_end].trim();
+    let unit = s[num_end..].trim();
+
+    let num: f64 = num_str.parse().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid bandwidth value: {}", s),
+        )
+    })?;
+
+    let multiplier = match unit {
+        "bps" => 1.0,
+        "kbps" | "k" => 1024.0,
+        "mbps" | "m" => 1024.0 * 1024.0,
+        "gbps" | "g" => 1024.0 * 1024.0 * 1024.0,
+        "tbps" | "t" => 1024.0 * 1024.0 * 1024.0 * 1024.0,
+        "" => 1024.0 * 1024.0, // Default to mbps if no unit
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unknown bandwidth unit: {}", unit),
+            ));
+        }
+    };
+
+    Ok((num * multiplier / 8.0) as u64) // Convert bits to bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate_limit() {
+        let config: RateLimitConfig = serde_yaml::from_str("up: 10mbps\ndown: 1 gbps").unwrap();
+        assert_eq!(
+            config.parse().unwrap(),
+            (Some(10 * 1024 * 1024 / 8), Some(1024 * 1024 * 1024 / 8))
+        );
+
+        let config: RateLimitConfig = serde_yaml::from_str("down: 800kbps").unwrap();
+        assert_eq!(config.parse().unwrap(), (None, Some(800 * 1024 / 8)));
+
+        let invalid = ["up: 0mbps", "up: fast", "up: 10 parsecs"];
+        for yaml in invalid {
+            let config: RateLimitConfig = serde_yaml::from_str(yaml).unwrap();
+            assert!(config.parse().is_err(), "{yaml}");
+        }
+        assert!(serde_yaml::from_str::<RateLimitConfig>("upload: 10mbps").is_err());
+    }
+}
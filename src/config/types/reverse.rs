@@ -0,0 +1,49 @@
+//! Reverse proxy bridge configuration.
+
+use serde::{Deserialize, Serialize};
+
+use crate::option_util::NoneOrSome;
+
+use super::rules::{ClientChain, RuleConfig};
+use super::selection::ConfigSelection;
+
+/// Bridge of a reverse proxy: keeps connections open to a portal, which opens
+/// connections to services reachable from the bridge over them.
+///
+/// ```yaml
+/// - reverse_bridge: tunnel.internal
+///   client_chain:
+///     address: "portal.example.com:443"
+///     protocol:
+///       type: tls
+///       protocol:
+///         type: vless
+///         user_id: "uuid"
+///   rules:
+///     - masks: "192.168.1.0/24"
+///       action: allow
+/// ```
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReverseBridgeConfig {
+    /// Domain that identifies the tunnels at the portal.
+    pub reverse_bridge: String,
+    /// Chains that reach the server of the portal.
+    #[serde(alias = "client_chain", default)]
+    pub client_chains: NoneOrSome<ClientChain>,
+    /// Number of tunnel connections kept open.
+    #[serde(default = "default_connections")]
+    pub connections: usize,
+    /// Routing rules for connections opened by the portal.
+    /// Default: Allow all traffic directly
+    #[serde(
+        alias = "rule",
+        default,
+        skip_serializing_if = "NoneOrSome::is_unspecified"
+    )]
+    pub rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+}
+
+fn default_connections() -> usize {
+    2
+}
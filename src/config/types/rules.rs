@@ -2,15 +2,33 @@
 
 use serde::{Deserialize, Serialize};
 
-use crate::address::{NetLocation, NetLocationMask};
+use crate::address::{Address, AddressMask, NetLocation, NetLocationMask, PortRange};
+use crate::geoip::GeoIpCode;
+use crate::geosite;
 use crate::option_util::{NoneOrSome, OneOrSome};
 
 use super::client::ClientConfig;
 use super::selection::ConfigSelection;
+use super::transport::DomainStrategy;
 
+/// A routing rule. A connection matches when its destination matches one of `masks`,
+/// and it also satisfies each of the other matchers that are not empty.
 #[derive(Debug, Clone)]
 pub struct RuleConfig {
     pub masks: OneOrSome<NetLocationMask>,
+    /// Hostname destinations containing one of these keywords, in lowercase.
+    pub domain_keywords: Vec<String>,
+    /// Destination ports.
+    pub ports: Vec<PortRange>,
+    /// Source IP/CIDR masks.
+    pub source_ips: Vec<AddressMask>,
+    /// GeoIP codes of the destination IP.
+    pub geoip: Vec<GeoIpCode>,
+    /// Geosite categories of hostname destinations, in lowercase, e.g. `google@cn`.
+    pub geosite: Vec<String>,
+    /// Block QUIC flows that match the rule, so that clients fall back to TCP. Flows
+    /// are only known to be QUIC on listeners that sniff.
+    pub block_quic: bool,
     pub action: RuleActionConfig,
 }
 
@@ -18,9 +36,21 @@ impl Default for RuleConfig {
     fn default() -> Self {
         Self {
             masks: OneOrSome::One(NetLocationMask::ANY),
+            domain_keywords: vec![],
+            ports: vec![],
+            source_ips: vec![],
+            geoip: vec![],
+            geosite: vec![],
+            block_quic: false,
             action: RuleActionConfig::Allow {
                 override_address: None,
+                override_port: None,
                 client_chains: NoneOrSome::One(ClientChain::default()),
+                balancer: BalanceStrategy::default(),
+                health_check: None,
+                dial: None,
+                domain_strategy: None,
+                resolve_locally: None,
             },
         }
     }
@@ -53,7 +83,7 @@ impl<'de> Deserialize<'de> for ClientChain {
     where
         D: serde::Deserializer<'de>,
     {
-        use serde::de::{Error, SeqAccess, Visitor};
+        use serde::de::{Error, IntoDeserializer, SeqAccess, Visitor};
         use std::fmt;
 
         struct ClientChainVisitor;
@@ -68,15 +98,13 @@ impl<'de> Deserialize<'de> for ClientChain {
                 )
             }
 
-            // String → single hop chain (group reference)
+            // String → single hop chain (group reference or share link)
             fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
             where
                 E: Error,
             {
                 Ok(ClientChain {
-                    hops: OneOrSome::One(ClientChainHop::Single(ConfigSelection::GroupName(
-                        value.to_string(),
-                    ))),
+                    hops: OneOrSome::One(ClientChainHop::deserialize(value.into_deserializer())?),
                 })
             }
 
@@ -185,11 +213,25 @@ impl<'de> Deserialize<'de> for RuleConfig {
         struct RuleConfigTemp {
             #[serde(alias = "mask")]
             masks: Option<OneOrSome<NetLocationMask>>,
+            #[serde(alias = "domain_keyword", default)]
+            domain_keywords: NoneOrSome<String>,
+            #[serde(alias = "port", default)]
+            ports: NoneOrSome<PortRange>,
+            #[serde(alias = "source_ip", default)]
+            source_ips: NoneOrSome<String>,
+            #[serde(default)]
+            geoip: NoneOrSome<String>,
+            #[serde(default)]
+            geosite: NoneOrSome<String>,
+            #[serde(default)]
+            block_quic: bool,
             // Action fields (from RuleActionConfig)
             #[serde(default)]
             action: Option<String>,
             #[serde(default)]
             override_address: Option<String>,
+            #[serde(default)]
+            override_port: Option<u16>,
             /// Legacy field - will be converted to client_chains
             #[serde(alias = "client_proxy", default)]
             client_proxies: NoneOrSome<ConfigSelection<ClientConfig>>,
@@ -197,12 +239,71 @@ impl<'de> Deserialize<'de> for RuleConfig {
             /// Alias: client_chain (singular) for backward compatibility
             #[serde(alias = "client_chain", default)]
             client_chains: NoneOrSome<ClientChain>,
+            #[serde(default)]
+            balancer: BalanceStrategy,
+            #[serde(default)]
+            health_check: Option<HealthCheckConfig>,
+            #[serde(default)]
+            dial: Option<DialConfig>,
+            #[serde(default)]
+            domain_strategy: Option<DomainStrategy>,
+            #[serde(default)]
+            resolve_locally: Option<bool>,
         }
 
         let temp = RuleConfigTemp::deserialize(deserializer)?;
 
-        // masks is required
-        let masks = temp.masks.ok_or_else(|| D::Error::missing_field("masks"))?;
+        let domain_keywords: Vec<String> = temp
+            .domain_keywords
+            .into_iter()
+            .map(|keyword| keyword.to_lowercase())
+            .collect();
+        if domain_keywords.iter().any(String::is_empty) {
+            return Err(D::Error::custom("domain keywords cannot be empty"));
+        }
+
+        let ports = temp.ports.into_vec();
+
+        let source_ips = temp
+            .source_ips
+            .into_iter()
+            .map(|source_ip| match AddressMask::from(&source_ip) {
+                Ok(AddressMask {
+                    address: Address::Hostname(_),
+                    ..
+                })
+                | Err(_) => Err(D::Error::custom(format!(
+                    "invalid source_ip '{source_ip}': expected an IP or CIDR"
+                ))),
+                Ok(mask) => Ok(mask),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let geoip = temp
+            .geoip
+            .into_iter()
+            .map(|code| GeoIpCode::from_str(&code).map_err(D::Error::custom))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let geosite = temp
+            .geosite
+            .into_iter()
+            .map(|category| geosite::parse_category(&category).map_err(D::Error::custom))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // masks is required, unless the rule has other matchers.
+        let masks = match temp.masks {
+            Some(masks) => masks,
+            None if !domain_keywords.is_empty()
+                || !ports.is_empty()
+                || !source_ips.is_empty()
+                || !geoip.is_empty()
+                || !geosite.is_empty() =>
+            {
+                OneOrSome::One(NetLocationMask::ANY)
+            }
+            None => return Err(D::Error::missing_field("masks")),
+        };
 
         // Determine action type
         let action_str = temp.action.as_deref().unwrap_or("allow");
@@ -220,6 +321,8 @@ impl<'de> Deserialize<'de> for RuleConfig {
                 } else {
                     None
                 };
+                validate_override_port(override_address.as_ref(), temp.override_port)
+                    .map_err(D::Error::custom)?;
 
                 // Check for conflicting fields
                 let has_client_proxies = !temp.client_proxies.is_empty();
@@ -259,7 +362,13 @@ impl<'de> Deserialize<'de> for RuleConfig {
 
                 RuleActionConfig::Allow {
                     override_address,
+                    override_port: temp.override_port,
                     client_chains,
+                    balancer: temp.balancer,
+                    health_check: temp.health_check,
+                    dial: temp.dial,
+                    domain_strategy: temp.domain_strategy,
+                    resolve_locally: temp.resolve_locally,
                 }
             }
             other => {
@@ -270,7 +379,16 @@ impl<'de> Deserialize<'de> for RuleConfig {
             }
         };
 
-        Ok(RuleConfig { masks, action })
+        Ok(RuleConfig {
+            masks,
+            domain_keywords,
+            ports,
+            source_ips,
+            geoip,
+            geosite,
+            block_quic: temp.block_quic,
+            action,
+        })
     }
 }
 
@@ -286,24 +404,79 @@ impl Serialize for RuleConfig {
             RuleActionConfig::Block => 1, // action
             RuleActionConfig::Allow {
                 override_address,
+                override_port,
                 client_chains,
+                balancer,
+                health_check,
+                dial,
+                domain_strategy,
+                resolve_locally,
             } => {
                 let mut count = 1; // action
                 if override_address.is_some() {
                     count += 1;
                 }
+                if override_port.is_some() {
+                    count += 1;
+                }
                 if !client_chains.is_empty() {
                     count += 1;
                 }
+                if !balancer.is_default() {
+                    count += 1;
+                }
+                if health_check.is_some() {
+                    count += 1;
+                }
+                if dial.is_some() {
+                    count += 1;
+                }
+                if domain_strategy.is_some() {
+                    count += 1;
+                }
+                if resolve_locally.is_some() {
+                    count += 1;
+                }
                 count
             }
         };
 
-        let mut map = serializer.serialize_map(Some(1 + action_field_count))?;
+        let matcher_field_count = usize::from(!self.domain_keywords.is_empty())
+            + usize::from(!self.ports.is_empty())
+            + usize::from(!self.source_ips.is_empty())
+            + usize::from(!self.geoip.is_empty())
+            + usize::from(!self.geosite.is_empty())
+            + usize::from(self.block_quic);
+
+        let mut map =
+            serializer.serialize_map(Some(1 + matcher_field_count + action_field_count))?;
 
         // Serialize masks
         map.serialize_entry("masks", &self.masks)?;
 
+        // Serialize other matchers
+        if !self.domain_keywords.is_empty() {
+            map.serialize_entry("domain_keywords", &self.domain_keywords)?;
+        }
+        if !self.ports.is_empty() {
+            map.serialize_entry("ports", &self.ports)?;
+        }
+        if !self.source_ips.is_empty() {
+            let source_ips: Vec<String> =
+                self.source_ips.iter().map(|mask| mask.to_string()).collect();
+            map.serialize_entry("source_ips", &source_ips)?;
+        }
+        if !self.geoip.is_empty() {
+            let geoip: Vec<String> = self.geoip.iter().map(|code| code.to_string()).collect();
+            map.serialize_entry("geoip", &geoip)?;
+        }
+        if !self.geosite.is_empty() {
+            map.serialize_entry("geosite", &self.geosite)?;
+        }
+        if self.block_quic {
+            map.serialize_entry("block_quic", &true)?;
+        }
+
         // Serialize action fields (flattened)
         match &self.action {
             RuleActionConfig::Block => {
@@ -311,15 +484,39 @@ impl Serialize for RuleConfig {
             }
             RuleActionConfig::Allow {
                 override_address,
+                override_port,
                 client_chains,
+                balancer,
+                health_check,
+                dial,
+                domain_strategy,
+                resolve_locally,
             } => {
                 map.serialize_entry("action", "allow")?;
                 if let Some(addr) = override_address {
                     map.serialize_entry("override_address", &addr.to_string())?;
                 }
+                if let Some(port) = override_port {
+                    map.serialize_entry("override_port", port)?;
+                }
                 if !client_chains.is_empty() {
                     map.serialize_entry("client_chains", client_chains)?;
                 }
+                if !balancer.is_default() {
+                    map.serialize_entry("balancer", balancer)?;
+                }
+                if let Some(health_check) = health_check {
+                    map.serialize_entry("health_check", health_check)?;
+                }
+                if let Some(dial) = dial {
+                    map.serialize_entry("dial", dial)?;
+                }
+                if let Some(domain_strategy) = domain_strategy {
+                    map.serialize_entry("domain_strategy", domain_strategy)?;
+                }
+                if let Some(resolve_locally) = resolve_locally {
+                    map.serialize_entry("resolve_locally", resolve_locally)?;
+                }
             }
         }
 
@@ -349,7 +546,7 @@ impl<'de> serde::de::Deserialize<'de> for ClientChainHop {
     where
         D: serde::de::Deserializer<'de>,
     {
-        use serde::de::{Error, Visitor};
+        use serde::de::{Error, IntoDeserializer, Visitor};
         use std::fmt;
 
         struct ClientChainHopVisitor;
@@ -368,10 +565,8 @@ impl<'de> serde::de::Deserialize<'de> for ClientChainHop {
             where
                 E: Error,
             {
-                // String -> Single group reference
-                Ok(ClientChainHop::Single(ConfigSelection::GroupName(
-                    value.to_string(),
-                )))
+                // String -> Single group reference or share link
+                ConfigSelection::deserialize(value.into_deserializer()).map(ClientChainHop::Single)
             }
 
             fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
@@ -452,8 +647,13 @@ impl serde::ser::Serialize for ClientChainHop {
 #[allow(clippy::large_enum_variant)]
 pub enum RuleActionConfig {
     Allow {
+        /// Replaces the destination address. A port of 0 keeps the destination port.
         override_address: Option<NetLocation>,
 
+        /// Replaces the destination port.
+        /// Field name: `override_port`
+        override_port: Option<u16>,
+
         /// Multiple proxy chains for round-robin selection.
         /// Each chain is a sequence of hops.
         /// Field name: `client_chains` (alias: `client_chain` for backward compatibility)
@@ -463,10 +663,169 @@ pub enum RuleActionConfig {
         /// - `NoneOrSome::One(chain)` → Single chain
         /// - `NoneOrSome::Some(chains)` → Multiple chains for round-robin
         client_chains: NoneOrSome<ClientChain>,
+
+        /// How one of the chains is picked for a new connection.
+        /// Field name: `balancer`
+        balancer: BalanceStrategy,
+
+        /// Periodic checks of the chains, used by the `url_test` and `failover` balancers.
+        /// Field name: `health_check`
+        health_check: Option<HealthCheckConfig>,
+
+        /// Timeouts and retries for connecting through the chains.
+        /// Field name: `dial`
+        dial: Option<DialConfig>,
+
+        /// Replaces the domain strategy of the clients of the chains.
+        /// Field name: `domain_strategy`
+        domain_strategy: Option<DomainStrategy>,
+
+        /// Replaces `resolve_locally` of the clients of the chains.
+        /// Field name: `resolve_locally`
+        resolve_locally: Option<bool>,
     },
     Block,
 }
 
+/// Strategy for picking one of a rule's client chains for a new connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BalanceStrategy {
+    /// Each chain in turn.
+    #[default]
+    #[serde(alias = "round-robin")]
+    RoundRobin,
+    /// A random chain.
+    Random,
+    /// The chain with the fewest open connections.
+    #[serde(alias = "least-connections")]
+    LeastConnections,
+    /// The same chain for each destination host, so that most destinations keep their
+    /// chain when chains are added or removed.
+    #[serde(alias = "consistent-hash")]
+    ConsistentHash,
+    /// The same chain for each client IP address, so that a client keeps its exit address
+    /// for all destinations. Connections without a known client address are spread like
+    /// `consistent_hash`.
+    #[serde(alias = "source-hash")]
+    SourceHash,
+    /// The chain with the lowest latency in health checks. The chain is only changed
+    /// when another one is faster by more than the tolerance.
+    #[serde(alias = "url-test")]
+    UrlTest,
+    /// The first chain, in the configured order, that passed its last health check.
+    #[serde(alias = "fallback")]
+    Failover,
+}
+
+impl BalanceStrategy {
+    pub fn is_default(&self) -> bool {
+        matches!(self, BalanceStrategy::RoundRobin)
+    }
+
+    /// Returns true if chains are picked based on health checks.
+    pub fn uses_health_checks(&self) -> bool {
+        matches!(self, BalanceStrategy::UrlTest | BalanceStrategy::Failover)
+    }
+}
+
+/// Health checks time a HTTP request to `url` through each chain.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /// http:// or https:// URL that is requested
+    #[serde(default = "default_health_check_url")]
+    pub url: String,
+    /// Seconds between checks
+    #[serde(default = "default_health_check_interval_secs")]
+    pub interval_secs: u64,
+    /// Seconds after which a check fails
+    #[serde(default = "default_health_check_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Milliseconds that another chain must be faster by to replace the current chain
+    #[serde(default = "default_health_check_tolerance_ms")]
+    pub tolerance_ms: u64,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            url: default_health_check_url(),
+            interval_secs: default_health_check_interval_secs(),
+            timeout_secs: default_health_check_timeout_secs(),
+            tolerance_ms: default_health_check_tolerance_ms(),
+        }
+    }
+}
+
+fn default_health_check_url() -> String {
+    String::from("https://www.gstatic.com/generate_204")
+}
+
+fn default_health_check_interval_secs() -> u64 {
+    300
+}
+
+fn default_health_check_timeout_secs() -> u64 {
+    5
+}
+
+fn default_health_check_tolerance_ms() -> u64 {
+    50
+}
+
+/// Limits how long connecting through a chain may take, and retries failed connections.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DialConfig {
+    /// Seconds after which connecting to the first hop fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_secs: Option<u64>,
+    /// Seconds after which the protocol handshake of a proxy hop fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake_timeout_secs: Option<u64>,
+    /// Times a failed connection is retried on the same chain
+    #[serde(default)]
+    pub retries: u32,
+    /// Milliseconds before the first retry, doubled for each further retry
+    #[serde(default = "default_dial_retry_backoff_ms")]
+    pub retry_backoff_ms: u64,
+    /// Try the next chain of the rule once the retries of a chain are exhausted
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+impl Default for DialConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_secs: None,
+            handshake_timeout_secs: None,
+            retries: 0,
+            retry_backoff_ms: default_dial_retry_backoff_ms(),
+            fallback: false,
+        }
+    }
+}
+
+fn default_dial_retry_backoff_ms() -> u64 {
+    100
+}
+
+/// Checks that `override_port` is valid, and doesn't conflict with the port of
+/// `override_address`.
+fn validate_override_port(
+    override_address: Option<&NetLocation>,
+    override_port: Option<u16>,
+) -> Result<(), &'static str> {
+    match override_port {
+        Some(0) => Err("override_port cannot be 0"),
+        Some(_) if override_address.is_some_and(|address| address.port() != 0) => {
+            Err("cannot specify both 'override_port' and an 'override_address' with a port")
+        }
+        _ => Ok(()),
+    }
+}
+
 impl<'de> Deserialize<'de> for RuleActionConfig {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -482,6 +841,8 @@ impl<'de> Deserialize<'de> for RuleActionConfig {
             action: Option<String>,
             #[serde(default)]
             override_address: Option<String>,
+            #[serde(default)]
+            override_port: Option<u16>,
             /// Legacy field - will be converted to client_chains
             #[serde(alias = "client_proxy", default)]
             client_proxies: NoneOrSome<ConfigSelection<ClientConfig>>,
@@ -489,6 +850,16 @@ impl<'de> Deserialize<'de> for RuleActionConfig {
             /// Alias: client_chain (singular) for backward compatibility
             #[serde(alias = "client_chain", default)]
             client_chains: NoneOrSome<ClientChain>,
+            #[serde(default)]
+            balancer: BalanceStrategy,
+            #[serde(default)]
+            health_check: Option<HealthCheckConfig>,
+            #[serde(default)]
+            dial: Option<DialConfig>,
+            #[serde(default)]
+            domain_strategy: Option<DomainStrategy>,
+            #[serde(default)]
+            resolve_locally: Option<bool>,
         }
 
         let temp = RuleActionTemp::deserialize(deserializer)?;
@@ -509,6 +880,8 @@ impl<'de> Deserialize<'de> for RuleActionConfig {
                 } else {
                     None
                 };
+                validate_override_port(override_address.as_ref(), temp.override_port)
+                    .map_err(D::Error::custom)?;
 
                 // Check for conflicting fields
                 let has_client_proxies = !temp.client_proxies.is_empty();
@@ -548,7 +921,13 @@ impl<'de> Deserialize<'de> for RuleActionConfig {
 
                 Ok(RuleActionConfig::Allow {
                     override_address,
+                    override_port: temp.override_port,
                     client_chains,
+                    balancer: temp.balancer,
+                    health_check: temp.health_check,
+                    dial: temp.dial,
+                    domain_strategy: temp.domain_strategy,
+                    resolve_locally: temp.resolve_locally,
                 })
             }
             other => Err(D::Error::custom(format!(
@@ -574,24 +953,66 @@ impl Serialize for RuleActionConfig {
             }
             RuleActionConfig::Allow {
                 override_address,
+                override_port,
                 client_chains,
+                balancer,
+                health_check,
+                dial,
+                domain_strategy,
+                resolve_locally,
             } => {
                 let mut count = 1; // action
                 if override_address.is_some() {
                     count += 1;
                 }
+                if override_port.is_some() {
+                    count += 1;
+                }
                 if !client_chains.is_empty() {
                     count += 1;
                 }
+                if !balancer.is_default() {
+                    count += 1;
+                }
+                if health_check.is_some() {
+                    count += 1;
+                }
+                if dial.is_some() {
+                    count += 1;
+                }
+                if domain_strategy.is_some() {
+                    count += 1;
+                }
+                if resolve_locally.is_some() {
+                    count += 1;
+                }
 
                 let mut map = serializer.serialize_map(Some(count))?;
                 map.serialize_entry("action", "allow")?;
                 if let Some(addr) = override_address {
                     map.serialize_entry("override_address", &addr.to_string())?;
                 }
+                if let Some(port) = override_port {
+                    map.serialize_entry("override_port", port)?;
+                }
                 if !client_chains.is_empty() {
                     map.serialize_entry("client_chains", client_chains)?;
                 }
+                if !balancer.is_default() {
+                    map.serialize_entry("balancer", balancer)?;
+                }
+                if let Some(health_check) = health_check {
+                    map.serialize_entry("health_check", health_check)?;
+                }
+                if let Some(dial) = dial {
+                    map.serialize_entry("dial", dial)?;
+                }
+                if let Some(domain_strategy) = domain_strategy {
+                    map.serialize_entry("domain_strategy", domain_strategy)?;
+                }
+                if let Some(resolve_locally) = resolve_locally {
+                    map.serialize_entry("resolve_locally", resolve_locally)?;
+                }
                 map.end()
             }
         }
@@ -614,12 +1035,26 @@ mod tests {
                     IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
                     8080,
                 )),
+                override_port: None,
                 client_chains: NoneOrSome::One(ClientChain {
                     hops: OneOrSome::One(ClientChainHop::Single(ConfigSelection::GroupName(
                         "test-proxy-group".to_string(),
                     ))),
                 }),
+                balancer: BalanceStrategy::LeastConnections,
+                health_check: Some(HealthCheckConfig {
+                    url: "http://example.com/".to_string(),
+                    ..Default::default()
+                }),
+                dial: Some(DialConfig {
+                    connect_timeout_secs: Some(5),
+                    retries: 2,
+                    ..Default::default()
+                }),
+                domain_strategy: Some(DomainStrategy::Ipv4Only),
+                resolve_locally: Some(true),
             },
+            ..Default::default()
         }
     }
 
@@ -632,7 +1067,20 @@ mod tests {
             serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
         assert!(matches!(
             deserialized.action,
-            RuleActionConfig::Allow { .. }
+            RuleActionConfig::Allow {
+                balancer: BalanceStrategy::LeastConnections,
+                health_check: Some(_),
+                dial: Some(DialConfig {
+                    connect_timeout_secs: Some(5),
+                    handshake_timeout_secs: None,
+                    retries: 2,
+                    retry_backoff_ms: 100,
+                    fallback: false,
+                }),
+                domain_strategy: Some(DomainStrategy::Ipv4Only),
+                resolve_locally: Some(true),
+                ..
+            }
         ));
     }
 
@@ -680,6 +1128,119 @@ override_address: "127.0.0.1:8080"
         }
     }
 
+    #[test]
+    fn test_rule_config_with_override_port() {
+        let yaml = r#"
+masks: 0.0.0.0/0:53
+override_address: 10.0.0.53
+override_port: 5353
+"#;
+        let rule: RuleConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            rule.action,
+            RuleActionConfig::Allow {
+                override_address: Some(_),
+                override_port: Some(5353),
+                ..
+            }
+        ));
+
+        let yaml_str = serde_yaml::to_string(&rule).unwrap();
+        let deserialized: RuleConfig = serde_yaml::from_str(&yaml_str).unwrap();
+        assert!(matches!(
+            deserialized.action,
+            RuleActionConfig::Allow {
+                override_port: Some(5353),
+                ..
+            }
+        ));
+
+        let result: Result<RuleConfig, _> =
+            serde_yaml::from_str("masks: 0.0.0.0/0\noverride_port: 0");
+        assert!(result.is_err());
+
+        let yaml = "masks: 0.0.0.0/0\noverride_address: 10.0.0.53:53\noverride_port: 5353";
+        let result: Result<RuleConfig, _> = serde_yaml::from_str(yaml);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rule_config_matchers() {
+        let yaml = r#"
+domain_keywords: [Google, youtube]
+ports: [443, "8000-8100"]
+source_ip: 192.168.1.0/24
+action: block
+"#;
+        let rule: RuleConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(rule.masks.len(), 1);
+        assert_eq!(rule.domain_keywords, vec!["google", "youtube"]);
+        assert_eq!(rule.ports.len(), 2);
+        assert_eq!(rule.source_ips.len(), 1);
+
+        let yaml_str = serde_yaml::to_string(&rule).unwrap();
+        let deserialized: RuleConfig = serde_yaml::from_str(&yaml_str).unwrap();
+        assert_eq!(deserialized.ports, rule.ports);
+        assert_eq!(deserialized.source_ips[0].to_string(), "192.168.1.0/24");
+    }
+
+    #[test]
+    fn test_rule_config_invalid_matchers() {
+        let result: Result<RuleConfig, _> = serde_yaml::from_str("action: block");
+        assert!(result.is_err());
+
+        let result: Result<RuleConfig, _> =
+            serde_yaml::from_str("source_ips: example.com\naction: block");
+        assert!(result.is_err());
+
+        let result: Result<RuleConfig, _> = serde_yaml::from_str("ports: 100-1\naction: block");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rule_config_geoip() {
+        let rule: RuleConfig = serde_yaml::from_str("geoip: [cn, private]").unwrap();
+        assert_eq!(
+            rule.geoip,
+            vec![GeoIpCode::Country(*b"CN"), GeoIpCode::Private]
+        );
+
+        let yaml_str = serde_yaml::to_string(&rule).unwrap();
+        let deserialized: RuleConfig = serde_yaml::from_str(&yaml_str).unwrap();
+        assert_eq!(deserialized.geoip, rule.geoip);
+
+        let result: Result<RuleConfig, _> = serde_yaml::from_str("geoip: china");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rule_config_geosite() {
+        let rule: RuleConfig = serde_yaml::from_str("geosite: [Google@CN, category-ads]").unwrap();
+        assert_eq!(rule.geosite, vec!["google@cn", "category-ads"]);
+
+        let yaml_str = serde_yaml::to_string(&rule).unwrap();
+        let deserialized: RuleConfig = serde_yaml::from_str(&yaml_str).unwrap();
+        assert_eq!(deserialized.geosite, rule.geosite);
+
+        let result: Result<RuleConfig, _> = serde_yaml::from_str("geosite: \"google@\"");
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_rule_config_block_quic() {
+        let rule: RuleConfig =
+            serde_yaml::from_str("masks: 0.0.0.0/0:443\nblock_quic: true").unwrap();
+        assert!(rule.block_quic);
+
+        let yaml_str = serde_yaml::to_string(&rule).unwrap();
+        let deserialized: RuleConfig = serde_yaml::from_str(&yaml_str).unwrap();
+        assert!(deserialized.block_quic);
+
+        let rule: RuleConfig = serde_yaml::from_str("masks: 0.0.0.0/0").unwrap();
+        assert!(!rule.block_quic);
+        assert!(!serde_yaml::to_string(&rule).unwrap().contains("block_quic"));
+    }
+
     // =========================================================================
     // ClientChain deserialization tests
     // =========================================================================
@@ -0,0 +1,18 @@
+//! Runtime configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Threads of the async runtime.
+///
+/// ```yaml
+/// - worker_threads: 8
+/// ```
+///
+/// Read once at startup, so changes take effect after a restart. The `--threads` command
+/// line option takes precedence.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RuntimeConfig {
+    /// Worker threads of the runtime. 1 runs everything on the main thread.
+    pub worker_threads: usize,
+}
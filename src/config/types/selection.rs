@@ -2,6 +2,7 @@
 
 use std::collections::HashMap;
 
+use crate::config::share_link::{convert_share_link, is_share_link};
 use crate::option_util::NoneOrSome;
 
 #[derive(Debug, Clone)]
@@ -99,7 +100,8 @@ where
 
             fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
                 formatter.write_str(
-                    "either a string (group name reference) or an inline configuration object",
+                    "either a string (group name reference or share link) or an inline \
+                     configuration object",
                 )
             }
 
@@ -107,7 +109,15 @@ where
             where
                 E: Error,
             {
-                Ok(ConfigSelection::GroupName(value.to_string()))
+                if !is_share_link(value) {
+                    return Ok(ConfigSelection::GroupName(value.to_string()));
+                }
+                // Share links like vless://... are inline client configs.
+                let (_, config) = convert_share_link(value)
+                    .map_err(|e| Error::custom(format!("invalid share link: {e}")))?;
+                T::deserialize(config)
+                    .map(ConfigSelection::Config)
+                    .map_err(|e| Error::custom(format!("invalid share link: {e}")))
             }
 
             fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
@@ -4,15 +4,25 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-use crate::address::NetLocation;
+use crate::address::{NetLocation, NetLocationPortRange};
 use crate::option_util::{NoneOrSome, OneOrSome};
 
-use super::common::{default_reality_server_short_ids, default_reality_time_diff, default_true};
-use super::dns::DnsConfig;
+use super::access_control::AccessControlConfig;
+use super::client::{Hysteria2Bandwidth, Hysteria2ObfsConfig, VlessFlow};
+use super::common::{
+    default_grpc_service_name, default_reality_server_short_ids, default_reality_time_diff,
+    default_snell_version, default_true, default_vmess_max_time_diff_secs, default_xhttp_path,
+    is_default_snell_version, is_default_vmess_max_time_diff_secs, is_false,
+};
+use super::dns::{DnsConfig, DnsUpstreamConfig};
+use super::rate_limit::RateLimitConfig;
 use super::rules::{ClientChainHop, RuleConfig};
 use super::selection::ConfigSelection;
-use super::shadowsocks::ShadowsocksConfig;
-use super::transport::{BindLocation, ServerQuicConfig, TcpConfig, Transport};
+use super::shadowsocks::{ShadowsocksConfig, ShadowsocksPluginConfig, ShadowsocksUserConfig};
+use super::transport::{
+    BindLocation, ConnectionLimitConfig, KcpConfig, ServerQuicConfig, SessionTimeoutConfig,
+    TcpConfig, Transport,
+};
 
 /// AnyTLS user configuration
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -67,13 +77,66 @@ impl serde::Serialize for NaiveFallbackConfig {
     }
 }
 
+/// Decoy website for connections that fail protocol authentication.
+///
+/// An absolute path is a directory to serve static files from, anything else is the
+/// address of a web server that the connection is forwarded to.
+#[derive(Debug, Clone)]
+pub enum DecoyConfig {
+    Directory(std::path::PathBuf),
+    Address(NetLocation),
+}
+
+impl<'de> serde::Deserialize<'de> for DecoyConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let s = String::deserialize(deserializer)?;
+        if s.starts_with('/') {
+            Ok(DecoyConfig::Directory(std::path::PathBuf::from(s)))
+        } else {
+            NetLocation::from_str(&s, None)
+                .map(DecoyConfig::Address)
+                .map_err(|e| {
+                    Error::custom(format!(
+                        "Decoy must be an absolute path or an address with a port: {e}"
+                    ))
+                })
+        }
+    }
+}
+
+impl serde::Serialize for DecoyConfig {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            DecoyConfig::Directory(path) => serializer.serialize_str(&path.display().to_string()),
+            DecoyConfig::Address(location) => location.serialize(serializer),
+        }
+    }
+}
+
 // Forward declarations for client types (used in ShadowTlsRemoteHandshake)
 use super::client::ClientConfig;
 
 /// Custom deserializer for ServerProxyConfig::Shadowsocks
 fn deserialize_shadowsocks_server<'de, D>(
     deserializer: D,
-) -> Result<(ShadowsocksConfig, bool), D::Error>
+) -> Result<
+    (
+        ShadowsocksConfig,
+        Vec<ShadowsocksUserConfig>,
+        bool,
+        Option<ShadowsocksPluginConfig>,
+        Option<String>,
+    ),
+    D::Error,
+>
 where
     D: serde::Deserializer<'de>,
 {
@@ -84,21 +147,40 @@ where
     struct ShadowsocksServerTemp {
         cipher: String,
         password: String,
+        #[serde(default, alias = "user")]
+        users: Vec<ShadowsocksUserConfig>,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        #[serde(default)]
+        plugin: Option<String>,
+        #[serde(default)]
+        plugin_opts: Option<String>,
+        #[serde(default)]
+        user_group: Option<String>,
     }
 
     let temp = ShadowsocksServerTemp::deserialize(deserializer)?;
     let config =
         ShadowsocksConfig::from_fields(&temp.cipher, &temp.password).map_err(Error::custom)?;
-
-    Ok((config, temp.udp_enabled))
+    let plugin = ShadowsocksPluginConfig::from_fields(temp.plugin, temp.plugin_opts)
+        .map_err(Error::custom)?;
+
+    Ok((
+        config,
+        temp.users,
+        temp.udp_enabled,
+        plugin,
+        temp.user_group,
+    ))
 }
 
 /// Custom serializer for ServerProxyConfig::Shadowsocks - flattens config fields
 fn serialize_shadowsocks_server<S>(
     config: &ShadowsocksConfig,
+    users: &[ShadowsocksUserConfig],
     udp_enabled: &bool,
+    plugin: &Option<ShadowsocksPluginConfig>,
+    user_group: &Option<String>,
     serializer: S,
 ) -> Result<S::Ok, S::Error>
 where
@@ -106,14 +188,31 @@ where
 {
     use serde::ser::SerializeStruct;
 
-    let mut state = serializer.serialize_struct("Shadowsocks", 3)?;
+    let field_count = 3
+        + usize::from(!users.is_empty())
+        + usize::from(user_group.is_some())
+        + plugin
+            .as_ref()
+            .map_or(0, ShadowsocksPluginConfig::field_count);
+    let mut state = serializer.serialize_struct("Shadowsocks", field_count)?;
     config.serialize_fields(&mut state)?;
+    if !users.is_empty() {
+        state.serialize_field("users", users)?;
+    }
     state.serialize_field("udp_enabled", udp_enabled)?;
+    if let Some(plugin) = plugin {
+        plugin.serialize_fields(&mut state)?;
+    }
+    if let Some(user_group) = user_group {
+        state.serialize_field("user_group", user_group)?;
+    }
     state.end()
 }
 
 /// Custom deserializer for ServerProxyConfig::Vmess that validates legacy force_aead field
-fn deserialize_vmess_server<'de, D>(deserializer: D) -> Result<(String, String, bool), D::Error>
+fn deserialize_vmess_server<'de, D>(
+    deserializer: D,
+) -> Result<(String, String, bool, Option<String>, u64), D::Error>
 where
     D: serde::Deserializer<'de>,
 {
@@ -123,11 +222,16 @@ where
     #[serde(deny_unknown_fields)]
     struct VmessServerTemp {
         cipher: String,
+        #[serde(default)]
         user_id: String,
         #[serde(default = "default_true")]
         udp_enabled: bool,
         #[serde(default)]
         force_aead: Option<bool>,
+        #[serde(default)]
+        user_group: Option<String>,
+        #[serde(default = "default_vmess_max_time_diff_secs")]
+        max_time_diff_secs: u64,
     }
 
     let temp = VmessServerTemp::deserialize(deserializer)?;
@@ -147,7 +251,13 @@ where
         );
     }
 
-    Ok((temp.cipher, temp.user_id, temp.udp_enabled))
+    Ok((
+        temp.cipher,
+        temp.user_id,
+        temp.udp_enabled,
+        temp.user_group,
+        temp.max_time_diff_secs,
+    ))
 }
 
 pub fn direct_allow_rule() -> NoneOrSome<ConfigSelection<RuleConfig>> {
@@ -169,6 +279,8 @@ pub struct ServerConfig {
     pub tcp_settings: Option<TcpConfig>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub quic_settings: Option<ServerQuicConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kcp_settings: Option<KcpConfig>,
     #[serde(
         alias = "rule",
         default = "direct_allow_rule",
@@ -179,6 +291,19 @@ pub struct ServerConfig {
     /// Can reference a dns_group by name or specify inline DNS servers.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub dns: Option<DnsConfig>,
+    /// Bandwidth limits shared by all connections of this server (optional).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Maximum number of connections this server handles at once (optional).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connection_limit: Option<ConnectionLimitConfig>,
+    /// Idle and half-close timeouts of forwarded sessions (optional).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_timeouts: Option<SessionTimeoutConfig>,
+    /// Client IPs that may connect to this server (optional). Replaces the default
+    /// access control.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub access_control: Option<AccessControlConfig>,
 }
 
 impl<'de> serde::de::Deserialize<'de> for ServerConfig {
@@ -193,17 +318,24 @@ impl<'de> serde::de::Deserialize<'de> for ServerConfig {
             .as_mapping()
             .ok_or_else(|| Error::custom("ServerConfig must be a YAML mapping"))?;
 
-        // Valid fields: address/path (bind_location), protocol, transport, tcp_settings, quic_settings, rules/rule, dns
+        // Valid fields: address/interface/path/systemd (bind_location), protocol, transport, tcp_settings, quic_settings, kcp_settings, rules/rule, dns, rate_limit, connection_limit, session_timeouts, access_control
         const VALID_FIELDS: &[&str] = &[
             "address",
-            "path", // BindLocation (flattened)
+            "interface",
+            "path",
+            "systemd", // BindLocation (flattened)
             "protocol",
             "transport",
             "tcp_settings",
             "quic_settings",
+            "kcp_settings",
             "rules",
             "rule",
             "dns",
+            "rate_limit",
+            "connection_limit",
+            "session_timeouts",
+            "access_control",
         ];
 
         // Check for unknown fields
@@ -219,19 +351,60 @@ impl<'de> serde::de::Deserialize<'de> for ServerConfig {
             }
         }
 
-        // Parse bind_location (flattened - either address or path)
-        let bind_location = if let Some(v) = map.get("address") {
-            serde_yaml::from_value(v.clone())
-                .map(BindLocation::Address)
-                .map_err(|e| Error::custom(format!("invalid address: {e}")))?
-        } else if let Some(v) = map.get("path") {
-            serde_yaml::from_value(v.clone())
-                .map(BindLocation::Path)
-                .map_err(|e| Error::custom(format!("invalid path: {e}")))?
-        } else {
+        // Parse bind_location (flattened - either address and/or interface, path or systemd)
+        let addresses: Vec<NetLocationPortRange> = map
+            .get("address")
+            .map(|v| serde_yaml::from_value::<OneOrSome<NetLocationPortRange>>(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid address: {e}")))?
+            .map(OneOrSome::into_vec)
+            .unwrap_or_default();
+        let interfaces: Vec<NetLocationPortRange> = map
+            .get("interface")
+            .map(|v| serde_yaml::from_value::<OneOrSome<NetLocationPortRange>>(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid interface: {e}")))?
+            .map(OneOrSome::into_vec)
+            .unwrap_or_default();
+        if interfaces
+            .iter()
+            .any(|interface| interface.address().hostname().is_none())
+        {
             return Err(Error::custom(
-                "server config must have either 'address' or 'path' field",
+                "invalid interface: expected an interface name and ports, like eth0:443",
             ));
+        }
+
+        let has_addresses = !addresses.is_empty() || !interfaces.is_empty();
+        let bind_location = match (map.get("path"), map.get("systemd")) {
+            (Some(_), _) | (_, Some(_)) if has_addresses => {
+                return Err(Error::custom(
+                    "server config can't have 'path' or 'systemd' with 'address' or 'interface'",
+                ));
+            }
+            (Some(_), Some(_)) => {
+                return Err(Error::custom(
+                    "server config can't have both 'path' and 'systemd'",
+                ));
+            }
+            (Some(v), None) => serde_yaml::from_value(v.clone())
+                .map(BindLocation::Path)
+                .map_err(|e| Error::custom(format!("invalid path: {e}")))?,
+            (None, Some(v)) => serde_yaml::from_value(v.clone())
+                .map(BindLocation::Systemd)
+                .map_err(|e| Error::custom(format!("invalid systemd socket name: {e}")))?,
+            (None, None) if addresses.len() == 1 && interfaces.is_empty() => {
+                BindLocation::Address(addresses.into_iter().next().unwrap())
+            }
+            (None, None) if has_addresses => BindLocation::Addresses {
+                addresses,
+                interfaces,
+            },
+            (None, None) => {
+                return Err(Error::custom(
+                    "server config must have either 'address', 'interface', 'path' or 'systemd' field",
+                ));
+            }
         };
 
         // Parse protocol (required)
@@ -267,6 +440,14 @@ impl<'de> serde::de::Deserialize<'de> for ServerConfig {
             .transpose()
             .map_err(|e| Error::custom(format!("invalid quic_settings: {e}")))?;
 
+        // Parse kcp_settings (optional, skip if null)
+        let kcp_settings: Option<KcpConfig> = map
+            .get("kcp_settings")
+            .filter(|v| !v.is_null())
+            .map(|v| serde_yaml::from_value(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid kcp_settings: {e}")))?;
+
         // Parse rules (optional, with alias "rule", default to direct_allow_rule, skip if null)
         let rules: NoneOrSome<ConfigSelection<RuleConfig>> = map
             .get("rules")
@@ -285,14 +466,51 @@ impl<'de> serde::de::Deserialize<'de> for ServerConfig {
             .transpose()
             .map_err(|e| Error::custom(format!("invalid dns: {e}")))?;
 
+        // Parse rate_limit (optional)
+        let rate_limit: Option<RateLimitConfig> = map
+            .get("rate_limit")
+            .filter(|v| !v.is_null())
+            .map(|v| serde_yaml::from_value(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid rate_limit: {e}")))?;
+
+        // Parse connection_limit (optional)
+        let connection_limit: Option<ConnectionLimitConfig> = map
+            .get("connection_limit")
+            .filter(|v| !v.is_null())
+            .map(|v| serde_yaml::from_value(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid connection_limit: {e}")))?;
+
+        // Parse session_timeouts (optional)
+        let session_timeouts: Option<SessionTimeoutConfig> = map
+            .get("session_timeouts")
+            .filter(|v| !v.is_null())
+            .map(|v| serde_yaml::from_value(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid session_timeouts: {e}")))?;
+
+        // Parse access_control (optional)
+        let access_control: Option<AccessControlConfig> = map
+            .get("access_control")
+            .filter(|v| !v.is_null())
+            .map(|v| serde_yaml::from_value(v.clone()))
+            .transpose()
+            .map_err(|e| Error::custom(format!("invalid access_control: {e}")))?;
+
         Ok(ServerConfig {
             bind_location,
             protocol,
             transport,
             tcp_settings,
             quic_settings,
+            kcp_settings,
             rules,
             dns,
+            rate_limit,
+            connection_limit,
+            session_timeouts,
+            access_control,
         })
     }
 }
@@ -382,8 +600,29 @@ pub struct TlsServerConfig {
     /// Requires TLS 1.3.
     #[serde(default)]
     pub vision: bool,
+
+    /// Key exchange with the hybrid X25519MLKEM768 group. When unset, rustls defaults are
+    /// used. When true, the hybrid group is preferred. When false, only classical groups
+    /// are offered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub post_quantum: Option<bool>,
+
+    /// ECH keys. Not supported, since rustls only implements ECH for clients. Parsed so
+    /// that configs setting them are rejected by validation instead of ignored.
+    #[serde(
+        alias = "ech_key",
+        default,
+        skip_serializing_if = "NoneOrSome::is_unspecified"
+    )]
+    pub ech_keys: NoneOrSome<String>,
+
     pub protocol: ServerProxyConfig,
 
+    /// Protocols to use instead of `protocol` when the client negotiates one of these
+    /// ALPN protocols, which must also be in `alpn_protocols`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub alpn_targets: HashMap<String, ServerProxyConfig>,
+
     #[serde(alias = "override_rule", default)]
     pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
 }
@@ -395,6 +634,33 @@ pub struct ShadowTlsServerConfig {
     pub protocol: ServerProxyConfig,
     #[serde(alias = "override_rule", default)]
     pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+    /// Destination of clients that fail authentication, reached through the client chain
+    /// of a remote handshake. Without it, these clients are forwarded to the handshake
+    /// server of a remote handshake, and rejected with a local handshake.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<NetLocation>,
+    #[serde(default, skip_serializing_if = "ShadowTlsWildcardSni::is_default")]
+    pub wildcard_sni: ShadowTlsWildcardSni,
+}
+
+/// Whether a remote handshake is done with the server of the SNI requested by the client,
+/// on the port of the handshake address, instead of the handshake address.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ShadowTlsWildcardSni {
+    #[default]
+    Off,
+    /// For clients that pass authentication.
+    Authed,
+    /// For all clients, including those forwarded to the handshake server after failing
+    /// authentication.
+    All,
+}
+
+impl ShadowTlsWildcardSni {
+    pub fn is_default(&self) -> bool {
+        matches!(self, ShadowTlsWildcardSni::Off)
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -603,6 +869,12 @@ impl serde::ser::Serialize for ShadowTlsServerHandshakeConfig {
 pub struct WebsocketServerConfig {
     #[serde(default)]
     pub matching_path: Option<String>,
+    /// Host header the request must have, compared case-insensitively. A leading `*.`
+    /// matches any subdomain, and the port is ignored unless the pattern has one.
+    #[serde(default)]
+    pub matching_host: Option<String>,
+    /// Headers the request must have. A value of `*` only requires the header to be
+    /// present.
     #[serde(default)]
     pub matching_headers: Option<HashMap<String, String>>,
     pub protocol: ServerProxyConfig,
@@ -613,6 +885,20 @@ pub struct WebsocketServerConfig {
     pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
 }
 
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DispatchTargetConfig {
+    /// Path the first request must have, compared without the query.
+    #[serde(alias = "path", default)]
+    pub matching_path: Option<String>,
+    /// Host the first request must have, as with WebSocket `matching_host`.
+    #[serde(alias = "host", default)]
+    pub matching_host: Option<String>,
+    pub protocol: ServerProxyConfig,
+
+    #[serde(alias = "override_rule", default)]
+    pub override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+}
+
 #[derive(Default, Debug, Clone, Deserialize, Serialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum WebsocketPingType {
@@ -635,6 +921,26 @@ impl WebsocketPingType {
     }
 }
 
+/// How the obfs4 layer obfuscates the timing of writes.
+#[derive(Default, Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Obfs4IatMode {
+    /// Writes are sent as soon as they are flushed.
+    #[default]
+    None,
+    /// Writes are split into full segments, with random delays between them.
+    Enabled,
+    /// Writes are split into segments of random lengths, with random delays between
+    /// them.
+    Paranoid,
+}
+
+impl Obfs4IatMode {
+    pub fn is_default(&self) -> bool {
+        matches!(self, Obfs4IatMode::None)
+    }
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum ServerProxyConfig {
@@ -643,6 +949,10 @@ pub enum ServerProxyConfig {
         username: Option<String>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         password: Option<String>,
+        /// Decoy website for requests that aren't proxy requests or fail authentication,
+        /// instead of an error response.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        decoy: Option<DecoyConfig>,
     },
     #[serde(alias = "socks5")]
     Socks {
@@ -650,6 +960,9 @@ pub enum ServerProxyConfig {
         username: Option<String>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         password: Option<String>,
+        /// Name of a user group, whose users are accepted besides the inline credential.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_group: Option<String>,
         /// Enable UDP functionality (UDP ASSOCIATE and UDP-over-TCP).
         /// When false (default), UDP ASSOCIATE returns "command not supported".
         #[serde(default = "default_true")]
@@ -662,28 +975,58 @@ pub enum ServerProxyConfig {
     )]
     Shadowsocks {
         config: ShadowsocksConfig,
+        /// Users of a multi-user 2022-blake3-aes server, identified by extensible
+        /// identity headers. The password is then the server's identity PSK.
+        users: Vec<ShadowsocksUserConfig>,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        /// SIP003 plugin listening on the server address in front of the shadowsocks server
+        plugin: Option<ShadowsocksPluginConfig>,
+        /// Name of a user group, whose users are accepted like `users`.
+        user_group: Option<String>,
     },
     Snell {
         cipher: String,
         password: String,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        /// Protocol version, 2 or 3. Version 2 rejects UDP and the connect command of
+        /// version 3.
+        #[serde(
+            default = "default_snell_version",
+            skip_serializing_if = "is_default_snell_version"
+        )]
+        version: u8,
     },
     Vless {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         user_id: String,
+        /// Name of a user group, whose users are accepted besides the inline credential.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_group: Option<String>,
         #[serde(default = "default_true")]
         udp_enabled: bool,
         /// Fallback destination for failed authentication (optional)
         /// When set, failed auth attempts are proxied here instead of rejected
         #[serde(default, skip_serializing_if = "Option::is_none")]
         fallback: Option<NetLocation>,
+        /// Flow of Xray configs. `xtls-rprx-vision` turns on Vision in the enclosing
+        /// TLS or Reality target, like `vision: true`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flow: Option<VlessFlow>,
     },
     Trojan {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         password: String,
+        /// Name of a user group, whose users are accepted besides the inline credential.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_group: Option<String>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         shadowsocks: Option<ShadowsocksConfig>,
+        /// Decoy website for connections that don't start with a valid password hash,
+        /// instead of closing them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        decoy: Option<DecoyConfig>,
     },
     Tls {
         // sni_targets is the previous field name
@@ -703,13 +1046,30 @@ pub enum ServerProxyConfig {
 
         #[serde(default, skip_serializing_if = "Option::is_none")]
         tls_buffer_size: Option<usize>,
+
+        /// Web server that connections with an unknown or missing SNI are forwarded to,
+        /// when there is no default target. The ClientHello is forwarded as is.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback: Option<NetLocation>,
     },
     #[serde(deserialize_with = "deserialize_vmess_server")]
     Vmess {
         cipher: String,
+        #[serde(skip_serializing_if = "String::is_empty")]
         user_id: String,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        /// Name of a user group, whose users are accepted besides the inline credential.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        user_group: Option<String>,
+        /// Maximum difference between the timestamp of a request and the server time.
+        /// Older clients with unsynchronized clocks need a larger value, which also
+        /// allows requests to be replayed for longer.
+        #[serde(
+            default = "default_vmess_max_time_diff_secs",
+            skip_serializing_if = "is_default_vmess_max_time_diff_secs"
+        )]
+        max_time_diff_secs: u64,
     },
     /// AnyTLS inner protocol handler
     /// Used as inner protocol within TLS/Reality/etc.
@@ -730,26 +1090,124 @@ pub enum ServerProxyConfig {
         #[serde(alias = "target")]
         targets: Box<OneOrSome<WebsocketServerConfig>>,
     },
+    /// gRPC transport (v2ray "gun"). Each gRPC call on the HTTP/2 connection is
+    /// handled as a separate stream by the inner protocol.
+    Grpc {
+        #[serde(default = "default_grpc_service_name")]
+        service_name: String,
+        protocol: Box<ServerProxyConfig>,
+        #[serde(alias = "override_rule", default)]
+        override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+    },
+    /// HTTPUpgrade transport (Xray/sing-box "httpupgrade"). An HTTP/1.1 upgrade
+    /// request, followed by the inner protocol without WebSocket framing.
+    #[serde(alias = "http_upgrade")]
+    HttpUpgrade {
+        /// Request path to accept, any path when unset. The query isn't matched.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+        /// Host header to accept, any host when unset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+        protocol: Box<ServerProxyConfig>,
+        #[serde(alias = "override_rule", default)]
+        override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+    },
+    /// XHTTP transport (Xray "xhttp", formerly SplitHTTP). Uploads are POST requests
+    /// and downloads a streamed GET response, over HTTP/1.1 or HTTP/2.
+    #[serde(alias = "splithttp")]
+    Xhttp {
+        /// Base path of the requests.
+        #[serde(default = "default_xhttp_path")]
+        path: String,
+        /// Host to accept, any host when unset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host: Option<String>,
+        protocol: Box<ServerProxyConfig>,
+        #[serde(alias = "override_rule", default)]
+        override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+    },
+    /// Dispatches each connection by the path and Host of its first HTTP request, like
+    /// Xray fallbacks. Connections that aren't HTTP/1 or match no target use `fallback`.
+    #[serde(alias = "fallbacks")]
+    Dispatch {
+        #[serde(alias = "target", default)]
+        targets: Vec<DispatchTargetConfig>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fallback: Option<Box<ServerProxyConfig>>,
+    },
+    /// obfs4 style obfuscation layer. Makes the inner protocol look like random bytes,
+    /// with a handshake that only clients that know the server's public key and node
+    /// ID can complete.
+    Obfs4 {
+        /// X25519 private key, base64url encoded.
+        private_key: String,
+        /// 20 byte node ID, base64url encoded.
+        node_id: String,
+        #[serde(default, skip_serializing_if = "Obfs4IatMode::is_default")]
+        iat_mode: Obfs4IatMode,
+        protocol: Box<ServerProxyConfig>,
+        #[serde(alias = "override_rule", default)]
+        override_rules: NoneOrSome<ConfigSelection<RuleConfig>>,
+    },
     #[serde(alias = "forward")]
     PortForward {
+        /// Targets that connections are spread over. A target with several ports has a
+        /// port for each listening port, matched in ascending order.
         #[serde(alias = "target")]
-        targets: OneOrSome<NetLocation>,
+        targets: OneOrSome<NetLocationPortRange>,
+        /// Forward UDP on the same ports as well.
+        #[serde(default, skip_serializing_if = "is_false")]
+        udp_enabled: bool,
     },
     Hysteria2 {
         password: String,
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        /// Bandwidth of the server. Clients are sent data at the lower of `up` and
+        /// their download bandwidth with Brutal congestion control.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bandwidth: Option<Hysteria2Bandwidth>,
+        /// Use BBR instead of the bandwidth of clients, and tell clients to use BBR.
+        #[serde(default, skip_serializing_if = "is_false")]
+        ignore_client_bandwidth: bool,
+        /// Obfuscation of QUIC packets, clients need the same settings.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        obfs: Option<Hysteria2ObfsConfig>,
     },
     #[serde(alias = "tuic")]
     TuicV5 {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         uuid: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         password: String,
+        /// Name of a user group, whose users are accepted besides the inline credential.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_group: Option<String>,
         /// Enable 0-RTT (0.5-RTT for server) handshake for faster connection establishment.
         /// Default is false for security - 0-RTT data is vulnerable to replay attacks.
         /// See: https://blog.cloudflare.com/even-faster-connection-establishment-with-quic-0-rtt-resumption/
         #[serde(default)]
         zero_rtt_handshake: bool,
     },
+    /// MASQUE proxy: HTTP/3 CONNECT for TCP and CONNECT-UDP (RFC 9298) for UDP.
+    /// Requires `transport: quic`.
+    Masque {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        username: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// Juicity proxy, authenticated like TUIC v5. Requires `transport: quic`.
+    Juicity {
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        uuid: String,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        password: String,
+        /// Name of a user group, whose users are accepted besides the inline credential.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_group: Option<String>,
+    },
     /// Mixed HTTP+SOCKS5 server (auto-detects protocol from first byte)
     /// Similar to mihomo's mixed-port feature.
     #[serde(alias = "http+socks", alias = "socks+http")]
@@ -761,6 +1219,10 @@ pub enum ServerProxyConfig {
         /// Enable UDP functionality for SOCKS5 (UDP ASSOCIATE and UDP-over-TCP)
         #[serde(default = "default_true")]
         udp_enabled: bool,
+        /// Decoy website for HTTP requests that aren't proxy requests or fail
+        /// authentication, like for `Http`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        decoy: Option<DecoyConfig>,
     },
     /// NaiveProxy server (HTTP/2 CONNECT with padding)
     /// Should be used within TLS for proper camouflage
@@ -780,6 +1242,91 @@ pub enum ServerProxyConfig {
         #[serde(default = "default_true")]
         udp_enabled: bool,
     },
+    /// SSH server serving `direct-tcpip` channels, for clients that use `ssh -D`,
+    /// `ssh -L` or `ssh -W`.
+    Ssh {
+        /// OpenSSH private host key, either inline PEM data or a file path
+        host_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        host_key_passphrase: Option<String>,
+        /// Public keys that clients authenticate with, in `authorized_keys` format
+        #[serde(alias = "authorized_key")]
+        authorized_keys: OneOrSome<String>,
+    },
+    /// DNS server answering queries with the server's `dns` resolver.
+    /// Listens on TCP, or on UDP with `transport: udp`.
+    Dns {
+        /// Upstream DNS servers for specific domains, checked in order before the
+        /// server's resolver.
+        #[serde(
+            alias = "upstream",
+            default,
+            skip_serializing_if = "NoneOrSome::is_unspecified"
+        )]
+        upstreams: NoneOrSome<DnsUpstreamConfig>,
+        /// Answer A and AAAA queries from the fake-IP ranges (default: false)
+        #[serde(default)]
+        fake_ip: bool,
+    },
+    /// Transparent proxy for connections and datagrams diverted by iptables/nftables
+    /// TPROXY rules (Linux only). Listens on TCP, or on UDP with `transport: udp`.
+    Tproxy {
+        /// Answer DNS queries to port 53 with the server's resolver instead of
+        /// forwarding them (default: false)
+        #[serde(default, skip_serializing_if = "is_false")]
+        dns_hijack: bool,
+        /// Find the domain of connections to IP addresses in the TLS server name or the
+        /// HTTP Host header, and route them by it (default: false)
+        #[serde(default, skip_serializing_if = "is_false")]
+        sniff: bool,
+        /// Connect to the sniffed domain instead of the original destination address
+        /// (default: false)
+        #[serde(default, skip_serializing_if = "is_false")]
+        sniff_override_destination: bool,
+    },
+    /// Transparent proxy for TCP connections redirected by iptables/nftables NAT
+    /// `REDIRECT` rules (Linux only).
+    Redirect {
+        /// Answer DNS queries to port 53 with the server's resolver instead of
+        /// forwarding them (default: false)
+        #[serde(default, skip_serializing_if = "is_false")]
+        dns_hijack: bool,
+        /// Find the domain of connections to IP addresses in the TLS server name or the
+        /// HTTP Host header, and route them by it (default: false)
+        #[serde(default, skip_serializing_if = "is_false")]
+        sniff: bool,
+        /// Connect to the sniffed domain instead of the original destination address
+        /// (default: false)
+        #[serde(default, skip_serializing_if = "is_false")]
+        sniff_override_destination: bool,
+    },
+}
+
+impl ServerProxyConfig {
+    /// Whether this is a transparent proxy, which forwards to the original destination
+    /// of diverted traffic.
+    pub fn is_transparent(&self) -> bool {
+        matches!(self, Self::Tproxy { .. } | Self::Redirect { .. })
+    }
+
+    /// Whether UDP is forwarded as well, on the same ports as TCP.
+    pub fn forwards_udp(&self) -> bool {
+        matches!(
+            self,
+            Self::PortForward {
+                udp_enabled: true,
+                ..
+            }
+        )
+    }
+
+    /// The SIP003 plugin listening in front of this server, if any.
+    pub fn sip003_plugin(&self) -> Option<&ShadowsocksPluginConfig> {
+        match self {
+            Self::Shadowsocks { plugin, .. } => plugin.as_ref(),
+            _ => None,
+        }
+    }
 }
 
 impl std::fmt::Display for ServerProxyConfig {
@@ -821,12 +1368,23 @@ impl std::fmt::Display for ServerProxyConfig {
             }
             Self::Vmess { .. } => write!(f, "Vmess"),
             Self::Websocket { .. } => write!(f, "Websocket"),
+            Self::Grpc { .. } => write!(f, "gRPC"),
+            Self::HttpUpgrade { .. } => write!(f, "HTTPUpgrade"),
+            Self::Xhttp { .. } => write!(f, "XHTTP"),
+            Self::Dispatch { .. } => write!(f, "Dispatch"),
+            Self::Obfs4 { .. } => write!(f, "obfs4"),
             Self::PortForward { .. } => write!(f, "Portforward"),
             Self::Hysteria2 { .. } => write!(f, "Hysteria2"),
             Self::TuicV5 { .. } => write!(f, "TuicV5"),
+            Self::Masque { .. } => write!(f, "MASQUE"),
+            Self::Juicity { .. } => write!(f, "Juicity"),
             Self::Mixed { .. } => write!(f, "Mixed (HTTP+SOCKS5)"),
             Self::Anytls { .. } => write!(f, "AnyTLS"),
             Self::Naiveproxy { .. } => write!(f, "NaiveProxy"),
+            Self::Ssh { .. } => write!(f, "SSH"),
+            Self::Dns { .. } => write!(f, "DNS"),
+            Self::Tproxy { .. } => write!(f, "TPROXY"),
+            Self::Redirect { .. } => write!(f, "REDIRECT"),
         }
     }
 }
@@ -845,12 +1403,18 @@ mod tests {
             protocol: ServerProxyConfig::Http {
                 username: Some("user".to_string()),
                 password: Some("pass".to_string()),
+                decoy: None,
             },
             transport: Transport::Tcp,
-            tcp_settings: Some(TcpConfig { no_delay: true }),
+            tcp_settings: Some(TcpConfig::default()),
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -863,12 +1427,18 @@ mod tests {
                 username: None,
                 password: None,
                 udp_enabled: false,
+                user_group: None,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -880,13 +1450,21 @@ mod tests {
                     cipher: "aes-256-gcm".try_into().unwrap(),
                     password: "secret123".to_string(),
                 },
+                users: vec![],
                 udp_enabled: true,
+                plugin: None,
+                user_group: None,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -898,8 +1476,10 @@ mod tests {
             ),
             protocol: ServerProxyConfig::Vless {
                 user_id: "550e8400-e29b-41d4-a716-446655440000".to_string(),
+                user_group: None,
                 udp_enabled: true,
                 fallback: None,
+                flow: None,
             },
             transport: Transport::Quic,
             tcp_settings: None,
@@ -911,8 +1491,13 @@ mod tests {
                 client_fingerprints: NoneOrSome::None,
                 num_endpoints: 1,
             }),
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -923,16 +1508,23 @@ mod tests {
             ),
             protocol: ServerProxyConfig::Trojan {
                 password: "trojan_password".to_string(),
+                user_group: None,
                 shadowsocks: Some(ShadowsocksConfig::Legacy {
                     cipher: "chacha20-poly1305".try_into().unwrap(),
                     password: "ss_password".to_string(),
                 }),
+                decoy: None,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -947,10 +1539,14 @@ mod tests {
                 client_ca_certs: NoneOrSome::One("ca.crt".to_string()),
                 client_fingerprints: NoneOrSome::One("abc123".to_string()),
                 vision: false,
+                post_quantum: None,
+                ech_keys: NoneOrSome::Unspecified,
                 protocol: ServerProxyConfig::Http {
                     username: None,
                     password: None,
+                    decoy: None,
                 },
+                alpn_targets: HashMap::new(),
                 override_rules: NoneOrSome::None,
             },
         );
@@ -968,21 +1564,31 @@ mod tests {
                     client_ca_certs: NoneOrSome::None,
                     client_fingerprints: NoneOrSome::None,
                     vision: false,
+                    post_quantum: None,
+                    ech_keys: NoneOrSome::Unspecified,
                     protocol: ServerProxyConfig::Http {
                         username: None,
                         password: None,
+                        decoy: None,
                     },
+                    alpn_targets: HashMap::new(),
                     override_rules: NoneOrSome::None,
                 })),
                 shadowtls_targets: HashMap::new(),
                 reality_targets: HashMap::new(),
                 tls_buffer_size: Some(8192),
+                fallback: None,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -995,12 +1601,19 @@ mod tests {
                 cipher: "aes-128-gcm".to_string(),
                 user_id: "b831381d-6324-4d53-ad4f-8cda48b30811".to_string(),
                 udp_enabled: false,
+                user_group: None,
+                max_time_diff_secs: 120,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -1012,10 +1625,12 @@ mod tests {
             protocol: ServerProxyConfig::Websocket {
                 targets: Box::new(OneOrSome::One(WebsocketServerConfig {
                     matching_path: Some("/ws".to_string()),
+                    matching_host: None,
                     matching_headers: None,
                     protocol: ServerProxyConfig::Http {
                         username: None,
                         password: None,
+                        decoy: None,
                     },
                     ping_type: WebsocketPingType::PingFrame,
                     override_rules: NoneOrSome::None,
@@ -1024,8 +1639,69 @@ mod tests {
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
+        }
+    }
+
+    fn create_test_server_config_grpc() -> ServerConfig {
+        ServerConfig {
+            bind_location: BindLocation::Address(
+                NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8443).into(),
+            ),
+            protocol: ServerProxyConfig::Grpc {
+                service_name: "TunService".to_string(),
+                protocol: Box::new(ServerProxyConfig::Http {
+                    username: None,
+                    password: None,
+                    decoy: None,
+                }),
+                override_rules: NoneOrSome::None,
+            },
+            transport: Transport::Tcp,
+            tcp_settings: None,
+            quic_settings: None,
+            kcp_settings: None,
+            rules: NoneOrSome::None,
+            dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
+        }
+    }
+
+    fn create_test_server_config_obfs4() -> ServerConfig {
+        ServerConfig {
+            bind_location: BindLocation::Address(
+                NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8443).into(),
+            ),
+            protocol: ServerProxyConfig::Obfs4 {
+                private_key: "SMa3Bz6lsSEcLkgQJ1CgUjfeVmgqhHm2Pj3YDOrEzGY".to_string(),
+                node_id: "AAECAwQFBgcICQoLDA0ODxAREhM".to_string(),
+                iat_mode: Obfs4IatMode::Paranoid,
+                protocol: Box::new(ServerProxyConfig::Http {
+                    username: None,
+                    password: None,
+                    decoy: None,
+                }),
+                override_rules: NoneOrSome::None,
+            },
+            transport: Transport::Tcp,
+            tcp_settings: None,
+            quic_settings: None,
+            kcp_settings: None,
+            rules: NoneOrSome::None,
+            dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -1036,15 +1712,21 @@ mod tests {
             ),
             protocol: ServerProxyConfig::PortForward {
                 targets: OneOrSome::Some(vec![
-                    NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 80),
-                    NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 80),
+                    NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 80).into(),
+                    NetLocation::from_ip_addr(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 80).into(),
                 ]),
+                udp_enabled: false,
             },
             transport: Transport::Tcp,
             tcp_settings: None,
             quic_settings: None,
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -1056,6 +1738,9 @@ mod tests {
             protocol: ServerProxyConfig::Hysteria2 {
                 password: "hysteria_pass".to_string(),
                 udp_enabled: true,
+                bandwidth: None,
+                ignore_client_bandwidth: false,
+                obfs: None,
             },
             transport: Transport::Quic,
             tcp_settings: None,
@@ -1067,8 +1752,13 @@ mod tests {
                 client_fingerprints: NoneOrSome::None,
                 num_endpoints: 1,
             }),
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -1080,6 +1770,7 @@ mod tests {
             protocol: ServerProxyConfig::TuicV5 {
                 uuid: "550e8400-e29b-41d4-a716-446655440000".to_string(),
                 password: "tuic_password".to_string(),
+                user_group: None,
                 zero_rtt_handshake: false,
             },
             transport: Transport::Quic,
@@ -1092,8 +1783,13 @@ mod tests {
                 client_fingerprints: NoneOrSome::None,
                 num_endpoints: 1,
             }),
+            kcp_settings: None,
             rules: NoneOrSome::None,
             dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
         }
     }
 
@@ -1196,6 +1892,37 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_server_config_grpc() {
+        let original = create_test_server_config_grpc();
+        let yaml_str = serde_yaml::to_string(&original).expect("Failed to serialize");
+        let deserialized: ServerConfig =
+            serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
+        match deserialized.protocol {
+            ServerProxyConfig::Grpc { service_name, .. } => {
+                assert_eq!(service_name, "TunService")
+            }
+            _ => panic!("Expected Grpc protocol"),
+        }
+    }
+
+    #[test]
+    fn test_server_config_obfs4() {
+        let original = create_test_server_config_obfs4();
+        let yaml_str = serde_yaml::to_string(&original).expect("Failed to serialize");
+        let deserialized: ServerConfig =
+            serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
+        match deserialized.protocol {
+            ServerProxyConfig::Obfs4 {
+                node_id, iat_mode, ..
+            } => {
+                assert_eq!(node_id, "AAECAwQFBgcICQoLDA0ODxAREhM");
+                assert_eq!(iat_mode, Obfs4IatMode::Paranoid);
+            }
+            _ => panic!("Expected Obfs4 protocol"),
+        }
+    }
+
     #[test]
     fn test_server_config_port_forward() {
         let original = create_test_server_config_port_forward();
@@ -1208,6 +1935,55 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_server_config_port_forward_port_range() {
+        let yaml = r#"
+address: "0.0.0.0:10000-10100"
+protocol:
+  type: forward
+  target: "10.0.0.1:20000-20100"
+  udp_enabled: true
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        let ServerProxyConfig::PortForward {
+            targets,
+            udp_enabled,
+        } = &config.protocol
+        else {
+            panic!("Expected PortForward protocol");
+        };
+        assert!(*udp_enabled);
+        assert!(config.protocol.forwards_udp());
+        let targets = targets.iter().collect::<Vec<_>>();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].ports().len(), 101);
+        assert_eq!(targets[0].to_string(), "10.0.0.1:20000-20100");
+    }
+
+    #[test]
+    fn test_server_config_ssh() {
+        let yaml = r#"
+address: "0.0.0.0:2222"
+protocol:
+  type: ssh
+  host_key: /etc/shoes/ssh_host_ed25519_key
+  authorized_key: "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4UzRMijUQge+SWupQlx6CrjkRRVTfX9yhyV+uvNhNo"
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        let ServerProxyConfig::Ssh {
+            host_key,
+            host_key_passphrase,
+            authorized_keys,
+        } = &config.protocol
+        else {
+            panic!("Expected Ssh protocol");
+        };
+        assert_eq!(host_key, "/etc/shoes/ssh_host_ed25519_key");
+        assert!(host_key_passphrase.is_none());
+        assert_eq!(authorized_keys.len(), 1);
+        assert_eq!(config.protocol.to_string(), "SSH");
+    }
+
     #[test]
     fn test_server_config_hysteria2() {
         let original = create_test_server_config_hysteria2();
@@ -1220,6 +1996,36 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_server_config_hysteria2_bandwidth() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+transport: quic
+quic_settings:
+  cert: cert.pem
+  key: key.pem
+protocol:
+  type: hysteria2
+  password: secret
+  bandwidth:
+    up: "1 gbps"
+    down_mbps: 500
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let ServerProxyConfig::Hysteria2 {
+            bandwidth,
+            ignore_client_bandwidth,
+            ..
+        } = config.protocol
+        else {
+            panic!("Expected Hysteria2 protocol");
+        };
+        let bandwidth = bandwidth.expect("bandwidth should be set");
+        assert_eq!(bandwidth.parse_up().unwrap(), 1024 * 1024 * 1024 / 8);
+        assert_eq!(bandwidth.parse_down().unwrap(), 500 * 1024 * 1024 / 8);
+        assert!(!ignore_client_bandwidth);
+    }
+
     #[test]
     fn test_server_config_tuic() {
         let original = create_test_server_config_tuic();
@@ -1232,6 +2038,323 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn test_server_config_masque() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+transport: quic
+quic_settings:
+  cert: cert.pem
+  key: key.pem
+protocol:
+  type: masque
+  username: user
+  password: pass
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        assert!(matches!(
+            config.protocol,
+            ServerProxyConfig::Masque {
+                username: Some(_),
+                password: Some(_),
+            }
+        ));
+    }
+
+    #[test]
+    fn test_server_config_juicity() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+transport: quic
+quic_settings:
+  cert: cert.pem
+  key: key.pem
+protocol:
+  type: juicity
+  uuid: 550e8400-e29b-41d4-a716-446655440000
+  password: secret
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        assert!(matches!(
+            config.protocol,
+            ServerProxyConfig::Juicity {
+                user_group: None,
+                ..
+            }
+        ));
+        assert_eq!(config.protocol.to_string(), "Juicity");
+    }
+
+    #[test]
+    fn test_server_config_xhttp() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+protocol:
+  type: splithttp
+  protocol:
+    type: vless
+    user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        match config.protocol {
+            ServerProxyConfig::Xhttp { ref path, .. } => assert_eq!(path, "/"),
+            _ => panic!("Expected Xhttp protocol"),
+        }
+        assert_eq!(config.protocol.to_string(), "XHTTP");
+    }
+
+    #[test]
+    fn test_server_config_dispatch() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+protocol:
+  type: tls
+  default_tls_target:
+    cert: cert.pem
+    key: key.pem
+    alpn_protocols: [h2, http/1.1]
+    protocol:
+      type: fallbacks
+      targets:
+        - path: /vless
+          protocol:
+            type: ws
+            targets:
+              - protocol:
+                  type: vless
+                  user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+        - host: "*.example.com"
+          protocol:
+            type: http
+      fallback:
+        type: trojan
+        password: secret
+    alpn_targets:
+      h2:
+        type: grpc
+        protocol:
+          type: vless
+          user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let ServerProxyConfig::Tls {
+            default_tls_target: Some(tls_config),
+            ..
+        } = config.protocol
+        else {
+            panic!("Expected TLS protocol");
+        };
+        assert!(matches!(
+            tls_config.alpn_targets.get("h2"),
+            Some(ServerProxyConfig::Grpc { .. })
+        ));
+        match tls_config.protocol {
+            ServerProxyConfig::Dispatch { targets, fallback } => {
+                assert_eq!(targets.len(), 2);
+                assert_eq!(targets[0].matching_path.as_deref(), Some("/vless"));
+                assert_eq!(targets[1].matching_host.as_deref(), Some("*.example.com"));
+                assert!(matches!(
+                    fallback.as_deref(),
+                    Some(ServerProxyConfig::Trojan { .. })
+                ));
+            }
+            _ => panic!("Expected Dispatch protocol"),
+        }
+    }
+
+    #[test]
+    fn test_server_config_decoy() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+protocol:
+  type: tls
+  fallback: 127.0.0.1:8443
+  targets:
+    example.com:
+      cert: example.crt
+      key: example.key
+      protocol:
+        type: trojan
+        password: secret
+        decoy: /var/www/html
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let ServerProxyConfig::Tls {
+            tls_targets,
+            fallback,
+            ..
+        } = config.protocol
+        else {
+            panic!("Expected TLS protocol");
+        };
+        assert_eq!(fallback.unwrap().port(), 8443);
+        match &tls_targets["example.com"].protocol {
+            ServerProxyConfig::Trojan {
+                decoy: Some(DecoyConfig::Directory(path)),
+                ..
+            } => assert_eq!(path, std::path::Path::new("/var/www/html")),
+            _ => panic!("Expected Trojan protocol with a decoy directory"),
+        }
+
+        let yaml = r#"
+address: "0.0.0.0:8080"
+protocol:
+  type: http
+  username: user
+  password: pass
+  decoy: localhost:80
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        assert!(matches!(
+            config.protocol,
+            ServerProxyConfig::Http {
+                decoy: Some(DecoyConfig::Address(_)),
+                ..
+            }
+        ));
+
+        let yaml = r#"
+address: "0.0.0.0:8080"
+protocol:
+  type: http
+  decoy: www
+"#;
+        assert!(serde_yaml::from_str::<ServerConfig>(yaml).is_err());
+
+        let yaml = r#"
+address: "0.0.0.0:7890"
+protocol:
+  type: mixed
+  decoy: /var/www/html
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        assert!(matches!(
+            config.protocol,
+            ServerProxyConfig::Mixed {
+                decoy: Some(DecoyConfig::Directory(_)),
+                udp_enabled: true,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_server_config_httpupgrade() {
+        let yaml = r#"
+address: "0.0.0.0:80"
+protocol:
+  type: httpupgrade
+  path: /up
+  protocol:
+    type: vless
+    user_id: b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        match config.protocol {
+            ServerProxyConfig::HttpUpgrade { path, host, .. } => {
+                assert_eq!(path.as_deref(), Some("/up"));
+                assert!(host.is_none());
+            }
+            _ => panic!("Expected HttpUpgrade protocol"),
+        }
+    }
+
+    #[test]
+    fn test_server_config_dns() {
+        let yaml = r#"
+address: "0.0.0.0:53"
+transport: udp
+protocol:
+  type: dns
+  fake_ip: true
+  upstreams:
+    - domains: [corp.example.com, internal]
+      servers: udp://10.0.0.53
+dns:
+  servers: udp://1.1.1.1
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.transport, Transport::Udp);
+        match config.protocol {
+            ServerProxyConfig::Dns { upstreams, fake_ip } => {
+                assert!(fake_ip);
+                let upstreams = upstreams.into_vec();
+                assert_eq!(upstreams.len(), 1);
+                assert_eq!(upstreams[0].domains.len(), 2);
+                assert_eq!(upstreams[0].servers.len(), 1);
+            }
+            _ => panic!("Expected Dns protocol"),
+        }
+    }
+
+    #[test]
+    fn test_server_config_transparent() {
+        let yaml = r#"
+address: "0.0.0.0:12345"
+transport: udp
+protocol:
+  type: tproxy
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.transport, Transport::Udp);
+        assert!(matches!(
+            config.protocol,
+            ServerProxyConfig::Tproxy {
+                dns_hijack: false,
+                sniff: false,
+                sniff_override_destination: false,
+            }
+        ));
+
+        let yaml = r#"
+address: "0.0.0.0:12345"
+protocol:
+  type: redirect
+  dns_hijack: true
+  sniff: true
+  sniff_override_destination: true
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        assert!(matches!(
+            config.protocol,
+            ServerProxyConfig::Redirect {
+                dns_hijack: true,
+                sniff: true,
+                sniff_override_destination: true,
+            }
+        ));
+    }
+
+    #[test]
+    fn test_server_config_kcp() {
+        use crate::config::{KcpCryptMethod, KcpMode};
+
+        let yaml = r#"
+address: "0.0.0.0:4000"
+transport: kcp
+kcp_settings:
+  key: secret
+  crypt: salsa20
+  mode: fast2
+  mtu: 1200
+  sndwnd: 2048
+protocol:
+  type: socks
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(config.transport, Transport::Kcp);
+        let kcp_settings = config.kcp_settings.unwrap();
+        assert_eq!(kcp_settings.key, "secret");
+        assert_eq!(kcp_settings.crypt, KcpCryptMethod::Salsa20);
+        assert_eq!(kcp_settings.mode, KcpMode::Fast2);
+        assert_eq!(kcp_settings.mtu, 1200);
+        assert_eq!(kcp_settings.sndwnd, Some(2048));
+        assert_eq!(kcp_settings.rcvwnd, None);
+        assert_eq!(kcp_settings.datashard, 10);
+        assert_eq!(kcp_settings.parityshard, 3);
+        assert_eq!(kcp_settings.nodelay_params(), (1, 20, 2, true));
+    }
+
     #[test]
     fn test_rejects_invalid_upstream_field() {
         let yaml = r#"
@@ -1315,6 +2438,75 @@ rules:
         );
     }
 
+    #[test]
+    fn test_server_config_address_list_and_interface() {
+        let yaml = r#"
+address:
+  - "127.0.0.1:8080"
+  - "[::1]:8080"
+interface: "eth0:8443,9000-9001"
+protocol:
+  type: http
+"#;
+
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        match &config.bind_location {
+            BindLocation::Addresses {
+                addresses,
+                interfaces,
+            } => {
+                assert_eq!(addresses.len(), 2);
+                assert_eq!(interfaces.len(), 1);
+                assert_eq!(interfaces[0].address().hostname(), Some("eth0"));
+            }
+            other => panic!("expected address list, got {other:?}"),
+        }
+
+        let serialized = serde_yaml::to_string(&config).unwrap();
+        let deserialized: ServerConfig = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.bind_location, config.bind_location);
+
+        let yaml = r#"
+interface: "10.0.0.1:8080"
+protocol:
+  type: http
+"#;
+        assert!(serde_yaml::from_str::<ServerConfig>(yaml).is_err());
+
+        let yaml = r#"
+address: "127.0.0.1:8080"
+path: "/tmp/shoes.sock"
+protocol:
+  type: http
+"#;
+        assert!(serde_yaml::from_str::<ServerConfig>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_server_config_systemd() {
+        let yaml = r#"
+systemd: https
+protocol:
+  type: http
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(
+            config.bind_location,
+            BindLocation::Systemd("https".to_string())
+        );
+        let serialized = serde_yaml::to_string(&config).unwrap();
+        let deserialized: ServerConfig = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.bind_location, config.bind_location);
+
+        let yaml = r#"
+systemd: https
+address: "127.0.0.1:8080"
+protocol:
+  type: http
+"#;
+        assert!(serde_yaml::from_str::<ServerConfig>(yaml).is_err());
+    }
+
     #[test]
     fn test_rejects_unknown_field_in_vmess_server() {
         let yaml = r#"
@@ -1360,6 +2552,149 @@ protocol:
         }
     }
 
+    #[test]
+    fn test_shadowsocks_plugin() {
+        let yaml = r#"
+address: "0.0.0.0:8388"
+protocol:
+  type: shadowsocks
+  cipher: aes-256-gcm
+  password: "secret"
+  plugin: v2ray-plugin
+  plugin_opts: "server;mode=websocket"
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let expected = ShadowsocksPluginConfig {
+            plugin: "v2ray-plugin".to_string(),
+            plugin_opts: Some("server;mode=websocket".to_string()),
+        };
+        let ServerProxyConfig::Shadowsocks { ref plugin, .. } = config.protocol else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        assert_eq!(plugin.as_ref(), Some(&expected));
+
+        let yaml_str = serde_yaml::to_string(&config).expect("Failed to serialize");
+        let deserialized: ServerConfig =
+            serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
+        let ServerProxyConfig::Shadowsocks { plugin, .. } = deserialized.protocol else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        assert_eq!(plugin, Some(expected));
+
+        let yaml = r#"
+address: "0.0.0.0:8388"
+protocol:
+  type: shadowsocks
+  cipher: aes-256-gcm
+  password: "secret"
+  plugin_opts: "server"
+"#;
+        assert!(serde_yaml::from_str::<ServerConfig>(yaml).is_err());
+    }
+
+    #[test]
+    fn test_shadowsocks_users() {
+        let yaml = r#"
+address: "0.0.0.0:8388"
+protocol:
+  type: shadowsocks
+  cipher: 2022-blake3-aes-128-gcm
+  password: "AAECAwQFBgcICQoLDA0ODw=="
+  users:
+    - name: alice
+      password: "EBESExQVFhcYGRobHB0eHw=="
+    - password: "ICEiIyQlJicoKSorLC0uLw=="
+"#;
+        let server_config: ServerConfig =
+            serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let ServerProxyConfig::Shadowsocks {
+            ref config,
+            ref users,
+            ..
+        } = server_config.protocol
+        else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        let user_keys = config.server_user_keys(users).unwrap();
+        assert_eq!(user_keys.len(), 2);
+        assert_eq!(user_keys[0].0, "alice");
+        assert_eq!(&user_keys[0].1[..], &(16u8..32).collect::<Vec<_>>()[..]);
+        assert_eq!(user_keys[1].0, "");
+
+        let yaml_str = serde_yaml::to_string(&server_config).expect("Failed to serialize");
+        let deserialized: ServerConfig =
+            serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
+        let ServerProxyConfig::Shadowsocks { users, .. } = deserialized.protocol else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        assert_eq!(users.len(), 2);
+        assert_eq!(users[0].name, "alice");
+
+        // Identity headers are encrypted with AES.
+        let yaml = r#"
+address: "0.0.0.0:8388"
+protocol:
+  type: shadowsocks
+  cipher: 2022-blake3-chacha20-poly1305
+  password: "AAECAwQFBgcICQoLDA0ODwABAgMEBQYHCAkKCwwNDg8="
+  users:
+    - password: "EBESExQVFhcYGRobHB0eHxAREhMUFRYXGBkaGxwdHh8="
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let ServerProxyConfig::Shadowsocks { config, users, .. } = config.protocol else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        assert!(config.server_user_keys(&users).is_err());
+    }
+
+    #[test]
+    fn test_user_group() {
+        let yaml = r#"
+address: "0.0.0.0:443"
+protocol:
+  type: vless
+  user_group: staff
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let ServerProxyConfig::Vless {
+            ref user_id,
+            ref user_group,
+            ..
+        } = config.protocol
+        else {
+            panic!("Expected Vless protocol");
+        };
+        assert!(user_id.is_empty());
+        assert_eq!(user_group.as_deref(), Some("staff"));
+
+        // The empty inline credential is not serialized.
+        let yaml_str = serde_yaml::to_string(&config).expect("Failed to serialize");
+        assert!(!yaml_str.contains("user_id"));
+        let deserialized: ServerConfig =
+            serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
+        let ServerProxyConfig::Vless { user_group, .. } = deserialized.protocol else {
+            panic!("Expected Vless protocol");
+        };
+        assert_eq!(user_group.as_deref(), Some("staff"));
+
+        let yaml = r#"
+address: "0.0.0.0:8388"
+protocol:
+  type: shadowsocks
+  cipher: 2022-blake3-aes-128-gcm
+  password: "AAECAwQFBgcICQoLDA0ODw=="
+  user_group: staff
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).expect("Failed to deserialize");
+        let yaml_str = serde_yaml::to_string(&config).expect("Failed to serialize");
+        let deserialized: ServerConfig =
+            serde_yaml::from_str(&yaml_str).expect("Failed to deserialize");
+        let ServerProxyConfig::Shadowsocks { user_group, .. } = deserialized.protocol else {
+            panic!("Expected Shadowsocks protocol");
+        };
+        assert_eq!(user_group.as_deref(), Some("staff"));
+    }
+
     #[test]
     fn test_shadowtls_handshake_serialization() {
         // Test local handshake with minimal fields
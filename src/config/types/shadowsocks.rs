@@ -1,7 +1,7 @@
 //! Shadowsocks configuration types.
 
 use base64::engine::{Engine as _, general_purpose::STANDARD as BASE64};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::shadowsocks::ShadowsocksCipher;
 
@@ -14,9 +14,60 @@ pub enum ShadowsocksConfig {
     Aead2022 {
         cipher: ShadowsocksCipher,
         key_bytes: Box<[u8]>,
+        /// Identity PSKs of a multi-user server and the relays in front of it, from a
+        /// client password of the form `iPSK:uPSK`. Empty for single-user passwords.
+        identity_keys: Vec<Box<[u8]>>,
     },
 }
 
+/// User of a multi-user shadowsocks 2022 server.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShadowsocksUserConfig {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub name: String,
+    /// Base64-encoded user PSK
+    pub password: String,
+}
+
+fn decode_aead2022_key(password: &str) -> std::io::Result<Box<[u8]>> {
+    let key_bytes = BASE64.decode(password).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Failed to base64 decode password for 2022-blake3 cipher: {}",
+                e
+            ),
+        )
+    })?;
+    Ok(key_bytes.into_boxed_slice())
+}
+
+/// Decode the PSK of a user of a multi-user server with `cipher`.
+pub fn decode_aead2022_user_key(
+    cipher: &ShadowsocksCipher,
+    password: &str,
+) -> std::io::Result<Box<[u8]>> {
+    let user_key = decode_aead2022_key(password)?;
+    check_aead2022_key_len(cipher, &user_key)?;
+    Ok(user_key)
+}
+
+fn check_aead2022_key_len(cipher: &ShadowsocksCipher, key_bytes: &[u8]) -> std::io::Result<()> {
+    if key_bytes.len() != cipher.key_len() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "2022-blake3-{} keys must be {} bytes, got {}",
+                cipher.name(),
+                cipher.key_len(),
+                key_bytes.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
 impl ShadowsocksConfig {
     /// Create a ShadowsocksConfig from cipher and password strings.
     /// Handles both legacy ciphers and 2022-blake3-* ciphers.
@@ -24,18 +75,31 @@ impl ShadowsocksConfig {
         match cipher.strip_prefix("2022-blake3-") {
             Some(stripped) => {
                 let cipher: ShadowsocksCipher = stripped.try_into()?;
-                let key_bytes = BASE64.decode(password).map_err(|e| {
-                    std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!(
-                            "Failed to base64 decode password for 2022-blake3 cipher: {}",
-                            e
-                        ),
-                    )
-                })?;
+                // Passwords for multi-user servers are `iPSK:uPSK`, with an iPSK for each
+                // relay in front of the server.
+                let mut identity_keys = password
+                    .split(':')
+                    .map(decode_aead2022_key)
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                let key_bytes = identity_keys.pop().unwrap();
+                if !identity_keys.is_empty() {
+                    if !cipher.supports_identity_headers() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidInput,
+                            format!(
+                                "2022-blake3-{} does not support multi-user passwords",
+                                cipher.name()
+                            ),
+                        ));
+                    }
+                    for key in identity_keys.iter().chain(std::iter::once(&key_bytes)) {
+                        check_aead2022_key_len(&cipher, key)?;
+                    }
+                }
                 Ok(ShadowsocksConfig::Aead2022 {
                     cipher,
-                    key_bytes: key_bytes.into_boxed_slice(),
+                    key_bytes,
+                    identity_keys,
                 })
             }
             None => {
@@ -59,14 +123,120 @@ impl ShadowsocksConfig {
                 state.serialize_field("cipher", cipher.name())?;
                 state.serialize_field("password", password)?;
             }
-            ShadowsocksConfig::Aead2022 { cipher, key_bytes } => {
+            ShadowsocksConfig::Aead2022 {
+                cipher,
+                key_bytes,
+                identity_keys,
+            } => {
                 let cipher_name = format!("2022-blake3-{}", cipher.name());
                 state.serialize_field("cipher", &cipher_name)?;
-                state.serialize_field("password", &BASE64.encode(key_bytes))?;
+                let password = identity_keys
+                    .iter()
+                    .chain(std::iter::once(key_bytes))
+                    .map(|key| BASE64.encode(key))
+                    .collect::<Vec<_>>()
+                    .join(":");
+                state.serialize_field("password", &password)?;
             }
         }
         Ok(())
     }
+
+    /// Decode the (name, PSK) pairs of a multi-user server with this config.
+    pub fn server_user_keys(
+        &self,
+        users: &[ShadowsocksUserConfig],
+    ) -> std::io::Result<Vec<(String, Box<[u8]>)>> {
+        match self {
+            ShadowsocksConfig::Aead2022 { identity_keys, .. } if !identity_keys.is_empty() => {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Shadowsocks server password must be a single key, \
+                     list multi-user keys under users",
+                ))
+            }
+            _ if users.is_empty() => Ok(vec![]),
+            ShadowsocksConfig::Aead2022 {
+                cipher, key_bytes, ..
+            } => {
+                if !cipher.supports_identity_headers() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!(
+                            "2022-blake3-{} does not support multi-user servers",
+                            cipher.name()
+                        ),
+                    ));
+                }
+                check_aead2022_key_len(cipher, key_bytes)?;
+                users
+                    .iter()
+                    .map(|user| {
+                        let user_key = decode_aead2022_user_key(cipher, &user.password)?;
+                        Ok((user.name.clone(), user_key))
+                    })
+                    .collect()
+            }
+            ShadowsocksConfig::Legacy { .. } => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Shadowsocks users require a 2022-blake3-aes cipher",
+            )),
+        }
+    }
+}
+
+/// SIP003 plugin run alongside a shadowsocks server or client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShadowsocksPluginConfig {
+    /// Plugin executable, e.g. "v2ray-plugin"
+    pub plugin: String,
+    /// Passed to the plugin in SS_PLUGIN_OPTIONS
+    pub plugin_opts: Option<String>,
+}
+
+impl ShadowsocksPluginConfig {
+    /// Create a plugin config from the flattened `plugin` and `plugin_opts` fields.
+    pub fn from_fields(
+        plugin: Option<String>,
+        plugin_opts: Option<String>,
+    ) -> std::io::Result<Option<Self>> {
+        match (plugin, plugin_opts) {
+            (Some(plugin), plugin_opts) => {
+                if plugin.is_empty() {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "plugin cannot be empty",
+                    ));
+                }
+                Ok(Some(Self {
+                    plugin,
+                    plugin_opts,
+                }))
+            }
+            (None, Some(_)) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "plugin_opts requires plugin",
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Number of fields written by `serialize_fields`.
+    pub fn field_count(&self) -> usize {
+        if self.plugin_opts.is_some() { 2 } else { 1 }
+    }
+
+    /// Serialize plugin and plugin_opts fields to a SerializeStruct.
+    pub fn serialize_fields<S: serde::ser::SerializeStruct>(
+        &self,
+        state: &mut S,
+    ) -> Result<(), S::Error> {
+        state.serialize_field("plugin", &self.plugin)?;
+        if let Some(ref plugin_opts) = self.plugin_opts {
+            state.serialize_field("plugin_opts", plugin_opts)?;
+        }
+        Ok(())
+    }
 }
 
 impl<'de> serde::de::Deserialize<'de> for ShadowsocksConfig {
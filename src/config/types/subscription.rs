@@ -0,0 +1,62 @@
+//! Subscription configuration.
+
+use serde::{Deserialize, Serialize};
+
+use super::rules::ClientChain;
+use super::selection::ConfigSelection;
+
+/// Proxies fetched from a subscription URL, which become a client group named after the
+/// subscription.
+///
+/// ```yaml
+/// - subscription: provider
+///   url: https://example.com/sub?token=secret
+///   interval_secs: 3600
+///   user_agent: clash.meta
+///   client_chain: my-proxy
+/// ```
+///
+/// The subscription is fetched when the configs are loaded, and again every
+/// `interval_secs`. Rules refer to the group like to other client groups, and its proxies
+/// are used as a pool.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriptionConfig {
+    /// Name of the client group with the proxies of the subscription.
+    pub subscription: String,
+    /// http:// or https:// URL of the subscription.
+    pub url: String,
+    #[serde(default)]
+    pub format: SubscriptionFormat,
+    /// Seconds between fetches of the subscription.
+    #[serde(default = "default_interval_secs")]
+    pub interval_secs: u64,
+    /// User-Agent header of requests, which providers often pick the format by.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Client chain or client group that the subscription is fetched through. The
+    /// subscription is fetched directly by default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_chain: Option<ConfigSelection<ClientChain>>,
+}
+
+/// Format of a subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionFormat {
+    /// Detected from the content.
+    #[default]
+    Auto,
+    /// Share links like `vless://...`, one per line, optionally base64 encoded.
+    #[serde(alias = "base64")]
+    UriList,
+    /// Clash config with a `proxies` list.
+    Clash,
+    /// sing-box config with an `outbounds` list.
+    #[serde(alias = "sing-box")]
+    Singbox,
+}
+
+fn default_interval_secs() -> u64 {
+    86400
+}
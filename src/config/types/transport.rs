@@ -1,27 +1,123 @@
 //! Transport-related configuration types.
 
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use serde::ser::SerializeMap;
 use serde::{Deserialize, Serialize};
 
 use crate::address::{NetLocation, NetLocationPortRange};
 use crate::option_util::{NoneOrOne, NoneOrSome};
+use crate::socket_util::interface_addresses;
 
-use super::common::default_true;
+use super::common::{default_true, is_false};
 
-#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
+#[derive(Debug, Clone, PartialEq)]
 pub enum BindLocation {
     Address(NetLocationPortRange),
+    /// A list of addresses, and the addresses of network interfaces. The address of each
+    /// entry of `interfaces` is the name of an interface, e.g. `eth0:443`.
+    Addresses {
+        addresses: Vec<NetLocationPortRange>,
+        interfaces: Vec<NetLocationPortRange>,
+    },
     Path(PathBuf),
+    /// The sockets passed by systemd socket activation with the name.
+    Systemd(String),
+}
+
+impl BindLocation {
+    /// Returns the socket addresses to listen on, with a listener per address. The
+    /// addresses of interfaces are looked up on each call, so that servers use the
+    /// current addresses when they are started or reloaded.
+    pub fn to_socket_addrs(&self) -> std::io::Result<Vec<SocketAddr>> {
+        match self {
+            BindLocation::Address(a) => a.to_socket_addrs(),
+            BindLocation::Addresses {
+                addresses,
+                interfaces,
+            } => {
+                let mut socket_addrs = vec![];
+                for a in addresses {
+                    socket_addrs.extend(a.to_socket_addrs()?);
+                }
+                for interface in interfaces {
+                    let name = interface.address().to_string();
+                    let interface_addrs = interface_addresses(&name)?;
+                    if interface_addrs.is_empty() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::NotFound,
+                            format!("interface {name} has no IP addresses"),
+                        ));
+                    }
+                    for addr in interface_addrs {
+                        for &port in interface.ports() {
+                            let mut addr = addr;
+                            addr.set_port(port);
+                            socket_addrs.push(addr);
+                        }
+                    }
+                }
+                Ok(socket_addrs)
+            }
+            BindLocation::Path(p) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("{} is a Unix socket path", p.display()),
+            )),
+            BindLocation::Systemd(name) => crate::systemd::socket_addrs(name),
+        }
+    }
+
+    /// Returns true if the addresses of network interfaces are looked up when listening.
+    pub fn has_interfaces(&self) -> bool {
+        matches!(self, BindLocation::Addresses { interfaces, .. } if !interfaces.is_empty())
+    }
 }
 
 impl std::fmt::Display for BindLocation {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             BindLocation::Address(n) => write!(f, "{n}"),
+            BindLocation::Addresses {
+                addresses,
+                interfaces,
+            } => {
+                let entries = addresses
+                    .iter()
+                    .map(ToString::to_string)
+                    .chain(interfaces.iter().map(|i| format!("interface {i}")));
+                write!(f, "{}", entries.collect::<Vec<_>>().join(", "))
+            }
             BindLocation::Path(p) => write!(f, "{}", p.display()),
+            BindLocation::Systemd(name) => write!(f, "systemd:{name}"),
+        }
+    }
+}
+
+/// Serializes to the `address`, `interface`, `path` or `systemd` field of a server config.
+impl Serialize for BindLocation {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut map = serializer.serialize_map(None)?;
+        match self {
+            BindLocation::Address(a) => map.serialize_entry("address", a)?,
+            BindLocation::Addresses {
+                addresses,
+                interfaces,
+            } => {
+                if !addresses.is_empty() {
+                    map.serialize_entry("address", addresses)?;
+                }
+                if !interfaces.is_empty() {
+                    map.serialize_entry("interface", interfaces)?;
+                }
+            }
+            BindLocation::Path(p) => map.serialize_entry("path", p)?,
+            BindLocation::Systemd(name) => map.serialize_entry("systemd", name)?,
         }
+        map.end()
     }
 }
 
@@ -32,6 +128,7 @@ pub enum Transport {
     Tcp,
     Quic,
     Udp,
+    Kcp,
 }
 
 impl Transport {
@@ -41,18 +138,213 @@ impl Transport {
     }
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Transport::Tcp => "tcp",
+            Transport::Quic => "quic",
+            Transport::Udp => "udp",
+            Transport::Kcp => "kcp",
+        })
+    }
+}
+
+/// Socket options of TCP connections.
+///
+/// ```yaml
+/// tcp_settings:
+///   no_delay: true
+///   fast_open: true
+///   mptcp: true
+///   keepalive_idle_secs: 60
+///   keepalive_interval_secs: 10
+///   keepalive_count: 3
+///   user_timeout_secs: 30
+///   per_core_listeners: true
+///   accept_proxy_protocol: true
+///   send_proxy_protocol: v2
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct TcpConfig {
     #[serde(default = "default_true")]
     pub no_delay: bool,
+    /// Sends data in the SYN of connections to servers that were connected before, and
+    /// accepts it from clients. Linux only.
+    #[serde(default)]
+    pub fast_open: bool,
+    /// Uses Multipath TCP, which can spread a connection over several network
+    /// interfaces. Falls back to TCP when the kernel doesn't support it. Linux only.
+    #[serde(default)]
+    pub mptcp: bool,
+    /// Idle time before keepalive probes are sent, 0 to disable keepalive. Defaults to
+    /// 300 seconds for servers and 120 seconds for clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_idle_secs: Option<u64>,
+    /// Time between keepalive probes. Defaults to 60 seconds for servers and 30 seconds
+    /// for clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval_secs: Option<u64>,
+    /// Number of unanswered keepalive probes before the connection is closed. Defaults
+    /// to the system setting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub keepalive_count: Option<u32>,
+    /// Time that sent data may remain unacknowledged before the connection is closed
+    /// (`TCP_USER_TIMEOUT`). Linux only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_timeout_secs: Option<u64>,
+    /// Listens with a `SO_REUSEPORT` socket per worker thread, each with its own accept
+    /// loop, so that the kernel spreads new connections over them. Servers only, Unix
+    /// only.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub per_core_listeners: bool,
+    /// Expects connections to start with a PROXY protocol header (v1 or v2), as sent by
+    /// HAProxy or nginx, and uses the client address in it as the source of the
+    /// connection. Servers only.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub accept_proxy_protocol: bool,
+    /// Starts connections with a PROXY protocol header describing the client connection
+    /// they were made for. Clients only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_proxy_protocol: Option<ProxyProtocolVersion>,
 }
 
 impl Default for TcpConfig {
     fn default() -> Self {
-        TcpConfig { no_delay: true }
+        TcpConfig {
+            no_delay: true,
+            fast_open: false,
+            mptcp: false,
+            keepalive_idle_secs: None,
+            keepalive_interval_secs: None,
+            keepalive_count: None,
+            user_timeout_secs: None,
+            per_core_listeners: false,
+            accept_proxy_protocol: false,
+            send_proxy_protocol: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocolVersion {
+    V1,
+    V2,
+}
+
+/// Connection attempts to destinations with IPv6 and IPv4 addresses (RFC 8305).
+///
+/// ```yaml
+/// happy_eyeballs:
+///   prefer: ipv4
+///   delay_ms: 300
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct HappyEyeballsConfig {
+    /// When false, only the first resolved address is tried.
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// Address family that is tried first.
+    #[serde(default)]
+    pub prefer: IpFamily,
+    /// Time to wait for a connection attempt before starting the next one.
+    #[serde(default = "default_happy_eyeballs_delay_ms")]
+    pub delay_ms: u64,
+}
+
+fn default_happy_eyeballs_delay_ms() -> u64 {
+    250
+}
+
+impl Default for HappyEyeballsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            prefer: IpFamily::default(),
+            delay_ms: default_happy_eyeballs_delay_ms(),
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IpFamily {
+    #[default]
+    Ipv6,
+    Ipv4,
+}
+
+/// How hostnames are resolved before connecting to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainStrategy {
+    PreferIpv4,
+    PreferIpv6,
+    Ipv4Only,
+    Ipv6Only,
+    /// Leaves hostnames to proxy servers to resolve. Direct connections resolve them
+    /// as if no strategy was set.
+    UseRemote,
+}
+
+impl DomainStrategy {
+    /// Returns the address family that is tried first, if the strategy prefers one.
+    pub fn preferred_family(self) -> Option<IpFamily> {
+        match self {
+            DomainStrategy::PreferIpv4 | DomainStrategy::Ipv4Only => Some(IpFamily::Ipv4),
+            DomainStrategy::PreferIpv6 | DomainStrategy::Ipv6Only => Some(IpFamily::Ipv6),
+            DomainStrategy::UseRemote => None,
+        }
+    }
+}
+
+/// Maximum number of connections that a server handles at once.
+///
+/// ```yaml
+/// connection_limit:
+///   max_connections: 1000
+///   mode: reject
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConnectionLimitConfig {
+    pub max_connections: usize,
+    #[serde(default)]
+    pub mode: ConnectionLimitMode,
+}
+
+/// Timeouts that close forwarded TCP sessions of a server.
+///
+/// ```yaml
+/// session_timeouts:
+///   idle_secs: 300
+///   half_close_secs: 0
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionTimeoutConfig {
+    /// Closes a session when no data was sent in either direction for this long.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idle_secs: Option<u64>,
+    /// Closes a session this long after one side finished sending. 0 closes it as soon
+    /// as one side finishes, and by default the other side may continue indefinitely.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub half_close_secs: Option<u64>,
+}
+
+/// What a server does with new connections when it is at its connection limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionLimitMode {
+    /// Stop accepting until a connection closes, leaving new connections waiting in the
+    /// listen backlog.
+    #[default]
+    Backpressure,
+    /// Accept and immediately close new connections.
+    Reject,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerQuicConfig {
     pub cert: String,
@@ -97,6 +389,155 @@ impl Default for ClientQuicConfig {
     }
 }
 
+/// Settings of the KCP transport, named like the options of kcptun.
+///
+/// ```yaml
+/// kcp_settings:
+///   key: "it's a secrect"
+///   crypt: aes
+///   mode: fast
+///   mtu: 1350
+///   sndwnd: 128
+///   rcvwnd: 512
+///   datashard: 10
+///   parityshard: 3
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct KcpConfig {
+    /// Pre-shared key of the packet encryption.
+    #[serde(default = "default_kcp_key")]
+    pub key: String,
+    #[serde(default)]
+    pub crypt: KcpCryptMethod,
+    #[serde(default)]
+    pub mode: KcpMode,
+    /// Maximum size of UDP packets, including the crypt and FEC headers.
+    #[serde(default = "default_kcp_mtu")]
+    pub mtu: usize,
+    /// Send window in packets. Defaults to 1024 for servers and 128 for clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sndwnd: Option<u32>,
+    /// Receive window in packets. Defaults to 1024 for servers and 512 for clients.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rcvwnd: Option<u32>,
+    /// Number of data packets in a FEC group, 0 disables FEC.
+    #[serde(default = "default_kcp_datashard")]
+    pub datashard: usize,
+    /// Number of parity packets in a FEC group, 0 disables FEC.
+    #[serde(default = "default_kcp_parityshard")]
+    pub parityshard: usize,
+    /// Sends acknowledgements as soon as packets arrive, instead of with the next flush.
+    #[serde(default)]
+    pub acknodelay: bool,
+    /// smux version of clients, 1 or 2. Servers accept both.
+    #[serde(default = "default_kcp_smuxver")]
+    pub smuxver: u8,
+    /// Used by `mode: manual`: 1 or 2 lower the minimum RTO and slow down the
+    /// retransmission backoff.
+    #[serde(default)]
+    pub nodelay: u32,
+    /// Used by `mode: manual`: milliseconds between flushes.
+    #[serde(default = "default_kcp_interval")]
+    pub interval: u32,
+    /// Used by `mode: manual`: number of later acknowledgements that retransmit a
+    /// packet, 0 to only retransmit on timeouts.
+    #[serde(default)]
+    pub resend: u32,
+    /// Used by `mode: manual`: 1 disables congestion control.
+    #[serde(default)]
+    pub nc: u32,
+}
+
+fn default_kcp_key() -> String {
+    // Default key of kcptun.
+    "it's a secrect".to_string()
+}
+
+fn default_kcp_mtu() -> usize {
+    1350
+}
+
+fn default_kcp_datashard() -> usize {
+    10
+}
+
+fn default_kcp_parityshard() -> usize {
+    3
+}
+
+fn default_kcp_smuxver() -> u8 {
+    1
+}
+
+fn default_kcp_interval() -> u32 {
+    50
+}
+
+impl Default for KcpConfig {
+    fn default() -> Self {
+        Self {
+            key: default_kcp_key(),
+            crypt: KcpCryptMethod::default(),
+            mode: KcpMode::default(),
+            mtu: default_kcp_mtu(),
+            sndwnd: None,
+            rcvwnd: None,
+            datashard: default_kcp_datashard(),
+            parityshard: default_kcp_parityshard(),
+            acknodelay: false,
+            smuxver: default_kcp_smuxver(),
+            nodelay: 0,
+            interval: default_kcp_interval(),
+            resend: 0,
+            nc: 0,
+        }
+    }
+}
+
+impl KcpConfig {
+    /// Returns the nodelay, interval, resend and nc parameters of the mode.
+    pub fn nodelay_params(&self) -> (u32, u32, u32, bool) {
+        match self.mode {
+            KcpMode::Normal => (0, 40, 2, true),
+            KcpMode::Fast => (0, 30, 2, true),
+            KcpMode::Fast2 => (1, 20, 2, true),
+            KcpMode::Fast3 => (1, 10, 2, true),
+            KcpMode::Manual => (self.nodelay, self.interval, self.resend, self.nc != 0),
+        }
+    }
+}
+
+/// Packet encryption of the KCP transport. kcptun's other ciphers aren't supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KcpCryptMethod {
+    /// AES-256.
+    #[default]
+    Aes,
+    #[serde(rename = "aes-128")]
+    Aes128,
+    Salsa20,
+    Xor,
+    /// Checksums packets without encrypting them.
+    None,
+    /// Sends packets without crypt header.
+    Null,
+}
+
+/// Retransmission presets of kcptun, from `normal` to the most aggressive `fast3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KcpMode {
+    Normal,
+    #[default]
+    Fast,
+    Fast2,
+    Fast3,
+    /// Uses the `nodelay`, `interval`, `resend` and `nc` settings.
+    Manual,
+}
+
 impl From<NetLocation> for BindLocation {
     fn from(loc: NetLocation) -> Self {
         BindLocation::Address(loc.into())
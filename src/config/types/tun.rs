@@ -9,6 +9,9 @@
 //! - **Linux**: Creates a new TUN device with the specified name and address.
 //!   Requires root privileges or `CAP_NET_ADMIN` capability.
 //!
+//! - **macOS**: Creates a utun device with the specified address. Requires root
+//!   privileges. Routes through the device are not added automatically.
+//!
 //! - **Android**: Requires a file descriptor from `VpnService.Builder.establish()`.
 //!   The VPN configuration (routes, DNS, etc.) is handled by the Android VpnService.
 //!
@@ -21,7 +24,7 @@ use serde::{Deserialize, Serialize};
 
 use crate::option_util::NoneOrSome;
 
-use super::common::default_true;
+use super::common::{default_true, is_false};
 use super::dns::DnsConfig;
 use super::rules::RuleConfig;
 use super::selection::ConfigSelection;
@@ -40,6 +43,26 @@ fn default_mtu() -> u16 {
     return 1500;
 }
 
+/// How the TUN server answers ICMP echo requests.
+#[derive(Default, Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TunIcmpMode {
+    /// Every ping is answered by shoes right away, whether the destination is
+    /// reachable or not.
+    #[default]
+    Local,
+    /// Pings are sent to the destination through the outbound selected by the rules,
+    /// and answered when the destination replies. Outbounds that can't carry ICMP are
+    /// answered locally, and blocked destinations are not answered.
+    Relay,
+}
+
+impl TunIcmpMode {
+    pub fn is_default(&self) -> bool {
+        matches!(self, TunIcmpMode::Local)
+    }
+}
+
 /// TUN device server configuration.
 ///
 /// This is a top-level config type (not nested under ServerConfig) because TUN
@@ -50,7 +73,7 @@ fn default_mtu() -> u16 {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct TunConfig {
-    /// TUN device name (Linux only, e.g., "tun0").
+    /// TUN device name (Linux/macOS, e.g., "tun0", or "utun8" on macOS).
     /// Ignored on iOS/Android where the device is provided via device_fd.
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub device_name: Option<String>,
@@ -62,18 +85,18 @@ pub struct TunConfig {
     pub device_fd: Option<i32>,
 
     /// TUN device IP address (e.g., "10.0.0.1").
-    /// - **Linux**: Sets the device's IP address
+    /// - **Linux/macOS**: Sets the device's IP address
     /// - **iOS/Android**: Informational only (address is set by VPN service)
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub address: Option<IpAddr>,
 
     /// TUN device netmask (e.g., "255.255.255.0").
-    /// - **Linux**: Sets the device's netmask
+    /// - **Linux/macOS**: Sets the device's netmask
     /// - **iOS/Android**: Informational only
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub netmask: Option<IpAddr>,
 
-    /// TUN device destination/gateway (Linux only).
+    /// TUN device destination/gateway (Linux/macOS).
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub destination: Option<IpAddr>,
 
@@ -98,6 +121,29 @@ pub struct TunConfig {
     #[serde(default = "default_true")]
     pub icmp_enabled: bool,
 
+    /// How ICMP echo requests (pings) are answered.
+    /// Default: local
+    #[serde(default, skip_serializing_if = "TunIcmpMode::is_default")]
+    pub icmp_mode: TunIcmpMode,
+
+    /// Answer DNS queries sent to port 53 with the resolver of shoes, instead of
+    /// forwarding them. Address queries get fake IPs when fake-IP mode is enabled.
+    /// Default: false
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dns_hijack: bool,
+
+    /// Find the domain of TCP connections in the TLS server name or the HTTP Host
+    /// header, and route them by it.
+    /// Default: false
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub sniff: bool,
+
+    /// Connect to the sniffed domain instead of the original destination address.
+    /// Requires `sniff`.
+    /// Default: false
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub sniff_override_destination: bool,
+
     /// Routing rules for traffic coming through the TUN device.
     /// Default: Allow all traffic directly
     #[serde(
@@ -0,0 +1,22 @@
+//! UDP session table configuration.
+
+use serde::{Deserialize, Serialize};
+
+/// Limits of the UDP session tables that servers keep for each client connection.
+///
+/// ```yaml
+/// - udp_max_sessions: 256
+///   udp_idle_timeout_secs: 60
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct UdpSessionConfig {
+    /// Sessions per client connection. When a new session would exceed it, the least
+    /// recently used session is closed. Unlimited when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp_max_sessions: Option<usize>,
+    /// Seconds without traffic after which a session is closed. Each protocol keeps its
+    /// own timeout when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub udp_idle_timeout_secs: Option<u64>,
+}
@@ -0,0 +1,857 @@
+//! Conversion of Xray and V2Ray configs.
+//!
+//! Proxy outbounds become client groups named after their tags, routing rules become a
+//! rule group, and inbounds become servers that use the rule group. Balancers are
+//! resolved into the client chains of the rules that use them.
+
+use serde_yaml::Value;
+
+use super::convert_util::{
+    ConvertedConfigs, Outbound, Outbounds, RuleMatchers, ServerTlsLayer, TlsLayer, TransportLayer,
+    client_group, direct_action, get_bool, get_str, get_str_list, get_u64, mapping, opt_str,
+    parse_duration_secs, parse_entry, parse_json_config, sequence, server_address, wrap_protocol,
+    wrap_server_protocol,
+};
+use super::types::{Config, RuleConfig};
+
+/// Name of the rule group with the converted rules.
+const RULE_GROUP: &str = "xray-rules";
+
+/// Converts an Xray config into shoes configs.
+///
+/// Inbounds, outbounds and rules that can't be converted are skipped and listed in the
+/// warnings. Returns an error only if the config is not a JSON object.
+pub fn convert_xray_config(config_str: &str) -> std::io::Result<ConvertedConfigs> {
+    let xray = parse_json_config(config_str, "Xray")?;
+    let mut converter = XrayConverter {
+        xray: &xray,
+        tags: vec![],
+        outbounds: Outbounds::default(),
+        converted: ConvertedConfigs::default(),
+    };
+    converter.convert_outbounds();
+    converter.convert_balancers();
+    converter.convert_routing();
+    converter.convert_inbounds();
+    Ok(converter.converted)
+}
+
+struct XrayConverter<'a> {
+    xray: &'a Value,
+    /// Tags of the outbounds in config order, which balancers select from.
+    tags: Vec<String>,
+    outbounds: Outbounds,
+    converted: ConvertedConfigs,
+}
+
+impl XrayConverter<'_> {
+    fn warn(&mut self, warning: String) {
+        self.converted.warnings.push(warning);
+    }
+
+    fn convert_outbounds(&mut self) {
+        for outbound in sequence(self.xray, "outbounds") {
+            let Some(tag) = outbound_tag(outbound) else {
+                self.warn("Skipped outbound without a protocol".to_string());
+                continue;
+            };
+            let converted = match get_str(outbound, "protocol").as_deref() {
+                Some("freedom") => Ok(Outbound::Direct),
+                Some("blackhole") => Ok(Outbound::Block),
+                _ => convert_outbound(outbound).and_then(|client| {
+                    self.converted.configs.push(client_group(&tag, client)?);
+                    let detour = outbound
+                        .get("proxySettings")
+                        .and_then(|settings| get_str(settings, "tag"));
+                    Ok(Outbound::Proxy { detour })
+                }),
+            };
+            match converted {
+                Ok(converted) => {
+                    self.outbounds.insert(tag.clone(), converted);
+                    self.tags.push(tag);
+                }
+                Err(e) => self.warn(format!("Skipped outbound {tag}: {e}")),
+            }
+        }
+    }
+
+    fn convert_balancers(&mut self) {
+        let routing = self.xray.get("routing").cloned().unwrap_or_default();
+        let observatory = self
+            .xray
+            .get("observatory")
+            .or_else(|| {
+                self.xray
+                    .get("burstObservatory")
+                    .and_then(|burst| burst.get("pingConfig"))
+            })
+            .cloned()
+            .unwrap_or_default();
+
+        for balancer in sequence(&routing, "balancers") {
+            let Some(tag) = get_str(balancer, "tag") else {
+                self.warn("Skipped balancer without a tag".to_string());
+                continue;
+            };
+            let selectors = get_str_list(balancer, "selector");
+            let members = self
+                .tags
+                .iter()
+                .filter(|outbound| {
+                    selectors
+                        .iter()
+                        .any(|selector| outbound.starts_with(selector))
+                })
+                .cloned()
+                .collect();
+            let strategy = balancer
+                .get("strategy")
+                .and_then(|strategy| get_str(strategy, "type"));
+            let (balancer, health_check) = match strategy.as_deref() {
+                None | Some("random") => ("random", Value::Null),
+                Some("roundRobin") => ("round_robin", Value::Null),
+                Some("leastPing") | Some("leastLoad") => ("url_test", health_check(&observatory)),
+                Some(other) => {
+                    self.warn(format!(
+                        "Skipped balancer {tag}: unsupported strategy {other}"
+                    ));
+                    continue;
+                }
+            };
+            self.outbounds.insert(
+                tag,
+                Outbound::Group {
+                    members,
+                    balancer,
+                    health_check,
+                },
+            );
+        }
+    }
+
+    fn convert_routing(&mut self) {
+        let routing = self.xray.get("routing").cloned().unwrap_or_default();
+        let mut rules = vec![];
+        for (index, rule) in sequence(&routing, "rules").iter().enumerate() {
+            let converted = convert_rule_matchers(rule).and_then(|(matchers, skipped)| {
+                let tag = get_str(rule, "outboundTag")
+                    .or_else(|| get_str(rule, "balancerTag"))
+                    .ok_or("missing outboundTag")?;
+                let action = self.outbounds.action(&tag)?;
+                Ok((matchers.into_rules(&action), skipped))
+            });
+            match converted {
+                Ok((converted, skipped)) => {
+                    rules.extend(converted);
+                    if !skipped.is_empty() {
+                        self.warn(format!(
+                            "Converted routing rule {} without {}",
+                            index + 1,
+                            skipped.join(", ")
+                        ));
+                    }
+                }
+                Err(e) => self.warn(format!("Skipped routing rule {}: {e}", index + 1)),
+            }
+        }
+
+        // Connections that match no rule use the first outbound, or connect directly
+        // without outbounds.
+        match sequence(self.xray, "outbounds")
+            .first()
+            .and_then(outbound_tag)
+        {
+            Some(tag) => match self.outbounds.action(&tag) {
+                Ok(action) => rules.extend(RuleMatchers::default().into_rules(&action)),
+                Err(e) => self.warn(format!("Skipped default outbound {tag}: {e}")),
+            },
+            None => rules.extend(RuleMatchers::default().into_rules(&direct_action())),
+        }
+
+        let mut converted = vec![];
+        for rule in rules {
+            match parse_entry::<RuleConfig>(rule.clone()) {
+                Ok(_) => converted.push(rule),
+                Err(e) => self.warn(format!("Skipped converted rule: {e}")),
+            }
+        }
+        let group = mapping([
+            ("rule_group", Value::from(RULE_GROUP)),
+            ("rules", Value::Sequence(converted)),
+        ]);
+        match parse_entry::<Config>(group) {
+            Ok(config) => self.converted.configs.push(config),
+            Err(e) => self.warn(format!("Skipped rules: {e}")),
+        }
+    }
+
+    fn convert_inbounds(&mut self) {
+        for inbound in sequence(self.xray, "inbounds") {
+            let name = get_str(inbound, "tag")
+                .or_else(|| get_str(inbound, "protocol"))
+                .unwrap_or_default();
+            let entries = match self.convert_inbound(inbound, &name) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    self.warn(format!("Skipped inbound {name}: {e}"));
+                    continue;
+                }
+            };
+            for entry in entries {
+                match parse_entry::<Config>(entry) {
+                    Ok(config) => self.converted.configs.push(config),
+                    Err(e) => self.warn(format!("Skipped inbound {name}: {e}")),
+                }
+            }
+        }
+    }
+
+    /// Converts an inbound into server configs.
+    fn convert_inbound(&mut self, inbound: &Value, name: &str) -> Result<Vec<Value>, String> {
+        let protocol = get_str(inbound, "protocol").ok_or("missing protocol")?;
+        let listen = get_str(inbound, "listen").unwrap_or_else(|| "0.0.0.0".to_string());
+        let port = get_str(inbound, "port").ok_or("missing port")?;
+        let port: u64 = port
+            .parse()
+            .map_err(|_| format!("unsupported port {port}"))?;
+        let address = Value::from(server_address(&listen, port));
+        let settings = inbound.get("settings").cloned().unwrap_or_default();
+        let stream = inbound.get("streamSettings").cloned().unwrap_or_default();
+
+        let clients = sequence(&settings, "clients");
+        let accounts = sequence(&settings, "accounts");
+        if (clients.len() > 1 && protocol != "shadowsocks") || accounts.len() > 1 {
+            self.warn(format!("Only the first user of inbound {name} is used"));
+        }
+        if settings.get("fallbacks").is_some() {
+            self.warn(format!("Skipped fallbacks of inbound {name}"));
+        }
+        let client = clients.first().cloned().unwrap_or_default();
+        let client_field = |key: &str| opt_str(get_str(&client, key));
+        let account = accounts.first().cloned().unwrap_or_default();
+
+        let server = |transport: Option<&str>, protocol: Value| {
+            mapping([
+                ("address", address.clone()),
+                ("transport", transport.map_or(Value::Null, Value::from)),
+                ("protocol", protocol),
+                ("rules", Value::from(RULE_GROUP)),
+            ])
+        };
+        let protocol = match protocol.as_str() {
+            "socks" | "http" | "mixed" => mapping([
+                ("type", Value::from(protocol.as_str())),
+                ("username", opt_str(get_str(&account, "user"))),
+                ("password", opt_str(get_str(&account, "pass"))),
+            ]),
+            "shadowsocks" => {
+                // Without a server password, each client has its own cipher, and only the
+                // first one is used.
+                let (method, password, users) = match get_str(&settings, "password") {
+                    Some(password) => (
+                        get_str(&settings, "method"),
+                        password,
+                        clients
+                            .iter()
+                            .map(|client| {
+                                mapping([
+                                    ("name", opt_str(get_str(client, "email"))),
+                                    ("password", opt_str(get_str(client, "password"))),
+                                ])
+                            })
+                            .collect(),
+                    ),
+                    None => (
+                        get_str(&client, "method"),
+                        get_str(&client, "password").ok_or("missing password")?,
+                        vec![],
+                    ),
+                };
+                mapping([
+                    ("type", Value::from("shadowsocks")),
+                    ("cipher", opt_str(method)),
+                    ("password", Value::from(password)),
+                    (
+                        "users",
+                        if users.is_empty() {
+                            Value::Null
+                        } else {
+                            Value::Sequence(users)
+                        },
+                    ),
+                ])
+            }
+            "vmess" => {
+                if get_u64(&client, "alterId").is_some_and(|alter_id| alter_id != 0) {
+                    return Err("legacy VMess with alterId is not supported".to_string());
+                }
+                mapping([
+                    ("type", Value::from("vmess")),
+                    ("cipher", Value::from("auto")),
+                    ("user_id", client_field("id")),
+                ])
+            }
+            "vless" => mapping([
+                ("type", Value::from("vless")),
+                ("user_id", client_field("id")),
+                (
+                    "flow",
+                    opt_str(get_str(&client, "flow").filter(|flow| !flow.is_empty())),
+                ),
+            ]),
+            "trojan" => mapping([
+                ("type", Value::from("trojan")),
+                ("password", client_field("password")),
+            ]),
+            "dokodemo-door" | "tunnel" => {
+                let network = get_str(&settings, "network").unwrap_or_else(|| "tcp".to_string());
+                if !get_bool(&settings, "followRedirect").unwrap_or(false) {
+                    let target_address = get_str(&settings, "address").ok_or("missing address")?;
+                    let target_port = get_u64(&settings, "port").ok_or("missing port")?;
+                    return Ok(vec![server(
+                        None,
+                        mapping([
+                            ("type", Value::from("forward")),
+                            (
+                                "targets",
+                                Value::from(server_address(&target_address, target_port)),
+                            ),
+                            ("udp_enabled", Value::Bool(network.contains("udp"))),
+                        ]),
+                    )]);
+                }
+                let tproxy = stream
+                    .get("sockopt")
+                    .and_then(|sockopt| get_str(sockopt, "tproxy"));
+                if tproxy.as_deref() != Some("tproxy") {
+                    return Ok(vec![server(
+                        None,
+                        mapping([("type", Value::from("redirect"))]),
+                    )]);
+                }
+                let protocol = mapping([("type", Value::from("tproxy"))]);
+                let mut servers = vec![];
+                if network.contains("tcp") {
+                    servers.push(server(None, protocol.clone()));
+                }
+                if network.contains("udp") {
+                    servers.push(server(Some("udp"), protocol));
+                }
+                return Ok(servers);
+            }
+            other => return Err(format!("unsupported inbound protocol {other}")),
+        };
+
+        let transport = convert_transport(&stream)?;
+        let tls = server_tls(&stream)?;
+        Ok(vec![server(
+            None,
+            wrap_server_protocol(protocol, transport, tls),
+        )])
+    }
+}
+
+/// Returns the tag of an outbound, or its protocol for the untagged default outbound.
+fn outbound_tag(outbound: &Value) -> Option<String> {
+    get_str(outbound, "tag").or_else(|| get_str(outbound, "protocol"))
+}
+
+fn health_check(observatory: &Value) -> Value {
+    let entries = [
+        ("url", opt_str(get_str(observatory, "probeURL"))),
+        (
+            "interval_secs",
+            get_str(observatory, "probeInterval")
+                .or_else(|| get_str(observatory, "interval"))
+                .and_then(|interval| parse_duration_secs(&interval))
+                .map_or(Value::Null, Value::from),
+        ),
+    ];
+    if entries.iter().all(|(_, value)| value.is_null()) {
+        return Value::Null;
+    }
+    mapping(entries)
+}
+
+/// Converts the matchers of a routing rule, and returns the values that were skipped.
+fn convert_rule_matchers(rule: &Value) -> Result<(RuleMatchers, Vec<String>), String> {
+    let fields = rule.as_mapping().ok_or("expected an object")?;
+    let mut matchers = RuleMatchers::default();
+    let mut skipped = vec![];
+    let mut has_destinations = false;
+
+    for key in fields.keys() {
+        let key = key.as_str().unwrap_or_default();
+        let values = get_str_list(rule, key);
+        match key {
+            "type" | "outboundTag" | "balancerTag" | "ruleTag" => {}
+            "domain" | "domains" => {
+                has_destinations = true;
+                for domain in values {
+                    if let Some(domain) = domain
+                        .strip_prefix("domain:")
+                        .or_else(|| domain.strip_prefix("full:"))
+                    {
+                        // Hostname masks also match subdomains, so full domains do too.
+                        matchers.masks.push(domain.to_string());
+                    } else if let Some(keyword) = domain.strip_prefix("keyword:") {
+                        matchers.domain_keywords.push(keyword.to_string());
+                    } else if let Some(category) = domain.strip_prefix("geosite:") {
+                        matchers.geosite.push(category.to_ascii_lowercase());
+                    } else if domain.contains(':') {
+                        skipped.push(domain);
+                    } else {
+                        matchers.domain_keywords.push(domain);
+                    }
+                }
+            }
+            "ip" => {
+                has_destinations = true;
+                for ip in values {
+                    match ip.strip_prefix("geoip:") {
+                        Some(code) if !code.starts_with('!') => {
+                            matchers.geoip.push(code.to_ascii_lowercase());
+                        }
+                        None if !ip.starts_with("ext:") => matchers.masks.push(ip),
+                        _ => skipped.push(ip),
+                    }
+                }
+            }
+            "port" => matchers.ports.extend(
+                values
+                    .iter()
+                    .flat_map(|ports| ports.split(','))
+                    .map(|port| port.trim().to_string()),
+            ),
+            "source" | "sourceIP" => {
+                if values
+                    .iter()
+                    .any(|ip| ip.starts_with("geoip:") || ip.starts_with("ext:"))
+                {
+                    return Err(format!("unsupported {key} GeoIP values"));
+                }
+                matchers.source_ips.extend(values);
+            }
+            "network" => {
+                let network = get_str(rule, key).unwrap_or_default();
+                if !(network.contains("tcp") && network.contains("udp")) {
+                    return Err(format!("unsupported network {network}"));
+                }
+            }
+            other => return Err(format!("unsupported field {other}")),
+        }
+    }
+
+    // A rule whose destinations were all skipped would match any destination.
+    if has_destinations
+        && matchers.masks.is_empty()
+        && matchers.domain_keywords.is_empty()
+        && matchers.geosite.is_empty()
+        && matchers.geoip.is_empty()
+    {
+        return Err(format!("unsupported values {}", skipped.join(", ")));
+    }
+    Ok((matchers, skipped))
+}
+
+/// Converts a proxy outbound into a client config.
+fn convert_outbound(outbound: &Value) -> Result<Value, String> {
+    let protocol = get_str(outbound, "protocol").ok_or("missing protocol")?;
+    let settings = outbound.get("settings").cloned().unwrap_or_default();
+    let stream = outbound.get("streamSettings").cloned().unwrap_or_default();
+    // The server is the first of vnext or servers, or the settings themselves, with its
+    // first user or its own credentials.
+    let server = sequence(&settings, "vnext")
+        .first()
+        .or_else(|| sequence(&settings, "servers").first())
+        .cloned()
+        .unwrap_or_else(|| settings.clone());
+    let user = sequence(&server, "users")
+        .first()
+        .cloned()
+        .unwrap_or_else(|| server.clone());
+    let field = |key: &str| opt_str(get_str(&user, key));
+
+    let mut transport = None;
+    let protocol = match protocol.as_str() {
+        "socks" => mapping([
+            ("type", Value::from("socks")),
+            ("username", field("user")),
+            ("password", field("pass")),
+        ]),
+        "http" => mapping([
+            ("type", Value::from("http")),
+            ("username", field("user")),
+            ("password", field("pass")),
+        ]),
+        "shadowsocks" => mapping([
+            ("type", Value::from("shadowsocks")),
+            ("cipher", field("method")),
+            ("password", field("password")),
+        ]),
+        "vmess" => {
+            if get_u64(&user, "alterId").is_some_and(|alter_id| alter_id != 0) {
+                return Err("legacy VMess with alterId is not supported".to_string());
+            }
+            transport = convert_transport(&stream)?;
+            mapping([
+                ("type", Value::from("vmess")),
+                (
+                    "cipher",
+                    Value::from(get_str(&user, "security").unwrap_or_else(|| "auto".to_string())),
+                ),
+                ("user_id", field("id")),
+            ])
+        }
+        "vless" => {
+            transport = convert_transport(&stream)?;
+            mapping([
+                ("type", Value::from("vless")),
+                ("user_id", field("id")),
+                (
+                    "flow",
+                    opt_str(get_str(&user, "flow").filter(|flow| !flow.is_empty())),
+                ),
+            ])
+        }
+        "trojan" => {
+            transport = convert_transport(&stream)?;
+            mapping([
+                ("type", Value::from("trojan")),
+                ("password", field("password")),
+            ])
+        }
+        "wireguard" => return convert_wireguard(&settings),
+        other => return Err(format!("unsupported outbound protocol {other}")),
+    };
+
+    let address = get_str(&server, "address").ok_or("missing address")?;
+    let port = get_u64(&server, "port").ok_or("missing port")?;
+    Ok(mapping([
+        ("address", Value::from(server_address(&address, port))),
+        (
+            "protocol",
+            wrap_protocol(protocol, transport, client_tls(&stream)?),
+        ),
+    ]))
+}
+
+fn convert_wireguard(settings: &Value) -> Result<Value, String> {
+    let peer = sequence(settings, "peers")
+        .first()
+        .cloned()
+        .ok_or("missing peers")?;
+    let endpoint = get_str(&peer, "endpoint").ok_or("missing peer endpoint")?;
+    let allowed_ips = get_str_list(&peer, "allowedIPs");
+    Ok(mapping([
+        ("address", Value::from(endpoint)),
+        (
+            "protocol",
+            mapping([
+                ("type", Value::from("wireguard")),
+                ("private_key", opt_str(get_str(settings, "secretKey"))),
+                ("peer_public_key", opt_str(get_str(&peer, "publicKey"))),
+                ("preshared_key", opt_str(get_str(&peer, "preSharedKey"))),
+                (
+                    "local_addresses",
+                    Value::from(get_str_list(settings, "address")),
+                ),
+                (
+                    "allowed_ips",
+                    if allowed_ips.is_empty() {
+                        Value::Null
+                    } else {
+                        Value::from(allowed_ips)
+                    },
+                ),
+                (
+                    "reserved",
+                    settings.get("reserved").cloned().unwrap_or_default(),
+                ),
+                (
+                    "mtu",
+                    get_u64(settings, "mtu").map_or(Value::Null, Value::from),
+                ),
+            ]),
+        ),
+    ]))
+}
+
+fn client_tls(stream: &Value) -> Result<Option<TlsLayer>, String> {
+    match get_str(stream, "security").as_deref() {
+        None | Some("") | Some("none") => Ok(None),
+        Some("tls") => {
+            let tls = stream.get("tlsSettings").cloned().unwrap_or_default();
+            Ok(Some(TlsLayer {
+                sni: get_str(&tls, "serverName"),
+                insecure: get_bool(&tls, "allowInsecure").unwrap_or(false),
+                alpn: get_str_list(&tls, "alpn"),
+                fingerprint: get_str(&tls, "fingerprint"),
+                reality: None,
+            }))
+        }
+        Some("reality") => {
+            let reality = stream.get("realitySettings").cloned().unwrap_or_default();
+            let public_key = get_str(&reality, "publicKey")
+                .or_else(|| get_str(&reality, "password"))
+                .ok_or("missing reality publicKey")?;
+            Ok(Some(TlsLayer {
+                sni: get_str(&reality, "serverName"),
+                fingerprint: get_str(&reality, "fingerprint"),
+                reality: Some((public_key, get_str(&reality, "shortId"))),
+                ..TlsLayer::default()
+            }))
+        }
+        Some(other) => Err(format!("unsupported security {other}")),
+    }
+}
+
+fn server_tls(stream: &Value) -> Result<Option<ServerTlsLayer>, String> {
+    match get_str(stream, "security").as_deref() {
+        None | Some("") | Some("none") => Ok(None),
+        Some("tls") => {
+            let tls = stream.get("tlsSettings").cloned().unwrap_or_default();
+            let certificate = sequence(&tls, "certificates")
+                .first()
+                .cloned()
+                .unwrap_or_default();
+            let (Some(cert), Some(key)) = (
+                get_str(&certificate, "certificateFile"),
+                get_str(&certificate, "keyFile"),
+            ) else {
+                return Err("only certificateFile and keyFile are supported".to_string());
+            };
+            Ok(Some(ServerTlsLayer::Tls {
+                cert,
+                key,
+                alpn: get_str_list(&tls, "alpn"),
+            }))
+        }
+        Some("reality") => {
+            let reality = stream.get("realitySettings").cloned().unwrap_or_default();
+            let dest = get_str(&reality, "target")
+                .or_else(|| get_str(&reality, "dest"))
+                .ok_or("missing reality target")?;
+            // A target without a host is a local port.
+            let dest = match dest.parse::<u16>() {
+                Ok(port) => format!("127.0.0.1:{port}"),
+                Err(_) => dest,
+            };
+            let server_names = get_str_list(&reality, "serverNames");
+            if server_names.is_empty() {
+                return Err("missing reality serverNames".to_string());
+            }
+            Ok(Some(ServerTlsLayer::Reality {
+                server_names,
+                private_key: get_str(&reality, "privateKey").ok_or("missing reality privateKey")?,
+                short_ids: get_str_list(&reality, "shortIds"),
+                dest,
+            }))
+        }
+        Some(other) => Err(format!("unsupported security {other}")),
+    }
+}
+
+fn convert_transport(stream: &Value) -> Result<Option<TransportLayer>, String> {
+    let settings = |key: &str| stream.get(key).cloned().unwrap_or_default();
+    match get_str(stream, "network").as_deref() {
+        None | Some("tcp") | Some("raw") => {
+            let header = settings("tcpSettings")
+                .get("header")
+                .and_then(|header| get_str(header, "type"));
+            match header.as_deref() {
+                None | Some("none") => Ok(None),
+                Some(other) => Err(format!("unsupported tcp header {other}")),
+            }
+        }
+        Some("ws") => {
+            let ws = settings("wsSettings");
+            let mut headers = ws
+                .get("headers")
+                .and_then(Value::as_mapping)
+                .cloned()
+                .unwrap_or_default();
+            let host = get_str(&ws, "host").or_else(|| {
+                headers
+                    .remove("Host")
+                    .or_else(|| headers.remove("host"))
+                    .and_then(|host| host.as_str().map(str::to_string))
+            });
+            Ok(Some(TransportLayer::Websocket {
+                path: get_str(&ws, "path"),
+                host,
+                headers,
+                max_early_data: None,
+            }))
+        }
+        Some("grpc") => {
+            let grpc = settings("grpcSettings");
+            Ok(Some(TransportLayer::Grpc {
+                service_name: get_str(&grpc, "serviceName"),
+                host: get_str(&grpc, "authority"),
+            }))
+        }
+        Some("httpupgrade") => {
+            let http_upgrade = settings("httpupgradeSettings");
+            Ok(Some(TransportLayer::HttpUpgrade {
+                path: get_str(&http_upgrade, "path"),
+                host: get_str(&http_upgrade, "host"),
+            }))
+        }
+        Some(other) => Err(format!("unsupported network {other}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientProxyConfig, RuleActionConfig, ServerProxyConfig};
+
+    const XRAY_CLIENT_CONFIG: &str = r#"{
+  // Xray configs may have comments.
+  "inbounds": [
+    {
+      "listen": "127.0.0.1",
+      "port": 10808,
+      "protocol": "socks",
+      "settings": {"auth": "password", "accounts": [{"user": "user", "pass": "pass"}], "udp": true}
+    }
+  ],
+  "outbounds": [
+    {
+      "tag": "proxy-reality",
+      "protocol": "vless",
+      "settings": {
+        "vnext": [{
+          "address": "203.0.113.1",
+          "port": 443,
+          "users": [{"id": "b831381d-6324-4d53-ad4f-8cda48b30811", "flow": "xtls-rprx-vision", "encryption": "none"}]
+        }]
+      },
+      "streamSettings": {
+        "network": "tcp",
+        "security": "reality",
+        "realitySettings": {
+          "serverName": "www.example.com",
+          "fingerprint": "chrome",
+          "publicKey": "Z84J2IelR9ch3k8VtlVhhs5ycBUlXA7wHBWcBrjqnAw",
+          "shortId": "0123456789abcdef"
+        }
+      }
+    },
+    {
+      "tag": "proxy-ss",
+      "protocol": "shadowsocks",
+      "settings": {"servers": [{"address": "192.0.2.1", "port": 8388, "method": "aes-128-gcm", "password": "secret"}]}
+    },
+    {"tag": "direct", "protocol": "freedom"},
+    {"tag": "block", "protocol": "blackhole"}
+  ],
+  "routing": {
+    "domainStrategy": "IPIfNonMatch",
+    "rules": [
+      {"type": "field", "domain": ["geosite:category-ads-all"], "outboundTag": "block"},
+      {"type": "field", "domain": ["domain:example.org", "regexp:^ads\\."], "ip": ["geoip:cn"], "outboundTag": "direct"},
+      {"type": "field", "inboundTag": ["socks-in"], "outboundTag": "direct"},
+      {"type": "field", "port": "443,8000-9000", "balancerTag": "balanced"}
+    ],
+    "balancers": [{"tag": "balanced", "selector": ["proxy-"], "strategy": {"type": "roundRobin"}}]
+  }
+}"#;
+
+    #[test]
+    fn test_convert_xray_client_config() {
+        let converted = convert_xray_config(XRAY_CLIENT_CONFIG).unwrap();
+        assert_eq!(converted.warnings.len(), 2, "{:?}", converted.warnings);
+        assert!(converted.warnings[0].starts_with("Converted routing rule 2 without regexp:"));
+        assert!(converted.warnings[1].starts_with("Skipped routing rule 3"));
+
+        let configs = converted.configs;
+        assert_eq!(configs.len(), 4);
+        match &configs[0] {
+            Config::ClientConfigGroup(group) => {
+                assert_eq!(group.client_group, "proxy-reality");
+                let client = match group.client_proxies.iter().next() {
+                    Some(crate::config::ConfigSelection::Config(client)) => client,
+                    _ => panic!("Expected inline client"),
+                };
+                assert!(matches!(client.protocol, ClientProxyConfig::Reality { .. }));
+            }
+            _ => panic!("Expected client group"),
+        }
+
+        let Config::RuleConfigGroup(group) = &configs[2] else {
+            panic!("Expected rule group");
+        };
+        let rules: Vec<&RuleConfig> = group.rules.iter().collect();
+        // The second routing rule is split into a rule for domains and one for GeoIP.
+        assert_eq!(rules.len(), 5);
+        assert!(matches!(rules[0].action, RuleActionConfig::Block));
+        assert_eq!(rules[1].masks.len(), 1);
+        assert_eq!(rules[2].geoip.len(), 1);
+        match &rules[3].action {
+            RuleActionConfig::Allow {
+                client_chains,
+                balancer,
+                ..
+            } => {
+                assert_eq!(client_chains.len(), 2);
+                assert_eq!(*balancer, crate::config::BalanceStrategy::RoundRobin);
+            }
+            RuleActionConfig::Block => panic!("Expected allow rule"),
+        }
+        assert_eq!(rules[3].ports.len(), 2);
+        // Connections that match no rule use the first outbound.
+        assert!(matches!(rules[4].action, RuleActionConfig::Allow { .. }));
+
+        match &configs[3] {
+            Config::Server(server) => {
+                assert_eq!(server.bind_location.to_string(), "127.0.0.1:10808");
+                assert!(matches!(
+                    &server.protocol,
+                    ServerProxyConfig::Socks { username: Some(username), .. } if username == "user"
+                ));
+            }
+            _ => panic!("Expected server"),
+        }
+    }
+
+    #[test]
+    fn test_convert_xray_server_config() {
+        let converted = convert_xray_config(
+            r#"{
+  "inbounds": [{
+    "port": 443,
+    "protocol": "vless",
+    "settings": {"clients": [{"id": "b831381d-6324-4d53-ad4f-8cda48b30811"}], "decryption": "none"},
+    "streamSettings": {
+      "network": "ws",
+      "security": "tls",
+      "tlsSettings": {"certificates": [{"certificateFile": "cert.pem", "keyFile": "key.pem"}]},
+      "wsSettings": {"path": "/ws"}
+    }
+  }],
+  "outbounds": [{"protocol": "freedom"}]
+}"#,
+        )
+        .unwrap();
+        assert!(converted.warnings.is_empty(), "{:?}", converted.warnings);
+        let Config::Server(server) = &converted.configs[1] else {
+            panic!("Expected server");
+        };
+        assert_eq!(server.bind_location.to_string(), "0.0.0.0:443");
+        match &server.protocol {
+            ServerProxyConfig::Tls {
+                default_tls_target: Some(target),
+                ..
+            } => assert!(matches!(
+                target.protocol,
+                ServerProxyConfig::Websocket { .. }
+            )),
+            _ => panic!("Expected TLS server"),
+        }
+
+        assert!(convert_xray_config("not json").is_err());
+    }
+}
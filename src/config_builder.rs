@@ -0,0 +1,696 @@
+//! Builders for configs, for applications that embed shoes.
+//!
+//! The builders produce the same config types as config files, and parse addresses,
+//! masks and ports with the same code. [`ConfigBuilder::validate`] runs the checks of
+//! [`validate()`](crate::config::validate()), and [`run_configs_with_reload`] starts the
+//! servers.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! use shoes::config::{ClientProxyConfig, ServerProxyConfig};
+//! use shoes::config_builder::{ConfigBuilder, OutboundBuilder, RuleBuilder, ServerConfigBuilder};
+//!
+//! let upstream = OutboundBuilder::new(
+//!     "proxy.example.com:1080",
+//!     ClientProxyConfig::Socks {
+//!         username: None,
+//!         password: None,
+//!         udp_enabled: true,
+//!     },
+//! )
+//! .build()?;
+//! let server = ServerConfigBuilder::new(
+//!     "127.0.0.1:8080",
+//!     ServerProxyConfig::Http {
+//!         username: None,
+//!         password: None,
+//!         decoy: None,
+//!     },
+//! )
+//! .rule(RuleBuilder::block().mask("10.0.0.0/8").build()?)
+//! .rule(RuleBuilder::allow().client_group("upstream").build()?)
+//! .build()?;
+//!
+//! let configs = ConfigBuilder::new()
+//!     .client_group("upstream", [upstream])
+//!     .server(server)
+//!     .build();
+//! shoes::run_configs_with_reload(configs, shoes::ReloadHandle::new(), true).await
+//! # }
+//! ```
+
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde_yaml::{Mapping, Value};
+
+use crate::config::report::validate_configs;
+use crate::config::{
+    AccessControlConfig, BalanceStrategy, BindLocation, ClientChain, ClientChainHop, ClientConfig,
+    ClientConfigGroup, ClientProxyConfig, ClientQuicConfig, Config, ConfigSelection,
+    ConnectionLimitConfig, DialConfig, DnsConfig, DnsServerSpec, DomainStrategy,
+    HappyEyeballsConfig, HealthCheckConfig, KcpConfig, RateLimitConfig, RuleActionConfig,
+    RuleConfig, RuleConfigGroup, ServerConfig, ServerProxyConfig, ServerQuicConfig,
+    SessionTimeoutConfig, TcpConfig, Transport, ValidationReport, direct_allow_rule,
+};
+use crate::option_util::{NoneOrOne, NoneOrSome, OneOrSome};
+use crate::reload::{self, ReloadHandle};
+
+/// Parses a value like the config file field `field`.
+fn parse_field<T: DeserializeOwned>(field: &str, value: Value) -> std::io::Result<T> {
+    serde_yaml::from_value(value).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid {field}: {e}"),
+        )
+    })
+}
+
+/// Builds a [`ServerConfig`] listening on an address or a Unix socket path.
+#[derive(Debug, Clone)]
+pub struct ServerConfigBuilder {
+    bind_location: BindTarget,
+    protocol: ServerProxyConfig,
+    transport: Transport,
+    tcp_settings: Option<TcpConfig>,
+    quic_settings: Option<ServerQuicConfig>,
+    kcp_settings: Option<KcpConfig>,
+    rules: Vec<ConfigSelection<RuleConfig>>,
+    dns: Option<String>,
+    rate_limit: Option<RateLimitConfig>,
+    connection_limit: Option<ConnectionLimitConfig>,
+    session_timeouts: Option<SessionTimeoutConfig>,
+    access_control: Option<AccessControlConfig>,
+}
+
+#[derive(Debug, Clone)]
+enum BindTarget {
+    Address(String),
+    Path(PathBuf),
+}
+
+impl ServerConfigBuilder {
+    /// Creates a builder for a server on `address`, e.g. `0.0.0.0:443` or
+    /// `[::]:8000-8010`.
+    pub fn new(address: impl Into<String>, protocol: ServerProxyConfig) -> Self {
+        Self::with_bind_target(BindTarget::Address(address.into()), protocol)
+    }
+
+    /// Creates a builder for a server on a Unix socket.
+    pub fn with_path(path: impl Into<PathBuf>, protocol: ServerProxyConfig) -> Self {
+        Self::with_bind_target(BindTarget::Path(path.into()), protocol)
+    }
+
+    fn with_bind_target(bind_location: BindTarget, protocol: ServerProxyConfig) -> Self {
+        Self {
+            bind_location,
+            protocol,
+            transport: Transport::default(),
+            tcp_settings: None,
+            quic_settings: None,
+            kcp_settings: None,
+            rules: vec![],
+            dns: None,
+            rate_limit: None,
+            connection_limit: None,
+            session_timeouts: None,
+            access_control: None,
+        }
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn tcp_settings(mut self, tcp_settings: TcpConfig) -> Self {
+        self.tcp_settings = Some(tcp_settings);
+        self
+    }
+
+    pub fn quic_settings(mut self, quic_settings: ServerQuicConfig) -> Self {
+        self.quic_settings = Some(quic_settings);
+        self
+    }
+
+    pub fn kcp_settings(mut self, kcp_settings: KcpConfig) -> Self {
+        self.kcp_settings = Some(kcp_settings);
+        self
+    }
+
+    /// Adds a rule. Servers without rules allow all connections directly.
+    pub fn rule(mut self, rule: RuleConfig) -> Self {
+        self.rules.push(ConfigSelection::Config(rule));
+        self
+    }
+
+    /// Adds the rules of a rule group.
+    pub fn rule_group(mut self, name: impl Into<String>) -> Self {
+        self.rules.push(ConfigSelection::GroupName(name.into()));
+        self
+    }
+
+    /// Resolves hostnames with a DNS group, or a DNS server URL like `udp://1.1.1.1`.
+    pub fn dns(mut self, dns: impl Into<String>) -> Self {
+        self.dns = Some(dns.into());
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: RateLimitConfig) -> Self {
+        self.rate_limit = Some(rate_limit);
+        self
+    }
+
+    pub fn connection_limit(mut self, connection_limit: ConnectionLimitConfig) -> Self {
+        self.connection_limit = Some(connection_limit);
+        self
+    }
+
+    pub fn session_timeouts(mut self, session_timeouts: SessionTimeoutConfig) -> Self {
+        self.session_timeouts = Some(session_timeouts);
+        self
+    }
+
+    pub fn access_control(mut self, access_control: AccessControlConfig) -> Self {
+        self.access_control = Some(access_control);
+        self
+    }
+
+    /// Returns an error if the address is invalid. The rest of the config is checked when
+    /// it is validated.
+    pub fn build(self) -> std::io::Result<ServerConfig> {
+        let bind_location = match self.bind_location {
+            BindTarget::Address(address) => {
+                BindLocation::Address(parse_field("address", Value::String(address))?)
+            }
+            BindTarget::Path(path) => BindLocation::Path(path),
+        };
+        let rules = if self.rules.is_empty() {
+            direct_allow_rule()
+        } else {
+            NoneOrSome::Some(self.rules)
+        };
+        Ok(ServerConfig {
+            bind_location,
+            protocol: self.protocol,
+            transport: self.transport,
+            tcp_settings: self.tcp_settings,
+            quic_settings: self.quic_settings,
+            kcp_settings: self.kcp_settings,
+            rules,
+            dns: self.dns.map(|dns| DnsConfig {
+                servers: NoneOrSome::One(DnsServerSpec::Simple(dns)),
+            }),
+            rate_limit: self.rate_limit,
+            connection_limit: self.connection_limit,
+            session_timeouts: self.session_timeouts,
+            access_control: self.access_control,
+        })
+    }
+}
+
+/// Builds a [`ClientConfig`], the outbound that rules send connections through.
+#[derive(Debug, Clone)]
+pub struct OutboundBuilder {
+    address: Option<String>,
+    config: ClientConfig,
+}
+
+impl OutboundBuilder {
+    /// Creates a builder for a proxy at `address`, e.g. `proxy.example.com:443`.
+    pub fn new(address: impl Into<String>, protocol: ClientProxyConfig) -> Self {
+        Self {
+            address: Some(address.into()),
+            config: ClientConfig {
+                protocol,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Creates a builder for direct connections.
+    pub fn direct() -> Self {
+        Self {
+            address: None,
+            config: ClientConfig::default(),
+        }
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.config.transport = transport;
+        self
+    }
+
+    /// Binds outgoing sockets to a network interface.
+    pub fn bind_interface(mut self, interface: impl Into<String>) -> Self {
+        self.config.bind_interface = NoneOrOne::One(interface.into());
+        self
+    }
+
+    pub fn fwmark(mut self, fwmark: u32) -> Self {
+        self.config.fwmark = Some(fwmark);
+        self
+    }
+
+    pub fn dscp(mut self, dscp: u8) -> Self {
+        self.config.dscp = Some(dscp);
+        self
+    }
+
+    pub fn domain_strategy(mut self, domain_strategy: DomainStrategy) -> Self {
+        self.config.domain_strategy = Some(domain_strategy);
+        self
+    }
+
+    pub fn resolve_locally(mut self, resolve_locally: bool) -> Self {
+        self.config.resolve_locally = resolve_locally;
+        self
+    }
+
+    /// Tunnels UDP over TCP with UoT V2 connect mode.
+    pub fn udp_over_tcp(mut self, udp_over_tcp: bool) -> Self {
+        self.config.udp_over_tcp = udp_over_tcp;
+        self
+    }
+
+    pub fn tcp_settings(mut self, tcp_settings: TcpConfig) -> Self {
+        self.config.tcp_settings = Some(tcp_settings);
+        self
+    }
+
+    pub fn happy_eyeballs(mut self, happy_eyeballs: HappyEyeballsConfig) -> Self {
+        self.config.happy_eyeballs = Some(happy_eyeballs);
+        self
+    }
+
+    pub fn quic_settings(mut self, quic_settings: ClientQuicConfig) -> Self {
+        self.config.quic_settings = Some(quic_settings);
+        self
+    }
+
+    pub fn kcp_settings(mut self, kcp_settings: KcpConfig) -> Self {
+        self.config.kcp_settings = Some(kcp_settings);
+        self
+    }
+
+    /// Returns an error if the address is invalid.
+    pub fn build(mut self) -> std::io::Result<ClientConfig> {
+        if let Some(address) = self.address {
+            self.config.address = parse_field("address", Value::String(address))?;
+        }
+        Ok(self.config)
+    }
+}
+
+/// Builds a [`RuleConfig`]. Matchers are parsed like the fields of a rule in a config
+/// file, and a rule without matchers matches all destinations.
+#[derive(Debug, Clone)]
+pub struct RuleBuilder {
+    /// Matcher and override fields, parsed when the rule is built.
+    fields: Mapping,
+    block: bool,
+    client_chains: Vec<ClientChain>,
+    balancer: Option<BalanceStrategy>,
+    health_check: Option<HealthCheckConfig>,
+    dial: Option<DialConfig>,
+    domain_strategy: Option<DomainStrategy>,
+    resolve_locally: Option<bool>,
+}
+
+impl RuleBuilder {
+    /// Creates a builder for a rule that allows matching connections. Without chains, they
+    /// are connected directly.
+    pub fn allow() -> Self {
+        Self::new(false)
+    }
+
+    /// Creates a builder for a rule that blocks matching connections.
+    pub fn block() -> Self {
+        Self::new(true)
+    }
+
+    fn new(block: bool) -> Self {
+        Self {
+            fields: Mapping::new(),
+            block,
+            client_chains: vec![],
+            balancer: None,
+            health_check: None,
+            dial: None,
+            domain_strategy: None,
+            resolve_locally: None,
+        }
+    }
+
+    fn push(mut self, field: &str, value: Value) -> Self {
+        let key = Value::String(field.to_string());
+        match self.fields.get_mut(&key) {
+            Some(Value::Sequence(values)) => values.push(value),
+            _ => {
+                self.fields.insert(key, Value::Sequence(vec![value]));
+            }
+        }
+        self
+    }
+
+    /// Matches destinations in an IP/CIDR or hostname mask, e.g. `10.0.0.0/8` or
+    /// `*.example.com:443`.
+    pub fn mask(self, mask: impl Into<String>) -> Self {
+        self.push("masks", Value::String(mask.into()))
+    }
+
+    /// Matches hostname destinations containing a keyword.
+    pub fn domain_keyword(self, keyword: impl Into<String>) -> Self {
+        self.push("domain_keywords", Value::String(keyword.into()))
+    }
+
+    /// Matches destination ports, e.g. `443` or `6000-7000`.
+    pub fn port(self, ports: impl Into<String>) -> Self {
+        self.push("ports", Value::String(ports.into()))
+    }
+
+    /// Matches source IPs in an IP/CIDR mask.
+    pub fn source_ip(self, source_ip: impl Into<String>) -> Self {
+        self.push("source_ips", Value::String(source_ip.into()))
+    }
+
+    /// Matches destinations in a country, or `private`.
+    pub fn geoip(self, code: impl Into<String>) -> Self {
+        self.push("geoip", Value::String(code.into()))
+    }
+
+    /// Matches hostname destinations in a geosite category.
+    pub fn geosite(self, category: impl Into<String>) -> Self {
+        self.push("geosite", Value::String(category.into()))
+    }
+
+    pub fn block_quic(mut self, block_quic: bool) -> Self {
+        self.fields
+            .insert(Value::from("block_quic"), Value::Bool(block_quic));
+        self
+    }
+
+    /// Replaces the destination, e.g. `127.0.0.1:8080`, or only its host.
+    pub fn override_address(mut self, address: impl Into<String>) -> Self {
+        self.fields.insert(
+            Value::from("override_address"),
+            Value::String(address.into()),
+        );
+        self
+    }
+
+    pub fn override_port(mut self, port: u16) -> Self {
+        self.fields
+            .insert(Value::from("override_port"), Value::from(port));
+        self
+    }
+
+    /// Adds a chain that connects through the hops in order.
+    pub fn chain(mut self, hops: impl IntoIterator<Item = ConfigSelection<ClientConfig>>) -> Self {
+        let hops: Vec<ClientChainHop> = hops.into_iter().map(ClientChainHop::Single).collect();
+        self.client_chains.push(ClientChain {
+            hops: OneOrSome::Some(hops),
+        });
+        self
+    }
+
+    /// Adds a chain with a single outbound.
+    pub fn client(self, client: ClientConfig) -> Self {
+        self.chain([ConfigSelection::Config(client)])
+    }
+
+    /// Adds a chain through the clients of a client group.
+    pub fn client_group(self, name: impl Into<String>) -> Self {
+        self.chain([ConfigSelection::GroupName(name.into())])
+    }
+
+    pub fn balancer(mut self, balancer: BalanceStrategy) -> Self {
+        self.balancer = Some(balancer);
+        self
+    }
+
+    pub fn health_check(mut self, health_check: HealthCheckConfig) -> Self {
+        self.health_check = Some(health_check);
+        self
+    }
+
+    pub fn dial(mut self, dial: DialConfig) -> Self {
+        self.dial = Some(dial);
+        self
+    }
+
+    /// Replaces the domain strategy of the clients of the chains.
+    pub fn domain_strategy(mut self, domain_strategy: DomainStrategy) -> Self {
+        self.domain_strategy = Some(domain_strategy);
+        self
+    }
+
+    /// Replaces `resolve_locally` of the clients of the chains.
+    pub fn resolve_locally(mut self, resolve_locally: bool) -> Self {
+        self.resolve_locally = Some(resolve_locally);
+        self
+    }
+
+    /// Returns an error if a matcher or override is invalid, or if a blocking rule has
+    /// allow options.
+    pub fn build(self) -> std::io::Result<RuleConfig> {
+        let mut fields = self.fields;
+        let has_matchers = [
+            "masks",
+            "domain_keywords",
+            "ports",
+            "source_ips",
+            "geoip",
+            "geosite",
+        ]
+        .into_iter()
+        .any(|field| fields.contains_key(field));
+        if !has_matchers {
+            fields.insert(Value::from("masks"), Value::from("0.0.0.0/0"));
+        }
+        let action = if self.block { "block" } else { "allow" };
+        fields.insert(Value::from("action"), Value::from(action));
+        let mut rule: RuleConfig = parse_field("rule", Value::Mapping(fields))?;
+
+        match &mut rule.action {
+            RuleActionConfig::Allow {
+                client_chains,
+                balancer,
+                health_check,
+                dial,
+                domain_strategy,
+                resolve_locally,
+                ..
+            } => {
+                if !self.client_chains.is_empty() {
+                    *client_chains = NoneOrSome::Some(self.client_chains);
+                }
+                *balancer = self.balancer.unwrap_or_default();
+                *health_check = self.health_check;
+                *dial = self.dial;
+                *domain_strategy = self.domain_strategy;
+                *resolve_locally = self.resolve_locally;
+            }
+            RuleActionConfig::Block => {
+                if !self.client_chains.is_empty()
+                    || self.balancer.is_some()
+                    || self.health_check.is_some()
+                    || self.dial.is_some()
+                    || self.domain_strategy.is_some()
+                    || self.resolve_locally.is_some()
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        "blocking rules cannot have chains or connection options",
+                    ));
+                }
+            }
+        }
+        Ok(rule)
+    }
+}
+
+/// Collects configs into the list that a config file holds.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigBuilder {
+    configs: Vec<Config>,
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn server(mut self, server: ServerConfig) -> Self {
+        self.configs.push(Config::Server(server));
+        self
+    }
+
+    /// Adds a named group of outbounds that rules and other groups can refer to.
+    pub fn client_group(
+        mut self,
+        name: impl Into<String>,
+        clients: impl IntoIterator<Item = ClientConfig>,
+    ) -> Self {
+        let clients: Vec<ConfigSelection<ClientConfig>> =
+            clients.into_iter().map(ConfigSelection::Config).collect();
+        self.configs
+            .push(Config::ClientConfigGroup(ClientConfigGroup {
+                client_group: name.into(),
+                client_proxies: OneOrSome::Some(clients),
+            }));
+        self
+    }
+
+    /// Adds a named group of rules that servers can refer to.
+    pub fn rule_group(
+        mut self,
+        name: impl Into<String>,
+        rules: impl IntoIterator<Item = RuleConfig>,
+    ) -> Self {
+        self.configs.push(Config::RuleConfigGroup(RuleConfigGroup {
+            rule_group: name.into(),
+            rules: OneOrSome::Some(rules.into_iter().collect()),
+        }));
+        self
+    }
+
+    /// Adds any other config, e.g. a DNS group or the metrics listener.
+    pub fn config(mut self, config: Config) -> Self {
+        self.configs.push(config);
+        self
+    }
+
+    pub fn build(self) -> Vec<Config> {
+        self.configs
+    }
+
+    /// Validates the configs like config files are validated, without starting servers.
+    pub async fn validate(&self) -> std::io::Result<ValidationReport> {
+        validate_configs(self.configs.clone()).await
+    }
+}
+
+/// Starts servers from configs built in code, e.g. with [`ConfigBuilder`].
+///
+/// Like [`crate::run_with_reload`], the configs are re-applied whenever `reload_handle` is
+/// triggered, or when `watch_files` is set and a certificate or key file changed.
+pub async fn run_configs_with_reload(
+    configs: Vec<Config>,
+    reload_handle: ReloadHandle,
+    watch_files: bool,
+) -> std::io::Result<()> {
+    reload::run(
+        || std::future::ready(Ok((configs.clone(), vec![]))),
+        reload_handle,
+        watch_files,
+    )
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn socks_server() -> ServerProxyConfig {
+        ServerProxyConfig::Socks {
+            username: None,
+            password: None,
+            user_group: None,
+            udp_enabled: true,
+        }
+    }
+
+    #[test]
+    fn test_rule_builder() {
+        let rule = RuleBuilder::allow()
+            .mask("10.0.0.0/8")
+            .port("443")
+            .domain_keyword("Example")
+            .client_group("upstream")
+            .balancer(BalanceStrategy::SourceHash)
+            .build()
+            .unwrap();
+        assert_eq!(rule.domain_keywords, vec!["example".to_string()]);
+        assert_eq!(rule.ports.len(), 1);
+        match rule.action {
+            RuleActionConfig::Allow {
+                client_chains,
+                balancer,
+                ..
+            } => {
+                assert_eq!(client_chains.len(), 1);
+                assert_eq!(balancer, BalanceStrategy::SourceHash);
+            }
+            RuleActionConfig::Block => panic!("Expected allow rule"),
+        }
+
+        assert!(matches!(
+            RuleBuilder::block().build().unwrap().action,
+            RuleActionConfig::Block
+        ));
+        assert!(
+            RuleBuilder::allow()
+                .source_ip("example.com")
+                .build()
+                .is_err()
+        );
+        assert!(
+            RuleBuilder::block()
+                .client_group("upstream")
+                .build()
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_config_builder_validate() {
+        let upstream = OutboundBuilder::new(
+            "127.0.0.1:1080",
+            ClientProxyConfig::Socks {
+                username: None,
+                password: None,
+                udp_enabled: false,
+            },
+        )
+        .build()
+        .unwrap();
+        let server = ServerConfigBuilder::new("127.0.0.1:8080", socks_server())
+            .rule(
+                RuleBuilder::allow()
+                    .client_group("upstream")
+                    .build()
+                    .unwrap(),
+            )
+            .build()
+            .unwrap();
+        let builder = ConfigBuilder::new()
+            .client_group("upstream", [upstream])
+            .server(server);
+        let report = builder.validate().await.unwrap();
+        assert_eq!(report.listeners, vec!["tcp://127.0.0.1:8080"]);
+
+        let server = ServerConfigBuilder::new("127.0.0.1:8081", socks_server())
+            .rule_group("missing")
+            .build()
+            .unwrap();
+        assert!(
+            ConfigBuilder::new()
+                .server(server)
+                .validate()
+                .await
+                .is_err()
+        );
+
+        assert!(
+            ServerConfigBuilder::new("127.0.0.1", socks_server())
+                .build()
+                .is_err()
+        );
+        assert!(
+            OutboundBuilder::new("proxy.example.com", ClientProxyConfig::Direct)
+                .build()
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,143 @@
+//! Limits of the number of connections that a server handles at once.
+//!
+//! Accept loops ask the limiter of their server for a permit, which each connection
+//! holds until it closes. The limiter is shared by all addresses of the server.
+
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::{ConnectionLimitConfig, ConnectionLimitMode};
+use crate::metrics::ListenerMetrics;
+
+#[derive(Debug, Default)]
+pub struct ConnectionLimiter {
+    config: Option<ConnectionLimitConfig>,
+    /// None when the server has no limit.
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: Option<ConnectionLimitConfig>) -> Self {
+        let semaphore = config
+            .as_ref()
+            .map(|config| Arc::new(Semaphore::new(config.max_connections)));
+        Self { config, semaphore }
+    }
+
+    pub fn config(&self) -> Option<&ConnectionLimitConfig> {
+        self.config.as_ref()
+    }
+
+    /// Called before accepting a connection. With backpressure, waits until the server
+    /// is below its limit and reserves a slot for the next connection.
+    pub async fn ready(&self) -> Option<OwnedSemaphorePermit> {
+        let mode = self.config.as_ref().map(|config| config.mode);
+        match (&self.semaphore, mode) {
+            (Some(semaphore), Some(ConnectionLimitMode::Backpressure)) => {
+                // The semaphore is never closed.
+                semaphore.clone().acquire_owned().await.ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Called after accepting a connection, with the slot reserved by `ready`. Returns
+    /// None if the connection should be rejected.
+    pub fn admit(
+        &self,
+        reserved: Option<OwnedSemaphorePermit>,
+        metrics: Option<Arc<ListenerMetrics>>,
+    ) -> Option<ConnectionPermit> {
+        let permit = match (&self.semaphore, reserved) {
+            (_, Some(permit)) => Some(permit),
+            (Some(semaphore), None) => match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    if let Some(metrics) = &metrics {
+                        metrics.connections_rejected.inc();
+                    }
+                    return None;
+                }
+            },
+            (None, None) => None,
+        };
+        if let Some(metrics) = &metrics {
+            metrics.active_connections.inc();
+        }
+        Some(ConnectionPermit {
+            _permit: permit,
+            metrics,
+        })
+    }
+}
+
+/// Held by a connection until it closes.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    _permit: Option<OwnedSemaphorePermit>,
+    metrics: Option<Arc<ListenerMetrics>>,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.active_connections.dec();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max_connections: usize, mode: ConnectionLimitMode) -> ConnectionLimiter {
+        ConnectionLimiter::new(Some(ConnectionLimitConfig {
+            max_connections,
+            mode,
+        }))
+    }
+
+    #[tokio::test]
+    async fn test_unlimited() {
+        let limiter = ConnectionLimiter::new(None);
+        let metrics = Arc::new(ListenerMetrics::default());
+        let permits: Vec<_> = (0..100)
+            .map(|_| limiter.admit(None, Some(metrics.clone())).unwrap())
+            .collect();
+        assert!(limiter.ready().await.is_none());
+        assert_eq!(metrics.active_connections.get(), 100);
+        drop(permits);
+        assert_eq!(metrics.active_connections.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let limiter = limiter(1, ConnectionLimitMode::Reject);
+        let metrics = Arc::new(ListenerMetrics::default());
+
+        assert!(limiter.ready().await.is_none());
+        let permit = limiter.admit(None, Some(metrics.clone())).unwrap();
+        assert!(limiter.admit(None, Some(metrics.clone())).is_none());
+        assert_eq!(metrics.connections_rejected.get(), 1);
+        assert_eq!(metrics.active_connections.get(), 1);
+
+        drop(permit);
+        assert!(limiter.admit(None, Some(metrics.clone())).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_backpressure() {
+        let limiter = limiter(1, ConnectionLimitMode::Backpressure);
+        let reserved = limiter.ready().await;
+        assert!(reserved.is_some());
+        let permit = limiter.admit(reserved, None).unwrap();
+
+        // The next connection is not accepted until the first one closes.
+        let ready = limiter.ready();
+        tokio::pin!(ready);
+        assert!(futures::poll!(ready.as_mut()).is_pending());
+        drop(permit);
+        assert!(ready.await.is_some());
+    }
+}
@@ -6,42 +6,82 @@
 // - Read and write whenever there's a space
 // - Circular buffer
 // - Cooperative yielding via tokio's coop budget to prevent task starvation
+// - Buffers taken from a per-thread pool
+// - Optional byte counters for metrics
+// - Optional rate limiters
+// - Optional idle and half-close timeouts
+// - Switching to splice(2) on Linux once both streams are plain TCP, or to io_uring with
+//   the io-uring feature
 
 use futures::ready;
+use log::debug;
 use tokio::io::ReadBuf;
+use tokio::time::{Instant, Sleep};
 
 use std::future::Future;
 use std::io;
 use std::pin::Pin;
 use std::task::{Context, Poll};
+use std::time::Duration;
 
 use crate::async_stream::AsyncStream;
-use crate::util::allocate_vec;
+use crate::buffer_pool::{self, PooledBuffer};
+use crate::config::SessionTimeoutConfig;
+use crate::metrics::ByteCounter;
+use crate::rate_limit::{RateLimiter, Throttle};
 
-const DEFAULT_BUF_SIZE: usize = 16384;
+/// Limits how long a copy continues when the streams are idle or half closed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CopyTimeouts {
+    /// Ends the copy when no data was read in either direction for this long.
+    pub idle: Option<Duration>,
+    /// Ends the copy this long after one direction finished. Zero ends it as soon as
+    /// one direction finishes, and None waits until both directions finish.
+    pub half_close: Option<Duration>,
+}
+
+impl From<&SessionTimeoutConfig> for CopyTimeouts {
+    fn from(config: &SessionTimeoutConfig) -> Self {
+        Self {
+            idle: config.idle_secs.map(Duration::from_secs),
+            half_close: config.half_close_secs.map(Duration::from_secs),
+        }
+    }
+}
 
 #[derive(Debug)]
 struct CopyBuffer {
     read_done: bool,
     need_flush: bool,
     need_write_ping: bool,
+    /// Whether data was read since the idle timer last looked.
+    active: bool,
     start_index: usize,
     cache_length: usize,
     size: usize,
-    buf: Box<[u8]>,
+    buf: PooledBuffer,
+    counter: ByteCounter,
+    throttle: Throttle,
 }
 
 impl CopyBuffer {
-    pub fn new(size: usize, need_initial_flush: bool) -> Self {
-        let buf = allocate_vec(size);
+    pub fn new(
+        size: usize,
+        need_initial_flush: bool,
+        counter: ByteCounter,
+        limiter: RateLimiter,
+    ) -> Self {
         Self {
             read_done: false,
             need_flush: need_initial_flush,
             need_write_ping: false,
+            active: false,
             start_index: 0,
             cache_length: 0,
             size,
-            buf: buf.into_boxed_slice(),
+            buf: buffer_pool::take(size),
+            counter,
+            throttle: Throttle::new(limiter),
         }
     }
 
@@ -80,7 +120,12 @@ impl CopyBuffer {
                 let me = &mut *self;
                 let mut buf =
                     ReadBuf::new(&mut me.buf[unused_start_index..unused_end_index_exclusive]);
-                match reader.as_mut().poll_read(cx, &mut buf) {
+                // Don't read while the rate limit is exceeded.
+                let poll = match me.throttle.poll_ready(cx) {
+                    Poll::Ready(()) => reader.as_mut().poll_read(cx, &mut buf),
+                    Poll::Pending => Poll::Pending,
+                };
+                match poll {
                     Poll::Ready(val) => {
                         val?;
                         let n = buf.filled().len();
@@ -88,6 +133,8 @@ impl CopyBuffer {
                             self.read_done = true;
                         } else {
                             self.cache_length += n;
+                            self.active = true;
+                            self.throttle.consume(n);
                             coop.made_progress();
                         }
                     }
@@ -141,6 +188,7 @@ impl CopyBuffer {
                                 "write zero byte into writer",
                             )));
                         } else {
+                            self.counter.add(written);
                             self.cache_length -= written;
                             if self.cache_length == 0 {
                                 self.start_index = 0;
@@ -183,6 +231,16 @@ enum TransferState {
     Done,
 }
 
+/// How [`CopyBidirectional`] ended.
+enum CopyEnd {
+    /// Both directions finished, or a timeout ended the copy.
+    Finished,
+    /// Both streams are plain TCP and the buffers are empty, so the rest of the copy can
+    /// be spliced.
+    #[cfg(target_os = "linux")]
+    Splice,
+}
+
 struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     a: &'a mut A,
     b: &'a mut B,
@@ -191,6 +249,13 @@ struct CopyBidirectional<'a, A: ?Sized, B: ?Sized> {
     a_to_b: TransferState,
     b_to_a: TransferState,
     sleep_future: Option<Pin<Box<tokio::time::Sleep>>>,
+    timeouts: CopyTimeouts,
+    idle_sleep: Option<Pin<Box<Sleep>>>,
+    last_activity: Instant,
+    half_close_sleep: Option<Pin<Box<Sleep>>>,
+    /// Whether to end the copy with [`CopyEnd::Splice`] once possible.
+    #[cfg(target_os = "linux")]
+    splice: bool,
 }
 
 fn transfer_one_direction<A, B>(
@@ -227,7 +292,7 @@ where
     A: AsyncStream + ?Sized,
     B: AsyncStream + ?Sized,
 {
-    type Output = io::Result<()>;
+    type Output = io::Result<CopyEnd>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let CopyBidirectional {
@@ -238,8 +303,28 @@ where
             a_to_b,
             b_to_a,
             sleep_future,
+            timeouts,
+            idle_sleep,
+            last_activity,
+            half_close_sleep,
+            #[cfg(target_os = "linux")]
+            splice,
         } = &mut *self;
 
+        // VISION streams only become plain TCP after some data was copied, so this is
+        // checked on every poll.
+        #[cfg(target_os = "linux")]
+        if *splice
+            && matches!(a_to_b, TransferState::Running)
+            && matches!(b_to_a, TransferState::Running)
+            && a_buf.cache_length == 0
+            && b_buf.cache_length == 0
+            && a.as_tcp_stream().is_some()
+            && b.as_tcp_stream().is_some()
+        {
+            return Poll::Ready(Ok(CopyEnd::Splice));
+        }
+
         if let Some(sleep) = sleep_future {
             let ping_fired = sleep.as_mut().poll(cx).is_ready();
             if ping_fired {
@@ -257,10 +342,43 @@ where
         let b_to_a = transfer_one_direction(cx, b_to_a, &mut *b_buf, &mut *b, &mut *a);
 
         match (a_to_b, b_to_a) {
-            (Poll::Ready(Err(e)), _) | (_, Poll::Ready(Err(e))) => Poll::Ready(Err(e)),
-            (Poll::Ready(Ok(())), Poll::Ready(Ok(()))) => Poll::Ready(Ok(())),
-            _ => Poll::Pending,
+            (Poll::Ready(Err(e)), _) | (_, Poll::Ready(Err(e))) => return Poll::Ready(Err(e)),
+            (Poll::Ready(Ok(())), Poll::Ready(Ok(()))) => {
+                return Poll::Ready(Ok(CopyEnd::Finished));
+            }
+            (Poll::Ready(Ok(())), Poll::Pending) | (Poll::Pending, Poll::Ready(Ok(()))) => {
+                if let Some(timeout) = timeouts.half_close {
+                    if timeout.is_zero() {
+                        debug!("Ending copy, one direction finished");
+                        return Poll::Ready(Ok(CopyEnd::Finished));
+                    }
+                    let sleep = half_close_sleep
+                        .get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+                    if sleep.as_mut().poll(cx).is_ready() {
+                        debug!("Ending copy, half closed for {timeout:?}");
+                        return Poll::Ready(Ok(CopyEnd::Finished));
+                    }
+                }
+            }
+            (Poll::Pending, Poll::Pending) => {}
+        }
+
+        if let (Some(timeout), Some(sleep)) = (timeouts.idle, idle_sleep) {
+            // The timer is only moved when it fires, rather than on every read.
+            if std::mem::take(&mut a_buf.active) | std::mem::take(&mut b_buf.active) {
+                *last_activity = Instant::now();
+            }
+            while sleep.as_mut().poll(cx).is_ready() {
+                let deadline = *last_activity + timeout;
+                if deadline <= Instant::now() {
+                    debug!("Ending copy, idle for {timeout:?}");
+                    return Poll::Ready(Ok(CopyEnd::Finished));
+                }
+                sleep.as_mut().reset(deadline);
+            }
         }
+
+        Poll::Pending
     }
 }
 
@@ -306,8 +424,8 @@ where
         b,
         a_need_initial_flush,
         b_need_initial_flush,
-        DEFAULT_BUF_SIZE,
-        DEFAULT_BUF_SIZE,
+        buffer_pool::relay_buffer_size(),
+        buffer_pool::relay_buffer_size(),
     )
     .await
 }
@@ -324,6 +442,79 @@ pub async fn copy_bidirectional_with_sizes<A, B>(
     a_to_b_buf_size: usize,
     b_to_a_buf_size: usize,
 ) -> io::Result<()>
+where
+    A: AsyncStream + ?Sized,
+    B: AsyncStream + ?Sized,
+{
+    copy_bidirectional_impl(
+        a,
+        b,
+        a_need_initial_flush,
+        b_need_initial_flush,
+        a_to_b_buf_size,
+        b_to_a_buf_size,
+        ByteCounter::default(),
+        ByteCounter::default(),
+        RateLimiter::default(),
+        RateLimiter::default(),
+        CopyTimeouts::default(),
+    )
+    .await
+}
+
+/// Copies data in both directions between `a` and `b`, adding the number of bytes
+/// written to `b` to `a_to_b_counter`, and the number of bytes written to `a` to
+/// `b_to_a_counter`.
+///
+/// This method is the same as the [`copy_bidirectional()`], except that it also
+/// updates the given counters as data is copied, reads no faster than the given
+/// limiters allow, and ends early as the given timeouts allow.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_bidirectional_with_counters<A, B>(
+    a: &mut A,
+    b: &mut B,
+    a_need_initial_flush: bool,
+    b_need_initial_flush: bool,
+    a_to_b_counter: ByteCounter,
+    b_to_a_counter: ByteCounter,
+    a_to_b_limiter: RateLimiter,
+    b_to_a_limiter: RateLimiter,
+    timeouts: CopyTimeouts,
+) -> io::Result<()>
+where
+    A: AsyncStream + ?Sized,
+    B: AsyncStream + ?Sized,
+{
+    copy_bidirectional_impl(
+        a,
+        b,
+        a_need_initial_flush,
+        b_need_initial_flush,
+        buffer_pool::relay_buffer_size(),
+        buffer_pool::relay_buffer_size(),
+        a_to_b_counter,
+        b_to_a_counter,
+        a_to_b_limiter,
+        b_to_a_limiter,
+        timeouts,
+    )
+    .await
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn copy_bidirectional_impl<A, B>(
+    a: &mut A,
+    b: &mut B,
+    a_need_initial_flush: bool,
+    b_need_initial_flush: bool,
+    a_to_b_buf_size: usize,
+    b_to_a_buf_size: usize,
+    a_to_b_counter: ByteCounter,
+    b_to_a_counter: ByteCounter,
+    a_to_b_limiter: RateLimiter,
+    b_to_a_limiter: RateLimiter,
+    timeouts: CopyTimeouts,
+) -> io::Result<()>
 where
     A: AsyncStream + ?Sized,
     B: AsyncStream + ?Sized,
@@ -336,17 +527,138 @@ where
         None
     };
 
-    CopyBidirectional {
-        a,
-        b,
+    // Splicing bypasses the rate limiters and timeouts, so it's only used without them.
+    #[cfg(target_os = "linux")]
+    let splice = a_to_b_limiter.is_empty()
+        && b_to_a_limiter.is_empty()
+        && timeouts == CopyTimeouts::default();
+    #[cfg(target_os = "linux")]
+    let (a_to_b_splice_counter, b_to_a_splice_counter) =
+        (a_to_b_counter.clone(), b_to_a_counter.clone());
+
+    let end = CopyBidirectional {
+        a: &mut *a,
+        b: &mut *b,
         // this is correctly reversed - CopyBuffer will copy from a (reader) to b (writer) using
         // a_buf, which means that the need_flush signal is for the writer (b), and vice versa for
         // b_buf.
-        a_buf: CopyBuffer::new(a_to_b_buf_size, b_need_initial_flush),
-        b_buf: CopyBuffer::new(b_to_a_buf_size, a_need_initial_flush),
+        a_buf: CopyBuffer::new(
+            a_to_b_buf_size,
+            b_need_initial_flush,
+            a_to_b_counter,
+            a_to_b_limiter,
+        ),
+        b_buf: CopyBuffer::new(
+            b_to_a_buf_size,
+            a_need_initial_flush,
+            b_to_a_counter,
+            b_to_a_limiter,
+        ),
         a_to_b: TransferState::Running,
         b_to_a: TransferState::Running,
         sleep_future,
+        timeouts,
+        idle_sleep: timeouts
+            .idle
+            .map(|timeout| Box::pin(tokio::time::sleep(timeout))),
+        last_activity: Instant::now(),
+        half_close_sleep: None,
+        #[cfg(target_os = "linux")]
+        splice,
+    }
+    .await?;
+
+    match end {
+        CopyEnd::Finished => Ok(()),
+        #[cfg(target_os = "linux")]
+        CopyEnd::Splice => {
+            let (Some(a), Some(b)) = (a.as_tcp_stream(), b.as_tcp_stream()) else {
+                unreachable!("splice requires TCP streams");
+            };
+            #[cfg(feature = "io-uring")]
+            if crate::uring::is_available() {
+                debug!("Relaying copy between TCP streams on io_uring");
+                return crate::uring::relay_bidirectional(
+                    a,
+                    b,
+                    a_to_b_splice_counter,
+                    b_to_a_splice_counter,
+                )
+                .await;
+            }
+            debug!("Splicing copy between TCP streams");
+            crate::splice::splice_bidirectional(a, b, a_to_b_splice_counter, b_to_a_splice_counter)
+                .await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    async fn copy_with_timeouts(
+        a: &mut TcpStream,
+        b: &mut TcpStream,
+        timeouts: CopyTimeouts,
+    ) -> Result<io::Result<()>, tokio::time::error::Elapsed> {
+        tokio::time::timeout(
+            Duration::from_millis(500),
+            copy_bidirectional_with_counters(
+                a,
+                b,
+                false,
+                false,
+                ByteCounter::default(),
+                ByteCounter::default(),
+                RateLimiter::default(),
+                RateLimiter::default(),
+                timeouts,
+            ),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let (mut a, _a_peer) = tcp_pair().await;
+        let (mut b, _b_peer) = tcp_pair().await;
+        let result = copy_with_timeouts(&mut a, &mut b, CopyTimeouts::default()).await;
+        assert!(result.is_err());
+
+        let timeouts = CopyTimeouts {
+            idle: Some(Duration::from_millis(50)),
+            half_close: None,
+        };
+        let result = copy_with_timeouts(&mut a, &mut b, timeouts).await;
+        assert!(matches!(result, Ok(Ok(()))));
+    }
+
+    #[tokio::test]
+    async fn test_half_close_timeout() {
+        let (mut a, mut a_peer) = tcp_pair().await;
+        let (mut b, _b_peer) = tcp_pair().await;
+        a_peer.shutdown().await.unwrap();
+        let result = copy_with_timeouts(&mut a, &mut b, CopyTimeouts::default()).await;
+        assert!(result.is_err());
+
+        let (mut a, mut a_peer) = tcp_pair().await;
+        let (mut b, _b_peer) = tcp_pair().await;
+        a_peer.shutdown().await.unwrap();
+        let timeouts = CopyTimeouts {
+            idle: None,
+            half_close: Some(Duration::ZERO),
+        };
+        let result = copy_with_timeouts(&mut a, &mut b, timeouts).await;
+        assert!(matches!(result, Ok(Ok(()))));
     }
-    .await
 }
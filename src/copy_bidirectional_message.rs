@@ -8,7 +8,9 @@ use std::task::{Context, Poll};
 use std::time::Instant;
 
 use crate::async_stream::AsyncMessageStream;
-use crate::util::allocate_vec;
+use crate::buffer_pool::{self, PooledBuffer};
+use crate::metrics::ByteCounter;
+use crate::rate_limit::{RateLimiter, Throttle};
 
 // Informed by https://stackoverflow.com/questions/14856639/udp-hole-punching-timeout
 pub const DEFAULT_ASSOCIATION_TIMEOUT_SECS: u32 = 200;
@@ -18,19 +20,23 @@ struct CopyBuffer {
     need_flush: bool,
     need_write_ping: bool,
     cache_length: usize,
-    buf: Box<[u8]>,
+    buf: PooledBuffer,
     read_count: usize,
+    counter: ByteCounter,
+    throttle: Throttle,
 }
 
 impl CopyBuffer {
-    pub fn new(need_flush: bool) -> Self {
+    pub fn new(need_flush: bool, counter: ByteCounter, limiter: RateLimiter) -> Self {
         Self {
             read_done: false,
             need_flush,
             need_write_ping: false,
             cache_length: 0,
-            buf: allocate_vec(65535).into_boxed_slice(),
+            buf: buffer_pool::take(65535),
             read_count: 0,
+            counter,
+            throttle: Throttle::new(limiter),
         }
     }
 
@@ -56,7 +62,12 @@ impl CopyBuffer {
             if !self.read_done && self.cache_length == 0 {
                 let me = &mut *self;
                 let mut buf = ReadBuf::new(&mut me.buf);
-                match reader.as_mut().poll_read_message(cx, &mut buf) {
+                // Don't read while the rate limit is exceeded.
+                let poll = match me.throttle.poll_ready(cx) {
+                    Poll::Ready(()) => reader.as_mut().poll_read_message(cx, &mut buf),
+                    Poll::Pending => Poll::Pending,
+                };
+                match poll {
                     Poll::Ready(val) => {
                         val?;
                         let n = buf.filled().len();
@@ -66,6 +77,7 @@ impl CopyBuffer {
                             self.cache_length = n;
                             did_read = true;
                             self.read_count = self.read_count.wrapping_add(n);
+                            self.throttle.consume(n);
                             coop.made_progress();
                         }
                     }
@@ -83,6 +95,7 @@ impl CopyBuffer {
                 {
                     Poll::Ready(val) => {
                         val?;
+                        self.counter.add(self.cache_length);
                         self.cache_length = 0;
                         self.need_flush = true;
                         // Don't bother writing ping, since we just wrote.
@@ -261,11 +274,20 @@ where
 /// # Return value
 ///
 /// Returns a tuple of bytes copied `a` to `b` and bytes copied `b` to `a`.
+///
+/// The size of the messages written to `b` is added to `a_to_b_counter`, and the
+/// size of the messages written to `a` is added to `b_to_a_counter`. Messages are
+/// read no faster than `a_to_b_limiter` and `b_to_a_limiter` allow.
+#[allow(clippy::too_many_arguments)]
 pub async fn copy_bidirectional_message<A, B>(
     a: &mut A,
     b: &mut B,
     a_initial_flush: bool,
     b_initial_flush: bool,
+    a_to_b_counter: ByteCounter,
+    b_to_a_counter: ByteCounter,
+    a_to_b_limiter: RateLimiter,
+    b_to_a_limiter: RateLimiter,
 ) -> Result<(), std::io::Error>
 where
     A: AsyncMessageStream + ?Sized,
@@ -281,8 +303,8 @@ where
         // this is correctly reversed - CopyBuffer will copy from a (reader) to b (writer) using
         // a_buf, which means that the need_flush signal is for the writer (b), and vice versa for
         // b_buf.
-        a_buf: CopyBuffer::new(b_initial_flush),
-        b_buf: CopyBuffer::new(a_initial_flush),
+        a_buf: CopyBuffer::new(b_initial_flush, a_to_b_counter, a_to_b_limiter),
+        b_buf: CopyBuffer::new(a_initial_flush, b_to_a_counter, b_to_a_limiter),
         a_to_b: TransferState::Running,
         b_to_a: TransferState::Running,
         sleep_future,
@@ -0,0 +1,135 @@
+//! Crypto provider of TLS connections, and a report of the CPU's crypto extensions.
+//!
+//! TLS and QUIC client and server configs, including those of encrypted DNS, are built
+//! with the rustls provider set by a top-level `crypto_provider` entry. aws-lc-rs is the
+//! default and is always built, and ring is available when built with the `ring` feature.
+//! The two differ most on ARM boards without the AES extensions, where their software AES
+//! implementations differ a lot in speed. The ciphers of Shadowsocks, VMess and REALITY
+//! always use aws-lc-rs.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock};
+
+use log::{info, warn};
+use parking_lot::RwLock;
+use rustls::crypto::CryptoProvider;
+
+use crate::config::{CryptoConfig, CryptoProviderKind};
+
+static PROVIDER: LazyLock<RwLock<(CryptoProviderKind, Arc<CryptoProvider>)>> =
+    LazyLock::new(|| {
+        let kind = CryptoProviderKind::default();
+        RwLock::new((kind, Arc::new(new_provider(kind))))
+    });
+
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+fn new_provider(kind: CryptoProviderKind) -> CryptoProvider {
+    match kind {
+        CryptoProviderKind::AwsLcRs => rustls::crypto::aws_lc_rs::default_provider(),
+        #[cfg(feature = "ring")]
+        CryptoProviderKind::Ring => rustls::crypto::ring::default_provider(),
+        #[cfg(not(feature = "ring"))]
+        CryptoProviderKind::Ring => {
+            unreachable!("validate_config rejects ring without the feature")
+        }
+    }
+}
+
+/// Returns the configured crypto provider.
+pub fn get() -> Arc<CryptoProvider> {
+    PROVIDER.read().1.clone()
+}
+
+/// Sets the crypto provider of TLS configs built from now on. Connections that already
+/// started keep their provider.
+pub fn configure(config: Option<CryptoConfig>) {
+    let kind = config
+        .map(|config| config.crypto_provider)
+        .unwrap_or_default();
+    let first = !CONFIGURED.swap(true, Ordering::Relaxed);
+    let mut provider = PROVIDER.write();
+    if provider.0 != kind {
+        *provider = (kind, Arc::new(new_provider(kind)));
+    } else if !first {
+        return;
+    }
+    info!("Using the {kind} crypto provider for TLS");
+}
+
+pub fn validate_config(config: &CryptoConfig) -> std::io::Result<()> {
+    if config.crypto_provider == CryptoProviderKind::Ring && cfg!(not(feature = "ring")) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid crypto config: the ring crypto provider requires building with the ring \
+             feature",
+        ));
+    }
+    Ok(())
+}
+
+/// Logs the crypto extensions of the CPU that AES-GCM implementations use when available.
+pub fn log_cpu_extensions() {
+    let extensions = cpu_crypto_extensions();
+    if extensions.is_empty() {
+        warn!(
+            "No AES CPU extensions detected, AES-GCM runs in software and ChaCha20-Poly1305 \
+             ciphers are likely faster"
+        );
+    } else {
+        info!("Using CPU crypto extensions: {}", extensions.join(", "));
+    }
+}
+
+fn cpu_crypto_extensions() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut extensions = Vec::new();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if std::arch::is_x86_feature_detected!("aes") {
+            extensions.push("AES-NI");
+        }
+        if std::arch::is_x86_feature_detected!("pclmulqdq") {
+            extensions.push("PCLMULQDQ");
+        }
+        if std::arch::is_x86_feature_detected!("avx2") {
+            extensions.push("AVX2");
+        }
+        if std::arch::is_x86_feature_detected!("sha") {
+            extensions.push("SHA");
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            extensions.push("NEON");
+        }
+        if std::arch::is_aarch64_feature_detected!("aes") {
+            extensions.push("AES");
+        }
+        if std::arch::is_aarch64_feature_detected!("pmull") {
+            extensions.push("PMULL");
+        }
+        if std::arch::is_aarch64_feature_detected!("sha2") {
+            extensions.push("SHA2");
+        }
+    }
+    extensions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_config() {
+        let config = CryptoConfig {
+            crypto_provider: CryptoProviderKind::AwsLcRs,
+        };
+        assert!(validate_config(&config).is_ok());
+        let config = CryptoConfig {
+            crypto_provider: CryptoProviderKind::Ring,
+        };
+        assert_eq!(validate_config(&config).is_ok(), cfg!(feature = "ring"));
+    }
+}
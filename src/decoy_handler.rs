@@ -0,0 +1,205 @@
+//! Decoy website for connections that fail protocol authentication.
+//!
+//! Instead of closing the connection, which tells an active prober that something other
+//! than a web server is listening, the connection is answered by a website: static files
+//! from a local directory, or a local web server that the connection is forwarded to.
+//! The data that was read before authentication failed is replayed to the decoy, so it
+//! sees the connection from the start.
+
+use std::convert::Infallible;
+use std::io;
+use std::path::{Component, Path};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Empty, Full, combinators::BoxBody};
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use log::debug;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+use crate::async_stream::AsyncStream;
+use crate::config::DecoyConfig;
+use crate::copy_bidirectional::copy_bidirectional;
+use crate::http2_handler::PrefixedStream;
+use crate::resolver::{Resolver, resolve_single_address};
+use crate::tcp::tcp_handler::TcpServerSetupResult;
+
+#[derive(Debug)]
+pub struct DecoyHandler {
+    config: DecoyConfig,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl DecoyHandler {
+    pub fn new(config: DecoyConfig, resolver: Arc<dyn Resolver>) -> Self {
+        Self { config, resolver }
+    }
+
+    /// Hands the connection to the decoy, after replaying `initial_data` that was already
+    /// read from it.
+    pub async fn serve(
+        &self,
+        server_stream: Box<dyn AsyncStream>,
+        initial_data: &[u8],
+    ) -> io::Result<TcpServerSetupResult> {
+        let server_stream: Box<dyn AsyncStream> = if initial_data.is_empty() {
+            server_stream
+        } else {
+            Box::new(PrefixedStream::new(
+                initial_data.to_vec().into_boxed_slice(),
+                server_stream,
+            ))
+        };
+
+        match self.config {
+            // Static files are served over HTTP/1.1.
+            DecoyConfig::Directory(ref base_path) => {
+                let base_path = base_path.clone();
+                tokio::spawn(async move {
+                    let service = hyper::service::service_fn(move |req| {
+                        let base_path = base_path.clone();
+                        async move { static_file_service(req, &base_path).await }
+                    });
+                    let result = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(server_stream), service)
+                        .await;
+                    if let Err(e) = result {
+                        debug!("Decoy website connection ended: {e}");
+                    }
+                });
+            }
+            // The connection is forwarded as is.
+            DecoyConfig::Address(ref location) => {
+                let dest_addr = resolve_single_address(&self.resolver, location).await?;
+                let mut dest_stream = TcpStream::connect(dest_addr).await?;
+                let mut server_stream = server_stream;
+                tokio::spawn(async move {
+                    let result =
+                        copy_bidirectional(&mut *server_stream, &mut dest_stream, false, false)
+                            .await;
+                    let _ = server_stream.shutdown().await;
+                    let _ = dest_stream.shutdown().await;
+                    if let Err(e) = result {
+                        debug!("Decoy forward ended: {e}");
+                    }
+                });
+            }
+        }
+
+        Ok(TcpServerSetupResult::AlreadyHandled)
+    }
+}
+
+fn empty_body() -> BoxBody<Bytes, io::Error> {
+    Empty::<Bytes>::new()
+        .map_err(|never| match never {})
+        .boxed()
+}
+
+fn full_body(data: Bytes) -> BoxBody<Bytes, io::Error> {
+    Full::new(data).map_err(|never| match never {}).boxed()
+}
+
+fn status_response(status: StatusCode) -> Response<BoxBody<Bytes, io::Error>> {
+    Response::builder()
+        .status(status)
+        .body(empty_body())
+        .unwrap()
+}
+
+async fn static_file_service(
+    req: Request<Incoming>,
+    base_path: &Path,
+) -> Result<Response<BoxBody<Bytes, io::Error>>, Infallible> {
+    match *req.method() {
+        Method::GET | Method::HEAD => {
+            let is_head = req.method() == Method::HEAD;
+            Ok(serve_static_file(base_path, req.uri().path(), is_head).await)
+        }
+        Method::OPTIONS => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("allow", "GET, HEAD, OPTIONS")
+            .body(empty_body())
+            .unwrap()),
+        _ => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .header("allow", "GET, HEAD, OPTIONS")
+            .body(empty_body())
+            .unwrap()),
+    }
+}
+
+/// Serves the file at `uri_path` under `base_path`, or the `index.html` of a directory.
+pub async fn serve_static_file(
+    base_path: &Path,
+    uri_path: &str,
+    is_head: bool,
+) -> Response<BoxBody<Bytes, io::Error>> {
+    // Sanitize path to prevent directory traversal
+    let request_path = uri_path.trim_start_matches('/');
+    let mut file_path = base_path.to_path_buf();
+
+    for component in Path::new(request_path).components() {
+        match component {
+            Component::Normal(c) => file_path.push(c),
+            Component::ParentDir => return status_response(StatusCode::FORBIDDEN),
+            _ => {}
+        }
+    }
+
+    if file_path.is_dir() {
+        file_path.push("index.html");
+    }
+
+    match tokio::fs::read(&file_path).await {
+        Ok(contents) => {
+            let mime = mime_guess::from_path(&file_path)
+                .first_or_octet_stream()
+                .to_string();
+            let content_length = contents.len();
+
+            let body = if is_head {
+                empty_body()
+            } else {
+                full_body(Bytes::from(contents))
+            };
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("content-type", mime)
+                .header("content-length", content_length)
+                .body(body)
+                .unwrap()
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => status_response(StatusCode::NOT_FOUND),
+        Err(_) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_serve_static_file() {
+        let base_path = std::env::temp_dir().join(format!("shoes-decoy-{}", std::process::id()));
+        std::fs::create_dir_all(&base_path).unwrap();
+        std::fs::write(base_path.join("index.html"), b"<html></html>").unwrap();
+
+        let response = serve_static_file(&base_path, "/", false).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["content-type"], "text/html");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(&body[..], b"<html></html>");
+
+        let response = serve_static_file(&base_path, "/missing.css", false).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = serve_static_file(&base_path, "/../etc/passwd", false).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        std::fs::remove_dir_all(&base_path).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+//! Dispatches connections by their first HTTP request, like Xray fallbacks.
+//!
+//! The head of the first request is read and matched against the path and Host of each
+//! target. The chosen handler reads the connection from the start, including the bytes
+//! read here, so it sees the same request. Connections that don't start with an HTTP/1
+//! request, such as Trojan or VLESS, go to the fallback handler.
+
+use async_trait::async_trait;
+use tokio::io::AsyncReadExt;
+
+use crate::async_stream::AsyncStream;
+use crate::http2_handler::PrefixedStream;
+use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+use crate::util::host_matches;
+
+/// Longest request head that is read to find the path and Host.
+const MAX_REQUEST_HEAD_LEN: usize = 8192;
+
+/// Methods that start an HTTP/1 request.
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET", b"POST", b"PUT", b"HEAD", b"DELETE", b"OPTIONS", b"PATCH", b"CONNECT", b"TRACE",
+];
+
+#[derive(Debug)]
+pub struct DispatchServerTarget {
+    pub matching_path: Option<String>,
+    pub matching_host: Option<String>,
+    pub handler: Box<dyn TcpServerHandler>,
+}
+
+#[derive(Debug)]
+pub struct DispatchServerHandler {
+    targets: Vec<DispatchServerTarget>,
+    fallback: Option<Box<dyn TcpServerHandler>>,
+}
+
+impl DispatchServerHandler {
+    pub fn new(
+        mut targets: Vec<DispatchServerTarget>,
+        fallback: Option<Box<dyn TcpServerHandler>>,
+    ) -> Self {
+        for target in targets.iter_mut() {
+            if let Some(ref mut path) = target.matching_path {
+                *path = path_without_query(path).to_string();
+            }
+        }
+        Self { targets, fallback }
+    }
+
+    fn find_handler(&self, request_head: Option<&RequestHead>) -> Option<&dyn TcpServerHandler> {
+        let target = request_head.and_then(|head| {
+            self.targets.iter().find(|target| {
+                target
+                    .matching_path
+                    .as_ref()
+                    .is_none_or(|path| path == path_without_query(&head.path))
+                    && target.matching_host.as_ref().is_none_or(|host| {
+                        head.host
+                            .as_ref()
+                            .is_some_and(|request_host| host_matches(host, request_host))
+                    })
+            })
+        });
+        match target {
+            Some(target) => Some(target.handler.as_ref()),
+            None => self.fallback.as_deref(),
+        }
+    }
+}
+
+fn path_without_query(path: &str) -> &str {
+    path.split_once('?').map_or(path, |(path, _)| path)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct RequestHead {
+    pub path: String,
+    pub host: Option<String>,
+}
+
+pub enum ParseResult {
+    /// The data doesn't start with an HTTP/1 request.
+    NotHttp,
+    /// More data is needed to parse the request head.
+    Incomplete,
+    Complete(RequestHead),
+}
+
+/// Parses the request line and Host header of the request at the start of `data`.
+pub fn parse_request_head(data: &[u8]) -> ParseResult {
+    let method_len = match data.iter().position(|&b| b == b' ') {
+        Some(len) => len,
+        None => {
+            // Wait for the method to be complete if it could still be one.
+            let could_be_method = HTTP_METHODS.iter().any(|method| method.starts_with(data));
+            return if could_be_method {
+                ParseResult::Incomplete
+            } else {
+                ParseResult::NotHttp
+            };
+        }
+    };
+    if !HTTP_METHODS.contains(&&data[..method_len]) {
+        return ParseResult::NotHttp;
+    }
+
+    let Some(head_len) = memchr::memmem::find(data, b"\r\n\r\n") else {
+        return ParseResult::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&data[..head_len]) else {
+        return ParseResult::NotHttp;
+    };
+
+    let mut lines = head.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(_), Some(path), Some(version), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return ParseResult::NotHttp;
+    };
+    if !version.starts_with("HTTP/1.") {
+        return ParseResult::NotHttp;
+    }
+
+    let host = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then(|| value.trim().to_string())
+    });
+
+    ParseResult::Complete(RequestHead {
+        path: path.to_string(),
+        host,
+    })
+}
+
+/// Reads the head of the first request. Returns the bytes read and the parsed head, or
+/// None if the connection isn't HTTP/1.
+async fn read_request_head(
+    stream: &mut Box<dyn AsyncStream>,
+) -> std::io::Result<(Vec<u8>, Option<RequestHead>)> {
+    let mut data = Vec::with_capacity(1024);
+    loop {
+        let n = stream.read_buf(&mut data).await?;
+        if n == 0 {
+            if data.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed before any data",
+                ));
+            }
+            return Ok((data, None));
+        }
+        match parse_request_head(&data) {
+            ParseResult::NotHttp => return Ok((data, None)),
+            ParseResult::Complete(head) => return Ok((data, Some(head))),
+            ParseResult::Incomplete if data.len() >= MAX_REQUEST_HEAD_LEN => {
+                return Ok((data, None));
+            }
+            ParseResult::Incomplete => {}
+        }
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for DispatchServerHandler {
+    async fn setup_server_stream(
+        &self,
+        mut server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let (data, request_head) = read_request_head(&mut server_stream).await?;
+
+        let handler = self.find_handler(request_head.as_ref()).ok_or_else(|| {
+            std::io::Error::other(match request_head {
+                Some(head) => format!(
+                    "No matching dispatch target for path {} and host {:?}",
+                    head.path, head.host
+                ),
+                None => "No dispatch fallback for non-HTTP connection".to_string(),
+            })
+        })?;
+
+        // The handler reads the connection from the start.
+        let stream = Box::new(PrefixedStream::new(data.into_boxed_slice(), server_stream));
+        handler.setup_server_stream(stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn complete(data: &[u8]) -> Option<RequestHead> {
+        match parse_request_head(data) {
+            ParseResult::Complete(head) => Some(head),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_parse_request_head() {
+        assert_eq!(
+            complete(
+                b"GET /vless?ed=2048 HTTP/1.1\r\nHost: example.com\r\nUpgrade: websocket\r\n\r\n"
+            ),
+            Some(RequestHead {
+                path: "/vless?ed=2048".to_string(),
+                host: Some("example.com".to_string()),
+            })
+        );
+        assert_eq!(
+            complete(b"POST / HTTP/1.0\r\n\r\n"),
+            Some(RequestHead {
+                path: "/".to_string(),
+                host: None,
+            })
+        );
+        assert!(matches!(parse_request_head(b"GE"), ParseResult::Incomplete));
+        assert!(matches!(
+            parse_request_head(b"GET / HTTP/1.1\r\nHost: a"),
+            ParseResult::Incomplete
+        ));
+        assert!(matches!(
+            parse_request_head(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"),
+            ParseResult::NotHttp
+        ));
+        assert!(matches!(
+            parse_request_head(b"0123456789abcdef\r\n"),
+            ParseResult::NotHttp
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_read_request_head_keeps_data() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let request = b"GET /trojan HTTP/1.1\r\nHost: example.com\r\n\r\nbody";
+        tokio::io::AsyncWriteExt::write_all(&mut client, request)
+            .await
+            .unwrap();
+
+        let mut server: Box<dyn AsyncStream> = Box::new(server);
+        let (data, head) = read_request_head(&mut server).await.unwrap();
+        assert_eq!(data, request);
+        assert_eq!(head.unwrap().path, "/trojan");
+    }
+}
@@ -4,8 +4,11 @@ use std::sync::Arc;
 
 use rustc_hash::FxHashMap;
 
-use crate::config::{DnsConfig, ExpandedDnsGroup, ExpandedDnsSpec};
+use crate::config::{
+    DnsConfig, ExpandedDnsGroup, ExpandedDnsSpec, ServerConfig, ServerProxyConfig,
+};
 use crate::dns::composite_resolver::CompositeResolver;
+use crate::dns::domain_resolver::DomainResolver;
 use crate::dns::hickory_resolver::HickoryResolver;
 use crate::dns::parsed::{ParsedDnsServer, ParsedDnsServerEntry, ParsedDnsUrl};
 use crate::option_util::NoneOrSome;
@@ -58,6 +61,32 @@ impl DnsRegistry {
             None => self.get_or_create_default(),
         }
     }
+
+    /// Get resolver for a server config. For `dns` servers with per-domain upstreams,
+    /// lookups under those domains are sent to the upstream's group.
+    pub fn get_for_server_config(&mut self, config: &ServerConfig) -> Arc<dyn Resolver> {
+        let resolver = self.get_for_server(config.dns.as_ref());
+        let ServerProxyConfig::Dns { upstreams, .. } = &config.protocol else {
+            return resolver;
+        };
+        if upstreams.is_empty() {
+            return resolver;
+        }
+
+        let upstreams = upstreams
+            .iter()
+            .map(|upstream| {
+                let upstream_resolver = self
+                    .get_by_name(upstream.resolved_group())
+                    .expect("dns group should exist (validated)");
+                (
+                    upstream.domains.iter().cloned().collect(),
+                    upstream_resolver,
+                )
+            })
+            .collect();
+        Arc::new(DomainResolver::new(upstreams, resolver))
+    }
 }
 
 impl Default for DnsRegistry {
@@ -78,6 +107,7 @@ pub fn build_resolver(entries: Vec<ParsedDnsServerEntry>) -> std::io::Result<Arc
         let bootstrap = entry.bootstrap_resolver;
         let chain = entry.client_chain;
         let ip_strategy = entry.ip_strategy;
+        let tls = &entry.tls_verification;
 
         let resolver: Arc<dyn Resolver> = match entry.server {
             // System resolver uses NativeResolver (ignores chain_group, bootstrap, ip_strategy)
@@ -89,19 +119,48 @@ pub fn build_resolver(entries: Vec<ParsedDnsServerEntry>) -> std::io::Result<Arc
             ParsedDnsServer::Tcp { addr } => {
                 Arc::new(HickoryResolver::tcp(addr, chain, bootstrap, ip_strategy)?)
             }
-            ParsedDnsServer::Tls { addr, server_name } => {
-                Arc::new(HickoryResolver::tls(addr, server_name, chain, bootstrap, ip_strategy)?)
-            }
+            ParsedDnsServer::Tls { addr, server_name } => Arc::new(HickoryResolver::tls(
+                addr,
+                server_name,
+                chain,
+                bootstrap,
+                ip_strategy,
+                tls,
+            )?),
             ParsedDnsServer::Https {
                 addr,
                 server_name,
                 path,
-            } => Arc::new(HickoryResolver::https(addr, server_name, path, chain, bootstrap, ip_strategy)?),
+            } => Arc::new(HickoryResolver::https(
+                addr,
+                server_name,
+                path,
+                chain,
+                bootstrap,
+                ip_strategy,
+                tls,
+            )?),
             ParsedDnsServer::H3 {
                 addr,
                 server_name,
                 path,
-            } => Arc::new(HickoryResolver::h3(addr, server_name, path, chain, bootstrap, ip_strategy)?),
+            } => Arc::new(HickoryResolver::h3(
+                addr,
+                server_name,
+                path,
+                chain,
+                bootstrap,
+                ip_strategy,
+                tls,
+            )?),
+            ParsedDnsServer::Quic { addr, server_name } => Arc::new(HickoryResolver::quic(
+                addr,
+                server_name,
+                chain,
+                bootstrap,
+                ip_strategy,
+                tls,
+            )?),
         };
         resolvers.push(resolver);
     }
@@ -197,6 +256,7 @@ async fn build_entry_from_spec(
                     direct_chain,
                     native,
                     super::IpStrategy::default(),
+                    super::DnsTlsVerification::default(),
                 );
                 build_resolver(vec![bootstrap_entry])?
             }
@@ -229,5 +289,11 @@ async fn build_entry_from_spec(
         .to_parsed_server(resolved_ip)
         .map_err(|e| std::io::Error::other(e.to_string()))?;
 
-    Ok(ParsedDnsServerEntry::new(server, chain_group, bootstrap_resolver, spec.ip_strategy))
+    Ok(ParsedDnsServerEntry::new(
+        server,
+        chain_group,
+        bootstrap_resolver,
+        spec.ip_strategy,
+        spec.tls_verification.clone(),
+    ))
 }
@@ -0,0 +1,118 @@
+//! Resolver that sends lookups for specific domains to their own upstreams.
+
+use std::fmt::Debug;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use crate::address::{Address, NetLocation};
+use crate::resolver::Resolver;
+
+/// Resolver that uses the first upstream whose domains match the looked up hostname,
+/// and the default resolver otherwise.
+pub struct DomainResolver {
+    upstreams: Vec<DomainUpstream>,
+    default: Arc<dyn Resolver>,
+}
+
+struct DomainUpstream {
+    /// Lowercase domains without leading or trailing dots.
+    domains: Vec<String>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Debug for DomainResolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DomainResolver")
+            .field("upstreams", &self.upstreams.len())
+            .finish()
+    }
+}
+
+impl DomainResolver {
+    /// Creates a resolver from `(domains, resolver)` upstreams. Each domain also matches
+    /// its subdomains.
+    pub fn new(
+        upstreams: Vec<(Vec<String>, Arc<dyn Resolver>)>,
+        default: Arc<dyn Resolver>,
+    ) -> Self {
+        let upstreams = upstreams
+            .into_iter()
+            .map(|(domains, resolver)| DomainUpstream {
+                domains: domains
+                    .iter()
+                    .map(|domain| domain.trim_matches('.').to_ascii_lowercase())
+                    .collect(),
+                resolver,
+            })
+            .collect();
+        Self { upstreams, default }
+    }
+
+    fn resolver_for(&self, hostname: &str) -> &Arc<dyn Resolver> {
+        let hostname = hostname.trim_end_matches('.').to_ascii_lowercase();
+        self.upstreams
+            .iter()
+            .find(|upstream| {
+                upstream
+                    .domains
+                    .iter()
+                    .any(|domain| matches_domain(&hostname, domain))
+            })
+            .map(|upstream| &upstream.resolver)
+            .unwrap_or(&self.default)
+    }
+}
+
+fn matches_domain(hostname: &str, domain: &str) -> bool {
+    hostname
+        .strip_suffix(domain)
+        .is_some_and(|prefix| prefix.is_empty() || prefix.ends_with('.'))
+}
+
+impl Resolver for DomainResolver {
+    fn resolve_location(
+        &self,
+        location: &NetLocation,
+    ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+        match location.address() {
+            Address::Hostname(hostname) => self.resolver_for(hostname).resolve_location(location),
+            _ => self.default.resolve_location(location),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resolver::NativeResolver;
+
+    #[test]
+    fn test_matches_domain() {
+        assert!(matches_domain("example.com", "example.com"));
+        assert!(matches_domain("www.example.com", "example.com"));
+        assert!(!matches_domain("badexample.com", "example.com"));
+        assert!(!matches_domain("example.org", "example.com"));
+    }
+
+    #[test]
+    fn test_resolver_for() {
+        let default: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
+        let corp: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
+        let resolver = DomainResolver::new(
+            vec![(vec![".Corp.Example.com".to_string()], corp.clone())],
+            default.clone(),
+        );
+
+        assert!(Arc::ptr_eq(
+            resolver.resolver_for("host.corp.example.com."),
+            &corp
+        ));
+        assert!(Arc::ptr_eq(
+            resolver.resolver_for("HOST.CORP.EXAMPLE.COM"),
+            &corp
+        ));
+        assert!(Arc::ptr_eq(resolver.resolver_for("example.com"), &default));
+    }
+}
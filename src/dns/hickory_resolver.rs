@@ -12,7 +12,7 @@ use hickory_resolver::Resolver;
 
 use crate::address::NetLocation;
 use crate::client_proxy_chain::ClientChainGroup;
-use crate::dns::parsed::IpStrategy;
+use crate::dns::parsed::{DnsTlsVerification, IpStrategy};
 use crate::dns::proxy_runtime::ProxyRuntimeProvider;
 use crate::resolver::Resolver as ShoesResolver;
 
@@ -49,6 +49,7 @@ impl HickoryResolver {
             bootstrap,
             ip_strategy,
             format!("udp://{}", addr),
+            &DnsTlsVerification::default(),
         )
     }
 
@@ -68,6 +69,7 @@ impl HickoryResolver {
             bootstrap,
             ip_strategy,
             format!("tcp://{}", addr),
+            &DnsTlsVerification::default(),
         )
     }
 
@@ -78,6 +80,7 @@ impl HickoryResolver {
         chain_group: Arc<ClientChainGroup>,
         bootstrap: Arc<dyn ShoesResolver>,
         ip_strategy: IpStrategy,
+        tls_verification: &DnsTlsVerification,
     ) -> std::io::Result<Self> {
         let mut conn_config = ConnectionConfig::tls(server_name.clone());
         conn_config.port = addr.port();
@@ -88,6 +91,7 @@ impl HickoryResolver {
             bootstrap,
             ip_strategy,
             format!("tls://{}#{}", addr, server_name),
+            tls_verification,
         )
     }
 
@@ -99,6 +103,7 @@ impl HickoryResolver {
         chain_group: Arc<ClientChainGroup>,
         bootstrap: Arc<dyn ShoesResolver>,
         ip_strategy: IpStrategy,
+        tls_verification: &DnsTlsVerification,
     ) -> std::io::Result<Self> {
         let mut conn_config = ConnectionConfig::https(server_name.clone(), Some(path));
         conn_config.port = addr.port();
@@ -109,6 +114,7 @@ impl HickoryResolver {
             bootstrap,
             ip_strategy,
             format!("https://{}", server_name),
+            tls_verification,
         )
     }
 
@@ -120,6 +126,7 @@ impl HickoryResolver {
         chain_group: Arc<ClientChainGroup>,
         bootstrap: Arc<dyn ShoesResolver>,
         ip_strategy: IpStrategy,
+        tls_verification: &DnsTlsVerification,
     ) -> std::io::Result<Self> {
         // Cloudflare has a broken GREASE implementation.
         // See: https://github.com/hyperium/h3/issues/206
@@ -137,6 +144,33 @@ impl HickoryResolver {
             bootstrap,
             ip_strategy,
             format!("h3://{}", server_name),
+            tls_verification,
+        )
+    }
+
+    /// Create a DNS-over-QUIC resolver.
+    /// Note: QUIC uses the chain_group but only works with direct chains.
+    pub fn quic(
+        addr: SocketAddr,
+        server_name: Arc<str>,
+        chain_group: Arc<ClientChainGroup>,
+        bootstrap: Arc<dyn ShoesResolver>,
+        ip_strategy: IpStrategy,
+        tls_verification: &DnsTlsVerification,
+    ) -> std::io::Result<Self> {
+        let protocol = ProtocolConfig::Quic {
+            server_name: server_name.clone(),
+        };
+        let mut conn_config = ConnectionConfig::new(protocol);
+        conn_config.port = addr.port();
+        Self::build(
+            addr.ip(),
+            conn_config,
+            chain_group,
+            bootstrap,
+            ip_strategy,
+            format!("quic://{}#{}", addr, server_name),
+            tls_verification,
         )
     }
 
@@ -147,6 +181,7 @@ impl HickoryResolver {
         bootstrap: Arc<dyn ShoesResolver>,
         ip_strategy: IpStrategy,
         description: String,
+        tls_verification: &DnsTlsVerification,
     ) -> std::io::Result<Self> {
         let ns_config = NameServerConfig::new(ip, true, vec![conn_config]);
         let config = ResolverConfig::from_parts(None, vec![], vec![ns_config]);
@@ -154,7 +189,7 @@ impl HickoryResolver {
 
         let mut builder = Resolver::builder_with_config(config, provider);
         builder.options_mut().ip_strategy = ip_strategy.to_hickory();
-        let builder = builder.with_tls_config(crate::rustls_config_util::create_dns_client_config());
+        let builder = builder.with_tls_config(tls_verification.create_client_config());
         let resolver = builder
             .build()
             .map_err(|e| std::io::Error::other(format!("failed to build resolver: {e}")))?;
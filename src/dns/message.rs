@@ -0,0 +1,243 @@
+//! Minimal DNS wire format handling, for answering queries locally.
+//!
+//! Only single-question queries are supported, which is what stub resolvers send.
+
+use std::net::IpAddr;
+
+/// Record type of IPv4 address queries.
+pub const TYPE_A: u16 = 1;
+/// Record type of IPv6 address queries.
+pub const TYPE_AAAA: u16 = 28;
+
+const CLASS_IN: u16 = 1;
+const HEADER_LEN: usize = 12;
+const MAX_NAME_LEN: usize = 255;
+
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const OPCODE_MASK: u16 = 0x7800;
+const RCODE_NO_ERROR: u16 = 0;
+const RCODE_SERVER_FAILURE: u16 = 2;
+
+/// Maximum size of a DNS message over UDP without EDNS.
+const MAX_UDP_MESSAGE_LEN: usize = 512;
+
+/// A DNS query with a single question.
+#[derive(Debug)]
+pub struct DnsQuery<'a> {
+    packet: &'a [u8],
+    /// End of the question section in `packet`.
+    question_end: usize,
+    /// Queried name in lowercase, without the trailing dot.
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+impl<'a> DnsQuery<'a> {
+    pub fn parse(packet: &'a [u8]) -> std::io::Result<Self> {
+        if packet.len() < HEADER_LEN {
+            return Err(invalid("DNS message is too short"));
+        }
+        let flags = u16::from_be_bytes([packet[2], packet[3]]);
+        if flags & (FLAG_RESPONSE | OPCODE_MASK) != 0 {
+            return Err(invalid("DNS message is not a standard query"));
+        }
+        let question_count = u16::from_be_bytes([packet[4], packet[5]]);
+        if question_count != 1 {
+            return Err(invalid("DNS query must have exactly one question"));
+        }
+
+        let mut name = String::new();
+        let mut pos = HEADER_LEN;
+        loop {
+            let len = *packet
+                .get(pos)
+                .ok_or_else(|| invalid("DNS query is truncated"))? as usize;
+            pos += 1;
+            if len == 0 {
+                break;
+            }
+            // Compression pointers and extended label types are not used in questions.
+            if len > 63 {
+                return Err(invalid("invalid DNS label"));
+            }
+            let label = packet
+                .get(pos..pos + len)
+                .ok_or_else(|| invalid("DNS query is truncated"))?;
+            if !label.is_ascii() {
+                return Err(invalid("invalid DNS label"));
+            }
+            if !name.is_empty() {
+                name.push('.');
+            }
+            name.extend(label.iter().map(|b| b.to_ascii_lowercase() as char));
+            if name.len() > MAX_NAME_LEN {
+                return Err(invalid("DNS name is too long"));
+            }
+            pos += len;
+        }
+
+        let fields = packet
+            .get(pos..pos + 4)
+            .ok_or_else(|| invalid("DNS query is truncated"))?;
+        let qtype = u16::from_be_bytes([fields[0], fields[1]]);
+        let qclass = u16::from_be_bytes([fields[2], fields[3]]);
+
+        Ok(Self {
+            packet,
+            question_end: pos + 4,
+            name,
+            qtype,
+            qclass,
+        })
+    }
+
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes([self.packet[0], self.packet[1]])
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn qtype(&self) -> u16 {
+        self.qtype
+    }
+
+    pub fn is_class_in(&self) -> bool {
+        self.qclass == CLASS_IN
+    }
+
+    /// Builds a successful response with `addresses` as answers. Addresses that don't
+    /// match the queried type are skipped, as are addresses that don't fit in a 512 byte
+    /// message.
+    pub fn response(&self, addresses: &[IpAddr], ttl: u32) -> Vec<u8> {
+        let answer_len = match self.qtype {
+            TYPE_AAAA => 28,
+            _ => 16,
+        };
+        let max_answers = MAX_UDP_MESSAGE_LEN.saturating_sub(self.question_end) / answer_len;
+        let answers: Vec<&IpAddr> = addresses
+            .iter()
+            .filter(|ip| match ip {
+                IpAddr::V4(_) => self.qtype == TYPE_A,
+                IpAddr::V6(_) => self.qtype == TYPE_AAAA,
+            })
+            .take(max_answers)
+            .collect();
+
+        let mut response = self.header(RCODE_NO_ERROR, answers.len() as u16);
+        for ip in answers {
+            // Pointer to the name in the question.
+            response.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            response.extend_from_slice(&self.qtype.to_be_bytes());
+            response.extend_from_slice(&CLASS_IN.to_be_bytes());
+            response.extend_from_slice(&ttl.to_be_bytes());
+            match ip {
+                IpAddr::V4(ip) => {
+                    response.extend_from_slice(&4u16.to_be_bytes());
+                    response.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    response.extend_from_slice(&16u16.to_be_bytes());
+                    response.extend_from_slice(&ip.octets());
+                }
+            }
+        }
+
+        response
+    }
+
+    /// Builds a response indicating that the query could not be answered.
+    pub fn failure_response(&self) -> Vec<u8> {
+        self.header(RCODE_SERVER_FAILURE, 0)
+    }
+
+    /// Starts a response with the header and the question of the query.
+    fn header(&self, rcode: u16, answer_count: u16) -> Vec<u8> {
+        let question = &self.packet[HEADER_LEN..self.question_end];
+        let mut response = Vec::with_capacity(MAX_UDP_MESSAGE_LEN);
+
+        let query_flags = u16::from_be_bytes([self.packet[2], self.packet[3]]);
+        let flags = FLAG_RESPONSE
+            | (query_flags & FLAG_RECURSION_DESIRED)
+            | FLAG_RECURSION_AVAILABLE
+            | rcode;
+        response.extend_from_slice(&self.id().to_be_bytes());
+        response.extend_from_slice(&flags.to_be_bytes());
+        response.extend_from_slice(&1u16.to_be_bytes());
+        response.extend_from_slice(&answer_count.to_be_bytes());
+        response.extend_from_slice(&[0, 0, 0, 0]);
+        response.extend_from_slice(question);
+        response
+    }
+}
+
+fn invalid(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Query for "Example.com" A, with recursion desired and an EDNS OPT record.
+    const QUERY: &[u8] = &[
+        0xab, 0xcd, 0x01, 0x20, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x07, b'E', b'x',
+        b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, 0x00, 0x01, 0x00, 0x01, 0x00,
+        0x00, 0x29, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_parse_query() {
+        let query = DnsQuery::parse(QUERY).unwrap();
+        assert_eq!(query.id(), 0xabcd);
+        assert_eq!(query.name(), "example.com");
+        assert_eq!(query.qtype(), TYPE_A);
+        assert!(query.is_class_in());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        assert!(DnsQuery::parse(&QUERY[..10]).is_err());
+        assert!(DnsQuery::parse(&QUERY[..27]).is_err());
+
+        let mut response = QUERY.to_vec();
+        response[2] |= 0x80;
+        assert!(DnsQuery::parse(&response).is_err());
+
+        let mut pointer = QUERY.to_vec();
+        pointer[12] = 0xc0;
+        assert!(DnsQuery::parse(&pointer).is_err());
+    }
+
+    #[test]
+    fn test_response() {
+        let query = DnsQuery::parse(QUERY).unwrap();
+        let response = query.response(&["1.2.3.4".parse().unwrap(), "::1".parse().unwrap()], 60);
+
+        let mut expected = vec![
+            0xab, 0xcd, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00,
+        ];
+        expected.extend_from_slice(&QUERY[12..29]);
+        expected.extend_from_slice(&[
+            0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x04, 1, 2, 3, 4,
+        ]);
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn test_response_limits() {
+        let query = DnsQuery::parse(QUERY).unwrap();
+        let addresses: Vec<IpAddr> = (0..=255).map(|i| [10, 0, 0, i].into()).collect();
+        let response = query.response(&addresses, 60);
+        assert!(response.len() <= MAX_UDP_MESSAGE_LEN);
+        assert_eq!(u16::from_be_bytes([response[6], response[7]]), 30);
+
+        let failure = query.failure_response();
+        assert_eq!(&failure[..4], &[0xab, 0xcd, 0x81, 0x82]);
+        assert_eq!(&failure[12..], &QUERY[12..29]);
+    }
+}
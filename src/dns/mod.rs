@@ -4,17 +4,23 @@
 //! - System resolver (NativeResolver)
 //! - UDP DNS
 //! - TCP DNS
-//! - DNS-over-TLS (DoT) - requires `hickory-tls` feature
-//! - DNS-over-HTTPS (DoH) - requires `hickory-https` feature
+//! - DNS-over-TLS (DoT)
+//! - DNS-over-HTTPS (DoH)
+//! - DNS-over-HTTP/3
+//! - DNS-over-QUIC (DoQ)
 //!
 //! TCP-based protocols (tcp://, tls://, https://) support routing through
-//! proxy chains via the ProxyRuntimeProvider.
+//! proxy chains via the ProxyRuntimeProvider, so that queries don't leak to the
+//! local network. hickory keeps the connection to each name server open, and
+//! DoH multiplexes concurrent queries over it.
 
 mod builder;
 mod composite_resolver;
+mod domain_resolver;
 mod hickory_resolver;
+pub mod message;
 mod parsed;
 mod proxy_runtime;
 
 pub use builder::build_dns_registry;
-pub use parsed::{IpStrategy, ParsedDnsUrl};
+pub use parsed::{DnsTlsVerification, IpStrategy, ParsedDnsUrl};
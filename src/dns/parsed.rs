@@ -40,6 +40,34 @@ impl IpStrategy {
     }
 }
 
+/// Server certificate verification for TLS-based DNS servers (tls, https, h3, quic).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsTlsVerification {
+    /// Verify the certificate chain and hostname against the webpki root CAs.
+    pub verify: bool,
+    /// SHA256 certificate fingerprints, one of which the server certificate must match.
+    pub server_fingerprints: Vec<String>,
+}
+
+impl DnsTlsVerification {
+    /// Create the rustls ClientConfig used by hickory for TLS connections.
+    pub fn create_client_config(&self) -> rustls::ClientConfig {
+        crate::rustls_config_util::create_dns_client_config(
+            self.verify,
+            self.server_fingerprints.clone(),
+        )
+    }
+}
+
+impl Default for DnsTlsVerification {
+    fn default() -> Self {
+        Self {
+            verify: true,
+            server_fingerprints: vec![],
+        }
+    }
+}
+
 /// Parsed DNS server with chain group, bootstrap resolver, and IP strategy.
 #[derive(Debug)]
 pub struct ParsedDnsServerEntry {
@@ -50,21 +78,26 @@ pub struct ParsedDnsServerEntry {
     pub bootstrap_resolver: Arc<dyn Resolver>,
     /// IP lookup strategy (IPv4/IPv6 selection).
     pub ip_strategy: IpStrategy,
+    /// Certificate verification, used by TLS-based protocols only.
+    pub tls_verification: DnsTlsVerification,
 }
 
 impl ParsedDnsServerEntry {
-    /// Create entry with the given chain group, bootstrap resolver, and IP strategy.
+    /// Create entry with the given chain group, bootstrap resolver, IP strategy and
+    /// certificate verification.
     pub fn new(
         server: ParsedDnsServer,
         chain: Arc<ClientChainGroup>,
         bootstrap: Arc<dyn Resolver>,
         ip_strategy: IpStrategy,
+        tls_verification: DnsTlsVerification,
     ) -> Self {
         Self {
             server,
             client_chain: chain,
             bootstrap_resolver: bootstrap,
             ip_strategy,
+            tls_verification,
         }
     }
 }
@@ -101,6 +134,12 @@ pub enum ParsedDnsServer {
         server_name: Arc<str>,
         path: Arc<str>,
     },
+
+    /// DNS-over-QUIC (RFC 9250).
+    Quic {
+        addr: SocketAddr,
+        server_name: Arc<str>,
+    },
 }
 
 /// Host parsed from a DNS URL - can be IP or hostname.
@@ -154,6 +193,13 @@ pub enum ParsedDnsUrl {
         server_name: String,
         path: String,
     },
+
+    /// DNS-over-QUIC.
+    Quic {
+        host: DnsHost,
+        port: u16,
+        server_name: String,
+    },
 }
 
 impl ParsedDnsUrl {
@@ -227,10 +273,27 @@ impl ParsedDnsUrl {
                     path,
                 })
             }
+            "quic" => {
+                let port = url.port().unwrap_or(853);
+                Ok(Self::Quic {
+                    host,
+                    port,
+                    server_name,
+                })
+            }
             _ => Err(DnsConfigError::UnsupportedScheme(scheme.to_string())),
         }
     }
 
+    /// Returns true if this protocol connects over TLS, so that certificate verification
+    /// options apply.
+    pub fn uses_tls(&self) -> bool {
+        matches!(
+            self,
+            Self::Tls { .. } | Self::Https { .. } | Self::H3 { .. } | Self::Quic { .. }
+        )
+    }
+
     /// Returns true if this URL contains a hostname (needs resolution).
     pub fn has_hostname(&self) -> bool {
         self.hostname().is_some()
@@ -244,7 +307,8 @@ impl ParsedDnsUrl {
             | Self::Tcp { host, .. }
             | Self::Tls { host, .. }
             | Self::Https { host, .. }
-            | Self::H3 { host, .. } => host.as_hostname(),
+            | Self::H3 { host, .. }
+            | Self::Quic { host, .. } => host.as_hostname(),
         }
     }
 
@@ -301,6 +365,17 @@ impl ParsedDnsUrl {
                     path: Arc::from(path.as_str()),
                 })
             }
+            Self::Quic {
+                host,
+                port,
+                server_name,
+            } => {
+                let ip = Self::get_ip(host, resolved_ip)?;
+                Ok(ParsedDnsServer::Quic {
+                    addr: SocketAddr::new(ip, *port),
+                    server_name: Arc::from(server_name.as_str()),
+                })
+            }
         }
     }
 
@@ -442,6 +517,28 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_https_hostname() {
+        let url = ParsedDnsUrl::parse("https://dns.google:8443/dns-query").unwrap();
+        assert!(url.has_hostname());
+        assert!(url.to_parsed_server(None).is_err());
+
+        let ip: IpAddr = "8.8.8.8".parse().unwrap();
+        match url.to_parsed_server(Some(ip)).unwrap() {
+            ParsedDnsServer::Https {
+                addr,
+                server_name,
+                path,
+            } => {
+                assert_eq!(addr.ip(), ip);
+                assert_eq!(addr.port(), 8443);
+                assert_eq!(&*server_name, "dns.google");
+                assert_eq!(&*path, "/dns-query");
+            }
+            _ => panic!("expected Https"),
+        }
+    }
+
     #[test]
     fn test_parse_h3_ip() {
         let url = ParsedDnsUrl::parse("h3://1.1.1.1/dns-query").unwrap();
@@ -481,6 +578,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_quic() {
+        let url = ParsedDnsUrl::parse("quic://dns.adguard-dns.com").unwrap();
+        assert!(url.has_hostname());
+        assert!(url.uses_tls());
+
+        let ip: IpAddr = "94.140.14.14".parse().unwrap();
+        match url.to_parsed_server(Some(ip)).unwrap() {
+            ParsedDnsServer::Quic { addr, server_name } => {
+                assert_eq!(addr.ip(), ip);
+                assert_eq!(addr.port(), 853);
+                assert_eq!(&*server_name, "dns.adguard-dns.com");
+            }
+            _ => panic!("expected Quic"),
+        }
+
+        let url = ParsedDnsUrl::parse("quic://1.1.1.1:8853").unwrap();
+        match url.to_parsed_server(None).unwrap() {
+            ParsedDnsServer::Quic { addr, .. } => assert_eq!(addr.port(), 8853),
+            _ => panic!("expected Quic"),
+        }
+
+        assert!(!ParsedDnsUrl::parse("udp://1.1.1.1").unwrap().uses_tls());
+    }
+
     #[test]
     fn test_parse_hostname() {
         let url = ParsedDnsUrl::parse("tls://dns.google").unwrap();
@@ -525,7 +647,7 @@ mod tests {
 
     #[test]
     fn test_parse_invalid_scheme() {
-        let result = ParsedDnsUrl::parse("quic://8.8.8.8");
+        let result = ParsedDnsUrl::parse("doq://8.8.8.8");
         assert!(result.is_err());
         assert!(matches!(
             result.unwrap_err(),
@@ -15,19 +15,20 @@ use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncStream;
 use crate::client_proxy_chain::ClientChainGroup;
 use crate::resolver::Resolver;
-use crate::socket_util::new_udp_socket;
+use crate::socket_util::{DialOptions, new_outbound_udp_socket};
 
 /// RuntimeProvider that routes TCP connections through a proxy chain.
-/// For direct-only chains, UDP and QUIC use the configured bind_interface.
+/// UDP and QUIC sockets are created by the dialer, with the socket options of
+/// direct-only chains.
 #[derive(Clone)]
 pub struct ProxyRuntimeProvider {
     chain_group: Arc<ClientChainGroup>,
     /// Resolver for proxy server hostnames (not the DNS queries themselves).
     /// Uses NativeResolver since we can't use the DNS server we're trying to reach.
     bootstrap_resolver: Arc<dyn Resolver>,
-    /// Bind interface for UDP/QUIC (from direct-only chain).
-    bind_interface: Option<String>,
-    /// QUIC socket binder that uses the bind_interface.
+    /// Socket options for UDP/QUIC (from direct-only chain).
+    dial_options: DialOptions,
+    /// QUIC socket binder that uses the socket options.
     quic_binder: ProxyQuicBinder,
 }
 
@@ -37,14 +38,14 @@ impl ProxyRuntimeProvider {
         chain_group: Arc<ClientChainGroup>,
         bootstrap_resolver: Arc<dyn Resolver>,
     ) -> Self {
-        let bind_interface = chain_group.get_bind_interface().map(ToString::to_string);
+        let dial_options = chain_group.get_dial_options().unwrap_or_default();
         let quic_binder = ProxyQuicBinder {
-            bind_interface: bind_interface.clone(),
+            dial_options: dial_options.clone(),
         };
         Self {
             chain_group,
             bootstrap_resolver,
-            bind_interface,
+            dial_options,
             quic_binder,
         }
     }
@@ -99,16 +100,11 @@ impl RuntimeProvider for ProxyRuntimeProvider {
         local_addr: SocketAddr,
         _server_addr: SocketAddr,
     ) -> Pin<Box<dyn Send + Future<Output = Result<Self::Udp, io::Error>>>> {
-        let bind_interface = self.bind_interface.clone();
+        let dial_options = self.dial_options.clone();
 
         Box::pin(async move {
-            if bind_interface.is_some() {
-                // Use our socket_util which supports bind_interface.
-                new_udp_socket(local_addr.is_ipv6(), bind_interface)
-            } else {
-                // Default: bind directly.
-                tokio::net::UdpSocket::bind(local_addr).await
-            }
+            let socket = new_outbound_udp_socket(local_addr.is_ipv6(), &dial_options, local_addr)?;
+            tokio::net::UdpSocket::from_std(socket.into())
         })
     }
 
@@ -117,10 +113,10 @@ impl RuntimeProvider for ProxyRuntimeProvider {
     }
 }
 
-/// QUIC socket binder that supports bind_interface.
+/// QUIC socket binder that creates sockets through the dialer.
 #[derive(Clone)]
 struct ProxyQuicBinder {
-    bind_interface: Option<String>,
+    dial_options: DialOptions,
 }
 
 impl QuicSocketBinder for ProxyQuicBinder {
@@ -129,35 +125,8 @@ impl QuicSocketBinder for ProxyQuicBinder {
         local_addr: SocketAddr,
         _server_addr: SocketAddr,
     ) -> Result<Arc<dyn quinn::AsyncUdpSocket>, io::Error> {
-        let socket = if self.bind_interface.is_some() {
-            // Use socket2 for bind_interface support.
-            let socket2_socket = crate::socket_util::new_socket2_udp_socket(
-                local_addr.is_ipv6(),
-                self.bind_interface.clone(),
-                Some(local_addr),
-                false,
-            )?;
-            // Convert socket2 -> std::net::UdpSocket.
-            #[cfg(unix)]
-            {
-                use std::os::unix::io::FromRawFd;
-                use std::os::unix::io::IntoRawFd;
-                let raw_fd = socket2_socket.into_raw_fd();
-                unsafe { std::net::UdpSocket::from_raw_fd(raw_fd) }
-            }
-            #[cfg(windows)]
-            {
-                use std::os::windows::io::FromRawSocket;
-                use std::os::windows::io::IntoRawSocket;
-                let raw_socket = socket2_socket.into_raw_socket();
-                unsafe { std::net::UdpSocket::from_raw_socket(raw_socket) }
-            }
-        } else {
-            // Default: bind directly.
-            std::net::UdpSocket::bind(local_addr)?
-        };
-
-        quinn::TokioRuntime.wrap_udp_socket(socket)
+        let socket = new_outbound_udp_socket(local_addr.is_ipv6(), &self.dial_options, local_addr)?;
+        quinn::TokioRuntime.wrap_udp_socket(socket.into())
     }
 }
 
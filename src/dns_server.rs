@@ -0,0 +1,331 @@
+//! Built-in DNS server.
+//!
+//! A and AAAA queries are answered with the server's resolver, or from the fake-IP ranges
+//! when `fake_ip` is enabled. Queries for other record types get an empty answer.
+//!
+//! Transparent listeners with `dns_hijack` answer the queries they intercept on port 53
+//! the same way, with a [`DnsHijacker`].
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::AsyncStream;
+use crate::config::{BindLocation, ServerConfig, ServerProxyConfig};
+use crate::dns::message::{DnsQuery, TYPE_A, TYPE_AAAA};
+use crate::fake_ip;
+use crate::resolver::Resolver;
+use crate::socket_util::new_socket2_udp_socket;
+use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+
+/// Destination port of the DNS queries that are hijacked.
+pub const DNS_PORT: u16 = 53;
+
+/// TTL of resolved answers.
+const ANSWER_TTL: u32 = 60;
+
+/// Idle time after which TCP connections are closed.
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Large enough for any UDP query, including EDNS queries.
+const UDP_BUFFER_LEN: usize = 65535;
+
+#[derive(Debug)]
+struct DnsServer {
+    resolver: Arc<dyn Resolver>,
+    fake_ip: bool,
+}
+
+impl DnsServer {
+    /// Returns the response to `packet`, or None if it's not a valid query.
+    async fn answer(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let query = match DnsQuery::parse(packet) {
+            Ok(query) => query,
+            Err(e) => {
+                debug!("Ignoring DNS message: {e}");
+                return None;
+            }
+        };
+
+        if self.fake_ip
+            && let Some(response) = fake_ip::answer_query(packet)
+        {
+            return Some(response);
+        }
+
+        if !query.is_class_in()
+            || !matches!(query.qtype(), TYPE_A | TYPE_AAAA)
+            || query.name().is_empty()
+        {
+            return Some(query.response(&[], ANSWER_TTL));
+        }
+
+        let location = NetLocation::new(Address::Hostname(query.name().to_string()), 0);
+        match self.resolver.resolve_location(&location).await {
+            Ok(addrs) => {
+                let ips: Vec<IpAddr> = addrs.iter().map(|addr| addr.ip()).collect();
+                Some(query.response(&ips, ANSWER_TTL))
+            }
+            Err(e) => {
+                debug!("Failed to resolve {}: {e}", query.name());
+                Some(query.failure_response())
+            }
+        }
+    }
+}
+
+/// Answers DNS queries that transparent listeners intercepted, instead of forwarding
+/// them. Address queries get fake IPs when fake-IP mode is enabled.
+#[derive(Debug, Clone)]
+pub struct DnsHijacker {
+    server: Arc<DnsServer>,
+}
+
+impl DnsHijacker {
+    pub fn new(resolver: Arc<dyn Resolver>) -> Self {
+        Self {
+            server: Arc::new(DnsServer {
+                resolver,
+                fake_ip: true,
+            }),
+        }
+    }
+
+    /// Returns the response to a UDP query, or None if it's not a valid query.
+    pub async fn answer(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        self.server.answer(packet).await
+    }
+
+    /// Answers the queries of a TCP connection until it's closed.
+    pub async fn serve_tcp<S>(&self, stream: S) -> std::io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        serve_tcp(stream, &self.server).await
+    }
+}
+
+/// Serves DNS over TCP, where each message is prefixed with its length.
+#[derive(Debug)]
+pub struct DnsTcpServerHandler {
+    server: Arc<DnsServer>,
+}
+
+impl DnsTcpServerHandler {
+    pub fn new(resolver: Arc<dyn Resolver>, fake_ip: bool) -> Self {
+        Self {
+            server: Arc::new(DnsServer { resolver, fake_ip }),
+        }
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for DnsTcpServerHandler {
+    async fn setup_server_stream(
+        &self,
+        server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let server = self.server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_tcp(server_stream, &server).await {
+                debug!("DNS TCP connection ended: {e}");
+            }
+        });
+        Ok(TcpServerSetupResult::AlreadyHandled)
+    }
+}
+
+async fn serve_tcp<S>(mut stream: S, server: &DnsServer) -> std::io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut query = vec![];
+    loop {
+        let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        };
+        query.resize(len, 0);
+        stream.read_exact(&mut query).await?;
+
+        let Some(response) = server.answer(&query).await else {
+            return Ok(());
+        };
+        let mut message = Vec::with_capacity(2 + response.len());
+        message.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        message.extend_from_slice(&response);
+        stream.write_all(&message).await?;
+        stream.flush().await?;
+    }
+}
+
+/// Starts UDP DNS servers on the addresses of the config.
+pub async fn start_dns_udp_servers(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let ServerConfig {
+        bind_location,
+        protocol,
+        ..
+    } = config;
+
+    let ServerProxyConfig::Dns { fake_ip, .. } = protocol else {
+        unreachable!("UDP servers of other protocols are started separately (validated)");
+    };
+
+    println!("Starting DNS UDP server at {}", &bind_location);
+
+    let bind_addresses = match bind_location {
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            bind_location.to_socket_addrs()?
+        }
+        BindLocation::Path(_) => {
+            return Err(std::io::Error::other(
+                "Cannot listen on path, UDP does not have unix domain socket support",
+            ));
+        }
+    };
+
+    let server = Arc::new(DnsServer { resolver, fake_ip });
+    let mut join_handles = Vec::with_capacity(bind_addresses.len());
+    for bind_address in bind_addresses {
+        let socket: std::net::UdpSocket =
+            new_socket2_udp_socket(bind_address.is_ipv6(), None, Some(bind_address), false)?.into();
+        let socket = Arc::new(tokio::net::UdpSocket::from_std(socket)?);
+        let server = server.clone();
+        join_handles.push(tokio::spawn(async move {
+            if let Err(e) = serve_udp(socket, server).await {
+                error!("DNS UDP server at {bind_address} stopped: {e}");
+            }
+        }));
+    }
+
+    Ok(join_handles)
+}
+
+async fn serve_udp(
+    socket: Arc<tokio::net::UdpSocket>,
+    server: Arc<DnsServer>,
+) -> std::io::Result<()> {
+    let mut buf = vec![0u8; UDP_BUFFER_LEN];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        let query = buf[..len].to_vec();
+        let socket = socket.clone();
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Some(response) = server.answer(&query).await
+                && let Err(e) = socket.send_to(&response, addr).await
+            {
+                debug!("Failed to send DNS response to {addr}: {e}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+
+    #[derive(Debug)]
+    struct StaticResolver;
+
+    impl Resolver for StaticResolver {
+        fn resolve_location(
+            &self,
+            location: &NetLocation,
+        ) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>> {
+            let result = match location.address().to_string().as_str() {
+                "example.com" => Ok(vec![
+                    SocketAddr::new([1, 2, 3, 4].into(), 0),
+                    SocketAddr::new("2001:db8::1".parse().unwrap(), 0),
+                ]),
+                _ => Err(std::io::Error::other("not found")),
+            };
+            Box::pin(std::future::ready(result))
+        }
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet
+    }
+
+    fn answer_count(response: &[u8]) -> u16 {
+        u16::from_be_bytes([response[6], response[7]])
+    }
+
+    fn rcode(response: &[u8]) -> u8 {
+        response[3] & 0x0f
+    }
+
+    #[tokio::test]
+    async fn test_answer() {
+        let server = DnsServer {
+            resolver: Arc::new(StaticResolver),
+            fake_ip: false,
+        };
+
+        let response = server.answer(&query("example.com", TYPE_A)).await.unwrap();
+        assert_eq!(rcode(&response), 0);
+        assert_eq!(answer_count(&response), 1);
+        assert_eq!(&response[response.len() - 4..], &[1, 2, 3, 4]);
+
+        let response = server
+            .answer(&query("example.com", TYPE_AAAA))
+            .await
+            .unwrap();
+        assert_eq!(answer_count(&response), 1);
+
+        // MX queries get an empty answer.
+        let response = server.answer(&query("example.com", 15)).await.unwrap();
+        assert_eq!(rcode(&response), 0);
+        assert_eq!(answer_count(&response), 0);
+
+        let response = server.answer(&query("missing.com", TYPE_A)).await.unwrap();
+        assert_eq!(rcode(&response), 2);
+
+        assert!(server.answer(&[0x12, 0x34]).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_hijacker_serve_tcp() {
+        let hijacker = DnsHijacker::new(Arc::new(StaticResolver));
+        let (mut client, server) = tokio::io::duplex(1024);
+        let task = tokio::spawn(async move { hijacker.serve_tcp(server).await });
+
+        // MX queries aren't answered from the fake-IP ranges, even if another test
+        // enabled fake-IP mode.
+        let query = query("example.com", 15);
+        client.write_u16(query.len() as u16).await.unwrap();
+        client.write_all(&query).await.unwrap();
+
+        let len = client.read_u16().await.unwrap() as usize;
+        let mut response = vec![0u8; len];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        assert_eq!(rcode(&response), 0);
+
+        drop(client);
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,410 @@
+//! Fake-IP DNS mode.
+//!
+//! A/AAAA queries answered by shoes get an address from a reserved range instead of the
+//! real address of the domain, and the domain is remembered for that address. When a
+//! connection is later made to the fake address, the routing layer maps it back to the
+//! domain, so that domain rules apply and the domain is resolved (or sent to the proxy)
+//! only once the route is known.
+//!
+//! The pool is configured with a top-level `fake_ip_range` entry. Mappings are kept
+//! across config reloads as long as the ranges don't change, since clients cache answers.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::{Arc, LazyLock};
+
+use lru::LruCache;
+use parking_lot::{Mutex, RwLock};
+
+use crate::address::{Address, NetLocation, ResolvedLocation};
+use crate::config::FakeIpConfig;
+use crate::dns::message::{DnsQuery, TYPE_A, TYPE_AAAA};
+
+/// Maximum number of domains mapped per address family. The least recently used mapping
+/// is dropped when a new domain needs an address.
+const MAX_MAPPINGS: usize = 65536;
+
+/// TTL of fake answers. Kept short so that clients query again, which refreshes the
+/// mapping, rather than connecting to an address whose mapping was dropped.
+const FAKE_IP_TTL: u32 = 1;
+
+/// The first addresses of a range are skipped, since the network address and the first
+/// host address (commonly the gateway) can't be handed out.
+const FIRST_OFFSET: u128 = 2;
+
+static POOL: LazyLock<RwLock<Option<Arc<FakeIpPool>>>> = LazyLock::new(|| RwLock::new(None));
+
+/// Sets the fake-IP ranges, or disables fake-IP mode. Existing mappings are kept if the
+/// ranges are unchanged.
+pub fn configure(config: Option<FakeIpConfig>) -> std::io::Result<()> {
+    let mut pool = POOL.write();
+    match config {
+        Some(config) => {
+            if pool.as_ref().is_none_or(|pool| pool.config != config) {
+                *pool = Some(Arc::new(FakeIpPool::new(config)?));
+            }
+        }
+        None => *pool = None,
+    }
+    Ok(())
+}
+
+/// Answers an A or AAAA query with a fake address. Returns None if fake-IP mode is
+/// disabled, or `packet` is not such a query.
+pub fn answer_query(packet: &[u8]) -> Option<Vec<u8>> {
+    let pool = POOL.read().clone()?;
+    pool.answer_query(packet)
+}
+
+/// Maps a destination in a fake-IP range back to the domain it was allocated for.
+///
+/// Returns an error if the address is in a fake-IP range but no domain is mapped to it,
+/// e.g. because the mapping was dropped or shoes was restarted.
+pub fn restore_location(location: ResolvedLocation) -> std::io::Result<ResolvedLocation> {
+    let ip = match location.location().address() {
+        Address::Ipv4(ip) => IpAddr::V4(*ip),
+        Address::Ipv6(ip) => IpAddr::V6(*ip),
+        Address::Hostname(_) => return Ok(location),
+    };
+    let Some(pool) = POOL.read().clone() else {
+        return Ok(location);
+    };
+    if !pool.contains(ip) {
+        return Ok(location);
+    }
+    match pool.lookup(ip) {
+        Some(domain) => Ok(ResolvedLocation::new(NetLocation::new(
+            Address::Hostname(domain.to_string()),
+            location.location().port(),
+        ))),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no domain is mapped to fake IP {ip}"),
+        )),
+    }
+}
+
+/// Whether `ip` is in a fake-IP range.
+pub fn is_fake_ip(ip: IpAddr) -> bool {
+    POOL.read().as_ref().is_some_and(|pool| pool.contains(ip))
+}
+
+/// Checks that `config` has valid ranges.
+pub fn validate_config(config: &FakeIpConfig) -> std::io::Result<()> {
+    FakeIpPool::new(config.clone()).map(|_| ())
+}
+
+/// Allocates fake addresses for domains, and maps them back.
+pub struct FakeIpPool {
+    config: FakeIpConfig,
+    ipv4: FamilyPool,
+    ipv6: Option<FamilyPool>,
+}
+
+impl FakeIpPool {
+    pub fn new(config: FakeIpConfig) -> std::io::Result<Self> {
+        let ipv4 = FakeIpRange::parse(&config.fake_ip_range, false)?;
+        let ipv6 = config
+            .fake_ip_range6
+            .as_deref()
+            .map(|range| FakeIpRange::parse(range, true))
+            .transpose()?;
+        Ok(Self {
+            config,
+            ipv4: FamilyPool::new(ipv4),
+            ipv6: ipv6.map(FamilyPool::new),
+        })
+    }
+
+    /// Answers an A or AAAA query with a fake address. AAAA queries get an empty answer
+    /// if no IPv6 range is configured.
+    pub fn answer_query(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let query = DnsQuery::parse(packet).ok()?;
+        if !query.is_class_in() || (query.qtype() != TYPE_A && query.qtype() != TYPE_AAAA) {
+            return None;
+        }
+        let answer = self.allocate(query.name(), query.qtype() == TYPE_AAAA);
+        Some(query.response(answer.as_slice(), FAKE_IP_TTL))
+    }
+
+    /// Returns the fake address for `domain`, allocating one if needed. Returns None for
+    /// IPv6 if no IPv6 range is configured.
+    pub fn allocate(&self, domain: &str, ipv6: bool) -> Option<IpAddr> {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        let pool = if ipv6 {
+            self.ipv6.as_ref()?
+        } else {
+            &self.ipv4
+        };
+        Some(pool.allocate(domain))
+    }
+
+    /// Returns whether `ip` is in one of the fake-IP ranges.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(_) => self.ipv4.range.contains(ip),
+            IpAddr::V6(_) => self
+                .ipv6
+                .as_ref()
+                .is_some_and(|pool| pool.range.contains(ip)),
+        }
+    }
+
+    /// Returns the domain that `ip` was allocated for.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Arc<str>> {
+        match ip {
+            IpAddr::V4(_) => self.ipv4.lookup(ip),
+            IpAddr::V6(_) => self.ipv6.as_ref()?.lookup(ip),
+        }
+    }
+}
+
+/// Mappings for one address family.
+struct FamilyPool {
+    range: FakeIpRange,
+    state: Mutex<FamilyState>,
+}
+
+struct FamilyState {
+    /// Offset of the next address to hand out.
+    next: u128,
+    by_domain: LruCache<Arc<str>, IpAddr>,
+    by_ip: HashMap<IpAddr, Arc<str>>,
+}
+
+impl FamilyPool {
+    fn new(range: FakeIpRange) -> Self {
+        let capacity = usize::try_from(range.usable_count())
+            .unwrap_or(usize::MAX)
+            .min(MAX_MAPPINGS);
+        Self {
+            range,
+            state: Mutex::new(FamilyState {
+                next: 0,
+                by_domain: LruCache::new(NonZeroUsize::new(capacity).unwrap()),
+                by_ip: HashMap::new(),
+            }),
+        }
+    }
+
+    fn allocate(&self, domain: String) -> IpAddr {
+        let mut state = self.state.lock();
+        if let Some(ip) = state.by_domain.get(domain.as_str()) {
+            return *ip;
+        }
+
+        if state.by_domain.len() == state.by_domain.cap().get()
+            && let Some((_, ip)) = state.by_domain.pop_lru()
+        {
+            state.by_ip.remove(&ip);
+        }
+
+        // Addresses are handed out in order, so that a dropped mapping's address is reused
+        // as late as possible. This terminates since at least one address is unmapped.
+        let ip = loop {
+            let ip = self.range.nth(state.next);
+            state.next = (state.next + 1) % self.range.usable_count();
+            if !state.by_ip.contains_key(&ip) {
+                break ip;
+            }
+        };
+
+        let domain: Arc<str> = domain.into();
+        state.by_domain.put(domain.clone(), ip);
+        state.by_ip.insert(ip, domain);
+        ip
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Arc<str>> {
+        let mut state = self.state.lock();
+        let domain = state.by_ip.get(&ip)?.clone();
+        state.by_domain.promote(&domain);
+        Some(domain)
+    }
+}
+
+/// A CIDR range, stored as integers so that IPv4 and IPv6 are handled alike.
+#[derive(Debug, Clone, Copy)]
+struct FakeIpRange {
+    network: u128,
+    size: u128,
+    is_ipv6: bool,
+}
+
+impl FakeIpRange {
+    fn parse(s: &str, is_ipv6: bool) -> std::io::Result<Self> {
+        let invalid = |reason: &str| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid fake IP range '{s}': {reason}"),
+            )
+        };
+
+        let (address, prefix_len) = s
+            .split_once('/')
+            .ok_or_else(|| invalid("expected CIDR notation, e.g. 198.18.0.0/15"))?;
+        let address: IpAddr = address.parse().map_err(|_| invalid("invalid address"))?;
+        let prefix_len: u32 = prefix_len
+            .parse()
+            .map_err(|_| invalid("invalid prefix length"))?;
+
+        let (address, bits) = match (address, is_ipv6) {
+            (IpAddr::V4(ip), false) => (u32::from(ip) as u128, 32),
+            (IpAddr::V6(ip), true) => (u128::from(ip), 128),
+            (_, false) => return Err(invalid("expected an IPv4 range")),
+            (_, true) => return Err(invalid("expected an IPv6 range")),
+        };
+        if prefix_len == 0 || prefix_len > bits {
+            return Err(invalid("invalid prefix length"));
+        }
+        if prefix_len > bits - 2 {
+            return Err(invalid("range must contain at least 4 addresses"));
+        }
+
+        let size = 1u128 << (bits - prefix_len);
+        Ok(Self {
+            network: address & !(size - 1),
+            size,
+            is_ipv6,
+        })
+    }
+
+    /// Number of addresses that can be handed out, excluding the first addresses and the
+    /// broadcast address.
+    fn usable_count(&self) -> u128 {
+        self.size - FIRST_OFFSET - 1
+    }
+
+    fn nth(&self, offset: u128) -> IpAddr {
+        let value = self.network + FIRST_OFFSET + offset;
+        if self.is_ipv6 {
+            IpAddr::V6(Ipv6Addr::from(value))
+        } else {
+            IpAddr::V4(Ipv4Addr::from(value as u32))
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let value = match (ip, self.is_ipv6) {
+            (IpAddr::V4(ip), false) => u32::from(ip) as u128,
+            (IpAddr::V6(ip), true) => u128::from(ip),
+            _ => return false,
+        };
+        value.wrapping_sub(self.network) < self.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pool(range: &str, range6: Option<&str>) -> FakeIpPool {
+        FakeIpPool::new(FakeIpConfig {
+            fake_ip_range: range.to_string(),
+            fake_ip_range6: range6.map(str::to_string),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_allocate_and_lookup() {
+        let pool = pool("198.18.0.0/15", Some("fc00::/18"));
+
+        let a = pool.allocate("example.com", false).unwrap();
+        assert_eq!(a, "198.18.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(pool.allocate("Example.COM.", false), Some(a));
+        let b = pool.allocate("example.org", false).unwrap();
+        assert_ne!(a, b);
+
+        let a6 = pool.allocate("example.com", true).unwrap();
+        assert_eq!(a6, "fc00::2".parse::<IpAddr>().unwrap());
+
+        assert_eq!(pool.lookup(a).as_deref(), Some("example.com"));
+        assert_eq!(pool.lookup(b).as_deref(), Some("example.org"));
+        assert_eq!(pool.lookup(a6).as_deref(), Some("example.com"));
+        assert_eq!(pool.lookup("198.18.1.1".parse().unwrap()), None);
+
+        assert!(pool.contains("198.19.255.255".parse().unwrap()));
+        assert!(!pool.contains("198.20.0.0".parse().unwrap()));
+        assert!(pool.contains("fc00::1".parse().unwrap()));
+        assert!(!pool.contains("fd00::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_ipv6_without_range() {
+        let pool = pool("198.18.0.0/15", None);
+        assert_eq!(pool.allocate("example.com", true), None);
+        assert!(!pool.contains("fc00::2".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_least_recently_used_mapping_is_reused() {
+        // A /29 has 8 addresses, of which 5 are handed out.
+        let pool = pool("10.0.0.0/29", None);
+        let ips: Vec<IpAddr> = (0..5)
+            .map(|i| pool.allocate(&format!("{i}.example.com"), false).unwrap())
+            .collect();
+        assert_eq!(ips[0], "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!(ips[4], "10.0.0.6".parse::<IpAddr>().unwrap());
+
+        // Using the first mapping makes the second one the least recently used.
+        assert!(pool.lookup(ips[0]).is_some());
+        let ip = pool.allocate("new.example.com", false).unwrap();
+        assert_eq!(ip, ips[1]);
+        assert_eq!(pool.lookup(ip).as_deref(), Some("new.example.com"));
+        assert_eq!(pool.lookup(ips[0]).as_deref(), Some("0.example.com"));
+    }
+
+    #[test]
+    fn test_invalid_ranges() {
+        for (range, range6) in [
+            ("198.18.0.0", None),
+            ("198.18.0.0/31", None),
+            ("fc00::/18", None),
+            ("198.18.0.0/15", Some("198.18.0.0/15")),
+            ("198.18.0.0/15", Some("fc00::/127")),
+            ("example.com/15", None),
+        ] {
+            let config = FakeIpConfig {
+                fake_ip_range: range.to_string(),
+                fake_ip_range6: range6.map(str::to_string),
+            };
+            assert!(validate_config(&config).is_err(), "{range} {range6:?}");
+        }
+    }
+
+    #[test]
+    fn test_answer_query() {
+        let pool = pool("198.18.0.0/15", None);
+
+        let response = pool.answer_query(&query("example.com", TYPE_A)).unwrap();
+        let query_len = 12 + 13 + 4;
+        assert_eq!(&response[..2], &[0x12, 0x34]);
+        // One answer with the fake address.
+        assert_eq!(&response[6..8], &[0, 1]);
+        assert_eq!(&response[response.len() - 4..], &[198, 18, 0, 2]);
+        assert_eq!(response.len(), query_len + 16);
+
+        // Without an IPv6 range, AAAA queries get an empty answer.
+        let response = pool.answer_query(&query("example.com", TYPE_AAAA)).unwrap();
+        assert_eq!(&response[6..8], &[0, 0]);
+        assert_eq!(response.len(), query_len);
+
+        // Other query types are not answered.
+        assert_eq!(pool.answer_query(&query("example.com", 16)), None);
+        assert_eq!(pool.answer_query(b"not a dns query"), None);
+    }
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut packet = vec![0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            packet.push(label.len() as u8);
+            packet.extend_from_slice(label.as_bytes());
+        }
+        packet.push(0);
+        packet.extend_from_slice(&qtype.to_be_bytes());
+        packet.extend_from_slice(&1u16.to_be_bytes());
+        packet
+    }
+}
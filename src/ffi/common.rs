@@ -188,15 +188,33 @@ pub async fn start_from_config(
 
     let configs: Vec<Config> = load_config_str(config_yaml)?;
 
-    let (configs, pem_count) = convert_cert_paths(configs).await?;
-    if pem_count > 0 {
-        info!("Loaded {} PEM files", pem_count);
+    let (configs, pem_paths) = convert_cert_paths(configs).await?;
+    if !pem_paths.is_empty() {
+        info!("Loaded {} PEM files", pem_paths.len());
     }
 
     let crate::config::ValidatedConfigs {
         configs: validated_configs,
         dns_groups,
+        geo,
+        fake_ip,
+        access_log,
+        udp_sessions,
+        buffer_pool,
+        crypto,
+        auto_ban,
+        user_groups,
     } = create_server_configs(configs)?;
+    crate::crypto_provider::configure(crypto);
+    crate::crypto_provider::log_cpu_extensions();
+    crate::geoip::set_database_path(geo.geoip_database);
+    crate::geosite::set_database_path(geo.geosite_database);
+    crate::fake_ip::configure(fake_ip)?;
+    crate::access_log::configure(access_log)?;
+    crate::udp_sessions::configure(udp_sessions);
+    crate::auto_ban::configure(auto_ban);
+    crate::buffer_pool::configure(buffer_pool);
+    crate::user_store::configure(user_groups);
 
     // Build DNS registry from expanded groups
     let mut dns_registry = build_dns_registry(dns_groups).await?;
@@ -245,7 +263,7 @@ pub async fn start_from_config(
     let mut join_handles: Vec<JoinHandle<()>> = Vec::new();
 
     for server_config in server_configs {
-        let resolver = dns_registry.get_for_server(server_config.dns.as_ref());
+        let resolver = dns_registry.get_for_server_config(&server_config);
         join_handles.extend(start_servers(Config::Server(server_config), resolver).await?);
     }
 
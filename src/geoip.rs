@@ -0,0 +1,202 @@
+//! GeoIP lookups for routing rules.
+//!
+//! Countries are looked up in a MaxMind `.mmdb` country database, configured with a
+//! top-level `geoip_database` entry. The database is opened on the first lookup rather
+//! than at startup, and lookup results are cached per IP.
+
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, LazyLock, OnceLock};
+
+use lru::LruCache;
+use maxminddb::{Reader, geoip2};
+use parking_lot::{Mutex, RwLock};
+
+const LOOKUP_CACHE_SIZE: usize = 4096;
+
+static DATABASE: LazyLock<RwLock<Arc<GeoIpDatabase>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(GeoIpDatabase::new(None))));
+
+/// A code matched by `geoip` rules.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GeoIpCode {
+    /// Private, loopback and link-local addresses. These don't need the database.
+    Private,
+    /// ISO 3166-1 alpha-2 country code, in uppercase.
+    Country([u8; 2]),
+}
+
+impl GeoIpCode {
+    pub fn from_str(s: &str) -> std::io::Result<Self> {
+        if s.eq_ignore_ascii_case("private") || s.eq_ignore_ascii_case("lan") {
+            return Ok(GeoIpCode::Private);
+        }
+        parse_country_code(s)
+            .map(GeoIpCode::Country)
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "invalid geoip code '{s}': expected a two-letter country code or 'private'"
+                    ),
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for GeoIpCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GeoIpCode::Private => write!(f, "private"),
+            GeoIpCode::Country([a, b]) => write!(f, "{}{}", *a as char, *b as char),
+        }
+    }
+}
+
+/// Sets the database used for lookups. The previously loaded database and its cached
+/// results are dropped, so that a reload picks up an updated database file.
+pub fn set_database_path(path: Option<String>) {
+    *DATABASE.write() = Arc::new(GeoIpDatabase::new(path));
+}
+
+/// Returns whether `ip` belongs to one of `codes`.
+pub fn matches(ip: IpAddr, codes: &[GeoIpCode]) -> std::io::Result<bool> {
+    let ip = ip.to_canonical();
+    if codes.contains(&GeoIpCode::Private) && is_private(ip) {
+        return Ok(true);
+    }
+    if !codes
+        .iter()
+        .any(|code| matches!(code, GeoIpCode::Country(_)))
+    {
+        return Ok(false);
+    }
+
+    let database = DATABASE.read().clone();
+    let country = database.lookup_country(ip)?;
+    Ok(country.is_some_and(|country| codes.contains(&GeoIpCode::Country(country))))
+}
+
+struct GeoIpDatabase {
+    path: Option<String>,
+    reader: OnceLock<Result<Reader<Vec<u8>>, String>>,
+    cache: Mutex<LruCache<IpAddr, Option<[u8; 2]>>>,
+}
+
+impl GeoIpDatabase {
+    fn new(path: Option<String>) -> Self {
+        Self {
+            path,
+            reader: OnceLock::new(),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(LOOKUP_CACHE_SIZE).unwrap())),
+        }
+    }
+
+    fn reader(&self) -> std::io::Result<&Reader<Vec<u8>>> {
+        let Some(path) = self.path.as_deref() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "geoip rules require a geoip_database config",
+            ));
+        };
+        // A failed load is remembered until the next reload, rather than retried on every
+        // lookup.
+        self.reader
+            .get_or_init(|| Reader::open_readfile(path).map_err(|e| e.to_string()))
+            .as_ref()
+            .map_err(|e| {
+                std::io::Error::other(format!("failed to load geoip database {path}: {e}"))
+            })
+    }
+
+    fn lookup_country(&self, ip: IpAddr) -> std::io::Result<Option<[u8; 2]>> {
+        if let Some(country) = self.cache.lock().get(&ip) {
+            return Ok(*country);
+        }
+
+        let record = self
+            .reader()?
+            .lookup::<geoip2::Country>(ip)
+            .map_err(|e| std::io::Error::other(format!("geoip lookup for {ip} failed: {e}")))?;
+        let country = record.and_then(|record| {
+            record
+                .country
+                .or(record.registered_country)
+                .and_then(|country| country.iso_code)
+                .and_then(parse_country_code)
+        });
+
+        self.cache.lock().put(ip, country);
+        Ok(country)
+    }
+}
+
+fn parse_country_code(s: &str) -> Option<[u8; 2]> {
+    match s.as_bytes() {
+        [a, b] if a.is_ascii_alphabetic() && b.is_ascii_alphabetic() => {
+            Some([a.to_ascii_uppercase(), b.to_ascii_uppercase()])
+        }
+        _ => None,
+    }
+}
+
+fn is_private(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_loopback() || ip.is_link_local(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unique_local() || ip.is_unicast_link_local(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn test_geoip_code() {
+        assert_eq!(
+            GeoIpCode::from_str("cn").unwrap(),
+            GeoIpCode::Country(*b"CN")
+        );
+        assert_eq!(GeoIpCode::from_str("LAN").unwrap(), GeoIpCode::Private);
+        assert_eq!(GeoIpCode::from_str("us").unwrap().to_string(), "US");
+        assert_eq!(GeoIpCode::Private.to_string(), "private");
+        assert!(GeoIpCode::from_str("usa").is_err());
+        assert!(GeoIpCode::from_str("1a").is_err());
+        assert!(GeoIpCode::from_str("").is_err());
+    }
+
+    #[test]
+    fn test_private_without_database() {
+        let codes = [GeoIpCode::Private];
+        let private = [
+            IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3)),
+            IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1)),
+            IpAddr::V4(Ipv4Addr::LOCALHOST),
+            IpAddr::V6(Ipv6Addr::LOCALHOST),
+            IpAddr::V6("fd00::1".parse().unwrap()),
+            IpAddr::V6("::ffff:172.16.0.1".parse().unwrap()),
+        ];
+        for ip in private {
+            assert!(matches(ip, &codes).unwrap(), "{ip}");
+        }
+        assert!(!matches(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), &codes).unwrap());
+    }
+
+    #[test]
+    fn test_country_without_database() {
+        let database = GeoIpDatabase::new(None);
+        assert!(
+            database
+                .lookup_country(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)))
+                .is_err()
+        );
+
+        let database = GeoIpDatabase::new(Some("/nonexistent/geoip.mmdb".to_string()));
+        assert!(
+            database
+                .lookup_country(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)))
+                .is_err()
+        );
+    }
+}
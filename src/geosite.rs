@@ -0,0 +1,500 @@
+//! Geosite domain lists for routing rules.
+//!
+//! Categories are read from a v2ray `geosite.dat` file, configured with a top-level
+//! `geosite_database` entry. The file is read on the first lookup rather than at startup,
+//! and each category is compiled into a matcher the first time a rule uses it.
+//!
+//! A rule can select entries of a category with an attribute, e.g. `google@cn`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, LazyLock, OnceLock};
+
+use parking_lot::RwLock;
+use regex::RegexSet;
+
+static DATABASE: LazyLock<RwLock<Arc<GeoSiteDatabase>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(GeoSiteDatabase::new(None))));
+
+// Field numbers from v2ray's routercommon.proto.
+const GEOSITE_LIST_ENTRY: u32 = 1;
+const GEOSITE_COUNTRY_CODE: u32 = 1;
+const GEOSITE_DOMAIN: u32 = 2;
+const DOMAIN_TYPE: u32 = 1;
+const DOMAIN_VALUE: u32 = 2;
+const DOMAIN_ATTRIBUTE: u32 = 3;
+const ATTRIBUTE_KEY: u32 = 1;
+
+const DOMAIN_TYPE_PLAIN: u64 = 0;
+const DOMAIN_TYPE_REGEX: u64 = 1;
+const DOMAIN_TYPE_DOMAIN: u64 = 2;
+const DOMAIN_TYPE_FULL: u64 = 3;
+
+/// Parses a rule's geosite category, e.g. `google` or `google@cn`, into its lowercase form.
+pub fn parse_category(s: &str) -> std::io::Result<String> {
+    let (name, attribute) = match s.split_once('@') {
+        Some((name, attribute)) => (name, Some(attribute)),
+        None => (s, None),
+    };
+    let is_valid = |part: &str| {
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || b == b'!')
+    };
+    if !is_valid(name) || !attribute.is_none_or(is_valid) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid geosite category '{s}'"),
+        ));
+    }
+    Ok(s.to_ascii_lowercase())
+}
+
+/// Sets the database used for lookups. The previously loaded database and its compiled
+/// categories are dropped, so that a reload picks up an updated database file.
+pub fn set_database_path(path: Option<String>) {
+    *DATABASE.write() = Arc::new(GeoSiteDatabase::new(path));
+}
+
+/// Returns whether `hostname` is in one of `categories`, which must have been parsed with
+/// [`parse_category`].
+pub fn matches(hostname: &str, categories: &[String]) -> std::io::Result<bool> {
+    let hostname = normalize_hostname(hostname);
+    let database = DATABASE.read().clone();
+    for category in categories {
+        if database.category(category)?.matches(&hostname) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn normalize_hostname(hostname: &str) -> Cow<'_, str> {
+    let hostname = hostname.strip_suffix('.').unwrap_or(hostname);
+    if hostname.bytes().any(|b| b.is_ascii_uppercase()) {
+        Cow::Owned(hostname.to_ascii_lowercase())
+    } else {
+        Cow::Borrowed(hostname)
+    }
+}
+
+struct GeoSiteDatabase {
+    path: Option<String>,
+    file: OnceLock<Result<GeoSiteFile, String>>,
+    categories: RwLock<HashMap<String, Arc<DomainMatcher>>>,
+}
+
+impl GeoSiteDatabase {
+    fn new(path: Option<String>) -> Self {
+        Self {
+            path,
+            file: OnceLock::new(),
+            categories: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn file(&self) -> std::io::Result<&GeoSiteFile> {
+        let Some(path) = self.path.as_deref() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "geosite rules require a geosite_database config",
+            ));
+        };
+        // A failed load is remembered until the next reload, rather than retried on every
+        // lookup.
+        self.file
+            .get_or_init(|| {
+                let data = std::fs::read(path).map_err(|e| e.to_string())?;
+                GeoSiteFile::parse(data).map_err(|e| e.to_string())
+            })
+            .as_ref()
+            .map_err(|e| {
+                std::io::Error::other(format!("failed to load geosite database {path}: {e}"))
+            })
+    }
+
+    fn category(&self, category: &str) -> std::io::Result<Arc<DomainMatcher>> {
+        if let Some(matcher) = self.categories.read().get(category) {
+            return Ok(matcher.clone());
+        }
+
+        let (name, attribute) = match category.split_once('@') {
+            Some((name, attribute)) => (name, Some(attribute)),
+            None => (category, None),
+        };
+        let matcher = Arc::new(self.file()?.compile(name, attribute)?);
+        self.categories
+            .write()
+            .insert(category.to_string(), matcher.clone());
+        Ok(matcher)
+    }
+}
+
+/// A `geosite.dat` file, indexed by category.
+struct GeoSiteFile {
+    data: Vec<u8>,
+    /// Lowercase category name to the range of its encoded `GeoSite` message in `data`.
+    categories: HashMap<String, Range<usize>>,
+}
+
+impl GeoSiteFile {
+    fn parse(data: Vec<u8>) -> std::io::Result<Self> {
+        let mut categories = HashMap::new();
+        let mut reader = ProtoReader::new(&data);
+        while let Some((field, value)) = reader.next_field()? {
+            let ProtoValue::Bytes(entry) = value else {
+                continue;
+            };
+            if field != GEOSITE_LIST_ENTRY {
+                continue;
+            }
+
+            let range = reader.pos - entry.len()..reader.pos;
+
+            let mut entry_reader = ProtoReader::new(entry);
+            while let Some((field, value)) = entry_reader.next_field()? {
+                if let (GEOSITE_COUNTRY_CODE, ProtoValue::Bytes(code)) = (field, value) {
+                    let code = std::str::from_utf8(code).map_err(std::io::Error::other)?;
+                    categories.insert(code.to_ascii_lowercase(), range);
+                    break;
+                }
+            }
+        }
+
+        Ok(Self { data, categories })
+    }
+
+    fn compile(&self, name: &str, attribute: Option<&str>) -> std::io::Result<DomainMatcher> {
+        let range = self.categories.get(name).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("geosite category '{name}' not found"),
+            )
+        })?;
+
+        let mut matcher = DomainMatcher::default();
+        let mut regexes = vec![];
+        let mut reader = ProtoReader::new(&self.data[range.clone()]);
+        while let Some((field, value)) = reader.next_field()? {
+            let (GEOSITE_DOMAIN, ProtoValue::Bytes(domain)) = (field, value) else {
+                continue;
+            };
+            let Some(domain) = parse_domain(domain, attribute)? else {
+                continue;
+            };
+            match domain.domain_type {
+                DOMAIN_TYPE_PLAIN => matcher.keywords.push(domain.value),
+                DOMAIN_TYPE_REGEX => regexes.push(domain.value),
+                DOMAIN_TYPE_DOMAIN => {
+                    matcher.domains.insert(domain.value);
+                }
+                DOMAIN_TYPE_FULL => {
+                    matcher.full.insert(domain.value);
+                }
+                _ => {}
+            }
+        }
+
+        if !regexes.is_empty() {
+            matcher.regexes = Some(RegexSet::new(&regexes).map_err(|e| {
+                std::io::Error::other(format!("invalid regex in geosite category '{name}': {e}"))
+            })?);
+        }
+
+        Ok(matcher)
+    }
+}
+
+struct Domain {
+    domain_type: u64,
+    value: String,
+}
+
+/// Decodes a `Domain` message. Returns None if `attribute` is set and the domain doesn't
+/// have it.
+fn parse_domain(data: &[u8], attribute: Option<&str>) -> std::io::Result<Option<Domain>> {
+    let mut domain_type = DOMAIN_TYPE_PLAIN;
+    let mut value = String::new();
+    let mut has_attribute = false;
+
+    let mut reader = ProtoReader::new(data);
+    while let Some((field, field_value)) = reader.next_field()? {
+        match (field, field_value) {
+            (DOMAIN_TYPE, ProtoValue::Varint(v)) => domain_type = v,
+            (DOMAIN_VALUE, ProtoValue::Bytes(v)) => {
+                value = std::str::from_utf8(v)
+                    .map_err(std::io::Error::other)?
+                    .to_string();
+            }
+            (DOMAIN_ATTRIBUTE, ProtoValue::Bytes(v)) if attribute.is_some() => {
+                let mut attribute_reader = ProtoReader::new(v);
+                while let Some((field, field_value)) = attribute_reader.next_field()? {
+                    if let (ATTRIBUTE_KEY, ProtoValue::Bytes(key)) = (field, field_value) {
+                        has_attribute |=
+                            attribute.is_some_and(|a| key.eq_ignore_ascii_case(a.as_bytes()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if attribute.is_some() && !has_attribute {
+        return Ok(None);
+    }
+    // Hostnames are matched in lowercase. Regexes are kept as is, since lowercasing would
+    // change escapes like `\D`.
+    if domain_type != DOMAIN_TYPE_REGEX {
+        value.make_ascii_lowercase();
+    }
+    Ok(Some(Domain { domain_type, value }))
+}
+
+#[derive(Default)]
+struct DomainMatcher {
+    /// Matched exactly.
+    full: HashSet<String>,
+    /// Matched exactly or as a parent domain.
+    domains: HashSet<String>,
+    /// Matched as a substring.
+    keywords: Vec<String>,
+    regexes: Option<RegexSet>,
+}
+
+impl DomainMatcher {
+    /// `hostname` must be lowercase.
+    fn matches(&self, hostname: &str) -> bool {
+        if self.full.contains(hostname) {
+            return true;
+        }
+
+        let mut suffix = hostname;
+        loop {
+            if self.domains.contains(suffix) {
+                return true;
+            }
+            match suffix.find('.') {
+                Some(i) => suffix = &suffix[i + 1..],
+                None => break,
+            }
+        }
+
+        if self
+            .keywords
+            .iter()
+            .any(|keyword| hostname.contains(keyword.as_str()))
+        {
+            return true;
+        }
+
+        self.regexes
+            .as_ref()
+            .is_some_and(|regexes| regexes.is_match(hostname))
+    }
+}
+
+enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    Fixed,
+}
+
+/// Minimal protobuf wire format reader, enough to walk the geosite messages.
+struct ProtoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn next_field(&mut self) -> std::io::Result<Option<(u32, ProtoValue<'a>)>> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.read_varint()?;
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.read_varint()?),
+            1 => {
+                self.skip(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = usize::try_from(self.read_varint()?).map_err(std::io::Error::other)?;
+                let start = self.pos;
+                self.skip(len)?;
+                ProtoValue::Bytes(&self.data[start..self.pos])
+            }
+            5 => {
+                self.skip(4)?;
+                ProtoValue::Fixed
+            }
+            wire_type => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("unsupported protobuf wire type {wire_type}"),
+                ));
+            }
+        };
+        Ok(Some((field, value)))
+    }
+
+    fn read_varint(&mut self) -> std::io::Result<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let Some(&b) = self.data.get(self.pos) else {
+                break;
+            };
+            self.pos += 1;
+            value |= u64::from(b & 0x7f) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "invalid protobuf varint",
+        ))
+    }
+
+    fn skip(&mut self, len: usize) -> std::io::Result<()> {
+        if self.data.len() - self.pos < len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "truncated protobuf message",
+            ));
+        }
+        self.pos += len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode_varint(mut v: u64, out: &mut Vec<u8>) {
+        while v >= 0x80 {
+            out.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        out.push(v as u8);
+    }
+
+    fn encode_bytes(field: u32, bytes: &[u8], out: &mut Vec<u8>) {
+        encode_varint(u64::from(field << 3 | 2), out);
+        encode_varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn encode_domain(domain_type: u64, value: &str, attributes: &[&str]) -> Vec<u8> {
+        let mut out = vec![];
+        encode_varint(u64::from(DOMAIN_TYPE << 3), &mut out);
+        encode_varint(domain_type, &mut out);
+        encode_bytes(DOMAIN_VALUE, value.as_bytes(), &mut out);
+        for attribute in attributes {
+            let mut encoded = vec![];
+            encode_bytes(ATTRIBUTE_KEY, attribute.as_bytes(), &mut encoded);
+            // bool_value = true
+            encode_varint(2 << 3, &mut encoded);
+            encode_varint(1, &mut encoded);
+            encode_bytes(DOMAIN_ATTRIBUTE, &encoded, &mut out);
+        }
+        out
+    }
+
+    fn encode_geosite(code: &str, domains: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = vec![];
+        encode_bytes(GEOSITE_COUNTRY_CODE, code.as_bytes(), &mut out);
+        for domain in domains {
+            encode_bytes(GEOSITE_DOMAIN, domain, &mut out);
+        }
+        out
+    }
+
+    fn test_file() -> GeoSiteFile {
+        let mut data = vec![];
+        let google = encode_geosite(
+            "GOOGLE",
+            &[
+                encode_domain(DOMAIN_TYPE_DOMAIN, "google.com", &[]),
+                encode_domain(DOMAIN_TYPE_DOMAIN, "google.cn", &["cn"]),
+                encode_domain(DOMAIN_TYPE_FULL, "www.gstatic.com", &[]),
+                encode_domain(DOMAIN_TYPE_PLAIN, "googleapis", &[]),
+                encode_domain(DOMAIN_TYPE_REGEX, r"^ggpht\d+\.com$", &[]),
+            ],
+        );
+        encode_bytes(GEOSITE_LIST_ENTRY, &google, &mut data);
+        let ads = encode_geosite(
+            "category-ads",
+            &[encode_domain(DOMAIN_TYPE_DOMAIN, "ads.example", &[])],
+        );
+        encode_bytes(GEOSITE_LIST_ENTRY, &ads, &mut data);
+        GeoSiteFile::parse(data).unwrap()
+    }
+
+    #[test]
+    fn test_parse_category() {
+        assert_eq!(parse_category("Google").unwrap(), "google");
+        assert_eq!(parse_category("google@CN").unwrap(), "google@cn");
+        assert_eq!(
+            parse_category("geolocation-!cn").unwrap(),
+            "geolocation-!cn"
+        );
+        assert!(parse_category("").is_err());
+        assert!(parse_category("google@").is_err());
+        assert!(parse_category("goo gle").is_err());
+    }
+
+    #[test]
+    fn test_category_matcher() {
+        let file = test_file();
+        assert_eq!(file.categories.len(), 2);
+
+        let matcher = file.compile("google", None).unwrap();
+        assert!(matcher.matches("google.com"));
+        assert!(matcher.matches("mail.google.com"));
+        assert!(matcher.matches("www.google.cn"));
+        assert!(matcher.matches("www.gstatic.com"));
+        assert!(!matcher.matches("gstatic.com"));
+        assert!(matcher.matches("content.googleapis.net"));
+        assert!(matcher.matches("ggpht3.com"));
+        assert!(!matcher.matches("notgoogle.com"));
+        assert!(!matcher.matches("example.com"));
+
+        let matcher = file.compile("google", Some("cn")).unwrap();
+        assert!(matcher.matches("google.cn"));
+        assert!(!matcher.matches("google.com"));
+
+        let matcher = file.compile("category-ads", None).unwrap();
+        assert!(matcher.matches("tracker.ads.example"));
+
+        assert!(file.compile("missing", None).is_err());
+    }
+
+    #[test]
+    fn test_truncated_file() {
+        let mut data = vec![];
+        encode_bytes(
+            GEOSITE_LIST_ENTRY,
+            &encode_geosite("google", &[]),
+            &mut data,
+        );
+        data.truncate(data.len() - 1);
+        assert!(GeoSiteFile::parse(data).is_err());
+    }
+
+    #[test]
+    fn test_normalize_hostname() {
+        assert_eq!(normalize_hostname("Mail.Google.com."), "mail.google.com");
+        assert!(matches!(
+            normalize_hostname("google.com"),
+            Cow::Borrowed("google.com")
+        ));
+    }
+}
@@ -0,0 +1,218 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{HeaderValue, Method, Request, Response, StatusCode};
+use log::debug;
+
+use super::gun_stream::GunStream;
+use crate::address::ResolvedLocation;
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::resolver::Resolver;
+use crate::tcp::tcp_handler::{
+    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+};
+use crate::tcp::tcp_server::{InboundContext, process_stream};
+
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+/// Returns the request path for a gun service, e.g. `/GunService/Tun`.
+pub fn grpc_service_path(service_name: &str) -> String {
+    format!("/{}/Tun", service_name.trim_matches('/'))
+}
+
+#[derive(Debug)]
+pub struct GrpcTcpServerHandler {
+    path: String,
+    handler: Arc<dyn TcpServerHandler>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl GrpcTcpServerHandler {
+    pub fn new(
+        service_name: &str,
+        handler: Box<dyn TcpServerHandler>,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
+        Self {
+            path: grpc_service_path(service_name),
+            handler: Arc::from(handler),
+            resolver,
+        }
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for GrpcTcpServerHandler {
+    async fn setup_server_stream(
+        &self,
+        server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let mut connection = h2::server::handshake(server_stream)
+            .await
+            .map_err(|e| std::io::Error::other(format!("gRPC H2 handshake failed: {e}")))?;
+
+        let path = self.path.clone();
+        let handler = self.handler.clone();
+        let resolver = self.resolver.clone();
+
+        // Every gRPC call on the connection is an independent tunnel, so the
+        // connection is served in the background like other multiplexed protocols.
+        tokio::spawn(async move {
+            while let Some(result) = connection.accept().await {
+                let (request, mut respond) = match result {
+                    Ok(r) => r,
+                    Err(e) => {
+                        debug!("gRPC connection ended: {e}");
+                        break;
+                    }
+                };
+
+                if request.method() != Method::POST || request.uri().path() != path {
+                    debug!(
+                        "Rejecting gRPC request: {} {}",
+                        request.method(),
+                        request.uri().path()
+                    );
+                    let response = Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(())
+                        .unwrap();
+                    let _ = respond.send_response(response, true);
+                    continue;
+                }
+
+                let response = Response::builder()
+                    .status(StatusCode::OK)
+                    .header(http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)
+                    .body(())
+                    .unwrap();
+                let send_stream = match respond.send_response(response, false) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        debug!("Failed to send gRPC response: {e}");
+                        continue;
+                    }
+                };
+
+                let stream = GunStream::new(send_stream, request.into_body(), true);
+                let handler = handler.clone();
+                let resolver = resolver.clone();
+                tokio::spawn(async move {
+                    let inbound = InboundContext::default();
+                    if let Err(e) = process_stream(stream, handler, resolver, inbound).await {
+                        debug!("gRPC stream finished with error: {e}");
+                    }
+                });
+            }
+        });
+
+        Ok(TcpServerSetupResult::AlreadyHandled)
+    }
+}
+
+#[derive(Debug)]
+pub struct GrpcTcpClientHandler {
+    uri: String,
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl GrpcTcpClientHandler {
+    pub fn new(service_name: &str, authority: &str, handler: Box<dyn TcpClientHandler>) -> Self {
+        Self {
+            uri: format!("https://{}{}", authority, grpc_service_path(service_name)),
+            handler,
+        }
+    }
+
+    async fn setup_client_stream_common(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<GunStream> {
+        let (mut send_request, connection) = h2::client::handshake(client_stream)
+            .await
+            .map_err(|e| std::io::Error::other(format!("gRPC H2 handshake failed: {e}")))?;
+
+        // The connection finishes on its own once the stream handles are dropped.
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("gRPC client H2 connection ended: {e}");
+            }
+        });
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(&self.uri)
+            .header(http::header::CONTENT_TYPE, GRPC_CONTENT_TYPE)
+            .header(http::header::TE, HeaderValue::from_static("trailers"))
+            .body(())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+
+        let (response_future, send_stream) = send_request
+            .send_request(request, false)
+            .map_err(|e| std::io::Error::other(format!("Failed to send gRPC request: {e}")))?;
+
+        let response = response_future
+            .await
+            .map_err(|e| std::io::Error::other(format!("gRPC response error: {e}")))?;
+
+        if response.status() != StatusCode::OK {
+            return Err(std::io::Error::other(format!(
+                "gRPC request failed with status: {}",
+                response.status()
+            )));
+        }
+
+        Ok(GunStream::new(send_stream, response.into_body(), false))
+    }
+}
+
+#[async_trait]
+impl TcpClientHandler for GrpcTcpClientHandler {
+    async fn setup_client_tcp_stream(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        remote_location: ResolvedLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        let gun_stream = self.setup_client_stream_common(client_stream).await?;
+        self.handler
+            .setup_client_tcp_stream(Box::new(gun_stream), remote_location)
+            .await
+    }
+
+    fn supports_udp_over_tcp(&self) -> bool {
+        self.handler.supports_udp_over_tcp()
+    }
+
+    async fn setup_client_udp_bidirectional(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        let gun_stream = self.setup_client_stream_common(client_stream).await?;
+        self.handler
+            .setup_client_udp_bidirectional(Box::new(gun_stream), target)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grpc_service_path() {
+        assert_eq!(grpc_service_path("GunService"), "/GunService/Tun");
+        assert_eq!(grpc_service_path("/my.Service/"), "/my.Service/Tun");
+    }
+
+    #[test]
+    fn test_client_uri() {
+        let handler = GrpcTcpClientHandler::new(
+            "example",
+            "cdn.example.com",
+            Box::new(crate::port_forward_handler::PortForwardClientHandler),
+        );
+        assert_eq!(handler.uri, "https://cdn.example.com/example/Tun");
+    }
+}
@@ -0,0 +1,373 @@
+//! Gun stream - a byte stream carried over a single gRPC bidirectional call.
+//!
+//! Each write is sent as one gRPC message (5-byte length prefix) containing a
+//! protobuf `Hunk { bytes data = 1; }`, matching v2ray/Xray's `GunService/Tun`.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use http::HeaderMap;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+/// gRPC message header: 1 byte compressed flag + 4 byte big-endian length.
+const GRPC_HEADER_LEN: usize = 5;
+
+/// Protobuf tag for field 1 with wire type 2 (length-delimited).
+const HUNK_DATA_TAG: u8 = 0x0a;
+
+/// Largest payload written in a single gRPC message.
+const MAX_WRITE_CHUNK: usize = 32 * 1024;
+
+/// Largest gRPC message accepted from the peer.
+const MAX_MESSAGE_LEN: usize = 4 * 1024 * 1024;
+
+/// Frames `data` as a gRPC message containing a single `Hunk`.
+pub fn encode_hunk(data: &[u8], dst: &mut BytesMut) {
+    let mut varint = [0u8; 10];
+    let varint_len = encode_varint(data.len() as u64, &mut varint);
+    let message_len = 1 + varint_len + data.len();
+
+    dst.reserve(GRPC_HEADER_LEN + message_len);
+    dst.put_u8(0);
+    dst.put_u32(message_len as u32);
+    dst.put_u8(HUNK_DATA_TAG);
+    dst.put_slice(&varint[..varint_len]);
+    dst.put_slice(data);
+}
+
+/// Decodes one gRPC message from the front of `src`.
+///
+/// Returns `Ok(None)` if `src` does not yet contain a complete message. On success the
+/// message is consumed from `src` and the hunk payload is returned, which may be empty.
+pub fn decode_hunk(src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+    if src.len() < GRPC_HEADER_LEN {
+        return Ok(None);
+    }
+    if src[0] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "compressed gRPC messages are not supported",
+        ));
+    }
+    let message_len = u32::from_be_bytes([src[1], src[2], src[3], src[4]]) as usize;
+    if message_len > MAX_MESSAGE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("gRPC message too large: {message_len}"),
+        ));
+    }
+    if src.len() < GRPC_HEADER_LEN + message_len {
+        return Ok(None);
+    }
+
+    src.advance(GRPC_HEADER_LEN);
+    let mut message = src.split_to(message_len).freeze();
+
+    // Skip any unknown fields, keeping the last occurrence of field 1.
+    let mut data = Bytes::new();
+    while message.has_remaining() {
+        let tag = decode_varint(&mut message)?;
+        let field_number = tag >> 3;
+        match tag & 0x7 {
+            0 => {
+                decode_varint(&mut message)?;
+            }
+            1 => skip_bytes(&mut message, 8)?,
+            2 => {
+                let len = decode_varint(&mut message)? as usize;
+                if len > message.remaining() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "truncated gRPC hunk",
+                    ));
+                }
+                let field = message.split_to(len);
+                if field_number == 1 {
+                    data = field;
+                }
+            }
+            5 => skip_bytes(&mut message, 4)?,
+            wire_type => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unsupported protobuf wire type {wire_type}"),
+                ));
+            }
+        }
+    }
+
+    Ok(Some(data))
+}
+
+fn skip_bytes(buf: &mut Bytes, len: usize) -> io::Result<()> {
+    if buf.remaining() < len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated protobuf field",
+        ));
+    }
+    buf.advance(len);
+    Ok(())
+}
+
+fn encode_varint(mut value: u64, dst: &mut [u8; 10]) -> usize {
+    let mut i = 0;
+    while value >= 0x80 {
+        dst[i] = (value as u8) | 0x80;
+        value >>= 7;
+        i += 1;
+    }
+    dst[i] = value as u8;
+    i + 1
+}
+
+fn decode_varint(buf: &mut Bytes) -> io::Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        if !buf.has_remaining() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated protobuf varint",
+            ));
+        }
+        let byte = buf.get_u8();
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "protobuf varint too long",
+    ))
+}
+
+/// A gun tunnel over one HTTP/2 stream.
+///
+/// The server side sends `grpc-status: 0` trailers on shutdown; the client side
+/// ends its stream with an empty DATA frame.
+pub struct GunStream {
+    send: h2::SendStream<Bytes>,
+    recv: h2::RecvStream,
+    is_server: bool,
+    /// Raw bytes received from the peer that have not been decoded yet
+    recv_buf: BytesMut,
+    /// Decoded hunk data not yet returned to the reader
+    read_data: Bytes,
+    /// Framed data waiting for send capacity
+    pending_write: Bytes,
+    recv_eof: bool,
+    shutdown_sent: bool,
+}
+
+impl GunStream {
+    pub fn new(send: h2::SendStream<Bytes>, recv: h2::RecvStream, is_server: bool) -> Self {
+        Self {
+            send,
+            recv,
+            is_server,
+            recv_buf: BytesMut::new(),
+            read_data: Bytes::new(),
+            pending_write: Bytes::new(),
+            recv_eof: false,
+            shutdown_sent: false,
+        }
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending_write.is_empty() {
+            let mut capacity = self.send.capacity();
+            if capacity == 0 {
+                self.send.reserve_capacity(self.pending_write.len());
+                capacity = match self.send.poll_capacity(cx) {
+                    Poll::Ready(Some(Ok(capacity))) => capacity,
+                    Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                    Poll::Ready(None) => {
+                        return Poll::Ready(Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            "gRPC stream closed",
+                        )));
+                    }
+                    Poll::Pending => return Poll::Pending,
+                };
+                if capacity == 0 {
+                    continue;
+                }
+            }
+            let to_send = capacity.min(self.pending_write.len());
+            let chunk = self.pending_write.split_to(to_send);
+            self.send
+                .send_data(chunk, false)
+                .map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for GunStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            if !self.read_data.is_empty() {
+                let to_copy = self.read_data.len().min(buf.remaining());
+                buf.put_slice(&self.read_data[..to_copy]);
+                self.read_data.advance(to_copy);
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(data) = decode_hunk(&mut self.recv_buf)? {
+                self.read_data = data;
+                continue;
+            }
+
+            if self.recv_eof {
+                if !self.recv_buf.is_empty() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "gRPC stream ended with a partial message",
+                    )));
+                }
+                return Poll::Ready(Ok(()));
+            }
+
+            match Pin::new(&mut self.recv).poll_data(cx) {
+                Poll::Ready(Some(Ok(data))) => {
+                    let _ = self.recv.flow_control().release_capacity(data.len());
+                    self.recv_buf.extend_from_slice(&data);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(io::Error::other(e))),
+                Poll::Ready(None) => self.recv_eof = true,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for GunStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Previously accepted data has to make progress before more is buffered.
+        match self.poll_send_pending(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let to_write = buf.len().min(MAX_WRITE_CHUNK);
+        let mut framed = BytesMut::new();
+        encode_hunk(&buf[..to_write], &mut framed);
+        self.pending_write = framed.freeze();
+
+        // Errors here will surface on the next write or flush.
+        let _ = self.poll_send_pending(cx);
+        Poll::Ready(Ok(to_write))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // h2 has no per-stream flush; the connection driver handles transmission
+        self.poll_send_pending(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.shutdown_sent {
+            match self.poll_send_pending(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+            let result = if self.is_server {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+                self.send.send_trailers(trailers)
+            } else {
+                self.send.send_data(Bytes::new(), true)
+            };
+            result.map_err(io::Error::other)?;
+            self.shutdown_sent = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for GunStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for GunStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_hunk() {
+        let mut buf = BytesMut::new();
+        encode_hunk(b"hello", &mut buf);
+        assert_eq!(
+            &buf[..],
+            &[0, 0, 0, 0, 7, 0x0a, 5, b'h', b'e', b'l', b'l', b'o']
+        );
+    }
+
+    #[test]
+    fn test_hunk_roundtrip() {
+        let payload = vec![0xabu8; 300];
+        let mut buf = BytesMut::new();
+        encode_hunk(&payload, &mut buf);
+        encode_hunk(b"", &mut buf);
+
+        let decoded = decode_hunk(&mut buf).unwrap().unwrap();
+        assert_eq!(&decoded[..], &payload[..]);
+        let decoded = decode_hunk(&mut buf).unwrap().unwrap();
+        assert!(decoded.is_empty());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_partial_message() {
+        let mut full = BytesMut::new();
+        encode_hunk(b"partial", &mut full);
+        let mut buf = BytesMut::from(&full[..full.len() - 1]);
+        assert!(decode_hunk(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), full.len() - 1);
+    }
+
+    #[test]
+    fn test_decode_skips_unknown_fields() {
+        // field 2 varint (0x10, 0x01) followed by field 1 bytes
+        let mut buf = BytesMut::from(&[0, 0, 0, 0, 5, 0x10, 0x01, 0x0a, 1, b'x'][..]);
+        let decoded = decode_hunk(&mut buf).unwrap().unwrap();
+        assert_eq!(&decoded[..], b"x");
+    }
+
+    #[test]
+    fn test_decode_rejects_compressed() {
+        let mut buf = BytesMut::from(&[1, 0, 0, 0, 0][..]);
+        assert!(decode_hunk(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_gun_stream_is_async_stream() {
+        fn assert_async_stream<T: AsyncStream>() {}
+        assert_async_stream::<GunStream>();
+    }
+}
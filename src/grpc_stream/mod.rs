@@ -0,0 +1,4 @@
+mod grpc_handler;
+mod gun_stream;
+
+pub use grpc_handler::{GrpcTcpClientHandler, GrpcTcpServerHandler};
@@ -0,0 +1,195 @@
+//! Byte streams over HTTP/3 request streams.
+//!
+//! h3 request streams only expose async send and receive methods, so a CONNECT stream
+//! is bridged to an in-memory pipe by two tasks, and the local end of the pipe is used
+//! as an AsyncStream.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+/// Buffer size of the pipe between a request stream and the local stream.
+pub const PIPE_BUFFER_SIZE: usize = 64 * 1024;
+
+pub type H3ClientRequestStream = h3::client::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+pub type H3ServerRequestStream = h3::server::RequestStream<h3_quinn::BidiStream<Bytes>, Bytes>;
+
+/// Send half of a request stream.
+trait H3SendHalf: Send + 'static {
+    fn send(&mut self, data: Bytes) -> impl Future<Output = io::Result<()>> + Send;
+
+    fn finish(&mut self) -> impl Future<Output = io::Result<()>> + Send;
+}
+
+/// Receive half of a request stream.
+trait H3RecvHalf: Send + 'static {
+    fn recv(&mut self) -> impl Future<Output = io::Result<Option<Bytes>>> + Send;
+}
+
+impl H3SendHalf for h3::client::RequestStream<h3_quinn::SendStream<Bytes>, Bytes> {
+    async fn send(&mut self, data: Bytes) -> io::Result<()> {
+        self.send_data(data).await.map_err(io::Error::other)
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        h3::client::RequestStream::finish(self)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+impl H3RecvHalf for h3::client::RequestStream<h3_quinn::RecvStream, Bytes> {
+    async fn recv(&mut self) -> io::Result<Option<Bytes>> {
+        Ok(self
+            .recv_data()
+            .await
+            .map_err(io::Error::other)?
+            .map(|mut data| data.copy_to_bytes(data.remaining())))
+    }
+}
+
+impl H3SendHalf for h3::server::RequestStream<h3_quinn::SendStream<Bytes>, Bytes> {
+    async fn send(&mut self, data: Bytes) -> io::Result<()> {
+        self.send_data(data).await.map_err(io::Error::other)
+    }
+
+    async fn finish(&mut self) -> io::Result<()> {
+        h3::server::RequestStream::finish(self)
+            .await
+            .map_err(io::Error::other)
+    }
+}
+
+impl H3RecvHalf for h3::server::RequestStream<h3_quinn::RecvStream, Bytes> {
+    async fn recv(&mut self) -> io::Result<Option<Bytes>> {
+        Ok(self
+            .recv_data()
+            .await
+            .map_err(io::Error::other)?
+            .map(|mut data| data.copy_to_bytes(data.remaining())))
+    }
+}
+
+/// Bridges a client request stream, after the response was received, to a pipe.
+pub fn pipe_client_request_stream(stream: H3ClientRequestStream) -> H3PipeStream {
+    let (send, recv) = stream.split();
+    spawn_pipe(send, recv)
+}
+
+/// Bridges a server request stream, after the response was sent, to a pipe.
+pub fn pipe_server_request_stream(stream: H3ServerRequestStream) -> H3PipeStream {
+    let (send, recv) = stream.split();
+    spawn_pipe(send, recv)
+}
+
+fn spawn_pipe<S: H3SendHalf, R: H3RecvHalf>(mut send: S, mut recv: R) -> H3PipeStream {
+    let (local, remote) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+    let (mut remote_read, mut remote_write) = tokio::io::split(remote);
+
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; PIPE_BUFFER_SIZE];
+        loop {
+            let n = match remote_read.read(&mut buf).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            if let Err(e) = send.send(Bytes::copy_from_slice(&buf[..n])).await {
+                debug!("H3 stream send failed: {e}");
+                return;
+            }
+        }
+        let _ = send.finish().await;
+    });
+
+    tokio::spawn(async move {
+        loop {
+            match recv.recv().await {
+                Ok(Some(data)) => {
+                    if remote_write.write_all(&data).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("H3 stream receive failed: {e}");
+                    break;
+                }
+            }
+        }
+        let _ = remote_write.shutdown().await;
+    });
+
+    H3PipeStream(local)
+}
+
+/// Local end of the pipe to an HTTP/3 request stream.
+pub struct H3PipeStream(DuplexStream);
+
+impl AsyncRead for H3PipeStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for H3PipeStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for H3PipeStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for H3PipeStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_h3_pipe_stream() {
+        let (local, mut remote) = tokio::io::duplex(PIPE_BUFFER_SIZE);
+        let mut stream = H3PipeStream(local);
+
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        remote.write_all(b"world").await.unwrap();
+        remote.shutdown().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"world");
+    }
+}
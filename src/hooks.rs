@@ -0,0 +1,314 @@
+//! Callbacks of applications that embed shoes, called on connection events.
+//!
+//! Hooks are called on the task of the connection, so a slow hook delays the
+//! connection. Each stream is a connection: a QUIC connection with several streams calls
+//! the hooks once per stream.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use shoes::hooks::{ConnectionContext, ConnectionHooks, set_connection_hooks};
+//!
+//! struct BlockUser;
+//!
+//! #[async_trait::async_trait]
+//! impl ConnectionHooks for BlockUser {
+//!     async fn on_auth(&self, context: &ConnectionContext) -> std::io::Result<()> {
+//!         if context.user.as_deref() == Some("mallory") {
+//!             return Err(std::io::Error::new(
+//!                 std::io::ErrorKind::PermissionDenied,
+//!                 "user is blocked",
+//!             ));
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! set_connection_hooks(Some(Arc::new(BlockUser)));
+//! ```
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use parking_lot::{Mutex, RwLock};
+
+use crate::address::NetLocation;
+use crate::metrics::{ByteCounter, Counter};
+
+/// Describes a connection, filled in as the connection is set up.
+#[allow(dead_code)] // Read by library embedders
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionContext {
+    /// Identifies the connection in the calls of its hooks.
+    pub id: u64,
+    /// Identifies the listener, e.g. `tcp://0.0.0.0:443`.
+    pub listener: String,
+    /// Name of the server protocol, e.g. `Vless`.
+    pub protocol: String,
+    pub source: Option<SocketAddr>,
+    /// Name of the user that the client authenticated as.
+    pub user: Option<String>,
+    /// The destination that the client requested, e.g. `example.com:443`. None for UDP
+    /// sessions that are routed per packet.
+    pub destination: Option<String>,
+    /// `tcp` or `udp`, once the connection is forwarded.
+    pub network: Option<&'static str>,
+    /// Identifies the client chain group that the connection was forwarded through, e.g.
+    /// `socks5@1.2.3.4:1080 | direct`.
+    pub outbound: Option<String>,
+}
+
+/// Traffic of a connection, passed to `on_close`.
+#[allow(dead_code)] // Read by library embedders
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    pub duration: Duration,
+    /// Bytes sent from the client towards the destination.
+    pub bytes_up: u64,
+    /// Bytes sent from the destination back to the client.
+    pub bytes_down: u64,
+}
+
+/// Callbacks on connection events. All methods do nothing by default.
+#[async_trait]
+pub trait ConnectionHooks: Send + Sync {
+    /// Called when a connection is accepted, before the handshake. Returning an error
+    /// closes the connection.
+    async fn on_connect(&self, _context: &ConnectionContext) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called after the handshake, with the user that the client authenticated as, if
+    /// any, and its destination. Returning an error closes the connection.
+    async fn on_auth(&self, _context: &ConnectionContext) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called when a connection fails, including when a hook rejected it.
+    async fn on_error(&self, _context: &ConnectionContext, _error: &std::io::Error) {}
+
+    /// Called when a connection ends, after `on_error` if it failed.
+    async fn on_close(&self, _context: &ConnectionContext, _stats: &ConnectionStats) {}
+}
+
+static HOOKS: LazyLock<RwLock<Option<Arc<dyn ConnectionHooks>>>> =
+    LazyLock::new(|| RwLock::new(None));
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Registers the hooks of new connections, or removes them.
+#[allow(dead_code)] // Used by library embedders
+pub fn set_connection_hooks(hooks: Option<Arc<dyn ConnectionHooks>>) {
+    *HOOKS.write() = hooks;
+}
+
+/// The hooks and context of a connection.
+pub struct HookedConnection {
+    hooks: Arc<dyn ConnectionHooks>,
+    context: Mutex<ConnectionContext>,
+    start: Instant,
+    bytes_up: Arc<Counter>,
+    bytes_down: Arc<Counter>,
+}
+
+impl std::fmt::Debug for HookedConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookedConnection")
+            .field("context", &*self.context.lock())
+            .finish_non_exhaustive()
+    }
+}
+
+impl HookedConnection {
+    /// Returns None if no hooks are registered.
+    pub fn new(listener: &str, protocol: &str, source: Option<SocketAddr>) -> Option<Arc<Self>> {
+        let hooks = HOOKS.read().clone()?;
+        Some(Arc::new(Self::with_hooks(
+            hooks, listener, protocol, source,
+        )))
+    }
+
+    fn with_hooks(
+        hooks: Arc<dyn ConnectionHooks>,
+        listener: &str,
+        protocol: &str,
+        source: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            hooks,
+            context: Mutex::new(ConnectionContext {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                listener: listener.to_string(),
+                protocol: protocol.to_string(),
+                source,
+                ..Default::default()
+            }),
+            start: Instant::now(),
+            bytes_up: Arc::new(Counter::default()),
+            bytes_down: Arc::new(Counter::default()),
+        }
+    }
+
+    fn context(&self) -> ConnectionContext {
+        self.context.lock().clone()
+    }
+
+    /// Runs `process` between `on_connect` and `on_close`.
+    pub async fn run<F>(&self, process: F) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        let result = match self.hooks.on_connect(&self.context()).await {
+            Ok(()) => process.await,
+            Err(e) => Err(e),
+        };
+        let context = self.context();
+        if let Err(e) = &result {
+            self.hooks.on_error(&context, e).await;
+        }
+        let stats = ConnectionStats {
+            duration: self.start.elapsed(),
+            bytes_up: self.bytes_up.get(),
+            bytes_down: self.bytes_down.get(),
+        };
+        self.hooks.on_close(&context, &stats).await;
+        result
+    }
+
+    /// Records the user and destination of the handshake, and calls `on_auth`.
+    pub async fn authenticate(
+        &self,
+        user: Option<&str>,
+        destination: Option<&NetLocation>,
+    ) -> std::io::Result<()> {
+        let context = {
+            let mut context = self.context.lock();
+            context.user = user.map(str::to_string);
+            context.destination = destination.map(NetLocation::to_string);
+            context.clone()
+        };
+        self.hooks.on_auth(&context).await
+    }
+
+    /// Records the outbound of a forwarded session, and adds the connection's byte
+    /// counts to the (up, down) counters of the session.
+    pub fn start_session(
+        &self,
+        network: &'static str,
+        destination: &NetLocation,
+        outbound: &str,
+        up: &mut ByteCounter,
+        down: &mut ByteCounter,
+    ) {
+        let mut context = self.context.lock();
+        context.network = Some(network);
+        context.destination = Some(destination.to_string());
+        context.outbound = Some(outbound.to_string());
+        up.push(self.bytes_up.clone());
+        down.push(self.bytes_down.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl ConnectionHooks for Recorder {
+        async fn on_connect(&self, context: &ConnectionContext) -> std::io::Result<()> {
+            self.events
+                .lock()
+                .push(format!("connect {}", context.listener));
+            Ok(())
+        }
+
+        async fn on_auth(&self, context: &ConnectionContext) -> std::io::Result<()> {
+            let user = context.user.clone().unwrap_or_default();
+            self.events.lock().push(format!("auth {user}"));
+            if user == "mallory" {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "blocked",
+                ));
+            }
+            Ok(())
+        }
+
+        async fn on_error(&self, _context: &ConnectionContext, error: &std::io::Error) {
+            self.events.lock().push(format!("error {error}"));
+        }
+
+        async fn on_close(&self, context: &ConnectionContext, stats: &ConnectionStats) {
+            self.events.lock().push(format!(
+                "close {} {} {}",
+                context.outbound.clone().unwrap_or_default(),
+                stats.bytes_up,
+                stats.bytes_down
+            ));
+        }
+    }
+
+    fn connection(recorder: &Arc<Recorder>) -> HookedConnection {
+        HookedConnection::with_hooks(recorder.clone(), "tcp://0.0.0.0:443", "Vless", None)
+    }
+
+    #[tokio::test]
+    async fn test_hooks_of_forwarded_connection() {
+        let recorder = Arc::new(Recorder::default());
+        let connection = connection(&recorder);
+        let destination = NetLocation::from_str("example.com:443", None).unwrap();
+        connection
+            .run(async {
+                connection
+                    .authenticate(Some("alice"), Some(&destination))
+                    .await?;
+                let mut up = ByteCounter::default();
+                let mut down = ByteCounter::default();
+                connection.start_session("tcp", &destination, "direct", &mut up, &mut down);
+                up.add(10);
+                down.add(20);
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            *recorder.events.lock(),
+            vec![
+                "connect tcp://0.0.0.0:443",
+                "auth alice",
+                "close direct 10 20"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hook_rejects_connection() {
+        let recorder = Arc::new(Recorder::default());
+        let connection = connection(&recorder);
+        let result = connection
+            .run(async { connection.authenticate(Some("mallory"), None).await })
+            .await;
+        assert_eq!(
+            result.unwrap_err().kind(),
+            std::io::ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            *recorder.events.lock(),
+            vec![
+                "connect tcp://0.0.0.0:443",
+                "auth mallory",
+                "error blocked",
+                "close  0 0"
+            ]
+        );
+    }
+}
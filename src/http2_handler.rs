@@ -0,0 +1,285 @@
+//! HTTP/2 forward proxy.
+//!
+//! HTTP proxy clients that send the HTTP/2 connection preface, usually after negotiating
+//! "h2" with ALPN, are served here instead of by the HTTP/1.1 parser. Every CONNECT
+//! stream is routed on its own, so browsers and naive-style clients can multiplex all
+//! their tunnels over a single connection.
+
+use std::convert::Infallible;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use http_body_util::Empty;
+use hyper::body::Incoming;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::resolver::Resolver;
+
+/// Start of the HTTP/2 client connection preface, which is never a valid HTTP/1 request.
+pub const HTTP2_PREFACE_START: &[u8] = b"PRI ";
+
+struct Http2ProxyConfig {
+    auth_token: Option<String>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+}
+
+/// Serves an HTTP/2 proxy connection in a background task.
+///
+/// `initial_data` is data that was already read from `stream`, including the preface.
+pub fn spawn_http2_proxy(
+    stream: Box<dyn AsyncStream>,
+    initial_data: Option<Box<[u8]>>,
+    auth_token: Option<&str>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+) {
+    let config = Arc::new(Http2ProxyConfig {
+        auth_token: auth_token.map(ToString::to_string),
+        proxy_selector,
+        resolver,
+    });
+    let io = TokioIo::new(PrefixedStream::new(
+        initial_data.unwrap_or_default(),
+        stream,
+    ));
+
+    tokio::spawn(async move {
+        let service = hyper::service::service_fn(move |req| {
+            let config = config.clone();
+            async move { proxy_service(req, config).await }
+        });
+
+        let result = hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+            .max_concurrent_streams(1024)
+            .serve_connection(io, service)
+            .await;
+
+        if let Err(e) = result {
+            debug!("HTTP/2 proxy connection error: {e}");
+        }
+    });
+}
+
+fn empty_response(status: StatusCode) -> Response<Empty<Bytes>> {
+    Response::builder()
+        .status(status)
+        .body(Empty::new())
+        .unwrap()
+}
+
+async fn proxy_service(
+    mut req: Request<Incoming>,
+    config: Arc<Http2ProxyConfig>,
+) -> Result<Response<Empty<Bytes>>, Infallible> {
+    if req.method() != Method::CONNECT {
+        debug!("Unsupported HTTP/2 proxy request method: {}", req.method());
+        return Ok(empty_response(StatusCode::METHOD_NOT_ALLOWED));
+    }
+
+    if let Some(ref auth_token) = config.auth_token
+        && !is_authorized(req.headers(), auth_token)
+    {
+        debug!("Missing or incorrect HTTP/2 CONNECT authentication");
+        // Clients only send credentials after a challenge.
+        return Ok(Response::builder()
+            .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+            .header("proxy-authenticate", "Basic realm=\"proxy\"")
+            .body(Empty::new())
+            .unwrap());
+    }
+
+    let Some(remote_location) = req
+        .uri()
+        .authority()
+        .and_then(|authority| parse_authority(authority).ok())
+    else {
+        debug!("Invalid HTTP/2 CONNECT destination: {}", req.uri());
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+
+    let decision = match config
+        .proxy_selector
+        .judge(remote_location.into(), &config.resolver)
+        .await
+    {
+        Ok(decision) => decision,
+        Err(e) => {
+            debug!("Failed to route HTTP/2 CONNECT request: {e}");
+            return Ok(empty_response(StatusCode::BAD_GATEWAY));
+        }
+    };
+
+    let mut client_stream = match decision {
+        ConnectDecision::Allow {
+            chain_group,
+            remote_location,
+        } => match chain_group
+            .connect_tcp(remote_location, &config.resolver)
+            .await
+        {
+            Ok(result) => result.client_stream,
+            Err(e) => {
+                debug!("HTTP/2 CONNECT failed to connect: {e}");
+                return Ok(empty_response(StatusCode::BAD_GATEWAY));
+            }
+        },
+        ConnectDecision::Block => {
+            debug!("HTTP/2 CONNECT blocked by rules");
+            return Ok(empty_response(StatusCode::FORBIDDEN));
+        }
+    };
+
+    let on_upgrade = hyper::upgrade::on(&mut req);
+    tokio::spawn(async move {
+        let upgraded = match on_upgrade.await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                debug!("HTTP/2 CONNECT upgrade failed: {e}");
+                return;
+            }
+        };
+        let mut server_stream = TokioIo::new(upgraded);
+        let result = tokio::io::copy_bidirectional(&mut server_stream, &mut client_stream).await;
+        let _ = client_stream.shutdown().await;
+        if let Err(e) = result {
+            debug!("HTTP/2 CONNECT stream ended: {e}");
+        }
+    });
+
+    Ok(empty_response(StatusCode::OK))
+}
+
+pub fn is_authorized(headers: &HeaderMap, auth_token: &str) -> bool {
+    let Some(value) = headers
+        .get("proxy-authorization")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    match value.split_once(' ') {
+        Some((scheme, token)) => scheme.eq_ignore_ascii_case("basic") && token.trim() == auth_token,
+        None => false,
+    }
+}
+
+pub fn parse_authority(authority: &http::uri::Authority) -> io::Result<NetLocation> {
+    let port = authority
+        .port_u16()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Missing port"))?;
+    // IPv6 hosts are enclosed in brackets.
+    let host = authority.host();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|host| host.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok(NetLocation::new(Address::from(host)?, port))
+}
+
+/// Stream that returns `prefix` before reading from `inner`.
+pub struct PrefixedStream {
+    prefix: Box<[u8]>,
+    offset: usize,
+    inner: Box<dyn AsyncStream>,
+}
+
+impl PrefixedStream {
+    pub fn new(prefix: Box<[u8]>, inner: Box<dyn AsyncStream>) -> Self {
+        Self {
+            prefix,
+            offset: 0,
+            inner,
+        }
+    }
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.offset < this.prefix.len() {
+            let len = (this.prefix.len() - this.offset).min(buf.remaining());
+            buf.put_slice(&this.prefix[this.offset..this.offset + len]);
+            this.offset += len;
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for PrefixedStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for PrefixedStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_authorized() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_authorized(&headers, "dXNlcjpwYXNz"));
+
+        headers.insert("proxy-authorization", "basic dXNlcjpwYXNz".parse().unwrap());
+        assert!(is_authorized(&headers, "dXNlcjpwYXNz"));
+        assert!(!is_authorized(&headers, "b3RoZXI6cGFzcw=="));
+
+        headers.insert(
+            "proxy-authorization",
+            "Bearer dXNlcjpwYXNz".parse().unwrap(),
+        );
+        assert!(!is_authorized(&headers, "dXNlcjpwYXNz"));
+    }
+
+    #[test]
+    fn test_parse_authority() {
+        let parse = |s: &str| parse_authority(&s.parse().unwrap());
+        assert_eq!(
+            parse("example.com:443").unwrap(),
+            NetLocation::new(Address::Hostname("example.com".to_string()), 443)
+        );
+        assert_eq!(
+            parse("[2001:db8::1]:8443").unwrap(),
+            NetLocation::new(Address::Ipv6("2001:db8::1".parse().unwrap()), 8443)
+        );
+        assert!(parse("example.com").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use async_trait::async_trait;
@@ -7,7 +8,10 @@ use tokio::io::AsyncWriteExt;
 
 use crate::address::{Address, NetLocation, ResolvedLocation};
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::decoy_handler::DecoyHandler;
+use crate::http2_handler::{HTTP2_PREFACE_START, spawn_http2_proxy};
 use crate::resolver::{Resolver, resolve_single_address};
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{
@@ -18,7 +22,7 @@ const PROXY_AUTH_HEADER_PREFIX: &str = "proxy-authorization: basic ";
 const CONNECTION_HEADER_PREFIX: &str = "connection: ";
 const PROXY_CONNECTION_HEADER_PREFIX: &str = "proxy-connection: ";
 
-fn create_http_auth_token(username: &str, password: &str) -> String {
+pub fn create_http_auth_token(username: &str, password: &str) -> String {
     BASE64.encode(format!("{username}:{password}"))
 }
 
@@ -26,6 +30,8 @@ fn create_http_auth_token(username: &str, password: &str) -> String {
 pub struct HttpTcpServerHandler {
     auth_token: Option<String>,
     proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    decoy: Option<DecoyHandler>,
 }
 
 unsafe impl Send for HttpTcpServerHandler {}
@@ -35,12 +41,16 @@ impl HttpTcpServerHandler {
     pub fn new(
         auth_credentials: Option<(String, String)>,
         proxy_selector: Arc<ClientProxySelector>,
+        resolver: Arc<dyn Resolver>,
+        decoy: Option<DecoyHandler>,
     ) -> Self {
         let auth_token = auth_credentials
             .map(|(username, password)| create_http_auth_token(&username, &password));
         Self {
             auth_token,
             proxy_selector,
+            resolver,
+            decoy,
         }
     }
 }
@@ -57,6 +67,8 @@ impl TcpServerHandler for HttpTcpServerHandler {
             server_stream,
             stream_reader,
             self.proxy_selector.clone(),
+            &self.resolver,
+            self.decoy.as_ref(),
         )
         .await
     }
@@ -65,15 +77,43 @@ impl TcpServerHandler for HttpTcpServerHandler {
 /// Core HTTP proxy server setup logic.
 /// Can be called from HttpTcpServerHandler or MixedTcpServerHandler.
 ///
-/// Takes ownership of `server_stream` and returns it in the result.
+/// Takes ownership of `server_stream` and returns it in the result. HTTP/2 connections
+/// are handed off to `http2_handler` and return `AlreadyHandled`.
+///
+/// When `decoy` is set, requests that aren't proxy requests or fail authentication are
+/// handed to it with the data read so far, instead of failing.
 pub async fn setup_http_server_stream_inner(
     auth_token: Option<&str>,
     mut server_stream: Box<dyn AsyncStream>,
     mut stream_reader: StreamReader,
     proxy_selector: Arc<ClientProxySelector>,
+    resolver: &Arc<dyn Resolver>,
+    decoy: Option<&DecoyHandler>,
 ) -> std::io::Result<TcpServerSetupResult> {
+    let start = stream_reader
+        .peek_slice(&mut server_stream, HTTP2_PREFACE_START.len())
+        .await?;
+    if start == HTTP2_PREFACE_START {
+        debug!("Detected HTTP/2 proxy connection");
+        spawn_http2_proxy(
+            server_stream,
+            stream_reader.unparsed_data_owned(),
+            auth_token,
+            proxy_selector,
+            resolver.clone(),
+        );
+        return Ok(TcpServerSetupResult::AlreadyHandled);
+    }
+
+    // Lines read so far, replayed to the decoy.
+    let mut read_data = Vec::new();
+
     let line = stream_reader.read_line(&mut server_stream).await?;
+    record_line(&mut read_data, decoy, line);
     if !line.ends_with(" HTTP/1.0") && !line.ends_with(" HTTP/1.1") {
+        if let Some(decoy) = decoy {
+            return serve_decoy(decoy, server_stream, read_data, &stream_reader).await;
+        }
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             format!("Unrecognized http request: {line}"),
@@ -87,6 +127,9 @@ pub async fn setup_http_server_stream_inner(
     // 3 spaces
     // total = 19
     if line.len() < 19 {
+        if let Some(decoy) = decoy {
+            return serve_decoy(decoy, server_stream, read_data, &stream_reader).await;
+        }
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
             format!("Invalid http request: {line}"),
@@ -98,30 +141,20 @@ pub async fn setup_http_server_stream_inner(
         if line.starts_with("CONNECT ") {
             let address = &line[8..line.len() - 9];
 
-            let separator_index = address.find(':').ok_or_else(|| {
-                std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid address format")
-            })?;
-
-            if address.len() <= separator_index + 1 {
-                return Err(std::io::Error::new(
+            // IPv6 addresses are enclosed in brackets, e.g. `[2001:db8::1]:443`.
+            let remote_location = NetLocation::from_str(address, None).map_err(|e| {
+                std::io::Error::new(
                     std::io::ErrorKind::InvalidInput,
-                    "Invalid address format",
-                ));
-            }
-
-            let domain_name = &address[0..separator_index];
-
-            let port = address[separator_index + 1..]
-                .parse::<u16>()
-                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-
-            let remote_location = NetLocation::new(Address::from(domain_name)?, port);
+                    format!("Invalid address format: {e}"),
+                )
+            })?;
 
             // wait for an empty \r\n before connecting, and check for auth header line if needed.
             let mut need_auth = auth_token.is_some();
 
             loop {
                 let line = stream_reader.read_line(&mut server_stream).await?;
+                record_line(&mut read_data, decoy, line);
                 if line.is_empty() {
                     break;
                 }
@@ -135,7 +168,11 @@ pub async fn setup_http_server_stream_inner(
                             "Received incorrect HTTP CONNECT authentication: {}",
                             &line[PROXY_AUTH_HEADER_PREFIX.len()..]
                         );
-                        return Err(std::io::Error::new(
+                        if let Some(decoy) = decoy {
+                            return serve_decoy(decoy, server_stream, read_data, &stream_reader)
+                                .await;
+                        }
+                        return Err(auto_ban::rejected(
                             std::io::ErrorKind::InvalidInput,
                             "Incorrect HTTP CONNECT authentication",
                         ));
@@ -147,6 +184,9 @@ pub async fn setup_http_server_stream_inner(
             }
 
             if need_auth {
+                if let Some(decoy) = decoy {
+                    return serve_decoy(decoy, server_stream, read_data, &stream_reader).await;
+                }
                 // FoxyProxy and similar clients require Proxy-Authenticate header to send credentials
                 server_stream.write_all(
                 &format!("{http_version} 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").into_bytes()
@@ -190,6 +230,10 @@ pub async fn setup_http_server_stream_inner(
             let url = &line[space_index + 1..];
 
             if !url.starts_with("http://") {
+                // Origin-form requests are sent to web servers, not proxies.
+                if let Some(decoy) = decoy {
+                    return serve_decoy(decoy, server_stream, read_data, &stream_reader).await;
+                }
                 // we can't handle https
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidInput,
@@ -204,15 +248,8 @@ pub async fn setup_http_server_stream_inner(
                 None => (url, "/"),
             };
 
-            let remote_location = match address.find(':') {
-                Some(i) => {
-                    let port = address[i + 1..]
-                        .parse::<u16>()
-                        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
-                    NetLocation::new(Address::from(&address[0..i])?, port)
-                }
-                None => NetLocation::new(Address::from(address)?, 80),
-            };
+            let remote_location = NetLocation::from_str(address, Some(80))
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
 
             let mut request = format!("{directive} {location} {http_version}\r\n");
 
@@ -221,6 +258,7 @@ pub async fn setup_http_server_stream_inner(
 
             loop {
                 let line = stream_reader.read_line(&mut server_stream).await?;
+                record_line(&mut read_data, decoy, line);
                 if line.is_empty() {
                     break;
                 }
@@ -237,7 +275,16 @@ pub async fn setup_http_server_stream_inner(
                                 "Received incorrect HTTP GET authentication: {}",
                                 &line[PROXY_AUTH_HEADER_PREFIX.len()..]
                             );
-                            return Err(std::io::Error::new(
+                            if let Some(decoy) = decoy {
+                                return serve_decoy(
+                                    decoy,
+                                    server_stream,
+                                    read_data,
+                                    &stream_reader,
+                                )
+                                .await;
+                            }
+                            return Err(auto_ban::rejected(
                                 std::io::ErrorKind::InvalidInput,
                                 "Incorrect HTTP GET authentication",
                             ));
@@ -270,6 +317,9 @@ pub async fn setup_http_server_stream_inner(
             }
 
             if need_auth {
+                if let Some(decoy) = decoy {
+                    return serve_decoy(decoy, server_stream, read_data, &stream_reader).await;
+                }
                 // FoxyProxy and similar clients require Proxy-Authenticate header to send credentials
                 server_stream.write_all(
                 &format!("{http_version} 407 Proxy Authentication Required\r\nProxy-Authenticate: Basic realm=\"proxy\"\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").into_bytes()
@@ -314,6 +364,24 @@ pub async fn setup_http_server_stream_inner(
     })
 }
 
+fn record_line(read_data: &mut Vec<u8>, decoy: Option<&DecoyHandler>, line: &str) {
+    if decoy.is_some() {
+        read_data.extend_from_slice(line.as_bytes());
+        read_data.extend_from_slice(b"\r\n");
+    }
+}
+
+/// Hands the connection to the decoy, replaying the lines read and the buffered data.
+async fn serve_decoy(
+    decoy: &DecoyHandler,
+    server_stream: Box<dyn AsyncStream>,
+    mut read_data: Vec<u8>,
+    stream_reader: &StreamReader,
+) -> std::io::Result<TcpServerSetupResult> {
+    read_data.extend_from_slice(stream_reader.unparsed_data());
+    decoy.serve(server_stream, &read_data).await
+}
+
 fn create_http_auth_header_line(username: &str, password: &str) -> String {
     format!(
         "Proxy-Authorization: Basic {}\r\n",
@@ -324,6 +392,10 @@ fn create_http_auth_header_line(username: &str, password: &str) -> String {
 pub struct HttpTcpClientHandler {
     auth_header: Option<String>,
     resolver: Option<Arc<dyn Resolver>>,
+    /// Extra header lines of the CONNECT request, each ending with CRLF.
+    headers: String,
+    /// True if `headers` sets the Host header.
+    has_host_header: bool,
 }
 
 impl std::fmt::Debug for HttpTcpClientHandler {
@@ -334,6 +406,7 @@ impl std::fmt::Debug for HttpTcpClientHandler {
                 &self.auth_header.as_ref().map(|_| "[redacted]"),
             )
             .field("resolver", &self.resolver.is_some())
+            .field("headers", &self.headers)
             .finish()
     }
 }
@@ -342,12 +415,22 @@ impl HttpTcpClientHandler {
     pub fn new(
         auth_credentials: Option<(String, String)>,
         resolver: Option<Arc<dyn Resolver>>,
+        headers: HashMap<String, String>,
     ) -> Self {
         let auth_header = auth_credentials
             .map(|(username, password)| create_http_auth_header_line(&username, &password));
+        let has_host_header = headers.keys().any(|name| name.eq_ignore_ascii_case("host"));
+        let mut headers: Vec<_> = headers.into_iter().collect();
+        headers.sort();
+        let headers = headers
+            .into_iter()
+            .map(|(name, value)| format!("{name}: {value}\r\n"))
+            .collect();
         Self {
             auth_header,
             resolver,
+            headers,
+            has_host_header,
         }
     }
 }
@@ -395,33 +478,20 @@ impl TcpClientHandler for HttpTcpClientHandler {
             remote_location.into_location()
         };
 
-        let mut connect_str = match connect_location.address() {
-            Address::Ipv6(addr) => {
-                format!(
-                    "CONNECT [{}]:{} HTTP/1.1\r\n",
-                    addr,
-                    connect_location.port()
-                )
-            }
-            Address::Ipv4(addr) => {
-                format!(
-                    "CONNECT {}:{} HTTP/1.1\r\n",
-                    addr,
-                    connect_location.port()
-                )
-            }
-            Address::Hostname(d) => {
-                format!(
-                    "CONNECT {}:{} HTTP/1.1\r\n",
-                    d,
-                    connect_location.port()
-                )
-            }
+        let authority = match connect_location.address() {
+            Address::Ipv6(addr) => format!("[{}]:{}", addr, connect_location.port()),
+            Address::Ipv4(addr) => format!("{}:{}", addr, connect_location.port()),
+            Address::Hostname(d) => format!("{}:{}", d, connect_location.port()),
         };
+        let mut connect_str = format!("CONNECT {authority} HTTP/1.1\r\n");
+        if !self.has_host_header {
+            connect_str.push_str(&format!("Host: {authority}\r\n"));
+        }
 
         if let Some(ref header) = self.auth_header {
             connect_str.push_str(header);
         }
+        connect_str.push_str(&self.headers);
         connect_str.push_str("\r\n");
         client_stream.write_all(&connect_str.into_bytes()).await?;
         client_stream.flush().await?;
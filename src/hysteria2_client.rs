@@ -25,11 +25,13 @@ use tokio::time::timeout;
 
 use crate::address::NetLocation;
 use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::hysteria2_congestion::set_brutal_tx;
 use crate::hysteria2_protocol::{
     AUTH_URI, FRAME_TYPE_TCP_REQUEST, MAX_ADDRESS_LENGTH, STATUS_AUTH_OK, header, tcp_status,
 };
 use crate::quic_stream::QuicStream;
 use crate::resolver::{NativeResolver, Resolver, resolve_single_address};
+use crate::socket_util::DialOptions;
 
 /// Authentication timeout - close connection if server doesn't authenticate within this time.
 /// Per protocol reference implementation, default is 3 seconds.
@@ -287,11 +289,10 @@ impl Hysteria2Client {
             (self.max_tx, false)
         };
 
-        // Store the negotiated values for later use
-        // Note: Quinn uses BBR by default, so we don't need to explicitly set it
-        if tx_auto {
-        } else if tx > 0 {
-        } else {
+        // Brutal sends at the negotiated rate, BBR is used for "auto" or without a rate
+        let brutal_tx = if tx_auto { 0 } else { tx };
+        if !set_brutal_tx(connection, brutal_tx) && brutal_tx > 0 {
+            warn!("[Hysteria2] Brutal congestion control is not available, using BBR");
         }
 
         Ok((tx, tx_auto))
@@ -951,8 +952,8 @@ pub struct Hysteria2SocketConnector {
     client: Arc<Hysteria2Client>,
     /// The established Hysteria2 connection (after authentication)
     connection: Arc<Mutex<Option<Hysteria2Connection>>>,
-    /// Optional bind interface for outgoing connections
-    bind_interface: Option<String>,
+    /// Options of outgoing sockets
+    dial_options: DialOptions,
 }
 
 impl Hysteria2SocketConnector {
@@ -967,7 +968,7 @@ impl Hysteria2SocketConnector {
         fast_open: bool,
         max_tx: u64,
         max_rx: u64,
-        bind_interface: Option<String>,
+        dial_options: DialOptions,
     ) -> Self {
         Self {
             client: Arc::new(Hysteria2Client::new(
@@ -981,7 +982,7 @@ impl Hysteria2SocketConnector {
                 max_rx,
             )),
             connection: Arc::new(Mutex::new(None)),
-            bind_interface,
+            dial_options,
         }
     }
 
@@ -1046,10 +1047,10 @@ impl crate::tcp::socket_connector::SocketConnector for Hysteria2SocketConnector
         let target_location = target.location();
 
         // Create a local UDP socket for bidirectional communication
-        // We use the bind_interface if configured in this connector
+        // We use the socket options configured in this connector
         let local_socket = Arc::new(new_udp_socket(
             target_location.address().is_ipv6(),
-            self.bind_interface.clone(),
+            &self.dial_options,
         )?);
 
         // Create the Hysteria2 UDP message stream
@@ -1064,7 +1065,11 @@ impl crate::tcp::socket_connector::SocketConnector for Hysteria2SocketConnector
 
     /// Returns the bind interface configured for this socket connector
     fn bind_interface(&self) -> Option<&str> {
-        self.bind_interface.as_deref()
+        self.dial_options.bind_interface.as_deref()
+    }
+
+    fn dial_options(&self) -> DialOptions {
+        self.dial_options.clone()
     }
 }
 
@@ -0,0 +1,265 @@
+//! Brutal congestion control for Hysteria2.
+//!
+//! Brutal sends at a fixed rate that was negotiated during authentication, instead of
+//! probing for bandwidth. The congestion window is sized so that the rate is kept up for
+//! the current RTT, and is grown by the observed ack rate so that lost packets don't
+//! lower the throughput. Connections without a negotiated rate use BBR.
+//!
+//! Per the reference implementation:
+//! https://github.com/apernet/hysteria/blob/master/core/internal/congestion/brutal/brutal.go
+
+use std::any::Any;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use quinn::congestion::{BbrConfig, Controller, ControllerFactory};
+use quinn_proto::RttEstimator;
+
+/// Number of one second slots that ack and loss counts are kept for.
+const PACKET_INFO_SLOT_COUNT: usize = 5;
+
+/// Packets need to be counted in the slots before the ack rate is used.
+const MIN_SAMPLE_COUNT: u64 = 50;
+
+/// The window is grown by at most 1 / MIN_ACK_RATE.
+const MIN_ACK_RATE: f64 = 0.8;
+
+/// Window multiplier that keeps the pipe full when acks are delayed.
+const CONGESTION_WINDOW_MULTIPLIER: f64 = 2.0;
+
+/// RTT used for the window before the first sample.
+const INITIAL_RTT: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, Default)]
+struct PacketInfo {
+    /// Second of the slot, relative to the start of the controller.
+    timestamp: u64,
+    ack_count: u64,
+    loss_count: u64,
+}
+
+/// Creates Brutal controllers. A controller only sends at a fixed rate once
+/// [`set_brutal_tx`] was called on its connection.
+#[derive(Debug, Default)]
+pub struct BrutalConfig {
+    bbr: Arc<BbrConfig>,
+}
+
+impl ControllerFactory for BrutalConfig {
+    fn build(self: Arc<Self>, now: Instant, current_mtu: u16) -> Box<dyn Controller> {
+        Box::new(Brutal {
+            tx: Arc::new(AtomicU64::new(0)),
+            bbr: self.bbr.clone().build(now, current_mtu),
+            start: now,
+            mtu: current_mtu,
+            smoothed_rtt: INITIAL_RTT,
+            packet_infos: [PacketInfo::default(); PACKET_INFO_SLOT_COUNT],
+        })
+    }
+}
+
+/// Sets the send rate in bytes per second of a connection that uses [`BrutalConfig`].
+/// A rate of 0 uses BBR. Returns false when the connection uses another controller.
+pub fn set_brutal_tx(connection: &quinn::Connection, tx: u64) -> bool {
+    // The state is a clone of the controller, which shares the rate with it.
+    match connection
+        .congestion_state()
+        .into_any()
+        .downcast::<Brutal>()
+    {
+        Ok(brutal) => {
+            brutal.tx.store(tx, Ordering::Relaxed);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+struct Brutal {
+    /// Send rate in bytes per second, shared with clones of the controller.
+    tx: Arc<AtomicU64>,
+    /// Used while there is no rate.
+    bbr: Box<dyn Controller>,
+    start: Instant,
+    mtu: u16,
+    smoothed_rtt: Duration,
+    packet_infos: [PacketInfo; PACKET_INFO_SLOT_COUNT],
+}
+
+impl Brutal {
+    fn tx(&self) -> u64 {
+        self.tx.load(Ordering::Relaxed)
+    }
+
+    fn update_packet_info(&mut self, now: Instant, ack_count: u64, loss_count: u64) {
+        let timestamp = now.saturating_duration_since(self.start).as_secs();
+        let slot = &mut self.packet_infos[timestamp as usize % PACKET_INFO_SLOT_COUNT];
+        if slot.timestamp == timestamp {
+            slot.ack_count += ack_count;
+            slot.loss_count += loss_count;
+        } else {
+            *slot = PacketInfo {
+                timestamp,
+                ack_count,
+                loss_count,
+            };
+        }
+    }
+
+    /// Ratio of acked packets to sent packets over the last slots.
+    fn ack_rate(&self, now: Instant) -> f64 {
+        let timestamp = now.saturating_duration_since(self.start).as_secs();
+        let min_timestamp = timestamp.saturating_sub(PACKET_INFO_SLOT_COUNT as u64);
+        let (ack_count, loss_count) = self
+            .packet_infos
+            .iter()
+            .filter(|info| info.timestamp >= min_timestamp)
+            .fold((0, 0), |(acks, losses), info| {
+                (acks + info.ack_count, losses + info.loss_count)
+            });
+        compute_ack_rate(ack_count, loss_count)
+    }
+}
+
+fn compute_ack_rate(ack_count: u64, loss_count: u64) -> f64 {
+    let total = ack_count + loss_count;
+    if total < MIN_SAMPLE_COUNT {
+        return 1.0;
+    }
+    (ack_count as f64 / total as f64).max(MIN_ACK_RATE)
+}
+
+fn compute_window(tx: u64, rtt: Duration, ack_rate: f64, mtu: u16) -> u64 {
+    let window = tx as f64 * rtt.as_secs_f64() * CONGESTION_WINDOW_MULTIPLIER / ack_rate;
+    (window as u64).max(mtu as u64)
+}
+
+impl Controller for Brutal {
+    fn on_sent(&mut self, now: Instant, bytes: u64, last_packet_number: u64) {
+        self.bbr.on_sent(now, bytes, last_packet_number);
+    }
+
+    fn on_ack(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        bytes: u64,
+        app_limited: bool,
+        rtt: &RttEstimator,
+    ) {
+        self.smoothed_rtt = rtt.get();
+        self.update_packet_info(now, bytes.div_ceil(self.mtu as u64), 0);
+        self.bbr.on_ack(now, sent, bytes, app_limited, rtt);
+    }
+
+    fn on_end_acks(
+        &mut self,
+        now: Instant,
+        in_flight: u64,
+        app_limited: bool,
+        largest_packet_num_acked: Option<u64>,
+    ) {
+        self.bbr
+            .on_end_acks(now, in_flight, app_limited, largest_packet_num_acked);
+    }
+
+    fn on_congestion_event(
+        &mut self,
+        now: Instant,
+        sent: Instant,
+        is_persistent_congestion: bool,
+        lost_bytes: u64,
+    ) {
+        self.update_packet_info(now, 0, lost_bytes.div_ceil(self.mtu as u64));
+        self.bbr
+            .on_congestion_event(now, sent, is_persistent_congestion, lost_bytes);
+    }
+
+    fn on_mtu_update(&mut self, new_mtu: u16) {
+        self.mtu = new_mtu;
+        self.bbr.on_mtu_update(new_mtu);
+    }
+
+    fn window(&self) -> u64 {
+        let tx = self.tx();
+        if tx == 0 {
+            return self.bbr.window();
+        }
+        compute_window(
+            tx,
+            self.smoothed_rtt,
+            self.ack_rate(Instant::now()),
+            self.mtu,
+        )
+    }
+
+    fn clone_box(&self) -> Box<dyn Controller> {
+        Box::new(Self {
+            tx: self.tx.clone(),
+            bbr: self.bbr.clone_box(),
+            start: self.start,
+            mtu: self.mtu,
+            smoothed_rtt: self.smoothed_rtt,
+            packet_infos: self.packet_infos,
+        })
+    }
+
+    fn initial_window(&self) -> u64 {
+        self.bbr.initial_window()
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_ack_rate() {
+        // Too few samples
+        assert_eq!(compute_ack_rate(10, 30), 1.0);
+        assert_eq!(compute_ack_rate(90, 10), 0.9);
+        // Heavy loss doesn't grow the window without bound
+        assert_eq!(compute_ack_rate(10, 90), MIN_ACK_RATE);
+    }
+
+    #[test]
+    fn test_compute_window() {
+        // 10 MB/s over 100ms, doubled
+        let rtt = Duration::from_millis(100);
+        assert_eq!(compute_window(10_000_000, rtt, 1.0, 1200), 2_000_000);
+        assert_eq!(compute_window(10_000_000, rtt, 0.5, 1200), 4_000_000);
+        // At least one packet
+        assert_eq!(compute_window(1000, Duration::ZERO, 1.0, 1200), 1200);
+    }
+
+    #[test]
+    fn test_packet_info_slots() {
+        let start = Instant::now();
+        let mut brutal = Brutal {
+            tx: Arc::new(AtomicU64::new(1_000_000)),
+            bbr: Arc::new(BbrConfig::default()).build(start, 1200),
+            start,
+            mtu: 1200,
+            smoothed_rtt: INITIAL_RTT,
+            packet_infos: [PacketInfo::default(); PACKET_INFO_SLOT_COUNT],
+        };
+
+        brutal.update_packet_info(start, 60, 40);
+        assert_eq!(brutal.ack_rate(start), MIN_ACK_RATE);
+
+        // Old slots are no longer counted.
+        let later = start + Duration::from_secs(PACKET_INFO_SLOT_COUNT as u64 + 1);
+        brutal.update_packet_info(later, 100, 0);
+        assert_eq!(brutal.ack_rate(later), 1.0);
+
+        // Clones share the rate.
+        let clone = brutal.clone_box().into_any().downcast::<Brutal>().unwrap();
+        clone.tx.store(0, Ordering::Relaxed);
+        assert_eq!(brutal.window(), brutal.bbr.window());
+    }
+}
@@ -0,0 +1,210 @@
+//! Salamander obfuscation for Hysteria2.
+//!
+//! Every UDP datagram is prefixed with a random 8 byte salt, and the QUIC packet is
+//! XORed with BLAKE2b-256(password || salt), so that datagrams don't look like QUIC.
+//! Per the reference implementation:
+//! https://github.com/apernet/hysteria/blob/master/extras/obfs/salamander.go
+
+use std::fmt::Debug;
+use std::io::{self, IoSliceMut};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use quinn::udp::{RecvMeta, Transmit};
+use quinn::{AsyncUdpSocket, UdpPoller};
+use rand::RngCore;
+
+pub const SALT_LEN: usize = 8;
+const KEY_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 4;
+
+pub struct Salamander {
+    password: Box<[u8]>,
+}
+
+impl Debug for Salamander {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Salamander").finish_non_exhaustive()
+    }
+}
+
+impl Salamander {
+    pub fn new(password: &str) -> io::Result<Self> {
+        if password.len() < MIN_PASSWORD_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("salamander password must be at least {MIN_PASSWORD_LEN} bytes"),
+            ));
+        }
+        Ok(Self {
+            password: password.as_bytes().into(),
+        })
+    }
+
+    fn key(&self, salt: &[u8]) -> [u8; KEY_LEN] {
+        let hash = blake2b_simd::Params::new()
+            .hash_length(KEY_LEN)
+            .to_state()
+            .update(&self.password)
+            .update(salt)
+            .finalize();
+        let mut key = [0u8; KEY_LEN];
+        key.copy_from_slice(hash.as_bytes());
+        key
+    }
+
+    fn xor(key: &[u8; KEY_LEN], data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= key[i % KEY_LEN];
+        }
+    }
+
+    /// Appends the obfuscated `packet` to `out`.
+    pub fn obfuscate(&self, packet: &[u8], out: &mut Vec<u8>) {
+        let mut salt = [0u8; SALT_LEN];
+        rand::rng().fill_bytes(&mut salt);
+        let key = self.key(&salt);
+        out.extend_from_slice(&salt);
+        let start = out.len();
+        out.extend_from_slice(packet);
+        Self::xor(&key, &mut out[start..]);
+    }
+
+    /// Restores the packet to the start of `datagram`, and returns its length.
+    /// Returns None when the datagram is too short to be obfuscated.
+    pub fn deobfuscate_in_place(&self, datagram: &mut [u8]) -> Option<usize> {
+        if datagram.len() <= SALT_LEN {
+            return None;
+        }
+        let key = self.key(&datagram[..SALT_LEN]);
+        Self::xor(&key, &mut datagram[SALT_LEN..]);
+        datagram.copy_within(SALT_LEN.., 0);
+        Some(datagram.len() - SALT_LEN)
+    }
+}
+
+/// UDP socket for QUIC endpoints that obfuscates sent datagrams and deobfuscates
+/// received ones.
+#[derive(Debug)]
+pub struct SalamanderUdpSocket {
+    inner: Arc<dyn AsyncUdpSocket>,
+    salamander: Arc<Salamander>,
+}
+
+impl SalamanderUdpSocket {
+    pub fn new(inner: Arc<dyn AsyncUdpSocket>, salamander: Arc<Salamander>) -> Self {
+        Self { inner, salamander }
+    }
+
+    /// Deobfuscates every segment of a received buffer, moving the packets together.
+    fn deobfuscate_segments(&self, buf: &mut [u8], meta: &mut RecvMeta) {
+        let stride = meta.stride.max(1);
+        let mut read = 0;
+        let mut written = 0;
+        while read < meta.len {
+            let end = (read + stride).min(meta.len);
+            if let Some(len) = self.salamander.deobfuscate_in_place(&mut buf[read..end]) {
+                buf.copy_within(read..read + len, written);
+                written += len;
+            }
+            read = end;
+        }
+        meta.len = written;
+        meta.stride = stride.saturating_sub(SALT_LEN);
+    }
+}
+
+impl AsyncUdpSocket for SalamanderUdpSocket {
+    fn create_io_poller(self: Arc<Self>) -> Pin<Box<dyn UdpPoller>> {
+        self.inner.clone().create_io_poller()
+    }
+
+    fn try_send(&self, transmit: &Transmit) -> io::Result<()> {
+        let segment_size = transmit.segment_size.unwrap_or(transmit.contents.len());
+        let segment_count = transmit.contents.len().div_ceil(segment_size.max(1));
+        let mut contents = Vec::with_capacity(transmit.contents.len() + segment_count * SALT_LEN);
+        for segment in transmit.contents.chunks(segment_size.max(1)) {
+            self.salamander.obfuscate(segment, &mut contents);
+        }
+        self.inner.try_send(&Transmit {
+            destination: transmit.destination,
+            ecn: transmit.ecn,
+            contents: &contents,
+            segment_size: transmit.segment_size.map(|size| size + SALT_LEN),
+            src_ip: transmit.src_ip,
+        })
+    }
+
+    fn poll_recv(
+        &self,
+        cx: &mut Context,
+        bufs: &mut [IoSliceMut<'_>],
+        meta: &mut [RecvMeta],
+    ) -> Poll<io::Result<usize>> {
+        let count = std::task::ready!(self.inner.poll_recv(cx, bufs, meta))?;
+        for (buf, meta) in bufs.iter_mut().zip(meta.iter_mut()).take(count) {
+            self.deobfuscate_segments(buf, meta);
+        }
+        Poll::Ready(Ok(count))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn max_transmit_segments(&self) -> usize {
+        self.inner.max_transmit_segments()
+    }
+
+    fn max_receive_segments(&self) -> usize {
+        self.inner.max_receive_segments()
+    }
+
+    fn may_fragment(&self) -> bool {
+        self.inner.may_fragment()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_salamander_round_trip() {
+        let salamander = Salamander::new("test_password").unwrap();
+        let packet = b"quic packet contents that are longer than the key length";
+
+        let mut datagram = Vec::new();
+        salamander.obfuscate(packet, &mut datagram);
+        assert_eq!(datagram.len(), packet.len() + SALT_LEN);
+        assert_ne!(&datagram[SALT_LEN..], &packet[..]);
+
+        let len = salamander.deobfuscate_in_place(&mut datagram).unwrap();
+        assert_eq!(&datagram[..len], &packet[..]);
+    }
+
+    #[test]
+    fn test_salamander_wrong_password() {
+        let salamander = Salamander::new("test_password").unwrap();
+        let other = Salamander::new("other_password").unwrap();
+
+        let mut datagram = Vec::new();
+        salamander.obfuscate(b"hello world", &mut datagram);
+        let len = other.deobfuscate_in_place(&mut datagram).unwrap();
+        assert_ne!(&datagram[..len], b"hello world");
+    }
+
+    #[test]
+    fn test_salamander_invalid_input() {
+        assert!(Salamander::new("abc").is_err());
+
+        let salamander = Salamander::new("test_password").unwrap();
+        assert!(
+            salamander
+                .deobfuscate_in_place(&mut [0u8; SALT_LEN])
+                .is_none()
+        );
+    }
+}
@@ -1,6 +1,6 @@
 use lru::LruCache;
 use std::collections::hash_map::Entry;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::str;
 use std::sync::Arc;
@@ -12,11 +12,11 @@ use crate::hysteria2_protocol::{
 
 use bytes::{Bytes, BytesMut};
 use log::{debug, error, warn};
+use quinn::Runtime;
 use rand::distr::Alphanumeric;
 use rand::{Rng, RngCore};
 use rustc_hash::FxHashMap;
 use tokio::io::AsyncWriteExt;
-use tokio::net::UdpSocket;
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 use tokio_util::sync::CancellationToken;
@@ -33,14 +33,52 @@ const AUTH_TIMEOUT: Duration = Duration::from_secs(3);
 /// Per official hysteria reference: https://github.com/apernet/hysteria/blob/master/core/server/server.go#L20
 const CLOSE_ERR_CODE_OK: u32 = 0x100; // HTTP3 ErrCodeNoError
 
+/// Bandwidth settings of the server, in bytes per second. 0 means unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Hysteria2ServerBandwidth {
+    pub max_tx: u64,
+    pub max_rx: u64,
+    pub ignore_client_bandwidth: bool,
+}
+
+impl Hysteria2ServerBandwidth {
+    /// Value of the Hysteria-CC-RX response header. "auto" tells the client to use BBR.
+    fn cc_rx_header(&self) -> String {
+        if self.ignore_client_bandwidth {
+            "auto".to_string()
+        } else {
+            self.max_rx.to_string()
+        }
+    }
+
+    /// Send rate to a client that can receive `client_rx`, 0 means BBR.
+    /// Per reference: actualTx = min(serverTx, clientRx)
+    fn negotiate_tx(&self, client_rx: u64) -> u64 {
+        if self.ignore_client_bandwidth {
+            return 0;
+        }
+        if client_rx == 0 || (self.max_tx > 0 && client_rx > self.max_tx) {
+            self.max_tx
+        } else {
+            client_rx
+        }
+    }
+}
+
+use crate::access_control::{AccessControl, admit_quic};
 use crate::address::NetLocation;
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
 use crate::copy_bidirectional::copy_bidirectional_with_sizes;
+use crate::hysteria2_congestion::{BrutalConfig, set_brutal_tx};
+use crate::hysteria2_obfs::{Salamander, SalamanderUdpSocket};
 use crate::quic_stream::QuicStream;
 use crate::resolver::{Resolver, ResolverCache};
+use crate::socket_util::DualStackUdpSocket;
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_server::setup_client_tcp_stream;
+use crate::udp_sessions::{self, Eviction};
 use crate::util::allocate_vec;
 
 async fn process_connection(
@@ -49,6 +87,7 @@ async fn process_connection(
     password: &'static str,
     conn: quinn::Incoming,
     udp_enabled: bool,
+    bandwidth: Hysteria2ServerBandwidth,
 ) -> std::io::Result<()> {
     let connection = conn.await?;
 
@@ -72,11 +111,21 @@ async fn process_connection(
     // Per sing-box reference, authentication timeout is 3 seconds
     match timeout(
         AUTH_TIMEOUT,
-        auth_connection(&mut h3_conn, password, udp_enabled),
+        auth_connection(
+            &mut h3_conn,
+            connection.remote_address().ip(),
+            password,
+            udp_enabled,
+            &bandwidth,
+        ),
     )
     .await
     {
-        Ok(Ok(())) => {}
+        Ok(Ok(client_rx)) => {
+            let tx = bandwidth.negotiate_tx(client_rx);
+            debug!("Hysteria2 client rx: {client_rx}, server tx: {tx}");
+            set_brutal_tx(&connection, tx);
+        }
         Ok(Err(e)) => {
             connection.close(CLOSE_ERR_CODE_OK.into(), b"auth failed");
             return Err(e);
@@ -191,9 +240,10 @@ fn validate_auth_request<T>(req: http::Request<T>, password: &str) -> std::io::R
     };
     let auth_str = auth_value.to_str().map_err(std::io::Error::other)?;
     if auth_str != password {
-        return Err(std::io::Error::other(format!(
-            "incorrect auth password: {auth_str}"
-        )));
+        return Err(auto_ban::rejected(
+            std::io::ErrorKind::PermissionDenied,
+            format!("incorrect auth password: {auth_str}"),
+        ));
     }
 
     Ok(())
@@ -208,23 +258,34 @@ fn generate_ascii_string() -> String {
         .collect()
 }
 
+/// Waits for the client to authenticate. Other requests are answered like by a web server,
+/// and the ones with a wrong password count towards the automatic ban of `client_ip`.
 async fn auth_connection(
     h3_conn: &mut h3::server::Connection<h3_quinn::Connection, bytes::Bytes>,
+    client_ip: IpAddr,
     password: &str,
     udp_enabled: bool,
-) -> std::io::Result<()> {
+    bandwidth: &Hysteria2ServerBandwidth,
+) -> std::io::Result<u64> {
     loop {
         match h3_conn.accept().await.map_err(std::io::Error::other)? {
             Some(resolver) => {
                 let (req, mut stream) = resolver.resolve_request().await.map_err(|err| {
                     std::io::Error::other(format!("Failed to resolve request: {err}"))
                 })?;
+                // Per reference, a missing or invalid rate means the client doesn't know it.
+                let client_rx = req
+                    .headers()
+                    .get(header::CC_RX)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.parse::<u64>().ok())
+                    .unwrap_or(0);
                 match validate_auth_request(req, password) {
                     Ok(()) => {
                         let resp = http::Response::builder()
                             .status(http::status::StatusCode::from_u16(STATUS_AUTH_OK).unwrap())
                             .header(header::UDP, if udp_enabled { "true" } else { "false" })
-                            .header(header::CC_RX, "0")
+                            .header(header::CC_RX, bandwidth.cc_rx_header())
                             .header(header::PADDING, generate_ascii_string())
                             .body(())
                             .unwrap();
@@ -236,10 +297,13 @@ async fn auth_connection(
 
                         stream.finish().await.map_err(std::io::Error::other)?;
 
-                        return Ok(());
+                        return Ok(client_rx);
                     }
                     Err(e) => {
                         error!("Received non-hysteria2 auth http3 request: {e}");
+                        if auto_ban::is_rejected(&e) {
+                            auto_ban::record_failure(client_ip);
+                        }
                         let resp = http::Response::builder()
                             .status(http::status::StatusCode::NOT_FOUND)
                             .body(())
@@ -265,7 +329,7 @@ async fn auth_connection(
 
 struct UdpSession {
     fragments: LruCache<u16, FragmentedPacket>,
-    send_socket: Arc<UdpSocket>,
+    send_socket: Arc<DualStackUdpSocket>,
     // we cache the last location in case of mid-session address changes, and
     // don't want to have to call ClientProxySelector::judge on every packet.
     last_location: NetLocation,
@@ -289,7 +353,7 @@ impl UdpSession {
     fn start(
         session_id: u32,
         connection: quinn::Connection,
-        client_socket: Arc<UdpSocket>,
+        client_socket: Arc<DualStackUdpSocket>,
         initial_location: NetLocation,
         initial_socket_addr: SocketAddr,
         override_local_write_location: Option<NetLocation>,
@@ -330,7 +394,7 @@ impl UdpSession {
 async fn run_udp_remote_to_local_loop(
     session_id: u32,
     connection: quinn::Connection,
-    socket: Arc<UdpSocket>,
+    socket: Arc<DualStackUdpSocket>,
     override_local_write_address: Option<NetLocation>,
     cancel_token: CancellationToken,
 ) -> std::io::Result<()> {
@@ -448,19 +512,25 @@ async fn run_udp_local_to_remote_loop(
     let mut resolver_cache = ResolverCache::new(resolver.clone());
     let mut sessions: FxHashMap<u32, UdpSession> = FxHashMap::default();
     let mut last_cleanup = std::time::Instant::now();
+    let source = Some(connection.remote_address().ip());
 
     // Match reference implementation defaults for UDP session management
     const CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
     const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
+    let session_limits = udp_sessions::limits();
+    let idle_timeout = session_limits.idle_timeout_or(IDLE_TIMEOUT);
+    let cleanup_interval = CLEANUP_INTERVAL.min(idle_timeout);
+
     loop {
         let now = std::time::Instant::now();
-        if (now - last_cleanup) > CLEANUP_INTERVAL {
+        if (now - last_cleanup) > cleanup_interval {
             sessions.retain(|session_id, session| {
-                if session.last_activity.elapsed() > IDLE_TIMEOUT {
+                if session.last_activity.elapsed() > idle_timeout {
                     // Cancel the session's background task before removing
                     session.cancel_token.cancel();
                     debug!("Removing inactive UDP session {session_id}");
+                    udp_sessions::record_eviction(Eviction::Idle);
                     false
                 } else {
                     true
@@ -544,11 +614,15 @@ async fn run_udp_local_to_remote_loop(
             }
         };
 
+        if session_limits.is_full(sessions.len()) && !sessions.contains_key(&session_id) {
+            evict_least_recently_used(&mut sessions);
+        }
+
         let mut session_entry = sessions.entry(session_id);
         let session = match session_entry {
             Entry::Vacant(entry) => {
                 let action = client_proxy_selector
-                    .judge(remote_location.clone().into(), &resolver)
+                    .judge_with_source(remote_location.clone().into(), source, &resolver)
                     .await;
 
                 let (_chain_group, updated_location) = match action {
@@ -604,8 +678,7 @@ async fn run_udp_local_to_remote_loop(
                 // TODO: the configured client socket is for the current remote_location, but
                 // the remote_location could be changed later on with a different client_socket
                 // configuration.
-                // Use IPv6 dual-stack socket for direct UDP
-                let client_socket = crate::socket_util::new_udp_socket(true, None)?;
+                let client_socket = DualStackUdpSocket::new(&Default::default())?;
 
                 let session = UdpSession::start(
                     session_id,
@@ -621,6 +694,7 @@ async fn run_udp_local_to_remote_loop(
             }
             Entry::Occupied(ref mut entry) => entry.get_mut(),
         };
+        session.last_activity = std::time::Instant::now();
 
         let (complete_payload, remote_location) = if fragment_count == 0 {
             error!("Ignoring empty UDP fragment for session {session_id}");
@@ -695,7 +769,7 @@ async fn run_udp_local_to_remote_loop(
                         remote_location.clone()
                     );
                     let action = client_proxy_selector
-                        .judge(remote_location.clone().into(), &resolver)
+                        .judge_with_source(remote_location.clone().into(), source, &resolver)
                         .await;
                     let updated_location = match action {
                         Ok(ConnectDecision::Allow {
@@ -742,11 +816,28 @@ async fn run_udp_local_to_remote_loop(
     }
 }
 
+/// Removes the least recently used session to make room for a new one.
+fn evict_least_recently_used(sessions: &mut FxHashMap<u32, UdpSession>) {
+    let Some(session_id) = sessions
+        .iter()
+        .min_by_key(|(_, session)| session.last_activity)
+        .map(|(session_id, _)| *session_id)
+    else {
+        return;
+    };
+    if let Some(session) = sessions.remove(&session_id) {
+        session.cancel_token.cancel();
+        debug!("UDP session table full, evicting session {session_id}");
+        udp_sessions::record_eviction(Eviction::Limit);
+    }
+}
+
 async fn run_tcp_loop(
     connection: quinn::Connection,
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
 ) -> std::io::Result<()> {
+    let source = connection.remote_address().ip();
     loop {
         let (send_stream, recv_stream) = match connection.accept_bi().await {
             Ok(s) => s,
@@ -766,8 +857,14 @@ async fn run_tcp_loop(
         let client_proxy_selector = client_proxy_selector.clone();
         let resolver = resolver.clone();
         tokio::spawn(async move {
-            if let Err(e) =
-                process_tcp_stream(client_proxy_selector, resolver, send_stream, recv_stream).await
+            if let Err(e) = process_tcp_stream(
+                client_proxy_selector,
+                resolver,
+                source,
+                send_stream,
+                recv_stream,
+            )
+            .await
             {
                 error!("Failed to process streams: {e}");
             }
@@ -845,6 +942,7 @@ async fn handle_tcp_header(
 async fn process_tcp_stream(
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    source: IpAddr,
     mut send: quinn::SendStream,
     mut recv: quinn::RecvStream,
 ) -> std::io::Result<()> {
@@ -865,11 +963,12 @@ async fn process_tcp_stream(
             client_proxy_selector,
             resolver,
             remote_location.clone(),
+            Some(source),
         ),
     );
 
     let mut client_stream = match setup_client_stream_future.await {
-        Ok(Ok(Some(s))) => s,
+        Ok(Ok(Some((s, _)))) => s,
         Ok(Ok(None)) => {
             // Must have been blocked.
             let _ = server_stream.shutdown().await;
@@ -978,6 +1077,7 @@ async fn read_varint(
     Ok(value)
 }
 
+#[allow(clippy::too_many_arguments)]
 pub async fn start_hysteria2_server(
     bind_address: SocketAddr,
     quic_server_config: Arc<quinn::crypto::rustls::QuicServerConfig>,
@@ -986,12 +1086,17 @@ pub async fn start_hysteria2_server(
     resolver: Arc<dyn Resolver>,
     num_endpoints: usize,
     udp_enabled: bool,
+    bandwidth: Hysteria2ServerBandwidth,
+    salamander: Option<Arc<Salamander>>,
+    access_control: Option<Arc<AccessControl>>,
 ) -> std::io::Result<Vec<JoinHandle<()>>> {
     let mut join_handles = vec![];
     for _ in 0..num_endpoints {
         let quic_server_config = quic_server_config.clone();
+        let salamander = salamander.clone();
         let resolver = resolver.clone();
         let client_proxy_selector = client_proxy_selector.clone();
+        let access_control = access_control.clone();
 
         let join_handle = tokio::spawn(async move {
             let mut server_config = quinn::ServerConfig::with_crypto(quic_server_config);
@@ -1015,7 +1120,9 @@ pub async fn start_hysteria2_server(
                 // Enable GSO (Generic Segmentation Offload) for better throughput
                 .enable_segmentation_offload(true)
                 // Lower initial RTT estimate for faster initial window growth
-                .initial_rtt(Duration::from_millis(100));
+                .initial_rtt(Duration::from_millis(100))
+                // Brutal once the rate is negotiated, BBR otherwise
+                .congestion_controller_factory(Arc::new(BrutalConfig::default()));
 
             // Use 7.5MB socket buffers for high-throughput QUIC (8.625MB on BSD for 15% kernel overhead)
             // https://github.com/quic-go/quic-go/wiki/UDP-Buffer-Sizes
@@ -1028,15 +1135,32 @@ pub async fn start_hysteria2_server(
             )
             .unwrap();
 
-            let endpoint = quinn::Endpoint::new(
-                quinn::EndpointConfig::default(),
-                Some(server_config),
-                socket2_socket.into(),
-                Arc::new(quinn::TokioRuntime),
-            )
-            .unwrap();
+            let endpoint = match salamander {
+                Some(salamander) => {
+                    let udp_socket = quinn::TokioRuntime
+                        .wrap_udp_socket(socket2_socket.into())
+                        .unwrap();
+                    quinn::Endpoint::new_with_abstract_socket(
+                        quinn::EndpointConfig::default(),
+                        Some(server_config),
+                        Arc::new(SalamanderUdpSocket::new(udp_socket, salamander)),
+                        Arc::new(quinn::TokioRuntime),
+                    )
+                    .unwrap()
+                }
+                None => quinn::Endpoint::new(
+                    quinn::EndpointConfig::default(),
+                    Some(server_config),
+                    socket2_socket.into(),
+                    Arc::new(quinn::TokioRuntime),
+                )
+                .unwrap(),
+            };
 
             while let Some(conn) = endpoint.accept().await {
+                let Some(conn) = admit_quic(access_control.as_deref(), conn) else {
+                    continue;
+                };
                 let cloned_selector = client_proxy_selector.clone();
                 let cloned_resolver = resolver.clone();
                 tokio::spawn(async move {
@@ -1046,6 +1170,7 @@ pub async fn start_hysteria2_server(
                         hysteria2_password,
                         conn,
                         udp_enabled,
+                        bandwidth,
                     )
                     .await
                     {
@@ -1179,8 +1304,9 @@ mod tests {
             .body(())
             .unwrap();
 
+        // Answered like by a web server, without counting towards bans.
         let result = validate_auth_request(req, "test_password");
-        assert!(result.is_err());
+        assert!(!auto_ban::is_rejected(&result.unwrap_err()));
     }
 
     #[test]
@@ -1195,7 +1321,7 @@ mod tests {
             .unwrap();
 
         let result = validate_auth_request(req, "test_password");
-        assert!(result.is_err());
+        assert!(auto_ban::is_rejected(&result.unwrap_err()));
     }
 
     #[test]
@@ -1282,6 +1408,31 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_negotiate_tx() {
+        let bandwidth = Hysteria2ServerBandwidth {
+            max_tx: 1000,
+            max_rx: 500,
+            ignore_client_bandwidth: false,
+        };
+        assert_eq!(bandwidth.negotiate_tx(0), 1000);
+        assert_eq!(bandwidth.negotiate_tx(800), 800);
+        assert_eq!(bandwidth.negotiate_tx(2000), 1000);
+        assert_eq!(bandwidth.cc_rx_header(), "500");
+
+        // Without a server limit, the client's rate is used
+        let unlimited = Hysteria2ServerBandwidth::default();
+        assert_eq!(unlimited.negotiate_tx(800), 800);
+        assert_eq!(unlimited.negotiate_tx(0), 0);
+
+        let ignored = Hysteria2ServerBandwidth {
+            ignore_client_bandwidth: true,
+            ..bandwidth
+        };
+        assert_eq!(ignored.negotiate_tx(800), 0);
+        assert_eq!(ignored.cc_rx_header(), "auto");
+    }
+
     #[test]
     fn test_generate_ascii_string_properties() {
         // Test various properties of generated ASCII strings
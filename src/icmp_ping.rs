@@ -0,0 +1,160 @@
+//! ICMP echo (ping) support for outbounds.
+//!
+//! Echo requests are built here and sent from an ICMP socket of the host, or from a
+//! socket inside a tunnel like WireGuard. The replies are matched by their sequence
+//! number and payload, since datagram ICMP sockets on Linux replace the identifier.
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::socket_util::{DialOptions, new_icmp_socket};
+
+pub const ICMPV4_ECHO_REPLY: u8 = 0;
+pub const ICMPV4_ECHO_REQUEST: u8 = 8;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Length of the echo header: type, code, checksum, identifier and sequence number.
+pub const ECHO_HEADER_LEN: usize = 8;
+
+/// Largest echo message we expect to receive.
+const MAX_ECHO_MESSAGE_SIZE: usize = 65535;
+
+/// Builds an ICMP echo request message, without the IP header.
+///
+/// The checksum of ICMPv6 messages covers a pseudo header with the source address, so
+/// it is left to the socket that sends them.
+pub fn build_echo_request(
+    is_ipv6: bool,
+    identifier: u16,
+    sequence: u16,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut message = Vec::with_capacity(ECHO_HEADER_LEN + payload.len());
+    message.push(if is_ipv6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMPV4_ECHO_REQUEST
+    });
+    message.push(0);
+    message.extend_from_slice(&[0, 0]);
+    message.extend_from_slice(&identifier.to_be_bytes());
+    message.extend_from_slice(&sequence.to_be_bytes());
+    message.extend_from_slice(payload);
+    if !is_ipv6 {
+        let checksum = internet_checksum(&message);
+        message[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    message
+}
+
+/// Parses an ICMP echo reply message, and returns its sequence number and payload.
+///
+/// IPv4 raw sockets, and datagram sockets on macOS, receive messages with their IP
+/// header, which is skipped.
+pub fn parse_echo_reply(is_ipv6: bool, mut message: &[u8]) -> Option<(u16, &[u8])> {
+    // Echo replies start with type 0, so a first nibble of 4 is an IPv4 header.
+    if !is_ipv6 && !message.is_empty() && message[0] >> 4 == 4 {
+        let header_len = usize::from(message[0] & 0x0f) * 4;
+        message = message.get(header_len..)?;
+    }
+    if message.len() < ECHO_HEADER_LEN {
+        return None;
+    }
+    let reply_type = if is_ipv6 {
+        ICMPV6_ECHO_REPLY
+    } else {
+        ICMPV4_ECHO_REPLY
+    };
+    if message[0] != reply_type || message[1] != 0 {
+        return None;
+    }
+    let sequence = u16::from_be_bytes([message[6], message[7]]);
+    Some((sequence, &message[ECHO_HEADER_LEN..]))
+}
+
+/// Computes the internet checksum (RFC 1071) of `data`.
+fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|chunk| match *chunk {
+            [high, low] => u32::from(u16::from_be_bytes([high, low])),
+            [high] => u32::from(high) << 8,
+            _ => unreachable!(),
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Sends an echo request to `ip` from an ICMP socket of the host, and waits for the
+/// reply. Callers are expected to apply a timeout.
+pub async fn ping(ip: IpAddr, payload: &[u8], options: &DialOptions) -> std::io::Result<()> {
+    let is_ipv6 = ip.is_ipv6();
+    let socket = new_icmp_socket(is_ipv6, options)?;
+
+    let sequence = rand::random();
+    let request = build_echo_request(is_ipv6, rand::random(), sequence, payload);
+    socket.send_to(&request, SocketAddr::new(ip, 0)).await?;
+
+    let mut buf = vec![0u8; MAX_ECHO_MESSAGE_SIZE];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if from.ip() != ip {
+            continue;
+        }
+        if let Some((reply_sequence, reply_payload)) = parse_echo_reply(is_ipv6, &buf[..len])
+            && reply_sequence == sequence
+            && reply_payload == payload
+        {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_reply(mut message: Vec<u8>, reply_type: u8) -> Vec<u8> {
+        message[0] = reply_type;
+        message
+    }
+
+    #[test]
+    fn test_echo_request_checksum() {
+        let request = build_echo_request(false, 0x1234, 7, b"abc");
+        assert_eq!(request[0], ICMPV4_ECHO_REQUEST);
+        assert_eq!(&request[4..8], &[0x12, 0x34, 0, 7]);
+        // A message with a valid checksum sums to zero.
+        assert_eq!(internet_checksum(&request), 0);
+
+        let request = build_echo_request(true, 0x1234, 7, b"abc");
+        assert_eq!(request[0], ICMPV6_ECHO_REQUEST);
+        assert_eq!(&request[2..4], &[0, 0]);
+    }
+
+    #[test]
+    fn test_parse_echo_reply() {
+        let reply = to_reply(build_echo_request(false, 1, 42, b"ping"), ICMPV4_ECHO_REPLY);
+        assert_eq!(parse_echo_reply(false, &reply), Some((42, &b"ping"[..])));
+        // Requests are not replies.
+        let request = build_echo_request(false, 1, 42, b"ping");
+        assert_eq!(parse_echo_reply(false, &request), None);
+        assert_eq!(parse_echo_reply(false, &reply[..4]), None);
+
+        let reply = to_reply(build_echo_request(true, 1, 42, b"ping"), ICMPV6_ECHO_REPLY);
+        assert_eq!(parse_echo_reply(true, &reply), Some((42, &b"ping"[..])));
+    }
+
+    #[test]
+    fn test_parse_echo_reply_skips_ipv4_header() {
+        let reply = to_reply(build_echo_request(false, 1, 42, b"ping"), ICMPV4_ECHO_REPLY);
+        let mut packet = vec![0x45];
+        packet.extend_from_slice(&[0; 19]);
+        packet.extend_from_slice(&reply);
+        assert_eq!(parse_echo_reply(false, &packet), Some((42, &b"ping"[..])));
+        assert_eq!(parse_echo_reply(false, &packet[..10]), None);
+    }
+}
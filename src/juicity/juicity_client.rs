@@ -0,0 +1,183 @@
+//! Juicity client.
+//!
+//! A single QUIC connection to the server is shared by all outgoing connections, and
+//! authenticated once when it is opened. Each TCP connection and UDP flow is a
+//! bidirectional stream.
+
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use log::debug;
+use tokio::sync::Mutex;
+
+use crate::address::{NetLocation, ResolvedLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::quic_stream::QuicStream;
+use crate::resolver::{Resolver, resolve_single_address};
+use crate::socks_handler::write_location_to_vec;
+use crate::tcp::socket_connector::SocketConnector;
+
+use super::juicity_packet_stream::JuicityPacketStream;
+use super::{AUTH_COMMAND, AUTH_VERSION, NETWORK_TCP, NETWORK_UDP};
+
+/// Socket connector that tunnels TCP and UDP through a Juicity server.
+///
+/// Like Hysteria2, this is a hop 0 connector: it owns the QUIC connection to the
+/// server, so no ProxyConnector is needed on top of it.
+pub struct JuicitySocketConnector {
+    endpoint: Arc<quinn::Endpoint>,
+    server_address: NetLocation,
+    sni_hostname: Option<String>,
+    uuid: Vec<u8>,
+    password: String,
+    bind_interface: Option<String>,
+    /// Connection slot for lazy init and reconnection
+    connection: Mutex<Option<quinn::Connection>>,
+}
+
+impl std::fmt::Debug for JuicitySocketConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JuicitySocketConnector")
+            .field("server_address", &self.server_address)
+            .field("sni_hostname", &self.sni_hostname)
+            .finish()
+    }
+}
+
+impl JuicitySocketConnector {
+    pub fn new(
+        endpoint: Arc<quinn::Endpoint>,
+        server_address: NetLocation,
+        sni_hostname: Option<String>,
+        uuid: Vec<u8>,
+        password: String,
+        bind_interface: Option<String>,
+    ) -> Self {
+        Self {
+            endpoint,
+            server_address,
+            sni_hostname,
+            uuid,
+            password,
+            bind_interface,
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn server_name(&self) -> &str {
+        self.sni_hostname
+            .as_deref()
+            .or(self.server_address.address().hostname())
+            .unwrap_or("example.com")
+    }
+
+    /// Get the current connection, or connect a new one if there is none or it was
+    /// closed.
+    async fn get_or_create_connection(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+    ) -> io::Result<quinn::Connection> {
+        let mut guard = self.connection.lock().await;
+
+        if let Some(ref connection) = *guard
+            && connection.close_reason().is_none()
+        {
+            return Ok(connection.clone());
+        }
+
+        debug!(
+            "Juicity: creating new connection to {}",
+            self.server_address
+        );
+
+        let server_addr = resolve_single_address(resolver, &self.server_address).await?;
+
+        let connection = self
+            .endpoint
+            .connect(server_addr, self.server_name())
+            .map_err(|e| io::Error::other(format!("Failed to connect to QUIC endpoint: {e}")))?
+            .await
+            .map_err(|e| io::Error::other(format!("QUIC connection failed: {e}")))?;
+
+        self.authenticate(&connection).await?;
+
+        *guard = Some(connection.clone());
+
+        Ok(connection)
+    }
+
+    /// Sends the TUIC v5 authenticate command on a unidirectional stream.
+    async fn authenticate(&self, connection: &quinn::Connection) -> io::Result<()> {
+        let mut token = [0u8; 32];
+        connection
+            .export_keying_material(&mut token, &self.uuid, self.password.as_bytes())
+            .map_err(|e| io::Error::other(format!("Failed to export keying material: {e:?}")))?;
+
+        let mut command = Vec::with_capacity(2 + 16 + 32);
+        command.push(AUTH_VERSION);
+        command.push(AUTH_COMMAND);
+        command.extend_from_slice(&self.uuid);
+        command.extend_from_slice(&token);
+
+        let mut send = connection.open_uni().await.map_err(io::Error::other)?;
+        send.write_all(&command).await.map_err(io::Error::other)?;
+        send.finish().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Opens a stream to `target` on the `network`.
+    async fn open_stream(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        network: u8,
+        target: &NetLocation,
+    ) -> io::Result<QuicStream> {
+        let connection = self.get_or_create_connection(resolver).await?;
+        let (mut send, recv) = connection.open_bi().await.map_err(io::Error::other)?;
+
+        let mut header = vec![network];
+        header.extend_from_slice(&write_location_to_vec(target));
+        send.write_all(&header).await.map_err(io::Error::other)?;
+
+        Ok(QuicStream::from(send, recv))
+    }
+}
+
+#[async_trait]
+impl SocketConnector for JuicitySocketConnector {
+    async fn connect(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        address: &ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncStream>> {
+        let stream = self
+            .open_stream(resolver, NETWORK_TCP, address.location())
+            .await?;
+
+        debug!("Juicity: opened TCP stream to {}", address.location());
+
+        Ok(Box::new(stream))
+    }
+
+    async fn connect_udp_bidirectional(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        target: ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncMessageStream>> {
+        let stream = self
+            .open_stream(resolver, NETWORK_UDP, target.location())
+            .await?;
+
+        debug!("Juicity: opened UDP stream to {}", target.location());
+
+        Ok(Box::new(JuicityPacketStream::with_target(
+            stream,
+            target.location().clone(),
+        )))
+    }
+
+    fn bind_interface(&self) -> Option<&str> {
+        self.bind_interface.as_deref()
+    }
+}
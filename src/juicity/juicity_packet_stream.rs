@@ -0,0 +1,371 @@
+//! UDP packets on a Juicity stream.
+//!
+//! Each packet carries its own address, in SOCKS5 format:
+//! ```text
+//! | ATYP | address  | port  | length | data     |
+//! | u8   | variable | u16be | u16be  | variable |
+//! ```
+//!
+//! Servers read the destination of each packet and write its source. Clients send all
+//! packets to the target of the session, and ignore the sources of received packets.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use tokio::io::ReadBuf;
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncReadTargetedMessage,
+    AsyncShutdownMessage, AsyncStream, AsyncTargetedMessageStream, AsyncWriteMessage,
+    AsyncWriteSourcedMessage,
+};
+use crate::slide_buffer::SlideBuffer;
+use crate::socks_handler::write_location_to_vec;
+
+/// Fits the largest packet with the largest address.
+const BUFFER_SIZE: usize = 65535 + 1 + 1 + 255 + 2 + 2;
+
+const ADDR_TYPE_IPV4: u8 = 0x01;
+const ADDR_TYPE_DOMAIN_NAME: u8 = 0x03;
+const ADDR_TYPE_IPV6: u8 = 0x04;
+
+/// Parses a SOCKS5 address. Returns Ok(None) if more data is needed.
+fn parse_address(data: &[u8]) -> std::io::Result<Option<(NetLocation, usize)>> {
+    let Some(&address_type) = data.first() else {
+        return Ok(None);
+    };
+    let (address, address_len) = match address_type {
+        ADDR_TYPE_IPV4 => {
+            if data.len() < 1 + 4 {
+                return Ok(None);
+            }
+            let ip = Ipv4Addr::new(data[1], data[2], data[3], data[4]);
+            (Address::Ipv4(ip), 1 + 4)
+        }
+        ADDR_TYPE_IPV6 => {
+            if data.len() < 1 + 16 {
+                return Ok(None);
+            }
+            let ip_bytes: [u8; 16] = data[1..17].try_into().unwrap();
+            (Address::Ipv6(Ipv6Addr::from(ip_bytes)), 1 + 16)
+        }
+        ADDR_TYPE_DOMAIN_NAME => {
+            let Some(&domain_len) = data.get(1) else {
+                return Ok(None);
+            };
+            let domain_len = domain_len as usize;
+            if data.len() < 2 + domain_len {
+                return Ok(None);
+            }
+            let domain = std::str::from_utf8(&data[2..2 + domain_len]).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("invalid domain: {e}"),
+                )
+            })?;
+            // Some clients pass IP addresses as domains.
+            (Address::from(domain)?, 2 + domain_len)
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown address type: {address_type}"),
+            ));
+        }
+    };
+    if data.len() < address_len + 2 {
+        return Ok(None);
+    }
+    let port = u16::from_be_bytes([data[address_len], data[address_len + 1]]);
+    Ok(Some((NetLocation::new(address, port), address_len + 2)))
+}
+
+pub struct JuicityPacketStream<S> {
+    stream: S,
+    /// Destination of the packets written by a client.
+    target: Option<NetLocation>,
+    read_buf: SlideBuffer,
+    pending_write: Vec<u8>,
+    write_offset: usize,
+    is_eof: bool,
+}
+
+impl<S: AsyncStream> JuicityPacketStream<S> {
+    /// Creates the stream of a server, which routes each packet on its own.
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            target: None,
+            read_buf: SlideBuffer::new(BUFFER_SIZE),
+            pending_write: Vec::with_capacity(BUFFER_SIZE),
+            write_offset: 0,
+            is_eof: false,
+        }
+    }
+
+    /// Creates the stream of a client that sends all packets to `target`.
+    pub fn with_target(stream: S, target: NetLocation) -> Self {
+        Self {
+            target: Some(target),
+            ..Self::new(stream)
+        }
+    }
+
+    /// Feeds data that was read with the header of the stream.
+    pub fn feed_initial_data(&mut self, data: &[u8]) -> std::io::Result<()> {
+        if data.len() > self.read_buf.remaining_capacity() {
+            return Err(std::io::Error::other(
+                "feed_initial_data called with too much data",
+            ));
+        }
+        self.read_buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Parses a complete packet from the read buffer, returning its address, and the
+    /// start and length of its payload.
+    fn try_parse_packet(&self) -> std::io::Result<Option<(NetLocation, usize, usize)>> {
+        let data = self.read_buf.as_slice();
+        let Some((location, address_len)) = parse_address(data)? else {
+            return Ok(None);
+        };
+        if data.len() < address_len + 2 {
+            return Ok(None);
+        }
+        let payload_len = u16::from_be_bytes([data[address_len], data[address_len + 1]]) as usize;
+        let payload_start = address_len + 2;
+        if data.len() < payload_start + payload_len {
+            return Ok(None);
+        }
+        Ok(Some((location, payload_start, payload_len)))
+    }
+
+    fn poll_read_packet(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<Option<NetLocation>>> {
+        if self.is_eof {
+            return Poll::Ready(Ok(None));
+        }
+
+        loop {
+            if let Some((location, payload_start, payload_len)) = self.try_parse_packet()? {
+                if buf.remaining() < payload_len {
+                    return Poll::Ready(Err(std::io::Error::other(
+                        "buffer is too small to hold the packet",
+                    )));
+                }
+                let data = self.read_buf.as_slice();
+                buf.put_slice(&data[payload_start..payload_start + payload_len]);
+                self.read_buf.consume(payload_start + payload_len);
+                return Poll::Ready(Ok(Some(location)));
+            }
+
+            self.read_buf.maybe_compact(4096);
+            let mut read_buf = ReadBuf::new(self.read_buf.write_slice());
+            ready!(Pin::new(&mut self.stream).poll_read(cx, &mut read_buf))?;
+            let bytes_read = read_buf.filled().len();
+            if bytes_read == 0 {
+                self.is_eof = true;
+                if self.read_buf.is_empty() {
+                    return Poll::Ready(Ok(None));
+                }
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "EOF reached in the middle of a packet",
+                )));
+            }
+            self.read_buf.advance_write(bytes_read);
+        }
+    }
+
+    fn poll_write_packet(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        location: &NetLocation,
+    ) -> Poll<std::io::Result<()>> {
+        if !self.pending_write.is_empty() {
+            ready!(self.poll_flush_packets(cx))?;
+        }
+
+        if buf.len() > u16::MAX as usize {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "packet too large",
+            )));
+        }
+
+        self.pending_write
+            .extend_from_slice(&write_location_to_vec(location));
+        self.pending_write
+            .extend_from_slice(&(buf.len() as u16).to_be_bytes());
+        self.pending_write.extend_from_slice(buf);
+        self.write_offset = 0;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_flush_packets(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.write_offset < self.pending_write.len() {
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.pending_write[self.write_offset..])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "failed to write packet",
+                )));
+            }
+            self.write_offset += n;
+        }
+        ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+        self.pending_write.clear();
+        self.write_offset = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncStream> AsyncReadTargetedMessage for JuicityPacketStream<S> {
+    fn poll_read_targeted_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<NetLocation>> {
+        let location = ready!(self.get_mut().poll_read_packet(cx, buf))?;
+        Poll::Ready(Ok(location.unwrap_or(NetLocation::UNSPECIFIED)))
+    }
+}
+
+impl<S: AsyncStream> AsyncWriteSourcedMessage for JuicityPacketStream<S> {
+    fn poll_write_sourced_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        source: &SocketAddr,
+    ) -> Poll<std::io::Result<()>> {
+        let source = NetLocation::from_ip_addr(source.ip(), source.port());
+        self.get_mut().poll_write_packet(cx, buf, &source)
+    }
+}
+
+impl<S: AsyncStream> AsyncReadMessage for JuicityPacketStream<S> {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.get_mut().poll_read_packet(cx, buf))?;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncStream> AsyncWriteMessage for JuicityPacketStream<S> {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let Some(target) = this.target.clone() else {
+            return Poll::Ready(Err(std::io::Error::other(
+                "Juicity packet stream has no target",
+            )));
+        };
+        this.poll_write_packet(cx, buf, &target)
+    }
+}
+
+impl<S: AsyncStream> AsyncFlushMessage for JuicityPacketStream<S> {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().poll_flush_packets(cx)
+    }
+}
+
+impl<S: AsyncStream> AsyncShutdownMessage for JuicityPacketStream<S> {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_flush_packets(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl<S: AsyncStream> AsyncPing for JuicityPacketStream<S> {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl<S: AsyncStream> AsyncTargetedMessageStream for JuicityPacketStream<S> {}
+
+impl<S: AsyncStream> AsyncMessageStream for JuicityPacketStream<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        let hostname = NetLocation::new(Address::Hostname("example.com".to_string()), 53);
+        let ipv6 = NetLocation::new(Address::Ipv6("2001:db8::1".parse().unwrap()), 443);
+        for location in [hostname, ipv6] {
+            let encoded = write_location_to_vec(&location);
+            assert_eq!(
+                parse_address(&encoded).unwrap(),
+                Some((location, encoded.len()))
+            );
+            assert_eq!(parse_address(&encoded[..encoded.len() - 1]).unwrap(), None);
+        }
+        assert!(parse_address(&[0x02, 0, 0]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_packets_roundtrip() {
+        use std::future::poll_fn;
+
+        let (client, server) = tokio::io::duplex(1024);
+        let target = NetLocation::new(Address::Ipv4(Ipv4Addr::new(192, 0, 2, 1)), 53);
+        let mut client = JuicityPacketStream::with_target(client, target.clone());
+        let mut server = JuicityPacketStream::new(server);
+
+        poll_fn(|cx| Pin::new(&mut client).poll_write_message(cx, b"query"))
+            .await
+            .unwrap();
+        poll_fn(|cx| Pin::new(&mut client).poll_flush_message(cx))
+            .await
+            .unwrap();
+
+        let mut data = [0u8; 64];
+        let mut buf = ReadBuf::new(&mut data);
+        let location = poll_fn(|cx| Pin::new(&mut server).poll_read_targeted_message(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(location, target);
+        assert_eq!(buf.filled(), b"query");
+
+        let source: SocketAddr = "192.0.2.1:53".parse().unwrap();
+        poll_fn(|cx| Pin::new(&mut server).poll_write_sourced_message(cx, b"answer", &source))
+            .await
+            .unwrap();
+        poll_fn(|cx| Pin::new(&mut server).poll_flush_message(cx))
+            .await
+            .unwrap();
+
+        let mut data = [0u8; 64];
+        let mut buf = ReadBuf::new(&mut data);
+        poll_fn(|cx| Pin::new(&mut client).poll_read_message(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(buf.filled(), b"answer");
+    }
+}
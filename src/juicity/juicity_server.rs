@@ -0,0 +1,277 @@
+//! Juicity server.
+//!
+//! Authenticates each connection like TUIC, then routes each bidirectional stream on
+//! its own: TCP streams to the address in their header, and UDP streams per packet.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::error;
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::access_control::{AccessControl, admit_quic};
+use crate::address::NetLocation;
+use crate::async_stream::AsyncStream;
+use crate::auto_ban;
+use crate::client_proxy_selector::ClientProxySelector;
+use crate::copy_bidirectional::copy_bidirectional_with_sizes;
+use crate::quic_stream::QuicStream;
+use crate::rate_limit::RateLimiter;
+use crate::resolver::Resolver;
+use crate::routing::{ServerStream, run_udp_routing};
+use crate::socks_handler::read_location;
+use crate::stream_reader::StreamReader;
+use crate::tcp::tcp_server::setup_client_tcp_stream;
+use crate::tuic_server::{TuicUsers, auth_connection};
+use crate::util::write_all;
+
+use super::juicity_packet_stream::JuicityPacketStream;
+use super::{NETWORK_TCP, NETWORK_UDP};
+
+/// Close connections that don't authenticate within this time.
+const AUTH_TIMEOUT: Duration = Duration::from_secs(3);
+
+struct JuicityServerConfig {
+    users: Arc<TuicUsers>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+}
+
+async fn process_connection(
+    config: Arc<JuicityServerConfig>,
+    conn: quinn::Incoming,
+) -> io::Result<()> {
+    let connection = conn.await?;
+
+    match timeout(AUTH_TIMEOUT, auth_connection(&connection, &config.users)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            connection.close(0u32.into(), b"auth failed");
+            if auto_ban::is_rejected(&e) {
+                auto_ban::record_failure(connection.remote_address().ip());
+            }
+            return Err(e);
+        }
+        Err(_elapsed) => {
+            connection.close(0u32.into(), b"auth timeout");
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "authentication timeout",
+            ));
+        }
+    }
+
+    let source = connection.remote_address().ip();
+    loop {
+        let (send, recv) = match connection.accept_bi().await {
+            Ok(s) => s,
+            Err(
+                quinn::ConnectionError::ApplicationClosed(_)
+                | quinn::ConnectionError::ConnectionClosed(_),
+            ) => {
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(io::Error::other(format!(
+                    "failed to accept bidirectional stream: {e}"
+                )));
+            }
+        };
+
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = process_stream(config, source, send, recv).await {
+                error!("Error processing Juicity stream: {e}");
+            }
+        });
+    }
+}
+
+async fn process_stream(
+    config: Arc<JuicityServerConfig>,
+    source: IpAddr,
+    send: quinn::SendStream,
+    mut recv: quinn::RecvStream,
+) -> io::Result<()> {
+    let mut stream_reader = StreamReader::new_with_buffer_size(1024);
+    let network = stream_reader.read_u8(&mut recv).await?;
+    let remote_location = read_location(&mut recv, &mut stream_reader).await?;
+
+    match network {
+        NETWORK_TCP => {
+            let server_stream: Box<dyn AsyncStream> = Box::new(QuicStream::from(send, recv));
+            process_tcp_stream(
+                config,
+                source,
+                server_stream,
+                remote_location,
+                stream_reader,
+            )
+            .await
+        }
+        NETWORK_UDP => {
+            // Packets carry their own destinations, so the address of the header is
+            // not used.
+            let mut server_stream = JuicityPacketStream::new(QuicStream::from(send, recv));
+            server_stream.feed_initial_data(stream_reader.unparsed_data())?;
+            run_udp_routing(
+                ServerStream::Targeted(Box::new(server_stream)),
+                config.proxy_selector.clone(),
+                config.resolver.clone(),
+                Some(source),
+                false,
+                RateLimiter::default(),
+                RateLimiter::default(),
+            )
+            .await
+        }
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown network: {network}"),
+        )),
+    }
+}
+
+async fn process_tcp_stream(
+    config: Arc<JuicityServerConfig>,
+    source: IpAddr,
+    mut server_stream: Box<dyn AsyncStream>,
+    remote_location: NetLocation,
+    stream_reader: StreamReader,
+) -> io::Result<()> {
+    let setup_client_stream_future = timeout(
+        Duration::from_secs(60),
+        setup_client_tcp_stream(
+            &mut server_stream,
+            config.proxy_selector.clone(),
+            config.resolver.clone(),
+            remote_location.clone(),
+            Some(source),
+        ),
+    );
+
+    let mut client_stream = match setup_client_stream_future.await {
+        Ok(Ok(Some((s, _)))) => s,
+        Ok(Ok(None)) => {
+            // Must have been blocked.
+            let _ = server_stream.shutdown().await;
+            return Ok(());
+        }
+        Ok(Err(e)) => {
+            let _ = server_stream.shutdown().await;
+            return Err(io::Error::new(
+                e.kind(),
+                format!("failed to setup client stream to {remote_location}: {e}"),
+            ));
+        }
+        Err(elapsed) => {
+            let _ = server_stream.shutdown().await;
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("client setup to {remote_location} timed out: {elapsed}"),
+            ));
+        }
+    };
+
+    let unparsed_data = stream_reader.unparsed_data();
+    let client_requires_flush = if unparsed_data.is_empty() {
+        false
+    } else {
+        write_all(&mut client_stream, unparsed_data).await?;
+        true
+    };
+    drop(stream_reader);
+
+    let copy_result = copy_bidirectional_with_sizes(
+        &mut server_stream,
+        &mut client_stream,
+        false, // no need to flush since it's QUIC
+        client_requires_flush,
+        32768,
+        32768,
+    )
+    .await;
+
+    let (_, _) = futures::join!(server_stream.shutdown(), client_stream.shutdown());
+
+    copy_result
+}
+
+pub async fn start_juicity_server(
+    bind_address: SocketAddr,
+    quic_server_config: Arc<quinn::crypto::rustls::QuicServerConfig>,
+    users: Arc<TuicUsers>,
+    client_proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    num_endpoints: usize,
+    access_control: Option<Arc<AccessControl>>,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    let config = Arc::new(JuicityServerConfig {
+        users,
+        proxy_selector: client_proxy_selector,
+        resolver,
+    });
+
+    let mut join_handles = vec![];
+    for _ in 0..num_endpoints {
+        let quic_server_config = quic_server_config.clone();
+        let config = config.clone();
+        let access_control = access_control.clone();
+
+        let join_handle = tokio::spawn(async move {
+            let mut server_config = quinn::ServerConfig::with_crypto(quic_server_config);
+
+            Arc::get_mut(&mut server_config.transport)
+                .unwrap()
+                .max_concurrent_bidi_streams(4096_u32.into())
+                // the client authenticates on a unidirectional stream
+                .max_concurrent_uni_streams(16_u32.into())
+                .max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()))
+                .keep_alive_interval(Some(Duration::from_secs(10)))
+                .send_window(16 * 1024 * 1024)
+                .receive_window((20u32 * 1024 * 1024).into())
+                .stream_receive_window((8u32 * 1024 * 1024).into())
+                .initial_mtu(1200)
+                .min_mtu(1200)
+                .mtu_discovery_config(Some(quinn::MtuDiscoveryConfig::default()))
+                .enable_segmentation_offload(true)
+                .initial_rtt(Duration::from_millis(100));
+
+            let socket2_socket = crate::socket_util::new_socket2_udp_socket_with_buffer_size(
+                bind_address.is_ipv6(),
+                None,
+                Some(bind_address),
+                true,
+                Some(8_625_000),
+            )
+            .unwrap();
+
+            let endpoint = quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                Some(server_config),
+                socket2_socket.into(),
+                Arc::new(quinn::TokioRuntime),
+            )
+            .unwrap();
+
+            while let Some(conn) = endpoint.accept().await {
+                let Some(conn) = admit_quic(access_control.as_deref(), conn) else {
+                    continue;
+                };
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = process_connection(config, conn).await {
+                        error!("Juicity connection ended with error: {e}");
+                    }
+                });
+            }
+        });
+        join_handles.push(join_handle);
+    }
+
+    Ok(join_handles)
+}
@@ -0,0 +1,32 @@
+//! Juicity proxying over QUIC.
+//!
+//! Clients authenticate each connection with the TUIC v5 authenticate command, sent on
+//! a unidirectional stream:
+//! ```text
+//! | version (5) | command (0) | uuid | token |
+//! | u8          | u8          | 16   | 32    |
+//! ```
+//! where the token is exported from the TLS session, with the uuid as the label and
+//! the password as the context.
+//!
+//! Each TCP connection and UDP flow is then a bidirectional stream, that starts with
+//! the network and the target in SOCKS5 format:
+//! ```text
+//! | network | ATYP | address  | port  |
+//! | u8      | u8   | variable | u16be |
+//! ```
+//! TCP streams carry the data of the connection after the header. UDP streams carry
+//! packets that each have their own address, see `juicity_packet_stream`.
+
+mod juicity_client;
+mod juicity_packet_stream;
+mod juicity_server;
+
+pub use juicity_client::JuicitySocketConnector;
+pub use juicity_server::start_juicity_server;
+
+const AUTH_VERSION: u8 = 5;
+const AUTH_COMMAND: u8 = 0;
+
+const NETWORK_TCP: u8 = 1;
+const NETWORK_UDP: u8 = 3;
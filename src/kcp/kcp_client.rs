@@ -0,0 +1,106 @@
+//! Client side of the KCP transport.
+//!
+//! Connections to the server are streams of a single smux session over a KCP
+//! connection, like kcptun with one connection. A new session is started when the
+//! previous one closed or the server address changed.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::debug;
+use tokio::sync::Mutex;
+
+use super::kcp_conn::{KcpConn, KcpSettings};
+use super::kcp_smux::{SmuxSession, SmuxStream};
+use crate::config::KcpConfig;
+use crate::socket_util::{DialOptions, new_udp_socket};
+
+#[derive(Debug)]
+pub struct KcpConnector {
+    settings: KcpSettings,
+    session: Mutex<Option<(SocketAddr, Arc<SmuxSession>)>>,
+}
+
+impl KcpConnector {
+    pub fn new(config: KcpConfig) -> io::Result<Self> {
+        Ok(Self {
+            settings: KcpSettings::new(config, false)?,
+            session: Mutex::new(None),
+        })
+    }
+
+    /// Opens a stream to the KCP server at `remote_addr`.
+    pub async fn connect(
+        &self,
+        remote_addr: SocketAddr,
+        dial_options: &DialOptions,
+    ) -> io::Result<SmuxStream> {
+        let session = {
+            // Held while connecting, so that concurrent connects share the new session.
+            let mut session = self.session.lock().await;
+            match session.as_ref() {
+                Some((addr, existing)) if *addr == remote_addr && !existing.is_closed() => {
+                    existing.clone()
+                }
+                _ => {
+                    debug!("Opening KCP connection to {remote_addr}");
+                    let socket = new_udp_socket(remote_addr.is_ipv6(), dial_options)?;
+                    let conn = KcpConn::connect(socket, remote_addr, &self.settings)?;
+                    let new_session =
+                        SmuxSession::client(Box::new(conn), self.settings.config().smuxver);
+                    *session = Some((remote_addr, new_session.clone()));
+                    new_session
+                }
+            }
+        };
+        session.open_stream().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kcp::kcp_conn::KcpListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::UdpSocket;
+
+    #[tokio::test]
+    async fn test_connector_shares_session() {
+        let config = KcpConfig::default();
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let mut listener = KcpListener::new(
+            server_socket,
+            KcpSettings::new(config.clone(), true).unwrap(),
+        );
+
+        let connector = KcpConnector::new(config).unwrap();
+        let dial_options = DialOptions::default();
+        let mut first = connector.connect(server_addr, &dial_options).await.unwrap();
+        let mut second = connector.connect(server_addr, &dial_options).await.unwrap();
+        first.write_all(b"first").await.unwrap();
+        second.write_all(b"second").await.unwrap();
+
+        let conn = listener.accept().await.unwrap();
+        let session = SmuxSession::server(Box::new(conn));
+        let mut buf = [0u8; 5];
+        session
+            .accept()
+            .await
+            .unwrap()
+            .read_exact(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"first");
+        let mut buf = [0u8; 6];
+        session
+            .accept()
+            .await
+            .unwrap()
+            .read_exact(&mut buf)
+            .await
+            .unwrap();
+        assert_eq!(&buf, b"second");
+    }
+}
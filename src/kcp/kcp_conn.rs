@@ -0,0 +1,714 @@
+//! KCP connections over UDP.
+//!
+//! Each connection has a task that feeds the packets of the peer to KCP, flushes KCP
+//! on its timer and after writes, and sends the packets through the packet layer: FEC
+//! and then encryption. Received packets are decrypted before they're passed to the
+//! task, by the listener or by the client's reader.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, mpsc};
+
+use super::kcp_crypt::{CRYPT_HEADER_LEN, KcpCrypt};
+use super::kcp_fec::{
+    FEC_DATA_HEADER_LEN, FEC_HEADER_LEN, FecDecoder, FecEncoder, TYPE_DATA, packet_type,
+};
+use super::kcp_protocol::{Kcp, OVERHEAD, SN_OFFSET};
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::config::KcpConfig;
+
+/// Number of received packets queued for a connection before they are dropped.
+const PACKET_CHANNEL_BUFFER: usize = 1024;
+
+/// Number of accepted connections queued for the listener.
+const ACCEPT_CHANNEL_BUFFER: usize = 128;
+
+/// Time that a closed connection keeps retransmitting unacknowledged data.
+const CLOSE_LINGER: Duration = Duration::from_secs(10);
+
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// Settings of one side of KCP connections.
+#[derive(Debug, Clone)]
+pub struct KcpSettings {
+    config: KcpConfig,
+    sndwnd: u32,
+    rcvwnd: u32,
+    crypt: Option<Arc<KcpCrypt>>,
+}
+
+impl KcpSettings {
+    pub fn new(config: KcpConfig, is_server: bool) -> io::Result<Self> {
+        let (default_sndwnd, default_rcvwnd) = if is_server { (1024, 1024) } else { (128, 512) };
+        let crypt = KcpCrypt::new(config.crypt, &config.key)?.map(Arc::new);
+        Ok(Self {
+            sndwnd: config.sndwnd.unwrap_or(default_sndwnd),
+            rcvwnd: config.rcvwnd.unwrap_or(default_rcvwnd),
+            crypt,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &KcpConfig {
+        &self.config
+    }
+
+    fn fec_enabled(&self) -> bool {
+        self.config.datashard > 0 && self.config.parityshard > 0
+    }
+
+    fn crypt_header_len(&self) -> usize {
+        if self.crypt.is_some() {
+            CRYPT_HEADER_LEN
+        } else {
+            0
+        }
+    }
+
+    fn new_kcp(&self, conv: u32) -> io::Result<Kcp> {
+        let mut kcp = Kcp::new(conv);
+        let mut reserved = self.crypt_header_len();
+        if self.fec_enabled() {
+            reserved += FEC_DATA_HEADER_LEN;
+        }
+        let invalid_mtu = |e| io::Error::new(io::ErrorKind::InvalidInput, format!("{e}"));
+        kcp.set_mtu(self.config.mtu).map_err(invalid_mtu)?;
+        kcp.set_reserved(reserved).map_err(invalid_mtu)?;
+        kcp.set_stream_mode(true);
+        kcp.set_wndsize(self.sndwnd, self.rcvwnd);
+        let (nodelay, interval, resend, nc) = self.config.nodelay_params();
+        kcp.set_nodelay(nodelay, interval, resend, nc);
+        Ok(kcp)
+    }
+
+    /// Decrypts a received packet, and returns the packet without the crypt header.
+    fn open<'a>(&self, packet: &'a mut [u8]) -> Option<&'a [u8]> {
+        match &self.crypt {
+            Some(crypt) => {
+                if !crypt.decrypt(packet) {
+                    return None;
+                }
+                Some(&packet[CRYPT_HEADER_LEN..])
+            }
+            None => Some(packet),
+        }
+    }
+}
+
+/// FEC and encryption of the packets that a connection sends.
+struct PacketEncoder {
+    crypt: Option<Arc<KcpCrypt>>,
+    crypt_header_len: usize,
+    fec_encoder: Option<FecEncoder>,
+}
+
+impl PacketEncoder {
+    fn new(settings: &KcpSettings) -> Self {
+        Self {
+            crypt: settings.crypt.clone(),
+            crypt_header_len: settings.crypt_header_len(),
+            fec_encoder: settings
+                .fec_enabled()
+                .then(|| FecEncoder::new(settings.config.datashard, settings.config.parityshard)),
+        }
+    }
+
+    /// Turns a KCP packet with the reserved header bytes into the UDP packets to send.
+    fn encode(&mut self, packet: &[u8], output: &mut Vec<Vec<u8>>) {
+        let mut packet = packet.to_vec();
+        let mut parity_packets = vec![];
+        if let Some(encoder) = self.fec_encoder.as_mut() {
+            parity_packets = encoder.encode(&mut packet[self.crypt_header_len..]);
+        }
+        self.seal(&mut packet);
+        output.push(packet);
+
+        for parity in parity_packets {
+            let mut packet = vec![0u8; self.crypt_header_len];
+            packet.extend_from_slice(&parity);
+            self.seal(&mut packet);
+            output.push(packet);
+        }
+    }
+
+    fn seal(&self, packet: &mut [u8]) {
+        if let Some(crypt) = &self.crypt {
+            crypt.encrypt(packet);
+        }
+    }
+}
+
+/// FEC of the packets that a connection receives.
+struct PacketDecoder {
+    fec_decoder: Option<FecDecoder>,
+}
+
+impl PacketDecoder {
+    fn new(settings: &KcpSettings) -> Self {
+        Self {
+            fec_decoder: settings
+                .fec_enabled()
+                .then(|| FecDecoder::new(settings.config.datashard, settings.config.parityshard)),
+        }
+    }
+
+    /// Passes a decrypted packet to KCP, with the packets that FEC recovered.
+    fn input(&mut self, kcp: &mut Kcp, packet: &[u8]) {
+        let result = match packet_type(packet) {
+            Some(flag) => {
+                let recovered = match self.fec_decoder.as_mut() {
+                    Some(decoder) => decoder.decode(packet),
+                    None => vec![],
+                };
+                for packet in recovered {
+                    let _ = kcp.input(&packet);
+                }
+                if flag == TYPE_DATA {
+                    kcp.input(&packet[FEC_DATA_HEADER_LEN..])
+                } else {
+                    Ok(0)
+                }
+            }
+            None => kcp.input(packet),
+        };
+        if let Err(e) = result {
+            debug!("Dropping KCP packet: {e}");
+        }
+    }
+}
+
+/// Returns the conversation ID and sequence number of a decrypted packet, if it's a
+/// KCP packet or a FEC data packet.
+fn parse_conv(packet: &[u8]) -> Option<(u32, u32)> {
+    let kcp_packet = match packet_type(packet) {
+        Some(TYPE_DATA) => &packet[FEC_DATA_HEADER_LEN..],
+        Some(_) => return None,
+        None => packet,
+    };
+    if kcp_packet.len() < OVERHEAD {
+        return None;
+    }
+    let conv = u32::from_le_bytes(kcp_packet[0..4].try_into().unwrap());
+    let sn = u32::from_le_bytes(kcp_packet[SN_OFFSET..SN_OFFSET + 4].try_into().unwrap());
+    Some((conv, sn))
+}
+
+struct KcpState {
+    kcp: Kcp,
+    read_buffer: Vec<u8>,
+    read_pos: usize,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// Set when the connection is shut down or dropped.
+    closed: bool,
+    /// Set when the peer stopped acknowledging data, or the connection task ended.
+    broken: bool,
+}
+
+impl KcpState {
+    fn wake_reader(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn wake_writer(&mut self) {
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn can_write(&self) -> bool {
+        self.kcp.wait_snd() < self.kcp.snd_wnd() as usize
+    }
+}
+
+struct KcpShared {
+    state: Mutex<KcpState>,
+    /// Wakes the connection task to flush written data.
+    flush: Notify,
+}
+
+/// A reliable byte stream over KCP.
+pub struct KcpConn {
+    shared: Arc<KcpShared>,
+    peer_addr: SocketAddr,
+}
+
+impl std::fmt::Debug for KcpConn {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KcpConn")
+            .field("peer_addr", &self.peer_addr)
+            .finish()
+    }
+}
+
+impl KcpConn {
+    /// Starts the task of a connection, which ends when `packets` is closed or the
+    /// connection is closed.
+    fn start(
+        kcp: Kcp,
+        settings: &KcpSettings,
+        socket: Arc<UdpSocket>,
+        peer_addr: SocketAddr,
+        packets: mpsc::Receiver<Vec<u8>>,
+    ) -> Self {
+        let shared = Arc::new(KcpShared {
+            state: Mutex::new(KcpState {
+                kcp,
+                read_buffer: Vec::new(),
+                read_pos: 0,
+                read_waker: None,
+                write_waker: None,
+                closed: false,
+                broken: false,
+            }),
+            flush: Notify::new(),
+        });
+        tokio::spawn(run_conn(
+            shared.clone(),
+            PacketEncoder::new(settings),
+            PacketDecoder::new(settings),
+            settings.config.acknodelay,
+            socket,
+            peer_addr,
+            packets,
+        ));
+        Self { shared, peer_addr }
+    }
+
+    /// Connects to a KCP server.
+    pub fn connect(
+        socket: UdpSocket,
+        peer_addr: SocketAddr,
+        settings: &KcpSettings,
+    ) -> io::Result<Self> {
+        let conv = rand::random::<u32>();
+        let kcp = settings.new_kcp(conv)?;
+        let socket = Arc::new(socket);
+        let (packet_tx, packet_rx) = mpsc::channel(PACKET_CHANNEL_BUFFER);
+        tokio::spawn(run_client_reader(
+            socket.clone(),
+            settings.clone(),
+            packet_tx,
+        ));
+        Ok(Self::start(kcp, settings, socket, peer_addr, packet_rx))
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn close(&self) {
+        let mut state = self.shared.state.lock();
+        if !state.closed {
+            state.closed = true;
+            drop(state);
+            self.shared.flush.notify_one();
+        }
+    }
+}
+
+impl Drop for KcpConn {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+async fn run_conn(
+    shared: Arc<KcpShared>,
+    mut encoder: PacketEncoder,
+    mut decoder: PacketDecoder,
+    ack_nodelay: bool,
+    socket: Arc<UdpSocket>,
+    peer_addr: SocketAddr,
+    mut packets: mpsc::Receiver<Vec<u8>>,
+) {
+    let start = Instant::now();
+    let now_ms = || start.elapsed().as_millis() as u32;
+    let mut closed_at = None;
+    let mut outgoing: Vec<Vec<u8>> = vec![];
+
+    enum Event {
+        Timer,
+        Flush,
+        Packet(Vec<u8>),
+    }
+    let mut event = Event::Timer;
+
+    loop {
+        let deadline = {
+            let mut state = shared.state.lock();
+            let state = &mut *state;
+            let current = now_ms();
+            let mut output = |packet: &[u8]| encoder.encode(packet, &mut outgoing);
+
+            match std::mem::replace(&mut event, Event::Timer) {
+                Event::Packet(packet) => {
+                    decoder.input(&mut state.kcp, &packet);
+                    // Process whatever else arrived before flushing.
+                    while let Ok(packet) = packets.try_recv() {
+                        decoder.input(&mut state.kcp, &packet);
+                    }
+                    if ack_nodelay && state.kcp.has_pending_acks() {
+                        state.kcp.flush(current, &mut output);
+                    }
+                    if state.kcp.peek_size().is_some() {
+                        state.wake_reader();
+                    }
+                }
+                Event::Flush => state.kcp.flush(current, &mut output),
+                Event::Timer => {}
+            }
+            state.kcp.update(current, &mut output);
+
+            if state.kcp.is_dead() {
+                debug!("KCP connection to {peer_addr} timed out");
+                state.broken = true;
+            }
+            if state.closed && closed_at.is_none() {
+                closed_at = Some(Instant::now());
+            }
+            let done = state.broken
+                || closed_at.is_some_and(|closed_at| {
+                    state.kcp.wait_snd() == 0 || closed_at.elapsed() >= CLOSE_LINGER
+                });
+            if done {
+                state.broken = true;
+                state.wake_reader();
+                state.wake_writer();
+                None
+            } else {
+                if state.can_write() {
+                    state.wake_writer();
+                }
+                let next = state.kcp.check(current);
+                Some(
+                    tokio::time::Instant::now()
+                        + Duration::from_millis(next.wrapping_sub(current) as u64),
+                )
+            }
+        };
+
+        for packet in outgoing.drain(..) {
+            if let Err(e) = socket.send_to(&packet, peer_addr).await {
+                debug!("Failed to send KCP packet to {peer_addr}: {e}");
+            }
+        }
+
+        let Some(deadline) = deadline else {
+            break;
+        };
+
+        event = tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => Event::Timer,
+            _ = shared.flush.notified() => Event::Flush,
+            packet = packets.recv() => match packet {
+                Some(packet) => Event::Packet(packet),
+                None => break,
+            },
+        };
+    }
+
+    let mut state = shared.state.lock();
+    state.broken = true;
+    state.wake_reader();
+    state.wake_writer();
+}
+
+/// Reads the packets of a client connection.
+async fn run_client_reader(
+    socket: Arc<UdpSocket>,
+    settings: KcpSettings,
+    packet_tx: mpsc::Sender<Vec<u8>>,
+) {
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let len = tokio::select! {
+            result = socket.recv_from(&mut buf) => match result {
+                Ok((len, _)) => len,
+                Err(e) => {
+                    debug!("KCP socket receive failed: {e}");
+                    continue;
+                }
+            },
+            _ = packet_tx.closed() => break,
+        };
+        let Some(packet) = settings.open(&mut buf[..len]) else {
+            continue;
+        };
+        if packet_tx.try_send(packet.to_vec()).is_err() && packet_tx.is_closed() {
+            break;
+        }
+    }
+}
+
+impl AsyncRead for KcpConn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.shared.state.lock();
+        let state = &mut *state;
+
+        if state.read_pos == state.read_buffer.len() {
+            state.read_buffer.clear();
+            state.read_pos = 0;
+            while state.kcp.recv(&mut state.read_buffer) {}
+        }
+
+        if state.read_pos < state.read_buffer.len() {
+            let n = buf
+                .remaining()
+                .min(state.read_buffer.len() - state.read_pos);
+            buf.put_slice(&state.read_buffer[state.read_pos..state.read_pos + n]);
+            state.read_pos += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        if state.closed {
+            return Poll::Ready(Ok(()));
+        }
+        if state.broken {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "KCP connection timed out",
+            )));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for KcpConn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut state = self.shared.state.lock();
+        if state.broken || state.closed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "KCP connection closed",
+            )));
+        }
+        if !state.can_write() {
+            state.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+
+        let n = buf.len().min(state.kcp.mss() * 16);
+        if let Err(e) = state.kcp.send(&buf[..n]) {
+            return Poll::Ready(Err(io::Error::other(e)));
+        }
+        drop(state);
+        self.shared.flush.notify_one();
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is flushed by the connection task.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for KcpConn {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for KcpConn {}
+
+struct ListenerSession {
+    conv: u32,
+    packet_tx: mpsc::Sender<Vec<u8>>,
+}
+
+/// Accepts KCP connections on a UDP socket.
+pub struct KcpListener {
+    incoming: mpsc::Receiver<KcpConn>,
+}
+
+impl KcpListener {
+    pub fn new(socket: UdpSocket, settings: KcpSettings) -> Self {
+        let (incoming_tx, incoming) = mpsc::channel(ACCEPT_CHANNEL_BUFFER);
+        tokio::spawn(run_listener(Arc::new(socket), settings, incoming_tx));
+        Self { incoming }
+    }
+
+    pub async fn accept(&mut self) -> Option<KcpConn> {
+        self.incoming.recv().await
+    }
+}
+
+async fn run_listener(
+    socket: Arc<UdpSocket>,
+    settings: KcpSettings,
+    incoming_tx: mpsc::Sender<KcpConn>,
+) {
+    let mut sessions: HashMap<SocketAddr, ListenerSession> = HashMap::new();
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+    loop {
+        let (len, addr) = tokio::select! {
+            result = socket.recv_from(&mut buf) => match result {
+                Ok(v) => v,
+                Err(e) => {
+                    debug!("KCP socket receive failed: {e}");
+                    continue;
+                }
+            },
+            _ = incoming_tx.closed() => break,
+        };
+        let Some(packet) = settings.open(&mut buf[..len]) else {
+            continue;
+        };
+        let conv_and_sn = parse_conv(packet);
+
+        if let Some(session) = sessions.get(&addr)
+            && !session.packet_tx.is_closed()
+        {
+            match conv_and_sn {
+                Some((conv, sn)) if conv != session.conv => {
+                    // A new conversation from the same address replaces the old one,
+                    // when it starts from the beginning.
+                    if sn != 0 {
+                        continue;
+                    }
+                }
+                _ => {
+                    let _ = session.packet_tx.try_send(packet.to_vec());
+                    continue;
+                }
+            }
+        }
+
+        let Some((conv, _)) = conv_and_sn else {
+            continue;
+        };
+        let kcp = match settings.new_kcp(conv) {
+            Ok(kcp) => kcp,
+            Err(e) => {
+                debug!("Failed to create KCP connection: {e}");
+                continue;
+            }
+        };
+
+        sessions.retain(|_, session| !session.packet_tx.is_closed());
+        let (packet_tx, packet_rx) = mpsc::channel(PACKET_CHANNEL_BUFFER);
+        let _ = packet_tx.try_send(packet.to_vec());
+        let conn = KcpConn::start(kcp, &settings, socket.clone(), addr, packet_rx);
+        if incoming_tx.try_send(conn).is_err() {
+            debug!("Dropping KCP connection from {addr}, too many pending connections");
+            continue;
+        }
+        sessions.insert(addr, ListenerSession { conv, packet_tx });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KcpCryptMethod;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn connect_pair(config: KcpConfig) -> (KcpConn, KcpConn) {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = server_socket.local_addr().unwrap();
+        let mut listener = KcpListener::new(
+            server_socket,
+            KcpSettings::new(config.clone(), true).unwrap(),
+        );
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut client = KcpConn::connect(
+            client_socket,
+            server_addr,
+            &KcpSettings::new(config, false).unwrap(),
+        )
+        .unwrap();
+        // The server only learns about the connection from its first packet.
+        client.write_all(b"ping").await.unwrap();
+
+        let mut server = listener.accept().await.unwrap();
+        let mut buf = [0u8; 4];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+        (client, server)
+    }
+
+    async fn check_transfer(config: KcpConfig) {
+        let (mut client, mut server) = connect_pair(config).await;
+
+        let data: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client
+        });
+
+        let mut received = vec![0u8; expected.len()];
+        server.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+
+        server.write_all(b"done").await.unwrap();
+        let mut client = writer.await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"done");
+    }
+
+    #[tokio::test]
+    async fn test_kcp_transfer() {
+        check_transfer(KcpConfig::default()).await;
+    }
+
+    #[tokio::test]
+    async fn test_kcp_transfer_without_fec_and_crypt() {
+        check_transfer(KcpConfig {
+            crypt: KcpCryptMethod::Null,
+            datashard: 0,
+            parityshard: 0,
+            ..Default::default()
+        })
+        .await;
+    }
+
+    #[test]
+    fn test_parse_conv() {
+        let mut kcp = Kcp::new(0x1234);
+        kcp.set_nodelay(0, 100, 0, true);
+        kcp.send(b"hello").unwrap();
+        let mut packets = vec![];
+        kcp.update(0, &mut |p: &[u8]| packets.push(p.to_vec()));
+        assert_eq!(parse_conv(&packets[0]), Some((0x1234, 0)));
+
+        let mut fec_packet = vec![0u8; FEC_DATA_HEADER_LEN];
+        fec_packet.extend_from_slice(&packets[0]);
+        FecEncoder::new(2, 1).encode(&mut fec_packet);
+        assert_eq!(parse_conv(&fec_packet), Some((0x1234, 0)));
+        assert_eq!(parse_conv(&fec_packet[..FEC_HEADER_LEN + 4]), None);
+    }
+}
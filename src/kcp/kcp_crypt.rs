@@ -0,0 +1,316 @@
+//! Packet encryption of kcptun.
+//!
+//! Encrypted packets start with a random 16 byte nonce and the CRC32 of the rest of
+//! the packet, and the whole packet is encrypted. The key is derived from the
+//! configured key with PBKDF2, like kcptun does.
+
+use std::num::NonZeroU32;
+
+use aws_lc_rs::cipher::{
+    AES_128, AES_256, DecryptingKey, DecryptionContext, EncryptingKey, EncryptionContext,
+    UnboundCipherKey,
+};
+use aws_lc_rs::iv::FixedLength;
+use aws_lc_rs::pbkdf2;
+use rand::RngCore;
+
+use crate::config::KcpCryptMethod;
+
+/// Length of the nonce and checksum.
+pub const CRYPT_HEADER_LEN: usize = 20;
+
+const NONCE_LEN: usize = 16;
+
+/// Salt of the key derivation of kcptun.
+const KEY_SALT: &[u8] = b"kcp-go";
+const KEY_ITERATIONS: u32 = 4096;
+
+/// Salt and length of the table of the xor cipher of kcp-go.
+const XOR_SALT: &[u8] = b"sH3CIVoF#rWLtJo6";
+const XOR_TABLE_LEN: usize = 1500;
+
+/// Initialization vector of the AES-CFB cipher of kcp-go.
+const AES_IV: [u8; 16] = [
+    167, 115, 79, 156, 18, 172, 27, 1, 164, 21, 242, 193, 252, 120, 230, 107,
+];
+
+enum Cipher {
+    /// Adds the nonce and checksum without encrypting.
+    None,
+    Aes {
+        encrypting_key: EncryptingKey,
+        decrypting_key: DecryptingKey,
+    },
+    Salsa20([u8; 32]),
+    Xor(Vec<u8>),
+}
+
+pub struct KcpCrypt {
+    cipher: Cipher,
+}
+
+impl std::fmt::Debug for KcpCrypt {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self.cipher {
+            Cipher::None => "none",
+            Cipher::Aes { .. } => "aes",
+            Cipher::Salsa20(_) => "salsa20",
+            Cipher::Xor(_) => "xor",
+        };
+        f.debug_struct("KcpCrypt").field("cipher", &name).finish()
+    }
+}
+
+fn derive_key(secret: &[u8], salt: &[u8], iterations: u32, out: &mut [u8]) {
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA1,
+        NonZeroU32::new(iterations).unwrap(),
+        salt,
+        secret,
+        out,
+    );
+}
+
+impl KcpCrypt {
+    /// Returns None for `null`, which sends packets without a crypt header.
+    pub fn new(method: KcpCryptMethod, key: &str) -> std::io::Result<Option<Self>> {
+        let mut pass = [0u8; 32];
+        derive_key(key.as_bytes(), KEY_SALT, KEY_ITERATIONS, &mut pass);
+
+        let cipher = match method {
+            KcpCryptMethod::Null => return Ok(None),
+            KcpCryptMethod::None => Cipher::None,
+            KcpCryptMethod::Aes => Self::aes_cipher(&pass)?,
+            KcpCryptMethod::Aes128 => Self::aes_cipher(&pass[..16])?,
+            KcpCryptMethod::Salsa20 => Cipher::Salsa20(pass),
+            KcpCryptMethod::Xor => {
+                let mut table = vec![0u8; XOR_TABLE_LEN];
+                derive_key(&pass, XOR_SALT, 32, &mut table);
+                Cipher::Xor(table)
+            }
+        };
+        Ok(Some(Self { cipher }))
+    }
+
+    fn aes_cipher(key: &[u8]) -> std::io::Result<Cipher> {
+        let algorithm = if key.len() == 16 { &AES_128 } else { &AES_256 };
+        let invalid_key =
+            |_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid KCP AES key");
+        let encrypting_key =
+            EncryptingKey::cfb128(UnboundCipherKey::new(algorithm, key).map_err(invalid_key)?)
+                .map_err(invalid_key)?;
+        let decrypting_key =
+            DecryptingKey::cfb128(UnboundCipherKey::new(algorithm, key).map_err(invalid_key)?)
+                .map_err(invalid_key)?;
+        Ok(Cipher::Aes {
+            encrypting_key,
+            decrypting_key,
+        })
+    }
+
+    /// Fills in the crypt header at the start of `packet` and encrypts it.
+    pub fn encrypt(&self, packet: &mut [u8]) {
+        rand::rng().fill_bytes(&mut packet[..NONCE_LEN]);
+        let checksum = crc32(&packet[CRYPT_HEADER_LEN..]);
+        packet[NONCE_LEN..CRYPT_HEADER_LEN].copy_from_slice(&checksum.to_le_bytes());
+
+        match &self.cipher {
+            Cipher::None => {}
+            Cipher::Aes { encrypting_key, .. } => {
+                encrypting_key
+                    .less_safe_encrypt(packet, EncryptionContext::Iv128(FixedLength::from(AES_IV)))
+                    .expect("AES-CFB encryption failed");
+            }
+            Cipher::Salsa20(key) => {
+                let nonce: [u8; 8] = packet[..8].try_into().unwrap();
+                salsa20_xor(key, &nonce, &mut packet[8..]);
+            }
+            Cipher::Xor(table) => xor_with_table(table, packet),
+        }
+    }
+
+    /// Decrypts `packet` in place, and returns whether its checksum is valid.
+    pub fn decrypt(&self, packet: &mut [u8]) -> bool {
+        if packet.len() < CRYPT_HEADER_LEN {
+            return false;
+        }
+
+        match &self.cipher {
+            Cipher::None => {}
+            Cipher::Aes { decrypting_key, .. } => {
+                if decrypting_key
+                    .decrypt(packet, DecryptionContext::Iv128(FixedLength::from(AES_IV)))
+                    .is_err()
+                {
+                    return false;
+                }
+            }
+            Cipher::Salsa20(key) => {
+                let nonce: [u8; 8] = packet[..8].try_into().unwrap();
+                salsa20_xor(key, &nonce, &mut packet[8..]);
+            }
+            Cipher::Xor(table) => xor_with_table(table, packet),
+        }
+
+        let checksum = u32::from_le_bytes(packet[NONCE_LEN..CRYPT_HEADER_LEN].try_into().unwrap());
+        checksum == crc32(&packet[CRYPT_HEADER_LEN..])
+    }
+}
+
+fn xor_with_table(table: &[u8], packet: &mut [u8]) {
+    for (byte, key) in packet.iter_mut().zip(table.iter()) {
+        *byte ^= key;
+    }
+}
+
+/// CRC32 with the IEEE polynomial.
+fn crc32(data: &[u8]) -> u32 {
+    static TABLE: std::sync::OnceLock<[u32; 256]> = std::sync::OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0u32; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 {
+                    (crc >> 1) ^ 0xedb88320
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        table
+    });
+
+    let mut crc = !0u32;
+    for &byte in data {
+        crc = table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn salsa20_block(key: &[u8; 32], nonce: &[u8; 8], counter: u64) -> [u8; 64] {
+    const SIGMA: &[u8; 16] = b"expand 32-byte k";
+    let word = |b: &[u8]| u32::from_le_bytes(b.try_into().unwrap());
+
+    let mut input = [0u32; 16];
+    input[0] = word(&SIGMA[0..4]);
+    input[5] = word(&SIGMA[4..8]);
+    input[10] = word(&SIGMA[8..12]);
+    input[15] = word(&SIGMA[12..16]);
+    for i in 0..4 {
+        input[1 + i] = word(&key[i * 4..i * 4 + 4]);
+        input[11 + i] = word(&key[16 + i * 4..16 + i * 4 + 4]);
+    }
+    input[6] = word(&nonce[0..4]);
+    input[7] = word(&nonce[4..8]);
+    input[8] = counter as u32;
+    input[9] = (counter >> 32) as u32;
+
+    let mut x = input;
+    let quarter_round = |x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize| {
+        x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+        x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+        x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+        x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+    };
+    for _ in 0..10 {
+        quarter_round(&mut x, 0, 4, 8, 12);
+        quarter_round(&mut x, 5, 9, 13, 1);
+        quarter_round(&mut x, 10, 14, 2, 6);
+        quarter_round(&mut x, 15, 3, 7, 11);
+        quarter_round(&mut x, 0, 1, 2, 3);
+        quarter_round(&mut x, 5, 6, 7, 4);
+        quarter_round(&mut x, 10, 11, 8, 9);
+        quarter_round(&mut x, 15, 12, 13, 14);
+    }
+
+    let mut block = [0u8; 64];
+    for i in 0..16 {
+        block[i * 4..i * 4 + 4].copy_from_slice(&x[i].wrapping_add(input[i]).to_le_bytes());
+    }
+    block
+}
+
+/// Salsa20/20 with a 256 bit key, starting at block 0.
+fn salsa20_xor(key: &[u8; 32], nonce: &[u8; 8], data: &mut [u8]) {
+    for (counter, chunk) in data.chunks_mut(64).enumerate() {
+        let block = salsa20_block(key, nonce, counter as u64);
+        for (byte, key) in chunk.iter_mut().zip(block.iter()) {
+            *byte ^= key;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn test_salsa20() {
+        // ECRYPT Salsa20 256 bit key, set 1, vector 0.
+        let mut key = [0u8; 32];
+        key[0] = 0x80;
+        let mut data = [0u8; 64];
+        salsa20_xor(&key, &[0u8; 8], &mut data);
+        assert_eq!(
+            data[..16],
+            [
+                0xe3, 0xbe, 0x8f, 0xdd, 0x8b, 0xec, 0xa2, 0xe3, 0xea, 0x8e, 0xf9, 0x47, 0x5b, 0x29,
+                0xa6, 0xe7
+            ]
+        );
+        assert_eq!(
+            data[48..],
+            [
+                0x44, 0x65, 0x2a, 0x83, 0xe7, 0x2a, 0x9c, 0x46, 0x18, 0x76, 0xaf, 0x4d, 0x7e, 0xf1,
+                0xa1, 0x17
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        for method in [
+            KcpCryptMethod::None,
+            KcpCryptMethod::Aes,
+            KcpCryptMethod::Aes128,
+            KcpCryptMethod::Salsa20,
+            KcpCryptMethod::Xor,
+        ] {
+            let crypt = KcpCrypt::new(method, "it's a secrect").unwrap().unwrap();
+            let mut packet = vec![0u8; CRYPT_HEADER_LEN];
+            packet.extend_from_slice(&[7u8; 200]);
+            crypt.encrypt(&mut packet);
+            if method != KcpCryptMethod::None {
+                assert_ne!(&packet[CRYPT_HEADER_LEN..], &[7u8; 200][..]);
+            }
+
+            let mut tampered = packet.clone();
+            tampered[100] ^= 1;
+            assert!(crypt.decrypt(&mut packet), "{method:?}");
+            assert_eq!(&packet[CRYPT_HEADER_LEN..], &[7u8; 200][..]);
+            assert!(!crypt.decrypt(&mut tampered), "{method:?}");
+        }
+        assert!(
+            KcpCrypt::new(KcpCryptMethod::Null, "key")
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_different_keys() {
+        let crypt = KcpCrypt::new(KcpCryptMethod::Aes, "key1").unwrap().unwrap();
+        let other = KcpCrypt::new(KcpCryptMethod::Aes, "key2").unwrap().unwrap();
+        let mut packet = vec![0u8; CRYPT_HEADER_LEN + 64];
+        crypt.encrypt(&mut packet);
+        assert!(!other.decrypt(&mut packet));
+    }
+}
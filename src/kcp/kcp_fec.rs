@@ -0,0 +1,504 @@
+//! Forward error correction of KCP packets, compatible with kcp-go.
+//!
+//! Packets are sent in groups of `data_shards` data packets followed by
+//! `parity_shards` Reed-Solomon parity packets, so that a group with up to
+//! `parity_shards` lost packets can be recovered without retransmissions.
+//!
+//! Every packet starts with a header of the sequence number (u32 LE) and the type
+//! (u16 LE). Data packets then have the length of the rest of the packet including the
+//! length field itself (u16 LE), and the parity is computed over the data packets from
+//! the length field on, padded with zeros to the longest one.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::OnceLock;
+
+/// Length of the sequence number and type.
+pub const FEC_HEADER_LEN: usize = 6;
+
+/// Length of the header of data packets, with the length field.
+pub const FEC_DATA_HEADER_LEN: usize = FEC_HEADER_LEN + 2;
+
+pub const TYPE_DATA: u16 = 0xf1;
+pub const TYPE_PARITY: u16 = 0xf2;
+
+/// Number of groups kept for recovery, later packets of older groups are dropped.
+const MAX_PENDING_GROUPS: usize = 8;
+
+/// Returns the type of a FEC packet, or None if the packet isn't one.
+pub fn packet_type(packet: &[u8]) -> Option<u16> {
+    if packet.len() < FEC_DATA_HEADER_LEN {
+        return None;
+    }
+    match u16::from_le_bytes([packet[4], packet[5]]) {
+        t @ (TYPE_DATA | TYPE_PARITY) => Some(t),
+        _ => None,
+    }
+}
+
+struct GaloisTables {
+    exp: [u8; 510],
+    log: [u8; 256],
+    mul: Vec<[u8; 256]>,
+}
+
+/// Tables of GF(2^8) with the polynomial 0x11d.
+fn galois() -> &'static GaloisTables {
+    static TABLES: OnceLock<GaloisTables> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut exp = [0u8; 510];
+        let mut log = [0u8; 256];
+        let mut x = 1u16;
+        for i in 0..255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        let mut mul = vec![[0u8; 256]; 256];
+        for a in 1..256 {
+            for b in 1..256 {
+                mul[a][b] = exp[log[a] as usize + log[b] as usize];
+            }
+        }
+        GaloisTables { exp, log, mul }
+    })
+}
+
+fn gal_mul(a: u8, b: u8) -> u8 {
+    galois().mul[a as usize][b as usize]
+}
+
+fn gal_div(a: u8, b: u8) -> u8 {
+    debug_assert!(b != 0);
+    if a == 0 {
+        return 0;
+    }
+    let tables = galois();
+    let diff = (tables.log[a as usize] as usize + 255 - tables.log[b as usize] as usize) % 255;
+    tables.exp[diff]
+}
+
+fn gal_exp(a: u8, n: usize) -> u8 {
+    if n == 0 {
+        return 1;
+    }
+    if a == 0 {
+        return 0;
+    }
+    let tables = galois();
+    tables.exp[(tables.log[a as usize] as usize * n) % 255]
+}
+
+type Matrix = Vec<Vec<u8>>;
+
+fn mat_mul(a: &Matrix, b: &Matrix) -> Matrix {
+    let mut result = vec![vec![0u8; b[0].len()]; a.len()];
+    for (r, row) in result.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            for (k, b_row) in b.iter().enumerate() {
+                *value ^= gal_mul(a[r][k], b_row[c]);
+            }
+        }
+    }
+    result
+}
+
+fn mat_invert(matrix: &Matrix) -> Option<Matrix> {
+    let n = matrix.len();
+    let mut work: Matrix = matrix
+        .iter()
+        .enumerate()
+        .map(|(r, row)| {
+            let mut row = row.clone();
+            row.extend((0..n).map(|c| (c == r) as u8));
+            row
+        })
+        .collect();
+
+    for r in 0..n {
+        if work[r][r] == 0 {
+            let swap = (r + 1..n).find(|&below| work[below][r] != 0)?;
+            work.swap(r, swap);
+        }
+        let scale = work[r][r];
+        if scale != 1 {
+            for value in work[r].iter_mut() {
+                *value = gal_div(*value, scale);
+            }
+        }
+        for other in 0..n {
+            if other != r && work[other][r] != 0 {
+                let factor = work[other][r];
+                for c in 0..2 * n {
+                    let delta = gal_mul(factor, work[r][c]);
+                    work[other][c] ^= delta;
+                }
+            }
+        }
+    }
+
+    Some(work.into_iter().map(|row| row[n..].to_vec()).collect())
+}
+
+/// Multiplies `coefficients` with the rows of `inputs` into `output`.
+fn code_row(coefficients: &[u8], inputs: &[&[u8]], output: &mut [u8]) {
+    output.fill(0);
+    let tables = galois();
+    for (&coefficient, input) in coefficients.iter().zip(inputs) {
+        if coefficient == 0 {
+            continue;
+        }
+        let mul = &tables.mul[coefficient as usize];
+        for (out, &byte) in output.iter_mut().zip(input.iter()) {
+            *out ^= mul[byte as usize];
+        }
+    }
+}
+
+/// Systematic Reed-Solomon code built from a Vandermonde matrix, as in
+/// klauspost/reedsolomon.
+struct ReedSolomon {
+    data_shards: usize,
+    /// Encoding matrix of all shards, the top rows are the identity matrix.
+    matrix: Matrix,
+}
+
+impl ReedSolomon {
+    fn new(data_shards: usize, parity_shards: usize) -> Self {
+        let total_shards = data_shards + parity_shards;
+        let vandermonde: Matrix = (0..total_shards)
+            .map(|r| (0..data_shards).map(|c| gal_exp(r as u8, c)).collect())
+            .collect();
+        let top_inverse = mat_invert(&vandermonde[..data_shards].to_vec())
+            .expect("vandermonde matrix is invertible");
+        Self {
+            data_shards,
+            matrix: mat_mul(&vandermonde, &top_inverse),
+        }
+    }
+
+    fn encode(&self, data: &[&[u8]], parity: &mut [Vec<u8>]) {
+        for (i, output) in parity.iter_mut().enumerate() {
+            code_row(&self.matrix[self.data_shards + i], data, output);
+        }
+    }
+
+    /// Recovers the missing data shards from any `data_shards` present shards of equal
+    /// length, returns false if there are too few.
+    fn reconstruct_data(&self, shards: &mut [Option<Vec<u8>>]) -> bool {
+        let present: Vec<usize> = (0..shards.len())
+            .filter(|&i| shards[i].is_some())
+            .take(self.data_shards)
+            .collect();
+        if present.len() < self.data_shards {
+            return false;
+        }
+        let missing: Vec<usize> = (0..self.data_shards)
+            .filter(|&i| shards[i].is_none())
+            .collect();
+        if missing.is_empty() {
+            return true;
+        }
+
+        let sub_matrix: Matrix = present.iter().map(|&i| self.matrix[i].clone()).collect();
+        let Some(decode_matrix) = mat_invert(&sub_matrix) else {
+            return false;
+        };
+
+        let shard_len = shards[present[0]].as_ref().unwrap().len();
+        let mut recovered = Vec::with_capacity(missing.len());
+        {
+            let inputs: Vec<&[u8]> = present
+                .iter()
+                .map(|&i| shards[i].as_deref().unwrap())
+                .collect();
+            for &i in missing.iter() {
+                let mut output = vec![0u8; shard_len];
+                code_row(&decode_matrix[i], &inputs, &mut output);
+                recovered.push(output);
+            }
+        }
+        for (i, output) in missing.into_iter().zip(recovered) {
+            shards[i] = Some(output);
+        }
+        true
+    }
+}
+
+pub struct FecEncoder {
+    data_shards: usize,
+    parity_shards: usize,
+    codec: ReedSolomon,
+    next: u32,
+    /// Sequence numbers wrap at a multiple of the group size.
+    paws: u32,
+    /// Data packets of the current group, from the length field on.
+    shards: Vec<Vec<u8>>,
+}
+
+impl std::fmt::Debug for FecEncoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FecEncoder")
+            .field("data_shards", &self.data_shards)
+            .field("parity_shards", &self.parity_shards)
+            .finish()
+    }
+}
+
+impl FecEncoder {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Self {
+        assert!(data_shards > 0 && parity_shards > 0 && data_shards + parity_shards <= 255);
+        let shard_size = (data_shards + parity_shards) as u32;
+        Self {
+            data_shards,
+            parity_shards,
+            codec: ReedSolomon::new(data_shards, parity_shards),
+            next: 0,
+            paws: (u32::MAX / shard_size) * shard_size,
+            shards: Vec::with_capacity(data_shards),
+        }
+    }
+
+    /// Fills in the header of a data packet, which starts with `FEC_DATA_HEADER_LEN`
+    /// bytes for it. Returns the parity packets when the packet completes a group.
+    pub fn encode(&mut self, packet: &mut [u8]) -> Vec<Vec<u8>> {
+        packet[0..4].copy_from_slice(&self.next.to_le_bytes());
+        packet[4..6].copy_from_slice(&TYPE_DATA.to_le_bytes());
+        let len = (packet.len() - FEC_HEADER_LEN) as u16;
+        packet[6..8].copy_from_slice(&len.to_le_bytes());
+        self.next = self.next.wrapping_add(1);
+
+        self.shards.push(packet[FEC_HEADER_LEN..].to_vec());
+        if self.shards.len() < self.data_shards {
+            return vec![];
+        }
+
+        let max_len = self.shards.iter().map(Vec::len).max().unwrap();
+        for shard in self.shards.iter_mut() {
+            shard.resize(max_len, 0);
+        }
+        let data: Vec<&[u8]> = self.shards.iter().map(Vec::as_slice).collect();
+        let mut parity = vec![vec![0u8; max_len]; self.parity_shards];
+        self.codec.encode(&data, &mut parity);
+        self.shards.clear();
+
+        parity
+            .into_iter()
+            .map(|shard| {
+                let mut packet = Vec::with_capacity(FEC_HEADER_LEN + shard.len());
+                packet.extend_from_slice(&self.next.to_le_bytes());
+                packet.extend_from_slice(&TYPE_PARITY.to_le_bytes());
+                packet.extend_from_slice(&shard);
+                self.next = (self.next + 1) % self.paws;
+                packet
+            })
+            .collect()
+    }
+}
+
+struct FecGroup {
+    /// Shards from the length field on, by index in the group.
+    shards: Vec<Option<Vec<u8>>>,
+    /// Set once the data packets were all received or recovered.
+    done: bool,
+}
+
+pub struct FecDecoder {
+    data_shards: usize,
+    shard_size: usize,
+    codec: ReedSolomon,
+    groups: HashMap<u32, FecGroup>,
+    /// First sequence numbers of the groups, oldest first.
+    order: VecDeque<u32>,
+}
+
+impl std::fmt::Debug for FecDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FecDecoder")
+            .field("data_shards", &self.data_shards)
+            .field("shard_size", &self.shard_size)
+            .field("pending_groups", &self.groups.len())
+            .finish()
+    }
+}
+
+impl FecDecoder {
+    pub fn new(data_shards: usize, parity_shards: usize) -> Self {
+        assert!(data_shards > 0 && parity_shards > 0 && data_shards + parity_shards <= 255);
+        Self {
+            data_shards,
+            shard_size: data_shards + parity_shards,
+            codec: ReedSolomon::new(data_shards, parity_shards),
+            groups: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Adds a FEC packet to its group, and returns the KCP packets of the data packets
+    /// that the group lost, once enough of the group was received to recover them.
+    pub fn decode(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        if packet_type(packet).is_none() {
+            return vec![];
+        }
+        let seqid = u32::from_le_bytes(packet[0..4].try_into().unwrap());
+        let index = (seqid % self.shard_size as u32) as usize;
+        let group_start = seqid - index as u32;
+
+        if !self.groups.contains_key(&group_start) {
+            if self.order.len() >= MAX_PENDING_GROUPS {
+                let oldest = self.order.pop_front().unwrap();
+                self.groups.remove(&oldest);
+            }
+            self.order.push_back(group_start);
+            self.groups.insert(
+                group_start,
+                FecGroup {
+                    shards: vec![None; self.shard_size],
+                    done: false,
+                },
+            );
+        }
+        let group = self.groups.get_mut(&group_start).unwrap();
+        if group.done || group.shards[index].is_some() {
+            return vec![];
+        }
+        group.shards[index] = Some(packet[FEC_HEADER_LEN..].to_vec());
+
+        let received = group.shards.iter().filter(|s| s.is_some()).count();
+        let received_data = group.shards[..self.data_shards]
+            .iter()
+            .filter(|s| s.is_some())
+            .count();
+        if received_data == self.data_shards {
+            group.done = true;
+            group.shards.clear();
+            return vec![];
+        }
+        if received < self.data_shards {
+            return vec![];
+        }
+
+        let lost: Vec<usize> = (0..self.data_shards)
+            .filter(|&i| group.shards[i].is_none())
+            .collect();
+        let max_len = group.shards.iter().flatten().map(Vec::len).max().unwrap();
+        for shard in group.shards.iter_mut().flatten() {
+            shard.resize(max_len, 0);
+        }
+
+        let mut shards = std::mem::take(&mut group.shards);
+        group.done = true;
+        if !self.codec.reconstruct_data(&mut shards) {
+            return vec![];
+        }
+
+        lost.into_iter()
+            .filter_map(|i| {
+                let shard = shards[i].take()?;
+                let len = u16::from_le_bytes([shard[0], shard[1]]) as usize;
+                if len < 2 || len > shard.len() {
+                    return None;
+                }
+                Some(shard[2..len].to_vec())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data_packet(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0u8; FEC_DATA_HEADER_LEN];
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_matrix_is_systematic() {
+        let codec = ReedSolomon::new(10, 3);
+        for r in 0..10 {
+            for c in 0..10 {
+                assert_eq!(codec.matrix[r][c], (r == c) as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_parity_matches_reedsolomon() {
+        // Same shards as testOneEncode of klauspost/reedsolomon.
+        let codec = ReedSolomon::new(5, 5);
+        let data: Vec<Vec<u8>> = vec![vec![0, 1], vec![4, 5], vec![2, 3], vec![6, 7], vec![8, 9]];
+        let inputs: Vec<&[u8]> = data.iter().map(Vec::as_slice).collect();
+        let mut parity = vec![vec![0u8; 2]; 5];
+        codec.encode(&inputs, &mut parity);
+        assert_eq!(
+            parity,
+            vec![
+                vec![12, 13],
+                vec![10, 11],
+                vec![14, 15],
+                vec![90, 91],
+                vec![94, 95]
+            ]
+        );
+    }
+
+    #[test]
+    fn test_recover_lost_packets() {
+        let mut encoder = FecEncoder::new(4, 2);
+        let mut decoder = FecDecoder::new(4, 2);
+
+        let payloads: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 10 + i as usize * 7]).collect();
+        let mut packets = vec![];
+        for payload in payloads.iter() {
+            let mut packet = data_packet(payload);
+            let parity = encoder.encode(&mut packet);
+            assert_eq!(packet_type(&packet), Some(TYPE_DATA));
+            packets.push(packet);
+            packets.extend(parity);
+        }
+        assert_eq!(packets.len(), 6);
+        assert_eq!(packet_type(&packets[5]), Some(TYPE_PARITY));
+
+        // Lose the first and third data packets.
+        let mut recovered = vec![];
+        for (i, packet) in packets.iter().enumerate() {
+            if i != 0 && i != 2 {
+                recovered.extend(decoder.decode(packet));
+            }
+        }
+        assert_eq!(recovered, vec![payloads[0].clone(), payloads[2].clone()]);
+
+        // Late packets of a finished group are ignored.
+        assert!(decoder.decode(&packets[0]).is_empty());
+    }
+
+    #[test]
+    fn test_no_recovery_without_loss() {
+        let mut encoder = FecEncoder::new(3, 1);
+        let mut decoder = FecDecoder::new(3, 1);
+        for round in 0..5u8 {
+            for i in 0..3u8 {
+                let mut packet = data_packet(&[round, i]);
+                let parity = encoder.encode(&mut packet);
+                assert!(decoder.decode(&packet).is_empty());
+                for parity in parity {
+                    assert!(decoder.decode(&parity).is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_packet_type() {
+        assert_eq!(packet_type(&[0u8; 4]), None);
+        // KCP packets have the command and fragment count at the type offset.
+        assert_eq!(packet_type(&[1, 0, 0, 0, 81, 0, 0, 0]), None);
+        assert_eq!(packet_type(&[1, 0, 0, 0, 0xf2, 0, 0, 0]), Some(TYPE_PARITY));
+    }
+}
@@ -0,0 +1,910 @@
+//! The KCP ARQ protocol, a port of ikcp.
+//!
+//! `Kcp` only keeps state: segments are queued with `send`, packets from the peer are
+//! passed to `input`, and `update` or `flush` hand the packets to send to a callback.
+//! Packets start with `reserved` bytes, which the packet layer fills with its crypt and
+//! FEC headers.
+
+use std::collections::VecDeque;
+
+const RTO_NDL: u32 = 30;
+const RTO_MIN: u32 = 100;
+const RTO_DEF: u32 = 200;
+const RTO_MAX: u32 = 60000;
+
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83;
+const CMD_WINS: u8 = 84;
+
+const ASK_SEND: u32 = 1;
+const ASK_TELL: u32 = 2;
+
+const WND_SND: u32 = 32;
+const WND_RCV: u32 = 128;
+const MTU_DEF: u32 = 1400;
+const INTERVAL: u32 = 100;
+const DEADLINK: u32 = 20;
+const THRESH_INIT: u32 = 2;
+const THRESH_MIN: u32 = 2;
+const PROBE_INIT: u32 = 7000;
+const PROBE_LIMIT: u32 = 120000;
+const FASTACK_LIMIT: u32 = 5;
+
+/// Length of a segment header.
+pub const OVERHEAD: usize = 24;
+
+/// Offset of the sequence number in a segment header.
+pub const SN_OFFSET: usize = 12;
+
+fn time_diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KcpError {
+    /// The packet is shorter than a segment header or a segment is truncated.
+    Truncated,
+    /// The conversation ID doesn't match.
+    WrongConv,
+    /// Unknown segment command.
+    UnknownCommand,
+    /// A message needs more than 255 fragments.
+    TooLarge,
+    /// The MTU is too small for a segment header.
+    InvalidMtu,
+}
+
+impl std::fmt::Display for KcpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KcpError::Truncated => write!(f, "truncated KCP packet"),
+            KcpError::WrongConv => write!(f, "wrong KCP conversation ID"),
+            KcpError::UnknownCommand => write!(f, "unknown KCP command"),
+            KcpError::TooLarge => write!(f, "KCP message is too large"),
+            KcpError::InvalidMtu => write!(f, "invalid KCP MTU"),
+        }
+    }
+}
+
+impl std::error::Error for KcpError {}
+
+#[derive(Debug, Default)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.conv.to_le_bytes());
+        buf.push(self.cmd);
+        buf.push(self.frg);
+        buf.extend_from_slice(&self.wnd.to_le_bytes());
+        buf.extend_from_slice(&self.ts.to_le_bytes());
+        buf.extend_from_slice(&self.sn.to_le_bytes());
+        buf.extend_from_slice(&self.una.to_le_bytes());
+        buf.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+    }
+}
+
+#[derive(Debug)]
+pub struct Kcp {
+    conv: u32,
+    mtu: usize,
+    mss: usize,
+    reserved: usize,
+    dead: bool,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    probe: u32,
+    current: u32,
+    interval: u32,
+    ts_flush: u32,
+    nodelay: u32,
+    updated: bool,
+    ts_probe: u32,
+    probe_wait: u32,
+    dead_link: u32,
+    incr: usize,
+    snd_queue: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    acklist: Vec<(u32, u32)>,
+    fastresend: u32,
+    fastlimit: u32,
+    nocwnd: bool,
+    stream: bool,
+    buffer: Vec<u8>,
+}
+
+impl Kcp {
+    pub fn new(conv: u32) -> Self {
+        let mtu = MTU_DEF as usize;
+        Self {
+            conv,
+            mtu,
+            mss: mtu - OVERHEAD,
+            reserved: 0,
+            dead: false,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: RTO_DEF,
+            rx_minrto: RTO_MIN,
+            snd_wnd: WND_SND,
+            rcv_wnd: WND_RCV,
+            rmt_wnd: WND_RCV,
+            cwnd: 0,
+            probe: 0,
+            current: 0,
+            interval: INTERVAL,
+            ts_flush: INTERVAL,
+            nodelay: 0,
+            updated: false,
+            ts_probe: 0,
+            probe_wait: 0,
+            dead_link: DEADLINK,
+            incr: 0,
+            snd_queue: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            acklist: Vec::new(),
+            fastresend: 0,
+            fastlimit: FASTACK_LIMIT,
+            nocwnd: false,
+            stream: false,
+            buffer: Vec::with_capacity(mtu),
+        }
+    }
+
+    pub fn conv(&self) -> u32 {
+        self.conv
+    }
+
+    pub fn mss(&self) -> usize {
+        self.mss
+    }
+
+    /// Returns true once a segment was sent `dead_link` times without being
+    /// acknowledged.
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// In stream mode, data is a byte stream instead of messages, and small writes are
+    /// merged into segments.
+    pub fn set_stream_mode(&mut self, stream: bool) {
+        self.stream = stream;
+    }
+
+    /// Sets the MTU of packets, including the reserved bytes.
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<(), KcpError> {
+        if mtu < 50 || mtu < OVERHEAD + self.reserved {
+            return Err(KcpError::InvalidMtu);
+        }
+        self.mtu = mtu;
+        self.mss = mtu - OVERHEAD - self.reserved;
+        self.buffer = Vec::with_capacity(mtu);
+        Ok(())
+    }
+
+    /// Reserves bytes at the start of every packet for the headers of the packet layer.
+    pub fn set_reserved(&mut self, reserved: usize) -> Result<(), KcpError> {
+        if reserved >= self.mtu - OVERHEAD {
+            return Err(KcpError::InvalidMtu);
+        }
+        self.reserved = reserved;
+        self.mss = self.mtu - OVERHEAD - reserved;
+        Ok(())
+    }
+
+    /// Sets the send window and the receive window, in packets.
+    pub fn set_wndsize(&mut self, sndwnd: u32, rcvwnd: u32) {
+        if sndwnd > 0 {
+            self.snd_wnd = sndwnd;
+        }
+        if rcvwnd > 0 {
+            // The receive window must be able to hold the largest message.
+            self.rcv_wnd = rcvwnd.max(WND_RCV);
+        }
+    }
+
+    /// `nodelay` lowers the minimum RTO and backs off retransmissions more slowly,
+    /// `interval` is the time between flushes in milliseconds, `resend` is the number
+    /// of later acknowledgements that trigger a fast retransmission (0 disables it), and
+    /// `nc` disables congestion control.
+    pub fn set_nodelay(&mut self, nodelay: u32, interval: u32, resend: u32, nc: bool) {
+        self.nodelay = nodelay;
+        self.rx_minrto = if nodelay > 0 { RTO_NDL } else { RTO_MIN };
+        self.interval = interval.clamp(10, 5000);
+        self.fastresend = resend;
+        self.nocwnd = nc;
+    }
+
+    /// Number of segments waiting to be sent or acknowledged.
+    pub fn wait_snd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    pub fn snd_wnd(&self) -> u32 {
+        self.snd_wnd
+    }
+
+    /// Queues data to send.
+    pub fn send(&mut self, mut data: &[u8]) -> Result<(), KcpError> {
+        if self.stream {
+            if let Some(last) = self.snd_queue.back_mut()
+                && last.data.len() < self.mss
+            {
+                let extend = data.len().min(self.mss - last.data.len());
+                last.data.extend_from_slice(&data[..extend]);
+                last.frg = 0;
+                data = &data[extend..];
+            }
+            if data.is_empty() {
+                return Ok(());
+            }
+        }
+
+        let count = if data.len() <= self.mss {
+            1
+        } else {
+            data.len().div_ceil(self.mss)
+        };
+        if !self.stream && count > 255 {
+            return Err(KcpError::TooLarge);
+        }
+
+        for i in 0..count {
+            let size = data.len().min(self.mss);
+            self.snd_queue.push_back(Segment {
+                frg: if self.stream {
+                    0
+                } else {
+                    (count - i - 1) as u8
+                },
+                data: data[..size].to_vec(),
+                ..Default::default()
+            });
+            data = &data[size..];
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the next message, if all of its fragments were received.
+    pub fn peek_size(&self) -> Option<usize> {
+        let first = self.rcv_queue.front()?;
+        if first.frg == 0 {
+            return Some(first.data.len());
+        }
+        if self.rcv_queue.len() < first.frg as usize + 1 {
+            return None;
+        }
+        let mut size = 0;
+        for seg in self.rcv_queue.iter() {
+            size += seg.data.len();
+            if seg.frg == 0 {
+                break;
+            }
+        }
+        Some(size)
+    }
+
+    /// Appends the next received message to `buf`, returns false if there is none.
+    pub fn recv(&mut self, buf: &mut Vec<u8>) -> bool {
+        if self.peek_size().is_none() {
+            return false;
+        }
+
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+
+        while let Some(seg) = self.rcv_queue.pop_front() {
+            buf.extend_from_slice(&seg.data);
+            if seg.frg == 0 {
+                break;
+            }
+        }
+
+        self.move_rcv_buf();
+
+        // Tell the peer that the window opened again.
+        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
+            self.probe |= ASK_TELL;
+        }
+        true
+    }
+
+    fn move_rcv_buf(&mut self) {
+        while let Some(seg) = self.rcv_buf.front() {
+            if seg.sn == self.rcv_nxt && self.rcv_queue.len() < self.rcv_wnd as usize {
+                let seg = self.rcv_buf.pop_front().unwrap();
+                self.rcv_queue.push_back(seg);
+                self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + self.interval.max(4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(self.rx_minrto, RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = match self.snd_buf.front() {
+            Some(seg) => seg.sn,
+            None => self.snd_nxt,
+        };
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if time_diff(sn, self.snd_una) < 0 || time_diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for i in 0..self.snd_buf.len() {
+            let seg_sn = self.snd_buf[i].sn;
+            if sn == seg_sn {
+                self.snd_buf.remove(i);
+                break;
+            }
+            if time_diff(sn, seg_sn) < 0 {
+                break;
+            }
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while let Some(seg) = self.snd_buf.front() {
+            if time_diff(una, seg.sn) > 0 {
+                self.snd_buf.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32) {
+        if time_diff(sn, self.snd_una) < 0 || time_diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in self.snd_buf.iter_mut() {
+            if time_diff(sn, seg.sn) < 0 {
+                break;
+            } else if sn != seg.sn {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, newseg: Segment) {
+        let sn = newseg.sn;
+        if time_diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0
+            || time_diff(sn, self.rcv_nxt) < 0
+        {
+            return;
+        }
+
+        let mut insert_at = 0;
+        let mut repeat = false;
+        for (i, seg) in self.rcv_buf.iter().enumerate().rev() {
+            if seg.sn == sn {
+                repeat = true;
+                break;
+            }
+            if time_diff(sn, seg.sn) > 0 {
+                insert_at = i + 1;
+                break;
+            }
+        }
+        if !repeat {
+            self.rcv_buf.insert(insert_at, newseg);
+        }
+
+        self.move_rcv_buf();
+    }
+
+    /// Processes a packet from the peer, without the reserved bytes. Returns the number
+    /// of segments that carried data.
+    pub fn input(&mut self, mut data: &[u8]) -> Result<usize, KcpError> {
+        if data.len() < OVERHEAD {
+            return Err(KcpError::Truncated);
+        }
+
+        let prev_una = self.snd_una;
+        let mut max_ack = None;
+        let mut pushes = 0;
+
+        while data.len() >= OVERHEAD {
+            let conv = u32::from_le_bytes(data[0..4].try_into().unwrap());
+            if conv != self.conv {
+                return Err(KcpError::WrongConv);
+            }
+            let cmd = data[4];
+            let frg = data[5];
+            let wnd = u16::from_le_bytes(data[6..8].try_into().unwrap());
+            let ts = u32::from_le_bytes(data[8..12].try_into().unwrap());
+            let sn = u32::from_le_bytes(data[12..16].try_into().unwrap());
+            let una = u32::from_le_bytes(data[16..20].try_into().unwrap());
+            let len = u32::from_le_bytes(data[20..24].try_into().unwrap()) as usize;
+            data = &data[OVERHEAD..];
+            if data.len() < len {
+                return Err(KcpError::Truncated);
+            }
+            if !matches!(cmd, CMD_PUSH | CMD_ACK | CMD_WASK | CMD_WINS) {
+                return Err(KcpError::UnknownCommand);
+            }
+
+            self.rmt_wnd = wnd as u32;
+            self.parse_una(una);
+            self.shrink_buf();
+
+            match cmd {
+                CMD_ACK => {
+                    if time_diff(self.current, ts) >= 0 {
+                        self.update_ack(time_diff(self.current, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    match max_ack {
+                        Some(max) if time_diff(sn, max) <= 0 => {}
+                        _ => max_ack = Some(sn),
+                    }
+                }
+                CMD_PUSH => {
+                    if time_diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 {
+                        self.acklist.push((sn, ts));
+                        if time_diff(sn, self.rcv_nxt) >= 0 {
+                            self.parse_data(Segment {
+                                conv,
+                                cmd,
+                                frg,
+                                wnd,
+                                ts,
+                                sn,
+                                una,
+                                data: data[..len].to_vec(),
+                                ..Default::default()
+                            });
+                        }
+                    }
+                    pushes += 1;
+                }
+                CMD_WASK => {
+                    self.probe |= ASK_TELL;
+                }
+                _ => {}
+            }
+
+            data = &data[len..];
+        }
+
+        if let Some(sn) = max_ack {
+            self.parse_fastack(sn);
+        }
+
+        if time_diff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                if self.incr < mss {
+                    self.incr = mss;
+                }
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd as usize + 1) * mss <= self.incr {
+                    self.cwnd = self.incr.div_ceil(mss.max(1)) as u32;
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd as usize * mss;
+            }
+        }
+
+        Ok(pushes)
+    }
+
+    /// Returns true if there are acknowledgements waiting to be flushed.
+    pub fn has_pending_acks(&self) -> bool {
+        !self.acklist.is_empty()
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        let queued = self.rcv_queue.len() as u32;
+        if queued < self.rcv_wnd {
+            (self.rcv_wnd - queued).min(u16::MAX as u32) as u16
+        } else {
+            0
+        }
+    }
+
+    fn output_if_full<F: FnMut(&[u8])>(&mut self, need: usize, output: &mut F) {
+        if self.buffer.len() + need > self.mtu {
+            output(&self.buffer);
+            self.buffer.clear();
+            self.buffer.resize(self.reserved, 0);
+        }
+    }
+
+    /// Sends acknowledgements, window probes and the segments that are due at
+    /// `current`.
+    pub fn flush<F: FnMut(&[u8])>(&mut self, current: u32, output: &mut F) {
+        self.current = current;
+        if !self.updated {
+            return;
+        }
+
+        self.buffer.clear();
+        self.buffer.resize(self.reserved, 0);
+
+        let mut seg = Segment {
+            conv: self.conv,
+            cmd: CMD_ACK,
+            wnd: self.wnd_unused(),
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
+
+        for (sn, ts) in std::mem::take(&mut self.acklist) {
+            self.output_if_full(OVERHEAD, output);
+            seg.sn = sn;
+            seg.ts = ts;
+            seg.encode(&mut self.buffer);
+        }
+
+        // Probe the window size of the peer when it's full.
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = self.current.wrapping_add(self.probe_wait);
+            } else if time_diff(self.current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(PROBE_INIT);
+                self.probe_wait += self.probe_wait / 2;
+                self.probe_wait = self.probe_wait.min(PROBE_LIMIT);
+                self.ts_probe = self.current.wrapping_add(self.probe_wait);
+                self.probe |= ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+
+        seg.sn = 0;
+        seg.ts = 0;
+        if self.probe & ASK_SEND != 0 {
+            seg.cmd = CMD_WASK;
+            self.output_if_full(OVERHEAD, output);
+            seg.encode(&mut self.buffer);
+        }
+        if self.probe & ASK_TELL != 0 {
+            seg.cmd = CMD_WINS;
+            self.output_if_full(OVERHEAD, output);
+            seg.encode(&mut self.buffer);
+        }
+        self.probe = 0;
+
+        let mut cwnd = self.snd_wnd.min(self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = cwnd.min(self.cwnd);
+        }
+
+        while time_diff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let Some(mut newseg) = self.snd_queue.pop_front() else {
+                break;
+            };
+            newseg.conv = self.conv;
+            newseg.cmd = CMD_PUSH;
+            newseg.wnd = seg.wnd;
+            newseg.ts = self.current;
+            newseg.sn = self.snd_nxt;
+            newseg.una = self.rcv_nxt;
+            newseg.resendts = self.current;
+            newseg.rto = self.rx_rto;
+            newseg.fastack = 0;
+            newseg.xmit = 0;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(newseg);
+        }
+
+        let resent = if self.fastresend > 0 {
+            self.fastresend
+        } else {
+            u32::MAX
+        };
+        let rtomin = if self.nodelay == 0 {
+            self.rx_rto >> 3
+        } else {
+            0
+        };
+
+        let mut lost = false;
+        let mut change = false;
+        let mut snd_buf = std::mem::take(&mut self.snd_buf);
+        for segment in snd_buf.iter_mut() {
+            let mut needsend = false;
+            if segment.xmit == 0 {
+                needsend = true;
+                segment.xmit += 1;
+                segment.rto = self.rx_rto;
+                segment.resendts = self.current.wrapping_add(segment.rto + rtomin);
+            } else if time_diff(self.current, segment.resendts) >= 0 {
+                needsend = true;
+                segment.xmit += 1;
+                if self.nodelay == 0 {
+                    segment.rto += segment.rto.max(self.rx_rto);
+                } else {
+                    let step = if self.nodelay < 2 {
+                        segment.rto
+                    } else {
+                        self.rx_rto
+                    };
+                    segment.rto += step / 2;
+                }
+                segment.resendts = self.current.wrapping_add(segment.rto);
+                lost = true;
+            } else if segment.fastack >= resent
+                && (segment.xmit <= self.fastlimit || self.fastlimit == 0)
+            {
+                needsend = true;
+                segment.xmit += 1;
+                segment.fastack = 0;
+                segment.resendts = self.current.wrapping_add(segment.rto);
+                change = true;
+            }
+
+            if needsend {
+                segment.ts = self.current;
+                segment.wnd = seg.wnd;
+                segment.una = self.rcv_nxt;
+
+                self.output_if_full(OVERHEAD + segment.data.len(), output);
+                segment.encode(&mut self.buffer);
+                self.buffer.extend_from_slice(&segment.data);
+
+                if segment.xmit >= self.dead_link {
+                    self.dead = true;
+                }
+            }
+        }
+        self.snd_buf = snd_buf;
+
+        if self.buffer.len() > self.reserved {
+            output(&self.buffer);
+        }
+        self.buffer.clear();
+
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(THRESH_MIN);
+            self.cwnd = self.ssthresh.saturating_add(resent);
+            self.incr = self.cwnd as usize * self.mss;
+        }
+
+        if lost {
+            self.ssthresh = (cwnd / 2).max(THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = self.mss;
+        }
+
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = self.mss;
+        }
+    }
+
+    /// Flushes when the flush interval elapsed. `current` is a timestamp in
+    /// milliseconds.
+    pub fn update<F: FnMut(&[u8])>(&mut self, current: u32, output: &mut F) {
+        self.current = current;
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+
+        let mut slap = time_diff(current, self.ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            self.ts_flush = current;
+            slap = 0;
+        }
+
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(self.interval);
+            if time_diff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(self.interval);
+            }
+            self.flush(current, output);
+        }
+    }
+
+    /// Returns when `update` should be called next.
+    pub fn check(&self, current: u32) -> u32 {
+        if !self.updated {
+            return current;
+        }
+
+        let mut ts_flush = self.ts_flush;
+        if !(-10000..10000).contains(&time_diff(current, ts_flush)) {
+            ts_flush = current;
+        }
+        if time_diff(current, ts_flush) >= 0 {
+            return current;
+        }
+
+        let tm_flush = time_diff(ts_flush, current) as u32;
+        let mut tm_packet = u32::MAX;
+        for seg in self.snd_buf.iter() {
+            let diff = time_diff(seg.resendts, current);
+            if diff <= 0 {
+                return current;
+            }
+            tm_packet = tm_packet.min(diff as u32);
+        }
+
+        let minimal = tm_packet.min(tm_flush).min(self.interval);
+        current.wrapping_add(minimal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs two KCP instances against each other, dropping the packets for which
+    /// `drop_packet` returns true.
+    fn transfer(data: &[u8], stream: bool, mut drop_packet: impl FnMut(usize) -> bool) -> Vec<u8> {
+        let mut sender = Kcp::new(7);
+        let mut receiver = Kcp::new(7);
+        for kcp in [&mut sender, &mut receiver] {
+            kcp.set_stream_mode(stream);
+            kcp.set_nodelay(1, 10, 2, true);
+            kcp.set_wndsize(128, 128);
+        }
+
+        for chunk in data.chunks(3000) {
+            sender.send(chunk).unwrap();
+        }
+
+        let mut received = vec![];
+        let mut packet_count = 0;
+        let mut now = 0u32;
+        while received.len() < data.len() {
+            assert!(now < 60_000, "transfer didn't finish");
+
+            let mut to_receiver = vec![];
+            sender.update(now, &mut |p: &[u8]| to_receiver.push(p.to_vec()));
+            for packet in to_receiver {
+                packet_count += 1;
+                if !drop_packet(packet_count) {
+                    receiver.input(&packet).unwrap();
+                }
+            }
+
+            let mut to_sender = vec![];
+            receiver.update(now, &mut |p: &[u8]| to_sender.push(p.to_vec()));
+            for packet in to_sender {
+                sender.input(&packet).unwrap();
+            }
+
+            while receiver.recv(&mut received) {}
+            now += 10;
+        }
+        assert!(!sender.is_dead());
+        received
+    }
+
+    #[test]
+    fn test_transfer() {
+        let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        assert_eq!(transfer(&data, false, |_| false), data);
+        assert_eq!(transfer(&data, true, |_| false), data);
+    }
+
+    #[test]
+    fn test_transfer_with_loss() {
+        let data: Vec<u8> = (0..100_000u32).map(|i| (i % 253) as u8).collect();
+        assert_eq!(transfer(&data, true, |n| n % 5 == 0), data);
+    }
+
+    #[test]
+    fn test_message_mode_keeps_boundaries() {
+        let mut sender = Kcp::new(1);
+        let mut receiver = Kcp::new(1);
+        sender.set_nodelay(0, 100, 0, true);
+        sender.send(&[1u8; 3000]).unwrap();
+        sender.send(b"second").unwrap();
+
+        let mut packets = vec![];
+        sender.update(0, &mut |p: &[u8]| packets.push(p.to_vec()));
+        for packet in packets {
+            receiver.input(&packet).unwrap();
+        }
+
+        let mut message = vec![];
+        assert!(receiver.recv(&mut message));
+        assert_eq!(message, vec![1u8; 3000]);
+        message.clear();
+        assert!(receiver.recv(&mut message));
+        assert_eq!(message, b"second");
+        assert!(!receiver.recv(&mut message));
+    }
+
+    #[test]
+    fn test_reserved_bytes() {
+        let mut sender = Kcp::new(1);
+        sender.set_mtu(1350).unwrap();
+        sender.set_reserved(28).unwrap();
+        sender.set_nodelay(0, 100, 0, true);
+        assert_eq!(sender.mss(), 1350 - OVERHEAD - 28);
+        sender.send(&[5u8; 5000]).unwrap();
+
+        let mut packets = vec![];
+        sender.update(0, &mut |p: &[u8]| packets.push(p.to_vec()));
+        assert!(!packets.is_empty());
+        for packet in packets.iter() {
+            assert!(packet.len() <= 1350);
+            assert_eq!(&packet[..28], &[0u8; 28]);
+            assert_eq!(&packet[28..32], &1u32.to_le_bytes());
+        }
+    }
+
+    #[test]
+    fn test_wrong_conv() {
+        let mut sender = Kcp::new(1);
+        let mut receiver = Kcp::new(2);
+        sender.set_nodelay(0, 100, 0, true);
+        sender.send(b"hello").unwrap();
+        let mut packets = vec![];
+        sender.update(0, &mut |p: &[u8]| packets.push(p.to_vec()));
+        assert_eq!(receiver.input(&packets[0]), Err(KcpError::WrongConv));
+        assert_eq!(receiver.input(&packets[0][..10]), Err(KcpError::Truncated));
+    }
+
+    #[test]
+    fn test_dead_link() {
+        let mut sender = Kcp::new(1);
+        sender.set_nodelay(2, 10, 0, true);
+        sender.send(b"hello").unwrap();
+        let mut now = 0;
+        while !sender.is_dead() {
+            assert!(now < 600_000);
+            sender.update(now, &mut |_: &[u8]| {});
+            now += 10;
+        }
+    }
+}
@@ -0,0 +1,123 @@
+//! Server side of the KCP transport.
+//!
+//! Every smux stream of an accepted KCP connection is handled like an accepted TCP
+//! connection.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use log::{debug, error};
+use tokio::task::JoinHandle;
+
+use super::kcp_conn::{KcpConn, KcpListener, KcpSettings};
+use super::kcp_smux::SmuxSession;
+use crate::auto_ban;
+use crate::config::{BindLocation, ServerConfig};
+use crate::resolver::Resolver;
+use crate::socket_util::new_socket2_udp_socket;
+use crate::tcp::tcp_server::{
+    InboundContext, TcpServerState, create_tcp_server_state, process_stream,
+};
+
+/// Starts KCP servers on the addresses of the config.
+pub async fn start_kcp_servers(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> std::io::Result<Vec<JoinHandle<()>>> {
+    println!(
+        "Starting {} KCP server at {}",
+        &config.protocol, &config.bind_location
+    );
+
+    let bind_addresses = match &config.bind_location {
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            config.bind_location.to_socket_addrs()?
+        }
+        BindLocation::Path(_) => {
+            return Err(std::io::Error::other(
+                "Cannot listen on path, KCP does not have unix domain socket support",
+            ));
+        }
+    };
+
+    let settings = KcpSettings::new(config.kcp_settings.clone().unwrap_or_default(), true)?;
+    let state = create_tcp_server_state(config, resolver);
+
+    let mut join_handles = Vec::with_capacity(bind_addresses.len());
+    for bind_address in bind_addresses {
+        let socket: std::net::UdpSocket =
+            new_socket2_udp_socket(bind_address.is_ipv6(), None, Some(bind_address), false)?.into();
+        let socket = tokio::net::UdpSocket::from_std(socket)?;
+        let listener = KcpListener::new(socket, settings.clone());
+        let state = state.clone();
+        join_handles.push(tokio::spawn(run_kcp_server(bind_address, listener, state)));
+    }
+
+    Ok(join_handles)
+}
+
+async fn run_kcp_server(
+    bind_address: SocketAddr,
+    mut listener: KcpListener,
+    state: TcpServerState,
+) {
+    let listener_label: Arc<str> = Arc::from(format!("kcp://{bind_address}"));
+    while let Some(conn) = listener.accept().await {
+        if let Some(access_control) = &state.access_control
+            && !access_control.permits(conn.peer_addr().ip())
+        {
+            debug!(
+                "Rejected {}, the address is not allowed to connect",
+                conn.peer_addr()
+            );
+            continue;
+        }
+        if auto_ban::is_banned(conn.peer_addr().ip()) {
+            debug!("Rejected {}, the address is banned", conn.peer_addr());
+            continue;
+        }
+        debug!("Accepted KCP connection from {}", conn.peer_addr());
+        tokio::spawn(run_session(conn, listener_label.clone(), state.clone()));
+    }
+}
+
+async fn run_session(conn: KcpConn, listener_label: Arc<str>, state: TcpServerState) {
+    let addr = conn.peer_addr();
+    let session = SmuxSession::server(Box::new(conn));
+
+    loop {
+        let reserved = state.connection_limiter.ready().await;
+        let Some(stream) = session.accept().await else {
+            break;
+        };
+
+        let TcpServerState {
+            protocol,
+            resolver,
+            server_handler,
+            rate_limit,
+            session_timeouts,
+            ..
+        } = state.clone();
+
+        let inbound = InboundContext::new(listener_label.clone(), protocol, Some(addr), rate_limit)
+            .with_session_timeouts(session_timeouts);
+        let Some(permit) = state
+            .connection_limiter
+            .admit(reserved, inbound.metrics.clone())
+        else {
+            debug!("Rejected {addr}, the listener is at its connection limit");
+            continue;
+        };
+
+        tokio::spawn(async move {
+            let _permit = permit;
+            if let Err(e) = process_stream(stream, server_handler, resolver, inbound).await {
+                error!("{}:{} finished with error: {:?}", addr.ip(), addr.port(), e);
+            } else {
+                debug!("{}:{} finished successfully", addr.ip(), addr.port());
+            }
+        });
+    }
+    debug!("KCP session from {addr} closed");
+}
@@ -0,0 +1,681 @@
+//! The smux stream multiplexer of kcptun, carrying the streams of a KCP connection.
+//!
+//! Frames have an 8 byte header: version, command, little endian data length and
+//! stream ID. Version 2 adds per-stream flow control with UPD frames, which tell the
+//! writer how much data was read and the size of the receive window.
+//!
+//! Like the mux.cool session, a writer task writes the frames queued by the streams,
+//! and a read loop dispatches data frames to the streams through bounded channels.
+
+use std::collections::HashMap;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicU32, Ordering};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use log::debug;
+use parking_lot::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, WriteHalf};
+use tokio::sync::{Notify, mpsc};
+use tokio_util::sync::PollSender;
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+const CMD_SYN: u8 = 0;
+const CMD_FIN: u8 = 1;
+const CMD_PSH: u8 = 2;
+const CMD_NOP: u8 = 3;
+const CMD_UPD: u8 = 4;
+
+const HEADER_LEN: usize = 8;
+const MAX_FRAME_SIZE: usize = 32768;
+
+/// Receive window of version 2 streams, which is also what the peer is assumed to
+/// have until its first UPD frame.
+const STREAM_WINDOW: u32 = 262144;
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Number of data frames buffered for each stream before the read loop waits.
+const STREAM_CHANNEL_BUFFER: usize = 16;
+
+/// Number of frames queued for the writer before streams wait.
+const FRAME_CHANNEL_BUFFER: usize = 64;
+
+/// Number of opened streams waiting to be accepted.
+const ACCEPT_CHANNEL_BUFFER: usize = 64;
+
+fn encode_frame(version: u8, cmd: u8, stream_id: u32, data: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + data.len());
+    frame.put_u8(version);
+    frame.put_u8(cmd);
+    frame.put_u16_le(data.len() as u16);
+    frame.put_u32_le(stream_id);
+    frame.put_slice(data);
+    frame.freeze()
+}
+
+fn encode_update_frame(stream_id: u32, consumed: u32, window: u32) -> Bytes {
+    let mut data = [0u8; 8];
+    data[..4].copy_from_slice(&consumed.to_le_bytes());
+    data[4..].copy_from_slice(&window.to_le_bytes());
+    encode_frame(2, CMD_UPD, stream_id, &data)
+}
+
+struct Frame {
+    version: u8,
+    cmd: u8,
+    stream_id: u32,
+    data: Bytes,
+}
+
+/// Reads a frame, returns None on EOF before a frame.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+    let n = reader.read(&mut header).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[n..]).await?;
+
+    let version = header[0];
+    if version != 1 && version != 2 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported smux version {version}"),
+        ));
+    }
+    let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+    let mut data = vec![0u8; len];
+    reader.read_exact(&mut data).await?;
+    Ok(Some(Frame {
+        version,
+        cmd: header[1],
+        stream_id: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+        data: Bytes::from(data),
+    }))
+}
+
+/// Send window of a version 2 stream, updated by the UPD frames of the peer.
+struct StreamWindow {
+    state: Mutex<WindowState>,
+}
+
+struct WindowState {
+    sent: u32,
+    peer_consumed: u32,
+    peer_window: u32,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+impl StreamWindow {
+    fn new() -> Self {
+        Self {
+            state: Mutex::new(WindowState {
+                sent: 0,
+                peer_consumed: 0,
+                peer_window: STREAM_WINDOW,
+                waker: None,
+                closed: false,
+            }),
+        }
+    }
+
+    fn update(&self, consumed: u32, window: u32) {
+        let mut state = self.state.lock();
+        state.peer_consumed = consumed;
+        state.peer_window = window;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn close(&self) {
+        let mut state = self.state.lock();
+        state.closed = true;
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+struct StreamEntry {
+    data_tx: mpsc::Sender<Bytes>,
+    window: Arc<StreamWindow>,
+}
+
+pub struct SmuxSession {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    frame_tx: mpsc::Sender<Bytes>,
+    /// Window updates and keepalives, which don't wait behind data frames.
+    control_tx: mpsc::UnboundedSender<Bytes>,
+    accept_rx: tokio::sync::Mutex<mpsc::Receiver<SmuxStream>>,
+    /// Unknown on the server until the first frame of the client.
+    version: AtomicU8,
+    next_stream_id: AtomicU32,
+    closed: Arc<AtomicBool>,
+    /// Stops the writer when the session is closed.
+    shutdown: Arc<Notify>,
+}
+
+impl std::fmt::Debug for SmuxSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SmuxSession")
+            .field("version", &self.version.load(Ordering::Relaxed))
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl SmuxSession {
+    /// Starts the client side of a session, which opens streams.
+    pub fn client(stream: Box<dyn AsyncStream>, version: u8) -> Arc<Self> {
+        Self::start(stream, version)
+    }
+
+    /// Starts the server side of a session, which accepts streams.
+    pub fn server(stream: Box<dyn AsyncStream>) -> Arc<Self> {
+        Self::start(stream, 0)
+    }
+
+    fn start(stream: Box<dyn AsyncStream>, version: u8) -> Arc<Self> {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let (frame_tx, frame_rx) = mpsc::channel(FRAME_CHANNEL_BUFFER);
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        let (accept_tx, accept_rx) = mpsc::channel(ACCEPT_CHANNEL_BUFFER);
+        let closed = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new(Notify::new());
+
+        tokio::spawn(write_loop(
+            write_half,
+            frame_rx,
+            control_rx,
+            closed.clone(),
+            shutdown.clone(),
+        ));
+
+        let session = Arc::new(Self {
+            streams: Mutex::new(HashMap::new()),
+            frame_tx,
+            control_tx,
+            accept_rx: tokio::sync::Mutex::new(accept_rx),
+            version: AtomicU8::new(version),
+            // Clients use odd stream IDs starting from 3, like smux.
+            next_stream_id: AtomicU32::new(3),
+            closed,
+            shutdown,
+        });
+        tokio::spawn(read_loop(session.clone(), read_half, accept_tx));
+        tokio::spawn(keepalive_loop(session.clone()));
+        session
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    fn add_stream(&self, stream_id: u32, version: u8) -> io::Result<SmuxStream> {
+        let mut streams = self.streams.lock();
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "smux session closed",
+            ));
+        }
+        // Forget the streams that were dropped after the peer closed them.
+        streams.retain(|_, entry| Arc::strong_count(&entry.window) > 1);
+
+        let (data_tx, data_rx) = mpsc::channel(STREAM_CHANNEL_BUFFER);
+        let window = Arc::new(StreamWindow::new());
+        streams.insert(
+            stream_id,
+            StreamEntry {
+                data_tx,
+                window: window.clone(),
+            },
+        );
+        Ok(SmuxStream {
+            stream_id,
+            version,
+            data_rx,
+            read_buffer: Bytes::new(),
+            frame_tx: PollSender::new(self.frame_tx.clone()),
+            control_tx: self.control_tx.clone(),
+            window,
+            consumed: 0,
+            unreported: 0,
+            reported: false,
+            write_closed: false,
+        })
+    }
+
+    /// Opens a stream to the server.
+    pub async fn open_stream(&self) -> io::Result<SmuxStream> {
+        let version = self.version.load(Ordering::Relaxed);
+        let stream_id = self.next_stream_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.add_stream(stream_id, version)?;
+        self.frame_tx
+            .send(encode_frame(version, CMD_SYN, stream_id, &[]))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "smux session closed"))?;
+        Ok(stream)
+    }
+
+    /// Accepts a stream opened by the client, returns None once the session is closed.
+    pub async fn accept(&self) -> Option<SmuxStream> {
+        self.accept_rx.lock().await.recv().await
+    }
+
+    /// Closes the session, ending the reads and writes of all streams.
+    pub fn close(&self) {
+        let mut streams = self.streams.lock();
+        self.closed.store(true, Ordering::Relaxed);
+        for (_, entry) in streams.drain() {
+            entry.window.close();
+        }
+        self.shutdown.notify_one();
+    }
+
+    async fn dispatch(&self, frame: Frame, accept_tx: &mpsc::Sender<SmuxStream>) {
+        match frame.cmd {
+            CMD_SYN => {
+                if self.streams.lock().contains_key(&frame.stream_id) {
+                    return;
+                }
+                let Ok(stream) = self.add_stream(frame.stream_id, frame.version) else {
+                    return;
+                };
+                let _ = accept_tx.send(stream).await;
+            }
+            CMD_PSH => {
+                if frame.data.is_empty() {
+                    return;
+                }
+                let sender = self
+                    .streams
+                    .lock()
+                    .get(&frame.stream_id)
+                    .map(|entry| entry.data_tx.clone());
+                let Some(sender) = sender else {
+                    debug!("Dropping data of unknown smux stream {}", frame.stream_id);
+                    return;
+                };
+                // Waits when the stream is not read fast enough, which stops reading
+                // frames of the whole session.
+                if sender.send(frame.data).await.is_err() {
+                    // The stream was dropped.
+                    self.streams.lock().remove(&frame.stream_id);
+                }
+            }
+            CMD_FIN => {
+                // Replacing the sender ends the stream's reads once queued data is
+                // read, and the entry stays for the UPD frames of its writes.
+                let mut streams = self.streams.lock();
+                if let Some(entry) = streams.get_mut(&frame.stream_id) {
+                    entry.data_tx = mpsc::channel(1).0;
+                }
+            }
+            CMD_UPD => {
+                if frame.data.len() < 8 {
+                    return;
+                }
+                let consumed = u32::from_le_bytes(frame.data[..4].try_into().unwrap());
+                let window = u32::from_le_bytes(frame.data[4..8].try_into().unwrap());
+                if let Some(entry) = self.streams.lock().get(&frame.stream_id) {
+                    entry.window.update(consumed, window);
+                }
+            }
+            CMD_NOP => {}
+            cmd => debug!("Ignoring unknown smux command {cmd}"),
+        }
+    }
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(
+    session: Arc<SmuxSession>,
+    mut reader: R,
+    accept_tx: mpsc::Sender<SmuxStream>,
+) {
+    loop {
+        let frame = match tokio::time::timeout(KEEPALIVE_TIMEOUT, read_frame(&mut reader)).await {
+            Ok(Ok(Some(frame))) => frame,
+            Ok(Ok(None)) => break,
+            Ok(Err(e)) => {
+                debug!("Smux session read failed: {e}");
+                break;
+            }
+            Err(_) => {
+                debug!("Smux session timed out");
+                break;
+            }
+        };
+        if session.version.load(Ordering::Relaxed) == 0 {
+            session.version.store(frame.version, Ordering::Relaxed);
+        }
+        session.dispatch(frame, &accept_tx).await;
+    }
+    session.close();
+}
+
+async fn keepalive_loop(session: Arc<SmuxSession>) {
+    let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+    loop {
+        interval.tick().await;
+        if session.is_closed() {
+            break;
+        }
+        let version = match session.version.load(Ordering::Relaxed) {
+            0 => continue,
+            version => version,
+        };
+        if session
+            .control_tx
+            .send(encode_frame(version, CMD_NOP, 0, &[]))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+async fn write_loop(
+    mut writer: WriteHalf<Box<dyn AsyncStream>>,
+    mut frame_rx: mpsc::Receiver<Bytes>,
+    mut control_rx: mpsc::UnboundedReceiver<Bytes>,
+    closed: Arc<AtomicBool>,
+    shutdown: Arc<Notify>,
+) {
+    loop {
+        let frame = tokio::select! {
+            biased;
+            frame = control_rx.recv() => frame,
+            frame = frame_rx.recv() => frame,
+            _ = shutdown.notified() => None,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+        if let Err(e) = write_frames(&mut writer, &mut frame_rx, &mut control_rx, frame).await {
+            debug!("Smux session write failed: {e}");
+            break;
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+    let _ = writer.shutdown().await;
+}
+
+async fn write_frames(
+    writer: &mut WriteHalf<Box<dyn AsyncStream>>,
+    frame_rx: &mut mpsc::Receiver<Bytes>,
+    control_rx: &mut mpsc::UnboundedReceiver<Bytes>,
+    frame: Bytes,
+) -> io::Result<()> {
+    writer.write_all(&frame).await?;
+    // Write whatever else is queued before flushing.
+    loop {
+        if let Ok(frame) = control_rx.try_recv() {
+            writer.write_all(&frame).await?;
+        } else if let Ok(frame) = frame_rx.try_recv() {
+            writer.write_all(&frame).await?;
+        } else {
+            break;
+        }
+    }
+    writer.flush().await
+}
+
+pub struct SmuxStream {
+    stream_id: u32,
+    version: u8,
+    data_rx: mpsc::Receiver<Bytes>,
+    read_buffer: Bytes,
+    frame_tx: PollSender<Bytes>,
+    control_tx: mpsc::UnboundedSender<Bytes>,
+    window: Arc<StreamWindow>,
+    /// Number of bytes read, reported to version 2 peers.
+    consumed: u32,
+    /// Number of bytes read since the last UPD frame.
+    unreported: u32,
+    reported: bool,
+    /// Set once the FIN frame is queued.
+    write_closed: bool,
+}
+
+impl SmuxStream {
+    pub fn stream_id(&self) -> u32 {
+        self.stream_id
+    }
+
+    fn closed_error() -> io::Error {
+        io::Error::new(io::ErrorKind::BrokenPipe, "smux session closed")
+    }
+}
+
+impl AsyncRead for SmuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_buffer.is_empty() {
+            match self.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.read_buffer = data,
+                // The session dropped the sender, on a FIN frame or when it closed.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = std::cmp::min(self.read_buffer.len(), buf.remaining());
+        buf.put_slice(&self.read_buffer[..n]);
+        self.read_buffer.advance(n);
+
+        if self.version == 2 {
+            let this = &mut *self;
+            this.consumed = this.consumed.wrapping_add(n as u32);
+            this.unreported += n as u32;
+            if !this.reported || this.unreported >= STREAM_WINDOW / 2 {
+                this.reported = true;
+                this.unreported = 0;
+                let _ = this.control_tx.send(encode_update_frame(
+                    this.stream_id,
+                    this.consumed,
+                    STREAM_WINDOW,
+                ));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for SmuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_closed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream is shut down",
+            )));
+        }
+
+        let mut max_len = std::cmp::min(buf.len(), MAX_FRAME_SIZE);
+        {
+            let mut window = self.window.state.lock();
+            if window.closed {
+                return Poll::Ready(Err(Self::closed_error()));
+            }
+            if self.version == 2 {
+                let in_flight = window.sent.wrapping_sub(window.peer_consumed);
+                let available = window.peer_window.saturating_sub(in_flight) as usize;
+                if available == 0 {
+                    window.waker = Some(cx.waker().clone());
+                    return Poll::Pending;
+                }
+                max_len = std::cmp::min(max_len, available);
+            }
+        }
+
+        match self.frame_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                let frame = encode_frame(self.version, CMD_PSH, self.stream_id, &buf[..max_len]);
+                if self.frame_tx.send_item(frame).is_err() {
+                    return Poll::Ready(Err(Self::closed_error()));
+                }
+                let mut window = self.window.state.lock();
+                window.sent = window.sent.wrapping_add(max_len as u32);
+                Poll::Ready(Ok(max_len))
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(Self::closed_error())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are flushed by the session's writer.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        // Wait for room so that the FIN frame follows the queued data.
+        match self.frame_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                self.write_closed = true;
+                let frame = encode_frame(self.version, CMD_FIN, self.stream_id, &[]);
+                if self.frame_tx.send_item(frame).is_err() {
+                    return Poll::Ready(Err(Self::closed_error()));
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(_)) => {
+                self.write_closed = true;
+                Poll::Ready(Err(Self::closed_error()))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for SmuxStream {
+    fn drop(&mut self) {
+        if !self.write_closed
+            && let Some(sender) = self.frame_tx.get_ref()
+        {
+            // Best effort, as drop can't wait for room in the channel.
+            let _ = sender.try_send(encode_frame(self.version, CMD_FIN, self.stream_id, &[]));
+        }
+    }
+}
+
+impl AsyncPing for SmuxStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for SmuxStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::duplex;
+
+    fn session_pair(version: u8) -> (Arc<SmuxSession>, Arc<SmuxSession>) {
+        let (client, server) = duplex(65536);
+        (
+            SmuxSession::client(Box::new(client), version),
+            SmuxSession::server(Box::new(server)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_read_frame() {
+        let frame = encode_frame(2, CMD_PSH, 5, b"hello");
+        assert_eq!(&frame[..HEADER_LEN], &[2, CMD_PSH, 5, 0, 5, 0, 0, 0]);
+        let frame = read_frame(&mut &frame[..]).await.unwrap().unwrap();
+        assert_eq!(frame.stream_id, 5);
+        assert_eq!(&frame.data[..], b"hello");
+        assert!(read_frame(&mut &[][..]).await.unwrap().is_none());
+
+        let invalid = encode_frame(3, CMD_NOP, 0, &[]);
+        assert!(read_frame(&mut &invalid[..]).await.is_err());
+    }
+
+    async fn check_streams(version: u8) {
+        let (client, server) = session_pair(version);
+
+        let mut client_stream = client.open_stream().await.unwrap();
+        let mut second_stream = client.open_stream().await.unwrap();
+        assert_eq!(client_stream.stream_id(), 3);
+        assert_eq!(second_stream.stream_id(), 5);
+
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            client_stream.write_all(&data).await.unwrap();
+            client_stream.shutdown().await.unwrap();
+            client_stream
+        });
+        second_stream.write_all(b"second").await.unwrap();
+
+        let mut server_stream = server.accept().await.unwrap();
+        let mut received = vec![];
+        server_stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, expected);
+
+        // Writes still work after the peer shut down its side.
+        server_stream.write_all(b"reply").await.unwrap();
+        drop(server_stream);
+        let mut client_stream = writer.await.unwrap();
+        let mut reply = vec![];
+        client_stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"reply");
+
+        let mut second_server_stream = server.accept().await.unwrap();
+        let mut buf = [0u8; 6];
+        second_server_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"second");
+        assert_eq!(server.version.load(Ordering::Relaxed), version);
+    }
+
+    #[tokio::test]
+    async fn test_streams_v1() {
+        check_streams(1).await;
+    }
+
+    #[tokio::test]
+    async fn test_streams_v2() {
+        check_streams(2).await;
+    }
+
+    #[tokio::test]
+    async fn test_close_ends_streams() {
+        let (client, server) = session_pair(2);
+        let mut client_stream = client.open_stream().await.unwrap();
+        client_stream.write_all(b"hi").await.unwrap();
+        let _server_stream = server.accept().await.unwrap();
+
+        client.close();
+        let mut buf = vec![];
+        client_stream.read_to_end(&mut buf).await.unwrap();
+        assert!(client_stream.write_all(b"more").await.is_err());
+        assert!(client.open_stream().await.is_err());
+        // The server sees the connection close.
+        assert!(server.accept().await.is_none());
+    }
+}
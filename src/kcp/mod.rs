@@ -0,0 +1,21 @@
+//! KCP transport, compatible with kcptun.
+//!
+//! KCP is a reliable protocol over UDP that retransmits faster than TCP, at the cost
+//! of more bandwidth, which helps on lossy links. Like kcptun, packets can carry
+//! Reed-Solomon parity for forward error correction, and are encrypted with a key
+//! shared by the client and server. The proxied connections are streams of an smux
+//! session over the KCP connection.
+//!
+//! kcptun peers need `--nocomp`, as snappy compression is not supported. The supported
+//! ciphers are `aes`, `aes-128`, `salsa20`, `xor`, `none` and `null`.
+
+mod kcp_client;
+mod kcp_conn;
+mod kcp_crypt;
+mod kcp_fec;
+mod kcp_protocol;
+mod kcp_server;
+mod kcp_smux;
+
+pub use kcp_client::KcpConnector;
+pub use kcp_server::start_kcp_servers;
@@ -55,29 +55,62 @@
 
 // Modules are declared here (mirroring main.rs) so the library crate can
 // expose them for FFI/mobile integration.
+mod access_control;
+mod access_log;
 mod address;
 mod anytls;
 mod async_stream;
+mod auto_ban;
 mod buf_reader;
+mod buffer_pool;
+mod chain_balancer;
+mod chain_health;
+mod clash_api;
 mod client_proxy_chain;
 mod client_proxy_selector;
+mod connection_limit;
 mod copy_bidirectional;
 mod copy_bidirectional_message;
 mod crypto;
+mod crypto_provider;
+mod decoy_handler;
+mod dispatch_handler;
 pub mod dns;
+mod dns_server;
+mod fake_ip;
+mod geoip;
+mod geosite;
+mod grpc_stream;
+mod h3_stream;
+mod http2_handler;
 mod http_handler;
 mod hysteria2_client;
+mod hysteria2_congestion;
+mod hysteria2_obfs;
 mod hysteria2_protocol;
 mod hysteria2_server;
+mod icmp_ping;
+mod juicity;
+mod kcp;
+mod masque;
+mod metrics;
 mod mixed_handler;
+mod mux;
 mod naiveproxy;
+mod obfs4;
 mod option_util;
 mod port_forward_handler;
+mod privileges;
+mod proxy_protocol;
 mod quic_server;
 mod quic_stream;
+mod rate_limit;
 mod reality;
 mod reality_client_handler;
+mod reload;
+mod replay_filter;
 pub mod resolver;
+mod reverse;
 mod routing;
 mod rustls_config_util;
 mod rustls_connection_util;
@@ -85,31 +118,67 @@ mod shadow_tls;
 mod shadowsocks;
 mod slide_buffer;
 mod snell;
+mod sniff;
 mod socket_util;
+#[cfg(target_os = "linux")]
+mod splice;
 mod socks5_udp_relay;
 mod socks_handler;
+mod ssh_client;
+mod ssh_server;
 mod stream_reader;
+mod subscription;
 mod sync_adapter;
+mod systemd;
 mod tcp;
 mod thread_util;
 mod tls_client_handler;
+mod tls_fingerprint;
 mod tls_server_handler;
+mod traffic_padding;
+#[cfg(target_os = "linux")]
+mod transparent;
 mod trojan_handler;
 mod tuic_server;
+mod udp_batch;
+mod udp_sessions;
 mod uot;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod user_store;
 mod util;
 mod uuid_util;
 mod vless;
 mod vmess;
 mod websocket;
+mod wireguard_client;
+mod xhttp;
 mod xudp;
 
 /// Configuration types.
 pub mod config;
 
+/// Builders for configs, for applications that embed shoes.
+pub mod config_builder;
+
 /// TUN device support for VPN mode.
 pub mod tun;
 
+/// Traffic statistics for embedding applications.
+pub mod stats;
+
+/// Connection event hooks for embedding applications.
+pub mod hooks;
+
+/// Hot configuration reload.
+pub use reload::{ReloadHandle, run_with_reload};
+
+/// Starting servers from configs built with [`config_builder`].
+pub use config_builder::run_configs_with_reload;
+
+/// Creation of outbound sockets.
+pub use socket_util::{DefaultDialer, DialOptions, Dialer, set_dialer};
+
 /// FFI bindings for mobile platforms.
 #[cfg(any(target_os = "android", target_os = "ios", feature = "ffi"))]
 pub mod ffi;
@@ -1,27 +1,61 @@
+mod access_control;
+mod access_log;
 mod address;
 mod anytls;
 mod async_stream;
+mod auto_ban;
 mod buf_reader;
+mod buffer_pool;
+mod chain_balancer;
+mod chain_health;
+mod clash_api;
 mod client_proxy_chain;
 mod client_proxy_selector;
 mod config;
+mod connection_limit;
 mod copy_bidirectional;
 mod copy_bidirectional_message;
 mod crypto;
+mod crypto_provider;
+mod decoy_handler;
+mod dispatch_handler;
 mod dns;
+mod dns_server;
+mod fake_ip;
+mod geoip;
+mod geosite;
+mod grpc_stream;
+mod h3_stream;
+mod hooks;
+mod http2_handler;
 mod http_handler;
 mod hysteria2_client;
+mod hysteria2_congestion;
+mod hysteria2_obfs;
 mod hysteria2_protocol;
 mod hysteria2_server;
+mod icmp_ping;
+mod juicity;
+mod kcp;
+mod masque;
+mod metrics;
 mod mixed_handler;
+mod mux;
 mod naiveproxy;
+mod obfs4;
 mod option_util;
 mod port_forward_handler;
+mod privileges;
+mod proxy_protocol;
 mod quic_server;
 mod quic_stream;
+mod rate_limit;
 mod reality;
 mod reality_client_handler;
+mod reload;
+mod replay_filter;
 mod resolver;
+mod reverse;
 mod routing;
 mod rustls_config_util;
 mod rustls_connection_util;
@@ -29,25 +63,43 @@ mod shadow_tls;
 mod shadowsocks;
 mod slide_buffer;
 mod snell;
+mod sniff;
 mod socket_util;
+#[cfg(target_os = "linux")]
+mod splice;
 mod socks5_udp_relay;
 mod socks_handler;
+mod ssh_client;
+mod ssh_server;
 mod stream_reader;
+mod subscription;
 mod sync_adapter;
+mod systemd;
 mod tcp;
 mod thread_util;
 mod tls_client_handler;
+mod tls_fingerprint;
 mod tls_server_handler;
+mod traffic_padding;
+#[cfg(target_os = "linux")]
+mod transparent;
 mod trojan_handler;
 mod tuic_server;
 mod tun;
+mod udp_batch;
 mod udp_message_stream;
+mod udp_sessions;
 mod uot;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod user_store;
 mod util;
 mod uuid_util;
 mod vless;
 mod vmess;
 mod websocket;
+mod wireguard_client;
+mod xhttp;
 mod xudp;
 
 #[cfg(not(any(target_env = "msvc", target_os = "ios")))]
@@ -64,27 +116,23 @@ use aws_lc_rs::rand::{SecureRandom, SystemRandom};
 use base64::engine::{Engine as _, general_purpose::STANDARD};
 use log::debug;
 use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use tcp_server::start_servers;
+use qrcode::render::unicode::Dense1x2;
 use tokio::runtime::Builder;
-use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
 
+use crate::obfs4::generate_node_id;
 use crate::reality::generate_keypair;
+use crate::reload::{ReloadHandle, run_with_reload};
 use crate::shadowsocks::ShadowsocksCipher;
-use crate::thread_util::set_num_threads;
-use tcp::*;
-
-#[derive(Debug)]
-struct ConfigChanged;
+use crate::thread_util::{configured_worker_threads, set_num_threads};
 
 fn start_notify_thread(
     config_paths: Vec<String>,
-) -> (RecommendedWatcher, UnboundedReceiver<ConfigChanged>) {
-    let (tx, rx) = unbounded_channel();
-
+    reload_handle: ReloadHandle,
+) -> RecommendedWatcher {
     let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| match res {
         Ok(event) => {
             if matches!(event.kind, EventKind::Modify(..)) {
-                tx.send(ConfigChanged {}).unwrap();
+                reload_handle.reload();
             }
         }
         Err(e) => println!("watch error: {e:?}"),
@@ -97,7 +145,26 @@ fn start_notify_thread(
             .unwrap();
     }
 
-    (watcher, rx)
+    watcher
+}
+
+#[cfg(unix)]
+fn start_sighup_listener(reload_handle: ReloadHandle) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("Failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            println!("Received SIGHUP");
+            reload_handle.reload();
+        }
+    });
 }
 
 fn print_usage_and_exit(arg0: String) {
@@ -105,17 +172,33 @@ fn print_usage_and_exit(arg0: String) {
     eprintln!();
     eprintln!("OPTIONS:");
     eprintln!("    -t, --threads NUM    Set the number of worker threads (default: CPU count)");
+    eprintln!("                         (overrides the worker_threads config)");
     eprintln!("    -d, --dry-run        Parse the config and exit");
-    eprintln!("    --no-reload          Disable automatic config reloading on file changes");
+    eprintln!("    --no-reload          Disable automatic reloading on config or cert changes");
+    eprintln!("                         (SIGHUP always triggers a reload)");
+    eprintln!("    --share-links HOST   Print client share links of the servers at HOST and exit");
+    eprintln!("    --qr                 Print the share links as QR codes too");
     eprintln!();
     eprintln!("COMMANDS:");
     eprintln!(
         "    generate-reality-keypair                       Generate a new Reality X25519 keypair"
     );
+    eprintln!(
+        "    generate-obfs4-keys                            Generate an obfs4 keypair and node ID"
+    );
     eprintln!("    generate-shadowsocks-2022-password <cipher>    Generate a Shadowsocks password");
     eprintln!(
         "    generate-vless-user-id                         Generate a random VLESS/VMESS user ID (UUID v4)"
     );
+    eprintln!(
+        "    convert-clash <clash.yaml>                     Print a Clash config as a shoes config"
+    );
+    eprintln!(
+        "    convert-singbox <config.json>                  Print a sing-box config as a shoes config"
+    );
+    eprintln!(
+        "    convert-xray <config.json>                     Print an Xray config as a shoes config"
+    );
     std::process::exit(1);
 }
 
@@ -173,11 +256,17 @@ fn main() {
         })
         .init();
 
+    // After logging is set up, so that its messages are shown, and before any thread is
+    // started, since it clears the variables set by systemd.
+    systemd::init();
+
     let mut args: Vec<String> = std::env::args().collect();
     let arg0 = args.remove(0);
     let mut num_threads = 0usize;
     let mut dry_run = false;
     let mut no_reload = false;
+    let mut share_links_host = None;
+    let mut qr = false;
 
     while !args.is_empty() && args[0].starts_with("-") {
         if args[0] == "--threads" || args[0] == "-t" {
@@ -201,6 +290,17 @@ fn main() {
         } else if args[0] == "--no-reload" {
             args.remove(0);
             no_reload = true;
+        } else if args[0] == "--share-links" {
+            args.remove(0);
+            if args.is_empty() {
+                eprintln!("Missing share links host argument.");
+                print_usage_and_exit(arg0);
+                return;
+            }
+            share_links_host = Some(args.remove(0));
+        } else if args[0] == "--qr" {
+            args.remove(0);
+            qr = true;
         } else {
             eprintln!("Invalid argument: {}", args[0]);
             print_usage_and_exit(arg0);
@@ -221,6 +321,20 @@ fn main() {
         return;
     }
 
+    if args.iter().any(|s| s == "generate-obfs4-keys") {
+        let (private_key, public_key) = generate_keypair().unwrap();
+        println!(
+            "--------------------------------------------------------------------------------"
+        );
+        println!("obfs4 private key: {}", private_key);
+        println!("obfs4 public key: {}", public_key);
+        println!("obfs4 node ID: {}", generate_node_id());
+        println!(
+            "--------------------------------------------------------------------------------"
+        );
+        return;
+    }
+
     if let Some(pos) = args
         .iter()
         .position(|s| s == "generate-shadowsocks-2022-password")
@@ -290,15 +404,75 @@ fn main() {
         return;
     }
 
+    type Converter = fn(&str) -> std::io::Result<config::ConvertedConfigs>;
+    let converters: [(&str, &str, Converter); 3] = [
+        ("convert-clash", "<clash.yaml>", config::convert_clash_config),
+        ("convert-singbox", "<config.json>", config::convert_singbox_config),
+        ("convert-xray", "<config.json>", config::convert_xray_config),
+    ];
+    for (command, file, convert) in converters {
+        let Some(pos) = args.iter().position(|s| s == command) else {
+            continue;
+        };
+        let Some(path) = args.get(pos + 1) else {
+            eprintln!("Usage: {arg0} {command} {file}");
+            std::process::exit(1);
+        };
+        let converted = match std::fs::read_to_string(path).and_then(|config| convert(&config)) {
+            Ok(converted) => converted,
+            Err(e) => {
+                eprintln!("Failed to convert {path}: {e}");
+                std::process::exit(1);
+            }
+        };
+        for warning in converted.warnings.iter() {
+            eprintln!("Warning: {warning}");
+        }
+        match serde_yaml::to_string(&converted.configs) {
+            Ok(yaml) => print!("{yaml}"),
+            Err(e) => {
+                eprintln!("Failed to write the shoes config: {e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
     if args.is_empty() {
         println!("No config specified, assuming loading from file config.shoes.yaml");
         args.push("config.shoes.yaml".to_string())
     }
 
+    if let Some(host) = share_links_host {
+        if let Err(e) = print_share_links(&args, &host, qr) {
+            eprintln!("Failed to create share links: {e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if dry_run {
         println!("Starting dry run.");
     }
 
+    crypto_provider::log_cpu_extensions();
+
+    if num_threads == 0 {
+        // The runtime is built before the configs are loaded, so read them once here.
+        // Errors are reported when they are loaded again.
+        let configs = Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .ok()
+            .and_then(|runtime| runtime.block_on(config::load_configs(&args)).ok());
+        if let Some(worker_threads) = configs
+            .as_deref()
+            .and_then(configured_worker_threads)
+        {
+            num_threads = worker_threads;
+        }
+    }
+
     if num_threads == 0 {
         num_threads = std::cmp::max(
             2,
@@ -330,112 +504,92 @@ fn main() {
         .expect("Could not build tokio runtime");
 
     runtime.block_on(async move {
-        let mut reload_state = if no_reload {
-            None
-        } else {
-            let (watcher, rx) = start_notify_thread(args.clone());
-            Some((watcher, rx))
-        };
-
-        loop {
-            let configs = match config::load_configs(&args).await {
-                Ok(c) => c,
+        if dry_run {
+            match dry_run_configs(&args).await {
+                Ok(()) => println!("Finishing dry run, config parsed successfully."),
                 Err(e) => {
-                    eprintln!("Failed to load server configs: {e}\n");
-                    print_usage_and_exit(arg0);
-                    return;
+                    eprintln!("Dry run failed: {e}\n");
+                    std::process::exit(1);
                 }
-            };
-
-            let (configs, load_file_count) = match config::convert_cert_paths(configs).await {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Failed to load cert files: {e}\n");
-                    print_usage_and_exit(arg0);
-                    return;
-                }
-            };
-
-            if load_file_count > 0 {
-                    println!("Loaded {load_file_count} certs/keys from files");
             }
+            return;
+        }
 
-            for config in configs.iter() {
-                debug!("================================================================================");
-                debug!("{config:#?}");
-            }
-            debug!("================================================================================");
-
-            if dry_run {
-                if let Err(e) = config::create_server_configs(configs) {
-                    eprintln!("Dry run failed, could not create server configs: {e}\n");
-                } else {
-                    println!("Finishing dry run, config parsed successfully.");
-                }
-                return;
-            }
-
-            let mut join_handles = vec![];
-
-            let server_configs = match config::create_server_configs(configs) {
-                Ok(c) => c,
-                Err(e) => {
-                    eprintln!("Failed to create server configs: {e}\n");
-                    print_usage_and_exit(arg0);
-                    return;
-                }
-            };
-
-            let config::ValidatedConfigs {
-                configs: server_configs,
-                dns_groups,
-            } = server_configs;
-
-            // Build DNS registry from expanded groups (async - resolves hostnames)
-            let mut dns_registry = match dns::build_dns_registry(dns_groups).await {
-                Ok(r) => r,
-                Err(e) => {
-                    eprintln!("Failed to build DNS registry: {e}\n");
-                    print_usage_and_exit(arg0);
-                    return;
-                }
-            };
-
-            println!("\nStarting {} server(s)..", server_configs.len());
-
-            for server_config in server_configs {
-                // Get the resolver for this server from the registry
-                let dns_ref = match &server_config {
-                    config::Config::Server(s) => s.dns.as_ref(),
-                    config::Config::TunServer(t) => t.dns.as_ref(),
-                    _ => None,
-                };
-                let resolver = dns_registry.get_for_server(dns_ref);
-                join_handles.extend(start_servers(server_config, resolver).await.unwrap());
-            }
+        let reload_handle = ReloadHandle::new();
 
-            match reload_state.as_mut() {
-                Some((_watcher, rx)) => {
-                    rx.recv().await.unwrap();
+        let _watcher = if no_reload {
+            None
+        } else {
+            Some(start_notify_thread(args.clone(), reload_handle.clone()))
+        };
 
-                    println!("Configs changed, restarting servers in 3 seconds..");
+        #[cfg(unix)]
+        start_sighup_listener(reload_handle.clone());
 
-                    for join_handle in join_handles {
-                        join_handle.abort();
-                    }
+        if let Err(e) = run_with_reload(args, reload_handle, !no_reload).await {
+            eprintln!("Failed to start servers: {e}\n");
+            print_usage_and_exit(arg0);
+        }
+    });
+}
 
-                    tokio::time::sleep(std::time::Duration::from_secs(3)).await;
+fn print_share_links(args: &Vec<String>, host: &str, qr: bool) -> std::io::Result<()> {
+    let configs = Builder::new_current_thread()
+        .enable_io()
+        .build()?
+        .block_on(config::load_configs(args))?;
+    let user_groups: Vec<config::UserGroupConfig> = configs
+        .iter()
+        .filter_map(|config| match config {
+            config::Config::UserGroup(group) => Some(group.clone()),
+            _ => None,
+        })
+        .collect();
 
-                    // Remove any extra events
-                    while rx.try_recv().is_ok() {}
-                }
-                None => {
-                    // No reload mode - wait forever
-                    // TODO: signal handling?
-                    futures::future::pending::<()>().await;
-                    unreachable!();
+    let mut found = false;
+    for config in configs.iter() {
+        let config::Config::Server(server) = config else {
+            continue;
+        };
+        let links = config::server_share_links(server, &user_groups, host)?;
+        if links.is_empty() {
+            continue;
+        }
+        found = true;
+        println!("{}://{}", server.transport, server.bind_location);
+        for link in links {
+            println!("{link}");
+            if qr {
+                match qrcode::QrCode::new(&link) {
+                    Ok(code) => println!(
+                        "{}",
+                        code.render::<Dense1x2>()
+                            .dark_color(Dense1x2::Light)
+                            .light_color(Dense1x2::Dark)
+                            .build()
+                    ),
+                    Err(e) => eprintln!("Failed to create QR code: {e}"),
                 }
             }
         }
-    });
+    }
+    if !found {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "no VLESS, Trojan, Shadowsocks or Hysteria2 servers that share links can describe",
+        ));
+    }
+    Ok(())
+}
+
+async fn dry_run_configs(args: &Vec<String>) -> std::io::Result<()> {
+    let report = config::validate(args).await?;
+    for path in report.included_files.iter() {
+        println!("Included {path}");
+    }
+    for listener in report.listeners.iter() {
+        println!("Would start {listener}");
+    }
+    debug!("{report:#?}");
+    Ok(())
 }
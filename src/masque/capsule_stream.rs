@@ -0,0 +1,338 @@
+//! UDP payloads carried in DATAGRAM capsules (RFC 9297).
+//!
+//! Each capsule is a varint type, a varint length, and the value. A DATAGRAM capsule
+//! value is a context ID varint followed by the payload; context ID 0 is a UDP
+//! payload for CONNECT-UDP. Capsules of other types and datagrams with other context
+//! IDs are skipped.
+
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::ReadBuf;
+
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncShutdownMessage,
+    AsyncStream, AsyncWriteMessage,
+};
+use crate::util::allocate_vec;
+
+const CAPSULE_TYPE_DATAGRAM: u64 = 0x00;
+
+/// Context ID of UDP payloads.
+const UDP_PAYLOAD_CONTEXT_ID: u64 = 0;
+
+/// Maximum size of a capsule value, enough for any UDP payload.
+const MAX_CAPSULE_VALUE_SIZE: usize = 65536;
+
+/// Room for the capsule type and length varints in front of the value.
+const MAX_CAPSULE_HEADER_SIZE: usize = 16;
+
+pub struct CapsuleMessageStream<S> {
+    stream: S,
+    read_buf: Box<[u8]>,
+    read_end_index: usize,
+    pending_write: Vec<u8>,
+    write_offset: usize,
+    is_eof: bool,
+}
+
+impl<S: AsyncStream> CapsuleMessageStream<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            read_buf: allocate_vec(MAX_CAPSULE_HEADER_SIZE + MAX_CAPSULE_VALUE_SIZE)
+                .into_boxed_slice(),
+            read_end_index: 0,
+            pending_write: Vec::with_capacity(MAX_CAPSULE_HEADER_SIZE + MAX_CAPSULE_VALUE_SIZE),
+            write_offset: 0,
+            is_eof: false,
+        }
+    }
+}
+
+/// Encodes a value as QUIC varint.
+fn encode_varint(value: u64, out: &mut Vec<u8>) {
+    if value < (1 << 6) {
+        out.push(value as u8);
+    } else if value < (1 << 14) {
+        out.extend_from_slice(&((0b01_u16 << 14) | value as u16).to_be_bytes());
+    } else if value < (1 << 30) {
+        out.extend_from_slice(&((0b10_u32 << 30) | value as u32).to_be_bytes());
+    } else {
+        out.extend_from_slice(&((0b11_u64 << 62) | value).to_be_bytes());
+    }
+}
+
+/// Decodes a QUIC varint, returns (value, bytes_consumed), or None if `data` is incomplete.
+fn decode_varint(data: &[u8]) -> Option<(u64, usize)> {
+    let first_byte = *data.first()?;
+    let num_bytes = 1 << (first_byte >> 6);
+    if data.len() < num_bytes {
+        return None;
+    }
+    let mut value = (first_byte & 0b00111111) as u64;
+    for byte in &data[1..num_bytes] {
+        value = (value << 8) | *byte as u64;
+    }
+    Some((value, num_bytes))
+}
+
+/// Decodes a capsule header, returns (capsule type, header length, value length).
+fn decode_capsule_header(data: &[u8]) -> Option<(u64, usize, usize)> {
+    let (capsule_type, type_len) = decode_varint(data)?;
+    let (value_len, len_len) = decode_varint(&data[type_len..])?;
+    Some((capsule_type, type_len + len_len, value_len as usize))
+}
+
+impl<S: AsyncStream> AsyncReadMessage for CapsuleMessageStream<S> {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out_buf: &mut ReadBuf<'_>,
+    ) -> Poll<Result<()>> {
+        let this = self.get_mut();
+
+        if this.is_eof {
+            return Poll::Ready(Ok(()));
+        }
+
+        loop {
+            if let Some((capsule_type, header_len, value_len)) =
+                decode_capsule_header(&this.read_buf[..this.read_end_index])
+            {
+                if value_len > MAX_CAPSULE_VALUE_SIZE {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("capsule too large: {value_len} bytes"),
+                    )));
+                }
+                let total_len = header_len + value_len;
+                if this.read_end_index >= total_len {
+                    let value = &this.read_buf[header_len..total_len];
+                    let payload = if capsule_type == CAPSULE_TYPE_DATAGRAM {
+                        match decode_varint(value) {
+                            Some((UDP_PAYLOAD_CONTEXT_ID, context_id_len)) => {
+                                Some(&value[context_id_len..])
+                            }
+                            Some(_) => None,
+                            None => {
+                                return Poll::Ready(Err(Error::new(
+                                    ErrorKind::InvalidData,
+                                    "DATAGRAM capsule without a context ID",
+                                )));
+                            }
+                        }
+                    } else {
+                        None
+                    };
+
+                    let is_udp_payload = payload.is_some();
+                    if let Some(payload) = payload {
+                        if out_buf.remaining() < payload.len() {
+                            return Poll::Ready(Err(Error::other(
+                                "out_buf is too small to hold the message",
+                            )));
+                        }
+                        out_buf.put_slice(payload);
+                    }
+
+                    if this.read_end_index > total_len {
+                        this.read_buf.copy_within(total_len..this.read_end_index, 0);
+                        this.read_end_index -= total_len;
+                    } else {
+                        this.read_end_index = 0;
+                    }
+
+                    if is_udp_payload {
+                        return Poll::Ready(Ok(()));
+                    }
+                    continue;
+                }
+            }
+
+            let read_buf_slice = &mut this.read_buf[this.read_end_index..];
+            // the buffer can hold the largest capsule, which was checked above.
+            assert!(!read_buf_slice.is_empty());
+            let mut tmp = ReadBuf::new(read_buf_slice);
+            match Pin::new(&mut this.stream).poll_read(cx, &mut tmp) {
+                Poll::Ready(Ok(())) => {
+                    let n = tmp.filled().len();
+                    if n == 0 {
+                        this.is_eof = true;
+                        if this.read_end_index == 0 {
+                            return Poll::Ready(Ok(()));
+                        } else {
+                            return Poll::Ready(Err(Error::new(
+                                ErrorKind::UnexpectedEof,
+                                "EOF reached in the middle of a capsule",
+                            )));
+                        }
+                    }
+                    this.read_end_index += n;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<S: AsyncStream> AsyncWriteMessage for CapsuleMessageStream<S> {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<()>> {
+        let mut this = self.get_mut();
+
+        if !this.pending_write.is_empty() {
+            if let Poll::Ready(Err(e)) = Pin::new(&mut this).poll_flush_message(cx) {
+                return Poll::Ready(Err(e));
+            }
+            if !this.pending_write.is_empty() {
+                return Poll::Pending;
+            }
+        }
+
+        if buf.len() > 65535 {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::InvalidInput,
+                "message size too large",
+            )));
+        }
+
+        // The value is the context ID, which always encodes to a single byte, and the payload.
+        encode_varint(CAPSULE_TYPE_DATAGRAM, &mut this.pending_write);
+        encode_varint(1 + buf.len() as u64, &mut this.pending_write);
+        encode_varint(UDP_PAYLOAD_CONTEXT_ID, &mut this.pending_write);
+        this.pending_write.extend_from_slice(buf);
+        this.write_offset = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncStream> AsyncFlushMessage for CapsuleMessageStream<S> {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while this.write_offset < this.pending_write.len() {
+            let chunk = &this.pending_write[this.write_offset..];
+            match Pin::new(&mut this.stream).poll_write(cx, chunk) {
+                Poll::Ready(Ok(n)) => {
+                    if n == 0 {
+                        return Poll::Ready(Err(Error::new(
+                            ErrorKind::WriteZero,
+                            "failed to write capsule",
+                        )));
+                    }
+                    this.write_offset += n;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        match Pin::new(&mut this.stream).poll_flush(cx) {
+            Poll::Ready(Ok(())) => {
+                this.pending_write.clear();
+                this.write_offset = 0;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl<S: AsyncStream> AsyncShutdownMessage for CapsuleMessageStream<S> {
+    fn poll_shutdown_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        match <Self as AsyncFlushMessage>::poll_flush_message(Pin::new(this), cx) {
+            Poll::Ready(Ok(())) => {}
+            other => return other,
+        }
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+impl<S: AsyncStream> AsyncPing for CapsuleMessageStream<S> {
+    fn supports_ping(&self) -> bool {
+        self.stream.supports_ping()
+    }
+
+    fn poll_write_ping(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.stream).poll_write_ping(cx)
+    }
+}
+
+impl<S: AsyncStream> AsyncMessageStream for CapsuleMessageStream<S> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for value in [0, 63, 64, 16383, 16384, (1 << 30) - 1, 1 << 30] {
+            let mut encoded = vec![];
+            encode_varint(value, &mut encoded);
+            assert_eq!(decode_varint(&encoded), Some((value, encoded.len())));
+            assert_eq!(decode_varint(&encoded[..encoded.len() - 1]), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_datagram_capsule() {
+        let (local, mut remote) = tcp_pair().await;
+        let mut stream = CapsuleMessageStream::new(local);
+
+        poll_fn(|cx| Pin::new(&mut stream).poll_write_message(cx, b"hello"))
+            .await
+            .unwrap();
+        poll_fn(|cx| Pin::new(&mut stream).poll_flush_message(cx))
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 8];
+        remote.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"\x00\x06\x00hello");
+    }
+
+    #[tokio::test]
+    async fn test_read_skips_other_capsules() {
+        let (local, mut remote) = tcp_pair().await;
+        let mut stream = CapsuleMessageStream::new(local);
+
+        // An unknown capsule, a datagram with another context ID, then a UDP payload.
+        remote.write_all(b"\x17\x02ab").await.unwrap();
+        remote.write_all(b"\x00\x03\x02cd").await.unwrap();
+        remote.write_all(b"\x00\x06\x00world").await.unwrap();
+        remote.shutdown().await.unwrap();
+
+        let mut data = [0u8; 64];
+        let mut buf = ReadBuf::new(&mut data);
+        poll_fn(|cx| Pin::new(&mut stream).poll_read_message(cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!(buf.filled(), b"world");
+
+        let mut buf = ReadBuf::new(&mut data);
+        poll_fn(|cx| Pin::new(&mut stream).poll_read_message(cx, &mut buf))
+            .await
+            .unwrap();
+        assert!(buf.filled().is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+//! MASQUE client.
+//!
+//! A single QUIC connection to the proxy is shared by all outgoing connections. TCP
+//! connections are CONNECT request streams, and UDP flows are CONNECT-UDP request
+//! streams carrying DATAGRAM capsules.
+
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use bytes::Bytes;
+use http::{Method, Request};
+use log::debug;
+use tokio::sync::Mutex;
+
+use crate::address::{Address, NetLocation, ResolvedLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::h3_stream::{H3ClientRequestStream, pipe_client_request_stream};
+use crate::resolver::{Resolver, resolve_single_address};
+use crate::tcp::socket_connector::SocketConnector;
+
+use super::capsule_stream::CapsuleMessageStream;
+use super::{CAPSULE_PROTOCOL_HEADER, udp_target_path};
+
+/// An HTTP/3 connection to the proxy.
+#[derive(Clone)]
+struct H3Session {
+    connection: quinn::Connection,
+    send_request: h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+}
+
+/// Socket connector that tunnels TCP and UDP through a MASQUE proxy.
+///
+/// Like Hysteria2, this is a hop 0 connector: it owns the QUIC connection to the
+/// proxy, so no ProxyConnector is needed on top of it.
+pub struct MasqueSocketConnector {
+    endpoint: Arc<quinn::Endpoint>,
+    server_address: NetLocation,
+    sni_hostname: Option<String>,
+    /// Proxy-Authorization header value, if credentials are configured
+    auth_header: Option<String>,
+    bind_interface: Option<String>,
+    /// Session slot for lazy init and reconnection
+    session: Mutex<Option<H3Session>>,
+}
+
+impl std::fmt::Debug for MasqueSocketConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MasqueSocketConnector")
+            .field("server_address", &self.server_address)
+            .field("sni_hostname", &self.sni_hostname)
+            .finish()
+    }
+}
+
+impl MasqueSocketConnector {
+    pub fn new(
+        endpoint: Arc<quinn::Endpoint>,
+        server_address: NetLocation,
+        sni_hostname: Option<String>,
+        credentials: Option<(String, String)>,
+        bind_interface: Option<String>,
+    ) -> Self {
+        let auth_header = credentials.map(|(username, password)| {
+            format!("Basic {}", BASE64.encode(format!("{username}:{password}")))
+        });
+        Self {
+            endpoint,
+            server_address,
+            sni_hostname,
+            auth_header,
+            bind_interface,
+            session: Mutex::new(None),
+        }
+    }
+
+    fn server_name(&self) -> &str {
+        self.sni_hostname
+            .as_deref()
+            .or(self.server_address.address().hostname())
+            .unwrap_or("example.com")
+    }
+
+    /// Get the current session, or connect a new one if there is none or it was closed.
+    async fn get_or_create_session(&self, resolver: &Arc<dyn Resolver>) -> io::Result<H3Session> {
+        let mut guard = self.session.lock().await;
+
+        if let Some(ref session) = *guard
+            && session.connection.close_reason().is_none()
+        {
+            return Ok(session.clone());
+        }
+
+        debug!("MASQUE: creating new H3 session to {}", self.server_address);
+
+        let server_addr = resolve_single_address(resolver, &self.server_address).await?;
+
+        let connection = self
+            .endpoint
+            .connect(server_addr, self.server_name())
+            .map_err(|e| io::Error::other(format!("Failed to connect to QUIC endpoint: {e}")))?
+            .await
+            .map_err(|e| io::Error::other(format!("QUIC connection failed: {e}")))?;
+
+        let (mut driver, send_request) =
+            h3::client::new(h3_quinn::Connection::new(connection.clone()))
+                .await
+                .map_err(|e| io::Error::other(format!("H3 client handshake failed: {e}")))?;
+
+        tokio::spawn(async move {
+            let e = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+            debug!("MASQUE client H3 connection ended: {e:?}");
+        });
+
+        let session = H3Session {
+            connection,
+            send_request,
+        };
+        *guard = Some(session.clone());
+
+        Ok(session)
+    }
+
+    /// Sends `request` on the session and waits for a successful response.
+    async fn send_request(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        mut request: Request<()>,
+    ) -> io::Result<H3ClientRequestStream> {
+        if let Some(ref auth_header) = self.auth_header {
+            request.headers_mut().insert(
+                "proxy-authorization",
+                auth_header.parse().map_err(io::Error::other)?,
+            );
+        }
+
+        let mut send_request = self.get_or_create_session(resolver).await?.send_request;
+        let mut stream = send_request
+            .send_request(request)
+            .await
+            .map_err(|e| io::Error::other(format!("Failed to send request: {e}")))?;
+
+        let response = stream
+            .recv_response()
+            .await
+            .map_err(|e| io::Error::other(format!("Response error: {e}")))?;
+
+        if !response.status().is_success() {
+            return Err(io::Error::other(format!(
+                "MASQUE request failed with status: {}",
+                response.status()
+            )));
+        }
+
+        Ok(stream)
+    }
+}
+
+/// Builds the authority of a CONNECT request to `target`.
+fn connect_authority(target: &NetLocation) -> String {
+    match target.address() {
+        Address::Ipv6(ip) => format!("[{ip}]:{}", target.port()),
+        address => format!("{address}:{}", target.port()),
+    }
+}
+
+#[async_trait]
+impl SocketConnector for MasqueSocketConnector {
+    async fn connect(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        address: &ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncStream>> {
+        let request = Request::builder()
+            .method(Method::CONNECT)
+            .uri(connect_authority(address.location()))
+            .body(())
+            .map_err(io::Error::other)?;
+
+        let stream = self.send_request(resolver, request).await?;
+
+        debug!("MASQUE: opened CONNECT stream to {}", address.location());
+
+        Ok(Box::new(pipe_client_request_stream(stream)))
+    }
+
+    async fn connect_udp_bidirectional(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        target: ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncMessageStream>> {
+        let uri = format!(
+            "https://{}{}",
+            connect_authority(&NetLocation::new(
+                Address::Hostname(self.server_name().to_string()),
+                self.server_address.port(),
+            )),
+            udp_target_path(target.location())
+        );
+        let mut request = Request::builder()
+            .method(Method::CONNECT)
+            .uri(uri)
+            .header(CAPSULE_PROTOCOL_HEADER, "?1")
+            .body(())
+            .map_err(io::Error::other)?;
+        request
+            .extensions_mut()
+            .insert(h3::ext::Protocol::CONNECT_UDP);
+
+        let stream = self.send_request(resolver, request).await?;
+
+        debug!("MASQUE: opened CONNECT-UDP stream to {}", target.location());
+
+        Ok(Box::new(CapsuleMessageStream::new(
+            pipe_client_request_stream(stream),
+        )))
+    }
+
+    fn bind_interface(&self) -> Option<&str> {
+        self.bind_interface.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_authority() {
+        assert_eq!(
+            connect_authority(&NetLocation::new(
+                Address::Hostname("example.com".to_string()),
+                443
+            )),
+            "example.com:443"
+        );
+        assert_eq!(
+            connect_authority(&NetLocation::new(
+                Address::Ipv6("2001:db8::1".parse().unwrap()),
+                443
+            )),
+            "[2001:db8::1]:443"
+        );
+    }
+}
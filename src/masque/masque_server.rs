@@ -0,0 +1,282 @@
+//! MASQUE server.
+//!
+//! Serves HTTP/3 CONNECT requests as TCP tunnels, and CONNECT-UDP requests as UDP
+//! flows to the target named by the request path. Each request stream is routed on
+//! its own.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use log::{debug, error};
+use tokio::io::AsyncWriteExt;
+use tokio::task::JoinHandle;
+
+use crate::access_control::{AccessControl, admit_quic};
+use crate::address::NetLocation;
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::h3_stream::{H3ServerRequestStream, pipe_server_request_stream};
+use crate::http2_handler::{is_authorized, parse_authority};
+use crate::metrics::ByteCounter;
+use crate::rate_limit::RateLimiter;
+use crate::resolver::Resolver;
+use crate::tcp::tcp_server::run_udp_copy;
+
+use super::capsule_stream::CapsuleMessageStream;
+use super::{CAPSULE_PROTOCOL_HEADER, parse_udp_target_path};
+
+struct MasqueServerConfig {
+    auth_token: Option<String>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+}
+
+async fn process_connection(
+    config: Arc<MasqueServerConfig>,
+    conn: quinn::Incoming,
+) -> io::Result<()> {
+    let connection = conn.await?;
+
+    // CONNECT-UDP is an extended CONNECT request. HTTP/3 datagrams are not enabled, so
+    // clients send UDP payloads in capsules on the request stream.
+    let mut h3_conn: h3::server::Connection<h3_quinn::Connection, Bytes> = h3::server::builder()
+        .enable_extended_connect(true)
+        .build(h3_quinn::Connection::new(connection))
+        .await
+        .map_err(io::Error::other)?;
+
+    loop {
+        match h3_conn.accept().await.map_err(io::Error::other)? {
+            Some(resolver) => {
+                let config = config.clone();
+                tokio::spawn(async move {
+                    let (req, stream) = match resolver.resolve_request().await {
+                        Ok(request) => request,
+                        Err(e) => {
+                            debug!("Failed to resolve MASQUE request: {e}");
+                            return;
+                        }
+                    };
+                    if let Err(e) = handle_request(req, stream, config).await {
+                        debug!("MASQUE request ended with error: {e}");
+                    }
+                });
+            }
+            // indicating no more streams to be received
+            None => return Ok(()),
+        }
+    }
+}
+
+async fn send_status(mut stream: H3ServerRequestStream, status: StatusCode) -> io::Result<()> {
+    let mut response = Response::builder().status(status);
+    if status == StatusCode::PROXY_AUTHENTICATION_REQUIRED {
+        response = response.header("proxy-authenticate", "Basic realm=\"proxy\"");
+    }
+    stream
+        .send_response(response.body(()).unwrap())
+        .await
+        .map_err(io::Error::other)?;
+    stream.finish().await.map_err(io::Error::other)
+}
+
+async fn handle_request(
+    req: Request<()>,
+    mut stream: H3ServerRequestStream,
+    config: Arc<MasqueServerConfig>,
+) -> io::Result<()> {
+    if req.method() != Method::CONNECT {
+        debug!("Unsupported MASQUE request method: {}", req.method());
+        return send_status(stream, StatusCode::METHOD_NOT_ALLOWED).await;
+    }
+
+    if let Some(ref auth_token) = config.auth_token
+        && !is_authorized(req.headers(), auth_token)
+    {
+        debug!("Missing or incorrect MASQUE authentication");
+        return send_status(stream, StatusCode::PROXY_AUTHENTICATION_REQUIRED).await;
+    }
+
+    let is_udp = match req.extensions().get::<h3::ext::Protocol>() {
+        None => false,
+        Some(protocol) if *protocol == h3::ext::Protocol::CONNECT_UDP => true,
+        Some(protocol) => {
+            debug!("Unsupported MASQUE CONNECT protocol: {protocol:?}");
+            return send_status(stream, StatusCode::NOT_IMPLEMENTED).await;
+        }
+    };
+
+    let remote_location: io::Result<NetLocation> = if is_udp {
+        parse_udp_target_path(req.uri().path())
+    } else {
+        req.uri()
+            .authority()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Missing authority"))
+            .and_then(parse_authority)
+    };
+    let remote_location = match remote_location {
+        Ok(location) => location,
+        Err(e) => {
+            debug!("Invalid MASQUE destination: {e}");
+            return send_status(stream, StatusCode::BAD_REQUEST).await;
+        }
+    };
+
+    let decision = match config
+        .proxy_selector
+        .judge(remote_location.into(), &config.resolver)
+        .await
+    {
+        Ok(decision) => decision,
+        Err(e) => {
+            debug!("Failed to route MASQUE request: {e}");
+            return send_status(stream, StatusCode::BAD_GATEWAY).await;
+        }
+    };
+
+    let (chain_group, remote_location) = match decision {
+        ConnectDecision::Allow {
+            chain_group,
+            remote_location,
+        } => (chain_group, remote_location),
+        ConnectDecision::Block => {
+            debug!("MASQUE request blocked by rules");
+            return send_status(stream, StatusCode::FORBIDDEN).await;
+        }
+    };
+
+    if is_udp {
+        let client_stream = match chain_group
+            .connect_udp_bidirectional(&config.resolver, remote_location)
+            .await
+        {
+            Ok(client_stream) => client_stream,
+            Err(e) => {
+                debug!("MASQUE CONNECT-UDP failed to connect: {e}");
+                return send_status(stream, StatusCode::BAD_GATEWAY).await;
+            }
+        };
+
+        let response = Response::builder()
+            .status(StatusCode::OK)
+            .header(CAPSULE_PROTOCOL_HEADER, "?1")
+            .body(())
+            .unwrap();
+        stream
+            .send_response(response)
+            .await
+            .map_err(io::Error::other)?;
+
+        let server_stream = CapsuleMessageStream::new(pipe_server_request_stream(stream));
+        run_udp_copy(
+            Box::new(server_stream),
+            client_stream,
+            false,
+            false,
+            ByteCounter::default(),
+            ByteCounter::default(),
+            RateLimiter::default(),
+            RateLimiter::default(),
+        )
+        .await
+    } else {
+        let mut client_stream = match chain_group
+            .connect_tcp(remote_location, &config.resolver)
+            .await
+        {
+            Ok(result) => result.client_stream,
+            Err(e) => {
+                debug!("MASQUE CONNECT failed to connect: {e}");
+                return send_status(stream, StatusCode::BAD_GATEWAY).await;
+            }
+        };
+
+        stream
+            .send_response(Response::builder().status(StatusCode::OK).body(()).unwrap())
+            .await
+            .map_err(io::Error::other)?;
+
+        let mut server_stream = pipe_server_request_stream(stream);
+        let result = tokio::io::copy_bidirectional(&mut server_stream, &mut client_stream).await;
+        let _ = client_stream.shutdown().await;
+        result.map(|_| ())
+    }
+}
+
+pub async fn start_masque_server(
+    bind_address: SocketAddr,
+    quic_server_config: Arc<quinn::crypto::rustls::QuicServerConfig>,
+    auth_token: Option<String>,
+    client_proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    num_endpoints: usize,
+    access_control: Option<Arc<AccessControl>>,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    let config = Arc::new(MasqueServerConfig {
+        auth_token,
+        proxy_selector: client_proxy_selector,
+        resolver,
+    });
+
+    let mut join_handles = vec![];
+    for _ in 0..num_endpoints {
+        let quic_server_config = quic_server_config.clone();
+        let config = config.clone();
+        let access_control = access_control.clone();
+
+        let join_handle = tokio::spawn(async move {
+            let mut server_config = quinn::ServerConfig::with_crypto(quic_server_config);
+
+            Arc::get_mut(&mut server_config.transport)
+                .unwrap()
+                .max_concurrent_bidi_streams(4096_u32.into())
+                // required for HTTP/3 QPACK updates
+                .max_concurrent_uni_streams(1024_u32.into())
+                .max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()))
+                .keep_alive_interval(Some(Duration::from_secs(10)))
+                .send_window(16 * 1024 * 1024)
+                .receive_window((20u32 * 1024 * 1024).into())
+                .stream_receive_window((8u32 * 1024 * 1024).into())
+                .initial_mtu(1200)
+                .min_mtu(1200)
+                .mtu_discovery_config(Some(quinn::MtuDiscoveryConfig::default()))
+                .enable_segmentation_offload(true)
+                .initial_rtt(Duration::from_millis(100));
+
+            let socket2_socket = crate::socket_util::new_socket2_udp_socket_with_buffer_size(
+                bind_address.is_ipv6(),
+                None,
+                Some(bind_address),
+                true,
+                Some(8_625_000),
+            )
+            .unwrap();
+
+            let endpoint = quinn::Endpoint::new(
+                quinn::EndpointConfig::default(),
+                Some(server_config),
+                socket2_socket.into(),
+                Arc::new(quinn::TokioRuntime),
+            )
+            .unwrap();
+
+            while let Some(conn) = endpoint.accept().await {
+                let Some(conn) = admit_quic(access_control.as_deref(), conn) else {
+                    continue;
+                };
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = process_connection(config, conn).await {
+                        error!("MASQUE connection ended with error: {e}");
+                    }
+                });
+            }
+        });
+        join_handles.push(join_handle);
+    }
+
+    Ok(join_handles)
+}
@@ -0,0 +1,102 @@
+//! MASQUE proxying over HTTP/3.
+//!
+//! TCP connections are tunneled with HTTP/3 CONNECT requests, and UDP flows with
+//! CONNECT-UDP (RFC 9298): an extended CONNECT request with the `connect-udp`
+//! protocol, to a path that names the target. Datagrams are sent in DATAGRAM
+//! capsules (RFC 9297) on the request stream, so HTTP/3 datagram support is not
+//! required on either side.
+
+mod capsule_stream;
+mod masque_client;
+mod masque_server;
+
+pub use capsule_stream::CapsuleMessageStream;
+pub use masque_client::MasqueSocketConnector;
+pub use masque_server::start_masque_server;
+
+use std::io;
+
+use crate::address::{Address, NetLocation};
+
+/// Path prefix of the default CONNECT-UDP URI template,
+/// `/.well-known/masque/udp/{target_host}/{target_port}/`.
+const UDP_PATH_PREFIX: &str = "/.well-known/masque/udp/";
+
+/// Header indicating that the capsule protocol is used on the request stream.
+const CAPSULE_PROTOCOL_HEADER: &str = "capsule-protocol";
+
+/// Builds the CONNECT-UDP request path for `target`.
+fn udp_target_path(target: &NetLocation) -> String {
+    let host = match target.address() {
+        // Colons are not allowed in a path segment of the template.
+        Address::Ipv6(ip) => ip.to_string().replace(':', "%3A"),
+        address => address.to_string(),
+    };
+    format!("{UDP_PATH_PREFIX}{host}/{}/", target.port())
+}
+
+/// Parses the target of a CONNECT-UDP request path.
+fn parse_udp_target_path(path: &str) -> io::Result<NetLocation> {
+    let invalid_path = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid CONNECT-UDP path: {path}"),
+        )
+    };
+
+    let target = path
+        .strip_prefix(UDP_PATH_PREFIX)
+        .ok_or_else(invalid_path)?;
+    let target = target.strip_suffix('/').unwrap_or(target);
+    let (host, port) = target.split_once('/').ok_or_else(invalid_path)?;
+    let host = host.replace("%3A", ":").replace("%3a", ":");
+    if host.is_empty() || host.contains('%') {
+        return Err(invalid_path());
+    }
+    let port = port.parse::<u16>().map_err(|_| invalid_path())?;
+
+    Ok(NetLocation::new(Address::from(&host)?, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_udp_target_path() {
+        let hostname = NetLocation::new(Address::Hostname("example.com".to_string()), 53);
+        assert_eq!(
+            udp_target_path(&hostname),
+            "/.well-known/masque/udp/example.com/53/"
+        );
+
+        let ipv6 = NetLocation::new(Address::Ipv6("2001:db8::1".parse().unwrap()), 443);
+        assert_eq!(
+            udp_target_path(&ipv6),
+            "/.well-known/masque/udp/2001%3Adb8%3A%3A1/443/"
+        );
+    }
+
+    #[test]
+    fn test_parse_udp_target_path() {
+        for target in [
+            NetLocation::new(Address::Hostname("example.com".to_string()), 53),
+            NetLocation::new(Address::Ipv4("192.0.2.1".parse().unwrap()), 8080),
+            NetLocation::new(Address::Ipv6("2001:db8::1".parse().unwrap()), 443),
+        ] {
+            assert_eq!(
+                parse_udp_target_path(&udp_target_path(&target)).unwrap(),
+                target
+            );
+        }
+
+        assert_eq!(
+            parse_udp_target_path("/.well-known/masque/udp/192.0.2.1/53").unwrap(),
+            NetLocation::new(Address::Ipv4("192.0.2.1".parse().unwrap()), 53)
+        );
+        assert!(parse_udp_target_path("/masque/udp/example.com/53/").is_err());
+        assert!(parse_udp_target_path("/.well-known/masque/udp/example.com/").is_err());
+        assert!(parse_udp_target_path("/.well-known/masque/udp//53/").is_err());
+        assert!(parse_udp_target_path("/.well-known/masque/udp/example.com/dns/").is_err());
+    }
+}
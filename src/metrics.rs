@@ -0,0 +1,568 @@
+//! Prometheus metrics.
+//!
+//! Counters are kept per listener (e.g. `tcp://0.0.0.0:443`) and per outbound chain
+//! group (e.g. `socks@1.2.3.4:1080`), and are served in the Prometheus text format by
+//! an HTTP listener configured with a top-level `metrics` entry.
+//!
+//! Metrics are only collected while a metrics listener is configured or a
+//! `StatsHandle` is alive, so servers without either don't pay for the bookkeeping.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
+
+use bytes::Bytes;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use parking_lot::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::config::MetricsConfig;
+
+const METRICS_PATH: &str = "/metrics";
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Number of live `CollectGuard`s.
+static COLLECTORS: AtomicUsize = AtomicUsize::new(0);
+static REGISTRY: LazyLock<Registry> = LazyLock::new(Registry::default);
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Metrics for a single listening address.
+#[derive(Debug, Default)]
+pub struct ListenerMetrics {
+    pub connections_accepted: Counter,
+    /// Open connections. For QUIC listeners, these are QUIC connections rather than
+    /// streams.
+    pub active_connections: Gauge,
+    /// Connections closed because the listener was at its connection limit.
+    pub connections_rejected: Counter,
+    pub active_sessions: Gauge,
+    pub handshake_failures: Counter,
+    pub udp_sessions: Counter,
+    /// Bytes sent from clients towards remote destinations.
+    pub bytes_up: Arc<Counter>,
+    /// Bytes sent from remote destinations back to clients.
+    pub bytes_down: Arc<Counter>,
+}
+
+impl ListenerMetrics {
+    /// Marks a session as active until the returned guard is dropped.
+    pub fn start_session(self: &Arc<Self>) -> SessionGuard {
+        self.active_sessions.inc();
+        SessionGuard(self.clone())
+    }
+}
+
+/// Decrements the active session count of a listener when dropped.
+#[derive(Debug)]
+pub struct SessionGuard(Arc<ListenerMetrics>);
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.0.active_sessions.dec();
+    }
+}
+
+/// Metrics for an outbound client chain group.
+#[derive(Debug, Default)]
+pub struct OutboundMetrics {
+    pub connections: Counter,
+    pub udp_sessions: Counter,
+    pub bytes_up: Arc<Counter>,
+    pub bytes_down: Arc<Counter>,
+    /// Changes of the chain used by url-test and failover groups.
+    pub chain_switches: Counter,
+}
+
+/// Sessions closed by the UDP session tables of all servers.
+#[derive(Debug, Default)]
+pub struct UdpSessionMetrics {
+    /// Sessions closed because they were idle.
+    pub expired: Counter,
+    /// Sessions evicted because their table was full.
+    pub evicted: Counter,
+}
+
+/// A set of counters that are incremented together for each chunk of copied data.
+///
+/// The default value counts nothing, and does not allocate.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounter(Vec<Arc<Counter>>);
+
+impl ByteCounter {
+    #[inline]
+    pub fn add(&self, n: usize) {
+        for counter in self.0.iter() {
+            counter.add(n as u64);
+        }
+    }
+
+    pub fn push(&mut self, counter: Arc<Counter>) {
+        self.0.push(counter);
+    }
+}
+
+/// Returns the (up, down) byte counters for a session on `listener` that was
+/// forwarded to `outbound`.
+pub fn byte_counters(
+    listener: Option<&ListenerMetrics>,
+    outbound: Option<&OutboundMetrics>,
+) -> (ByteCounter, ByteCounter) {
+    let mut up = vec![];
+    let mut down = vec![];
+    if let Some(listener) = listener {
+        up.push(listener.bytes_up.clone());
+        down.push(listener.bytes_down.clone());
+    }
+    if let Some(outbound) = outbound {
+        up.push(outbound.bytes_up.clone());
+        down.push(outbound.bytes_down.clone());
+    }
+    (ByteCounter(up), ByteCounter(down))
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed) || COLLECTORS.load(Ordering::Relaxed) > 0
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Collects metrics until the returned guard is dropped, even without a metrics
+/// listener.
+#[allow(dead_code)] // Used by the stats API of the library
+pub fn collect() -> CollectGuard {
+    COLLECTORS.fetch_add(1, Ordering::Relaxed);
+    CollectGuard(())
+}
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct CollectGuard(());
+
+impl Drop for CollectGuard {
+    fn drop(&mut self) {
+        COLLECTORS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Returns the metrics of all listeners, sorted by label.
+#[allow(dead_code)]
+pub fn listeners() -> Vec<(String, Arc<ListenerMetrics>)> {
+    REGISTRY
+        .listeners
+        .read()
+        .iter()
+        .map(|(label, metrics)| (label.clone(), metrics.clone()))
+        .collect()
+}
+
+/// Returns the metrics of all outbounds, sorted by label.
+#[allow(dead_code)]
+pub fn outbounds() -> Vec<(String, Arc<OutboundMetrics>)> {
+    REGISTRY
+        .outbounds
+        .read()
+        .iter()
+        .map(|(label, metrics)| (label.clone(), metrics.clone()))
+        .collect()
+}
+
+/// Returns the metrics for a listener, or None if metrics are disabled.
+pub fn listener(label: &str) -> Option<Arc<ListenerMetrics>> {
+    if !is_enabled() {
+        return None;
+    }
+    Some(REGISTRY.listener(label))
+}
+
+/// Returns the metrics for an outbound, or None if metrics are disabled.
+pub fn outbound(label: &str) -> Option<Arc<OutboundMetrics>> {
+    if !is_enabled() {
+        return None;
+    }
+    Some(REGISTRY.outbound(label))
+}
+
+/// Returns the metrics of UDP session tables, or None if metrics are disabled.
+pub fn udp_sessions() -> Option<&'static UdpSessionMetrics> {
+    if !is_enabled() {
+        return None;
+    }
+    Some(&REGISTRY.udp_sessions)
+}
+
+/// Counts a replayed handshake of `protocol`, if metrics are enabled.
+pub fn replay_detected(protocol: &'static str) {
+    if is_enabled() {
+        REGISTRY.replays.write().entry(protocol).or_default().inc();
+    }
+}
+
+/// Returns the replayed handshakes detected for each protocol, sorted by protocol.
+#[allow(dead_code)]
+pub fn replays() -> Vec<(&'static str, u64)> {
+    REGISTRY
+        .replays
+        .read()
+        .iter()
+        .map(|(protocol, counter)| (*protocol, counter.get()))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    listeners: RwLock<BTreeMap<String, Arc<ListenerMetrics>>>,
+    outbounds: RwLock<BTreeMap<String, Arc<OutboundMetrics>>>,
+    udp_sessions: UdpSessionMetrics,
+    /// Replayed handshakes detected for each protocol.
+    replays: RwLock<BTreeMap<&'static str, Counter>>,
+}
+
+impl Registry {
+    fn listener(&self, label: &str) -> Arc<ListenerMetrics> {
+        if let Some(metrics) = self.listeners.read().get(label) {
+            return metrics.clone();
+        }
+        self.listeners
+            .write()
+            .entry(label.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn outbound(&self, label: &str) -> Arc<OutboundMetrics> {
+        if let Some(metrics) = self.outbounds.read().get(label) {
+            return metrics.clone();
+        }
+        self.outbounds
+            .write()
+            .entry(label.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        let listeners = self.listeners.read();
+        let listener_metrics: [(&str, &str, &str, fn(&ListenerMetrics) -> String); 8] = [
+            (
+                "shoes_listener_connections_accepted_total",
+                "counter",
+                "Connections accepted by the listener.",
+                |m| m.connections_accepted.get().to_string(),
+            ),
+            (
+                "shoes_listener_active_connections",
+                "gauge",
+                "Connections currently open on the listener.",
+                |m| m.active_connections.get().to_string(),
+            ),
+            (
+                "shoes_listener_connections_rejected_total",
+                "counter",
+                "Connections rejected because the listener was at its connection limit.",
+                |m| m.connections_rejected.get().to_string(),
+            ),
+            (
+                "shoes_listener_active_sessions",
+                "gauge",
+                "Sessions currently being handled by the listener.",
+                |m| m.active_sessions.get().to_string(),
+            ),
+            (
+                "shoes_listener_handshake_failures_total",
+                "counter",
+                "Connections that failed or timed out during the server handshake.",
+                |m| m.handshake_failures.get().to_string(),
+            ),
+            (
+                "shoes_listener_udp_sessions_total",
+                "counter",
+                "UDP sessions started through the listener.",
+                |m| m.udp_sessions.get().to_string(),
+            ),
+            (
+                "shoes_listener_bytes_up_total",
+                "counter",
+                "Bytes forwarded from clients to remote destinations.",
+                |m| m.bytes_up.get().to_string(),
+            ),
+            (
+                "shoes_listener_bytes_down_total",
+                "counter",
+                "Bytes forwarded from remote destinations to clients.",
+                |m| m.bytes_down.get().to_string(),
+            ),
+        ];
+        for (name, kind, help, value) in listener_metrics {
+            write_header(&mut out, name, kind, help);
+            for (label, metrics) in listeners.iter() {
+                write_sample(&mut out, name, "listener", label, &value(metrics));
+            }
+        }
+        drop(listeners);
+
+        let outbounds = self.outbounds.read();
+        let outbound_metrics: [(&str, &str, fn(&OutboundMetrics) -> String); 5] = [
+            (
+                "shoes_outbound_connections_total",
+                "TCP connections opened through the outbound.",
+                |m| m.connections.get().to_string(),
+            ),
+            (
+                "shoes_outbound_udp_sessions_total",
+                "UDP sessions opened through the outbound.",
+                |m| m.udp_sessions.get().to_string(),
+            ),
+            (
+                "shoes_outbound_bytes_up_total",
+                "Bytes sent through the outbound.",
+                |m| m.bytes_up.get().to_string(),
+            ),
+            (
+                "shoes_outbound_bytes_down_total",
+                "Bytes received through the outbound.",
+                |m| m.bytes_down.get().to_string(),
+            ),
+            (
+                "shoes_outbound_chain_switches_total",
+                "Times a url_test or failover outbound switched to another chain.",
+                |m| m.chain_switches.get().to_string(),
+            ),
+        ];
+        for (name, help, value) in outbound_metrics {
+            write_header(&mut out, name, "counter", help);
+            for (label, metrics) in outbounds.iter() {
+                write_sample(&mut out, name, "outbound", label, &value(metrics));
+            }
+        }
+        drop(outbounds);
+
+        let udp_session_metrics: [(&str, &str, &Counter); 2] = [
+            (
+                "shoes_udp_sessions_expired_total",
+                "UDP sessions closed because they were idle.",
+                &self.udp_sessions.expired,
+            ),
+            (
+                "shoes_udp_sessions_evicted_total",
+                "UDP sessions evicted because their session table was full.",
+                &self.udp_sessions.evicted,
+            ),
+        ];
+        for (name, help, counter) in udp_session_metrics {
+            write_header(&mut out, name, "counter", help);
+            let _ = writeln!(out, "{name} {}", counter.get());
+        }
+
+        let name = "shoes_replays_detected_total";
+        write_header(
+            &mut out,
+            name,
+            "counter",
+            "Handshakes rejected because their nonce was seen before.",
+        );
+        for (protocol, counter) in self.replays.read().iter() {
+            write_sample(
+                &mut out,
+                name,
+                "protocol",
+                protocol,
+                &counter.get().to_string(),
+            );
+        }
+
+        out
+    }
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+fn write_sample(out: &mut String, name: &str, label_name: &str, label: &str, value: &str) {
+    let _ = writeln!(
+        out,
+        "{name}{{{label_name}=\"{}\"}} {value}",
+        escape_label_value(label)
+    );
+}
+
+fn escape_label_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+async fn metrics_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+    let response = if req.uri().path() != METRICS_PATH {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from_static(b"not found\n")))
+    } else if req.method() != Method::GET && req.method() != Method::HEAD {
+        Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Full::new(Bytes::new()))
+    } else {
+        Response::builder()
+            .header(http::header::CONTENT_TYPE, CONTENT_TYPE)
+            .body(Full::new(Bytes::from(REGISTRY.render())))
+    };
+    Ok(response.unwrap())
+}
+
+/// Starts the HTTP listener serving `/metrics`, and enables metrics collection.
+pub async fn start_metrics_server(config: MetricsConfig) -> std::io::Result<JoinHandle<()>> {
+    println!(
+        "Starting metrics server at http://{}{METRICS_PATH}",
+        config.metrics
+    );
+
+    let listener = tokio::net::TcpListener::bind(config.metrics).await?;
+    set_enabled(true);
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    error!("Metrics accept failed: {e}");
+                    continue;
+                }
+            };
+
+            tokio::spawn(async move {
+                let service = hyper::service::service_fn(metrics_service);
+                if let Err(e) = hyper::server::conn::http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await
+                {
+                    debug!("Metrics connection from {addr} ended with error: {e}");
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let registry = Registry::default();
+
+        let listener = registry.listener("tcp://127.0.0.1:1080");
+        listener.connections_accepted.inc();
+        listener.connections_rejected.inc();
+        let guard = listener.start_session();
+        let outbound = registry.outbound("direct");
+        outbound.connections.inc();
+        outbound.chain_switches.inc();
+        registry.udp_sessions.evicted.inc();
+        registry.replays.write().entry("vmess").or_default().inc();
+
+        let (up, down) = byte_counters(Some(&listener), Some(&outbound));
+        up.add(100);
+        down.add(2000);
+
+        let rendered = registry.render();
+        assert!(rendered.contains("# TYPE shoes_listener_active_sessions gauge\n"));
+        assert!(rendered.contains(
+            "shoes_listener_connections_accepted_total{listener=\"tcp://127.0.0.1:1080\"} 1\n"
+        ));
+        assert!(
+            rendered
+                .contains("shoes_listener_active_sessions{listener=\"tcp://127.0.0.1:1080\"} 1\n")
+        );
+        assert!(rendered.contains(
+            "shoes_listener_connections_rejected_total{listener=\"tcp://127.0.0.1:1080\"} 1\n"
+        ));
+        assert!(
+            rendered
+                .contains("shoes_listener_bytes_up_total{listener=\"tcp://127.0.0.1:1080\"} 100\n")
+        );
+        assert!(rendered.contains("shoes_outbound_connections_total{outbound=\"direct\"} 1\n"));
+        assert!(rendered.contains("shoes_outbound_bytes_down_total{outbound=\"direct\"} 2000\n"));
+        assert!(rendered.contains("shoes_outbound_chain_switches_total{outbound=\"direct\"} 1\n"));
+        assert!(rendered.contains("shoes_udp_sessions_expired_total 0\n"));
+        assert!(rendered.contains("shoes_udp_sessions_evicted_total 1\n"));
+        assert!(rendered.contains("shoes_replays_detected_total{protocol=\"vmess\"} 1\n"));
+
+        drop(guard);
+        assert_eq!(listener.active_sessions.get(), 0);
+    }
+
+    #[test]
+    fn test_registry_returns_same_metrics() {
+        let registry = Registry::default();
+        registry.listener("a").udp_sessions.inc();
+        assert_eq!(registry.listener("a").udp_sessions.get(), 1);
+        assert_eq!(registry.listener("b").udp_sessions.get(), 0);
+    }
+
+    #[test]
+    fn test_escape_label_value() {
+        assert_eq!(escape_label_value("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_default_byte_counter() {
+        let counter = ByteCounter::default();
+        assert!(counter.0.is_empty());
+        counter.add(10);
+    }
+}
@@ -1,10 +1,13 @@
 //! Mixed HTTP+SOCKS5 server handler.
 //!
 //! This module provides a server handler that auto-detects whether the client
-//! is speaking HTTP or SOCKS5 based on the first byte of the connection:
+//! is speaking HTTP or SOCKS based on the first byte of the connection:
 //! - 0x05 = SOCKS5 (RFC 1928 specifies version byte first)
+//! - 0x04 = SOCKS4 or SOCKS4a
 //! - Anything else = HTTP
 //!
+//! HTTP connections are handled like by the HTTP server, including its decoy website.
+//!
 //! This is similar to mihomo's mixed-port feature.
 
 use std::net::IpAddr;
@@ -15,9 +18,12 @@ use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 
 use crate::async_stream::AsyncStream;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::decoy_handler::DecoyHandler;
 use crate::http_handler::setup_http_server_stream_inner;
 use crate::resolver::Resolver;
-use crate::socks_handler::{VER_SOCKS5, setup_socks_server_stream_inner};
+use crate::socks_handler::{
+    SocksUsers, VER_SOCKS4, VER_SOCKS5, create_socks_users, setup_socks_server_stream_inner,
+};
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
 
@@ -27,8 +33,8 @@ use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
 /// appropriate handler implementation.
 #[derive(Debug)]
 pub struct MixedTcpServerHandler {
-    /// Authentication for both HTTP and SOCKS5
-    auth_info: Option<(String, String)>,
+    /// Authentication for SOCKS5
+    auth_info: Option<SocksUsers>,
     /// Pre-computed HTTP auth token (base64 encoded)
     http_auth_token: Option<String>,
     /// Enable UDP functionality for SOCKS5 (UDP ASSOCIATE and UDP-over-TCP)
@@ -39,6 +45,8 @@ pub struct MixedTcpServerHandler {
     proxy_selector: Arc<ClientProxySelector>,
     /// DNS resolver
     resolver: Arc<dyn Resolver>,
+    /// Decoy website for HTTP requests that aren't proxy requests
+    decoy: Option<DecoyHandler>,
 }
 
 impl MixedTcpServerHandler {
@@ -50,24 +58,27 @@ impl MixedTcpServerHandler {
     /// * `bind_ip` - IP address to bind UDP sockets on (should match TCP server)
     /// * `proxy_selector` - Proxy selector for outbound connections
     /// * `resolver` - DNS resolver
+    /// * `decoy` - Optional decoy website for HTTP requests
     pub fn new(
         auth_info: Option<(String, String)>,
         udp_enabled: bool,
         bind_ip: IpAddr,
         proxy_selector: Arc<ClientProxySelector>,
         resolver: Arc<dyn Resolver>,
+        decoy: Option<DecoyHandler>,
     ) -> Self {
         let http_auth_token = auth_info
             .as_ref()
             .map(|(username, password)| BASE64.encode(format!("{username}:{password}")));
 
         Self {
-            auth_info,
+            auth_info: create_socks_users(auth_info, None),
             http_auth_token,
             udp_enabled,
             bind_ip,
             proxy_selector,
             resolver,
+            decoy,
         }
     }
 }
@@ -83,9 +94,9 @@ impl TcpServerHandler for MixedTcpServerHandler {
         // Peek at first byte to detect protocol
         let first_byte = stream_reader.peek_u8(&mut server_stream).await?;
 
-        if first_byte == VER_SOCKS5 {
-            // SOCKS5 protocol
-            log::debug!("Mixed handler: detected SOCKS5 protocol");
+        if is_socks(first_byte) {
+            // SOCKS protocol
+            log::debug!("Mixed handler: detected SOCKS{first_byte} protocol");
 
             let udp_bind_ip = if self.udp_enabled {
                 Some(self.bind_ip)
@@ -114,8 +125,35 @@ impl TcpServerHandler for MixedTcpServerHandler {
                 server_stream,
                 stream_reader,
                 self.proxy_selector.clone(),
+                &self.resolver,
+                self.decoy.as_ref(),
             )
             .await
         }
     }
 }
+
+/// Returns true if a connection starting with `first_byte` is a SOCKS connection.
+fn is_socks(first_byte: u8) -> bool {
+    first_byte == VER_SOCKS5 || first_byte == VER_SOCKS4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_socks() {
+        // SOCKS5 greeting and SOCKS4 CONNECT request.
+        assert!(is_socks(0x05));
+        assert!(is_socks(0x04));
+        // HTTP/1 requests, and the HTTP/2 preface.
+        for request in [
+            &b"CONNECT example.com:443 HTTP/1.1"[..],
+            b"GET http://example.com/",
+            b"PRI * HTTP/2.0",
+        ] {
+            assert!(!is_socks(request[0]));
+        }
+    }
+}
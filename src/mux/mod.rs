@@ -0,0 +1,41 @@
+//! Stream multiplexing with the mux.cool protocol of V2Ray and Xray.
+//!
+//! Many proxied TCP connections share one connection to a VMess, VLESS or Trojan
+//! server, which saves a handshake per connection on high-latency links. The shared
+//! connection goes to the `v1.mux.cool` pseudo-destination (VMess and VLESS send their
+//! MUX command instead), and carries frames of sub-connections using the same frame
+//! metadata as XUDP.
+//!
+//! Sub-connections have bounded channels in both directions. A sub-connection that is
+//! not read stops the reads of its session instead of being buffered without limit.
+
+mod mux_client;
+mod mux_frame;
+mod mux_server;
+mod mux_session;
+mod mux_stream;
+mod mux_tunnels;
+
+pub use mux_client::MuxClient;
+pub use mux_server::{
+    FIRST_FRAME_PREFIX_LEN, is_tcp_mux_frame, run_mux_server_session, spawn_mux_server_session,
+};
+pub use mux_tunnels::MuxTunnels;
+
+use crate::address::{Address, NetLocation};
+
+pub const MUX_COOL_ADDRESS: &str = "v1.mux.cool";
+pub const MUX_COOL_PORT: u16 = 9527;
+
+/// Destination of connections carrying mux sessions.
+pub fn mux_cool_location() -> NetLocation {
+    NetLocation::new(
+        Address::Hostname(MUX_COOL_ADDRESS.to_string()),
+        MUX_COOL_PORT,
+    )
+}
+
+/// Returns whether a connection to `location` carries a mux session.
+pub fn is_mux_cool_location(location: &NetLocation) -> bool {
+    location.address().hostname() == Some(MUX_COOL_ADDRESS)
+}
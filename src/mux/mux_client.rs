@@ -0,0 +1,158 @@
+//! Client side of mux.cool: a pool of mux sessions of an outbound.
+//!
+//! A connection is opened to the server when no session has room for another
+//! sub-connection, and sessions without sub-connections are closed after a while.
+
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::AsyncRead;
+use tokio::sync::Mutex;
+
+use super::mux_frame::{encode_new_frame, read_frame};
+use super::mux_session::MuxSession;
+use super::mux_stream::MuxStream;
+use crate::address::NetLocation;
+use crate::async_stream::AsyncStream;
+
+/// How often sessions are checked for sub-connections. A session is closed when it had
+/// none at two consecutive checks.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Debug)]
+pub(super) struct ClientSession {
+    pub(super) session: Arc<MuxSession>,
+    next_session_id: AtomicU32,
+}
+
+impl ClientSession {
+    /// Starts reading frames from `stream`. Sessions that are closed when idle are
+    /// reopened on demand, the others live until the connection ends.
+    pub(super) fn start(stream: Box<dyn AsyncStream>, close_idle: bool) -> Arc<Self> {
+        let (session, read_half) = MuxSession::new(stream);
+        tokio::spawn(read_loop(session.clone(), read_half));
+        if close_idle {
+            tokio::spawn(close_when_idle(session.clone()));
+        }
+        Arc::new(Self {
+            session,
+            // Session ID 0 is not used by mux.cool clients.
+            next_session_id: AtomicU32::new(1),
+        })
+    }
+
+    pub(super) fn has_room(&self, concurrency: usize) -> bool {
+        !self.session.is_closed()
+            && self.session.active_streams() < concurrency
+            && !self.ids_exhausted()
+    }
+
+    /// Returns whether every session ID was used, so no more streams can be opened.
+    pub(super) fn ids_exhausted(&self) -> bool {
+        self.next_session_id.load(Ordering::Relaxed) > u16::MAX as u32
+    }
+
+    pub(super) fn add_stream(&self) -> io::Result<MuxStream> {
+        let session_id = self.next_session_id.fetch_add(1, Ordering::Relaxed) as u16;
+        self.session.add_stream(session_id)
+    }
+
+    /// Sends the New frame of `stream`, which opens it at the other side.
+    pub(super) async fn open(&self, stream: &MuxStream, target: &NetLocation) -> io::Result<()> {
+        debug!("Opening mux stream {} to {}", stream.session_id(), target);
+        self.session
+            .send_frame(encode_new_frame(stream.session_id(), target)?)
+            .await
+    }
+}
+
+/// Pool of mux sessions that carry up to `concurrency` sub-connections each.
+#[derive(Debug)]
+pub struct MuxClient {
+    concurrency: usize,
+    sessions: Mutex<Vec<Arc<ClientSession>>>,
+}
+
+impl MuxClient {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            concurrency,
+            sessions: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Opens a sub-connection to `target`. `connect` opens a new connection to the
+    /// mux.cool destination of the server, and is only awaited when every session is
+    /// full.
+    pub async fn open_stream<F>(
+        &self,
+        target: &NetLocation,
+        connect: F,
+    ) -> io::Result<Box<dyn AsyncStream>>
+    where
+        F: Future<Output = io::Result<Box<dyn AsyncStream>>>,
+    {
+        let (session, stream) = {
+            // Held while connecting, so that concurrent opens share the new session.
+            let mut sessions = self.sessions.lock().await;
+            sessions.retain(|session| !session.session.is_closed());
+
+            let session = match sessions
+                .iter()
+                .find(|session| session.has_room(self.concurrency))
+            {
+                Some(session) => session.clone(),
+                None => {
+                    debug!("Opening new mux session");
+                    let session = ClientSession::start(connect.await?, true);
+                    sessions.push(session.clone());
+                    session
+                }
+            };
+
+            let stream = session.add_stream()?;
+            (session, stream)
+        };
+
+        session.open(&stream, target).await?;
+
+        Ok(Box::new(stream))
+    }
+}
+
+async fn read_loop<R: AsyncRead + Unpin>(session: Arc<MuxSession>, mut reader: R) {
+    loop {
+        match read_frame(&mut reader).await {
+            Ok(Some(frame)) => session.dispatch(frame).await,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Mux session read failed: {e}");
+                break;
+            }
+        }
+    }
+    session.close();
+}
+
+async fn close_when_idle(session: Arc<MuxSession>) {
+    let mut interval = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    // The first tick completes immediately.
+    interval.tick().await;
+    let mut was_idle = false;
+    loop {
+        interval.tick().await;
+        if session.is_closed() {
+            break;
+        }
+        let is_idle = session.active_streams() == 0;
+        if was_idle && is_idle && session.close_if_idle() {
+            debug!("Closed idle mux session");
+            break;
+        }
+        was_idle = is_idle;
+    }
+}
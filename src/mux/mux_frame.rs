@@ -0,0 +1,154 @@
+//! Reading and writing mux.cool frames.
+//!
+//! A frame is the frame metadata shared with XUDP, followed by a 2 byte data length
+//! and the data when the metadata has the DATA option.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::address::NetLocation;
+use crate::xudp::frame::{FrameMetadata, FrameOption, SessionStatus, TargetNetwork};
+
+/// Largest data carried by a single frame.
+pub const MAX_FRAME_DATA_LEN: usize = 65535;
+
+#[derive(Debug)]
+pub struct MuxFrame {
+    pub metadata: FrameMetadata,
+    pub data: Option<Bytes>,
+}
+
+/// Encodes a frame opening a TCP sub-connection to `target`.
+pub fn encode_new_frame(session_id: u16, target: &NetLocation) -> std::io::Result<Bytes> {
+    let metadata = FrameMetadata {
+        session_id,
+        status: SessionStatus::New,
+        option: FrameOption::new(),
+        target: Some(target.clone()),
+        network: Some(TargetNetwork::Tcp),
+    };
+    let mut buf = BytesMut::new();
+    metadata.encode(&mut buf)?;
+    Ok(buf.freeze())
+}
+
+/// Encodes a frame carrying `data` of a sub-connection.
+pub fn encode_data_frame(session_id: u16, data: &[u8]) -> Bytes {
+    let metadata = FrameMetadata {
+        session_id,
+        status: SessionStatus::Keep,
+        option: FrameOption::new().with_data(),
+        target: None,
+        network: None,
+    };
+    let mut buf = BytesMut::with_capacity(8 + data.len());
+    // Keep frames without a UDP target always encode.
+    metadata.encode(&mut buf).unwrap();
+    buf.put_u16(data.len() as u16);
+    buf.put_slice(data);
+    buf.freeze()
+}
+
+/// Encodes a frame closing a sub-connection, with the ERROR option if it failed.
+pub fn encode_end_frame(session_id: u16, is_error: bool) -> Bytes {
+    let option = if is_error {
+        FrameOption::from(FrameOption::ERROR)
+    } else {
+        FrameOption::new()
+    };
+    let metadata = FrameMetadata {
+        session_id,
+        status: SessionStatus::End,
+        option,
+        target: None,
+        network: None,
+    };
+    let mut buf = BytesMut::with_capacity(6);
+    metadata.encode(&mut buf).unwrap();
+    buf.freeze()
+}
+
+/// Encodes a frame that keeps the connection alive, which the other side ignores.
+pub fn encode_keepalive_frame() -> Bytes {
+    let metadata = FrameMetadata {
+        session_id: 0,
+        status: SessionStatus::KeepAlive,
+        option: FrameOption::new(),
+        target: None,
+        network: None,
+    };
+    let mut buf = BytesMut::with_capacity(6);
+    metadata.encode(&mut buf).unwrap();
+    buf.freeze()
+}
+
+/// Reads the next frame, or returns None if the stream ended between frames.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Option<MuxFrame>> {
+    let mut length_bytes = [0u8; 2];
+    let n = reader.read(&mut length_bytes).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n == 1 {
+        reader.read_exact(&mut length_bytes[1..]).await?;
+    }
+
+    let metadata_len = u16::from_be_bytes(length_bytes) as usize;
+    let mut buf = BytesMut::zeroed(2 + metadata_len);
+    buf[0..2].copy_from_slice(&length_bytes);
+    reader.read_exact(&mut buf[2..]).await?;
+    let metadata = FrameMetadata::decode(&mut buf)?.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, "incomplete frame metadata")
+    })?;
+
+    let data = if metadata.option.has_data() {
+        let data_len = reader.read_u16().await? as usize;
+        let mut data = BytesMut::zeroed(data_len);
+        reader.read_exact(&mut data).await?;
+        Some(data.freeze())
+    } else {
+        None
+    };
+
+    Ok(Some(MuxFrame { metadata, data }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let target = NetLocation::new(Address::Hostname("example.com".to_string()), 443);
+        let mut encoded = vec![];
+        encoded.extend_from_slice(&encode_new_frame(1, &target).unwrap());
+        encoded.extend_from_slice(&encode_data_frame(1, b"hello"));
+        encoded.extend_from_slice(&encode_end_frame(1, true));
+
+        let mut reader = &encoded[..];
+        let frame = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(frame.metadata.session_id, 1);
+        assert_eq!(frame.metadata.status, SessionStatus::New);
+        assert_eq!(frame.metadata.network, Some(TargetNetwork::Tcp));
+        assert_eq!(frame.metadata.target, Some(target));
+        assert!(frame.data.is_none());
+
+        let frame = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(frame.metadata.status, SessionStatus::Keep);
+        assert_eq!(frame.data.as_deref(), Some(&b"hello"[..]));
+
+        let frame = read_frame(&mut reader).await.unwrap().unwrap();
+        assert_eq!(frame.metadata.status, SessionStatus::End);
+        assert!(frame.metadata.option.has_error());
+
+        assert!(read_frame(&mut reader).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_read_truncated_frame() {
+        let encoded = encode_data_frame(2, b"hello");
+        let mut reader = &encoded[..encoded.len() - 1];
+        assert!(read_frame(&mut reader).await.is_err());
+    }
+}
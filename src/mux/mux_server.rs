@@ -0,0 +1,208 @@
+//! Server side of mux.cool.
+//!
+//! Each TCP sub-connection is routed on its own, like the connection it would have been
+//! without mux. UDP sub-connections are rejected, clients send them with XUDP instead.
+
+use std::io::{self, Cursor};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::mux_frame::{encode_end_frame, encode_keepalive_frame, read_frame};
+use super::mux_session::MuxSession;
+use super::mux_stream::MuxStream;
+use crate::address::NetLocation;
+use crate::async_stream::AsyncStream;
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::copy_bidirectional::copy_bidirectional;
+use crate::resolver::Resolver;
+use crate::xudp::frame::{SessionStatus, TargetNetwork};
+
+/// Length of the start of the first frame that tells whether a connection is mux.cool:
+/// the metadata length, session ID, status, option and network.
+pub const FIRST_FRAME_PREFIX_LEN: usize = 7;
+
+/// Returns whether the start of the first frame of a mux connection opens a TCP
+/// sub-connection. Otherwise, the connection is XUDP.
+pub fn is_tcp_mux_frame(prefix: &[u8]) -> bool {
+    prefix.len() >= FIRST_FRAME_PREFIX_LEN
+        && prefix[4] == SessionStatus::New as u8
+        && prefix[6] == TargetNetwork::Tcp as u8
+}
+
+struct MuxServerConfig {
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+}
+
+/// Serves the sub-connections of a mux connection in a background task.
+///
+/// `initial_data` is data that was already read from `stream`.
+pub fn spawn_mux_server_session(
+    stream: Box<dyn AsyncStream>,
+    initial_data: Option<Box<[u8]>>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+) {
+    let config = Arc::new(MuxServerConfig {
+        proxy_selector,
+        resolver,
+    });
+    tokio::spawn(run_session(stream, initial_data, config, None));
+}
+
+/// Serves the sub-connections of a mux connection that this side opened, until the
+/// connection ends.
+///
+/// A KeepAlive frame is sent right away and then every `keepalive_interval`, so that the
+/// connection is established through lazy outbounds and is not dropped while idle.
+pub async fn run_mux_server_session(
+    stream: Box<dyn AsyncStream>,
+    initial_data: Option<Box<[u8]>>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    keepalive_interval: Duration,
+) {
+    let config = Arc::new(MuxServerConfig {
+        proxy_selector,
+        resolver,
+    });
+    run_session(stream, initial_data, config, Some(keepalive_interval)).await
+}
+
+async fn run_session(
+    stream: Box<dyn AsyncStream>,
+    initial_data: Option<Box<[u8]>>,
+    config: Arc<MuxServerConfig>,
+    keepalive_interval: Option<Duration>,
+) {
+    let (session, read_half) = MuxSession::new(stream);
+    if let Some(keepalive_interval) = keepalive_interval {
+        tokio::spawn(send_keepalives(
+            Arc::downgrade(&session),
+            keepalive_interval,
+        ));
+    }
+    let mut reader = Cursor::new(initial_data.unwrap_or_default()).chain(read_half);
+
+    loop {
+        let frame = match read_frame(&mut reader).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("Mux session read failed: {e}");
+                break;
+            }
+        };
+
+        if frame.metadata.status != SessionStatus::New {
+            session.dispatch(frame).await;
+            continue;
+        }
+
+        let session_id = frame.metadata.session_id;
+        let target = match (frame.metadata.network, &frame.metadata.target) {
+            (Some(TargetNetwork::Tcp), Some(target)) => target.clone(),
+            _ => {
+                debug!("Rejecting mux stream {session_id}: only TCP is supported");
+                if session
+                    .send_frame(encode_end_frame(session_id, true))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let Ok(stream) = session.add_stream(session_id) else {
+            break;
+        };
+        // The New frame can carry the first data of the stream.
+        session.dispatch(frame).await;
+
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Err(e) = forward_stream(stream, target, &config).await {
+                debug!("Mux stream {session_id} ended with error: {e}");
+            }
+        });
+    }
+
+    session.close();
+}
+
+async fn send_keepalives(session: Weak<MuxSession>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        // The session is gone when its reads ended.
+        let Some(session) = session.upgrade() else {
+            break;
+        };
+        if session.is_closed() || session.send_frame(encode_keepalive_frame()).await.is_err() {
+            break;
+        }
+    }
+}
+
+async fn forward_stream(
+    mut stream: MuxStream,
+    target: NetLocation,
+    config: &MuxServerConfig,
+) -> io::Result<()> {
+    let decision = config
+        .proxy_selector
+        .judge(target.clone().into(), &config.resolver)
+        .await?;
+
+    let (chain_group, remote_location) = match decision {
+        ConnectDecision::Allow {
+            chain_group,
+            remote_location,
+        } => (chain_group, remote_location),
+        ConnectDecision::Block => {
+            debug!("Mux stream to {target} blocked by rules");
+            return Ok(());
+        }
+    };
+
+    debug!("Mux stream {} -> {}", stream.session_id(), remote_location);
+
+    let mut client_stream = chain_group
+        .connect_tcp(remote_location, &config.resolver)
+        .await?
+        .client_stream;
+
+    let result = copy_bidirectional(&mut stream, &mut *client_stream, false, false).await;
+
+    let _ = stream.shutdown().await;
+    let _ = client_stream.shutdown().await;
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::mux::mux_frame::{encode_data_frame, encode_new_frame};
+
+    #[test]
+    fn test_is_tcp_mux_frame() {
+        let target = NetLocation::new(Address::Hostname("example.com".to_string()), 443);
+        let tcp_frame = encode_new_frame(1, &target).unwrap();
+        assert!(is_tcp_mux_frame(&tcp_frame[..FIRST_FRAME_PREFIX_LEN]));
+
+        // XUDP frames open UDP sub-connections.
+        let mut udp_frame = tcp_frame.to_vec();
+        udp_frame[6] = TargetNetwork::Udp as u8;
+        assert!(!is_tcp_mux_frame(&udp_frame));
+
+        assert!(!is_tcp_mux_frame(&encode_data_frame(1, b"hello")));
+        assert!(!is_tcp_mux_frame(&tcp_frame[..FIRST_FRAME_PREFIX_LEN - 1]));
+    }
+}
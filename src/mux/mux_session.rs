@@ -0,0 +1,192 @@
+//! The connection shared by the sub-connections of a mux session.
+//!
+//! A writer task writes the frames queued by the streams, and the owner of the session
+//! runs the read loop, dispatching data and End frames to the streams.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use log::debug;
+use tokio::io::{AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::sync::{Notify, mpsc};
+
+use super::mux_frame::MuxFrame;
+use super::mux_stream::MuxStream;
+use crate::async_stream::AsyncStream;
+use crate::xudp::frame::SessionStatus;
+
+/// Number of data frames buffered for each stream before the read loop waits.
+const STREAM_CHANNEL_BUFFER: usize = 16;
+
+/// Number of frames queued for the writer before streams wait.
+const FRAME_CHANNEL_BUFFER: usize = 64;
+
+pub struct MuxSession {
+    streams: Mutex<HashMap<u16, mpsc::Sender<Bytes>>>,
+    frame_tx: mpsc::Sender<Bytes>,
+    active_streams: Arc<AtomicUsize>,
+    closed: Arc<AtomicBool>,
+    /// Stops the writer when the session is closed.
+    shutdown: Arc<Notify>,
+}
+
+impl std::fmt::Debug for MuxSession {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MuxSession")
+            .field("active_streams", &self.active_streams())
+            .field("closed", &self.is_closed())
+            .finish()
+    }
+}
+
+impl MuxSession {
+    /// Starts the writer of a session on `stream`, and returns the read half for the
+    /// read loop.
+    pub fn new(stream: Box<dyn AsyncStream>) -> (Arc<Self>, ReadHalf<Box<dyn AsyncStream>>) {
+        let (read_half, write_half) = tokio::io::split(stream);
+        let (frame_tx, frame_rx) = mpsc::channel(FRAME_CHANNEL_BUFFER);
+        let closed = Arc::new(AtomicBool::new(false));
+        let shutdown = Arc::new(Notify::new());
+
+        tokio::spawn(write_loop(
+            write_half,
+            frame_rx,
+            closed.clone(),
+            shutdown.clone(),
+        ));
+
+        let session = Arc::new(Self {
+            streams: Mutex::new(HashMap::new()),
+            frame_tx,
+            active_streams: Arc::new(AtomicUsize::new(0)),
+            closed,
+            shutdown,
+        });
+        (session, read_half)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    pub fn active_streams(&self) -> usize {
+        self.active_streams.load(Ordering::Relaxed)
+    }
+
+    /// Adds the stream of a sub-connection.
+    pub fn add_stream(&self, session_id: u16) -> io::Result<MuxStream> {
+        let mut streams = self.streams.lock().unwrap();
+        if self.is_closed() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "mux session closed",
+            ));
+        }
+        let (data_tx, data_rx) = mpsc::channel(STREAM_CHANNEL_BUFFER);
+        streams.insert(session_id, data_tx);
+        Ok(MuxStream::new(
+            session_id,
+            data_rx,
+            self.frame_tx.clone(),
+            self.active_streams.clone(),
+        ))
+    }
+
+    /// Queues an encoded frame for the writer.
+    pub async fn send_frame(&self, frame: Bytes) -> io::Result<()> {
+        self.frame_tx
+            .send(frame)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "mux session closed"))
+    }
+
+    /// Delivers the data and End frames of open streams.
+    pub async fn dispatch(&self, frame: MuxFrame) {
+        let session_id = frame.metadata.session_id;
+        match frame.metadata.status {
+            SessionStatus::New | SessionStatus::Keep => {
+                let Some(data) = frame.data.filter(|data| !data.is_empty()) else {
+                    return;
+                };
+                let sender = self.streams.lock().unwrap().get(&session_id).cloned();
+                let Some(sender) = sender else {
+                    debug!("Dropping data of unknown mux stream {session_id}");
+                    return;
+                };
+                // Waits when the stream is not read fast enough, which stops reading
+                // frames of the whole session.
+                if sender.send(data).await.is_err() {
+                    // The stream was dropped.
+                    self.streams.lock().unwrap().remove(&session_id);
+                }
+            }
+            SessionStatus::End => {
+                // Dropping the sender ends the stream's reads once queued data is read.
+                self.streams.lock().unwrap().remove(&session_id);
+            }
+            SessionStatus::KeepAlive => {}
+        }
+    }
+
+    /// Closes the session, ending the reads of all streams and stopping the writer.
+    pub fn close(&self) {
+        let mut streams = self.streams.lock().unwrap();
+        self.close_locked(&mut streams);
+    }
+
+    /// Closes the session if it has no streams, returns whether it was closed.
+    pub fn close_if_idle(&self) -> bool {
+        let mut streams = self.streams.lock().unwrap();
+        // Streams are added with the lock held, so none can be added concurrently.
+        if self.active_streams() > 0 {
+            return false;
+        }
+        self.close_locked(&mut streams);
+        true
+    }
+
+    fn close_locked(&self, streams: &mut HashMap<u16, mpsc::Sender<Bytes>>) {
+        self.closed.store(true, Ordering::Relaxed);
+        streams.clear();
+        self.shutdown.notify_one();
+    }
+}
+
+async fn write_loop(
+    mut writer: WriteHalf<Box<dyn AsyncStream>>,
+    mut frame_rx: mpsc::Receiver<Bytes>,
+    closed: Arc<AtomicBool>,
+    shutdown: Arc<Notify>,
+) {
+    loop {
+        let frame = tokio::select! {
+            frame = frame_rx.recv() => frame,
+            _ = shutdown.notified() => None,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+        if let Err(e) = write_frames(&mut writer, &mut frame_rx, frame).await {
+            debug!("Mux session write failed: {e}");
+            break;
+        }
+    }
+    closed.store(true, Ordering::Relaxed);
+    let _ = writer.shutdown().await;
+}
+
+async fn write_frames(
+    writer: &mut WriteHalf<Box<dyn AsyncStream>>,
+    frame_rx: &mut mpsc::Receiver<Bytes>,
+    frame: Bytes,
+) -> io::Result<()> {
+    writer.write_all(&frame).await?;
+    // Write whatever else is queued before flushing.
+    while let Ok(frame) = frame_rx.try_recv() {
+        writer.write_all(&frame).await?;
+    }
+    writer.flush().await
+}
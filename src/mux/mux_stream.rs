@@ -0,0 +1,218 @@
+//! A sub-connection of a mux session.
+//!
+//! Reads come from the data frames that the session's read loop pushes into a bounded
+//! channel, and writes are framed and sent to the session's writer through another
+//! bounded channel. A full channel makes the writer wait, which is the flow control
+//! of the session.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+
+use super::mux_frame::{MAX_FRAME_DATA_LEN, encode_data_frame, encode_end_frame};
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+pub struct MuxStream {
+    session_id: u16,
+    data_rx: mpsc::Receiver<Bytes>,
+    read_buffer: Bytes,
+    frame_tx: PollSender<Bytes>,
+    /// Set once the End frame is queued.
+    write_closed: bool,
+    /// Number of open streams of the session, decremented on drop.
+    active_streams: Arc<AtomicUsize>,
+}
+
+impl MuxStream {
+    pub fn new(
+        session_id: u16,
+        data_rx: mpsc::Receiver<Bytes>,
+        frame_tx: mpsc::Sender<Bytes>,
+        active_streams: Arc<AtomicUsize>,
+    ) -> Self {
+        active_streams.fetch_add(1, Ordering::Relaxed);
+        Self {
+            session_id,
+            data_rx,
+            read_buffer: Bytes::new(),
+            frame_tx: PollSender::new(frame_tx),
+            write_closed: false,
+            active_streams,
+        }
+    }
+
+    pub fn session_id(&self) -> u16 {
+        self.session_id
+    }
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.read_buffer.is_empty() {
+            match self.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.read_buffer = data,
+                // The session dropped the sender, on an End frame or when it closed.
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = std::cmp::min(self.read_buffer.len(), buf.remaining());
+        buf.put_slice(&self.read_buffer[..n]);
+        self.read_buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.write_closed {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "stream is shut down",
+            )));
+        }
+
+        match self.frame_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                let n = std::cmp::min(buf.len(), MAX_FRAME_DATA_LEN);
+                let frame = encode_data_frame(self.session_id, &buf[..n]);
+                match self.frame_tx.send_item(frame) {
+                    Ok(()) => Poll::Ready(Ok(n)),
+                    Err(_) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "mux session closed",
+                    ))),
+                }
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "mux session closed",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Frames are flushed by the session's writer.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.write_closed {
+            return Poll::Ready(Ok(()));
+        }
+
+        // Wait for room so that the End frame follows the queued data.
+        match self.frame_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                self.write_closed = true;
+                let frame = encode_end_frame(self.session_id, false);
+                if self.frame_tx.send_item(frame).is_err() {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "mux session closed",
+                    )));
+                }
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(_)) => {
+                self.write_closed = true;
+                Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "mux session closed",
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.active_streams.fetch_sub(1, Ordering::Relaxed);
+        if !self.write_closed
+            && let Some(sender) = self.frame_tx.get_ref()
+        {
+            // Best effort, as drop can't wait for room in the channel.
+            let _ = sender.try_send(encode_end_frame(self.session_id, false));
+        }
+    }
+}
+
+impl AsyncPing for MuxStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for MuxStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mux::mux_frame::read_frame;
+    use crate::xudp::frame::SessionStatus;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_stream_frames_writes() {
+        let (frame_tx, mut frame_rx) = mpsc::channel(4);
+        let (_data_tx, data_rx) = mpsc::channel(4);
+        let active_streams = Arc::new(AtomicUsize::new(0));
+        let mut stream = MuxStream::new(7, data_rx, frame_tx, active_streams.clone());
+        assert_eq!(active_streams.load(Ordering::Relaxed), 1);
+
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert!(stream.write_all(b"again").await.is_err());
+
+        let data_frame = frame_rx.recv().await.unwrap();
+        let frame = read_frame(&mut &data_frame[..]).await.unwrap().unwrap();
+        assert_eq!(frame.metadata.session_id, 7);
+        assert_eq!(frame.data.as_deref(), Some(&b"hello"[..]));
+
+        let end_frame = frame_rx.recv().await.unwrap();
+        let frame = read_frame(&mut &end_frame[..]).await.unwrap().unwrap();
+        assert_eq!(frame.metadata.status, SessionStatus::End);
+
+        // The End frame was already sent, so dropping sends nothing more.
+        drop(stream);
+        assert_eq!(active_streams.load(Ordering::Relaxed), 0);
+        assert!(frame_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_reads_until_sender_dropped() {
+        let (frame_tx, _frame_rx) = mpsc::channel(4);
+        let (data_tx, data_rx) = mpsc::channel(4);
+        let mut stream = MuxStream::new(1, data_rx, frame_tx, Arc::new(AtomicUsize::new(0)));
+
+        data_tx.send(Bytes::from_static(b"hello ")).await.unwrap();
+        data_tx.send(Bytes::from_static(b"world")).await.unwrap();
+        drop(data_tx);
+
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello world");
+    }
+}
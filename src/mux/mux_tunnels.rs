@@ -0,0 +1,101 @@
+//! Client side of mux.cool over connections opened by the server side.
+//!
+//! The reverse proxy portal receives connections from bridges and opens sub-connections
+//! over them, so unlike `MuxClient` it can't open a connection when it needs one.
+//! Sessions are kept until their connection ends.
+
+use std::io;
+use std::sync::Arc;
+
+use log::debug;
+use parking_lot::Mutex;
+
+use super::mux_client::ClientSession;
+use crate::address::NetLocation;
+use crate::async_stream::AsyncStream;
+
+/// Mux sessions over connections that were opened by the other side.
+#[derive(Debug, Default)]
+pub struct MuxTunnels {
+    sessions: Mutex<Vec<Arc<ClientSession>>>,
+}
+
+impl MuxTunnels {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a mux session over `stream`.
+    pub fn add(&self, stream: Box<dyn AsyncStream>) {
+        let session = ClientSession::start(stream, false);
+        let mut sessions = self.sessions.lock();
+        sessions.retain(|session| !session.session.is_closed());
+        sessions.push(session);
+        debug!("Mux tunnel added, {} connected", sessions.len());
+    }
+
+    /// Returns the number of sessions that are still open.
+    pub fn connected(&self) -> usize {
+        self.sessions
+            .lock()
+            .iter()
+            .filter(|session| !session.session.is_closed())
+            .count()
+    }
+
+    /// Opens a sub-connection to `target` on the session with the fewest streams.
+    pub async fn open_stream(&self, target: &NetLocation) -> io::Result<Box<dyn AsyncStream>> {
+        let (session, stream) = {
+            let mut sessions = self.sessions.lock();
+            // Sessions that used every session ID are closed once idle, so that the other
+            // side opens a new connection.
+            sessions.retain(|session| {
+                !session.session.is_closed()
+                    && !(session.ids_exhausted() && session.session.close_if_idle())
+            });
+
+            let session = sessions
+                .iter()
+                .filter(|session| session.has_room(usize::MAX))
+                .min_by_key(|session| session.session.active_streams())
+                .cloned()
+                .ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::NotConnected, "no tunnel connected")
+                })?;
+
+            let stream = session.add_stream()?;
+            (session, stream)
+        };
+
+        session.open(&stream, target).await?;
+
+        Ok(Box::new(stream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::mux::mux_frame::read_frame;
+    use crate::xudp::frame::SessionStatus;
+
+    #[tokio::test]
+    async fn test_open_stream() {
+        let tunnels = MuxTunnels::new();
+        let target = NetLocation::new(Address::Hostname("example.com".to_string()), 80);
+
+        let err = tunnels.open_stream(&target).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        let (local, mut remote) = tokio::io::duplex(1024);
+        tunnels.add(Box::new(local));
+        assert_eq!(tunnels.connected(), 1);
+
+        let _stream = tunnels.open_stream(&target).await.unwrap();
+        let frame = read_frame(&mut remote).await.unwrap().unwrap();
+        assert_eq!(frame.metadata.status, SessionStatus::New);
+        assert_eq!(frame.metadata.session_id, 1);
+        assert_eq!(frame.metadata.target, Some(target));
+    }
+}
@@ -1,11 +1,13 @@
 mod h2_multi_stream;
 mod naive_client_handler;
 mod naive_client_session;
+mod naive_h3_connector;
 mod naive_hyper_service;
 mod naive_padding_stream;
 mod naive_server_handler;
 mod user_lookup;
 
 pub use naive_client_handler::NaiveProxyTcpClientHandler;
+pub use naive_h3_connector::NaiveH3SocketConnector;
 pub use naive_server_handler::setup_naive_server_stream;
 pub use user_lookup::UserLookup;
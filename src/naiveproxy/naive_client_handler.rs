@@ -20,7 +20,6 @@ use std::io;
 use std::sync::Arc;
 
 use async_trait::async_trait;
-use base64::engine::{Engine as _, general_purpose::STANDARD as BASE64};
 use log::debug;
 use tokio::sync::Mutex;
 
@@ -28,7 +27,7 @@ use crate::address::ResolvedLocation;
 use crate::async_stream::AsyncStream;
 use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
 
-use super::naive_client_session::NaiveClientSession;
+use super::naive_client_session::{NaiveClientSession, basic_auth_header};
 
 /// NaiveProxy client handler with HTTP/2 multiplexing.
 ///
@@ -64,11 +63,8 @@ impl Clone for NaiveProxyTcpClientHandler {
 
 impl NaiveProxyTcpClientHandler {
     pub fn new(username: &str, password: &str, padding_enabled: bool) -> Self {
-        let credentials = format!("{}:{}", username, password);
-        let auth_header = format!("Basic {}", BASE64.encode(&credentials));
-
         Self {
-            auth_header,
+            auth_header: basic_auth_header(username, password),
             padding_enabled,
             session: Arc::new(Mutex::new(None)),
         }
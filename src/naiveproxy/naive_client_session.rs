@@ -17,9 +17,11 @@
 
 use std::io;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 
+use base64::engine::{Engine as _, general_purpose::STANDARD as BASE64};
 use bytes::Bytes;
-use http::{Method, Request, Version};
+use http::{HeaderMap, Method, Request, Version};
 use log::debug;
 use rand::Rng;
 
@@ -43,6 +45,8 @@ pub struct NaiveClientSession {
     send_request: h2::client::SendRequest<Bytes>,
     /// Handle to abort the connection driver on drop (shared across clones)
     driver_handle: Arc<DriverHandle>,
+    /// Set by the connection driver when the H2 connection ends
+    closed: Arc<AtomicBool>,
 }
 
 /// Wrapper to abort the driver when all session clones are dropped.
@@ -60,6 +64,7 @@ impl Clone for NaiveClientSession {
         Self {
             send_request: self.send_request.clone(),
             driver_handle: Arc::clone(&self.driver_handle),
+            closed: Arc::clone(&self.closed),
         }
     }
 }
@@ -83,10 +88,13 @@ impl NaiveClientSession {
             .await
             .map_err(|e| io::Error::other(format!("H2 client handshake failed: {}", e)))?;
 
+        let closed = Arc::new(AtomicBool::new(false));
+        let driver_closed = closed.clone();
         let abort_handle = tokio::spawn(async move {
             if let Err(e) = connection.await {
                 debug!("NaiveProxy client H2 connection ended: {}", e);
             }
+            driver_closed.store(true, Ordering::Relaxed);
         })
         .abort_handle();
 
@@ -95,14 +103,17 @@ impl NaiveClientSession {
         Ok(Self {
             send_request,
             driver_handle: Arc::new(DriverHandle(abort_handle)),
+            closed,
         })
     }
 
     /// Check if this session is still usable for new streams.
+    ///
+    /// A session is usable until its H2 connection ends. Streams can still fail
+    /// afterwards, e.g. when the server sends GOAWAY, in which case the error is
+    /// returned from `open_stream`.
     pub fn is_ready(&self) -> bool {
-        // SendRequest::poll_ready would be more accurate, but this is a good heuristic
-        // The actual check happens when we try to send a request
-        true // Optimistic - let send_request fail if not ready
+        !self.closed.load(Ordering::Relaxed)
     }
 
     /// Open a new CONNECT stream to the specified target.
@@ -114,23 +125,7 @@ impl NaiveClientSession {
         auth_header: &str,
         padding_enabled: bool,
     ) -> io::Result<Box<dyn AsyncStream>> {
-        let authority = format_authority(target);
-
-        let mut request = Request::builder()
-            .method(Method::CONNECT)
-            .uri(&authority)
-            .version(Version::HTTP_2)
-            .header("proxy-authorization", auth_header);
-
-        if padding_enabled {
-            let padding_len = rand::rng().random_range(16..=32);
-            request = request.header("padding", generate_padding_header(padding_len));
-            request = request.header("padding-type-request", "1, 0");
-        }
-
-        let request = request
-            .body(())
-            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let request = connect_request(target, Version::HTTP_2, auth_header, padding_enabled)?;
 
         // No ready() call needed - matches h2 benchmarks pattern
         let (response_future, send_stream) = self
@@ -155,24 +150,7 @@ impl NaiveClientSession {
             )));
         }
 
-        let padding_type = if padding_enabled {
-            if let Some(reply) = response.headers().get("padding-type-reply") {
-                let reply_str = reply.to_str().unwrap_or("1");
-                reply_str
-                    .trim()
-                    .parse::<u8>()
-                    .ok()
-                    .and_then(PaddingType::from_u8)
-                    .unwrap_or(PaddingType::Variant1)
-            } else if response.headers().contains_key("padding") {
-                // Backward compat: padding header without type means Variant1
-                PaddingType::Variant1
-            } else {
-                PaddingType::None
-            }
-        } else {
-            PaddingType::None
-        };
+        let padding_type = reply_padding_type(response.headers(), padding_enabled);
 
         let recv_stream = response.into_body();
         let h2_stream = H2MultiStream::new(send_stream, recv_stream);
@@ -193,6 +171,59 @@ impl NaiveClientSession {
     }
 }
 
+/// Returns the Basic Auth `proxy-authorization` header value for the credentials.
+pub(super) fn basic_auth_header(username: &str, password: &str) -> String {
+    let credentials = format!("{}:{}", username, password);
+    format!("Basic {}", BASE64.encode(&credentials))
+}
+
+/// Builds a CONNECT request to `target`, with padding negotiation headers if enabled.
+pub(super) fn connect_request(
+    target: &NetLocation,
+    version: Version,
+    auth_header: &str,
+    padding_enabled: bool,
+) -> io::Result<Request<()>> {
+    let authority = format_authority(target);
+
+    let mut request = Request::builder()
+        .method(Method::CONNECT)
+        .uri(&authority)
+        .version(version)
+        .header("proxy-authorization", auth_header);
+
+    if padding_enabled {
+        let padding_len = rand::rng().random_range(16..=32);
+        request = request.header("padding", generate_padding_header(padding_len));
+        request = request.header("padding-type-request", "1, 0");
+    }
+
+    request
+        .body(())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Returns the padding type chosen by the server in its CONNECT response.
+pub(super) fn reply_padding_type(headers: &HeaderMap, padding_enabled: bool) -> PaddingType {
+    if !padding_enabled {
+        return PaddingType::None;
+    }
+    if let Some(reply) = headers.get("padding-type-reply") {
+        let reply_str = reply.to_str().unwrap_or("1");
+        reply_str
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .and_then(PaddingType::from_u8)
+            .unwrap_or(PaddingType::Variant1)
+    } else if headers.contains_key("padding") {
+        // Backward compat: padding header without type means Variant1
+        PaddingType::Variant1
+    } else {
+        PaddingType::None
+    }
+}
+
 /// Format authority for CONNECT request
 fn format_authority(location: &NetLocation) -> String {
     match location.address() {
@@ -225,4 +256,17 @@ mod tests {
         let loc = NetLocation::new(Address::Hostname("example.com".to_string()), 443);
         assert_eq!(format_authority(&loc), "example.com:443");
     }
+
+    #[test]
+    fn test_reply_padding_type() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(reply_padding_type(&headers, true), PaddingType::None);
+
+        headers.insert("padding", "!!!!".parse().unwrap());
+        assert_eq!(reply_padding_type(&headers, true), PaddingType::Variant1);
+        assert_eq!(reply_padding_type(&headers, false), PaddingType::None);
+
+        headers.insert("padding-type-reply", "0".parse().unwrap());
+        assert_eq!(reply_padding_type(&headers, true), PaddingType::None);
+    }
 }
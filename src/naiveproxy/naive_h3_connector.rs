@@ -0,0 +1,191 @@
+//! NaiveProxy client over HTTP/3.
+//!
+//! Used when a NaiveProxy client is configured with `transport: quic`. A single QUIC
+//! connection to the server is shared by all outgoing connections, each of which is
+//! a CONNECT request stream on it. Padding is negotiated with the same headers as
+//! over HTTP/2.
+
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::Version;
+use log::debug;
+use tokio::sync::Mutex;
+
+use crate::address::{NetLocation, ResolvedLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::h3_stream::pipe_client_request_stream;
+use crate::resolver::{Resolver, resolve_single_address};
+use crate::tcp::socket_connector::SocketConnector;
+
+use super::naive_client_session::{basic_auth_header, connect_request, reply_padding_type};
+use super::naive_padding_stream::{NaivePaddingStream, PaddingDirection, PaddingType};
+
+/// An HTTP/3 connection to the server.
+#[derive(Clone)]
+struct H3Session {
+    connection: quinn::Connection,
+    send_request: h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+}
+
+/// Socket connector that tunnels connections through HTTP/3 CONNECT requests.
+///
+/// Like Hysteria2, this is a hop 0 connector: it owns the QUIC connection to the
+/// server, so no ProxyConnector is needed on top of it.
+pub struct NaiveH3SocketConnector {
+    endpoint: Arc<quinn::Endpoint>,
+    server_address: NetLocation,
+    sni_hostname: Option<String>,
+    /// Base64-encoded credentials for Basic Auth
+    auth_header: String,
+    padding_enabled: bool,
+    bind_interface: Option<String>,
+    /// Session slot for lazy init and reconnection
+    session: Mutex<Option<H3Session>>,
+}
+
+impl std::fmt::Debug for NaiveH3SocketConnector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NaiveH3SocketConnector")
+            .field("server_address", &self.server_address)
+            .field("sni_hostname", &self.sni_hostname)
+            .field("padding_enabled", &self.padding_enabled)
+            .finish()
+    }
+}
+
+impl NaiveH3SocketConnector {
+    pub fn new(
+        endpoint: Arc<quinn::Endpoint>,
+        server_address: NetLocation,
+        sni_hostname: Option<String>,
+        username: &str,
+        password: &str,
+        padding_enabled: bool,
+        bind_interface: Option<String>,
+    ) -> Self {
+        Self {
+            endpoint,
+            server_address,
+            sni_hostname,
+            auth_header: basic_auth_header(username, password),
+            padding_enabled,
+            bind_interface,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Get the current session, or connect a new one if there is none or it was closed.
+    async fn get_or_create_session(&self, resolver: &Arc<dyn Resolver>) -> io::Result<H3Session> {
+        let mut guard = self.session.lock().await;
+
+        if let Some(ref session) = *guard
+            && session.connection.close_reason().is_none()
+        {
+            return Ok(session.clone());
+        }
+
+        debug!("NaiveProxy: creating new H3 session for multiplexing");
+
+        let server_addr = resolve_single_address(resolver, &self.server_address).await?;
+        let domain = self
+            .sni_hostname
+            .as_deref()
+            .or(self.server_address.address().hostname())
+            .unwrap_or("example.com");
+
+        let connection = self
+            .endpoint
+            .connect(server_addr, domain)
+            .map_err(|e| io::Error::other(format!("Failed to connect to QUIC endpoint: {e}")))?
+            .await
+            .map_err(|e| io::Error::other(format!("QUIC connection failed: {e}")))?;
+
+        let (mut driver, send_request) =
+            h3::client::new(h3_quinn::Connection::new(connection.clone()))
+                .await
+                .map_err(|e| io::Error::other(format!("H3 client handshake failed: {e}")))?;
+
+        tokio::spawn(async move {
+            let e = std::future::poll_fn(|cx| driver.poll_close(cx)).await;
+            debug!("NaiveProxy client H3 connection ended: {e:?}");
+        });
+
+        let session = H3Session {
+            connection,
+            send_request,
+        };
+        *guard = Some(session.clone());
+
+        Ok(session)
+    }
+}
+
+#[async_trait]
+impl SocketConnector for NaiveH3SocketConnector {
+    async fn connect(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        address: &ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncStream>> {
+        let mut send_request = self.get_or_create_session(resolver).await?.send_request;
+
+        let request = connect_request(
+            address.location(),
+            Version::HTTP_3,
+            &self.auth_header,
+            self.padding_enabled,
+        )?;
+
+        let mut stream = send_request
+            .send_request(request)
+            .await
+            .map_err(|e| io::Error::other(format!("Failed to send CONNECT: {e}")))?;
+
+        let response = stream
+            .recv_response()
+            .await
+            .map_err(|e| io::Error::other(format!("CONNECT response error: {e}")))?;
+
+        if response.status() != http::StatusCode::OK {
+            return Err(io::Error::other(format!(
+                "CONNECT failed with status: {}",
+                response.status()
+            )));
+        }
+
+        let padding_type = reply_padding_type(response.headers(), self.padding_enabled);
+
+        let h3_stream = pipe_client_request_stream(stream);
+        let client_stream: Box<dyn AsyncStream> = if padding_type != PaddingType::None {
+            Box::new(NaivePaddingStream::new(
+                h3_stream,
+                PaddingDirection::Client,
+                padding_type,
+            ))
+        } else {
+            Box::new(h3_stream)
+        };
+
+        debug!("NaiveProxy: opened H3 stream to {}", address.location());
+
+        Ok(client_stream)
+    }
+
+    async fn connect_udp_bidirectional(
+        &self,
+        _resolver: &Arc<dyn Resolver>,
+        _target: ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncMessageStream>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "NaiveProxy over HTTP/3 does not support UDP",
+        ))
+    }
+
+    fn bind_interface(&self) -> Option<&str> {
+        self.bind_interface.as_deref()
+    }
+}
@@ -10,7 +10,7 @@ use std::sync::Arc;
 
 use bytes::Bytes;
 use http::{Method, Request, Response, StatusCode};
-use http_body_util::{BodyExt, Empty, Full, combinators::BoxBody};
+use http_body_util::{BodyExt, Empty, combinators::BoxBody};
 use hyper::body::Incoming;
 use hyper_util::rt::{TokioExecutor, TokioIo};
 use log::debug;
@@ -22,6 +22,9 @@ use crate::async_stream::{AsyncMessageStream, AsyncStream};
 use crate::client_proxy_selector::ClientProxySelector;
 use crate::copy_bidirectional::copy_bidirectional_with_sizes;
 use crate::crypto::CryptoTlsStream;
+use crate::decoy_handler::serve_static_file;
+use crate::metrics::ByteCounter;
+use crate::rate_limit::RateLimiter;
 use crate::resolver::Resolver;
 use crate::routing::{ServerStream, run_udp_routing};
 use crate::socks_handler::read_location_direct;
@@ -114,10 +117,6 @@ fn empty_body() -> BoxBody<Bytes, io::Error> {
         .boxed()
 }
 
-fn full_body(data: Bytes) -> BoxBody<Bytes, io::Error> {
-    Full::new(data).map_err(|never| match never {}).boxed()
-}
-
 /// Run the hyper-based NaiveProxy service
 ///
 /// This is an internal function called by `setup_naive_server_stream` after
@@ -415,55 +414,7 @@ async fn serve_fallback(
             .unwrap());
     };
 
-    // Sanitize path to prevent directory traversal
-    let request_path = uri_path.trim_start_matches('/');
-    let mut file_path = base_path.clone();
-
-    for component in std::path::Path::new(request_path).components() {
-        match component {
-            std::path::Component::Normal(c) => file_path.push(c),
-            std::path::Component::ParentDir => {
-                return Ok(Response::builder()
-                    .status(StatusCode::FORBIDDEN)
-                    .body(empty_body())
-                    .unwrap());
-            }
-            _ => {}
-        }
-    }
-
-    if file_path.is_dir() {
-        file_path.push("index.html");
-    }
-
-    match tokio::fs::read(&file_path).await {
-        Ok(contents) => {
-            let mime = mime_guess::from_path(&file_path)
-                .first_or_octet_stream()
-                .to_string();
-
-            let body = if is_head {
-                empty_body()
-            } else {
-                full_body(Bytes::from(contents.clone()))
-            };
-
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("content-type", mime)
-                .header("content-length", contents.len())
-                .body(body)
-                .unwrap())
-        }
-        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(empty_body())
-            .unwrap()),
-        Err(_) => Ok(Response::builder()
-            .status(StatusCode::INTERNAL_SERVER_ERROR)
-            .body(empty_body())
-            .unwrap()),
-    }
+    Ok(serve_static_file(base_path, uri_path, is_head).await)
 }
 
 /// Handle a single NaiveProxy stream after setup
@@ -495,7 +446,10 @@ async fn handle_naive_stream<S: AsyncStream + 'static>(
                 ServerStream::Targeted(Box::new(uot_stream)),
                 proxy_selector,
                 resolver,
+                None,
                 false,
+                RateLimiter::default(),
+                RateLimiter::default(),
             )
             .await;
         } else if host == UOT_V2_MAGIC_ADDRESS {
@@ -536,6 +490,10 @@ async fn handle_naive_stream<S: AsyncStream + 'static>(
                             client_stream,
                             false,
                             false,
+                            ByteCounter::default(),
+                            ByteCounter::default(),
+                            RateLimiter::default(),
+                            RateLimiter::default(),
                         )
                         .await;
                     }
@@ -554,7 +512,10 @@ async fn handle_naive_stream<S: AsyncStream + 'static>(
                     ServerStream::Targeted(Box::new(uot_stream)),
                     proxy_selector,
                     resolver,
+                    None,
                     false,
+                    RateLimiter::default(),
+                    RateLimiter::default(),
                 )
                 .await;
             }
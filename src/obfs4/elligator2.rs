@@ -0,0 +1,305 @@
+//! Elligator 2 encoding of Curve25519 public keys.
+//!
+//! About half of the public keys can be encoded as a representative: 254 bits that are
+//! indistinguishable from random. The two remaining bits of the 32 byte representative
+//! are filled with random bits by the sender, and ignored by the receiver.
+//!
+//! Field elements use five 51 bit limbs, like the 64 bit backend of curve25519-dalek.
+//! Only public values are encoded, so the arithmetic is not constant time.
+
+const MASK_51: u64 = (1 << 51) - 1;
+
+/// Montgomery curve coefficient of Curve25519.
+const A: u64 = 486662;
+
+/// p - 2, to invert by exponentiation.
+const P_MINUS_2: [u8; 32] = exponent(0xeb, 0x7f);
+/// (p - 1) / 2, the Legendre symbol exponent.
+const P_MINUS_1_HALF: [u8; 32] = exponent(0xf6, 0x3f);
+/// (p + 3) / 8, the square root candidate exponent.
+const P_PLUS_3_EIGHTH: [u8; 32] = exponent(0xfe, 0x0f);
+/// (p - 1) / 4, to compute sqrt(-1) = 2^((p - 1) / 4).
+const P_MINUS_1_QUARTER: [u8; 32] = exponent(0xfb, 0x1f);
+
+/// Builds a little endian exponent of the form 2^k - c, with all middle bytes set.
+const fn exponent(low: u8, high: u8) -> [u8; 32] {
+    let mut bytes = [0xff; 32];
+    bytes[0] = low;
+    bytes[31] = high;
+    bytes
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FieldElement([u64; 5]);
+
+impl FieldElement {
+    const ZERO: Self = Self([0; 5]);
+    const ONE: Self = Self([1, 0, 0, 0, 0]);
+
+    fn from_u64(value: u64) -> Self {
+        Self([value, 0, 0, 0, 0]).reduce()
+    }
+
+    /// Loads a little endian element, ignoring the top bit.
+    fn from_bytes(bytes: &[u8; 32]) -> Self {
+        let word = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap());
+        let (w0, w1, w2, w3) = (word(0), word(1), word(2), word(3));
+        Self([
+            w0 & MASK_51,
+            ((w0 >> 51) | (w1 << 13)) & MASK_51,
+            ((w1 >> 38) | (w2 << 26)) & MASK_51,
+            ((w2 >> 25) | (w3 << 39)) & MASK_51,
+            (w3 >> 12) & MASK_51,
+        ])
+    }
+
+    /// Stores the fully reduced element in little endian.
+    fn to_bytes(self) -> [u8; 32] {
+        let mut limbs = self.reduce().0;
+
+        // Adds 19 if the element is at least p, so that the carry out of the top limb
+        // subtracts p.
+        let mut q = (limbs[0] + 19) >> 51;
+        for limb in &limbs[1..] {
+            q = (limb + q) >> 51;
+        }
+        limbs[0] += 19 * q;
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK_51;
+        }
+        limbs[4] &= MASK_51;
+
+        let mut bytes = [0u8; 32];
+        let mut acc: u128 = 0;
+        let mut acc_bits = 0;
+        let mut index = 0;
+        for limb in limbs {
+            acc |= (limb as u128) << acc_bits;
+            acc_bits += 51;
+            while acc_bits >= 8 {
+                bytes[index] = acc as u8;
+                acc >>= 8;
+                acc_bits -= 8;
+                index += 1;
+            }
+        }
+        bytes[index] = acc as u8;
+        bytes
+    }
+
+    /// Carries each limb into the next, leaving limbs of at most 51 bits plus a small
+    /// excess in the lowest limb.
+    fn reduce(self) -> Self {
+        let mut limbs = self.0;
+        for i in 0..4 {
+            limbs[i + 1] += limbs[i] >> 51;
+            limbs[i] &= MASK_51;
+        }
+        limbs[0] += 19 * (limbs[4] >> 51);
+        limbs[4] &= MASK_51;
+        Self(limbs)
+    }
+
+    fn add(self, other: Self) -> Self {
+        let mut limbs = self.0;
+        for (limb, other) in limbs.iter_mut().zip(other.0) {
+            *limb += other;
+        }
+        Self(limbs).reduce()
+    }
+
+    fn sub(self, other: Self) -> Self {
+        // Adds 2p so that the limbs don't underflow.
+        const TWO_P: [u64; 5] = [
+            0xfffffffffffda,
+            0xffffffffffffe,
+            0xffffffffffffe,
+            0xffffffffffffe,
+            0xffffffffffffe,
+        ];
+        let other = other.reduce();
+        let mut limbs = self.reduce().0;
+        for i in 0..5 {
+            limbs[i] = limbs[i] + TWO_P[i] - other.0[i];
+        }
+        Self(limbs).reduce()
+    }
+
+    fn neg(self) -> Self {
+        Self::ZERO.sub(self)
+    }
+
+    fn mul(self, other: Self) -> Self {
+        let a = self.0.map(u128::from);
+        let b = other.0.map(u128::from);
+        let b19 = b.map(|limb| limb * 19);
+
+        let mut c = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+        for i in 0..4 {
+            c[i + 1] += c[i] >> 51;
+            c[i] &= MASK_51 as u128;
+        }
+        c[0] += 19 * (c[4] >> 51);
+        c[4] &= MASK_51 as u128;
+
+        Self(c.map(|limb| limb as u64)).reduce()
+    }
+
+    fn square(self) -> Self {
+        self.mul(self)
+    }
+
+    /// Raises the element to a little endian exponent.
+    fn pow(self, exponent: &[u8; 32]) -> Self {
+        let mut result = Self::ONE;
+        for byte in exponent.iter().rev() {
+            for bit in (0..8).rev() {
+                result = result.square();
+                if (byte >> bit) & 1 == 1 {
+                    result = result.mul(self);
+                }
+            }
+        }
+        result
+    }
+
+    fn invert(self) -> Self {
+        self.pow(&P_MINUS_2)
+    }
+
+    fn is_zero(self) -> bool {
+        self.to_bytes() == [0u8; 32]
+    }
+
+    fn equals(self, other: Self) -> bool {
+        self.to_bytes() == other.to_bytes()
+    }
+
+    /// True for squares, including zero.
+    fn is_square(self) -> bool {
+        let legendre = self.pow(&P_MINUS_1_HALF);
+        legendre.is_zero() || legendre.equals(Self::ONE)
+    }
+
+    /// Returns the square root that is at most (p - 1) / 2, if there is one.
+    fn sqrt(self) -> Option<Self> {
+        let candidate = self.pow(&P_PLUS_3_EIGHTH);
+        let root = if candidate.square().equals(self) {
+            candidate
+        } else if candidate.square().equals(self.neg()) {
+            candidate.mul(Self::from_u64(2).pow(&P_MINUS_1_QUARTER))
+        } else {
+            return None;
+        };
+        Some(if root.is_negative() { root.neg() } else { root })
+    }
+
+    /// True if the element is greater than (p - 1) / 2.
+    fn is_negative(self) -> bool {
+        // Doubling an element greater than (p - 1) / 2 wraps around p, which makes it odd.
+        self.add(self).to_bytes()[0] & 1 == 1
+    }
+}
+
+/// Returns the representative of a public key, or None if it has none. The two top bits
+/// are zero.
+pub fn representative(public_key: &[u8; 32]) -> Option<[u8; 32]> {
+    let u = FieldElement::from_bytes(public_key);
+    let a = FieldElement::from_u64(A);
+    let u_plus_a = u.add(a);
+    if u.is_zero() || u_plus_a.is_zero() {
+        return None;
+    }
+
+    // r = sqrt(-(u + A) / (2u)) maps back to u for points on the curve.
+    let denominator = u.add(u).invert();
+    let r = u_plus_a.neg().mul(denominator).sqrt()?;
+    Some(r.to_bytes())
+}
+
+/// Returns the public key of a representative, ignoring its two top bits.
+pub fn public_key(representative: &[u8; 32]) -> [u8; 32] {
+    let mut bytes = *representative;
+    bytes[31] &= 0x3f;
+    let r = FieldElement::from_bytes(&bytes);
+    let a = FieldElement::from_u64(A);
+
+    // w = -A / (1 + 2r^2), which is on the curve if w^3 + Aw^2 + w is a square.
+    // Otherwise -w - A is.
+    let w = a
+        .neg()
+        .mul(FieldElement::ONE.add(r.square().add(r.square())).invert());
+    let w_squared = w.square();
+    let curve = w_squared.mul(w).add(a.mul(w_squared)).add(w);
+    let u = if curve.is_square() { w } else { w.neg().sub(a) };
+    u.to_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn element(seed: u64) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        let mut state = seed;
+        for byte in bytes.iter_mut() {
+            state = state
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            *byte = (state >> 56) as u8;
+        }
+        bytes[31] &= 0x3f;
+        bytes
+    }
+
+    #[test]
+    fn test_field_arithmetic() {
+        for seed in 0..16 {
+            let x = FieldElement::from_bytes(&element(seed));
+            if x.is_zero() {
+                continue;
+            }
+            assert!(x.mul(x.invert()).equals(FieldElement::ONE));
+            assert!(x.sub(x).is_zero());
+            assert!(x.add(x.neg()).is_zero());
+            let root = x.square().sqrt().unwrap();
+            assert!(root.square().equals(x.square()));
+            assert!(!root.is_negative());
+        }
+
+        let minus_one = FieldElement::ONE.neg();
+        let sqrt_minus_one = FieldElement::from_u64(2).pow(&P_MINUS_1_QUARTER);
+        assert!(sqrt_minus_one.square().equals(minus_one));
+
+        // p itself reduces to zero.
+        let mut p = [0xff; 32];
+        p[0] = 0xed;
+        p[31] = 0x7f;
+        assert_eq!(FieldElement::from_bytes(&p).to_bytes(), [0u8; 32]);
+    }
+
+    #[test]
+    fn test_representative_roundtrip() {
+        let mut encoded = 0;
+        for seed in 0..64 {
+            let public = public_key(&element(seed));
+            if let Some(representative) = representative(&public) {
+                encoded += 1;
+                assert_eq!(representative[31] & 0xc0, 0);
+                assert_eq!(public_key(&representative), public);
+                let mut high_bits = representative;
+                high_bits[31] |= 0xc0;
+                assert_eq!(public_key(&high_bits), public);
+            }
+        }
+        // The points of random representatives are representable.
+        assert_eq!(encoded, 64);
+    }
+}
@@ -0,0 +1,20 @@
+//! obfs4 style obfuscation layer.
+//!
+//! Wraps any TCP based protocol in a stream that looks like uniformly random bytes:
+//! the handshake is an ntor key exchange with Elligator 2 encoded keys and random
+//! padding, and the data after it is encrypted into frames with masked lengths, random
+//! padding and optionally random delays between writes.
+//!
+//! The design follows obfs4, but the framing isn't compatible with obfs4proxy or
+//! lyrebird, so both ends need to run shoes.
+
+mod elligator2;
+mod obfs4_framing;
+mod obfs4_handler;
+mod obfs4_handshake;
+mod obfs4_stream;
+
+pub use obfs4_handler::{Obfs4TcpClientHandler, Obfs4TcpServerHandler};
+// generate_node_id is used by binary, not by library's public API
+#[allow(unused_imports)]
+pub use obfs4_handshake::{Obfs4Identity, Obfs4ServerIdentity, decode_node_id, generate_node_id};
@@ -0,0 +1,262 @@
+//! Frame encoding after the handshake.
+//!
+//! ```text
+//! | length | ChaCha20-Poly1305(type | data length | data | padding) |
+//! | u16be  | 3 + data + padding + 16                                 |
+//! ```
+//! The length of each frame is masked with the first two bytes of a keyed BLAKE3 hash
+//! of the frame counter, so that the stream has no plaintext bytes. The nonce is the
+//! four byte nonce prefix followed by the frame counter in big endian.
+
+use std::io;
+
+use aws_lc_rs::aead::{Aad, CHACHA20_POLY1305, LessSafeKey, Nonce, UnboundKey};
+use bytes::{Buf, BytesMut};
+
+/// Largest frame on the wire, sized to fill a TCP segment.
+pub const MAX_SEGMENT_LEN: usize = 1448;
+/// Bytes added to each frame: the length, the record header and the tag.
+pub const FRAME_OVERHEAD: usize = LENGTH_LEN + RECORD_HEADER_LEN + TAG_LEN;
+/// Most data that fits into a single frame.
+pub const MAX_DATA_LEN: usize = MAX_SEGMENT_LEN - FRAME_OVERHEAD;
+
+const LENGTH_LEN: usize = 2;
+const RECORD_HEADER_LEN: usize = 3;
+const TAG_LEN: usize = 16;
+const MIN_FRAME_LEN: usize = RECORD_HEADER_LEN + TAG_LEN;
+const MAX_FRAME_LEN: usize = MAX_SEGMENT_LEN - LENGTH_LEN;
+
+const RECORD_TYPE_PAYLOAD: u8 = 0;
+
+/// Keys for a single direction of the stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectionKeys {
+    key: [u8; 32],
+    nonce_prefix: [u8; 4],
+    length_key: [u8; 32],
+}
+
+impl DirectionKeys {
+    pub const LEN: usize = 32 + 4 + 32;
+
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            key: bytes[..32].try_into().unwrap(),
+            nonce_prefix: bytes[32..36].try_into().unwrap(),
+            length_key: bytes[36..Self::LEN].try_into().unwrap(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct SessionKeys {
+    pub encrypt: DirectionKeys,
+    pub decrypt: DirectionKeys,
+}
+
+struct FrameCipher {
+    key: LessSafeKey,
+    nonce_prefix: [u8; 4],
+    length_key: [u8; 32],
+    counter: u64,
+}
+
+impl FrameCipher {
+    fn new(keys: &DirectionKeys) -> Self {
+        Self {
+            key: LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &keys.key).unwrap()),
+            nonce_prefix: keys.nonce_prefix,
+            length_key: keys.length_key,
+            counter: 0,
+        }
+    }
+
+    fn nonce(&self) -> Nonce {
+        let mut nonce = [0u8; 12];
+        nonce[..4].copy_from_slice(&self.nonce_prefix);
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        Nonce::assume_unique_for_key(nonce)
+    }
+
+    fn length_mask(&self) -> u16 {
+        let hash = blake3::keyed_hash(&self.length_key, &self.counter.to_be_bytes());
+        u16::from_be_bytes([hash.as_bytes()[0], hash.as_bytes()[1]])
+    }
+
+    fn advance(&mut self) -> io::Result<()> {
+        self.counter = self
+            .counter
+            .checked_add(1)
+            .ok_or_else(|| io::Error::other("obfs4 frame counter exhausted"))?;
+        Ok(())
+    }
+}
+
+pub struct FrameEncoder {
+    cipher: FrameCipher,
+}
+
+impl FrameEncoder {
+    pub fn new(keys: &DirectionKeys) -> Self {
+        Self {
+            cipher: FrameCipher::new(keys),
+        }
+    }
+
+    /// Appends a frame with up to MAX_DATA_LEN bytes of `data` followed by
+    /// `padding_len` zero bytes.
+    pub fn encode(&mut self, data: &[u8], padding_len: usize, dst: &mut Vec<u8>) -> io::Result<()> {
+        let record_len = RECORD_HEADER_LEN + data.len() + padding_len;
+        if record_len + TAG_LEN > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "obfs4 frame too long",
+            ));
+        }
+
+        let frame_len = (record_len + TAG_LEN) as u16;
+        dst.extend_from_slice(&(frame_len ^ self.cipher.length_mask()).to_be_bytes());
+
+        let mut record = Vec::with_capacity(record_len + TAG_LEN);
+        record.push(RECORD_TYPE_PAYLOAD);
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(data);
+        record.resize(record_len, 0);
+        self.cipher
+            .key
+            .seal_in_place_append_tag(self.cipher.nonce(), Aad::empty(), &mut record)
+            .map_err(|_| io::Error::other("obfs4 frame encryption failed"))?;
+        dst.extend_from_slice(&record);
+
+        self.cipher.advance()
+    }
+}
+
+pub struct FrameDecoder {
+    cipher: FrameCipher,
+    /// Unmasked length of the frame being received.
+    frame_len: Option<usize>,
+}
+
+impl FrameDecoder {
+    pub fn new(keys: &DirectionKeys) -> Self {
+        Self {
+            cipher: FrameCipher::new(keys),
+            frame_len: None,
+        }
+    }
+
+    /// True if the length of a frame was decoded, but not the rest of it.
+    pub fn is_receiving_frame(&self) -> bool {
+        self.frame_len.is_some()
+    }
+
+    /// Removes a frame from `src` and returns its data, which is empty for frames that
+    /// only carry padding. Returns None if the frame isn't complete yet.
+    pub fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<BytesMut>> {
+        let frame_len = match self.frame_len {
+            Some(frame_len) => frame_len,
+            None => {
+                if src.len() < LENGTH_LEN {
+                    return Ok(None);
+                }
+                let frame_len = (src.get_u16() ^ self.cipher.length_mask()) as usize;
+                if !(MIN_FRAME_LEN..=MAX_FRAME_LEN).contains(&frame_len) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid obfs4 frame length: {frame_len}"),
+                    ));
+                }
+                self.frame_len = Some(frame_len);
+                frame_len
+            }
+        };
+        if src.len() < frame_len {
+            return Ok(None);
+        }
+        self.frame_len = None;
+
+        let mut record = src.split_to(frame_len);
+        let record_len = self
+            .cipher
+            .key
+            .open_in_place(self.cipher.nonce(), Aad::empty(), &mut record)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid obfs4 frame"))?
+            .len();
+        self.cipher.advance()?;
+        record.truncate(record_len);
+
+        if record[0] != RECORD_TYPE_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown obfs4 record type: {}", record[0]),
+            ));
+        }
+        let data_len = u16::from_be_bytes([record[1], record[2]]) as usize;
+        if RECORD_HEADER_LEN + data_len > record_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid obfs4 data length: {data_len}"),
+            ));
+        }
+        record.advance(RECORD_HEADER_LEN);
+        record.truncate(data_len);
+        Ok(Some(record))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(seed: u8) -> DirectionKeys {
+        let bytes: Vec<u8> = (0..DirectionKeys::LEN as u8)
+            .map(|i| i.wrapping_mul(seed))
+            .collect();
+        DirectionKeys::from_bytes(&bytes)
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut encoder = FrameEncoder::new(&keys(3));
+        let mut decoder = FrameDecoder::new(&keys(3));
+
+        let mut wire = Vec::new();
+        encoder.encode(b"hello", 10, &mut wire).unwrap();
+        encoder.encode(&[], 100, &mut wire).unwrap();
+        encoder.encode(&[7u8; MAX_DATA_LEN], 0, &mut wire).unwrap();
+        assert_eq!(wire.len(), 3 * FRAME_OVERHEAD + 5 + 10 + 100 + MAX_DATA_LEN);
+        assert!(encoder.encode(&[7u8; MAX_DATA_LEN], 1, &mut wire).is_err());
+
+        // Feeds the frames a byte at a time.
+        let mut src = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in wire {
+            src.extend_from_slice(&[byte]);
+            if let Some(data) = decoder.decode(&mut src).unwrap() {
+                decoded.push(data.to_vec());
+            }
+        }
+        assert!(src.is_empty());
+        assert_eq!(
+            decoded,
+            vec![b"hello".to_vec(), vec![], vec![7u8; MAX_DATA_LEN]]
+        );
+    }
+
+    #[test]
+    fn test_frame_wrong_key() {
+        let mut encoder = FrameEncoder::new(&keys(3));
+        let mut decoder = FrameDecoder::new(&keys(5));
+
+        // A full segment holds any valid length, so either the length or the tag is
+        // rejected.
+        let mut wire = Vec::new();
+        encoder
+            .encode(b"hello", MAX_DATA_LEN - 5, &mut wire)
+            .unwrap();
+        assert_eq!(wire.len(), MAX_SEGMENT_LEN);
+        let mut src = BytesMut::from(&wire[..]);
+        assert!(decoder.decode(&mut src).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::debug;
+use rand::Rng;
+use tokio::io::AsyncReadExt;
+
+use super::obfs4_handshake::{
+    Obfs4Identity, Obfs4ServerIdentity, client_handshake, server_handshake,
+};
+use super::obfs4_stream::Obfs4Stream;
+use crate::address::ResolvedLocation;
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::config::Obfs4IatMode;
+use crate::tcp::tcp_handler::{
+    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+};
+
+/// Longest time that a failed handshake keeps the connection open.
+const MAX_FAILED_HANDSHAKE_DELAY_SECS: u64 = 30;
+
+/// Reads and discards data until EOF or a random delay, so that failed handshakes
+/// don't close the connection at a predictable point.
+async fn discard_until_close(stream: &mut Box<dyn AsyncStream>) {
+    let delay_secs = rand::rng().random_range(1..=MAX_FAILED_HANDSHAKE_DELAY_SECS);
+    let _ = tokio::time::timeout(Duration::from_secs(delay_secs), async {
+        let mut buf = [0u8; 4096];
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+        }
+    })
+    .await;
+}
+
+#[derive(Debug)]
+pub struct Obfs4TcpServerHandler {
+    identity: Arc<Obfs4ServerIdentity>,
+    iat_mode: Obfs4IatMode,
+    handler: Box<dyn TcpServerHandler>,
+}
+
+impl Obfs4TcpServerHandler {
+    pub fn new(
+        identity: Obfs4ServerIdentity,
+        iat_mode: Obfs4IatMode,
+        handler: Box<dyn TcpServerHandler>,
+    ) -> Self {
+        Self {
+            identity: Arc::new(identity),
+            iat_mode,
+            handler,
+        }
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for Obfs4TcpServerHandler {
+    async fn setup_server_stream(
+        &self,
+        mut server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let keys = match server_handshake(&mut server_stream, &self.identity).await {
+            Ok(keys) => keys,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::UnexpectedEof {
+                    debug!("obfs4 handshake failed, discarding connection: {e}");
+                    discard_until_close(&mut server_stream).await;
+                }
+                return Err(e);
+            }
+        };
+
+        let obfs4_stream = Obfs4Stream::new(server_stream, keys, self.iat_mode);
+        let mut target_setup_result = self
+            .handler
+            .setup_server_stream(Box::new(obfs4_stream))
+            .await;
+        if let Ok(ref mut setup_result) = target_setup_result {
+            setup_result.set_need_initial_flush(true);
+        }
+        target_setup_result
+    }
+}
+
+#[derive(Debug)]
+pub struct Obfs4TcpClientHandler {
+    identity: Obfs4Identity,
+    iat_mode: Obfs4IatMode,
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl Obfs4TcpClientHandler {
+    pub fn new(
+        identity: Obfs4Identity,
+        iat_mode: Obfs4IatMode,
+        handler: Box<dyn TcpClientHandler>,
+    ) -> Self {
+        Self {
+            identity,
+            iat_mode,
+            handler,
+        }
+    }
+
+    async fn setup_client_stream_common(
+        &self,
+        mut client_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<Obfs4Stream> {
+        let (keys, initial_data) = client_handshake(&mut client_stream, &self.identity).await?;
+        let mut obfs4_stream = Obfs4Stream::new(client_stream, keys, self.iat_mode);
+        obfs4_stream.feed_initial_data(&initial_data);
+        Ok(obfs4_stream)
+    }
+}
+
+#[async_trait]
+impl TcpClientHandler for Obfs4TcpClientHandler {
+    async fn setup_client_tcp_stream(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        remote_location: ResolvedLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        let obfs4_stream = self.setup_client_stream_common(client_stream).await?;
+        self.handler
+            .setup_client_tcp_stream(Box::new(obfs4_stream), remote_location)
+            .await
+    }
+
+    fn supports_udp_over_tcp(&self) -> bool {
+        self.handler.supports_udp_over_tcp()
+    }
+
+    async fn setup_client_udp_bidirectional(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        let obfs4_stream = self.setup_client_stream_common(client_stream).await?;
+        self.handler
+            .setup_client_udp_bidirectional(Box::new(obfs4_stream), target)
+            .await
+    }
+}
@@ -0,0 +1,560 @@
+//! obfs4 style handshake: an ntor key exchange with Elligator 2 encoded keys.
+//!
+//! Client to server:
+//! ```text
+//! | X' | padding   | mark | MAC |
+//! | 32 | 77..=8128 | 16   | 16  |
+//! ```
+//! Server to client:
+//! ```text
+//! | Y' | AUTH | padding  | mark | MAC |
+//! | 32 | 32   | 0..=8096 | 16   | 16  |
+//! ```
+//! X' and Y' are the representatives of the ephemeral keys. The mark is
+//! HMAC-SHA256(B | NODEID, X' or Y') and the MAC is HMAC-SHA256(B | NODEID, everything
+//! before it | E), both truncated to 16 bytes, where B is the server's public key and E
+//! is the number of hours since the Unix epoch in decimal. The mark lets the receiver
+//! find the end of the random padding, and the MAC authenticates the message. AUTH
+//! proves that the server knows the private key of B.
+
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use aws_lc_rs::agreement;
+use aws_lc_rs::hkdf::{HKDF_SHA256, KeyType, Salt};
+use aws_lc_rs::hmac;
+use base64::engine::{Engine as _, general_purpose::URL_SAFE_NO_PAD};
+use lru::LruCache;
+use parking_lot::Mutex;
+use rand::{Rng, RngCore};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::elligator2;
+use super::obfs4_framing::{DirectionKeys, SessionKeys};
+use crate::async_stream::AsyncStream;
+use crate::auto_ban;
+use crate::util::write_all;
+
+const NODE_ID_LEN: usize = 20;
+const KEY_LEN: usize = 32;
+
+const REPRESENTATIVE_LEN: usize = 32;
+const AUTH_LEN: usize = 32;
+const MARK_LEN: usize = 16;
+const MAC_LEN: usize = 16;
+
+/// Longest handshake of either side, including padding.
+const MAX_HANDSHAKE_LEN: usize = 8192;
+
+const CLIENT_MIN_HANDSHAKE_LEN: usize = REPRESENTATIVE_LEN + MARK_LEN + MAC_LEN;
+const SERVER_MIN_HANDSHAKE_LEN: usize = REPRESENTATIVE_LEN + AUTH_LEN + MARK_LEN + MAC_LEN;
+
+/// Keeps the client's handshake at least as long as the server's, plus its first frame.
+const CLIENT_MIN_PAD_LEN: usize = 77;
+const CLIENT_MAX_PAD_LEN: usize = MAX_HANDSHAKE_LEN - CLIENT_MIN_HANDSHAKE_LEN;
+const SERVER_MAX_PAD_LEN: usize = MAX_HANDSHAKE_LEN - SERVER_MIN_HANDSHAKE_LEN;
+
+const PROTO_ID: &[u8] = b"ntor-curve25519-sha256-1";
+const T_MAC: &[u8] = b"ntor-curve25519-sha256-1:mac";
+const T_KEY: &[u8] = b"ntor-curve25519-sha256-1:key_extract";
+const T_VERIFY: &[u8] = b"ntor-curve25519-sha256-1:key_verify";
+const M_EXPAND: &[u8] = b"ntor-curve25519-sha256-1:key_expand";
+
+/// Number of client MACs remembered to reject replayed handshakes.
+const REPLAY_FILTER_CAPACITY: usize = 65536;
+
+/// The identity of a server, known to its clients.
+#[derive(Clone)]
+pub struct Obfs4Identity {
+    node_id: [u8; NODE_ID_LEN],
+    public_key: [u8; KEY_LEN],
+    mac_key: hmac::Key,
+}
+
+impl std::fmt::Debug for Obfs4Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Obfs4Identity")
+            .field("node_id", &self.node_id)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+impl Obfs4Identity {
+    pub fn new(node_id: [u8; NODE_ID_LEN], public_key: [u8; KEY_LEN]) -> Self {
+        let mut mac_key = Vec::with_capacity(KEY_LEN + NODE_ID_LEN);
+        mac_key.extend_from_slice(&public_key);
+        mac_key.extend_from_slice(&node_id);
+        Self {
+            node_id,
+            public_key,
+            mac_key: hmac::Key::new(hmac::HMAC_SHA256, &mac_key),
+        }
+    }
+
+    fn truncated_mac(&self, parts: &[&[u8]]) -> [u8; MAC_LEN] {
+        let mut context = hmac::Context::with_key(&self.mac_key);
+        for part in parts {
+            context.update(part);
+        }
+        context.sign().as_ref()[..MAC_LEN].try_into().unwrap()
+    }
+}
+
+/// The identity of a server with its private key.
+pub struct Obfs4ServerIdentity {
+    identity: Obfs4Identity,
+    private_key: [u8; KEY_LEN],
+    replay_filter: Mutex<LruCache<[u8; MAC_LEN], ()>>,
+}
+
+impl std::fmt::Debug for Obfs4ServerIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Obfs4ServerIdentity")
+            .field("identity", &self.identity)
+            .finish()
+    }
+}
+
+impl Obfs4ServerIdentity {
+    pub fn new(node_id: [u8; NODE_ID_LEN], private_key: [u8; KEY_LEN]) -> io::Result<Self> {
+        let public_key = public_key(&private_key)?;
+        Ok(Self {
+            identity: Obfs4Identity::new(node_id, public_key),
+            private_key,
+            replay_filter: Mutex::new(LruCache::new(REPLAY_FILTER_CAPACITY.try_into().unwrap())),
+        })
+    }
+}
+
+fn public_key(private_key: &[u8; KEY_LEN]) -> io::Result<[u8; KEY_LEN]> {
+    let private_key = agreement::PrivateKey::from_private_key(&agreement::X25519, private_key)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid X25519 private key"))?;
+    let public_key = private_key
+        .compute_public_key()
+        .map_err(|_| io::Error::other("failed to compute X25519 public key"))?;
+    Ok(public_key.as_ref().try_into().unwrap())
+}
+
+fn x25519(private_key: &[u8; KEY_LEN], public_key: &[u8; KEY_LEN]) -> io::Result<[u8; KEY_LEN]> {
+    let private_key = agreement::PrivateKey::from_private_key(&agreement::X25519, private_key)
+        .map_err(|_| io::Error::other("invalid X25519 private key"))?;
+    let public_key = agreement::UnparsedPublicKey::new(&agreement::X25519, public_key);
+    let mut shared_secret = [0u8; KEY_LEN];
+    agreement::agree(
+        &private_key,
+        public_key,
+        io::Error::new(io::ErrorKind::InvalidData, "X25519 key agreement failed"),
+        |key_material| {
+            shared_secret.copy_from_slice(key_material);
+            Ok(())
+        },
+    )?;
+    Ok(shared_secret)
+}
+
+/// An ephemeral key whose public key has a representative.
+struct EphemeralKey {
+    private_key: [u8; KEY_LEN],
+    public_key: [u8; KEY_LEN],
+    representative: [u8; REPRESENTATIVE_LEN],
+}
+
+impl EphemeralKey {
+    fn generate() -> io::Result<Self> {
+        let mut rng = rand::rng();
+        // Half of the keys are representable, so this takes two tries on average.
+        loop {
+            let mut private_key = [0u8; KEY_LEN];
+            rng.fill_bytes(&mut private_key);
+            let public_key = public_key(&private_key)?;
+            if let Some(mut representative) = elligator2::representative(&public_key) {
+                representative[31] |= rng.random::<u8>() & 0xc0;
+                return Ok(Self {
+                    private_key,
+                    public_key,
+                    representative,
+                });
+            }
+        }
+    }
+}
+
+/// Generates a random node ID, base64url encoded.
+pub fn generate_node_id() -> String {
+    let mut node_id = [0u8; NODE_ID_LEN];
+    rand::rng().fill_bytes(&mut node_id);
+    URL_SAFE_NO_PAD.encode(node_id)
+}
+
+/// Decodes a base64url encoded node ID.
+pub fn decode_node_id(encoded: &str) -> io::Result<[u8; NODE_ID_LEN]> {
+    let decoded = URL_SAFE_NO_PAD
+        .decode(encoded)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid base64: {e}")))?;
+    decoded.try_into().map_err(|decoded: Vec<u8>| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Invalid node ID length: {} (expected {NODE_ID_LEN})",
+                decoded.len()
+            ),
+        )
+    })
+}
+
+fn epoch_hour(offset: i64) -> Vec<u8> {
+    let hours = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / 3600;
+    (hours as i64 + offset).to_string().into_bytes()
+}
+
+fn random_padding(min_len: usize, max_len: usize) -> Vec<u8> {
+    let mut rng = rand::rng();
+    let mut padding = vec![0u8; rng.random_range(min_len..=max_len)];
+    rng.fill_bytes(&mut padding);
+    padding
+}
+
+fn hmac_sha256(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut context = hmac::Context::with_key(&hmac::Key::new(hmac::HMAC_SHA256, key));
+    for part in parts {
+        context.update(part);
+    }
+    context.sign().as_ref().try_into().unwrap()
+}
+
+/// Returns the key seed and the server's AUTH of an ntor exchange, where `exp_y` is the
+/// shared secret of the ephemeral keys and `exp_b` of the client's ephemeral key and
+/// the server's identity key.
+fn ntor(
+    identity: &Obfs4Identity,
+    exp_y: &[u8; KEY_LEN],
+    exp_b: &[u8; KEY_LEN],
+    client_public_key: &[u8; KEY_LEN],
+    server_public_key: &[u8; KEY_LEN],
+) -> ([u8; 32], [u8; AUTH_LEN]) {
+    let secret_input: [&[u8]; 7] = [
+        exp_y,
+        exp_b,
+        &identity.node_id,
+        &identity.public_key,
+        client_public_key,
+        server_public_key,
+        PROTO_ID,
+    ];
+    let key_seed = hmac_sha256(T_KEY, &secret_input);
+    let verify = hmac_sha256(T_VERIFY, &secret_input);
+    let auth = hmac_sha256(
+        T_MAC,
+        &[
+            &verify,
+            &identity.node_id,
+            &identity.public_key,
+            server_public_key,
+            client_public_key,
+            PROTO_ID,
+            b"Server",
+        ],
+    );
+    (key_seed, auth)
+}
+
+struct KeyLength(usize);
+
+impl KeyType for KeyLength {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// Expands the key seed into the keys of both directions, client to server first.
+fn session_keys(key_seed: &[u8; 32], is_client: bool) -> SessionKeys {
+    let mut okm = [0u8; 2 * DirectionKeys::LEN];
+    Salt::new(HKDF_SHA256, &[])
+        .extract(key_seed)
+        .expand(&[M_EXPAND], KeyLength(okm.len()))
+        .unwrap()
+        .fill(&mut okm)
+        .unwrap();
+    let (client_keys, server_keys) = okm.split_at(DirectionKeys::LEN);
+    let client_keys = DirectionKeys::from_bytes(client_keys);
+    let server_keys = DirectionKeys::from_bytes(server_keys);
+    if is_client {
+        SessionKeys {
+            encrypt: client_keys,
+            decrypt: server_keys,
+        }
+    } else {
+        SessionKeys {
+            encrypt: server_keys,
+            decrypt: client_keys,
+        }
+    }
+}
+
+/// Reads until `find` locates the end of the handshake in the data read so far,
+/// returning the data and the result.
+async fn read_handshake<T>(
+    stream: &mut Box<dyn AsyncStream>,
+    min_len: usize,
+    mut find: impl FnMut(&[u8]) -> Option<T>,
+) -> io::Result<(Vec<u8>, T)> {
+    let mut buf = Vec::with_capacity(MAX_HANDSHAKE_LEN);
+    let mut read_buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut read_buf).await?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "EOF during obfs4 handshake",
+            ));
+        }
+        buf.extend_from_slice(&read_buf[..n]);
+        if buf.len() >= min_len
+            && let Some(found) = find(&buf)
+        {
+            return Ok((buf, found));
+        }
+        if buf.len() >= MAX_HANDSHAKE_LEN {
+            return Err(auto_ban::rejected(
+                io::ErrorKind::InvalidData,
+                "obfs4 handshake mark not found",
+            ));
+        }
+    }
+}
+
+/// Returns the position of the mark, searching from `start` within the first
+/// MAX_HANDSHAKE_LEN bytes. The mark must be followed by a complete MAC.
+fn find_mark(buf: &[u8], mark: &[u8; MARK_LEN], start: usize) -> Option<usize> {
+    let end = buf.len().min(MAX_HANDSHAKE_LEN);
+    if end < start + MARK_LEN + MAC_LEN {
+        return None;
+    }
+    memchr::memmem::find(&buf[start..end - MAC_LEN], mark).map(|pos| start + pos)
+}
+
+/// Runs the client handshake, returning the session keys and the data that the server
+/// sent after its handshake.
+pub async fn client_handshake(
+    stream: &mut Box<dyn AsyncStream>,
+    identity: &Obfs4Identity,
+) -> io::Result<(SessionKeys, Vec<u8>)> {
+    let ephemeral = EphemeralKey::generate()?;
+    let epoch_hour = epoch_hour(0);
+
+    let padding = random_padding(CLIENT_MIN_PAD_LEN, CLIENT_MAX_PAD_LEN);
+    let mark = identity.truncated_mac(&[&ephemeral.representative]);
+    let mac = identity.truncated_mac(&[&ephemeral.representative, &padding, &mark, &epoch_hour]);
+
+    let mut request = Vec::with_capacity(CLIENT_MIN_HANDSHAKE_LEN + padding.len());
+    request.extend_from_slice(&ephemeral.representative);
+    request.extend_from_slice(&padding);
+    request.extend_from_slice(&mark);
+    request.extend_from_slice(&mac);
+    write_all(stream, &request).await?;
+    stream.flush().await?;
+
+    let mut server_mark = None;
+    let (response, mark_pos) = read_handshake(stream, SERVER_MIN_HANDSHAKE_LEN, |buf| {
+        let mark = server_mark
+            .get_or_insert_with(|| identity.truncated_mac(&[&buf[..REPRESENTATIVE_LEN]]));
+        find_mark(buf, mark, REPRESENTATIVE_LEN + AUTH_LEN)
+    })
+    .await?;
+
+    let mac_pos = mark_pos + MARK_LEN;
+    let expected_mac = identity.truncated_mac(&[&response[..mac_pos], &epoch_hour]);
+    if !bool::from(expected_mac.ct_eq(&response[mac_pos..mac_pos + MAC_LEN])) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "invalid obfs4 server handshake MAC",
+        ));
+    }
+
+    let server_representative: [u8; REPRESENTATIVE_LEN] =
+        response[..REPRESENTATIVE_LEN].try_into().unwrap();
+    let server_public_key = elligator2::public_key(&server_representative);
+    let exp_y = x25519(&ephemeral.private_key, &server_public_key)?;
+    let exp_b = x25519(&ephemeral.private_key, &identity.public_key)?;
+    let (key_seed, auth) = ntor(
+        identity,
+        &exp_y,
+        &exp_b,
+        &ephemeral.public_key,
+        &server_public_key,
+    );
+    let server_auth = &response[REPRESENTATIVE_LEN..REPRESENTATIVE_LEN + AUTH_LEN];
+    if !bool::from(auth.ct_eq(server_auth)) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "obfs4 server failed to authenticate",
+        ));
+    }
+
+    let initial_data = response[mac_pos + MAC_LEN..].to_vec();
+    Ok((session_keys(&key_seed, true), initial_data))
+}
+
+/// Runs the server handshake, returning the session keys.
+pub async fn server_handshake(
+    stream: &mut Box<dyn AsyncStream>,
+    server_identity: &Obfs4ServerIdentity,
+) -> io::Result<SessionKeys> {
+    let identity = &server_identity.identity;
+
+    let mut client_mark = None;
+    let (request, mark_pos) = read_handshake(
+        stream,
+        CLIENT_MIN_HANDSHAKE_LEN + CLIENT_MIN_PAD_LEN,
+        |buf| {
+            let mark = client_mark
+                .get_or_insert_with(|| identity.truncated_mac(&[&buf[..REPRESENTATIVE_LEN]]));
+            find_mark(buf, mark, REPRESENTATIVE_LEN + CLIENT_MIN_PAD_LEN)
+        },
+    )
+    .await?;
+
+    let mac_pos = mark_pos + MARK_LEN;
+    let client_mac = &request[mac_pos..mac_pos + MAC_LEN];
+    // Accepts clocks that are off by up to an hour.
+    let client_epoch_hour = [0, -1, 1]
+        .into_iter()
+        .map(epoch_hour)
+        .find(|candidate| {
+            let expected_mac = identity.truncated_mac(&[&request[..mac_pos], candidate]);
+            bool::from(expected_mac.ct_eq(client_mac))
+        })
+        .ok_or_else(|| {
+            auto_ban::rejected(
+                io::ErrorKind::PermissionDenied,
+                "invalid obfs4 client handshake MAC",
+            )
+        })?;
+
+    // Clients wait for the server's handshake before sending data.
+    if request.len() != mac_pos + MAC_LEN {
+        return Err(auto_ban::rejected(
+            io::ErrorKind::InvalidData,
+            "unexpected data after obfs4 client handshake",
+        ));
+    }
+
+    let client_mac: [u8; MAC_LEN] = client_mac.try_into().unwrap();
+    if server_identity
+        .replay_filter
+        .lock()
+        .put(client_mac, ())
+        .is_some()
+    {
+        return Err(auto_ban::rejected(
+            io::ErrorKind::PermissionDenied,
+            "replayed obfs4 client handshake",
+        ));
+    }
+
+    let client_representative: [u8; REPRESENTATIVE_LEN] =
+        request[..REPRESENTATIVE_LEN].try_into().unwrap();
+    let client_public_key = elligator2::public_key(&client_representative);
+    let ephemeral = EphemeralKey::generate()?;
+    let exp_y = x25519(&ephemeral.private_key, &client_public_key)?;
+    let exp_b = x25519(&server_identity.private_key, &client_public_key)?;
+    let (key_seed, auth) = ntor(
+        identity,
+        &exp_y,
+        &exp_b,
+        &client_public_key,
+        &ephemeral.public_key,
+    );
+
+    let padding = random_padding(0, SERVER_MAX_PAD_LEN);
+    let mark = identity.truncated_mac(&[&ephemeral.representative]);
+    let mac = identity.truncated_mac(&[
+        &ephemeral.representative,
+        &auth,
+        &padding,
+        &mark,
+        &client_epoch_hour,
+    ]);
+
+    let mut response = Vec::with_capacity(SERVER_MIN_HANDSHAKE_LEN + padding.len());
+    response.extend_from_slice(&ephemeral.representative);
+    response.extend_from_slice(&auth);
+    response.extend_from_slice(&padding);
+    response.extend_from_slice(&mark);
+    response.extend_from_slice(&mac);
+    write_all(stream, &response).await?;
+    stream.flush().await?;
+
+    Ok(session_keys(&key_seed, false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate_server_keys() -> ([u8; NODE_ID_LEN], [u8; KEY_LEN], [u8; KEY_LEN]) {
+        let mut rng = rand::rng();
+        let mut node_id = [0u8; NODE_ID_LEN];
+        rng.fill_bytes(&mut node_id);
+        let mut private_key = [0u8; KEY_LEN];
+        rng.fill_bytes(&mut private_key);
+        let public_key = public_key(&private_key).unwrap();
+        (node_id, private_key, public_key)
+    }
+
+    #[test]
+    fn test_node_id() {
+        let node_id = generate_node_id();
+        assert_eq!(decode_node_id(&node_id).unwrap().len(), NODE_ID_LEN);
+        assert!(decode_node_id("AAAA").is_err());
+        assert!(decode_node_id("not base64!").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (node_id, private_key, public_key) = generate_server_keys();
+        let server_identity = Obfs4ServerIdentity::new(node_id, private_key).unwrap();
+        let client_identity = Obfs4Identity::new(node_id, public_key);
+
+        let (client, server) = tokio::io::duplex(MAX_HANDSHAKE_LEN * 2);
+        let mut client: Box<dyn AsyncStream> = Box::new(client);
+        let mut server: Box<dyn AsyncStream> = Box::new(server);
+
+        let (client_result, server_result) = tokio::join!(
+            client_handshake(&mut client, &client_identity),
+            server_handshake(&mut server, &server_identity)
+        );
+        let (client_keys, initial_data) = client_result.unwrap();
+        let server_keys = server_result.unwrap();
+        assert!(initial_data.is_empty());
+        assert_eq!(client_keys.encrypt, server_keys.decrypt);
+        assert_eq!(client_keys.decrypt, server_keys.encrypt);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_wrong_identity() {
+        let (node_id, private_key, _) = generate_server_keys();
+        let (_, _, other_public_key) = generate_server_keys();
+        let server_identity = Obfs4ServerIdentity::new(node_id, private_key).unwrap();
+        let client_identity = Obfs4Identity::new(node_id, other_public_key);
+
+        let (client, server) = tokio::io::duplex(MAX_HANDSHAKE_LEN * 2);
+        let mut client: Box<dyn AsyncStream> = Box::new(client);
+        let mut server: Box<dyn AsyncStream> = Box::new(server);
+
+        // The server can't find the client's mark without the right identity, and waits
+        // for more data until the client gives up and closes the stream.
+        tokio::spawn(async move {
+            let handshake = client_handshake(&mut client, &client_identity);
+            let _ = tokio::time::timeout(std::time::Duration::from_millis(100), handshake).await;
+        });
+        let result = server_handshake(&mut server, &server_identity).await;
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
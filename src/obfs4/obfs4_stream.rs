@@ -0,0 +1,355 @@
+//! Stream that carries data in obfs4 frames, and hides the lengths and timing of
+//! writes.
+//!
+//! Written data is encoded into frames immediately, and sent on flush. Each flush pads
+//! the burst of frames so that the last segment has a length sampled from a random
+//! distribution. Depending on the IAT (inter-arrival time) mode, the burst is then
+//! written at once, or in chunks with random delays in between.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use futures::ready;
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
+
+use super::obfs4_framing::{
+    FRAME_OVERHEAD, FrameDecoder, FrameEncoder, MAX_DATA_LEN, MAX_SEGMENT_LEN, SessionKeys,
+};
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::config::Obfs4IatMode;
+
+/// Pending output above which writes wait for it to drain.
+const MAX_PENDING_WRITE_LEN: usize = 64 * 1024;
+
+const READ_CHUNK_LEN: usize = 16 * 1024;
+
+/// Longest delay between chunks in the IAT modes.
+const MAX_IAT_DELAY_MICROS: u64 = 10_000;
+const IAT_DELAY_STEP_MICROS: u64 = 100;
+
+/// A random weighted distribution of lengths, different for each connection.
+struct LengthDistribution {
+    lengths: Vec<usize>,
+    cumulative_weights: Vec<u32>,
+}
+
+impl LengthDistribution {
+    fn new(min_len: usize, max_len: usize) -> Self {
+        let mut rng = rand::rng();
+        let count = rng.random_range(1..=100);
+        let mut lengths = Vec::with_capacity(count);
+        let mut cumulative_weights = Vec::with_capacity(count);
+        let mut total = 0;
+        for _ in 0..count {
+            lengths.push(rng.random_range(min_len..=max_len));
+            total += rng.random_range(1..=100);
+            cumulative_weights.push(total);
+        }
+        Self {
+            lengths,
+            cumulative_weights,
+        }
+    }
+
+    fn sample(&self) -> usize {
+        let total = *self.cumulative_weights.last().unwrap();
+        let target = rand::rng().random_range(0..total);
+        let index = self
+            .cumulative_weights
+            .partition_point(|&weight| weight <= target);
+        self.lengths[index]
+    }
+}
+
+/// Returns the padding that brings the last segment of a burst to `target_len`. Padding
+/// is sent in frames, so it's either zero or at least FRAME_OVERHEAD.
+fn padding_len(burst_len: usize, target_len: usize) -> usize {
+    let tail_len = burst_len % MAX_SEGMENT_LEN;
+    let padding_len = if target_len >= tail_len {
+        target_len - tail_len
+    } else {
+        MAX_SEGMENT_LEN - tail_len + target_len
+    };
+    if padding_len > 0 && padding_len < FRAME_OVERHEAD {
+        padding_len + MAX_SEGMENT_LEN
+    } else {
+        padding_len
+    }
+}
+
+pub struct Obfs4Stream {
+    stream: Box<dyn AsyncStream>,
+    encoder: FrameEncoder,
+    decoder: FrameDecoder,
+    iat_mode: Obfs4IatMode,
+    length_distribution: LengthDistribution,
+
+    read_buf: BytesMut,
+    plaintext: BytesMut,
+    is_eof: bool,
+
+    write_buf: Vec<u8>,
+    write_pos: usize,
+    /// Bytes of frames written since the last padding.
+    burst_len: usize,
+    /// Bytes left in the current chunk, in the IAT modes.
+    chunk_remaining: usize,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Obfs4Stream {
+    pub fn new(stream: Box<dyn AsyncStream>, keys: SessionKeys, iat_mode: Obfs4IatMode) -> Self {
+        Self {
+            stream,
+            encoder: FrameEncoder::new(&keys.encrypt),
+            decoder: FrameDecoder::new(&keys.decrypt),
+            iat_mode,
+            length_distribution: LengthDistribution::new(0, MAX_SEGMENT_LEN - 1),
+            read_buf: BytesMut::with_capacity(READ_CHUNK_LEN),
+            plaintext: BytesMut::new(),
+            is_eof: false,
+            write_buf: Vec::with_capacity(MAX_SEGMENT_LEN * 4),
+            write_pos: 0,
+            burst_len: 0,
+            chunk_remaining: 0,
+            delay: None,
+        }
+    }
+
+    /// Feeds data that was read along with the handshake.
+    pub fn feed_initial_data(&mut self, data: &[u8]) {
+        self.read_buf.extend_from_slice(data);
+    }
+
+    fn encode_padding(&mut self) -> io::Result<()> {
+        if self.burst_len == 0 {
+            return Ok(());
+        }
+        let target_len = self.length_distribution.sample();
+        let mut padding_len = padding_len(self.burst_len, target_len);
+        while padding_len > 0 {
+            let mut frame_len = padding_len.min(MAX_SEGMENT_LEN);
+            if padding_len - frame_len > 0 && padding_len - frame_len < FRAME_OVERHEAD {
+                frame_len = padding_len - FRAME_OVERHEAD;
+            }
+            self.encoder
+                .encode(&[], frame_len - FRAME_OVERHEAD, &mut self.write_buf)?;
+            padding_len -= frame_len;
+        }
+        self.burst_len = 0;
+        Ok(())
+    }
+
+    fn next_chunk_len(&self) -> usize {
+        match self.iat_mode {
+            Obfs4IatMode::None => usize::MAX,
+            Obfs4IatMode::Enabled => MAX_SEGMENT_LEN,
+            Obfs4IatMode::Paranoid => self.length_distribution.sample().max(1),
+        }
+    }
+
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write_pos < self.write_buf.len() {
+            if let Some(delay) = self.delay.as_mut() {
+                // Sends the previous chunk before waiting.
+                ready!(Pin::new(&mut self.stream).poll_flush(cx))?;
+                ready!(delay.as_mut().poll(cx));
+                self.delay = None;
+            }
+
+            if self.chunk_remaining == 0 {
+                self.chunk_remaining = self.next_chunk_len();
+            }
+            let end = self
+                .write_buf
+                .len()
+                .min(self.write_pos.saturating_add(self.chunk_remaining));
+            let n = ready!(
+                Pin::new(&mut self.stream).poll_write(cx, &self.write_buf[self.write_pos..end])
+            )?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_pos += n;
+            self.chunk_remaining -= n;
+
+            if self.chunk_remaining == 0 && self.write_pos < self.write_buf.len() {
+                let steps =
+                    rand::rng().random_range(0..=MAX_IAT_DELAY_MICROS / IAT_DELAY_STEP_MICROS);
+                self.delay = Some(Box::pin(tokio::time::sleep(Duration::from_micros(
+                    steps * IAT_DELAY_STEP_MICROS,
+                ))));
+            }
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        if self.iat_mode == Obfs4IatMode::None {
+            self.chunk_remaining = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Obfs4Stream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            if !this.plaintext.is_empty() {
+                let n = this.plaintext.len().min(buf.remaining());
+                buf.put_slice(&this.plaintext.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+
+            if let Some(data) = this.decoder.decode(&mut this.read_buf)? {
+                this.plaintext = data;
+                continue;
+            }
+
+            if this.is_eof {
+                if this.read_buf.is_empty() && !this.decoder.is_receiving_frame() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "EOF in the middle of an obfs4 frame",
+                )));
+            }
+
+            let mut chunk = [0u8; READ_CHUNK_LEN];
+            let mut chunk_buf = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk_buf))?;
+            if chunk_buf.filled().is_empty() {
+                this.is_eof = true;
+            } else {
+                this.read_buf.extend_from_slice(chunk_buf.filled());
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Obfs4Stream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.write_buf.len() - this.write_pos >= MAX_PENDING_WRITE_LEN {
+            ready!(this.poll_drain(cx))?;
+        }
+        if this.write_pos > 0 {
+            this.write_buf.drain(..this.write_pos);
+            this.write_pos = 0;
+        }
+
+        for data in buf.chunks(MAX_DATA_LEN) {
+            this.encoder.encode(data, 0, &mut this.write_buf)?;
+            this.burst_len += data.len() + FRAME_OVERHEAD;
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        this.encode_padding()?;
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.as_mut().poll_flush(cx))?;
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for Obfs4Stream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        unimplemented!();
+    }
+}
+
+impl AsyncStream for Obfs4Stream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::obfs4::obfs4_framing::DirectionKeys;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_padding_len() {
+        for burst_len in [1, 21, 500, 1447, 1448, 1449, 5000] {
+            for target_len in [0, 1, 20, 21, 700, 1447] {
+                let padding_len = padding_len(burst_len, target_len);
+                assert!(padding_len == 0 || padding_len >= FRAME_OVERHEAD);
+                assert_eq!((burst_len + padding_len) % MAX_SEGMENT_LEN, target_len);
+            }
+        }
+    }
+
+    #[test]
+    fn test_length_distribution() {
+        let distribution = LengthDistribution::new(10, 20);
+        for _ in 0..100 {
+            assert!((10..=20).contains(&distribution.sample()));
+        }
+    }
+
+    fn session_keys(is_client: bool) -> SessionKeys {
+        let a = DirectionKeys::from_bytes(&[1u8; DirectionKeys::LEN]);
+        let b = DirectionKeys::from_bytes(&[2u8; DirectionKeys::LEN]);
+        if is_client {
+            SessionKeys {
+                encrypt: a,
+                decrypt: b,
+            }
+        } else {
+            SessionKeys {
+                encrypt: b,
+                decrypt: a,
+            }
+        }
+    }
+
+    async fn roundtrip(iat_mode: Obfs4IatMode) {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let mut client = Obfs4Stream::new(Box::new(client), session_keys(true), iat_mode);
+        let mut server = Obfs4Stream::new(Box::new(server), session_keys(false), iat_mode);
+
+        let data: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            client.write_all(&data).await.unwrap();
+            client.flush().await.unwrap();
+            client.write_all(b"end").await.unwrap();
+            client.shutdown().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        server.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(&received[..expected.len()], &expected[..]);
+        assert_eq!(&received[expected.len()..], b"end");
+    }
+
+    #[tokio::test]
+    async fn test_stream_roundtrip() {
+        roundtrip(Obfs4IatMode::None).await;
+        roundtrip(Obfs4IatMode::Enabled).await;
+        roundtrip(Obfs4IatMode::Paranoid).await;
+    }
+}
@@ -1,26 +1,74 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
 use async_trait::async_trait;
+use log::{debug, error};
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
+use crate::access_control::AccessControl;
 use crate::address::{NetLocation, ResolvedLocation};
-use crate::async_stream::AsyncStream;
-use crate::client_proxy_selector::ClientProxySelector;
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::auto_ban;
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::config::{BindLocation, ConfigSelection, ServerConfig, ServerProxyConfig};
+use crate::resolver::Resolver;
+use crate::socket_util::new_socket2_udp_socket;
+use crate::tcp::tcp_client_handler_factory::create_tcp_client_proxy_selector;
 use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
 use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+use crate::udp_batch::RecvBatch;
 
+/// Idle time after which UDP flows are closed.
+const UDP_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Number of datagrams queued per UDP flow before new datagrams are dropped.
+const UDP_FLOW_CHANNEL_SIZE: usize = 64;
+
+const UDP_BUFFER_LEN: usize = 65535;
+
+/// Targets that connections and UDP flows are spread over in turn.
 #[derive(Debug)]
-pub struct PortForwardServerHandler {
+struct Targets {
     targets: Vec<NetLocation>,
     next_target_index: AtomicU32,
+}
+
+impl Targets {
+    fn new(targets: Vec<NetLocation>) -> Self {
+        Self {
+            targets,
+            next_target_index: AtomicU32::new(0),
+        }
+    }
+
+    fn next(&self) -> &NetLocation {
+        if self.targets.len() == 1 {
+            &self.targets[0]
+        } else {
+            let target_index = self.next_target_index.fetch_add(1, Ordering::Relaxed) as usize;
+            &self.targets[target_index % self.targets.len()]
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PortForwardServerHandler {
+    targets: Targets,
     proxy_selector: Arc<ClientProxySelector>,
 }
 
 impl PortForwardServerHandler {
     pub fn new(targets: Vec<NetLocation>, proxy_selector: Arc<ClientProxySelector>) -> Self {
         Self {
-            targets,
-            next_target_index: AtomicU32::new(0),
+            targets: Targets::new(targets),
             proxy_selector,
         }
     }
@@ -32,15 +80,8 @@ impl TcpServerHandler for PortForwardServerHandler {
         &self,
         server_stream: Box<dyn AsyncStream>,
     ) -> std::io::Result<TcpServerSetupResult> {
-        let location = if self.targets.len() == 1 {
-            &self.targets[0]
-        } else {
-            let target_index = self.next_target_index.fetch_add(1, Ordering::Relaxed) as usize;
-            &self.targets[target_index % self.targets.len()]
-        };
-
         Ok(TcpServerSetupResult::TcpForward {
-            remote_location: location.clone(),
+            remote_location: self.targets.next().clone(),
             stream: server_stream,
             need_initial_flush: true,
             connection_success_response: None,
@@ -66,3 +107,224 @@ impl TcpClientHandler for PortForwardClientHandler {
         })
     }
 }
+
+struct UdpForwarder {
+    socket: Arc<UdpSocket>,
+    targets: Targets,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    access_control: Option<Arc<AccessControl>>,
+}
+
+/// Starts UDP listeners on the addresses of a port forward config. Datagrams from each
+/// client are forwarded to a target, and responses are sent back from the listener.
+pub async fn start_port_forward_udp_servers(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    let ServerConfig {
+        bind_location,
+        protocol,
+        rules,
+        access_control,
+        ..
+    } = config;
+
+    let ServerProxyConfig::PortForward { targets, .. } = protocol else {
+        unreachable!("only port forward servers forward UDP");
+    };
+
+    println!("Starting port forward UDP server at {}", &bind_location);
+
+    let bind_addresses = match bind_location {
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            bind_location.to_socket_addrs()?
+        }
+        BindLocation::Path(_) => {
+            return Err(io::Error::other(
+                "Cannot listen on path, UDP does not have unix domain socket support",
+            ));
+        }
+    };
+
+    // Targets with several ports were split per listening port during validation.
+    let targets = targets
+        .into_iter()
+        .map(|target| target.single_location().unwrap())
+        .collect::<Vec<_>>();
+    let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
+    let proxy_selector = create_tcp_client_proxy_selector(rules, resolver.clone());
+    // Validated when loading config
+    let access_control = access_control
+        .map(|config| Arc::new(AccessControl::new(&config).expect("Invalid access control")));
+
+    let mut join_handles = Vec::with_capacity(bind_addresses.len());
+    for bind_address in bind_addresses {
+        let socket: std::net::UdpSocket =
+            new_socket2_udp_socket(bind_address.is_ipv6(), None, Some(bind_address), false)?.into();
+        let forwarder = Arc::new(UdpForwarder {
+            socket: Arc::new(UdpSocket::from_std(socket)?),
+            targets: Targets::new(targets.clone()),
+            proxy_selector: proxy_selector.clone(),
+            resolver: resolver.clone(),
+            access_control: access_control.clone(),
+        });
+        join_handles.push(tokio::spawn(async move {
+            if let Err(e) = run_udp_forwarder(forwarder).await {
+                error!("Port forward UDP server at {bind_address} stopped: {e}");
+            }
+        }));
+    }
+
+    Ok(join_handles)
+}
+
+async fn run_udp_forwarder(forwarder: Arc<UdpForwarder>) -> io::Result<()> {
+    let mut flows: HashMap<SocketAddr, mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; UDP_BUFFER_LEN];
+    let mut recv_batch = RecvBatch::default();
+
+    loop {
+        let (len, source) = recv_batch.recv_from(&forwarder.socket, &mut buf).await?;
+        let payload = buf[..len].to_vec();
+
+        if let Some(tx) = flows.get(&source) {
+            match tx.try_send(payload) {
+                Ok(()) => continue,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    debug!("Dropping UDP datagram from {source}, queue is full");
+                    continue;
+                }
+                Err(mpsc::error::TrySendError::Closed(payload)) => {
+                    flows.remove(&source);
+                    flows.retain(|_, tx| !tx.is_closed());
+                    start_udp_flow(&mut flows, source, payload, &forwarder);
+                }
+            }
+        } else {
+            flows.retain(|_, tx| !tx.is_closed());
+            start_udp_flow(&mut flows, source, payload, &forwarder);
+        }
+    }
+}
+
+fn start_udp_flow(
+    flows: &mut HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>,
+    source: SocketAddr,
+    first_payload: Vec<u8>,
+    forwarder: &Arc<UdpForwarder>,
+) {
+    if let Some(access_control) = &forwarder.access_control
+        && !access_control.permits(source.ip())
+    {
+        debug!("Dropping UDP datagram from {source}, the address is not allowed to connect");
+        return;
+    }
+    if auto_ban::is_banned(source.ip()) {
+        debug!("Dropping UDP datagram from {source}, the address is banned");
+        return;
+    }
+
+    let (tx, rx) = mpsc::channel(UDP_FLOW_CHANNEL_SIZE);
+    // The channel is empty, so this can't fail.
+    let _ = tx.try_send(first_payload);
+    flows.insert(source, tx);
+
+    let target = forwarder.targets.next().clone();
+    let forwarder = forwarder.clone();
+    tokio::spawn(async move {
+        if let Err(e) = run_udp_flow(source, &target, rx, &forwarder).await {
+            debug!("UDP flow {source} -> {target} finished with error: {e}");
+        }
+    });
+}
+
+/// Forwards datagrams from `source` to `target` through the selected chain, and sends
+/// responses back to `source` from the listener.
+async fn run_udp_flow(
+    source: SocketAddr,
+    target: &NetLocation,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    forwarder: &UdpForwarder,
+) -> io::Result<()> {
+    let decision = forwarder
+        .proxy_selector
+        .judge_with_source(
+            target.clone().into(),
+            Some(source.ip()),
+            &forwarder.resolver,
+        )
+        .await?;
+    let mut remote = match decision {
+        ConnectDecision::Allow {
+            chain_group,
+            remote_location,
+        } => {
+            chain_group
+                .connect_udp_bidirectional_with_source(
+                    &forwarder.resolver,
+                    remote_location,
+                    Some(source.ip()),
+                )
+                .await?
+        }
+        ConnectDecision::Block => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "destination blocked",
+            ));
+        }
+    };
+
+    let mut read_buf = vec![0u8; UDP_BUFFER_LEN];
+
+    loop {
+        tokio::select! {
+            payload = rx.recv() => {
+                let Some(payload) = payload else {
+                    return Ok(());
+                };
+                send_message(&mut remote, &payload).await?;
+            }
+            response = read_message(&mut remote, &mut read_buf) => {
+                let len = response?;
+                forwarder.socket.send_to(&read_buf[..len], source).await?;
+            }
+            _ = tokio::time::sleep(UDP_FLOW_IDLE_TIMEOUT) => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+async fn send_message(stream: &mut Box<dyn AsyncMessageStream>, data: &[u8]) -> io::Result<()> {
+    std::future::poll_fn(|cx| Pin::new(&mut **stream).poll_write_message(cx, data)).await?;
+    std::future::poll_fn(|cx| Pin::new(&mut **stream).poll_flush_message(cx)).await
+}
+
+async fn read_message(
+    stream: &mut Box<dyn AsyncMessageStream>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut read_buf = ReadBuf::new(buf);
+    std::future::poll_fn(|cx| Pin::new(&mut **stream).poll_read_message(cx, &mut read_buf)).await?;
+    Ok(read_buf.filled().len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+
+    #[test]
+    fn test_targets_round_robin() {
+        let targets = Targets::new(vec![
+            NetLocation::new(Address::Hostname("a.example".to_string()), 80),
+            NetLocation::new(Address::Hostname("b.example".to_string()), 80),
+        ]);
+        let next = (0..3)
+            .map(|_| targets.next().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(next, ["a.example:80", "b.example:80", "a.example:80"]);
+    }
+}
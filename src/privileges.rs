@@ -0,0 +1,203 @@
+//! Dropping root privileges once the servers are started.
+//!
+//! Listeners are bound, and certificate, key and access log files are opened, while still
+//! running as root. Then the process switches to the configured group and user, without
+//! supplementary groups. Reloads run as that user, so the files they read must be readable
+//! by it, and new listeners can't use privileged ports unless systemd passes their sockets.
+//! Capabilities are dropped too, so outbounds that set `fwmark` or `bind_interface` on their
+//! sockets fail, and need shoes to be started as the user with ambient capabilities instead.
+
+use std::ffi::CString;
+use std::sync::OnceLock;
+
+use log::{info, warn};
+
+use crate::config::PrivilegesConfig;
+
+/// The privileges config of the first load. Changes need a restart.
+static CONFIG: OnceLock<Option<PrivilegesConfig>> = OnceLock::new();
+
+pub fn validate_config(config: &PrivilegesConfig) -> std::io::Result<()> {
+    if config.user.is_none() && config.group.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "invalid privileges config: user or group must be set",
+        ));
+    }
+    resolve(config).map(|_| ())
+}
+
+pub fn configure(config: Option<PrivilegesConfig>) {
+    if let Err(config) = CONFIG.set(config)
+        && CONFIG.get() != Some(&config)
+    {
+        warn!("Changes to user and group take effect after a restart");
+    }
+}
+
+/// Switches to the user and group of the config, if any. Returns an error if they can't be
+/// switched to, e.g. when not running as root.
+pub fn drop_privileges() -> std::io::Result<()> {
+    let Some(Some(config)) = CONFIG.get() else {
+        return Ok(());
+    };
+    let (uid, gid) = resolve(config)?;
+
+    if unsafe { libc::geteuid() } != 0 {
+        if uid.is_none_or(|uid| uid == unsafe { libc::geteuid() })
+            && gid == unsafe { libc::getegid() }
+        {
+            return Ok(());
+        }
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "switching user or group requires starting as root",
+        ));
+    }
+
+    // Supplementary groups go first, since changing them needs root.
+    if unsafe { libc::setgroups(0, std::ptr::null()) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    if let Some(uid) = uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(std::io::Error::other(
+                "root privileges could not be dropped",
+            ));
+        }
+    }
+
+    match uid {
+        Some(uid) => info!("Switched to user {uid} and group {gid}"),
+        None => info!("Switched to group {gid}"),
+    }
+    Ok(())
+}
+
+/// Returns the user ID, if any, and the group ID to switch to.
+fn resolve(config: &PrivilegesConfig) -> std::io::Result<(Option<libc::uid_t>, libc::gid_t)> {
+    let user = config.user.as_deref().map(lookup_user).transpose()?;
+    let gid = match (config.group.as_deref(), user) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some((_, Some(gid)))) => gid,
+        (None, _) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "invalid privileges config: user {} has no primary group, set group",
+                    config.user.as_deref().unwrap_or_default()
+                ),
+            ));
+        }
+    };
+    Ok((user.map(|(uid, _)| uid), gid))
+}
+
+/// Returns the ID and the primary group of a user name or ID. Numeric IDs without a
+/// passwd entry have no primary group.
+fn lookup_user(user: &str) -> std::io::Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let numeric = user.parse::<libc::uid_t>().ok();
+    let name = CString::new(user)?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let ret = match numeric {
+            Some(uid) => unsafe {
+                libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result)
+            },
+            None => unsafe {
+                libc::getpwnam_r(
+                    name.as_ptr(),
+                    &mut passwd,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            },
+        };
+        match ret {
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            errno => return Err(std::io::Error::from_raw_os_error(errno)),
+        }
+    }
+    match (result.is_null(), numeric) {
+        (false, _) => Ok((passwd.pw_uid, Some(passwd.pw_gid))),
+        (true, Some(uid)) => Ok((uid, None)),
+        (true, None) => Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("invalid privileges config: user {user} not found"),
+        )),
+    }
+}
+
+/// Returns the ID of a group name or ID.
+fn lookup_group(group: &str) -> std::io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group)?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut result: *mut libc::group = std::ptr::null_mut();
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        let ret = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut result,
+            )
+        };
+        match ret {
+            0 => break,
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            errno => return Err(std::io::Error::from_raw_os_error(errno)),
+        }
+    }
+    if result.is_null() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("invalid privileges config: group {group} not found"),
+        ));
+    }
+    Ok(entry.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(user: Option<&str>, group: Option<&str>) -> PrivilegesConfig {
+        PrivilegesConfig {
+            user: user.map(str::to_string),
+            group: group.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_resolve() {
+        assert_eq!(resolve(&config(Some("root"), None)).unwrap(), (Some(0), 0));
+        assert_eq!(
+            resolve(&config(Some("0"), Some("1234"))).unwrap(),
+            (Some(0), 1234)
+        );
+        assert_eq!(resolve(&config(None, Some("1234"))).unwrap(), (None, 1234));
+        assert!(resolve(&config(Some("shoes-no-such-user"), None)).is_err());
+        assert!(resolve(&config(None, Some("shoes-no-such-group"))).is_err());
+    }
+
+    #[test]
+    fn test_validate_config() {
+        assert!(validate_config(&config(None, None)).is_err());
+        assert!(validate_config(&config(Some("root"), None)).is_ok());
+    }
+}
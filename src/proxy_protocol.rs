@@ -0,0 +1,393 @@
+//! PROXY protocol (v1 and v2) headers, as sent by load balancers such as HAProxy and
+//! nginx to pass on the addresses of the client connections they forward.
+//!
+//! Servers with `accept_proxy_protocol` read the header with [`read_header`], and run the
+//! connection within [`with_client_addresses`] so that clients with `send_proxy_protocol`
+//! can describe the same client connection in the header they send.
+
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::auto_ban;
+use crate::config::ProxyProtocolVersion;
+
+/// Time allowed for a PROXY protocol header to arrive after a connection is accepted.
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+const V1_PREFIX: &[u8; 5] = b"PROXY";
+// The longest v1 header, "PROXY UNKNOWN" followed by two IPv6 addresses and two ports.
+const V1_MAX_LENGTH: usize = 107;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_VERSION: u8 = 0x20;
+const V2_COMMAND_LOCAL: u8 = 0x00;
+const V2_COMMAND_PROXY: u8 = 0x01;
+const V2_FAMILY_UNSPEC: u8 = 0x00;
+const V2_FAMILY_TCP4: u8 = 0x11;
+const V2_FAMILY_TCP6: u8 = 0x21;
+
+/// Addresses of a client connection, as seen by the server it connected to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionAddresses {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+fn invalid_header(message: &str) -> std::io::Error {
+    auto_ban::rejected(
+        std::io::ErrorKind::InvalidData,
+        format!("invalid PROXY protocol header: {message}"),
+    )
+}
+
+/// Reads a v1 or v2 PROXY protocol header from the start of a connection, without
+/// reading any of the data that follows it.
+///
+/// Returns `None` for headers that don't carry addresses, such as the ones of health
+/// checks (v2 `LOCAL`) and of non-TCP connections (v1 `UNKNOWN`).
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Option<ConnectionAddresses>> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == V1_PREFIX {
+        read_v1_header(stream).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2_header(stream).await
+    } else {
+        Err(invalid_header("missing signature"))
+    }
+}
+
+async fn read_v1_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Option<ConnectionAddresses>> {
+    let mut line = V1_PREFIX.to_vec();
+    loop {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(invalid_header("v1 header is too long"));
+        }
+        let byte = stream.read_u8().await?;
+        line.push(byte);
+        if byte == b'\n' {
+            break;
+        }
+    }
+    parse_v1_header(&line)
+}
+
+fn parse_v1_header(line: &[u8]) -> std::io::Result<Option<ConnectionAddresses>> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| invalid_header("v1 header does not end with CRLF"))?;
+    let line = std::str::from_utf8(line).map_err(|_| invalid_header("v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        [
+            "PROXY",
+            family @ ("TCP4" | "TCP6"),
+            source_ip,
+            destination_ip,
+            source_port,
+            destination_port,
+        ] => {
+            let parse_ip = |ip: &str| -> std::io::Result<IpAddr> {
+                let ip = match *family {
+                    "TCP4" => ip.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => ip.parse::<Ipv6Addr>().map(IpAddr::V6),
+                };
+                ip.map_err(|_| invalid_header("invalid v1 address"))
+            };
+            let parse_port = |port: &str| -> std::io::Result<u16> {
+                // Ports are written without leading zeros.
+                if port.len() > 1 && port.starts_with('0') {
+                    return Err(invalid_header("invalid v1 port"));
+                }
+                port.parse().map_err(|_| invalid_header("invalid v1 port"))
+            };
+            Ok(Some(ConnectionAddresses {
+                source: SocketAddr::new(parse_ip(source_ip)?, parse_port(source_port)?),
+                destination: SocketAddr::new(
+                    parse_ip(destination_ip)?,
+                    parse_port(destination_port)?,
+                ),
+            }))
+        }
+        _ => Err(invalid_header("malformed v1 header")),
+    }
+}
+
+async fn read_v2_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> std::io::Result<Option<ConnectionAddresses>> {
+    let mut header = [0u8; 11];
+    stream.read_exact(&mut header).await?;
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid_header("missing signature"));
+    }
+    let version_command = header[7];
+    let family = header[8];
+    let length = u16::from_be_bytes([header[9], header[10]]) as usize;
+
+    // The address block may be followed by TLVs, which are skipped.
+    let mut payload = vec![0u8; length];
+    stream.read_exact(&mut payload).await?;
+
+    if version_command & 0xf0 != V2_VERSION {
+        return Err(invalid_header("unsupported version"));
+    }
+    match version_command & 0x0f {
+        V2_COMMAND_LOCAL => return Ok(None),
+        V2_COMMAND_PROXY => {}
+        _ => return Err(invalid_header("unsupported command")),
+    }
+    parse_v2_addresses(family, &payload)
+}
+
+fn parse_v2_addresses(family: u8, payload: &[u8]) -> std::io::Result<Option<ConnectionAddresses>> {
+    let (source_ip, destination_ip, ports): (IpAddr, IpAddr, &[u8]) = match family {
+        V2_FAMILY_TCP4 => {
+            if payload.len() < 12 {
+                return Err(invalid_header("truncated v2 addresses"));
+            }
+            let source: [u8; 4] = payload[0..4].try_into().unwrap();
+            let destination: [u8; 4] = payload[4..8].try_into().unwrap();
+            (source.into(), destination.into(), &payload[8..12])
+        }
+        V2_FAMILY_TCP6 => {
+            if payload.len() < 36 {
+                return Err(invalid_header("truncated v2 addresses"));
+            }
+            let source: [u8; 16] = payload[0..16].try_into().unwrap();
+            let destination: [u8; 16] = payload[16..32].try_into().unwrap();
+            (source.into(), destination.into(), &payload[32..36])
+        }
+        // UDP and Unix socket connections don't have addresses that a TCP server can use.
+        _ => return Ok(None),
+    };
+    Ok(Some(ConnectionAddresses {
+        source: SocketAddr::new(source_ip, u16::from_be_bytes([ports[0], ports[1]])),
+        destination: SocketAddr::new(destination_ip, u16::from_be_bytes([ports[2], ports[3]])),
+    }))
+}
+
+/// Returns both addresses in the same family, mapping IPv4 addresses to IPv6 when the
+/// other one is IPv6, since a header can only describe addresses of one family.
+fn same_family(addresses: &ConnectionAddresses) -> (SocketAddr, SocketAddr) {
+    let to_ipv6 = |address: SocketAddr| match address {
+        SocketAddr::V4(v4) => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        v6 => v6,
+    };
+    match (addresses.source, addresses.destination) {
+        (source @ SocketAddr::V4(_), destination @ SocketAddr::V4(_)) => (source, destination),
+        (source, destination) => (to_ipv6(source), to_ipv6(destination)),
+    }
+}
+
+/// Encodes a PROXY protocol header describing a client connection. Without addresses,
+/// the header tells the receiver to use the addresses of the connection it arrives on.
+pub fn encode_header(
+    version: ProxyProtocolVersion,
+    addresses: Option<&ConnectionAddresses>,
+) -> Vec<u8> {
+    let addresses = addresses.map(same_family);
+    match version {
+        ProxyProtocolVersion::V1 => match addresses {
+            Some((source, destination)) => {
+                let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+                format!(
+                    "PROXY {family} {} {} {} {}\r\n",
+                    source.ip(),
+                    destination.ip(),
+                    source.port(),
+                    destination.port()
+                )
+                .into_bytes()
+            }
+            None => b"PROXY UNKNOWN\r\n".to_vec(),
+        },
+        ProxyProtocolVersion::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            match addresses {
+                Some((source, destination)) => {
+                    header.push(V2_VERSION | V2_COMMAND_PROXY);
+                    let mut payload = Vec::with_capacity(36);
+                    match (source.ip(), destination.ip()) {
+                        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+                            header.push(V2_FAMILY_TCP4);
+                            payload.extend_from_slice(&source_ip.octets());
+                            payload.extend_from_slice(&destination_ip.octets());
+                        }
+                        (IpAddr::V6(source_ip), IpAddr::V6(destination_ip)) => {
+                            header.push(V2_FAMILY_TCP6);
+                            payload.extend_from_slice(&source_ip.octets());
+                            payload.extend_from_slice(&destination_ip.octets());
+                        }
+                        _ => unreachable!("addresses are in the same family"),
+                    }
+                    payload.extend_from_slice(&source.port().to_be_bytes());
+                    payload.extend_from_slice(&destination.port().to_be_bytes());
+                    header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+                    header.extend_from_slice(&payload);
+                }
+                None => {
+                    header.push(V2_VERSION | V2_COMMAND_LOCAL);
+                    header.push(V2_FAMILY_UNSPEC);
+                    header.extend_from_slice(&0u16.to_be_bytes());
+                }
+            }
+            header
+        }
+    }
+}
+
+tokio::task_local! {
+    static CLIENT_ADDRESSES: Option<ConnectionAddresses>;
+}
+
+/// Runs the handling of a client connection, making its addresses available to the
+/// outbound connections made for it through [`client_addresses`].
+pub async fn with_client_addresses<F: Future>(
+    addresses: Option<ConnectionAddresses>,
+    future: F,
+) -> F::Output {
+    CLIENT_ADDRESSES.scope(addresses, future).await
+}
+
+/// Returns the addresses of the client connection being handled, if they are known.
+pub fn client_addresses() -> Option<ConnectionAddresses> {
+    CLIENT_ADDRESSES
+        .try_with(|addresses| *addresses)
+        .ok()
+        .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addresses(source: &str, destination: &str) -> ConnectionAddresses {
+        ConnectionAddresses {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        }
+    }
+
+    async fn read(mut data: &[u8]) -> (std::io::Result<Option<ConnectionAddresses>>, Vec<u8>) {
+        let result = read_header(&mut data).await;
+        (result, data.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_header() {
+        let (result, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\nGET /").await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addresses("192.0.2.1:56324", "198.51.100.2:443"))
+        );
+        assert_eq!(rest, b"GET /");
+
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addresses("[2001:db8::1]:56324", "[2001:db8::2]:443"))
+        );
+
+        let (result, rest) = read(b"PROXY UNKNOWN\r\ndata").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"data");
+
+        assert!(
+            read(b"PROXY TCP4 2001:db8::1 192.0.2.1 1 2\r\n")
+                .await
+                .0
+                .is_err()
+        );
+        assert!(
+            read(b"PROXY TCP4 192.0.2.1 192.0.2.2 01 2\r\n")
+                .await
+                .0
+                .is_err()
+        );
+        assert!(
+            read(b"PROXY TCP4 192.0.2.1 192.0.2.2 1\r\n")
+                .await
+                .0
+                .is_err()
+        );
+        assert!(read(b"GET / HTTP/1.1\r\n").await.0.is_err());
+        assert!(read(&[b'X'; 200]).await.0.is_err());
+        let mut long = b"PROXY ".to_vec();
+        long.extend_from_slice(&[b'X'; 200]);
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_header() {
+        let mut data = V2_SIGNATURE.to_vec();
+        data.extend_from_slice(&[0x21, 0x11, 0x00, 0x10]);
+        data.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2]);
+        data.extend_from_slice(&56324u16.to_be_bytes());
+        data.extend_from_slice(&443u16.to_be_bytes());
+        // A TLV that should be skipped.
+        data.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        data.extend_from_slice(b"data");
+        let (result, rest) = read(&data).await;
+        assert_eq!(
+            result.unwrap(),
+            Some(addresses("192.0.2.1:56324", "198.51.100.2:443"))
+        );
+        assert_eq!(rest, b"data");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend_from_slice(&[0x20, 0x00, 0x00, 0x00]);
+        assert_eq!(read(&local).await.0.unwrap(), None);
+
+        let mut bad_version = V2_SIGNATURE.to_vec();
+        bad_version.extend_from_slice(&[0x11, 0x11, 0x00, 0x00]);
+        assert!(read(&bad_version).await.0.is_err());
+
+        let mut truncated = V2_SIGNATURE.to_vec();
+        truncated.extend_from_slice(&[0x21, 0x21, 0x00, 0x04, 0, 0, 0, 0]);
+        assert!(read(&truncated).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_encode_header() {
+        let ipv4 = addresses("192.0.2.1:56324", "198.51.100.2:443");
+        assert_eq!(
+            encode_header(ProxyProtocolVersion::V1, Some(&ipv4)),
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 56324 443\r\n"
+        );
+        assert_eq!(
+            encode_header(ProxyProtocolVersion::V1, None),
+            b"PROXY UNKNOWN\r\n"
+        );
+
+        let mixed = addresses("192.0.2.1:56324", "[2001:db8::2]:443");
+        assert_eq!(
+            encode_header(ProxyProtocolVersion::V1, Some(&mixed)),
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 56324 443\r\n"
+        );
+
+        for version in [ProxyProtocolVersion::V1, ProxyProtocolVersion::V2] {
+            for connection in [ipv4, addresses("[2001:db8::1]:1", "[2001:db8::2]:2")] {
+                let header = encode_header(version, Some(&connection));
+                assert_eq!(read(&header).await.0.unwrap(), Some(connection));
+            }
+            assert_eq!(read(&encode_header(version, None)).await.0.unwrap(), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_addresses() {
+        assert_eq!(client_addresses(), None);
+        let connection = addresses("192.0.2.1:56324", "198.51.100.2:443");
+        let inner = with_client_addresses(Some(connection), async { client_addresses() }).await;
+        assert_eq!(inner, Some(connection));
+    }
+}
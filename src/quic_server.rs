@@ -8,29 +8,49 @@ use tokio::io::AsyncWriteExt;
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
+use crate::access_control::{AccessControl, admit_quic};
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::ConnectDecision;
 use crate::config::{
-    BindLocation, ConfigSelection, ServerConfig, ServerProxyConfig, ServerQuicConfig,
+    BindLocation, ConfigSelection, Hysteria2ObfsConfig, ServerConfig, ServerProxyConfig,
+    ServerQuicConfig, resolve_hysteria2_bandwidth,
 };
-use crate::copy_bidirectional::copy_bidirectional;
+use crate::connection_limit::ConnectionLimiter;
+use crate::copy_bidirectional::{CopyTimeouts, copy_bidirectional_with_counters};
+use crate::hooks::HookedConnection;
+use crate::http_handler::create_http_auth_token;
+use crate::hysteria2_obfs::Salamander;
+use crate::hysteria2_server::Hysteria2ServerBandwidth;
+use crate::metrics;
 use crate::quic_stream::QuicStream;
+use crate::rate_limit::BandwidthLimit;
 use crate::resolver::Resolver;
+use crate::reverse::find_portal;
 use crate::routing::{ServerStream, run_udp_routing};
 use crate::rustls_config_util::create_server_config;
 use crate::socket_util::new_socket2_udp_socket;
+use crate::systemd;
 use crate::tcp::tcp_client_handler_factory::create_tcp_client_proxy_selector;
 use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
-use crate::tcp::tcp_server::{run_udp_copy, setup_client_tcp_stream};
+use crate::tcp::tcp_server::{
+    ForwardSession, InboundContext, Outbound, run_udp_copy, setup_client_tcp_stream,
+};
 use crate::tcp::tcp_server_handler_factory::create_tcp_server_handler;
-use crate::uuid_util::parse_uuid;
+use crate::user_store::with_authenticated_user;
 
+#[allow(clippy::too_many_arguments)]
 async fn start_quic_server(
     bind_address: SocketAddr,
     quic_server_config: Arc<quinn::crypto::rustls::QuicServerConfig>,
     resolver: Arc<dyn Resolver>,
     server_handler: Arc<dyn TcpServerHandler>,
+    protocol: Arc<str>,
     num_endpoints: usize,
+    rate_limit: Option<Arc<BandwidthLimit>>,
+    connection_limiter: Arc<ConnectionLimiter>,
+    session_timeouts: CopyTimeouts,
+    access_control: Option<Arc<AccessControl>>,
 ) -> std::io::Result<Vec<JoinHandle<()>>> {
     // TODO: consider setting transport config
     //   Arc::get_mut(&mut server_config.transport)
@@ -40,6 +60,8 @@ async fn start_quic_server(
     //     .keep_alive_interval(Some(Duration::from_secs(15)))
     //     .max_idle_timeout(Some(Duration::from_secs(30).try_into().unwrap()));
 
+    let listener_label: Arc<str> = Arc::from(format!("quic://{bind_address}"));
+
     let mut join_handles = vec![];
     for _ in 0..num_endpoints {
         let server_config = quinn::ServerConfig::with_crypto(quic_server_config.clone());
@@ -56,12 +78,48 @@ async fn start_quic_server(
 
         let resolver = resolver.clone();
         let server_handler = server_handler.clone();
+        let listener_label = listener_label.clone();
+        let protocol = protocol.clone();
+        let rate_limit = rate_limit.clone();
+        let connection_limiter = connection_limiter.clone();
+        let access_control = access_control.clone();
         let join_handle = tokio::spawn(async move {
-            while let Some(conn) = endpoint.accept().await {
+            loop {
+                let reserved = connection_limiter.ready().await;
+                let Some(conn) = endpoint.accept().await else {
+                    break;
+                };
+                let Some(conn) = admit_quic(access_control.as_deref(), conn) else {
+                    continue;
+                };
+                let Some(permit) =
+                    connection_limiter.admit(reserved, metrics::listener(&listener_label))
+                else {
+                    debug!(
+                        "Refused {}, the listener is at its connection limit",
+                        conn.remote_address()
+                    );
+                    conn.refuse();
+                    continue;
+                };
                 let resolver = resolver.clone();
                 let server_handler = server_handler.clone();
+                let listener_label = listener_label.clone();
+                let protocol = protocol.clone();
+                let rate_limit = rate_limit.clone();
                 tokio::spawn(async move {
-                    if let Err(e) = process_connection(resolver, server_handler, conn).await {
+                    let _permit = permit;
+                    if let Err(e) = process_connection(
+                        resolver,
+                        server_handler,
+                        listener_label,
+                        protocol,
+                        rate_limit,
+                        session_timeouts,
+                        conn,
+                    )
+                    .await
+                    {
                         error!("Connection ended with error: {e}");
                     }
                 });
@@ -77,9 +135,33 @@ async fn start_quic_server(
 async fn process_connection(
     resolver: Arc<dyn Resolver>,
     server_handler: Arc<dyn TcpServerHandler>,
+    listener_label: Arc<str>,
+    protocol: Arc<str>,
+    rate_limit: Option<Arc<BandwidthLimit>>,
+    session_timeouts: CopyTimeouts,
     conn: quinn::Incoming,
 ) -> std::io::Result<()> {
-    let connection = conn.await?;
+    let remote_address = conn.remote_address();
+    let connection = match conn.await {
+        Ok(c) => c,
+        Err(e) => {
+            if let Some(m) = metrics::listener(&listener_label) {
+                m.handshake_failures.inc();
+            }
+            // Failed TLS handshakes, unlike timeouts and resets.
+            if matches!(e, quinn::ConnectionError::TransportError(_)) {
+                auto_ban::record_failure(remote_address.ip());
+            }
+            return Err(e.into());
+        }
+    };
+    let inbound = InboundContext::new(
+        listener_label,
+        protocol,
+        Some(connection.remote_address()),
+        rate_limit,
+    )
+    .with_session_timeouts(session_timeouts);
 
     loop {
         let stream = match connection.accept_bi().await {
@@ -94,8 +176,11 @@ async fn process_connection(
         };
         let cloned_resolver = resolver.clone();
         let cloned_handler = server_handler.clone();
+        let cloned_inbound = inbound.clone();
         tokio::spawn(async move {
-            if let Err(e) = process_streams(cloned_resolver, cloned_handler, stream).await {
+            if let Err(e) =
+                process_streams(cloned_resolver, cloned_handler, cloned_inbound, stream).await
+            {
                 error!("Failed to process streams: {e}");
             }
         });
@@ -107,8 +192,28 @@ async fn process_connection(
 async fn process_streams(
     resolver: Arc<dyn Resolver>,
     server_handler: Arc<dyn TcpServerHandler>,
+    mut inbound: InboundContext,
+    stream: (quinn::SendStream, quinn::RecvStream),
+) -> std::io::Result<()> {
+    inbound.hooks = HookedConnection::new(&inbound.listener, &inbound.protocol, inbound.source);
+    match inbound.hooks.clone() {
+        Some(hooks) => {
+            hooks
+                .run(forward_streams(resolver, server_handler, inbound, stream))
+                .await
+        }
+        None => forward_streams(resolver, server_handler, inbound, stream).await,
+    }
+}
+
+async fn forward_streams(
+    resolver: Arc<dyn Resolver>,
+    server_handler: Arc<dyn TcpServerHandler>,
+    mut inbound: InboundContext,
     (send, recv): (quinn::SendStream, quinn::RecvStream),
 ) -> std::io::Result<()> {
+    let _session_guard = inbound.metrics.as_ref().map(|m| m.start_session());
+
     let quic_stream: Box<dyn AsyncStream> = Box::new(QuicStream::from(send, recv));
 
     let setup_server_stream_future = timeout(
@@ -116,22 +221,34 @@ async fn process_streams(
         server_handler.setup_server_stream(quic_stream),
     );
 
-    let setup_result = match setup_server_stream_future.await {
+    let (setup_result, user) = with_authenticated_user(setup_server_stream_future).await;
+    inbound.user = user;
+
+    let setup_result = match setup_result {
         Ok(Ok(r)) => r,
         Ok(Err(e)) => {
+            inbound.record_handshake_failure(&e);
             return Err(std::io::Error::new(
                 e.kind(),
                 format!("failed to setup server stream: {e}"),
             ));
         }
         Err(elapsed) => {
-            return Err(std::io::Error::new(
+            let e = std::io::Error::new(
                 std::io::ErrorKind::TimedOut,
                 format!("server setup timed out: {elapsed}"),
-            ));
+            );
+            inbound.record_handshake_failure(&e);
+            return Err(e);
         }
     };
 
+    if let Some(hooks) = &inbound.hooks {
+        hooks
+            .authenticate(inbound.user.as_deref(), setup_result.remote_location())
+            .await?;
+    }
+
     match setup_result {
         TcpServerSetupResult::TcpForward {
             remote_location,
@@ -141,6 +258,17 @@ async fn process_streams(
             connection_success_response,
             initial_remote_data,
         } => {
+            // Connections of reverse proxy bridges become tunnels of their portal.
+            if let Some(portal) = find_portal(&remote_location) {
+                return portal
+                    .accept_tunnel(
+                        server_stream,
+                        connection_success_response,
+                        initial_remote_data,
+                    )
+                    .await;
+            }
+
             let setup_client_stream_future = timeout(
                 Duration::from_secs(60),
                 setup_client_tcp_stream(
@@ -148,10 +276,11 @@ async fn process_streams(
                     proxy_selector,
                     resolver,
                     remote_location.clone(),
+                    inbound.source.map(|addr| addr.ip()),
                 ),
             );
 
-            let mut client_stream = match setup_client_stream_future.await {
+            let (mut client_stream, outbound) = match setup_client_stream_future.await {
                 Ok(Ok(Some(s))) => s,
                 Ok(Ok(None)) => {
                     // Must have been blocked.
@@ -174,6 +303,8 @@ async fn process_streams(
                 }
             };
 
+            let session = ForwardSession::start("tcp", &inbound, &remote_location, &outbound);
+
             if let Some(data) = connection_success_response {
                 server_stream.write_all(&data).await?;
                 // server_need_initial_flush should be set to true by the handler if
@@ -183,18 +314,25 @@ async fn process_streams(
             let client_need_initial_flush = match initial_remote_data {
                 Some(data) => {
                     client_stream.write_all(&data).await?;
+                    session.up_counter.add(data.len());
                     true
                 }
                 None => false,
             };
 
-            let copy_result = copy_bidirectional(
-                &mut server_stream,
-                &mut client_stream,
-                server_need_initial_flush,
-                client_need_initial_flush,
-            )
-            .await;
+            let copy_result = session
+                .run(copy_bidirectional_with_counters(
+                    &mut server_stream,
+                    &mut client_stream,
+                    server_need_initial_flush,
+                    client_need_initial_flush,
+                    session.up_counter.clone(),
+                    session.down_counter.clone(),
+                    session.up_limiter.clone(),
+                    session.down_limiter.clone(),
+                    inbound.session_timeouts,
+                ))
+                .await;
 
             let (_, _) = futures::join!(server_stream.shutdown(), client_stream.shutdown());
 
@@ -207,23 +345,41 @@ async fn process_streams(
             need_initial_flush: server_need_initial_flush,
             proxy_selector,
         } => {
-            let action = proxy_selector.judge(remote_location.into(), &resolver).await?;
+            let source = inbound.source.map(|addr| addr.ip());
+            let action = proxy_selector
+                .judge_with_source(remote_location.into(), source, &resolver)
+                .await?;
             match action {
                 ConnectDecision::Allow {
                     chain_group,
                     remote_location,
                 } => {
+                    let destination = remote_location.location().clone();
                     let client_stream = chain_group
-                        .connect_udp_bidirectional(&resolver, remote_location)
+                        .connect_udp_bidirectional_with_source(&resolver, remote_location, source)
                         .await?;
 
-                    run_udp_copy(
-                        server_stream,
-                        client_stream,
-                        server_need_initial_flush,
-                        false,
-                    )
-                    .await
+                    let outbound = Outbound::new(chain_group);
+                    if let Some(m) = &inbound.metrics {
+                        m.udp_sessions.inc();
+                    }
+                    if let Some(m) = &outbound.metrics {
+                        m.udp_sessions.inc();
+                    }
+                    let session = ForwardSession::start("udp", &inbound, &destination, &outbound);
+
+                    session
+                        .run(run_udp_copy(
+                            server_stream,
+                            client_stream,
+                            server_need_initial_flush,
+                            false,
+                            session.up_counter.clone(),
+                            session.down_counter.clone(),
+                            session.up_limiter.clone(),
+                            session.down_limiter.clone(),
+                        ))
+                        .await
                 }
                 ConnectDecision::Block => Ok(()),
             }
@@ -233,12 +389,19 @@ async fn process_streams(
             need_initial_flush,
             proxy_selector,
         } => {
+            if let Some(m) = &inbound.metrics {
+                m.udp_sessions.inc();
+            }
             // Routes each packet based on its destination
+            let (up_limiter, down_limiter) = inbound.rate_limiters();
             run_udp_routing(
                 ServerStream::Targeted(server_stream),
                 proxy_selector,
                 resolver,
+                inbound.source.map(|addr| addr.ip()),
                 need_initial_flush,
+                up_limiter,
+                down_limiter,
             )
             .await
         }
@@ -247,12 +410,19 @@ async fn process_streams(
             need_initial_flush,
             proxy_selector,
         } => {
+            if let Some(m) = &inbound.metrics {
+                m.udp_sessions.inc();
+            }
             // Routes each session based on its destination
+            let (up_limiter, down_limiter) = inbound.rate_limiters();
             run_udp_routing(
                 ServerStream::Session(server_stream),
                 proxy_selector,
                 resolver,
+                inbound.source.map(|addr| addr.ip()),
                 need_initial_flush,
+                up_limiter,
+                down_limiter,
             )
             .await
         }
@@ -272,18 +442,28 @@ pub async fn start_quic_servers(
         quic_settings,
         protocol,
         rules,
+        rate_limit,
+        connection_limit,
+        session_timeouts,
+        access_control,
         ..
     } = config;
 
     println!("Starting {} QUIC server at {}", &protocol, &bind_location);
 
+    // Validated when loading config
+    let access_control = access_control
+        .map(|config| Arc::new(AccessControl::new(&config).expect("Invalid access control")));
+
     let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
     // A direct entry must always exist
     assert!(!rules.is_empty());
 
     let bind_addresses = match bind_location {
         // TODO: switch to non-blocking resolve?
-        BindLocation::Address(a) => a.to_socket_addrs()?,
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            bind_location.to_socket_addrs()?
+        }
         BindLocation::Path(_) => {
             return Err(std::io::Error::other(
                 "Cannot listen on path, QUIC does not have unix domain socket support",
@@ -299,6 +479,16 @@ pub async fn start_quic_servers(
         client_fingerprints,
         num_endpoints,
     } = quic_settings.unwrap();
+    // Endpoints can't share a socket passed by systemd, since each reads all of its
+    // packets.
+    let num_endpoints = if bind_addresses
+        .iter()
+        .any(|address| systemd::has_socket(*address, socket2::Type::DGRAM))
+    {
+        1
+    } else {
+        num_endpoints
+    };
 
     // Certificates are already embedded as PEM data during config validation
     let cert_bytes = cert.as_bytes().to_vec();
@@ -315,6 +505,7 @@ pub async fn start_quic_servers(
         processed_ca_certs,
         &alpn_protocols.into_vec(),
         &client_fingerprints.into_vec(),
+        None,
     ));
 
     let quic_server_config: quinn::crypto::rustls::QuicServerConfig =
@@ -322,8 +513,7 @@ pub async fn start_quic_servers(
 
     let quic_server_config = Arc::new(quic_server_config);
 
-    let client_proxy_selector =
-        Arc::new(create_tcp_client_proxy_selector(rules.clone(), resolver.clone()));
+    let client_proxy_selector = create_tcp_client_proxy_selector(rules.clone(), resolver.clone());
 
     let mut handles = vec![];
 
@@ -331,9 +521,24 @@ pub async fn start_quic_servers(
         ServerProxyConfig::Hysteria2 {
             password,
             udp_enabled,
+            bandwidth,
+            ignore_client_bandwidth,
+            obfs,
         } => {
             // TODO: hash password instead of passing directly
             let hysteria2_password: &'static str = Box::leak(password.into_boxed_str());
+            let (max_tx, max_rx) = resolve_hysteria2_bandwidth(&bandwidth)?;
+            let bandwidth = Hysteria2ServerBandwidth {
+                max_tx,
+                max_rx,
+                ignore_client_bandwidth,
+            };
+            let salamander = match obfs {
+                Some(Hysteria2ObfsConfig::Salamander { password }) => {
+                    Some(Arc::new(Salamander::new(&password)?))
+                }
+                None => None,
+            };
 
             for bind_address in bind_addresses.into_iter() {
                 let quic_server_config = quic_server_config.clone();
@@ -347,6 +552,9 @@ pub async fn start_quic_servers(
                     resolver,
                     num_endpoints,
                     udp_enabled,
+                    bandwidth,
+                    salamander.clone(),
+                    access_control.clone(),
                 )
                 .await?;
                 handles.extend(hysteria2_handles);
@@ -356,9 +564,13 @@ pub async fn start_quic_servers(
             uuid,
             password,
             zero_rtt_handshake,
+            user_group,
         } => {
-            let uuid: &'static [u8] = Box::leak(parse_uuid(&uuid)?.into_boxed_slice());
-            let password: &'static str = Box::leak(password.into_boxed_str());
+            let users = Arc::new(crate::tuic_server::create_tuic_users(
+                &uuid,
+                &password,
+                user_group.as_deref(),
+            ));
             for bind_address in bind_addresses.into_iter() {
                 let quic_server_config = quic_server_config.clone();
                 let client_proxy_selector = client_proxy_selector.clone();
@@ -366,19 +578,74 @@ pub async fn start_quic_servers(
                 let tuic_handles = crate::tuic_server::start_tuic_server(
                     bind_address,
                     quic_server_config,
-                    uuid,
-                    password,
+                    users.clone(),
                     client_proxy_selector,
                     resolver,
                     num_endpoints,
                     zero_rtt_handshake,
+                    access_control.clone(),
                 )
                 .await?;
                 handles.extend(tuic_handles);
             }
         }
+        ServerProxyConfig::Masque { username, password } => {
+            let auth_token = match (username, password) {
+                (None, None) => None,
+                (username, password) => Some(create_http_auth_token(
+                    &username.unwrap_or_default(),
+                    &password.unwrap_or_default(),
+                )),
+            };
+            for bind_address in bind_addresses.into_iter() {
+                let masque_handles = crate::masque::start_masque_server(
+                    bind_address,
+                    quic_server_config.clone(),
+                    auth_token.clone(),
+                    client_proxy_selector.clone(),
+                    resolver.clone(),
+                    num_endpoints,
+                    access_control.clone(),
+                )
+                .await?;
+                handles.extend(masque_handles);
+            }
+        }
+        ServerProxyConfig::Juicity {
+            uuid,
+            password,
+            user_group,
+        } => {
+            let users = Arc::new(crate::tuic_server::create_tuic_users(
+                &uuid,
+                &password,
+                user_group.as_deref(),
+            ));
+            for bind_address in bind_addresses.into_iter() {
+                let juicity_handles = crate::juicity::start_juicity_server(
+                    bind_address,
+                    quic_server_config.clone(),
+                    users.clone(),
+                    client_proxy_selector.clone(),
+                    resolver.clone(),
+                    num_endpoints,
+                    access_control.clone(),
+                )
+                .await?;
+                handles.extend(juicity_handles);
+            }
+        }
         tcp_protocol => {
             let bind_ip = bind_addresses.first().map(|addr| addr.ip());
+            // Validated when loading config
+            let rate_limit = rate_limit
+                .map(|config| Arc::new(BandwidthLimit::new(&config).expect("Invalid rate limit")));
+            let connection_limiter = Arc::new(ConnectionLimiter::new(connection_limit));
+            let session_timeouts = session_timeouts
+                .as_ref()
+                .map(CopyTimeouts::from)
+                .unwrap_or_default();
+            let protocol_name: Arc<str> = Arc::from(tcp_protocol.to_string());
 
             let tcp_handler: Arc<dyn TcpServerHandler> =
                 create_tcp_server_handler(tcp_protocol, &client_proxy_selector, &resolver, bind_ip)
@@ -393,7 +660,12 @@ pub async fn start_quic_servers(
                     quic_server_config,
                     resolver,
                     tcp_handler,
+                    protocol_name.clone(),
                     num_endpoints,
+                    rate_limit.clone(),
+                    connection_limiter.clone(),
+                    session_timeouts,
+                    access_control.clone(),
                 )
                 .await?;
 
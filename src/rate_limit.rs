@@ -0,0 +1,242 @@
+//! Bandwidth limits of servers and users.
+//!
+//! Limits are token buckets that are shared by every connection of a server or user.
+//! Copy loops take tokens for the bytes they read, and stop reading until the bucket
+//! refills when it runs out.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use tokio::time::{Instant, Sleep};
+
+use crate::config::RateLimitConfig;
+use crate::user_store::user_stores;
+
+/// A token bucket that holds up to a second of traffic.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Bytes per second.
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Negative when more bytes were taken than were available.
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+        Self {
+            rate,
+            state: Mutex::new(BucketState {
+                tokens: rate,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `n` bytes, and returns how long to wait until the bucket is no longer in
+    /// debt.
+    pub fn take(&self, n: usize) -> Duration {
+        self.take_at(n, Instant::now())
+    }
+
+    fn take_at(&self, n: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock();
+        let elapsed = now
+            .saturating_duration_since(state.last_refill)
+            .as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+        state.last_refill = now;
+        state.tokens -= n as f64;
+        if state.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.tokens / self.rate)
+        }
+    }
+}
+
+/// The buckets that traffic in one direction of a session is taken from.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter(Vec<Arc<TokenBucket>>);
+
+impl RateLimiter {
+    pub fn push(&mut self, bucket: Arc<TokenBucket>) {
+        self.0.push(bucket);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Takes `n` bytes from every bucket, and returns the longest wait.
+    pub fn take(&self, n: usize) -> Duration {
+        self.0
+            .iter()
+            .map(|bucket| bucket.take(n))
+            .max()
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+/// Delays a copy loop while its limiter is in debt.
+#[derive(Debug, Default)]
+pub struct Throttle {
+    limiter: RateLimiter,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl Throttle {
+    pub fn new(limiter: RateLimiter) -> Self {
+        Self {
+            limiter,
+            delay: None,
+        }
+    }
+
+    /// Returns Ready when the next read may be done.
+    pub fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            futures::ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Takes `n` bytes that were read.
+    #[inline]
+    pub fn consume(&mut self, n: usize) {
+        if self.limiter.is_empty() {
+            return;
+        }
+        let wait = self.limiter.take(n);
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+    }
+}
+
+/// The buckets of a server or user.
+#[derive(Debug)]
+pub struct BandwidthLimit {
+    config: RateLimitConfig,
+    up: Option<Arc<TokenBucket>>,
+    down: Option<Arc<TokenBucket>>,
+}
+
+impl BandwidthLimit {
+    pub fn new(config: &RateLimitConfig) -> std::io::Result<Self> {
+        let (up, down) = config.parse()?;
+        Ok(Self {
+            config: config.clone(),
+            up: up.map(|rate| Arc::new(TokenBucket::new(rate))),
+            down: down.map(|rate| Arc::new(TokenBucket::new(rate))),
+        })
+    }
+
+    /// Adds the buckets to the (up, down) limiters of a session.
+    pub fn add_to(&self, up: &mut RateLimiter, down: &mut RateLimiter) {
+        if let Some(bucket) = &self.up {
+            up.push(bucket.clone());
+        }
+        if let Some(bucket) = &self.down {
+            down.push(bucket.clone());
+        }
+    }
+}
+
+static USER_LIMITS: LazyLock<Mutex<HashMap<String, Arc<BandwidthLimit>>>> =
+    LazyLock::new(Default::default);
+
+/// Returns the limit of a user of a user group, or None if the user has no limit.
+///
+/// Connections of the user share the limit until its `rate_limit` is changed.
+pub fn user_limit(name: &str) -> Option<Arc<BandwidthLimit>> {
+    let config = user_stores().into_iter().find_map(|(_, store)| {
+        store
+            .users()
+            .iter()
+            .find(|user| user.name == name)
+            .and_then(|user| user.rate_limit.clone())
+    })?;
+    let mut limits = USER_LIMITS.lock();
+    if let Some(limit) = limits.get(name)
+        && limit.config == config
+    {
+        return Some(limit.clone());
+    }
+    // Validated when the user was added.
+    let limit = Arc::new(BandwidthLimit::new(&config).ok()?);
+    limits.insert(name.to_string(), limit.clone());
+    Some(limit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(1000);
+        let start = bucket.state.lock().last_refill;
+        assert_eq!(bucket.take_at(1000, start), Duration::ZERO);
+        assert_eq!(bucket.take_at(500, start), Duration::from_millis(500));
+
+        let now = start + Duration::from_millis(500);
+        assert_eq!(bucket.take_at(0, now), Duration::ZERO);
+
+        // The bucket doesn't fill beyond a second of traffic.
+        let now = now + Duration::from_secs(10);
+        assert_eq!(bucket.take_at(2000, now), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_rate_limiter_takes_longest_wait() {
+        let mut limiter = RateLimiter::default();
+        assert_eq!(limiter.take(1_000_000), Duration::ZERO);
+
+        limiter.push(Arc::new(TokenBucket::new(1_000_000)));
+        limiter.push(Arc::new(TokenBucket::new(1000)));
+        let wait = limiter.take(2000);
+        assert!(wait > Duration::from_millis(900) && wait <= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_throttle() {
+        let mut limiter = RateLimiter::default();
+        limiter.push(Arc::new(TokenBucket::new(10_000)));
+        let mut throttle = Throttle::new(limiter);
+
+        throttle.consume(10_000);
+        assert!(throttle.delay.is_none());
+        throttle.consume(1000);
+        let start = Instant::now();
+        std::future::poll_fn(|cx| throttle.poll_ready(cx)).await;
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(throttle.delay.is_none());
+    }
+
+    #[test]
+    fn test_bandwidth_limit() {
+        let limit = BandwidthLimit::new(&RateLimitConfig {
+            up: Some("8kbps".to_string()),
+            down: None,
+        })
+        .unwrap();
+        let mut up = RateLimiter::default();
+        let mut down = RateLimiter::default();
+        limit.add_to(&mut up, &mut down);
+        assert_eq!(up.0.len(), 1);
+        assert!(down.is_empty());
+    }
+}
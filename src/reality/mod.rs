@@ -21,7 +21,9 @@ mod reality_util;
 pub use reality_cipher_suite::{CipherSuite, DEFAULT_CIPHER_SUITES};
 // generate_keypair is used by binary, not by library's public API
 #[allow(unused_imports)]
-pub use reality_util::{decode_private_key, decode_public_key, decode_short_id, generate_keypair};
+pub use reality_util::{
+    decode_private_key, decode_public_key, decode_short_id, derive_public_key, generate_keypair,
+};
 
 // Re-exports for crypto_connection module
 pub use reality_client_connection::{
@@ -31,6 +31,7 @@ use super::reality_tls13_messages::{
 };
 use super::reality_util::{extract_server_cipher_suite, extract_server_public_key};
 use crate::slide_buffer::SlideBuffer;
+use crate::tls_fingerprint::TlsFingerprint;
 use crate::util::allocate_vec;
 
 /// Configuration for REALITY client connections
@@ -44,6 +45,8 @@ pub struct RealityClientConfig {
     pub server_name: String,
     /// Supported TLS 1.3 cipher suites (empty = use defaults)
     pub cipher_suites: Vec<CipherSuite>,
+    /// Browser whose ClientHello is imitated (None = minimal ClientHello)
+    pub fingerprint: Option<TlsFingerprint>,
 }
 
 /// Handshake state machine for REALITY client
@@ -190,14 +193,24 @@ impl RealityClientConnection {
             self.config.cipher_suites.clone()
         };
         let cipher_suite_ids: Vec<u16> = cipher_suites.iter().map(|cs| cs.id()).collect();
-        let mut client_hello = construct_client_hello(
-            &client_random,
-            &session_id_for_hello,
-            our_public_key_bytes.as_ref(),
-            &self.config.server_name,
-            &cipher_suite_ids,
-            DEFAULT_ALPN_PROTOCOLS,
-        )?;
+        let mut client_hello = match self.config.fingerprint {
+            Some(fingerprint) => fingerprint.client_hello(
+                &client_random,
+                &session_id_for_hello,
+                our_public_key_bytes.as_ref(),
+                &self.config.server_name,
+                &cipher_suite_ids,
+                DEFAULT_ALPN_PROTOCOLS,
+            ),
+            None => construct_client_hello(
+                &client_random,
+                &session_id_for_hello,
+                our_public_key_bytes.as_ref(),
+                &self.config.server_name,
+                &cipher_suite_ids,
+                DEFAULT_ALPN_PROTOCOLS,
+            )?,
+        };
 
         // Now encrypt the SessionId using the ClientHello with zeroed SessionId as AAD
         // Use slice directly from client_random to avoid copying
@@ -458,6 +458,18 @@ pub fn negotiate_cipher_suite(
     None
 }
 
+/// Derives the base64url-encoded public key of a base64url-encoded private key
+pub fn derive_public_key(private_key: &str) -> std::io::Result<String> {
+    let private_key_bytes = decode_private_key(private_key)?;
+    let private_key =
+        agreement::PrivateKey::from_private_key(&agreement::X25519, &private_key_bytes)
+            .map_err(|_| std::io::Error::other("Failed to create X25519 key"))?;
+    let public_key_bytes = private_key
+        .compute_public_key()
+        .map_err(|_| std::io::Error::other("Failed to compute public key"))?;
+    Ok(URL_SAFE_NO_PAD.encode(public_key_bytes.as_ref()))
+}
+
 pub fn generate_keypair() -> std::io::Result<(String, String)> {
     // Generate 32 random bytes for private key
     let mut private_key_bytes = [0u8; 32];
@@ -503,6 +515,13 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_derive_public_key() {
+        let (private_key, public_key) = generate_keypair().unwrap();
+        assert_eq!(derive_public_key(&private_key).unwrap(), public_key);
+        assert!(derive_public_key("invalid").is_err());
+    }
+
     #[test]
     fn test_extract_client_random() {
         // Create a minimal ClientHello with random
@@ -531,6 +550,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_fingerprinted_client_hello() {
+        use crate::tls_fingerprint::TlsFingerprint;
+
+        for fingerprint in [
+            TlsFingerprint::Chrome,
+            TlsFingerprint::Firefox,
+            TlsFingerprint::Safari,
+            TlsFingerprint::Random,
+        ] {
+            let hello = fingerprint.client_hello(
+                &[0x11; 32],
+                &[0x22; 32],
+                &[0x33; 32],
+                "www.example.com",
+                &[0x1302],
+                &["h2", "http/1.1"],
+            );
+            let mut record = vec![0x16, 0x03, 0x01];
+            record.extend_from_slice(&(hello.len() as u16).to_be_bytes());
+            record.extend_from_slice(&hello);
+
+            assert_eq!(extract_client_random(&record).unwrap(), [0x11; 32]);
+            assert_eq!(extract_session_id_slice(&record).unwrap(), &[0x22; 32]);
+            assert_eq!(extract_client_public_key(&record).unwrap(), [0x33; 32]);
+            let cipher_suites = extract_client_cipher_suites(&record).unwrap();
+            assert_eq!(
+                negotiate_cipher_suite(crate::reality::DEFAULT_CIPHER_SUITES, &cipher_suites),
+                Some(CipherSuite::AES_256_GCM_SHA384)
+            );
+        }
+    }
+
     #[test]
     fn test_decode_public_key() {
         use base64::engine::{Engine as _, general_purpose::URL_SAFE_NO_PAD};
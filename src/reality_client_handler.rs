@@ -11,6 +11,7 @@ use crate::async_stream::AsyncStream;
 use crate::crypto::{CryptoConnection, CryptoTlsStream, perform_crypto_handshake};
 use crate::reality::{CipherSuite, RealityClientConfig, RealityClientConnection};
 use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
+use crate::tls_fingerprint::TlsFingerprint;
 
 /// REALITY client handler using buffered Connection API
 ///
@@ -22,13 +23,18 @@ pub struct RealityClientHandler {
     short_id: [u8; 8],
     server_name: rustls::pki_types::ServerName<'static>,
     cipher_suites: Vec<CipherSuite>,
+    fingerprint: Option<TlsFingerprint>,
     handler: RealityInnerClientHandler,
 }
 
 #[derive(Debug)]
 pub enum RealityInnerClientHandler {
     Default(Box<dyn TcpClientHandler>),
-    VisionVless { uuid: Box<[u8]>, udp_enabled: bool },
+    VisionVless {
+        uuid: Box<[u8]>,
+        udp_enabled: bool,
+        xudp: bool,
+    },
 }
 
 impl RealityClientHandler {
@@ -37,6 +43,7 @@ impl RealityClientHandler {
         short_id: [u8; 8],
         server_name: rustls::pki_types::ServerName<'static>,
         cipher_suites: Vec<CipherSuite>,
+        fingerprint: Option<TlsFingerprint>,
         handler: Box<dyn TcpClientHandler>,
     ) -> Self {
         Self {
@@ -44,6 +51,7 @@ impl RealityClientHandler {
             short_id,
             server_name,
             cipher_suites,
+            fingerprint,
             handler: RealityInnerClientHandler::Default(handler),
         }
     }
@@ -52,17 +60,21 @@ impl RealityClientHandler {
         short_id: [u8; 8],
         server_name: rustls::pki_types::ServerName<'static>,
         cipher_suites: Vec<CipherSuite>,
+        fingerprint: Option<TlsFingerprint>,
         user_id: Box<[u8]>,
         udp_enabled: bool,
+        xudp: bool,
     ) -> Self {
         Self {
             public_key,
             short_id,
             server_name,
             cipher_suites,
+            fingerprint,
             handler: RealityInnerClientHandler::VisionVless {
                 uuid: user_id,
                 udp_enabled,
+                xudp,
             },
         }
     }
@@ -93,6 +105,7 @@ impl RealityClientHandler {
             short_id: self.short_id,
             server_name: server_name_str.to_string(),
             cipher_suites: self.cipher_suites.clone(),
+            fingerprint: self.fingerprint,
         };
 
         let reality_conn = RealityClientConnection::new(reality_config)?;
@@ -153,11 +166,12 @@ impl TcpClientHandler for RealityClientHandler {
                     .setup_client_udp_bidirectional(Box::new(tls_stream), target)
                     .await
             }
-            RealityInnerClientHandler::VisionVless { uuid, .. } => {
+            RealityInnerClientHandler::VisionVless { uuid, xudp, .. } => {
                 crate::vless::vless_client_handler::setup_vless_udp_bidirectional(
                     tls_stream,
                     uuid,
                     target.into_location(),
+                    *xudp,
                 )
                 .await
             }
@@ -0,0 +1,571 @@
+//! Hot configuration reload.
+//!
+//! A reload re-reads the config files and diff-applies the result to the running servers:
+//! - Servers that are new in the config are started.
+//! - Servers that were removed stop accepting connections. Established connections are
+//!   not interrupted and finish on their own.
+//! - TCP servers that are still bound to the same location keep their listening sockets.
+//!   New connections use the updated protocol and rules, while established connections
+//!   keep the handler they were accepted with.
+//! - QUIC, UDP, TPROXY, REDIRECT, TUN, metrics and Clash API servers, and reverse proxy
+//!   bridges, are restarted only if their config changed.
+//! - GeoIP and geosite databases are reopened on their next use, so that updated files are
+//!   picked up.
+//! - Fake-IP mappings are kept unless the fake-IP ranges changed.
+//! - The access log is kept open unless its config changed.
+//!
+//! Certificate and key files can also be checked for changes periodically. A changed file
+//! triggers a reload, so that renewed certificates are used for new connections without
+//! restarting.
+//!
+//! Subscriptions are fetched by the first load, and a reload is triggered whenever one of
+//! them is due for a refresh.
+//!
+//! A failed reload leaves the running servers untouched.
+//!
+//! systemd is notified when the servers are started, and when a reload starts and ends.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{debug, error};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+
+use crate::access_log;
+use crate::auto_ban;
+use crate::buffer_pool;
+use crate::clash_api;
+use crate::config::{self, Config, ServerConfig, ServerProxyConfig, Transport};
+use crate::crypto_provider;
+use crate::dns;
+use crate::fake_ip;
+use crate::geoip;
+use crate::geosite;
+use crate::metrics;
+use crate::privileges;
+use crate::resolver::Resolver;
+use crate::subscription::Subscriptions;
+use crate::systemd;
+use crate::tcp::tcp_server::{
+    TcpServerState, create_tcp_server_state, start_reloadable_tcp_servers, start_servers,
+};
+use crate::udp_sessions;
+use crate::user_store;
+
+/// Delay before reloading, so that bursts of file change events cause a single reload.
+const RELOAD_DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Interval at which included config, certificate and key files are checked for changes.
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Handle used to trigger a config reload.
+///
+/// Cloning the handle is cheap; all clones trigger the same reload loop. Reload requests
+/// made while a reload is pending are coalesced.
+#[derive(Debug, Clone)]
+pub struct ReloadHandle {
+    tx: Arc<watch::Sender<u64>>,
+}
+
+impl ReloadHandle {
+    pub fn new() -> Self {
+        let (tx, _rx) = watch::channel(0);
+        Self { tx: Arc::new(tx) }
+    }
+
+    /// Requests that the config files are re-read and applied.
+    pub fn reload(&self) {
+        self.tx
+            .send_modify(|generation| *generation = generation.wrapping_add(1));
+    }
+
+    fn subscribe(&self) -> watch::Receiver<u64> {
+        self.tx.subscribe()
+    }
+}
+
+impl Default for ReloadHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Starts servers from the config files, and re-applies the config files whenever
+/// `reload_handle` is triggered, or when `watch_files` is set and an included config file, a
+/// certificate or a key file changed.
+///
+/// Returns an error if the initial config could not be loaded or started. Errors during
+/// later reloads are logged and the previous servers keep running.
+pub async fn run_with_reload(
+    config_paths: Vec<String>,
+    reload_handle: ReloadHandle,
+    watch_files: bool,
+) -> std::io::Result<()> {
+    let config_paths = &config_paths;
+    run(
+        || config::load_configs_with_includes(config_paths),
+        reload_handle,
+        watch_files,
+    )
+    .await
+}
+
+/// Starts servers from the configs returned by `load_configs`, with the paths of the
+/// included config files, and loads them again on every reload like [`run_with_reload`].
+pub(crate) async fn run<F, Fut>(
+    load_configs: F,
+    reload_handle: ReloadHandle,
+    watch_files: bool,
+) -> std::io::Result<()>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = std::io::Result<(Vec<Config>, Vec<String>)>>,
+{
+    let mut reload_rx = reload_handle.subscribe();
+    let mut servers = RunningServers::default();
+    let mut subscriptions = Subscriptions::default();
+
+    let (loaded, watched_paths) = load_servers(&load_configs, &mut subscriptions).await?;
+    println!("\nStarting {} server(s)..", loaded.len());
+    servers.apply(loaded).await?;
+    privileges::drop_privileges()?;
+    systemd::notify("READY=1");
+    let _watchdog = systemd::start_watchdog();
+    let mut watched_files = WatchedFiles::new(watched_paths).await;
+
+    let mut file_check = tokio::time::interval_at(
+        tokio::time::Instant::now() + FILE_CHECK_INTERVAL,
+        FILE_CHECK_INTERVAL,
+    );
+    file_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let refresh_at = subscriptions.next_refresh();
+        tokio::select! {
+            changed = reload_rx.changed() => {
+                if changed.is_err() {
+                    // Unreachable while we hold `reload_handle`.
+                    return Ok(());
+                }
+            }
+            _ = file_check.tick(), if watch_files => {
+                if !watched_files.changed().await {
+                    continue;
+                }
+                println!("Included config, certificate or key files changed");
+            }
+            _ = tokio::time::sleep_until(refresh_at.unwrap_or_else(tokio::time::Instant::now)),
+                if refresh_at.is_some() => {
+                println!("Refreshing subscriptions");
+            }
+        }
+
+        systemd::notify_reloading();
+        println!(
+            "Reloading configs in {} second(s)..",
+            RELOAD_DEBOUNCE.as_secs()
+        );
+        tokio::time::sleep(RELOAD_DEBOUNCE).await;
+        reload_rx.borrow_and_update();
+
+        let (loaded, watched_paths) = match load_servers(&load_configs, &mut subscriptions).await {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                error!("Failed to reload configs, keeping current servers: {e}");
+                subscriptions.retry_later();
+                systemd::notify("READY=1");
+                continue;
+            }
+        };
+
+        if let Err(e) = servers.apply(loaded).await {
+            error!("Failed to apply reloaded configs: {e}");
+        }
+        systemd::notify("READY=1");
+        watched_files = WatchedFiles::new(watched_paths).await;
+    }
+}
+
+/// Modification times of the included config files, and of the certificate and key files
+/// used by the servers.
+struct WatchedFiles {
+    modified: Vec<(String, Option<SystemTime>)>,
+}
+
+impl WatchedFiles {
+    async fn new(paths: Vec<String>) -> Self {
+        let mut modified = Vec::with_capacity(paths.len());
+        for path in paths {
+            let time = modified_time(&path).await;
+            modified.push((path, time));
+        }
+        Self { modified }
+    }
+
+    /// Returns true if any file was modified, replaced or removed. Metadata of symlink
+    /// targets is used, so that renewals that switch symlinks are noticed too.
+    async fn changed(&self) -> bool {
+        for (path, time) in self.modified.iter() {
+            if modified_time(path).await != *time {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+async fn modified_time(path: &str) -> Option<SystemTime> {
+    tokio::fs::metadata(path)
+        .await
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// A validated server config, ready to be started.
+struct LoadedServer {
+    /// Identifies the listener, e.g. its bind location and transport.
+    key: String,
+    /// Differs whenever anything affecting the server changed, including its DNS groups.
+    fingerprint: String,
+    config: Config,
+    resolver: Arc<dyn Resolver>,
+}
+
+/// Loads the servers of the configs, and returns them with the paths of the included
+/// config files and the certificate and key files they use. Subscriptions are replaced by
+/// their client groups, fetching those that are due.
+async fn load_servers<F, Fut>(
+    load_configs: &F,
+    subscriptions: &mut Subscriptions,
+) -> std::io::Result<(Vec<LoadedServer>, Vec<String>)>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = std::io::Result<(Vec<Config>, Vec<String>)>>,
+{
+    let (configs, mut watched_paths) = load_configs().await?;
+
+    let (configs, pem_paths) = config::convert_cert_paths(configs).await?;
+    if !pem_paths.is_empty() {
+        println!("Loaded {} certs/keys from files", pem_paths.len());
+    }
+    let configs = subscriptions.materialize(configs).await?;
+
+    for config in configs.iter() {
+        debug!("================================================================================");
+        debug!("{config:#?}");
+    }
+    debug!("================================================================================");
+
+    let config::ValidatedConfigs {
+        configs: server_configs,
+        dns_groups,
+        geo,
+        fake_ip,
+        access_log,
+        udp_sessions,
+        buffer_pool,
+        crypto,
+        auto_ban,
+        privileges,
+        user_groups,
+    } = config::create_server_configs(configs)?;
+
+    // DNS over TLS clients are built with the registry, so the provider is set first.
+    crypto_provider::configure(crypto);
+
+    let dns_group_fingerprints: HashMap<String, String> = dns_groups
+        .iter()
+        .map(|group| (group.name.clone(), format!("{group:?}")))
+        .collect();
+
+    // Build DNS registry from expanded groups (async - resolves hostnames)
+    let mut dns_registry = dns::build_dns_registry(dns_groups).await?;
+
+    let mut loaded = Vec::with_capacity(server_configs.len());
+    for config in server_configs {
+        let (key, dns_config) = match &config {
+            Config::Server(s) => (server_key(s), s.dns.as_ref()),
+            Config::TunServer(t) => (
+                format!("tun://{}", t.device_name.as_deref().unwrap_or("default")),
+                t.dns.as_ref(),
+            ),
+            Config::Metrics(m) => (format!("metrics://{}", m.metrics), None),
+            Config::ClashApi(c) => (format!("clash_api://{}", c.clash_api), None),
+            Config::ReverseBridge(b) => (format!("reverse_bridge://{}", b.reverse_bridge), None),
+            _ => unreachable!(
+                "create_server_configs only returns Server, TunServer, ReverseBridge, Metrics \
+                 and ClashApi"
+            ),
+        };
+
+        let upstream_groups: Vec<&str> = match &config {
+            Config::Server(ServerConfig {
+                protocol: ServerProxyConfig::Dns { upstreams, .. },
+                ..
+            }) => upstreams.iter().map(|u| u.resolved_group()).collect(),
+            _ => vec![],
+        };
+        let dns_fingerprint: String = dns_config
+            .and_then(|c| c.resolved_group())
+            .into_iter()
+            .chain(upstream_groups)
+            .filter_map(|name| dns_group_fingerprints.get(name))
+            .map(String::as_str)
+            .collect();
+        let fingerprint = format!("{config:?}{dns_fingerprint}");
+
+        let resolver = match &config {
+            Config::Server(s) => dns_registry.get_for_server_config(s),
+            _ => dns_registry.get_for_server(dns_config),
+        };
+        loaded.push(LoadedServer {
+            key,
+            fingerprint,
+            config,
+            resolver,
+        });
+    }
+
+    geoip::set_database_path(geo.geoip_database);
+    geosite::set_database_path(geo.geosite_database);
+    fake_ip::configure(fake_ip)?;
+    access_log::configure(access_log)?;
+    udp_sessions::configure(udp_sessions);
+    auto_ban::configure(auto_ban);
+    privileges::configure(privileges);
+    buffer_pool::configure(buffer_pool);
+    user_store::configure(user_groups);
+
+    watched_paths.extend(pem_paths);
+    Ok((loaded, watched_paths))
+}
+
+/// Servers that listen on the addresses of interfaces are keyed by the current addresses
+/// too, so that a reload restarts them when the addresses of an interface change.
+fn server_key(config: &ServerConfig) -> String {
+    if config.bind_location.has_interfaces() {
+        let socket_addrs = match config.bind_location.to_socket_addrs() {
+            Ok(socket_addrs) => socket_addrs
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            Err(e) => e.to_string(),
+        };
+        return format!(
+            "{}://{} ({socket_addrs})",
+            config.transport, config.bind_location
+        );
+    }
+    format!("{}://{}", config.transport, config.bind_location)
+}
+
+/// Transparent servers use their own listeners, so their handler can't be replaced in
+/// place.
+fn is_transparent(config: &Config) -> bool {
+    matches!(config, Config::Server(s) if s.protocol.is_transparent())
+}
+
+struct RunningServer {
+    fingerprint: String,
+    join_handles: Vec<JoinHandle<()>>,
+    /// Only set for TCP servers, whose handler can be replaced in place.
+    tcp_state: Option<watch::Sender<TcpServerState>>,
+}
+
+impl RunningServer {
+    /// Stops accepting new connections. Established connections run in their own
+    /// tasks and are left to finish.
+    async fn stop(self) {
+        for join_handle in self.join_handles.iter() {
+            join_handle.abort();
+        }
+        // Wait for the listeners to be dropped so that their addresses can be reused.
+        for join_handle in self.join_handles {
+            let _ = join_handle.await;
+        }
+    }
+}
+
+#[derive(Default)]
+struct RunningServers {
+    servers: HashMap<String, RunningServer>,
+}
+
+impl RunningServers {
+    async fn apply(&mut self, loaded: Vec<LoadedServer>) -> std::io::Result<()> {
+        let mut keys = HashSet::with_capacity(loaded.len());
+        for server in loaded.iter() {
+            if !keys.insert(server.key.as_str()) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Multiple servers configured for {}", server.key),
+                ));
+            }
+        }
+
+        // Stop collecting metrics once the metrics listener is removed.
+        if !loaded
+            .iter()
+            .any(|server| matches!(server.config, Config::Metrics(_)))
+        {
+            metrics::set_enabled(false);
+        }
+        // Likewise, stop tracking connections once the Clash API listener is removed.
+        if !loaded
+            .iter()
+            .any(|server| matches!(server.config, Config::ClashApi(_)))
+        {
+            clash_api::set_enabled(false);
+        }
+
+        let mut next_servers = HashMap::with_capacity(loaded.len());
+        let mut to_start = vec![];
+
+        for server in loaded {
+            match self.servers.remove(&server.key) {
+                Some(running) if running.fingerprint == server.fingerprint => {
+                    next_servers.insert(server.key, running);
+                }
+                Some(mut running)
+                    if running.tcp_state.is_some() && !is_transparent(&server.config) =>
+                {
+                    let LoadedServer {
+                        key,
+                        fingerprint,
+                        config,
+                        resolver,
+                    } = server;
+                    let Config::Server(server_config) = config else {
+                        unreachable!("only TCP servers have replaceable state");
+                    };
+                    println!(
+                        "Updating {} TCP server at {}",
+                        &server_config.protocol, &server_config.bind_location
+                    );
+                    let mut state = create_tcp_server_state(server_config, resolver);
+                    let tcp_state = running.tcp_state.as_ref().unwrap();
+                    // Keep counting the open connections if the limit is unchanged.
+                    let connection_limiter = tcp_state.borrow().connection_limiter.clone();
+                    if connection_limiter.config() == state.connection_limiter.config() {
+                        state.connection_limiter = connection_limiter;
+                    }
+                    tcp_state.send_replace(state);
+                    running.fingerprint = fingerprint;
+                    next_servers.insert(key, running);
+                }
+                Some(running) => {
+                    println!("Restarting server at {}", server.key);
+                    running.stop().await;
+                    to_start.push(server);
+                }
+                None => to_start.push(server),
+            }
+        }
+
+        for (key, running) in self.servers.drain() {
+            println!("Stopping server at {key}");
+            running.stop().await;
+        }
+
+        let mut result = Ok(());
+        for server in to_start {
+            let LoadedServer {
+                key,
+                fingerprint,
+                config,
+                resolver,
+            } = server;
+
+            let started = match config {
+                // Servers behind a SIP003 plugin are restarted on changes, so that the
+                // plugin is restarted too. Port forwards with UDP are restarted so that
+                // their UDP listeners pick up the changes.
+                Config::Server(server_config)
+                    if server_config.transport == Transport::Tcp
+                        && !server_config.protocol.is_transparent()
+                        && server_config.protocol.sip003_plugin().is_none()
+                        && !server_config.protocol.forwards_udp() =>
+                {
+                    start_reloadable_tcp_servers(server_config, resolver)
+                        .await
+                        .map(|(join_handles, tcp_state)| (join_handles, Some(tcp_state)))
+                }
+                config => start_servers(config, resolver)
+                    .await
+                    .map(|join_handles| (join_handles, None)),
+            };
+
+            match started {
+                Ok((join_handles, tcp_state)) => {
+                    next_servers.insert(
+                        key,
+                        RunningServer {
+                            fingerprint,
+                            join_handles,
+                            tcp_state,
+                        },
+                    );
+                }
+                Err(e) => {
+                    error!("Failed to start server at {key}: {e}");
+                    result = Err(e);
+                }
+            }
+        }
+
+        self.servers = next_servers;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reload_requests_are_coalesced() {
+        let handle = ReloadHandle::new();
+        let mut rx = handle.subscribe();
+
+        handle.clone().reload();
+        handle.reload();
+
+        assert!(rx.has_changed().unwrap());
+        rx.borrow_and_update();
+        assert!(!rx.has_changed().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_watched_files_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cert.pem");
+        std::fs::write(&path, "old").unwrap();
+        let path = path.to_str().unwrap().to_string();
+
+        let watched_files = WatchedFiles::new(vec![path.clone()]).await;
+        assert!(!watched_files.changed().await);
+
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::UNIX_EPOCH).unwrap();
+        assert!(watched_files.changed().await);
+
+        let watched_files = WatchedFiles::new(vec![path.clone()]).await;
+        assert!(!watched_files.changed().await);
+        std::fs::remove_file(&path).unwrap();
+        assert!(watched_files.changed().await);
+    }
+
+    #[test]
+    fn test_server_key() {
+        let yaml = r#"
+address: "127.0.0.1:1080"
+protocol:
+  type: socks
+"#;
+        let config: ServerConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(server_key(&config), "tcp://127.0.0.1:1080");
+    }
+}
@@ -0,0 +1,163 @@
+//! Replay filter for the nonces of handshakes.
+//!
+//! Protocols whose handshakes start with a random nonce, like shadowsocks salts and VMess
+//! auth IDs, remember the nonces they saw to reject handshakes that an active prober
+//! recorded and sent again. Nonces are kept in two bloom filters: new nonces go to the
+//! current one, and when it is older than the window or full, it becomes the previous one
+//! and the oldest is cleared. A nonce is remembered for at least the window, unless more
+//! than the capacity of nonces arrive within it, with a fixed memory cost per filter.
+
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+
+use log::debug;
+use parking_lot::Mutex;
+
+use crate::metrics;
+
+/// Nonces per bloom filter.
+const CAPACITY: usize = 100_000;
+
+/// Bits per nonce, for a false positive rate of about one in a million at capacity.
+const BITS_PER_NONCE: usize = 29;
+
+/// Bits set per nonce.
+const HASHES: u64 = 20;
+
+/// Remembers the nonces seen within a time window.
+#[derive(Debug)]
+pub struct ReplayFilter {
+    /// Protocol that the replays are counted for, e.g. `vmess`.
+    protocol: &'static str,
+    window: Duration,
+    hashers: (RandomState, RandomState),
+    state: Mutex<Generations>,
+}
+
+#[derive(Debug)]
+struct Generations {
+    current: BloomFilter,
+    previous: BloomFilter,
+    started: Instant,
+}
+
+#[derive(Debug)]
+struct BloomFilter {
+    bits: Box<[u64]>,
+    len: usize,
+}
+
+impl BloomFilter {
+    fn new() -> Self {
+        Self {
+            bits: vec![0; (CAPACITY * BITS_PER_NONCE).div_ceil(64)].into_boxed_slice(),
+            len: 0,
+        }
+    }
+
+    fn num_bits(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn contains(&self, (h1, h2): (u64, u64)) -> bool {
+        (0..HASHES).all(|i| {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits();
+            self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0
+        })
+    }
+
+    fn insert(&mut self, (h1, h2): (u64, u64)) {
+        for i in 0..HASHES {
+            let bit = h1.wrapping_add(i.wrapping_mul(h2)) % self.num_bits();
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+        self.len += 1;
+    }
+
+    fn clear(&mut self) {
+        self.bits.fill(0);
+        self.len = 0;
+    }
+}
+
+impl ReplayFilter {
+    pub fn new(protocol: &'static str, window: Duration) -> Self {
+        Self {
+            protocol,
+            window,
+            hashers: (RandomState::new(), RandomState::new()),
+            state: Mutex::new(Generations {
+                current: BloomFilter::new(),
+                previous: BloomFilter::new(),
+                started: Instant::now(),
+            }),
+        }
+    }
+
+    /// Remembers `nonce`, and returns false if it was already seen, in which case the
+    /// replay is counted in the metrics.
+    pub fn check(&self, nonce: &[u8]) -> bool {
+        self.check_at(nonce, Instant::now())
+    }
+
+    fn check_at(&self, nonce: &[u8], now: Instant) -> bool {
+        // Double hashing, with an odd step so that it can't be a multiple of the size.
+        let hashes = (
+            self.hashers.0.hash_one(nonce),
+            self.hashers.1.hash_one(nonce) | 1,
+        );
+
+        let mut state = self.state.lock();
+        if state.current.contains(hashes) || state.previous.contains(hashes) {
+            drop(state);
+            debug!("Detected replayed {} handshake", self.protocol);
+            metrics::replay_detected(self.protocol);
+            return false;
+        }
+
+        if state.current.len >= CAPACITY || now.duration_since(state.started) >= self.window {
+            let Generations {
+                current, previous, ..
+            } = &mut *state;
+            std::mem::swap(current, previous);
+            current.clear();
+            state.started = now;
+        }
+        state.current.insert(hashes);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_filter() {
+        let filter = ReplayFilter::new("test", Duration::from_secs(60));
+        let start = Instant::now();
+
+        assert!(filter.check_at(b"nonce-1", start));
+        assert!(filter.check_at(b"nonce-2", start));
+        assert!(!filter.check_at(b"nonce-1", start + Duration::from_secs(30)));
+
+        // Nonces are remembered until the generation after theirs ends.
+        assert!(filter.check_at(b"nonce-3", start + Duration::from_secs(60)));
+        assert!(!filter.check_at(b"nonce-1", start + Duration::from_secs(90)));
+        assert!(filter.check_at(b"nonce-4", start + Duration::from_secs(120)));
+        assert!(filter.check_at(b"nonce-1", start + Duration::from_secs(121)));
+        assert!(!filter.check_at(b"nonce-3", start + Duration::from_secs(121)));
+    }
+
+    #[test]
+    fn test_replay_filter_no_false_positives() {
+        let filter = ReplayFilter::new("test", Duration::from_secs(60));
+        let start = Instant::now();
+        for i in 0..10_000u32 {
+            assert!(filter.check_at(&i.to_be_bytes(), start));
+        }
+        for i in 0..10_000u32 {
+            assert!(!filter.check_at(&i.to_be_bytes(), start));
+        }
+    }
+}
@@ -11,6 +11,7 @@ use log::debug;
 use rustc_hash::FxHashMap;
 
 use crate::address::{NetLocation, ResolvedLocation};
+use crate::config::DomainStrategy;
 
 type ResolveFuture = Pin<Box<dyn Future<Output = std::io::Result<Vec<SocketAddr>>> + Send>>;
 
@@ -48,8 +49,16 @@ pub async fn resolve_single_address(
     resolver: &Arc<dyn Resolver>,
     location: &NetLocation,
 ) -> std::io::Result<SocketAddr> {
+    Ok(resolve_addresses(resolver, location).await?[0])
+}
+
+/// Resolves all addresses of a location. Never returns an empty list.
+pub async fn resolve_addresses(
+    resolver: &Arc<dyn Resolver>,
+    location: &NetLocation,
+) -> std::io::Result<Vec<SocketAddr>> {
     if let Some(socket_addr) = location.to_socket_addr_nonblocking() {
-        return Ok(socket_addr);
+        return Ok(vec![socket_addr]);
     }
     let resolve_results = resolver.resolve_location(location).await?;
     if resolve_results.is_empty() {
@@ -57,7 +66,49 @@ pub async fn resolve_single_address(
             "could not resolve location: {location}"
         )));
     }
-    Ok(resolve_results[0])
+    Ok(resolve_results)
+}
+
+/// Resolves all addresses of a location, filtered and ordered by `domain_strategy` when
+/// the location is a hostname. Never returns an empty list.
+pub async fn resolve_addresses_with_strategy(
+    resolver: &Arc<dyn Resolver>,
+    location: &NetLocation,
+    domain_strategy: Option<DomainStrategy>,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let addrs = resolve_addresses(resolver, location).await?;
+    let Some(domain_strategy) = domain_strategy else {
+        return Ok(addrs);
+    };
+    if location.address().hostname().is_none() {
+        return Ok(addrs);
+    }
+    let addrs = apply_domain_strategy(addrs, domain_strategy);
+    if addrs.is_empty() {
+        return Err(std::io::Error::other(format!(
+            "no addresses of {location} match domain strategy {domain_strategy:?}"
+        )));
+    }
+    Ok(addrs)
+}
+
+/// Filters and orders resolved addresses by a domain strategy.
+pub fn apply_domain_strategy(
+    addrs: Vec<SocketAddr>,
+    domain_strategy: DomainStrategy,
+) -> Vec<SocketAddr> {
+    match domain_strategy {
+        DomainStrategy::PreferIpv4 | DomainStrategy::PreferIpv6 => {
+            let prefer_ipv4 = domain_strategy == DomainStrategy::PreferIpv4;
+            let mut addrs = addrs;
+            // Stable, so the order within each family is kept.
+            addrs.sort_by_key(|addr| addr.is_ipv4() != prefer_ipv4);
+            addrs
+        }
+        DomainStrategy::Ipv4Only => addrs.into_iter().filter(SocketAddr::is_ipv4).collect(),
+        DomainStrategy::Ipv6Only => addrs.into_iter().filter(SocketAddr::is_ipv6).collect(),
+        DomainStrategy::UseRemote => addrs,
+    }
 }
 
 /// Resolve a ResolvedLocation lazily. If already resolved, returns the cached
@@ -85,7 +136,7 @@ pub struct CachingNativeResolver {
 
 struct CachedResolveResult {
     timestamp: Instant,
-    addr: SocketAddr,
+    addrs: Vec<SocketAddr>,
 }
 
 impl std::fmt::Debug for CachingNativeResolver {
@@ -126,8 +177,8 @@ impl Resolver for CachingNativeResolver {
                 if Instant::now().duration_since(cached.timestamp)
                     <= Duration::from_secs(self.result_timeout_secs)
                 {
-                    let addr = cached.addr;
-                    return Box::pin(async move { Ok(vec![addr]) });
+                    let addrs = cached.addrs.clone();
+                    return Box::pin(async move { Ok(addrs) });
                 }
             }
         }
@@ -150,12 +201,11 @@ impl Resolver for CachingNativeResolver {
                 )));
             }
 
-            // Cache the first result
             cache.lock().insert(
                 location,
                 CachedResolveResult {
                     timestamp: Instant::now(),
-                    addr: addrs[0],
+                    addrs: addrs.clone(),
                 },
             );
 
@@ -281,3 +331,32 @@ impl ResolverCache {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply_domain_strategy() {
+        let addrs: Vec<SocketAddr> = ["[::1]:443", "127.0.0.1:443", "[::2]:443", "127.0.0.2:443"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ips = |strategy| -> Vec<String> {
+            apply_domain_strategy(addrs.clone(), strategy)
+                .iter()
+                .map(|addr| addr.ip().to_string())
+                .collect()
+        };
+        assert_eq!(
+            ips(DomainStrategy::PreferIpv4),
+            ["127.0.0.1", "127.0.0.2", "::1", "::2"]
+        );
+        assert_eq!(
+            ips(DomainStrategy::PreferIpv6),
+            ["::1", "::2", "127.0.0.1", "127.0.0.2"]
+        );
+        assert_eq!(ips(DomainStrategy::Ipv6Only), ["::1", "::2"]);
+        assert_eq!(ips(DomainStrategy::UseRemote).len(), 4);
+    }
+}
@@ -0,0 +1,109 @@
+//! Bridge side of a reverse proxy.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::AsyncStream;
+use crate::client_proxy_chain::ClientChainGroup;
+use crate::client_proxy_selector::ClientProxySelector;
+use crate::config::{ConfigSelection, ReverseBridgeConfig};
+use crate::mux::run_mux_server_session;
+use crate::resolver::Resolver;
+use crate::tcp::chain_builder::build_client_chain_group;
+use crate::tcp::tcp_client_handler_factory::create_tcp_client_proxy_selector;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval of the KeepAlive frames of idle tunnels, which stops NATs and firewalls from
+/// dropping them.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+struct Bridge {
+    tunnel_location: NetLocation,
+    chain_group: ClientChainGroup,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl Bridge {
+    async fn connect(&self) -> io::Result<(Box<dyn AsyncStream>, Option<Box<[u8]>>)> {
+        let result = timeout(
+            CONNECT_TIMEOUT,
+            self.chain_group
+                .connect_tcp(self.tunnel_location.clone().into(), &self.resolver),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+        Ok((
+            result.client_stream,
+            result.early_data.map(Vec::into_boxed_slice),
+        ))
+    }
+}
+
+/// Keeps `connections` tunnels open to the portal, reconnecting the ones that end.
+pub fn start_reverse_bridge(
+    config: ReverseBridgeConfig,
+    resolver: Arc<dyn Resolver>,
+) -> JoinHandle<()> {
+    println!(
+        "Starting reverse proxy bridge for {} with {} connections",
+        config.reverse_bridge, config.connections
+    );
+
+    let connections = config.connections;
+    let rules = config.rules.map(ConfigSelection::unwrap_config).into_vec();
+    let bridge = Arc::new(Bridge {
+        tunnel_location: NetLocation::new(Address::Hostname(config.reverse_bridge), 0),
+        chain_group: build_client_chain_group(config.client_chains, resolver.clone()),
+        proxy_selector: create_tcp_client_proxy_selector(rules, resolver.clone()),
+        resolver,
+    });
+
+    tokio::spawn(async move {
+        let tunnels = (0..connections).map(|_| run_tunnel(bridge.clone()));
+        futures::future::join_all(tunnels).await;
+    })
+}
+
+async fn run_tunnel(bridge: Arc<Bridge>) {
+    let mut retry_delay = MIN_RETRY_DELAY;
+    loop {
+        match bridge.connect().await {
+            Ok((stream, initial_data)) => {
+                debug!(
+                    "Reverse proxy tunnel to {} connected",
+                    bridge.tunnel_location
+                );
+                retry_delay = MIN_RETRY_DELAY;
+                run_mux_server_session(
+                    stream,
+                    initial_data,
+                    bridge.proxy_selector.clone(),
+                    bridge.resolver.clone(),
+                    KEEPALIVE_INTERVAL,
+                )
+                .await;
+                debug!("Reverse proxy tunnel to {} closed", bridge.tunnel_location);
+                tokio::time::sleep(retry_delay).await;
+            }
+            Err(e) => {
+                error!(
+                    "Failed to connect reverse proxy tunnel to {}: {e}",
+                    bridge.tunnel_location
+                );
+                tokio::time::sleep(retry_delay).await;
+                retry_delay = (retry_delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+}
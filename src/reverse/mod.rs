@@ -0,0 +1,14 @@
+//! Reverse proxy in the style of V2Ray: exposes services behind a NAT through a public
+//! server.
+//!
+//! A bridge behind the NAT keeps connections open to a server, with port 0 of a domain
+//! as their destination. When the server has a portal for the domain, it takes those
+//! connections as tunnels instead of forwarding them. Connections routed to the portal
+//! client config are carried over the tunnels with mux.cool, and the bridge routes each
+//! of them with its own rules, which decide what is reachable behind the NAT.
+
+mod bridge;
+mod portal;
+
+pub use bridge::start_reverse_bridge;
+pub use portal::{PortalSocketConnector, find_portal};
@@ -0,0 +1,155 @@
+//! Portal side of a reverse proxy.
+
+use std::io;
+use std::sync::{Arc, LazyLock, Weak};
+
+use async_trait::async_trait;
+use log::debug;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tokio::io::AsyncWriteExt;
+
+use crate::address::{NetLocation, ResolvedLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::http2_handler::PrefixedStream;
+use crate::mux::MuxTunnels;
+use crate::resolver::Resolver;
+use crate::tcp::socket_connector::SocketConnector;
+
+/// Portals by lowercase domain. A portal lives as long as a client config uses it, and
+/// is shared by all of them, so tunnels survive config reloads.
+static PORTALS: LazyLock<Mutex<FxHashMap<String, Weak<Portal>>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// Tunnels from the bridges of a domain.
+#[derive(Debug)]
+pub struct Portal {
+    domain: String,
+    tunnels: MuxTunnels,
+}
+
+impl Portal {
+    /// Returns the portal of `domain`, creating it if no client config uses it yet.
+    pub fn get_or_create(domain: &str) -> Arc<Self> {
+        let domain = domain.to_ascii_lowercase();
+        let mut portals = PORTALS.lock();
+        if let Some(portal) = portals.get(&domain).and_then(Weak::upgrade) {
+            return portal;
+        }
+        portals.retain(|_, portal| portal.strong_count() > 0);
+        let portal = Arc::new(Self {
+            domain: domain.clone(),
+            tunnels: MuxTunnels::new(),
+        });
+        portals.insert(domain, Arc::downgrade(&portal));
+        portal
+    }
+
+    /// Takes the connection of a bridge as a tunnel, after writing the response of the
+    /// server protocol. `initial_data` was already read from the connection.
+    pub async fn accept_tunnel(
+        &self,
+        mut stream: Box<dyn AsyncStream>,
+        connection_success_response: Option<Box<[u8]>>,
+        initial_data: Option<Box<[u8]>>,
+    ) -> io::Result<()> {
+        if let Some(data) = connection_success_response {
+            stream.write_all(&data).await?;
+        }
+        stream.flush().await?;
+
+        let stream: Box<dyn AsyncStream> = match initial_data {
+            Some(data) => Box::new(PrefixedStream::new(data, stream)),
+            None => stream,
+        };
+        debug!("Reverse proxy tunnel connected to portal {}", self.domain);
+        self.tunnels.add(stream);
+        Ok(())
+    }
+}
+
+/// Returns the portal that a connection to `location` is a tunnel of, if any. Bridges
+/// connect to port 0 of the domain of the portal.
+pub fn find_portal(location: &NetLocation) -> Option<Arc<Portal>> {
+    if location.port() != 0 {
+        return None;
+    }
+    let hostname = location.address().hostname()?;
+    PORTALS
+        .lock()
+        .get(&hostname.to_ascii_lowercase())
+        .and_then(Weak::upgrade)
+}
+
+/// Connects to targets through the tunnels of a portal, the bridge connects to them.
+#[derive(Debug)]
+pub struct PortalSocketConnector {
+    portal: Arc<Portal>,
+}
+
+impl PortalSocketConnector {
+    pub fn new(domain: &str) -> Self {
+        Self {
+            portal: Portal::get_or_create(domain),
+        }
+    }
+}
+
+#[async_trait]
+impl SocketConnector for PortalSocketConnector {
+    async fn connect(
+        &self,
+        _resolver: &Arc<dyn Resolver>,
+        address: &ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncStream>> {
+        self.portal
+            .tunnels
+            .open_stream(address.location())
+            .await
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("reverse proxy portal {}: {e}", self.portal.domain),
+                )
+            })
+    }
+
+    async fn connect_udp_bidirectional(
+        &self,
+        _resolver: &Arc<dyn Resolver>,
+        _target: ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncMessageStream>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "UDP is not supported through a reverse proxy portal",
+        ))
+    }
+
+    fn bind_interface(&self) -> Option<&str> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+
+    #[test]
+    fn test_find_portal() {
+        let connector = PortalSocketConnector::new("Portal-Test.internal");
+        let tunnel_location =
+            NetLocation::new(Address::Hostname("portal-test.INTERNAL".to_string()), 0);
+        let portal = find_portal(&tunnel_location).unwrap();
+        assert!(Arc::ptr_eq(&portal, &connector.portal));
+
+        let other_port =
+            NetLocation::new(Address::Hostname("portal-test.internal".to_string()), 80);
+        assert!(find_portal(&other_port).is_none());
+
+        // The portal is removed with the last client config that uses it.
+        drop(portal);
+        drop(connector);
+        assert!(find_portal(&tunnel_location).is_none());
+    }
+}
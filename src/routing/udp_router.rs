@@ -5,12 +5,13 @@
 //! - Separate buffer pools for outbound/inbound to prevent starvation
 //! - Zero-copy queuing: read directly into pool buffer, queue if write pending
 //! - DelayQueue for O(1) session expiry (no iteration)
+//! - Least recently used session eviction when the session limit is reached
 //! - Work queues for pending writes/flushes/responses (no iteration over all sessions)
 
 use std::collections::{HashSet, VecDeque};
 use std::future::Future;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::pin::Pin;
 use std::sync::Arc;
@@ -33,10 +34,12 @@ use crate::async_stream::{
     AsyncWriteSessionMessage, AsyncWriteSourcedMessage,
 };
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::rate_limit::{RateLimiter, Throttle};
 use crate::resolver::{Resolver, resolve_single_address};
+use crate::udp_sessions::{self, Eviction, UdpSessionLimits};
 use crate::util::allocate_vec;
 
-/// Timeout for inactive sessions
+/// Timeout for inactive sessions, unless `udp_idle_timeout_secs` is configured
 const SESSION_TIMEOUT_SECS: u64 = 200;
 
 /// Maximum UDP packet size
@@ -180,7 +183,8 @@ struct RoutingSession {
     /// Last time we wrote to the remote (for ping decisions)
     last_write: Instant,
 
-    /// Last iteration when expiry was reset (to avoid redundant resets)
+    /// Last iteration when expiry was reset (to avoid redundant resets, and to find the
+    /// least recently used session)
     last_expiry_iteration: usize,
 }
 
@@ -191,6 +195,7 @@ impl RoutingSession {
         resolved_addr: SocketAddr,
         lookup_key: LookupKey,
         remote: Box<dyn AsyncMessageStream>,
+        iteration: usize,
     ) -> Self {
         Self {
             destination,
@@ -205,7 +210,7 @@ impl RoutingSession {
             remote_read_eof: false,
             remote_write_eof: false,
             last_write: Instant::now(),
-            last_expiry_iteration: 0,
+            last_expiry_iteration: iteration,
         }
     }
 
@@ -220,7 +225,7 @@ impl RoutingSession {
     fn reset_expiry(
         &mut self,
         expiry_queue: &mut DelayQueue<SessionKey>,
-        _id: SessionKey,
+        timeout: Duration,
         iteration: usize,
     ) {
         if self.last_expiry_iteration == iteration {
@@ -231,7 +236,7 @@ impl RoutingSession {
         // Use reset() which is more efficient than remove() + insert()
         // as it reuses the same slab entry and key
         if let Some(ref key) = self.expiry_key {
-            expiry_queue.reset(key, Duration::from_secs(SESSION_TIMEOUT_SECS));
+            expiry_queue.reset(key, timeout);
         }
     }
 }
@@ -397,6 +402,10 @@ pub struct UdpRouter<'a> {
     server_write_pool: BufferPool,
 
     expiry_queue: DelayQueue<SessionKey>,
+    /// Limits of the session table.
+    session_limits: UdpSessionLimits,
+    /// Timeout for inactive sessions.
+    session_timeout: Duration,
     ping_timer: tokio::time::Interval,
     expiry_iteration: usize,
 
@@ -404,6 +413,12 @@ pub struct UdpRouter<'a> {
 
     selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    /// Client address matched against source IP rules, if known.
+    source: Option<IpAddr>,
+    /// Limits reads from the server.
+    up_throttle: Throttle,
+    /// Limits reads from the remotes.
+    down_throttle: Throttle,
 }
 
 impl<'a> UdpRouter<'a> {
@@ -412,8 +427,12 @@ impl<'a> UdpRouter<'a> {
         server: &'a mut ServerStream,
         selector: Arc<ClientProxySelector>,
         resolver: Arc<dyn Resolver>,
+        source: Option<IpAddr>,
         need_initial_flush: bool,
+        up_limiter: RateLimiter,
+        down_limiter: RateLimiter,
     ) -> Self {
+        let session_limits = udp_sessions::limits();
         let session_lookup = match server {
             ServerStream::Targeted(_) => SessionLookup::ByDestination(FxHashMap::default()),
             ServerStream::Session(_) => SessionLookup::BySessionId(FxHashMap::default()),
@@ -438,11 +457,17 @@ impl<'a> UdpRouter<'a> {
             remote_write_pool: BufferPool::new(REMOTE_WRITE_POOL_SIZE),
             server_write_pool: BufferPool::new(SERVER_WRITE_POOL_SIZE),
             expiry_queue: DelayQueue::new(),
+            session_limits,
+            session_timeout: session_limits
+                .idle_timeout_or(Duration::from_secs(SESSION_TIMEOUT_SECS)),
             ping_timer: tokio::time::interval(PING_CHECK_INTERVAL),
             expiry_iteration: 0,
             last_server_write: Instant::now(),
             selector,
             resolver,
+            source,
+            up_throttle: Throttle::new(up_limiter),
+            down_throttle: Throttle::new(down_limiter),
         }
     }
 
@@ -521,6 +546,11 @@ impl<'a> UdpRouter<'a> {
         let mut remote_writes_progress = false;
 
         loop {
+            // Don't read while the rate limit is exceeded.
+            if self.up_throttle.poll_ready(cx).is_pending() {
+                break;
+            }
+
             // Read packet from server directly into pool buffer
             let mut read_buf = ReadBuf::new(&mut buf);
             let packet = match self.server.poll_read_message(cx, &mut read_buf) {
@@ -548,6 +578,7 @@ impl<'a> UdpRouter<'a> {
                 self.set_server_read_eof();
                 break;
             }
+            self.up_throttle.consume(len);
 
             // Look up session
             let key_state = match &self.session_lookup {
@@ -580,7 +611,7 @@ impl<'a> UdpRouter<'a> {
                             session.last_write = Instant::now();
                             session.reset_expiry(
                                 &mut self.expiry_queue,
-                                *id,
+                                self.session_timeout,
                                 self.expiry_iteration,
                             );
                             if !session.in_remote_flush_queue {
@@ -663,7 +694,11 @@ impl<'a> UdpRouter<'a> {
                 Poll::Ready(Ok(())) => {
                     session.in_remote_write_queue -= 1;
                     session.last_write = Instant::now();
-                    session.reset_expiry(&mut self.expiry_queue, id, self.expiry_iteration);
+                    session.reset_expiry(
+                        &mut self.expiry_queue,
+                        self.session_timeout,
+                        self.expiry_iteration,
+                    );
                     if !session.in_remote_flush_queue {
                         session.in_remote_flush_queue = true;
                         self.remote_flush_queue.push_back(id);
@@ -754,6 +789,11 @@ impl<'a> UdpRouter<'a> {
             }
 
             for _ in session.in_server_write_queue..MAX_PENDING_SERVER_WRITES_PER_SESSION {
+                // Don't read while the rate limit is exceeded.
+                if self.down_throttle.poll_ready(cx).is_pending() {
+                    break;
+                }
+
                 let mut read_buf = ReadBuf::new(&mut buf);
 
                 match Pin::new(&mut session.remote).poll_read_message(cx, &mut read_buf) {
@@ -772,7 +812,12 @@ impl<'a> UdpRouter<'a> {
                         }
 
                         remote_read_progress = true;
-                        session.reset_expiry(&mut self.expiry_queue, id, self.expiry_iteration);
+                        self.down_throttle.consume(len);
+                        session.reset_expiry(
+                            &mut self.expiry_queue,
+                            self.session_timeout,
+                            self.expiry_iteration,
+                        );
 
                         match self.server.poll_write_message(
                             cx,
@@ -944,13 +989,21 @@ impl<'a> UdpRouter<'a> {
                 };
                 debug_assert!(matches!(pending_key_state.unwrap(), KeyState::Pending));
 
-                let mut session =
-                    RoutingSession::new(destination, session_id, resolved_addr, lookup_key, remote);
+                if self.session_limits.is_full(self.sessions.len()) {
+                    self.evict_least_recently_used();
+                }
+
+                let mut session = RoutingSession::new(
+                    destination,
+                    session_id,
+                    resolved_addr,
+                    lookup_key,
+                    remote,
+                    self.expiry_iteration,
+                );
 
                 // TODO: part of constructor, we now know the id in advance
-                let expiry_key = self
-                    .expiry_queue
-                    .insert(id, Duration::from_secs(SESSION_TIMEOUT_SECS));
+                let expiry_key = self.expiry_queue.insert(id, self.session_timeout);
                 session.expiry_key = Some(expiry_key);
 
                 // Try to write immediately
@@ -1037,13 +1090,16 @@ impl<'a> UdpRouter<'a> {
         let initial_data = data.to_vec();
         let selector = Arc::clone(&self.selector);
         let resolver = Arc::clone(&self.resolver);
+        let source = self.source;
         let dest_for_future = destination.clone();
 
         let future: SessionCreateFuture = Box::pin(async move {
             let resolved_addr = resolve_single_address(&resolver, &dest_for_future).await?;
             // Create ResolvedLocation with pre-resolved address
             let resolved_location = ResolvedLocation::with_resolved(dest_for_future, resolved_addr);
-            let decision = selector.judge(resolved_location, &resolver).await?;
+            let decision = selector
+                .judge_with_source(resolved_location, source, &resolver)
+                .await?;
 
             match decision {
                 ConnectDecision::Allow {
@@ -1051,7 +1107,7 @@ impl<'a> UdpRouter<'a> {
                     remote_location,
                 } => {
                     let client_stream = chain_group
-                        .connect_udp_bidirectional(&resolver, remote_location)
+                        .connect_udp_bidirectional_with_source(&resolver, remote_location, source)
                         .await?;
 
                     Ok(SessionCreateResult {
@@ -1106,6 +1162,20 @@ impl<'a> UdpRouter<'a> {
         self.pending_shutdowns.push_back(session.remote);
     }
 
+    /// Remove the least recently used session to make room for a new one
+    fn evict_least_recently_used(&mut self) {
+        let Some((&id, session)) = self
+            .sessions
+            .iter()
+            .min_by_key(|(_, session)| session.last_expiry_iteration)
+        else {
+            return;
+        };
+        debug!("Session table full, evicting: {}", session.destination);
+        udp_sessions::record_eviction(Eviction::Limit);
+        self.remove_session(id);
+    }
+
     /// Process expired sessions
     fn process_expired(&mut self, cx: &mut Context<'_>) {
         while let Poll::Ready(Some(expired)) = self.expiry_queue.poll_expired(cx) {
@@ -1114,6 +1184,7 @@ impl<'a> UdpRouter<'a> {
             if let Some(session) = self.sessions.get_mut(&id) {
                 debug!("Session expired: {}", session.destination);
                 session.expiry_key = None;
+                udp_sessions::record_eviction(Eviction::Idle);
             }
             self.remove_session(id);
         }
@@ -1319,13 +1390,28 @@ impl UdpRouter<'_> {
 }
 
 /// Run per-destination routing for any server UDP stream type.
+///
+/// `source` is the client address matched against source IP rules, if known. Packets
+/// from the client are limited by `up_limiter`, and packets to it by `down_limiter`.
 pub async fn run_udp_routing(
     mut server: ServerStream,
     selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    source: Option<IpAddr>,
     need_initial_flush: bool,
+    up_limiter: RateLimiter,
+    down_limiter: RateLimiter,
 ) -> io::Result<()> {
-    let result = UdpRouter::new(&mut server, selector, resolver, need_initial_flush).await;
+    let result = UdpRouter::new(
+        &mut server,
+        selector,
+        resolver,
+        source,
+        need_initial_flush,
+        up_limiter,
+        down_limiter,
+    )
+    .await;
     let _ = server.shutdown_message().await;
     result
 }
@@ -2,8 +2,12 @@ use std::collections::BTreeSet;
 use std::sync::Arc;
 use std::sync::OnceLock;
 
+use base64::engine::{Engine as _, general_purpose::STANDARD as BASE64};
 use rustls::pki_types::pem::PemObject;
 
+use crate::tls_fingerprint::TlsFingerprint;
+
+#[allow(clippy::too_many_arguments)]
 pub fn create_client_config(
     verify_webpki: bool,
     server_fingerprints: Vec<String>,
@@ -11,9 +15,20 @@ pub fn create_client_config(
     enable_sni: bool,
     client_key_and_cert: Option<(Vec<u8>, Vec<u8>)>,
     tls13_only: bool,
+    fingerprint: Option<TlsFingerprint>,
+    ech_config: Option<rustls::client::EchConfig>,
+    post_quantum: Option<bool>,
 ) -> rustls::ClientConfig {
-    let builder = rustls::ClientConfig::builder_with_provider(get_crypto_provider());
-    let builder = if tls13_only {
+    let provider = match fingerprint {
+        Some(fingerprint) => fingerprint.crypto_provider(&get_crypto_provider()),
+        None => get_crypto_provider().as_ref().clone(),
+    };
+    let provider = Arc::new(with_post_quantum(provider, post_quantum));
+    let builder = rustls::ClientConfig::builder_with_provider(provider);
+    let builder = if let Some(ech_config) = ech_config {
+        // ECH implies TLS 1.3 only.
+        builder.with_ech(ech_config.into()).unwrap()
+    } else if tls13_only {
         builder
             .with_protocol_versions(&[&rustls::version::TLS13])
             .unwrap()
@@ -186,10 +201,40 @@ impl rustls::client::danger::ServerCertVerifier for DisabledVerifier {
 }
 
 fn get_crypto_provider() -> Arc<rustls::crypto::CryptoProvider> {
-    static INSTANCE: OnceLock<Arc<rustls::crypto::CryptoProvider>> = OnceLock::new();
-    INSTANCE
-        .get_or_init(|| Arc::new(rustls::crypto::aws_lc_rs::default_provider()))
-        .clone()
+    crate::crypto_provider::get()
+}
+
+/// Applies the `post_quantum` setting to the key exchange groups of `provider`.
+///
+/// When unset, the provider defaults are kept. When enabled, the X25519MLKEM768 hybrid
+/// group is preferred over classical groups. When disabled, only classical groups are used.
+fn with_post_quantum(
+    mut provider: rustls::crypto::CryptoProvider,
+    post_quantum: Option<bool>,
+) -> rustls::crypto::CryptoProvider {
+    let Some(post_quantum) = post_quantum else {
+        return provider;
+    };
+    provider
+        .kx_groups
+        .retain(|group| !is_post_quantum_group(group.name()));
+    if post_quantum {
+        provider
+            .kx_groups
+            .insert(0, rustls::crypto::aws_lc_rs::kx_group::X25519MLKEM768);
+    }
+    provider
+}
+
+fn is_post_quantum_group(group: rustls::NamedGroup) -> bool {
+    matches!(
+        group,
+        rustls::NamedGroup::X25519MLKEM768
+            | rustls::NamedGroup::secp256r1MLKEM768
+            | rustls::NamedGroup::MLKEM512
+            | rustls::NamedGroup::MLKEM768
+            | rustls::NamedGroup::MLKEM1024
+    )
 }
 
 fn get_supported_algorithms() -> rustls::crypto::WebPkiSupportedAlgorithms {
@@ -197,14 +242,10 @@ fn get_supported_algorithms() -> rustls::crypto::WebPkiSupportedAlgorithms {
 }
 
 fn get_disabled_verifier() -> Arc<DisabledVerifier> {
-    static INSTANCE: OnceLock<Arc<DisabledVerifier>> = OnceLock::new();
-    INSTANCE
-        .get_or_init(|| {
-            Arc::new(DisabledVerifier {
-                supported_algs: get_supported_algorithms(),
-            })
-        })
-        .clone()
+    // Not cached, as the supported algorithms depend on the configured provider.
+    Arc::new(DisabledVerifier {
+        supported_algs: get_supported_algorithms(),
+    })
 }
 
 fn get_root_cert_store() -> Arc<rustls::RootCertStore> {
@@ -219,9 +260,26 @@ fn get_root_cert_store() -> Arc<rustls::RootCertStore> {
         .clone()
 }
 
-/// Creates a simple TLS ClientConfig with root CA verification.
-/// Used by hickory-resolver for DoT/DoH connections.
-pub fn create_dns_client_config() -> rustls::ClientConfig {
+/// Creates a TLS ClientConfig for hickory-resolver's DoT/DoH/DoQ connections.
+/// Uses root CA verification unless `verify` is disabled or fingerprints are given.
+pub fn create_dns_client_config(
+    verify: bool,
+    server_fingerprints: Vec<String>,
+) -> rustls::ClientConfig {
+    if !verify || !server_fingerprints.is_empty() {
+        // hickory sets the ALPN protocols itself.
+        return create_client_config(
+            verify,
+            server_fingerprints,
+            vec![],
+            true,
+            None,
+            false,
+            None,
+            None,
+            None,
+        );
+    }
     rustls::ClientConfig::builder_with_provider(get_crypto_provider())
         .with_safe_default_protocol_versions()
         .unwrap()
@@ -235,6 +293,7 @@ pub fn create_server_config(
     ca_cert_bytes: Vec<Vec<u8>>,
     alpn_protocols: &[String],
     client_fingerprints: &[String],
+    post_quantum: Option<bool>,
 ) -> rustls::ServerConfig {
     // Parse all certificates from the PEM file (server cert + intermediates)
     let certs: Vec<_> = rustls::pki_types::CertificateDer::pem_slice_iter(cert_bytes)
@@ -267,7 +326,8 @@ pub fn create_server_config(
         Some(verifier)
     };
 
-    let builder = rustls::ServerConfig::builder_with_provider(get_crypto_provider())
+    let provider = with_post_quantum(get_crypto_provider().as_ref().clone(), post_quantum);
+    let builder = rustls::ServerConfig::builder_with_provider(Arc::new(provider))
         .with_safe_default_protocol_versions()
         .unwrap();
     let builder = if client_fingerprints.is_empty() && webpki_verifier.is_none() {
@@ -295,6 +355,26 @@ pub fn create_server_config(
     config
 }
 
+/// Parses a base64 ECHConfigList, as published in the `ech` parameter of HTTPS DNS records.
+pub fn parse_ech_config_list(encoded: &str) -> std::io::Result<rustls::client::EchConfig> {
+    let bytes = BASE64.decode(encoded.trim()).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid ECH config list, could not decode base64: {e}"),
+        )
+    })?;
+    rustls::client::EchConfig::new(
+        bytes.into(),
+        rustls::crypto::aws_lc_rs::hpke::ALL_SUPPORTED_SUITES,
+    )
+    .map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid ECH config list: {e}"),
+        )
+    })
+}
+
 pub fn process_fingerprints(client_fingerprints: &[String]) -> std::io::Result<BTreeSet<Vec<u8>>> {
     let mut result = BTreeSet::new();
 
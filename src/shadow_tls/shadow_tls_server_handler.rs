@@ -12,10 +12,11 @@ use tokio::io::AsyncWriteExt;
 
 use super::shadow_tls_hmac::ShadowTlsHmac;
 use super::shadow_tls_stream::ShadowTlsStream;
-use crate::address::NetLocation;
+use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncStream;
 use crate::buf_reader::BufReader;
 use crate::client_proxy_chain::ClientProxyChain;
+use crate::config::ShadowTlsWildcardSni;
 use crate::resolver::Resolver;
 use crate::rustls_connection_util::feed_rustls_server_connection;
 use crate::stream_reader::StreamReader;
@@ -36,6 +37,9 @@ pub struct ShadowTlsServerTarget {
     initial_hmac: ShadowTlsHmac,
     initial_xor_context: ShadowTlsXorContext,
     handshake: ShadowTlsServerTargetHandshake,
+    /// Destination of clients that fail authentication, and the chain to reach it.
+    fallback: Option<(NetLocation, ClientProxyChain)>,
+    wildcard_sni: ShadowTlsWildcardSni,
     handler: Box<dyn TcpServerHandler>,
 }
 
@@ -43,6 +47,8 @@ impl ShadowTlsServerTarget {
     pub fn new(
         password: String,
         handshake: ShadowTlsServerTargetHandshake,
+        fallback: Option<(NetLocation, ClientProxyChain)>,
+        wildcard_sni: ShadowTlsWildcardSni,
         handler: Box<dyn TcpServerHandler>,
     ) -> Self {
         let password_bytes = password.into_bytes();
@@ -57,9 +63,32 @@ impl ShadowTlsServerTarget {
             initial_hmac,
             initial_xor_context: ShadowTlsXorContext(initial_xor_context),
             handshake,
+            fallback,
+            wildcard_sni,
             handler,
         }
     }
+
+    /// Returns the handshake server of a remote handshake for a client that requested
+    /// `server_name`, which is the requested server with wildcard SNI.
+    fn handshake_location(
+        &self,
+        location: &NetLocation,
+        server_name: Option<&str>,
+        authenticated: bool,
+    ) -> NetLocation {
+        let use_server_name = match self.wildcard_sni {
+            ShadowTlsWildcardSni::Off => false,
+            ShadowTlsWildcardSni::Authed => authenticated,
+            ShadowTlsWildcardSni::All => true,
+        };
+        match server_name {
+            Some(server_name) if use_server_name => {
+                NetLocation::new(Address::Hostname(server_name.to_string()), location.port())
+            }
+            _ => location.clone(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -259,21 +288,36 @@ pub async fn setup_shadowtls_server_stream(
     // Validates ClientHello before consuming anything to allow fallback if needed.
     if let Err(e) = validate_shadowtls_client_hello(&parsed_client_hello, &target.initial_hmac) {
         if e.kind() == std::io::ErrorKind::PermissionDenied {
-            // Falls back to handshake server in Remote mode for auth failures.
-            if let ShadowTlsServerTargetHandshake::Remote {
-                ref location,
-                ref client_chain,
-            } = target.handshake
-            {
+            // Falls back to the configured fallback, or the handshake server in Remote mode,
+            // for auth failures.
+            let fallback = match (&target.fallback, &target.handshake) {
+                (Some((location, client_chain)), _) => Some((location.clone(), client_chain)),
+                (
+                    None,
+                    ShadowTlsServerTargetHandshake::Remote {
+                        location,
+                        client_chain,
+                    },
+                ) => Some((
+                    target.handshake_location(
+                        location,
+                        parsed_client_hello.requested_server_name.as_deref(),
+                        false,
+                    ),
+                    client_chain,
+                )),
+                (None, ShadowTlsServerTargetHandshake::Local(_)) => None,
+            };
+            if let Some((location, client_chain)) = fallback {
                 log::warn!(
-                    "ShadowTLS authentication failed, falling back to handshake server: {} - reason: {}",
+                    "ShadowTLS authentication failed, falling back to: {} - reason: {}",
                     location,
                     e
                 );
                 return shadowtls_fallback_to_handshake_server(
                     server_stream,
                     &parsed_client_hello.client_hello_frame,
-                    location,
+                    &location,
                     client_chain,
                     resolver,
                 )
@@ -287,6 +331,7 @@ pub async fn setup_shadowtls_server_stream(
     let ParsedClientHello {
         client_hello_frame,
         client_reader,
+        requested_server_name,
         ..
     } = parsed_client_hello;
 
@@ -300,7 +345,7 @@ pub async fn setup_shadowtls_server_stream(
             client_hello_frame,
             &target.initial_hmac,
             &target.initial_xor_context,
-            location.clone(),
+            target.handshake_location(location, requested_server_name.as_deref(), true),
             client_chain,
             resolver,
         )
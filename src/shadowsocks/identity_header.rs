@@ -0,0 +1,190 @@
+//! Extensible identity headers (EIH) of the shadowsocks 2022 edition.
+//!
+//! See https://github.com/Shadowsocks-NET/shadowsocks-specs/blob/main/2022-2-shadowsocks-2022-extensible-identity-headers.md
+//!
+//! A multi-user server has an identity PSK (iPSK), and each user has their own PSK
+//! (uPSK). After the salt, the client sends an identity header: the first 16 bytes of
+//! the BLAKE3 hash of the uPSK, encrypted with AES using a subkey derived from the iPSK
+//! and the salt. The server decrypts it to find the user, and the rest of the session
+//! uses the uPSK. Clients going through relays send one identity header per iPSK.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use aws_lc_rs::cipher::{
+    AES_128, AES_256, DecryptingKey, DecryptionContext, EncryptingKey, EncryptionContext,
+    UnboundCipherKey,
+};
+
+use super::blake3_key::Blake3Key;
+use super::shadowsocks_key::ShadowsocksKey;
+use crate::util::allocate_vec;
+
+pub const IDENTITY_HEADER_LEN: usize = 16;
+
+const IDENTITY_CONTEXT_STR: &str = "shadowsocks 2022 identity subkey";
+
+/// Hash of a PSK as carried in an identity header.
+fn psk_hash(psk: &[u8]) -> [u8; IDENTITY_HEADER_LEN] {
+    let mut hash = [0u8; IDENTITY_HEADER_LEN];
+    hash.copy_from_slice(&blake3::hash(psk).as_bytes()[..IDENTITY_HEADER_LEN]);
+    hash
+}
+
+fn identity_cipher_key(identity_key: &[u8], salt: &[u8]) -> UnboundCipherKey {
+    let mut hasher = blake3::Hasher::new_derive_key(IDENTITY_CONTEXT_STR);
+    hasher.update(identity_key);
+    hasher.update(salt);
+    let mut subkey = allocate_vec(identity_key.len());
+    hasher.finalize_xof().fill(&mut subkey);
+
+    let algorithm = if identity_key.len() == 16 {
+        &AES_128
+    } else {
+        &AES_256
+    };
+    UnboundCipherKey::new(algorithm, &subkey).unwrap()
+}
+
+/// Identity PSKs of a client, and the identity headers they encrypt.
+#[derive(Debug)]
+pub struct IdentityKeys {
+    /// (iPSK, hash of the next PSK in the chain), the last being the hash of the uPSK
+    keys: Vec<(Box<[u8]>, [u8; IDENTITY_HEADER_LEN])>,
+}
+
+impl IdentityKeys {
+    pub fn new(identity_keys: &[Box<[u8]>], user_key: &[u8]) -> Self {
+        let keys = identity_keys
+            .iter()
+            .enumerate()
+            .map(|(i, identity_key)| {
+                let next_key = identity_keys.get(i + 1).map_or(user_key, |key| &key[..]);
+                (identity_key.clone(), psk_hash(next_key))
+            })
+            .collect();
+        Self { keys }
+    }
+
+    pub fn headers_len(&self) -> usize {
+        self.keys.len() * IDENTITY_HEADER_LEN
+    }
+
+    /// Writes the identity headers for `salt` to `out`, which is `headers_len()` long.
+    pub fn write_headers(&self, salt: &[u8], out: &mut [u8]) {
+        for ((identity_key, next_hash), header) in self
+            .keys
+            .iter()
+            .zip(out.chunks_exact_mut(IDENTITY_HEADER_LEN))
+        {
+            header.copy_from_slice(next_hash);
+            EncryptingKey::ecb(identity_cipher_key(identity_key, salt))
+                .unwrap()
+                .less_safe_encrypt(header, EncryptionContext::None)
+                .unwrap();
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ShadowsocksUser {
+    pub name: String,
+    pub key: Arc<Box<dyn ShadowsocksKey>>,
+}
+
+/// Users of a multi-user server, identified by the identity header.
+#[derive(Debug)]
+pub struct ShadowsocksUsers {
+    identity_key: Box<[u8]>,
+    /// uPSK hash -> user
+    users: HashMap<[u8; IDENTITY_HEADER_LEN], ShadowsocksUser>,
+}
+
+impl ShadowsocksUsers {
+    /// Create the users of a server with `identity_key` as iPSK, from (name, uPSK) pairs.
+    pub fn new(
+        identity_key: Box<[u8]>,
+        users: Vec<(String, Box<[u8]>)>,
+        session_key_len: usize,
+    ) -> Self {
+        let users = users
+            .into_iter()
+            .map(|(name, user_key)| {
+                let hash = psk_hash(&user_key);
+                let key: Arc<Box<dyn ShadowsocksKey>> =
+                    Arc::new(Box::new(Blake3Key::new(user_key, session_key_len)));
+                (hash, ShadowsocksUser { name, key })
+            })
+            .collect();
+        Self {
+            identity_key,
+            users,
+        }
+    }
+
+    /// Finds the user of the identity header sent after `salt`.
+    pub fn identify(&self, salt: &[u8], header: &[u8]) -> Option<&ShadowsocksUser> {
+        let mut hash = [0u8; IDENTITY_HEADER_LEN];
+        hash.copy_from_slice(header);
+        DecryptingKey::ecb(identity_cipher_key(&self.identity_key, salt))
+            .unwrap()
+            .decrypt(&mut hash, DecryptionContext::None)
+            .ok()?;
+        self.users.get(&hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identify_user() {
+        let identity_key: Box<[u8]> = vec![1u8; 32].into_boxed_slice();
+        let alice_key: Box<[u8]> = vec![2u8; 32].into_boxed_slice();
+        let bob_key: Box<[u8]> = vec![3u8; 32].into_boxed_slice();
+        let users = ShadowsocksUsers::new(
+            identity_key.clone(),
+            vec![
+                ("alice".to_string(), alice_key.clone()),
+                ("bob".to_string(), bob_key),
+            ],
+            32,
+        );
+
+        let salt = [4u8; 32];
+        let identity_keys = IdentityKeys::new(&[identity_key], &alice_key);
+        let mut header = [0u8; IDENTITY_HEADER_LEN];
+        identity_keys.write_headers(&salt, &mut header);
+
+        assert_ne!(header, psk_hash(&alice_key));
+        assert_eq!(users.identify(&salt, &header).unwrap().name, "alice");
+        assert!(users.identify(&[5u8; 32], &header).is_none());
+    }
+
+    #[test]
+    fn test_identity_header_chain() {
+        let relay_key: Box<[u8]> = vec![1u8; 16].into_boxed_slice();
+        let server_key: Box<[u8]> = vec![2u8; 16].into_boxed_slice();
+        let user_key: Box<[u8]> = vec![3u8; 16].into_boxed_slice();
+        let identity_keys = IdentityKeys::new(&[relay_key.clone(), server_key.clone()], &user_key);
+        assert_eq!(identity_keys.headers_len(), 2 * IDENTITY_HEADER_LEN);
+
+        let salt = [4u8; 16];
+        let mut headers = [0u8; 2 * IDENTITY_HEADER_LEN];
+        identity_keys.write_headers(&salt, &mut headers);
+
+        // The relay identifies the server key, and the server identifies the user.
+        let relay = ShadowsocksUsers::new(
+            relay_key,
+            vec![("server".to_string(), server_key.clone())],
+            16,
+        );
+        assert_eq!(
+            relay.identify(&salt, &headers[..16]).unwrap().name,
+            "server"
+        );
+        let server = ShadowsocksUsers::new(server_key, vec![("user".to_string(), user_key)], 16);
+        assert_eq!(server.identify(&salt, &headers[16..]).unwrap().name, "user");
+    }
+}
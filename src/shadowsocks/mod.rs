@@ -1,13 +1,13 @@
 mod aead_util;
 mod blake3_key;
 mod default_key;
-mod salt_checker;
+mod identity_header;
 mod shadowsocks_cipher;
 mod shadowsocks_key;
 mod shadowsocks_stream;
 mod shadowsocks_stream_type;
 mod shadowsocks_tcp_handler;
-mod timed_salt_checker;
+mod sip003_plugin;
 
 pub use default_key::DefaultKey;
 pub use shadowsocks_cipher::ShadowsocksCipher;
@@ -15,3 +15,6 @@ pub use shadowsocks_key::ShadowsocksKey;
 pub use shadowsocks_stream::ShadowsocksStream;
 pub use shadowsocks_stream_type::ShadowsocksStreamType;
 pub use shadowsocks_tcp_handler::ShadowsocksTcpHandler;
+pub use sip003_plugin::{
+    Sip003Plugin, Sip003SocketConnector, allocate_local_address, start_server_plugin,
+};
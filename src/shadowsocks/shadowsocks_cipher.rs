@@ -49,6 +49,12 @@ impl ShadowsocksCipher {
     pub fn name(&self) -> &'static str {
         self.name
     }
+
+    /// Whether the cipher can be used with shadowsocks 2022 identity headers, which are
+    /// encrypted with AES.
+    pub fn supports_identity_headers(&self) -> bool {
+        self.name.starts_with("aes-")
+    }
 }
 
 impl TryFrom<&str> for ShadowsocksCipher {
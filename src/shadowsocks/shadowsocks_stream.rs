@@ -8,19 +8,20 @@ use aws_lc_rs::aead::{
 };
 use aws_lc_rs::error::Unspecified;
 use futures::ready;
-use parking_lot::Mutex;
 use rand::RngCore;
 use subtle::ConstantTimeEq;
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 use super::aead_util::TAG_LEN;
-use super::salt_checker::SaltChecker;
+use super::identity_header::{IDENTITY_HEADER_LEN, IdentityKeys, ShadowsocksUsers};
 use super::shadowsocks_key::ShadowsocksKey;
 use super::shadowsocks_stream_type::ShadowsocksStreamType;
 use crate::async_stream::{
     AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncShutdownMessage,
     AsyncStream, AsyncWriteMessage,
 };
+use crate::auto_ban;
+use crate::replay_filter::ReplayFilter;
 use crate::util::allocate_vec;
 
 fn generate_iv(buf: &mut [u8]) {
@@ -56,7 +57,13 @@ pub struct ShadowsocksStream {
     algorithm: &'static Algorithm,
     salt_len: usize,
     key: Arc<Box<dyn ShadowsocksKey>>,
-    salt_checker: Option<Arc<Mutex<dyn SaltChecker>>>,
+    replay_filter: Option<Arc<ReplayFilter>>,
+    /// Identity headers sent by an AEAD2022 client to a multi-user server.
+    identity_keys: Option<Arc<IdentityKeys>>,
+    /// Users of a multi-user AEAD2022 server.
+    users: Option<Arc<ShadowsocksUsers>>,
+    /// Name of the user identified by the identity header.
+    user_name: Option<String>,
     encrypt_iv: Box<[u8]>,
     decrypt_iv: Option<Box<[u8]>>,
 
@@ -95,7 +102,7 @@ impl ShadowsocksStream {
         algorithm: &'static Algorithm,
         salt_len: usize,
         key: Arc<Box<dyn ShadowsocksKey>>,
-        salt_checker: Option<Arc<Mutex<dyn SaltChecker>>>,
+        replay_filter: Option<Arc<ReplayFilter>>,
     ) -> Self {
         let max_payload_len = stream_type.max_payload_len();
         let max_packet_len = max_payload_len + METADATA_SIZE;
@@ -121,7 +128,10 @@ impl ShadowsocksStream {
             algorithm,
             salt_len,
             key,
-            salt_checker,
+            replay_filter,
+            identity_keys: None,
+            users: None,
+            user_name: None,
             encrypt_iv,
             // Needed for AEAD2022 server response.
             decrypt_iv: None,
@@ -147,6 +157,23 @@ impl ShadowsocksStream {
         }
     }
 
+    /// Sends identity headers, for a client of a multi-user AEAD2022 server.
+    pub fn with_identity_keys(mut self, identity_keys: Arc<IdentityKeys>) -> Self {
+        self.identity_keys = Some(identity_keys);
+        self
+    }
+
+    /// Requires an identity header, for a multi-user AEAD2022 server.
+    pub fn with_users(mut self, users: Arc<ShadowsocksUsers>) -> Self {
+        self.users = Some(users);
+        self
+    }
+
+    /// Name of the user identified by the identity header, once the header was read.
+    pub fn user_name(&self) -> Option<&str> {
+        self.user_name.as_deref()
+    }
+
     fn process_opening_key(&mut self) -> std::io::Result<()> {
         let decrypt_iv = &self.unprocessed_buf[0..self.salt_len];
         let session_key = self.key.create_session_key(decrypt_iv);
@@ -189,7 +216,7 @@ impl ShadowsocksStream {
                     )
                     .is_err()
                 {
-                    return Err(std::io::Error::new(
+                    return Err(auto_ban::rejected(
                         std::io::ErrorKind::InvalidData,
                         "open failed for length",
                     ));
@@ -204,7 +231,7 @@ impl ShadowsocksStream {
                 // The higher two bits are reserved and must be set to zero. Payload is
                 // therefore limited to 16*1024 - 1 bytes."
                 if data_len_no_tag > self.stream_type.max_payload_len() {
-                    return Err(std::io::Error::new(
+                    return Err(auto_ban::rejected(
                         std::io::ErrorKind::InvalidData,
                         "data length larger than max allowed size",
                     ));
@@ -241,7 +268,7 @@ impl ShadowsocksStream {
             )
             .is_err()
         {
-            return Err(std::io::Error::new(
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::InvalidData,
                 "open failed for data",
             ));
@@ -386,8 +413,9 @@ impl ShadowsocksStream {
             ShadowsocksStreamType::Aead => self.salt_len,
             ShadowsocksStreamType::AEAD2022Server => {
                 // Expect the encrypted client (request) header
-                // salt (salt_len) + encrypted packet [type (1) + timestamp (8) + length (2)] + tag (TAG_LEN)
-                self.salt_len + 11 + TAG_LEN
+                // salt (salt_len) + identity header if multi-user (IDENTITY_HEADER_LEN)
+                // + encrypted packet [type (1) + timestamp (8) + length (2)] + tag (TAG_LEN)
+                self.salt_len + self.identity_header_len() + 11 + TAG_LEN
             }
             ShadowsocksStreamType::AEAD2022Client => {
                 // Expect the server (response) header
@@ -397,28 +425,66 @@ impl ShadowsocksStream {
         }
     }
 
+    fn identity_header_len(&self) -> usize {
+        if self.users.is_some() {
+            IDENTITY_HEADER_LEN
+        } else {
+            0
+        }
+    }
+
+    /// Switches to the key of the user identified by the identity header.
+    fn process_identity_header(&mut self) -> std::io::Result<()> {
+        let users = self.users.as_ref().unwrap();
+        let salt = &self.unprocessed_buf[0..self.salt_len];
+        let header = &self.unprocessed_buf[self.salt_len..self.salt_len + IDENTITY_HEADER_LEN];
+        let user = users.identify(salt, header).ok_or_else(|| {
+            auto_ban::rejected(
+                std::io::ErrorKind::PermissionDenied,
+                "unknown user in identity header",
+            )
+        })?;
+
+        self.key = user.key.clone();
+        self.user_name = Some(user.name.clone());
+
+        // The response is encrypted with the user's key.
+        let session_key = self.key.create_session_key(&self.encrypt_iv);
+        let unbound_key = UnboundKey::new(self.algorithm, &session_key).unwrap();
+        self.sealing_key = SealingKey::new(unbound_key, IncreasingSequence::new());
+        Ok(())
+    }
+
     fn process_read_header(&mut self) -> std::io::Result<()> {
         match self.stream_type {
             ShadowsocksStreamType::Aead => {
-                if let Some(salt_checker) = &self.salt_checker {
+                if let Some(replay_filter) = &self.replay_filter {
                     let decrypt_iv = &self.unprocessed_buf[0..self.salt_len];
-                    if !salt_checker.lock().insert_and_check(decrypt_iv) {
-                        return Err(std::io::Error::other("got duplicate salt"));
+                    if !replay_filter.check(decrypt_iv) {
+                        return Err(auto_ban::rejected(
+                            std::io::ErrorKind::InvalidData,
+                            "got duplicate salt",
+                        ));
                     }
                 }
                 self.process_opening_key()?;
                 self.unprocessed_start_offset += self.salt_len;
             }
             ShadowsocksStreamType::AEAD2022Server => {
+                if self.users.is_some() {
+                    self.process_identity_header()?;
+                }
                 self.process_opening_key()?;
 
+                let header_start = self.salt_len + self.identity_header_len();
+
                 if self
                     .opening_key
                     .as_mut()
                     .unwrap()
                     .open_in_place(
                         Aad::empty(),
-                        &mut self.unprocessed_buf[self.salt_len..self.salt_len + 11 + TAG_LEN],
+                        &mut self.unprocessed_buf[header_start..header_start + 11 + TAG_LEN],
                     )
                     .is_err()
                 {
@@ -428,18 +494,18 @@ impl ShadowsocksStream {
                     ));
                 }
 
-                if self.unprocessed_buf[self.salt_len] != 0 {
+                if self.unprocessed_buf[header_start] != 0 {
                     // HeaderTypeClientStream = 0
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
                         format!(
                             "invalid client header type, got {}",
-                            self.unprocessed_buf[self.salt_len]
+                            self.unprocessed_buf[header_start]
                         ),
                     ));
                 }
 
-                let timestamp_bytes = &self.unprocessed_buf[self.salt_len + 1..self.salt_len + 9];
+                let timestamp_bytes = &self.unprocessed_buf[header_start + 1..header_start + 9];
                 let timestamp_secs = u64::from_be_bytes(timestamp_bytes.try_into().unwrap());
                 let current_time_secs = current_time_secs();
                 if current_time_secs >= timestamp_secs {
@@ -459,21 +525,24 @@ impl ShadowsocksStream {
                 }
 
                 let decrypt_iv = &self.unprocessed_buf[0..self.salt_len];
-                if let Some(salt_checker) = &self.salt_checker
-                    && !salt_checker.lock().insert_and_check(decrypt_iv)
+                if let Some(replay_filter) = &self.replay_filter
+                    && !replay_filter.check(decrypt_iv)
                 {
-                    return Err(std::io::Error::other("got duplicate salt"));
+                    return Err(auto_ban::rejected(
+                        std::io::ErrorKind::InvalidData,
+                        "got duplicate salt",
+                    ));
                 }
 
                 // Needed for writing the response
                 self.decrypt_iv = Some(decrypt_iv.to_vec().into_boxed_slice());
 
-                let variable_header_len = ((self.unprocessed_buf[self.salt_len + 9] as usize) << 8)
-                    | (self.unprocessed_buf[self.salt_len + 10] as usize);
+                let variable_header_len = ((self.unprocessed_buf[header_start + 9] as usize) << 8)
+                    | (self.unprocessed_buf[header_start + 10] as usize);
 
                 self.unprocessed_pending_len = Some(variable_header_len);
 
-                self.unprocessed_start_offset += self.salt_len + 11 + TAG_LEN;
+                self.unprocessed_start_offset += header_start + 11 + TAG_LEN;
             }
             ShadowsocksStreamType::AEAD2022Client => {
                 self.process_opening_key()?;
@@ -525,10 +594,13 @@ impl ShadowsocksStream {
                     }
                 }
 
-                if let Some(salt_checker) = &self.salt_checker {
+                if let Some(replay_filter) = &self.replay_filter {
                     let decrypt_iv = &self.unprocessed_buf[0..self.salt_len];
-                    if !salt_checker.lock().insert_and_check(decrypt_iv) {
-                        return Err(std::io::Error::other("got duplicate salt"));
+                    if !replay_filter.check(decrypt_iv) {
+                        return Err(auto_ban::rejected(
+                            std::io::ErrorKind::InvalidData,
+                            "got duplicate salt",
+                        ));
                     }
                 }
 
@@ -619,6 +691,15 @@ impl ShadowsocksStream {
                 self.write_cache[0..self.salt_len].copy_from_slice(&self.encrypt_iv);
                 self.write_cache_end_offset = self.salt_len;
 
+                if let Some(ref identity_keys) = self.identity_keys {
+                    let headers_len = identity_keys.headers_len();
+                    identity_keys.write_headers(
+                        &self.encrypt_iv,
+                        &mut self.write_cache[self.salt_len..self.salt_len + headers_len],
+                    );
+                    self.write_cache_end_offset += headers_len;
+                }
+
                 let mut request_header = allocate_vec(1 + 8 + 2);
 
                 // HeaderTypeClientStream = 0
@@ -631,7 +712,7 @@ impl ShadowsocksStream {
                 assert!(
                     buf_len
                         <= self.write_cache.len()
-                            - self.salt_len
+                            - self.write_cache_end_offset
                             - (request_header.len() + TAG_LEN)
                             - TAG_LEN
                 );
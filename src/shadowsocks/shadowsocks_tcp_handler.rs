@@ -1,37 +1,47 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
-use parking_lot::Mutex;
+use log::warn;
 use rand::{Rng, RngCore};
 use tokio::io::AsyncWriteExt;
 
-use super::salt_checker::SaltChecker;
-use super::timed_salt_checker::TimedSaltChecker;
 use crate::address::{Address, NetLocation, ResolvedLocation};
 use crate::async_stream::AsyncMessageStream;
 use crate::async_stream::AsyncStream;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::config::{UserConfig, decode_aead2022_user_key};
+use crate::replay_filter::ReplayFilter;
 use crate::socks_handler::{read_location, write_location_to_vec};
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
 use crate::uot::{UOT_V1_MAGIC_ADDRESS, UOT_V2_MAGIC_ADDRESS, UotV1ServerStream, UotV2Stream};
+use crate::user_store::{UserIndex, get_user_store, set_authenticated_user};
 use crate::util::write_all;
 
 use super::blake3_key::Blake3Key;
 use super::default_key::DefaultKey;
+use super::identity_header::{IdentityKeys, ShadowsocksUsers};
 use super::shadowsocks_cipher::ShadowsocksCipher;
 use super::shadowsocks_key::ShadowsocksKey;
 use super::shadowsocks_stream::ShadowsocksStream;
 use super::shadowsocks_stream_type::ShadowsocksStreamType;
 
+/// Time for which the salts of handshakes are remembered to reject replays.
+const SALT_WINDOW: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct ShadowsocksTcpHandler {
     cipher: ShadowsocksCipher,
     key: Arc<Box<dyn ShadowsocksKey>>,
     aead2022: bool,
-    salt_checker: Option<Arc<Mutex<dyn SaltChecker>>>,
+    replay_filter: Option<Arc<ReplayFilter>>,
+    /// Identity headers to send, for a client of a multi-user AEAD2022 server.
+    identity_keys: Option<Arc<IdentityKeys>>,
+    /// Users identified by the identity header, for a multi-user AEAD2022 server.
+    users: Option<UserIndex<ShadowsocksUsers>>,
     udp_enabled: bool,
     /// Proxy selector for server handler use. None when used as client handler.
     proxy_selector: Option<Arc<ClientProxySelector>>,
@@ -53,7 +63,9 @@ impl ShadowsocksTcpHandler {
             cipher,
             key,
             aead2022: false,
-            salt_checker: None,
+            replay_filter: Some(Arc::new(ReplayFilter::new("shadowsocks", SALT_WINDOW))),
+            identity_keys: None,
+            users: None,
             udp_enabled,
             proxy_selector: Some(proxy_selector),
         }
@@ -69,16 +81,24 @@ impl ShadowsocksTcpHandler {
             cipher,
             key,
             aead2022: false,
-            salt_checker: None,
+            replay_filter: None,
+            identity_keys: None,
+            users: None,
             udp_enabled,
             proxy_selector: None,
         }
     }
 
-    /// Create a new AEAD2022 handler for server use
+    /// Create a new AEAD2022 handler for server use.
+    ///
+    /// When `users` is not empty or there is a `user_group`, this is a multi-user
+    /// server: `key_bytes` is the identity PSK, and clients authenticate as one of the
+    /// users, whose password is their PSK.
     pub fn new_aead2022_server(
         cipher: ShadowsocksCipher,
         key_bytes: &[u8],
+        users: Vec<UserConfig>,
+        user_group: Option<&str>,
         udp_enabled: bool,
         proxy_selector: Arc<ClientProxySelector>,
     ) -> Self {
@@ -86,35 +106,96 @@ impl ShadowsocksTcpHandler {
             key_bytes.to_vec().into_boxed_slice(),
             cipher.algorithm().key_len(),
         )));
+        let users = if users.is_empty() && user_group.is_none() {
+            None
+        } else {
+            let store = user_group.map(|name| {
+                get_user_store(name)
+                    .unwrap_or_else(|| panic!("Unknown user group {name} (should be validated)"))
+            });
+            let identity_key: Box<[u8]> = key_bytes.into();
+            Some(UserIndex::new(users, store, move |users| {
+                let user_keys = users
+                    .iter()
+                    .filter_map(|user| {
+                        let password = user.password.as_ref()?;
+                        match decode_aead2022_user_key(&cipher, password) {
+                            Ok(user_key) => Some((user.name.clone(), user_key)),
+                            Err(e) => {
+                                warn!("Ignoring shadowsocks user {}: {e}", user.name);
+                                None
+                            }
+                        }
+                    })
+                    .collect();
+                ShadowsocksUsers::new(
+                    identity_key.clone(),
+                    user_keys,
+                    cipher.algorithm().key_len(),
+                )
+            }))
+        };
         Self {
             cipher,
             key,
             aead2022: true,
-            salt_checker: Some(Arc::new(Mutex::new(TimedSaltChecker::new(60)))),
+            replay_filter: Some(Arc::new(ReplayFilter::new("shadowsocks", SALT_WINDOW))),
+            identity_keys: None,
+            users,
             udp_enabled,
             proxy_selector: Some(proxy_selector),
         }
     }
 
-    /// Create a new AEAD2022 handler for client use
+    /// Create a new AEAD2022 handler for client use.
+    ///
+    /// `identity_keys` are the identity PSKs of a multi-user server, and of any relays
+    /// in front of it, with `key_bytes` being the user PSK.
     pub fn new_aead2022_client(
         cipher: ShadowsocksCipher,
         key_bytes: &[u8],
+        identity_keys: &[Box<[u8]>],
         udp_enabled: bool,
     ) -> Self {
         let key: Arc<Box<dyn ShadowsocksKey>> = Arc::new(Box::new(Blake3Key::new(
             key_bytes.to_vec().into_boxed_slice(),
             cipher.algorithm().key_len(),
         )));
+        let identity_keys = if identity_keys.is_empty() {
+            None
+        } else {
+            Some(Arc::new(IdentityKeys::new(identity_keys, key_bytes)))
+        };
         Self {
             cipher,
             key,
             aead2022: true,
-            salt_checker: Some(Arc::new(Mutex::new(TimedSaltChecker::new(60)))),
+            replay_filter: Some(Arc::new(ReplayFilter::new("shadowsocks", SALT_WINDOW))),
+            identity_keys,
+            users: None,
             udp_enabled,
             proxy_selector: None,
         }
     }
+
+    fn new_client_stream(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        stream_type: ShadowsocksStreamType,
+    ) -> ShadowsocksStream {
+        let client_stream = ShadowsocksStream::new(
+            client_stream,
+            stream_type,
+            self.cipher.algorithm(),
+            self.cipher.salt_len(),
+            self.key.clone(),
+            self.replay_filter.clone(),
+        );
+        match self.identity_keys {
+            Some(ref identity_keys) => client_stream.with_identity_keys(identity_keys.clone()),
+            None => client_stream,
+        }
+    }
 }
 
 #[async_trait]
@@ -135,14 +216,22 @@ impl TcpServerHandler for ShadowsocksTcpHandler {
             self.cipher.algorithm(),
             self.cipher.salt_len(),
             self.key.clone(),
-            self.salt_checker.clone(),
+            self.replay_filter.clone(),
         );
+        if let Some(ref users) = self.users {
+            server_stream = server_stream.with_users(users.get());
+        }
 
         let mut stream_reader = StreamReader::new_with_buffer_size(1024);
 
         // Blocks waiting for the location since the client always sends it before expecting a response.
         let remote_location = read_location(&mut server_stream, &mut stream_reader).await?;
 
+        if let Some(user_name) = server_stream.user_name() {
+            log::debug!("Shadowsocks user {} -> {}", user_name, remote_location);
+            set_authenticated_user(user_name);
+        }
+
         if self.aead2022 {
             let padding_len = stream_reader.read_u16_be(&mut server_stream).await?;
 
@@ -272,14 +361,8 @@ impl TcpClientHandler for ShadowsocksTcpHandler {
             ShadowsocksStreamType::Aead
         };
 
-        let mut client_stream: Box<dyn AsyncStream> = Box::new(ShadowsocksStream::new(
-            client_stream,
-            stream_type,
-            self.cipher.algorithm(),
-            self.cipher.salt_len(),
-            self.key.clone(),
-            self.salt_checker.clone(),
-        ));
+        let mut client_stream: Box<dyn AsyncStream> =
+            Box::new(self.new_client_stream(client_stream, stream_type));
 
         let mut location_vec = write_location_to_vec(remote_location.location());
 
@@ -314,6 +397,7 @@ impl TcpClientHandler for ShadowsocksTcpHandler {
         client_stream: Box<dyn AsyncStream>,
         target: ResolvedLocation,
     ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        use crate::uot::uot_common::write_v2_connect_request;
         use crate::uot::{UOT_V2_MAGIC_ADDRESS, UotV2Stream};
 
         let stream_type = if self.aead2022 {
@@ -322,14 +406,8 @@ impl TcpClientHandler for ShadowsocksTcpHandler {
             ShadowsocksStreamType::Aead
         };
 
-        let mut client_stream: Box<dyn AsyncStream> = Box::new(ShadowsocksStream::new(
-            client_stream,
-            stream_type,
-            self.cipher.algorithm(),
-            self.cipher.salt_len(),
-            self.key.clone(),
-            self.salt_checker.clone(),
-        ));
+        let mut client_stream: Box<dyn AsyncStream> =
+            Box::new(self.new_client_stream(client_stream, stream_type));
 
         // UoT V2 connect mode: Single destination. Writes magic address first.
         let magic_location =
@@ -349,10 +427,7 @@ impl TcpClientHandler for ShadowsocksTcpHandler {
         write_all(&mut client_stream, &location_vec).await?;
 
         // Writes UoT V2 request header: isConnect(1) + SOCKS address
-        let mut uot_header = Vec::with_capacity(64);
-        uot_header.push(1u8); // isConnect = 1 (connect mode)
-        let target_bytes = write_location_to_vec(target.location());
-        uot_header.extend_from_slice(&target_bytes);
+        let uot_header = write_v2_connect_request(target.location());
         write_all(&mut client_stream, &uot_header).await?;
         client_stream.flush().await?;
 
@@ -0,0 +1,241 @@
+//! SIP003 plugin processes.
+//!
+//! A plugin is an external program, such as v2ray-plugin, kcptun or simple-obfs, that
+//! carries shadowsocks connections over its own transport. It listens on
+//! `SS_LOCAL_HOST:SS_LOCAL_PORT` and forwards to `SS_REMOTE_HOST:SS_REMOTE_PORT`, with
+//! its options in `SS_PLUGIN_OPTIONS`.
+//!
+//! For a client, shadowsocks connects to the plugin on a local port, and `SS_REMOTE_*`
+//! is the server. For a server, the plugin listens on the server address in
+//! `SS_REMOTE_*`, and forwards to the shadowsocks listener on a local port in
+//! `SS_LOCAL_*`.
+
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use crate::address::{NetLocation, ResolvedLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::config::ShadowsocksPluginConfig;
+use crate::resolver::Resolver;
+use crate::socket_util::DialOptions;
+use crate::tcp::socket_connector::SocketConnector;
+
+/// How often a server plugin is checked, and restarted if it exited.
+const PLUGIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns an unused localhost address for the local side of a plugin.
+pub fn allocate_local_address() -> io::Result<SocketAddr> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?;
+    listener.local_addr()
+}
+
+/// A running plugin process, which is killed on drop.
+#[derive(Debug)]
+pub struct Sip003Plugin {
+    config: ShadowsocksPluginConfig,
+    remote: NetLocation,
+    local: SocketAddr,
+    child: Child,
+}
+
+impl Sip003Plugin {
+    pub fn start(
+        config: ShadowsocksPluginConfig,
+        remote: NetLocation,
+        local: SocketAddr,
+    ) -> io::Result<Self> {
+        let child = spawn_plugin(&config, &remote, local)?;
+        info!(
+            "Started SIP003 plugin {} (pid {}) for {} <-> {}",
+            config.plugin,
+            child.id(),
+            local,
+            remote
+        );
+        Ok(Self {
+            config,
+            remote,
+            local,
+            child,
+        })
+    }
+
+    /// Local address that the plugin listens on (client) or forwards to (server).
+    pub fn local_address(&self) -> SocketAddr {
+        self.local
+    }
+
+    /// Restarts the plugin if it exited.
+    pub fn ensure_running(&mut self) -> io::Result<()> {
+        if let Some(status) = self.child.try_wait()? {
+            error!(
+                "SIP003 plugin {} exited with {}, restarting",
+                self.config.plugin, status
+            );
+            self.child = spawn_plugin(&self.config, &self.remote, self.local)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Sip003Plugin {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Environment variables passed to a plugin, as defined by SIP003.
+fn plugin_env(
+    config: &ShadowsocksPluginConfig,
+    remote: &NetLocation,
+    local: SocketAddr,
+) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("SS_REMOTE_HOST", remote.address().to_string()),
+        ("SS_REMOTE_PORT", remote.port().to_string()),
+        ("SS_LOCAL_HOST", local.ip().to_string()),
+        ("SS_LOCAL_PORT", local.port().to_string()),
+    ];
+    if let Some(ref plugin_opts) = config.plugin_opts {
+        env.push(("SS_PLUGIN_OPTIONS", plugin_opts.clone()));
+    }
+    env
+}
+
+fn spawn_plugin(
+    config: &ShadowsocksPluginConfig,
+    remote: &NetLocation,
+    local: SocketAddr,
+) -> io::Result<Child> {
+    Command::new(&config.plugin)
+        .envs(plugin_env(config, remote, local))
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to start SIP003 plugin {}: {e}", config.plugin),
+            )
+        })
+}
+
+/// Starts the plugin of a server listening on `bind_address`, returning a task that
+/// restarts it if it exits. The plugin is stopped when the task is aborted.
+pub fn start_server_plugin(
+    config: ShadowsocksPluginConfig,
+    bind_address: NetLocation,
+    local: SocketAddr,
+) -> io::Result<JoinHandle<()>> {
+    let mut plugin = Sip003Plugin::start(config, bind_address, local)?;
+    Ok(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PLUGIN_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(e) = plugin.ensure_running() {
+                error!("Failed to restart SIP003 plugin: {e}");
+            }
+        }
+    }))
+}
+
+/// Socket connector that connects to a client plugin instead of the server, and keeps
+/// the plugin running while the chain is in use.
+#[derive(Debug)]
+pub struct Sip003SocketConnector {
+    inner: Box<dyn SocketConnector>,
+    plugin: Mutex<Sip003Plugin>,
+}
+
+impl Sip003SocketConnector {
+    pub fn new(inner: Box<dyn SocketConnector>, plugin: Sip003Plugin) -> Self {
+        Self {
+            inner,
+            plugin: Mutex::new(plugin),
+        }
+    }
+}
+
+#[async_trait]
+impl SocketConnector for Sip003SocketConnector {
+    async fn connect(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        _address: &ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncStream>> {
+        let local = {
+            let mut plugin = self.plugin.lock().unwrap();
+            plugin.ensure_running()?;
+            plugin.local_address()
+        };
+        let local = ResolvedLocation::with_resolved(
+            NetLocation::from_ip_addr(local.ip(), local.port()),
+            local,
+        );
+        self.inner.connect(resolver, &local).await
+    }
+
+    async fn connect_udp_bidirectional(
+        &self,
+        _resolver: &Arc<dyn Resolver>,
+        _target: ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncMessageStream>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SIP003 plugins only carry TCP connections",
+        ))
+    }
+
+    fn bind_interface(&self) -> Option<&str> {
+        self.inner.bind_interface()
+    }
+
+    fn dial_options(&self) -> DialOptions {
+        self.inner.dial_options()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_local_address() {
+        let address = allocate_local_address().unwrap();
+        assert!(address.ip().is_loopback());
+        assert_ne!(address.port(), 0);
+    }
+
+    #[test]
+    fn test_plugin_env() {
+        let config = ShadowsocksPluginConfig {
+            plugin: "obfs-local".to_string(),
+            plugin_opts: Some("obfs=http".to_string()),
+        };
+        let remote = NetLocation::from_str("example.com:8388", None).unwrap();
+        let local: SocketAddr = "127.0.0.1:10800".parse().unwrap();
+        assert_eq!(
+            plugin_env(&config, &remote, local),
+            vec![
+                ("SS_REMOTE_HOST", "example.com".to_string()),
+                ("SS_REMOTE_PORT", "8388".to_string()),
+                ("SS_LOCAL_HOST", "127.0.0.1".to_string()),
+                ("SS_LOCAL_PORT", "10800".to_string()),
+                ("SS_PLUGIN_OPTIONS", "obfs=http".to_string()),
+            ]
+        );
+
+        let config = ShadowsocksPluginConfig {
+            plugin_opts: None,
+            ..config
+        };
+        assert_eq!(plugin_env(&config, &remote, local).len(), 4);
+    }
+}
@@ -1,4 +1,5 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use argon2::{Config as Argon2Config, ThreadMode, Variant, Version};
 use async_trait::async_trait;
@@ -9,7 +10,9 @@ use super::snell_udp_stream::{SnellUdpClientStream, SnellUdpStream};
 use crate::address::{Address, NetLocation, ResolvedLocation};
 use crate::async_stream::AsyncMessageStream;
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::replay_filter::ReplayFilter;
 use crate::shadowsocks::{
     ShadowsocksCipher, ShadowsocksKey, ShadowsocksStream, ShadowsocksStreamType,
 };
@@ -60,11 +63,24 @@ impl ShadowsocksKey for SnellKey {
     }
 }
 
+// Commands of the version byte 1, which all protocol versions send.
+const COMMAND_PING: u8 = 0;
+// Connect of Snell v3, which also added UDP.
+const COMMAND_CONNECT: u8 = 1;
+// Connect of Snell v2.
+const COMMAND_CONNECT_V2: u8 = 5;
+const COMMAND_UDP: u8 = 6;
+
+/// Time for which the salts of handshakes are remembered to reject replays.
+const SALT_WINDOW: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct SnellServerHandler {
     cipher: ShadowsocksCipher,
     key: Arc<Box<dyn ShadowsocksKey>>,
+    replay_filter: Arc<ReplayFilter>,
     udp_enabled: bool,
+    version: u8,
     proxy_selector: Arc<ClientProxySelector>,
 }
 
@@ -73,6 +89,7 @@ impl SnellServerHandler {
         cipher: ShadowsocksCipher,
         password: &str,
         udp_enabled: bool,
+        version: u8,
         proxy_selector: Arc<ClientProxySelector>,
     ) -> Self {
         let key: Arc<Box<dyn ShadowsocksKey>> = Arc::new(Box::new(SnellKey::new(
@@ -82,7 +99,9 @@ impl SnellServerHandler {
         Self {
             cipher,
             key,
+            replay_filter: Arc::new(ReplayFilter::new("snell", SALT_WINDOW)),
             udp_enabled,
+            version,
             proxy_selector,
         }
     }
@@ -103,42 +122,48 @@ impl TcpServerHandler for SnellServerHandler {
             self.cipher.algorithm(),
             self.cipher.salt_len(),
             self.key.clone(),
-            None,
+            Some(self.replay_filter.clone()),
         );
 
         let mut stream_reader = StreamReader::new_with_buffer_size(400);
 
         let version = stream_reader.read_u8(&mut server_stream).await?;
         if version != 1 {
-            return Err(std::io::Error::other(format!(
-                "unexpected snell version: {version}"
-            )));
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::InvalidData,
+                format!("unexpected snell version: {version}"),
+            ));
         }
 
         let command_type = stream_reader.read_u8(&mut server_stream).await?;
         let is_udp = match command_type {
-            0 => {
-                // Ping command
+            COMMAND_PING => {
                 write_all(&mut server_stream, &[0x01]).await?;
                 server_stream.flush().await?;
                 return Err(std::io::Error::other("responded to ping"));
             }
-            1 | 5 => {
-                // 1 is Connect, used by Snell v3
-                // 5 is Connect v2, used by Snell v2
-                false
+            COMMAND_CONNECT_V2 => false,
+            COMMAND_CONNECT | COMMAND_UDP if self.version < 3 => {
+                return Err(auto_ban::rejected(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "Got snell v3 command {command_type} on a snell v{} server",
+                        self.version
+                    ),
+                ));
             }
-            6 => {
-                // UDP command
+            COMMAND_CONNECT => false,
+            COMMAND_UDP => {
                 if !self.udp_enabled {
                     return Err(std::io::Error::other("snell UDP requested but not enabled"));
                 }
                 true
             }
             unknown_command => {
-                return Err(std::io::Error::other(format!(
-                    "Got unknown command: {unknown_command}"
-                )));
+                return Err(auto_ban::rejected(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Got unknown command: {unknown_command}"),
+                ));
             }
         };
 
@@ -204,10 +229,11 @@ pub struct SnellClientHandler {
     cipher: ShadowsocksCipher,
     key: Arc<Box<dyn ShadowsocksKey>>,
     udp_enabled: bool,
+    version: u8,
 }
 
 impl SnellClientHandler {
-    pub fn new(cipher: ShadowsocksCipher, password: &str, udp_enabled: bool) -> Self {
+    pub fn new(cipher: ShadowsocksCipher, password: &str, udp_enabled: bool, version: u8) -> Self {
         let key: Arc<Box<dyn ShadowsocksKey>> = Arc::new(Box::new(SnellKey::new(
             password,
             cipher.algorithm().key_len(),
@@ -216,6 +242,7 @@ impl SnellClientHandler {
             cipher,
             key,
             udp_enabled,
+            version,
         }
     }
 }
@@ -242,11 +269,17 @@ impl TcpClientHandler for SnellClientHandler {
             return Err(std::io::Error::other("hostname is too long"));
         }
 
+        let command = if self.version < 3 {
+            COMMAND_CONNECT_V2
+        } else {
+            COMMAND_CONNECT
+        };
+
         write_all(
             &mut client_stream,
             &[
                 1, // snell version,
-                1, // connect command,
+                command,
                 0, // client id length,
                 hostname_bytes.len() as u8,
             ],
@@ -288,7 +321,7 @@ impl TcpClientHandler for SnellClientHandler {
     }
 
     fn supports_udp_over_tcp(&self) -> bool {
-        self.udp_enabled
+        self.udp_enabled && self.version >= 3
     }
 
     async fn setup_client_udp_bidirectional(
@@ -309,7 +342,7 @@ impl TcpClientHandler for SnellClientHandler {
             &mut ss_stream,
             &[
                 1, // snell version
-                6, // UDP command
+                COMMAND_UDP,
                 0, // client id length
             ],
         )
@@ -0,0 +1,399 @@
+//! Protocol sniffing of intercepted connections.
+//!
+//! Transparent and TUN listeners only know the destination address of a connection.
+//! Sniffing reads the first bytes sent by the client and looks for the domain it is
+//! connecting to, in the server name of a TLS ClientHello or the Host header of an HTTP
+//! request, so that domain rules apply to the connection. The bytes read are forwarded
+//! to the destination afterwards. UDP flows are sniffed for the ClientHello in QUIC
+//! Initial packets in the same way.
+//!
+//! The connection is routed by the sniffed domain but still connects to the original
+//! address, unless `override_destination` is set, in which case the domain is resolved
+//! again or sent to the proxy.
+
+mod quic;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::{Instant, timeout_at};
+
+use crate::address::{Address, NetLocation, ResolvedLocation};
+use crate::buf_reader::BufReader;
+use crate::dispatch_handler::{ParseResult, parse_request_head};
+use crate::fake_ip;
+use crate::util::strip_host_port;
+
+use self::quic::QuicSniffer;
+
+/// How long to wait for the client to send enough data. Connections where the server
+/// speaks first, like SSH or SMTP, are delayed by this much.
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Sniffing stops once this much was read, which is a TLS record of the largest size.
+const MAX_SNIFF_LEN: usize = TLS_HEADER_LEN + 16384;
+
+const READ_BUFFER_LEN: usize = 4096;
+
+/// How many datagrams of a UDP flow are sniffed at most. Large ClientHellos, like ones
+/// with post-quantum key shares, are split across several QUIC Initial packets.
+const MAX_SNIFF_DATAGRAMS: usize = 8;
+
+const TLS_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_TYPE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const SERVER_NAME_TYPE_HOSTNAME: u8 = 0;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Sniffed {
+    /// The data isn't the start of a protocol with a domain.
+    NotMatched,
+    /// More data is needed.
+    Incomplete,
+    Domain(String),
+}
+
+/// Finds the domain of connections to route them by.
+#[derive(Debug, Clone, Copy)]
+pub struct Sniffer {
+    override_destination: bool,
+}
+
+impl Sniffer {
+    pub fn new(override_destination: bool) -> Self {
+        Self {
+            override_destination,
+        }
+    }
+
+    /// Reads the first bytes of a connection to `destination`. Returns the bytes read,
+    /// and the location to route the connection by if a domain was found.
+    pub async fn sniff<S>(
+        &self,
+        stream: &mut S,
+        destination: SocketAddr,
+    ) -> io::Result<(Vec<u8>, Option<ResolvedLocation>)>
+    where
+        S: AsyncRead + Unpin,
+    {
+        if fake_ip::is_fake_ip(destination.ip()) {
+            // Routed by the domain that the address was handed out for.
+            return Ok((Vec::new(), None));
+        }
+
+        let mut data = Vec::new();
+        let domain = read_domain(stream, &mut data).await?;
+        let routing_location = domain.map(|domain| self.routing_location(domain, destination));
+        Ok((data, routing_location))
+    }
+
+    /// Starts sniffing a UDP flow to `destination`.
+    pub fn sniff_datagrams(&self, destination: SocketAddr) -> DatagramSniffer {
+        DatagramSniffer {
+            sniffer: *self,
+            destination,
+            quic: QuicSniffer::default(),
+            datagrams: 0,
+            deadline: Instant::now() + SNIFF_TIMEOUT,
+        }
+    }
+
+    fn routing_location(&self, domain: String, destination: SocketAddr) -> ResolvedLocation {
+        let location = NetLocation::new(Address::Hostname(domain), destination.port());
+        if self.override_destination {
+            ResolvedLocation::new(location)
+        } else {
+            ResolvedLocation::with_resolved(location, destination)
+        }
+    }
+}
+
+/// The result of sniffing a UDP flow.
+#[derive(Debug, Default)]
+pub struct SniffedFlow {
+    /// The location to route the flow by, if a domain was found.
+    pub routing_location: Option<ResolvedLocation>,
+    /// Whether the flow is a QUIC connection.
+    pub is_quic: bool,
+}
+
+/// Sniffs the first datagrams that a client sends in a UDP flow. The datagrams should be
+/// held back until the result is known.
+#[derive(Debug)]
+pub struct DatagramSniffer {
+    sniffer: Sniffer,
+    destination: SocketAddr,
+    quic: QuicSniffer,
+    datagrams: usize,
+    deadline: Instant,
+}
+
+impl DatagramSniffer {
+    /// Sniffs a datagram sent by the client. Returns None if more datagrams are needed.
+    pub fn push(&mut self, datagram: &[u8]) -> Option<SniffedFlow> {
+        self.datagrams += 1;
+        match self.quic.push(datagram) {
+            Sniffed::Domain(domain) => Some(self.sniffed(Some(domain))),
+            Sniffed::Incomplete if self.datagrams < MAX_SNIFF_DATAGRAMS => None,
+            Sniffed::Incomplete | Sniffed::NotMatched => Some(self.finish()),
+        }
+    }
+
+    /// Returns the result without waiting for more datagrams, once the deadline passed.
+    pub fn finish(&self) -> SniffedFlow {
+        self.sniffed(None)
+    }
+
+    /// When to stop waiting for more datagrams.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn sniffed(&self, domain: Option<String>) -> SniffedFlow {
+        // Fake-IP destinations are routed by the domain that the address was handed out for.
+        let domain = domain.filter(|_| !fake_ip::is_fake_ip(self.destination.ip()));
+        SniffedFlow {
+            routing_location: domain
+                .map(|domain| self.sniffer.routing_location(domain, self.destination)),
+            is_quic: self.quic.is_quic(),
+        }
+    }
+}
+
+/// Reads from `stream` into `data` until a domain is found, the data can't have one, or
+/// the client stops sending.
+async fn read_domain<S>(stream: &mut S, data: &mut Vec<u8>) -> io::Result<Option<String>>
+where
+    S: AsyncRead + Unpin,
+{
+    let deadline = Instant::now() + SNIFF_TIMEOUT;
+    let mut buf = [0u8; READ_BUFFER_LEN];
+    loop {
+        match sniff_domain(data) {
+            Sniffed::Domain(domain) => return Ok(Some(domain)),
+            Sniffed::NotMatched => return Ok(None),
+            Sniffed::Incomplete if data.len() >= MAX_SNIFF_LEN => return Ok(None),
+            Sniffed::Incomplete => {}
+        }
+        match timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(Ok(0)) | Err(_) => return Ok(None),
+            Ok(Ok(len)) => data.extend_from_slice(&buf[..len]),
+            Ok(Err(e)) => return Err(e),
+        }
+    }
+}
+
+fn sniff_domain(data: &[u8]) -> Sniffed {
+    match (sniff_tls_server_name(data), sniff_http_host(data)) {
+        (Sniffed::Domain(domain), _) | (_, Sniffed::Domain(domain)) => Sniffed::Domain(domain),
+        (Sniffed::Incomplete, _) | (_, Sniffed::Incomplete) => Sniffed::Incomplete,
+        _ => Sniffed::NotMatched,
+    }
+}
+
+/// Finds the server name of a ClientHello in the first TLS record of `data`.
+fn sniff_tls_server_name(data: &[u8]) -> Sniffed {
+    match data {
+        [] => return Sniffed::Incomplete,
+        [CONTENT_TYPE_HANDSHAKE] => return Sniffed::Incomplete,
+        [CONTENT_TYPE_HANDSHAKE, 0x03, ..] => {}
+        _ => return Sniffed::NotMatched,
+    }
+    if data.len() < TLS_HEADER_LEN {
+        return Sniffed::Incomplete;
+    }
+    let record_len = u16::from_be_bytes([data[3], data[4]]) as usize;
+    if TLS_HEADER_LEN + record_len > MAX_SNIFF_LEN {
+        return Sniffed::NotMatched;
+    }
+    let Some(record) = data.get(TLS_HEADER_LEN..TLS_HEADER_LEN + record_len) else {
+        return Sniffed::Incomplete;
+    };
+    // ClientHellos that don't fit in one record are rare, and not sniffed.
+    let server_name = read_client_hello_server_name(record).ok().flatten();
+    let domain = server_name.as_deref().and_then(to_domain);
+    domain.map_or(Sniffed::NotMatched, Sniffed::Domain)
+}
+
+fn read_client_hello_server_name(record: &[u8]) -> io::Result<Option<String>> {
+    let mut reader = BufReader::new(record);
+    if reader.read_u8()? != HANDSHAKE_TYPE_CLIENT_HELLO {
+        return Ok(None);
+    }
+    reader.skip(3)?; // message length
+    reader.skip(2 + 32)?; // version and random
+    let session_id_len = reader.read_u8()?;
+    reader.skip(session_id_len as usize)?;
+    let cipher_suites_len = reader.read_u16_be()?;
+    reader.skip(cipher_suites_len as usize)?;
+    let compression_methods_len = reader.read_u8()?;
+    reader.skip(compression_methods_len as usize)?;
+
+    let extensions_len = reader.read_u16_be()?;
+    let mut extensions = BufReader::new(reader.read_slice(extensions_len as usize)?);
+    while !extensions.is_consumed() {
+        let extension_type = extensions.read_u16_be()?;
+        let extension_len = extensions.read_u16_be()?;
+        let extension = extensions.read_slice(extension_len as usize)?;
+        if extension_type != EXTENSION_SERVER_NAME {
+            continue;
+        }
+
+        let mut server_names = BufReader::new(extension);
+        let list_len = server_names.read_u16_be()?;
+        let mut list = BufReader::new(server_names.read_slice(list_len as usize)?);
+        while !list.is_consumed() {
+            let name_type = list.read_u8()?;
+            let name_len = list.read_u16_be()?;
+            let name = list.read_str(name_len as usize)?;
+            if name_type == SERVER_NAME_TYPE_HOSTNAME {
+                return Ok(Some(name.to_string()));
+            }
+        }
+        return Ok(None);
+    }
+    Ok(None)
+}
+
+/// Finds the Host header of an HTTP/1 request at the start of `data`.
+fn sniff_http_host(data: &[u8]) -> Sniffed {
+    match parse_request_head(data) {
+        ParseResult::NotHttp => Sniffed::NotMatched,
+        ParseResult::Incomplete => Sniffed::Incomplete,
+        ParseResult::Complete(head) => {
+            let domain = head
+                .host
+                .as_deref()
+                .map(strip_host_port)
+                .and_then(to_domain);
+            domain.map_or(Sniffed::NotMatched, Sniffed::Domain)
+        }
+    }
+}
+
+/// Returns `name` as a lowercase domain, or None if it's an IP address or not a valid
+/// domain.
+fn to_domain(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name);
+    let is_valid = !name.is_empty()
+        && name.len() <= 253
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_'));
+    if !is_valid || name.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    Some(name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    /// Builds a TLS record with a ClientHello that has a server_name extension for
+    /// `server_name`, after another extension.
+    pub(super) fn client_hello_record(server_name: &str) -> Vec<u8> {
+        let mut server_name_list = vec![SERVER_NAME_TYPE_HOSTNAME];
+        server_name_list.extend_from_slice(&(server_name.len() as u16).to_be_bytes());
+        server_name_list.extend_from_slice(server_name.as_bytes());
+
+        let mut extensions = Vec::new();
+        // supported_versions: TLS 1.3
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        extensions.extend_from_slice(&EXTENSION_SERVER_NAME.to_be_bytes());
+        extensions.extend_from_slice(&(server_name_list.len() as u16 + 2).to_be_bytes());
+        extensions.extend_from_slice(&(server_name_list.len() as u16).to_be_bytes());
+        extensions.extend_from_slice(&server_name_list);
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0u8; 32]); // random
+        body.push(32); // session ID
+        body.extend_from_slice(&[0u8; 32]);
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+        body.extend_from_slice(&[0x01, 0x00]); // compression methods
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![HANDSHAKE_TYPE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_sniff_tls_server_name() {
+        let record = client_hello_record("Example.COM");
+        assert_eq!(
+            sniff_domain(&record),
+            Sniffed::Domain("example.com".to_string())
+        );
+        assert_eq!(sniff_domain(&record[..3]), Sniffed::Incomplete);
+        assert_eq!(
+            sniff_domain(&record[..record.len() - 1]),
+            Sniffed::Incomplete
+        );
+
+        let record = client_hello_record("10.0.0.1");
+        assert_eq!(sniff_domain(&record), Sniffed::NotMatched);
+    }
+
+    #[test]
+    fn test_sniff_http_host() {
+        let request = b"GET / HTTP/1.1\r\nHost: www.example.com:8080\r\n\r\n";
+        assert_eq!(
+            sniff_domain(request),
+            Sniffed::Domain("www.example.com".to_string())
+        );
+        assert_eq!(sniff_domain(&request[..20]), Sniffed::Incomplete);
+        assert_eq!(
+            sniff_domain(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+            Sniffed::NotMatched
+        );
+        assert_eq!(
+            sniff_domain(b"SSH-2.0-OpenSSH_9.6\r\n"),
+            Sniffed::NotMatched
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sniff_stream() {
+        let destination: SocketAddr = "93.184.216.34:443".parse().unwrap();
+        let record = client_hello_record("example.com");
+
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let (write_result, sniff_result) = tokio::join!(
+            async {
+                // The record is sent in two parts.
+                client.write_all(&record[..100]).await?;
+                client.write_all(&record[100..]).await
+            },
+            Sniffer::new(false).sniff(&mut server, destination)
+        );
+        write_result.unwrap();
+        let (data, routing_location) = sniff_result.unwrap();
+        assert_eq!(data, record);
+        let routing_location = routing_location.unwrap();
+        assert_eq!(
+            routing_location.location(),
+            &NetLocation::new(Address::Hostname("example.com".to_string()), 443)
+        );
+        assert_eq!(routing_location.resolved_addr(), Some(destination));
+
+        // Nothing is read when the client waits for the server to speak first.
+        let (_client, mut server) = tokio::io::duplex(1024);
+        let (data, routing_location) = Sniffer::new(true)
+            .sniff(&mut server, destination)
+            .await
+            .unwrap();
+        assert!(data.is_empty());
+        assert!(routing_location.is_none());
+    }
+}
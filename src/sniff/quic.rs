@@ -0,0 +1,489 @@
+//! Sniffing of QUIC Initial packets.
+//!
+//! The Initial packets of a QUIC connection are encrypted with keys derived from the
+//! destination connection ID, which is sent in the clear (RFC 9001, section 5.2), so
+//! the ClientHello in their CRYPTO frames can be read to find the server name.
+
+use std::io;
+
+use aws_lc_rs::aead::quic::{AES_128, HeaderProtectionKey};
+use aws_lc_rs::aead::{AES_128_GCM, Aad, LessSafeKey, Nonce, UnboundKey};
+use aws_lc_rs::hkdf::{HKDF_SHA256, KeyType, Prk, Salt};
+
+use super::{HANDSHAKE_TYPE_CLIENT_HELLO, Sniffed, read_client_hello_server_name, to_domain};
+use crate::buf_reader::BufReader;
+
+/// ClientHellos larger than this are not sniffed.
+const MAX_CRYPTO_LEN: usize = 16384;
+
+const MAX_CONNECTION_ID_LEN: usize = 20;
+const MAX_PACKET_NUMBER_LEN: usize = 4;
+const SAMPLE_LEN: usize = 16;
+
+const FRAME_TYPE_PADDING: u64 = 0x00;
+const FRAME_TYPE_PING: u64 = 0x01;
+const FRAME_TYPE_ACK: u64 = 0x02;
+const FRAME_TYPE_ACK_ECN: u64 = 0x03;
+const FRAME_TYPE_CRYPTO: u64 = 0x06;
+
+struct QuicVersion {
+    version: u32,
+    /// The long header packet type of Initial packets.
+    initial_type: u8,
+    initial_salt: [u8; 20],
+    key_label: &'static [u8],
+    iv_label: &'static [u8],
+    hp_label: &'static [u8],
+}
+
+const QUIC_VERSIONS: [QuicVersion; 2] = [
+    // QUIC version 1, RFC 9001.
+    QuicVersion {
+        version: 0x00000001,
+        initial_type: 0,
+        initial_salt: [
+            0x38, 0x76, 0x2c, 0xf7, 0xf5, 0x59, 0x34, 0xb3, 0x4d, 0x17, 0x9a, 0xe6, 0xa4, 0xc8,
+            0x0c, 0xad, 0xcc, 0xbb, 0x7f, 0x0a,
+        ],
+        key_label: b"quic key",
+        iv_label: b"quic iv",
+        hp_label: b"quic hp",
+    },
+    // QUIC version 2, RFC 9369.
+    QuicVersion {
+        version: 0x6b3343cf,
+        initial_type: 1,
+        initial_salt: [
+            0x0d, 0xed, 0xe3, 0xde, 0xf7, 0x00, 0xa6, 0xdb, 0x81, 0x93, 0x81, 0xbe, 0x6e, 0x26,
+            0x9d, 0xcb, 0xf9, 0xbd, 0x2e, 0xd9,
+        ],
+        key_label: b"quicv2 key",
+        iv_label: b"quicv2 iv",
+        hp_label: b"quicv2 hp",
+    },
+];
+
+struct OutputLen(usize);
+
+impl KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// HKDF-Expand-Label from TLS 1.3 (RFC 8446, section 7.1), with an empty context.
+fn expand_label(prk: &Prk, label: &[u8], out: &mut [u8]) -> io::Result<()> {
+    const LABEL_PREFIX: &[u8] = b"tls13 ";
+    let out_len = (out.len() as u16).to_be_bytes();
+    let label_len = [(LABEL_PREFIX.len() + label.len()) as u8];
+    let info: [&[u8]; 5] = [&out_len, &label_len, LABEL_PREFIX, label, &[0]];
+    prk.expand(&info, OutputLen(out.len()))
+        .and_then(|okm| okm.fill(out))
+        .map_err(|_| io::Error::other("failed to expand QUIC initial secret"))
+}
+
+/// The keys that protect the Initial packets sent by the client.
+struct InitialSecrets {
+    key: [u8; 16],
+    iv: [u8; 12],
+    hp: [u8; 16],
+}
+
+impl InitialSecrets {
+    fn client(version: &QuicVersion, dcid: &[u8]) -> io::Result<Self> {
+        let initial_secret = Salt::new(HKDF_SHA256, &version.initial_salt).extract(dcid);
+        let mut client_secret = [0u8; 32];
+        expand_label(&initial_secret, b"client in", &mut client_secret)?;
+        let client_secret = Prk::new_less_safe(HKDF_SHA256, &client_secret);
+
+        let mut secrets = Self {
+            key: [0u8; 16],
+            iv: [0u8; 12],
+            hp: [0u8; 16],
+        };
+        expand_label(&client_secret, version.key_label, &mut secrets.key)?;
+        expand_label(&client_secret, version.iv_label, &mut secrets.iv)?;
+        expand_label(&client_secret, version.hp_label, &mut secrets.hp)?;
+        Ok(secrets)
+    }
+}
+
+fn read_varint(reader: &mut BufReader<'_>) -> io::Result<u64> {
+    let first_byte = reader.read_u8()?;
+    let len = 1 << (first_byte >> 6);
+    let mut value = (first_byte & 0x3f) as u64;
+    for _ in 1..len {
+        value = (value << 8) | reader.read_u8()? as u64;
+    }
+    Ok(value)
+}
+
+fn invalid_packet(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Decrypts the client Initial packet at the start of `packet`. Returns its payload and
+/// the length of the packet, or None if it isn't an Initial packet of a known version.
+fn decrypt_initial_packet(packet: &[u8]) -> io::Result<Option<(Vec<u8>, usize)>> {
+    let mut reader = BufReader::new(packet);
+    let first_byte = reader.read_u8()?;
+    // Long header with the fixed bit set.
+    if first_byte & 0xc0 != 0xc0 {
+        return Ok(None);
+    }
+    let version = u32::from_be_bytes(reader.read_slice(4)?.try_into().unwrap());
+    let Some(version) = QUIC_VERSIONS.iter().find(|v| v.version == version) else {
+        return Ok(None);
+    };
+    if (first_byte >> 4) & 0x03 != version.initial_type {
+        return Ok(None);
+    }
+
+    let dcid_len = reader.read_u8()? as usize;
+    if dcid_len > MAX_CONNECTION_ID_LEN {
+        return Err(invalid_packet("QUIC connection ID is too long"));
+    }
+    let dcid = reader.read_slice(dcid_len)?.to_vec();
+    let scid_len = reader.read_u8()? as usize;
+    if scid_len > MAX_CONNECTION_ID_LEN {
+        return Err(invalid_packet("QUIC connection ID is too long"));
+    }
+    reader.skip(scid_len)?;
+    let token_len = read_varint(&mut reader)?;
+    reader.skip(token_len as usize)?;
+    let length = read_varint(&mut reader)? as usize;
+
+    let pn_offset = reader.position();
+    let packet_len = pn_offset
+        .checked_add(length)
+        .filter(|&len| len <= packet.len())
+        .ok_or_else(|| invalid_packet("truncated QUIC packet"))?;
+    if length < MAX_PACKET_NUMBER_LEN + SAMPLE_LEN {
+        return Err(invalid_packet("QUIC packet is too short"));
+    }
+
+    let secrets = InitialSecrets::client(version, &dcid)?;
+    let header_key = HeaderProtectionKey::new(&AES_128, &secrets.hp)
+        .map_err(|_| io::Error::other("invalid QUIC header protection key"))?;
+    // The sample is taken as if the packet number was 4 bytes long.
+    let sample_offset = pn_offset + MAX_PACKET_NUMBER_LEN;
+    let mask = header_key
+        .new_mask(&packet[sample_offset..sample_offset + SAMPLE_LEN])
+        .map_err(|_| io::Error::other("failed to remove QUIC header protection"))?;
+
+    let mut header = packet[..pn_offset + MAX_PACKET_NUMBER_LEN].to_vec();
+    header[0] ^= mask[0] & 0x0f;
+    let pn_len = (header[0] & 0x03) as usize + 1;
+    header.truncate(pn_offset + pn_len);
+    // Clients number their Initial packets from 0, so the truncated packet number is
+    // the full one.
+    let mut packet_number = 0u64;
+    for (byte, mask) in header[pn_offset..].iter_mut().zip(&mask[1..]) {
+        *byte ^= mask;
+        packet_number = (packet_number << 8) | *byte as u64;
+    }
+
+    let mut nonce = secrets.iv;
+    for (byte, pn_byte) in nonce[4..].iter_mut().zip(packet_number.to_be_bytes()) {
+        *byte ^= pn_byte;
+    }
+    let key = LessSafeKey::new(
+        UnboundKey::new(&AES_128_GCM, &secrets.key)
+            .map_err(|_| io::Error::other("invalid QUIC packet key"))?,
+    );
+    let mut payload = packet[pn_offset + pn_len..packet_len].to_vec();
+    let payload_len = key
+        .open_in_place(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header),
+            &mut payload,
+        )
+        .map_err(|_| invalid_packet("failed to decrypt QUIC Initial packet"))?
+        .len();
+    payload.truncate(payload_len);
+    Ok(Some((payload, packet_len)))
+}
+
+/// Collects the CRYPTO frames of the Initial packets sent by a client until the
+/// ClientHello is complete.
+#[derive(Debug, Default)]
+pub(super) struct QuicSniffer {
+    /// The offsets and data of the CRYPTO frames received.
+    fragments: Vec<(usize, Vec<u8>)>,
+    is_quic: bool,
+}
+
+impl QuicSniffer {
+    /// Whether an Initial packet was received.
+    pub(super) fn is_quic(&self) -> bool {
+        self.is_quic
+    }
+
+    /// Sniffs a datagram sent by the client.
+    pub(super) fn push(&mut self, datagram: &[u8]) -> Sniffed {
+        let mut remaining = datagram;
+        // Datagrams can hold several packets, and Initial packets come first.
+        while !remaining.is_empty() {
+            let (payload, packet_len) = match decrypt_initial_packet(remaining) {
+                Ok(Some(packet)) => packet,
+                Ok(None) | Err(_) if self.is_quic => break,
+                Ok(None) | Err(_) => return Sniffed::NotMatched,
+            };
+            self.is_quic = true;
+            if self.read_frames(&payload).is_err() {
+                return Sniffed::NotMatched;
+            }
+            remaining = &remaining[packet_len..];
+        }
+        sniff_client_hello(&self.contiguous_crypto())
+    }
+
+    fn read_frames(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut reader = BufReader::new(payload);
+        while !reader.is_consumed() {
+            match read_varint(&mut reader)? {
+                FRAME_TYPE_PADDING | FRAME_TYPE_PING => {}
+                frame_type @ (FRAME_TYPE_ACK | FRAME_TYPE_ACK_ECN) => {
+                    read_varint(&mut reader)?; // largest acknowledged
+                    read_varint(&mut reader)?; // ACK delay
+                    let range_count = read_varint(&mut reader)?;
+                    read_varint(&mut reader)?; // first ACK range
+                    for _ in 0..range_count {
+                        read_varint(&mut reader)?; // gap
+                        read_varint(&mut reader)?; // ACK range length
+                    }
+                    if frame_type == FRAME_TYPE_ACK_ECN {
+                        for _ in 0..3 {
+                            read_varint(&mut reader)?;
+                        }
+                    }
+                }
+                FRAME_TYPE_CRYPTO => {
+                    let offset = read_varint(&mut reader)? as usize;
+                    let len = read_varint(&mut reader)? as usize;
+                    if offset.saturating_add(len) > MAX_CRYPTO_LEN {
+                        return Err(invalid_packet("QUIC ClientHello is too large"));
+                    }
+                    let data = reader.read_slice(len)?;
+                    self.fragments.push((offset, data.to_vec()));
+                }
+                // Frames like CONNECTION_CLOSE end the packet for sniffing purposes.
+                _ => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the CRYPTO data from the start up to the first gap. Clients may reorder
+    /// and split the ClientHello between packets.
+    fn contiguous_crypto(&mut self) -> Vec<u8> {
+        self.fragments.sort_by_key(|(offset, _)| *offset);
+        let mut data = Vec::new();
+        for (offset, fragment) in &self.fragments {
+            if *offset > data.len() {
+                break;
+            }
+            if offset + fragment.len() > data.len() {
+                data.extend_from_slice(&fragment[data.len() - offset..]);
+            }
+        }
+        data
+    }
+}
+
+/// Finds the server name of the ClientHello at the start of `data`.
+fn sniff_client_hello(data: &[u8]) -> Sniffed {
+    match data {
+        [] => return Sniffed::Incomplete,
+        [HANDSHAKE_TYPE_CLIENT_HELLO, ..] => {}
+        _ => return Sniffed::NotMatched,
+    }
+    if data.len() < 4 {
+        return Sniffed::Incomplete;
+    }
+    let message_len = 4 + u32::from_be_bytes([0, data[1], data[2], data[3]]) as usize;
+    if message_len > MAX_CRYPTO_LEN {
+        return Sniffed::NotMatched;
+    }
+    let Some(message) = data.get(..message_len) else {
+        return Sniffed::Incomplete;
+    };
+    let server_name = read_client_hello_server_name(message).ok().flatten();
+    let domain = server_name.as_deref().and_then(to_domain);
+    domain.map_or(Sniffed::NotMatched, Sniffed::Domain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sniff::tests::client_hello_record;
+
+    const DATAGRAM_LEN: usize = 1200;
+    const TAG_LEN: usize = 16;
+
+    /// Builds a client Initial packet with `frames`, padded to fill a datagram.
+    fn initial_packet(
+        version: &QuicVersion,
+        dcid: &[u8],
+        packet_number: u8,
+        frames: &[u8],
+    ) -> Vec<u8> {
+        let secrets = InitialSecrets::client(version, dcid).unwrap();
+
+        // A 1-byte packet number, and empty source connection ID and token.
+        let mut header = vec![0xc0 | (version.initial_type << 4)];
+        header.extend_from_slice(&version.version.to_be_bytes());
+        header.push(dcid.len() as u8);
+        header.extend_from_slice(dcid);
+        header.extend_from_slice(&[0, 0]);
+        let payload_len = DATAGRAM_LEN - header.len() - 2 - 1 - TAG_LEN;
+        header.extend_from_slice(&(0x4000 | (1 + payload_len + TAG_LEN) as u16).to_be_bytes());
+        let pn_offset = header.len();
+        header.push(packet_number);
+
+        let mut payload = frames.to_vec();
+        payload.resize(payload_len, FRAME_TYPE_PADDING as u8);
+        let mut nonce = secrets.iv;
+        nonce[11] ^= packet_number;
+        let key = LessSafeKey::new(UnboundKey::new(&AES_128_GCM, &secrets.key).unwrap());
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(&header),
+            &mut payload,
+        )
+        .unwrap();
+
+        let header_key = HeaderProtectionKey::new(&AES_128, &secrets.hp).unwrap();
+        let sample_offset = MAX_PACKET_NUMBER_LEN - 1;
+        let mask = header_key
+            .new_mask(&payload[sample_offset..sample_offset + SAMPLE_LEN])
+            .unwrap();
+        header[0] ^= mask[0] & 0x0f;
+        header[pn_offset] ^= mask[1];
+
+        header.extend_from_slice(&payload);
+        header
+    }
+
+    fn crypto_frame(offset: usize, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![FRAME_TYPE_CRYPTO as u8];
+        frame.extend_from_slice(&(0x4000 | offset as u16).to_be_bytes());
+        frame.extend_from_slice(&(0x4000 | data.len() as u16).to_be_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn test_initial_secrets() {
+        // RFC 9001, appendix A.1, and RFC 9369, appendix A.1.
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+
+        let secrets = InitialSecrets::client(&QUIC_VERSIONS[0], &dcid).unwrap();
+        assert_eq!(
+            secrets.key,
+            [
+                0x1f, 0x36, 0x96, 0x13, 0xdd, 0x76, 0xd5, 0x46, 0x77, 0x30, 0xef, 0xcb, 0xe3, 0xb1,
+                0xa2, 0x2d
+            ]
+        );
+        assert_eq!(
+            secrets.iv,
+            [
+                0xfa, 0x04, 0x4b, 0x2f, 0x42, 0xa3, 0xfd, 0x3b, 0x46, 0xfb, 0x25, 0x5c
+            ]
+        );
+        assert_eq!(
+            secrets.hp,
+            [
+                0x9f, 0x50, 0x44, 0x9e, 0x04, 0xa0, 0xe8, 0x10, 0x28, 0x3a, 0x1e, 0x99, 0x33, 0xad,
+                0xed, 0xd2
+            ]
+        );
+
+        let secrets = InitialSecrets::client(&QUIC_VERSIONS[1], &dcid).unwrap();
+        assert_eq!(
+            secrets.key,
+            [
+                0x8b, 0x1a, 0x0b, 0xc1, 0x21, 0x28, 0x42, 0x90, 0xa2, 0x9e, 0x09, 0x71, 0xb5, 0xcd,
+                0x04, 0x5d
+            ]
+        );
+        assert_eq!(
+            secrets.iv,
+            [
+                0x91, 0xf7, 0x3e, 0x23, 0x51, 0xd8, 0xfa, 0x91, 0x66, 0x0e, 0x90, 0x9f
+            ]
+        );
+        assert_eq!(
+            secrets.hp,
+            [
+                0x45, 0xb9, 0x5e, 0x15, 0x23, 0x5d, 0x6f, 0x45, 0xa6, 0xb1, 0x9c, 0xbc, 0xb0, 0x29,
+                0x4b, 0xa9
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_varint() {
+        // RFC 9000, appendix A.1.
+        let data = [
+            0xc2, 0x19, 0x7c, 0x5e, 0xff, 0x14, 0xe8, 0x8c, 0x9d, 0x7f, 0x3e, 0x7d, 0x7b, 0xbd,
+            0x25,
+        ];
+        let mut reader = BufReader::new(&data);
+        assert_eq!(read_varint(&mut reader).unwrap(), 151_288_809_941_952_652);
+        assert_eq!(read_varint(&mut reader).unwrap(), 494_878_333);
+        assert_eq!(read_varint(&mut reader).unwrap(), 15_293);
+        assert_eq!(read_varint(&mut reader).unwrap(), 37);
+        assert!(reader.is_consumed());
+    }
+
+    #[test]
+    fn test_sniff_initial_packets() {
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let client_hello = client_hello_record("www.Example.com")[5..].to_vec();
+
+        // The second half of the ClientHello arrives first.
+        let mut frames = vec![FRAME_TYPE_PING as u8];
+        frames.extend_from_slice(&crypto_frame(50, &client_hello[50..]));
+        let first = initial_packet(&QUIC_VERSIONS[0], &dcid, 0, &frames);
+        let second = initial_packet(
+            &QUIC_VERSIONS[0],
+            &dcid,
+            1,
+            &crypto_frame(0, &client_hello[..50]),
+        );
+
+        let mut sniffer = QuicSniffer::default();
+        assert_eq!(sniffer.push(&first), Sniffed::Incomplete);
+        assert!(sniffer.is_quic());
+        assert_eq!(
+            sniffer.push(&second),
+            Sniffed::Domain("www.example.com".to_string())
+        );
+
+        let packet = initial_packet(&QUIC_VERSIONS[1], &dcid, 0, &crypto_frame(0, &client_hello));
+        assert_eq!(
+            QuicSniffer::default().push(&packet),
+            Sniffed::Domain("www.example.com".to_string())
+        );
+    }
+
+    #[test]
+    fn test_sniff_not_quic() {
+        // A DNS query.
+        let mut sniffer = QuicSniffer::default();
+        assert_eq!(
+            sniffer.push(&[0x12, 0x34, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00]),
+            Sniffed::NotMatched
+        );
+        assert!(!sniffer.is_quic());
+
+        // An Initial packet that doesn't decrypt.
+        let dcid = [0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        let mut packet = initial_packet(&QUIC_VERSIONS[0], &dcid, 0, &[FRAME_TYPE_PING as u8]);
+        let last = packet.len() - 1;
+        packet[last] ^= 0xff;
+        assert_eq!(QuicSniffer::default().push(&packet), Sniffed::NotMatched);
+    }
+}
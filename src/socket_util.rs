@@ -1,24 +1,378 @@
+use std::ffi::CStr;
+use std::fmt::Debug;
 use std::mem::ManuallyDrop;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd};
 use std::path::Path;
+use std::sync::{Arc, LazyLock};
 
+use parking_lot::RwLock;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 
+use crate::config::{ClientConfig, TcpConfig};
+use crate::systemd;
+use crate::tun::protect_socket;
+
+/// Options of the outbound sockets of a client config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DialOptions {
+    /// Network interface to bind sockets to.
+    pub bind_interface: Option<String>,
+    /// Firewall mark (`SO_MARK`) of sockets, used by policy routing. Linux and Android
+    /// only.
+    pub fwmark: Option<u32>,
+    /// DSCP value (0-63) of sent packets.
+    pub dscp: Option<u8>,
+    /// Creates TCP sockets with Multipath TCP when the kernel supports it. Linux only.
+    pub mptcp: bool,
+}
+
+impl DialOptions {
+    pub fn from_client_config(config: &ClientConfig) -> Self {
+        Self {
+            bind_interface: config.bind_interface.clone().into_option(),
+            fwmark: config.fwmark,
+            dscp: config.dscp,
+            mptcp: config
+                .tcp_settings
+                .as_ref()
+                .is_some_and(|tcp_config| tcp_config.mptcp),
+        }
+    }
+
+    pub fn with_bind_interface(bind_interface: Option<String>) -> Self {
+        Self {
+            bind_interface,
+            ..Default::default()
+        }
+    }
+}
+
+/// Creates the sockets of outbound TCP connections and UDP sessions, including
+/// connections to proxy servers and DNS servers. Listening sockets are not created
+/// through the dialer.
+///
+/// Library users can replace the dialer with `set_dialer`, e.g. to protect sockets from
+/// being routed into a VPN or to create them in another network namespace.
+pub trait Dialer: Send + Sync + Debug {
+    /// Returns a TCP socket that is not connected yet.
+    fn tcp_socket(&self, is_ipv6: bool, options: &DialOptions) -> std::io::Result<Socket>;
+
+    /// Returns a UDP socket that is not bound yet.
+    fn udp_socket(&self, is_ipv6: bool, options: &DialOptions) -> std::io::Result<Socket>;
+}
+
+/// Creates non-blocking sockets with the given options, and protects them with the
+/// socket protector of the TUN device when one is set.
+#[derive(Debug, Default)]
+pub struct DefaultDialer;
+
+impl DefaultDialer {
+    fn prepare_socket(
+        &self,
+        socket: Socket,
+        is_ipv6: bool,
+        options: &DialOptions,
+    ) -> std::io::Result<Socket> {
+        socket.set_nonblocking(true)?;
+        apply_dial_options(&socket, is_ipv6, options)?;
+        protect_socket(socket.as_raw_fd())?;
+        Ok(socket)
+    }
+}
+
+impl Dialer for DefaultDialer {
+    fn tcp_socket(&self, is_ipv6: bool, options: &DialOptions) -> std::io::Result<Socket> {
+        let socket = new_stream_socket(get_domain(is_ipv6), options.mptcp)?;
+        self.prepare_socket(socket, is_ipv6, options)
+    }
+
+    fn udp_socket(&self, is_ipv6: bool, options: &DialOptions) -> std::io::Result<Socket> {
+        let socket = Socket::new(get_domain(is_ipv6), Type::DGRAM, Some(Protocol::UDP))?;
+        self.prepare_socket(socket, is_ipv6, options)
+    }
+}
+
+fn get_domain(is_ipv6: bool) -> Domain {
+    if is_ipv6 { Domain::IPV6 } else { Domain::IPV4 }
+}
+
+/// Set once creating a Multipath TCP socket failed, after which TCP is used.
+#[cfg(target_os = "linux")]
+static MPTCP_UNAVAILABLE: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Creates a Multipath TCP socket if `mptcp` is set and the kernel supports it, or a TCP
+/// socket otherwise.
+pub fn new_stream_socket(domain: Domain, mptcp: bool) -> std::io::Result<Socket> {
+    #[cfg(target_os = "linux")]
+    if mptcp && !MPTCP_UNAVAILABLE.load(std::sync::atomic::Ordering::Relaxed) {
+        let protocol = Protocol::from(libc::IPPROTO_MPTCP);
+        match Socket::new(domain, Type::STREAM, Some(protocol)) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => {
+                log::warn!("Multipath TCP is not available, falling back to TCP: {e}");
+                MPTCP_UNAVAILABLE.store(true, std::sync::atomic::Ordering::Relaxed);
+            }
+        }
+    }
+
+    // This should be handled during config validation.
+    #[cfg(not(target_os = "linux"))]
+    if mptcp {
+        panic!("Could not use Multipath TCP, unsupported platform.")
+    }
+
+    Socket::new(domain, Type::STREAM, Some(Protocol::TCP))
+}
+
+/// Binds a socket to the interface, and sets the fwmark and DSCP value of the options.
+pub fn apply_dial_options(
+    socket: &Socket,
+    is_ipv6: bool,
+    options: &DialOptions,
+) -> std::io::Result<()> {
+    if let Some(ref _interface) = options.bind_interface {
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        socket.bind_device(Some(_interface.as_bytes()))?;
+
+        // This should be handled during config validation.
+        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+        panic!("Could not bind to device, unsupported platform.")
+    }
+
+    if let Some(_fwmark) = options.fwmark {
+        #[cfg(any(target_os = "android", target_os = "linux"))]
+        set_int_option(
+            socket,
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            _fwmark as libc::c_int,
+        )
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!("failed to set fwmark, CAP_NET_ADMIN is required: {e}"),
+            )
+        })?;
+
+        // This should be handled during config validation.
+        #[cfg(not(any(target_os = "android", target_os = "linux")))]
+        panic!("Could not set fwmark, unsupported platform.")
+    }
+
+    if let Some(dscp) = options.dscp {
+        // DSCP is the upper 6 bits of the TOS or traffic class byte.
+        let tos = libc::c_int::from(dscp) << 2;
+        if is_ipv6 {
+            set_int_option(socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+        } else {
+            set_int_option(socket, libc::IPPROTO_IP, libc::IP_TOS, tos)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_int_option(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+static DIALER: LazyLock<RwLock<Arc<dyn Dialer>>> =
+    LazyLock::new(|| RwLock::new(Arc::new(DefaultDialer)));
+
+/// Replaces the dialer of new outbound sockets, or restores the default dialer.
+#[allow(dead_code)] // Used by library embedders
+pub fn set_dialer(dialer: Option<Arc<dyn Dialer>>) {
+    *DIALER.write() = dialer.unwrap_or_else(|| Arc::new(DefaultDialer));
+}
+
+fn dialer() -> Arc<dyn Dialer> {
+    DIALER.read().clone()
+}
+
 pub fn new_udp_socket(
     is_ipv6: bool,
-    bind_interface: Option<String>,
+    options: &DialOptions,
 ) -> std::io::Result<tokio::net::UdpSocket> {
-    let socket = new_socket2_udp_socket(
-        is_ipv6,
-        bind_interface,
-        Some(get_unspecified_socket_addr(is_ipv6)),
-        false,
-    )?;
+    let socket = new_outbound_udp_socket(is_ipv6, options, get_unspecified_socket_addr(is_ipv6))?;
 
     into_tokio_udp_socket(socket)
 }
 
+/// Creates an outbound UDP socket through the dialer, bound to `bind_address`.
+pub fn new_outbound_udp_socket(
+    is_ipv6: bool,
+    options: &DialOptions,
+    bind_address: SocketAddr,
+) -> std::io::Result<socket2::Socket> {
+    let socket = dialer().udp_socket(is_ipv6, options)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SockAddr::from(bind_address))?;
+    Ok(socket)
+}
+
+/// Creates an ICMP socket for echo requests with the given options. An unprivileged
+/// datagram socket is used where the system allows it (`net.ipv4.ping_group_range` on
+/// Linux), and a raw socket otherwise.
+pub fn new_icmp_socket(
+    is_ipv6: bool,
+    options: &DialOptions,
+) -> std::io::Result<tokio::net::UdpSocket> {
+    let protocol = if is_ipv6 {
+        Protocol::ICMPV6
+    } else {
+        Protocol::ICMPV4
+    };
+    let domain = get_domain(is_ipv6);
+    let socket = match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => socket,
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Socket::new(domain, Type::from(libc::SOCK_RAW), Some(protocol))?
+        }
+        Err(e) => return Err(e),
+    };
+    socket.set_nonblocking(true)?;
+    apply_dial_options(&socket, is_ipv6, options)?;
+    protect_socket(socket.as_raw_fd())?;
+
+    // The datagram API of UdpSocket works for ICMP sockets too.
+    into_tokio_udp_socket(socket)
+}
+
+/// An outbound UDP socket for sessions whose destinations can be IPv4 or IPv6, like the
+/// UDP sessions of Hysteria2 and TUIC clients. An IPv6 socket accepting IPv4-mapped
+/// addresses is used, or an IPv4 socket on hosts without IPv6.
+///
+/// IPv4 destinations are mapped to IPv6 when sending, and the sources of received
+/// packets are converted back, so callers only see the addresses of the destinations.
+#[derive(Debug)]
+pub struct DualStackUdpSocket {
+    socket: tokio::net::UdpSocket,
+    is_ipv6: bool,
+}
+
+impl DualStackUdpSocket {
+    pub fn new(options: &DialOptions) -> std::io::Result<Self> {
+        let socket = match dialer().udp_socket(true, options) {
+            Ok(socket) => socket,
+            Err(e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) => {
+                let socket =
+                    new_outbound_udp_socket(false, options, get_unspecified_socket_addr(false))?;
+                return Ok(Self {
+                    socket: into_tokio_udp_socket(socket)?,
+                    is_ipv6: false,
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        socket.set_nonblocking(true)?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SockAddr::from(get_unspecified_socket_addr(true)))?;
+        Ok(Self {
+            socket: into_tokio_udp_socket(socket)?,
+            is_ipv6: true,
+        })
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> std::io::Result<usize> {
+        let target = match target {
+            SocketAddr::V4(v4) if self.is_ipv6 => {
+                SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+            }
+            SocketAddr::V6(_) if !self.is_ipv6 => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    "IPv6 is not available on this host",
+                ));
+            }
+            target => target,
+        };
+        self.socket.send_to(buf, target).await
+    }
+
+    pub fn try_recv_from(&self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let (len, addr) = self.socket.try_recv_from(buf)?;
+        Ok((len, to_canonical_socket_addr(addr)))
+    }
+
+    pub async fn readable(&self) -> std::io::Result<()> {
+        self.socket.readable().await
+    }
+}
+
+/// Converts IPv4-mapped IPv6 addresses, as seen on dual-stack sockets, to IPv4.
+pub fn to_canonical_socket_addr(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Lets a socket bound to the unspecified IPv6 address `::` accept IPv4 as well, so that
+/// listening on `[::]` covers both families whatever the default of the host is, e.g.
+/// `net.ipv6.bindv6only` on Linux or the IPv6-only default of BSDs and Windows.
+fn set_dual_stack(socket: &Socket, bind_address: SocketAddr) -> std::io::Result<()> {
+    if let SocketAddr::V6(addr) = bind_address
+        && addr.ip().is_unspecified()
+    {
+        socket.set_only_v6(false)?;
+    }
+    Ok(())
+}
+
+/// Returns the IPv4 and IPv6 addresses of a network interface, with port 0. Link-local
+/// IPv6 addresses have the scope ID of the interface, so that they can be bound.
+pub fn interface_addresses(name: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    let mut addresses = vec![];
+    let mut ifaddr = ifaddrs;
+    while !ifaddr.is_null() {
+        let entry = unsafe { &*ifaddr };
+        ifaddr = entry.ifa_next;
+        if entry.ifa_addr.is_null()
+            || unsafe { CStr::from_ptr(entry.ifa_name) }.to_bytes() != name.as_bytes()
+        {
+            continue;
+        }
+        let address = match libc::c_int::from(unsafe { (*entry.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                SocketAddr::new(IpAddr::V4(ip), 0)
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(entry.ifa_addr as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, addr.sin6_scope_id))
+            }
+            _ => continue,
+        };
+        addresses.push(address);
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(addresses)
+}
+
 fn get_unspecified_socket_addr(is_ipv6: bool) -> SocketAddr {
     if !is_ipv6 {
         SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0)
@@ -43,6 +397,14 @@ pub fn new_socket2_udp_socket_with_buffer_size(
     reuse_port: bool,
     buffer_size: Option<usize>,
 ) -> std::io::Result<socket2::Socket> {
+    // Options of sockets passed by systemd are set by their socket unit.
+    if let Some(bind_address) = bind_address
+        && let Some(socket) = systemd::take_socket(bind_address, Type::DGRAM)?
+    {
+        socket.set_nonblocking(true)?;
+        return Ok(socket);
+    }
+
     let domain = if is_ipv6 { Domain::IPV6 } else { Domain::IPV4 };
     let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
 
@@ -75,6 +437,7 @@ pub fn new_socket2_udp_socket_with_buffer_size(
     }
 
     if let Some(bind_address) = bind_address {
+        set_dual_stack(&socket, bind_address)?;
         socket.bind(&SockAddr::from(bind_address))?;
     }
 
@@ -87,26 +450,15 @@ fn into_tokio_udp_socket(socket: socket2::Socket) -> std::io::Result<tokio::net:
     tokio::net::UdpSocket::from_std(std_udp_socket)
 }
 
+/// Creates an outbound TCP socket through the dialer.
 pub fn new_tcp_socket(
-    bind_interface: Option<String>,
+    options: &DialOptions,
     is_ipv6: bool,
 ) -> std::io::Result<tokio::net::TcpSocket> {
-    let tcp_socket = if is_ipv6 {
-        tokio::net::TcpSocket::new_v6()?
-    } else {
-        tokio::net::TcpSocket::new_v4()?
-    };
-
-    if let Some(_b) = bind_interface {
-        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
-        tcp_socket.bind_device(Some(_b.as_bytes()))?;
-
-        // This should be handled during config validation.
-        #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
-        panic!("Could not bind to device, unsupported platform.")
-    }
-
-    Ok(tcp_socket)
+    let socket = dialer().tcp_socket(is_ipv6, options)?;
+    socket.set_nonblocking(true)?;
+    let std_stream: std::net::TcpStream = socket.into();
+    Ok(tokio::net::TcpSocket::from_std_stream(std_stream))
 }
 
 pub fn set_tcp_keepalive(
@@ -128,22 +480,128 @@ pub fn set_tcp_keepalive(
     Ok(())
 }
 
+/// Sets the no-delay, keepalive and user timeout options of a TCP config on a connected
+/// stream. Keepalive times that the config doesn't set default to `default_idle` and
+/// `default_interval`.
+pub fn configure_tcp_stream(
+    tcp_stream: &tokio::net::TcpStream,
+    config: &TcpConfig,
+    default_idle: std::time::Duration,
+    default_interval: std::time::Duration,
+) -> std::io::Result<()> {
+    if config.no_delay {
+        tcp_stream.set_nodelay(true)?;
+    }
+
+    let idle_time = config
+        .keepalive_idle_secs
+        .map_or(default_idle, std::time::Duration::from_secs);
+    if idle_time.is_zero() {
+        set_tcp_keepalive(tcp_stream, idle_time, idle_time)?;
+    } else {
+        let send_interval = config
+            .keepalive_interval_secs
+            .map_or(default_interval, std::time::Duration::from_secs);
+        set_tcp_keepalive(tcp_stream, idle_time, send_interval)?;
+
+        if let Some(_count) = config.keepalive_count {
+            #[cfg(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "ios",
+                target_os = "linux",
+                target_os = "macos"
+            ))]
+            set_int_option(
+                tcp_stream,
+                libc::IPPROTO_TCP,
+                libc::TCP_KEEPCNT,
+                _count as libc::c_int,
+            )?;
+
+            // This should be handled during config validation.
+            #[cfg(not(any(
+                target_os = "android",
+                target_os = "freebsd",
+                target_os = "ios",
+                target_os = "linux",
+                target_os = "macos"
+            )))]
+            panic!("Could not set keepalive count, unsupported platform.")
+        }
+    }
+
+    if let Some(_timeout) = config.user_timeout_secs {
+        #[cfg(target_os = "linux")]
+        set_int_option(
+            tcp_stream,
+            libc::IPPROTO_TCP,
+            libc::TCP_USER_TIMEOUT,
+            libc::c_int::try_from(_timeout.saturating_mul(1000)).unwrap_or(libc::c_int::MAX),
+        )?;
+
+        // This should be handled during config validation.
+        #[cfg(not(target_os = "linux"))]
+        panic!("Could not set TCP user timeout, unsupported platform.")
+    }
+
+    Ok(())
+}
+
+/// Enables TCP Fast Open on a socket before it connects, so that the first write is sent
+/// in the SYN when the server was connected before.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fast_open_connect(tcp_socket: &tokio::net::TcpSocket) -> std::io::Result<()> {
+    set_int_option(tcp_socket, libc::IPPROTO_TCP, libc::TCP_FASTOPEN_CONNECT, 1)
+}
+
+// This should be handled during config validation.
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fast_open_connect(_tcp_socket: &tokio::net::TcpSocket) -> std::io::Result<()> {
+    panic!("Could not enable TCP Fast Open, unsupported platform.")
+}
+
+/// Enables TCP Fast Open on a listener, so that it accepts data in the SYN of clients.
+#[cfg(target_os = "linux")]
+pub fn set_tcp_fast_open_listen(listener: &tokio::net::TcpListener) -> std::io::Result<()> {
+    // Maximum number of pending connections that sent data in their SYN.
+    set_int_option(listener, libc::IPPROTO_TCP, libc::TCP_FASTOPEN, 256)
+}
+
+// This should be handled during config validation.
+#[cfg(not(target_os = "linux"))]
+pub fn set_tcp_fast_open_listen(_listener: &tokio::net::TcpListener) -> std::io::Result<()> {
+    panic!("Could not enable TCP Fast Open, unsupported platform.")
+}
+
 // TODO: change backlog to Option<u32> and make configuration, backlog -1 uses somaxconn on linux
 // https://github.com/rust-lang/rust/blob/3534594029ed1495290e013647a1f53da561f7f1/library/std/src/os/unix/net/listener.rs#L93
 pub fn new_tcp_listener(
     bind_address: SocketAddr,
     backlog: u32,
     bind_interface: Option<String>,
+    mptcp: bool,
+    reuse_port: bool,
 ) -> std::io::Result<tokio::net::TcpListener> {
-    let domain = if bind_address.is_ipv6() {
-        Domain::IPV6
-    } else {
-        Domain::IPV4
-    };
-    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    // Options of sockets passed by systemd are set by their socket unit.
+    if let Some(socket) = systemd::take_socket(bind_address, Type::STREAM)? {
+        socket.set_nonblocking(true)?;
+        let std_listener: std::net::TcpListener = socket.into();
+        return tokio::net::TcpListener::from_std(std_listener);
+    }
+
+    let socket = new_stream_socket(get_domain(bind_address.is_ipv6()), mptcp)?;
 
     socket.set_nonblocking(true)?;
     socket.set_reuse_address(true)?;
+    if reuse_port {
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+
+        // This should be handled during config validation.
+        #[cfg(not(unix))]
+        panic!("Could not set SO_REUSEPORT, unsupported platform.")
+    }
 
     #[allow(unused_variables)]
     if let Some(ref interface) = bind_interface {
@@ -155,6 +613,7 @@ pub fn new_tcp_listener(
         panic!("Could not bind to device, unsupported platform.")
     }
 
+    set_dual_stack(&socket, bind_address)?;
     socket.bind(&SockAddr::from(bind_address))?;
 
     let backlog = backlog.try_into().unwrap_or(4096);
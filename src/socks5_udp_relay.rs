@@ -3,7 +3,8 @@
 //! This module implements the SOCKS5 UDP relay protocol as specified in RFC 1928.
 //! When a SOCKS5 client sends a UDP ASSOCIATE request, this module creates a
 //! local UDP socket and handles forwarding UDP datagrams between the client
-//! and the target destination.
+//! and the target destination. It also implements the client side of an
+//! association, for SOCKS5 outbounds.
 //!
 //! SOCKS5 UDP datagram format:
 //! ```text
@@ -18,15 +19,17 @@ use std::task::{Context, Poll};
 
 use bytes::BytesMut;
 use log::error;
-use tokio::io::ReadBuf;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
 
 use crate::address::{Address, NetLocation};
 use crate::async_stream::{
-    AsyncFlushMessage, AsyncPing, AsyncReadTargetedMessage, AsyncShutdownMessage,
-    AsyncTargetedMessageStream, AsyncWriteSourcedMessage,
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncReadTargetedMessage,
+    AsyncShutdownMessage, AsyncStream, AsyncTargetedMessageStream, AsyncWriteMessage,
+    AsyncWriteSourcedMessage,
 };
+use crate::socks_handler::write_location_to_vec;
 
 /// SOCKS5 address types
 const ATYP_IPV4: u8 = 0x01;
@@ -104,6 +107,48 @@ impl SocksUdpRelay {
     }
 }
 
+/// Parses the header of a SOCKS5 UDP datagram.
+///
+/// Returns the address of the header and the offset of the payload, or None if the
+/// datagram is truncated, fragmented or has an unknown address type.
+fn parse_datagram_header(data: &[u8]) -> Option<(NetLocation, usize)> {
+    // Header format: RSV(2) + FRAG(1) + ATYP + DST.ADDR + DST.PORT
+    if data.len() < 10 || data[2] != 0 {
+        return None;
+    }
+
+    match data[3] {
+        ATYP_IPV4 => {
+            // 3 (RSV+FRAG) + 1 (ATYP) + 4 (IP) + 2 (PORT)
+            let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+            let port = u16::from_be_bytes([data[8], data[9]]);
+            Some((NetLocation::new(Address::Ipv4(ip), port), 10))
+        }
+        ATYP_IPV6 => {
+            // 3 (RSV+FRAG) + 1 (ATYP) + 16 (IP) + 2 (PORT)
+            if data.len() < 22 {
+                return None;
+            }
+            let ip_bytes: [u8; 16] = data[4..20].try_into().unwrap();
+            let ip = std::net::Ipv6Addr::from(ip_bytes);
+            let port = u16::from_be_bytes([data[20], data[21]]);
+            Some((NetLocation::new(Address::Ipv6(ip), port), 22))
+        }
+        ATYP_DOMAIN => {
+            // 3 (RSV+FRAG) + 1 (ATYP) + 1 (LEN) + DOMAIN + 2 (PORT)
+            let domain_len = data[4] as usize;
+            if data.len() < 5 + domain_len + 2 {
+                return None;
+            }
+            let domain = std::str::from_utf8(&data[5..5 + domain_len]).ok()?;
+            let port = u16::from_be_bytes([data[5 + domain_len], data[6 + domain_len]]);
+            let address = Address::from(domain).ok()?;
+            Some((NetLocation::new(address, port), 5 + domain_len + 2))
+        }
+        _ => None,
+    }
+}
+
 impl AsyncPing for SocksUdpRelay {
     fn supports_ping(&self) -> bool {
         false
@@ -131,69 +176,8 @@ impl AsyncReadTargetedMessage for SocksUdpRelay {
                     *this.client_addr.lock().unwrap() = Some(from_addr);
 
                     // Parse SOCKS5 datagram header to get destination address
-                    // Header format: RSV(2) + FRAG(1) + ATYP + DST.ADDR + DST.PORT
-                    if data.len() < 10 {
-                        // Datagram too short, skip
+                    let Some((target_addr, header_len)) = parse_datagram_header(&data) else {
                         continue;
-                    }
-
-                    // Skip RSV (2 bytes) and FRAG (1 byte)
-                    let atyp = data[3];
-
-                    // Calculate payload start and target address based on ATYP
-                    let (target_addr, header_len) = match atyp {
-                        ATYP_IPV4 => {
-                            // ATYP(1) + IPv4(4) + PORT(2) = 7 bytes after the first 3 bytes
-                            if data.len() < 10 {
-                                continue;
-                            }
-                            let ip = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
-                            let port = u16::from_be_bytes([data[8], data[9]]);
-                            (
-                                NetLocation::new(Address::Ipv4(ip), port),
-                                10, // 3 (RSV+FRAG) + 1 (ATYP) + 4 (IP) + 2 (PORT)
-                            )
-                        }
-                        ATYP_IPV6 => {
-                            // ATYP(1) + IPv6(16) + PORT(2) = 19 bytes after the first 3 bytes
-                            if data.len() < 22 {
-                                continue;
-                            }
-                            let ip_bytes: [u8; 16] = data[4..20].try_into().unwrap();
-                            let ip = std::net::Ipv6Addr::from(ip_bytes);
-                            let port = u16::from_be_bytes([data[20], data[21]]);
-                            (
-                                NetLocation::new(Address::Ipv6(ip), port),
-                                22, // 3 (RSV+FRAG) + 1 (ATYP) + 16 (IP) + 2 (PORT)
-                            )
-                        }
-                        ATYP_DOMAIN => {
-                            // ATYP(1) + LEN(1) + DOMAIN + PORT(2)
-                            if data.len() < 5 {
-                                continue;
-                            }
-                            let domain_len = data[4] as usize;
-                            if data.len() < 5 + domain_len + 2 {
-                                continue;
-                            }
-                            let domain = std::str::from_utf8(&data[5..5 + domain_len])
-                                .ok()
-                                .map(|s| s.to_string());
-                            let port =
-                                u16::from_be_bytes([data[5 + domain_len], data[6 + domain_len]]);
-                            if let Some(d) = domain {
-                                (
-                                    NetLocation::new(Address::from(d.as_str()).unwrap(), port),
-                                    3 + 1 + 1 + domain_len + 2,
-                                )
-                            } else {
-                                continue;
-                            }
-                        }
-                        _ => {
-                            // Unknown ATYP, skip
-                            continue;
-                        }
                     };
 
                     // Extract payload
@@ -309,3 +293,198 @@ impl AsyncShutdownMessage for SocksUdpRelay {
 }
 
 impl AsyncTargetedMessageStream for SocksUdpRelay {}
+
+/// Client side of a SOCKS5 UDP association, used by SOCKS5 outbounds.
+///
+/// Datagrams to the target are sent to the relay of the server with a SOCKS5 header,
+/// and the header is removed from the replies of the relay. The association lasts as
+/// long as the TCP control connection, which this stream holds open until it is shut
+/// down.
+pub struct SocksUdpClientStream {
+    /// Connected to the relay, so that only datagrams from the relay are received.
+    udp_socket: UdpSocket,
+    control_stream: Box<dyn AsyncStream>,
+    /// RSV + FRAG + the address of the target, prepended to every sent datagram.
+    header: Vec<u8>,
+    send_buf: Vec<u8>,
+    recv_buf: Box<[u8]>,
+}
+
+impl SocksUdpClientStream {
+    pub fn new(
+        udp_socket: UdpSocket,
+        control_stream: Box<dyn AsyncStream>,
+        target: &NetLocation,
+    ) -> Self {
+        let mut header = vec![0, 0, 0];
+        header.extend(write_location_to_vec(target));
+        Self {
+            udp_socket,
+            control_stream,
+            header,
+            send_buf: Vec::with_capacity(RECEIVE_BUFFER_SIZE),
+            recv_buf: vec![0u8; RECEIVE_BUFFER_SIZE].into_boxed_slice(),
+        }
+    }
+
+    /// Returns true if the server closed the control connection, which ends the
+    /// association. Data on the control connection is discarded.
+    fn poll_control_closed(&mut self, cx: &mut Context<'_>) -> bool {
+        let mut buf = [0u8; 64];
+        loop {
+            let mut read_buf = ReadBuf::new(&mut buf);
+            match Pin::new(&mut self.control_stream).poll_read(cx, &mut read_buf) {
+                Poll::Ready(Ok(())) if read_buf.filled().is_empty() => return true,
+                Poll::Ready(Ok(())) => continue,
+                Poll::Ready(Err(_)) => return true,
+                Poll::Pending => return false,
+            }
+        }
+    }
+}
+
+impl AsyncPing for SocksUdpClientStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncReadMessage for SocksUdpClientStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.poll_control_closed(cx) {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "SOCKS5 control connection closed",
+            )));
+        }
+
+        loop {
+            let mut recv_buf = ReadBuf::new(&mut this.recv_buf);
+            match this.udp_socket.poll_recv(cx, &mut recv_buf) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let data = recv_buf.filled();
+
+            let Some((_source, header_len)) = parse_datagram_header(data) else {
+                // Not a valid reply of the relay, skip
+                continue;
+            };
+            let payload = &data[header_len..];
+
+            if payload.len() > buf.remaining() {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "UDP payload too large: {} > {}",
+                        payload.len(),
+                        buf.remaining()
+                    ),
+                )));
+            }
+
+            buf.put_slice(payload);
+            return Poll::Ready(Ok(()));
+        }
+    }
+}
+
+impl AsyncWriteMessage for SocksUdpClientStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.send_buf.clear();
+        this.send_buf.extend_from_slice(&this.header);
+        this.send_buf.extend_from_slice(buf);
+        this.udp_socket
+            .poll_send(cx, &this.send_buf)
+            .map(|r| r.map(|_| ()))
+    }
+}
+
+impl AsyncFlushMessage for SocksUdpClientStream {
+    fn poll_flush_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncShutdownMessage for SocksUdpClientStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        // Closing the control connection ends the association on the server.
+        Pin::new(&mut self.get_mut().control_stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncMessageStream for SocksUdpClientStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+
+    #[tokio::test]
+    async fn test_client_stream_through_relay() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let control_client = tokio::net::TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (control_server, _) = listener.accept().await.unwrap();
+
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp_socket
+            .connect(relay.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        let target = NetLocation::from_str("example.com:53", None).unwrap();
+        let mut stream = SocksUdpClientStream::new(udp_socket, Box::new(control_client), &target);
+
+        poll_fn(|cx| Pin::new(&mut stream).poll_write_message(cx, b"query"))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        let (len, client_addr) = relay.recv_from(&mut buf).await.unwrap();
+        let (location, header_len) = parse_datagram_header(&buf[..len]).unwrap();
+        assert_eq!(location, target);
+        assert_eq!(&buf[header_len..len], b"query");
+
+        // Replies carry the address of the target, which is removed.
+        let mut reply = vec![0, 0, 0, ATYP_IPV4, 93, 184, 216, 34, 0, 53];
+        reply.extend_from_slice(b"answer");
+        relay.send_to(&reply, client_addr).await.unwrap();
+        let mut read_buf = ReadBuf::new(&mut buf);
+        poll_fn(|cx| Pin::new(&mut stream).poll_read_message(cx, &mut read_buf))
+            .await
+            .unwrap();
+        assert_eq!(read_buf.filled(), b"answer");
+
+        // Closing the control connection ends the association.
+        drop(control_server);
+        let mut read_buf = ReadBuf::new(&mut buf);
+        let err = poll_fn(|cx| Pin::new(&mut stream).poll_read_message(cx, &mut read_buf))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+}
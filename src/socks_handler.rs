@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::sync::OnceLock;
@@ -6,18 +7,23 @@ use async_trait::async_trait;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::address::{Address, NetLocation, ResolvedLocation};
-use crate::async_stream::AsyncStream;
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
-use crate::resolver::Resolver;
+use crate::rate_limit::RateLimiter;
+use crate::resolver::{Resolver, resolve_single_address};
 use crate::routing::{ServerStream, run_udp_routing};
-use crate::socks5_udp_relay::SocksUdpRelay;
+use crate::socket_util::{DialOptions, new_udp_socket};
+use crate::socks5_udp_relay::{SocksUdpClientStream, SocksUdpRelay};
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
 use crate::uot::{UOT_V1_MAGIC_ADDRESS, UOT_V2_MAGIC_ADDRESS, UotV1ServerStream, UotV2Stream};
+use crate::user_store::{UserIndex, create_user_index, inline_user, set_authenticated_user};
 use crate::util::write_all;
 
+pub const VER_SOCKS4: u8 = 0x04;
 pub const VER_SOCKS5: u8 = 0x05;
 pub const VER_AUTH: u8 = 0x01;
 
@@ -39,9 +45,35 @@ pub const REPLY_SUCCESS: u8 = 0x00;
 pub const REPLY_GENERAL_FAILURE: u8 = 0x01;
 pub const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
 
+// SOCKS4 replies start with a null byte instead of the version
+const SOCKS4_REPLY_GRANTED: [u8; 8] = [0x00, 0x5a, 0, 0, 0, 0, 0, 0];
+const SOCKS4_REPLY_REJECTED: [u8; 8] = [0x00, 0x5b, 0, 0, 0, 0, 0, 0];
+
+/// SOCKS5 passwords by username.
+pub type SocksUsers = UserIndex<HashMap<String, String>>;
+
+/// Creates the users of a SOCKS5 server from its username/password and its user group.
+/// Returns None when the server doesn't require authentication.
+pub fn create_socks_users(
+    credentials: Option<(String, String)>,
+    user_group: Option<&str>,
+) -> Option<SocksUsers> {
+    if credentials.is_none() && user_group.is_none() {
+        return None;
+    }
+    let inline =
+        credentials.and_then(|(username, password)| inline_user(username, Some(password), None));
+    Some(create_user_index(inline, user_group, |users| {
+        users
+            .iter()
+            .filter_map(|user| Some((user.name.clone(), user.password.clone()?)))
+            .collect()
+    }))
+}
+
 #[derive(Debug)]
 pub struct SocksTcpServerHandler {
-    auth_info: Option<(String, String)>,
+    auth_info: Option<SocksUsers>,
     /// Enable UDP functionality (UDP ASSOCIATE and UDP-over-TCP)
     udp_enabled: bool,
     /// IP address to bind UDP sockets on (same as TCP server)
@@ -56,13 +88,13 @@ impl SocksTcpServerHandler {
     /// Create a new SOCKS5 server handler.
     ///
     /// # Arguments
-    /// * `auth_info` - Optional username/password for authentication
+    /// * `auth_info` - Optional users for username/password authentication
     /// * `udp_enabled` - Enable UDP functionality (UDP ASSOCIATE and UDP-over-TCP)
     /// * `bind_ip` - IP address to bind UDP sockets on (should match TCP server)
     /// * `proxy_selector` - Proxy selector for outbound connections
     /// * `resolver` - DNS resolver
     pub fn new(
-        auth_info: Option<(String, String)>,
+        auth_info: Option<SocksUsers>,
         udp_enabled: bool,
         bind_ip: IpAddr,
         proxy_selector: Arc<ClientProxySelector>,
@@ -102,20 +134,20 @@ impl TcpServerHandler for SocksTcpServerHandler {
     }
 }
 
-/// Core SOCKS5 server setup logic.
+/// Core SOCKS5 server setup logic, which also accepts SOCKS4 and SOCKS4a requests.
 /// Can be called from SocksTcpServerHandler or MixedTcpServerHandler.
 ///
 /// Takes ownership of `server_stream` and returns it in the result.
 ///
 /// # Arguments
-/// * `auth_info` - Optional username/password for authentication
+/// * `auth_info` - Optional users for username/password authentication
 /// * `udp_bind_ip` - If Some, UDP is enabled and this is the IP to bind UDP sockets on
 /// * `proxy_selector` - Proxy selector for outbound connections (only cloned if UDP request)
 /// * `resolver` - DNS resolver (only cloned if UDP request)
 /// * `server_stream` - The client TCP stream
 /// * `stream_reader` - Stream reader for parsing
 pub async fn setup_socks_server_stream_inner(
-    auth_info: Option<&(String, String)>,
+    auth_info: Option<&SocksUsers>,
     udp_bind_ip: Option<IpAddr>,
     proxy_selector: &Arc<ClientProxySelector>,
     resolver: &Arc<dyn Resolver>,
@@ -123,8 +155,12 @@ pub async fn setup_socks_server_stream_inner(
     mut stream_reader: StreamReader,
 ) -> std::io::Result<TcpServerSetupResult> {
     let socks_version = stream_reader.read_u8(&mut server_stream).await?;
+    if socks_version == VER_SOCKS4 {
+        return setup_socks4_server_stream(auth_info, proxy_selector, server_stream, stream_reader)
+            .await;
+    }
     if socks_version != VER_SOCKS5 {
-        return Err(std::io::Error::new(
+        return Err(auto_ban::rejected(
             std::io::ErrorKind::InvalidInput,
             format!("Unsupported SOCKS version: {socks_version}"),
         ));
@@ -158,7 +194,7 @@ pub async fn setup_socks_server_stream_inner(
 
     write_all(&mut server_stream, &[VER_SOCKS5, supported_method]).await?;
 
-    if let Some((target_username, target_password)) = auth_info {
+    if let Some(users) = auth_info {
         let auth_version = stream_reader.read_u8(&mut server_stream).await?;
         if auth_version != VER_AUTH {
             return Err(std::io::Error::new(
@@ -190,12 +226,13 @@ pub async fn setup_socks_server_stream_inner(
         };
 
         // TODO: consider reading both username and password before checking.
-        if target_username != username_str {
-            return Err(std::io::Error::new(
+        let users = users.get();
+        let Some((username, target_password)) = users.get_key_value(username_str) else {
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::InvalidInput,
                 "SOCKS username does not match",
             ));
-        }
+        };
 
         let password_len = stream_reader.read_u8(&mut server_stream).await? as usize;
         if password_len == 0 {
@@ -220,11 +257,12 @@ pub async fn setup_socks_server_stream_inner(
         };
 
         if target_password != password_str {
-            return Err(std::io::Error::new(
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::InvalidInput,
                 "SOCKS password does not match",
             ));
         }
+        set_authenticated_user(username);
 
         write_all(&mut server_stream, &[VER_AUTH, RESULT_SUCCESS]).await?;
     }
@@ -382,6 +420,98 @@ pub async fn setup_socks_server_stream_inner(
     })
 }
 
+/// Handles a SOCKS4 or SOCKS4a request, after its version byte.
+///
+/// SOCKS4 only sends a user ID without a password, so servers that require
+/// authentication reject it. Only CONNECT is supported.
+async fn setup_socks4_server_stream(
+    auth_info: Option<&SocksUsers>,
+    proxy_selector: &Arc<ClientProxySelector>,
+    mut server_stream: Box<dyn AsyncStream>,
+    mut stream_reader: StreamReader,
+) -> std::io::Result<TcpServerSetupResult> {
+    let (command, location) = read_socks4_request(&mut server_stream, &mut stream_reader).await?;
+
+    if auth_info.is_some() {
+        write_all(&mut server_stream, &SOCKS4_REPLY_REJECTED).await?;
+        return Err(auto_ban::rejected(
+            std::io::ErrorKind::PermissionDenied,
+            "SOCKS4 request to a server that requires authentication",
+        ));
+    }
+
+    if command != CMD_CONNECT {
+        write_all(&mut server_stream, &SOCKS4_REPLY_REJECTED).await?;
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("Unsupported SOCKS4 command: {command}"),
+        ));
+    }
+
+    Ok(TcpServerSetupResult::TcpForward {
+        remote_location: location,
+        stream: server_stream,
+        need_initial_flush: true,
+        connection_success_response: Some(SOCKS4_REPLY_GRANTED.to_vec().into_boxed_slice()),
+        initial_remote_data: stream_reader.unparsed_data_owned(),
+        proxy_selector: proxy_selector.clone(),
+    })
+}
+
+/// Reads the command and destination of a SOCKS4 request, after its version byte.
+///
+/// SOCKS4a requests have an IP address of 0.0.0.x, with x non-zero, and the hostname
+/// after the user ID.
+async fn read_socks4_request<T: AsyncReadExt + Unpin>(
+    stream: &mut T,
+    stream_reader: &mut StreamReader,
+) -> std::io::Result<(u8, NetLocation)> {
+    let request = stream_reader.read_slice(stream, 7).await?;
+    let command = request[0];
+    let port = u16::from_be_bytes([request[1], request[2]]);
+    let ip = Ipv4Addr::new(request[3], request[4], request[5], request[6]);
+
+    // The user ID is ignored, since it can't be authenticated.
+    read_socks4_string(stream, stream_reader).await?;
+
+    let octets = ip.octets();
+    let address = if octets[..3] == [0, 0, 0] && octets[3] != 0 {
+        let hostname = read_socks4_string(stream, stream_reader).await?;
+        // Parses as Address since some clients pass IP addresses as hostnames.
+        Address::from(&hostname)?
+    } else {
+        Address::Ipv4(ip)
+    };
+    Ok((command, NetLocation::new(address, port)))
+}
+
+/// Reads a null-terminated string of a SOCKS4 request.
+async fn read_socks4_string<T: AsyncReadExt + Unpin>(
+    stream: &mut T,
+    stream_reader: &mut StreamReader,
+) -> std::io::Result<String> {
+    let mut bytes = vec![];
+    loop {
+        let byte = stream_reader.read_u8(stream).await?;
+        if byte == 0 {
+            break;
+        }
+        if bytes.len() == 255 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "SOCKS4 string is too long",
+            ));
+        }
+        bytes.push(byte);
+    }
+    String::from_utf8(bytes).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Failed to decode SOCKS4 string: {e}"),
+        )
+    })
+}
+
 /// Handle SOCKS5 UDP ASSOCIATE command.
 ///
 /// Takes ownership of `server_stream` for use in the spawned UDP relay task.
@@ -493,7 +623,15 @@ async fn run_udp_associate(
 
     // Runs per-destination routing in parallel with TCP monitoring.
     tokio::select! {
-        result = run_udp_routing(ServerStream::Targeted(server_stream), proxy_selector, resolver, false) => {
+        result = run_udp_routing(
+            ServerStream::Targeted(server_stream),
+            proxy_selector,
+            resolver,
+            None,
+            false,
+            RateLimiter::default(),
+            RateLimiter::default(),
+        ) => {
             result
         }
         _ = monitor_tcp_close(&mut tcp_stream) => {
@@ -522,10 +660,25 @@ async fn monitor_tcp_close(stream: &mut Box<dyn AsyncStream>) -> std::io::Result
 pub struct SocksTcpClientHandler {
     prefix_data: Vec<u8>,
     has_auth: bool,
+    udp_associate: Option<SocksUdpAssociate>,
+}
+
+/// What a SOCKS5 client needs to send UDP through the relay of the server.
+#[derive(Debug)]
+pub struct SocksUdpAssociate {
+    /// Address of the server, which is used when it replies with an unspecified relay
+    /// address.
+    pub server_location: NetLocation,
+    /// Options of the UDP sockets to the relay.
+    pub dial_options: DialOptions,
+    pub resolver: Arc<dyn Resolver>,
 }
 
 impl SocksTcpClientHandler {
-    pub fn new(auth_info: Option<(String, String)>) -> Self {
+    pub fn new(
+        auth_info: Option<(String, String)>,
+        udp_associate: Option<SocksUdpAssociate>,
+    ) -> Self {
         let mut data = vec![
             VER_SOCKS5,
             1, // number of methods,
@@ -541,34 +694,32 @@ impl SocksTcpClientHandler {
             data.push(password.len() as u8);
             data.extend_from_slice(password.as_bytes());
         }
-        data.extend(&[
-            VER_SOCKS5,
-            CMD_CONNECT,
-            0x0, // reserved
-        ]);
 
         Self {
             prefix_data: data,
             has_auth: auth_info.is_some(),
+            udp_associate,
         }
     }
-}
 
-#[async_trait]
-impl TcpClientHandler for SocksTcpClientHandler {
-    async fn setup_client_tcp_stream(
+    /// Authenticates and sends a request with `command`. Returns the address of the
+    /// reply, and the reader with any data after the reply.
+    async fn send_request(
         &self,
-        mut client_stream: Box<dyn AsyncStream>,
-        remote_location: ResolvedLocation,
-    ) -> std::io::Result<TcpClientSetupResult> {
-        write_all(&mut client_stream, &self.prefix_data).await?;
-        let location_bytes = write_location_to_vec(remote_location.location());
-        write_all(&mut client_stream, &location_bytes).await?;
+        client_stream: &mut Box<dyn AsyncStream>,
+        command: u8,
+        location: &NetLocation,
+    ) -> std::io::Result<(NetLocation, StreamReader)> {
+        let mut request = self.prefix_data.clone();
+        // The last byte is reserved.
+        request.extend(&[VER_SOCKS5, command, 0x0]);
+        request.extend(write_location_to_vec(location));
+        write_all(client_stream, &request).await?;
         client_stream.flush().await?;
 
         let mut stream_reader = StreamReader::new_with_buffer_size(400);
 
-        let socks_version = stream_reader.read_u8(&mut client_stream).await?;
+        let socks_version = stream_reader.read_u8(client_stream).await?;
         if socks_version != VER_SOCKS5 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -576,7 +727,7 @@ impl TcpClientHandler for SocksTcpClientHandler {
             ));
         }
 
-        let auth_method = stream_reader.read_u8(&mut client_stream).await?;
+        let auth_method = stream_reader.read_u8(client_stream).await?;
         if auth_method == METHOD_INVALID {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -585,7 +736,7 @@ impl TcpClientHandler for SocksTcpClientHandler {
         }
 
         if self.has_auth {
-            let auth_version = stream_reader.read_u8(&mut client_stream).await?;
+            let auth_version = stream_reader.read_u8(client_stream).await?;
             if auth_version != VER_AUTH {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidInput,
@@ -593,7 +744,7 @@ impl TcpClientHandler for SocksTcpClientHandler {
                 ));
             }
 
-            let auth_result = stream_reader.read_u8(&mut client_stream).await?;
+            let auth_result = stream_reader.read_u8(client_stream).await?;
             if auth_result != RESULT_SUCCESS {
                 return Err(std::io::Error::new(
                     std::io::ErrorKind::InvalidInput,
@@ -602,7 +753,7 @@ impl TcpClientHandler for SocksTcpClientHandler {
             }
         }
 
-        let socks_version = stream_reader.read_u8(&mut client_stream).await?;
+        let socks_version = stream_reader.read_u8(client_stream).await?;
         if socks_version != VER_SOCKS5 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -610,15 +761,20 @@ impl TcpClientHandler for SocksTcpClientHandler {
             ));
         }
 
-        let connect_response = stream_reader.read_u8(&mut client_stream).await?;
-        if connect_response != RESULT_SUCCESS {
+        let response = stream_reader.read_u8(client_stream).await?;
+        if response != RESULT_SUCCESS {
+            let command_name = if command == CMD_UDP_ASSOCIATE {
+                "udp associate"
+            } else {
+                "connect"
+            };
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
-                format!("SOCKS server connect command failed: error {connect_response}"),
+                format!("SOCKS server {command_name} command failed: error {response}"),
             ));
         }
 
-        let reserved = stream_reader.read_u8(&mut client_stream).await?;
+        let reserved = stream_reader.read_u8(client_stream).await?;
         if reserved != 0 {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
@@ -626,8 +782,23 @@ impl TcpClientHandler for SocksTcpClientHandler {
             ));
         }
 
-        // Read the final location part of the connect response.
-        read_location(&mut client_stream, &mut stream_reader).await?;
+        // Read the final location part of the response.
+        let bound_location = read_location(client_stream, &mut stream_reader).await?;
+
+        Ok((bound_location, stream_reader))
+    }
+}
+
+#[async_trait]
+impl TcpClientHandler for SocksTcpClientHandler {
+    async fn setup_client_tcp_stream(
+        &self,
+        mut client_stream: Box<dyn AsyncStream>,
+        remote_location: ResolvedLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        let (_, stream_reader) = self
+            .send_request(&mut client_stream, CMD_CONNECT, remote_location.location())
+            .await?;
 
         let early_data = stream_reader.unparsed_data();
         let early_data = if early_data.is_empty() {
@@ -641,6 +812,54 @@ impl TcpClientHandler for SocksTcpClientHandler {
             early_data,
         })
     }
+
+    fn supports_udp_over_tcp(&self) -> bool {
+        self.udp_associate.is_some()
+    }
+
+    async fn setup_client_udp_bidirectional(
+        &self,
+        mut client_stream: Box<dyn AsyncStream>,
+        target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        let Some(udp_associate) = self.udp_associate.as_ref() else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "UDP is not enabled for this SOCKS5 client",
+            ));
+        };
+
+        // The address that we will send from is not known before the socket is bound,
+        // so let the server accept datagrams from any address.
+        let (relay_location, _) = self
+            .send_request(
+                &mut client_stream,
+                CMD_UDP_ASSOCIATE,
+                &NetLocation::new(Address::Ipv4(Ipv4Addr::UNSPECIFIED), 0),
+            )
+            .await?;
+
+        let relay_addr = match relay_location.to_socket_addr_nonblocking() {
+            Some(addr) if !addr.ip().is_unspecified() => addr,
+            Some(addr) => {
+                let server_addr =
+                    resolve_single_address(&udp_associate.resolver, &udp_associate.server_location)
+                        .await?;
+                SocketAddr::new(server_addr.ip(), addr.port())
+            }
+            None => resolve_single_address(&udp_associate.resolver, &relay_location).await?,
+        };
+        log::debug!("SOCKS5 UDP ASSOCIATE: relay at {relay_addr}");
+
+        let udp_socket = new_udp_socket(relay_addr.is_ipv6(), &udp_associate.dial_options)?;
+        udp_socket.connect(relay_addr).await?;
+
+        Ok(Box::new(SocksUdpClientStream::new(
+            udp_socket,
+            client_stream,
+            target.location(),
+        )))
+    }
 }
 
 pub async fn read_location<T: AsyncReadExt + Unpin>(
@@ -798,3 +1017,37 @@ pub fn write_location_to_vec(location: &NetLocation) -> Vec<u8> {
     vec.push((port & 0xff) as u8);
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_request(request: &[u8]) -> std::io::Result<(u8, NetLocation)> {
+        let mut stream = request;
+        read_socks4_request(&mut stream, &mut StreamReader::new_with_buffer_size(400)).await
+    }
+
+    #[tokio::test]
+    async fn test_read_socks4_request() {
+        let (command, location) = read_request(&[1, 0x01, 0xbb, 192, 0, 2, 1, b'u', 0])
+            .await
+            .unwrap();
+        assert_eq!(command, CMD_CONNECT);
+        assert_eq!(location.to_string(), "192.0.2.1:443");
+
+        let mut socks4a = vec![1, 0, 80, 0, 0, 0, 1, 0];
+        socks4a.extend_from_slice(b"example.com\0");
+        let (_, location) = read_request(&socks4a).await.unwrap();
+        assert_eq!(location.to_string(), "example.com:80");
+
+        assert!(
+            read_request(&[1, 0, 80, 0, 0, 0, 1, 0, b'x'])
+                .await
+                .is_err()
+        );
+        let mut long_user_id = vec![1, 0, 80, 192, 0, 2, 1];
+        long_user_id.extend_from_slice(&[b'u'; 256]);
+        long_user_id.push(0);
+        assert!(read_request(&long_user_id).await.is_err());
+    }
+}
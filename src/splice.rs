@@ -0,0 +1,167 @@
+//! Zero-copy relaying between TCP streams with splice(2).
+//!
+//! Data is moved from one socket to the other through a pipe, so that it stays in the
+//! kernel instead of being copied to and from userspace buffers. Copies switch to this
+//! when both streams pass data through unchanged, e.g. port forwarding, fallbacks and
+//! VISION streams after they switched to direct mode.
+
+use std::io;
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use tokio::io::Interest;
+use tokio::net::TcpStream;
+
+use crate::metrics::ByteCounter;
+
+/// Bytes moved by each splice call, the default capacity of a pipe.
+const PIPE_SIZE: usize = 64 * 1024;
+
+struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    fn new() -> io::Result<Self> {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe {
+            Self {
+                read: OwnedFd::from_raw_fd(fds[0]),
+                write: OwnedFd::from_raw_fd(fds[1]),
+            }
+        })
+    }
+}
+
+fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Moves data from `reader` to `writer` until `reader` reaches EOF, then shuts down
+/// writing to `writer`.
+async fn splice_one_direction(
+    reader: &TcpStream,
+    writer: &TcpStream,
+    counter: &ByteCounter,
+) -> io::Result<()> {
+    let pipe = Pipe::new()?;
+    loop {
+        // The pipe is always drained before reading again, so this only waits for the
+        // reader.
+        let n = reader
+            .async_io(Interest::READABLE, || {
+                splice(reader.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_SIZE)
+            })
+            .await?;
+        if n == 0 {
+            break;
+        }
+
+        let mut remaining = n;
+        while remaining > 0 {
+            let written = writer
+                .async_io(Interest::WRITABLE, || {
+                    splice(pipe.read.as_raw_fd(), writer.as_raw_fd(), remaining)
+                })
+                .await?;
+            if written == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "write zero byte into writer",
+                ));
+            }
+            remaining -= written;
+            counter.add(written);
+        }
+    }
+    socket2::SockRef::from(writer).shutdown(Shutdown::Write)
+}
+
+/// Copies data in both directions between `a` and `b` like
+/// [`copy_bidirectional`](crate::copy_bidirectional::copy_bidirectional), adding the
+/// number of bytes written to `b` to `a_to_b_counter`, and the number of bytes written to
+/// `a` to `b_to_a_counter`.
+pub async fn splice_bidirectional(
+    a: &mut TcpStream,
+    b: &mut TcpStream,
+    a_to_b_counter: ByteCounter,
+    b_to_a_counter: ByteCounter,
+) -> io::Result<()> {
+    let (a, b) = (&*a, &*b);
+    tokio::try_join!(
+        splice_one_direction(a, b, &a_to_b_counter),
+        splice_one_direction(b, a, &b_to_a_counter),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::metrics::Counter;
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_splice_bidirectional() {
+        let (mut client, mut a) = tcp_pair().await;
+        let (mut b, mut remote) = tcp_pair().await;
+
+        let up = Arc::new(Counter::default());
+        let mut up_counter = ByteCounter::default();
+        up_counter.push(up.clone());
+        let relay = tokio::spawn(async move {
+            splice_bidirectional(&mut a, &mut b, up_counter, ByteCounter::default()).await
+        });
+
+        let data = vec![7u8; 1024 * 1024];
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).await.unwrap();
+                response
+            })
+        };
+
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        remote.write_all(b"done").await.unwrap();
+        remote.shutdown().await.unwrap();
+
+        assert_eq!(writer.await.unwrap(), b"done");
+        relay.await.unwrap().unwrap();
+        assert_eq!(up.get(), data.len() as u64);
+    }
+}
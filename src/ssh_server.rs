@@ -0,0 +1,300 @@
+//! SSH server implementation.
+//!
+//! Serves `direct-tcpip` channels, the ones that `ssh -D` / `ssh -L` / `ssh -W` open,
+//! so that clients with only an SSH client can use the server as a proxy:
+//! - Clients authenticate with one of the authorized public keys
+//! - Each `direct-tcpip` channel is routed with the server rules, like a connection
+//! - Sessions, shells and remote forwarding are rejected, so clients should use `ssh -N`
+//!
+//! Protocol reference: RFC 4254 section 7.2 (TCP/IP Forwarding Channels)
+
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::debug;
+use russh::keys::{PrivateKey, PublicKey};
+use russh::server::{self, Auth, Msg, Session};
+use russh::{Channel, ChannelStream};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::address::{Address, NetLocation};
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::copy_bidirectional::copy_bidirectional;
+use crate::resolver::Resolver;
+use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+
+/// Interval for SSH-level keepalives on idle sessions.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay before rejecting a failed authentication attempt, which slows down guessing.
+const AUTH_REJECTION_TIME: Duration = Duration::from_secs(1);
+
+/// Parses a public key in `authorized_keys` format, e.g. `ssh-ed25519 AAAA... comment`.
+pub fn parse_ssh_public_key(public_key: &str) -> std::io::Result<PublicKey> {
+    PublicKey::from_openssh(public_key.trim()).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid SSH public key: {e}"),
+        )
+    })
+}
+
+#[derive(Debug)]
+struct SshServerConfig {
+    authorized_keys: Vec<PublicKey>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+}
+
+impl SshServerConfig {
+    fn is_authorized(&self, public_key: &PublicKey) -> bool {
+        self.authorized_keys
+            .iter()
+            .any(|key| key.key_data() == public_key.key_data())
+    }
+}
+
+#[derive(Debug)]
+pub struct SshServerHandler {
+    ssh_config: Arc<server::Config>,
+    config: Arc<SshServerConfig>,
+}
+
+impl SshServerHandler {
+    pub fn new(
+        host_key: PrivateKey,
+        authorized_keys: Vec<PublicKey>,
+        proxy_selector: Arc<ClientProxySelector>,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
+        let ssh_config = server::Config {
+            keys: vec![host_key],
+            keepalive_interval: Some(KEEPALIVE_INTERVAL),
+            auth_rejection_time: AUTH_REJECTION_TIME,
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        Self {
+            ssh_config: Arc::new(ssh_config),
+            config: Arc::new(SshServerConfig {
+                authorized_keys,
+                proxy_selector,
+                resolver,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for SshServerHandler {
+    async fn setup_server_stream(
+        &self,
+        server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let handler = SshSessionHandler {
+            config: self.config.clone(),
+            user: None,
+        };
+        let session = server::run_stream(self.ssh_config.clone(), server_stream, handler)
+            .await
+            .map_err(|e| std::io::Error::other(format!("SSH handshake failed: {e}")))?;
+
+        tokio::spawn(async move {
+            if let Err(e) = session.await {
+                debug!("SSH session ended with error: {e}");
+            }
+        });
+
+        Ok(TcpServerSetupResult::AlreadyHandled)
+    }
+}
+
+struct SshSessionHandler {
+    config: Arc<SshServerConfig>,
+    /// The authenticated user, only used for logging.
+    user: Option<String>,
+}
+
+impl server::Handler for SshSessionHandler {
+    type Error = russh::Error;
+
+    async fn auth_publickey_offered(
+        &mut self,
+        _user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if self.config.is_authorized(public_key) {
+            Ok(Auth::Accept)
+        } else {
+            Ok(Auth::reject())
+        }
+    }
+
+    async fn auth_publickey(
+        &mut self,
+        user: &str,
+        public_key: &PublicKey,
+    ) -> Result<Auth, Self::Error> {
+        if !self.config.is_authorized(public_key) {
+            debug!("Rejecting SSH public key of user {user}");
+            return Ok(Auth::reject());
+        }
+        debug!("SSH user {user} authenticated");
+        self.user = Some(user.to_string());
+        Ok(Auth::Accept)
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Ok(port) = u16::try_from(port_to_connect) else {
+            return Ok(false);
+        };
+        let Ok(address) = Address::from(host_to_connect) else {
+            return Ok(false);
+        };
+        let target = NetLocation::new(address, port);
+        debug!(
+            "SSH user {} opened channel to {target}",
+            self.user.as_deref().unwrap_or_default()
+        );
+
+        let config = self.config.clone();
+        let stream = SshChannelStream::new(channel.into_stream());
+        tokio::spawn(async move {
+            if let Err(e) = forward_channel(stream, &target, &config).await {
+                debug!("SSH channel to {target} ended with error: {e}");
+            }
+        });
+        Ok(true)
+    }
+}
+
+async fn forward_channel(
+    mut stream: SshChannelStream,
+    target: &NetLocation,
+    config: &SshServerConfig,
+) -> std::io::Result<()> {
+    let decision = config
+        .proxy_selector
+        .judge(target.clone().into(), &config.resolver)
+        .await?;
+
+    let (chain_group, remote_location) = match decision {
+        ConnectDecision::Allow {
+            chain_group,
+            remote_location,
+        } => (chain_group, remote_location),
+        ConnectDecision::Block => {
+            debug!("SSH channel to {target} blocked by rules");
+            let _ = stream.shutdown().await;
+            return Ok(());
+        }
+    };
+
+    let mut client_stream = chain_group
+        .connect_tcp(remote_location, &config.resolver)
+        .await?
+        .client_stream;
+
+    let result = copy_bidirectional(&mut stream, &mut *client_stream, false, false).await;
+
+    let _ = stream.shutdown().await;
+    let _ = client_stream.shutdown().await;
+
+    result
+}
+
+/// A `direct-tcpip` channel stream opened by a client.
+///
+/// The channel is wrapped in a Mutex only to make the stream `Sync`; all access goes
+/// through `Mutex::get_mut`, which never locks.
+struct SshChannelStream {
+    inner: Mutex<ChannelStream<Msg>>,
+}
+
+impl SshChannelStream {
+    fn new(stream: ChannelStream<Msg>) -> Self {
+        Self {
+            inner: Mutex::new(stream),
+        }
+    }
+
+    fn stream(&mut self) -> Pin<&mut ChannelStream<Msg>> {
+        let stream = self
+            .inner
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Pin::new(stream)
+    }
+}
+
+impl AsyncRead for SshChannelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.get_mut().stream().poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for SshChannelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.get_mut().stream().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().stream().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.get_mut().stream().poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for SshChannelStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for SshChannelStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_public_key() {
+        let key = parse_ssh_public_key(
+            "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIG4UzRMijUQge+SWupQlx6CrjkRRVTfX9yhyV+uvNhNo \
+             test@example.com\n",
+        )
+        .unwrap();
+        assert_eq!(key.comment(), "test@example.com");
+    }
+
+    #[test]
+    fn test_parse_invalid_public_key() {
+        assert!(parse_ssh_public_key("ssh-ed25519 not-base64").is_err());
+    }
+}
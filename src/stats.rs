@@ -0,0 +1,239 @@
+//! Traffic statistics for applications that embed shoes.
+//!
+//! A [`StatsHandle`] reads the same counters as the Prometheus metrics, and keeps them
+//! collected while it is alive, so that GUI clients can show traffic and the health of
+//! outbounds without configuring a metrics listener.
+//!
+//! ```no_run
+//! # async fn run() -> std::io::Result<()> {
+//! let stats = shoes::stats::StatsHandle::new();
+//! let reload_handle = shoes::ReloadHandle::new();
+//! tokio::spawn(shoes::run_with_reload(
+//!     vec![String::from("config.yaml")],
+//!     reload_handle,
+//!     true,
+//! ));
+//!
+//! let snapshot = stats.snapshot();
+//! println!(
+//!     "{} connections, {} bytes up, {} bytes down",
+//!     snapshot.active_connections, snapshot.bytes_up, snapshot.bytes_down
+//! );
+//! # Ok(())
+//! # }
+//! ```
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::auto_ban;
+use crate::client_proxy_selector::outbound_selectors;
+use crate::metrics::{self, CollectGuard};
+
+/// Handle to the traffic statistics of the servers in this process.
+///
+/// Connections are counted from when the first handle is created, until all clones of
+/// it are dropped. Create the handle before starting the servers to count all of them.
+#[derive(Debug, Clone)]
+pub struct StatsHandle {
+    _collect: Arc<CollectGuard>,
+}
+
+impl StatsHandle {
+    pub fn new() -> Self {
+        Self {
+            _collect: Arc::new(metrics::collect()),
+        }
+    }
+
+    /// Returns the current statistics of all listeners and outbounds.
+    pub fn snapshot(&self) -> Stats {
+        let listeners = self.listeners();
+        let outbounds = self.outbounds();
+        Stats {
+            active_connections: listeners
+                .iter()
+                .map(|listener| listener.active_connections)
+                .sum(),
+            bytes_up: listeners.iter().map(|listener| listener.bytes_up).sum(),
+            bytes_down: listeners.iter().map(|listener| listener.bytes_down).sum(),
+            udp_sessions_expired: metrics::udp_sessions().map_or(0, |m| m.expired.get()),
+            udp_sessions_evicted: metrics::udp_sessions().map_or(0, |m| m.evicted.get()),
+            replays_detected: metrics::replays().iter().map(|(_, count)| count).sum(),
+            listeners,
+            outbounds,
+        }
+    }
+
+    /// Returns the statistics of each listener, sorted by name.
+    pub fn listeners(&self) -> Vec<ListenerStats> {
+        metrics::listeners()
+            .into_iter()
+            .map(|(name, metrics)| ListenerStats {
+                name,
+                connections_accepted: metrics.connections_accepted.get(),
+                active_connections: metrics.active_connections.get().max(0) as u64,
+                active_sessions: metrics.active_sessions.get().max(0) as u64,
+                bytes_up: metrics.bytes_up.get(),
+                bytes_down: metrics.bytes_down.get(),
+            })
+            .collect()
+    }
+
+    /// Returns the statistics and chain health of each outbound client chain group that
+    /// is in use, sorted by name.
+    pub fn outbounds(&self) -> Vec<OutboundStats> {
+        let outbound_metrics = metrics::outbounds();
+        outbound_selectors()
+            .into_iter()
+            .map(|selector| {
+                let metrics = outbound_metrics
+                    .iter()
+                    .find(|(name, _)| name == selector.name())
+                    .map(|(_, metrics)| metrics);
+                let selected = selector.selected();
+                let chains = selector
+                    .chains()
+                    .iter()
+                    .enumerate()
+                    .map(|(index, chain)| ChainStats {
+                        name: chain.name.clone(),
+                        selected: selected == Some(index),
+                        healthy: selector.health().is_healthy(index),
+                        latency: selector.health().latency(index),
+                        last_check: selector
+                            .health()
+                            .history(index)
+                            .last()
+                            .map(|record| record.time),
+                    })
+                    .collect();
+                OutboundStats {
+                    name: selector.name().to_string(),
+                    connections: metrics.map_or(0, |m| m.connections.get()),
+                    udp_sessions: metrics.map_or(0, |m| m.udp_sessions.get()),
+                    bytes_up: metrics.map_or(0, |m| m.bytes_up.get()),
+                    bytes_down: metrics.map_or(0, |m| m.bytes_down.get()),
+                    chains,
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the client IPs that are banned for failing handshakes, sorted by IP.
+    pub fn bans(&self) -> Vec<BanStats> {
+        let now = SystemTime::now();
+        auto_ban::bans()
+            .into_iter()
+            .map(|(ip, remaining)| BanStats {
+                ip,
+                expires: now + remaining,
+            })
+            .collect()
+    }
+
+    /// Lifts the ban of `ip`. Returns false if it wasn't banned.
+    pub fn unban(&self, ip: IpAddr) -> bool {
+        auto_ban::unban(ip)
+    }
+
+    /// Lifts all bans.
+    pub fn clear_bans(&self) {
+        auto_ban::clear()
+    }
+}
+
+impl Default for StatsHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Totals over all listeners, with the statistics of each listener and outbound.
+#[derive(Debug, Clone)]
+pub struct Stats {
+    pub active_connections: u64,
+    /// Bytes sent from clients towards remote destinations.
+    pub bytes_up: u64,
+    /// Bytes sent from remote destinations back to clients.
+    pub bytes_down: u64,
+    /// UDP sessions closed because they were idle.
+    pub udp_sessions_expired: u64,
+    /// UDP sessions evicted because their session table was full.
+    pub udp_sessions_evicted: u64,
+    /// Handshakes rejected because they replayed an earlier one.
+    pub replays_detected: u64,
+    pub listeners: Vec<ListenerStats>,
+    pub outbounds: Vec<OutboundStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenerStats {
+    /// Identifies the listener, e.g. `tcp://0.0.0.0:443`.
+    pub name: String,
+    pub connections_accepted: u64,
+    /// Open connections. For QUIC listeners, these are QUIC connections rather than
+    /// streams.
+    pub active_connections: u64,
+    pub active_sessions: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboundStats {
+    /// Identifies the client chain group, e.g. `socks5@1.2.3.4:1080 | direct`.
+    pub name: String,
+    pub connections: u64,
+    pub udp_sessions: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub chains: Vec<ChainStats>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChainStats {
+    /// Identifies the chain, e.g. `socks5@1.2.3.4:1080 -> vless@5.6.7.8:443`.
+    pub name: String,
+    /// True if the chain was pinned, e.g. through the Clash API.
+    pub selected: bool,
+    /// True if the chain passed its last health check, or was not checked yet.
+    pub healthy: bool,
+    /// Latency of the last health check, or None if it failed or the chain was not
+    /// checked yet.
+    pub latency: Option<Duration>,
+    pub last_check: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BanStats {
+    pub ip: IpAddr,
+    /// When the ban is lifted.
+    pub expires: SystemTime,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_handle_collects_metrics() {
+        let stats = StatsHandle::new();
+        assert!(metrics::is_enabled());
+
+        let listener = metrics::listener("tcp://127.0.0.1:10443").unwrap();
+        listener.bytes_up.add(100);
+        listener.active_connections.inc();
+
+        let snapshot = stats.snapshot();
+        let listener_stats = snapshot
+            .listeners
+            .iter()
+            .find(|listener| listener.name == "tcp://127.0.0.1:10443")
+            .unwrap();
+        assert_eq!(listener_stats.bytes_up, 100);
+        assert_eq!(listener_stats.active_connections, 1);
+        assert!(snapshot.bytes_up >= 100);
+    }
+}
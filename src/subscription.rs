@@ -0,0 +1,321 @@
+//! Subscriptions, fetched when the configs are loaded and refreshed on their interval.
+//!
+//! A subscription is fetched with a HTTP GET request, directly or through a client chain,
+//! and its proxies replace the subscription entry as a client group. Fetched groups are
+//! reused by reloads until the subscription is due for a refresh, so that reloads caused
+//! by changed config files don't fetch every subscription again.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, warn};
+use tokio::time::Instant;
+
+use crate::address::{Address, NetLocation, ResolvedLocation};
+use crate::async_stream::AsyncStream;
+use crate::client_proxy_chain::{ClientProxyChain, DialTimeouts};
+use crate::config::{self, ClientChain, Config, SubscriptionConfig};
+use crate::crypto::{CryptoConnection, CryptoTlsStream, perform_crypto_handshake};
+use crate::http2_handler::PrefixedStream;
+use crate::resolver::{NativeResolver, Resolver};
+use crate::rustls_config_util::create_client_config;
+use crate::tcp::chain_builder::build_client_proxy_chain;
+
+/// User-Agent of requests without a `user_agent` override.
+const DEFAULT_USER_AGENT: &str = "shoes";
+
+/// Time after which a fetch fails, including redirects.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+const MAX_REDIRECTS: usize = 5;
+
+/// Largest subscription that is read.
+const MAX_SUBSCRIPTION_SIZE: usize = 16 * 1024 * 1024;
+
+/// Delay before a failed refresh is retried, unless the interval is shorter.
+const RETRY_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Client groups of the fetched subscriptions.
+#[derive(Default)]
+pub struct Subscriptions {
+    fetched: HashMap<String, FetchedSubscription>,
+}
+
+#[derive(Clone)]
+struct FetchedSubscription {
+    /// Debug output of the subscription config, so that changed configs are fetched again.
+    config: String,
+    group: Config,
+    refresh_at: Instant,
+}
+
+impl Subscriptions {
+    /// Replaces the subscriptions of `configs` with the client groups of their proxies.
+    ///
+    /// Subscriptions are fetched when they are new, changed or due for a refresh, in the
+    /// order of the configs, so that a subscription can be fetched through the group of an
+    /// earlier one. A failed refresh keeps the proxies of the previous fetch and is retried
+    /// later, while failing to fetch a new subscription is an error.
+    pub async fn materialize(&mut self, mut configs: Vec<Config>) -> std::io::Result<Vec<Config>> {
+        let mut fetched: HashMap<String, FetchedSubscription> = HashMap::new();
+        for index in 0..configs.len() {
+            let Config::Subscription(subscription) = &configs[index] else {
+                continue;
+            };
+            let subscription = subscription.clone();
+            config::validate_subscription(&subscription)?;
+            if fetched.contains_key(&subscription.subscription) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("subscription already exists: {}", subscription.subscription),
+                ));
+            }
+
+            let config = format!("{subscription:?}");
+            let interval = Duration::from_secs(subscription.interval_secs);
+            let now = Instant::now();
+            let previous = self
+                .fetched
+                .get(&subscription.subscription)
+                .filter(|previous| previous.config == config);
+            let current = match previous {
+                Some(previous) if previous.refresh_at > now => previous.clone(),
+                _ => match fetch_subscription(&subscription, &configs).await {
+                    Ok(group) => FetchedSubscription {
+                        config,
+                        group,
+                        refresh_at: now + interval,
+                    },
+                    Err(e) => {
+                        let Some(previous) = previous else {
+                            return Err(e);
+                        };
+                        error!(
+                            "Failed to refresh subscription {}, keeping its proxies: {e}",
+                            subscription.subscription
+                        );
+                        FetchedSubscription {
+                            refresh_at: now + RETRY_INTERVAL.min(interval),
+                            ..previous.clone()
+                        }
+                    }
+                },
+            };
+            configs[index] = current.group.clone();
+            fetched.insert(subscription.subscription, current);
+        }
+        self.fetched = fetched;
+        Ok(configs)
+    }
+
+    /// Returns when the next subscription is due for a refresh.
+    pub fn next_refresh(&self) -> Option<Instant> {
+        self.fetched
+            .values()
+            .map(|fetched| fetched.refresh_at)
+            .min()
+    }
+
+    /// Delays the refreshes that are due, e.g. after a reload failed before the
+    /// subscriptions were fetched.
+    pub fn retry_later(&mut self) {
+        let now = Instant::now();
+        for fetched in self.fetched.values_mut() {
+            if fetched.refresh_at <= now {
+                fetched.refresh_at = now + RETRY_INTERVAL;
+            }
+        }
+    }
+}
+
+/// Fetches a subscription, through its client chain, and converts it into a client group.
+async fn fetch_subscription(
+    subscription: &SubscriptionConfig,
+    configs: &[Config],
+) -> std::io::Result<Config> {
+    let chain = config::subscription_chain(subscription, configs)?;
+    let user_agent = subscription
+        .user_agent
+        .as_deref()
+        .unwrap_or(DEFAULT_USER_AGENT);
+    let content = tokio::time::timeout(FETCH_TIMEOUT, fetch(&subscription.url, user_agent, chain))
+        .await
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "fetching subscription {} timed out",
+                    subscription.subscription
+                ),
+            )
+        })?
+        .map_err(|e| {
+            std::io::Error::new(
+                e.kind(),
+                format!(
+                    "failed to fetch subscription {}: {e}",
+                    subscription.subscription
+                ),
+            )
+        })?;
+
+    let (group, warnings) = config::convert_subscription(subscription, &content)?;
+    for warning in warnings {
+        warn!("Subscription {}: {warning}", subscription.subscription);
+    }
+    if let Config::ClientConfigGroup(group) = &group {
+        println!(
+            "Fetched {} proxies from subscription {}",
+            group.client_proxies.len(),
+            subscription.subscription
+        );
+    }
+    Ok(group)
+}
+
+/// Fetches the body of a URL, following redirects.
+async fn fetch(url: &str, user_agent: &str, chain: ClientChain) -> std::io::Result<String> {
+    let resolver: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
+    let chain = build_client_proxy_chain(chain.hops, resolver.clone());
+
+    let mut url = url::Url::parse(url)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    for _ in 0..=MAX_REDIRECTS {
+        match get(&url, user_agent, &chain, &resolver).await? {
+            Response::Body(body) => {
+                return String::from_utf8(body).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "subscription is not valid UTF-8",
+                    )
+                });
+            }
+            Response::Redirect(location) => {
+                url = url.join(&location).map_err(|e| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("invalid redirect location {location}: {e}"),
+                    )
+                })?;
+            }
+        }
+    }
+    Err(std::io::Error::other("too many redirects"))
+}
+
+enum Response {
+    Body(Vec<u8>),
+    Redirect(String),
+}
+
+/// Sends a GET request over a new connection through the chain.
+async fn get(
+    url: &url::Url,
+    user_agent: &str,
+    chain: &ClientProxyChain,
+    resolver: &Arc<dyn Resolver>,
+) -> std::io::Result<Response> {
+    let invalid = |message: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid url {url}: {message}"),
+        )
+    };
+    let tls = match url.scheme() {
+        "http" => false,
+        "https" => true,
+        _ => return Err(invalid("expected a http or https URL")),
+    };
+    let (address, server_name) = match url.host() {
+        Some(url::Host::Domain(domain)) => (Address::Hostname(domain.to_string()), domain),
+        Some(url::Host::Ipv4(ip)) => (Address::Ipv4(ip), url.host_str().unwrap()),
+        Some(url::Host::Ipv6(ip)) => (
+            Address::Ipv6(ip),
+            url.host_str().unwrap().trim_matches(['[', ']']),
+        ),
+        None => return Err(invalid("missing host")),
+    };
+    let port = url.port_or_known_default().unwrap();
+    let host = match url.port() {
+        Some(port) => format!("{}:{port}", url.host_str().unwrap()),
+        None => url.host_str().unwrap().to_string(),
+    };
+    let path = match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    };
+
+    let result = chain
+        .connect_tcp(
+            ResolvedLocation::new(NetLocation::new(address, port)),
+            resolver,
+            DialTimeouts::default(),
+        )
+        .await?;
+    let mut stream = result.client_stream;
+    if tls {
+        let server_name = rustls::pki_types::ServerName::try_from(server_name.to_string())
+            .map_err(|e| invalid(&e.to_string()))?;
+        let client_config = Arc::new(create_client_config(
+            true,
+            vec![],
+            vec![String::from("http/1.1")],
+            true,
+            None,
+            false,
+            None,
+            None,
+            None,
+        ));
+        let client_conn = rustls::ClientConnection::new(client_config, server_name)
+            .map_err(std::io::Error::other)?;
+        let mut connection = CryptoConnection::new_rustls_client(client_conn);
+        perform_crypto_handshake(&mut connection, &mut stream, 16384).await?;
+        stream = Box::new(CryptoTlsStream::new(stream, connection)) as Box<dyn AsyncStream>;
+    }
+    let stream = PrefixedStream::new(
+        result.early_data.unwrap_or_default().into_boxed_slice(),
+        stream,
+    );
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(std::io::Error::other)?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            debug!("Subscription connection failed: {e}");
+        }
+    });
+
+    let request = http::Request::get(path)
+        .header(http::header::HOST, host)
+        .header(http::header::USER_AGENT, user_agent)
+        .header(http::header::ACCEPT, "*/*")
+        .body(Empty::<Bytes>::new())
+        .map_err(std::io::Error::other)?;
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(std::io::Error::other)?;
+
+    let status = response.status();
+    if status.is_redirection()
+        && let Some(location) = response.headers().get(http::header::LOCATION)
+    {
+        let location = location.to_str().map_err(std::io::Error::other)?;
+        return Ok(Response::Redirect(location.to_string()));
+    }
+    if !status.is_success() {
+        return Err(std::io::Error::other(format!("HTTP status {status}")));
+    }
+    let body = Limited::new(response.into_body(), MAX_SUBSCRIPTION_SIZE)
+        .collect()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?
+        .to_bytes();
+    Ok(Response::Body(body.to_vec()))
+}
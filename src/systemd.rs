@@ -0,0 +1,252 @@
+//! systemd socket activation and service notifications.
+//!
+//! When started by a socket unit, systemd passes the sockets it bound as file descriptors
+//! starting at 3, described by `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`. Servers
+//! select them with `systemd: <name>`, where the name is the `FileDescriptorName=` of the
+//! socket unit, or the name of the socket unit by default. Listeners bound to the address
+//! of a passed socket use that socket instead of binding a new one, so that privileged
+//! ports can be used without root. Passed sockets stay open, and are used again when
+//! servers are restarted by a reload.
+//!
+//! For `Type=notify` and `Type=notify-reload` services, `READY=1` is sent once the servers
+//! are started, and `RELOADING=1` while the configs are reloaded. When `WatchdogSec=` is
+//! set, `WATCHDOG=1` is sent at half the watchdog interval.
+
+use std::mem::ManuallyDrop;
+use std::net::SocketAddr;
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use log::{debug, error};
+use socket2::{Socket, Type};
+use tokio::task::JoinHandle;
+
+/// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// The variables set by systemd, read by [`init`].
+#[derive(Debug)]
+struct Environment {
+    /// Passed sockets and their names.
+    listen_fds: Vec<(String, RawFd)>,
+    notify_socket: Option<String>,
+    watchdog_interval: Option<Duration>,
+}
+
+static ENVIRONMENT: OnceLock<Environment> = OnceLock::new();
+
+const VARIABLES: &[&str] = &[
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+    "NOTIFY_SOCKET",
+    "WATCHDOG_PID",
+    "WATCHDOG_USEC",
+];
+
+/// Reads the passed sockets and the notification socket, and removes their variables from
+/// the environment so that child processes like SIP003 plugins don't use them.
+///
+/// Must be called at startup, before other threads are started.
+pub fn init() {
+    let pid = std::process::id().to_string();
+    let listen_fds = if std::env::var("LISTEN_PID").is_ok_and(|listen_pid| listen_pid == pid) {
+        let count = std::env::var("LISTEN_FDS")
+            .ok()
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0);
+        let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+        fd_names(count, &names)
+            .into_iter()
+            .zip(LISTEN_FDS_START..)
+            .map(|(name, fd)| {
+                // Passed sockets are kept open for reloads, but not passed on to children.
+                unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+                (name, fd)
+            })
+            .collect()
+    } else {
+        vec![]
+    };
+    let notify_socket = std::env::var("NOTIFY_SOCKET").ok();
+    let watchdog_interval = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .filter(|_| {
+            std::env::var("WATCHDOG_PID")
+                .ok()
+                .is_none_or(|watchdog_pid| watchdog_pid == pid)
+        })
+        .and_then(|usec| usec.parse().ok())
+        .filter(|&usec| usec > 0)
+        .map(Duration::from_micros);
+
+    for name in VARIABLES {
+        // SAFETY: no other threads exist yet.
+        unsafe { std::env::remove_var(name) };
+    }
+
+    for (name, fd) in listen_fds.iter() {
+        debug!("systemd passed socket {name} as fd {fd}");
+    }
+    let _ = ENVIRONMENT.set(Environment {
+        listen_fds,
+        notify_socket,
+        watchdog_interval,
+    });
+}
+
+/// Returns the names of `count` passed sockets. Sockets without a name in `names` are
+/// named `unknown`, like systemd does.
+fn fd_names(count: usize, names: &str) -> Vec<String> {
+    let mut names = names.split(':');
+    (0..count)
+        .map(|_| {
+            let name = names.next().filter(|name| !name.is_empty());
+            name.unwrap_or("unknown").to_string()
+        })
+        .collect()
+}
+
+fn listen_fds() -> &'static [(String, RawFd)] {
+    ENVIRONMENT
+        .get()
+        .map(|environment| environment.listen_fds.as_slice())
+        .unwrap_or_default()
+}
+
+/// Borrows a passed socket. It is never closed, so that it can be used after a reload.
+fn borrow_socket(fd: RawFd) -> ManuallyDrop<Socket> {
+    ManuallyDrop::new(unsafe { Socket::from_raw_fd(fd) })
+}
+
+/// Returns the local addresses of the sockets passed with the name. Stream and datagram
+/// sockets bound to the same address share an entry, and listeners pick the socket of
+/// their type.
+pub fn socket_addrs(name: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let mut socket_addrs = vec![];
+    for (_, fd) in listen_fds().iter().filter(|(fd_name, _)| fd_name == name) {
+        let socket_addr = borrow_socket(*fd)
+            .local_addr()?
+            .as_socket()
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("systemd socket {name} is not an IP socket"),
+                )
+            })?;
+        if !socket_addrs.contains(&socket_addr) {
+            socket_addrs.push(socket_addr);
+        }
+    }
+    if socket_addrs.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("systemd did not pass a socket named {name}"),
+        ));
+    }
+    Ok(socket_addrs)
+}
+
+fn find_socket(bind_address: SocketAddr, socket_type: Type) -> Option<RawFd> {
+    listen_fds().iter().map(|(_, fd)| *fd).find(|fd| {
+        let socket = borrow_socket(*fd);
+        socket.r#type().is_ok_and(|t| t == socket_type)
+            && socket
+                .local_addr()
+                .is_ok_and(|addr| addr.as_socket() == Some(bind_address))
+    })
+}
+
+/// Returns true if a socket with the type and bound to the address was passed.
+pub fn has_socket(bind_address: SocketAddr, socket_type: Type) -> bool {
+    find_socket(bind_address, socket_type).is_some()
+}
+
+/// Returns a duplicate of the passed socket with the type and bound to the address, if
+/// any. Stream sockets are already listening.
+pub fn take_socket(bind_address: SocketAddr, socket_type: Type) -> std::io::Result<Option<Socket>> {
+    match find_socket(bind_address, socket_type) {
+        Some(fd) => {
+            debug!("Using socket passed by systemd for {bind_address}");
+            borrow_socket(fd).try_clone().map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Sends a notification like `READY=1` to systemd. Does nothing when systemd doesn't
+/// expect notifications.
+pub fn notify(state: &str) {
+    let Some(path) = ENVIRONMENT
+        .get()
+        .and_then(|environment| environment.notify_socket.as_deref())
+    else {
+        return;
+    };
+    if let Err(e) = send_notification(path, state) {
+        error!("Failed to notify systemd: {e}");
+    }
+}
+
+/// Notifies systemd that the configs are being reloaded. `READY=1` must follow once the
+/// reload is done.
+pub fn notify_reloading() {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+    notify(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"));
+}
+
+fn send_notification(path: &str, state: &str) -> std::io::Result<()> {
+    let socket = std::os::unix::net::UnixDatagram::unbound()?;
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Starts sending `WATCHDOG=1` at half the watchdog interval of the service, if it has
+/// one.
+pub fn start_watchdog() -> Option<JoinHandle<()>> {
+    let interval = ENVIRONMENT.get()?.watchdog_interval? / 2;
+    debug!("Notifying the systemd watchdog every {interval:?}");
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1");
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fd_names() {
+        assert_eq!(fd_names(2, "https:dns"), vec!["https", "dns"]);
+        assert_eq!(fd_names(2, "https"), vec!["https", "unknown"]);
+        assert_eq!(fd_names(1, ""), vec!["unknown"]);
+        assert!(fd_names(0, "https").is_empty());
+    }
+
+    #[test]
+    fn test_socket_addrs_without_sockets() {
+        let err = socket_addrs("https").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert!(!has_socket("127.0.0.1:443".parse().unwrap(), Type::STREAM));
+    }
+}
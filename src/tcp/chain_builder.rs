@@ -3,14 +3,27 @@
 use std::sync::Arc;
 
 use crate::client_proxy_chain::{ClientChainGroup, ClientProxyChain, InitialHopEntry};
+use crate::client_proxy_selector::{ChainInfo, get_outbound_selector};
 use crate::config::ConfigSelection;
-use crate::config::{ClientChainHop, ClientConfig, ClientProxyConfig};
+use crate::config::{
+    ClientChain, ClientChainHop, ClientConfig, ClientProxyConfig, DomainStrategy,
+    Hysteria2ObfsConfig, Transport,
+};
 use crate::hysteria2_client::Hysteria2SocketConnector;
+use crate::hysteria2_congestion::BrutalConfig;
+use crate::hysteria2_obfs::Salamander;
+use crate::juicity::JuicitySocketConnector;
+use crate::masque::MasqueSocketConnector;
+use crate::naiveproxy::NaiveH3SocketConnector;
 use crate::resolver::Resolver;
+use crate::reverse::PortalSocketConnector;
+use crate::shadowsocks::{Sip003Plugin, Sip003SocketConnector, allocate_local_address};
+use crate::socket_util::DialOptions;
 use crate::tcp::proxy_connector::ProxyConnector;
 use crate::tcp::proxy_connector_impl::ProxyConnectorImpl;
 use crate::tcp::socket_connector::SocketConnector;
 use crate::tcp::socket_connector_impl::SocketConnectorImpl;
+use crate::wireguard_client::{WireguardSocketConnector, WireguardTunnelConfig};
 
 /// Build a ClientProxyChain from a client_chain configuration.
 ///
@@ -63,17 +76,19 @@ pub fn build_client_proxy_chain(
             // Hysteria2 uses its own socket connector that handles QUIC + HTTP/3 auth
             if matches!(config.protocol, ClientProxyConfig::Hysteria2 { .. }) {
                 // For Hysteria2, we need to extract the password and create a special socket connector
-                let (password, udp_enabled, fast_open, bandwidth) = match &config.protocol {
+                let (password, udp_enabled, fast_open, bandwidth, obfs) = match &config.protocol {
                     ClientProxyConfig::Hysteria2 {
                         password,
                         udp_enabled,
                         fast_open,
                         bandwidth,
+                        obfs,
                     } => (
                         password.clone(),
                         *udp_enabled,
                         *fast_open,
                         bandwidth.clone(),
+                        obfs.clone(),
                     ),
                     _ => unreachable!(),
                 };
@@ -91,7 +106,7 @@ pub fn build_client_proxy_chain(
                 let target_address = find_first_proxy_address(&hops, config)
                     .expect("Hysteria2 requires a target address");
 
-                let bind_interface = config.bind_interface.clone().into_option();
+                let dial_options = DialOptions::from_client_config(config);
 
                 // Get SNI hostname from quic_settings or use target address
                 let default_sni_hostname = target_address.address().hostname().map(ToString::to_string);
@@ -101,7 +116,14 @@ pub fn build_client_proxy_chain(
                     default_sni_hostname,
                 )
                 .with_tls13_only(true)
-                .with_transport_config(256, 255, 15, 60);
+                .with_transport_config(256, 255, 15, 60)
+                .with_congestion_controller(Arc::new(BrutalConfig::default()));
+
+                if let Some(Hysteria2ObfsConfig::Salamander { password }) = obfs {
+                    let salamander = Salamander::new(&password)
+                        .expect("Invalid salamander password (should be validated during config load)");
+                    quic_config = quic_config.with_salamander(Arc::new(salamander));
+                }
 
                 // Add "h3" ALPN if not present, as it is required by Hysteria2
                 let h3 = "h3".to_string();
@@ -114,7 +136,7 @@ pub fn build_client_proxy_chain(
                 let endpoint = crate::tcp::socket_connector_impl::create_quic_endpoint(
                     &quic_config,
                     target_address.address().is_ipv6(),
-                    bind_interface.clone(),
+                    &dial_options,
                 ).expect("Failed to create QUIC endpoint for Hysteria2");
 
                 let socket = Box::new(Hysteria2SocketConnector::new(
@@ -126,7 +148,7 @@ pub fn build_client_proxy_chain(
                     fast_open,
                     max_tx,
                     max_rx,
-                    bind_interface,
+                    dial_options,
                 )) as Box<dyn SocketConnector>;
 
                 // Hysteria2 is a direct protocol from the proxy chain perspective
@@ -134,9 +156,219 @@ pub fn build_client_proxy_chain(
                 return InitialHopEntry::Direct(socket);
             }
 
+            // NaiveProxy over QUIC speaks HTTP/3 CONNECT on its own QUIC connection, so
+            // like Hysteria2 it needs no ProxyConnector.
+            if let ClientProxyConfig::Naiveproxy {
+                username,
+                password,
+                padding,
+            } = &config.protocol
+                && config.transport == Transport::Quic
+            {
+                let target_address = &config.address;
+                let dial_options = DialOptions::from_client_config(config);
+                let default_sni_hostname =
+                    target_address.address().hostname().map(ToString::to_string);
+
+                let mut quic_config =
+                    crate::tcp::socket_connector_impl::QuicEndpointConfig::from_client_config(
+                        config.quic_settings.clone().unwrap_or_default(),
+                        default_sni_hostname,
+                    )
+                    .with_tls13_only(true)
+                    // The server's HTTP/3 control and QPACK streams are unidirectional.
+                    .with_transport_config(0, 16, 15, 60);
+
+                if quic_config.alpn_protocols.is_empty() {
+                    quic_config.alpn_protocols.push("h3".to_string());
+                }
+
+                let effective_sni = quic_config.sni_hostname.clone();
+
+                let endpoint = crate::tcp::socket_connector_impl::create_quic_endpoint(
+                    &quic_config,
+                    target_address.address().is_ipv6(),
+                    &dial_options,
+                )
+                .expect("Failed to create QUIC endpoint for NaiveProxy");
+
+                let socket = Box::new(NaiveH3SocketConnector::new(
+                    endpoint,
+                    target_address.clone(),
+                    effective_sni,
+                    username,
+                    password,
+                    *padding,
+                    dial_options.bind_interface,
+                )) as Box<dyn SocketConnector>;
+
+                return InitialHopEntry::Direct(socket);
+            }
+
+            // MASQUE opens a request stream on its own QUIC connection for every TCP
+            // connection and UDP flow, so like Hysteria2 it needs no ProxyConnector.
+            if let ClientProxyConfig::Masque { username, password } = &config.protocol {
+                let target_address = &config.address;
+                let dial_options = DialOptions::from_client_config(config);
+                let default_sni_hostname =
+                    target_address.address().hostname().map(ToString::to_string);
+
+                let mut quic_config =
+                    crate::tcp::socket_connector_impl::QuicEndpointConfig::from_client_config(
+                        config.quic_settings.clone().unwrap_or_default(),
+                        default_sni_hostname,
+                    )
+                    .with_tls13_only(true)
+                    // The server's HTTP/3 control and QPACK streams are unidirectional.
+                    .with_transport_config(0, 16, 15, 60);
+
+                if quic_config.alpn_protocols.is_empty() {
+                    quic_config.alpn_protocols.push("h3".to_string());
+                }
+
+                let effective_sni = quic_config.sni_hostname.clone();
+
+                let endpoint = crate::tcp::socket_connector_impl::create_quic_endpoint(
+                    &quic_config,
+                    target_address.address().is_ipv6(),
+                    &dial_options,
+                )
+                .expect("Failed to create QUIC endpoint for MASQUE");
+
+                let credentials = match (username, password) {
+                    (None, None) => None,
+                    (username, password) => Some((
+                        username.clone().unwrap_or_default(),
+                        password.clone().unwrap_or_default(),
+                    )),
+                };
+
+                let socket = Box::new(MasqueSocketConnector::new(
+                    endpoint,
+                    target_address.clone(),
+                    effective_sni,
+                    credentials,
+                    dial_options.bind_interface,
+                )) as Box<dyn SocketConnector>;
+
+                return InitialHopEntry::Direct(socket);
+            }
+
+            // Juicity opens a stream on its own QUIC connection for every TCP connection
+            // and UDP flow, so like Hysteria2 it needs no ProxyConnector.
+            if let ClientProxyConfig::Juicity { uuid, password } = &config.protocol {
+                let target_address = &config.address;
+                let dial_options = DialOptions::from_client_config(config);
+                let default_sni_hostname =
+                    target_address.address().hostname().map(ToString::to_string);
+
+                let mut quic_config =
+                    crate::tcp::socket_connector_impl::QuicEndpointConfig::from_client_config(
+                        config.quic_settings.clone().unwrap_or_default(),
+                        default_sni_hostname,
+                    )
+                    .with_tls13_only(true)
+                    // The server doesn't open streams.
+                    .with_transport_config(0, 0, 15, 60);
+
+                if quic_config.alpn_protocols.is_empty() {
+                    quic_config.alpn_protocols.push("h3".to_string());
+                }
+
+                let effective_sni = quic_config.sni_hostname.clone();
+
+                let endpoint = crate::tcp::socket_connector_impl::create_quic_endpoint(
+                    &quic_config,
+                    target_address.address().is_ipv6(),
+                    &dial_options,
+                )
+                .expect("Failed to create QUIC endpoint for Juicity");
+
+                // Validated when loading config
+                let uuid = crate::uuid_util::parse_uuid(uuid).expect("Invalid Juicity uuid");
+
+                let socket = Box::new(JuicitySocketConnector::new(
+                    endpoint,
+                    target_address.clone(),
+                    effective_sni,
+                    uuid,
+                    password.clone(),
+                    dial_options.bind_interface,
+                )) as Box<dyn SocketConnector>;
+
+                return InitialHopEntry::Direct(socket);
+            }
+
+            // WireGuard owns the UDP socket to the peer and connects to targets
+            // inside the tunnel, so like Hysteria2 it needs no ProxyConnector.
+            if let ClientProxyConfig::Wireguard {
+                private_key,
+                peer_public_key,
+                preshared_key,
+                local_addresses,
+                allowed_ips,
+                reserved,
+                mtu,
+                persistent_keepalive,
+                udp_enabled,
+            } = &config.protocol
+            {
+                let tunnel_config = WireguardTunnelConfig::from_fields(
+                    private_key,
+                    peer_public_key,
+                    preshared_key.as_deref(),
+                    &local_addresses.clone().into_vec(),
+                    &allowed_ips.clone().into_vec(),
+                    &reserved.clone().into_vec(),
+                    *mtu,
+                    *persistent_keepalive,
+                )
+                .expect("Invalid WireGuard config (checked during config validation)");
+
+                let socket = Box::new(WireguardSocketConnector::new(
+                    tunnel_config,
+                    config.address.clone(),
+                    *udp_enabled,
+                    DialOptions::from_client_config(config),
+                )) as Box<dyn SocketConnector>;
+
+                return InitialHopEntry::Direct(socket);
+            }
+
+            // A portal connects to targets through the tunnels of bridges, so like
+            // WireGuard it needs no ProxyConnector.
+            if let ClientProxyConfig::Portal { domain } = &config.protocol {
+                let socket =
+                    Box::new(PortalSocketConnector::new(domain)) as Box<dyn SocketConnector>;
+                return InitialHopEntry::Direct(socket);
+            }
+
             // Standard path: create SocketConnector from config
             let target_address = find_first_proxy_address(&hops, config);
 
+            // A SIP003 plugin carries shadowsocks connections to the server, so the
+            // socket connects to the plugin on localhost instead.
+            if let ClientProxyConfig::Shadowsocks {
+                plugin: Some(plugin),
+                ..
+            } = &config.protocol
+            {
+                let local_address =
+                    allocate_local_address().expect("Failed to allocate SIP003 plugin port");
+                let plugin =
+                    Sip003Plugin::start(plugin.clone(), config.address.clone(), local_address)
+                        .expect("Failed to start SIP003 plugin");
+                let socket = SocketConnectorImpl::from_config(config, target_address)
+                    .map(|s| Box::new(s) as Box<dyn SocketConnector>)
+                    .expect("Failed to create SocketConnector");
+                let socket: Box<dyn SocketConnector> =
+                    Box::new(Sip003SocketConnector::new(socket, plugin));
+                let proxy = ProxyConnectorImpl::from_config(config.clone(), resolver.clone())
+                    .map(|p| Box::new(p) as Box<dyn ProxyConnector>)
+                    .expect("Failed to create ProxyConnector for non-direct config");
+                return InitialHopEntry::Proxy { socket, proxy };
+            }
+
             let socket = SocketConnectorImpl::from_config(config, target_address)
                 .map(|s| Box::new(s) as Box<dyn SocketConnector>)
                 .expect("Failed to create SocketConnector");
@@ -212,27 +444,134 @@ pub fn build_direct_chain_group(resolver: Arc<dyn Resolver>) -> ClientChainGroup
     build_client_chain_group(crate::option_util::NoneOrSome::None, resolver)
 }
 
+/// Sets the domain strategy of every client of the chains. No chains means a single
+/// direct chain, which is created so that its strategy can be set.
+pub fn override_domain_strategy(
+    client_chains: crate::option_util::NoneOrSome<ClientChain>,
+    domain_strategy: DomainStrategy,
+) -> crate::option_util::NoneOrSome<ClientChain> {
+    override_client_configs(client_chains, |config| {
+        config.domain_strategy = Some(domain_strategy)
+    })
+}
+
+/// Sets whether every client of the chains resolves destination hostnames locally.
+pub fn override_resolve_locally(
+    client_chains: crate::option_util::NoneOrSome<ClientChain>,
+    resolve_locally: bool,
+) -> crate::option_util::NoneOrSome<ClientChain> {
+    override_client_configs(client_chains, |config| {
+        config.resolve_locally = resolve_locally
+    })
+}
+
+fn override_client_configs(
+    client_chains: crate::option_util::NoneOrSome<ClientChain>,
+    update: impl Fn(&mut ClientConfig),
+) -> crate::option_util::NoneOrSome<ClientChain> {
+    let mut client_chains = if client_chains.is_empty() {
+        vec![ClientChain::default()]
+    } else {
+        client_chains.into_vec()
+    };
+    for chain in client_chains.iter_mut() {
+        for hop in chain.hops.iter_mut() {
+            let selections: Vec<&mut ConfigSelection<ClientConfig>> = match hop {
+                ClientChainHop::Single(selection) => vec![selection],
+                ClientChainHop::Pool(selections) => selections.iter_mut().collect(),
+            };
+            for selection in selections {
+                update(selection.unwrap_config_mut());
+            }
+        }
+    }
+    crate::option_util::NoneOrSome::Some(client_chains)
+}
+
 /// Build a ClientChainGroup from config chains.
 pub fn build_client_chain_group(
     client_chains: crate::option_util::NoneOrSome<crate::config::ClientChain>,
     resolver: Arc<dyn Resolver>,
 ) -> ClientChainGroup {
-    let chains: Vec<ClientProxyChain> = if client_chains.is_empty() {
-        vec![build_client_proxy_chain(
-            crate::option_util::OneOrSome::One(ClientChainHop::Single(ConfigSelection::Config(
-                ClientConfig::default(),
-            ))),
-            resolver,
-        )]
+    let client_chains = if client_chains.is_empty() {
+        // Default to a single direct chain.
+        vec![crate::config::ClientChain::default()]
     } else {
-        client_chains
-            .into_vec()
-            .into_iter()
-            .map(|chain| build_client_proxy_chain(chain.hops, resolver.clone()))
-            .collect()
+        client_chains.into_vec()
     };
 
-    ClientChainGroup::new(chains)
+    let names: Vec<(String, String)> = client_chains
+        .iter()
+        .map(|chain| (client_chain_label(chain), client_chain_kind(chain)))
+        .collect();
+
+    let chains: Vec<ClientProxyChain> = client_chains
+        .into_iter()
+        .map(|chain| build_client_proxy_chain(chain.hops, resolver.clone()))
+        .collect();
+
+    let chain_infos = names
+        .into_iter()
+        .zip(chains.iter())
+        .map(|((name, kind), chain)| ChainInfo {
+            name,
+            kind,
+            supports_udp: chain.supports_udp(),
+        })
+        .collect();
+
+    ClientChainGroup::new(chains).with_selector(get_outbound_selector(chain_infos))
+}
+
+/// Returns a label identifying the chain in metrics, e.g.
+/// `socks@1.2.3.4:1080 -> vless@5.6.7.8:443`.
+fn client_chain_label(chain: &crate::config::ClientChain) -> String {
+    chain
+        .hops
+        .iter()
+        .map(|hop| match hop {
+            ClientChainHop::Single(selection) => client_config_label(selection),
+            ClientChainHop::Pool(selections) => format!(
+                "[{}]",
+                selections
+                    .iter()
+                    .map(client_config_label)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        })
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+/// Returns the proxy type of the chain, e.g. `SOCKS5`, `Relay` for multi-hop chains,
+/// or `LoadBalance` for a single pool.
+fn client_chain_kind(chain: &crate::config::ClientChain) -> String {
+    if chain.hops.len() > 1 {
+        return String::from("Relay");
+    }
+    match chain.hops.iter().next() {
+        Some(ClientChainHop::Single(ConfigSelection::Config(config))) => {
+            config.protocol.protocol_name().to_string()
+        }
+        _ => String::from("LoadBalance"),
+    }
+}
+
+fn client_config_label(selection: &ConfigSelection<ClientConfig>) -> String {
+    match selection {
+        ConfigSelection::Config(config) => match config.protocol {
+            ClientProxyConfig::Direct => String::from("direct"),
+            // The address of a portal is not used.
+            ClientProxyConfig::Portal { ref domain } => format!("portal@{domain}"),
+            ref protocol => format!(
+                "{}@{}",
+                protocol.protocol_name().to_lowercase(),
+                config.address
+            ),
+        },
+        ConfigSelection::GroupName(group_name) => group_name.clone(),
+    }
 }
 
 #[cfg(test)]
@@ -254,6 +593,7 @@ mod tests {
             protocol: ClientProxyConfig::Socks {
                 username: None,
                 password: None,
+                udp_enabled: false,
             },
             ..Default::default()
         }
@@ -357,6 +697,32 @@ mod tests {
         let group = build_client_chain_group(chains, mock_resolver());
         // 2 chains in group
         assert!(group.supports_udp()); // direct chain supports UDP
+        assert_eq!(group.label(), "socks5@127.0.0.1:1080 | direct");
+    }
+
+    #[test]
+    fn test_client_chain_label() {
+        let chain = ClientChain {
+            hops: OneOrSome::Some(vec![
+                ClientChainHop::Single(ConfigSelection::Config(direct_config())),
+                ClientChainHop::Pool(OneOrSome::Some(vec![
+                    ConfigSelection::Config(socks_config(1080)),
+                    ConfigSelection::Config(socks_config(1081)),
+                ])),
+            ]),
+        };
+        assert_eq!(
+            client_chain_label(&chain),
+            "direct -> [socks5@127.0.0.1:1080, socks5@127.0.0.1:1081]"
+        );
+        assert_eq!(client_chain_kind(&chain), "Relay");
+
+        let chain = ClientChain {
+            hops: OneOrSome::One(ClientChainHop::Single(ConfigSelection::Config(
+                socks_config(1080),
+            ))),
+        };
+        assert_eq!(client_chain_kind(&chain), "SOCKS5");
     }
 
     #[test]
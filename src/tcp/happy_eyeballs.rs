@@ -0,0 +1,130 @@
+//! Happy Eyeballs (RFC 8305) connection attempts to destinations with several
+//! addresses.
+//!
+//! Addresses are tried in an order that alternates between IPv6 and IPv4, starting
+//! with the preferred family. Each attempt starts when the previous one fails or after
+//! the attempt delay, and the first connection that succeeds is used.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+
+use crate::config::IpFamily;
+
+/// Orders addresses so that the families alternate, starting with `prefer` when it has
+/// addresses. The order of addresses within a family is kept.
+pub fn sort_addresses(addrs: Vec<SocketAddr>, prefer: IpFamily) -> Vec<SocketAddr> {
+    let (mut preferred, mut other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == (prefer == IpFamily::Ipv6));
+    if preferred.is_empty() {
+        std::mem::swap(&mut preferred, &mut other);
+    }
+
+    (0..preferred.len().max(other.len()))
+        .flat_map(|i| [preferred.get(i), other.get(i)])
+        .flatten()
+        .copied()
+        .collect()
+}
+
+/// Connects to the addresses in order, starting the next attempt after `delay` or when
+/// an attempt fails. Returns the first connection that succeeds, or the error of the
+/// last attempt.
+pub async fn connect<T, F, Fut>(
+    addrs: &[SocketAddr],
+    delay: Duration,
+    connect: F,
+) -> std::io::Result<T>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = std::io::Result<T>>,
+{
+    let mut next_index = 0;
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    loop {
+        if next_index < addrs.len() {
+            attempts.push(connect(addrs[next_index]));
+            next_index += 1;
+        } else if attempts.is_empty() {
+            return Err(
+                last_error.unwrap_or_else(|| std::io::Error::other("no addresses to connect to"))
+            );
+        }
+
+        let attempt_delay = tokio::time::sleep(delay);
+        tokio::pin!(attempt_delay);
+        loop {
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(connection) => return Ok(connection),
+                    Err(e) => {
+                        last_error = Some(e);
+                        break;
+                    }
+                },
+                _ = &mut attempt_delay, if next_index < addrs.len() => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_sort_addresses_alternates_families() {
+        let addrs = vec![
+            addr("1.1.1.1:443"),
+            addr("1.0.0.1:443"),
+            addr("[2606:4700::1111]:443"),
+        ];
+        assert_eq!(
+            sort_addresses(addrs.clone(), IpFamily::Ipv6),
+            vec![
+                addr("[2606:4700::1111]:443"),
+                addr("1.1.1.1:443"),
+                addr("1.0.0.1:443"),
+            ]
+        );
+        assert_eq!(
+            sort_addresses(addrs, IpFamily::Ipv4),
+            vec![
+                addr("1.1.1.1:443"),
+                addr("[2606:4700::1111]:443"),
+                addr("1.0.0.1:443"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connect_uses_first_success() {
+        let addrs = [addr("[::1]:1"), addr("127.0.0.1:2"), addr("127.0.0.1:3")];
+        let result = connect(&addrs, Duration::from_millis(10), |addr| async move {
+            match addr.port() {
+                // Hangs, so the next address is tried after the delay.
+                1 => std::future::pending().await,
+                2 => Err(std::io::Error::other("refused")),
+                _ => Ok(addr),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), addr("127.0.0.1:3"));
+
+        let result = connect(&addrs[1..2], Duration::from_millis(10), |_| async {
+            Err::<(), _>(std::io::Error::other("refused"))
+        })
+        .await;
+        assert_eq!(result.unwrap_err().to_string(), "refused");
+    }
+}
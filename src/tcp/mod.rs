@@ -1,4 +1,5 @@
 pub mod chain_builder;
+pub mod happy_eyeballs;
 pub mod proxy_connector;
 pub mod proxy_connector_impl;
 pub mod socket_connector;
@@ -19,7 +19,9 @@ use async_trait::async_trait;
 use std::fmt::Debug;
 
 use crate::address::{NetLocation, ResolvedLocation};
+use crate::anytls::AnyTlsClientPool;
 use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::mux::MuxClient;
 use crate::tcp::tcp_handler::TcpClientSetupResult;
 
 /// Trait for proxy protocol connectors.
@@ -42,6 +44,24 @@ pub trait ProxyConnector: Send + Sync + Debug {
     /// Check if this connector supports UDP-over-TCP tunneling.
     fn supports_udp_over_tcp(&self) -> bool;
 
+    /// Returns the mux session pool, if TCP connections through this connector are
+    /// multiplexed. Only used when this is the last hop of a chain.
+    fn mux_client(&self) -> Option<&MuxClient> {
+        None
+    }
+
+    /// Returns the AnyTLS session pool, if this connector is an AnyTLS client. Only used
+    /// when this is the last hop of a chain.
+    fn anytls_pool(&self) -> Option<&AnyTlsClientPool> {
+        None
+    }
+
+    /// Returns the target to send to the proxy. Connectors that resolve hostnames locally
+    /// replace them with an IP address.
+    async fn prepare_target(&self, target: &ResolvedLocation) -> std::io::Result<ResolvedLocation> {
+        Ok(target.clone())
+    }
+
     /// Setup protocol on existing stream.
     ///
     /// # Arguments
@@ -9,12 +9,16 @@ use async_trait::async_trait;
 use log::debug;
 
 use super::proxy_connector::ProxyConnector;
-use super::tcp_client_handler_factory::create_tcp_client_handler;
+use super::tcp_client_handler_factory::{create_anytls_client_pool, create_tcp_client_handler};
 use crate::address::{NetLocation, ResolvedLocation};
+use crate::anytls::AnyTlsClientPool;
 use crate::async_stream::{AsyncMessageStream, AsyncStream};
-use crate::config::ClientConfig;
-use crate::resolver::Resolver;
+use crate::config::{ClientConfig, DomainStrategy};
+use crate::mux::MuxClient;
+use crate::resolver::{Resolver, resolve_addresses_with_strategy};
+use crate::socket_util::DialOptions;
 use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
+use crate::uot::UotClientHandler;
 
 /// Implementation of ProxyConnector for proxy protocol setup.
 ///
@@ -22,12 +26,21 @@ use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
 /// - `protocol`
 /// - `address`
 ///
+/// When the protocol has a mux config, TCP connections share mux sessions. AnyTLS
+/// clients reuse idle sessions. With `resolve_locally`, hostname targets are resolved
+/// before they are sent to the proxy. With `udp_over_tcp`, UDP is tunneled with UoT.
+///
 /// This connector only wraps protocols on existing streams - it does not
 /// create socket connections. Socket creation is handled by SocketConnector.
 #[derive(Debug)]
 pub struct ProxyConnectorImpl {
     location: NetLocation,
     client_handler: Box<dyn TcpClientHandler>,
+    mux_client: Option<MuxClient>,
+    anytls_pool: Option<AnyTlsClientPool>,
+    /// Resolves hostname targets when `resolve_locally` is set.
+    local_resolver: Option<Arc<dyn Resolver>>,
+    domain_strategy: Option<DomainStrategy>,
 }
 
 impl ProxyConnectorImpl {
@@ -40,14 +53,30 @@ impl ProxyConnectorImpl {
         }
 
         let default_sni_hostname = config.address.address().hostname().map(ToString::to_string);
+        let dial_options = DialOptions::from_client_config(&config);
+        let mux_client = config
+            .protocol
+            .mux_config()
+            .map(|mux| MuxClient::new(mux.concurrency));
+        let anytls_pool = create_anytls_client_pool(&config.protocol);
+        let local_resolver = config.resolve_locally.then(|| resolver.clone());
+        let mut client_handler = create_tcp_client_handler(
+            config.protocol,
+            default_sni_hostname,
+            Some((&config.address, dial_options)),
+            resolver,
+        );
+        if config.udp_over_tcp {
+            client_handler = Box::new(UotClientHandler::new(client_handler));
+        }
 
         Some(Self {
             location: config.address,
-            client_handler: create_tcp_client_handler(
-                config.protocol,
-                default_sni_hostname,
-                resolver,
-            ),
+            client_handler,
+            mux_client,
+            anytls_pool,
+            local_resolver,
+            domain_strategy: config.domain_strategy,
         })
     }
 
@@ -57,6 +86,10 @@ impl ProxyConnectorImpl {
         Self {
             location,
             client_handler: handler,
+            mux_client: None,
+            anytls_pool: None,
+            local_resolver: None,
+            domain_strategy: None,
         }
     }
 }
@@ -71,6 +104,35 @@ impl ProxyConnector for ProxyConnectorImpl {
         self.client_handler.supports_udp_over_tcp()
     }
 
+    fn mux_client(&self) -> Option<&MuxClient> {
+        self.mux_client.as_ref()
+    }
+
+    fn anytls_pool(&self) -> Option<&AnyTlsClientPool> {
+        self.anytls_pool.as_ref()
+    }
+
+    async fn prepare_target(&self, target: &ResolvedLocation) -> std::io::Result<ResolvedLocation> {
+        let Some(resolver) = &self.local_resolver else {
+            return Ok(target.clone());
+        };
+        if target.address().hostname().is_none() {
+            return Ok(target.clone());
+        }
+        let addr = match target.resolved_addr() {
+            Some(addr) if self.domain_strategy.is_none() => addr,
+            _ => {
+                resolve_addresses_with_strategy(resolver, target.location(), self.domain_strategy)
+                    .await?[0]
+            }
+        };
+        debug!(
+            "[ProxyConnector] resolved {} locally to {addr}",
+            target.location()
+        );
+        Ok(NetLocation::from_ip_addr(addr.ip(), addr.port()).into())
+    }
+
     async fn setup_tcp_stream(
         &self,
         stream: Box<dyn AsyncStream>,
@@ -80,8 +142,9 @@ impl ProxyConnector for ProxyConnectorImpl {
             "[ProxyConnector] setup_tcp_stream: {} -> {}",
             self.location, target
         );
+        let target = self.prepare_target(target).await?;
         self.client_handler
-            .setup_client_tcp_stream(stream, target.clone())
+            .setup_client_tcp_stream(stream, target)
             .await
     }
 
@@ -94,6 +157,7 @@ impl ProxyConnector for ProxyConnectorImpl {
             "[ProxyConnector] setup_udp_bidirectional: {} -> {}",
             self.location, target
         );
+        let target = self.prepare_target(&target).await?;
         self.client_handler
             .setup_client_udp_bidirectional(stream, target)
             .await
@@ -125,6 +189,7 @@ mod tests {
             protocol: ClientProxyConfig::Socks {
                 username: None,
                 password: None,
+                udp_enabled: false,
             },
             ..Default::default()
         };
@@ -132,5 +197,57 @@ mod tests {
         assert!(connector.is_some());
         let connector = connector.unwrap();
         assert_eq!(connector.proxy_location().port(), 1080);
+        assert!(connector.mux_client().is_none());
+    }
+
+    #[test]
+    fn test_from_mux_config() {
+        let config: ClientConfig = serde_yaml::from_str(
+            r#"
+address: "127.0.0.1:443"
+protocol:
+  type: trojan
+  password: secret
+  mux:
+    concurrency: 4
+"#,
+        )
+        .unwrap();
+        let connector = ProxyConnectorImpl::from_config(config, mock_resolver()).unwrap();
+        assert!(connector.mux_client().is_some());
+    }
+
+    #[test]
+    fn test_from_anytls_config() {
+        let config: ClientConfig = serde_yaml::from_str(
+            r#"
+address: "127.0.0.1:443"
+protocol:
+  type: tls
+  protocol:
+    type: anytls
+    password: secret
+    idle_session_timeout_secs: 60
+"#,
+        )
+        .unwrap();
+        let connector = ProxyConnectorImpl::from_config(config, mock_resolver()).unwrap();
+        assert!(connector.anytls_pool().is_some());
+        assert!(connector.mux_client().is_none());
+    }
+
+    #[test]
+    fn test_from_udp_over_tcp_config() {
+        let config: ClientConfig = serde_yaml::from_str(
+            r#"
+address: "127.0.0.1:8080"
+protocol:
+  type: http
+udp_over_tcp: true
+"#,
+        )
+        .unwrap();
+        let connector = ProxyConnectorImpl::from_config(config, mock_resolver()).unwrap();
+        assert!(connector.supports_udp_over_tcp());
     }
 }
@@ -2,20 +2,23 @@
 //!
 //! This trait handles the socket-level connection at hop 0 of a chain.
 //! It is responsible for:
-//! - Creating TCP sockets with bind_interface
+//! - Creating TCP sockets with bind_interface, fwmark and DSCP
 //! - Creating and caching QUIC endpoints
 //! - UDP socket creation for direct connections
+//! - ICMP echo requests for direct connections
 //!
 //! ## Design
 //!
 //! Every `ClientConfig` implicitly defines a `SocketConnector` through its
-//! socket-related fields: `bind_interface`, `transport`, `tcp_settings`, `quic_settings`.
+//! socket-related fields: `bind_interface`, `fwmark`, `dscp`, `transport`, `tcp_settings`,
+//! `quic_settings`.
 //!
 //! When a config is used:
 //! - **As hop 0**: The SocketConnector is used to create the connection
 //! - **As hop 1+**: The SocketConnector config is ignored (connection comes from previous hop)
 
 use std::fmt::Debug;
+use std::net::IpAddr;
 use std::sync::Arc;
 
 use async_trait::async_trait;
@@ -23,6 +26,7 @@ use async_trait::async_trait;
 use crate::address::ResolvedLocation;
 use crate::async_stream::{AsyncMessageStream, AsyncStream};
 use crate::resolver::Resolver;
+use crate::socket_util::DialOptions;
 
 /// Trait for creating socket connections at hop 0.
 ///
@@ -55,6 +59,27 @@ pub trait SocketConnector: Send + Sync + Debug {
         target: ResolvedLocation,
     ) -> std::io::Result<Box<dyn AsyncMessageStream>>;
 
+    /// Send an ICMP echo request to `ip` and wait for the reply.
+    ///
+    /// Returns an `Unsupported` error when this socket connector can't send echo
+    /// requests.
+    async fn ping(
+        &self,
+        _resolver: &Arc<dyn Resolver>,
+        _ip: IpAddr,
+        _payload: &[u8],
+    ) -> std::io::Result<()> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "ICMP is not supported by this outbound",
+        ))
+    }
+
     /// Returns the bind interface configured for this socket connector, if any.
     fn bind_interface(&self) -> Option<&str>;
+
+    /// Returns the options of the sockets created by this socket connector.
+    fn dial_options(&self) -> DialOptions {
+        DialOptions::with_bind_interface(self.bind_interface().map(ToString::to_string))
+    }
 }
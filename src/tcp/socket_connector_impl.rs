@@ -1,9 +1,9 @@
 //! SocketConnectorImpl - Implementation of SocketConnector trait.
 //!
-//! Handles TCP and QUIC transports with bind_interface support.
+//! Handles TCP, QUIC and KCP transports with bind_interface, fwmark and DSCP support.
 //! Created from the socket-related fields of any ClientConfig.
 
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::pin::Pin;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU8, Ordering};
@@ -11,18 +11,29 @@ use std::task::{Context, Poll};
 
 use async_trait::async_trait;
 use log::{debug, error};
-use tokio::io::ReadBuf;
+use quinn::Runtime;
+use tokio::io::{AsyncWriteExt, ReadBuf};
 use tokio::net::UdpSocket;
 
 use crate::address::{NetLocation, ResolvedLocation};
 use crate::async_stream::AsyncStream;
-use crate::config::{ClientConfig, ClientQuicConfig, Transport};
+use crate::config::{
+    ClientConfig, ClientQuicConfig, DomainStrategy, HappyEyeballsConfig, TcpConfig, Transport,
+};
+use crate::hysteria2_obfs::{Salamander, SalamanderUdpSocket};
+use crate::icmp_ping;
+use crate::kcp::KcpConnector;
+use crate::proxy_protocol;
 use crate::quic_stream::QuicStream;
-use crate::resolver::{resolve_location, resolve_single_address, Resolver};
+use crate::resolver::{resolve_addresses_with_strategy, Resolver};
 use crate::rustls_config_util::create_client_config;
-use crate::socket_util::{new_tcp_socket, new_udp_socket, set_tcp_keepalive};
+use crate::socket_util::{
+    DialOptions, configure_tcp_stream, new_tcp_socket, new_udp_socket, set_tcp_fast_open_connect,
+};
 use crate::thread_util::get_num_threads;
+use crate::udp_batch::{RecvBatch, SendBatch};
 
+use super::happy_eyeballs;
 use super::socket_connector::SocketConnector;
 
 const MAX_QUIC_ENDPOINTS: usize = 32;
@@ -38,6 +49,8 @@ pub struct QuicEndpointConfig {
     pub cert: Option<String>,
     pub tls13_only: bool,
     pub transport_config: Option<Arc<quinn::TransportConfig>>,
+    /// Obfuscates the datagrams of the endpoint, used by Hysteria2.
+    pub salamander: Option<Arc<Salamander>>,
 }
 
 impl QuicEndpointConfig {
@@ -76,6 +89,7 @@ impl QuicEndpointConfig {
             cert,
             tls13_only: false,
             transport_config: None,
+            salamander: None,
         }
     }
 
@@ -104,21 +118,46 @@ impl QuicEndpointConfig {
         self.transport_config = Some(Arc::new(transport_config));
         self
     }
+
+    pub fn with_salamander(mut self, salamander: Arc<Salamander>) -> Self {
+        self.salamander = Some(salamander);
+        self
+    }
+
+    /// Sets the congestion controller of the transport config set by
+    /// `with_transport_config`.
+    pub fn with_congestion_controller(
+        mut self,
+        factory: Arc<dyn quinn::congestion::ControllerFactory + Send + Sync + 'static>,
+    ) -> Self {
+        let transport_config = self
+            .transport_config
+            .as_mut()
+            .and_then(Arc::get_mut)
+            .expect("transport config should be set and not shared");
+        transport_config.congestion_controller_factory(factory);
+        self
+    }
 }
 
 /// Create a single QUIC endpoint.
 pub fn create_quic_endpoint(
     config: &QuicEndpointConfig,
     is_ipv6: bool,
-    bind_interface: Option<String>,
+    dial_options: &DialOptions,
 ) -> std::io::Result<Arc<quinn::Endpoint>> {
-    let tls13_suite =
-        match rustls::crypto::aws_lc_rs::cipher_suite::TLS13_AES_128_GCM_SHA256 {
-            rustls::SupportedCipherSuite::Tls13(t) => t,
-            _ => {
-                panic!("Could not retrieve Tls13CipherSuite");
+    let tls13_suite = crate::crypto_provider::get()
+        .cipher_suites
+        .iter()
+        .find_map(|suite| match suite {
+            rustls::SupportedCipherSuite::Tls13(t)
+                if suite.suite() == rustls::CipherSuite::TLS13_AES_128_GCM_SHA256 =>
+            {
+                Some(*t)
             }
-        };
+            _ => None,
+        })
+        .expect("Could not retrieve Tls13CipherSuite");
 
     let key_and_cert_bytes = config.key.clone().zip(config.cert.clone()).map(|(key, cert)| {
         let cert_bytes = cert.as_bytes().to_vec();
@@ -133,6 +172,9 @@ pub fn create_quic_endpoint(
         config.sni_hostname.is_some(),
         key_and_cert_bytes,
         config.tls13_only,
+        None,
+        None,
+        None,
     );
 
     let quic_client_config = quinn::crypto::rustls::QuicClientConfig::with_initial(
@@ -156,7 +198,7 @@ pub fn create_quic_endpoint(
         quinn_client_config.transport_config(Arc::new(transport_config));
     }
 
-    let udp_socket = match new_udp_socket(is_ipv6, bind_interface) {
+    let udp_socket = match new_udp_socket(is_ipv6, dial_options) {
         Ok(s) => s,
         Err(e) => {
             return Err(std::io::Error::other(format!(
@@ -166,13 +208,24 @@ pub fn create_quic_endpoint(
     };
     let udp_socket = udp_socket.into_std().unwrap();
 
-    let mut endpoint = quinn::Endpoint::new(
-        quinn::EndpointConfig::default(),
-        None,
-        udp_socket,
-        Arc::new(quinn::TokioRuntime),
-    )
-    .unwrap();
+    let mut endpoint = match &config.salamander {
+        Some(salamander) => {
+            let udp_socket = quinn::TokioRuntime.wrap_udp_socket(udp_socket)?;
+            quinn::Endpoint::new_with_abstract_socket(
+                quinn::EndpointConfig::default(),
+                None,
+                Arc::new(SalamanderUdpSocket::new(udp_socket, salamander.clone())),
+                Arc::new(quinn::TokioRuntime),
+            )?
+        }
+        None => quinn::Endpoint::new(
+            quinn::EndpointConfig::default(),
+            None,
+            udp_socket,
+            Arc::new(quinn::TokioRuntime),
+        )
+        .unwrap(),
+    };
     endpoint.set_default_client_config(quinn_client_config);
     Ok(Arc::new(endpoint))
 }
@@ -180,25 +233,33 @@ pub fn create_quic_endpoint(
 #[derive(Debug)]
 enum TransportConfig {
     Tcp {
-        no_delay: bool,
+        tcp_config: TcpConfig,
+        happy_eyeballs: HappyEyeballsConfig,
     },
     Quic {
         sni_hostname: Option<String>,
         endpoints: Vec<Arc<quinn::Endpoint>>,
         next_endpoint_index: AtomicU8,
     },
+    Kcp {
+        connector: KcpConnector,
+    },
 }
 
-/// Implementation of SocketConnector for TCP and QUIC transports.
+/// Implementation of SocketConnector for TCP, QUIC and KCP transports.
 ///
 /// Created from the socket-related fields of any ClientConfig:
-/// - `bind_interface`
+/// - `bind_interface`, `fwmark` and `dscp`
+/// - `domain_strategy`
 /// - `transport`
-/// - `tcp_settings`
+/// - `tcp_settings` and `happy_eyeballs`
 /// - `quic_settings`
+/// - `kcp_settings`
 #[derive(Debug)]
 pub struct SocketConnectorImpl {
-    bind_interface: Option<String>,
+    dial_options: DialOptions,
+    /// None when hostnames are resolved without a strategy, including `use_remote`.
+    domain_strategy: Option<DomainStrategy>,
     transport: TransportConfig,
 }
 
@@ -211,12 +272,12 @@ impl SocketConnectorImpl {
     ///   Pass None for direct protocol (QUIC is not supported for direct).
     ///
     /// # Returns
-    /// None if QUIC endpoint creation fails.
+    /// None if QUIC endpoint or KCP connector creation fails.
     pub fn from_config(
         config: &ClientConfig,
         target_address: Option<&NetLocation>,
     ) -> Option<Self> {
-        let bind_interface = config.bind_interface.clone().into_option();
+        let dial_options = DialOptions::from_client_config(config);
 
         let default_sni_hostname =
             target_address.and_then(|addr| addr.address().hostname().map(ToString::to_string));
@@ -229,14 +290,10 @@ impl SocketConnectorImpl {
         };
 
         let transport = match *effective_transport {
-            Transport::Tcp | Transport::Udp => {
-                let no_delay = config
-                    .tcp_settings
-                    .as_ref()
-                    .map(|tc| tc.no_delay)
-                    .unwrap_or(true);
-                TransportConfig::Tcp { no_delay }
-            }
+            Transport::Tcp | Transport::Udp => TransportConfig::Tcp {
+                tcp_config: config.tcp_settings.clone().unwrap_or_default(),
+                happy_eyeballs: config.happy_eyeballs.clone().unwrap_or_default(),
+            },
             Transport::Quic => {
                 // QUIC requires a target address for endpoint creation
                 let target_address = target_address.expect(
@@ -257,7 +314,7 @@ impl SocketConnectorImpl {
                     if let Ok(endpoint) = create_quic_endpoint(
                         &quic_config,
                         target_address.address().is_ipv6(),
-                        bind_interface.clone(),
+                        &dial_options,
                     ) {
                         endpoints.push(endpoint);
                     } else {
@@ -273,10 +330,22 @@ impl SocketConnectorImpl {
                     next_endpoint_index: AtomicU8::new(0),
                 }
             }
+            Transport::Kcp => {
+                match KcpConnector::new(config.kcp_settings.clone().unwrap_or_default()) {
+                    Ok(connector) => TransportConfig::Kcp { connector },
+                    Err(e) => {
+                        error!("Failed to create KCP connector: {e}");
+                        return None;
+                    }
+                }
+            }
         };
 
         Some(Self {
-            bind_interface,
+            dial_options,
+            domain_strategy: config
+                .domain_strategy
+                .filter(|strategy| *strategy != DomainStrategy::UseRemote),
             transport,
         })
     }
@@ -287,10 +356,49 @@ impl SocketConnectorImpl {
     #[cfg(test)]
     pub fn new_tcp(bind_interface: Option<String>, no_delay: bool) -> Self {
         Self {
-            bind_interface,
-            transport: TransportConfig::Tcp { no_delay },
+            dial_options: DialOptions::with_bind_interface(bind_interface),
+            domain_strategy: None,
+            transport: TransportConfig::Tcp {
+                tcp_config: TcpConfig {
+                    no_delay,
+                    ..Default::default()
+                },
+                happy_eyeballs: HappyEyeballsConfig::default(),
+            },
+        }
+    }
+}
+
+impl SocketConnectorImpl {
+    /// Returns the addresses to connect to. With a domain strategy, hostnames are
+    /// resolved again even when they were resolved for routing rules.
+    async fn resolve(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        address: &ResolvedLocation,
+    ) -> std::io::Result<Vec<SocketAddr>> {
+        match address.resolved_addr() {
+            Some(addr) if self.domain_strategy.is_none() => Ok(vec![addr]),
+            _ => {
+                resolve_addresses_with_strategy(resolver, address.location(), self.domain_strategy)
+                    .await
+            }
         }
     }
+
+    async fn connect_tcp(
+        &self,
+        tcp_config: &TcpConfig,
+        target_addr: SocketAddr,
+    ) -> std::io::Result<tokio::net::TcpStream> {
+        let tcp_socket = new_tcp_socket(&self.dial_options, target_addr.is_ipv6())?;
+        if tcp_config.fast_open
+            && let Err(e) = set_tcp_fast_open_connect(&tcp_socket)
+        {
+            error!("Failed to enable TCP Fast Open: {e}");
+        }
+        tcp_socket.connect(target_addr).await
+    }
 }
 
 #[async_trait]
@@ -300,27 +408,43 @@ impl SocketConnector for SocketConnectorImpl {
         resolver: &Arc<dyn Resolver>,
         address: &ResolvedLocation,
     ) -> std::io::Result<Box<dyn AsyncStream>> {
-        let target_addr = match address.resolved_addr() {
-            Some(r) => r,
-            None => resolve_single_address(resolver, address.location()).await?,
-        };
-
         match &self.transport {
-            TransportConfig::Tcp { no_delay } => {
-                let tcp_socket =
-                    new_tcp_socket(self.bind_interface.clone(), target_addr.is_ipv6())?;
-                let stream = tcp_socket.connect(target_addr).await?;
+            TransportConfig::Tcp {
+                tcp_config,
+                happy_eyeballs,
+            } => {
+                let target_addrs = self.resolve(resolver, address).await?;
+                let mut stream = if happy_eyeballs.enabled && target_addrs.len() > 1 {
+                    let prefer = self
+                        .domain_strategy
+                        .and_then(DomainStrategy::preferred_family)
+                        .unwrap_or(happy_eyeballs.prefer);
+                    let target_addrs = happy_eyeballs::sort_addresses(target_addrs, prefer);
+                    happy_eyeballs::connect(
+                        &target_addrs,
+                        std::time::Duration::from_millis(happy_eyeballs.delay_ms),
+                        |target_addr| self.connect_tcp(tcp_config, target_addr),
+                    )
+                    .await?
+                } else {
+                    self.connect_tcp(tcp_config, target_addrs[0]).await?
+                };
 
-                if let Err(e) = set_tcp_keepalive(
+                if let Err(e) = configure_tcp_stream(
                     &stream,
+                    tcp_config,
                     std::time::Duration::from_secs(120),
                     std::time::Duration::from_secs(30),
                 ) {
-                    error!("Failed to set TCP keepalive: {e}");
+                    error!("Failed to set TCP options: {e}");
                 }
 
-                if *no_delay && let Err(e) = stream.set_nodelay(true) {
-                    error!("Failed to set TCP no-delay: {e}");
+                if let Some(version) = tcp_config.send_proxy_protocol {
+                    let header = proxy_protocol::encode_header(
+                        version,
+                        proxy_protocol::client_addresses().as_ref(),
+                    );
+                    stream.write_all(&header).await?;
                 }
 
                 Ok(Box::new(stream))
@@ -330,6 +454,8 @@ impl SocketConnector for SocketConnectorImpl {
                 next_endpoint_index,
                 sni_hostname,
             } => {
+                let target_addr = self.resolve(resolver, address).await?[0];
+
                 let domain = match sni_hostname {
                     Some(s) => s.as_str(),
                     None => address.address().hostname().unwrap_or("example.com"),
@@ -356,21 +482,26 @@ impl SocketConnector for SocketConnectorImpl {
 
                 Ok(Box::new(QuicStream::from(send, recv)))
             }
+            TransportConfig::Kcp { connector } => {
+                let target_addr = self.resolve(resolver, address).await?[0];
+                let stream = connector.connect(target_addr, &self.dial_options).await?;
+                Ok(Box::new(stream))
+            }
         }
     }
 
     async fn connect_udp_bidirectional(
         &self,
         resolver: &Arc<dyn Resolver>,
-        mut target: ResolvedLocation,
+        target: ResolvedLocation,
     ) -> std::io::Result<Box<dyn crate::async_stream::AsyncMessageStream>> {
         debug!(
             "[SocketConnector] connect_udp_bidirectional called, target: {}",
             target.location()
         );
 
-        let remote_addr = resolve_location(&mut target, resolver).await?;
-        let client_socket = new_udp_socket(remote_addr.is_ipv6(), self.bind_interface.clone())?;
+        let remote_addr = self.resolve(resolver, &target).await?[0];
+        let client_socket = new_udp_socket(remote_addr.is_ipv6(), &self.dial_options)?;
 
         // Don't use connect() - wrap in UnconnectedUdpSocket instead.
         // A connected UDP socket filters incoming packets by source address,
@@ -382,16 +513,32 @@ impl SocketConnector for SocketConnectorImpl {
         )))
     }
 
+    async fn ping(
+        &self,
+        _resolver: &Arc<dyn Resolver>,
+        ip: IpAddr,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        icmp_ping::ping(ip, payload, &self.dial_options).await
+    }
+
     fn bind_interface(&self) -> Option<&str> {
-        self.bind_interface.as_deref()
+        self.dial_options.bind_interface.as_deref()
+    }
+
+    fn dial_options(&self) -> DialOptions {
+        self.dial_options.clone()
     }
 }
 
 /// A UDP socket wrapper that tracks the destination and uses send_to/recv_from.
 /// Unlike a connected UDP socket, this accepts incoming packets from any source.
+/// Datagrams are received and sent in batches.
 struct UnconnectedUdpSocket {
     socket: UdpSocket,
     destination: SocketAddr,
+    recv_batch: RecvBatch,
+    send_batch: SendBatch,
 }
 
 impl UnconnectedUdpSocket {
@@ -399,6 +546,8 @@ impl UnconnectedUdpSocket {
         Self {
             socket,
             destination,
+            recv_batch: RecvBatch::default(),
+            send_batch: SendBatch::default(),
         }
     }
 }
@@ -410,7 +559,7 @@ impl crate::async_stream::AsyncReadMessage for UnconnectedUdpSocket {
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<()>> {
         let this = self.get_mut();
-        match this.socket.poll_recv_from(cx, buf) {
+        match this.recv_batch.poll_recv_from(&this.socket, cx, buf) {
             Poll::Ready(Ok(addr)) => {
                 log::debug!(
                     "[UnconnectedUdp] Received {} bytes from {} (target: {})",
@@ -433,27 +582,25 @@ impl crate::async_stream::AsyncWriteMessage for UnconnectedUdpSocket {
         buf: &[u8],
     ) -> Poll<std::io::Result<()>> {
         let this = self.get_mut();
-        this.socket
-            .poll_send_to(cx, buf, this.destination)
-            .map(|r| r.map(|_| ()))
+        this.send_batch
+            .poll_send_to(&this.socket, cx, buf, this.destination)
     }
 }
 
 impl crate::async_stream::AsyncFlushMessage for UnconnectedUdpSocket {
-    fn poll_flush_message(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.send_batch.poll_flush(&this.socket, cx)
     }
 }
 
 impl crate::async_stream::AsyncShutdownMessage for UnconnectedUdpSocket {
     fn poll_shutdown_message(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+        let this = self.get_mut();
+        this.send_batch.poll_flush(&this.socket, cx)
     }
 }
 
@@ -478,9 +625,12 @@ mod tests {
         let connector = SocketConnectorImpl::new_tcp(Some("eth0".to_string()), true);
         assert!(matches!(
             connector.transport,
-            TransportConfig::Tcp { no_delay: true }
+            TransportConfig::Tcp { ref tcp_config, .. } if tcp_config.no_delay
         ));
-        assert_eq!(connector.bind_interface, Some("eth0".to_string()));
+        assert_eq!(
+            connector.dial_options.bind_interface,
+            Some("eth0".to_string())
+        );
     }
 
     #[test]
@@ -1,32 +1,44 @@
 //! Factory functions for creating TCP client handlers from config.
 
 use std::sync::Arc;
+use std::time::Duration;
 
 use log::debug;
 
-use crate::anytls::{AnyTlsClientHandler, PaddingFactory};
-use crate::client_proxy_selector::{ClientProxySelector, ConnectAction, ConnectRule};
+use crate::address::NetLocation;
+use crate::anytls::{AnyTlsClientHandler, AnyTlsClientPool, PaddingFactory};
+use crate::client_proxy_chain::DialPolicy;
+use crate::client_proxy_selector::{
+    ClientProxySelector, ConnectAction, ConnectRule, register_proxy_selector,
+};
 use crate::config::{
-    ClientProxyConfig, RuleActionConfig, RuleConfig, ShadowsocksConfig, TlsClientConfig,
-    WebsocketClientConfig,
+    ClientProxyConfig, GrpcClientConfig, HttpUpgradeClientConfig, RuleActionConfig, RuleConfig,
+    ShadowsocksConfig, TlsClientConfig, WebsocketClientConfig, XhttpClientConfig,
 };
+use crate::grpc_stream::GrpcTcpClientHandler;
 use crate::http_handler::HttpTcpClientHandler;
 use crate::naiveproxy::NaiveProxyTcpClientHandler;
+use crate::obfs4::{Obfs4Identity, Obfs4TcpClientHandler, decode_node_id};
 use crate::port_forward_handler::PortForwardClientHandler;
 use crate::resolver::Resolver;
-use crate::rustls_config_util::create_client_config;
+use crate::rustls_config_util::{create_client_config, parse_ech_config_list};
 use crate::shadow_tls::ShadowTlsClientHandler;
 use crate::shadowsocks::ShadowsocksTcpHandler;
 use crate::snell::snell_handler::SnellClientHandler;
-use crate::socks_handler::SocksTcpClientHandler;
-use crate::tcp::chain_builder::build_client_chain_group;
+use crate::socket_util::DialOptions;
+use crate::socks_handler::{SocksTcpClientHandler, SocksUdpAssociate};
+use crate::ssh_client::{SshClientHandler, parse_ssh_private_key};
+use crate::tcp::chain_builder::{
+    build_client_chain_group, override_domain_strategy, override_resolve_locally,
+};
 use crate::tcp::tcp_handler::TcpClientHandler;
 use crate::tls_client_handler::TlsClientHandler;
 use crate::trojan_handler::TrojanTcpHandler;
 use crate::uuid_util::parse_uuid;
 use crate::vless::vless_client_handler::VlessTcpClientHandler;
 use crate::vmess::VmessTcpClientHandler;
-use crate::websocket::WebsocketTcpClientHandler;
+use crate::websocket::{HttpUpgradeTcpClientHandler, WebsocketTcpClientHandler};
+use crate::xhttp::XhttpTcpClientHandler;
 
 fn create_auth_credentials(
     username: Option<String>,
@@ -38,9 +50,13 @@ fn create_auth_credentials(
     }
 }
 
+/// `server` is the address of the proxy server and the options of sockets to it, for
+/// protocols that open sockets of their own to the server. It is None for inner
+/// protocols.
 pub fn create_tcp_client_handler(
     client_proxy_config: ClientProxyConfig,
     default_sni_hostname: Option<String>,
+    server: Option<(&NetLocation, DialOptions)>,
     resolver: Arc<dyn Resolver>,
 ) -> Box<dyn TcpClientHandler> {
     match client_proxy_config {
@@ -51,6 +67,7 @@ pub fn create_tcp_client_handler(
             username,
             password,
             resolve_hostname,
+            headers,
         } => {
             let http_resolver = if resolve_hostname {
                 Some(resolver.clone())
@@ -60,26 +77,56 @@ pub fn create_tcp_client_handler(
             Box::new(HttpTcpClientHandler::new(
                 create_auth_credentials(username, password),
                 http_resolver,
+                headers,
+            ))
+        }
+        ClientProxyConfig::Socks {
+            username,
+            password,
+            udp_enabled,
+        } => {
+            let udp_associate = match server {
+                Some((server_location, dial_options)) if udp_enabled => Some(SocksUdpAssociate {
+                    server_location: server_location.clone(),
+                    dial_options,
+                    resolver: resolver.clone(),
+                }),
+                _ => None,
+            };
+            Box::new(SocksTcpClientHandler::new(
+                create_auth_credentials(username, password),
+                udp_associate,
             ))
         }
-        ClientProxyConfig::Socks { username, password } => Box::new(SocksTcpClientHandler::new(
-            create_auth_credentials(username, password),
-        )),
         ClientProxyConfig::Shadowsocks {
             config,
             udp_enabled,
+            ..
         } => match config {
             ShadowsocksConfig::Legacy { cipher, password } => Box::new(
                 ShadowsocksTcpHandler::new_client(cipher, &password, udp_enabled),
             ),
-            ShadowsocksConfig::Aead2022 { cipher, key_bytes } => Box::new(
-                ShadowsocksTcpHandler::new_aead2022_client(cipher, &key_bytes, udp_enabled),
-            ),
+            ShadowsocksConfig::Aead2022 {
+                cipher,
+                key_bytes,
+                identity_keys,
+            } => Box::new(ShadowsocksTcpHandler::new_aead2022_client(
+                cipher,
+                &key_bytes,
+                &identity_keys,
+                udp_enabled,
+            )),
         },
         ClientProxyConfig::Snell {
             config: ShadowsocksConfig::Legacy { cipher, password },
             udp_enabled,
-        } => Box::new(SnellClientHandler::new(cipher, &password, udp_enabled)),
+            version,
+        } => Box::new(SnellClientHandler::new(
+            cipher,
+            &password,
+            udp_enabled,
+            version,
+        )),
         ClientProxyConfig::Snell {
             config: ShadowsocksConfig::Aead2022 { .. },
             ..
@@ -88,13 +135,17 @@ pub fn create_tcp_client_handler(
                 "Snell does not support shadowsocks 2022 ciphers (checked during config validation)"
             )
         }
+        // Mux sessions are pooled by the ProxyConnector, above the handler.
         ClientProxyConfig::Vless {
             user_id,
             udp_enabled,
-        } => Box::new(VlessTcpClientHandler::new(&user_id, udp_enabled)),
+            xudp,
+            ..
+        } => Box::new(VlessTcpClientHandler::new(&user_id, udp_enabled, xudp)),
         ClientProxyConfig::Trojan {
             password,
             shadowsocks,
+            ..
         } => Box::new(TrojanTcpHandler::new_client(&password, &shadowsocks)),
         ClientProxyConfig::Tls(tls_client_config) => {
             let TlsClientConfig {
@@ -107,6 +158,9 @@ pub fn create_tcp_client_handler(
                 key,
                 cert,
                 vision,
+                client_fingerprint,
+                ech_config_list,
+                post_quantum,
             } = tls_client_config;
 
             let sni_hostname = if sni_hostname.is_unspecified() {
@@ -129,6 +183,13 @@ pub fn create_tcp_client_handler(
                 (key_bytes, cert_bytes)
             });
 
+            let ech_config = ech_config_list.map(|ech_config_list| {
+                if sni_hostname.is_none() {
+                    panic!("ECH requires sni_hostname to be specified");
+                }
+                parse_ech_config_list(&ech_config_list).expect("Invalid ECH config list")
+            });
+
             let client_config = Arc::new(create_client_config(
                 verify,
                 server_fingerprints.into_vec(),
@@ -136,6 +197,9 @@ pub fn create_tcp_client_handler(
                 sni_hostname.is_some(),
                 key_and_cert_bytes,
                 false, // tls13_only
+                client_fingerprint,
+                ech_config,
+                post_quantum,
             ));
 
             let server_name = match sni_hostname {
@@ -149,6 +213,8 @@ pub fn create_tcp_client_handler(
                 let ClientProxyConfig::Vless {
                     user_id,
                     udp_enabled,
+                    xudp,
+                    ..
                 } = protocol.as_ref()
                 else {
                     // Validated when loading config
@@ -163,9 +229,10 @@ pub fn create_tcp_client_handler(
                     server_name,
                     user_id_bytes,
                     *udp_enabled,
+                    *xudp,
                 ))
             } else {
-                let handler = create_tcp_client_handler(*protocol, None, resolver.clone());
+                let handler = create_tcp_client_handler(*protocol, None, None, resolver.clone());
 
                 Box::new(TlsClientHandler::new(
                     client_config,
@@ -181,6 +248,7 @@ pub fn create_tcp_client_handler(
             sni_hostname,
             cipher_suites,
             vision,
+            client_fingerprint,
             protocol,
         } => {
             // Decode public key from base64url
@@ -208,6 +276,8 @@ pub fn create_tcp_client_handler(
                 let ClientProxyConfig::Vless {
                     user_id,
                     udp_enabled,
+                    xudp,
+                    ..
                 } = protocol.as_ref()
                 else {
                     unreachable!("Vision requires VLESS (should be validated during config load)")
@@ -221,17 +291,21 @@ pub fn create_tcp_client_handler(
                         short_id_bytes,
                         server_name,
                         cipher_suites,
+                        client_fingerprint,
                         user_id_bytes,
                         *udp_enabled,
+                        *xudp,
                     ),
                 )
             } else {
-                let inner_handler = create_tcp_client_handler(*protocol, None, resolver.clone());
+                let inner_handler =
+                    create_tcp_client_handler(*protocol, None, None, resolver.clone());
                 Box::new(crate::reality_client_handler::RealityClientHandler::new(
                     public_key_bytes,
                     short_id_bytes,
                     server_name,
                     cipher_suites,
+                    client_fingerprint,
                     inner_handler,
                 ))
             }
@@ -239,6 +313,7 @@ pub fn create_tcp_client_handler(
         ClientProxyConfig::ShadowTls {
             password,
             sni_hostname,
+            client_fingerprint,
             protocol,
         } => {
             let sni_hostname = sni_hostname.or(default_sni_hostname);
@@ -261,9 +336,12 @@ pub fn create_tcp_client_handler(
                 enable_sni, // Enable SNI if hostname provided
                 None,       // No client cert
                 true,       // tls13_only - required for ShadowTLS v3
+                client_fingerprint,
+                None,       // No ECH - the handshake server must see the real SNI
+                None,       // Default key exchange groups
             ));
 
-            let handler = create_tcp_client_handler(*protocol, None, resolver.clone());
+            let handler = create_tcp_client_handler(*protocol, None, None, resolver.clone());
 
             Box::new(ShadowTlsClientHandler::new(
                 password,
@@ -276,44 +354,175 @@ pub fn create_tcp_client_handler(
             cipher,
             user_id,
             udp_enabled,
-        } => Box::new(VmessTcpClientHandler::new(&cipher, &user_id, udp_enabled)),
+            chunk_masking,
+            global_padding,
+            ..
+        } => Box::new(VmessTcpClientHandler::new(
+            &cipher,
+            &user_id,
+            udp_enabled,
+            chunk_masking,
+            global_padding,
+        )),
         ClientProxyConfig::Websocket(websocket_client_config) => {
             let WebsocketClientConfig {
                 matching_path,
+                host,
                 matching_headers,
                 ping_type,
+                max_early_data,
                 protocol,
             } = websocket_client_config;
 
-            let handler = create_tcp_client_handler(*protocol, None, resolver.clone());
+            // A Host header in matching_headers takes precedence over the SNI hostname.
+            let has_host_header = matching_headers
+                .as_ref()
+                .is_some_and(|h| h.keys().any(|k| k.eq_ignore_ascii_case("host")));
+            let host = host.or(if has_host_header {
+                None
+            } else {
+                default_sni_hostname
+            });
+
+            let handler = create_tcp_client_handler(*protocol, None, None, resolver.clone());
 
             Box::new(WebsocketTcpClientHandler::new(
                 matching_path,
+                host,
                 matching_headers.map(|h| h.into_iter().collect()),
                 ping_type,
+                max_early_data,
+                handler,
+            ))
+        }
+        ClientProxyConfig::Grpc(grpc_client_config) => {
+            let GrpcClientConfig {
+                service_name,
+                host,
+                protocol,
+            } = grpc_client_config;
+
+            let authority = host
+                .or(default_sni_hostname)
+                .unwrap_or_else(|| "localhost".to_string());
+
+            let handler = create_tcp_client_handler(*protocol, None, None, resolver.clone());
+
+            Box::new(GrpcTcpClientHandler::new(&service_name, &authority, handler))
+        }
+        ClientProxyConfig::HttpUpgrade(httpupgrade_client_config) => {
+            let HttpUpgradeClientConfig {
+                path,
+                host,
+                headers,
+                protocol,
+            } = httpupgrade_client_config;
+
+            let host = host
+                .or(default_sni_hostname)
+                .unwrap_or_else(|| "localhost".to_string());
+
+            let handler = create_tcp_client_handler(*protocol, None, None, resolver.clone());
+
+            Box::new(HttpUpgradeTcpClientHandler::new(
+                path,
+                &host,
+                headers.map(|h| h.into_iter().collect()),
                 handler,
             ))
         }
+        ClientProxyConfig::Xhttp(xhttp_client_config) => {
+            let XhttpClientConfig {
+                path,
+                host,
+                mode,
+                protocol,
+            } = xhttp_client_config;
+
+            let authority = host
+                .or(default_sni_hostname)
+                .unwrap_or_else(|| "localhost".to_string());
+
+            let handler = create_tcp_client_handler(*protocol, None, None, resolver.clone());
+
+            Box::new(XhttpTcpClientHandler::new(&path, &authority, mode, handler))
+        }
+        ClientProxyConfig::Obfs4 {
+            public_key,
+            node_id,
+            iat_mode,
+            protocol,
+        } => {
+            let public_key =
+                crate::reality::decode_public_key(&public_key).expect("Invalid obfs4 public key");
+            let node_id = decode_node_id(&node_id).expect("Invalid obfs4 node ID");
+            let identity = Obfs4Identity::new(node_id, public_key);
+
+            let handler =
+                create_tcp_client_handler(*protocol, default_sni_hostname, None, resolver.clone());
+
+            Box::new(Obfs4TcpClientHandler::new(identity, iat_mode, handler))
+        }
         ClientProxyConfig::PortForward => Box::new(PortForwardClientHandler),
         ClientProxyConfig::Hysteria2 { .. } => {
             panic!("Hysteria2 is a QUIC protocol and should be handled by the socket connector, not as a TCP client handler. Ensure Hysteria2 configs use transport: quic.")
         }
+        ClientProxyConfig::Masque { .. } => {
+            panic!("MASQUE is a QUIC protocol and should be handled by the socket connector, not as a TCP client handler. Ensure MASQUE configs use transport: quic.")
+        }
+        ClientProxyConfig::Juicity { .. } => {
+            panic!("Juicity is a QUIC protocol and should be handled by the socket connector, not as a TCP client handler. Ensure Juicity configs use transport: quic.")
+        }
+        ClientProxyConfig::Ssh {
+            username,
+            password,
+            private_key,
+            private_key_passphrase,
+            host_key_fingerprints,
+            verify_host_key,
+        } => {
+            let host_key_fingerprints = verify_host_key.then(|| host_key_fingerprints.into_vec());
+            match private_key {
+                Some(private_key) => {
+                    // Private key PEM data is embedded during config validation
+                    let private_key = parse_ssh_private_key(
+                        &private_key,
+                        private_key_passphrase.as_deref(),
+                    )
+                    .expect("Invalid SSH private key");
+                    Box::new(SshClientHandler::new_with_private_key(
+                        username,
+                        private_key,
+                        host_key_fingerprints,
+                    ))
+                }
+                None => Box::new(SshClientHandler::new_with_password(
+                    username,
+                    password.expect("SSH requires password or private_key"),
+                    host_key_fingerprints,
+                )),
+            }
+        }
+        ClientProxyConfig::Wireguard { .. } => {
+            panic!("WireGuard should be handled by the socket connector, not as a TCP client handler. WireGuard is only valid at hop 0 (checked during config validation).")
+        }
+        ClientProxyConfig::Portal { .. } => {
+            panic!("Portal should be handled by the socket connector, not as a TCP client handler. Portal is only valid at hop 0 (checked during config validation).")
+        }
         ClientProxyConfig::Anytls {
             password,
             udp_enabled,
             padding_scheme,
+            traffic_padding,
+            ..
         } => {
-            let padding = match padding_scheme {
-                Some(lines) => {
-                    let scheme = lines.join("\n");
-                    Arc::new(
-                        PaddingFactory::new(scheme.as_bytes())
-                            .expect("Invalid padding scheme in AnyTLS config"),
-                    )
-                }
-                None => PaddingFactory::default_factory(),
-            };
-            Box::new(AnyTlsClientHandler::new(password, padding, udp_enabled))
+            let padding = create_anytls_padding(padding_scheme.as_deref());
+            Box::new(AnyTlsClientHandler::new(
+                password,
+                padding,
+                traffic_padding,
+                udp_enabled,
+            ))
         }
         ClientProxyConfig::Naiveproxy {
             username,
@@ -325,26 +534,104 @@ pub fn create_tcp_client_handler(
     }
 }
 
+/// Creates the session pool of an AnyTLS client, if the innermost protocol is AnyTLS.
+pub fn create_anytls_client_pool(protocol: &ClientProxyConfig) -> Option<AnyTlsClientPool> {
+    match protocol.anytls_config()? {
+        ClientProxyConfig::Anytls {
+            password,
+            padding_scheme,
+            idle_session_timeout_secs,
+            traffic_padding,
+            ..
+        } => Some(AnyTlsClientPool::new(
+            password.clone(),
+            create_anytls_padding(padding_scheme.as_deref()),
+            *traffic_padding,
+            Duration::from_secs(*idle_session_timeout_secs),
+        )),
+        _ => None,
+    }
+}
+
+fn create_anytls_padding(padding_scheme: Option<&[String]>) -> Arc<PaddingFactory> {
+    match padding_scheme {
+        Some(lines) => {
+            let scheme = lines.join("\n");
+            Arc::new(
+                PaddingFactory::new(scheme.as_bytes())
+                    .expect("Invalid padding scheme in AnyTLS config"),
+            )
+        }
+        None => PaddingFactory::default_factory(),
+    }
+}
+
+/// Creates a selector for the rules, registering it so that the rules can be listed
+/// through the Clash API.
 pub fn create_tcp_client_proxy_selector(
     rules: Vec<RuleConfig>,
     resolver: Arc<dyn Resolver>,
-) -> ClientProxySelector {
+) -> Arc<ClientProxySelector> {
     let rules = rules
         .into_iter()
         .map(|rule_config| {
-            let RuleConfig { masks, action } = rule_config;
+            let RuleConfig {
+                masks,
+                domain_keywords,
+                ports,
+                source_ips,
+                geoip,
+                geosite,
+                block_quic,
+                action,
+            } = rule_config;
             let connect_action = match action {
                 RuleActionConfig::Allow {
                     override_address,
+                    override_port,
                     client_chains,
+                    balancer,
+                    health_check,
+                    dial,
+                    domain_strategy,
+                    resolve_locally,
                 } => {
-                    let chain_group = build_client_chain_group(client_chains, resolver.clone());
+                    let client_chains = match domain_strategy {
+                        Some(domain_strategy) => {
+                            override_domain_strategy(client_chains, domain_strategy)
+                        }
+                        None => client_chains,
+                    };
+                    let client_chains = match resolve_locally {
+                        Some(resolve_locally) => {
+                            override_resolve_locally(client_chains, resolve_locally)
+                        }
+                        None => client_chains,
+                    };
+                    let mut chain_group = build_client_chain_group(client_chains, resolver.clone())
+                        .with_balancer(balancer);
+                    if let Some(health_check) = health_check {
+                        chain_group =
+                            chain_group.with_health_check(&health_check, resolver.clone());
+                    }
+                    if let Some(dial) = dial {
+                        chain_group = chain_group.with_dial_policy(DialPolicy::from(&dial));
+                    }
                     ConnectAction::new_allow(override_address, chain_group)
+                        .with_override_port(override_port)
                 }
                 RuleActionConfig::Block => ConnectAction::new_block(),
             };
             ConnectRule::new(masks.into_vec(), connect_action)
+                .with_domain_keywords(domain_keywords)
+                .with_ports(ports)
+                .with_source_ips(source_ips)
+                .with_geoip(geoip)
+                .with_geosite(geosite)
+                .with_block_quic(block_quic)
         })
         .collect::<Vec<_>>();
-    ClientProxySelector::new(rules)
+    let selector = Arc::new(ClientProxySelector::new(rules));
+    register_proxy_selector(&selector);
+    selector
 }
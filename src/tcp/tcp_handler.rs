@@ -67,6 +67,20 @@ impl TcpServerSetupResult {
             TcpServerSetupResult::AlreadyHandled => {}
         }
     }
+
+    /// Returns the destination that the client requested, for results that forward to
+    /// a single destination.
+    pub fn remote_location(&self) -> Option<&NetLocation> {
+        match self {
+            TcpServerSetupResult::TcpForward {
+                remote_location, ..
+            }
+            | TcpServerSetupResult::BidirectionalUdp {
+                remote_location, ..
+            } => Some(remote_location),
+            _ => None,
+        }
+    }
 }
 
 #[async_trait]
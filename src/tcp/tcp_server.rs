@@ -1,66 +1,249 @@
-use std::net::SocketAddr;
+use std::future::Future;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use log::{debug, error};
-use tokio::io::AsyncWriteExt;
-use tokio::task::JoinHandle;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::watch;
+use tokio::task::{JoinHandle, JoinSet};
 use tokio::time::timeout;
 
 use super::tcp_client_handler_factory::create_tcp_client_proxy_selector;
 use super::tcp_server_handler_factory::create_tcp_server_handler;
 
-use crate::address::NetLocation;
+use crate::access_control::AccessControl;
+use crate::access_log::{self, SessionLog};
+use crate::address::{NetLocation, ResolvedLocation};
 use crate::async_stream::AsyncMessageStream;
 use crate::async_stream::{AsyncShutdownMessageExt, AsyncStream};
-use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::auto_ban;
+use crate::clash_api::{self, ConnectionGuard, start_clash_api_server};
+use crate::client_proxy_chain::ClientChainGroup;
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision, OutboundSelector};
+#[cfg(target_os = "linux")]
+use crate::config::ServerProxyConfig;
 use crate::config::{BindLocation, Config, ConfigSelection, ServerConfig, TcpConfig, Transport};
-use crate::copy_bidirectional::copy_bidirectional;
+use crate::connection_limit::ConnectionLimiter;
+use crate::copy_bidirectional::{CopyTimeouts, copy_bidirectional_with_counters};
 use crate::copy_bidirectional_message::copy_bidirectional_message;
+use crate::dns_server::start_dns_udp_servers;
+use crate::hooks::HookedConnection;
+use crate::kcp::start_kcp_servers;
+use crate::metrics::{self, ByteCounter, ListenerMetrics, OutboundMetrics, start_metrics_server};
+use crate::port_forward_handler::start_port_forward_udp_servers;
+use crate::proxy_protocol::{self, ConnectionAddresses, with_client_addresses};
 use crate::quic_server::start_quic_servers;
+use crate::rate_limit::{BandwidthLimit, RateLimiter, user_limit};
 use crate::resolver::Resolver;
+use crate::reverse::{find_portal, start_reverse_bridge};
 use crate::routing::{ServerStream, run_udp_routing};
-use crate::socket_util::{new_tcp_listener, set_tcp_keepalive};
+use crate::shadowsocks::{allocate_local_address, start_server_plugin};
+use crate::socket_util::{
+    configure_tcp_stream, new_tcp_listener, set_tcp_fast_open_listen, to_canonical_socket_addr,
+};
 use crate::tcp::tcp_handler::{TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult};
+use crate::thread_util::get_num_threads;
+#[cfg(target_os = "linux")]
+use crate::transparent::{start_tproxy_udp_servers, start_transparent_tcp_servers};
 use crate::tun::start_tun_server;
+use crate::user_store::with_authenticated_user;
 use crate::util::write_all;
 
+/// State used to set up newly accepted connections.
+///
+/// Listeners read the latest state on every accept, so a config reload can replace it
+/// without closing the listening socket. Connections that were already accepted keep
+/// the handler they started with.
+#[derive(Clone)]
+pub struct TcpServerState {
+    /// Name of the server protocol, e.g. `Vless`.
+    pub protocol: Arc<str>,
+    pub tcp_config: TcpConfig,
+    pub resolver: Arc<dyn Resolver>,
+    pub server_handler: Arc<dyn TcpServerHandler>,
+    /// Bandwidth limit shared by the connections of the server.
+    pub rate_limit: Option<Arc<BandwidthLimit>>,
+    pub connection_limiter: Arc<ConnectionLimiter>,
+    /// Idle and half-close timeouts of the forwarded sessions.
+    pub session_timeouts: CopyTimeouts,
+    /// Client IPs that may connect.
+    pub access_control: Option<Arc<AccessControl>>,
+}
+
 async fn run_tcp_server(
     bind_address: SocketAddr,
-    tcp_config: TcpConfig,
-    resolver: Arc<dyn Resolver>,
-    server_handler: Arc<dyn TcpServerHandler>,
+    server_state: watch::Receiver<TcpServerState>,
 ) -> std::io::Result<()> {
-    let TcpConfig { no_delay } = tcp_config;
+    let tcp_config = server_state.borrow().tcp_config.clone();
+    let listener_label: Arc<str> = Arc::from(format!("tcp://{bind_address}"));
+    let new_listener = |bind_address| {
+        let listener = new_tcp_listener(
+            bind_address,
+            4096,
+            None,
+            tcp_config.mptcp,
+            tcp_config.per_core_listeners,
+        )?;
+        if tcp_config.fast_open {
+            set_tcp_fast_open_listen(&listener)?;
+        }
+        Ok::<_, std::io::Error>(listener)
+    };
+
+    let listener = new_listener(bind_address)?;
+    if !tcp_config.per_core_listeners {
+        return accept_tcp_connections(listener, listener_label, server_state).await;
+    }
 
-    let listener = new_tcp_listener(bind_address, 4096, None)?;
+    // Bind the other listeners to the same port, in case the first one was bound to port 0.
+    let bind_address = listener.local_addr()?;
+    let mut acceptors = JoinSet::new();
+    acceptors.spawn(accept_tcp_connections(
+        listener,
+        listener_label.clone(),
+        server_state.clone(),
+    ));
+    for _ in 1..get_num_threads() {
+        acceptors.spawn(accept_tcp_connections(
+            new_listener(bind_address)?,
+            listener_label.clone(),
+            server_state.clone(),
+        ));
+    }
+    debug!(
+        "Accepting connections on {bind_address} with {} listeners",
+        acceptors.len()
+    );
+
+    // The accept loops only end with an error. When this task is aborted, dropping the set
+    // aborts them too.
+    match acceptors.join_next().await {
+        Some(Ok(result)) => result,
+        Some(Err(e)) => Err(std::io::Error::other(e)),
+        None => Ok(()),
+    }
+}
+
+async fn accept_tcp_connections(
+    listener: TcpListener,
+    listener_label: Arc<str>,
+    server_state: watch::Receiver<TcpServerState>,
+) -> std::io::Result<()> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    let mut uring_acceptor = crate::uring::UringAcceptor::new(&listener);
 
     loop {
-        let (stream, addr) = match listener.accept().await {
+        let connection_limiter = server_state.borrow().connection_limiter.clone();
+        let reserved = connection_limiter.ready().await;
+
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        let accepted = match uring_acceptor.as_mut() {
+            Some(acceptor) => match acceptor.accept().await {
+                Some(accepted) => accepted,
+                None => {
+                    debug!("io_uring accept stopped, falling back to epoll");
+                    uring_acceptor = None;
+                    continue;
+                }
+            },
+            None => listener.accept().await,
+        };
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        let accepted = listener.accept().await;
+
+        let (stream, addr) = match accepted {
             Ok(v) => v,
             Err(e) => {
                 error!("Accept failed: {e}");
                 continue;
             }
         };
+        // IPv4 clients of listeners on `[::]` have IPv4-mapped addresses.
+        let addr = to_canonical_socket_addr(addr);
+
+        let TcpServerState {
+            protocol,
+            tcp_config,
+            resolver,
+            server_handler,
+            rate_limit,
+            session_timeouts,
+            access_control,
+            ..
+        } = server_state.borrow().clone();
+
+        // Behind a load balancer, the client address is only known once the PROXY protocol
+        // header is read.
+        if !tcp_config.accept_proxy_protocol
+            && let Some(access_control) = &access_control
+            && !access_control.permits(addr.ip())
+        {
+            debug!("Rejected {addr}, the address is not allowed to connect");
+            continue;
+        }
+        if !tcp_config.accept_proxy_protocol && auto_ban::is_banned(addr.ip()) {
+            debug!("Rejected {addr}, the address is banned");
+            continue;
+        }
 
-        if let Err(e) = set_tcp_keepalive(
+        if let Err(e) = configure_tcp_stream(
             &stream,
+            &tcp_config,
             std::time::Duration::from_secs(300),
             std::time::Duration::from_secs(60),
         ) {
-            error!("Failed to set TCP keepalive: {e}");
+            error!("Failed to set TCP options: {e}");
         }
 
-        if no_delay && let Err(e) = stream.set_nodelay(true) {
-            error!("Failed to set TCP nodelay: {e}");
-        }
+        let inbound = InboundContext::new(listener_label.clone(), protocol, Some(addr), rate_limit)
+            .with_session_timeouts(session_timeouts);
+        let Some(permit) = connection_limiter.admit(reserved, inbound.metrics.clone()) else {
+            debug!("Rejected {addr}, the listener is at its connection limit");
+            continue;
+        };
 
-        let cloned_resolver = resolver.clone();
-        let cloned_handler = server_handler.clone();
+        let accept_proxy_protocol = tcp_config.accept_proxy_protocol;
         tokio::spawn(async move {
-            if let Err(e) = process_stream(stream, cloned_handler, cloned_resolver).await {
+            let _permit = permit;
+            let (mut stream, mut inbound) = (stream, inbound);
+            let mut connection = stream
+                .local_addr()
+                .ok()
+                .map(|destination| ConnectionAddresses {
+                    source: addr,
+                    destination: to_canonical_socket_addr(destination),
+                });
+            if accept_proxy_protocol {
+                connection =
+                    match read_proxy_protocol_header(&mut stream, &mut inbound, connection).await {
+                        Ok(connection) => connection,
+                        Err(e) => {
+                            error!("{}:{} failed to set up: {e}", addr.ip(), addr.port());
+                            return;
+                        }
+                    };
+            }
+            let addr = inbound.source.unwrap_or(addr);
+            if accept_proxy_protocol
+                && let Some(access_control) = &access_control
+                && !access_control.permits(addr.ip())
+            {
+                debug!("Rejected {addr}, the address is not allowed to connect");
+                return;
+            }
+            if accept_proxy_protocol && auto_ban::is_banned(addr.ip()) {
+                debug!("Rejected {addr}, the address is banned");
+                return;
+            }
+            let result = with_client_addresses(
+                connection,
+                process_stream(stream, server_handler, resolver, inbound),
+            )
+            .await;
+            if let Err(e) = result {
                 error!("{}:{} finished with error: {:?}", addr.ip(), addr.port(), e);
             } else {
                 debug!("{}:{} finished successfully", addr.ip(), addr.port());
@@ -72,8 +255,7 @@ async fn run_tcp_server(
 #[cfg(target_family = "unix")]
 async fn run_unix_server(
     path_buf: PathBuf,
-    resolver: Arc<dyn Resolver>,
-    server_handler: Arc<dyn TcpServerHandler>,
+    server_state: watch::Receiver<TcpServerState>,
 ) -> std::io::Result<()> {
     if tokio::fs::symlink_metadata(&path_buf).await.is_ok() {
         println!(
@@ -83,9 +265,13 @@ async fn run_unix_server(
         let _ = tokio::fs::remove_file(&path_buf).await;
     }
 
+    let listener_label: Arc<str> = Arc::from(format!("unix://{}", path_buf.display()));
     let listener = crate::socket_util::new_unix_listener(path_buf, 4096)?;
 
     loop {
+        let connection_limiter = server_state.borrow().connection_limiter.clone();
+        let reserved = connection_limiter.ready().await;
+
         let (stream, addr) = match listener.accept().await {
             Ok(v) => v,
             Err(e) => {
@@ -94,10 +280,49 @@ async fn run_unix_server(
             }
         };
 
-        let cloned_resolver = resolver.clone();
-        let cloned_handler = server_handler.clone();
+        let TcpServerState {
+            protocol,
+            tcp_config,
+            resolver,
+            server_handler,
+            rate_limit,
+            session_timeouts,
+            ..
+        } = server_state.borrow().clone();
+
+        let inbound = InboundContext::new(listener_label.clone(), protocol, None, rate_limit)
+            .with_session_timeouts(session_timeouts);
+        let Some(permit) = connection_limiter.admit(reserved, inbound.metrics.clone()) else {
+            debug!("Rejected {addr:?}, the listener is at its connection limit");
+            continue;
+        };
+
         tokio::spawn(async move {
-            if let Err(e) = process_stream(stream, cloned_handler, cloned_resolver).await {
+            let _permit = permit;
+            let (mut stream, mut inbound) = (stream, inbound);
+            let mut connection = None;
+            if tcp_config.accept_proxy_protocol {
+                connection = match read_proxy_protocol_header(&mut stream, &mut inbound, None).await
+                {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("{addr:?} failed to set up: {e}");
+                        return;
+                    }
+                };
+            }
+            if let Some(source) = inbound.source
+                && auto_ban::is_banned(source.ip())
+            {
+                debug!("Rejected {source}, the address is banned");
+                return;
+            }
+            let result = with_client_addresses(
+                connection,
+                process_stream(stream, server_handler, resolver, inbound),
+            )
+            .await;
+            if let Err(e) = result {
                 error!("{addr:?} finished with error: {e:?}");
             } else {
                 debug!("{addr:?} finished successfully");
@@ -106,6 +331,41 @@ async fn run_unix_server(
     }
 }
 
+/// Reads the PROXY protocol header that starts connections to listeners with
+/// `accept_proxy_protocol`, and makes the client address in it the source of the
+/// connection.
+///
+/// Returns the addresses of the client connection, which are the ones of the accepted
+/// connection when the header doesn't carry any.
+async fn read_proxy_protocol_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    inbound: &mut InboundContext,
+    connection: Option<ConnectionAddresses>,
+) -> std::io::Result<Option<ConnectionAddresses>> {
+    let result = timeout(
+        proxy_protocol::HEADER_TIMEOUT,
+        proxy_protocol::read_header(stream),
+    )
+    .await
+    .unwrap_or_else(|_| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "timed out waiting for the PROXY protocol header",
+        ))
+    });
+    match result {
+        Ok(Some(addresses)) => {
+            inbound.source = Some(addresses.source);
+            Ok(Some(addresses))
+        }
+        Ok(None) => Ok(connection),
+        Err(e) => {
+            inbound.record_handshake_failure(&e);
+            Err(e)
+        }
+    }
+}
+
 async fn setup_server_stream<AS>(
     stream: AS,
     server_handler: Arc<dyn TcpServerHandler>,
@@ -117,35 +377,250 @@ where
     server_handler.setup_server_stream(server_stream).await
 }
 
+/// Describes the listener and client that an accepted stream came from.
+#[derive(Debug, Clone, Default)]
+pub struct InboundContext {
+    /// Identifies the listener, e.g. `tcp://0.0.0.0:443`.
+    pub listener: Arc<str>,
+    /// Name of the server protocol, e.g. `Vless`.
+    pub protocol: Arc<str>,
+    pub source: Option<SocketAddr>,
+    pub metrics: Option<Arc<ListenerMetrics>>,
+    /// Bandwidth limit of the listener.
+    pub rate_limit: Option<Arc<BandwidthLimit>>,
+    /// Idle and half-close timeouts of the listener.
+    pub session_timeouts: CopyTimeouts,
+    /// Name of the user that the client authenticated as, once the server stream is set
+    /// up.
+    pub user: Option<String>,
+    /// Hooks of library embedders, set per stream.
+    pub hooks: Option<Arc<HookedConnection>>,
+    /// Location that forwarded connections are routed by instead of the requested one,
+    /// e.g. the domain found by sniffing.
+    pub routing_location: Option<ResolvedLocation>,
+}
+
+impl InboundContext {
+    /// Creates the context for a newly accepted connection, and counts it in the
+    /// listener's metrics.
+    pub fn new(
+        listener: Arc<str>,
+        protocol: Arc<str>,
+        source: Option<SocketAddr>,
+        rate_limit: Option<Arc<BandwidthLimit>>,
+    ) -> Self {
+        let metrics = metrics::listener(&listener);
+        if let Some(m) = &metrics {
+            m.connections_accepted.inc();
+        }
+        Self {
+            listener,
+            protocol,
+            source,
+            metrics,
+            rate_limit,
+            session_timeouts: CopyTimeouts::default(),
+            user: None,
+            hooks: None,
+            routing_location: None,
+        }
+    }
+
+    /// Sets the timeouts of the sessions forwarded from the connection.
+    pub fn with_session_timeouts(mut self, session_timeouts: CopyTimeouts) -> Self {
+        self.session_timeouts = session_timeouts;
+        self
+    }
+
+    /// Returns the (up, down) rate limiters of the listener and the user.
+    pub fn rate_limiters(&self) -> (RateLimiter, RateLimiter) {
+        let mut up = RateLimiter::default();
+        let mut down = RateLimiter::default();
+        if let Some(limit) = &self.rate_limit {
+            limit.add_to(&mut up, &mut down);
+        }
+        if let Some(limit) = self.user.as_deref().and_then(user_limit) {
+            limit.add_to(&mut up, &mut down);
+        }
+        (up, down)
+    }
+
+    /// Counts a failed handshake in the listener's metrics, and towards the automatic ban
+    /// of the client address when the client was rejected, see [`auto_ban::rejected`].
+    pub fn record_handshake_failure(&self, error: &std::io::Error) {
+        if let Some(m) = &self.metrics {
+            m.handshake_failures.inc();
+        }
+        if let Some(source) = self.source
+            && auto_ban::is_rejected(error)
+        {
+            auto_ban::record_failure(source.ip());
+        }
+    }
+}
+
+/// The chain group that a client stream was connected through.
+pub struct Outbound {
+    pub selector: Arc<OutboundSelector>,
+    pub metrics: Option<Arc<OutboundMetrics>>,
+}
+
+impl Outbound {
+    pub fn new(chain_group: &ClientChainGroup) -> Self {
+        Self {
+            selector: chain_group.selector().clone(),
+            metrics: chain_group.outbound_metrics(),
+        }
+    }
+}
+
+/// Byte counters, rate limiters, Clash API tracking and the access log entry of a
+/// forwarded session.
+pub struct ForwardSession {
+    /// Counts bytes sent from the client towards the destination.
+    pub up_counter: ByteCounter,
+    /// Counts bytes sent from the destination back to the client.
+    pub down_counter: ByteCounter,
+    /// Limits bytes sent from the client towards the destination.
+    pub up_limiter: RateLimiter,
+    /// Limits bytes sent from the destination back to the client.
+    pub down_limiter: RateLimiter,
+    connection_guard: Option<ConnectionGuard>,
+    _access_log: Option<SessionLog>,
+}
+
+impl ForwardSession {
+    pub fn start(
+        network: &'static str,
+        inbound: &InboundContext,
+        destination: &NetLocation,
+        outbound: &Outbound,
+    ) -> Self {
+        let (mut up_counter, mut down_counter) =
+            metrics::byte_counters(inbound.metrics.as_deref(), outbound.metrics.as_deref());
+        let connection_guard = clash_api::track_connection(
+            network,
+            &inbound.listener,
+            inbound.source,
+            destination,
+            outbound.selector.name(),
+        );
+        if let Some(guard) = &connection_guard {
+            guard.add_byte_counters(&mut up_counter, &mut down_counter);
+        }
+        let access_log = access_log::start_session(
+            network,
+            &inbound.listener,
+            &inbound.protocol,
+            inbound.user.as_deref(),
+            inbound.source,
+            destination,
+            outbound.selector.name(),
+        );
+        if let Some(log) = &access_log {
+            log.add_byte_counters(&mut up_counter, &mut down_counter);
+        }
+        if let Some(hooks) = &inbound.hooks {
+            hooks.start_session(
+                network,
+                destination,
+                outbound.selector.name(),
+                &mut up_counter,
+                &mut down_counter,
+            );
+        }
+        let (up_limiter, down_limiter) = inbound.rate_limiters();
+        Self {
+            up_counter,
+            down_counter,
+            up_limiter,
+            down_limiter,
+            connection_guard,
+            _access_log: access_log,
+        }
+    }
+
+    /// Runs `copy` until it completes, or until the session is closed through the
+    /// Clash API.
+    pub async fn run<F>(&self, copy: F) -> std::io::Result<()>
+    where
+        F: Future<Output = std::io::Result<()>>,
+    {
+        match &self.connection_guard {
+            Some(guard) => guard.run(copy).await,
+            None => copy.await,
+        }
+    }
+}
+
+/// Sets up and forwards an accepted stream.
+///
+/// The session is counted in the listener's metrics and tracked by the Clash API when
+/// they are enabled, and the connection hooks are called when they are registered.
 pub async fn process_stream<AS>(
     stream: AS,
     server_handler: Arc<dyn TcpServerHandler>,
     resolver: Arc<dyn Resolver>,
+    mut inbound: InboundContext,
 ) -> std::io::Result<()>
 where
     AS: AsyncStream + 'static,
 {
+    inbound.hooks = HookedConnection::new(&inbound.listener, &inbound.protocol, inbound.source);
+    match inbound.hooks.clone() {
+        Some(hooks) => {
+            hooks
+                .run(forward_stream(stream, server_handler, resolver, inbound))
+                .await
+        }
+        None => forward_stream(stream, server_handler, resolver, inbound).await,
+    }
+}
+
+async fn forward_stream<AS>(
+    stream: AS,
+    server_handler: Arc<dyn TcpServerHandler>,
+    resolver: Arc<dyn Resolver>,
+    mut inbound: InboundContext,
+) -> std::io::Result<()>
+where
+    AS: AsyncStream + 'static,
+{
+    let _session_guard = inbound.metrics.as_ref().map(|m| m.start_session());
+
     let setup_server_stream_future = timeout(
         Duration::from_secs(60),
         setup_server_stream(stream, server_handler),
     );
 
-    let setup_result = match setup_server_stream_future.await {
+    let (setup_result, user) = with_authenticated_user(setup_server_stream_future).await;
+    inbound.user = user;
+
+    let setup_result = match setup_result {
         Ok(Ok(r)) => r,
         Ok(Err(e)) => {
+            inbound.record_handshake_failure(&e);
             return Err(std::io::Error::new(
                 e.kind(),
                 format!("failed to setup server stream: {e}"),
             ));
         }
         Err(elapsed) => {
-            return Err(std::io::Error::new(
+            let e = std::io::Error::new(
                 std::io::ErrorKind::TimedOut,
                 format!("server setup timed out: {elapsed}"),
-            ));
+            );
+            inbound.record_handshake_failure(&e);
+            return Err(e);
         }
     };
 
+    if let Some(hooks) = &inbound.hooks {
+        hooks
+            .authenticate(inbound.user.as_deref(), setup_result.remote_location())
+            .await?;
+    }
+
     match setup_result {
         TcpServerSetupResult::TcpForward {
             remote_location,
@@ -155,17 +630,33 @@ where
             connection_success_response,
             initial_remote_data,
         } => {
+            // Connections of reverse proxy bridges become tunnels of their portal.
+            if let Some(portal) = find_portal(&remote_location) {
+                return portal
+                    .accept_tunnel(
+                        server_stream,
+                        connection_success_response,
+                        initial_remote_data,
+                    )
+                    .await;
+            }
+
+            let routing_location = inbound
+                .routing_location
+                .take()
+                .unwrap_or_else(|| remote_location.clone().into());
             let setup_client_stream_future = timeout(
                 Duration::from_secs(60),
                 setup_client_tcp_stream(
                     &mut server_stream,
                     proxy_selector,
                     resolver,
-                    remote_location.clone(),
+                    routing_location,
+                    inbound.source.map(|addr| addr.ip()),
                 ),
             );
 
-            let mut client_stream = match setup_client_stream_future.await {
+            let (mut client_stream, outbound) = match setup_client_stream_future.await {
                 Ok(Ok(Some(s))) => s,
                 Ok(Ok(None)) => {
                     // Must have been blocked.
@@ -188,6 +679,8 @@ where
                 }
             };
 
+            let session = ForwardSession::start("tcp", &inbound, &remote_location, &outbound);
+
             if let Some(data) = connection_success_response {
                 write_all(&mut server_stream, &data).await?;
                 // server_need_initial_flush should be set to true by the handler if
@@ -197,18 +690,25 @@ where
             let client_need_initial_flush = match initial_remote_data {
                 Some(data) => {
                     write_all(&mut client_stream, &data).await?;
+                    session.up_counter.add(data.len());
                     true
                 }
                 None => false,
             };
 
-            let copy_result = copy_bidirectional(
-                &mut server_stream,
-                &mut client_stream,
-                server_need_initial_flush,
-                client_need_initial_flush,
-            )
-            .await;
+            let copy_result = session
+                .run(copy_bidirectional_with_counters(
+                    &mut server_stream,
+                    &mut client_stream,
+                    server_need_initial_flush,
+                    client_need_initial_flush,
+                    session.up_counter.clone(),
+                    session.down_counter.clone(),
+                    session.up_limiter.clone(),
+                    session.down_limiter.clone(),
+                    inbound.session_timeouts,
+                ))
+                .await;
 
             let (_, _) = futures::join!(server_stream.shutdown(), client_stream.shutdown());
 
@@ -221,23 +721,41 @@ where
             need_initial_flush: server_need_initial_flush,
             proxy_selector,
         } => {
-            let action = proxy_selector.judge(remote_location.into(), &resolver).await?;
+            let source = inbound.source.map(|addr| addr.ip());
+            let action = proxy_selector
+                .judge_with_source(remote_location.into(), source, &resolver)
+                .await?;
             match action {
                 ConnectDecision::Allow {
                     chain_group,
                     remote_location,
                 } => {
+                    let destination = remote_location.location().clone();
                     let client_stream = chain_group
-                        .connect_udp_bidirectional(&resolver, remote_location)
+                        .connect_udp_bidirectional_with_source(&resolver, remote_location, source)
                         .await?;
 
-                    run_udp_copy(
-                        server_stream,
-                        client_stream,
-                        server_need_initial_flush,
-                        false,
-                    )
-                    .await
+                    let outbound = Outbound::new(chain_group);
+                    if let Some(m) = &inbound.metrics {
+                        m.udp_sessions.inc();
+                    }
+                    if let Some(m) = &outbound.metrics {
+                        m.udp_sessions.inc();
+                    }
+                    let session = ForwardSession::start("udp", &inbound, &destination, &outbound);
+
+                    session
+                        .run(run_udp_copy(
+                            server_stream,
+                            client_stream,
+                            server_need_initial_flush,
+                            false,
+                            session.up_counter.clone(),
+                            session.down_counter.clone(),
+                            session.up_limiter.clone(),
+                            session.down_limiter.clone(),
+                        ))
+                        .await
                 }
                 ConnectDecision::Block => Err(std::io::Error::new(
                     std::io::ErrorKind::ConnectionRefused,
@@ -250,12 +768,19 @@ where
             need_initial_flush,
             proxy_selector,
         } => {
+            if let Some(m) = &inbound.metrics {
+                m.udp_sessions.inc();
+            }
             // Per-destination routing: each packet is routed based on its destination
+            let (up_limiter, down_limiter) = inbound.rate_limiters();
             run_udp_routing(
                 ServerStream::Targeted(server_stream),
                 proxy_selector,
                 resolver,
+                inbound.source.map(|addr| addr.ip()),
                 need_initial_flush,
+                up_limiter,
+                down_limiter,
             )
             .await
         }
@@ -264,12 +789,19 @@ where
             need_initial_flush,
             proxy_selector,
         } => {
+            if let Some(m) = &inbound.metrics {
+                m.udp_sessions.inc();
+            }
             // Per-destination routing: each session is routed based on its destination
+            let (up_limiter, down_limiter) = inbound.rate_limiters();
             run_udp_routing(
                 ServerStream::Session(server_stream),
                 proxy_selector,
                 resolver,
+                inbound.source.map(|addr| addr.ip()),
                 need_initial_flush,
+                up_limiter,
+                down_limiter,
             )
             .await
         }
@@ -281,14 +813,20 @@ where
     }
 }
 
+/// Connects to `remote_location` through the client chain group selected for a connection
+/// from `source`.
+///
+/// Returns None if the connection was blocked, otherwise the client stream along with
+/// the chain group it was made through.
 pub async fn setup_client_tcp_stream(
     server_stream: &mut Box<dyn AsyncStream>,
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
-    remote_location: NetLocation,
-) -> std::io::Result<Option<Box<dyn AsyncStream>>> {
+    remote_location: impl Into<ResolvedLocation>,
+    source: Option<IpAddr>,
+) -> std::io::Result<Option<(Box<dyn AsyncStream>, Outbound)>> {
     let action = client_proxy_selector
-        .judge(remote_location.into(), &resolver)
+        .judge_with_source(remote_location.into(), source, &resolver)
         .await?;
 
     match action {
@@ -299,14 +837,21 @@ pub async fn setup_client_tcp_stream(
             let TcpClientSetupResult {
                 client_stream,
                 early_data,
-            } = chain_group.connect_tcp(remote_location, &resolver).await?;
+            } = chain_group
+                .connect_tcp_with_source(remote_location, source, &resolver)
+                .await?;
+
+            let outbound = Outbound::new(chain_group);
+            if let Some(m) = &outbound.metrics {
+                m.connections.inc();
+            }
 
             if let Some(data) = early_data {
                 server_stream.write_all(&data).await?;
                 server_stream.flush().await?;
             }
 
-            Ok(Some(client_stream))
+            Ok(Some((client_stream, outbound)))
         }
         ConnectDecision::Block => Ok(None),
     }
@@ -317,18 +862,31 @@ pub async fn setup_client_tcp_stream(
 ///
 /// After the copy completes (whether successfully or with an error), both streams
 /// are shut down to ensure proper cleanup and FIN frames are sent.
+///
+/// `up_counter` counts bytes sent to the client stream, and `down_counter` counts
+/// bytes sent back to the server stream. They are limited by `up_limiter` and
+/// `down_limiter`.
 #[inline]
+#[allow(clippy::too_many_arguments)]
 pub async fn run_udp_copy(
     mut server_stream: Box<dyn AsyncMessageStream>,
     mut client_stream: Box<dyn AsyncMessageStream>,
     server_need_initial_flush: bool,
     client_need_initial_flush: bool,
+    up_counter: ByteCounter,
+    down_counter: ByteCounter,
+    up_limiter: RateLimiter,
+    down_limiter: RateLimiter,
 ) -> std::io::Result<()> {
     let copy_result = copy_bidirectional_message(
         &mut server_stream,
         &mut client_stream,
         server_need_initial_flush,
         client_need_initial_flush,
+        up_counter,
+        down_counter,
+        up_limiter,
+        down_limiter,
     )
     .await;
 
@@ -351,7 +909,19 @@ pub async fn start_servers(
         Config::Server(server_config) => {
             start_tcp_or_quic_servers(server_config, resolver).await
         }
-        _ => unreachable!("create_server_configs only returns Server and TunServer"),
+        Config::Metrics(metrics_config) => {
+            start_metrics_server(metrics_config).await.map(|t| vec![t])
+        }
+        Config::ClashApi(clash_api_config) => {
+            start_clash_api_server(clash_api_config).await.map(|t| vec![t])
+        }
+        Config::ReverseBridge(bridge_config) => {
+            Ok(vec![start_reverse_bridge(bridge_config, resolver)])
+        }
+        _ => unreachable!(
+            "create_server_configs only returns Server, TunServer, ReverseBridge, Metrics and \
+             ClashApi"
+        ),
     }
 }
 
@@ -362,6 +932,34 @@ async fn start_tcp_or_quic_servers(
     let mut join_handles = Vec::with_capacity(3);
 
     match config.transport {
+        #[cfg(target_os = "linux")]
+        Transport::Tcp if config.protocol.is_transparent() => {
+            match start_transparent_tcp_servers(config.clone(), resolver).await {
+                Ok(handles) => {
+                    join_handles.extend(handles);
+                }
+                Err(e) => {
+                    for join_handle in join_handles {
+                        join_handle.abort();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Transport::Tcp if config.protocol.forwards_udp() => {
+            join_handles.extend(start_tcp_servers(config.clone(), resolver.clone()).await?);
+            match start_port_forward_udp_servers(config.clone(), resolver).await {
+                Ok(handles) => {
+                    join_handles.extend(handles);
+                }
+                Err(e) => {
+                    for join_handle in join_handles {
+                        join_handle.abort();
+                    }
+                    return Err(e);
+                }
+            }
+        }
         Transport::Tcp => match start_tcp_servers(config.clone(), resolver).await {
             Ok(handles) => {
                 join_handles.extend(handles);
@@ -384,7 +982,42 @@ async fn start_tcp_or_quic_servers(
                 return Err(e);
             }
         },
-        Transport::Udp => todo!(),
+        #[cfg(target_os = "linux")]
+        Transport::Udp if matches!(config.protocol, ServerProxyConfig::Tproxy { .. }) => {
+            match start_tproxy_udp_servers(config.clone(), resolver).await {
+                Ok(handles) => {
+                    join_handles.extend(handles);
+                }
+                Err(e) => {
+                    for join_handle in join_handles {
+                        join_handle.abort();
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Transport::Udp => match start_dns_udp_servers(config.clone(), resolver).await {
+            Ok(handles) => {
+                join_handles.extend(handles);
+            }
+            Err(e) => {
+                for join_handle in join_handles {
+                    join_handle.abort();
+                }
+                return Err(e);
+            }
+        },
+        Transport::Kcp => match start_kcp_servers(config.clone(), resolver).await {
+            Ok(handles) => {
+                join_handles.extend(handles);
+            }
+            Err(e) => {
+                for join_handle in join_handles {
+                    join_handle.abort();
+                }
+                return Err(e);
+            }
+        },
     }
 
     if join_handles.is_empty() {
@@ -401,15 +1034,28 @@ async fn start_tcp_servers(
     config: ServerConfig,
     resolver: Arc<dyn Resolver>,
 ) -> std::io::Result<Vec<JoinHandle<()>>> {
+    let (handles, _state_tx) = start_reloadable_tcp_servers(config, resolver).await?;
+    Ok(handles)
+}
+
+/// Creates the handler state for a TCP server config.
+pub fn create_tcp_server_state(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> TcpServerState {
     let ServerConfig {
         bind_location,
         tcp_settings,
         protocol,
         rules,
+        rate_limit,
+        connection_limit,
+        session_timeouts,
+        access_control,
         ..
     } = config;
 
-    println!("Starting {} TCP server at {}", &protocol, &bind_location);
+    let protocol_name: Arc<str> = Arc::from(protocol.to_string());
 
     let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
     // We should always have a direct entry.
@@ -417,37 +1063,94 @@ async fn start_tcp_servers(
 
     let tcp_config = tcp_settings.unwrap_or_else(TcpConfig::default);
 
-    let client_proxy_selector =
-        Arc::new(create_tcp_client_proxy_selector(rules.clone(), resolver.clone()));
+    let client_proxy_selector = create_tcp_client_proxy_selector(rules.clone(), resolver.clone());
 
     // Extract bind_ip from bind_location for handlers that need it (e.g., SOCKS5 UDP ASSOCIATE)
     let bind_ip = match &bind_location {
-        BindLocation::Address(a) => {
-            // Use to_socket_addrs() and extract IP from first result
-            a.to_socket_addrs()
-                .ok()
-                .and_then(|addrs| addrs.first().map(|addr| addr.ip()))
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            // Use the IP shared by all listeners, otherwise the unspecified address that
+            // covers them.
+            let ips: Vec<IpAddr> = bind_location
+                .to_socket_addrs()
+                .map(|addrs| addrs.iter().map(|addr| addr.ip()).collect())
+                .unwrap_or_default();
+            match ips.first() {
+                Some(ip) if ips.iter().all(|other| other == ip) => Some(*ip),
+                _ if ips.iter().any(IpAddr::is_ipv6) => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+                _ => None,
+            }
         }
         BindLocation::Path(_) => None, // Unix socket, no IP needed
     };
 
-    let tcp_handler: Arc<dyn TcpServerHandler> =
+    let server_handler: Arc<dyn TcpServerHandler> =
         create_tcp_server_handler(protocol, &client_proxy_selector, &resolver, bind_ip).into();
-    debug!("TCP handler: {tcp_handler:?}");
+    debug!("TCP handler: {server_handler:?}");
+
+    // Validated when loading config
+    let rate_limit = rate_limit
+        .map(|config| Arc::new(BandwidthLimit::new(&config).expect("Invalid rate limit")));
+    let access_control = access_control
+        .map(|config| Arc::new(AccessControl::new(&config).expect("Invalid access control")));
+
+    TcpServerState {
+        protocol: protocol_name,
+        tcp_config,
+        resolver,
+        server_handler,
+        rate_limit,
+        connection_limiter: Arc::new(ConnectionLimiter::new(connection_limit)),
+        session_timeouts: session_timeouts
+            .as_ref()
+            .map(CopyTimeouts::from)
+            .unwrap_or_default(),
+        access_control,
+    }
+}
+
+/// Starts TCP servers for the config, returning a sender that replaces the
+/// state used for new connections.
+pub async fn start_reloadable_tcp_servers(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> std::io::Result<(Vec<JoinHandle<()>>, watch::Sender<TcpServerState>)> {
+    println!(
+        "Starting {} TCP server at {}",
+        &config.protocol, &config.bind_location
+    );
+
+    let bind_location = config.bind_location.clone();
+    let sip003_plugin = config.protocol.sip003_plugin().cloned();
+    let (state_tx, state_rx) = watch::channel(create_tcp_server_state(config, resolver));
 
     let mut handles = vec![];
 
     match bind_location {
-        BindLocation::Address(a) => {
-            let socket_addrs = a.to_socket_addrs()?;
+        BindLocation::Address(a) if sip003_plugin.is_some() => {
+            // The plugin listens on the server address, and forwards to the server on a
+            // local port.
+            let bind_address = a.single_location().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "SIP003 plugins require a single port",
+                )
+            })?;
+            let local_address = allocate_local_address()?;
+            handles.push(start_server_plugin(
+                sip003_plugin.unwrap(),
+                bind_address,
+                local_address,
+            )?);
+            handles.push(tokio::spawn(async move {
+                run_tcp_server(local_address, state_rx).await.unwrap();
+            }));
+        }
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            let socket_addrs = bind_location.to_socket_addrs()?;
             for socket_addr in socket_addrs {
-                let tcp_config = tcp_config.clone();
-                let tcp_handler = tcp_handler.clone();
-                let resolver = resolver.clone();
+                let state_rx = state_rx.clone();
                 let handle = tokio::spawn(async move {
-                    run_tcp_server(socket_addr, tcp_config, resolver, tcp_handler)
-                        .await
-                        .unwrap();
+                    run_tcp_server(socket_addr, state_rx).await.unwrap();
                 });
                 handles.push(handle);
             }
@@ -455,11 +1158,8 @@ async fn start_tcp_servers(
         BindLocation::Path(path_buf) => {
             #[cfg(target_family = "unix")]
             {
-                let tcp_handler = tcp_handler.clone();
                 let handle = tokio::spawn(async move {
-                    run_unix_server(path_buf, resolver, tcp_handler)
-                        .await
-                        .unwrap();
+                    run_unix_server(path_buf, state_rx).await.unwrap();
                 });
                 handles.push(handle);
             }
@@ -473,5 +1173,5 @@ async fn start_tcp_servers(
         }
     }
 
-    Ok(handles)
+    Ok((handles, state_tx))
 }
@@ -9,12 +9,18 @@ use crate::anytls::{AnyTlsServerHandler, PaddingFactory};
 use crate::client_proxy_selector::ClientProxySelector;
 use crate::config::{ClientChainHop, ClientConfig};
 use crate::config::{
-    ConfigSelection, RealityServerConfig, ServerProxyConfig, ShadowTlsServerConfig,
-    ShadowTlsServerHandshakeConfig, ShadowsocksConfig, TlsServerConfig, WebsocketServerConfig,
+    ConfigSelection, DispatchTargetConfig, RealityServerConfig, ServerProxyConfig,
+    ShadowTlsServerConfig, ShadowTlsServerHandshakeConfig, ShadowsocksConfig, TlsServerConfig,
+    UserConfig, WebsocketServerConfig,
 };
+use crate::decoy_handler::DecoyHandler;
+use crate::dispatch_handler::{DispatchServerHandler, DispatchServerTarget};
+use crate::dns_server::DnsTcpServerHandler;
+use crate::grpc_stream::GrpcTcpServerHandler;
 use crate::http_handler::HttpTcpServerHandler;
 use crate::mixed_handler::MixedTcpServerHandler;
 use crate::naiveproxy::UserLookup;
+use crate::obfs4::{Obfs4ServerIdentity, Obfs4TcpServerHandler, decode_node_id};
 use crate::option_util::OneOrSome;
 use crate::port_forward_handler::PortForwardServerHandler;
 use crate::reality::RealityServerTarget;
@@ -23,18 +29,22 @@ use crate::rustls_config_util::create_server_config;
 use crate::shadow_tls::{ShadowTlsServerTarget, ShadowTlsServerTargetHandshake};
 use crate::shadowsocks::ShadowsocksTcpHandler;
 use crate::snell::snell_handler::SnellServerHandler;
-use crate::socks_handler::SocksTcpServerHandler;
+use crate::socks_handler::{SocksTcpServerHandler, create_socks_users};
+use crate::ssh_client::parse_ssh_private_key;
+use crate::ssh_server::{SshServerHandler, parse_ssh_public_key};
 use crate::tcp::chain_builder::build_client_proxy_chain;
 use crate::tcp::tcp_handler::TcpServerHandler;
 use crate::tls_server_handler::NaiveConfig;
 use crate::tls_server_handler::{
     InnerProtocol, TlsServerHandler, TlsServerTarget, VisionVlessConfig,
 };
-use crate::trojan_handler::TrojanTcpHandler;
-use crate::uuid_util::parse_uuid;
-use crate::vless::vless_server_handler::VlessTcpServerHandler;
+use crate::trojan_handler::{TrojanTcpHandler, create_trojan_users};
+use crate::vless::vless_server_handler::{VlessTcpServerHandler, create_vless_users};
 use crate::vmess::VmessTcpServerHandler;
-use crate::websocket::{WebsocketServerTarget, WebsocketTcpServerHandler};
+use crate::websocket::{
+    HttpUpgradeTcpServerHandler, WebsocketServerTarget, WebsocketTcpServerHandler,
+};
+use crate::xhttp::XhttpTcpServerHandler;
 
 use super::tcp_client_handler_factory::create_tcp_client_proxy_selector;
 
@@ -66,19 +76,29 @@ pub fn create_tcp_server_handler(
     bind_ip: Option<IpAddr>,
 ) -> Box<dyn TcpServerHandler> {
     match server_proxy_config {
-        ServerProxyConfig::Http { username, password } => Box::new(HttpTcpServerHandler::new(
+        ServerProxyConfig::Http {
+            username,
+            password,
+            decoy,
+        } => Box::new(HttpTcpServerHandler::new(
             create_auth_credentials(username, password),
             client_proxy_selector.clone(),
+            resolver.clone(),
+            decoy.map(|config| DecoyHandler::new(config, resolver.clone())),
         )),
         ServerProxyConfig::Socks {
             username,
             password,
             udp_enabled,
+            user_group,
         } => {
             // Use 0.0.0.0 as default if bind_ip not provided
             let ip = bind_ip.unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
             Box::new(SocksTcpServerHandler::new(
-                create_auth_credentials(username, password),
+                create_socks_users(
+                    create_auth_credentials(username, password),
+                    user_group.as_deref(),
+                ),
                 udp_enabled,
                 ip,
                 client_proxy_selector.clone(),
@@ -89,6 +109,7 @@ pub fn create_tcp_server_handler(
             username,
             password,
             udp_enabled,
+            decoy,
         } => {
             // Use 0.0.0.0 as default if bind_ip not provided
             let ip = bind_ip.unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED));
@@ -98,11 +119,15 @@ pub fn create_tcp_server_handler(
                 ip,
                 client_proxy_selector.clone(),
                 resolver.clone(),
+                decoy.map(|config| DecoyHandler::new(config, resolver.clone())),
             ))
         }
         ServerProxyConfig::Shadowsocks {
             config,
+            users,
             udp_enabled,
+            user_group,
+            ..
         } => match config {
             ShadowsocksConfig::Legacy { cipher, password } => {
                 Box::new(ShadowsocksTcpHandler::new_server(
@@ -112,10 +137,25 @@ pub fn create_tcp_server_handler(
                     client_proxy_selector.clone(),
                 ))
             }
-            ShadowsocksConfig::Aead2022 { cipher, key_bytes } => {
+            ShadowsocksConfig::Aead2022 {
+                cipher,
+                ref key_bytes,
+                ..
+            } => {
+                let users = users
+                    .into_iter()
+                    .map(|user| UserConfig {
+                        name: user.name,
+                        password: Some(user.password),
+                        user_id: None,
+                        rate_limit: None,
+                    })
+                    .collect();
                 Box::new(ShadowsocksTcpHandler::new_aead2022_server(
                     cipher,
-                    &key_bytes,
+                    key_bytes,
+                    users,
+                    user_group.as_deref(),
                     udp_enabled,
                     client_proxy_selector.clone(),
                 ))
@@ -125,18 +165,22 @@ pub fn create_tcp_server_handler(
             cipher,
             password,
             udp_enabled,
+            version,
         } => Box::new(SnellServerHandler::new(
             cipher.as_str().try_into().unwrap(),
             &password,
             udp_enabled,
+            version,
             client_proxy_selector.clone(),
         )),
         ServerProxyConfig::Vless {
             user_id,
             udp_enabled,
             fallback,
+            user_group,
+            ..
         } => Box::new(VlessTcpServerHandler::new(
-            &user_id,
+            Arc::new(create_vless_users(&user_id, user_group.as_deref())),
             udp_enabled,
             client_proxy_selector.clone(),
             resolver.clone(),
@@ -145,10 +189,14 @@ pub fn create_tcp_server_handler(
         ServerProxyConfig::Trojan {
             password,
             shadowsocks,
+            user_group,
+            decoy,
         } => Box::new(TrojanTcpHandler::new_server(
-            &password,
+            create_trojan_users(&password, user_group.as_deref()),
             &shadowsocks,
             client_proxy_selector.clone(),
+            resolver.clone(),
+            decoy.map(|config| DecoyHandler::new(config, resolver.clone())),
         )),
         ServerProxyConfig::Tls {
             tls_targets,
@@ -156,6 +204,7 @@ pub fn create_tcp_server_handler(
             shadowtls_targets,
             reality_targets,
             tls_buffer_size,
+            fallback,
         } => {
             let mut all_targets = tls_targets
                 .into_iter()
@@ -203,6 +252,7 @@ pub fn create_tcp_server_handler(
                 all_targets,
                 default_tls_target,
                 tls_buffer_size,
+                fallback,
                 resolver.clone(),
             ))
         }
@@ -210,11 +260,16 @@ pub fn create_tcp_server_handler(
             cipher,
             user_id,
             udp_enabled,
+            user_group,
+            max_time_diff_secs,
         } => Box::new(VmessTcpServerHandler::new(
             &cipher,
             &user_id,
+            user_group.as_deref(),
             udp_enabled,
+            max_time_diff_secs,
             client_proxy_selector.clone(),
+            resolver.clone(),
         )),
         ServerProxyConfig::Websocket { targets } => {
             let server_targets: Vec<WebsocketServerTarget> = targets
@@ -226,8 +281,118 @@ pub fn create_tcp_server_handler(
                 .collect::<Vec<_>>();
             Box::new(WebsocketTcpServerHandler::new(server_targets))
         }
-        ServerProxyConfig::PortForward { targets } => {
-            let targets = targets.into_vec();
+        ServerProxyConfig::Grpc {
+            service_name,
+            protocol,
+            override_rules,
+        } => {
+            let effective_selector = if !override_rules.is_empty() {
+                let rules = override_rules
+                    .map(ConfigSelection::unwrap_config)
+                    .into_vec();
+                create_tcp_client_proxy_selector(rules, resolver.clone())
+            } else {
+                client_proxy_selector.clone()
+            };
+
+            let handler =
+                create_tcp_server_handler(*protocol, &effective_selector, resolver, bind_ip);
+            Box::new(GrpcTcpServerHandler::new(&service_name, handler, resolver.clone()))
+        }
+        ServerProxyConfig::HttpUpgrade {
+            path,
+            host,
+            protocol,
+            override_rules,
+        } => {
+            let effective_selector = if !override_rules.is_empty() {
+                let rules = override_rules
+                    .map(ConfigSelection::unwrap_config)
+                    .into_vec();
+                create_tcp_client_proxy_selector(rules, resolver.clone())
+            } else {
+                client_proxy_selector.clone()
+            };
+
+            let handler =
+                create_tcp_server_handler(*protocol, &effective_selector, resolver, bind_ip);
+            Box::new(HttpUpgradeTcpServerHandler::new(path, host, handler))
+        }
+        ServerProxyConfig::Xhttp {
+            path,
+            host,
+            protocol,
+            override_rules,
+        } => {
+            let effective_selector = if !override_rules.is_empty() {
+                let rules = override_rules
+                    .map(ConfigSelection::unwrap_config)
+                    .into_vec();
+                create_tcp_client_proxy_selector(rules, resolver.clone())
+            } else {
+                client_proxy_selector.clone()
+            };
+
+            let handler =
+                create_tcp_server_handler(*protocol, &effective_selector, resolver, bind_ip);
+            Box::new(XhttpTcpServerHandler::new(
+                &path,
+                host,
+                handler,
+                resolver.clone(),
+            ))
+        }
+        ServerProxyConfig::Dispatch { targets, fallback } => {
+            let dispatch_targets = targets
+                .into_iter()
+                .map(|dispatch_target_config| {
+                    create_dispatch_server_target(
+                        dispatch_target_config,
+                        client_proxy_selector,
+                        resolver,
+                        bind_ip,
+                    )
+                })
+                .collect();
+            let fallback = fallback.map(|fallback| {
+                create_tcp_server_handler(*fallback, client_proxy_selector, resolver, bind_ip)
+            });
+            Box::new(DispatchServerHandler::new(dispatch_targets, fallback))
+        }
+        ServerProxyConfig::Obfs4 {
+            private_key,
+            node_id,
+            iat_mode,
+            protocol,
+            override_rules,
+        } => {
+            // Keys are validated during config load
+            let private_key = crate::reality::decode_private_key(&private_key)
+                .expect("Invalid obfs4 private key (should be validated during config load)");
+            let node_id = decode_node_id(&node_id)
+                .expect("Invalid obfs4 node ID (should be validated during config load)");
+            let identity = Obfs4ServerIdentity::new(node_id, private_key)
+                .expect("Invalid obfs4 private key (should be validated during config load)");
+
+            let effective_selector = if !override_rules.is_empty() {
+                let rules = override_rules
+                    .map(ConfigSelection::unwrap_config)
+                    .into_vec();
+                create_tcp_client_proxy_selector(rules, resolver.clone())
+            } else {
+                client_proxy_selector.clone()
+            };
+
+            let handler =
+                create_tcp_server_handler(*protocol, &effective_selector, resolver, bind_ip);
+            Box::new(Obfs4TcpServerHandler::new(identity, iat_mode, handler))
+        }
+        ServerProxyConfig::PortForward { targets, .. } => {
+            // Targets with several ports were split per listening port during validation.
+            let targets = targets
+                .into_iter()
+                .map(|target| target.single_location().unwrap())
+                .collect();
             Box::new(PortForwardServerHandler::new(
                 targets,
                 client_proxy_selector.clone(),
@@ -266,6 +431,33 @@ pub fn create_tcp_server_handler(
                 fallback,
             ))
         }
+        ServerProxyConfig::Ssh {
+            host_key,
+            host_key_passphrase,
+            authorized_keys,
+        } => {
+            // Keys are checked during config validation
+            let host_key = parse_ssh_private_key(&host_key, host_key_passphrase.as_deref())
+                .expect("Invalid SSH host key");
+            let authorized_keys = authorized_keys
+                .iter()
+                .map(|key| parse_ssh_public_key(key).expect("Invalid SSH public key"))
+                .collect();
+            Box::new(SshServerHandler::new(
+                host_key,
+                authorized_keys,
+                client_proxy_selector.clone(),
+                resolver.clone(),
+            ))
+        }
+        ServerProxyConfig::Dns { fake_ip, .. } => {
+            // Per-domain upstreams are part of the resolver (see DnsRegistry).
+            Box::new(DnsTcpServerHandler::new(resolver.clone(), fake_ip))
+        }
+        ServerProxyConfig::Tproxy { .. } | ServerProxyConfig::Redirect { .. } => {
+            // Transparent servers have their own listeners, and can't be nested (validated)
+            unreachable!("transparent protocols are not handled by TCP server handlers")
+        }
         ServerProxyConfig::Naiveproxy { .. } => {
             // This should be caught at config validation time
             unreachable!(
@@ -292,7 +484,10 @@ fn create_tls_server_target(
         client_ca_certs,
         client_fingerprints,
         vision,
+        post_quantum,
+        ech_keys: _,
         protocol,
+        alpn_targets,
         override_rules,
     } = tls_server_config;
 
@@ -327,6 +522,7 @@ fn create_tls_server_target(
         client_ca_certs,
         &effective_alpn,
         &client_fingerprints.into_vec(),
+        post_quantum,
     ));
 
     // Compute effective selector: if override_rules exist, create new selector; otherwise use parent's
@@ -334,7 +530,7 @@ fn create_tls_server_target(
         let rules = override_rules
             .map(ConfigSelection::unwrap_config)
             .into_vec();
-        Arc::new(create_tcp_client_proxy_selector(rules, resolver.clone()))
+        create_tcp_client_proxy_selector(rules, resolver.clone())
     } else {
         client_proxy_selector.clone()
     };
@@ -366,13 +562,12 @@ fn create_tls_server_target(
             user_id,
             udp_enabled,
             fallback,
+            user_group,
+            ..
         } = &protocol
         {
-            let user_id_bytes = parse_uuid(user_id)
-                .expect("Invalid user_id UUID")
-                .into_boxed_slice();
             InnerProtocol::VisionVless(VisionVlessConfig {
-                user_id: user_id_bytes,
+                users: Arc::new(create_vless_users(user_id, user_group.as_deref())),
                 udp_enabled: *udp_enabled,
                 fallback: fallback.clone(),
             })
@@ -384,10 +579,20 @@ fn create_tls_server_target(
         InnerProtocol::Normal(handler)
     };
 
+    let alpn_handlers = alpn_targets
+        .into_iter()
+        .map(|(alpn, alpn_protocol)| {
+            let handler =
+                create_tcp_server_handler(alpn_protocol, &effective_selector, resolver, bind_ip);
+            (alpn.into_bytes(), handler)
+        })
+        .collect();
+
     TlsServerTarget::Tls {
         server_config,
         effective_selector,
         inner_protocol,
+        alpn_handlers,
     }
 }
 
@@ -402,9 +607,11 @@ fn create_shadow_tls_server_target(
         handshake,
         protocol,
         override_rules,
+        fallback,
+        wildcard_sni,
     } = shadow_tls_server_config;
 
-    let target_handshake = match handshake {
+    let (target_handshake, fallback) = match handshake {
         ShadowTlsServerHandshakeConfig::Local(handshake) => {
             // Certificates are already embedded as PEM data during config validation
             let cert_bytes = handshake.cert.as_bytes().to_vec();
@@ -422,15 +629,36 @@ fn create_shadow_tls_server_target(
                 client_ca_certs,
                 &handshake.alpn_protocols.into_vec(),
                 &handshake.client_fingerprints.into_vec(),
+                None,
             ));
 
-            ShadowTlsServerTargetHandshake::new_local(server_config)
+            // The fallback is connected to directly.
+            let fallback = fallback.map(|location| {
+                let direct_hop =
+                    ClientChainHop::Single(ConfigSelection::Config(ClientConfig::default()));
+                let client_chain =
+                    build_client_proxy_chain(OneOrSome::One(direct_hop), resolver.clone());
+                (location, client_chain)
+            });
+
+            (
+                ShadowTlsServerTargetHandshake::new_local(server_config),
+                fallback,
+            )
         }
         ShadowTlsServerHandshakeConfig::Remote(handshake) => {
             // Build ClientProxyChain from client_chain
             // client_chain is guaranteed to be non-empty (defaults to direct hop)
+            let fallback = fallback.map(|location| {
+                let client_chain =
+                    build_client_proxy_chain(handshake.client_chain.clone(), resolver.clone());
+                (location, client_chain)
+            });
             let client_chain = build_client_proxy_chain(handshake.client_chain, resolver.clone());
-            ShadowTlsServerTargetHandshake::new_remote(handshake.address, client_chain)
+            (
+                ShadowTlsServerTargetHandshake::new_remote(handshake.address, client_chain),
+                fallback,
+            )
         }
     };
 
@@ -439,7 +667,7 @@ fn create_shadow_tls_server_target(
         let rules = override_rules
             .map(ConfigSelection::unwrap_config)
             .into_vec();
-        Arc::new(create_tcp_client_proxy_selector(rules, resolver.clone()))
+        create_tcp_client_proxy_selector(rules, resolver.clone())
     } else {
         client_proxy_selector.clone()
     };
@@ -449,6 +677,8 @@ fn create_shadow_tls_server_target(
     TlsServerTarget::ShadowTls(ShadowTlsServerTarget::new(
         password,
         target_handshake,
+        fallback,
+        wildcard_sni,
         handler,
     ))
 }
@@ -493,7 +723,7 @@ fn create_reality_server_target(
         let rules = override_rules
             .map(ConfigSelection::unwrap_config)
             .into_vec();
-        Arc::new(create_tcp_client_proxy_selector(rules, resolver.clone()))
+        create_tcp_client_proxy_selector(rules, resolver.clone())
     } else {
         client_proxy_selector.clone()
     };
@@ -525,13 +755,12 @@ fn create_reality_server_target(
             user_id,
             udp_enabled,
             fallback,
+            user_group,
+            ..
         } = &protocol
         {
-            let user_id_bytes = parse_uuid(user_id)
-                .expect("Invalid user_id UUID")
-                .into_boxed_slice();
             InnerProtocol::VisionVless(VisionVlessConfig {
-                user_id: user_id_bytes,
+                users: Arc::new(create_vless_users(user_id, user_group.as_deref())),
                 udp_enabled: *udp_enabled,
                 fallback: fallback.clone(),
             })
@@ -578,6 +807,37 @@ fn create_reality_server_target(
     })
 }
 
+fn create_dispatch_server_target(
+    dispatch_target_config: DispatchTargetConfig,
+    client_proxy_selector: &Arc<ClientProxySelector>,
+    resolver: &Arc<dyn Resolver>,
+    bind_ip: Option<IpAddr>,
+) -> DispatchServerTarget {
+    let DispatchTargetConfig {
+        matching_path,
+        matching_host,
+        protocol,
+        override_rules,
+    } = dispatch_target_config;
+
+    let effective_selector = if !override_rules.is_empty() {
+        let rules = override_rules
+            .map(ConfigSelection::unwrap_config)
+            .into_vec();
+        create_tcp_client_proxy_selector(rules, resolver.clone())
+    } else {
+        client_proxy_selector.clone()
+    };
+
+    let handler = create_tcp_server_handler(protocol, &effective_selector, resolver, bind_ip);
+
+    DispatchServerTarget {
+        matching_path,
+        matching_host,
+        handler,
+    }
+}
+
 fn create_websocket_server_target(
     websocket_server_config: WebsocketServerConfig,
     client_proxy_selector: &Arc<ClientProxySelector>,
@@ -586,6 +846,7 @@ fn create_websocket_server_target(
 ) -> WebsocketServerTarget {
     let WebsocketServerConfig {
         matching_path,
+        matching_host,
         matching_headers,
         ping_type,
         protocol,
@@ -606,7 +867,7 @@ fn create_websocket_server_target(
         let rules = override_rules
             .map(ConfigSelection::unwrap_config)
             .into_vec();
-        Arc::new(create_tcp_client_proxy_selector(rules, resolver.clone()))
+        create_tcp_client_proxy_selector(rules, resolver.clone())
     } else {
         client_proxy_selector.clone()
     };
@@ -615,6 +876,7 @@ fn create_websocket_server_target(
 
     WebsocketServerTarget {
         matching_path,
+        matching_host,
         matching_headers,
         ping_type,
         handler,
@@ -1,5 +1,7 @@
 use std::sync::OnceLock;
 
+use crate::config::Config;
+
 static NUM_THREADS: OnceLock<usize> = OnceLock::new();
 
 pub fn set_num_threads(num_threads: usize) {
@@ -9,3 +11,11 @@ pub fn set_num_threads(num_threads: usize) {
 pub fn get_num_threads() -> usize {
     *NUM_THREADS.get().unwrap()
 }
+
+/// Returns the worker threads set by a runtime config, if any.
+pub fn configured_worker_threads(configs: &[Config]) -> Option<usize> {
+    configs.iter().find_map(|config| match config {
+        Config::Runtime(runtime) => Some(runtime.worker_threads),
+        _ => None,
+    })
+}
@@ -19,7 +19,11 @@ pub struct TlsClientHandler {
 #[derive(Debug)]
 pub enum TlsInnerClientHandler {
     Default(Box<dyn TcpClientHandler>),
-    VisionVless { uuid: Box<[u8]>, udp_enabled: bool },
+    VisionVless {
+        uuid: Box<[u8]>,
+        udp_enabled: bool,
+        xudp: bool,
+    },
 }
 
 impl TlsClientHandler {
@@ -43,12 +47,17 @@ impl TlsClientHandler {
         server_name: rustls::pki_types::ServerName<'static>,
         uuid: Box<[u8]>,
         udp_enabled: bool,
+        xudp: bool,
     ) -> Self {
         Self {
             client_config,
             tls_buffer_size,
             server_name,
-            handler: TlsInnerClientHandler::VisionVless { uuid, udp_enabled },
+            handler: TlsInnerClientHandler::VisionVless {
+                uuid,
+                udp_enabled,
+                xudp,
+            },
         }
     }
 }
@@ -129,11 +138,12 @@ impl TcpClientHandler for TlsClientHandler {
                     .setup_client_udp_bidirectional(Box::new(tls_stream), target)
                     .await
             }
-            TlsInnerClientHandler::VisionVless { uuid, .. } => {
+            TlsInnerClientHandler::VisionVless { uuid, xudp, .. } => {
                 crate::vless::vless_client_handler::setup_vless_udp_bidirectional(
                     tls_stream,
                     uuid,
                     target.into_location(),
+                    *xudp,
                 )
                 .await
             }
@@ -0,0 +1,574 @@
+//! Browser TLS ClientHello fingerprints.
+//!
+//! The REALITY client writes its own ClientHello, so it copies the cipher suites, extension
+//! order, GREASE values, groups, signature algorithms and padding of the selected browser.
+//! Key shares are limited to X25519, and extensions that need state (session resumption,
+//! ECH) are not sent.
+//!
+//! rustls based TLS clients can't control the ClientHello layout, and only use the
+//! browser's cipher suite and key exchange group preferences.
+
+use rand::Rng;
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+/// Placeholder for a GREASE value (RFC 8701) in the profiles below.
+const GREASE: u16 = 0x0a0a;
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_STATUS_REQUEST: u16 = 5;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SIGNED_CERTIFICATE_TIMESTAMP: u16 = 18;
+const EXT_PADDING: u16 = 21;
+const EXT_EXTENDED_MASTER_SECRET: u16 = 23;
+const EXT_COMPRESS_CERTIFICATE: u16 = 27;
+const EXT_RECORD_SIZE_LIMIT: u16 = 28;
+const EXT_DELEGATED_CREDENTIALS: u16 = 34;
+const EXT_SESSION_TICKET: u16 = 35;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 45;
+const EXT_KEY_SHARE: u16 = 51;
+const EXT_APPLICATION_SETTINGS: u16 = 17513;
+const EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+
+const GROUP_X25519: u16 = 0x001d;
+
+/// Browser whose TLS client is imitated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TlsFingerprint {
+    Chrome,
+    Firefox,
+    Safari,
+    /// A random browser for each connection, with a shuffled extension order.
+    #[serde(alias = "randomized")]
+    Random,
+}
+
+struct Profile {
+    cipher_suites: &'static [u16],
+    extensions: &'static [u16],
+    supported_groups: &'static [u16],
+    signature_algorithms: &'static [u16],
+    supported_versions: &'static [u16],
+    cert_compression_algorithms: &'static [u16],
+    /// Chrome sends its extensions in random order, except for GREASE and padding.
+    shuffle_extensions: bool,
+}
+
+static CHROME: Profile = Profile {
+    cipher_suites: &[
+        GREASE, 0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013,
+        0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ],
+    extensions: &[
+        GREASE,
+        EXT_SERVER_NAME,
+        EXT_EXTENDED_MASTER_SECRET,
+        EXT_RENEGOTIATION_INFO,
+        EXT_SUPPORTED_GROUPS,
+        EXT_EC_POINT_FORMATS,
+        EXT_SESSION_TICKET,
+        EXT_ALPN,
+        EXT_STATUS_REQUEST,
+        EXT_SIGNATURE_ALGORITHMS,
+        EXT_SIGNED_CERTIFICATE_TIMESTAMP,
+        EXT_KEY_SHARE,
+        EXT_PSK_KEY_EXCHANGE_MODES,
+        EXT_SUPPORTED_VERSIONS,
+        EXT_COMPRESS_CERTIFICATE,
+        EXT_APPLICATION_SETTINGS,
+        GREASE,
+        EXT_PADDING,
+    ],
+    supported_groups: &[GREASE, GROUP_X25519, 0x0017, 0x0018],
+    signature_algorithms: &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+    ],
+    supported_versions: &[GREASE, 0x0304, 0x0303],
+    // brotli
+    cert_compression_algorithms: &[0x0002],
+    shuffle_extensions: true,
+};
+
+static FIREFOX: Profile = Profile {
+    cipher_suites: &[
+        0x1301, 0x1303, 0x1302, 0xc02b, 0xc02f, 0xcca9, 0xcca8, 0xc02c, 0xc030, 0xc00a, 0xc009,
+        0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+    ],
+    extensions: &[
+        EXT_SERVER_NAME,
+        EXT_EXTENDED_MASTER_SECRET,
+        EXT_RENEGOTIATION_INFO,
+        EXT_SUPPORTED_GROUPS,
+        EXT_EC_POINT_FORMATS,
+        EXT_SESSION_TICKET,
+        EXT_ALPN,
+        EXT_STATUS_REQUEST,
+        EXT_DELEGATED_CREDENTIALS,
+        EXT_KEY_SHARE,
+        EXT_SUPPORTED_VERSIONS,
+        EXT_SIGNATURE_ALGORITHMS,
+        EXT_PSK_KEY_EXCHANGE_MODES,
+        EXT_RECORD_SIZE_LIMIT,
+        EXT_PADDING,
+    ],
+    supported_groups: &[GROUP_X25519, 0x0017, 0x0018, 0x0019, 0x0100, 0x0101],
+    signature_algorithms: &[
+        0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601, 0x0203, 0x0201,
+    ],
+    supported_versions: &[0x0304, 0x0303],
+    cert_compression_algorithms: &[],
+    shuffle_extensions: false,
+};
+
+static SAFARI: Profile = Profile {
+    cipher_suites: &[
+        GREASE, 0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b, 0xcca9, 0xc030, 0xc02f, 0xcca8, 0xc00a,
+        0xc009, 0xc014, 0xc013, 0x009d, 0x009c, 0x0035, 0x002f, 0xc008, 0xc012, 0x000a,
+    ],
+    extensions: &[
+        GREASE,
+        EXT_SERVER_NAME,
+        EXT_EXTENDED_MASTER_SECRET,
+        EXT_RENEGOTIATION_INFO,
+        EXT_SUPPORTED_GROUPS,
+        EXT_EC_POINT_FORMATS,
+        EXT_ALPN,
+        EXT_STATUS_REQUEST,
+        EXT_SIGNATURE_ALGORITHMS,
+        EXT_SIGNED_CERTIFICATE_TIMESTAMP,
+        EXT_KEY_SHARE,
+        EXT_PSK_KEY_EXCHANGE_MODES,
+        EXT_SUPPORTED_VERSIONS,
+        EXT_COMPRESS_CERTIFICATE,
+        GREASE,
+        EXT_PADDING,
+    ],
+    supported_groups: &[GREASE, GROUP_X25519, 0x0017, 0x0018, 0x0019],
+    // Safari really lists rsa_pss_rsae_sha384 twice.
+    signature_algorithms: &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0203, 0x0805, 0x0805, 0x0501, 0x0806, 0x0601, 0x0201,
+    ],
+    supported_versions: &[GREASE, 0x0304, 0x0303, 0x0302, 0x0301],
+    // zlib
+    cert_compression_algorithms: &[0x0001],
+    shuffle_extensions: false,
+};
+
+/// GREASE values of a single ClientHello. Browsers pick a separate value for each list.
+struct GreaseValues {
+    cipher_suite: u16,
+    group: u16,
+    version: u16,
+    first_extension: u16,
+    last_extension: u16,
+}
+
+impl GreaseValues {
+    fn new(rng: &mut impl Rng) -> Self {
+        let first_extension = random_grease(rng);
+        let mut last_extension = random_grease(rng);
+        // Extension types must be unique.
+        while last_extension == first_extension {
+            last_extension = random_grease(rng);
+        }
+        Self {
+            cipher_suite: random_grease(rng),
+            group: random_grease(rng),
+            version: random_grease(rng),
+            first_extension,
+            last_extension,
+        }
+    }
+}
+
+fn random_grease(rng: &mut impl Rng) -> u16 {
+    let nibble: u16 = rng.random_range(0..16);
+    (nibble << 12) | (nibble << 4) | 0x0a0a
+}
+
+fn replace_grease(values: &[u16], grease: u16) -> impl Iterator<Item = u16> + '_ {
+    values
+        .iter()
+        .map(move |&value| if value == GREASE { grease } else { value })
+}
+
+impl TlsFingerprint {
+    fn profile(self, rng: &mut impl Rng) -> (&'static Profile, bool) {
+        match self {
+            TlsFingerprint::Chrome => (&CHROME, CHROME.shuffle_extensions),
+            TlsFingerprint::Firefox => (&FIREFOX, FIREFOX.shuffle_extensions),
+            TlsFingerprint::Safari => (&SAFARI, SAFARI.shuffle_extensions),
+            TlsFingerprint::Random => {
+                let profiles = [&CHROME, &FIREFOX, &SAFARI];
+                (profiles[rng.random_range(0..profiles.len())], true)
+            }
+        }
+    }
+
+    /// Builds a TLS 1.3 ClientHello handshake message (without record header) with an
+    /// X25519 key share.
+    ///
+    /// `tls13_cipher_suites` limits the offered TLS 1.3 cipher suites when not empty. The
+    /// session ID starts at offset 39, like in the default REALITY ClientHello.
+    pub fn client_hello(
+        self,
+        client_random: &[u8; 32],
+        session_id: &[u8; 32],
+        client_public_key: &[u8],
+        server_name: &str,
+        tls13_cipher_suites: &[u16],
+        alpn_protocols: &[&str],
+    ) -> Vec<u8> {
+        let mut rng = rand::rng();
+        let (profile, shuffle_extensions) = self.profile(&mut rng);
+        let grease = GreaseValues::new(&mut rng);
+
+        let mut hello = Vec::with_capacity(512);
+        hello.push(0x01);
+        hello.extend_from_slice(&[0u8; 3]);
+        hello.extend_from_slice(&[0x03, 0x03]);
+        hello.extend_from_slice(client_random);
+        hello.push(session_id.len() as u8);
+        hello.extend_from_slice(session_id);
+
+        let cipher_suites: Vec<u16> = replace_grease(profile.cipher_suites, grease.cipher_suite)
+            .filter(|&suite| {
+                suite >> 8 != 0x13
+                    || tls13_cipher_suites.is_empty()
+                    || tls13_cipher_suites.contains(&suite)
+            })
+            .collect();
+        put_u16(&mut hello, (cipher_suites.len() * 2) as u16);
+        for suite in cipher_suites {
+            put_u16(&mut hello, suite);
+        }
+
+        // Null compression only.
+        hello.extend_from_slice(&[0x01, 0x00]);
+
+        let mut extension_types = profile.extensions.to_vec();
+        if shuffle_extensions {
+            let mut shuffled: Vec<u16> = extension_types
+                .iter()
+                .copied()
+                .filter(|&ext| ext != GREASE && ext != EXT_PADDING)
+                .collect();
+            shuffled.shuffle(&mut rng);
+            let mut shuffled = shuffled.into_iter();
+            for ext in extension_types.iter_mut() {
+                if *ext != GREASE && *ext != EXT_PADDING {
+                    *ext = shuffled.next().unwrap();
+                }
+            }
+        }
+
+        let mut extensions = Vec::with_capacity(512);
+        let mut seen_grease = false;
+        for ext in extension_types {
+            let mut body = Vec::new();
+            let ext_type = match ext {
+                GREASE if !seen_grease => {
+                    seen_grease = true;
+                    grease.first_extension
+                }
+                GREASE => {
+                    body.push(0);
+                    grease.last_extension
+                }
+                EXT_SERVER_NAME => {
+                    if server_name.is_empty() {
+                        continue;
+                    }
+                    put_u16(&mut body, (server_name.len() + 3) as u16);
+                    body.push(0);
+                    put_u16(&mut body, server_name.len() as u16);
+                    body.extend_from_slice(server_name.as_bytes());
+                    ext
+                }
+                EXT_ALPN => {
+                    if alpn_protocols.is_empty() {
+                        continue;
+                    }
+                    let list_len: usize = alpn_protocols.iter().map(|p| 1 + p.len()).sum();
+                    put_u16(&mut body, list_len as u16);
+                    for protocol in alpn_protocols {
+                        body.push(protocol.len() as u8);
+                        body.extend_from_slice(protocol.as_bytes());
+                    }
+                    ext
+                }
+                EXT_APPLICATION_SETTINGS => {
+                    if !alpn_protocols.contains(&"h2") {
+                        continue;
+                    }
+                    body.extend_from_slice(&[0x00, 0x03, 0x02, b'h', b'2']);
+                    ext
+                }
+                EXT_SUPPORTED_GROUPS => {
+                    put_u16_list(
+                        &mut body,
+                        replace_grease(profile.supported_groups, grease.group),
+                    );
+                    ext
+                }
+                EXT_SIGNATURE_ALGORITHMS => {
+                    put_u16_list(&mut body, profile.signature_algorithms.iter().copied());
+                    ext
+                }
+                EXT_DELEGATED_CREDENTIALS => {
+                    put_u16_list(&mut body, [0x0403, 0x0503, 0x0603, 0x0203].into_iter());
+                    ext
+                }
+                EXT_SUPPORTED_VERSIONS => {
+                    let versions: Vec<u16> =
+                        replace_grease(profile.supported_versions, grease.version).collect();
+                    body.push((versions.len() * 2) as u8);
+                    for version in versions {
+                        put_u16(&mut body, version);
+                    }
+                    ext
+                }
+                EXT_COMPRESS_CERTIFICATE => {
+                    body.push((profile.cert_compression_algorithms.len() * 2) as u8);
+                    for &algorithm in profile.cert_compression_algorithms {
+                        put_u16(&mut body, algorithm);
+                    }
+                    ext
+                }
+                EXT_KEY_SHARE => {
+                    let mut shares = Vec::new();
+                    if profile.supported_groups.contains(&GREASE) {
+                        put_u16(&mut shares, grease.group);
+                        shares.extend_from_slice(&[0x00, 0x01, 0x00]);
+                    }
+                    put_u16(&mut shares, GROUP_X25519);
+                    put_u16(&mut shares, client_public_key.len() as u16);
+                    shares.extend_from_slice(client_public_key);
+                    put_u16(&mut body, shares.len() as u16);
+                    body.extend_from_slice(&shares);
+                    ext
+                }
+                // OCSP, without responder IDs or request extensions.
+                EXT_STATUS_REQUEST => {
+                    body.extend_from_slice(&[0x01, 0x00, 0x00, 0x00, 0x00]);
+                    ext
+                }
+                // Uncompressed points only.
+                EXT_EC_POINT_FORMATS => {
+                    body.extend_from_slice(&[0x01, 0x00]);
+                    ext
+                }
+                // psk_dhe_ke
+                EXT_PSK_KEY_EXCHANGE_MODES => {
+                    body.extend_from_slice(&[0x01, 0x01]);
+                    ext
+                }
+                EXT_RECORD_SIZE_LIMIT => {
+                    put_u16(&mut body, 0x4001);
+                    ext
+                }
+                EXT_RENEGOTIATION_INFO => {
+                    body.push(0);
+                    ext
+                }
+                EXT_PADDING => {
+                    // Pad messages between 256 and 511 bytes to 512 bytes, like BoringSSL,
+                    // to work around buggy middleboxes.
+                    let unpadded_len = hello.len() + 2 + extensions.len();
+                    if !(0x100..0x200).contains(&unpadded_len) {
+                        continue;
+                    }
+                    let padding_len = (0x200 - unpadded_len).saturating_sub(4).max(1);
+                    body.resize(padding_len, 0);
+                    ext
+                }
+                // Extensions without data.
+                _ => ext,
+            };
+            put_u16(&mut extensions, ext_type);
+            put_u16(&mut extensions, body.len() as u16);
+            extensions.extend_from_slice(&body);
+        }
+
+        put_u16(&mut hello, extensions.len() as u16);
+        hello.extend_from_slice(&extensions);
+
+        let message_len = hello.len() - 4;
+        hello[1..4].copy_from_slice(&(message_len as u32).to_be_bytes()[1..]);
+        hello
+    }
+
+    /// Returns `provider` with its cipher suites and key exchange groups limited to and
+    /// ordered like the browser's.
+    pub fn crypto_provider(
+        self,
+        provider: &rustls::crypto::CryptoProvider,
+    ) -> rustls::crypto::CryptoProvider {
+        let (profile, _) = self.profile(&mut rand::rng());
+        let position = |list: &[u16], id: u16| list.iter().position(|&value| value == id);
+
+        let mut provider = provider.clone();
+        provider
+            .cipher_suites
+            .retain(|suite| position(profile.cipher_suites, u16::from(suite.suite())).is_some());
+        provider
+            .cipher_suites
+            .sort_by_key(|suite| position(profile.cipher_suites, u16::from(suite.suite())));
+        provider
+            .kx_groups
+            .retain(|group| position(profile.supported_groups, u16::from(group.name())).is_some());
+        provider
+            .kx_groups
+            .sort_by_key(|group| position(profile.supported_groups, u16::from(group.name())));
+        provider
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u16_list(buf: &mut Vec<u8>, values: impl Iterator<Item = u16>) {
+    let values: Vec<u16> = values.collect();
+    put_u16(buf, (values.len() * 2) as u16);
+    for value in values {
+        put_u16(buf, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the extension types of a ClientHello built by `client_hello`.
+    fn extension_types(hello: &[u8]) -> Vec<u16> {
+        let cipher_suites_len = u16::from_be_bytes([hello[71], hello[72]]) as usize;
+        let mut pos = 73 + cipher_suites_len + 2;
+        let extensions_end = pos + 2 + u16::from_be_bytes([hello[pos], hello[pos + 1]]) as usize;
+        assert_eq!(extensions_end, hello.len());
+        pos += 2;
+
+        let mut types = vec![];
+        while pos < extensions_end {
+            types.push(u16::from_be_bytes([hello[pos], hello[pos + 1]]));
+            pos += 4 + u16::from_be_bytes([hello[pos + 2], hello[pos + 3]]) as usize;
+        }
+        assert_eq!(pos, extensions_end);
+        types
+    }
+
+    fn client_hello(fingerprint: TlsFingerprint) -> Vec<u8> {
+        fingerprint.client_hello(
+            &[1; 32],
+            &[2; 32],
+            &[3; 32],
+            "www.example.com",
+            &[],
+            &["h2", "http/1.1"],
+        )
+    }
+
+    #[test]
+    fn test_client_hello_layout() {
+        for fingerprint in [
+            TlsFingerprint::Chrome,
+            TlsFingerprint::Firefox,
+            TlsFingerprint::Safari,
+            TlsFingerprint::Random,
+        ] {
+            let hello = client_hello(fingerprint);
+            assert_eq!(hello[0], 0x01);
+            assert_eq!(
+                u32::from_be_bytes([0, hello[1], hello[2], hello[3]]) as usize,
+                hello.len() - 4
+            );
+            assert_eq!(&hello[6..38], &[1; 32]);
+            assert_eq!(hello[38], 32);
+            assert_eq!(&hello[39..71], &[2; 32]);
+
+            let types = extension_types(&hello);
+            assert!(types.contains(&EXT_KEY_SHARE));
+            assert!(types.contains(&EXT_SUPPORTED_VERSIONS));
+            assert!(hello.windows(15).any(|w| w == b"www.example.com"));
+        }
+    }
+
+    #[test]
+    fn test_client_hello_grease() {
+        let hello = client_hello(TlsFingerprint::Chrome);
+        let is_grease = |value: u16| value & 0x0f0f == 0x0a0a && value >> 12 == (value >> 4) & 0xf;
+        assert!(is_grease(u16::from_be_bytes([hello[73], hello[74]])));
+
+        let types = extension_types(&hello);
+        assert!(is_grease(types[0]));
+        assert_eq!(types.iter().filter(|&&t| is_grease(t)).count(), 2);
+        assert!(types.contains(&EXT_APPLICATION_SETTINGS));
+
+        let hello = client_hello(TlsFingerprint::Firefox);
+        assert_eq!(
+            extension_types(&hello)[..4],
+            [
+                EXT_SERVER_NAME,
+                EXT_EXTENDED_MASTER_SECRET,
+                EXT_RENEGOTIATION_INFO,
+                EXT_SUPPORTED_GROUPS
+            ]
+        );
+        assert!(!extension_types(&hello).iter().any(|&t| is_grease(t)));
+    }
+
+    #[test]
+    fn test_client_hello_tls13_cipher_suites() {
+        let hello = TlsFingerprint::Firefox.client_hello(
+            &[1; 32],
+            &[2; 32],
+            &[3; 32],
+            "www.example.com",
+            &[0x1302],
+            &[],
+        );
+        let cipher_suites_len = u16::from_be_bytes([hello[71], hello[72]]) as usize;
+        let suites: Vec<u16> = hello[73..73 + cipher_suites_len]
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        assert_eq!(suites[0], 0x1302);
+        assert!(!suites.contains(&0x1301));
+        assert!(!suites.contains(&0x1303));
+        assert!(!extension_types(&hello).contains(&EXT_ALPN));
+    }
+
+    #[test]
+    fn test_client_hello_padding() {
+        // Chrome's ClientHello is around 300 bytes without padding.
+        let hello = client_hello(TlsFingerprint::Chrome);
+        assert_eq!(extension_types(&hello).last(), Some(&EXT_PADDING));
+        assert_eq!(hello.len(), 0x200);
+    }
+
+    #[test]
+    fn test_crypto_provider() {
+        let provider =
+            TlsFingerprint::Firefox.crypto_provider(&rustls::crypto::aws_lc_rs::default_provider());
+        let suites: Vec<u16> = provider
+            .cipher_suites
+            .iter()
+            .map(|suite| u16::from(suite.suite()))
+            .collect();
+        assert_eq!(&suites[..3], &[0x1301, 0x1303, 0x1302]);
+        assert_eq!(u16::from(provider.kx_groups[0].name()), GROUP_X25519);
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let fingerprint: TlsFingerprint = serde_yaml::from_str("randomized").unwrap();
+        assert_eq!(fingerprint, TlsFingerprint::Random);
+        let fingerprint: TlsFingerprint = serde_yaml::from_str("chrome").unwrap();
+        assert_eq!(fingerprint, TlsFingerprint::Chrome);
+    }
+}
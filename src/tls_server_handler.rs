@@ -6,8 +6,10 @@ use rustc_hash::FxHashMap;
 
 use crate::async_stream::AsyncStream;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::config::DecoyConfig;
 use crate::crypto::perform_crypto_handshake;
 use crate::crypto::{CryptoConnection, CryptoTlsStream};
+use crate::decoy_handler::DecoyHandler;
 use crate::naiveproxy::UserLookup;
 use crate::reality::{RealityServerTarget, setup_reality_server_stream};
 use crate::resolver::Resolver;
@@ -16,13 +18,14 @@ use crate::shadow_tls::{
     ParsedClientHello, ShadowTlsServerTarget, read_client_hello, setup_shadowtls_server_stream,
 };
 use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+use crate::vless::vless_server_handler::VlessUsers;
 
 use crate::address::NetLocation;
 
 /// Configuration for Vision VLESS inner protocol
 #[derive(Debug, Clone)]
 pub struct VisionVlessConfig {
-    pub user_id: Box<[u8]>,
+    pub users: Arc<VlessUsers>,
     pub udp_enabled: bool,
     pub fallback: Option<NetLocation>,
 }
@@ -64,6 +67,8 @@ pub enum TlsServerTarget {
         effective_selector: Arc<ClientProxySelector>,
         /// What to do after TLS termination - normal handler, Vision VLESS, or Naive
         inner_protocol: InnerProtocol,
+        /// Handlers used instead of `inner_protocol` for these negotiated ALPN protocols.
+        alpn_handlers: FxHashMap<Vec<u8>, Box<dyn TcpServerHandler>>,
     },
     ShadowTls(ShadowTlsServerTarget),
     Reality(RealityServerTarget),
@@ -76,6 +81,8 @@ pub struct TlsServerHandler {
     // used to resolve ShadowTLS handshake server hostnames and reality fallback destinations
     fallback_resolver: Arc<dyn Resolver>,
     tls_buffer_size: Option<usize>,
+    /// Web server for connections without a target, instead of closing them.
+    fallback: Option<DecoyHandler>,
 }
 
 impl TlsServerHandler {
@@ -83,17 +90,37 @@ impl TlsServerHandler {
         sni_targets: FxHashMap<String, TlsServerTarget>,
         default_target: Option<TlsServerTarget>,
         tls_buffer_size: Option<usize>,
+        fallback: Option<NetLocation>,
         resolver: Arc<dyn Resolver>,
     ) -> Self {
+        let fallback = fallback
+            .map(|location| DecoyHandler::new(DecoyConfig::Address(location), resolver.clone()));
         Self {
             sni_targets,
             default_target,
             fallback_resolver: resolver,
             tls_buffer_size,
+            fallback,
         }
     }
 }
 
+/// Returns the target of an SNI hostname: the exact hostname, then the closest wildcard
+/// pattern like `*.example.com`, then `*`.
+fn find_sni_target<'a, T>(targets: &'a FxHashMap<String, T>, hostname: &str) -> Option<&'a T> {
+    if let Some(target) = targets.get(hostname) {
+        return Some(target);
+    }
+    let mut suffix = hostname;
+    while let Some((_, parent)) = suffix.split_once('.') {
+        if let Some(target) = targets.get(&format!("*.{parent}")) {
+            return Some(target);
+        }
+        suffix = parent;
+    }
+    targets.get("*")
+}
+
 #[async_trait]
 impl TcpServerHandler for TlsServerHandler {
     async fn setup_server_stream(
@@ -103,25 +130,29 @@ impl TcpServerHandler for TlsServerHandler {
         let parsed_client_hello = read_client_hello(&mut server_stream).await?;
 
         let target = match parsed_client_hello.requested_server_name.as_ref() {
-            None => match self.default_target {
-                Some(ref t) => t,
-                None => {
-                    return Err(std::io::Error::other(
-                        "No default target for unspecified SNI",
-                    ));
-                }
-            },
-            Some(hostname) => match self.sni_targets.get(hostname) {
-                Some(t) => t,
-                None => match self.default_target {
-                    Some(ref t) => t,
-                    None => {
-                        return Err(std::io::Error::other(format!(
-                            "No default target for unknown SNI: {hostname}"
-                        )));
-                    }
+            None => self.default_target.as_ref(),
+            Some(hostname) => {
+                find_sni_target(&self.sni_targets, hostname).or(self.default_target.as_ref())
+            }
+        };
+
+        let Some(target) = target else {
+            if let Some(ref fallback) = self.fallback {
+                // Forward the ClientHello as is, so the fallback server does the handshake.
+                let ParsedClientHello {
+                    mut client_hello_frame,
+                    client_reader,
+                    ..
+                } = parsed_client_hello;
+                client_hello_frame.extend_from_slice(client_reader.unparsed_data());
+                return fallback.serve(server_stream, &client_hello_frame).await;
+            }
+            return Err(std::io::Error::other(
+                match parsed_client_hello.requested_server_name {
+                    None => "No default target for unspecified SNI".to_string(),
+                    Some(hostname) => format!("No default target for unknown SNI: {hostname}"),
                 },
-            },
+            ));
         };
 
         match target {
@@ -129,6 +160,7 @@ impl TcpServerHandler for TlsServerHandler {
                 server_config,
                 effective_selector,
                 inner_protocol,
+                alpn_handlers,
             } => {
                 let ParsedClientHello {
                     client_hello_frame,
@@ -172,29 +204,37 @@ impl TcpServerHandler for TlsServerHandler {
                 perform_crypto_handshake(&mut connection, &mut server_stream, 16384).await?;
                 let tls_stream = CryptoTlsStream::new(server_stream, connection);
 
-                let mut target_setup_result = match inner_protocol {
-                    InnerProtocol::Normal(handler) => {
-                        handler.setup_server_stream(Box::new(tls_stream)).await
-                    }
-                    InnerProtocol::VisionVless(vision_cfg) => {
-                        crate::vless::vless_server_handler::setup_custom_tls_vision_vless_server_stream(
-                            tls_stream,
-                            &vision_cfg.user_id,
-                            vision_cfg.udp_enabled,
-                            effective_selector.clone(),
-                            &self.fallback_resolver,
-                            vision_cfg.fallback.clone(),
-                        )
-                        .await
-                    }
-                    InnerProtocol::Naive(naive_cfg) => {
-                        crate::naiveproxy::setup_naive_server_stream(
-                            tls_stream,
-                            naive_cfg,
-                            effective_selector.clone(),
-                            self.fallback_resolver.clone(),
-                        )
-                        .await
+                let alpn_handler = tls_stream
+                    .alpn_protocol()
+                    .and_then(|alpn| alpn_handlers.get(alpn));
+
+                let mut target_setup_result = if let Some(handler) = alpn_handler {
+                    handler.setup_server_stream(Box::new(tls_stream)).await
+                } else {
+                    match inner_protocol {
+                        InnerProtocol::Normal(handler) => {
+                            handler.setup_server_stream(Box::new(tls_stream)).await
+                        }
+                        InnerProtocol::VisionVless(vision_cfg) => {
+                            crate::vless::vless_server_handler::setup_custom_tls_vision_vless_server_stream(
+                                tls_stream,
+                                &vision_cfg.users,
+                                vision_cfg.udp_enabled,
+                                effective_selector.clone(),
+                                &self.fallback_resolver,
+                                vision_cfg.fallback.clone(),
+                            )
+                            .await
+                        }
+                        InnerProtocol::Naive(naive_cfg) => {
+                            crate::naiveproxy::setup_naive_server_stream(
+                                tls_stream,
+                                naive_cfg,
+                                effective_selector.clone(),
+                                self.fallback_resolver.clone(),
+                            )
+                            .await
+                        }
                     }
                 };
 
@@ -228,3 +268,36 @@ impl TcpServerHandler for TlsServerHandler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_sni_target() {
+        let targets: FxHashMap<String, &str> = [
+            ("example.com", "exact"),
+            ("*.example.com", "wildcard"),
+            ("*.cdn.example.com", "cdn"),
+        ]
+        .into_iter()
+        .map(|(sni, target)| (sni.to_string(), target))
+        .collect();
+
+        assert_eq!(find_sni_target(&targets, "example.com"), Some(&"exact"));
+        assert_eq!(
+            find_sni_target(&targets, "www.example.com"),
+            Some(&"wildcard")
+        );
+        assert_eq!(
+            find_sni_target(&targets, "a.b.example.com"),
+            Some(&"wildcard")
+        );
+        assert_eq!(find_sni_target(&targets, "a.cdn.example.com"), Some(&"cdn"));
+        assert_eq!(find_sni_target(&targets, "example.org"), None);
+
+        let mut targets = targets;
+        targets.insert("*".to_string(), "any");
+        assert_eq!(find_sni_target(&targets, "example.org"), Some(&"any"));
+    }
+}
@@ -0,0 +1,130 @@
+//! Traffic padding for outbounds.
+//!
+//! Protocols that can carry dummy data, like AnyTLS waste frames, pad their writes by a
+//! random amount, and sometimes send standalone dummy frames, so that the sizes of the
+//! writes don't follow the sizes of the payload. The padding is limited by an overhead
+//! budget: the padding sent on a connection stays below `overhead_percent` of its payload.
+//! Writes can also be delayed by a random time to blur their timing.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+use crate::config::TrafficPaddingConfig;
+
+/// Chance that a write is followed by a standalone dummy frame, when the budget allows.
+const DUMMY_FRAME_CHANCE: f64 = 0.25;
+
+/// Padding state of a connection.
+#[derive(Debug)]
+pub struct TrafficPadding {
+    overhead_percent: u64,
+    max_delay_ms: u64,
+    payload_bytes: u64,
+    padding_bytes: u64,
+}
+
+impl TrafficPadding {
+    pub fn new(config: &TrafficPaddingConfig) -> Self {
+        Self {
+            overhead_percent: config.overhead_percent as u64,
+            max_delay_ms: config.max_delay_ms,
+            payload_bytes: 0,
+            padding_bytes: 0,
+        }
+    }
+
+    /// Padding bytes that can be sent without going over the budget.
+    fn budget(&self) -> u64 {
+        (self.payload_bytes * self.overhead_percent / 100).saturating_sub(self.padding_bytes)
+    }
+
+    /// Takes a random number of padding bytes between `min` and `max` from the budget.
+    fn take(&mut self, min: usize, max: usize) -> Option<usize> {
+        let max = (max as u64).min(self.budget()) as usize;
+        if max < min {
+            return None;
+        }
+        let len = rand::rng().random_range(min..=max);
+        self.padding_bytes += len as u64;
+        Some(len)
+    }
+
+    /// Records a write of `payload_len` bytes, and returns the number of padding bytes to
+    /// add to it, between `min` and `max`, or None when the budget doesn't allow it. `min`
+    /// is usually the size of the header of a dummy frame.
+    pub fn padding_len(&mut self, payload_len: usize, min: usize, max: usize) -> Option<usize> {
+        self.payload_bytes += payload_len as u64;
+        self.take(min, max)
+    }
+
+    /// Returns the size of a standalone dummy frame to send after a write, between `min`
+    /// and `max`, or None when no frame should be sent.
+    pub fn dummy_frame_len(&mut self, min: usize, max: usize) -> Option<usize> {
+        if !rand::rng().random_bool(DUMMY_FRAME_CHANCE) {
+            return None;
+        }
+        self.take(min, max)
+    }
+
+    /// Returns a random time to wait before a write, or None when writes aren't delayed.
+    pub fn delay(&self) -> Option<Duration> {
+        if self.max_delay_ms == 0 {
+            return None;
+        }
+        Some(Duration::from_millis(
+            rand::rng().random_range(0..=self.max_delay_ms),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn traffic_padding(overhead_percent: u32, max_delay_ms: u64) -> TrafficPadding {
+        TrafficPadding::new(&TrafficPaddingConfig {
+            overhead_percent,
+            max_delay_ms,
+        })
+    }
+
+    #[test]
+    fn test_padding_within_budget() {
+        let mut padding = traffic_padding(10, 0);
+        let mut payload = 0;
+        let mut total = 0;
+        for i in 0..1000 {
+            let len = 100 + i % 1400;
+            payload += len;
+            if let Some(padding_len) = padding.padding_len(len, 7, 65535 + 7) {
+                assert!(padding_len >= 7);
+                total += padding_len;
+            }
+            if let Some(frame_len) = padding.dummy_frame_len(7, 1024 + 7) {
+                assert!((7..=1024 + 7).contains(&frame_len));
+                total += frame_len;
+            }
+            assert!(total * 10 <= payload);
+        }
+        assert!(total > 0);
+    }
+
+    #[test]
+    fn test_padding_needs_budget_for_min() {
+        let mut padding = traffic_padding(10, 0);
+        // 10% of 60 bytes is less than a frame header.
+        assert_eq!(padding.padding_len(60, 7, 100), None);
+        assert_eq!(padding.padding_len(10, 7, 7), Some(7));
+        assert_eq!(padding.budget(), 0);
+    }
+
+    #[test]
+    fn test_delay() {
+        assert_eq!(traffic_padding(10, 0).delay(), None);
+        let padding = traffic_padding(10, 20);
+        for _ in 0..100 {
+            assert!(padding.delay().unwrap() <= Duration::from_millis(20));
+        }
+    }
+}
@@ -0,0 +1,781 @@
+//! Transparent proxy listeners (Linux only).
+//!
+//! TPROXY listeners receive connections and datagrams that iptables/nftables diverted
+//! to them without changing the destination address. The original destination is the
+//! local address of accepted TCP connections, and is passed as ancillary data with
+//! `IP_RECVORIGDSTADDR` for UDP datagrams. Replies to UDP clients are sent from a
+//! transparent socket bound to the original destination.
+//!
+//! REDIRECT listeners receive TCP connections whose destination was rewritten by NAT
+//! rules, and recover the original destination with `SO_ORIGINAL_DST`.
+//!
+//! With `dns_hijack`, queries to port 53 are answered with the server's resolver instead
+//! of being forwarded to their original destination. With `sniff`, TCP connections and
+//! QUIC flows are routed by the domain found in their first bytes.
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error};
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::io::{Interest, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::timeout_at;
+
+use crate::address::NetLocation;
+use crate::async_stream::AsyncMessageStream;
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::config::{BindLocation, ConfigSelection, ServerConfig, ServerProxyConfig, TcpConfig};
+use crate::dns_server::{DNS_PORT, DnsHijacker};
+use crate::http2_handler::PrefixedStream;
+use crate::port_forward_handler::PortForwardServerHandler;
+use crate::resolver::Resolver;
+use crate::sniff::{DatagramSniffer, SniffedFlow, Sniffer};
+use crate::socket_util::{
+    configure_tcp_stream, new_tcp_listener, set_tcp_fast_open_listen, to_canonical_socket_addr,
+};
+use crate::tcp::tcp_client_handler_factory::create_tcp_client_proxy_selector;
+use crate::tcp::tcp_handler::TcpServerHandler;
+use crate::tcp::tcp_server::{InboundContext, process_stream};
+
+/// Idle time after which UDP flows are closed.
+const UDP_FLOW_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Number of datagrams queued per UDP flow before new datagrams are dropped.
+const UDP_FLOW_CHANNEL_SIZE: usize = 64;
+
+const UDP_BUFFER_LEN: usize = 65535;
+
+/// How the original destination of accepted TCP connections is recovered.
+#[derive(Debug, Clone, Copy)]
+enum OriginalDestination {
+    /// Connections diverted by TPROXY keep their original destination as the local
+    /// address.
+    LocalAddress,
+    /// NAT rules record the original destination, which is read with
+    /// `SO_ORIGINAL_DST`.
+    SoOriginalDst,
+}
+
+impl OriginalDestination {
+    fn get(self, stream: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
+        let addr = match self {
+            Self::LocalAddress => stream.local_addr()?,
+            Self::SoOriginalDst => get_so_original_dst(stream)?,
+        };
+        Ok(to_canonical_socket_addr(addr))
+    }
+}
+
+/// Starts TPROXY or REDIRECT TCP listeners on the addresses of the config.
+pub async fn start_transparent_tcp_servers(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    println!(
+        "Starting {} TCP server at {}",
+        &config.protocol, &config.bind_location
+    );
+
+    let original_destination = match config.protocol {
+        ServerProxyConfig::Tproxy { .. } => OriginalDestination::LocalAddress,
+        ServerProxyConfig::Redirect { .. } => OriginalDestination::SoOriginalDst,
+        _ => unreachable!("only tproxy and redirect servers are transparent"),
+    };
+    let protocol: Arc<str> = Arc::from(config.protocol.to_string());
+    let tcp_config = config
+        .tcp_settings
+        .clone()
+        .unwrap_or_else(TcpConfig::default);
+    let (bind_addresses, proxy_selector, dns_hijacker, sniffer) = prepare(config, &resolver)?;
+
+    let mut join_handles = Vec::with_capacity(bind_addresses.len());
+    for bind_address in bind_addresses {
+        let listener = match original_destination {
+            OriginalDestination::LocalAddress => new_transparent_tcp_listener(bind_address)?,
+            OriginalDestination::SoOriginalDst => {
+                new_tcp_listener(bind_address, 4096, None, tcp_config.mptcp, false)?
+            }
+        };
+        if tcp_config.fast_open {
+            set_tcp_fast_open_listen(&listener)?;
+        }
+        let proxy_selector = proxy_selector.clone();
+        let resolver = resolver.clone();
+        let tcp_config = tcp_config.clone();
+        let protocol = protocol.clone();
+        let dns_hijacker = dns_hijacker.clone();
+        join_handles.push(tokio::spawn(async move {
+            run_transparent_tcp_server(
+                listener,
+                bind_address,
+                protocol,
+                original_destination,
+                tcp_config,
+                proxy_selector,
+                resolver,
+                dns_hijacker,
+                sniffer,
+            )
+            .await;
+        }));
+    }
+
+    Ok(join_handles)
+}
+
+/// Starts TPROXY UDP listeners on the addresses of the config.
+pub async fn start_tproxy_udp_servers(
+    config: ServerConfig,
+    resolver: Arc<dyn Resolver>,
+) -> io::Result<Vec<JoinHandle<()>>> {
+    println!("Starting TPROXY UDP server at {}", &config.bind_location);
+
+    let (bind_addresses, proxy_selector, dns_hijacker, sniffer) = prepare(config, &resolver)?;
+
+    let mut join_handles = Vec::with_capacity(bind_addresses.len());
+    for bind_address in bind_addresses {
+        let socket = new_transparent_udp_listener(bind_address)?;
+        let proxy_selector = proxy_selector.clone();
+        let resolver = resolver.clone();
+        let dns_hijacker = dns_hijacker.clone();
+        join_handles.push(tokio::spawn(async move {
+            let result = run_tproxy_udp_server(
+                socket,
+                bind_address,
+                proxy_selector,
+                resolver,
+                dns_hijacker,
+                sniffer,
+            )
+            .await;
+            if let Err(e) = result {
+                error!("TPROXY UDP server at {bind_address} stopped: {e}");
+            }
+        }));
+    }
+
+    Ok(join_handles)
+}
+
+/// Returns the bind addresses, proxy selector, DNS hijacker and sniffer of a transparent
+/// server config.
+fn prepare(
+    config: ServerConfig,
+    resolver: &Arc<dyn Resolver>,
+) -> io::Result<(
+    Vec<SocketAddr>,
+    Arc<ClientProxySelector>,
+    Option<DnsHijacker>,
+    Option<Sniffer>,
+)> {
+    let ServerConfig {
+        bind_location,
+        protocol,
+        rules,
+        ..
+    } = config;
+
+    let bind_addresses = match bind_location {
+        BindLocation::Address(_) | BindLocation::Addresses { .. } | BindLocation::Systemd(_) => {
+            bind_location.to_socket_addrs()?
+        }
+        BindLocation::Path(_) => {
+            return Err(io::Error::other(
+                "Cannot listen on path, transparent proxies require an address",
+            ));
+        }
+    };
+
+    let rules = rules.map(ConfigSelection::unwrap_config).into_vec();
+    let proxy_selector = create_tcp_client_proxy_selector(rules, resolver.clone());
+
+    let (dns_hijack, sniff, sniff_override_destination) = match protocol {
+        ServerProxyConfig::Tproxy {
+            dns_hijack,
+            sniff,
+            sniff_override_destination,
+        }
+        | ServerProxyConfig::Redirect {
+            dns_hijack,
+            sniff,
+            sniff_override_destination,
+        } => (dns_hijack, sniff, sniff_override_destination),
+        _ => unreachable!("only tproxy and redirect servers are transparent"),
+    };
+    let dns_hijacker = dns_hijack.then(|| DnsHijacker::new(resolver.clone()));
+    let sniffer = sniff.then(|| Sniffer::new(sniff_override_destination));
+
+    Ok((bind_addresses, proxy_selector, dns_hijacker, sniffer))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_transparent_tcp_server(
+    listener: tokio::net::TcpListener,
+    bind_address: SocketAddr,
+    protocol: Arc<str>,
+    original_destination: OriginalDestination,
+    tcp_config: TcpConfig,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    dns_hijacker: Option<DnsHijacker>,
+    sniffer: Option<Sniffer>,
+) {
+    let listener_label: Arc<str> = Arc::from(format!("tcp://{bind_address}"));
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(v) => v,
+            Err(e) => {
+                error!("Accept failed: {e}");
+                continue;
+            }
+        };
+
+        let destination = match original_destination.get(&stream) {
+            Ok(destination) => destination,
+            Err(e) => {
+                error!("Failed to get original destination of {addr}: {e}");
+                continue;
+            }
+        };
+        if is_listener_address(destination, bind_address) {
+            // Connecting to the destination would loop back to this listener.
+            debug!("Ignoring direct connection from {addr} to transparent listener");
+            continue;
+        }
+
+        if let Err(e) = configure_tcp_stream(
+            &stream,
+            &tcp_config,
+            std::time::Duration::from_secs(300),
+            std::time::Duration::from_secs(60),
+        ) {
+            error!("Failed to set TCP options: {e}");
+        }
+
+        if destination.port() == DNS_PORT
+            && let Some(dns_hijacker) = &dns_hijacker
+        {
+            let dns_hijacker = dns_hijacker.clone();
+            tokio::spawn(async move {
+                if let Err(e) = dns_hijacker.serve_tcp(stream).await {
+                    debug!("Hijacked DNS connection {addr} -> {destination} ended: {e}");
+                }
+            });
+            continue;
+        }
+
+        let server_handler: Arc<dyn TcpServerHandler> = Arc::new(PortForwardServerHandler::new(
+            vec![to_net_location(destination)],
+            proxy_selector.clone(),
+        ));
+        let resolver = resolver.clone();
+        let inbound =
+            InboundContext::new(listener_label.clone(), protocol.clone(), Some(addr), None);
+
+        tokio::spawn(async move {
+            let result = match sniffer {
+                Some(sniffer) => {
+                    sniff_and_process_stream(
+                        stream,
+                        destination,
+                        sniffer,
+                        server_handler,
+                        resolver,
+                        inbound,
+                    )
+                    .await
+                }
+                None => process_stream(stream, server_handler, resolver, inbound).await,
+            };
+            if let Err(e) = result {
+                error!("{addr} -> {destination} finished with error: {e:?}");
+            } else {
+                debug!("{addr} -> {destination} finished successfully");
+            }
+        });
+    }
+}
+
+/// Reads the first bytes of `stream` to route it by its domain, then processes it with
+/// the bytes read put back in front.
+async fn sniff_and_process_stream(
+    mut stream: tokio::net::TcpStream,
+    destination: SocketAddr,
+    sniffer: Sniffer,
+    server_handler: Arc<dyn TcpServerHandler>,
+    resolver: Arc<dyn Resolver>,
+    mut inbound: InboundContext,
+) -> io::Result<()> {
+    let (data, routing_location) = sniffer.sniff(&mut stream, destination).await?;
+    if let Some(location) = &routing_location {
+        debug!("Sniffed {} for {destination}", location.location());
+    }
+    inbound.routing_location = routing_location;
+    let stream = PrefixedStream::new(data.into_boxed_slice(), Box::new(stream));
+    process_stream(stream, server_handler, resolver, inbound).await
+}
+
+async fn run_tproxy_udp_server(
+    socket: tokio::net::UdpSocket,
+    bind_address: SocketAddr,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    dns_hijacker: Option<DnsHijacker>,
+    sniffer: Option<Sniffer>,
+) -> io::Result<()> {
+    let mut flows: HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Vec<u8>>> = HashMap::new();
+    let mut buf = vec![0u8; UDP_BUFFER_LEN];
+
+    loop {
+        let (len, source, destination) = socket
+            .async_io(Interest::READABLE, || {
+                recv_with_original_destination(socket.as_raw_fd(), &mut buf)
+            })
+            .await?;
+        let Some(destination) = destination else {
+            debug!("Dropping UDP datagram from {source} without original destination");
+            continue;
+        };
+        if is_listener_address(destination, bind_address) {
+            debug!("Dropping UDP datagram from {source} sent directly to TPROXY listener");
+            continue;
+        }
+        let payload = buf[..len].to_vec();
+
+        if destination.port() == DNS_PORT
+            && let Some(dns_hijacker) = &dns_hijacker
+        {
+            tokio::spawn(answer_hijacked_query(
+                dns_hijacker.clone(),
+                source,
+                destination,
+                payload,
+            ));
+            continue;
+        }
+
+        let key = (source, destination);
+        if let Some(tx) = flows.get(&key) {
+            match tx.try_send(payload) {
+                Ok(()) => continue,
+                Err(mpsc::error::TrySendError::Full(_)) => {
+                    debug!("Dropping UDP datagram from {source} to {destination}, queue is full");
+                    continue;
+                }
+                Err(mpsc::error::TrySendError::Closed(payload)) => {
+                    flows.remove(&key);
+                    flows.retain(|_, tx| !tx.is_closed());
+                    start_udp_flow(
+                        &mut flows,
+                        key,
+                        payload,
+                        &proxy_selector,
+                        &resolver,
+                        sniffer,
+                    );
+                }
+            }
+        } else {
+            flows.retain(|_, tx| !tx.is_closed());
+            start_udp_flow(
+                &mut flows,
+                key,
+                payload,
+                &proxy_selector,
+                &resolver,
+                sniffer,
+            );
+        }
+    }
+}
+
+fn start_udp_flow(
+    flows: &mut HashMap<(SocketAddr, SocketAddr), mpsc::Sender<Vec<u8>>>,
+    (source, destination): (SocketAddr, SocketAddr),
+    first_payload: Vec<u8>,
+    proxy_selector: &Arc<ClientProxySelector>,
+    resolver: &Arc<dyn Resolver>,
+    sniffer: Option<Sniffer>,
+) {
+    let (tx, rx) = mpsc::channel(UDP_FLOW_CHANNEL_SIZE);
+    // The channel is empty, so this can't fail.
+    let _ = tx.try_send(first_payload);
+    flows.insert((source, destination), tx);
+
+    let proxy_selector = proxy_selector.clone();
+    let resolver = resolver.clone();
+    tokio::spawn(async move {
+        let result = run_udp_flow(source, destination, rx, proxy_selector, resolver, sniffer).await;
+        if let Err(e) = result {
+            debug!("UDP flow {source} -> {destination} finished with error: {e}");
+        }
+    });
+}
+
+/// Forwards datagrams from `source` to `destination` through the selected chain, and
+/// sends responses back to `source` from the address of `destination`.
+async fn run_udp_flow(
+    source: SocketAddr,
+    destination: SocketAddr,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+    sniffer: Option<Sniffer>,
+) -> io::Result<()> {
+    // Datagrams read while sniffing are sent once the chain is connected.
+    let mut pending = Vec::new();
+    let flow = match sniffer {
+        Some(sniffer) => {
+            sniff_udp_flow(sniffer.sniff_datagrams(destination), &mut rx, &mut pending).await
+        }
+        None => SniffedFlow::default(),
+    };
+    if let Some(location) = &flow.routing_location {
+        debug!("Sniffed {} for {destination}", location.location());
+    }
+    let location = flow
+        .routing_location
+        .unwrap_or_else(|| to_net_location(destination).into());
+
+    let decision = if flow.is_quic {
+        proxy_selector
+            .judge_quic(location, Some(source.ip()), &resolver)
+            .await?
+    } else {
+        proxy_selector
+            .judge_with_source(location, Some(source.ip()), &resolver)
+            .await?
+    };
+    let mut remote = match decision {
+        ConnectDecision::Allow {
+            chain_group,
+            remote_location,
+        } => {
+            chain_group
+                .connect_udp_bidirectional_with_source(
+                    &resolver,
+                    remote_location,
+                    Some(source.ip()),
+                )
+                .await?
+        }
+        ConnectDecision::Block => {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "destination blocked",
+            ));
+        }
+    };
+
+    for payload in pending {
+        send_message(&mut remote, &payload).await?;
+    }
+
+    let reply_socket = new_transparent_udp_reply_socket(destination)?;
+    let mut read_buf = vec![0u8; UDP_BUFFER_LEN];
+
+    loop {
+        tokio::select! {
+            payload = rx.recv() => {
+                let Some(payload) = payload else {
+                    return Ok(());
+                };
+                send_message(&mut remote, &payload).await?;
+            }
+            response = read_message(&mut remote, &mut read_buf) => {
+                let len = response?;
+                reply_socket.send_to(&read_buf[..len], source).await?;
+            }
+            _ = tokio::time::sleep(UDP_FLOW_IDLE_TIMEOUT) => {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Reads datagrams of a flow into `pending` until the sniffer has a result.
+async fn sniff_udp_flow(
+    mut sniffer: DatagramSniffer,
+    rx: &mut mpsc::Receiver<Vec<u8>>,
+    pending: &mut Vec<Vec<u8>>,
+) -> SniffedFlow {
+    loop {
+        let payload = match timeout_at(sniffer.deadline(), rx.recv()).await {
+            Ok(Some(payload)) => payload,
+            Ok(None) | Err(_) => return sniffer.finish(),
+        };
+        let flow = sniffer.push(&payload);
+        pending.push(payload);
+        if let Some(flow) = flow {
+            return flow;
+        }
+    }
+}
+
+/// Answers a hijacked DNS query from the address it was sent to.
+async fn answer_hijacked_query(
+    dns_hijacker: DnsHijacker,
+    source: SocketAddr,
+    destination: SocketAddr,
+    query: Vec<u8>,
+) {
+    let Some(response) = dns_hijacker.answer(&query).await else {
+        return;
+    };
+    let result = match new_transparent_udp_reply_socket(destination) {
+        Ok(socket) => socket.send_to(&response, source).await.map(|_| ()),
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        debug!("Failed to answer hijacked DNS query {source} -> {destination}: {e}");
+    }
+}
+
+async fn send_message(stream: &mut Box<dyn AsyncMessageStream>, data: &[u8]) -> io::Result<()> {
+    std::future::poll_fn(|cx| Pin::new(&mut **stream).poll_write_message(cx, data)).await?;
+    std::future::poll_fn(|cx| Pin::new(&mut **stream).poll_flush_message(cx)).await
+}
+
+async fn read_message(
+    stream: &mut Box<dyn AsyncMessageStream>,
+    buf: &mut [u8],
+) -> io::Result<usize> {
+    let mut read_buf = ReadBuf::new(buf);
+    std::future::poll_fn(|cx| Pin::new(&mut **stream).poll_read_message(cx, &mut read_buf)).await?;
+    Ok(read_buf.filled().len())
+}
+
+fn is_listener_address(destination: SocketAddr, bind_address: SocketAddr) -> bool {
+    destination.port() == bind_address.port()
+        && (bind_address.ip().is_unspecified() || destination.ip() == bind_address.ip())
+}
+
+fn to_net_location(addr: SocketAddr) -> NetLocation {
+    NetLocation::from_ip_addr(addr.ip(), addr.port())
+}
+
+fn new_transparent_tcp_listener(bind_address: SocketAddr) -> io::Result<tokio::net::TcpListener> {
+    let socket = new_transparent_socket(bind_address, Type::STREAM, Protocol::TCP)?;
+    socket.bind(&SockAddr::from(bind_address))?;
+    socket.listen(4096)?;
+
+    let std_listener: std::net::TcpListener = socket.into();
+    tokio::net::TcpListener::from_std(std_listener)
+}
+
+fn new_transparent_udp_listener(bind_address: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let socket = new_transparent_socket(bind_address, Type::DGRAM, Protocol::UDP)?;
+    if bind_address.is_ipv6() {
+        set_socket_option(&socket, libc::SOL_IPV6, libc::IPV6_RECVORIGDSTADDR)?;
+    } else {
+        set_socket_option(&socket, libc::SOL_IP, libc::IP_RECVORIGDSTADDR)?;
+    }
+    socket.bind(&SockAddr::from(bind_address))?;
+
+    let std_socket: std::net::UdpSocket = socket.into();
+    tokio::net::UdpSocket::from_std(std_socket)
+}
+
+/// Creates a UDP socket bound to the non-local address `destination`, to send responses
+/// that appear to come from it.
+fn new_transparent_udp_reply_socket(destination: SocketAddr) -> io::Result<tokio::net::UdpSocket> {
+    let socket = new_transparent_socket(destination, Type::DGRAM, Protocol::UDP)?;
+    socket.bind(&SockAddr::from(destination))?;
+
+    let std_socket: std::net::UdpSocket = socket.into();
+    tokio::net::UdpSocket::from_std(std_socket)
+}
+
+fn new_transparent_socket(address: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    let domain = if address.is_ipv6() {
+        Domain::IPV6
+    } else {
+        Domain::IPV4
+    };
+    let socket = Socket::new(domain, ty, Some(protocol))?;
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+
+    let result = if address.is_ipv6() {
+        set_socket_option(&socket, libc::SOL_IPV6, libc::IPV6_TRANSPARENT)
+    } else {
+        set_socket_option(&socket, libc::SOL_IP, libc::IP_TRANSPARENT)
+    };
+    result.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("failed to enable transparent proxying, CAP_NET_ADMIN is required: {e}"),
+        )
+    })?;
+
+    Ok(socket)
+}
+
+/// Enables a boolean socket option.
+fn set_socket_option(socket: &Socket, level: libc::c_int, name: libc::c_int) -> io::Result<()> {
+    let value: libc::c_int = 1;
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Returns the destination of a connection before it was redirected by NAT rules.
+fn get_so_original_dst(stream: &tokio::net::TcpStream) -> io::Result<SocketAddr> {
+    let (level, name) = if to_canonical_socket_addr(stream.local_addr()?).is_ipv6() {
+        (libc::SOL_IPV6, libc::IP6T_SO_ORIGINAL_DST)
+    } else {
+        (libc::SOL_IP, libc::SO_ORIGINAL_DST)
+    };
+
+    let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            level,
+            name,
+            &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if ret < 0 {
+        let e = io::Error::last_os_error();
+        return Err(io::Error::new(
+            e.kind(),
+            format!("failed to get original destination, was the connection redirected? {e}"),
+        ));
+    }
+
+    unsafe { read_socket_addr(&addr as *const libc::sockaddr_storage as *const u8) }
+        .ok_or_else(|| io::Error::other("unsupported original destination address family"))
+}
+
+/// Receives a datagram, returning its length, source address, and original destination
+/// address if it was diverted by TPROXY.
+fn recv_with_original_destination(
+    fd: RawFd,
+    buf: &mut [u8],
+) -> io::Result<(usize, SocketAddr, Option<SocketAddr>)> {
+    let mut source: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    // u64 elements keep the buffer aligned for cmsghdr.
+    let mut control = [0u64; 16];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_name = &mut source as *mut libc::sockaddr_storage as *mut libc::c_void;
+    msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    let len = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let source = unsafe { read_socket_addr(&source as *const libc::sockaddr_storage as *const u8) }
+        .ok_or_else(|| io::Error::other("unsupported source address family"))?;
+
+    let mut destination = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        let header = unsafe { &*cmsg };
+        if (header.cmsg_level == libc::SOL_IP && header.cmsg_type == libc::IP_ORIGDSTADDR)
+            || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == libc::IPV6_ORIGDSTADDR)
+        {
+            destination = unsafe { read_socket_addr(libc::CMSG_DATA(cmsg)) };
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    Ok((
+        len as usize,
+        to_canonical_socket_addr(source),
+        destination.map(to_canonical_socket_addr),
+    ))
+}
+
+/// Reads an IPv4 or IPv6 socket address.
+///
+/// # Safety
+/// `ptr` must point to a `sockaddr_in` or a `sockaddr_in6`, as indicated by its family.
+unsafe fn read_socket_addr(ptr: *const u8) -> Option<SocketAddr> {
+    let family = unsafe { std::ptr::read_unaligned(ptr as *const libc::sa_family_t) };
+    match family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { std::ptr::read_unaligned(ptr as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { std::ptr::read_unaligned(ptr as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_listener_address() {
+        let bind: SocketAddr = "0.0.0.0:12345".parse().unwrap();
+        assert!(is_listener_address(
+            "127.0.0.1:12345".parse().unwrap(),
+            bind
+        ));
+        assert!(!is_listener_address("1.2.3.4:443".parse().unwrap(), bind));
+
+        let bind: SocketAddr = "127.0.0.1:12345".parse().unwrap();
+        assert!(!is_listener_address("1.2.3.4:12345".parse().unwrap(), bind));
+    }
+
+    #[test]
+    fn test_read_socket_addr() {
+        let source: SocketAddr = "[::ffff:1.2.3.4]:53".parse().unwrap();
+        let sockaddr = SockAddr::from(source);
+        let addr = unsafe { read_socket_addr(sockaddr.as_ptr() as *const u8) }.unwrap();
+        assert_eq!(addr, source);
+        assert_eq!(
+            to_canonical_socket_addr(addr),
+            "1.2.3.4:53".parse().unwrap()
+        );
+
+        let source: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let sockaddr = SockAddr::from(source);
+        let addr = unsafe { read_socket_addr(sockaddr.as_ptr() as *const u8) }.unwrap();
+        assert_eq!(addr, source);
+    }
+}
@@ -1,14 +1,19 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use aws_lc_rs::digest::SHA224;
-use subtle::ConstantTimeEq;
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use crate::address::ResolvedLocation;
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
-use crate::config::ShadowsocksConfig;
+use crate::config::{ShadowsocksConfig, UserConfig};
+use crate::decoy_handler::DecoyHandler;
+use crate::http2_handler::PrefixedStream;
+use crate::mux::{is_mux_cool_location, spawn_mux_server_session};
+use crate::resolver::Resolver;
 use crate::shadowsocks::{
     DefaultKey, ShadowsocksCipher, ShadowsocksKey, ShadowsocksStream, ShadowsocksStreamType,
 };
@@ -17,8 +22,32 @@ use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
+use crate::user_store::{UserIndex, create_user_index, inline_user, set_authenticated_user};
 use crate::util::write_all;
 
+/// Length of the hex encoded SHA224 password hash.
+const PASSWORD_HASH_LEN: usize = 56;
+
+/// Trojan users by password hash.
+pub type TrojanUsers = UserIndex<HashMap<Box<[u8]>, UserConfig>>;
+
+/// Creates the users of a Trojan server from its password, which may be empty, and its
+/// user group.
+pub fn create_trojan_users(password: &str, user_group: Option<&str>) -> TrojanUsers {
+    let inline = (!password.is_empty())
+        .then(|| inline_user(String::new(), Some(password.to_string()), None))
+        .flatten();
+    create_user_index(inline, user_group, |users| {
+        users
+            .iter()
+            .filter_map(|user| {
+                let password = user.password.as_ref()?;
+                Some((create_password_hash(password), user.clone()))
+            })
+            .collect()
+    })
+}
+
 #[derive(Debug)]
 struct ShadowsocksData {
     cipher: ShadowsocksCipher,
@@ -27,33 +56,58 @@ struct ShadowsocksData {
 
 #[derive(Debug)]
 pub struct TrojanTcpHandler {
+    /// Password hash sent by the client handler. Empty when used as server handler.
     password_hash: Box<[u8]>,
+    /// Users accepted by the server handler. None when used as client handler.
+    users: Option<TrojanUsers>,
     shadowsocks_data: Option<ShadowsocksData>,
     /// Proxy selector for server handler use. None when used as client handler.
     proxy_selector: Option<Arc<ClientProxySelector>>,
+    /// Resolver for mux sub-connections of the server handler. None when used as client handler.
+    resolver: Option<Arc<dyn Resolver>>,
+    /// Decoy website for connections that don't start with a valid password hash.
+    decoy: Option<DecoyHandler>,
 }
 
 impl TrojanTcpHandler {
     /// Create a new handler for server use (with proxy_selector for routing)
     pub fn new_server(
-        password: &str,
+        users: TrojanUsers,
         shadowsocks_config: &Option<ShadowsocksConfig>,
         proxy_selector: Arc<ClientProxySelector>,
+        resolver: Arc<dyn Resolver>,
+        decoy: Option<DecoyHandler>,
     ) -> Self {
-        Self::new_inner(password, shadowsocks_config, Some(proxy_selector))
+        Self::new_inner(
+            Box::default(),
+            Some(users),
+            shadowsocks_config,
+            Some(proxy_selector),
+            Some(resolver),
+            decoy,
+        )
     }
 
     /// Create a new handler for client use (no proxy_selector needed)
     pub fn new_client(password: &str, shadowsocks_config: &Option<ShadowsocksConfig>) -> Self {
-        Self::new_inner(password, shadowsocks_config, None)
+        Self::new_inner(
+            create_password_hash(password),
+            None,
+            shadowsocks_config,
+            None,
+            None,
+            None,
+        )
     }
 
     fn new_inner(
-        password: &str,
+        password_hash: Box<[u8]>,
+        users: Option<TrojanUsers>,
         shadowsocks_config: &Option<ShadowsocksConfig>,
         proxy_selector: Option<Arc<ClientProxySelector>>,
+        resolver: Option<Arc<dyn Resolver>>,
+        decoy: Option<DecoyHandler>,
     ) -> Self {
-        let password_hash = create_password_hash(password);
         let shadowsocks_data = shadowsocks_config.as_ref().map(|config| match config {
             ShadowsocksConfig::Legacy {
                 cipher,
@@ -75,8 +129,11 @@ impl TrojanTcpHandler {
 
         Self {
             password_hash,
+            users,
             shadowsocks_data,
             proxy_selector,
+            resolver,
+            decoy,
         }
     }
 }
@@ -102,24 +159,46 @@ impl TcpServerHandler for TrojanTcpHandler {
             ));
         }
 
+        let users = self
+            .users
+            .as_ref()
+            .expect("Trojan server handler without users")
+            .get();
+
+        if let Some(ref decoy) = self.decoy {
+            // Keep what is read, so that the decoy sees the connection from the start.
+            let data = read_hash_line(&mut server_stream).await?;
+            let is_valid = data.len() >= PASSWORD_HASH_LEN + CRLF_BYTES.len()
+                && data[PASSWORD_HASH_LEN..PASSWORD_HASH_LEN + CRLF_BYTES.len()] == CRLF_BYTES
+                && users.contains_key(&data[..PASSWORD_HASH_LEN]);
+            if !is_valid {
+                return decoy.serve(server_stream, &data).await;
+            }
+            server_stream = Box::new(PrefixedStream::new(data.into_boxed_slice(), server_stream));
+        }
+
         let mut stream_reader = StreamReader::new_with_buffer_size(400);
 
         // read the entire line rather than exactly 56 bytes, so that we can masquerade as an HTTP server
         // and handle the request as if it were a HTTP request.
-        // TODO: implement http response
         let received_hash = stream_reader.read_line_bytes(&mut server_stream).await?;
-        if received_hash.len() != self.password_hash.len() {
-            return Err(std::io::Error::other(format!(
-                "Invalid password hash length, expected {}, got {}",
-                self.password_hash.len(),
-                received_hash.len()
-            )));
+        if received_hash.len() != PASSWORD_HASH_LEN {
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "Invalid password hash length, expected {PASSWORD_HASH_LEN}, got {}",
+                    received_hash.len()
+                ),
+            ));
         }
 
-        // Use constant-time comparison to prevent timing attacks
-        if self.password_hash.ct_eq(received_hash).unwrap_u8() == 0 {
-            return Err(std::io::Error::other("Invalid password hash"));
-        }
+        let Some(user) = users.get(received_hash) else {
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::PermissionDenied,
+                "Invalid password hash",
+            ));
+        };
+        set_authenticated_user(&user.name);
 
         let command_type = stream_reader.read_u8(&mut server_stream).await?;
 
@@ -146,22 +225,53 @@ impl TcpServerHandler for TrojanTcpHandler {
             )));
         }
 
+        let proxy_selector = self
+            .proxy_selector
+            .clone()
+            .expect("proxy_selector required for server handler");
+
+        if is_mux_cool_location(&remote_location) {
+            spawn_mux_server_session(
+                server_stream,
+                stream_reader.unparsed_data_owned(),
+                proxy_selector,
+                self.resolver
+                    .clone()
+                    .expect("resolver required for server handler"),
+            );
+            return Ok(TcpServerSetupResult::AlreadyHandled);
+        }
+
         Ok(TcpServerSetupResult::TcpForward {
             remote_location,
             stream: server_stream,
             need_initial_flush: false,
             connection_success_response: None,
             initial_remote_data: stream_reader.unparsed_data_owned(),
-            proxy_selector: self
-                .proxy_selector
-                .clone()
-                .expect("proxy_selector required for server handler"),
+            proxy_selector,
         })
     }
 }
 
 const CRLF_BYTES: [u8; 2] = [0x0d, 0x0a];
 
+/// Reads until the password hash and CRLF could have been received, or a line ends.
+async fn read_hash_line(stream: &mut Box<dyn AsyncStream>) -> std::io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(512);
+    while data.len() < PASSWORD_HASH_LEN + CRLF_BYTES.len() && !data.contains(&b'\n') {
+        if stream.read_buf(&mut data).await? == 0 {
+            if data.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "connection closed before any data",
+                ));
+            }
+            break;
+        }
+    }
+    Ok(data)
+}
+
 #[async_trait]
 impl TcpClientHandler for TrojanTcpHandler {
     async fn setup_client_tcp_stream(
@@ -1,4 +1,5 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::str;
 use std::sync::Arc;
@@ -9,20 +10,25 @@ use dashmap::DashMap;
 use log::{debug, error};
 use lru::LruCache;
 use tokio::io::AsyncWriteExt;
-use tokio::net::UdpSocket;
 use tokio::task::JoinHandle;
 use tokio::time::timeout;
 use tokio_util::sync::CancellationToken;
 
+use crate::access_control::{AccessControl, admit_quic};
 use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
 use crate::copy_bidirectional::copy_bidirectional_with_sizes;
 use crate::quic_stream::QuicStream;
 use crate::resolver::{Resolver, resolve_single_address};
+use crate::socket_util::DualStackUdpSocket;
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_server::setup_client_tcp_stream;
+use crate::udp_sessions::{self, Eviction, UdpSessionLimits};
+use crate::user_store::{UserIndex, create_user_index, inline_user};
 use crate::util::{allocate_vec, write_all};
+use crate::uuid_util::parse_uuid;
 
 const COMMAND_TYPE_AUTHENTICATE: u8 = 0x00;
 const COMMAND_TYPE_CONNECT: u8 = 0x01;
@@ -51,11 +57,36 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 
 type UdpSessionMap = Arc<DashMap<u16, UdpSession>>;
 
+/// Passwords by uuid.
+pub type TuicUsers = UserIndex<HashMap<Vec<u8>, String>>;
+
+/// Creates the users of a server from its uuid and password, which may be empty when
+/// the server has a user group.
+pub fn create_tuic_users(uuid: &str, password: &str, user_group: Option<&str>) -> TuicUsers {
+    let inline = if uuid.is_empty() {
+        None
+    } else {
+        inline_user(
+            String::new(),
+            Some(password.to_string()),
+            Some(uuid.to_string()),
+        )
+    };
+    create_user_index(inline, user_group, |users| {
+        users
+            .iter()
+            .filter_map(|user| {
+                let uuid = parse_uuid(user.user_id.as_ref()?).ok()?;
+                Some((uuid, user.password.clone()?))
+            })
+            .collect()
+    })
+}
+
 async fn process_connection(
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
-    uuid: &'static [u8],
-    password: &'static str,
+    users: Arc<TuicUsers>,
     conn: quinn::Incoming,
     zero_rtt_handshake: bool,
 ) -> std::io::Result<()> {
@@ -77,10 +108,13 @@ async fn process_connection(
 
     // Authentication with timeout - per sing-box reference, default 3 seconds.
     // This prevents malicious clients from holding connections open without authenticating.
-    match timeout(AUTH_TIMEOUT, auth_connection(&connection, uuid, password)).await {
+    match timeout(AUTH_TIMEOUT, auth_connection(&connection, &users)).await {
         Ok(Ok(())) => {}
         Ok(Err(e)) => {
             connection.close(0u32.into(), b"auth failed");
+            if auto_ban::is_rejected(&e) {
+                auto_ban::record_failure(connection.remote_address().ip());
+            }
             return Err(e);
         }
         Err(_elapsed) => {
@@ -102,6 +136,7 @@ async fn process_connection(
     // 2. multiple threads can modify different sessions concurrently
     // 3. the outer write lock is only needed for adding/removing sessions
     let udp_session_map = Arc::new(DashMap::new());
+    let session_limits = udp_sessions::limits();
 
     // Clone what we need for each loop before creating async blocks
     let heartbeat_connection = connection.clone();
@@ -131,6 +166,7 @@ async fn process_connection(
         uni_client_proxy_selector,
         uni_resolver,
         uni_udp_session_map,
+        session_limits,
         uni_cancel_token,
     );
 
@@ -139,6 +175,7 @@ async fn process_connection(
         client_proxy_selector,
         resolver,
         udp_session_map,
+        session_limits,
         datagram_cancel_token,
     );
 
@@ -187,20 +224,12 @@ async fn run_heartbeat_loop(
     }
 }
 
-async fn auth_connection(
+/// Waits for the client to authenticate on a unidirectional stream. Also used by Juicity,
+/// which authenticates the same way.
+pub async fn auth_connection(
     connection: &quinn::Connection,
-    uuid: &'static [u8],
-    password: &'static str,
+    users: &TuicUsers,
 ) -> std::io::Result<()> {
-    let mut expected_token_bytes = [0u8; 32];
-    connection
-        .export_keying_material(
-            &mut expected_token_bytes,
-            uuid.as_ref(),
-            password.as_bytes(),
-        )
-        .map_err(|e| std::io::Error::other(format!("Failed to export keying material: {e:?}")))?;
-
     // Loop until we receive an AUTH command.
     // Other commands (like DISSOCIATE) may arrive on uni streams before AUTH.
     // We discard non-AUTH streams and wait for the next one.
@@ -210,9 +239,10 @@ async fn auth_connection(
         let mut stream_reader = StreamReader::new_with_buffer_size(80);
         let tuic_version = stream_reader.read_u8(&mut recv_stream).await?;
         if tuic_version != 5 {
-            return Err(std::io::Error::other(format!(
-                "invalid tuic version: {tuic_version}"
-            )));
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid tuic version: {tuic_version}"),
+            ));
         }
         let command_type = stream_reader.read_u8(&mut recv_stream).await?;
 
@@ -223,15 +253,27 @@ async fn auth_connection(
         }
 
         let specified_uuid = stream_reader.read_slice(&mut recv_stream, 16).await?;
-        if specified_uuid != uuid {
-            return Err(std::io::Error::new(
+        let Some(password) = users.get().get(specified_uuid).cloned() else {
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::PermissionDenied,
                 format!("incorrect uuid: {specified_uuid:?}"),
             ));
-        }
+        };
+
+        let mut expected_token_bytes = [0u8; 32];
+        connection
+            .export_keying_material(
+                &mut expected_token_bytes,
+                specified_uuid,
+                password.as_bytes(),
+            )
+            .map_err(|e| {
+                std::io::Error::other(format!("Failed to export keying material: {e:?}"))
+            })?;
+
         let token_bytes = stream_reader.read_slice(&mut recv_stream, 32).await?;
         if token_bytes != expected_token_bytes {
-            return Err(std::io::Error::new(
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::PermissionDenied,
                 "incorrect token",
             ));
@@ -266,8 +308,15 @@ async fn run_bidirectional_loop(
         let client_proxy_selector = client_proxy_selector.clone();
         let resolver = resolver.clone();
         tokio::spawn(async move {
-            match process_tcp_stream(client_proxy_selector, resolver, send_stream, recv_stream)
-                .await
+            let source = conn.remote_address().ip();
+            match process_tcp_stream(
+                client_proxy_selector,
+                resolver,
+                source,
+                send_stream,
+                recv_stream,
+            )
+            .await
             {
                 Ok(()) => {}
                 Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
@@ -387,6 +436,7 @@ fn serialize_socket_addr(addr: &SocketAddr) -> Vec<u8> {
 async fn process_tcp_stream(
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    source: IpAddr,
     send: quinn::SendStream,
     mut recv: quinn::RecvStream,
 ) -> std::io::Result<()> {
@@ -418,11 +468,12 @@ async fn process_tcp_stream(
             client_proxy_selector,
             resolver,
             remote_location.clone(),
+            Some(source),
         ),
     );
 
     let mut client_stream = match setup_client_stream_future.await {
-        Ok(Ok(Some(s))) => s,
+        Ok(Ok(Some((s, _)))) => s,
         Ok(Ok(None)) => {
             // Must have been blocked.
             let _ = server_stream.shutdown().await;
@@ -471,7 +522,7 @@ async fn process_tcp_stream(
 }
 
 struct UdpSession {
-    send_socket: Arc<UdpSocket>,
+    send_socket: Arc<DualStackUdpSocket>,
     // we cache the last location in case of mid-session address changes, and
     // don't want to have to call ClientProxySelector::judge on every packet.
     last_location: NetLocation,
@@ -495,7 +546,7 @@ impl UdpSession {
     fn start_with_send_stream(
         assoc_id: u16,
         send_stream: quinn::SendStream,
-        client_socket: Arc<UdpSocket>,
+        client_socket: Arc<DualStackUdpSocket>,
         initial_location: NetLocation,
         initial_socket_addr: SocketAddr,
         override_local_write_location: Option<NetLocation>,
@@ -535,7 +586,7 @@ impl UdpSession {
     fn start_with_datagram(
         assoc_id: u16,
         connection: quinn::Connection,
-        client_socket: Arc<UdpSocket>,
+        client_socket: Arc<DualStackUdpSocket>,
         initial_location: NetLocation,
         initial_socket_addr: SocketAddr,
         override_local_write_location: Option<NetLocation>,
@@ -575,6 +626,7 @@ impl UdpSession {
     async fn resolve_address(
         &self,
         location: &NetLocation,
+        source: IpAddr,
         client_proxy_selector: &Arc<ClientProxySelector>,
         resolver: &Arc<dyn Resolver>,
     ) -> std::io::Result<(SocketAddr, bool)> {
@@ -585,7 +637,7 @@ impl UdpSession {
                     (self.last_socket_addr, false)
                 } else {
                     let action = client_proxy_selector
-                        .judge(location.clone().into(), resolver)
+                        .judge_with_source(location.clone().into(), Some(source), resolver)
                         .await?;
 
                     let updated_location = match action {
@@ -625,7 +677,7 @@ impl UdpSession {
 async fn run_udp_remote_to_local_stream_loop(
     assoc_id: u16,
     mut send_stream: quinn::SendStream,
-    socket: Arc<UdpSocket>,
+    socket: Arc<DualStackUdpSocket>,
     override_local_write_address: Option<NetLocation>,
     cancel_token: CancellationToken,
 ) -> std::io::Result<()> {
@@ -704,7 +756,7 @@ async fn run_udp_remote_to_local_stream_loop(
 async fn run_udp_remote_to_local_datagram_loop(
     assoc_id: u16,
     connection: quinn::Connection,
-    client_socket: Arc<UdpSocket>,
+    client_socket: Arc<DualStackUdpSocket>,
     override_local_write_location: Option<NetLocation>,
     cancel_token: CancellationToken,
 ) -> std::io::Result<()> {
@@ -823,34 +875,59 @@ async fn run_udp_remote_to_local_datagram_loop(
         }
     }
 }
+
+/// Removes the sessions that had no traffic for `idle_timeout`.
+fn remove_idle_sessions(udp_session_map: &UdpSessionMap, idle_timeout: Duration) {
+    udp_session_map.retain(|assoc_id, session| {
+        if session.last_activity.elapsed() > idle_timeout {
+            // Cancel the session's background task before removing
+            session.cancel_token.cancel();
+            debug!("Removing inactive UDP session {assoc_id}");
+            udp_sessions::record_eviction(Eviction::Idle);
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Removes the least recently used session to make room for a new one.
+fn evict_least_recently_used(udp_session_map: &UdpSessionMap) {
+    let Some(assoc_id) = udp_session_map
+        .iter()
+        .min_by_key(|entry| entry.last_activity)
+        .map(|entry| *entry.key())
+    else {
+        return;
+    };
+    if let Some((_, session)) = udp_session_map.remove(&assoc_id) {
+        session.cancel_token.cancel();
+        debug!("UDP session table full, evicting session {assoc_id}");
+        udp_sessions::record_eviction(Eviction::Limit);
+    }
+}
+
 async fn run_unidirectional_loop(
     connection: quinn::Connection,
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
     udp_session_map: UdpSessionMap,
+    session_limits: UdpSessionLimits,
     cancel_token: CancellationToken,
 ) -> std::io::Result<()> {
     // Spawn a cleanup task for UDP sessions that terminates when connection closes
     let cleanup_session_map = udp_session_map.clone();
     let cleanup_cancel_token = cancel_token.clone();
+    let idle_timeout = session_limits.idle_timeout_or(IDLE_TIMEOUT);
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+        let mut interval = tokio::time::interval(CLEANUP_INTERVAL.min(idle_timeout));
         loop {
             tokio::select! {
                 _ = cleanup_cancel_token.cancelled() => {
                     break;
                 }
                 _ = interval.tick() => {
-                    cleanup_session_map.retain(|assoc_id, session| {
-                        if session.last_activity.elapsed() > IDLE_TIMEOUT {
-                            // Cancel the session's background task before removing
-                            session.cancel_token.cancel();
-                            debug!("Removing inactive UDP session {assoc_id}");
-                            false
-                        } else {
-                            true
-                        }
-                    });
+                    remove_idle_sessions(&cleanup_session_map, idle_timeout);
                 }
             }
         }
@@ -886,6 +963,7 @@ async fn run_unidirectional_loop(
                 resolver,
                 recv_stream,
                 udp_session_map,
+                session_limits,
                 cancel_token,
             )
             .await
@@ -911,6 +989,7 @@ async fn process_uni_stream(
     resolver: Arc<dyn Resolver>,
     mut recv_stream: quinn::RecvStream,
     udp_session_map: UdpSessionMap,
+    session_limits: UdpSessionLimits,
     cancel_token: CancellationToken,
 ) -> std::io::Result<()> {
     let mut stream_reader = StreamReader::new_with_buffer_size(MAX_HEADER_LEN + 65535);
@@ -974,6 +1053,7 @@ async fn process_uni_stream(
         remote_location,
         payload_fragment,
         true,
+        session_limits,
         &cancel_token,
     )
     .await
@@ -995,6 +1075,7 @@ async fn process_udp_packet(
     remote_location: Option<NetLocation>,
     payload_fragment: &[u8],
     is_uni_stream: bool,
+    session_limits: UdpSessionLimits,
     cancel_token: &CancellationToken,
 ) -> std::io::Result<()> {
     if frag_total == 0 {
@@ -1011,6 +1092,8 @@ async fn process_udp_packet(
         )));
     }
 
+    let source = connection.remote_address().ip();
+
     let session = {
         match udp_session_map.get(&assoc_id) {
             Some(s) => s,
@@ -1026,7 +1109,7 @@ async fn process_udp_packet(
                 let remote_location = remote_location.clone().unwrap();
 
                 let action = client_proxy_selector
-                    .judge(remote_location.clone().into(), resolver)
+                    .judge_with_source(remote_location.clone().into(), Some(source), resolver)
                     .await;
 
                 let (_chain_group, updated_location) = match action {
@@ -1064,8 +1147,7 @@ async fn process_udp_packet(
                         (None, None)
                     };
 
-                // Use IPv6 dual-stack socket for direct UDP
-                let client_socket = crate::socket_util::new_udp_socket(true, None)?;
+                let client_socket = DualStackUdpSocket::new(&Default::default())?;
 
                 let session = if is_uni_stream {
                     // TODO: should we only have a single send stream?
@@ -1094,6 +1176,10 @@ async fn process_udp_packet(
                     )
                 };
 
+                if session_limits.is_full(udp_session_map.len()) {
+                    evict_least_recently_used(udp_session_map);
+                }
+
                 // it's possible that the session is already on the map since we last checked.
                 // TODO: why is there no way to get a Ref<_> from an Entry<_>? see if we can
                 // do better than converting into a RefMut<_> and then downgrading.
@@ -1116,7 +1202,7 @@ async fn process_udp_packet(
         let remote_location = remote_location.as_ref().unwrap();
 
         let (socket_addr, is_updated) = session
-            .resolve_address(remote_location, client_proxy_selector, resolver)
+            .resolve_address(remote_location, source, client_proxy_selector, resolver)
             .await
             .map_err(|e| {
                 std::io::Error::other(format!(
@@ -1209,7 +1295,7 @@ async fn process_udp_packet(
         let remote_location = remote_location.unwrap();
 
         let (socket_addr, is_updated) = session
-            .resolve_address(&remote_location, client_proxy_selector, resolver)
+            .resolve_address(&remote_location, source, client_proxy_selector, resolver)
             .await
             .map_err(|e| {
                 std::io::Error::other(format!(
@@ -1250,26 +1336,20 @@ async fn run_datagram_loop(
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
     udp_session_map: UdpSessionMap,
+    session_limits: UdpSessionLimits,
     cancel_token: CancellationToken,
 ) -> std::io::Result<()> {
     // Use LRU cache for fragment reassembly to prevent unbounded memory growth.
     let mut fragments: LruCache<u16, FragmentedPacket> =
         LruCache::new(NonZeroUsize::new(MAX_FRAGMENT_CACHE_SIZE).unwrap());
     let mut last_cleanup = std::time::Instant::now();
+    let idle_timeout = session_limits.idle_timeout_or(IDLE_TIMEOUT);
+    let cleanup_interval = CLEANUP_INTERVAL.min(idle_timeout);
 
     loop {
         let now = std::time::Instant::now();
-        if (now - last_cleanup) > CLEANUP_INTERVAL {
-            udp_session_map.retain(|assoc_id, session| {
-                if session.last_activity.elapsed() > IDLE_TIMEOUT {
-                    // Cancel the session's background task before removing
-                    session.cancel_token.cancel();
-                    debug!("Removing inactive UDP session {assoc_id}");
-                    false
-                } else {
-                    true
-                }
-            });
+        if (now - last_cleanup) > cleanup_interval {
+            remove_idle_sessions(&udp_session_map, idle_timeout);
             last_cleanup = now;
         }
 
@@ -1378,6 +1458,7 @@ async fn run_datagram_loop(
             remote_location,
             payload_fragment,
             false,
+            session_limits,
             &cancel_token,
         )
         .await
@@ -1391,18 +1472,20 @@ async fn run_datagram_loop(
 pub async fn start_tuic_server(
     bind_address: SocketAddr,
     quic_server_config: Arc<quinn::crypto::rustls::QuicServerConfig>,
-    uuid: &'static [u8],
-    password: &'static str,
+    users: Arc<TuicUsers>,
     client_proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
     num_endpoints: usize,
     zero_rtt_handshake: bool,
+    access_control: Option<Arc<AccessControl>>,
 ) -> std::io::Result<Vec<JoinHandle<()>>> {
     let mut join_handles = vec![];
     for _ in 0..num_endpoints {
         let quic_server_config = quic_server_config.clone();
         let resolver = resolver.clone();
         let client_proxy_selector = client_proxy_selector.clone();
+        let users = users.clone();
+        let access_control = access_control.clone();
 
         let join_handle = tokio::spawn(async move {
             let mut server_config = quinn::ServerConfig::with_crypto(quic_server_config);
@@ -1446,14 +1529,17 @@ pub async fn start_tuic_server(
             .unwrap();
 
             while let Some(conn) = endpoint.accept().await {
+                let Some(conn) = admit_quic(access_control.as_deref(), conn) else {
+                    continue;
+                };
                 let cloned_selector = client_proxy_selector.clone();
                 let cloned_resolver = resolver.clone();
+                let cloned_users = users.clone();
                 tokio::spawn(async move {
                     if let Err(e) = process_connection(
                         cloned_selector,
                         cloned_resolver,
-                        uuid,
-                        password,
+                        cloned_users,
                         conn,
                         zero_rtt_handshake,
                     )
@@ -0,0 +1,287 @@
+//! ICMP echo relay for the TUN server.
+//!
+//! In `relay` ICMP mode, the stack thread sends echo requests here instead of letting
+//! smoltcp answer them. Each request is routed like a connection to its destination,
+//! and pinged through the outbound of the matching rule. The echo reply is written to
+//! TUN once the destination replies, so pings report the real reachability and latency.
+//!
+//! Only direct outbounds and WireGuard (inside the tunnel) can carry ICMP. Pings routed
+//! through other outbounds are answered locally, and blocked pings are not answered.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use etherparse::PacketBuilder;
+use log::{debug, info};
+use smoltcp::wire::{IpProtocol, Ipv4Packet, Ipv6Packet};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use super::{PacketBuffer, socket_addr_to_net_location};
+use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::icmp_ping::{ECHO_HEADER_LEN, ICMPV4_ECHO_REQUEST, ICMPV6_ECHO_REQUEST};
+use crate::resolver::{Resolver, resolve_location};
+
+/// How long to wait for the echo reply of a relayed ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An ICMP echo request read from TUN.
+#[derive(Debug, PartialEq, Eq)]
+struct EchoRequest {
+    src: IpAddr,
+    dst: IpAddr,
+    identifier: u16,
+    sequence: u16,
+    payload: Vec<u8>,
+}
+
+impl EchoRequest {
+    /// Builds the IP packet of the echo reply to this request.
+    fn reply_packet(&self) -> io::Result<PacketBuffer> {
+        let mut packet = Vec::new();
+        let result = match (self.dst, self.src) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                let builder = PacketBuilder::ipv4(
+                    src.octets(),
+                    dst.octets(),
+                    64, // TTL
+                )
+                .icmpv4_echo_reply(self.identifier, self.sequence);
+                packet.reserve(builder.size(self.payload.len()));
+                builder.write(&mut packet, &self.payload)
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                let builder = PacketBuilder::ipv6(
+                    src.octets(),
+                    dst.octets(),
+                    64, // Hop limit
+                )
+                .icmpv6_echo_reply(self.identifier, self.sequence);
+                packet.reserve(builder.size(self.payload.len()));
+                builder.write(&mut packet, &self.payload)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "IP version mismatch between source and destination",
+                ));
+            }
+        };
+
+        result.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(packet)
+    }
+}
+
+/// Returns the IP version, source, destination and ICMP message of a raw IP packet.
+fn icmp_message(packet: &[u8]) -> Option<(bool, IpAddr, IpAddr, &[u8])> {
+    match packet.first()? >> 4 {
+        4 => {
+            let ip_packet = Ipv4Packet::new_checked(packet).ok()?;
+            if ip_packet.next_header() != IpProtocol::Icmp {
+                return None;
+            }
+            let src = IpAddr::from(ip_packet.src_addr().octets());
+            let dst = IpAddr::from(ip_packet.dst_addr().octets());
+            Some((false, src, dst, ip_packet.payload()))
+        }
+        6 => {
+            let ip_packet = Ipv6Packet::new_checked(packet).ok()?;
+            if ip_packet.next_header() != IpProtocol::Icmpv6 {
+                return None;
+            }
+            let src = IpAddr::from(ip_packet.src_addr().octets());
+            let dst = IpAddr::from(ip_packet.dst_addr().octets());
+            Some((true, src, dst, ip_packet.payload()))
+        }
+        _ => None,
+    }
+}
+
+/// Returns the source, destination and ICMP message of a raw IP packet containing an
+/// ICMP or ICMPv6 echo request.
+fn echo_request_message(packet: &[u8]) -> Option<(IpAddr, IpAddr, &[u8])> {
+    let (is_ipv6, src, dst, message) = icmp_message(packet)?;
+    if message.len() < ECHO_HEADER_LEN {
+        return None;
+    }
+    let request_type = if is_ipv6 {
+        ICMPV6_ECHO_REQUEST
+    } else {
+        ICMPV4_ECHO_REQUEST
+    };
+    if message[0] != request_type || message[1] != 0 {
+        return None;
+    }
+    Some((src, dst, message))
+}
+
+/// Returns true if a raw IP packet is an ICMP or ICMPv6 echo request.
+pub fn is_echo_request(packet: &[u8]) -> bool {
+    echo_request_message(packet).is_some()
+}
+
+/// Parses a raw IP packet containing an ICMP or ICMPv6 echo request.
+fn parse_echo_request(packet: &[u8]) -> Option<EchoRequest> {
+    let (src, dst, message) = echo_request_message(packet)?;
+    Some(EchoRequest {
+        src,
+        dst,
+        identifier: u16::from_be_bytes([message[4], message[5]]),
+        sequence: u16::from_be_bytes([message[6], message[7]]),
+        payload: message[ECHO_HEADER_LEN..].to_vec(),
+    })
+}
+
+/// Relays ICMP echo requests from the stack thread, and sends the echo replies back.
+pub async fn handle_icmp_packets(
+    mut from_stack_rx: UnboundedReceiver<PacketBuffer>,
+    to_stack_tx: UnboundedSender<PacketBuffer>,
+    proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
+) {
+    info!("Starting ICMP echo relay");
+
+    while let Some(packet) = from_stack_rx.recv().await {
+        let Some(request) = parse_echo_request(&packet) else {
+            continue;
+        };
+        let to_stack_tx = to_stack_tx.clone();
+        let proxy_selector = proxy_selector.clone();
+        let resolver = resolver.clone();
+
+        tokio::spawn(async move {
+            match relay_echo_request(&request, &proxy_selector, &resolver).await {
+                Ok(true) => match request.reply_packet() {
+                    Ok(reply) => {
+                        let _ = to_stack_tx.send(reply);
+                    }
+                    Err(e) => debug!("Failed to build echo reply to {}: {}", request.src, e),
+                },
+                Ok(false) => {
+                    debug!("ICMP: ping from {} to {} blocked", request.src, request.dst);
+                }
+                Err(e) => {
+                    debug!(
+                        "ICMP: ping from {} to {} failed: {}",
+                        request.src, request.dst, e
+                    );
+                }
+            }
+        });
+    }
+
+    info!("ICMP echo relay stopped");
+}
+
+/// Pings the destination of `request` through the outbound selected by the rules.
+/// Returns false if the destination is blocked and the request should not be answered.
+async fn relay_echo_request(
+    request: &EchoRequest,
+    proxy_selector: &ClientProxySelector,
+    resolver: &Arc<dyn Resolver>,
+) -> io::Result<bool> {
+    let target = socket_addr_to_net_location(SocketAddr::new(request.dst, 0));
+    let decision = proxy_selector
+        .judge_with_source(target.into(), Some(request.src), resolver)
+        .await?;
+    let ConnectDecision::Allow {
+        chain_group,
+        mut remote_location,
+    } = decision
+    else {
+        return Ok(false);
+    };
+
+    // Fake IPs are routed by their domain, which is pinged at its real address.
+    let ip = resolve_location(&mut remote_location, resolver).await?.ip();
+    let ping = chain_group.ping(resolver, ip, &request.payload, Some(request.src));
+    match tokio::time::timeout(PING_TIMEOUT, ping).await {
+        Ok(Ok(())) => Ok(true),
+        Ok(Err(e)) if e.kind() == io::ErrorKind::Unsupported => {
+            debug!("ICMP: answering ping to {} locally: {}", request.dst, e);
+            Ok(true)
+        }
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("no echo reply from {ip}"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn echo_request_packet(src: IpAddr, dst: IpAddr, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        match (src, dst) {
+            (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                PacketBuilder::ipv4(src.octets(), dst.octets(), 64)
+                    .icmpv4_echo_request(7, 42)
+                    .write(&mut packet, payload)
+                    .unwrap();
+            }
+            (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                PacketBuilder::ipv6(src.octets(), dst.octets(), 64)
+                    .icmpv6_echo_request(7, 42)
+                    .write(&mut packet, payload)
+                    .unwrap();
+            }
+            _ => unreachable!(),
+        }
+        packet
+    }
+
+    #[test]
+    fn test_parse_echo_request() {
+        let src: IpAddr = "10.0.0.2".parse().unwrap();
+        let dst: IpAddr = "1.1.1.1".parse().unwrap();
+        let packet = echo_request_packet(src, dst, b"ping");
+
+        assert!(is_echo_request(&packet));
+        assert_eq!(
+            parse_echo_request(&packet),
+            Some(EchoRequest {
+                src,
+                dst,
+                identifier: 7,
+                sequence: 42,
+                payload: b"ping".to_vec(),
+            })
+        );
+        assert!(!is_echo_request(&packet[..20]));
+    }
+
+    #[test]
+    fn test_ipv4_echo_reply() {
+        let src: IpAddr = "10.0.0.2".parse().unwrap();
+        let dst: IpAddr = "1.1.1.1".parse().unwrap();
+        let request = parse_echo_request(&echo_request_packet(src, dst, b"ping")).unwrap();
+
+        let reply = request.reply_packet().unwrap();
+        // Replies are not relayed again.
+        assert!(!is_echo_request(&reply));
+        let (is_ipv6, reply_src, reply_dst, message) = icmp_message(&reply).unwrap();
+        assert!(!is_ipv6);
+        assert_eq!((reply_src, reply_dst), (dst, src));
+        assert_eq!(&message[..2], &[0, 0]);
+        assert_eq!(&message[4..], &[0, 7, 0, 42, b'p', b'i', b'n', b'g']);
+    }
+
+    #[test]
+    fn test_ipv6_echo_reply() {
+        let src: IpAddr = "fd00::2".parse().unwrap();
+        let dst: IpAddr = "2001:db8::1".parse().unwrap();
+        let request = parse_echo_request(&echo_request_packet(src, dst, b"ping")).unwrap();
+
+        let reply = request.reply_packet().unwrap();
+        let (is_ipv6, reply_src, reply_dst, message) = icmp_message(&reply).unwrap();
+        assert!(is_ipv6);
+        assert_eq!((reply_src, reply_dst), (dst, src));
+        assert_eq!(&message[..2], &[129, 0]);
+        assert_eq!(&message[4..], &[0, 7, 0, 42, b'p', b'i', b'n', b'g']);
+    }
+}
@@ -1,7 +1,8 @@
 //! TUN device support for shoes.
 //!
 //! This module provides VPN functionality by accepting IP packets from a TUN
-//! device and routing TCP/UDP traffic through configured proxy chains.
+//! device and routing TCP/UDP traffic through configured proxy chains. ICMP echo
+//! requests are answered locally, or relayed to their destination.
 //!
 //! # Architecture
 //!
@@ -20,6 +21,9 @@
 //! - **Linux**: Creates TUN device with specified name/address. Requires root
 //!   privileges or `CAP_NET_ADMIN` capability.
 //!
+//! - **macOS**: Creates a utun device with the specified address. Requires root
+//!   privileges. Routes to the device must be added separately, e.g. with `route add`.
+//!
 //! - **Android**: Accepts raw FD from `VpnService.Builder.establish()`. The
 //!   VPN configuration (routes, DNS, etc.) is handled by the Android VpnService.
 //!   You must pass the FD via `TunServerConfig::raw_fd()`.
@@ -28,6 +32,7 @@
 //!   Use `TunServerConfig::packet_information(true)` if using the socket FD
 //!   directly, or `false` if using the readPackets/writePackets API.
 
+mod icmp_handler;
 mod tcp_conn;
 mod tcp_stack_direct;
 mod tun_server;
@@ -51,14 +56,17 @@ use std::os::unix::io::IntoRawFd;
 use std::sync::Arc;
 
 use log::{debug, info, warn};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::{mpsc, oneshot};
 use tokio::task::JoinHandle;
 
 use crate::address::{Address, NetLocation};
 use crate::client_proxy_selector::ClientProxySelector;
-use crate::config::TunConfig;
+use crate::config::{TunConfig, TunIcmpMode};
 use crate::config::selection::ConfigSelection;
+use crate::dns_server::{DNS_PORT, DnsHijacker};
 use crate::resolver::{NativeResolver, Resolver};
+use crate::sniff::Sniffer;
 use crate::tcp::tcp_client_handler_factory::create_tcp_client_proxy_selector;
 
 use tcp_stack_direct::{NewTcpConnection, TcpStackDirect};
@@ -81,28 +89,46 @@ pub async fn run_tun_server(
     mut shutdown_rx: oneshot::Receiver<()>,
 ) -> std::io::Result<()> {
     info!(
-        "Starting TUN server (direct mode): mtu={}, tcp={}, udp={}, icmp={}",
-        config.mtu, config.tcp_enabled, config.udp_enabled, config.icmp_enabled
+        "Starting TUN server (direct mode): mtu={}, tcp={}, udp={}, icmp={}, icmp_mode={:?}, dns_hijack={}, sniff={}",
+        config.mtu,
+        config.tcp_enabled,
+        config.udp_enabled,
+        config.icmp_enabled,
+        config.icmp_mode,
+        config.dns_hijack,
+        config.sniff
     );
 
-    let fd = if let Some(fd) = config.raw_fd {
+    let dns_hijacker = config
+        .dns_hijack
+        .then(|| DnsHijacker::new(resolver.clone()));
+    let sniffer = config
+        .sniff
+        .then(|| Sniffer::new(config.sniff_override_destination));
+
+    let (fd, packet_information) = if let Some(fd) = config.raw_fd {
         info!("Using provided raw FD: {}", fd);
-        fd
+        (fd, config.packet_information)
     } else {
         let tun_device = config.create_sync_device()?;
         let fd = tun_device.into_raw_fd();
         info!("Created TUN device with FD: {}", fd);
-        fd
+        // Reads and writes on the utun fd always include the packet information header.
+        (fd, cfg!(target_os = "macos"))
     };
 
     let mtu = config.mtu as usize;
 
     // Create the direct TCP stack (runs smoltcp in dedicated thread with select())
-    let mut tcp_stack = TcpStackDirect::new(fd, mtu);
+    let icmp_mode = config.icmp_enabled.then_some(config.icmp_mode);
+    let mut tcp_stack = TcpStackDirect::new(fd, mtu, packet_information, icmp_mode);
 
     // Get UDP receiver (stack thread filters UDP and sends here)
     let udp_from_stack_rx = tcp_stack.take_udp_rx().expect("udp_rx already taken");
 
+    // Get ICMP receiver (stack thread sends echo requests to relay here)
+    let icmp_from_stack_rx = tcp_stack.take_icmp_rx().expect("icmp_rx already taken");
+
     // Channel for sending UDP responses back (stack thread will write to TUN)
     let (udp_to_stack_tx, udp_to_stack_rx) = mpsc::unbounded_channel::<PacketBuffer>();
     tcp_stack.set_udp_response_tx(udp_to_stack_rx);
@@ -113,6 +139,7 @@ pub async fn run_tun_server(
     let tcp_task: Option<JoinHandle<()>> = if config.tcp_enabled {
         let proxy_selector = proxy_selector.clone();
         let resolver = resolver.clone();
+        let dns_hijacker = dns_hijacker.clone();
 
         Some(tokio::spawn(async move {
             info!("Starting TCP connection handler");
@@ -120,16 +147,33 @@ pub async fn run_tun_server(
             while let Some(new_conn) = tcp_conn_rx.recv().await {
                 let proxy_selector = proxy_selector.clone();
                 let resolver = resolver.clone();
+                let dns_hijacker = dns_hijacker.clone();
 
                 tokio::spawn(async move {
                     let remote_addr = new_conn.remote_addr;
+
+                    if remote_addr.port() == DNS_PORT
+                        && let Some(dns_hijacker) = dns_hijacker
+                    {
+                        if let Err(e) = dns_hijacker.serve_tcp(new_conn.connection).await {
+                            debug!("Hijacked DNS connection to {} failed: {}", remote_addr, e);
+                        }
+                        return;
+                    }
+
                     let target = socket_addr_to_net_location(remote_addr);
 
                     debug!("Handling TCP connection to {:?}", target);
 
-                    if let Err(e) =
-                        handle_tcp_connection(new_conn.connection, target, proxy_selector, resolver)
-                            .await
+                    if let Err(e) = handle_tcp_connection(
+                        new_conn.connection,
+                        remote_addr,
+                        target,
+                        proxy_selector,
+                        resolver,
+                        sniffer,
+                    )
+                    .await
                     {
                         debug!("TCP connection to {} failed: {}", remote_addr, e);
                     }
@@ -142,12 +186,39 @@ pub async fn run_tun_server(
         None
     };
 
+    // Echo replies are written to TUN like UDP responses.
+    let icmp_task = if icmp_mode == Some(TunIcmpMode::Relay) {
+        let to_stack_tx = udp_to_stack_tx.clone();
+        let proxy_selector = proxy_selector.clone();
+        let resolver = resolver.clone();
+
+        Some(tokio::spawn(async move {
+            icmp_handler::handle_icmp_packets(
+                icmp_from_stack_rx,
+                to_stack_tx,
+                proxy_selector,
+                resolver,
+            )
+            .await;
+        }))
+    } else {
+        None
+    };
+
     let udp_task = if config.udp_enabled {
         let proxy_selector = proxy_selector.clone();
         let resolver = resolver.clone();
 
         Some(tokio::spawn(async move {
-            handle_udp_packets(udp_from_stack_rx, udp_to_stack_tx, proxy_selector, resolver).await;
+            handle_udp_packets(
+                udp_from_stack_rx,
+                udp_to_stack_tx,
+                proxy_selector,
+                resolver,
+                dns_hijacker,
+                sniffer,
+            )
+            .await;
         }))
     } else {
         None
@@ -176,6 +247,9 @@ pub async fn run_tun_server(
     if let Some(t) = udp_task {
         t.abort();
     }
+    if let Some(t) = icmp_task {
+        t.abort();
+    }
 
     // tcp_stack is dropped here, which stops the stack thread
 
@@ -193,14 +267,27 @@ fn socket_addr_to_net_location(addr: SocketAddr) -> NetLocation {
 }
 
 /// Handle a TCP connection by forwarding it through the proxy chain.
+///
+/// With a sniffer, the connection is routed by the domain found in its first bytes,
+/// which are then sent to the remote first.
 async fn handle_tcp_connection(
     mut connection: tcp_conn::TcpConnection,
+    remote_addr: SocketAddr,
     target: NetLocation,
     proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    sniffer: Option<Sniffer>,
 ) -> std::io::Result<()> {
+    let (initial_data, routing_location) = match sniffer {
+        Some(sniffer) => sniffer.sniff(&mut connection, remote_addr).await?,
+        None => (Vec::new(), None),
+    };
+    if let Some(location) = &routing_location {
+        debug!("TCP: sniffed {} for {}", location.location(), remote_addr);
+    }
+
     let decision = proxy_selector
-        .judge(target.into(), &resolver)
+        .judge(routing_location.unwrap_or_else(|| target.into()), &resolver)
         .await?;
 
     match decision {
@@ -221,6 +308,9 @@ async fn handle_tcp_connection(
                     );
 
                     let mut remote = setup_result.client_stream;
+                    if !initial_data.is_empty() {
+                        remote.write_all(&initial_data).await?;
+                    }
                     let result = tokio::io::copy_bidirectional(&mut connection, &mut remote).await;
 
                     match result {
@@ -261,13 +351,22 @@ async fn handle_udp_packets(
     to_stack_tx: mpsc::UnboundedSender<PacketBuffer>,
     proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    dns_hijacker: Option<DnsHijacker>,
+    sniffer: Option<Sniffer>,
 ) {
     info!("Starting UDP handler (session-based)");
 
     let udp_handler = udp_handler::UdpHandler::new(from_stack_rx, to_stack_tx);
     let (reader, writer) = udp_handler.split();
 
-    let manager = TunUdpManager::new(reader, writer, proxy_selector, resolver);
+    let manager = TunUdpManager::new(
+        reader,
+        writer,
+        proxy_selector,
+        resolver,
+        dns_hijacker,
+        sniffer,
+    );
 
     if let Err(e) = manager.run().await {
         warn!("UDP handler error: {}", e);
@@ -304,6 +403,10 @@ pub async fn run_tun_from_config(
         .tcp_enabled(config.tcp_enabled)
         .udp_enabled(config.udp_enabled)
         .icmp_enabled(config.icmp_enabled)
+        .icmp_mode(config.icmp_mode)
+        .dns_hijack(config.dns_hijack)
+        .sniff(config.sniff)
+        .sniff_override_destination(config.sniff_override_destination)
         .close_fd_on_drop(close_fd_on_drop);
 
     if let Some(ref name) = config.device_name {
@@ -330,7 +433,7 @@ pub async fn run_tun_from_config(
 
     let rules = config.rules.map(ConfigSelection::unwrap_config).into_vec();
     let resolver: Arc<dyn Resolver> = Arc::new(NativeResolver::new());
-    let client_proxy_selector = Arc::new(create_tcp_client_proxy_selector(rules, resolver.clone()));
+    let client_proxy_selector = create_tcp_client_proxy_selector(rules, resolver.clone());
 
     run_tun_server(
         tun_server_config,
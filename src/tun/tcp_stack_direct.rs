@@ -18,7 +18,7 @@ use std::{
     time::Duration,
 };
 
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 
 use log::{debug, error, info, trace, warn};
 use smoltcp::{
@@ -35,7 +35,9 @@ use smoltcp::{
 };
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
+use super::icmp_handler::is_echo_request;
 use super::tcp_conn::{TcpConnection, TcpConnectionControl};
+use crate::config::TunIcmpMode;
 
 pub type PacketBuffer = Vec<u8>;
 
@@ -43,6 +45,9 @@ pub type PacketBuffer = Vec<u8>;
 /// Each buffer has capacity ~65536, so 64 * 65536 = 4MB max.
 const BUFFER_POOL_MAX_SIZE: usize = 64;
 
+/// Length of the packet information header that utun devices prefix packets with.
+const PACKET_INFORMATION_LEN: usize = 4;
+
 static BUFFER_POOL: LazyLock<Mutex<Vec<BytesMut>>> = LazyLock::new(|| Mutex::new(Vec::new()));
 
 /// Pooled buffer that returns to pool on drop instead of deallocating.
@@ -125,6 +130,8 @@ pub struct TcpStackDirect {
     running: Arc<AtomicBool>,
     /// Receiver for UDP packets (filtered from TUN by the stack thread)
     udp_rx: Option<UnboundedReceiver<PacketBuffer>>,
+    /// Receiver for ICMP echo requests to relay (filtered from TUN by the stack thread)
+    icmp_rx: Option<UnboundedReceiver<PacketBuffer>>,
     /// Shared state with the stack thread
     shared_state: Arc<Mutex<SharedState>>,
     /// TUN file descriptor (owned, will be closed on drop)
@@ -155,11 +162,19 @@ impl TcpStackDirect {
     /// # Arguments
     /// * `fd` - Raw file descriptor for the TUN device
     /// * `mtu` - Maximum transmission unit
+    /// * `packet_information` - Whether packets on the fd have a packet information header
+    /// * `icmp_mode` - How ICMP echo requests are answered, or None to drop ICMP packets
     ///
     /// This spawns a dedicated OS thread for running the smoltcp interface.
     /// The thread uses `select()` on the fd for efficient event-driven I/O.
-    pub fn new(fd: RawFd, mtu: usize) -> Self {
+    pub fn new(
+        fd: RawFd,
+        mtu: usize,
+        packet_information: bool,
+        icmp_mode: Option<TunIcmpMode>,
+    ) -> Self {
         let (udp_tx, udp_rx) = mpsc::unbounded_channel();
+        let (icmp_tx, icmp_rx) = mpsc::unbounded_channel();
 
         let running = Arc::new(AtomicBool::new(true));
         let shared_state = Arc::new(Mutex::new(SharedState {
@@ -175,7 +190,16 @@ impl TcpStackDirect {
                 .name("shoes-smoltcp-direct".to_owned())
                 .spawn(move || {
                     let result = panic::catch_unwind(AssertUnwindSafe(|| {
-                        run_direct_stack_thread(fd, mtu, udp_tx, running.clone(), shared_state);
+                        run_direct_stack_thread(
+                            fd,
+                            mtu,
+                            packet_information,
+                            icmp_mode,
+                            udp_tx,
+                            icmp_tx,
+                            running.clone(),
+                            shared_state,
+                        );
                     }));
 
                     match result {
@@ -206,6 +230,7 @@ impl TcpStackDirect {
             stack_thread,
             running,
             udp_rx: Some(udp_rx),
+            icmp_rx: Some(icmp_rx),
             shared_state,
             tun_fd: fd,
         }
@@ -216,6 +241,11 @@ impl TcpStackDirect {
         self.udp_rx.take()
     }
 
+    /// Take the receiver for ICMP echo requests to relay (filtered from TUN by the stack).
+    pub fn take_icmp_rx(&mut self) -> Option<UnboundedReceiver<PacketBuffer>> {
+        self.icmp_rx.take()
+    }
+
     /// Set the channel for UDP responses to write back to TUN.
     pub fn set_udp_response_tx(&mut self, rx: UnboundedReceiver<PacketBuffer>) {
         if let Ok(mut state) = self.shared_state.lock() {
@@ -242,14 +272,16 @@ impl TcpStackDirect {
 struct DirectDevice {
     fd: RawFd,
     mtu: usize,
+    packet_information: bool,
     pending_rx: Option<PooledBuffer>,
 }
 
 impl DirectDevice {
-    fn new(fd: RawFd, mtu: usize) -> Self {
+    fn new(fd: RawFd, mtu: usize, packet_information: bool) -> Self {
         Self {
             fd,
             mtu,
+            packet_information,
             pending_rx: None,
         }
     }
@@ -265,7 +297,12 @@ impl DirectDevice {
         buffer.resize(self.mtu + 4, 0);
 
         match read_nonblocking(self.fd, &mut buffer) {
-            Ok(n) if n > 0 => {
+            Ok(n) if self.packet_information && n > PACKET_INFORMATION_LEN => {
+                buffer.truncate(n);
+                buffer.advance(PACKET_INFORMATION_LEN);
+                Some(buffer)
+            }
+            Ok(n) if !self.packet_information && n > 0 => {
                 buffer.truncate(n);
                 Some(buffer)
             }
@@ -280,7 +317,21 @@ impl DirectDevice {
 
     /// Write a packet to TUN.
     fn write_packet(&self, data: &[u8]) -> io::Result<()> {
-        write_all(self.fd, data)
+        if self.packet_information {
+            let mut packet = Vec::with_capacity(PACKET_INFORMATION_LEN + data.len());
+            packet.extend_from_slice(&packet_information_header(data));
+            packet.extend_from_slice(data);
+            write_all(self.fd, &packet)
+        } else {
+            write_all(self.fd, data)
+        }
+    }
+
+    fn tx_token(&self) -> DirectTxToken {
+        DirectTxToken {
+            fd: self.fd,
+            packet_information: self.packet_information,
+        }
     }
 }
 
@@ -294,7 +345,7 @@ impl Device for DirectDevice {
     ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
         if let Some(buffer) = self.pending_rx.take() {
             let rx = DirectRxToken { buffer };
-            let tx = DirectTxToken { fd: self.fd };
+            let tx = self.tx_token();
             Some((rx, tx))
         } else {
             None
@@ -302,7 +353,7 @@ impl Device for DirectDevice {
     }
 
     fn transmit(&mut self, _timestamp: SmolInstant) -> Option<Self::TxToken<'_>> {
-        Some(DirectTxToken { fd: self.fd })
+        Some(self.tx_token())
     }
 
     fn capabilities(&self) -> DeviceCapabilities {
@@ -334,6 +385,7 @@ impl RxToken for DirectRxToken {
 
 struct DirectTxToken {
     fd: RawFd,
+    packet_information: bool,
 }
 
 impl TxToken for DirectTxToken {
@@ -341,8 +393,17 @@ impl TxToken for DirectTxToken {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buffer = vec![0u8; len];
-        let result = f(&mut buffer);
+        let offset = if self.packet_information {
+            PACKET_INFORMATION_LEN
+        } else {
+            0
+        };
+        let mut buffer = vec![0u8; offset + len];
+        let result = f(&mut buffer[offset..]);
+        if self.packet_information {
+            let header = packet_information_header(&buffer[offset..]);
+            buffer[..offset].copy_from_slice(&header);
+        }
 
         if let Err(e) = write_all(self.fd, &buffer) {
             warn!("Failed to write to TUN: {}", e);
@@ -359,10 +420,14 @@ const MAX_PACKET_BATCH: usize = 64; // Process more packets per poll iteration
 const MAX_CONCURRENT_CONNECTIONS: usize = 1024; // Limit concurrent connections like gvisor
 
 /// Run the direct smoltcp stack thread.
+#[allow(clippy::too_many_arguments)]
 fn run_direct_stack_thread(
     fd: RawFd,
     mtu: usize,
+    packet_information: bool,
+    icmp_mode: Option<TunIcmpMode>,
     udp_tx: UnboundedSender<PacketBuffer>,
+    icmp_tx: UnboundedSender<PacketBuffer>,
     running: Arc<AtomicBool>,
     shared_state: Arc<Mutex<SharedState>>,
 ) {
@@ -374,7 +439,7 @@ fn run_direct_stack_thread(
         return;
     }
 
-    let mut device = DirectDevice::new(fd, mtu);
+    let mut device = DirectDevice::new(fd, mtu, packet_information);
 
     let mut iface_config = InterfaceConfig::new(HardwareAddress::Ip);
     iface_config.random_seed = rand::random();
@@ -418,7 +483,7 @@ fn run_direct_stack_thread(
     info!("smoltcp direct stack thread started, entering main loop");
 
     while running.load(Ordering::Relaxed) {
-        // Checks for UDP responses to write to TUN.
+        // Checks for UDP responses and ICMP echo replies to write to TUN.
         if let Ok(mut state) = shared_state.try_lock()
             && let Some(ref mut udp_rx) = state.udp_response_rx
         {
@@ -499,10 +564,19 @@ fn run_direct_stack_thread(
 
                         tcp_packets.push(pkt);
                     }
-                    IpProtocol::Icmp | IpProtocol::Icmpv6 => {
-                        // ICMP goes to smoltcp immediately
-                        tcp_packets.push(pkt);
-                    }
+                    IpProtocol::Icmp | IpProtocol::Icmpv6 => match icmp_mode {
+                        Some(TunIcmpMode::Relay) if is_echo_request(&pkt) => {
+                            // Relayed echo requests go to tokio, which writes the replies
+                            let _ = icmp_tx.send(pkt.to_vec());
+                        }
+                        Some(_) => {
+                            // Other ICMP goes to smoltcp immediately
+                            tcp_packets.push(pkt);
+                        }
+                        None => {
+                            trace!("dropping ICMP packet, ICMP is disabled");
+                        }
+                    },
                     IpProtocol::Udp => {
                         // UDP goes to tokio - convert to Vec since it leaves our pool
                         let _ = udp_tx.send(pkt.to_vec());
@@ -845,6 +919,16 @@ fn should_filter_packet(packet: &[u8]) -> bool {
     }
 }
 
+/// Returns the packet information header for an outgoing packet, which holds the address
+/// family of the packet in network byte order.
+fn packet_information_header(packet: &[u8]) -> [u8; PACKET_INFORMATION_LEN] {
+    let family = match packet.first().map(|b| b >> 4) {
+        Some(6) => libc::AF_INET6,
+        _ => libc::AF_INET,
+    };
+    (family as u32).to_be_bytes()
+}
+
 /// Set a file descriptor to non-blocking mode (call once at startup).
 fn set_nonblocking(fd: RawFd) -> io::Result<()> {
     let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
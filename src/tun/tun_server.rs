@@ -11,6 +11,17 @@
 //!     .netmask("255.255.255.0".parse().unwrap());
 //! ```
 //!
+//! ## macOS
+//! On macOS, a utun device is created. The name must be `utunN`, and is picked by the
+//! system when not set:
+//! ```ignore
+//! let config = TunServerConfig::new()
+//!     .tun_name("utun8")
+//!     .address("10.0.0.1".parse().unwrap())
+//!     .destination("10.0.0.1".parse().unwrap())
+//!     .netmask("255.255.255.0".parse().unwrap());
+//! ```
+//!
 //! ## Android
 //! On Android, you must provide the FD from `VpnService.Builder.establish()`:
 //! ```ignore
@@ -39,9 +50,11 @@ use std::net::IpAddr;
 use log::info;
 use tun::{Configuration as TunConfiguration, Device};
 
+use crate::config::TunIcmpMode;
+
 /// Configuration for the TUN server.
 ///
-/// This struct supports all platforms (Linux, Android, iOS) with platform-specific
+/// This struct supports all platforms (Linux, macOS, Android, iOS) with platform-specific
 /// options. See module-level documentation for usage examples.
 #[derive(Clone, Debug)]
 pub struct TunServerConfig {
@@ -57,24 +70,38 @@ pub struct TunServerConfig {
     /// Enable ICMP (ping) handling.
     /// Default: true
     pub icmp_enabled: bool,
+    /// How ICMP echo requests are answered.
+    /// Default: local
+    pub icmp_mode: TunIcmpMode,
+    /// Answer DNS queries to port 53 with the resolver instead of forwarding them.
+    /// Default: false
+    pub dns_hijack: bool,
+    /// Route TCP connections by the domain found in their TLS server name or HTTP Host
+    /// header.
+    /// Default: false
+    pub sniff: bool,
+    /// Connect to the sniffed domain instead of the original destination address.
+    /// Default: false
+    pub sniff_override_destination: bool,
     /// TUN device name.
     /// - **Linux**: Used to name the TUN device (e.g., "tun0")
+    /// - **macOS**: Used to name the utun device (e.g., "utun8")
     /// - **Android/iOS**: Ignored (device is provided via FD)
     pub tun_name: Option<String>,
     /// TUN device address.
-    /// - **Linux**: Sets the device's IP address
+    /// - **Linux/macOS**: Sets the device's IP address
     /// - **Android/iOS**: Informational only (address is set by VPN service)
     pub address: Option<IpAddr>,
     /// TUN device netmask.
-    /// - **Linux**: Sets the device's netmask
+    /// - **Linux/macOS**: Sets the device's netmask
     /// - **Android/iOS**: Informational only
     pub netmask: Option<IpAddr>,
     /// TUN device destination/gateway.
-    /// - **Linux**: Sets the device's destination address
+    /// - **Linux/macOS**: Sets the device's destination address
     /// - **Android/iOS**: Not used
     pub destination: Option<IpAddr>,
     /// Raw file descriptor for the TUN device.
-    /// - **Linux**: Optional (if not set, creates a new TUN device)
+    /// - **Linux/macOS**: Optional (if not set, creates a new TUN device)
     /// - **Android**: Required (from `VpnService.Builder.establish()`)
     /// - **iOS**: Required (from `NEPacketTunnelProvider.packetFlow`)
     pub raw_fd: Option<i32>,
@@ -87,8 +114,8 @@ pub struct TunServerConfig {
     /// Enable packet information header.
     /// - **iOS**: Set to `true` if using socket FD from `NEPacketTunnelProvider.packetFlow`,
     ///   `false` if using `readPackets`/`writePackets` API
+    /// - **macOS**: Only used with `raw_fd`, devices created by shoes always have the header
     /// - **Linux/Android**: Not used
-    #[allow(dead_code)] // Used on iOS
     pub packet_information: bool,
 }
 
@@ -111,6 +138,10 @@ impl Default for TunServerConfig {
             tcp_enabled: true,
             udp_enabled: true,
             icmp_enabled: true,
+            icmp_mode: TunIcmpMode::Local,
+            dns_hijack: false,
+            sniff: false,
+            sniff_override_destination: false,
             tun_name: None,
             address: None,
             netmask: None,
@@ -134,25 +165,25 @@ impl TunServerConfig {
         self
     }
 
-    /// Set the TUN device name (Linux only).
+    /// Set the TUN device name (Linux/macOS).
     pub fn tun_name(mut self, name: impl Into<String>) -> Self {
         self.tun_name = Some(name.into());
         self
     }
 
-    /// Set the TUN device address (Linux only).
+    /// Set the TUN device address (Linux/macOS).
     pub fn address(mut self, addr: IpAddr) -> Self {
         self.address = Some(addr);
         self
     }
 
-    /// Set the TUN device netmask (Linux only).
+    /// Set the TUN device netmask (Linux/macOS).
     pub fn netmask(mut self, mask: IpAddr) -> Self {
         self.netmask = Some(mask);
         self
     }
 
-    /// Set the TUN device destination/gateway (Linux only).
+    /// Set the TUN device destination/gateway (Linux/macOS).
     pub fn destination(mut self, dest: IpAddr) -> Self {
         self.destination = Some(dest);
         self
@@ -178,11 +209,11 @@ impl TunServerConfig {
         self
     }
 
-    /// Set whether packet information header is present (iOS only).
+    /// Set whether packet information header is present on `raw_fd` (iOS/macOS).
     ///
     /// - `true` if using socket FD from `NEPacketTunnelProvider.packetFlow`
     /// - `false` if using `readPackets`/`writePackets` API
-    #[allow(dead_code)] // Used on iOS
+    #[allow(dead_code)] // Used on iOS/macOS
     pub fn packet_information(mut self, pi: bool) -> Self {
         self.packet_information = pi;
         self
@@ -206,6 +237,31 @@ impl TunServerConfig {
         self
     }
 
+    /// Set how ICMP echo requests are answered.
+    pub fn icmp_mode(mut self, mode: TunIcmpMode) -> Self {
+        self.icmp_mode = mode;
+        self
+    }
+
+    /// Enable or disable answering DNS queries to port 53 with the resolver.
+    pub fn dns_hijack(mut self, enabled: bool) -> Self {
+        self.dns_hijack = enabled;
+        self
+    }
+
+    /// Enable or disable routing TCP connections by their sniffed domain.
+    pub fn sniff(mut self, enabled: bool) -> Self {
+        self.sniff = enabled;
+        self
+    }
+
+    /// Enable or disable connecting to the sniffed domain instead of the original
+    /// destination address.
+    pub fn sniff_override_destination(mut self, enabled: bool) -> Self {
+        self.sniff_override_destination = enabled;
+        self
+    }
+
     /// Create a synchronous TUN device from this configuration.
     ///
     /// This is used by the direct mode stack which reads/writes directly
@@ -234,6 +290,23 @@ impl TunServerConfig {
             config.up();
         }
 
+        #[cfg(target_os = "macos")]
+        {
+            if let Some(ref name) = self.tun_name {
+                config.tun_name(name);
+            }
+            if let Some(addr) = self.address {
+                config.address(addr);
+            }
+            if let Some(mask) = self.netmask {
+                config.netmask(mask);
+            }
+            if let Some(dest) = self.destination {
+                config.destination(dest);
+            }
+            config.up();
+        }
+
         #[cfg(target_os = "ios")]
         {
             config.platform_config(|p| {
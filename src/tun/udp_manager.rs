@@ -15,12 +15,15 @@ use log::debug;
 use lru::LruCache;
 use tokio::io::ReadBuf;
 use tokio::sync::mpsc;
-use tokio::time::{Instant, interval};
+use tokio::time::{Instant, interval, sleep_until};
 
 use crate::address::{Address, NetLocation};
 use crate::async_stream::AsyncMessageStream;
 use crate::client_proxy_selector::{ClientProxySelector, ConnectDecision};
+use crate::dns_server::{DNS_PORT, DnsHijacker};
+use crate::fake_ip;
 use crate::resolver::Resolver;
+use crate::sniff::{DatagramSniffer, SniffedFlow, Sniffer};
 
 use super::udp_handler::{UdpMessage, UdpReader, UdpWriter};
 
@@ -60,6 +63,10 @@ pub struct TunUdpManager {
     proxy_selector: Arc<ClientProxySelector>,
     /// DNS resolver
     resolver: Arc<dyn Resolver>,
+    /// Answers DNS queries to port 53 when DNS hijacking is enabled
+    dns_hijacker: Option<DnsHijacker>,
+    /// Routes flows by the domain found in their first datagrams when sniffing is enabled
+    sniffer: Option<Sniffer>,
     /// Receiver for responses from sessions
     response_rx: mpsc::UnboundedReceiver<UdpMessage>,
     /// Sender cloned into each session for responses
@@ -98,6 +105,8 @@ impl TunUdpManager {
         writer: UdpWriter,
         proxy_selector: Arc<ClientProxySelector>,
         resolver: Arc<dyn Resolver>,
+        dns_hijacker: Option<DnsHijacker>,
+        sniffer: Option<Sniffer>,
     ) -> Self {
         let (response_tx, response_rx) = mpsc::unbounded_channel();
 
@@ -107,6 +116,8 @@ impl TunUdpManager {
             sessions: LruCache::new(NonZeroUsize::new(MAX_SESSIONS).unwrap()),
             proxy_selector,
             resolver,
+            dns_hijacker,
+            sniffer,
             response_rx,
             response_tx,
         }
@@ -174,6 +185,29 @@ impl TunUdpManager {
         remote_addr: SocketAddr,
         payload: Vec<u8>,
     ) -> io::Result<()> {
+        // Hijacked queries are answered with the resolver, in a task so that resolving
+        // doesn't hold up other packets.
+        if remote_addr.port() == DNS_PORT
+            && let Some(dns_hijacker) = &self.dns_hijacker
+        {
+            let dns_hijacker = dns_hijacker.clone();
+            let response_tx = self.response_tx.clone();
+            tokio::spawn(async move {
+                if let Some(response) = dns_hijacker.answer(&payload).await {
+                    let _ = response_tx.send((response, remote_addr, local_addr));
+                }
+            });
+            return Ok(());
+        }
+
+        // With fake-IP enabled, address queries are answered locally so that the
+        // connections that follow can be routed by domain.
+        if remote_addr.port() == DNS_PORT
+            && let Some(response) = fake_ip::answer_query(&payload)
+        {
+            return self.write_to_tun(&response, remote_addr, local_addr);
+        }
+
         // Get or create session for this local address
         let session = if let Some(session) = self.sessions.get_mut(&local_addr) {
             // Update last active time
@@ -212,6 +246,7 @@ impl TunUdpManager {
             self.response_tx.clone(),
             self.proxy_selector.clone(),
             self.resolver.clone(),
+            self.sniffer,
         ));
 
         let session = Session {
@@ -283,12 +318,16 @@ async fn session_task(
     response_tx: mpsc::UnboundedSender<UdpMessage>,
     proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
+    sniffer: Option<Sniffer>,
 ) {
     debug!("[TunUdpSession {}] Starting", peer_addr);
 
     // Per-destination connections
     let mut connections: HashMap<NetLocation, DestinationConn> = HashMap::new();
 
+    // Destinations being sniffed, with the datagrams held back until they are connected
+    let mut sniffing: HashMap<NetLocation, (DatagramSniffer, Vec<Vec<u8>>)> = HashMap::new();
+
     // Buffer for reading responses
     let mut read_buf = vec![0u8; 65535];
 
@@ -315,19 +354,37 @@ async fn session_task(
                         c
                     }
                     None => {
-                        match create_connection(&dest, &proxy_selector, &resolver).await {
-                            Ok(remote) => {
+                        let (flow, pending) = match &sniffer {
+                            Some(sniffer) => {
+                                let (datagram_sniffer, pending) =
+                                    sniffing.entry(dest.clone()).or_insert_with(|| {
+                                        (sniffer.sniff_datagrams(dest_addr), Vec::new())
+                                    });
+                                let Some(flow) = datagram_sniffer.push(&payload) else {
+                                    pending.push(payload);
+                                    continue;
+                                };
+                                let (_, pending) = sniffing.remove(&dest).unwrap();
+                                (flow, pending)
+                            }
+                            None => (SniffedFlow::default(), Vec::new()),
+                        };
+                        let created = open_connection(
+                            &dest,
+                            flow,
+                            pending,
+                            peer_addr,
+                            &proxy_selector,
+                            &resolver,
+                        )
+                        .await;
+                        match created {
+                            Ok(conn) => {
                                 debug!(
                                     "[TunUdpSession {}] Created connection to {}",
                                     peer_addr, dest
                                 );
-                                connections.insert(
-                                    dest.clone(),
-                                    DestinationConn {
-                                        remote,
-                                        last_active: Instant::now(),
-                                    },
-                                );
+                                connections.insert(dest.clone(), conn);
                                 connections.get_mut(&dest).unwrap()
                             }
                             Err(e) => {
@@ -351,6 +408,43 @@ async fn session_task(
                 }
             }
 
+            // Connect destinations whose sniffing timed out
+            _ = sniff_deadline(&sniffing) => {
+                let now = Instant::now();
+                let expired: Vec<NetLocation> = sniffing
+                    .iter()
+                    .filter(|(_, (datagram_sniffer, _))| datagram_sniffer.deadline() <= now)
+                    .map(|(dest, _)| dest.clone())
+                    .collect();
+                for dest in expired {
+                    let (datagram_sniffer, pending) = sniffing.remove(&dest).unwrap();
+                    let created = open_connection(
+                        &dest,
+                        datagram_sniffer.finish(),
+                        pending,
+                        peer_addr,
+                        &proxy_selector,
+                        &resolver,
+                    )
+                    .await;
+                    match created {
+                        Ok(conn) => {
+                            debug!(
+                                "[TunUdpSession {}] Created connection to {}",
+                                peer_addr, dest
+                            );
+                            connections.insert(dest, conn);
+                        }
+                        Err(e) => {
+                            debug!(
+                                "[TunUdpSession {}] Failed to connect to {}: {}",
+                                peer_addr, dest, e
+                            );
+                        }
+                    }
+                }
+            }
+
             // Poll connections for responses (round-robin to be fair)
             _ = poll_and_forward_responses(
                 peer_addr,
@@ -379,15 +473,60 @@ async fn session_task(
     debug!("[TunUdpSession {}] Stopping", peer_addr);
 }
 
-/// Create a connection to a destination through the proxy chain.
+/// Waits until the earliest sniffing deadline, or forever if nothing is being sniffed.
+async fn sniff_deadline(sniffing: &HashMap<NetLocation, (DatagramSniffer, Vec<Vec<u8>>)>) {
+    let deadline = sniffing
+        .values()
+        .map(|(sniffer, _)| sniffer.deadline())
+        .min();
+    match deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Create a connection to a destination, and send the datagrams that were held back
+/// while sniffing.
+async fn open_connection(
+    dest: &NetLocation,
+    flow: SniffedFlow,
+    pending: Vec<Vec<u8>>,
+    peer_addr: SocketAddr,
+    proxy_selector: &Arc<ClientProxySelector>,
+    resolver: &Arc<dyn Resolver>,
+) -> io::Result<DestinationConn> {
+    let mut remote = create_connection(dest, flow, peer_addr, proxy_selector, resolver).await?;
+    for datagram in pending {
+        send_message(&mut remote, &datagram).await?;
+    }
+    Ok(DestinationConn {
+        remote,
+        last_active: Instant::now(),
+    })
+}
+
+/// Create a connection to a destination through the proxy chain selected for `peer_addr`.
+/// Sniffed flows are routed by their domain, and QUIC flows may be blocked.
 async fn create_connection(
     dest: &NetLocation,
+    flow: SniffedFlow,
+    peer_addr: SocketAddr,
     proxy_selector: &Arc<ClientProxySelector>,
     resolver: &Arc<dyn Resolver>,
 ) -> io::Result<Box<dyn AsyncMessageStream>> {
-    let decision = proxy_selector
-        .judge(dest.into(), resolver)
-        .await?;
+    if let Some(location) = &flow.routing_location {
+        debug!("Sniffed {} for {}", location.location(), dest);
+    }
+    let location = flow.routing_location.unwrap_or_else(|| dest.into());
+    let decision = if flow.is_quic {
+        proxy_selector
+            .judge_quic(location, Some(peer_addr.ip()), resolver)
+            .await?
+    } else {
+        proxy_selector
+            .judge_with_source(location, Some(peer_addr.ip()), resolver)
+            .await?
+    };
 
     match decision {
         ConnectDecision::Allow {
@@ -395,7 +534,11 @@ async fn create_connection(
             remote_location,
         } => {
             let stream = chain_group
-                .connect_udp_bidirectional(resolver, remote_location)
+                .connect_udp_bidirectional_with_source(
+                    resolver,
+                    remote_location,
+                    Some(peer_addr.ip()),
+                )
                 .await?;
             Ok(stream)
         }
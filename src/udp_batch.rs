@@ -0,0 +1,379 @@
+//! Batched UDP socket I/O.
+//!
+//! Relaying UDP at high packet rates is bound by the cost of a syscall per datagram. On
+//! Linux, [`RecvBatch`] receives up to 32 datagrams with a single recvmmsg(2) call and
+//! hands them out one at a time, and [`SendBatch`] queues datagrams until they are
+//! flushed, then sends them with sendmmsg(2). Elsewhere, both fall back to a syscall per
+//! datagram.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use tokio::io::ReadBuf;
+use tokio::net::UdpSocket;
+
+use crate::util::allocate_vec;
+
+/// Datagrams received or sent by a single syscall.
+const BATCH_SIZE: usize = 32;
+
+/// Largest UDP payload.
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+thread_local! {
+    /// Space for a batch of the largest datagrams. Received datagrams are copied out of it
+    /// into the queue of their socket, so that each socket only holds what it received.
+    static RECV_SCRATCH: RefCell<Box<[u8]>> =
+        RefCell::new(allocate_vec(BATCH_SIZE * MAX_DATAGRAM_SIZE).into_boxed_slice());
+}
+
+/// Datagrams received from a socket that weren't read yet.
+#[derive(Debug, Default)]
+pub struct RecvBatch {
+    /// Payloads of the datagrams, back to back.
+    data: Vec<u8>,
+    /// Offset in `data` of the next datagram.
+    offset: usize,
+    /// Length and source of each datagram.
+    datagrams: VecDeque<(usize, SocketAddr)>,
+}
+
+impl RecvBatch {
+    /// Reads the next datagram into `buf` like [`UdpSocket::poll_recv_from`], receiving
+    /// another batch from `socket` when none are left. As with recv_from, the datagram is
+    /// truncated if `buf` is too small.
+    pub fn poll_recv_from(
+        &mut self,
+        socket: &UdpSocket,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<SocketAddr>> {
+        loop {
+            if let Some((len, source)) = self.datagrams.pop_front() {
+                let payload = &self.data[self.offset..self.offset + len];
+                buf.put_slice(&payload[..len.min(buf.remaining())]);
+                self.offset += len;
+                return Poll::Ready(Ok(source));
+            }
+
+            self.offset = 0;
+            self.data.clear();
+            // Don't hold on to a large batch once it was read.
+            if self.data.capacity() > MAX_DATAGRAM_SIZE {
+                self.data = Vec::new();
+            }
+
+            ready!(socket.poll_recv_ready(cx))?;
+            match recv_batch(socket, &mut self.data, &mut self.datagrams) {
+                Ok(()) => {}
+                // Readiness was cleared, so polling again registers for the next datagram.
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    /// Reads the next datagram into `buf`, returning its length and source.
+    pub async fn recv_from(
+        &mut self,
+        socket: &UdpSocket,
+        buf: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        let mut read_buf = ReadBuf::new(buf);
+        let source =
+            std::future::poll_fn(|cx| self.poll_recv_from(socket, cx, &mut read_buf)).await?;
+        Ok((read_buf.filled().len(), source))
+    }
+}
+
+/// Datagrams queued for a socket until they are flushed.
+#[derive(Debug, Default)]
+pub struct SendBatch {
+    /// Payloads of the datagrams, back to back.
+    data: Vec<u8>,
+    /// Offset in `data` of the first datagram that wasn't sent.
+    offset: usize,
+    /// Length and target of each datagram.
+    datagrams: VecDeque<(usize, SocketAddr)>,
+}
+
+impl SendBatch {
+    /// Queues a datagram to `target`. Queued datagrams are flushed first when a full batch
+    /// is queued.
+    pub fn poll_send_to(
+        &mut self,
+        socket: &UdpSocket,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> Poll<io::Result<()>> {
+        if self.datagrams.len() >= BATCH_SIZE {
+            ready!(self.poll_flush(socket, cx))?;
+        }
+        self.data.extend_from_slice(buf);
+        self.datagrams.push_back((buf.len(), target));
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends all queued datagrams. If sending a datagram fails, the datagrams after it
+    /// are dropped with it.
+    pub fn poll_flush(&mut self, socket: &UdpSocket, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.datagrams.is_empty() {
+            ready!(socket.poll_send_ready(cx))?;
+            match send_batch(socket, &self.data[self.offset..], &self.datagrams) {
+                Ok(sent) => {
+                    for (len, _) in self.datagrams.drain(..sent) {
+                        self.offset += len;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => {
+                    self.clear();
+                    return Poll::Ready(Err(e));
+                }
+            }
+        }
+        self.clear();
+        Poll::Ready(Ok(()))
+    }
+
+    fn clear(&mut self) {
+        self.offset = 0;
+        self.data.clear();
+        self.datagrams.clear();
+        if self.data.capacity() > MAX_DATAGRAM_SIZE {
+            self.data = Vec::new();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn recv_batch(
+    socket: &UdpSocket,
+    data: &mut Vec<u8>,
+    datagrams: &mut VecDeque<(usize, SocketAddr)>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    socket.try_io(tokio::io::Interest::READABLE, || {
+        RECV_SCRATCH.with_borrow_mut(|scratch| {
+            let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+            let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+            let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+            for (i, slot) in scratch.chunks_exact_mut(MAX_DATAGRAM_SIZE).enumerate() {
+                iovecs[i] = libc::iovec {
+                    iov_base: slot.as_mut_ptr().cast(),
+                    iov_len: slot.len(),
+                };
+                let hdr = &mut msgs[i].msg_hdr;
+                hdr.msg_name = (&mut addrs[i] as *mut libc::sockaddr_storage).cast();
+                hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as _;
+                hdr.msg_iov = &mut iovecs[i];
+                hdr.msg_iovlen = 1;
+            }
+
+            let n = unsafe {
+                libc::recvmmsg(
+                    socket.as_raw_fd(),
+                    msgs.as_mut_ptr(),
+                    BATCH_SIZE as _,
+                    libc::MSG_DONTWAIT as _,
+                    std::ptr::null_mut(),
+                )
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+
+            for (i, msg) in msgs.iter().take(n as usize).enumerate() {
+                let source = socket_addr_from_storage(&addrs[i])?;
+                let len = msg.msg_len as usize;
+                let start = i * MAX_DATAGRAM_SIZE;
+                data.extend_from_slice(&scratch[start..start + len]);
+                datagrams.push_back((len, source));
+            }
+            Ok(())
+        })
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_batch(
+    socket: &UdpSocket,
+    data: &mut Vec<u8>,
+    datagrams: &mut VecDeque<(usize, SocketAddr)>,
+) -> io::Result<()> {
+    RECV_SCRATCH.with_borrow_mut(|scratch| {
+        let (len, source) = socket.try_recv_from(&mut scratch[..MAX_DATAGRAM_SIZE])?;
+        data.extend_from_slice(&scratch[..len]);
+        datagrams.push_back((len, source));
+        Ok(())
+    })
+}
+
+/// Sends the first datagrams of `datagrams`, whose payloads are back to back in `data`,
+/// and returns how many were sent.
+#[cfg(target_os = "linux")]
+fn send_batch(
+    socket: &UdpSocket,
+    data: &[u8],
+    datagrams: &VecDeque<(usize, SocketAddr)>,
+) -> io::Result<usize> {
+    use std::os::fd::AsRawFd;
+
+    let count = datagrams.len().min(BATCH_SIZE);
+    let mut addrs: [libc::sockaddr_storage; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut iovecs: [libc::iovec; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut msgs: [libc::mmsghdr; BATCH_SIZE] = unsafe { std::mem::zeroed() };
+    let mut offset = 0;
+    for (i, (len, target)) in datagrams.iter().take(count).enumerate() {
+        iovecs[i] = libc::iovec {
+            iov_base: data[offset..].as_ptr() as *mut libc::c_void,
+            iov_len: *len,
+        };
+        offset += len;
+        let hdr = &mut msgs[i].msg_hdr;
+        hdr.msg_namelen = socket_addr_to_storage(target, &mut addrs[i]);
+        hdr.msg_name = (&mut addrs[i] as *mut libc::sockaddr_storage).cast();
+        hdr.msg_iov = &mut iovecs[i];
+        hdr.msg_iovlen = 1;
+    }
+
+    socket.try_io(tokio::io::Interest::WRITABLE, || {
+        let n = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                count as _,
+                libc::MSG_DONTWAIT as _,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_batch(
+    socket: &UdpSocket,
+    data: &[u8],
+    datagrams: &VecDeque<(usize, SocketAddr)>,
+) -> io::Result<usize> {
+    let (len, target) = datagrams[0];
+    socket.try_send_to(&data[..len], target)?;
+    Ok(1)
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr_from_storage(storage: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Ok(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                u16::from_be(addr.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Ok(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )))
+        }
+        family => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected address family {family}"),
+        )),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn socket_addr_to_storage(addr: &SocketAddr, storage: &mut libc::sockaddr_storage) -> u32 {
+    match addr {
+        SocketAddr::V4(addr) => {
+            let sin = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = addr.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>() as u32
+        }
+        SocketAddr::V6(addr) => {
+            let sin6 = unsafe { &mut *(storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = addr.port().to_be();
+            sin6.sin6_addr.s6_addr = addr.ip().octets();
+            sin6.sin6_flowinfo = addr.flowinfo();
+            sin6.sin6_scope_id = addr.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>() as u32
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_batched_send_and_receive() {
+        let sender = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+
+        // More than a batch, so that queueing the last ones flushes the first.
+        let count = BATCH_SIZE + 8;
+        let mut send_batch = SendBatch::default();
+        for i in 0..count {
+            let payload = vec![i as u8; i + 1];
+            std::future::poll_fn(|cx| send_batch.poll_send_to(&sender, cx, &payload, target))
+                .await
+                .unwrap();
+        }
+        std::future::poll_fn(|cx| send_batch.poll_flush(&sender, cx))
+            .await
+            .unwrap();
+        assert!(send_batch.datagrams.is_empty());
+
+        let mut recv_batch = RecvBatch::default();
+        let mut buf = [0u8; 1024];
+        for i in 0..count {
+            let (len, source) = recv_batch.recv_from(&receiver, &mut buf).await.unwrap();
+            assert_eq!(source, sender.local_addr().unwrap());
+            assert_eq!(&buf[..len], vec![i as u8; i + 1].as_slice());
+        }
+
+        // Datagrams are truncated to the read buffer like recv_from.
+        sender.send_to(&[1, 2, 3, 4], target).await.unwrap();
+        sender.send_to(&[5], target).await.unwrap();
+        let mut small = [0u8; 2];
+        let (len, _) = recv_batch.recv_from(&receiver, &mut small).await.unwrap();
+        assert_eq!(&small[..len], &[1, 2]);
+        let (len, _) = recv_batch.recv_from(&receiver, &mut small).await.unwrap();
+        assert_eq!(&small[..len], &[5]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_socket_addr_storage_roundtrip() {
+        let addrs: [SocketAddr; 2] = [
+            "192.0.2.1:53".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+        ];
+        for addr in addrs {
+            let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+            socket_addr_to_storage(&addr, &mut storage);
+            assert_eq!(socket_addr_from_storage(&storage).unwrap(), addr);
+        }
+    }
+}
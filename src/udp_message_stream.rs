@@ -15,11 +15,15 @@ use crate::async_stream::{
     AsyncSourcedMessageStream, AsyncWriteTargetedMessage,
 };
 use crate::resolver::{Resolver, ResolverCache};
+use crate::udp_batch::{RecvBatch, SendBatch};
 
 /// A thin wrapper around a directly connecting UdpSocket to support hostname resolution.
+/// Datagrams are received and sent in batches.
 pub struct UdpMessageStream {
     socket: UdpSocket,
     resolver_cache: ResolverCache,
+    recv_batch: RecvBatch,
+    send_batch: SendBatch,
 }
 
 impl UdpMessageStream {
@@ -27,6 +31,8 @@ impl UdpMessageStream {
         Self {
             socket,
             resolver_cache: ResolverCache::new(resolver),
+            recv_batch: RecvBatch::default(),
+            send_batch: SendBatch::default(),
         }
     }
 }
@@ -37,7 +43,8 @@ impl AsyncReadSourcedMessage for UdpMessageStream {
         cx: &mut Context<'_>,
         buf: &mut ReadBuf<'_>,
     ) -> Poll<std::io::Result<SocketAddr>> {
-        self.get_mut().socket.poll_recv_from(cx, buf)
+        let this = self.get_mut();
+        this.recv_batch.poll_recv_from(&this.socket, cx, buf)
     }
 }
 
@@ -52,28 +59,25 @@ impl AsyncWriteTargetedMessage for UdpMessageStream {
 
         let socket_addr = ready!(this.resolver_cache.poll_resolve_location(cx, target))?;
 
-        // TODO: do we need to check usize result here?
-        this.socket
-            .poll_send_to(cx, buf, socket_addr)
-            .map(|result| result.map(|_| ()))
+        this.send_batch
+            .poll_send_to(&this.socket, cx, buf, socket_addr)
     }
 }
 
 impl AsyncFlushMessage for UdpMessageStream {
-    fn poll_flush_message(
-        self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
-    ) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.send_batch.poll_flush(&this.socket, cx)
     }
 }
 
 impl AsyncShutdownMessage for UdpMessageStream {
     fn poll_shutdown_message(
         self: Pin<&mut Self>,
-        _cx: &mut Context<'_>,
+        cx: &mut Context<'_>,
     ) -> Poll<std::io::Result<()>> {
-        Poll::Ready(Ok(()))
+        let this = self.get_mut();
+        this.send_batch.poll_flush(&this.socket, cx)
     }
 }
 
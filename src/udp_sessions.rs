@@ -0,0 +1,119 @@
+//! Limits of the UDP session tables of servers.
+//!
+//! Servers that forward UDP keep a table of sessions for each client connection, with an
+//! outbound socket or stream per destination or client session ID. The tables are bounded
+//! by a top-level `udp_max_sessions` / `udp_idle_timeout_secs` entry: sessions without
+//! traffic for the idle timeout are closed, and when a table is full, its least recently
+//! used session is evicted to make room for a new one.
+
+use std::time::Duration;
+
+use parking_lot::RwLock;
+
+use crate::config::UdpSessionConfig;
+use crate::metrics;
+
+static LIMITS: RwLock<UdpSessionLimits> = RwLock::new(UdpSessionLimits::UNLIMITED);
+
+/// Limits of a UDP session table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpSessionLimits {
+    max_sessions: Option<usize>,
+    idle_timeout: Option<Duration>,
+}
+
+impl UdpSessionLimits {
+    const UNLIMITED: Self = Self {
+        max_sessions: None,
+        idle_timeout: None,
+    };
+
+    /// Returns true if a table with `len` sessions has to evict one before adding another.
+    pub fn is_full(&self, len: usize) -> bool {
+        self.max_sessions.is_some_and(|max| len >= max)
+    }
+
+    /// Returns the configured idle timeout, or the protocol's `default`.
+    pub fn idle_timeout_or(&self, default: Duration) -> Duration {
+        self.idle_timeout.unwrap_or(default)
+    }
+}
+
+impl From<&UdpSessionConfig> for UdpSessionLimits {
+    fn from(config: &UdpSessionConfig) -> Self {
+        Self {
+            max_sessions: config.udp_max_sessions,
+            idle_timeout: config.udp_idle_timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+/// Sets the limits of session tables created from now on. Existing tables keep the limits
+/// they were created with.
+pub fn configure(config: Option<UdpSessionConfig>) {
+    *LIMITS.write() = config
+        .as_ref()
+        .map_or(UdpSessionLimits::UNLIMITED, UdpSessionLimits::from);
+}
+
+pub fn validate_config(config: &UdpSessionConfig) -> std::io::Result<()> {
+    let invalid = |message: &str| {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid UDP session config: {message}"),
+        ))
+    };
+    if config.udp_max_sessions == Some(0) {
+        return invalid("udp_max_sessions must be at least 1");
+    }
+    if config.udp_idle_timeout_secs == Some(0) {
+        return invalid("udp_idle_timeout_secs must be at least 1");
+    }
+    Ok(())
+}
+
+/// Returns the limits for a new session table.
+pub fn limits() -> UdpSessionLimits {
+    *LIMITS.read()
+}
+
+/// Why a session table closed a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Eviction {
+    /// The session had no traffic for the idle timeout.
+    Idle,
+    /// The table was full, and the session was the least recently used one.
+    Limit,
+}
+
+/// Counts a session closed by its table.
+pub fn record_eviction(eviction: Eviction) {
+    let Some(metrics) = metrics::udp_sessions() else {
+        return;
+    };
+    match eviction {
+        Eviction::Idle => metrics.expired.inc(),
+        Eviction::Limit => metrics.evicted.inc(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let default = Duration::from_secs(60);
+        let limits = UdpSessionLimits::UNLIMITED;
+        assert!(!limits.is_full(usize::MAX));
+        assert_eq!(limits.idle_timeout_or(default), default);
+
+        let limits = UdpSessionLimits::from(&UdpSessionConfig {
+            udp_max_sessions: Some(2),
+            udp_idle_timeout_secs: Some(10),
+        });
+        assert!(!limits.is_full(1));
+        assert!(limits.is_full(2));
+        assert_eq!(limits.idle_timeout_or(default), Duration::from_secs(10));
+    }
+}
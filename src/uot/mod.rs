@@ -22,6 +22,8 @@
 //! If isConnect=1, subsequent packets are length-prefixed only (V2 connect mode).
 //! If isConnect=0, subsequent packets use V1 format (multi-destination).
 //!
+//! Outbounds with `udp_over_tcp` send UDP in V2 connect mode through `UotClientHandler`.
+//!
 //! **Important:** The V2 Request header uses SOCKS5-style ATYP (0x01/0x03/0x04),
 //! NOT the AddrParser format below. Protocol handlers must use SOCKS5 address
 //! parsing for the V2 Request destination.
@@ -31,9 +33,11 @@
 //! - 0x01: IPv6 Address (16 bytes)
 //! - 0x02: Domain Name (1 byte length + domain)
 
+mod uot_client_handler;
 pub mod uot_common;
 mod uot_v1_server_stream;
 
+pub use uot_client_handler::UotClientHandler;
 pub use uot_v1_server_stream::UotV1ServerStream;
 
 /// UoT V2 connect mode stream - identical format to VlessMessageStream (length-prefixed u16be + data)
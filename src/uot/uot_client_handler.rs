@@ -0,0 +1,74 @@
+//! UoT V2 client handler
+//!
+//! Wraps the handler of a TCP-based outbound to tunnel UDP with UoT V2 connect mode,
+//! for protocols without UDP support or networks that drop UDP. The wrapped handler opens
+//! a TCP stream to the V2 magic address, and the V2 request sets the destination of the
+//! UDP packets. Each UDP flow uses its own stream, so the server keeps a separate NAT
+//! mapping per destination.
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use super::uot_common::write_v2_connect_request;
+use super::{UOT_V2_MAGIC_ADDRESS, UotV2Stream};
+use crate::address::{Address, NetLocation, ResolvedLocation};
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
+
+/// Client handler that tunnels UDP over TCP streams of the wrapped handler.
+#[derive(Debug)]
+pub struct UotClientHandler {
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl UotClientHandler {
+    pub fn new(handler: Box<dyn TcpClientHandler>) -> Self {
+        Self { handler }
+    }
+}
+
+#[async_trait]
+impl TcpClientHandler for UotClientHandler {
+    async fn setup_client_tcp_stream(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        remote_location: ResolvedLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        self.handler
+            .setup_client_tcp_stream(client_stream, remote_location)
+            .await
+    }
+
+    fn supports_udp_over_tcp(&self) -> bool {
+        true
+    }
+
+    async fn setup_client_udp_bidirectional(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        let magic_location =
+            NetLocation::new(Address::Hostname(UOT_V2_MAGIC_ADDRESS.to_string()), 0);
+        let TcpClientSetupResult {
+            mut client_stream,
+            early_data,
+        } = self
+            .handler
+            .setup_client_tcp_stream(client_stream, magic_location.into())
+            .await?;
+        if early_data.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "UoT server sent data before the UoT request",
+            ));
+        }
+
+        client_stream
+            .write_all(&write_v2_connect_request(target.location()))
+            .await?;
+        client_stream.flush().await?;
+
+        Ok(Box::new(UotV2Stream::new(client_stream)))
+    }
+}
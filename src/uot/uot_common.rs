@@ -3,6 +3,7 @@
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use crate::address::{Address, NetLocation};
+use crate::socks_handler::write_location_to_vec;
 
 /// ATYP values for UoT (different from SOCKS5!)
 pub const ATYP_IPV4: u8 = 0x00;
@@ -82,6 +83,15 @@ pub fn write_uot_address(buf: &mut [u8], addr: &SocketAddr) -> usize {
     }
 }
 
+/// Write the UoT V2 request for connect mode (isConnect=1 + SOCKS5-style address)
+pub fn write_v2_connect_request(target: &NetLocation) -> Vec<u8> {
+    let address = write_location_to_vec(target);
+    let mut request = Vec::with_capacity(1 + address.len());
+    request.push(1); // isConnect = 1 (connect mode)
+    request.extend_from_slice(&address);
+    request
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -181,4 +191,14 @@ mod tests {
         assert_eq!(&buf[1..17], &Ipv6Addr::LOCALHOST.octets());
         assert_eq!(&buf[17..19], &443u16.to_be_bytes());
     }
+
+    #[test]
+    fn test_write_v2_connect_request() {
+        let target = NetLocation::new(Address::Hostname("example.com".to_string()), 53);
+        let request = write_v2_connect_request(&target);
+        // isConnect=1, then SOCKS5 ATYP=0x03 (domain), unlike the UoT ATYP of packets
+        assert_eq!(&request[..3], &[1, 0x03, 11]);
+        assert_eq!(&request[3..14], b"example.com");
+        assert_eq!(&request[14..], &53u16.to_be_bytes());
+    }
 }
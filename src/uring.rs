@@ -0,0 +1,554 @@
+//! io_uring backend for accepting and relaying TCP connections.
+//!
+//! Enabled with the `io-uring` feature on Linux. Listeners accept connections with a
+//! multishot accept on a ring of their own, so that a single submission keeps accepting
+//! until the listener is closed. Relays between two plain TCP streams, which otherwise
+//! splice(2), are handed to a driver thread that runs all of them on one ring: the reads
+//! and writes of every relay that make progress are submitted together with a single
+//! io_uring_enter(2) call.
+//!
+//! When a ring can't be created, e.g. on old kernels or where seccomp forbids io_uring,
+//! listeners and relays fall back to epoll and splice.
+
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, LazyLock};
+
+use io_uring::{IoUring, cqueue, opcode, squeue, types};
+use log::{debug, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::buffer_pool::{self, PooledBuffer};
+use crate::metrics::ByteCounter;
+
+const ACCEPT_RING_ENTRIES: u32 = 64;
+const RELAY_RING_ENTRIES: u32 = 4096;
+
+/// Accepted connections waiting for the accept loop of a listener.
+const ACCEPT_QUEUE_LEN: usize = 1024;
+
+const ACCEPT: u64 = 0;
+const ACCEPT_POLL: u64 = 1;
+
+/// user_data of the read of the driver's eventfd. Relay operations use the slot of the
+/// relay and the direction instead, see [`user_data`].
+const WAKE: u64 = u64::MAX;
+
+fn push(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<()> {
+    loop {
+        // The entries only point to memory that outlives the operation.
+        if unsafe { ring.submission().push(entry) }.is_ok() {
+            return Ok(());
+        }
+        // The submission queue is full, make room by submitting it.
+        ring.submit()?;
+    }
+}
+
+fn result_error(result: i32) -> io::Error {
+    io::Error::from_raw_os_error(-result)
+}
+
+/// Accepts connections of a TCP listener with io_uring multishot accept.
+pub struct UringAcceptor {
+    /// Duplicate of the listener's descriptor, used by the accept thread.
+    listener: Arc<OwnedFd>,
+    accepted: mpsc::Receiver<io::Result<std::net::TcpStream>>,
+}
+
+impl UringAcceptor {
+    /// Starts accepting connections of `listener`, or returns None if io_uring is
+    /// unavailable.
+    pub fn new(listener: &TcpListener) -> Option<Self> {
+        match Self::start(listener) {
+            Ok(acceptor) => Some(acceptor),
+            Err(e) => {
+                warn!("io_uring accept is unavailable, falling back to epoll: {e}");
+                None
+            }
+        }
+    }
+
+    fn start(listener: &TcpListener) -> io::Result<Self> {
+        let fd = Arc::new(listener.as_fd().try_clone_to_owned()?);
+        let ring = IoUring::new(ACCEPT_RING_ENTRIES)?;
+        let (tx, accepted) = mpsc::channel(ACCEPT_QUEUE_LEN);
+        let thread_fd = fd.clone();
+        std::thread::Builder::new()
+            .name("uring-accept".to_string())
+            .spawn(move || {
+                if let Err(e) = run_acceptor(ring, thread_fd.as_raw_fd(), &tx) {
+                    let _ = tx.blocking_send(Err(e));
+                }
+            })?;
+        Ok(Self {
+            listener: fd,
+            accepted,
+        })
+    }
+
+    /// Returns the next accepted connection, or None if the acceptor stopped and the
+    /// listener has to accept connections itself.
+    pub async fn accept(&mut self) -> Option<io::Result<(TcpStream, SocketAddr)>> {
+        let stream = match self.accepted.recv().await? {
+            Ok(stream) => stream,
+            Err(e) => return Some(Err(e)),
+        };
+        let accepted = stream
+            .set_nonblocking(true)
+            .and_then(|()| TcpStream::from_std(stream))
+            .and_then(|stream| {
+                let addr = stream.peer_addr()?;
+                Ok((stream, addr))
+            });
+        Some(accepted)
+    }
+}
+
+impl Drop for UringAcceptor {
+    fn drop(&mut self) {
+        // Fails the multishot accept, which stops the accept thread.
+        unsafe {
+            libc::shutdown(self.listener.as_raw_fd(), libc::SHUT_RDWR);
+        }
+    }
+}
+
+fn run_acceptor(
+    mut ring: IoUring,
+    listener: RawFd,
+    accepted: &mpsc::Sender<io::Result<std::net::TcpStream>>,
+) -> io::Result<()> {
+    let fd = types::Fd(listener);
+    let accept = opcode::AcceptMulti::new(fd)
+        .flags(libc::SOCK_CLOEXEC)
+        .build()
+        .user_data(ACCEPT);
+    let poll = opcode::PollAdd::new(fd, libc::POLLIN as u32)
+        .build()
+        .user_data(ACCEPT_POLL);
+    push(&mut ring, &accept)?;
+
+    loop {
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+        let completions: Vec<cqueue::Entry> = ring.completion().collect();
+        for completion in completions {
+            let result = completion.result();
+            if completion.user_data() == ACCEPT_POLL {
+                if result < 0 {
+                    return Err(result_error(result));
+                }
+                push(&mut ring, &accept)?;
+                continue;
+            }
+
+            if result >= 0 {
+                let stream = unsafe { std::net::TcpStream::from_raw_fd(result) };
+                if accepted.blocking_send(Ok(stream)).is_err() {
+                    // The listener was dropped.
+                    return Ok(());
+                }
+            } else if result == -libc::EINVAL || result == -libc::EBADF {
+                // The listener was shut down.
+                return Ok(());
+            } else if result != -libc::EAGAIN
+                && accepted.blocking_send(Err(result_error(result))).is_err()
+            {
+                return Ok(());
+            }
+
+            // The multishot accept ended, e.g. after an error. Wait for the next
+            // connection before accepting again.
+            if !cqueue::more(completion.flags()) {
+                push(&mut ring, &poll)?;
+            }
+        }
+    }
+}
+
+/// A relay handed to the driver thread.
+struct NewRelay {
+    fds: [OwnedFd; 2],
+    counters: [ByteCounter; 2],
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+struct Driver {
+    relays: std::sync::mpsc::Sender<NewRelay>,
+    wake: OwnedFd,
+}
+
+static DRIVER: LazyLock<Option<Driver>> = LazyLock::new(|| match start_driver() {
+    Ok(driver) => Some(driver),
+    Err(e) => {
+        warn!("io_uring relays are unavailable, falling back to splice: {e}");
+        None
+    }
+});
+
+fn start_driver() -> io::Result<Driver> {
+    let ring = IoUring::new(RELAY_RING_ENTRIES)?;
+    let wake = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if wake < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let wake = unsafe { OwnedFd::from_raw_fd(wake) };
+    let thread_wake = wake.try_clone()?;
+    let (relays, new_relays) = std::sync::mpsc::channel();
+    std::thread::Builder::new()
+        .name("uring-relay".to_string())
+        .spawn(move || {
+            if let Err(e) = run_driver(ring, thread_wake, new_relays) {
+                warn!("io_uring relay driver stopped: {e}");
+            }
+        })?;
+    Ok(Driver { relays, wake })
+}
+
+/// Returns true if relays can run on io_uring.
+pub fn is_available() -> bool {
+    DRIVER.is_some()
+}
+
+/// Copies data in both directions between `a` and `b` on the driver thread, like
+/// [`splice_bidirectional`](crate::splice::splice_bidirectional). Requires
+/// [`is_available`].
+pub async fn relay_bidirectional(
+    a: &TcpStream,
+    b: &TcpStream,
+    a_to_b_counter: ByteCounter,
+    b_to_a_counter: ByteCounter,
+) -> io::Result<()> {
+    let driver = DRIVER.as_ref().expect("io_uring is unavailable");
+    let (done, result) = oneshot::channel();
+    let relay = NewRelay {
+        fds: [
+            a.as_fd().try_clone_to_owned()?,
+            b.as_fd().try_clone_to_owned()?,
+        ],
+        counters: [a_to_b_counter, b_to_a_counter],
+        done,
+    };
+    if driver.relays.send(relay).is_err() {
+        return Err(io::Error::other("io_uring relay driver stopped"));
+    }
+    let one = 1u64.to_ne_bytes();
+    unsafe {
+        libc::write(driver.wake.as_raw_fd(), one.as_ptr().cast(), one.len());
+    }
+
+    // If the relay is dropped before it finishes, shut the sockets down so that the
+    // driver finishes it too and closes its descriptors.
+    let guard = ShutdownGuard([a.as_raw_fd(), b.as_raw_fd()]);
+    let result = result
+        .await
+        .unwrap_or_else(|_| Err(io::Error::other("io_uring relay driver stopped")));
+    std::mem::forget(guard);
+    result
+}
+
+struct ShutdownGuard([RawFd; 2]);
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        for fd in self.0 {
+            unsafe {
+                libc::shutdown(fd, libc::SHUT_RDWR);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Reading,
+    WaitingToRead,
+    Writing,
+    WaitingToWrite,
+    ShuttingDown,
+    Done,
+}
+
+/// One direction of a relay, reading from `fds[index]` and writing to the other.
+struct Direction {
+    state: State,
+    buf: PooledBuffer,
+    start: usize,
+    end: usize,
+    counter: ByteCounter,
+}
+
+struct Relay {
+    fds: [OwnedFd; 2],
+    directions: [Direction; 2],
+    error: Option<io::Error>,
+    done: oneshot::Sender<io::Result<()>>,
+}
+
+impl Relay {
+    /// Returns the next operation of `direction`, given the result of the previous one, or
+    /// None if the direction is done.
+    fn advance(&mut self, direction: usize, result: i32) -> Option<squeue::Entry> {
+        let reader = types::Fd(self.fds[direction].as_raw_fd());
+        let writer = types::Fd(self.fds[1 - direction].as_raw_fd());
+        let dir = &mut self.directions[direction];
+
+        if result < 0 && result != -libc::EAGAIN && dir.state != State::ShuttingDown {
+            self.fail(direction, result_error(result));
+            return None;
+        }
+
+        let entry = match dir.state {
+            State::Reading if result == -libc::EAGAIN => {
+                dir.state = State::WaitingToRead;
+                opcode::PollAdd::new(reader, libc::POLLIN as u32).build()
+            }
+            State::Reading if result == 0 => {
+                dir.state = State::ShuttingDown;
+                opcode::Shutdown::new(writer, libc::SHUT_WR).build()
+            }
+            State::Reading => {
+                dir.start = 0;
+                dir.end = result as usize;
+                dir.state = State::Writing;
+                dir.write(writer)
+            }
+            State::WaitingToRead => {
+                dir.state = State::Reading;
+                dir.read(reader)
+            }
+            State::Writing if result == -libc::EAGAIN => {
+                dir.state = State::WaitingToWrite;
+                opcode::PollAdd::new(writer, libc::POLLOUT as u32).build()
+            }
+            State::Writing if result == 0 => {
+                let error = io::Error::new(io::ErrorKind::WriteZero, "write zero byte into writer");
+                self.fail(direction, error);
+                return None;
+            }
+            State::Writing => {
+                dir.counter.add(result as usize);
+                dir.start += result as usize;
+                if dir.start < dir.end {
+                    dir.write(writer)
+                } else {
+                    dir.state = State::Reading;
+                    dir.read(reader)
+                }
+            }
+            State::WaitingToWrite => {
+                dir.state = State::Writing;
+                dir.write(writer)
+            }
+            // The peer may have closed the connection already.
+            State::ShuttingDown | State::Done => {
+                dir.state = State::Done;
+                return None;
+            }
+        };
+        Some(entry)
+    }
+
+    /// Ends the relay with `error`. The sockets are shut down, so that the operation of
+    /// the other direction completes.
+    fn fail(&mut self, direction: usize, error: std::io::Error) {
+        self.directions[direction].state = State::Done;
+        self.error.get_or_insert(error);
+        for fd in &self.fds {
+            unsafe {
+                libc::shutdown(fd.as_raw_fd(), libc::SHUT_RDWR);
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.directions.iter().all(|dir| dir.state == State::Done)
+    }
+}
+
+impl Direction {
+    fn read(&mut self, reader: types::Fd) -> squeue::Entry {
+        opcode::Recv::new(reader, self.buf.as_mut_ptr(), self.buf.len() as u32).build()
+    }
+
+    fn write(&self, writer: types::Fd) -> squeue::Entry {
+        let pending = &self.buf[self.start..self.end];
+        opcode::Send::new(writer, pending.as_ptr(), pending.len() as u32)
+            .flags(libc::MSG_NOSIGNAL)
+            .build()
+    }
+}
+
+/// Identifies an operation of the relay in `slot` for `direction`.
+fn user_data(slot: usize, direction: usize) -> u64 {
+    ((slot as u64) << 1) | direction as u64
+}
+
+fn run_driver(
+    ring: IoUring,
+    wake: OwnedFd,
+    new_relays: std::sync::mpsc::Receiver<NewRelay>,
+) -> io::Result<()> {
+    let mut relays: Vec<Option<Relay>> = Vec::new();
+    let mut free_slots: Vec<usize> = Vec::new();
+    let mut wake_buf = Box::new([0u8; 8]);
+    // Declared last so that it's dropped first, which cancels the operations that use the
+    // buffers above.
+    let mut ring = ring;
+    let wake_read = opcode::Read::new(types::Fd(wake.as_raw_fd()), wake_buf.as_mut_ptr(), 8)
+        .build()
+        .user_data(WAKE);
+    push(&mut ring, &wake_read)?;
+
+    loop {
+        match ring.submit_and_wait(1) {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+
+        let completions: Vec<cqueue::Entry> = ring.completion().collect();
+        for completion in completions {
+            if completion.user_data() == WAKE {
+                while let Ok(new_relay) = new_relays.try_recv() {
+                    let slot = free_slots.pop().unwrap_or_else(|| {
+                        relays.push(None);
+                        relays.len() - 1
+                    });
+                    let mut relay = start_relay(new_relay);
+                    for direction in 0..2 {
+                        let reader = types::Fd(relay.fds[direction].as_raw_fd());
+                        let read = relay.directions[direction].read(reader);
+                        push(&mut ring, &read.user_data(user_data(slot, direction)))?;
+                    }
+                    relays[slot] = Some(relay);
+                }
+                push(&mut ring, &wake_read)?;
+                continue;
+            }
+
+            let slot = (completion.user_data() >> 1) as usize;
+            let direction = (completion.user_data() & 1) as usize;
+            let Some(relay) = relays[slot].as_mut() else {
+                continue;
+            };
+            if let Some(entry) = relay.advance(direction, completion.result()) {
+                push(&mut ring, &entry.user_data(user_data(slot, direction)))?;
+            }
+            if relay.is_done() {
+                let relay = relays[slot].take().unwrap();
+                free_slots.push(slot);
+                let result = match relay.error {
+                    Some(e) => Err(e),
+                    None => Ok(()),
+                };
+                debug!("io_uring relay finished: {result:?}");
+                // The relay may have been dropped.
+                let _ = relay.done.send(result);
+            }
+        }
+    }
+}
+
+fn start_relay(new_relay: NewRelay) -> Relay {
+    let NewRelay {
+        fds,
+        counters: [a_to_b_counter, b_to_a_counter],
+        done,
+    } = new_relay;
+    let size = buffer_pool::relay_buffer_size();
+    let direction = |counter| Direction {
+        state: State::Reading,
+        buf: buffer_pool::take(size),
+        start: 0,
+        end: 0,
+        counter,
+    };
+    Relay {
+        fds,
+        directions: [direction(a_to_b_counter), direction(b_to_a_counter)],
+        error: None,
+        done,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::metrics::Counter;
+
+    async fn tcp_pair() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (client, server) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        (client.unwrap(), server.unwrap().0)
+    }
+
+    #[tokio::test]
+    async fn test_relay_bidirectional() {
+        if !is_available() {
+            // io_uring is unavailable, e.g. in a container.
+            return;
+        }
+        let (mut client, a) = tcp_pair().await;
+        let (b, mut remote) = tcp_pair().await;
+
+        let up = Arc::new(Counter::default());
+        let mut up_counter = ByteCounter::default();
+        up_counter.push(up.clone());
+        let relay = tokio::spawn(async move {
+            relay_bidirectional(&a, &b, up_counter, ByteCounter::default()).await
+        });
+
+        let data = vec![7u8; 1024 * 1024];
+        let writer = {
+            let data = data.clone();
+            tokio::spawn(async move {
+                client.write_all(&data).await.unwrap();
+                client.shutdown().await.unwrap();
+                let mut response = Vec::new();
+                client.read_to_end(&mut response).await.unwrap();
+                response
+            })
+        };
+
+        let mut received = Vec::new();
+        remote.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+        remote.write_all(b"done").await.unwrap();
+        remote.shutdown().await.unwrap();
+
+        assert_eq!(writer.await.unwrap(), b"done");
+        relay.await.unwrap().unwrap();
+        assert_eq!(up.get(), data.len() as u64);
+    }
+
+    #[tokio::test]
+    async fn test_uring_acceptor() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let Some(mut acceptor) = UringAcceptor::new(&listener) else {
+            return;
+        };
+
+        for _ in 0..3 {
+            let client = TcpStream::connect(addr).await.unwrap();
+            // Multishot accept needs Linux 5.19.
+            let Some(accepted) = acceptor.accept().await else {
+                return;
+            };
+            let (_stream, peer) = accepted.unwrap();
+            assert_eq!(peer, client.local_addr().unwrap());
+        }
+    }
+}
@@ -0,0 +1,366 @@
+//! Users of servers with per-user credentials.
+//!
+//! Servers check clients against the users of a `UserIndex`, which combines the inline
+//! credential of the server with the users of its `user_group`. The stores of user
+//! groups are registered by name, so that users can be added and removed through the
+//! Clash API or by reloading the config, without restarting the servers.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+
+use parking_lot::{Mutex, RwLock};
+
+use crate::config::{UserConfig, UserGroupConfig};
+use crate::uuid_util::parse_uuid;
+
+/// Checks that a user has a name and a credential, that its user_id is a UUID and that
+/// its rate limits are valid.
+pub fn validate_user(user: &UserConfig) -> std::io::Result<()> {
+    if user.name.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "user name cannot be empty",
+        ));
+    }
+    if user.password.is_none() && user.user_id.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("user {} needs a password or a user_id", user.name),
+        ));
+    }
+    if let Some(user_id) = &user.user_id {
+        parse_uuid(user_id)?;
+    }
+    if let Some(rate_limit) = &user.rate_limit {
+        rate_limit.parse()?;
+    }
+    Ok(())
+}
+
+/// The users of a user group.
+#[derive(Debug, Default)]
+pub struct UserStore {
+    /// Replaced on every change, so that indexes can tell when to rebuild.
+    users: RwLock<Arc<Vec<UserConfig>>>,
+}
+
+impl UserStore {
+    pub fn new(users: Vec<UserConfig>) -> Self {
+        Self {
+            users: RwLock::new(Arc::new(users)),
+        }
+    }
+
+    pub fn users(&self) -> Arc<Vec<UserConfig>> {
+        self.users.read().clone()
+    }
+
+    fn set_users(&self, users: Vec<UserConfig>) {
+        let mut current = self.users.write();
+        if **current != users {
+            *current = Arc::new(users);
+        }
+    }
+
+    /// Adds a user, replacing the user with the same name.
+    pub fn add(&self, user: UserConfig) -> std::io::Result<()> {
+        validate_user(&user)?;
+        let mut current = self.users.write();
+        let mut users: Vec<UserConfig> = current
+            .iter()
+            .filter(|existing| existing.name != user.name)
+            .cloned()
+            .collect();
+        users.push(user);
+        *current = Arc::new(users);
+        Ok(())
+    }
+
+    /// Removes a user, returns false if there is no user with the name.
+    pub fn remove(&self, name: &str) -> bool {
+        let mut current = self.users.write();
+        if !current.iter().any(|user| user.name == name) {
+            return false;
+        }
+        let users = current
+            .iter()
+            .filter(|user| user.name != name)
+            .cloned()
+            .collect();
+        *current = Arc::new(users);
+        true
+    }
+}
+
+static USER_STORES: LazyLock<Mutex<HashMap<String, Arc<UserStore>>>> =
+    LazyLock::new(Default::default);
+
+/// Sets the users of the configured user groups. Stores of groups that are still
+/// configured are kept, so that running servers see the new users.
+pub fn configure(groups: Vec<UserGroupConfig>) {
+    configure_stores(&mut USER_STORES.lock(), groups);
+}
+
+fn configure_stores(stores: &mut HashMap<String, Arc<UserStore>>, groups: Vec<UserGroupConfig>) {
+    let mut configured = HashMap::with_capacity(groups.len());
+    for group in groups {
+        let store = match stores.remove(&group.user_group) {
+            Some(store) => {
+                store.set_users(group.users);
+                store
+            }
+            None => Arc::new(UserStore::new(group.users)),
+        };
+        configured.insert(group.user_group, store);
+    }
+    *stores = configured;
+}
+
+pub fn get_user_store(name: &str) -> Option<Arc<UserStore>> {
+    USER_STORES.lock().get(name).cloned()
+}
+
+/// Returns the user groups and their stores, sorted by name.
+pub fn user_stores() -> Vec<(String, Arc<UserStore>)> {
+    let mut stores: Vec<(String, Arc<UserStore>)> = USER_STORES
+        .lock()
+        .iter()
+        .map(|(name, store)| (name.clone(), store.clone()))
+        .collect();
+    stores.sort_by(|a, b| a.0.cmp(&b.0));
+    stores
+}
+
+type BuildFn<T> = Box<dyn Fn(&[UserConfig]) -> T + Send + Sync>;
+
+/// A lookup structure of a protocol, e.g. users by user_id, built from the users of a
+/// server and rebuilt when the users of its group change.
+pub struct UserIndex<T> {
+    /// Users of the inline credential of the server.
+    inline_users: Vec<UserConfig>,
+    store: Option<Arc<UserStore>>,
+    build: BuildFn<T>,
+    /// The store users the index was built from.
+    cache: Mutex<(Arc<Vec<UserConfig>>, Arc<T>)>,
+}
+
+impl<T> std::fmt::Debug for UserIndex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UserIndex")
+            .field("inline_users", &self.inline_users.len())
+            .field("has_store", &self.store.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T> UserIndex<T> {
+    pub fn new(
+        inline_users: Vec<UserConfig>,
+        store: Option<Arc<UserStore>>,
+        build: impl Fn(&[UserConfig]) -> T + Send + Sync + 'static,
+    ) -> Self {
+        let store_users = store
+            .as_ref()
+            .map(|store| store.users())
+            .unwrap_or_default();
+        let index = build(&Self::all_users(&inline_users, &store_users));
+        Self {
+            inline_users,
+            store,
+            build: Box::new(build),
+            cache: Mutex::new((store_users, Arc::new(index))),
+        }
+    }
+
+    fn all_users(inline_users: &[UserConfig], store_users: &[UserConfig]) -> Vec<UserConfig> {
+        inline_users.iter().chain(store_users).cloned().collect()
+    }
+
+    pub fn get(&self) -> Arc<T> {
+        let Some(store) = &self.store else {
+            return self.cache.lock().1.clone();
+        };
+        let store_users = store.users();
+        let mut cache = self.cache.lock();
+        if !Arc::ptr_eq(&cache.0, &store_users) {
+            let index = (self.build)(&Self::all_users(&self.inline_users, &store_users));
+            *cache = (store_users, Arc::new(index));
+        }
+        cache.1.clone()
+    }
+}
+
+/// Inline user of a server, or None if the server only has a user group. Only SOCKS
+/// users have a name, the username.
+pub fn inline_user(
+    name: String,
+    password: Option<String>,
+    user_id: Option<String>,
+) -> Option<UserConfig> {
+    if password.is_none() && user_id.is_none() {
+        return None;
+    }
+    Some(UserConfig {
+        name,
+        password,
+        user_id,
+        rate_limit: None,
+    })
+}
+
+/// Creates the index of a server from its inline user and user group, which was
+/// checked to exist when loading the config.
+pub fn create_user_index<T>(
+    inline_user: Option<UserConfig>,
+    user_group: Option<&str>,
+    build: impl Fn(&[UserConfig]) -> T + Send + Sync + 'static,
+) -> UserIndex<T> {
+    let store = user_group.map(|name| {
+        get_user_store(name)
+            .unwrap_or_else(|| panic!("Unknown user group {name} (should be validated)"))
+    });
+    UserIndex::new(inline_user.into_iter().collect(), store, build)
+}
+
+/// Users by user_id, for protocols that identify users by UUID.
+pub fn users_by_user_id(users: &[UserConfig]) -> HashMap<Vec<u8>, UserConfig> {
+    users
+        .iter()
+        .filter_map(|user| {
+            let user_id = parse_uuid(user.user_id.as_ref()?).ok()?;
+            Some((user_id, user.clone()))
+        })
+        .collect()
+}
+
+tokio::task_local! {
+    static AUTHENTICATED_USER: RefCell<Option<String>>;
+}
+
+/// Records the name of the user that the client of the current connection authenticated
+/// as. Inline users without a name are not recorded.
+pub fn set_authenticated_user(name: &str) {
+    if name.is_empty() {
+        return;
+    }
+    let _ = AUTHENTICATED_USER.try_with(|user| *user.borrow_mut() = Some(name.to_string()));
+}
+
+/// Runs the setup of a connection, and returns its output along with the user that the
+/// handlers authenticated.
+pub async fn with_authenticated_user<F: Future>(future: F) -> (F::Output, Option<String>) {
+    AUTHENTICATED_USER
+        .scope(RefCell::new(None), async move {
+            let output = future.await;
+            let user = AUTHENTICATED_USER.with(|user| user.take());
+            (output, user)
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RateLimitConfig;
+
+    fn user(name: &str, password: &str) -> UserConfig {
+        UserConfig {
+            name: name.to_string(),
+            password: Some(password.to_string()),
+            user_id: None,
+            rate_limit: None,
+        }
+    }
+
+    fn passwords(users: &[UserConfig]) -> Vec<String> {
+        users
+            .iter()
+            .filter_map(|user| user.password.clone())
+            .collect()
+    }
+
+    #[test]
+    fn test_validate_user() {
+        assert!(validate_user(&user("alice", "secret")).is_ok());
+        assert!(validate_user(&user("", "secret")).is_err());
+        let mut no_credential = user("alice", "secret");
+        no_credential.password = None;
+        assert!(validate_user(&no_credential).is_err());
+        no_credential.user_id = Some("not a uuid".to_string());
+        assert!(validate_user(&no_credential).is_err());
+        no_credential.user_id = Some("b85798ef-e9dc-46a4-9a87-8da4499d36d0".to_string());
+        assert!(validate_user(&no_credential).is_ok());
+        no_credential.rate_limit = Some(RateLimitConfig {
+            up: Some("0".to_string()),
+            down: None,
+        });
+        assert!(validate_user(&no_credential).is_err());
+    }
+
+    #[test]
+    fn test_user_index_follows_store() {
+        let store = Arc::new(UserStore::new(vec![user("alice", "a")]));
+        let index = UserIndex::new(vec![user("inline", "i")], Some(store.clone()), passwords);
+        assert_eq!(*index.get(), vec!["i", "a"]);
+
+        store.add(user("bob", "b")).unwrap();
+        assert_eq!(*index.get(), vec!["i", "a", "b"]);
+
+        // Adding a user with the same name replaces it.
+        store.add(user("alice", "a2")).unwrap();
+        assert_eq!(*index.get(), vec!["i", "b", "a2"]);
+
+        assert!(store.remove("bob"));
+        assert!(!store.remove("bob"));
+        assert_eq!(*index.get(), vec!["i", "a2"]);
+
+        // The index is only rebuilt when the users change.
+        assert!(Arc::ptr_eq(&index.get(), &index.get()));
+    }
+
+    #[test]
+    fn test_configure_keeps_stores() {
+        let mut stores = HashMap::new();
+        configure_stores(
+            &mut stores,
+            vec![UserGroupConfig {
+                user_group: "group".to_string(),
+                users: vec![user("alice", "a")],
+            }],
+        );
+        let store = stores["group"].clone();
+        store.add(user("bob", "b")).unwrap();
+
+        // Reloading replaces the users added at runtime.
+        configure_stores(
+            &mut stores,
+            vec![UserGroupConfig {
+                user_group: "group".to_string(),
+                users: vec![user("carol", "c")],
+            }],
+        );
+        assert!(Arc::ptr_eq(&store, &stores["group"]));
+        assert_eq!(passwords(&store.users()), vec!["c"]);
+
+        configure_stores(&mut stores, vec![]);
+        assert!(stores.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authenticated_user() {
+        let (output, user) = with_authenticated_user(async {
+            set_authenticated_user("alice");
+            1
+        })
+        .await;
+        assert_eq!(output, 1);
+        assert_eq!(user.as_deref(), Some("alice"));
+
+        let (_, user) = with_authenticated_user(async { set_authenticated_user("") }).await;
+        assert_eq!(user, None);
+
+        // Outside of a connection setup, the user is ignored.
+        set_authenticated_user("bob");
+    }
+}
@@ -24,3 +24,55 @@ pub async fn write_all<T: AsyncWriteExt + Unpin>(
     }
     Ok(())
 }
+
+/// Matches a Host header against `pattern`, which may start with `*.` to match any
+/// subdomain. The port of the header is ignored unless the pattern has one.
+pub fn host_matches(pattern: &str, request_host: &str) -> bool {
+    let request_host = if strip_host_port(pattern).len() != pattern.len() {
+        request_host
+    } else {
+        strip_host_port(request_host)
+    };
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => request_host
+            .len()
+            .checked_sub(suffix.len() + 1)
+            .is_some_and(|dot| {
+                dot > 0
+                    && request_host.as_bytes()[dot] == b'.'
+                    && request_host[dot + 1..].eq_ignore_ascii_case(suffix)
+            }),
+        None => request_host.eq_ignore_ascii_case(pattern),
+    }
+}
+
+/// Removes the port from a Host header, e.g. `example.com:8080` or `[::1]:80`.
+pub fn strip_host_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // [ipv6]:port
+        return host
+            .split_once(']')
+            .map_or(host, |(ip, _)| &host[..ip.len() + 1]);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_host_matches() {
+        assert!(host_matches("example.com", "Example.com"));
+        assert!(host_matches("example.com", "example.com:443"));
+        assert!(!host_matches("example.com:80", "example.com:443"));
+        assert!(!host_matches("example.com", "www.example.com"));
+        assert!(host_matches("*.example.com", "cdn.example.com:8443"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "badexample.com"));
+        assert!(host_matches("[::1]", "[::1]:443"));
+    }
+}
@@ -1490,4 +1490,19 @@ where
     }
 }
 
-impl<IO> AsyncStream for VisionStream<IO> where IO: AsyncStream {}
+impl<IO> AsyncStream for VisionStream<IO>
+where
+    IO: AsyncStream,
+{
+    fn as_tcp_stream(&mut self) -> Option<&mut tokio::net::TcpStream> {
+        // Data passes through unchanged once both directions are in direct mode.
+        if self.read_mode == VisionMode::Direct
+            && self.write_mode == VisionMode::Direct
+            && self.pending_read.is_empty()
+        {
+            self.tcp.as_tcp_stream()
+        } else {
+            None
+        }
+    }
+}
@@ -5,18 +5,21 @@ use crate::address::{Address, NetLocation, ResolvedLocation};
 use crate::async_stream::AsyncMessageStream;
 use crate::async_stream::AsyncStream;
 use crate::crypto::CryptoTlsStream;
+use crate::mux::{is_mux_cool_location, mux_cool_location};
 use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
 use crate::util::{allocate_vec, write_all};
 use crate::uuid_util::parse_uuid;
+use crate::xudp::XudpClientStream;
 
 use super::vision_stream::VisionStream;
 use super::vless_message_stream::VlessMessageStream;
 use super::vless_response_stream::VlessResponseStream;
-use super::vless_util::{COMMAND_TCP, COMMAND_UDP, vision_flow_addon_data};
+use super::vless_util::{COMMAND_MUX, COMMAND_TCP, COMMAND_UDP, vision_flow_addon_data};
 
 pub struct VlessTcpClientHandler {
     user_id: Box<[u8]>,
     udp_enabled: bool,
+    xudp: bool,
 }
 
 impl std::fmt::Debug for VlessTcpClientHandler {
@@ -24,15 +27,17 @@ impl std::fmt::Debug for VlessTcpClientHandler {
         f.debug_struct("VlessTcpClientHandler")
             .field("user_id", &self.user_id)
             .field("udp_enabled", &self.udp_enabled)
+            .field("xudp", &self.xudp)
             .finish()
     }
 }
 
 impl VlessTcpClientHandler {
-    pub fn new(user_id: &str, udp_enabled: bool) -> Self {
+    pub fn new(user_id: &str, udp_enabled: bool, xudp: bool) -> Self {
         Self {
             user_id: parse_uuid(user_id).unwrap().into_boxed_slice(),
             udp_enabled,
+            xudp,
         }
     }
 }
@@ -61,32 +66,45 @@ impl TcpClientHandler for VlessTcpClientHandler {
 
     async fn setup_client_udp_bidirectional(
         &self,
-        mut client_stream: Box<dyn AsyncStream>,
+        client_stream: Box<dyn AsyncStream>,
         target: ResolvedLocation,
     ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
-        write_vless_udp_header(&mut client_stream, &self.user_id, target.location()).await?;
-        client_stream.flush().await?;
-        let response_stream = Box::new(VlessResponseStream::new(client_stream));
-        let message_stream = VlessMessageStream::new(response_stream);
-        Ok(Box::new(message_stream))
+        setup_vless_udp_bidirectional(
+            client_stream,
+            &self.user_id,
+            target.into_location(),
+            self.xudp,
+        )
+        .await
     }
 }
 
 /// Helper function for setup_client_udp_bidirectional that can be called from TlsClientHandler
 /// for Vision VLESS or regular VLESS over TLS.
-pub async fn setup_vless_udp_bidirectional<IO>(
-    mut stream: CryptoTlsStream<IO>,
+///
+/// With `xudp`, packets are sent in XUDP frames over a MUX connection instead of a UDP
+/// connection to the target, which gets full-cone NAT from the server.
+pub async fn setup_vless_udp_bidirectional<S>(
+    mut stream: S,
     user_id: &[u8],
     target: NetLocation,
+    xudp: bool,
 ) -> std::io::Result<Box<dyn AsyncMessageStream>>
 where
-    IO: crate::async_stream::AsyncStream + 'static,
+    S: AsyncStream + 'static,
 {
-    write_vless_udp_header(&mut stream, user_id, &target).await?;
+    if xudp {
+        write_vless_header(&mut stream, user_id, &[], &mux_cool_location()).await?;
+    } else {
+        write_vless_udp_header(&mut stream, user_id, &target).await?;
+    }
     stream.flush().await?;
     let response_stream = Box::new(VlessResponseStream::new(stream));
-    let message_stream = VlessMessageStream::new(response_stream);
-    Ok(Box::new(message_stream))
+    if xudp {
+        Ok(Box::new(XudpClientStream::new(response_stream, target)))
+    } else {
+        Ok(Box::new(VlessMessageStream::new(response_stream)))
+    }
 }
 
 pub async fn setup_custom_tls_vision_vless_client_stream<IO>(
@@ -204,6 +222,13 @@ async fn write_vless_header<S: AsyncWriteExt + Unpin>(
 
     let addon_end = 18 + addon_data.len();
 
+    // Mux connections use the mux command (3), which has no port or address.
+    if is_mux_cool_location(remote_location) {
+        header_bytes.truncate(addon_end + 1);
+        header_bytes[addon_end] = COMMAND_MUX;
+        return write_all(stream, &header_bytes).await;
+    }
+
     // command (1 = tcp)
     header_bytes[addon_end] = COMMAND_TCP;
 
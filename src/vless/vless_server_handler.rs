@@ -1,20 +1,25 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use async_trait::async_trait;
 use log::debug;
-use subtle::ConstantTimeEq;
 use tokio::io::AsyncWriteExt;
 use tokio::net::TcpStream;
 
 use crate::address::NetLocation;
 use crate::async_stream::AsyncStream;
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::config::UserConfig;
 use crate::crypto::CryptoTlsStream;
+use crate::mux::{FIRST_FRAME_PREFIX_LEN, is_tcp_mux_frame, spawn_mux_server_session};
 use crate::resolver::Resolver;
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+use crate::user_store::{
+    UserIndex, create_user_index, inline_user, set_authenticated_user, users_by_user_id,
+};
 use crate::util::write_all;
-use crate::uuid_util::parse_uuid;
 use crate::xudp::XudpMessageStream;
 
 use super::vision_stream::VisionStream;
@@ -24,8 +29,20 @@ use super::vless_util::{
     parse_remote_location_from_reader,
 };
 
+/// VLESS users by user_id.
+pub type VlessUsers = UserIndex<HashMap<Vec<u8>, UserConfig>>;
+
+/// Creates the users of a VLESS server from its user_id, which may be empty, and its
+/// user group.
+pub fn create_vless_users(user_id: &str, user_group: Option<&str>) -> VlessUsers {
+    let inline = (!user_id.is_empty())
+        .then(|| inline_user(String::new(), None, Some(user_id.to_string())))
+        .flatten();
+    create_user_index(inline, user_group, users_by_user_id)
+}
+
 pub struct VlessTcpServerHandler {
-    user_id: Box<[u8]>,
+    users: Arc<VlessUsers>,
     udp_enabled: bool,
     proxy_selector: Arc<ClientProxySelector>,
     resolver: Arc<dyn Resolver>,
@@ -35,7 +52,7 @@ pub struct VlessTcpServerHandler {
 impl std::fmt::Debug for VlessTcpServerHandler {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("VlessTcpServerHandler")
-            .field("user_id", &self.user_id)
+            .field("users", &self.users)
             .field("udp_enabled", &self.udp_enabled)
             .field("fallback", &self.fallback)
             .finish()
@@ -44,14 +61,14 @@ impl std::fmt::Debug for VlessTcpServerHandler {
 
 impl VlessTcpServerHandler {
     pub fn new(
-        user_id: &str,
+        users: Arc<VlessUsers>,
         udp_enabled: bool,
         proxy_selector: Arc<ClientProxySelector>,
         resolver: Arc<dyn Resolver>,
         fallback: Option<NetLocation>,
     ) -> Self {
         Self {
-            user_id: parse_uuid(user_id).unwrap().into_boxed_slice(),
+            users,
             udp_enabled,
             proxy_selector,
             resolver,
@@ -144,15 +161,16 @@ impl TcpServerHandler for VlessTcpServerHandler {
                 )
                 .await;
             }
-            return Err(std::io::Error::other(format!(
-                "invalid client protocol version, expected 0, got {client_version}"
-            )));
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid client protocol version, expected 0, got {client_version}"),
+            ));
         }
 
         let header = stream_reader.peek_slice(&mut server_stream, 17).await?;
         let target_id = &header[1..17];
 
-        if self.user_id.ct_eq(target_id).unwrap_u8() == 0 {
+        let Some(user) = self.users.get().get(target_id).cloned() else {
             debug!("VLESS UUID mismatch");
             if let Some(ref fallback) = self.fallback {
                 return vless_fallback_to_dest(
@@ -163,8 +181,12 @@ impl TcpServerHandler for VlessTcpServerHandler {
                 )
                 .await;
             }
-            return Err(std::io::Error::other("Unknown user id"));
-        }
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::PermissionDenied,
+                "Unknown user id",
+            ));
+        };
+        set_authenticated_user(&user.name);
 
         stream_reader.consume(17);
 
@@ -227,6 +249,22 @@ impl TcpServerHandler for VlessTcpServerHandler {
                 })
             }
             COMMAND_MUX => {
+                // The first frame tells mux.cool TCP sub-connections from XUDP.
+                let prefix = stream_reader
+                    .peek_slice(&mut server_stream, FIRST_FRAME_PREFIX_LEN)
+                    .await?;
+                if is_tcp_mux_frame(prefix) {
+                    let unparsed_data = stream_reader.unparsed_data_owned();
+                    write_all(&mut server_stream, SERVER_RESPONSE_HEADER).await?;
+                    spawn_mux_server_session(
+                        server_stream,
+                        unparsed_data,
+                        self.proxy_selector.clone(),
+                        self.resolver.clone(),
+                    );
+                    return Ok(TcpServerSetupResult::AlreadyHandled);
+                }
+
                 if !self.udp_enabled {
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
@@ -261,7 +299,7 @@ impl TcpServerHandler for VlessTcpServerHandler {
 /// Setup a VISION+VLESS stream from a CryptoTlsStream (for REALITY+Vision support)
 pub async fn setup_custom_tls_vision_vless_server_stream<IO>(
     mut tls_stream: CryptoTlsStream<IO>,
-    user_id: &[u8],
+    users: &VlessUsers,
     udp_enabled: bool,
     proxy_selector: Arc<ClientProxySelector>,
     resolver: &Arc<dyn Resolver>,
@@ -281,22 +319,26 @@ where
         if let Some(ref fb) = fallback {
             return vless_fallback_to_dest(tls_stream, stream_reader, fb, resolver).await;
         }
-        return Err(std::io::Error::other(format!(
-            "invalid client protocol version, expected 0, got {client_version}"
-        )));
+        return Err(auto_ban::rejected(
+            std::io::ErrorKind::InvalidData,
+            format!("invalid client protocol version, expected 0, got {client_version}"),
+        ));
     }
 
     let header = stream_reader.peek_slice(&mut tls_stream, 17).await?;
     let target_id = &header[1..17];
 
-    // Verify user ID using constant-time comparison to prevent timing attacks
-    if user_id.ct_eq(target_id).unwrap_u8() == 0 {
+    let Some(user) = users.get().get(target_id).cloned() else {
         debug!("VLESS/Vision UUID mismatch");
         if let Some(ref fb) = fallback {
             return vless_fallback_to_dest(tls_stream, stream_reader, fb, resolver).await;
         }
-        return Err(std::io::Error::other("Unknown user id"));
-    }
+        return Err(auto_ban::rejected(
+            std::io::ErrorKind::PermissionDenied,
+            "Unknown user id",
+        ));
+    };
+    set_authenticated_user(&user.name);
 
     // Both checks passed - copy UUID for VisionStream, then consume version + UUID
     let mut user_uuid = [0u8; 16];
@@ -1,6 +1,6 @@
 use std::net::{Ipv4Addr, Ipv6Addr};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use async_trait::async_trait;
 use aws_lc_rs::aead::{
@@ -14,7 +14,7 @@ use bytes::BytesMut;
 use rand::{Rng, RngCore};
 use sha3::Shake128;
 use sha3::digest::{ExtendableOutput, Update};
-use tokio::io::AsyncWriteExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
 use super::fnv1a::Fnv1aHasher;
 use super::md5::{compute_md5, create_chacha_key};
@@ -22,11 +22,18 @@ use super::nonce::{SingleUseNonce, VmessNonceSequence};
 use super::vmess_stream::{ReadHeaderInfo, VmessStream};
 use crate::address::{Address, NetLocation, ResolvedLocation};
 use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::auto_ban;
 use crate::client_proxy_selector::ClientProxySelector;
+use crate::mux::{
+    FIRST_FRAME_PREFIX_LEN, is_mux_cool_location, is_tcp_mux_frame, spawn_mux_server_session,
+};
+use crate::replay_filter::ReplayFilter;
+use crate::resolver::Resolver;
 use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
+use crate::user_store::{UserIndex, create_user_index, inline_user, set_authenticated_user};
 use crate::util::{allocate_vec, write_all};
 use crate::uuid_util::parse_uuid;
 use crate::xudp::XudpMessageStream;
@@ -38,34 +45,152 @@ const COMMAND_TCP: u8 = 1;
 const COMMAND_UDP: u8 = 2;
 const COMMAND_MUX: u8 = 3; // MUX/XUDP mode
 
+// Request options, see headers.pb.go in v2ray-core.
+const OPTION_CHUNK_STREAM: u8 = 0x01;
+const OPTION_CHUNK_MASKING: u8 = 0x04;
+const OPTION_GLOBAL_PADDING: u8 = 0x08;
+const OPTION_AUTHENTICATED_LENGTH: u8 = 0x10;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum DataCipher {
     Any,
     Aes128Gcm,
     ChaCha20Poly1305,
     None,
+    /// No encryption and no chunk masking.
+    Zero,
 }
 
 impl From<&str> for DataCipher {
     fn from(name: &str) -> Self {
-        match name {
-            "" | "any" => DataCipher::Any,
-            "aes-128-gcm" => DataCipher::Aes128Gcm,
-            "chacha20-poly1305" | "chacha20-ietf-poly1305" => DataCipher::ChaCha20Poly1305,
-            "none" => DataCipher::None,
-            _ => {
+        match DataCipher::parse(name) {
+            Some(data_cipher) => data_cipher,
+            None => {
                 panic!("Unknown cipher: {name}");
             }
         }
     }
 }
 
-pub struct VmessTcpServerHandler {
-    data_cipher: DataCipher,
+impl DataCipher {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "" | "any" | "auto" => Some(DataCipher::Any),
+            "aes-128-gcm" => Some(DataCipher::Aes128Gcm),
+            "chacha20-poly1305" | "chacha20-ietf-poly1305" => Some(DataCipher::ChaCha20Poly1305),
+            "none" => Some(DataCipher::None),
+            "zero" => Some(DataCipher::Zero),
+            _ => None,
+        }
+    }
+
+    /// The cipher that clients use for `any`: AES-128-GCM on architectures with AES
+    /// instructions, and ChaCha20-Poly1305 elsewhere, like v2ray's `auto`.
+    fn auto() -> Self {
+        if cfg!(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "s390x"
+        )) {
+            DataCipher::Aes128Gcm
+        } else {
+            DataCipher::ChaCha20Poly1305
+        }
+    }
+}
+
+/// Returns true if `name` is a cipher (`security` in v2ray) of the `cipher` config field.
+pub fn is_supported_cipher(name: &str) -> bool {
+    DataCipher::parse(name).is_some()
+}
+
+/// Returns the data cipher of a request from its security nibble and option byte.
+///
+/// The developer docs have incorrect values for the security type, see headers.pb.go in
+/// v2ray-core for the correct values. The `zero` security is sent as none without the
+/// chunk stream option, like v2ray and Xray do.
+fn requested_data_cipher(security: u8, option: u8) -> std::io::Result<DataCipher> {
+    if option & OPTION_AUTHENTICATED_LENGTH != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Auth length option is not supported",
+        ));
+    }
+
+    let chunk_stream = option & OPTION_CHUNK_STREAM != 0;
+    let data_cipher = match security {
+        1 => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Unsupported aes-128-cfb data cipher requested",
+            ));
+        }
+        3 => DataCipher::Aes128Gcm,
+        4 => DataCipher::ChaCha20Poly1305,
+        5 if chunk_stream => DataCipher::None,
+        5 => DataCipher::Zero,
+        unknown_cipher_type => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Unknown requested cipher: {unknown_cipher_type}"),
+            ));
+        }
+    };
+
+    if !chunk_stream && data_cipher != DataCipher::Zero {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Standard format data stream was not requested",
+        ));
+    }
+
+    Ok(data_cipher)
+}
+
+/// Keys of a VMess server user.
+struct VmessUserKeys {
+    name: String,
     instruction_key: [u8; 16],
     aead_decrypting_key: CipherDecryptingKey,
+}
+
+impl VmessUserKeys {
+    fn new(name: String, user_id: &str) -> std::io::Result<Self> {
+        let mut user_id_bytes = parse_uuid(user_id)?;
+        user_id_bytes.extend(b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
+        let instruction_key: [u8; 16] = compute_md5(&user_id_bytes);
+
+        let derived_key = super::sha2::kdf(&instruction_key, &[b"AES Auth ID Encryption"]);
+        let unbound_key = UnboundCipherKey::new(&AES_128, &derived_key[0..16]).unwrap();
+        let aead_decrypting_key = CipherDecryptingKey::ecb(unbound_key).unwrap();
+        Ok(Self {
+            name,
+            instruction_key,
+            aead_decrypting_key,
+        })
+    }
+
+    /// Decrypts the AEAD auth ID, and returns it if it was encrypted with this user's key.
+    fn decrypt_auth_id(&self, cert_hash: &[u8; 16]) -> Option<[u8; 16]> {
+        let mut aead_bytes = *cert_hash;
+        self.aead_decrypting_key
+            .decrypt(&mut aead_bytes, DecryptionContext::None)
+            .ok()?;
+        let checksum = super::crc32::crc32c(&aead_bytes[0..12]);
+        let expected_checksum = u32::from_be_bytes(aead_bytes[12..16].try_into().unwrap());
+        (checksum == expected_checksum).then_some(aead_bytes)
+    }
+}
+
+pub struct VmessTcpServerHandler {
+    data_cipher: DataCipher,
+    users: UserIndex<Vec<VmessUserKeys>>,
     udp_enabled: bool,
+    max_time_diff_secs: u64,
+    /// Auth IDs seen while their timestamp is accepted.
+    replay_filter: ReplayFilter,
     proxy_selector: Arc<ClientProxySelector>,
+    resolver: Arc<dyn Resolver>,
 }
 
 impl std::fmt::Debug for VmessTcpServerHandler {
@@ -73,31 +198,46 @@ impl std::fmt::Debug for VmessTcpServerHandler {
         f.debug_struct("VmessTcpServerHandler")
             .field("data_cipher", &self.data_cipher)
             .field("udp_enabled", &self.udp_enabled)
+            .field("max_time_diff_secs", &self.max_time_diff_secs)
             .finish_non_exhaustive()
     }
 }
 
 impl VmessTcpServerHandler {
+    /// Creates a server that accepts `user_id`, which may be empty, and the users of
+    /// `user_group`.
     pub fn new(
         cipher_name: &str,
         user_id: &str,
+        user_group: Option<&str>,
         udp_enabled: bool,
+        max_time_diff_secs: u64,
         proxy_selector: Arc<ClientProxySelector>,
+        resolver: Arc<dyn Resolver>,
     ) -> Self {
-        let mut user_id_bytes = parse_uuid(user_id).unwrap();
-        user_id_bytes.extend(b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
-        let instruction_key: [u8; 16] = compute_md5(&user_id_bytes);
-
-        let derived_key = super::sha2::kdf(&instruction_key, &[b"AES Auth ID Encryption"]);
-        let unbound_key = UnboundCipherKey::new(&AES_128, &derived_key[0..16]).unwrap();
-        let aead_decrypting_key = CipherDecryptingKey::ecb(unbound_key).unwrap();
+        let inline = (!user_id.is_empty())
+            .then(|| inline_user(String::new(), None, Some(user_id.to_string())))
+            .flatten();
+        let users = create_user_index(inline, user_group, |users| {
+            users
+                .iter()
+                .filter_map(|user| {
+                    VmessUserKeys::new(user.name.clone(), user.user_id.as_ref()?).ok()
+                })
+                .collect()
+        });
 
         Self {
             data_cipher: cipher_name.into(),
-            aead_decrypting_key,
-            instruction_key,
+            users,
             udp_enabled,
+            max_time_diff_secs,
+            replay_filter: ReplayFilter::new(
+                "vmess",
+                Duration::from_secs(max_time_diff_secs.saturating_mul(2)),
+            ),
             proxy_selector,
+            resolver,
         }
     }
 }
@@ -115,38 +255,36 @@ impl TcpServerHandler for VmessTcpServerHandler {
             .read_slice_into(&mut server_stream, &mut cert_hash)
             .await?;
 
-        // we need to copy it over because if this is an aead request, we need the original
-        // bytes for decrypting the header.
-        let mut aead_bytes = [0u8; 16];
-        aead_bytes.copy_from_slice(&cert_hash);
-
-        self.aead_decrypting_key
-            .decrypt(&mut aead_bytes, DecryptionContext::None)
-            .map_err(|_| {
-                std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "AEAD auth ID decryption failed",
-                )
-            })?;
-        let checksum = super::crc32::crc32c(&aead_bytes[0..12]);
-        let expected_checksum = u32::from_be_bytes(aead_bytes[12..16].try_into().unwrap());
-
-        if checksum != expected_checksum {
-            return Err(std::io::Error::new(
+        // The auth ID is decrypted with the key of each user, until the checksum matches.
+        // The original bytes are still needed for decrypting the header.
+        let users = self.users.get();
+        let Some((user, aead_bytes)) = users
+            .iter()
+            .find_map(|user| Some((user, user.decrypt_auth_id(&cert_hash)?)))
+        else {
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::InvalidData,
                 "AEAD authentication failed: checksum mismatch",
             ));
-        }
+        };
 
         let time_secs = u64::from_be_bytes(aead_bytes[0..8].try_into().unwrap());
         let current_time_secs = SystemTime::UNIX_EPOCH.elapsed().unwrap().as_secs();
         let time_delta = time_secs.abs_diff(current_time_secs);
-        if time_delta > 120 {
-            return Err(std::io::Error::new(
+        if time_delta > self.max_time_diff_secs {
+            return Err(auto_ban::rejected(
                 std::io::ErrorKind::InvalidData,
                 format!("Hash timestamp is too old ({time_secs} is {time_delta} seconds old)"),
             ));
         }
+        if !self.replay_filter.check(&cert_hash) {
+            return Err(auto_ban::rejected(
+                std::io::ErrorKind::InvalidData,
+                "AEAD authentication failed: replayed auth ID",
+            ));
+        }
+        let instruction_key = user.instruction_key;
+        set_authenticated_user(&user.name);
 
         let mut encrypted_payload_length = [0u8; 18];
         stream_reader
@@ -159,12 +297,12 @@ impl TcpServerHandler for VmessTcpServerHandler {
             .await?;
 
         let header_length_aead_key = super::sha2::kdf(
-            &self.instruction_key,
+            &instruction_key,
             &[b"VMess Header AEAD Key_Length", &cert_hash, &nonce],
         );
 
         let header_length_nonce = super::sha2::kdf(
-            &self.instruction_key,
+            &instruction_key,
             &[b"VMess Header AEAD Nonce_Length", &cert_hash, &nonce],
         );
 
@@ -188,12 +326,12 @@ impl TcpServerHandler for VmessTcpServerHandler {
         let payload_length = u16::from_be_bytes(encrypted_payload_length[0..2].try_into().unwrap());
 
         let header_aead_key = super::sha2::kdf(
-            &self.instruction_key,
+            &instruction_key,
             &[b"VMess Header AEAD Key", &cert_hash, &nonce],
         );
 
         let header_nonce = super::sha2::kdf(
-            &self.instruction_key,
+            &instruction_key,
             &[b"VMess Header AEAD Nonce", &cert_hash, &nonce],
         );
 
@@ -361,22 +499,12 @@ impl TcpServerHandler for VmessTcpServerHandler {
         let response_authentication_v = fixed_header[33];
         let option = fixed_header[34];
 
-        if option & 0x01 != 0x01 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Standard format data stream was not requested",
-            ));
-        }
+        let requested_data_cipher = requested_data_cipher(fixed_header[35] & 0b1111, option)?;
 
-        if option & 0x10 == 0x10 {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Auth length option is not supported",
-            ));
-        }
-
-        let enable_chunk_masking = option & 0x04 == 0x04;
-        let enable_global_padding = option & 0x08 == 0x08;
+        // Zero sends data as is, so the packet options don't apply.
+        let chunk_stream = requested_data_cipher != DataCipher::Zero;
+        let enable_chunk_masking = chunk_stream && option & OPTION_CHUNK_MASKING != 0;
+        let enable_global_padding = chunk_stream && option & OPTION_GLOBAL_PADDING != 0;
 
         if enable_global_padding && !enable_chunk_masking {
             return Err(std::io::Error::new(
@@ -385,26 +513,6 @@ impl TcpServerHandler for VmessTcpServerHandler {
             ));
         }
 
-        // the developer docs have incorrect values for the data type,
-        // see headers.pb.go in v2ray-core for the correct values.
-        let requested_data_cipher = match fixed_header[35] & 0b1111 {
-            1 => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Unsupported aes-128-cfb data cipher requested",
-                ));
-            }
-            3 => DataCipher::Aes128Gcm,
-            4 => DataCipher::ChaCha20Poly1305,
-            5 => DataCipher::None,
-            unknown_cipher_type => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unknown requested cipher: {unknown_cipher_type}"),
-                ));
-            }
-        };
-
         if self.data_cipher != DataCipher::Any && requested_data_cipher != self.data_cipher {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
@@ -448,7 +556,7 @@ impl TcpServerHandler for VmessTcpServerHandler {
                         .unwrap(),
                 ))
             }
-            DataCipher::None => None,
+            DataCipher::None | DataCipher::Zero => None,
             DataCipher::Any => unreachable!(),
         };
 
@@ -537,6 +645,7 @@ impl TcpServerHandler for VmessTcpServerHandler {
                     read_length_shake_reader,
                     write_length_shake_reader,
                     enable_global_padding,
+                    chunk_stream,
                     Some(prefix_bytes),
                     None,
                 );
@@ -565,6 +674,13 @@ impl TcpServerHandler for VmessTcpServerHandler {
                         "UDP not enabled",
                     ));
                 }
+                if !chunk_stream {
+                    // Packets can't be told apart without chunks.
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "UDP is not supported with the zero cipher",
+                    ));
+                }
 
                 let mut vmess_stream = VmessStream::new(
                     server_stream,
@@ -573,6 +689,7 @@ impl TcpServerHandler for VmessTcpServerHandler {
                     read_length_shake_reader,
                     write_length_shake_reader,
                     enable_global_padding,
+                    chunk_stream,
                     Some(prefix_bytes),
                     None,
                 );
@@ -592,13 +709,6 @@ impl TcpServerHandler for VmessTcpServerHandler {
                 })
             }
             COMMAND_MUX => {
-                if !self.udp_enabled {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        "MUX/XUDP requires UDP to be enabled",
-                    ));
-                }
-
                 // For XUDP mode, use is_udp=false since XUDP wraps the stream
                 let mut vmess_stream = VmessStream::new(
                     server_stream,
@@ -607,6 +717,7 @@ impl TcpServerHandler for VmessTcpServerHandler {
                     read_length_shake_reader,
                     write_length_shake_reader,
                     enable_global_padding,
+                    chunk_stream,
                     Some(prefix_bytes),
                     None,
                 );
@@ -616,11 +727,29 @@ impl TcpServerHandler for VmessTcpServerHandler {
                     vmess_stream.feed_initial_read_data(unparsed_data)?;
                 }
 
-                // Wrap VmessStream with XudpMessageStream for session multiplexing
-                let xudp_stream = XudpMessageStream::new(Box::new(vmess_stream));
+                // The first frame tells mux.cool TCP sub-connections from XUDP.
+                let mut prefix = [0u8; FIRST_FRAME_PREFIX_LEN];
+                vmess_stream.read_exact(&mut prefix).await?;
+                if is_tcp_mux_frame(&prefix) {
+                    spawn_mux_server_session(
+                        Box::new(vmess_stream),
+                        Some(Box::new(prefix)),
+                        self.proxy_selector.clone(),
+                        self.resolver.clone(),
+                    );
+                    return Ok(TcpServerSetupResult::AlreadyHandled);
+                }
 
-                // No unparsed data to feed since VmessStream already consumed it
-                // (XUDP framing starts after VMess header)
+                if !self.udp_enabled {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "MUX/XUDP requires UDP to be enabled",
+                    ));
+                }
+
+                // Wrap VmessStream with XudpMessageStream for session multiplexing
+                let mut xudp_stream = XudpMessageStream::new(Box::new(vmess_stream));
+                xudp_stream.feed_initial_read_data(&prefix)?;
 
                 Ok(TcpServerSetupResult::SessionBasedUdp {
                     stream: Box::new(xudp_stream),
@@ -660,6 +789,8 @@ pub struct VmessTcpClientHandler {
     instruction_key: [u8; 16],
     aead_encrypting_key: CipherEncryptingKey,
     udp_enabled: bool,
+    chunk_masking: bool,
+    global_padding: bool,
 }
 
 impl std::fmt::Debug for VmessTcpClientHandler {
@@ -667,12 +798,22 @@ impl std::fmt::Debug for VmessTcpClientHandler {
         f.debug_struct("VmessTcpClientHandler")
             .field("data_cipher", &self.data_cipher)
             .field("udp_enabled", &self.udp_enabled)
+            .field("chunk_masking", &self.chunk_masking)
+            .field("global_padding", &self.global_padding)
             .finish_non_exhaustive()
     }
 }
 
 impl VmessTcpClientHandler {
-    pub fn new(cipher_name: &str, user_id: &str, udp_enabled: bool) -> Self {
+    /// Creates a client. `chunk_masking` and `global_padding` are the packet options of
+    /// requests, and are turned off by the `zero` cipher.
+    pub fn new(
+        cipher_name: &str,
+        user_id: &str,
+        udp_enabled: bool,
+        chunk_masking: bool,
+        global_padding: bool,
+    ) -> Self {
         let mut user_id_bytes = parse_uuid(user_id).unwrap();
         user_id_bytes.extend(b"c48619fe-8f02-49e0-b9e9-edf763e17e21");
         let instruction_key: [u8; 16] = compute_md5(&user_id_bytes);
@@ -681,12 +822,40 @@ impl VmessTcpClientHandler {
         let unbound_key = UnboundCipherKey::new(&AES_128, &derived_key[0..16]).unwrap();
         let aead_encrypting_key = CipherEncryptingKey::ecb(unbound_key).unwrap();
 
+        let data_cipher = match cipher_name.into() {
+            DataCipher::Any => DataCipher::auto(),
+            data_cipher => data_cipher,
+        };
+        let chunk_masking = chunk_masking && data_cipher != DataCipher::Zero;
+
         Self {
-            data_cipher: cipher_name.into(),
+            data_cipher,
             aead_encrypting_key,
             instruction_key,
             udp_enabled,
+            chunk_masking,
+            global_padding: global_padding && chunk_masking,
+        }
+    }
+
+    /// Whether data is sent in chunks, which the `zero` cipher turns off.
+    fn chunk_stream(&self) -> bool {
+        self.data_cipher != DataCipher::Zero
+    }
+
+    /// The option byte of requests.
+    fn request_options(&self) -> u8 {
+        let mut options = 0;
+        if self.chunk_stream() {
+            options |= OPTION_CHUNK_STREAM;
+        }
+        if self.chunk_masking {
+            options |= OPTION_CHUNK_MASKING;
+        }
+        if self.global_padding {
+            options |= OPTION_GLOBAL_PADDING;
         }
+        options
     }
 }
 
@@ -751,7 +920,7 @@ impl TcpClientHandler for VmessTcpClientHandler {
         let response_header_iv = truncated_iv;
         let response_header_key = truncated_key;
 
-        let (read_length_shake_reader, write_length_shake_reader) = {
+        let (read_length_shake_reader, write_length_shake_reader) = if self.chunk_masking {
             let mut request_hasher = Shake128::default();
             request_hasher.update(data_encryption_iv);
             let request_reader = request_hasher.finalize_xof();
@@ -761,6 +930,8 @@ impl TcpClientHandler for VmessTcpClientHandler {
             let response_reader = response_hasher.finalize_xof();
 
             (Some(response_reader), Some(request_reader))
+        } else {
+            (None, None)
         };
 
         let (encryption_method, unbound_keys) = match self.data_cipher {
@@ -775,7 +946,6 @@ impl TcpClientHandler for VmessTcpClientHandler {
                 )
             }
             DataCipher::ChaCha20Poly1305 | DataCipher::Any => {
-                // default to chacha.
                 // key is 32 bytes
                 (
                     4u8,
@@ -793,7 +963,7 @@ impl TcpClientHandler for VmessTcpClientHandler {
                     )),
                 )
             }
-            DataCipher::None => (5u8, None),
+            DataCipher::None | DataCipher::Zero => (5u8, None),
         };
 
         let data_keys = if let Some((unbound_opening_key, unbound_sealing_key)) = unbound_keys {
@@ -812,43 +982,49 @@ impl TcpClientHandler for VmessTcpClientHandler {
 
         // continue filling out other parts of instructions_to_addr_type.
 
-        // set options, standard format data stream and metadata obfuscation
-        header_bytes[34] = 0x01 | 0x04;
+        header_bytes[34] = self.request_options();
 
         // only 4 bits, generate this now before our first await.
         let margin_len: u8 = rand::random::<u8>() & 0xf;
         header_bytes[35] = (margin_len << 4) | encryption_method;
 
-        // specify tcp protocol
-        header_bytes[37] = 1;
+        let remote_location = remote_location.into_location();
+        let mut cursor = if is_mux_cool_location(&remote_location) {
+            // Mux connections use the mux command, which has no port or address.
+            header_bytes[37] = COMMAND_MUX;
+            38
+        } else {
+            // specify tcp protocol
+            header_bytes[37] = COMMAND_TCP;
 
-        let (remote_address, remote_port) = remote_location.into_location().unwrap_components();
+            let (remote_address, remote_port) = remote_location.unwrap_components();
 
-        header_bytes[38] = (remote_port >> 8) as u8;
-        header_bytes[39] = (remote_port & 0xff) as u8;
+            header_bytes[38] = (remote_port >> 8) as u8;
+            header_bytes[39] = (remote_port & 0xff) as u8;
 
-        let mut cursor = match remote_address {
-            Address::Ipv4(v4addr) => {
-                header_bytes[40] = 1;
-                header_bytes[41..45].copy_from_slice(&v4addr.octets());
-                45
-            }
-            Address::Ipv6(v6addr) => {
-                header_bytes[40] = 3;
-                header_bytes[41..57].copy_from_slice(&v6addr.octets());
-                57
-            }
-            Address::Hostname(hostname) => {
-                if hostname.len() > 255 {
-                    return Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("Hostname is too long: {hostname}"),
-                    ));
+            match remote_address {
+                Address::Ipv4(v4addr) => {
+                    header_bytes[40] = 1;
+                    header_bytes[41..45].copy_from_slice(&v4addr.octets());
+                    45
+                }
+                Address::Ipv6(v6addr) => {
+                    header_bytes[40] = 3;
+                    header_bytes[41..57].copy_from_slice(&v6addr.octets());
+                    57
+                }
+                Address::Hostname(hostname) => {
+                    if hostname.len() > 255 {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Hostname is too long: {hostname}"),
+                        ));
+                    }
+                    header_bytes[40] = 2;
+                    header_bytes[41] = hostname.len() as u8;
+                    header_bytes[42..42 + hostname.len()].copy_from_slice(hostname.as_bytes());
+                    42 + hostname.len()
                 }
-                header_bytes[40] = 2;
-                header_bytes[41] = hostname.len() as u8;
-                header_bytes[42..42 + hostname.len()].copy_from_slice(hostname.as_bytes());
-                42 + hostname.len()
             }
         };
 
@@ -943,7 +1119,8 @@ impl TcpClientHandler for VmessTcpClientHandler {
             data_keys,
             read_length_shake_reader,
             write_length_shake_reader,
-            false,
+            self.global_padding,
+            self.chunk_stream(),
             None,
             Some(read_header_info),
         ));
@@ -955,7 +1132,9 @@ impl TcpClientHandler for VmessTcpClientHandler {
     }
 
     fn supports_udp_over_tcp(&self) -> bool {
-        self.udp_enabled // VMess supports UDP-over-TCP tunneling when enabled
+        // VMess supports UDP-over-TCP tunneling when enabled, except with the zero cipher,
+        // where packets can't be told apart.
+        self.udp_enabled && self.chunk_stream()
     }
 
     async fn setup_client_udp_bidirectional(
@@ -980,6 +1159,12 @@ impl VmessTcpClientHandler {
     ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
         // VMess single-target UDP mode: Send VMess header with COMMAND_UDP (2).
         // Same as TCP setup but with command=2 and is_udp=true for VmessStream.
+        if !self.chunk_stream() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "UDP is not supported with the zero cipher",
+            ));
+        }
 
         // AEAD allows 120 second delta from the current time.
         let random_delta: u64 = rand::rng().random_range(0..241);
@@ -1032,7 +1217,7 @@ impl VmessTcpClientHandler {
         let response_header_iv = truncated_iv;
         let response_header_key = truncated_key;
 
-        let (read_length_shake_reader, write_length_shake_reader) = {
+        let (read_length_shake_reader, write_length_shake_reader) = if self.chunk_masking {
             let mut request_hasher = Shake128::default();
             request_hasher.update(data_encryption_iv);
             let request_reader = request_hasher.finalize_xof();
@@ -1042,6 +1227,8 @@ impl VmessTcpClientHandler {
             let response_reader = response_hasher.finalize_xof();
 
             (Some(response_reader), Some(request_reader))
+        } else {
+            (None, None)
         };
 
         let (encryption_method, unbound_keys) = match self.data_cipher {
@@ -1061,7 +1248,7 @@ impl VmessTcpClientHandler {
                         .unwrap(),
                 )),
             ),
-            DataCipher::None => (5u8, None),
+            DataCipher::None | DataCipher::Zero => (5u8, None),
         };
 
         let data_keys = if let Some((unbound_opening_key, unbound_sealing_key)) = unbound_keys {
@@ -1078,8 +1265,7 @@ impl VmessTcpClientHandler {
             None
         };
 
-        // Set options: standard format data stream and metadata obfuscation
-        header_bytes[34] = 0x01 | 0x04;
+        header_bytes[34] = self.request_options();
 
         // Margin length (4 bits) + encryption method
         let margin_len: u8 = rand::random::<u8>() & 0xf;
@@ -1208,7 +1394,8 @@ impl VmessTcpClientHandler {
             data_keys,
             read_length_shake_reader,
             write_length_shake_reader,
-            false,
+            self.global_padding,
+            true,
             None,
             Some(read_header_info),
         );
@@ -1216,3 +1403,47 @@ impl VmessTcpClientHandler {
         Ok(Box::new(vmess_stream))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER_ID: &str = "b0e80a62-8a51-47f0-91f1-f0f7faf8d9d4";
+
+    #[test]
+    fn test_zero_request_options() {
+        // v2ray and Xray send zero as security 5 (none), with the chunk stream and chunk
+        // masking options cleared.
+        let client = VmessTcpClientHandler::new("zero", USER_ID, true, true, true);
+        assert_eq!(client.request_options(), 0);
+        assert!(!client.supports_udp_over_tcp());
+
+        let client = VmessTcpClientHandler::new("none", USER_ID, true, true, true);
+        assert_eq!(
+            client.request_options(),
+            OPTION_CHUNK_STREAM | OPTION_CHUNK_MASKING | OPTION_GLOBAL_PADDING
+        );
+        assert!(client.supports_udp_over_tcp());
+    }
+
+    #[test]
+    fn test_requested_data_cipher() {
+        assert_eq!(requested_data_cipher(5, 0x00).unwrap(), DataCipher::Zero);
+        assert_eq!(requested_data_cipher(5, 0x01).unwrap(), DataCipher::None);
+        assert_eq!(requested_data_cipher(5, 0x05).unwrap(), DataCipher::None);
+        // Chunk masking only applies to chunks.
+        assert_eq!(requested_data_cipher(5, 0x04).unwrap(), DataCipher::Zero);
+        assert_eq!(
+            requested_data_cipher(3, 0x01).unwrap(),
+            DataCipher::Aes128Gcm
+        );
+        assert_eq!(
+            requested_data_cipher(4, 0x0d).unwrap(),
+            DataCipher::ChaCha20Poly1305
+        );
+        assert!(requested_data_cipher(3, 0x00).is_err());
+        assert!(requested_data_cipher(3, 0x11).is_err());
+        assert!(requested_data_cipher(1, 0x01).is_err());
+        assert!(requested_data_cipher(6, 0x01).is_err());
+    }
+}
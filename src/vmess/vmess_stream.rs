@@ -82,6 +82,9 @@ pub struct VmessStream {
     tag_len: usize,
     read_length_mask: Option<LengthMask>,
     write_length_mask: Option<LengthMask>,
+    /// Whether data is sent in chunks. Without the chunk stream option, as requested by the
+    /// `zero` security, data is sent as is after the headers.
+    chunk_stream: bool,
 
     unprocessed_buf: Box<[u8]>,
     unprocessed_start_offset: usize,
@@ -161,6 +164,7 @@ impl VmessStream {
         read_length_shake_reader: Option<VmessReader>,
         write_length_shake_reader: Option<VmessReader>,
         enable_global_padding: bool,
+        chunk_stream: bool,
         prefix_write_bytes: Option<BytesMut>,
         read_header_info: Option<ReadHeaderInfo>,
     ) -> Self {
@@ -215,6 +219,7 @@ impl VmessStream {
                 .map(|reader| LengthMask::new(reader, enable_global_padding)),
             write_length_mask: write_length_shake_reader
                 .map(|reader| LengthMask::new(reader, enable_global_padding)),
+            chunk_stream,
             unprocessed_buf,
             unprocessed_start_offset: 0,
             unprocessed_end_offset: 0,
@@ -394,6 +399,10 @@ impl VmessStream {
         // returns true if a full packet was decrypted, false if not (ie. more data required)
         let available_len = self.unprocessed_end_offset - self.unprocessed_start_offset;
 
+        if !self.chunk_stream {
+            return Ok(self.process_unchunked(available_len));
+        }
+
         let (padding_len, data_len) = match self.unprocessed_pending_len {
             None => {
                 if available_len < 2 {
@@ -511,6 +520,33 @@ impl VmessStream {
         Ok(DecryptState::Success)
     }
 
+    /// Moves data that isn't sent in chunks to the processed buffer as is.
+    fn process_unchunked(&mut self, available_len: usize) -> DecryptState {
+        if available_len == 0 {
+            return DecryptState::NeedData;
+        }
+        let processed_space = self.processed_buf.len() - self.processed_end_offset;
+        if processed_space == 0 {
+            return DecryptState::BufferFull;
+        }
+
+        let len = std::cmp::min(available_len, processed_space);
+        self.processed_buf[self.processed_end_offset..self.processed_end_offset + len]
+            .copy_from_slice(
+                &self.unprocessed_buf
+                    [self.unprocessed_start_offset..self.unprocessed_start_offset + len],
+            );
+        self.processed_end_offset += len;
+        self.unprocessed_start_offset += len;
+
+        if self.unprocessed_start_offset == self.unprocessed_end_offset {
+            self.unprocessed_start_offset = 0;
+            self.unprocessed_end_offset = 0;
+        }
+
+        DecryptState::Success
+    }
+
     fn read_processed(&mut self, buf: &mut ReadBuf<'_>) {
         assert!(
             self.processed_end_offset > 0,
@@ -553,6 +589,22 @@ impl VmessStream {
 
         // note that this should allow creating an empty packet.
         let write_packet_space = self.write_packet.len() - self.write_packet_end_offset;
+
+        if !self.chunk_stream {
+            // There is no empty packet at the end, the stream is just shut down.
+            if self.write_cache_size > 0 && write_packet_space == 0 {
+                return false;
+            }
+            let data_size = std::cmp::min(write_packet_space, self.write_cache_size);
+            self.write_packet
+                [self.write_packet_end_offset..self.write_packet_end_offset + data_size]
+                .copy_from_slice(&self.write_cache[0..data_size]);
+            self.write_packet_end_offset += data_size;
+            self.write_cache
+                .copy_within(data_size..self.write_cache_size, 0);
+            self.write_cache_size -= data_size;
+            return true;
+        }
         let max_padding_len = if self.write_length_mask.is_some() {
             MAX_PADDING_LEN
         } else {
@@ -1400,4 +1452,53 @@ mod tests {
         let header_with_opts = [response_v, 0x01, 0x00, 0x00];
         assert!(check_header_response(&header_with_opts, response_v).is_ok());
     }
+
+    #[tokio::test]
+    async fn test_zero_data_layout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt, duplex};
+
+        // Without the chunk stream option, data follows the response header as is.
+        let (stream, mut peer) = duplex(1024);
+        let mut vmess_stream = VmessStream::new(
+            Box::new(stream),
+            false,
+            None,
+            None,
+            None,
+            false,
+            false,
+            Some(BytesMut::from(&b"header"[..])),
+            None,
+        );
+        vmess_stream.write_all(b"hello").await.unwrap();
+        vmess_stream.shutdown().await.unwrap();
+        let mut written = Vec::new();
+        peer.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"headerhello");
+
+        peer.write_all(b"world").await.unwrap();
+        drop(peer);
+        let mut read = Vec::new();
+        vmess_stream.read_to_end(&mut read).await.unwrap();
+        assert_eq!(read, b"world");
+
+        // With it, none sends chunks with a length prefix, and an empty chunk at the end.
+        let (stream, mut peer) = duplex(1024);
+        let mut vmess_stream = VmessStream::new(
+            Box::new(stream),
+            false,
+            None,
+            None,
+            None,
+            false,
+            true,
+            None,
+            None,
+        );
+        vmess_stream.write_all(b"hello").await.unwrap();
+        vmess_stream.shutdown().await.unwrap();
+        let mut written = Vec::new();
+        peer.read_to_end(&mut written).await.unwrap();
+        assert_eq!(written, b"\x00\x05hello\x00\x00");
+    }
 }
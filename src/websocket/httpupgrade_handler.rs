@@ -0,0 +1,318 @@
+//! HTTPUpgrade transport, as in Xray and sing-box.
+//!
+//! The connection starts with a WebSocket upgrade request and response, after which the
+//! inner protocol is sent as is, without WebSocket framing.
+
+use async_trait::async_trait;
+use rustc_hash::FxHashMap;
+use tokio::io::AsyncWriteExt;
+
+use super::websocket_early_data::{EARLY_DATA_HEADER, decode_early_data};
+use super::websocket_handler::ParsedHttpData;
+use crate::address::ResolvedLocation;
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::http2_handler::PrefixedStream;
+use crate::tcp::tcp_handler::{
+    TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
+};
+
+/// Returns the path without its query, which isn't matched.
+fn path_without_query(path: &str) -> &str {
+    path.split_once('?').map_or(path, |(path, _)| path)
+}
+
+/// Returns the stream that reads `prefix` first, if there is one.
+fn prefix_stream(prefix: Vec<u8>, stream: Box<dyn AsyncStream>) -> Box<dyn AsyncStream> {
+    if prefix.is_empty() {
+        stream
+    } else {
+        Box::new(PrefixedStream::new(prefix.into_boxed_slice(), stream))
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpUpgradeTcpServerHandler {
+    path: Option<String>,
+    host: Option<String>,
+    handler: Box<dyn TcpServerHandler>,
+}
+
+impl HttpUpgradeTcpServerHandler {
+    pub fn new(
+        path: Option<String>,
+        host: Option<String>,
+        handler: Box<dyn TcpServerHandler>,
+    ) -> Self {
+        Self {
+            path: path.map(|path| path_without_query(&path).to_string()),
+            host,
+            handler,
+        }
+    }
+}
+
+/// Reads the upgrade request and writes the response, without flushing it.
+async fn accept_upgrade(
+    path: Option<&str>,
+    host: Option<&str>,
+    mut server_stream: Box<dyn AsyncStream>,
+) -> std::io::Result<Box<dyn AsyncStream>> {
+    let ParsedHttpData {
+        first_line,
+        headers: request_headers,
+        stream_reader,
+    } = ParsedHttpData::parse(&mut server_stream).await?;
+
+    let request_path = first_line
+        .strip_prefix("GET ")
+        .and_then(|line| {
+            line.strip_suffix(" HTTP/1.1")
+                .or_else(|| line.strip_suffix(" HTTP/1.0"))
+        })
+        .ok_or_else(|| std::io::Error::other(format!("invalid http request: {first_line}")))?;
+
+    if let Some(path) = path
+        && path != path_without_query(request_path)
+    {
+        return Err(std::io::Error::other(format!(
+            "unexpected httpupgrade path: {request_path}"
+        )));
+    }
+
+    if let Some(host) = host {
+        match request_headers.get("host") {
+            Some(request_host) if request_host.eq_ignore_ascii_case(host) => {}
+            request_host => {
+                return Err(std::io::Error::other(format!(
+                    "unexpected httpupgrade host: {request_host:?}"
+                )));
+            }
+        }
+    }
+
+    if !request_headers
+        .get("upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    {
+        return Err(std::io::Error::other("missing httpupgrade upgrade header"));
+    }
+
+    // Xray clients can send early data like with WebSocket.
+    let (early_data_response_header, mut prefix) = match request_headers
+        .get(EARLY_DATA_HEADER)
+        .and_then(|v| Some((v, decode_early_data(v)?)))
+    {
+        Some((v, early_data)) => (format!("Sec-WebSocket-Protocol: {v}\r\n"), early_data),
+        None => ("".to_string(), vec![]),
+    };
+
+    let http_response = format!(
+        concat!(
+            "HTTP/1.1 101 Switching Protocols\r\n",
+            "Connection: Upgrade\r\n",
+            "Upgrade: websocket\r\n",
+            "{}",
+            "\r\n"
+        ),
+        early_data_response_header
+    );
+    server_stream.write_all(http_response.as_bytes()).await?;
+
+    prefix.extend_from_slice(stream_reader.unparsed_data());
+    Ok(prefix_stream(prefix, server_stream))
+}
+
+#[async_trait]
+impl TcpServerHandler for HttpUpgradeTcpServerHandler {
+    async fn setup_server_stream(
+        &self,
+        server_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<TcpServerSetupResult> {
+        let stream =
+            accept_upgrade(self.path.as_deref(), self.host.as_deref(), server_stream).await?;
+
+        let mut target_setup_result = self.handler.setup_server_stream(stream).await;
+        if let Ok(ref mut setup_result) = target_setup_result
+            && !matches!(setup_result, TcpServerSetupResult::AlreadyHandled)
+        {
+            setup_result.set_need_initial_flush(true);
+        }
+        target_setup_result
+    }
+}
+
+#[derive(Debug)]
+pub struct HttpUpgradeTcpClientHandler {
+    http_request: Box<[u8]>,
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl HttpUpgradeTcpClientHandler {
+    pub fn new(
+        path: Option<String>,
+        host: &str,
+        headers: Option<FxHashMap<String, String>>,
+        handler: Box<dyn TcpClientHandler>,
+    ) -> Self {
+        let mut http_request = String::with_capacity(256);
+        http_request.push_str("GET ");
+        http_request.push_str(path.as_deref().unwrap_or("/"));
+        http_request.push_str(" HTTP/1.1\r\nHost: ");
+        http_request.push_str(host);
+        http_request.push_str("\r\n");
+        http_request.push_str(concat!("Connection: Upgrade\r\n", "Upgrade: websocket\r\n"));
+        if let Some(headers) = headers {
+            for (header_key, header_val) in headers {
+                http_request.push_str(&header_key);
+                http_request.push_str(": ");
+                http_request.push_str(&header_val);
+                http_request.push_str("\r\n");
+            }
+        }
+        http_request.push_str("\r\n");
+
+        Self {
+            http_request: http_request.into_bytes().into_boxed_slice(),
+            handler,
+        }
+    }
+}
+
+/// Writes the upgrade request and reads the response.
+async fn connect_upgrade(
+    http_request: &[u8],
+    mut client_stream: Box<dyn AsyncStream>,
+) -> std::io::Result<Box<dyn AsyncStream>> {
+    client_stream.write_all(http_request).await?;
+    client_stream.flush().await?;
+
+    let ParsedHttpData {
+        first_line,
+        headers: response_headers,
+        stream_reader,
+    } = ParsedHttpData::parse(&mut client_stream).await?;
+
+    if !first_line.starts_with("HTTP/1.1 101") && !first_line.starts_with("HTTP/1.0 101") {
+        return Err(std::io::Error::other(format!(
+            "Bad httpupgrade response: {first_line}"
+        )));
+    }
+
+    if !response_headers
+        .get("upgrade")
+        .is_some_and(|v| v.eq_ignore_ascii_case("websocket"))
+    {
+        return Err(std::io::Error::other(
+            "missing httpupgrade upgrade response header",
+        ));
+    }
+
+    Ok(prefix_stream(
+        stream_reader.unparsed_data().to_vec(),
+        client_stream,
+    ))
+}
+
+#[async_trait]
+impl TcpClientHandler for HttpUpgradeTcpClientHandler {
+    async fn setup_client_tcp_stream(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        remote_location: ResolvedLocation,
+    ) -> std::io::Result<TcpClientSetupResult> {
+        let stream = connect_upgrade(&self.http_request, client_stream).await?;
+        self.handler
+            .setup_client_tcp_stream(stream, remote_location)
+            .await
+    }
+
+    fn supports_udp_over_tcp(&self) -> bool {
+        self.handler.supports_udp_over_tcp()
+    }
+
+    async fn setup_client_udp_bidirectional(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        let stream = connect_upgrade(&self.http_request, client_stream).await?;
+        self.handler
+            .setup_client_udp_bidirectional(stream, target)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, duplex};
+
+    #[tokio::test]
+    async fn test_upgrade_with_early_data() {
+        let (client_stream, server_stream) = duplex(4096);
+        let server_task = tokio::spawn(async move {
+            let mut stream = accept_upgrade(
+                Some(path_without_query("/up?ed=2048")),
+                Some("example.com"),
+                Box::new(server_stream),
+            )
+            .await
+            .unwrap();
+            let mut buf = [0u8; 10];
+            stream.read_exact(&mut buf).await.unwrap();
+            buf
+        });
+
+        let mut client_stream: Box<dyn AsyncStream> = Box::new(client_stream);
+        client_stream
+            .write_all(
+                concat!(
+                    "GET /up?ed=2048 HTTP/1.1\r\n",
+                    "Host: example.com\r\n",
+                    "Connection: Upgrade\r\n",
+                    "Upgrade: websocket\r\n",
+                    "Sec-WebSocket-Protocol: aGVsbG8\r\n",
+                    "\r\n",
+                    "world"
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(&server_task.await.unwrap(), b"helloworld");
+        let ParsedHttpData {
+            first_line,
+            headers,
+            ..
+        } = ParsedHttpData::parse(&mut client_stream).await.unwrap();
+        assert_eq!(first_line, "HTTP/1.1 101 Switching Protocols");
+        assert_eq!(headers.get("sec-websocket-protocol").unwrap(), "aGVsbG8");
+    }
+
+    #[tokio::test]
+    async fn test_connect_upgrade() {
+        let http_request = b"GET /up HTTP/1.1\r\nHost: example.com\r\n\r\n";
+        let (client_stream, server_stream) = duplex(4096);
+        let server_task = tokio::spawn(async move {
+            let mut server_stream: Box<dyn AsyncStream> = Box::new(server_stream);
+            let request = ParsedHttpData::parse(&mut server_stream).await.unwrap();
+            server_stream
+                .write_all(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\ndata")
+                .await
+                .unwrap();
+            request
+        });
+
+        let mut stream = connect_upgrade(http_request, Box::new(client_stream))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"data");
+
+        let request = server_task.await.unwrap();
+        assert_eq!(request.first_line, "GET /up HTTP/1.1");
+        assert_eq!(request.headers.get("host").unwrap(), "example.com");
+    }
+}
@@ -1,6 +1,9 @@
+mod httpupgrade_handler;
+mod websocket_early_data;
 mod websocket_handler;
 mod websocket_stream;
 
+pub use httpupgrade_handler::{HttpUpgradeTcpClientHandler, HttpUpgradeTcpServerHandler};
 pub use websocket_handler::{
     WebsocketServerTarget, WebsocketTcpClientHandler, WebsocketTcpServerHandler,
 };
@@ -0,0 +1,236 @@
+//! WebSocket early data, as sent by v2ray and Xray.
+//!
+//! The client delays the upgrade request until the inner protocol's first write, and
+//! sends up to `max_early_data` bytes of it base64url-encoded in the
+//! `Sec-WebSocket-Protocol` header, which saves a round trip per connection. The
+//! server echoes the header, as browsers and CDNs expect a selected subprotocol, and
+//! reads the decoded data before the first frame.
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use base64::Engine as _;
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use futures::ready;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::oneshot;
+
+use super::websocket_handler::WebsocketClientRequest;
+use super::websocket_stream::WebsocketStream;
+use crate::async_stream::{AsyncPing, AsyncStream};
+use crate::config::WebsocketPingType;
+
+/// Lowercase name of the header carrying early data.
+pub const EARLY_DATA_HEADER: &str = "sec-websocket-protocol";
+
+pub fn encode_early_data(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+/// Decodes the early data of a header value, which may have padding or be standard
+/// base64. Returns None if the value isn't base64, e.g. a real subprotocol name.
+pub fn decode_early_data(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim_end_matches('=');
+    URL_SAFE_NO_PAD
+        .decode(value)
+        .or_else(|_| STANDARD_NO_PAD.decode(value))
+        .ok()
+}
+
+/// Splits the `ed` query parameter, the early data size of v2ray and Xray clients,
+/// from a path.
+pub fn split_early_data_path(path: &str) -> (String, Option<usize>) {
+    let Some((base, query)) = path.split_once('?') else {
+        return (path.to_string(), None);
+    };
+
+    let mut max_early_data = None;
+    let mut params = vec![];
+    for param in query.split('&') {
+        match param.strip_prefix("ed=").map(str::parse::<usize>) {
+            Some(Ok(size)) => max_early_data = Some(size),
+            _ => params.push(param),
+        }
+    }
+
+    if params.is_empty() {
+        (base.to_string(), max_early_data)
+    } else {
+        (format!("{base}?{}", params.join("&")), max_early_data)
+    }
+}
+
+enum State {
+    /// Collecting the early data, until it's full or the stream is flushed or read.
+    Buffering {
+        stream: Box<dyn AsyncStream>,
+        early_data: Vec<u8>,
+    },
+    Connecting(oneshot::Receiver<std::io::Result<WebsocketStream>>),
+    Connected(Box<WebsocketStream>),
+    Failed,
+}
+
+/// Client stream that sends the upgrade request with the start of the first write.
+pub struct EarlyDataClientStream {
+    state: State,
+    request: Arc<WebsocketClientRequest>,
+    max_early_data: usize,
+}
+
+impl EarlyDataClientStream {
+    pub fn new(
+        stream: Box<dyn AsyncStream>,
+        request: Arc<WebsocketClientRequest>,
+        max_early_data: usize,
+    ) -> Self {
+        Self {
+            state: State::Buffering {
+                stream,
+                early_data: Vec::with_capacity(max_early_data),
+            },
+            request,
+            max_early_data,
+        }
+    }
+
+    /// Starts the upgrade request with the buffered early data, and waits for it.
+    fn poll_connected(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<&mut WebsocketStream>> {
+        loop {
+            match self.state {
+                State::Buffering { .. } => {
+                    let State::Buffering { stream, early_data } =
+                        std::mem::replace(&mut self.state, State::Failed)
+                    else {
+                        unreachable!();
+                    };
+                    let (result_tx, result_rx) = oneshot::channel();
+                    let request = self.request.clone();
+                    tokio::spawn(async move {
+                        let result = request.connect(stream, &early_data).await;
+                        let _ = result_tx.send(result);
+                    });
+                    self.state = State::Connecting(result_rx);
+                }
+                State::Connecting(ref mut result_rx) => {
+                    let result = ready!(Pin::new(result_rx).poll(cx));
+                    match result {
+                        Ok(Ok(stream)) => self.state = State::Connected(Box::new(stream)),
+                        Ok(Err(e)) => {
+                            self.state = State::Failed;
+                            return Poll::Ready(Err(e));
+                        }
+                        Err(_) => {
+                            self.state = State::Failed;
+                            return Poll::Ready(Err(std::io::Error::other(
+                                "websocket upgrade was cancelled",
+                            )));
+                        }
+                    }
+                }
+                State::Connected(ref mut stream) => return Poll::Ready(Ok(stream)),
+                State::Failed => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::BrokenPipe,
+                        "websocket upgrade failed",
+                    )));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncRead for EarlyDataClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_connected(cx))?;
+        Pin::new(stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for EarlyDataClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if let State::Buffering {
+            ref mut early_data, ..
+        } = this.state
+            && early_data.len() < this.max_early_data
+        {
+            let n = std::cmp::min(buf.len(), this.max_early_data - early_data.len());
+            early_data.extend_from_slice(&buf[..n]);
+            return Poll::Ready(Ok(n));
+        }
+        let stream = ready!(this.poll_connected(cx))?;
+        Pin::new(stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_connected(cx))?;
+        Pin::new(stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let stream = ready!(self.get_mut().poll_connected(cx))?;
+        Pin::new(stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for EarlyDataClientStream {
+    fn supports_ping(&self) -> bool {
+        self.request.ping_type != WebsocketPingType::Disabled
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        match self.get_mut().state {
+            State::Connected(ref mut stream) => Pin::new(stream.as_mut()).poll_write_ping(cx),
+            // Nothing to keep alive before the upgrade request.
+            _ => Poll::Ready(Ok(false)),
+        }
+    }
+}
+
+impl AsyncStream for EarlyDataClientStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_early_data_encoding() {
+        let data = [0xfbu8, 0xff, 0x01, 0x02];
+        let encoded = encode_early_data(&data);
+        assert_eq!(encoded, "-_8BAg");
+        assert_eq!(decode_early_data(&encoded).unwrap(), data);
+        // Padded and standard base64 of other clients.
+        assert_eq!(decode_early_data("+/8BAg==").unwrap(), data);
+        assert!(decode_early_data("chat, superchat").is_none());
+    }
+
+    #[test]
+    fn test_split_early_data_path() {
+        assert_eq!(split_early_data_path("/ws"), ("/ws".to_string(), None));
+        assert_eq!(
+            split_early_data_path("/ws?ed=2048"),
+            ("/ws".to_string(), Some(2048))
+        );
+        assert_eq!(
+            split_early_data_path("/ws?a=1&ed=1024&b=2"),
+            ("/ws?a=1&b=2".to_string(), Some(1024))
+        );
+        assert_eq!(
+            split_early_data_path("/ws?ed=abc"),
+            ("/ws?ed=abc".to_string(), None)
+        );
+    }
+}
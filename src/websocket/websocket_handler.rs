@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use aws_lc_rs::digest::{SHA1_FOR_LEGACY_USE_ONLY, digest};
@@ -6,6 +7,10 @@ use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
 use rustc_hash::FxHashMap;
 use tokio::io::AsyncWriteExt;
 
+use super::websocket_early_data::{
+    EARLY_DATA_HEADER, EarlyDataClientStream, decode_early_data, encode_early_data,
+    split_early_data_path,
+};
 use super::websocket_stream::WebsocketStream;
 use crate::address::ResolvedLocation;
 use crate::async_stream::AsyncMessageStream;
@@ -15,10 +20,12 @@ use crate::stream_reader::StreamReader;
 use crate::tcp::tcp_handler::{
     TcpClientHandler, TcpClientSetupResult, TcpServerHandler, TcpServerSetupResult,
 };
+use crate::util::host_matches;
 
 #[derive(Debug)]
 pub struct WebsocketServerTarget {
     pub matching_path: Option<String>,
+    pub matching_host: Option<String>,
     pub matching_headers: Option<FxHashMap<String, String>>,
     pub ping_type: WebsocketPingType,
     pub handler: Box<dyn TcpServerHandler>,
@@ -30,7 +37,13 @@ pub struct WebsocketTcpServerHandler {
 }
 
 impl WebsocketTcpServerHandler {
-    pub fn new(server_targets: Vec<WebsocketServerTarget>) -> Self {
+    pub fn new(mut server_targets: Vec<WebsocketServerTarget>) -> Self {
+        // Request paths are matched without the early data parameter.
+        for target in server_targets.iter_mut() {
+            if let Some(ref mut path) = target.matching_path {
+                *path = split_early_data_path(path).0;
+            }
+        }
         Self { server_targets }
     }
 }
@@ -63,7 +76,8 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
             first_line.truncate(first_line.len() - 9);
 
             // return the path after 'GET '
-            first_line.split_off(4)
+            let (path, _) = split_early_data_path(&first_line.split_off(4));
+            path
         };
 
         let websocket_key = request_headers
@@ -73,6 +87,7 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
         'outer: for server_target in self.server_targets.iter() {
             let WebsocketServerTarget {
                 matching_path,
+                matching_host,
                 matching_headers,
                 ping_type,
                 handler,
@@ -84,10 +99,20 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
                 continue;
             }
 
+            if let Some(host) = matching_host
+                && !request_headers
+                    .get("host")
+                    .is_some_and(|request_host| host_matches(host, request_host))
+            {
+                continue;
+            }
+
             if let Some(headers) = matching_headers {
                 for (header_key, header_val) in headers {
-                    if request_headers.get(header_key) != Some(header_val) {
-                        continue 'outer;
+                    match request_headers.get(header_key) {
+                        Some(_) if header_val == "*" => {}
+                        Some(request_val) if request_val == header_val => {}
+                        _ => continue 'outer,
                     }
                 }
             }
@@ -105,6 +130,16 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
                     None => "".to_string(),
                 };
 
+            // The protocol header is only early data if it decodes, otherwise it's left to be
+            // a subprotocol the handler doesn't know about.
+            let (early_data_response_header, early_data) = match request_headers
+                .get(EARLY_DATA_HEADER)
+                .and_then(|v| Some((v, decode_early_data(v)?)))
+            {
+                Some((v, early_data)) => (format!("Sec-WebSocket-Protocol: {v}\r\n"), early_data),
+                None => ("".to_string(), vec![]),
+            };
+
             let http_response = format!(
                 concat!(
                     "HTTP/1.1 101 Switching Protocol\r\n",
@@ -112,20 +147,25 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
                     "Upgrade: websocket\r\n",
                     "Connection: Upgrade\r\n",
                     "{}",
+                    "{}",
                     "Sec-WebSocket-Accept: {}\r\n",
                     "\r\n"
                 ),
-                host_response_header, websocket_version_response_header, websocket_key_response,
+                host_response_header,
+                websocket_version_response_header,
+                early_data_response_header,
+                websocket_key_response,
             );
 
             server_stream.write_all(http_response.as_bytes()).await?;
 
-            let websocket_stream = Box::new(WebsocketStream::new(
+            let mut websocket_stream = Box::new(WebsocketStream::new(
                 server_stream,
                 false,
                 ping_type.clone(),
                 stream_reader.unparsed_data(),
             ));
+            websocket_stream.set_early_data(early_data);
 
             let mut target_setup_result = handler.setup_server_stream(websocket_stream).await;
 
@@ -145,42 +185,33 @@ impl TcpServerHandler for WebsocketTcpServerHandler {
 }
 
 #[derive(Debug)]
-pub struct WebsocketTcpClientHandler {
-    matching_path: Option<String>,
-    matching_headers: Option<FxHashMap<String, String>>,
-    ping_type: WebsocketPingType,
-    handler: Box<dyn TcpClientHandler>,
+pub struct WebsocketClientRequest {
+    path: String,
+    host: Option<String>,
+    headers: Option<FxHashMap<String, String>>,
+    pub ping_type: WebsocketPingType,
 }
 
-impl WebsocketTcpClientHandler {
-    pub fn new(
-        matching_path: Option<String>,
-        matching_headers: Option<FxHashMap<String, String>>,
-        ping_type: WebsocketPingType,
-        handler: Box<dyn TcpClientHandler>,
-    ) -> Self {
-        Self {
-            matching_path,
-            matching_headers,
-            ping_type,
-            handler,
-        }
-    }
-
-    async fn setup_client_stream_common(
+impl WebsocketClientRequest {
+    /// Sends the upgrade request with `early_data` and reads the response.
+    pub async fn connect(
         &self,
         mut client_stream: Box<dyn AsyncStream>,
+        early_data: &[u8],
     ) -> std::io::Result<WebsocketStream> {
-        let request_path = self.matching_path.as_deref().unwrap_or("/");
-
         let websocket_key = create_websocket_key();
         let mut http_request = String::with_capacity(1024);
         http_request.push_str("GET ");
-        http_request.push_str(request_path);
+        http_request.push_str(&self.path);
         http_request.push_str(" HTTP/1.1\r\n");
+        if let Some(ref host) = self.host {
+            http_request.push_str("Host: ");
+            http_request.push_str(host);
+            http_request.push_str("\r\n");
+        }
         http_request.push_str(concat!("Connection: Upgrade\r\n", "Upgrade: websocket\r\n",));
 
-        if let Some(ref headers) = self.matching_headers {
+        if let Some(ref headers) = self.headers {
             for (header_key, header_val) in headers {
                 http_request.push_str(header_key);
                 http_request.push_str(": ");
@@ -189,6 +220,12 @@ impl WebsocketTcpClientHandler {
             }
         }
 
+        if !early_data.is_empty() {
+            http_request.push_str("Sec-WebSocket-Protocol: ");
+            http_request.push_str(&encode_early_data(early_data));
+            http_request.push_str("\r\n");
+        }
+
         http_request.push_str(concat!(
             "Sec-WebSocket-Version: 13\r\n",
             "Sec-WebSocket-Key: "
@@ -231,6 +268,59 @@ impl WebsocketTcpClientHandler {
     }
 }
 
+#[derive(Debug)]
+pub struct WebsocketTcpClientHandler {
+    request: Arc<WebsocketClientRequest>,
+    max_early_data: usize,
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl WebsocketTcpClientHandler {
+    pub fn new(
+        matching_path: Option<String>,
+        host: Option<String>,
+        mut matching_headers: Option<FxHashMap<String, String>>,
+        ping_type: WebsocketPingType,
+        max_early_data: Option<usize>,
+        handler: Box<dyn TcpClientHandler>,
+    ) -> Self {
+        // Paths of v2ray and Xray configs set the early data size with an `ed` parameter.
+        let (path, path_early_data) =
+            split_early_data_path(matching_path.as_deref().unwrap_or("/"));
+        // The host replaces a Host header, so that only one is sent.
+        if host.is_some()
+            && let Some(ref mut headers) = matching_headers
+        {
+            headers.retain(|key, _| !key.eq_ignore_ascii_case("host"));
+        }
+        Self {
+            request: Arc::new(WebsocketClientRequest {
+                path,
+                host,
+                headers: matching_headers,
+                ping_type,
+            }),
+            max_early_data: max_early_data.or(path_early_data).unwrap_or(0),
+            handler,
+        }
+    }
+
+    async fn setup_client_stream_common(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+    ) -> std::io::Result<Box<dyn AsyncStream>> {
+        if self.max_early_data > 0 {
+            return Ok(Box::new(EarlyDataClientStream::new(
+                client_stream,
+                self.request.clone(),
+                self.max_early_data,
+            )));
+        }
+        let websocket_stream = self.request.connect(client_stream, &[]).await?;
+        Ok(Box::new(websocket_stream))
+    }
+}
+
 #[async_trait]
 impl TcpClientHandler for WebsocketTcpClientHandler {
     async fn setup_client_tcp_stream(
@@ -240,7 +330,7 @@ impl TcpClientHandler for WebsocketTcpClientHandler {
     ) -> std::io::Result<TcpClientSetupResult> {
         let websocket_stream = self.setup_client_stream_common(client_stream).await?;
         self.handler
-            .setup_client_tcp_stream(Box::new(websocket_stream), remote_location)
+            .setup_client_tcp_stream(websocket_stream, remote_location)
             .await
     }
 
@@ -255,19 +345,19 @@ impl TcpClientHandler for WebsocketTcpClientHandler {
     ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
         let websocket_stream = self.setup_client_stream_common(client_stream).await?;
         self.handler
-            .setup_client_udp_bidirectional(Box::new(websocket_stream), target)
+            .setup_client_udp_bidirectional(websocket_stream, target)
             .await
     }
 }
 
-struct ParsedHttpData {
-    first_line: String,
-    headers: HashMap<String, String>,
-    stream_reader: StreamReader,
+pub(super) struct ParsedHttpData {
+    pub first_line: String,
+    pub headers: HashMap<String, String>,
+    pub stream_reader: StreamReader,
 }
 
 impl ParsedHttpData {
-    async fn parse(stream: &mut Box<dyn AsyncStream>) -> std::io::Result<Self> {
+    pub async fn parse(stream: &mut Box<dyn AsyncStream>) -> std::io::Result<Self> {
         let mut stream_reader = StreamReader::new();
         let mut first_line: Option<String> = None;
         // don't use FxHashMap for unvalidated user data
@@ -1,6 +1,7 @@
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
+use bytes::{Buf, Bytes};
 use futures::ready;
 use log::warn;
 use rand::RngCore;
@@ -15,6 +16,8 @@ pub struct WebsocketStream {
     is_client: bool,
     ping_type: WebsocketPingType,
     pending_initial_data: bool,
+    // Early data of the upgrade request, read before any frames.
+    early_data: Bytes,
 
     read_state: ReadState,
     read_frame_masked: bool,
@@ -96,6 +99,7 @@ impl WebsocketStream {
             is_client,
             ping_type,
             pending_initial_data,
+            early_data: Bytes::new(),
             read_state: ReadState::Init,
             read_frame_masked: false,
             read_frame_opcode: OpCode::Unknown(99),
@@ -114,6 +118,10 @@ impl WebsocketStream {
         }
     }
 
+    pub fn set_early_data(&mut self, early_data: Vec<u8>) {
+        self.early_data = Bytes::from(early_data);
+    }
+
     fn step_init(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> std::io::Result<()> {
         let unprocessed_len = self.unprocessed_end_offset - self.unprocessed_start_offset;
         if unprocessed_len < 2 {
@@ -571,6 +579,13 @@ impl AsyncRead for WebsocketStream {
     ) -> std::task::Poll<std::io::Result<()>> {
         let this = self.get_mut();
 
+        if !this.early_data.is_empty() {
+            let len = std::cmp::min(this.early_data.len(), buf.remaining());
+            buf.put_slice(&this.early_data[..len]);
+            this.early_data.advance(len);
+            return Poll::Ready(Ok(()));
+        }
+
         // If there is unprocessed data and we are reading content, it must be because there
         // is data still to be read, but the passed in `buf` from the previous iteration
         // didn't have enough space to read it all.
@@ -0,0 +1,1178 @@
+//! WireGuard client implementation.
+//!
+//! This module forwards TCP and UDP traffic, and ICMP echo requests, through a
+//! WireGuard peer:
+//! - The Noise IKpsk2 handshake and transport encryption are handled by boringtun
+//! - A userspace smoltcp interface provides the TCP/IP stack inside the tunnel
+//! - A single driver task owns the outer UDP socket and moves packets between
+//!   the peer and the smoltcp interface
+//!
+//! The tunnel is created lazily on the first connection and shared by all
+//! connections made through the same `WireguardSocketConnector`.
+//!
+//! Protocol reference: https://www.wireguard.com/protocol/
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use base64::engine::{Engine as _, general_purpose::STANDARD as BASE64};
+use boringtun::noise::{Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use smoltcp::iface::{Config as InterfaceConfig, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use smoltcp::socket::{icmp, tcp, udp};
+use smoltcp::time::Instant as SmolInstant;
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr, IpEndpoint};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, OnceCell};
+
+use crate::address::{NetLocation, ResolvedLocation};
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncShutdownMessage,
+    AsyncStream, AsyncWriteMessage,
+};
+use crate::icmp_ping::{build_echo_request, parse_echo_reply};
+use crate::resolver::{Resolver, resolve_location, resolve_single_address};
+use crate::socket_util::{DialOptions, new_udp_socket};
+use crate::tcp::socket_connector::SocketConnector;
+
+/// Largest datagram we expect from the peer (and largest encapsulated packet we send).
+const MAX_DATAGRAM_SIZE: usize = 65535;
+
+/// boringtun expects `update_timers` to be called roughly every 250ms.
+const TIMER_TICK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Upper bound on how long the driver sleeps between smoltcp polls.
+const MAX_POLL_DELAY: Duration = Duration::from_millis(50);
+
+/// How long to wait for the TCP handshake inside the tunnel to complete.
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TCP_SOCKET_BUFFER_SIZE: usize = 256 * 1024;
+const UDP_SOCKET_BUFFER_SIZE: usize = 64 * 1024;
+const UDP_SOCKET_METADATA_COUNT: usize = 64;
+const ICMP_SOCKET_BUFFER_SIZE: usize = 4 * 1024;
+const ICMP_SOCKET_METADATA_COUNT: usize = 4;
+
+/// Lower bound of the ephemeral port range used for tunnel sockets.
+const EPHEMERAL_PORT_START: u16 = 49152;
+
+/// Parsed WireGuard peer configuration.
+#[derive(Clone)]
+pub struct WireguardTunnelConfig {
+    pub private_key: [u8; 32],
+    pub peer_public_key: [u8; 32],
+    pub preshared_key: Option<[u8; 32]>,
+    /// Local addresses of the tunnel interface.
+    pub addresses: Vec<(IpAddr, u8)>,
+    /// Destinations that may be reached through the peer.
+    pub allowed_ips: Vec<(IpAddr, u8)>,
+    /// Bytes 1..4 of every WireGuard message header (used by Cloudflare WARP).
+    pub reserved: Option<[u8; 3]>,
+    pub mtu: usize,
+    pub persistent_keepalive: Option<u16>,
+}
+
+impl std::fmt::Debug for WireguardTunnelConfig {
+    // Keys are intentionally omitted.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WireguardTunnelConfig")
+            .field("addresses", &self.addresses)
+            .field("allowed_ips", &self.allowed_ips)
+            .field("reserved", &self.reserved)
+            .field("mtu", &self.mtu)
+            .field("persistent_keepalive", &self.persistent_keepalive)
+            .finish()
+    }
+}
+
+impl WireguardTunnelConfig {
+    #[allow(clippy::too_many_arguments)]
+    pub fn from_fields(
+        private_key: &str,
+        peer_public_key: &str,
+        preshared_key: Option<&str>,
+        addresses: &[String],
+        allowed_ips: &[String],
+        reserved: &[u8],
+        mtu: u16,
+        persistent_keepalive: Option<u16>,
+    ) -> std::io::Result<Self> {
+        let private_key = decode_key(private_key, "private_key")?;
+        let peer_public_key = decode_key(peer_public_key, "peer_public_key")?;
+        let preshared_key = preshared_key
+            .map(|key| decode_key(key, "preshared_key"))
+            .transpose()?;
+
+        if addresses.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "WireGuard requires at least one local address",
+            ));
+        }
+        let addresses = addresses
+            .iter()
+            .map(|s| parse_cidr(s))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let allowed_ips = allowed_ips
+            .iter()
+            .map(|s| parse_cidr(s))
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        let reserved = match reserved.len() {
+            0 => None,
+            3 => Some([reserved[0], reserved[1], reserved[2]]),
+            n => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("WireGuard reserved must contain exactly 3 bytes, got {n}"),
+                ));
+            }
+        };
+
+        if mtu < 576 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("WireGuard mtu is too small: {mtu} (minimum 576)"),
+            ));
+        }
+
+        Ok(Self {
+            private_key,
+            peer_public_key,
+            preshared_key,
+            addresses,
+            allowed_ips,
+            reserved,
+            mtu: mtu as usize,
+            persistent_keepalive,
+        })
+    }
+
+    /// Returns true if `ip` is covered by one of the allowed IP ranges.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        self.allowed_ips
+            .iter()
+            .any(|(network, prefix)| cidr_contains(*network, *prefix, ip))
+    }
+}
+
+fn decode_key(encoded: &str, field: &str) -> std::io::Result<[u8; 32]> {
+    let decoded = BASE64.decode(encoded.trim()).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("WireGuard {field} is not valid base64: {e}"),
+        )
+    })?;
+    decoded.try_into().map_err(|v: Vec<u8>| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "WireGuard {field} has invalid length: {} (expected 32)",
+                v.len()
+            ),
+        )
+    })
+}
+
+fn parse_cidr(s: &str) -> std::io::Result<(IpAddr, u8)> {
+    let (address_str, prefix_str) = match s.rfind('/') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let address: IpAddr = address_str.parse().map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid WireGuard address '{s}': {e}"),
+        )
+    })?;
+    let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix_str {
+        Some(p) => p.parse::<u8>().map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid prefix length in '{s}': {e}"),
+            )
+        })?,
+        None => max_prefix,
+    };
+    if prefix > max_prefix {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid prefix length in '{s}': {prefix} (max {max_prefix})"),
+        ));
+    }
+    Ok((address, prefix))
+}
+
+fn cidr_contains(network: IpAddr, prefix: u8, ip: IpAddr) -> bool {
+    match (network, ip) {
+        (IpAddr::V4(network), IpAddr::V4(ip)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+            (u32::from(network) & mask) == (u32::from(ip) & mask)
+        }
+        (IpAddr::V6(network), IpAddr::V6(ip)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+            (u128::from(network) & mask) == (u128::from(ip) & mask)
+        }
+        _ => false,
+    }
+}
+
+/// In-memory smoltcp device: packets from the peer are queued in `rx_queue`,
+/// packets produced by smoltcp are queued in `tx_queue` for encapsulation.
+struct TunnelDevice {
+    rx_queue: VecDeque<Vec<u8>>,
+    tx_queue: VecDeque<Vec<u8>>,
+    mtu: usize,
+}
+
+impl Device for TunnelDevice {
+    type RxToken<'a> = TunnelRxToken;
+    type TxToken<'a> = TunnelTxToken<'a>;
+
+    fn receive(
+        &mut self,
+        _timestamp: SmolInstant,
+    ) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let buffer = self.rx_queue.pop_front()?;
+        Some((
+            TunnelRxToken { buffer },
+            TunnelTxToken {
+                queue: &mut self.tx_queue,
+            },
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: SmolInstant) -> Option<Self::TxToken<'_>> {
+        Some(TunnelTxToken {
+            queue: &mut self.tx_queue,
+        })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ip;
+        caps.max_transmission_unit = self.mtu;
+        caps
+    }
+}
+
+struct TunnelRxToken {
+    buffer: Vec<u8>,
+}
+
+impl RxToken for TunnelRxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.buffer)
+    }
+}
+
+struct TunnelTxToken<'a> {
+    queue: &'a mut VecDeque<Vec<u8>>,
+}
+
+impl TxToken for TunnelTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut buffer = vec![0u8; len];
+        let result = f(&mut buffer);
+        self.queue.push_back(buffer);
+        result
+    }
+}
+
+/// smoltcp state shared between the driver task and the tunnel streams.
+struct StackState {
+    iface: Interface,
+    device: TunnelDevice,
+    sockets: SocketSet<'static>,
+    /// Wakers of streams waiting for the stack to make progress.
+    wakers: HashMap<SocketHandle, Waker>,
+    /// TCP sockets whose stream was dropped, with their ports; removed once fully closed.
+    closing: Vec<(SocketHandle, u16)>,
+    /// Ports and ICMP identifiers of the sockets in `sockets`.
+    used_ports: HashSet<u16>,
+    next_port: u16,
+}
+
+impl StackState {
+    fn register_waker(&mut self, handle: SocketHandle, waker: &Waker) {
+        match self.wakers.get(&handle) {
+            Some(existing) if existing.will_wake(waker) => {}
+            _ => {
+                self.wakers.insert(handle, waker.clone());
+            }
+        }
+    }
+
+    /// Reserves an ephemeral port that no socket of the stack uses. Sockets of long
+    /// connections keep their port, so the counter skips ports that are still in use
+    /// after wrapping around.
+    fn allocate_port(&mut self) -> std::io::Result<u16> {
+        for _ in EPHEMERAL_PORT_START..=u16::MAX {
+            let port = self.next_port;
+            self.next_port = port.checked_add(1).unwrap_or(EPHEMERAL_PORT_START);
+            if self.used_ports.insert(port) {
+                return Ok(port);
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            "All WireGuard ephemeral ports are in use",
+        ))
+    }
+
+    /// Removes a socket and releases its port.
+    fn remove_socket(&mut self, handle: SocketHandle, port: u16) {
+        self.wakers.remove(&handle);
+        self.sockets.remove(handle);
+        self.used_ports.remove(&port);
+    }
+
+    /// Polls the interface and returns the IP packets that need to be sent to the peer.
+    fn poll(&mut self) -> (Vec<Vec<u8>>, Option<Duration>) {
+        let now = SmolInstant::now();
+        self.iface.poll(now, &mut self.device, &mut self.sockets);
+
+        let sockets = &mut self.sockets;
+        let used_ports = &mut self.used_ports;
+        self.closing.retain(|(handle, port)| {
+            let socket = sockets.get::<tcp::Socket>(*handle);
+            if matches!(socket.state(), tcp::State::Closed | tcp::State::TimeWait) {
+                sockets.remove(*handle);
+                used_ports.remove(port);
+                false
+            } else {
+                true
+            }
+        });
+
+        for (_, waker) in self.wakers.drain() {
+            waker.wake();
+        }
+
+        let outgoing = self.device.tx_queue.drain(..).collect();
+        let delay = self
+            .iface
+            .poll_delay(now, &self.sockets)
+            .map(|d| Duration::from_micros(d.total_micros()));
+        (outgoing, delay)
+    }
+}
+
+/// A running tunnel. Dropping the last reference stops the driver task.
+struct WireguardTunnel {
+    stack: Mutex<StackState>,
+    /// Wakes the driver when a stream produced data or changed socket state.
+    driver_notify: Arc<Notify>,
+    config: WireguardTunnelConfig,
+}
+
+impl std::fmt::Debug for WireguardTunnel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WireguardTunnel")
+            .field("addresses", &self.config.addresses)
+            .field("mtu", &self.config.mtu)
+            .finish()
+    }
+}
+
+impl WireguardTunnel {
+    fn new(config: WireguardTunnelConfig) -> std::io::Result<Self> {
+        let mut device = TunnelDevice {
+            rx_queue: VecDeque::new(),
+            tx_queue: VecDeque::new(),
+            mtu: config.mtu,
+        };
+
+        let mut iface_config = InterfaceConfig::new(HardwareAddress::Ip);
+        iface_config.random_seed = rand::random();
+        let mut iface = Interface::new(iface_config, &mut device, SmolInstant::now());
+
+        let mut has_ipv4 = None;
+        let mut has_ipv6 = None;
+        let mut push_error = None;
+        iface.update_ip_addrs(|addrs| {
+            for (address, prefix) in config.addresses.iter() {
+                if addrs
+                    .push(IpCidr::new(IpAddress::from(*address), *prefix))
+                    .is_err()
+                {
+                    push_error = Some(*address);
+                }
+                match address {
+                    IpAddr::V4(v4) => has_ipv4 = has_ipv4.or(Some(*v4)),
+                    IpAddr::V6(v6) => has_ipv6 = has_ipv6.or(Some(*v6)),
+                }
+            }
+        });
+        if let Some(address) = push_error {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Too many WireGuard addresses, could not add {address}"),
+            ));
+        }
+
+        // Every destination is routed through the peer. With Medium::Ip there is
+        // no neighbor discovery, so the gateway only needs to be a valid address.
+        if let Some(v4) = has_ipv4 {
+            let _ = iface.routes_mut().add_default_ipv4_route(v4.into());
+        }
+        if let Some(v6) = has_ipv6 {
+            let _ = iface.routes_mut().add_default_ipv6_route(v6.into());
+        }
+
+        let port_offset = rand::random::<u16>() % (u16::MAX - EPHEMERAL_PORT_START);
+
+        Ok(Self {
+            stack: Mutex::new(StackState {
+                iface,
+                device,
+                sockets: SocketSet::new(vec![]),
+                wakers: HashMap::new(),
+                closing: Vec::new(),
+                used_ports: HashSet::new(),
+                next_port: EPHEMERAL_PORT_START + port_offset,
+            }),
+            driver_notify: Arc::new(Notify::new()),
+            config,
+        })
+    }
+
+    fn notify_driver(&self) {
+        self.driver_notify.notify_one();
+    }
+
+    fn check_allowed(&self, addr: &SocketAddr) -> std::io::Result<()> {
+        if self.config.is_allowed(addr.ip()) {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!("{} is not within the WireGuard allowed_ips", addr.ip()),
+            ))
+        }
+    }
+
+    async fn connect_tcp(self: &Arc<Self>, remote: SocketAddr) -> std::io::Result<TunnelTcpStream> {
+        self.check_allowed(&remote)?;
+
+        let (handle, port) = {
+            let mut stack = self.stack.lock();
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0u8; TCP_SOCKET_BUFFER_SIZE]),
+                tcp::SocketBuffer::new(vec![0u8; TCP_SOCKET_BUFFER_SIZE]),
+            );
+            socket.set_nagle_enabled(false);
+            socket.set_ack_delay(None);
+            socket.set_keep_alive(Some(smoltcp::time::Duration::from_secs(30)));
+            socket.set_timeout(Some(smoltcp::time::Duration::from_secs(300)));
+
+            let port = stack.allocate_port()?;
+            let StackState { iface, .. } = &mut *stack;
+            if let Err(e) = socket.connect(iface.context(), IpEndpoint::from(remote), port) {
+                stack.used_ports.remove(&port);
+                return Err(std::io::Error::other(format!(
+                    "Failed to connect to {remote} through WireGuard: {e}"
+                )));
+            }
+            (stack.sockets.add(socket), port)
+        };
+        self.notify_driver();
+
+        let stream = TunnelTcpStream {
+            tunnel: self.clone(),
+            handle,
+            port,
+        };
+
+        let established = std::future::poll_fn(|cx| {
+            let mut stack = self.stack.lock();
+            let socket = stack.sockets.get::<tcp::Socket>(handle);
+            match socket.state() {
+                tcp::State::Established => Poll::Ready(Ok(())),
+                tcp::State::SynSent | tcp::State::SynReceived => {
+                    stack.register_waker(handle, cx.waker());
+                    Poll::Pending
+                }
+                state => Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    format!("WireGuard TCP connection to {remote} failed (state {state})"),
+                ))),
+            }
+        });
+
+        match tokio::time::timeout(TCP_CONNECT_TIMEOUT, established).await {
+            Ok(Ok(())) => Ok(stream),
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("WireGuard TCP connection to {remote} timed out"),
+            )),
+        }
+    }
+
+    fn bind_udp(self: &Arc<Self>, remote: SocketAddr) -> std::io::Result<TunnelUdpStream> {
+        self.check_allowed(&remote)?;
+
+        let (handle, port) = {
+            let mut stack = self.stack.lock();
+            let mut socket = udp::Socket::new(
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_SOCKET_METADATA_COUNT],
+                    vec![0u8; UDP_SOCKET_BUFFER_SIZE],
+                ),
+                udp::PacketBuffer::new(
+                    vec![udp::PacketMetadata::EMPTY; UDP_SOCKET_METADATA_COUNT],
+                    vec![0u8; UDP_SOCKET_BUFFER_SIZE],
+                ),
+            );
+            let port = stack.allocate_port()?;
+            if let Err(e) = socket.bind(port) {
+                stack.used_ports.remove(&port);
+                return Err(std::io::Error::other(format!(
+                    "Failed to bind WireGuard UDP socket: {e}"
+                )));
+            }
+            (stack.sockets.add(socket), port)
+        };
+
+        Ok(TunnelUdpStream {
+            tunnel: self.clone(),
+            handle,
+            port,
+            remote: IpEndpoint::from(remote),
+        })
+    }
+
+    /// Sends an echo request to `ip` through the tunnel and waits for the reply.
+    async fn ping(self: &Arc<Self>, ip: IpAddr, payload: &[u8]) -> std::io::Result<()> {
+        self.check_allowed(&SocketAddr::new(ip, 0))?;
+
+        let sequence = rand::random();
+        let remote = IpAddress::from(ip);
+
+        let socket = {
+            let mut stack = self.stack.lock();
+            let identifier = stack.allocate_port()?;
+            let request = build_echo_request(ip.is_ipv6(), identifier, sequence, payload);
+            let mut socket = icmp::Socket::new(
+                icmp::PacketBuffer::new(
+                    vec![icmp::PacketMetadata::EMPTY; ICMP_SOCKET_METADATA_COUNT],
+                    vec![0u8; ICMP_SOCKET_BUFFER_SIZE],
+                ),
+                icmp::PacketBuffer::new(
+                    vec![icmp::PacketMetadata::EMPTY; ICMP_SOCKET_METADATA_COUNT],
+                    vec![0u8; ICMP_SOCKET_BUFFER_SIZE],
+                ),
+            );
+            let sent = socket
+                .bind(icmp::Endpoint::Ident(identifier))
+                .map_err(|e| {
+                    std::io::Error::other(format!("Failed to bind WireGuard ICMP socket: {e}"))
+                })
+                .and_then(|()| {
+                    socket.send_slice(&request, remote).map_err(|e| {
+                        std::io::Error::other(format!("WireGuard ICMP send error: {e}"))
+                    })
+                });
+            if let Err(e) = sent {
+                stack.used_ports.remove(&identifier);
+                return Err(e);
+            }
+            TunnelIcmpSocket {
+                tunnel: self.clone(),
+                handle: stack.sockets.add(socket),
+                identifier,
+            }
+        };
+        self.notify_driver();
+
+        std::future::poll_fn(|cx| -> Poll<std::io::Result<()>> {
+            let mut stack = self.stack.lock();
+            let icmp_socket = stack.sockets.get_mut::<icmp::Socket>(socket.handle);
+
+            while icmp_socket.can_recv() {
+                let (message, from) = icmp_socket.recv().map_err(|e| {
+                    std::io::Error::other(format!("WireGuard ICMP recv error: {e}"))
+                })?;
+                if from == remote
+                    && parse_echo_reply(ip.is_ipv6(), message).is_some_and(
+                        |(reply_sequence, reply_payload)| {
+                            reply_sequence == sequence && reply_payload == payload
+                        },
+                    )
+                {
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            stack.register_waker(socket.handle, cx.waker());
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+/// Sends an encapsulated packet to the peer, applying the reserved header bytes.
+async fn send_to_peer(
+    socket: &UdpSocket,
+    packet: &mut [u8],
+    reserved: Option<[u8; 3]>,
+) -> std::io::Result<()> {
+    if let Some(reserved) = reserved
+        && packet.len() >= 4
+    {
+        packet[1..4].copy_from_slice(&reserved);
+    }
+    socket.send(packet).await.map(|_| ())
+}
+
+/// Moves packets between the peer and the smoltcp interface until the tunnel is dropped.
+async fn run_tunnel_driver(
+    tunnel: Weak<WireguardTunnel>,
+    driver_notify: Arc<Notify>,
+    socket: UdpSocket,
+    mut tunn: Tunn,
+    reserved: Option<[u8; 3]>,
+    peer_addr: SocketAddr,
+) {
+    let mut recv_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut out_buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    let mut inbound: Vec<Vec<u8>> = Vec::new();
+    let mut timer = tokio::time::interval(TIMER_TICK_INTERVAL);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    // Start the handshake right away so the first connection doesn't pay for it.
+    if let TunnResult::WriteToNetwork(packet) =
+        tunn.format_handshake_initiation(&mut out_buf, false)
+        && let Err(e) = send_to_peer(&socket, packet, reserved).await
+    {
+        warn!("Failed to send WireGuard handshake to {peer_addr}: {e}");
+    }
+
+    loop {
+        let (outgoing, delay) = {
+            let Some(tunnel) = tunnel.upgrade() else {
+                break;
+            };
+            let mut stack = tunnel.stack.lock();
+            stack.device.rx_queue.extend(inbound.drain(..));
+            stack.poll()
+        };
+
+        for packet in outgoing {
+            match tunn.encapsulate(&packet, &mut out_buf) {
+                TunnResult::WriteToNetwork(packet) => {
+                    if let Err(e) = send_to_peer(&socket, packet, reserved).await {
+                        debug!("Failed to send WireGuard packet to {peer_addr}: {e}");
+                    }
+                }
+                TunnResult::Err(e) => {
+                    debug!("WireGuard encapsulate error: {e:?}");
+                }
+                // Queued until the handshake completes.
+                _ => {}
+            }
+        }
+
+        let delay = delay.unwrap_or(MAX_POLL_DELAY).min(MAX_POLL_DELAY);
+
+        tokio::select! {
+            result = socket.recv(&mut recv_buf) => {
+                let n = match result {
+                    Ok(n) => n,
+                    Err(e) => {
+                        debug!("WireGuard UDP receive error from {peer_addr}: {e}");
+                        continue;
+                    }
+                };
+                let datagram = &mut recv_buf[..n];
+                if reserved.is_some() && datagram.len() >= 4 {
+                    datagram[1..4].fill(0);
+                }
+
+                match tunn.decapsulate(Some(peer_addr.ip()), datagram, &mut out_buf) {
+                    TunnResult::WriteToNetwork(packet) => {
+                        if let Err(e) = send_to_peer(&socket, packet, reserved).await {
+                            debug!("Failed to send WireGuard packet to {peer_addr}: {e}");
+                        }
+                        // Flush packets that were queued while the handshake was in progress.
+                        while let TunnResult::WriteToNetwork(packet) =
+                            tunn.decapsulate(None, &[], &mut out_buf)
+                        {
+                            if let Err(e) = send_to_peer(&socket, packet, reserved).await {
+                                debug!("Failed to send WireGuard packet to {peer_addr}: {e}");
+                            }
+                        }
+                    }
+                    TunnResult::WriteToTunnelV4(packet, _) | TunnResult::WriteToTunnelV6(packet, _) => {
+                        inbound.push(packet.to_vec());
+                    }
+                    TunnResult::Err(e) => {
+                        debug!("WireGuard decapsulate error: {e:?}");
+                    }
+                    TunnResult::Done => {}
+                }
+            }
+            _ = driver_notify.notified() => {}
+            _ = tokio::time::sleep(delay) => {}
+            _ = timer.tick() => {
+                match tunn.update_timers(&mut out_buf) {
+                    TunnResult::WriteToNetwork(packet) => {
+                        if let Err(e) = send_to_peer(&socket, packet, reserved).await {
+                            debug!("Failed to send WireGuard packet to {peer_addr}: {e}");
+                        }
+                    }
+                    TunnResult::Err(e) => {
+                        debug!("WireGuard timer error: {e:?}");
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    debug!("WireGuard tunnel to {peer_addr} stopped");
+}
+
+/// TCP connection inside the WireGuard tunnel.
+struct TunnelTcpStream {
+    tunnel: Arc<WireguardTunnel>,
+    handle: SocketHandle,
+    port: u16,
+}
+
+impl Drop for TunnelTcpStream {
+    fn drop(&mut self) {
+        let mut stack = self.tunnel.stack.lock();
+        stack.wakers.remove(&self.handle);
+        let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+        socket.close();
+        stack.closing.push((self.handle, self.port));
+        drop(stack);
+        self.tunnel.notify_driver();
+    }
+}
+
+impl AsyncRead for TunnelTcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut stack = this.tunnel.stack.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(this.handle);
+
+        if socket.can_recv() {
+            let n = socket
+                .recv_slice(buf.initialize_unfilled())
+                .map_err(|e| std::io::Error::other(format!("WireGuard TCP recv error: {e}")))?;
+            buf.advance(n);
+            drop(stack);
+            // Freed receive window space, let the stack send a window update.
+            this.tunnel.notify_driver();
+            return Poll::Ready(Ok(()));
+        }
+
+        if !socket.may_recv() {
+            if socket.state() == tcp::State::Closed {
+                return Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()));
+            }
+            // Peer sent FIN.
+            return Poll::Ready(Ok(()));
+        }
+
+        stack.register_waker(this.handle, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for TunnelTcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let mut stack = this.tunnel.stack.lock();
+        let socket = stack.sockets.get_mut::<tcp::Socket>(this.handle);
+
+        if !socket.may_send() {
+            return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()));
+        }
+
+        if socket.can_send() {
+            let n = socket
+                .send_slice(buf)
+                .map_err(|e| std::io::Error::other(format!("WireGuard TCP send error: {e}")))?;
+            drop(stack);
+            this.tunnel.notify_driver();
+            return Poll::Ready(Ok(n));
+        }
+
+        stack.register_waker(this.handle, cx.waker());
+        Poll::Pending
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut stack = this.tunnel.stack.lock();
+        stack.sockets.get_mut::<tcp::Socket>(this.handle).close();
+        drop(stack);
+        this.tunnel.notify_driver();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for TunnelTcpStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for TunnelTcpStream {}
+
+/// UDP socket inside the WireGuard tunnel, bound to a single remote endpoint.
+struct TunnelUdpStream {
+    tunnel: Arc<WireguardTunnel>,
+    handle: SocketHandle,
+    port: u16,
+    remote: IpEndpoint,
+}
+
+impl Drop for TunnelUdpStream {
+    fn drop(&mut self) {
+        self.tunnel
+            .stack
+            .lock()
+            .remove_socket(self.handle, self.port);
+    }
+}
+
+impl AsyncReadMessage for TunnelUdpStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut stack = this.tunnel.stack.lock();
+        let socket = stack.sockets.get_mut::<udp::Socket>(this.handle);
+
+        while socket.can_recv() {
+            let (n, metadata) = socket
+                .recv_slice(buf.initialize_unfilled())
+                .map_err(|e| std::io::Error::other(format!("WireGuard UDP recv error: {e}")))?;
+            if metadata.endpoint != this.remote {
+                debug!(
+                    "Dropping WireGuard UDP packet from unexpected source {}",
+                    metadata.endpoint
+                );
+                continue;
+            }
+            buf.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+
+        stack.register_waker(this.handle, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncWriteMessage for TunnelUdpStream {
+    fn poll_write_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let mut stack = this.tunnel.stack.lock();
+        let socket = stack.sockets.get_mut::<udp::Socket>(this.handle);
+
+        if socket.can_send() {
+            socket
+                .send_slice(buf, this.remote)
+                .map_err(|e| std::io::Error::other(format!("WireGuard UDP send error: {e}")))?;
+            drop(stack);
+            this.tunnel.notify_driver();
+            return Poll::Ready(Ok(()));
+        }
+
+        stack.register_waker(this.handle, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl AsyncFlushMessage for TunnelUdpStream {
+    fn poll_flush_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncShutdownMessage for TunnelUdpStream {
+    fn poll_shutdown_message(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for TunnelUdpStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncMessageStream for TunnelUdpStream {}
+
+/// ICMP socket inside the WireGuard tunnel, used for a single echo request.
+struct TunnelIcmpSocket {
+    tunnel: Arc<WireguardTunnel>,
+    handle: SocketHandle,
+    identifier: u16,
+}
+
+impl Drop for TunnelIcmpSocket {
+    fn drop(&mut self) {
+        self.tunnel
+            .stack
+            .lock()
+            .remove_socket(self.handle, self.identifier);
+    }
+}
+
+/// SocketConnector that connects to destinations through a WireGuard peer.
+///
+/// WireGuard terminates the chain at hop 0: the returned streams already reach
+/// the final destination (or the next hop's address) through the tunnel.
+#[derive(Debug)]
+pub struct WireguardSocketConnector {
+    config: WireguardTunnelConfig,
+    peer_address: NetLocation,
+    udp_enabled: bool,
+    dial_options: DialOptions,
+    tunnel: OnceCell<Arc<WireguardTunnel>>,
+}
+
+impl WireguardSocketConnector {
+    pub fn new(
+        config: WireguardTunnelConfig,
+        peer_address: NetLocation,
+        udp_enabled: bool,
+        dial_options: DialOptions,
+    ) -> Self {
+        Self {
+            config,
+            peer_address,
+            udp_enabled,
+            dial_options,
+            tunnel: OnceCell::new(),
+        }
+    }
+
+    async fn get_or_create_tunnel(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+    ) -> std::io::Result<&Arc<WireguardTunnel>> {
+        self.tunnel
+            .get_or_try_init(|| async {
+                let peer_addr = resolve_single_address(resolver, &self.peer_address).await?;
+                let socket = new_udp_socket(peer_addr.is_ipv6(), &self.dial_options)?;
+                socket.connect(peer_addr).await?;
+
+                let tunn = Tunn::new(
+                    StaticSecret::from(self.config.private_key),
+                    PublicKey::from(self.config.peer_public_key),
+                    self.config.preshared_key,
+                    self.config.persistent_keepalive,
+                    rand::random::<u32>() >> 8,
+                    None,
+                );
+
+                let tunnel = Arc::new(WireguardTunnel::new(self.config.clone())?);
+                tokio::spawn(run_tunnel_driver(
+                    Arc::downgrade(&tunnel),
+                    tunnel.driver_notify.clone(),
+                    socket,
+                    tunn,
+                    self.config.reserved,
+                    peer_addr,
+                ));
+                Ok(tunnel)
+            })
+            .await
+    }
+}
+
+#[async_trait::async_trait]
+impl SocketConnector for WireguardSocketConnector {
+    async fn connect(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        address: &ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncStream>> {
+        let tunnel = self.get_or_create_tunnel(resolver).await?;
+        let mut address = address.clone();
+        let remote = resolve_location(&mut address, resolver).await?;
+        let stream = tunnel.connect_tcp(remote).await?;
+        Ok(Box::new(stream))
+    }
+
+    async fn connect_udp_bidirectional(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        mut target: ResolvedLocation,
+    ) -> std::io::Result<Box<dyn AsyncMessageStream>> {
+        if !self.udp_enabled {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "UDP is disabled for this WireGuard client",
+            ));
+        }
+        let tunnel = self.get_or_create_tunnel(resolver).await?;
+        let remote = resolve_location(&mut target, resolver).await?;
+        let stream = tunnel.bind_udp(remote)?;
+        Ok(Box::new(stream))
+    }
+
+    async fn ping(
+        &self,
+        resolver: &Arc<dyn Resolver>,
+        ip: IpAddr,
+        payload: &[u8],
+    ) -> std::io::Result<()> {
+        let tunnel = self.get_or_create_tunnel(resolver).await?;
+        tunnel.ping(ip, payload).await
+    }
+
+    fn bind_interface(&self) -> Option<&str> {
+        self.dial_options.bind_interface.as_deref()
+    }
+
+    fn dial_options(&self) -> DialOptions {
+        self.dial_options.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_KEY: &str = "YNXtAzepDqRv9H52osJVDQnznT5AL11eVlIHq3nApGA=";
+
+    fn test_config(
+        allowed_ips: &[&str],
+        reserved: &[u8],
+    ) -> std::io::Result<WireguardTunnelConfig> {
+        WireguardTunnelConfig::from_fields(
+            TEST_KEY,
+            TEST_KEY,
+            None,
+            &["172.16.0.2/32".to_string(), "fd01::2/128".to_string()],
+            &allowed_ips
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+            reserved,
+            1420,
+            None,
+        )
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = test_config(&["0.0.0.0/0", "::/0"], &[1, 2, 3]).unwrap();
+        assert_eq!(config.addresses.len(), 2);
+        assert_eq!(config.reserved, Some([1, 2, 3]));
+        assert_eq!(config.mtu, 1420);
+    }
+
+    #[test]
+    fn test_invalid_key_rejected() {
+        let result = WireguardTunnelConfig::from_fields(
+            "not-a-key",
+            TEST_KEY,
+            None,
+            &["172.16.0.2/32".to_string()],
+            &[],
+            &[],
+            1420,
+            None,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_invalid_reserved_rejected() {
+        assert!(test_config(&["0.0.0.0/0"], &[1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_allowed_ips() {
+        let config = test_config(&["10.0.0.0/8", "2001:db8::/32"], &[]).unwrap();
+        assert!(config.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!config.is_allowed("11.0.0.1".parse().unwrap()));
+        assert!(config.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!config.is_allowed("2001:db9::1".parse().unwrap()));
+
+        let config = test_config(&["0.0.0.0/0"], &[]).unwrap();
+        assert!(config.is_allowed("8.8.8.8".parse().unwrap()));
+        assert!(!config.is_allowed("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_allocate_port_skips_used_ports() {
+        let tunnel = WireguardTunnel::new(test_config(&["0.0.0.0/0"], &[]).unwrap()).unwrap();
+        let mut stack = tunnel.stack.lock();
+        stack.next_port = u16::MAX;
+        stack.used_ports.insert(EPHEMERAL_PORT_START);
+        assert_eq!(stack.allocate_port().unwrap(), u16::MAX);
+        assert_eq!(stack.allocate_port().unwrap(), EPHEMERAL_PORT_START + 1);
+
+        stack.used_ports.extend(EPHEMERAL_PORT_START..=u16::MAX);
+        assert!(stack.allocate_port().is_err());
+        stack.used_ports.remove(&u16::MAX);
+        assert_eq!(stack.allocate_port().unwrap(), u16::MAX);
+    }
+
+    #[test]
+    fn test_parse_cidr_defaults_to_host_prefix() {
+        assert_eq!(
+            parse_cidr("192.168.1.1").unwrap(),
+            ("192.168.1.1".parse().unwrap(), 32)
+        );
+        assert_eq!(parse_cidr("::1").unwrap(), ("::1".parse().unwrap(), 128));
+        assert!(parse_cidr("10.0.0.0/33").is_err());
+    }
+}
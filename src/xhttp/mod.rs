@@ -0,0 +1,7 @@
+mod xhttp_client;
+mod xhttp_protocol;
+mod xhttp_server;
+mod xhttp_stream;
+
+pub use xhttp_client::XhttpTcpClientHandler;
+pub use xhttp_server::XhttpTcpServerHandler;
@@ -0,0 +1,277 @@
+//! Client side of the XHTTP transport.
+//!
+//! Each proxied connection uses its own HTTP/2 connection, which carries all the
+//! requests of its session.
+
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use h2::client::SendRequest;
+use http::{Method, Request, StatusCode};
+use log::debug;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+use super::xhttp_protocol::{CLIENT_POST_BYTES, generate_padding, normalize_path};
+use super::xhttp_stream::{CHANNEL_CAPACITY, XhttpStream};
+use crate::address::ResolvedLocation;
+use crate::async_stream::{AsyncMessageStream, AsyncStream};
+use crate::config::XhttpMode;
+use crate::tcp::tcp_handler::{TcpClientHandler, TcpClientSetupResult};
+use crate::uuid_util::generate_uuid;
+
+/// Least time between packet-up uploads, Xray's default `scMinPostsIntervalMs`.
+const MIN_POST_INTERVAL: Duration = Duration::from_millis(30);
+
+#[derive(Debug)]
+pub struct XhttpTcpClientHandler {
+    /// Base URI of the requests, ending with a slash.
+    base_uri: Arc<str>,
+    mode: XhttpMode,
+    handler: Box<dyn TcpClientHandler>,
+}
+
+impl XhttpTcpClientHandler {
+    pub fn new(
+        path: &str,
+        authority: &str,
+        mode: XhttpMode,
+        handler: Box<dyn TcpClientHandler>,
+    ) -> Self {
+        Self {
+            base_uri: Arc::from(format!("https://{}{}", authority, normalize_path(path))),
+            mode,
+            handler,
+        }
+    }
+
+    async fn setup_client_stream_common(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+    ) -> io::Result<XhttpStream> {
+        let (send_request, connection) = h2::client::handshake(client_stream)
+            .await
+            .map_err(|e| io::Error::other(format!("XHTTP H2 handshake failed: {e}")))?;
+
+        // The connection finishes on its own once the stream handles are dropped.
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                debug!("XHTTP client H2 connection ended: {e}");
+            }
+        });
+
+        let (download_tx, download_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (upload_tx, upload_rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+        match self.mode {
+            XhttpMode::StreamOne => {
+                let mut send_request = send_request.ready().await.map_err(io::Error::other)?;
+                let request = new_request(Method::POST, &self.base_uri, true)?;
+                let (response, send_stream) = send_request
+                    .send_request(request, false)
+                    .map_err(io::Error::other)?;
+                tokio::spawn(copy_response(response, download_tx));
+                tokio::spawn(copy_upload(upload_rx, send_stream));
+            }
+            XhttpMode::StreamUp | XhttpMode::PacketUp => {
+                let session_uri = format!("{}{}", self.base_uri, generate_uuid());
+                let mut send_request = send_request.ready().await.map_err(io::Error::other)?;
+                let request = new_request(Method::GET, &session_uri, false)?;
+                let (response, _) = send_request
+                    .send_request(request, true)
+                    .map_err(io::Error::other)?;
+                tokio::spawn(copy_response(response, download_tx));
+
+                if self.mode == XhttpMode::StreamUp {
+                    let mut send_request = send_request.ready().await.map_err(io::Error::other)?;
+                    let request = new_request(Method::POST, &session_uri, true)?;
+                    let (response, send_stream) = send_request
+                        .send_request(request, false)
+                        .map_err(io::Error::other)?;
+                    tokio::spawn(async move {
+                        // The server keeps the response open until the upload ends.
+                        if let Err(e) = response.await {
+                            debug!("XHTTP upload request failed: {e}");
+                        }
+                    });
+                    tokio::spawn(copy_upload(upload_rx, send_stream));
+                } else {
+                    tokio::spawn(run_packet_upload(send_request, session_uri, upload_rx));
+                }
+            }
+        }
+
+        Ok(XhttpStream::new(download_rx, upload_tx))
+    }
+}
+
+fn new_request(method: Method, uri: &str, streamed: bool) -> io::Result<Request<()>> {
+    let mut builder = Request::builder()
+        .method(method)
+        .uri(format!("{uri}?x_padding={}", generate_padding()));
+    if streamed {
+        // Like Xray, so that CDNs pass streamed request bodies through as with gRPC.
+        builder = builder.header(http::header::CONTENT_TYPE, "application/grpc");
+    }
+    builder
+        .body(())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Sends the download of the response to the stream.
+async fn copy_response(response: h2::client::ResponseFuture, data_tx: mpsc::Sender<Bytes>) {
+    let response = match response.await {
+        Ok(response) => response,
+        Err(e) => {
+            debug!("XHTTP download request failed: {e}");
+            return;
+        }
+    };
+    if response.status() != StatusCode::OK {
+        debug!(
+            "XHTTP download request failed with status: {}",
+            response.status()
+        );
+        return;
+    }
+
+    let mut body = response.into_body();
+    while let Some(data) = body.data().await {
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                debug!("XHTTP download ended with error: {e}");
+                return;
+            }
+        };
+        let _ = body.flow_control().release_capacity(data.len());
+        if data_tx.send(data).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn send_data(send_stream: &mut h2::SendStream<Bytes>, mut data: Bytes) -> io::Result<()> {
+    while !data.is_empty() {
+        send_stream.reserve_capacity(data.len());
+        let capacity = match std::future::poll_fn(|cx| send_stream.poll_capacity(cx)).await {
+            Some(Ok(capacity)) => capacity,
+            Some(Err(e)) => return Err(io::Error::other(e)),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "XHTTP request closed",
+                ));
+            }
+        };
+        let chunk = data.split_to(capacity.min(data.len()));
+        send_stream
+            .send_data(chunk, false)
+            .map_err(io::Error::other)?;
+    }
+    Ok(())
+}
+
+/// Sends the stream's writes in the body of a streamed upload request.
+async fn copy_upload(mut data_rx: mpsc::Receiver<Bytes>, mut send_stream: h2::SendStream<Bytes>) {
+    while let Some(data) = data_rx.recv().await {
+        if let Err(e) = send_data(&mut send_stream, data).await {
+            debug!("XHTTP upload ended with error: {e}");
+            return;
+        }
+    }
+    let _ = send_stream.send_data(Bytes::new(), true);
+}
+
+/// Sends the stream's writes as numbered upload requests, coalescing the writes
+/// queued while the previous request was sent.
+async fn run_packet_upload(
+    mut send_request: SendRequest<Bytes>,
+    session_uri: String,
+    mut data_rx: mpsc::Receiver<Bytes>,
+) {
+    let mut seq = 0u64;
+    let mut next_post = Instant::now();
+    while let Some(data) = data_rx.recv().await {
+        let mut body = BytesMut::from(&data[..]);
+        while body.len() < CLIENT_POST_BYTES {
+            match data_rx.try_recv() {
+                Ok(data) => body.extend_from_slice(&data),
+                Err(_) => break,
+            }
+        }
+
+        tokio::time::sleep_until(next_post).await;
+        next_post = Instant::now() + MIN_POST_INTERVAL;
+
+        if let Err(e) = send_packet(&mut send_request, &session_uri, seq, body.freeze()).await {
+            debug!("XHTTP upload {seq} failed: {e}");
+            return;
+        }
+        seq += 1;
+    }
+}
+
+async fn send_packet(
+    send_request: &mut SendRequest<Bytes>,
+    session_uri: &str,
+    seq: u64,
+    data: Bytes,
+) -> io::Result<()> {
+    std::future::poll_fn(|cx| send_request.poll_ready(cx))
+        .await
+        .map_err(io::Error::other)?;
+    let request = new_request(Method::POST, &format!("{session_uri}/{seq}"), false)?;
+    let (response, mut send_stream) = send_request
+        .send_request(request, false)
+        .map_err(io::Error::other)?;
+    send_data(&mut send_stream, data).await?;
+    send_stream
+        .send_data(Bytes::new(), true)
+        .map_err(io::Error::other)?;
+
+    // Later uploads don't wait for the response, the server orders them by number.
+    tokio::spawn(async move {
+        match response.await {
+            Ok(response) if response.status() == StatusCode::OK => {}
+            Ok(response) => debug!(
+                "XHTTP upload {seq} failed with status: {}",
+                response.status()
+            ),
+            Err(e) => debug!("XHTTP upload {seq} failed: {e}"),
+        }
+    });
+    Ok(())
+}
+
+#[async_trait]
+impl TcpClientHandler for XhttpTcpClientHandler {
+    async fn setup_client_tcp_stream(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        remote_location: ResolvedLocation,
+    ) -> io::Result<TcpClientSetupResult> {
+        let stream = self.setup_client_stream_common(client_stream).await?;
+        self.handler
+            .setup_client_tcp_stream(Box::new(stream), remote_location)
+            .await
+    }
+
+    fn supports_udp_over_tcp(&self) -> bool {
+        self.handler.supports_udp_over_tcp()
+    }
+
+    async fn setup_client_udp_bidirectional(
+        &self,
+        client_stream: Box<dyn AsyncStream>,
+        target: ResolvedLocation,
+    ) -> io::Result<Box<dyn AsyncMessageStream>> {
+        let stream = self.setup_client_stream_common(client_stream).await?;
+        self.handler
+            .setup_client_udp_bidirectional(Box::new(stream), target)
+            .await
+    }
+}
@@ -0,0 +1,129 @@
+//! Request layout of Xray's XHTTP (SplitHTTP) transport.
+//!
+//! A session is identified by a random UUID. In packet-up mode, the client downloads
+//! with `GET <path><uuid>` and uploads with `POST <path><uuid>/<seq>`, one request per
+//! packet, which the server puts back in order. Stream-up uploads with a single
+//! streamed `POST <path><uuid>` instead, and stream-one does both directions in a
+//! single streamed `POST <path>`.
+
+use std::ops::RangeInclusive;
+
+use rand::Rng;
+
+/// Largest body of a packet-up upload request, Xray's default `scMaxEachPostBytes`.
+pub const MAX_EACH_POST_BYTES: usize = 1_000_000;
+
+/// Packet-up uploads are coalesced up to this size, so that a post stays below
+/// `MAX_EACH_POST_BYTES` after adding one more write.
+pub const CLIENT_POST_BYTES: usize = 512 * 1024;
+
+/// Out of order packet-up uploads buffered by the server, Xray's default
+/// `scMaxBufferedPosts`.
+pub const MAX_BUFFERED_POSTS: usize = 30;
+
+/// Length range of the `x_padding` query of requests and the `X-Padding` header of
+/// responses, Xray's default `xPaddingBytes`.
+pub const PADDING_RANGE: RangeInclusive<usize> = 100..=1000;
+
+/// Returns the path with a leading and trailing slash and without a query, which is
+/// what the session ID and sequence number are appended to.
+pub fn normalize_path(path: &str) -> String {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        "/".to_string()
+    } else {
+        format!("/{path}/")
+    }
+}
+
+pub fn generate_padding() -> String {
+    "X".repeat(rand::rng().random_range(PADDING_RANGE))
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum XhttpRequest<'a> {
+    /// `POST <path>`, both directions of a stream-one session.
+    StreamOne,
+    /// `GET <path><session>`, the download of a session.
+    Download { session_id: &'a str },
+    /// `POST <path><session>`, the streamed upload of a stream-up session.
+    StreamUpload { session_id: &'a str },
+    /// `POST <path><session>/<seq>`, one upload of a packet-up session.
+    PacketUpload { session_id: &'a str, seq: u64 },
+}
+
+/// Parses the path of a request under the normalized `base_path`. Returns None for
+/// paths and methods that aren't XHTTP requests.
+pub fn parse_request<'a>(
+    method: &http::Method,
+    base_path: &str,
+    request_path: &'a str,
+) -> Option<XhttpRequest<'a>> {
+    let rest = request_path
+        .strip_prefix(base_path)
+        .or_else(|| (request_path == base_path.trim_end_matches('/')).then_some(""))?;
+    let mut parts = rest.split('/').filter(|part| !part.is_empty());
+    let request = match (parts.next(), parts.next()) {
+        (None, _) if *method == http::Method::POST => XhttpRequest::StreamOne,
+        (Some(session_id), None) if *method == http::Method::GET => {
+            XhttpRequest::Download { session_id }
+        }
+        (Some(session_id), None) if *method == http::Method::POST => {
+            XhttpRequest::StreamUpload { session_id }
+        }
+        (Some(session_id), Some(seq)) if *method == http::Method::POST => {
+            XhttpRequest::PacketUpload {
+                session_id,
+                seq: seq.parse().ok()?,
+            }
+        }
+        _ => return None,
+    };
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::Method;
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path(""), "/");
+        assert_eq!(normalize_path("/"), "/");
+        assert_eq!(normalize_path("/xhttp"), "/xhttp/");
+        assert_eq!(normalize_path("xhttp/?ed=2048"), "/xhttp/");
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let base = "/xhttp/";
+        assert_eq!(
+            parse_request(&Method::POST, base, "/xhttp"),
+            Some(XhttpRequest::StreamOne)
+        );
+        assert_eq!(
+            parse_request(&Method::GET, base, "/xhttp/abc"),
+            Some(XhttpRequest::Download { session_id: "abc" })
+        );
+        assert_eq!(
+            parse_request(&Method::POST, base, "/xhttp/abc"),
+            Some(XhttpRequest::StreamUpload { session_id: "abc" })
+        );
+        assert_eq!(
+            parse_request(&Method::POST, base, "/xhttp/abc/3"),
+            Some(XhttpRequest::PacketUpload {
+                session_id: "abc",
+                seq: 3
+            })
+        );
+        assert_eq!(parse_request(&Method::GET, base, "/xhttp/"), None);
+        assert_eq!(parse_request(&Method::POST, base, "/xhttp/abc/x"), None);
+        assert_eq!(parse_request(&Method::POST, base, "/xhttp/abc/1/2"), None);
+        assert_eq!(parse_request(&Method::GET, base, "/other/abc"), None);
+    }
+}
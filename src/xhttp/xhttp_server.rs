@@ -0,0 +1,436 @@
+//! Server side of the XHTTP transport.
+//!
+//! Requests are served over HTTP/1.1 and HTTP/2, as CDNs usually connect to the
+//! origin with HTTP/1.1. The requests of a packet-up or stream-up session can come on
+//! different connections, so sessions are shared by all connections of the handler,
+//! and the inner protocol is started when the download request arrives.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{Request, Response, StatusCode};
+use http_body_util::{BodyExt, Empty, Limited, combinators::BoxBody};
+use hyper::body::{Frame, Incoming};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::debug;
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+
+use super::xhttp_protocol::{
+    MAX_BUFFERED_POSTS, MAX_EACH_POST_BYTES, XhttpRequest, generate_padding, normalize_path,
+    parse_request,
+};
+use super::xhttp_stream::{CHANNEL_CAPACITY, XhttpStream};
+use crate::async_stream::AsyncStream;
+use crate::http2_handler::PrefixedStream;
+use crate::resolver::Resolver;
+use crate::tcp::tcp_handler::{TcpServerHandler, TcpServerSetupResult};
+use crate::tcp::tcp_server::{InboundContext, process_stream};
+
+/// Time for the download request of a session to arrive after its first request.
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Longest session ID accepted, a UUID is 36 characters.
+const MAX_SESSION_ID_LEN: usize = 64;
+
+type ResponseBody = BoxBody<Bytes, io::Error>;
+
+/// Response body that streams the data sent to a channel, and ends when the sender
+/// is dropped.
+struct ChannelBody(mpsc::Receiver<Bytes>);
+
+impl hyper::body::Body for ChannelBody {
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, io::Error>>> {
+        self.0
+            .poll_recv(cx)
+            .map(|data| data.map(|data| Ok(Frame::data(data))))
+    }
+}
+
+/// Upload half of a packet-up or stream-up session.
+#[derive(Debug)]
+struct UploadQueue {
+    /// Dropped when a stream-up upload ends.
+    data_tx: Option<mpsc::Sender<Bytes>>,
+    next_seq: u64,
+    /// Packets that arrived before the previous ones.
+    pending: BTreeMap<u64, Bytes>,
+}
+
+#[derive(Debug)]
+struct Session {
+    upload: tokio::sync::Mutex<UploadQueue>,
+    /// Read side of the upload, taken by the download request.
+    data_rx: Mutex<Option<mpsc::Receiver<Bytes>>>,
+}
+
+#[derive(Debug)]
+struct XhttpServer {
+    path: String,
+    host: Option<String>,
+    handler: Arc<dyn TcpServerHandler>,
+    resolver: Arc<dyn Resolver>,
+    sessions: Mutex<FxHashMap<String, Arc<Session>>>,
+}
+
+impl XhttpServer {
+    fn get_or_create_session(self: &Arc<Self>, session_id: &str) -> Arc<Session> {
+        let mut sessions = self.sessions.lock();
+        if let Some(session) = sessions.get(session_id) {
+            return session.clone();
+        }
+
+        let (data_tx, data_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let session = Arc::new(Session {
+            upload: tokio::sync::Mutex::new(UploadQueue {
+                data_tx: Some(data_tx),
+                next_seq: 0,
+                pending: BTreeMap::new(),
+            }),
+            data_rx: Mutex::new(Some(data_rx)),
+        });
+        sessions.insert(session_id.to_string(), session.clone());
+
+        // Forget sessions whose download never arrives.
+        let server = Arc::downgrade(self);
+        let session_id = session_id.to_string();
+        tokio::spawn(async move {
+            tokio::time::sleep(SESSION_TIMEOUT).await;
+            if let Some(server) = server.upgrade() {
+                let mut sessions = server.sessions.lock();
+                if sessions
+                    .get(&session_id)
+                    .is_some_and(|session| session.data_rx.lock().is_some())
+                {
+                    debug!("XHTTP session {session_id} timed out without a download");
+                    sessions.remove(&session_id);
+                }
+            }
+        });
+
+        session
+    }
+
+    fn is_expected_host(&self, request: &Request<Incoming>) -> bool {
+        let Some(ref host) = self.host else {
+            return true;
+        };
+        let request_host = request.uri().host().or_else(|| {
+            request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.rsplit_once(':').map_or(v, |(host, _)| host))
+        });
+        request_host.is_some_and(|request_host| request_host.eq_ignore_ascii_case(host))
+    }
+
+    /// Runs the inner protocol on the stream of a session.
+    fn start_stream(self: &Arc<Self>, stream: XhttpStream, session_id: Option<String>) {
+        let server = self.clone();
+        tokio::spawn(async move {
+            let inbound = InboundContext::default();
+            if let Err(e) = process_stream(
+                stream,
+                server.handler.clone(),
+                server.resolver.clone(),
+                inbound,
+            )
+            .await
+            {
+                debug!("XHTTP stream finished with error: {e}");
+            }
+            if let Some(session_id) = session_id {
+                server.sessions.lock().remove(&session_id);
+            }
+        });
+    }
+
+    async fn serve(self: Arc<Self>, request: Request<Incoming>) -> Response<ResponseBody> {
+        if !self.is_expected_host(&request) {
+            debug!(
+                "Rejecting XHTTP request for host {:?}",
+                request.uri().host()
+            );
+            return empty_response(StatusCode::NOT_FOUND);
+        }
+
+        let path = request.uri().path().to_string();
+        let Some(xhttp_request) = parse_request(request.method(), &self.path, &path) else {
+            debug!("Rejecting XHTTP request: {} {}", request.method(), path);
+            return empty_response(StatusCode::NOT_FOUND);
+        };
+
+        if let XhttpRequest::Download { session_id }
+        | XhttpRequest::StreamUpload { session_id }
+        | XhttpRequest::PacketUpload { session_id, .. } = xhttp_request
+            && session_id.len() > MAX_SESSION_ID_LEN
+        {
+            return empty_response(StatusCode::BAD_REQUEST);
+        }
+
+        match xhttp_request {
+            XhttpRequest::StreamOne => {
+                let (upload_tx, upload_rx) = mpsc::channel(CHANNEL_CAPACITY);
+                let (download_tx, download_rx) = mpsc::channel(CHANNEL_CAPACITY);
+                tokio::spawn(copy_request_body(request.into_body(), upload_tx));
+                self.start_stream(XhttpStream::new(upload_rx, download_tx), None);
+                stream_response(download_rx)
+            }
+            XhttpRequest::Download { session_id } => {
+                let session = self.get_or_create_session(session_id);
+                let Some(upload_rx) = session.data_rx.lock().take() else {
+                    debug!("XHTTP session {session_id} already has a download");
+                    return empty_response(StatusCode::CONFLICT);
+                };
+                let (download_tx, download_rx) = mpsc::channel(CHANNEL_CAPACITY);
+                self.start_stream(
+                    XhttpStream::new(upload_rx, download_tx),
+                    Some(session_id.to_string()),
+                );
+                stream_response(download_rx)
+            }
+            XhttpRequest::StreamUpload { session_id } => {
+                let session = self.get_or_create_session(session_id);
+                let Some(upload_tx) = session.upload.lock().await.data_tx.take() else {
+                    return empty_response(StatusCode::CONFLICT);
+                };
+                // The response stays open until the upload ends, as Xray clients expect.
+                let (done_tx, done_rx) = mpsc::channel(1);
+                let body = request.into_body();
+                tokio::spawn(async move {
+                    copy_request_body(body, upload_tx).await;
+                    drop(done_tx);
+                });
+                stream_response(done_rx)
+            }
+            XhttpRequest::PacketUpload { session_id, seq } => {
+                let data = match Limited::new(request.into_body(), MAX_EACH_POST_BYTES)
+                    .collect()
+                    .await
+                {
+                    Ok(collected) => collected.to_bytes(),
+                    Err(e) => {
+                        debug!("Failed to read XHTTP upload of session {session_id}: {e}");
+                        return empty_response(StatusCode::BAD_REQUEST);
+                    }
+                };
+                let session = self.get_or_create_session(session_id);
+                match push_packet(&session, seq, data).await {
+                    Ok(()) => empty_response(StatusCode::OK),
+                    Err(e) => {
+                        debug!("Rejecting XHTTP upload of session {session_id}: {e}");
+                        empty_response(StatusCode::BAD_REQUEST)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Queues a packet-up upload, and sends the packets that are now in order.
+async fn push_packet(session: &Session, seq: u64, data: Bytes) -> io::Result<()> {
+    let mut upload = session.upload.lock().await;
+    let upload = &mut *upload;
+    if seq < upload.next_seq {
+        return Err(io::Error::other(format!("duplicate packet {seq}")));
+    }
+    if upload.pending.len() >= MAX_BUFFERED_POSTS {
+        return Err(io::Error::other("too many out of order packets"));
+    }
+    upload.pending.insert(seq, data);
+
+    while let Some(data) = upload.pending.remove(&upload.next_seq) {
+        upload.next_seq += 1;
+        if data.is_empty() {
+            continue;
+        }
+        let Some(ref data_tx) = upload.data_tx else {
+            return Err(io::Error::other("upload is closed"));
+        };
+        // Waiting with the lock held makes later uploads wait for the reader too.
+        data_tx
+            .send(data)
+            .await
+            .map_err(|_| io::Error::other("session is closed"))?;
+    }
+    Ok(())
+}
+
+async fn copy_request_body(mut body: Incoming, data_tx: mpsc::Sender<Bytes>) {
+    while let Some(frame) = body.frame().await {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                debug!("XHTTP upload ended with error: {e}");
+                break;
+            }
+        };
+        if let Ok(data) = frame.into_data()
+            && !data.is_empty()
+            && data_tx.send(data).await.is_err()
+        {
+            break;
+        }
+    }
+}
+
+fn response_builder(status: StatusCode) -> http::response::Builder {
+    Response::builder()
+        .status(status)
+        .header("X-Padding", generate_padding())
+        // Keeps proxies and CDNs from buffering or caching the response.
+        .header("X-Accel-Buffering", "no")
+        .header(http::header::CACHE_CONTROL, "no-store")
+}
+
+fn empty_response(status: StatusCode) -> Response<ResponseBody> {
+    response_builder(status)
+        .body(Empty::new().map_err(|never| match never {}).boxed())
+        .unwrap()
+}
+
+fn stream_response(data_rx: mpsc::Receiver<Bytes>) -> Response<ResponseBody> {
+    response_builder(StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "text/event-stream")
+        .body(ChannelBody(data_rx).boxed())
+        .unwrap()
+}
+
+#[derive(Debug)]
+pub struct XhttpTcpServerHandler {
+    server: Arc<XhttpServer>,
+}
+
+impl XhttpTcpServerHandler {
+    pub fn new(
+        path: &str,
+        host: Option<String>,
+        handler: Box<dyn TcpServerHandler>,
+        resolver: Arc<dyn Resolver>,
+    ) -> Self {
+        Self {
+            server: Arc::new(XhttpServer {
+                path: normalize_path(path),
+                host,
+                handler: Arc::from(handler),
+                resolver,
+                sessions: Mutex::new(FxHashMap::default()),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl TcpServerHandler for XhttpTcpServerHandler {
+    async fn setup_server_stream(
+        &self,
+        mut server_stream: Box<dyn AsyncStream>,
+    ) -> io::Result<TcpServerSetupResult> {
+        // HTTP/2 connections start with the "PRI * HTTP/2.0" preface.
+        let mut prefix = [0u8; 3];
+        server_stream.read_exact(&mut prefix).await?;
+        let is_h2 = &prefix == b"PRI";
+        let io = TokioIo::new(PrefixedStream::new(Box::new(prefix), server_stream));
+
+        let server = self.server.clone();
+        let service = hyper::service::service_fn(move |request| {
+            let server = server.clone();
+            async move { Ok::<_, Infallible>(server.serve(request).await) }
+        });
+
+        // Every request is served on its own, so the connection is served in the
+        // background like other multiplexed protocols.
+        tokio::spawn(async move {
+            let result = if is_h2 {
+                hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                    .auto_date_header(false)
+                    .serve_connection(io, service)
+                    .await
+            } else {
+                hyper::server::conn::http1::Builder::new()
+                    .auto_date_header(false)
+                    .serve_connection(io, service)
+                    .await
+            };
+            if let Err(e) = result {
+                debug!("XHTTP connection ended: {e}");
+            }
+        });
+
+        Ok(TcpServerSetupResult::AlreadyHandled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_session() -> (Session, mpsc::Receiver<Bytes>) {
+        let (data_tx, data_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let session = Session {
+            upload: tokio::sync::Mutex::new(UploadQueue {
+                data_tx: Some(data_tx),
+                next_seq: 0,
+                pending: BTreeMap::new(),
+            }),
+            data_rx: Mutex::new(None),
+        };
+        (session, data_rx)
+    }
+
+    #[tokio::test]
+    async fn test_push_packet_reorders() {
+        let (session, mut data_rx) = new_session();
+        push_packet(&session, 1, Bytes::from_static(b"b"))
+            .await
+            .unwrap();
+        assert!(data_rx.try_recv().is_err());
+        push_packet(&session, 0, Bytes::from_static(b"a"))
+            .await
+            .unwrap();
+        push_packet(&session, 2, Bytes::from_static(b"c"))
+            .await
+            .unwrap();
+        assert_eq!(data_rx.recv().await.unwrap(), Bytes::from_static(b"a"));
+        assert_eq!(data_rx.recv().await.unwrap(), Bytes::from_static(b"b"));
+        assert_eq!(data_rx.recv().await.unwrap(), Bytes::from_static(b"c"));
+
+        assert!(
+            push_packet(&session, 1, Bytes::from_static(b"b"))
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_push_packet_limits_pending() {
+        let (session, _data_rx) = new_session();
+        for seq in 1..=MAX_BUFFERED_POSTS as u64 {
+            push_packet(&session, seq, Bytes::from_static(b"x"))
+                .await
+                .unwrap();
+        }
+        let seq = MAX_BUFFERED_POSTS as u64 + 1;
+        assert!(
+            push_packet(&session, seq, Bytes::from_static(b"x"))
+                .await
+                .is_err()
+        );
+    }
+}
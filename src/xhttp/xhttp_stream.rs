@@ -0,0 +1,136 @@
+//! Byte stream of an XHTTP session.
+//!
+//! The HTTP requests of a session are served by separate tasks, so the stream only
+//! exchanges data with them through bounded channels. A closed download channel is
+//! EOF, and a closed upload channel ends the session's upload.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
+
+use crate::async_stream::{AsyncPing, AsyncStream};
+
+/// Capacity of the channels between the stream and the request tasks.
+pub const CHANNEL_CAPACITY: usize = 16;
+
+/// Largest write sent through the channel at once.
+const MAX_WRITE_CHUNK: usize = 32 * 1024;
+
+pub struct XhttpStream {
+    data_rx: mpsc::Receiver<Bytes>,
+    read_buffer: Bytes,
+    data_tx: PollSender<Bytes>,
+}
+
+impl XhttpStream {
+    pub fn new(data_rx: mpsc::Receiver<Bytes>, data_tx: mpsc::Sender<Bytes>) -> Self {
+        Self {
+            data_rx,
+            read_buffer: Bytes::new(),
+            data_tx: PollSender::new(data_tx),
+        }
+    }
+}
+
+impl AsyncRead for XhttpStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read_buffer.is_empty() {
+            match self.data_rx.poll_recv(cx) {
+                Poll::Ready(Some(data)) => self.read_buffer = data,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let n = std::cmp::min(self.read_buffer.len(), buf.remaining());
+        buf.put_slice(&self.read_buffer[..n]);
+        self.read_buffer.advance(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for XhttpStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        match self.data_tx.poll_reserve(cx) {
+            Poll::Ready(Ok(())) => {
+                let n = std::cmp::min(buf.len(), MAX_WRITE_CHUNK);
+                match self.data_tx.send_item(Bytes::copy_from_slice(&buf[..n])) {
+                    Ok(()) => Poll::Ready(Ok(n)),
+                    Err(_) => Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "xhttp session closed",
+                    ))),
+                }
+            }
+            Poll::Ready(Err(_)) => Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "xhttp session closed",
+            ))),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Data is sent by the request tasks as soon as it's queued.
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.data_tx.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncPing for XhttpStream {
+    fn supports_ping(&self) -> bool {
+        false
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<bool>> {
+        Poll::Ready(Ok(false))
+    }
+}
+
+impl AsyncStream for XhttpStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_stream_channels() {
+        let (download_tx, download_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let (upload_tx, mut upload_rx) = mpsc::channel(CHANNEL_CAPACITY);
+        let mut stream = XhttpStream::new(download_rx, upload_tx);
+
+        download_tx.send(Bytes::from_static(b"down")).await.unwrap();
+        drop(download_tx);
+        let mut buf = vec![];
+        stream.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(buf, b"down");
+
+        stream.write_all(b"up").await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(upload_rx.recv().await.unwrap(), Bytes::from_static(b"up"));
+        assert!(upload_rx.recv().await.is_none());
+        assert!(stream.write_all(b"again").await.is_err());
+    }
+}
@@ -0,0 +1,299 @@
+// XUDP client message stream - single-target UDP over a VLESS MUX connection
+// Packets carry their address in every frame like Xray's packet encoding, so responses
+// from any source of the server's UDP mapping are delivered (full-cone NAT).
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::ready;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::address::NetLocation;
+use crate::async_stream::{
+    AsyncFlushMessage, AsyncMessageStream, AsyncPing, AsyncReadMessage, AsyncShutdownMessage,
+    AsyncStream, AsyncWriteMessage,
+};
+
+use super::frame::{
+    FrameMetadata, FrameOption, GLOBAL_ID_LEN, SessionStatus, TargetNetwork, decode_frame,
+};
+
+/// Session ID used for packets, the address of each frame identifies the destination.
+const SESSION_ID: u16 = 0;
+
+pub struct XudpClientStream {
+    /// Underlying byte stream, after the VLESS header with the MUX command
+    inner_stream: Box<dyn AsyncStream>,
+
+    /// Destination of written packets
+    target: NetLocation,
+
+    /// Sent in the New frame so the server keeps the same UDP mapping for this stream
+    global_id: [u8; GLOBAL_ID_LEN],
+
+    /// Whether the New frame was sent
+    session_started: bool,
+
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+
+    /// EOF flag, set on EOF of the inner stream or an End frame
+    is_eof: bool,
+}
+
+impl XudpClientStream {
+    pub fn new(inner_stream: Box<dyn AsyncStream>, target: NetLocation) -> Self {
+        Self {
+            inner_stream,
+            target,
+            global_id: rand::random(),
+            session_started: false,
+            read_buffer: BytesMut::with_capacity(65536),
+            write_buffer: BytesMut::with_capacity(65536),
+            is_eof: false,
+        }
+    }
+}
+
+impl AsyncReadMessage for XudpClientStream {
+    fn poll_read_message(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        loop {
+            if this.is_eof {
+                return Poll::Ready(Ok(()));
+            }
+
+            while let Some((metadata, data)) = decode_frame(&mut this.read_buffer)? {
+                if metadata.option.has_error() {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionReset,
+                        "XUDP session closed by remote with error",
+                    )));
+                }
+                match metadata.status {
+                    SessionStatus::End => {
+                        this.is_eof = true;
+                        return Poll::Ready(Ok(()));
+                    }
+                    SessionStatus::KeepAlive => continue,
+                    SessionStatus::New | SessionStatus::Keep => {}
+                }
+                // Packets are accepted regardless of their source address.
+                let Some(data) = data.filter(|data| !data.is_empty()) else {
+                    continue;
+                };
+                if data.len() > buf.remaining() {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "buffer too small for incoming message",
+                    )));
+                }
+                buf.put_slice(&data);
+                return Poll::Ready(Ok(()));
+            }
+
+            let original_len = this.read_buffer.len();
+            this.read_buffer.resize(original_len + 8192, 0);
+            let mut read_buf = ReadBuf::new(&mut this.read_buffer[original_len..]);
+            let result = Pin::new(&mut this.inner_stream).poll_read(cx, &mut read_buf);
+            let n = read_buf.filled().len();
+            this.read_buffer.truncate(original_len + n);
+            ready!(result)?;
+
+            if n == 0 {
+                if !this.read_buffer.is_empty() {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "EOF reached in the middle of a frame",
+                    )));
+                }
+                this.is_eof = true;
+            }
+        }
+    }
+}
+
+impl AsyncWriteMessage for XudpClientStream {
+    fn poll_write_message(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<()>> {
+        if !self.write_buffer.is_empty() {
+            ready!(self.as_mut().poll_flush_message(cx))?;
+        }
+
+        if buf.len() > u16::MAX as usize {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "message size too large",
+            )));
+        }
+
+        let this = self.get_mut();
+        let status = if this.session_started {
+            SessionStatus::Keep
+        } else {
+            SessionStatus::New
+        };
+        let metadata = FrameMetadata {
+            session_id: SESSION_ID,
+            status,
+            option: FrameOption::new().with_data(),
+            target: Some(this.target.clone()),
+            network: Some(TargetNetwork::Udp),
+        };
+        metadata.encode_with_global_id(&mut this.write_buffer, Some(&this.global_id))?;
+        this.write_buffer.put_u16(buf.len() as u16);
+        this.write_buffer.extend_from_slice(buf);
+        this.session_started = true;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncFlushMessage for XudpClientStream {
+    fn poll_flush_message(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+
+        while !this.write_buffer.is_empty() {
+            let n = ready!(Pin::new(&mut this.inner_stream).poll_write(cx, &this.write_buffer))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::WriteZero,
+                    "failed to write frame",
+                )));
+            }
+            this.write_buffer.advance(n);
+        }
+
+        Pin::new(&mut this.inner_stream).poll_flush(cx)
+    }
+}
+
+impl AsyncShutdownMessage for XudpClientStream {
+    fn poll_shutdown_message(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        ready!(self.as_mut().poll_flush_message(cx))?;
+        Pin::new(&mut self.get_mut().inner_stream).poll_shutdown(cx)
+    }
+}
+
+impl AsyncPing for XudpClientStream {
+    fn supports_ping(&self) -> bool {
+        self.inner_stream.supports_ping()
+    }
+
+    fn poll_write_ping(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<bool>> {
+        Pin::new(&mut self.get_mut().inner_stream).poll_write_ping(cx)
+    }
+}
+
+impl AsyncMessageStream for XudpClientStream {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address::Address;
+    use crate::async_stream::AsyncShutdownMessageExt;
+    use std::future::poll_fn;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    impl AsyncPing for DuplexStream {
+        fn supports_ping(&self) -> bool {
+            false
+        }
+
+        fn poll_write_ping(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<bool>> {
+            Poll::Ready(Ok(false))
+        }
+    }
+
+    impl AsyncStream for DuplexStream {}
+
+    fn location(address: &str, port: u16) -> NetLocation {
+        NetLocation::new(Address::Ipv4(address.parse().unwrap()), port)
+    }
+
+    #[tokio::test]
+    async fn test_client_stream_full_cone() {
+        let (client, mut server) = tokio::io::duplex(65536);
+        let target = location("1.1.1.1", 3478);
+        let mut stream = XudpClientStream::new(Box::new(client), target.clone());
+
+        for payload in [&b"first"[..], &b"second"[..]] {
+            poll_fn(|cx| Pin::new(&mut stream).poll_write_message(cx, payload))
+                .await
+                .unwrap();
+            poll_fn(|cx| Pin::new(&mut stream).poll_flush_message(cx))
+                .await
+                .unwrap();
+        }
+
+        let mut received = BytesMut::new();
+        let mut frames = vec![];
+        while frames.len() < 2 {
+            let mut chunk = [0u8; 1024];
+            let n = server.read(&mut chunk).await.unwrap();
+            received.extend_from_slice(&chunk[..n]);
+            while let Some(frame) = decode_frame(&mut received).unwrap() {
+                frames.push(frame);
+            }
+        }
+        assert_eq!(frames[0].0.status, SessionStatus::New);
+        assert_eq!(frames[1].0.status, SessionStatus::Keep);
+        for (metadata, _) in &frames {
+            assert_eq!(metadata.target.as_ref(), Some(&target));
+        }
+        assert_eq!(frames[1].1.as_deref(), Some(&b"second"[..]));
+
+        // A response from another source of the mapping is delivered.
+        let mut response = BytesMut::new();
+        FrameMetadata {
+            session_id: SESSION_ID,
+            status: SessionStatus::Keep,
+            option: FrameOption::new().with_data(),
+            target: Some(location("1.1.1.1", 3479)),
+            network: Some(TargetNetwork::Udp),
+        }
+        .encode(&mut response)
+        .unwrap();
+        response.put_u16(5);
+        response.put_slice(b"reply");
+        FrameMetadata {
+            session_id: SESSION_ID,
+            status: SessionStatus::End,
+            option: FrameOption::new(),
+            target: None,
+            network: None,
+        }
+        .encode(&mut response)
+        .unwrap();
+        server.write_all(&response).await.unwrap();
+
+        let mut data = [0u8; 1024];
+        let mut read_buf = ReadBuf::new(&mut data);
+        poll_fn(|cx| Pin::new(&mut stream).poll_read_message(cx, &mut read_buf))
+            .await
+            .unwrap();
+        assert_eq!(read_buf.filled(), b"reply");
+
+        let mut read_buf = ReadBuf::new(&mut data);
+        poll_fn(|cx| Pin::new(&mut stream).poll_read_message(cx, &mut read_buf))
+            .await
+            .unwrap();
+        assert!(read_buf.filled().is_empty());
+
+        stream.shutdown_message().await.unwrap();
+    }
+}
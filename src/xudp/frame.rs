@@ -6,6 +6,9 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 
 use crate::address::{Address, NetLocation};
 
+/// Length of the GlobalID that follows the address of New UDP frames
+pub const GLOBAL_ID_LEN: usize = 8;
+
 /// XUDP session status
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -111,6 +114,16 @@ pub struct FrameMetadata {
 impl FrameMetadata {
     /// Encode frame metadata to bytes
     pub fn encode(&self, buf: &mut BytesMut) -> std::io::Result<()> {
+        self.encode_with_global_id(buf, None)
+    }
+
+    /// Encode frame metadata to bytes, appending the GlobalID of a New UDP frame.
+    /// Servers use the GlobalID to keep the same UDP mapping for the client (full-cone NAT).
+    pub fn encode_with_global_id(
+        &self,
+        buf: &mut BytesMut,
+        global_id: Option<&[u8; GLOBAL_ID_LEN]>,
+    ) -> std::io::Result<()> {
         // Reserve space for length (will fill in at end)
         let length_pos = buf.len();
         buf.put_u16(0);
@@ -146,6 +159,12 @@ impl FrameMetadata {
 
             // Address
             encode_address(buf, target.address())?;
+
+            if let Some(global_id) = global_id
+                && network == TargetNetwork::Udp
+            {
+                buf.put_slice(global_id);
+            }
         } else if matches!(self.status, SessionStatus::Keep)
             && matches!(self.network, Some(TargetNetwork::Udp))
         {
@@ -260,6 +279,46 @@ impl FrameMetadata {
     }
 }
 
+/// Decode one complete frame (metadata and optional data) from the buffer.
+///
+/// Returns None without consuming anything if the buffer doesn't hold a complete frame yet.
+pub fn decode_frame(
+    buf: &mut BytesMut,
+) -> std::io::Result<Option<(FrameMetadata, Option<BytesMut>)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let metadata_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+    let mut frame_len = 2 + metadata_len;
+    if buf.len() < frame_len {
+        return Ok(None);
+    }
+
+    // The option byte follows the length, session ID and status.
+    let has_data = metadata_len >= 4 && FrameOption::from(buf[5]).has_data();
+    if has_data {
+        if buf.len() < frame_len + 2 {
+            return Ok(None);
+        }
+        let data_len = u16::from_be_bytes([buf[frame_len], buf[frame_len + 1]]) as usize;
+        frame_len += 2 + data_len;
+        if buf.len() < frame_len {
+            return Ok(None);
+        }
+    }
+
+    let mut frame = buf.split_to(frame_len);
+    let metadata = FrameMetadata::decode(&mut frame)?
+        .expect("metadata decode should succeed after length check");
+    let data = if has_data {
+        frame.advance(2);
+        Some(frame)
+    } else {
+        None
+    };
+    Ok(Some((metadata, data)))
+}
+
 /// Encode address to buffer (VLESS address format)
 fn encode_address(buf: &mut BytesMut, address: &Address) -> std::io::Result<()> {
     match address {
@@ -483,6 +542,76 @@ mod tests {
         assert_eq!(buf.len(), 0, "Buffer should be fully consumed after decode");
     }
 
+    #[test]
+    fn test_encode_with_global_id() {
+        let global_id = [1, 2, 3, 4, 5, 6, 7, 8];
+        let metadata = FrameMetadata {
+            session_id: 0,
+            status: SessionStatus::New,
+            option: FrameOption::new().with_data(),
+            target: Some(NetLocation::new(
+                Address::Ipv4("8.8.8.8".parse().unwrap()),
+                53,
+            )),
+            network: Some(TargetNetwork::Udp),
+        };
+
+        let mut buf = BytesMut::new();
+        metadata
+            .encode_with_global_id(&mut buf, Some(&global_id))
+            .unwrap();
+        // session_id(2) + status(1) + option(1) + network(1) + port(2) + addr_type(1) + ipv4(4)
+        assert_eq!(
+            u16::from_be_bytes([buf[0], buf[1]]),
+            12 + GLOBAL_ID_LEN as u16
+        );
+        assert_eq!(&buf[buf.len() - GLOBAL_ID_LEN..], &global_id);
+
+        let decoded = FrameMetadata::decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.target, metadata.target);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let metadata = FrameMetadata {
+            session_id: 0,
+            status: SessionStatus::Keep,
+            option: FrameOption::new().with_data(),
+            target: Some(NetLocation::new(
+                Address::Ipv4("1.1.1.1".parse().unwrap()),
+                53,
+            )),
+            network: Some(TargetNetwork::Udp),
+        };
+        let mut encoded = BytesMut::new();
+        metadata.encode(&mut encoded).unwrap();
+        encoded.put_u16(5);
+        encoded.put_slice(b"hello");
+        FrameMetadata {
+            session_id: 0,
+            status: SessionStatus::End,
+            option: FrameOption::new(),
+            target: None,
+            network: None,
+        }
+        .encode(&mut encoded)
+        .unwrap();
+
+        // The incomplete End frame is left in the buffer.
+        let mut buf = BytesMut::from(&encoded[..encoded.len() - 1]);
+        let (decoded, data) = decode_frame(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.target, metadata.target);
+        assert_eq!(data.as_deref(), Some(&b"hello"[..]));
+        assert!(decode_frame(&mut buf).unwrap().is_none());
+        assert_eq!(buf.len(), 5);
+
+        buf.put_u8(encoded[encoded.len() - 1]);
+        let (decoded, data) = decode_frame(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.status, SessionStatus::End);
+        assert!(data.is_none());
+    }
+
     /// Test Keep frame without destination (TCP keep)
     /// This is a minimal Keep frame with just session_id + status + option
     #[test]